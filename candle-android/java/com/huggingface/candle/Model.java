@@ -0,0 +1,127 @@
+package com.huggingface.candle;
+
+import android.content.res.AssetManager;
+
+/**
+ * A quantized llama model loaded from a gguf file, running on the cpu.
+ *
+ * <p>Generations hold a lock on the model, run them on a background thread. The model must be
+ * closed to release its memory.
+ */
+public final class Model implements AutoCloseable {
+    static {
+        System.loadLibrary("candle_android");
+    }
+
+    private long handle;
+
+    private Model(long handle) {
+        this.handle = handle;
+    }
+
+    /** Loads a model from a file, {@code tokenizerPath} can be null to only use token ids. */
+    public static Model load(String path, String tokenizerPath) {
+        return new Model(nativeLoad(path, tokenizerPath));
+    }
+
+    /**
+     * Loads a model from the application assets. The gguf file should be stored uncompressed,
+     * e.g. with {@code androidResources { noCompress += "gguf" }}, so that it is not decompressed
+     * in memory.
+     */
+    public static Model loadFromAsset(AssetManager assets, String modelAsset, String tokenizerAsset) {
+        return new Model(nativeLoadFromAsset(assets, modelAsset, tokenizerAsset));
+    }
+
+    /**
+     * Sets the number of threads used by the cpu kernels, this must be called before the first
+     * model is loaded. Returns false if the thread pool was already started.
+     */
+    public static boolean setThreadCount(int numThreads) {
+        return nativeSetThreadCount(numThreads);
+    }
+
+    public static int getThreadCount() {
+        return nativeGetThreadCount();
+    }
+
+    /** Whether the library was built with the neon kernels. */
+    public static boolean hasNeon() {
+        return nativeHasNeon();
+    }
+
+    /** The end of sequence token, or -1 if not specified in the gguf metadata. */
+    public synchronized long eosToken() {
+        return nativeEosToken(checkHandle());
+    }
+
+    /**
+     * Generates a completion of {@code prompt}. A {@code temperature} of 0 or less results in
+     * greedy decoding, {@code topP} and {@code topK} are disabled with 0. Returns the number of
+     * generated tokens.
+     */
+    public synchronized int generate(
+            String prompt,
+            int maxTokens,
+            float temperature,
+            float topP,
+            int topK,
+            long seed,
+            float repeatPenalty,
+            TokenCallback callback) {
+        return nativeGenerate(
+                checkHandle(), prompt, maxTokens, temperature, topP, topK, seed, repeatPenalty, callback);
+    }
+
+    public int generate(String prompt, int maxTokens, TokenCallback callback) {
+        return generate(prompt, maxTokens, 0.8f, 0f, 0, 299792458L, 1.1f, callback);
+    }
+
+    /** Releases the kv cache, e.g. on {@code onTrimMemory}. */
+    public synchronized void clearCache() {
+        nativeClearCache(checkHandle());
+    }
+
+    @Override
+    public synchronized void close() {
+        if (handle != 0) {
+            nativeFree(handle);
+            handle = 0;
+        }
+    }
+
+    private long checkHandle() {
+        if (handle == 0) {
+            throw new IllegalStateException("the model has been closed");
+        }
+        return handle;
+    }
+
+    private static native long nativeLoad(String path, String tokenizerPath);
+
+    private static native long nativeLoadFromAsset(
+            AssetManager assets, String modelAsset, String tokenizerAsset);
+
+    private static native void nativeFree(long handle);
+
+    private static native void nativeClearCache(long handle);
+
+    private static native long nativeEosToken(long handle);
+
+    private static native int nativeGenerate(
+            long handle,
+            String prompt,
+            int maxTokens,
+            float temperature,
+            float topP,
+            int topK,
+            long seed,
+            float repeatPenalty,
+            TokenCallback callback);
+
+    private static native boolean nativeSetThreadCount(int numThreads);
+
+    private static native int nativeGetThreadCount();
+
+    private static native boolean nativeHasNeon();
+}
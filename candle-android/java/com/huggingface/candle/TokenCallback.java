@@ -0,0 +1,10 @@
+package com.huggingface.candle;
+
+/** Receives the tokens produced by {@link Model#generate}. */
+public interface TokenCallback {
+    /**
+     * Called for each generated token, {@code text} is the newly decoded text or null when the
+     * token does not complete a character yet. Returning false stops the generation.
+     */
+    boolean onToken(int token, String text);
+}
@@ -0,0 +1,75 @@
+//! Access to the files bundled in the application package through the NDK asset manager.
+use jni::objects::JObject;
+use jni::JNIEnv;
+use std::ffi::{c_char, c_int, c_void, CString};
+
+#[repr(C)]
+struct AAssetManager {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct AAsset {
+    _private: [u8; 0],
+}
+
+// Maps the asset in memory when it is stored uncompressed in the package.
+const AASSET_MODE_BUFFER: c_int = 3;
+
+#[link(name = "android")]
+extern "C" {
+    fn AAssetManager_fromJava(
+        env: *mut jni::sys::JNIEnv,
+        asset_manager: jni::sys::jobject,
+    ) -> *mut AAssetManager;
+    fn AAssetManager_open(
+        mgr: *mut AAssetManager,
+        filename: *const c_char,
+        mode: c_int,
+    ) -> *mut AAsset;
+    fn AAsset_getBuffer(asset: *mut AAsset) -> *const c_void;
+    fn AAsset_getLength64(asset: *mut AAsset) -> i64;
+    fn AAsset_close(asset: *mut AAsset);
+}
+
+/// An opened asset, the content is available as a byte slice until the asset is dropped.
+pub struct Asset {
+    asset: *mut AAsset,
+}
+
+impl Asset {
+    /// Opens `name` using an `android.content.res.AssetManager` object. Models should be stored
+    /// uncompressed in the package, e.g. with `noCompress 'gguf'`, so that they can be mapped
+    /// rather than decompressed in memory.
+    pub fn open(env: &JNIEnv, asset_manager: &JObject, name: &str) -> Result<Self, String> {
+        let name_c = CString::new(name).map_err(|_| format!("invalid asset name {name}"))?;
+        let asset = unsafe {
+            let mgr = AAssetManager_fromJava(env.get_raw(), asset_manager.as_raw());
+            if mgr.is_null() {
+                return Err("invalid asset manager".to_string());
+            }
+            AAssetManager_open(mgr, name_c.as_ptr(), AASSET_MODE_BUFFER)
+        };
+        if asset.is_null() {
+            return Err(format!("cannot open asset {name}"));
+        }
+        Ok(Self { asset })
+    }
+
+    pub fn bytes(&self) -> Result<&[u8], String> {
+        unsafe {
+            let data = AAsset_getBuffer(self.asset);
+            if data.is_null() {
+                return Err("cannot map asset".to_string());
+            }
+            let len = AAsset_getLength64(self.asset) as usize;
+            Ok(std::slice::from_raw_parts(data as *const u8, len))
+        }
+    }
+}
+
+impl Drop for Asset {
+    fn drop(&mut self) {
+        unsafe { AAsset_close(self.asset) }
+    }
+}
@@ -0,0 +1,298 @@
+//! JNI bindings to run quantized models on Android, see `java/com/huggingface/candle/Model.java`
+//! for the Java side.
+//!
+//! The models run on the cpu, the aarch64 builds use the neon kernels. Models are referred to
+//! from Java through the address of a `candle_c::CandleModel`.
+use std::ffi::{c_char, c_void, CStr, CString};
+
+use candle_c::{CandleGenerationParams, CandleModel};
+use jni::objects::{JClass, JObject, JString, JValue};
+use jni::sys::{jboolean, jfloat, jint, jlong};
+use jni::JNIEnv;
+
+#[cfg(target_os = "android")]
+mod asset;
+
+fn last_error() -> String {
+    let err = candle_c::candle_last_error();
+    if err.is_null() {
+        "unknown candle error".to_string()
+    } else {
+        unsafe { CStr::from_ptr(err) }.to_string_lossy().to_string()
+    }
+}
+
+fn throw(env: &mut JNIEnv, msg: impl AsRef<str>) {
+    // If an exception is already pending, it is the one reported to the caller.
+    if !env.exception_check().unwrap_or(true) {
+        let _ = env.throw_new("java/lang/RuntimeException", msg.as_ref());
+    }
+}
+
+fn get_string(env: &mut JNIEnv, s: &JString) -> Result<Option<CString>, String> {
+    if s.is_null() {
+        return Ok(None);
+    }
+    let s: String = env.get_string(s).map_err(|e| e.to_string())?.into();
+    CString::new(s)
+        .map(Some)
+        .map_err(|_| "unexpected nul character in string".to_string())
+}
+
+fn model_ptr(handle: jlong) -> *mut CandleModel {
+    handle as *mut CandleModel
+}
+
+/// Runs `f` with a cpu device that is released afterwards, models hold their own reference to it.
+fn with_cpu_device(f: impl FnOnce(*const candle_c::CandleDevice) -> *mut CandleModel) -> jlong {
+    let device = candle_c::candle_device_cpu();
+    let model = f(device);
+    unsafe { candle_c::candle_device_free(device) };
+    model as jlong
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_huggingface_candle_Model_nativeLoad(
+    mut env: JNIEnv,
+    _class: JClass,
+    path: JString,
+    tokenizer_path: JString,
+) -> jlong {
+    let paths = get_string(&mut env, &path).and_then(|path| {
+        let path = path.ok_or_else(|| "path is null".to_string())?;
+        Ok((path, get_string(&mut env, &tokenizer_path)?))
+    });
+    let (path, tokenizer_path) = match paths {
+        Ok(paths) => paths,
+        Err(err) => {
+            throw(&mut env, err);
+            return 0;
+        }
+    };
+    let tokenizer_path = tokenizer_path
+        .as_ref()
+        .map_or(std::ptr::null(), |p| p.as_ptr());
+    let model = with_cpu_device(|device| unsafe {
+        candle_c::candle_model_load_gguf(path.as_ptr(), tokenizer_path, device)
+    });
+    if model == 0 {
+        throw(&mut env, last_error())
+    }
+    model
+}
+
+#[cfg(target_os = "android")]
+#[no_mangle]
+pub extern "system" fn Java_com_huggingface_candle_Model_nativeLoadFromAsset(
+    mut env: JNIEnv,
+    _class: JClass,
+    asset_manager: JObject,
+    model_asset: JString,
+    tokenizer_asset: JString,
+) -> jlong {
+    let load = |env: &mut JNIEnv| -> Result<jlong, String> {
+        let model_asset =
+            get_string(env, &model_asset)?.ok_or_else(|| "model asset is null".to_string())?;
+        let model = asset::Asset::open(env, &asset_manager, &model_asset.to_string_lossy())?;
+        let tokenizer = match get_string(env, &tokenizer_asset)? {
+            None => None,
+            Some(name) => Some(asset::Asset::open(
+                env,
+                &asset_manager,
+                &name.to_string_lossy(),
+            )?),
+        };
+        let data = model.bytes()?;
+        let tokenizer = match &tokenizer {
+            None => None,
+            Some(tokenizer) => Some(tokenizer.bytes()?),
+        };
+        let (tokenizer_json, tokenizer_len) =
+            tokenizer.map_or((std::ptr::null(), 0), |t| (t.as_ptr(), t.len()));
+        let model = with_cpu_device(|device| unsafe {
+            candle_c::candle_model_load_gguf_from_memory(
+                data.as_ptr(),
+                data.len(),
+                tokenizer_json,
+                tokenizer_len,
+                device,
+            )
+        });
+        if model == 0 {
+            return Err(last_error());
+        }
+        Ok(model)
+    };
+    match load(&mut env) {
+        Ok(model) => model,
+        Err(err) => {
+            throw(&mut env, err);
+            0
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_huggingface_candle_Model_nativeFree(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) {
+    unsafe { candle_c::candle_model_free(model_ptr(handle)) }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_huggingface_candle_Model_nativeClearCache(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) {
+    unsafe { candle_c::candle_model_clear_kv_cache(model_ptr(handle)) }
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_huggingface_candle_Model_nativeEosToken(
+    _env: JNIEnv,
+    _class: JClass,
+    handle: jlong,
+) -> jlong {
+    unsafe { candle_c::candle_model_eos_token(model_ptr(handle)) }
+}
+
+struct Callback<'a, 'local> {
+    env: &'a mut JNIEnv<'local>,
+    callback: &'a JObject<'local>,
+    error: Option<String>,
+}
+
+impl Callback<'_, '_> {
+    fn on_token(&mut self, token: u32, text: *const c_char) -> Result<bool, String> {
+        let env = &mut *self.env;
+        let text = if text.is_null() {
+            JObject::null()
+        } else {
+            let text = unsafe { CStr::from_ptr(text) }.to_string_lossy();
+            env.new_string(text).map_err(|e| e.to_string())?.into()
+        };
+        let res = env.call_method(
+            self.callback,
+            "onToken",
+            "(ILjava/lang/String;)Z",
+            &[JValue::Int(token as jint), JValue::Object(&text)],
+        );
+        let _ = env.delete_local_ref(text);
+        // Exceptions raised by the callback stay pending and are rethrown in Java.
+        res.and_then(|v| v.z()).map_err(|e| e.to_string())
+    }
+}
+
+unsafe extern "C" fn on_token(token: u32, text: *const c_char, user_data: *mut c_void) -> i32 {
+    let callback = &mut *(user_data as *mut Callback);
+    match callback.on_token(token, text) {
+        Ok(keep_going) => !keep_going as i32,
+        Err(err) => {
+            callback.error = Some(err);
+            1
+        }
+    }
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "system" fn Java_com_huggingface_candle_Model_nativeGenerate<'local>(
+    mut env: JNIEnv<'local>,
+    _class: JClass<'local>,
+    handle: jlong,
+    prompt: JString,
+    max_tokens: jint,
+    temperature: jfloat,
+    top_p: jfloat,
+    top_k: jint,
+    seed: jlong,
+    repeat_penalty: jfloat,
+    callback: JObject<'local>,
+) -> jint {
+    let prompt = match get_string(&mut env, &prompt) {
+        Ok(Some(prompt)) => prompt,
+        Ok(None) => {
+            throw(&mut env, "prompt is null");
+            return -1;
+        }
+        Err(err) => {
+            throw(&mut env, err);
+            return -1;
+        }
+    };
+    let params = CandleGenerationParams {
+        max_tokens: max_tokens.max(0) as usize,
+        temperature: temperature as f64,
+        top_p: top_p as f64,
+        top_k: top_k.max(0) as usize,
+        seed: seed as u64,
+        repeat_penalty,
+        ..candle_c::candle_generation_params_default()
+    };
+    let mut cb = Callback {
+        env: &mut env,
+        callback: &callback,
+        error: None,
+    };
+    let (callback_fn, user_data): (candle_c::CandleTokenCallback, *mut c_void) =
+        if callback.is_null() {
+            (None, std::ptr::null_mut())
+        } else {
+            (Some(on_token), &mut cb as *mut Callback as *mut c_void)
+        };
+    let generated = unsafe {
+        candle_c::candle_model_generate_text(
+            model_ptr(handle),
+            prompt.as_ptr(),
+            &params,
+            callback_fn,
+            user_data,
+        )
+    };
+    let error = cb.error.take();
+    if generated < 0 {
+        throw(&mut env, last_error());
+        return -1;
+    }
+    if let Some(err) = error {
+        throw(&mut env, err);
+        return -1;
+    }
+    generated as jint
+}
+
+/// Sets the number of threads used by the cpu kernels. This only has an effect before the first
+/// model is run, the thread pool cannot be resized once started.
+#[no_mangle]
+pub extern "system" fn Java_com_huggingface_candle_Model_nativeSetThreadCount(
+    _env: JNIEnv,
+    _class: JClass,
+    num_threads: jint,
+) -> jboolean {
+    let num_threads = num_threads.max(1) as usize;
+    // The gemm and quantized matmul kernels size their work with this variable.
+    std::env::set_var("RAYON_NUM_THREADS", num_threads.to_string());
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(num_threads)
+        .build_global()
+        .is_ok() as jboolean
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_huggingface_candle_Model_nativeGetThreadCount(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jint {
+    candle::utils::get_num_threads() as jint
+}
+
+#[no_mangle]
+pub extern "system" fn Java_com_huggingface_candle_Model_nativeHasNeon(
+    _env: JNIEnv,
+    _class: JClass,
+) -> jboolean {
+    candle::utils::with_neon() as jboolean
+}
@@ -0,0 +1,22 @@
+// swift-tools-version:5.7
+// Run ./build-xcframework.sh before using this package.
+import PackageDescription
+
+let package = Package(
+    name: "Candle",
+    platforms: [.iOS(.v15), .macOS(.v12)],
+    products: [
+        .library(name: "Candle", targets: ["Candle"])
+    ],
+    targets: [
+        .binaryTarget(name: "CandleC", path: "build/CandleC.xcframework"),
+        .target(
+            name: "Candle",
+            dependencies: ["CandleC"],
+            linkerSettings: [
+                .linkedFramework("Metal"),
+                .linkedFramework("Foundation"),
+            ]
+        ),
+    ]
+)
@@ -0,0 +1,260 @@
+import CandleC
+import Dispatch
+import Foundation
+
+/// An error reported by the candle C API.
+public struct CandleError: Error, CustomStringConvertible {
+    public let description: String
+
+    static func last() -> CandleError {
+        guard let msg = candle_last_error() else {
+            return CandleError(description: "unknown candle error")
+        }
+        return CandleError(description: String(cString: msg))
+    }
+}
+
+/// The device models run on.
+public final class Device {
+    let handle: OpaquePointer
+
+    private init(_ handle: OpaquePointer?) throws {
+        guard let handle = handle else { throw CandleError.last() }
+        self.handle = handle
+    }
+
+    public static func cpu() throws -> Device {
+        try Device(candle_device_cpu())
+    }
+
+    public static func metal(ordinal: Int = 0) throws -> Device {
+        try Device(candle_device_metal(ordinal))
+    }
+
+    /// Releases the buffers cached by the allocator that are not used anymore.
+    public func releaseMemory() {
+        _ = candle_device_release_memory(handle)
+    }
+
+    deinit {
+        candle_device_free(handle)
+    }
+}
+
+/// The sampling parameters, see `CandleGenerationParams` in `candle.h`.
+public struct GenerationParams {
+    public var maxTokens: Int
+    /// Values of 0 or less result in greedy decoding.
+    public var temperature: Double
+    /// Nucleus sampling threshold, disabled when nil.
+    public var topP: Double?
+    /// Only sample among the `topK` most likely tokens, disabled when nil.
+    public var topK: Int?
+    public var seed: UInt64
+    public var repeatPenalty: Float
+    public var repeatLastN: Int
+
+    public init() {
+        let params = candle_generation_params_default()
+        maxTokens = params.max_tokens
+        temperature = params.temperature
+        topP = nil
+        topK = nil
+        seed = params.seed
+        repeatPenalty = params.repeat_penalty
+        repeatLastN = params.repeat_last_n
+    }
+
+    var cParams: CandleGenerationParams {
+        var params = candle_generation_params_default()
+        params.max_tokens = maxTokens
+        params.temperature = temperature
+        params.top_p = topP ?? 0
+        params.top_k = topK ?? 0
+        params.seed = seed
+        params.repeat_penalty = repeatPenalty
+        params.repeat_last_n = repeatLastN
+        return params
+    }
+}
+
+/// A quantized llama model loaded from a gguf file.
+///
+/// Generations are serialized, a model can be shared between threads. When the system reports
+/// memory pressure, the running generation is stopped and the kv cache is released.
+public final class Model {
+    private let handle: OpaquePointer
+    private let device: Device
+    private let lock = NSLock()
+    private let cancelled = AtomicFlag()
+    private var memoryPressureSource: DispatchSourceMemoryPressure?
+
+    /// Called after the model released its memory because of a memory pressure event.
+    public var onMemoryPressure: ((DispatchSource.MemoryPressureEvent) -> Void)?
+
+    public init(path: String, tokenizerPath: String? = nil, device: Device) throws {
+        let handle: OpaquePointer?
+        if let tokenizerPath = tokenizerPath {
+            handle = candle_model_load_gguf(path, tokenizerPath, device.handle)
+        } else {
+            handle = candle_model_load_gguf(path, nil, device.handle)
+        }
+        guard let handle = handle else { throw CandleError.last() }
+        self.handle = handle
+        self.device = device
+        startMonitoringMemoryPressure()
+    }
+
+    deinit {
+        memoryPressureSource?.cancel()
+        candle_model_free(handle)
+    }
+
+    /// The end of sequence token, if stored in the gguf metadata.
+    public var eosToken: UInt32? {
+        let token = candle_model_eos_token(handle)
+        return token < 0 ? nil : UInt32(token)
+    }
+
+    /// Stops the running generation, if any, after the current token.
+    public func cancel() {
+        cancelled.set()
+    }
+
+    /// Releases the kv cache and the unused device buffers. This waits for the running
+    /// generation, call `cancel` first to stop it.
+    public func releaseMemory() {
+        lock.lock()
+        defer { lock.unlock() }
+        candle_model_clear_kv_cache(handle)
+        device.releaseMemory()
+    }
+
+    private func startMonitoringMemoryPressure() {
+        let source = DispatchSource.makeMemoryPressureSource(
+            eventMask: [.warning, .critical], queue: .global(qos: .utility))
+        source.setEventHandler { [weak self, weak source] in
+            guard let self = self, let source = source else { return }
+            let event = source.data
+            self.cancel()
+            self.releaseMemory()
+            self.onMemoryPressure?(event)
+        }
+        source.resume()
+        memoryPressureSource = source
+    }
+
+    private final class CallbackBox {
+        let model: Model
+        let onToken: (UInt32, String?) -> Bool
+
+        init(model: Model, onToken: @escaping (UInt32, String?) -> Bool) {
+            self.model = model
+            self.onToken = onToken
+        }
+    }
+
+    private static let callback: CandleTokenCallback = { token, text, userData in
+        let box = Unmanaged<CallbackBox>.fromOpaque(userData!).takeUnretainedValue()
+        if box.model.cancelled.isSet {
+            return 1
+        }
+        let text = text.map { String(cString: $0) }
+        return box.onToken(token, text) ? 0 : 1
+    }
+
+    private func run(
+        _ onToken: @escaping (UInt32, String?) -> Bool,
+        _ generate: (UnsafeMutableRawPointer) -> Int64
+    ) throws -> Int {
+        lock.lock()
+        defer { lock.unlock() }
+        cancelled.reset()
+        let box = Unmanaged.passRetained(CallbackBox(model: self, onToken: onToken))
+        defer { box.release() }
+        let generated = generate(box.toOpaque())
+        if generated < 0 {
+            throw CandleError.last()
+        }
+        return Int(generated)
+    }
+
+    /// Generates a completion of `prompt`, calling `onToken` with each token and the text it
+    /// completes, if any. Returning false from `onToken` stops the generation. This requires the
+    /// model to have been loaded with a tokenizer. Returns the number of generated tokens.
+    @discardableResult
+    public func generate(
+        prompt: String,
+        params: GenerationParams = GenerationParams(),
+        onToken: @escaping (UInt32, String?) -> Bool
+    ) throws -> Int {
+        var cParams = params.cParams
+        return try run(onToken) { userData in
+            candle_model_generate_text(handle, prompt, &cParams, Model.callback, userData)
+        }
+    }
+
+    /// Same as `generate(prompt:params:onToken:)` with a prompt made of token ids.
+    @discardableResult
+    public func generate(
+        tokens: [UInt32],
+        params: GenerationParams = GenerationParams(),
+        onToken: @escaping (UInt32, String?) -> Bool
+    ) throws -> Int {
+        var cParams = params.cParams
+        return try run(onToken) { userData in
+            tokens.withUnsafeBufferPointer { tokens in
+                candle_model_generate(
+                    handle, tokens.baseAddress, tokens.count, &cParams, Model.callback, userData)
+            }
+        }
+    }
+
+    /// Streams the text of a completion, the generation runs on a background queue and stops
+    /// when the stream is no longer consumed.
+    public func stream(
+        prompt: String, params: GenerationParams = GenerationParams()
+    ) -> AsyncThrowingStream<String, Error> {
+        AsyncThrowingStream { continuation in
+            let finished = AtomicFlag()
+            continuation.onTermination = { _ in finished.set() }
+            DispatchQueue.global(qos: .userInitiated).async {
+                do {
+                    try self.generate(prompt: prompt, params: params) { _, text in
+                        if let text = text {
+                            continuation.yield(text)
+                        }
+                        return !finished.isSet
+                    }
+                    continuation.finish()
+                } catch {
+                    continuation.finish(throwing: error)
+                }
+            }
+        }
+    }
+}
+
+/// A flag that can be set from any thread.
+private final class AtomicFlag: @unchecked Sendable {
+    private let lock = NSLock()
+    private var value = false
+
+    func set() {
+        lock.lock()
+        value = true
+        lock.unlock()
+    }
+
+    func reset() {
+        lock.lock()
+        value = false
+        lock.unlock()
+    }
+
+    var isSet: Bool {
+        lock.lock()
+        defer { lock.unlock() }
+        return value
+    }
+}
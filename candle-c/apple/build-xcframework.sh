@@ -0,0 +1,44 @@
+#!/usr/bin/env bash
+# Builds CandleC.xcframework, a static build of the candle C API for iOS devices, the iOS
+# simulator and macOS. The metal backend is enabled by default, set FEATURES to change it.
+set -euo pipefail
+
+FEATURES=${FEATURES:-metal}
+ROOT=$(cd "$(dirname "$0")/../.." && pwd)
+TARGET_DIR=${CARGO_TARGET_DIR:-$ROOT/target}
+OUT=$ROOT/candle-c/apple/build
+LIB=libcandle_c.a
+
+IOS_TARGETS="aarch64-apple-ios"
+SIM_TARGETS="aarch64-apple-ios-sim x86_64-apple-ios"
+MAC_TARGETS="aarch64-apple-darwin x86_64-apple-darwin"
+
+cd "$ROOT"
+for target in $IOS_TARGETS $SIM_TARGETS $MAC_TARGETS; do
+    rustup target add "$target" > /dev/null
+    cargo build --release -p candle-c --features "$FEATURES" --target "$target"
+done
+
+rm -rf "$OUT"
+mkdir -p "$OUT/headers" "$OUT/ios" "$OUT/ios-sim" "$OUT/macos"
+cp candle-c/include/candle.h candle-c/apple/module.modulemap "$OUT/headers/"
+
+universal() {
+    local dest=$1
+    shift
+    local libs=()
+    for target in "$@"; do
+        libs+=("$TARGET_DIR/$target/release/$LIB")
+    done
+    lipo -create "${libs[@]}" -output "$dest/$LIB"
+}
+universal "$OUT/ios" $IOS_TARGETS
+universal "$OUT/ios-sim" $SIM_TARGETS
+universal "$OUT/macos" $MAC_TARGETS
+
+xcodebuild -create-xcframework \
+    -library "$OUT/ios/$LIB" -headers "$OUT/headers" \
+    -library "$OUT/ios-sim/$LIB" -headers "$OUT/headers" \
+    -library "$OUT/macos/$LIB" -headers "$OUT/headers" \
+    -output "$OUT/CandleC.xcframework"
+echo "built $OUT/CandleC.xcframework"
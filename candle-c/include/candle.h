@@ -0,0 +1,90 @@
+/* C API for candle, see src/lib.rs for the details of each function. */
+#ifndef CANDLE_H
+#define CANDLE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct CandleDevice CandleDevice;
+typedef struct CandleTensor CandleTensor;
+typedef struct CandleModel CandleModel;
+
+typedef enum CandleDType {
+  CANDLE_DTYPE_U8 = 0,
+  CANDLE_DTYPE_U32 = 1,
+  CANDLE_DTYPE_I64 = 2,
+  CANDLE_DTYPE_BF16 = 3,
+  CANDLE_DTYPE_F16 = 4,
+  CANDLE_DTYPE_F32 = 5,
+  CANDLE_DTYPE_F64 = 6,
+} CandleDType;
+
+typedef struct CandleGenerationParams {
+  size_t max_tokens;
+  double temperature;    /* <= 0 for greedy decoding */
+  double top_p;          /* disabled when not in (0, 1) */
+  size_t top_k;          /* disabled when 0 */
+  uint64_t seed;
+  float repeat_penalty;  /* 1 disables the penalty */
+  size_t repeat_last_n;
+} CandleGenerationParams;
+
+/* Called for each generated token, `text` is the newly decoded text or NULL. Return a non-zero
+ * value to stop the generation. */
+typedef int32_t (*CandleTokenCallback)(uint32_t token, const char *text, void *user_data);
+
+/* Errors: functions return NULL or a negative value on failure. */
+const char *candle_last_error(void);
+const char *candle_version(void);
+
+/* Devices. */
+CandleDevice *candle_device_cpu(void);
+CandleDevice *candle_device_cuda(size_t ordinal);
+CandleDevice *candle_device_metal(size_t ordinal);
+void candle_device_free(CandleDevice *device);
+int32_t candle_device_release_memory(const CandleDevice *device);
+
+/* Tensors. */
+CandleTensor *candle_tensor_new(const void *data, CandleDType dtype, const size_t *dims,
+                                size_t rank, const CandleDevice *device);
+CandleTensor *candle_tensor_zeros(CandleDType dtype, const size_t *dims, size_t rank,
+                                  const CandleDevice *device);
+void candle_tensor_free(CandleTensor *tensor);
+size_t candle_tensor_rank(const CandleTensor *tensor);
+const size_t *candle_tensor_dims(const CandleTensor *tensor);
+size_t candle_tensor_elem_count(const CandleTensor *tensor);
+CandleDType candle_tensor_dtype(const CandleTensor *tensor);
+int32_t candle_tensor_copy_to(const CandleTensor *tensor, void *out, size_t out_len);
+CandleTensor *candle_tensor_to_dtype(const CandleTensor *tensor, CandleDType dtype);
+CandleTensor *candle_tensor_to_device(const CandleTensor *tensor, const CandleDevice *device);
+CandleTensor *candle_tensor_matmul(const CandleTensor *lhs, const CandleTensor *rhs);
+CandleTensor *candle_tensor_add(const CandleTensor *lhs, const CandleTensor *rhs);
+
+/* Quantized llama models and generation. */
+CandleGenerationParams candle_generation_params_default(void);
+CandleModel *candle_model_load_gguf(const char *model_path, const char *tokenizer_path,
+                                    const CandleDevice *device);
+CandleModel *candle_model_load_gguf_from_memory(const uint8_t *data, size_t len,
+                                                const uint8_t *tokenizer_json,
+                                                size_t tokenizer_len, const CandleDevice *device);
+void candle_model_free(CandleModel *model);
+void candle_model_clear_kv_cache(CandleModel *model);
+int64_t candle_model_eos_token(const CandleModel *model);
+int64_t candle_model_tokenize(const CandleModel *model, const char *text, uint32_t *tokens,
+                              size_t capacity);
+int64_t candle_model_generate(CandleModel *model, const uint32_t *prompt, size_t prompt_len,
+                              const CandleGenerationParams *params, CandleTokenCallback callback,
+                              void *user_data);
+int64_t candle_model_generate_text(CandleModel *model, const char *prompt,
+                                   const CandleGenerationParams *params,
+                                   CandleTokenCallback callback, void *user_data);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* CANDLE_H */
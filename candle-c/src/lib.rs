@@ -0,0 +1,402 @@
+//! A C API to embed candle in applications written in other languages.
+//!
+//! All the objects are opaque pointers that are created by the `candle_*_new`/`candle_*_load`
+//! functions and must be released with the matching `candle_*_free` function. Functions that can
+//! fail return a null pointer or a negative value, the error message can then be retrieved with
+//! [`candle_last_error`]. The corresponding header is `include/candle.h`.
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use std::cell::RefCell;
+use std::ffi::{c_char, c_void, CStr, CString};
+
+use candle::{DType, Device, Tensor};
+
+mod model;
+pub use model::{
+    candle_generation_params_default, candle_model_clear_kv_cache, candle_model_eos_token,
+    candle_model_free, candle_model_generate, candle_model_generate_text, candle_model_load_gguf,
+    candle_model_load_gguf_from_memory, candle_model_tokenize, CandleGenerationParams, CandleModel,
+    CandleTokenCallback,
+};
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(err: impl std::fmt::Display) {
+    let msg = CString::new(err.to_string().replace('\0', " "))
+        .unwrap_or_else(|_| c"unknown error".to_owned());
+    LAST_ERROR.with(|e| *e.borrow_mut() = Some(msg))
+}
+
+/// Runs `f`, storing the error message if it fails or panics so that no unwinding crosses the
+/// FFI boundary.
+pub(crate) fn ffi_call<T>(f: impl FnOnce() -> candle::Result<T>) -> Option<T> {
+    match std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)) {
+        Ok(Ok(v)) => Some(v),
+        Ok(Err(err)) => {
+            set_last_error(err);
+            None
+        }
+        Err(_) => {
+            set_last_error("panic in candle");
+            None
+        }
+    }
+}
+
+pub(crate) unsafe fn c_str<'a>(s: *const c_char, name: &str) -> candle::Result<&'a str> {
+    if s.is_null() {
+        candle::bail!("{name} is null")
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| candle::Error::Msg(format!("{name} is not valid utf8")))
+}
+
+pub(crate) unsafe fn as_ref<'a, T>(ptr: *const T, name: &str) -> candle::Result<&'a T> {
+    match ptr.as_ref() {
+        Some(v) => Ok(v),
+        None => candle::bail!("{name} is null"),
+    }
+}
+
+/// Returns the message of the last error that occurred on the calling thread, or null if there
+/// was none. The string is owned by candle and remains valid until the next failing call on the
+/// same thread.
+#[no_mangle]
+pub extern "C" fn candle_last_error() -> *const c_char {
+    LAST_ERROR.with(|e| match e.borrow().as_ref() {
+        Some(msg) => msg.as_ptr(),
+        None => std::ptr::null(),
+    })
+}
+
+/// Returns the version of the library as a static string.
+#[no_mangle]
+pub extern "C" fn candle_version() -> *const c_char {
+    concat!(env!("CARGO_PKG_VERSION"), "\0").as_ptr() as *const c_char
+}
+
+/// The element types of tensors, the values match the `CandleDType` enum of the header.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CandleDType {
+    U8 = 0,
+    U32 = 1,
+    I64 = 2,
+    BF16 = 3,
+    F16 = 4,
+    F32 = 5,
+    F64 = 6,
+}
+
+impl From<CandleDType> for DType {
+    fn from(dtype: CandleDType) -> Self {
+        match dtype {
+            CandleDType::U8 => DType::U8,
+            CandleDType::U32 => DType::U32,
+            CandleDType::I64 => DType::I64,
+            CandleDType::BF16 => DType::BF16,
+            CandleDType::F16 => DType::F16,
+            CandleDType::F32 => DType::F32,
+            CandleDType::F64 => DType::F64,
+        }
+    }
+}
+
+impl From<DType> for CandleDType {
+    fn from(dtype: DType) -> Self {
+        match dtype {
+            DType::U8 => CandleDType::U8,
+            DType::U32 => CandleDType::U32,
+            DType::I64 => CandleDType::I64,
+            DType::BF16 => CandleDType::BF16,
+            DType::F16 => CandleDType::F16,
+            DType::F32 => CandleDType::F32,
+            DType::F64 => CandleDType::F64,
+        }
+    }
+}
+
+/// An opaque handle to a device.
+pub struct CandleDevice(pub(crate) Device);
+
+/// An opaque handle to a tensor.
+pub struct CandleTensor(pub(crate) Tensor);
+
+fn device_ptr(device: candle::Result<Device>) -> *mut CandleDevice {
+    match ffi_call(|| device) {
+        Some(device) => Box::into_raw(Box::new(CandleDevice(device))),
+        None => std::ptr::null_mut(),
+    }
+}
+
+fn tensor_ptr(f: impl FnOnce() -> candle::Result<Tensor>) -> *mut CandleTensor {
+    match ffi_call(f) {
+        Some(tensor) => Box::into_raw(Box::new(CandleTensor(tensor))),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Returns the cpu device.
+#[no_mangle]
+pub extern "C" fn candle_device_cpu() -> *mut CandleDevice {
+    device_ptr(Ok(Device::Cpu))
+}
+
+/// Returns the cuda device with the given ordinal, or null if cuda is not available.
+#[no_mangle]
+pub extern "C" fn candle_device_cuda(ordinal: usize) -> *mut CandleDevice {
+    device_ptr(Device::new_cuda(ordinal))
+}
+
+/// Returns the metal device with the given ordinal, or null if metal is not available.
+#[no_mangle]
+pub extern "C" fn candle_device_metal(ordinal: usize) -> *mut CandleDevice {
+    device_ptr(Device::new_metal(ordinal))
+}
+
+/// Releases a device, the tensors and models created on it remain valid.
+///
+/// # Safety
+/// `device` must be null or a pointer returned by one of the `candle_device_*` functions that
+/// has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn candle_device_free(device: *mut CandleDevice) {
+    if !device.is_null() {
+        drop(Box::from_raw(device))
+    }
+}
+
+/// Releases the memory cached by the device allocator that is not used by any tensor, this is a
+/// no-op on the cpu. Returns 0 on success and -1 on failure.
+///
+/// # Safety
+/// `device` must be a valid device.
+#[no_mangle]
+pub unsafe extern "C" fn candle_device_release_memory(device: *const CandleDevice) -> i32 {
+    let res = ffi_call(|| match &as_ref(device, "device")?.0 {
+        #[cfg(feature = "metal")]
+        Device::Metal(device) => device.drop_unused_buffers(),
+        _ => Ok(()),
+    });
+    if res.is_some() {
+        0
+    } else {
+        -1
+    }
+}
+
+unsafe fn dims<'a>(dims: *const usize, rank: usize) -> candle::Result<&'a [usize]> {
+    if rank == 0 {
+        Ok(&[])
+    } else if dims.is_null() {
+        candle::bail!("dims is null")
+    } else {
+        Ok(std::slice::from_raw_parts(dims, rank))
+    }
+}
+
+/// Creates a tensor by copying `data`, which holds the elements of a contiguous array of type
+/// `dtype` and of shape `dims`.
+///
+/// # Safety
+/// `data` must point to `elem_count * dtype_size` readable bytes, `dims` to `rank` values and
+/// `device` must be a valid device.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_new(
+    data: *const c_void,
+    dtype: CandleDType,
+    dims: *const usize,
+    rank: usize,
+    device: *const CandleDevice,
+) -> *mut CandleTensor {
+    tensor_ptr(|| {
+        let dims = self::dims(dims, rank)?;
+        let device = &as_ref(device, "device")?.0;
+        let dtype = DType::from(dtype);
+        let elem_count: usize = dims.iter().product();
+        if data.is_null() && elem_count > 0 {
+            candle::bail!("data is null")
+        }
+        let size_in_bytes = elem_count * dtype.size_in_bytes();
+        let data = if size_in_bytes == 0 {
+            &[]
+        } else {
+            std::slice::from_raw_parts(data as *const u8, size_in_bytes)
+        };
+        Tensor::from_raw_buffer(data, dtype, dims, device)
+    })
+}
+
+/// Creates a tensor filled with zeros.
+///
+/// # Safety
+/// `dims` must point to `rank` values and `device` must be a valid device.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_zeros(
+    dtype: CandleDType,
+    dims: *const usize,
+    rank: usize,
+    device: *const CandleDevice,
+) -> *mut CandleTensor {
+    tensor_ptr(|| {
+        let dims = self::dims(dims, rank)?;
+        let device = &as_ref(device, "device")?.0;
+        Tensor::zeros(dims, dtype.into(), device)
+    })
+}
+
+/// Releases a tensor.
+///
+/// # Safety
+/// `tensor` must be null or a tensor that has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_free(tensor: *mut CandleTensor) {
+    if !tensor.is_null() {
+        drop(Box::from_raw(tensor))
+    }
+}
+
+/// Returns the number of dimensions of the tensor.
+///
+/// # Safety
+/// `tensor` must be a valid tensor.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_rank(tensor: *const CandleTensor) -> usize {
+    (*tensor).0.rank()
+}
+
+/// Returns a pointer to the `rank` dimensions of the tensor, valid as long as the tensor is.
+///
+/// # Safety
+/// `tensor` must be a valid tensor.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_dims(tensor: *const CandleTensor) -> *const usize {
+    (*tensor).0.dims().as_ptr()
+}
+
+/// Returns the number of elements of the tensor.
+///
+/// # Safety
+/// `tensor` must be a valid tensor.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_elem_count(tensor: *const CandleTensor) -> usize {
+    (*tensor).0.elem_count()
+}
+
+/// Returns the element type of the tensor.
+///
+/// # Safety
+/// `tensor` must be a valid tensor.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_dtype(tensor: *const CandleTensor) -> CandleDType {
+    (*tensor).0.dtype().into()
+}
+
+/// Copies the elements of the tensor in row-major order to `out`, moving them to the host if
+/// needed. `out_len` is the size of `out` in bytes and must be at least the size of the tensor
+/// data. Returns 0 on success and -1 on failure.
+///
+/// # Safety
+/// `tensor` must be a valid tensor and `out` must point to `out_len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_copy_to(
+    tensor: *const CandleTensor,
+    out: *mut c_void,
+    out_len: usize,
+) -> i32 {
+    let res = ffi_call(|| {
+        let tensor = &as_ref(tensor, "tensor")?.0;
+        let size_in_bytes = tensor.elem_count() * tensor.dtype().size_in_bytes();
+        if out_len < size_in_bytes {
+            candle::bail!("output buffer too small, {out_len} < {size_in_bytes}")
+        }
+        if size_in_bytes == 0 {
+            return Ok(());
+        }
+        if out.is_null() {
+            candle::bail!("out is null")
+        }
+        let out = std::slice::from_raw_parts_mut(out as *mut u8, size_in_bytes);
+        let tensor = tensor.flatten_all()?.to_device(&Device::Cpu)?;
+        macro_rules! copy {
+            ($ty:ty) => {{
+                let data = tensor.to_vec1::<$ty>()?;
+                let bytes = std::slice::from_raw_parts(data.as_ptr() as *const u8, size_in_bytes);
+                out.copy_from_slice(bytes)
+            }};
+        }
+        match tensor.dtype() {
+            DType::U8 => copy!(u8),
+            DType::U32 => copy!(u32),
+            DType::I64 => copy!(i64),
+            DType::BF16 => copy!(half::bf16),
+            DType::F16 => copy!(half::f16),
+            DType::F32 => copy!(f32),
+            DType::F64 => copy!(f64),
+        }
+        Ok(())
+    });
+    if res.is_some() {
+        0
+    } else {
+        -1
+    }
+}
+
+/// Converts the tensor to another element type, returns a new tensor.
+///
+/// # Safety
+/// `tensor` must be a valid tensor.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_to_dtype(
+    tensor: *const CandleTensor,
+    dtype: CandleDType,
+) -> *mut CandleTensor {
+    tensor_ptr(|| as_ref(tensor, "tensor")?.0.to_dtype(dtype.into()))
+}
+
+/// Copies the tensor to another device, returns a new tensor.
+///
+/// # Safety
+/// `tensor` and `device` must be valid.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_to_device(
+    tensor: *const CandleTensor,
+    device: *const CandleDevice,
+) -> *mut CandleTensor {
+    tensor_ptr(|| {
+        let device = &as_ref(device, "device")?.0;
+        as_ref(tensor, "tensor")?.0.to_device(device)
+    })
+}
+
+/// Returns the matrix product of two tensors.
+///
+/// # Safety
+/// `lhs` and `rhs` must be valid tensors.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_matmul(
+    lhs: *const CandleTensor,
+    rhs: *const CandleTensor,
+) -> *mut CandleTensor {
+    tensor_ptr(|| as_ref(lhs, "lhs")?.0.matmul(&as_ref(rhs, "rhs")?.0))
+}
+
+/// Returns the element-wise sum of two tensors, with broadcasting.
+///
+/// # Safety
+/// `lhs` and `rhs` must be valid tensors.
+#[no_mangle]
+pub unsafe extern "C" fn candle_tensor_add(
+    lhs: *const CandleTensor,
+    rhs: *const CandleTensor,
+) -> *mut CandleTensor {
+    tensor_ptr(|| as_ref(lhs, "lhs")?.0.broadcast_add(&as_ref(rhs, "rhs")?.0))
+}
@@ -0,0 +1,416 @@
+//! Quantized llama models loaded from gguf files and token by token generation.
+use std::ffi::{c_char, c_void, CString};
+
+use candle::quantized::gguf_file;
+use candle::{DType, Device, Tensor};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use candle_transformers::models::quantized_llama::ModelWeights;
+use tokenizers::Tokenizer;
+
+use crate::{as_ref, c_str, ffi_call, CandleDevice};
+
+/// The sampling parameters of [`candle_model_generate`].
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CandleGenerationParams {
+    /// The maximum number of tokens to generate.
+    pub max_tokens: usize,
+    /// The sampling temperature, 0 or less results in greedy decoding.
+    pub temperature: f64,
+    /// Nucleus sampling threshold, disabled when not in `(0, 1)`.
+    pub top_p: f64,
+    /// Only sample among the `top_k` most likely tokens, disabled when 0.
+    pub top_k: usize,
+    pub seed: u64,
+    /// The penalty applied to the logits of the recent tokens, 1 disables it.
+    pub repeat_penalty: f32,
+    /// The number of recent tokens the repeat penalty applies to.
+    pub repeat_last_n: usize,
+}
+
+impl Default for CandleGenerationParams {
+    fn default() -> Self {
+        Self {
+            max_tokens: 256,
+            temperature: 0.8,
+            top_p: 0.,
+            top_k: 0,
+            seed: 299792458,
+            repeat_penalty: 1.1,
+            repeat_last_n: 64,
+        }
+    }
+}
+
+impl CandleGenerationParams {
+    fn sampling(&self) -> Sampling {
+        let temperature = self.temperature;
+        if temperature <= 1e-7 {
+            return Sampling::ArgMax;
+        }
+        let top_p = (self.top_p > 0. && self.top_p < 1.).then_some(self.top_p);
+        let top_k = (self.top_k > 0).then_some(self.top_k);
+        match (top_k, top_p) {
+            (None, None) => Sampling::All { temperature },
+            (Some(k), None) => Sampling::TopK { k, temperature },
+            (None, Some(p)) => Sampling::TopP { p, temperature },
+            (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+        }
+    }
+}
+
+/// Called for each generated token with the text it completes, `text` is null when there is no
+/// tokenizer or when the token does not complete a valid utf8 sequence yet. The text is only
+/// valid for the duration of the call. Returning a non-zero value stops the generation.
+pub type CandleTokenCallback =
+    Option<unsafe extern "C" fn(token: u32, text: *const c_char, user_data: *mut c_void) -> i32>;
+
+/// An opaque handle to a quantized llama model and its optional tokenizer.
+pub struct CandleModel {
+    model: ModelWeights,
+    tokenizer: Option<Tokenizer>,
+    eos_token_id: Option<u32>,
+    device: Device,
+}
+
+/// Incrementally decodes the generated tokens so that the text can be streamed.
+struct TextStream<'a> {
+    tokenizer: &'a Tokenizer,
+    tokens: Vec<u32>,
+    prev_index: usize,
+}
+
+impl TextStream<'_> {
+    fn decode(&self, tokens: &[u32]) -> candle::Result<String> {
+        self.tokenizer
+            .decode(tokens, true)
+            .map_err(|e| candle::Error::Msg(format!("cannot decode: {e}")))
+    }
+
+    fn next_token(&mut self, token: u32) -> candle::Result<Option<String>> {
+        let prev_text = self.decode(&self.tokens[self.prev_index..])?;
+        self.tokens.push(token);
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if text.len() > prev_text.len() && !text.ends_with('\u{FFFD}') {
+            // Keep the last token as context as some tokenizers decode leading spaces
+            // differently at the start of a sequence.
+            self.prev_index = self.tokens.len() - 1;
+            Ok(Some(text[prev_text.len()..].to_string()))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+fn forward(
+    model: &mut ModelWeights,
+    device: &Device,
+    tokens: &[u32],
+    index_pos: usize,
+) -> candle::Result<Tensor> {
+    let input = Tensor::new(tokens, device)?.unsqueeze(0)?;
+    model
+        .forward(&input, index_pos)?
+        .squeeze(0)?
+        .to_dtype(DType::F32)
+}
+
+impl CandleModel {
+    fn load(path: &str, tokenizer: Option<&str>, device: &Device) -> candle::Result<Self> {
+        let tokenizer = match tokenizer {
+            None => None,
+            Some(tokenizer) => Some(
+                Tokenizer::from_file(tokenizer)
+                    .map_err(|e| candle::Error::Msg(format!("cannot load tokenizer: {e}")))?,
+            ),
+        };
+        let mut file = std::fs::File::open(path)?;
+        Self::from_reader(&mut file, tokenizer, device).map_err(|e| e.with_path(path))
+    }
+
+    fn from_reader<R: std::io::Read + std::io::Seek>(
+        reader: &mut R,
+        tokenizer: Option<Tokenizer>,
+        device: &Device,
+    ) -> candle::Result<Self> {
+        let content = gguf_file::Content::read(reader)?;
+        let eos_token_id = content
+            .metadata
+            .get("tokenizer.ggml.eos_token_id")
+            .and_then(|v| v.to_u32().ok());
+        let model = ModelWeights::from_gguf(content, reader, device)?;
+        Ok(Self {
+            model,
+            tokenizer,
+            eos_token_id,
+            device: device.clone(),
+        })
+    }
+
+    fn tokenize(&self, text: &str) -> candle::Result<Vec<u32>> {
+        let tokenizer = match self.tokenizer.as_ref() {
+            Some(tokenizer) => tokenizer,
+            None => candle::bail!("the model has been loaded without a tokenizer"),
+        };
+        let encoding = tokenizer
+            .encode(text, true)
+            .map_err(|e| candle::Error::Msg(format!("cannot encode: {e}")))?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    unsafe fn generate(
+        &mut self,
+        prompt: &[u32],
+        params: &CandleGenerationParams,
+        callback: CandleTokenCallback,
+        user_data: *mut c_void,
+    ) -> candle::Result<usize> {
+        if prompt.is_empty() {
+            candle::bail!("the prompt should contain at least one token")
+        }
+        let mut logits_processor = LogitsProcessor::from_sampling(params.seed, params.sampling());
+        let Self {
+            model,
+            tokenizer,
+            eos_token_id,
+            device,
+        } = self;
+        let mut stream = tokenizer.as_ref().map(|tokenizer| TextStream {
+            tokenizer,
+            tokens: vec![],
+            prev_index: 0,
+        });
+        let mut all_tokens = prompt.to_vec();
+        let mut generated = 0;
+        // Starting at position 0 resets the kv cache.
+        let mut logits = forward(model, device, prompt, 0)?;
+        while generated < params.max_tokens {
+            if params.repeat_penalty != 1. {
+                let start_at = all_tokens.len().saturating_sub(params.repeat_last_n);
+                logits = candle_transformers::utils::apply_repeat_penalty(
+                    &logits,
+                    params.repeat_penalty,
+                    &all_tokens[start_at..],
+                )?;
+            }
+            let next_token = logits_processor.sample(&logits)?;
+            if *eos_token_id == Some(next_token) {
+                break;
+            }
+            all_tokens.push(next_token);
+            generated += 1;
+            let text = match stream.as_mut() {
+                None => None,
+                Some(stream) => match stream.next_token(next_token)? {
+                    None => None,
+                    Some(text) => {
+                        Some(CString::new(text.replace('\0', "")).map_err(candle::Error::wrap)?)
+                    }
+                },
+            };
+            if let Some(callback) = callback {
+                let text = text.as_ref().map_or(std::ptr::null(), |t| t.as_ptr());
+                if callback(next_token, text, user_data) != 0 {
+                    break;
+                }
+            }
+            if generated < params.max_tokens {
+                logits = forward(model, device, &[next_token], all_tokens.len() - 1)?;
+            }
+        }
+        Ok(generated)
+    }
+}
+
+/// Returns the default generation parameters.
+#[no_mangle]
+pub extern "C" fn candle_generation_params_default() -> CandleGenerationParams {
+    CandleGenerationParams::default()
+}
+
+/// Loads a quantized llama model from a gguf file. `tokenizer_path` can be null, in which case
+/// only token ids can be used as prompts. Returns null on failure.
+///
+/// # Safety
+/// The paths must be null terminated strings and `device` a valid device.
+#[no_mangle]
+pub unsafe extern "C" fn candle_model_load_gguf(
+    model_path: *const c_char,
+    tokenizer_path: *const c_char,
+    device: *const CandleDevice,
+) -> *mut CandleModel {
+    let model = ffi_call(|| {
+        let model_path = c_str(model_path, "model_path")?;
+        let tokenizer_path = if tokenizer_path.is_null() {
+            None
+        } else {
+            Some(c_str(tokenizer_path, "tokenizer_path")?)
+        };
+        let device = &as_ref(device, "device")?.0;
+        CandleModel::load(model_path, tokenizer_path, device)
+    });
+    match model {
+        Some(model) => Box::into_raw(Box::new(model)),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Same as [`candle_model_load_gguf`] but reads the gguf file from the `len` bytes of `data`, and
+/// the optional tokenizer from the `tokenizer_len` bytes of `tokenizer_json`. This can be used
+/// with memory mapped files or application assets, the buffers are copied and can be released
+/// once the function returns.
+///
+/// # Safety
+/// `data` must point to `len` readable bytes, `tokenizer_json` must be null or point to
+/// `tokenizer_len` readable bytes and `device` must be a valid device.
+#[no_mangle]
+pub unsafe extern "C" fn candle_model_load_gguf_from_memory(
+    data: *const u8,
+    len: usize,
+    tokenizer_json: *const u8,
+    tokenizer_len: usize,
+    device: *const CandleDevice,
+) -> *mut CandleModel {
+    let model = ffi_call(|| {
+        if data.is_null() {
+            candle::bail!("data is null")
+        }
+        let data = std::slice::from_raw_parts(data, len);
+        let tokenizer = if tokenizer_json.is_null() {
+            None
+        } else {
+            let json = std::slice::from_raw_parts(tokenizer_json, tokenizer_len);
+            let tokenizer = Tokenizer::from_bytes(json)
+                .map_err(|e| candle::Error::Msg(format!("cannot load tokenizer: {e}")))?;
+            Some(tokenizer)
+        };
+        let device = &as_ref(device, "device")?.0;
+        CandleModel::from_reader(&mut std::io::Cursor::new(data), tokenizer, device)
+    });
+    match model {
+        Some(model) => Box::into_raw(Box::new(model)),
+        None => std::ptr::null_mut(),
+    }
+}
+
+/// Releases a model.
+///
+/// # Safety
+/// `model` must be null or a model that has not been freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn candle_model_free(model: *mut CandleModel) {
+    if !model.is_null() {
+        drop(Box::from_raw(model))
+    }
+}
+
+/// Drops the kv cache of the model to release memory, e.g. when the application receives a
+/// memory warning. The cache is rebuilt by the next generation.
+///
+/// # Safety
+/// `model` must be a valid model that is not being used by another thread.
+#[no_mangle]
+pub unsafe extern "C" fn candle_model_clear_kv_cache(model: *mut CandleModel) {
+    if let Some(model) = model.as_mut() {
+        model.model.clear_kv_cache()
+    }
+}
+
+/// Returns the end of sequence token stored in the gguf metadata, or -1 if there is none.
+///
+/// # Safety
+/// `model` must be a valid model.
+#[no_mangle]
+pub unsafe extern "C" fn candle_model_eos_token(model: *const CandleModel) -> i64 {
+    match (*model).eos_token_id {
+        Some(token) => token as i64,
+        None => -1,
+    }
+}
+
+/// Tokenizes `text` and writes up to `capacity` token ids to `tokens`. Returns the total number of
+/// tokens, which can be larger than `capacity` in which case the call should be repeated with a
+/// larger buffer, or -1 on failure.
+///
+/// # Safety
+/// `model` must be a valid model, `text` a null terminated string and `tokens` must point to
+/// `capacity` writable values.
+#[no_mangle]
+pub unsafe extern "C" fn candle_model_tokenize(
+    model: *const CandleModel,
+    text: *const c_char,
+    tokens: *mut u32,
+    capacity: usize,
+) -> i64 {
+    let res = ffi_call(|| {
+        let model = as_ref(model, "model")?;
+        let ids = model.tokenize(c_str(text, "text")?)?;
+        let len = ids.len().min(capacity);
+        if len > 0 {
+            if tokens.is_null() {
+                candle::bail!("tokens is null")
+            }
+            std::slice::from_raw_parts_mut(tokens, len).copy_from_slice(&ids[..len]);
+        }
+        Ok(ids.len() as i64)
+    });
+    res.unwrap_or(-1)
+}
+
+/// Generates tokens following the `prompt_len` token ids of `prompt`, `callback` is called after
+/// each token. The generation stops after `params.max_tokens` tokens, on the end of sequence
+/// token, or when the callback returns a non-zero value. `params` can be null to use the default
+/// parameters. Returns the number of generated tokens or -1 on failure.
+///
+/// # Safety
+/// `model` must be a valid model, `prompt` must point to `prompt_len` values and `params` must be
+/// null or valid. `user_data` is passed as is to the callback.
+#[no_mangle]
+pub unsafe extern "C" fn candle_model_generate(
+    model: *mut CandleModel,
+    prompt: *const u32,
+    prompt_len: usize,
+    params: *const CandleGenerationParams,
+    callback: CandleTokenCallback,
+    user_data: *mut c_void,
+) -> i64 {
+    let res = ffi_call(|| {
+        let model = match model.as_mut() {
+            Some(model) => model,
+            None => candle::bail!("model is null"),
+        };
+        if prompt.is_null() {
+            candle::bail!("prompt is null")
+        }
+        let prompt = std::slice::from_raw_parts(prompt, prompt_len);
+        let params = params.as_ref().copied().unwrap_or_default();
+        model.generate(prompt, &params, callback, user_data)
+    });
+    res.map_or(-1, |n| n as i64)
+}
+
+/// Same as [`candle_model_generate`] but the prompt is a null terminated string, this requires
+/// the model to have been loaded with a tokenizer.
+///
+/// # Safety
+/// `model` must be a valid model, `prompt` a null terminated string and `params` must be null or
+/// valid. `user_data` is passed as is to the callback.
+#[no_mangle]
+pub unsafe extern "C" fn candle_model_generate_text(
+    model: *mut CandleModel,
+    prompt: *const c_char,
+    params: *const CandleGenerationParams,
+    callback: CandleTokenCallback,
+    user_data: *mut c_void,
+) -> i64 {
+    let res = ffi_call(|| {
+        let model = match model.as_mut() {
+            Some(model) => model,
+            None => candle::bail!("model is null"),
+        };
+        let prompt = model.tokenize(c_str(prompt, "prompt")?)?;
+        let params = params.as_ref().copied().unwrap_or_default();
+        model.generate(&prompt, &params, callback, user_data)
+    });
+    res.map_or(-1, |n| n as i64)
+}
@@ -0,0 +1,249 @@
+use anyhow::Result;
+use candle::quantized::{gguf_file, GgmlDType, QTensor};
+use candle::{Device, Tensor};
+use candle_c::*;
+use std::ffi::{c_char, c_void, CStr, CString};
+
+fn last_error() -> String {
+    let err = candle_last_error();
+    assert!(!err.is_null());
+    unsafe { CStr::from_ptr(err) }.to_string_lossy().to_string()
+}
+
+#[test]
+fn tensors() -> Result<()> {
+    unsafe {
+        let device = candle_device_cpu();
+        let data = [1f32, 2., 3., 4., 5., 6.];
+        let lhs = candle_tensor_new(
+            data.as_ptr() as *const c_void,
+            CandleDType::F32,
+            [2, 3].as_ptr(),
+            2,
+            device,
+        );
+        assert!(!lhs.is_null());
+        assert_eq!(candle_tensor_rank(lhs), 2);
+        assert_eq!(
+            std::slice::from_raw_parts(candle_tensor_dims(lhs), 2),
+            [2, 3]
+        );
+        assert_eq!(candle_tensor_elem_count(lhs), 6);
+        assert_eq!(candle_tensor_dtype(lhs), CandleDType::F32);
+
+        let rhs = candle_tensor_new(
+            data.as_ptr() as *const c_void,
+            CandleDType::F32,
+            [3, 2].as_ptr(),
+            2,
+            device,
+        );
+        let prod = candle_tensor_matmul(lhs, rhs);
+        let mut out = [0f32; 4];
+        assert_eq!(
+            candle_tensor_copy_to(prod, out.as_mut_ptr() as *mut c_void, 16),
+            0
+        );
+        assert_eq!(out, [22., 28., 49., 64.]);
+        // Too small an output buffer.
+        assert_eq!(
+            candle_tensor_copy_to(prod, out.as_mut_ptr() as *mut c_void, 8),
+            -1
+        );
+        assert!(last_error().contains("output buffer too small"));
+
+        let prod_u8 = candle_tensor_to_dtype(prod, CandleDType::U8);
+        let mut out = [0u8; 4];
+        assert_eq!(
+            candle_tensor_copy_to(prod_u8, out.as_mut_ptr() as *mut c_void, 4),
+            0
+        );
+        assert_eq!(out, [22, 28, 49, 64]);
+
+        // Incompatible shapes.
+        assert!(candle_tensor_matmul(lhs, lhs).is_null());
+        assert!(last_error().contains("shape mismatch"));
+
+        let zeros = candle_tensor_zeros(CandleDType::F32, [3].as_ptr(), 1, device);
+        let sum = candle_tensor_add(lhs, zeros);
+        let mut out = [0f32; 6];
+        assert_eq!(
+            candle_tensor_copy_to(sum, out.as_mut_ptr() as *mut c_void, 24),
+            0
+        );
+        assert_eq!(out, data);
+
+        for t in [lhs, rhs, prod, prod_u8, zeros, sum] {
+            candle_tensor_free(t)
+        }
+        candle_device_free(device);
+    }
+    Ok(())
+}
+
+// Writes a small randomly initialized llama model to a gguf file.
+fn write_tiny_llama(path: &std::path::Path) -> Result<()> {
+    let dev = Device::Cpu;
+    let (vocab, dim, hidden, heads) = (32, 64, 128, 4);
+    let mut tensors = vec![];
+    let mut add = |name: String, shape: &[usize]| -> Result<()> {
+        let t = Tensor::randn(0f32, 0.5, shape, &dev)?;
+        let qt = if shape.len() == 1 {
+            QTensor::quantize(&(t.abs()? + 1.)?, GgmlDType::F32)?
+        } else {
+            QTensor::quantize(&t, GgmlDType::Q8_0)?
+        };
+        tensors.push((name, qt));
+        Ok(())
+    };
+    add("token_embd.weight".to_string(), &[vocab, dim])?;
+    add("output_norm.weight".to_string(), &[dim])?;
+    add("output.weight".to_string(), &[vocab, dim])?;
+    let prefix = "blk.0";
+    for name in ["attn_q", "attn_k", "attn_v", "attn_output"] {
+        add(format!("{prefix}.{name}.weight"), &[dim, dim])?;
+    }
+    add(format!("{prefix}.ffn_gate.weight"), &[hidden, dim])?;
+    add(format!("{prefix}.ffn_up.weight"), &[hidden, dim])?;
+    add(format!("{prefix}.ffn_down.weight"), &[dim, hidden])?;
+    add(format!("{prefix}.attn_norm.weight"), &[dim])?;
+    add(format!("{prefix}.ffn_norm.weight"), &[dim])?;
+    use gguf_file::Value;
+    let metadata = [
+        ("llama.attention.head_count", Value::U32(heads as u32)),
+        ("llama.attention.head_count_kv", Value::U32(heads as u32)),
+        ("llama.block_count", Value::U32(1)),
+        ("llama.embedding_length", Value::U32(dim as u32)),
+        (
+            "llama.rope.dimension_count",
+            Value::U32((dim / heads) as u32),
+        ),
+        ("llama.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+    ];
+    let metadata = metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    let tensors = tensors
+        .iter()
+        .map(|(k, v)| (k.as_str(), v))
+        .collect::<Vec<_>>();
+    let mut file = std::fs::File::create(path)?;
+    gguf_file::write(&mut file, &metadata, &tensors)?;
+    Ok(())
+}
+
+struct Collected {
+    tokens: Vec<u32>,
+    stop_after: usize,
+}
+
+unsafe extern "C" fn collect(token: u32, text: *const c_char, user_data: *mut c_void) -> i32 {
+    assert!(text.is_null());
+    let collected = &mut *(user_data as *mut Collected);
+    collected.tokens.push(token);
+    (collected.tokens.len() >= collected.stop_after) as i32
+}
+
+#[test]
+fn generation() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("candle-c-tiny-{}.gguf", std::process::id()));
+    write_tiny_llama(&path)?;
+    let model_path = CString::new(path.to_string_lossy().as_bytes())?;
+    unsafe {
+        let device = candle_device_cpu();
+        let missing = CString::new("does-not-exist.gguf")?;
+        assert!(candle_model_load_gguf(missing.as_ptr(), std::ptr::null(), device).is_null());
+        assert!(!last_error().is_empty());
+
+        let model = candle_model_load_gguf(model_path.as_ptr(), std::ptr::null(), device);
+        assert!(!model.is_null(), "{}", last_error());
+        assert_eq!(candle_model_eos_token(model), -1);
+        let text = CString::new("hello")?;
+        assert_eq!(
+            candle_model_tokenize(model, text.as_ptr(), std::ptr::null_mut(), 0),
+            -1
+        );
+
+        let data = std::fs::read(&path)?;
+        let from_memory = candle_model_load_gguf_from_memory(
+            data.as_ptr(),
+            data.len(),
+            std::ptr::null(),
+            0,
+            device,
+        );
+        assert!(!from_memory.is_null(), "{}", last_error());
+
+        let prompt = [1u32, 5, 7];
+        let mut params = candle_generation_params_default();
+        params.max_tokens = 8;
+        params.seed = 42;
+        let mut runs = vec![];
+        for _ in 0..2 {
+            let mut collected = Collected {
+                tokens: vec![],
+                stop_after: usize::MAX,
+            };
+            let n = candle_model_generate(
+                model,
+                prompt.as_ptr(),
+                prompt.len(),
+                &params,
+                Some(collect),
+                &mut collected as *mut Collected as *mut c_void,
+            );
+            assert_eq!(n, 8);
+            runs.push(collected.tokens)
+        }
+        // The kv cache is reset between generations so the same seed gives the same tokens.
+        assert_eq!(runs[0], runs[1]);
+        let mut collected = Collected {
+            tokens: vec![],
+            stop_after: usize::MAX,
+        };
+        candle_model_generate(
+            from_memory,
+            prompt.as_ptr(),
+            prompt.len(),
+            &params,
+            Some(collect),
+            &mut collected as *mut Collected as *mut c_void,
+        );
+        assert_eq!(collected.tokens, runs[0]);
+        candle_model_free(from_memory);
+        assert!(runs[0].iter().all(|&t| t < 32));
+
+        let mut collected = Collected {
+            tokens: vec![],
+            stop_after: 3,
+        };
+        let n = candle_model_generate(
+            model,
+            prompt.as_ptr(),
+            prompt.len(),
+            &params,
+            Some(collect),
+            &mut collected as *mut Collected as *mut c_void,
+        );
+        assert_eq!(n, 3);
+        assert_eq!(collected.tokens.len(), 3);
+        assert_eq!(collected.tokens, runs[0][..3]);
+
+        candle_model_clear_kv_cache(model);
+        assert_eq!(candle_device_release_memory(device), 0);
+
+        let n = candle_model_generate(
+            model,
+            prompt.as_ptr(),
+            0,
+            &params,
+            None,
+            std::ptr::null_mut(),
+        );
+        assert_eq!(n, -1);
+        assert!(last_error().contains("at least one token"));
+
+        candle_model_free(model);
+        candle_device_free(device);
+    }
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
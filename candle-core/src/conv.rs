@@ -65,7 +65,7 @@ pub enum CudnnFwdAlgo {
     Count,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct ParamsConv2D {
     pub(crate) b_size: usize,
     pub(crate) i_h: usize,
@@ -5,6 +5,7 @@ use half::{bf16, f16};
 use rayon::prelude::*;
 
 mod utils;
+mod winograd;
 pub use utils::{
     binary_map, binary_map_vec, unary_map, unary_map_vec, Map1, Map1Any, Map2, Map2U8,
 };
@@ -12,6 +13,7 @@ pub use utils::{
 const USE_IM2COL_CONV1D: bool = true;
 const USE_COL2IM_CONV1D_TR: bool = true;
 const USE_IM2COL_CONV2D: bool = true;
+const USE_WINOGRAD_CONV2D: bool = true;
 
 // TODO: Maybe we should not implement [Clone] here and instead have an explicit allocator +
 // intercept the oom errors to avoid panicking and provide a proper error.
@@ -1207,6 +1209,7 @@ impl<'a> Map2 for ConvTranspose2D<'a> {
 struct MatMul((usize, usize, usize, usize));
 
 impl MatMul {
+    #[cfg(any(feature = "mkl", feature = "accelerate"))]
     fn striding_error(&self, lhs_l: &Layout, rhs_l: &Layout, msg: &'static str) -> Error {
         Error::MatMulUnexpectedStriding(Box::new(crate::error::MatMulUnexpectedStriding {
             lhs_l: lhs_l.clone(),
@@ -1217,6 +1220,7 @@ impl MatMul {
         .bt()
     }
 
+    #[cfg(any(feature = "mkl", feature = "accelerate"))]
     fn ab_skip(&self, lhs_l: &Layout, rhs_l: &Layout) -> Result<(usize, usize)> {
         let lhs_stride = lhs_l.stride();
         let rhs_stride = rhs_l.stride();
@@ -1240,6 +1244,16 @@ impl MatMul {
         };
         Ok((a_skip, b_skip))
     }
+
+    /// The offsets of the matrixes of each batch. Unlike `ab_skip`, the batch dimensions can use
+    /// arbitrary strides, e.g. the heads of an attention tensor transposed from
+    /// `(b, seq_len, heads, head_dim)` do not have to be made contiguous.
+    #[cfg(all(not(feature = "mkl"), not(feature = "accelerate")))]
+    fn batch_offsets(l: &Layout) -> Vec<usize> {
+        let rank = l.dims().len();
+        let (dims, stride) = (&l.dims()[..rank - 2], &l.stride()[..rank - 2]);
+        crate::StridedIndex::new(dims, stride, l.start_offset()).collect()
+    }
 }
 
 impl Map2 for MatMul {
@@ -1261,9 +1275,9 @@ impl Map2 for MatMul {
         }
 
         let (b, m, n, k) = self.0;
-        let lhs = &lhs[lhs_l.start_offset()..];
-        let rhs = &rhs[rhs_l.start_offset()..];
 
+        // The gemm kernel supports arbitrary row and column strides so the transposed or narrowed
+        // matrixes are used without copies.
         let lhs_stride = lhs_l.stride();
         let rhs_stride = rhs_l.stride();
         let rank = lhs_stride.len();
@@ -1273,7 +1287,8 @@ impl Map2 for MatMul {
         let rhs_cs = rhs_stride[rank - 1];
         let rhs_rs = rhs_stride[rank - 2];
 
-        let (a_skip, b_skip) = self.ab_skip(lhs_l, rhs_l)?;
+        let lhs_offsets = Self::batch_offsets(lhs_l);
+        let rhs_offsets = Self::batch_offsets(rhs_l);
         let c_skip: usize = m * n;
 
         let dst_shape: Shape = (m, n).into();
@@ -1288,9 +1303,9 @@ impl Map2 for MatMul {
         } else {
             Parallelism::None
         };
-        for step in 0..b {
-            let lhs_p = &lhs[step * a_skip..];
-            let rhs_p = &rhs[step * b_skip..];
+        for (step, (&lhs_o, &rhs_o)) in lhs_offsets.iter().zip(rhs_offsets.iter()).enumerate() {
+            let lhs_p = &lhs[lhs_o..];
+            let rhs_p = &rhs[rhs_o..];
             let dst_p = &mut dst[step * c_skip..];
             unsafe {
                 gemm(
@@ -2228,10 +2243,10 @@ impl BackendStorage for CpuStorage {
                     .alloc_uninit(kernel_l.shape(), kernel.dtype())?
             };
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, l_out, params.c_out)).transpose(1, 2)?;
         let mut res_t = unsafe { self.device().alloc_uninit(res_l.shape(), res.dtype())? };
@@ -2301,6 +2316,24 @@ impl BackendStorage for CpuStorage {
         kernel_l: &Layout,
         params: &crate::conv::ParamsConv2D,
     ) -> Result<Self> {
+        if USE_WINOGRAD_CONV2D && winograd::is_supported(params, self.dtype()) {
+            return winograd::Conv2DWinograd(params).map(self, l, kernel, kernel_l);
+        }
+        if params.k_h == 1 && params.k_w == 1 && params.stride == 1 && params.padding == 0 {
+            // 1x1 convolutions are a matmul between the kernel and the input, no im2col needed.
+            let (b, c_in, c_out) = (params.b_size, params.c_in, params.c_out);
+            let m = params.i_h * params.i_w;
+            let (s0, s1, s2, s3) = crate::shape::dims4(l.stride())?;
+            if s3 == 1 && s2 == params.i_w && s1 == m && kernel_l.is_contiguous() {
+                let inp_l = Layout::new((b, c_in, m).into(), vec![s0, m, 1], l.start_offset());
+                let kernel_l = Layout::new(
+                    (b, c_out, c_in).into(),
+                    vec![0, c_in, 1],
+                    kernel_l.start_offset(),
+                );
+                return kernel.matmul(self, (b, c_out, m, c_in), &kernel_l, &inp_l);
+            }
+        }
         if !USE_IM2COL_CONV2D {
             return Conv2D(params).map(self, l, kernel, kernel_l);
         }
@@ -2330,10 +2363,10 @@ impl BackendStorage for CpuStorage {
                     .alloc_uninit(kernel_l.shape(), kernel.dtype())?
             };
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, h_out, w_out, params.c_out))
             .transpose(1, 2)?
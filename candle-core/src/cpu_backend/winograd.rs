@@ -0,0 +1,283 @@
+//! Winograd convolutions for 3x3 kernels, "Fast Algorithms for Convolutional Neural Networks",
+//! <https://arxiv.org/abs/1509.09308>.
+//!
+//! The output is computed by tiles of `m x m` values from input tiles of `(m + 2) x (m + 2)`
+//! values. The input tiles and the kernels are first transformed, the products for all the
+//! positions of the transformed tiles are then computed as a batched matmul over the channels,
+//! and the results are finally transformed back to output tiles. This uses `(m + 2)^2` products
+//! per tile rather than `9 m^2` for a direct convolution, and much less memory than im2col.
+use super::{Map2, MatMul};
+use crate::conv::ParamsConv2D;
+use crate::{DType, Layout, Result, WithDType};
+use rayon::prelude::*;
+
+/// The transformation matrices for `F(m x m, 3 x 3)` with tiles of size `alpha = m + 2`, stored
+/// in row major order: `bt` is `alpha x alpha`, `g` is `alpha x 3`, and `at` is `m x alpha`.
+struct Transform {
+    bt: &'static [f64],
+    g: &'static [f64],
+    at: &'static [f64],
+}
+
+const F2X2_3X3: Transform = Transform {
+    #[rustfmt::skip]
+    bt: &[
+        1., 0., -1., 0.,
+        0., 1., 1., 0.,
+        0., -1., 1., 0.,
+        0., 1., 0., -1.,
+    ],
+    #[rustfmt::skip]
+    g: &[
+        1., 0., 0.,
+        0.5, 0.5, 0.5,
+        0.5, -0.5, 0.5,
+        0., 0., 1.,
+    ],
+    #[rustfmt::skip]
+    at: &[
+        1., 1., 1., 0.,
+        0., 1., -1., -1.,
+    ],
+};
+
+const F4X4_3X3: Transform = Transform {
+    #[rustfmt::skip]
+    bt: &[
+        4., 0., -5., 0., 1., 0.,
+        0., -4., -4., 1., 1., 0.,
+        0., 4., -4., -1., 1., 0.,
+        0., -2., -1., 2., 1., 0.,
+        0., 2., -1., -2., 1., 0.,
+        0., 4., 0., -5., 0., 1.,
+    ],
+    #[rustfmt::skip]
+    g: &[
+        1. / 4., 0., 0.,
+        -1. / 6., -1. / 6., -1. / 6.,
+        -1. / 6., 1. / 6., -1. / 6.,
+        1. / 24., 1. / 12., 1. / 6.,
+        1. / 24., -1. / 12., 1. / 6.,
+        0., 0., 1.,
+    ],
+    #[rustfmt::skip]
+    at: &[
+        1., 1., 1., 1., 1., 0.,
+        0., 1., -1., 2., -2., 0.,
+        0., 1., 1., 4., 4., 0.,
+        0., 1., -1., 8., -8., 1.,
+    ],
+};
+
+/// The output tile size, the larger tiles are more efficient but less accurate so they are only
+/// used when there are enough of them.
+fn tile_size(p: &ParamsConv2D) -> usize {
+    if p.out_h() >= 8 && p.out_w() >= 8 {
+        4
+    } else {
+        2
+    }
+}
+
+/// The minimal number of tiles for the winograd convolution to be used, the kernel transform
+/// has to be amortized over the tiles.
+const MIN_TILES: usize = 32;
+
+/// Returns true if the winograd convolution should be used for these parameters, i.e. for 3x3
+/// kernels with no stride nor dilation on f32 and f64 values, with enough tiles.
+pub(super) fn is_supported(p: &ParamsConv2D, dtype: DType) -> bool {
+    let m = tile_size(p);
+    matches!(dtype, DType::F32 | DType::F64)
+        && p.k_h == 3
+        && p.k_w == 3
+        && p.stride == 1
+        && p.dilation == 1
+        && p.b_size * p.out_h().div_ceil(m) * p.out_w().div_ceil(m) >= MIN_TILES
+}
+
+/// Computes `dst = lhs x rhs` for a `R x I` matrix `lhs` and a `I x C` matrix `rhs`, all in row
+/// major order.
+#[inline(always)]
+fn mm<T: WithDType, const R: usize, const I: usize, const C: usize>(
+    lhs: &[T],
+    rhs: &[T],
+    dst: &mut [T],
+) {
+    let (lhs, rhs, dst) = (&lhs[..R * I], &rhs[..I * C], &mut dst[..R * C]);
+    for r in 0..R {
+        for c in 0..C {
+            let mut acc = T::zero();
+            for i in 0..I {
+                acc += lhs[r * I + i] * rhs[i * C + c]
+            }
+            dst[r * C + c] = acc
+        }
+    }
+}
+
+/// Computes `dst = lhs x rhs^T` for a `R x I` matrix `lhs` and a `C x I` matrix `rhs`, all in row
+/// major order.
+#[inline(always)]
+fn mm_t<T: WithDType, const R: usize, const I: usize, const C: usize>(
+    lhs: &[T],
+    rhs: &[T],
+    dst: &mut [T],
+) {
+    let (lhs, rhs, dst) = (&lhs[..R * I], &rhs[..C * I], &mut dst[..R * C]);
+    for r in 0..R {
+        for c in 0..C {
+            let mut acc = T::zero();
+            for i in 0..I {
+                acc += lhs[r * I + i] * rhs[c * I + i]
+            }
+            dst[r * C + c] = acc
+        }
+    }
+}
+
+/// The size of the buffers used for the tiles, large enough for `F(4x4, 3x3)`.
+const MAX_A2: usize = 36;
+
+fn to_t<T: WithDType>(vs: &[f64]) -> Vec<T> {
+    vs.iter().map(|&v| T::from_f64(v)).collect()
+}
+
+/// The convolution with output tiles of size `M x M` and input tiles of size `A x A`, `A = M + 2`.
+fn conv2d<T: WithDType, const M: usize, const A: usize>(
+    p: &ParamsConv2D,
+    tr: &Transform,
+    inp: &[T],
+    inp_l: &Layout,
+    k: &[T],
+    k_l: &Layout,
+) -> Result<Vec<T>> {
+    let (out_h, out_w) = (p.out_h(), p.out_w());
+    let a2 = A * A;
+    let (bt, g, at) = (to_t::<T>(tr.bt), to_t::<T>(tr.g), to_t::<T>(tr.at));
+    let (tiles_h, tiles_w) = (out_h.div_ceil(M), out_w.div_ceil(M));
+    let tiles_per_img = tiles_h * tiles_w;
+    let n_tiles = p.b_size * tiles_per_img;
+
+    // Kernel transform, u has shape (alpha^2, c_out, c_in).
+    let k = &k[k_l.start_offset()..];
+    let (k_s0, k_s1, k_s2, k_s3) = crate::shape::dims4(k_l.stride())?;
+    let u_stride = p.c_out * p.c_in;
+    let u = vec![T::zero(); a2 * u_stride];
+    (0..p.c_out).into_par_iter().for_each(|c_out| {
+        let mut kernel = [T::zero(); 9];
+        let mut tmp = [T::zero(); MAX_A2];
+        // The transformed kernels for all the input channels, stored as (alpha^2, c_in) so that
+        // they can be copied to u by contiguous rows.
+        let mut res = vec![T::zero(); a2 * p.c_in];
+        let mut tile = [T::zero(); MAX_A2];
+        for c_in in 0..p.c_in {
+            for (i, v) in kernel.iter_mut().enumerate() {
+                *v = k[c_out * k_s0 + c_in * k_s1 + (i / 3) * k_s2 + (i % 3) * k_s3]
+            }
+            mm::<T, A, 3, 3>(&g, &kernel, &mut tmp);
+            mm_t::<T, A, 3, A>(&tmp, &g, &mut tile);
+            for (xi, &v) in tile[..a2].iter().enumerate() {
+                res[xi * p.c_in + c_in] = v
+            }
+        }
+        let u_p = u.as_ptr();
+        for (xi, res) in res.chunks_exact(p.c_in).enumerate() {
+            // Safety: the indexes are unique per c_out which is used to parallelise the tasks so
+            // no two threads can write at the same location.
+            unsafe {
+                let ptr = u_p.add(xi * u_stride + c_out * p.c_in) as *mut T;
+                std::ptr::copy_nonoverlapping(res.as_ptr(), ptr, p.c_in)
+            }
+        }
+    });
+
+    // Input transform, v has shape (alpha^2, c_in, n_tiles).
+    let inp = &inp[inp_l.start_offset()..];
+    let (i_s0, i_s1, i_s2, i_s3) = crate::shape::dims4(inp_l.stride())?;
+    let v_stride = p.c_in * n_tiles;
+    let v = vec![T::zero(); a2 * v_stride];
+    (0..p.c_in).into_par_iter().for_each(|c_in| {
+        let mut d = [T::zero(); MAX_A2];
+        let mut tmp = [T::zero(); MAX_A2];
+        let mut res = [T::zero(); MAX_A2];
+        for tile_idx in 0..n_tiles {
+            let b = tile_idx / tiles_per_img;
+            let ty = (tile_idx / tiles_w) % tiles_h;
+            let tx = tile_idx % tiles_w;
+            let inp = &inp[b * i_s0 + c_in * i_s1..];
+            for i in 0..A {
+                let y = (ty * M + i) as isize - p.padding as isize;
+                let y_inside = y >= 0 && (y as usize) < p.i_h;
+                for j in 0..A {
+                    let x = (tx * M + j) as isize - p.padding as isize;
+                    d[i * A + j] = if y_inside && x >= 0 && (x as usize) < p.i_w {
+                        inp[y as usize * i_s2 + x as usize * i_s3]
+                    } else {
+                        T::zero()
+                    }
+                }
+            }
+            mm::<T, A, A, A>(&bt, &d, &mut tmp);
+            mm_t::<T, A, A, A>(&tmp, &bt, &mut res);
+            let v_p = v.as_ptr();
+            for (xi, &r) in res[..a2].iter().enumerate() {
+                // Safety: the indexes are unique per c_in which is used to parallelise the tasks
+                // so no two threads can write at the same location.
+                unsafe {
+                    let ptr = v_p.add(xi * v_stride + c_in * n_tiles + tile_idx) as *mut T;
+                    *ptr = r
+                }
+            }
+        }
+    });
+
+    // Batched matmul over the transformed positions, mm has shape (alpha^2, c_out, n_tiles).
+    let mm_out = MatMul((a2, p.c_out, n_tiles, p.c_in)).f(
+        &u,
+        &Layout::contiguous((a2, p.c_out, p.c_in)),
+        &v,
+        &Layout::contiguous((a2, p.c_in, n_tiles)),
+    )?;
+    drop(v);
+
+    // Output transform, dst has shape (b_size, c_out, out_h, out_w).
+    let mut dst = vec![T::zero(); p.b_size * p.c_out * out_h * out_w];
+    dst.par_chunks_exact_mut(out_h * out_w)
+        .enumerate()
+        .for_each(|(idx, dst)| {
+            let (b, c_out) = (idx / p.c_out, idx % p.c_out);
+            let mut mv = [T::zero(); MAX_A2];
+            let mut tmp = [T::zero(); MAX_A2];
+            let mut y = [T::zero(); MAX_A2];
+            for tile_idx in 0..tiles_per_img {
+                let (ty, tx) = (tile_idx / tiles_w, tile_idx % tiles_w);
+                let col = b * tiles_per_img + tile_idx;
+                for (xi, mv) in mv[..a2].iter_mut().enumerate() {
+                    *mv = mm_out[(xi * p.c_out + c_out) * n_tiles + col]
+                }
+                mm::<T, M, A, A>(&at, &mv, &mut tmp);
+                mm_t::<T, M, A, M>(&tmp, &at, &mut y);
+                for i in 0..M.min(out_h - ty * M) {
+                    for j in 0..M.min(out_w - tx * M) {
+                        dst[(ty * M + i) * out_w + tx * M + j] = y[i * M + j]
+                    }
+                }
+            }
+        });
+    Ok(dst)
+}
+
+pub(super) struct Conv2DWinograd<'a>(pub(super) &'a ParamsConv2D);
+
+impl Map2 for Conv2DWinograd<'_> {
+    const OP: &'static str = "conv2d-winograd";
+
+    fn f<T: WithDType>(&self, inp: &[T], inp_l: &Layout, k: &[T], k_l: &Layout) -> Result<Vec<T>> {
+        let p = self.0;
+        if tile_size(p) == 4 {
+            conv2d::<T, 4, 6>(p, &F4X4_3X3, inp, inp_l, k, k_l)
+        } else {
+            conv2d::<T, 2, 4>(p, &F2X2_3X3, inp, inp_l, k, k_l)
+        }
+    }
+}
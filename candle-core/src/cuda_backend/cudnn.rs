@@ -1,10 +1,11 @@
 use crate::WithDType;
 use cudarc;
 use cudarc::cudnn::safe::{ConvForward, Cudnn};
-use cudarc::driver::{CudaSlice, CudaView, DeviceRepr, ValidAsZeroBits};
+use cudarc::cudnn::sys::cudnnConvolutionFwdAlgo_t as A;
+use cudarc::driver::{CudaSlice, CudaView, DeviceRepr, DeviceSlice, ValidAsZeroBits};
 use std::cell::RefCell;
 use std::collections::HashMap;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 
 // The cudnn handles are stored per thread here rather than on the CudaDevice as they are neither
 // send nor sync.
@@ -12,6 +13,68 @@ thread_local! {
     static CUDNN: RefCell<HashMap<crate::cuda_backend::DeviceId, Arc<Cudnn>>> = HashMap::new().into();
 }
 
+// The workspaces are reused between the convolutions and only grow when a larger one is required,
+// this is safe as all the kernels of a device are run on the same stream.
+thread_local! {
+    static WORKSPACES: RefCell<HashMap<crate::cuda_backend::DeviceId, CudaSlice<u8>>> = HashMap::new().into();
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct AlgoKey {
+    device_id: crate::cuda_backend::DeviceId,
+    dtype: crate::DType,
+    params: crate::conv::ParamsConv2D,
+    src_stride: Vec<usize>,
+    benchmark: bool,
+}
+
+// The algorithms selected for each convolution shape, these are shared between threads.
+static ALGOS: Mutex<Option<HashMap<AlgoKey, A>>> = Mutex::new(None);
+
+const FWD_ALGOS: [A; 8] = [
+    A::CUDNN_CONVOLUTION_FWD_ALGO_IMPLICIT_GEMM,
+    A::CUDNN_CONVOLUTION_FWD_ALGO_IMPLICIT_PRECOMP_GEMM,
+    A::CUDNN_CONVOLUTION_FWD_ALGO_GEMM,
+    A::CUDNN_CONVOLUTION_FWD_ALGO_DIRECT,
+    A::CUDNN_CONVOLUTION_FWD_ALGO_FFT,
+    A::CUDNN_CONVOLUTION_FWD_ALGO_FFT_TILING,
+    A::CUDNN_CONVOLUTION_FWD_ALGO_WINOGRAD,
+    A::CUDNN_CONVOLUTION_FWD_ALGO_WINOGRAD_NONFUSED,
+];
+
+/// Removes the cached convolution algorithms and releases the workspaces of the current thread.
+pub fn clear_cache() {
+    if let Ok(mut algos) = ALGOS.lock() {
+        *algos = None
+    }
+    WORKSPACES.with(|ws| ws.borrow_mut().clear())
+}
+
+/// Calls `f` with a workspace of at least `size` bytes for this device, or with `None` if no
+/// workspace is required.
+fn with_workspace<R>(
+    dev: &crate::cuda_backend::CudaDevice,
+    size: usize,
+    f: impl FnOnce(Option<&mut CudaSlice<u8>>) -> crate::Result<R>,
+) -> crate::Result<R> {
+    if size == 0 {
+        return f(None);
+    }
+    WORKSPACES.with(|ws| {
+        let mut ws = ws.borrow_mut();
+        let workspace = match ws.remove(&dev.id()) {
+            Some(workspace) if workspace.len() >= size => workspace,
+            previous => {
+                // Release the previous workspace before allocating the new one.
+                drop(previous);
+                dev.cuda_device().alloc_zeros::<u8>(size)?
+            }
+        };
+        let workspace = ws.entry(dev.id()).or_insert(workspace);
+        f(Some(workspace))
+    })
+}
+
 impl From<cudarc::cudnn::CudnnError> for crate::Error {
     fn from(err: cudarc::cudnn::CudnnError) -> Self {
         crate::Error::wrap(err)
@@ -36,7 +99,6 @@ pub(crate) fn launch_conv2d<
     dev: &crate::cuda_backend::CudaDevice,
 ) -> crate::Result<()> {
     use crate::conv::CudnnFwdAlgo as CandleAlgo;
-    use cudarc::cudnn::sys::cudnnConvolutionFwdAlgo_t as A;
 
     let device_id = dev.id();
     let cudnn = CUDNN.with(|cudnn| {
@@ -95,7 +157,37 @@ pub(crate) fn launch_conv2d<
         y: &y,
     };
     let alg = match params.cudnn_fwd_algo {
-        None => conv2d.pick_algorithm()?,
+        None => {
+            let benchmark = crate::cuda_backend::cudnn_benchmark();
+            let key = AlgoKey {
+                device_id,
+                dtype: T::DTYPE,
+                params: params.clone(),
+                src_stride: src_l.stride().to_vec(),
+                benchmark,
+            };
+            let cached = ALGOS
+                .lock()
+                .map_err(|e| crate::Error::Msg(format!("cudnn algo cache lock: {e}")))?
+                .as_ref()
+                .and_then(|algos| algos.get(&key).copied());
+            match cached {
+                Some(alg) => alg,
+                None => {
+                    let alg = if benchmark {
+                        benchmark_algorithms(&conv2d, src, filter, dst, dev)?
+                    } else {
+                        conv2d.pick_algorithm()?
+                    };
+                    ALGOS
+                        .lock()
+                        .map_err(|e| crate::Error::Msg(format!("cudnn algo cache lock: {e}")))?
+                        .get_or_insert_with(HashMap::new)
+                        .insert(key, alg);
+                    alg
+                }
+            }
+        }
         Some(CandleAlgo::ImplicitGemm) => A::CUDNN_CONVOLUTION_FWD_ALGO_IMPLICIT_GEMM,
         Some(CandleAlgo::ImplicitPrecompGemm) => {
             A::CUDNN_CONVOLUTION_FWD_ALGO_IMPLICIT_PRECOMP_GEMM
@@ -109,16 +201,71 @@ pub(crate) fn launch_conv2d<
         Some(CandleAlgo::Count) => A::CUDNN_CONVOLUTION_FWD_ALGO_COUNT,
     };
     let workspace_size = conv2d.get_workspace_size(alg)?;
-    let mut workspace = dev.cuda_device().alloc_zeros::<u8>(workspace_size)?;
-    unsafe {
-        conv2d.launch::<CudaSlice<u8>, _, _, _>(
-            alg,
-            Some(&mut workspace),
-            (T::one(), T::zero()),
-            src,
-            filter,
-            dst,
-        )?;
+    with_workspace(dev, workspace_size, |workspace| {
+        unsafe {
+            conv2d.launch::<CudaSlice<u8>, _, _, _>(
+                alg,
+                workspace,
+                (T::one(), T::zero()),
+                src,
+                filter,
+                dst,
+            )?;
+        }
+        Ok(())
+    })
+}
+
+/// Runs all the algorithms supported for this convolution and returns the fastest one. The
+/// algorithms requiring a workspace larger than the limit are skipped.
+fn benchmark_algorithms<T, Y>(
+    conv2d: &ConvForward<T, Y, T>,
+    src: &CudaView<T>,
+    filter: &CudaView<T>,
+    dst: &mut CudaSlice<T>,
+    dev: &crate::cuda_backend::CudaDevice,
+) -> crate::Result<A>
+where
+    T: DeviceRepr + WithDType + ValidAsZeroBits + cudarc::cudnn::CudnnDataType,
+    Y: cudarc::cudnn::CudnnDataType,
+{
+    let cuda_device = dev.cuda_device();
+    let workspace_limit = crate::cuda_backend::cudnn_workspace_limit();
+    let mut best: Option<(A, std::time::Duration)> = None;
+    for alg in FWD_ALGOS {
+        let workspace_size = match conv2d.get_workspace_size(alg) {
+            Ok(size) if size <= workspace_limit => size,
+            _ => continue,
+        };
+        let mut run = |workspace: Option<&mut CudaSlice<u8>>| -> crate::Result<_> {
+            let start = std::time::Instant::now();
+            unsafe {
+                conv2d.launch::<CudaSlice<u8>, _, _, _>(
+                    alg,
+                    workspace,
+                    (T::one(), T::zero()),
+                    src,
+                    filter,
+                    dst,
+                )?;
+            }
+            cuda_device.synchronize()?;
+            Ok(start.elapsed())
+        };
+        // The first run is a warmup, the second one is timed. Algorithms that are not supported
+        // for this configuration fail and are skipped.
+        let elapsed = with_workspace(dev, workspace_size, |mut workspace| {
+            run(workspace.as_deref_mut())?;
+            run(workspace)
+        });
+        if let Ok(elapsed) = elapsed {
+            if best.is_none_or(|(_, best)| elapsed < best) {
+                best = Some((alg, elapsed))
+            }
+        }
+    }
+    match best {
+        Some((alg, _)) => Ok(alg),
+        None => Ok(conv2d.pick_algorithm()?),
     }
-    Ok(())
 }
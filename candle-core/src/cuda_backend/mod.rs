@@ -1059,12 +1059,16 @@ fn gemm_config<T>(
     let lhs_m1 = lhs_stride[lhs_stride.len() - 1];
     let lhs_m2 = lhs_stride[lhs_stride.len() - 2];
     // The a tensor has dims batching, k, n (rhs)
+    // The leading dimension can be larger than the matrix, so that narrowed matrixes or the heads
+    // of a transposed attention tensor are used without copies.
     // We also allow for the case where the stride on the minor dimension is not as expected but
     // there is a single element.
-    let (lda, transa) = if (rhs_m1 == 1 || n == 1) && (rhs_m2 == n || k == 1) {
-        (n as i32, cublasOperation_t::CUBLAS_OP_N)
-    } else if (rhs_m1 == k || n == 1) && (rhs_m2 == 1 || k == 1) {
-        (k as i32, cublasOperation_t::CUBLAS_OP_T)
+    let (lda, transa) = if (rhs_m1 == 1 || n == 1) && (rhs_m2 >= n || k == 1) {
+        let lda = if k == 1 { n } else { rhs_m2 };
+        (lda as i32, cublasOperation_t::CUBLAS_OP_N)
+    } else if (rhs_m2 == 1 || k == 1) && (rhs_m1 >= k || n == 1) {
+        let lda = if n == 1 { k } else { rhs_m1 };
+        (lda as i32, cublasOperation_t::CUBLAS_OP_T)
     } else {
         Err(CudaError::MatMulNonContiguous {
             lhs_stride: lhs_l.clone(),
@@ -1075,10 +1079,12 @@ fn gemm_config<T>(
     // The b tensor has dims batching, m, k (lhs)
     // We also allow for the case where the stride on the minor dimension is not as expected but
     // there is a single element.
-    let (ldb, transb) = if (lhs_m1 == 1 || k == 1) && (lhs_m2 == k || m == 1) {
-        (k as i32, cublasOperation_t::CUBLAS_OP_N)
-    } else if (lhs_m1 == m || k == 1) && (lhs_m2 == 1 || m == 1) {
-        (m as i32, cublasOperation_t::CUBLAS_OP_T)
+    let (ldb, transb) = if (lhs_m1 == 1 || k == 1) && (lhs_m2 >= k || m == 1) {
+        let ldb = if m == 1 { k } else { lhs_m2 };
+        (ldb as i32, cublasOperation_t::CUBLAS_OP_N)
+    } else if (lhs_m2 == 1 || m == 1) && (lhs_m1 >= m || k == 1) {
+        let ldb = if k == 1 { m } else { lhs_m1 };
+        (ldb as i32, cublasOperation_t::CUBLAS_OP_T)
     } else {
         Err(CudaError::MatMulNonContiguous {
             lhs_stride: lhs_l.clone(),
@@ -1101,24 +1107,9 @@ fn gemm_config<T>(
         transb,
     };
 
-    let stride_b: usize = match lhs_stride[..lhs_stride.len() - 2] {
-        [s1, stride] if s1 == stride * lhs_l.dims()[1] => stride,
-        [_, stride] if lhs_l.dims()[0] == 1 => stride,
-        [stride, _] if lhs_l.dims()[1] == 1 => stride,
-        [stride] => stride,
-        [] => m * k,
-        _ => Err(CudaError::MatMulNonContiguous {
-            lhs_stride: lhs_l.clone(),
-            rhs_stride: rhs_l.clone(),
-            mnk: (m, n, k),
-        })?,
-    };
-    let stride_a: usize = match rhs_stride[..rhs_stride.len() - 2] {
-        [s1, stride] if s1 == stride * rhs_l.dims()[1] => stride,
-        [_, stride] if rhs_l.dims()[0] == 1 => stride,
-        [stride, _] if rhs_l.dims()[1] == 1 => stride,
-        [stride] => stride,
-        [] => n * k,
+    // The broadcasted batch dimensions use a stride of 0.
+    let (stride_b, stride_a) = match (lhs_l.matmul_batch_stride(), rhs_l.matmul_batch_stride()) {
+        (Some(stride_b), Some(stride_a)) => (stride_b, stride_a),
         _ => Err(CudaError::MatMulNonContiguous {
             lhs_stride: lhs_l.clone(),
             rhs_stride: rhs_l.clone(),
@@ -1375,10 +1366,10 @@ impl BackendStorage for CudaStorage {
                     .alloc_uninit(kernel_l.shape(), kernel.dtype())?
             };
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, l_out, n)).transpose(1, 2)?;
         let mut res_t = unsafe { self.device().alloc_uninit(res_l.shape(), res.dtype())? };
@@ -1489,10 +1480,10 @@ impl BackendStorage for CudaStorage {
                     .alloc_uninit(kernel_l.shape(), kernel.dtype())?
             };
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, h_out, w_out, n))
             .transpose(1, 2)?
@@ -1928,6 +1919,34 @@ pub fn set_gemm_reduced_precision_bf16(b: bool) {
     MM_BF16_REDUCED_PRECISION.store(b, std::sync::atomic::Ordering::Relaxed)
 }
 
+static CUDNN_BENCHMARK: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+static CUDNN_WORKSPACE_LIMIT: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(usize::MAX);
+
+/// This bool controls whether the cudnn convolution algorithms are selected by benchmarking all
+/// the available ones the first time a convolution shape is encountered rather than by using the
+/// cudnn heuristics. The selected algorithms are cached in both cases.
+pub fn cudnn_benchmark() -> bool {
+    CUDNN_BENCHMARK.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// This bool controls whether the cudnn convolution algorithms are selected by benchmarking all
+/// the available ones the first time a convolution shape is encountered rather than by using the
+/// cudnn heuristics. The selected algorithms are cached in both cases.
+pub fn set_cudnn_benchmark(b: bool) {
+    CUDNN_BENCHMARK.store(b, std::sync::atomic::Ordering::Relaxed)
+}
+
+/// The maximum workspace size in bytes that a benchmarked cudnn convolution algorithm can use.
+pub fn cudnn_workspace_limit() -> usize {
+    CUDNN_WORKSPACE_LIMIT.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// The maximum workspace size in bytes that a benchmarked cudnn convolution algorithm can use.
+pub fn set_cudnn_workspace_limit(n: usize) {
+    CUDNN_WORKSPACE_LIMIT.store(n, std::sync::atomic::Ordering::Relaxed)
+}
+
 unsafe fn gemm_strided_batched_f32(
     cublas: &cudarc::cublas::CudaBlas,
     cfg: StridedBatchedConfig<f32>,
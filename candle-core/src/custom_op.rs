@@ -1,6 +1,9 @@
+use crate::backend::{BackendDevice, BackendStorage};
 use crate::op::{BackpropOp, Op};
 use crate::tensor::from_storage;
-use crate::{CpuStorage, CudaStorage, Layout, MetalStorage, Result, Shape, Tensor};
+use crate::{
+    CpuStorage, CudaStorage, Layout, MetaStorage, MetalStorage, Result, Shape, Tensor, WgpuStorage,
+};
 use std::sync::Arc;
 
 /// Unary ops that can be defined in user-land.
@@ -32,6 +35,19 @@ pub trait CustomOp1 {
         ))
     }
 
+    /// The forward pass, as run on a wgpu device. By default the storages are copied to the cpu
+    /// to run `cpu_fwd` and the result is copied back to the device.
+    fn wgpu_fwd(&self, storage: &WgpuStorage, layout: &Layout) -> Result<(WgpuStorage, Shape)> {
+        let (s, shape) = self.cpu_fwd(&storage.to_cpu_storage()?, layout)?;
+        Ok((storage.device().storage_from_cpu_storage_owned(s)?, shape))
+    }
+
+    /// The forward pass on the meta device, this should only return the dtype of the result
+    /// and its shape without doing any computation.
+    fn meta_fwd(&self, _storage: &MetaStorage, _layout: &Layout) -> Result<(MetaStorage, Shape)> {
+        Err(crate::Error::NotSupportedOnMetaDevice { op: self.name() }.bt())
+    }
+
     /// This function takes as argument the argument `arg` used in the forward pass, the result
     /// produced by the forward operation `res` and the gradient of the result `grad_res`.
     /// The function should return the gradient of the argument.
@@ -81,6 +97,31 @@ pub trait CustomOp2 {
         ))
     }
 
+    /// The forward pass, as run on a wgpu device. By default the storages are copied to the cpu
+    /// to run `cpu_fwd` and the result is copied back to the device.
+    fn wgpu_fwd(
+        &self,
+        s1: &WgpuStorage,
+        l1: &Layout,
+        s2: &WgpuStorage,
+        l2: &Layout,
+    ) -> Result<(WgpuStorage, Shape)> {
+        let (s, shape) = self.cpu_fwd(&s1.to_cpu_storage()?, l1, &s2.to_cpu_storage()?, l2)?;
+        Ok((s1.device().storage_from_cpu_storage_owned(s)?, shape))
+    }
+
+    /// The forward pass on the meta device, this should only return the dtype of the result
+    /// and its shape without doing any computation.
+    fn meta_fwd(
+        &self,
+        _: &MetaStorage,
+        _: &Layout,
+        _: &MetaStorage,
+        _: &Layout,
+    ) -> Result<(MetaStorage, Shape)> {
+        Err(crate::Error::NotSupportedOnMetaDevice { op: self.name() }.bt())
+    }
+
     fn bwd(
         &self,
         _arg1: &Tensor,
@@ -139,6 +180,42 @@ pub trait CustomOp3 {
         ))
     }
 
+    /// The forward pass, as run on a wgpu device. By default the storages are copied to the cpu
+    /// to run `cpu_fwd` and the result is copied back to the device.
+    fn wgpu_fwd(
+        &self,
+        s1: &WgpuStorage,
+        l1: &Layout,
+        s2: &WgpuStorage,
+        l2: &Layout,
+        s3: &WgpuStorage,
+        l3: &Layout,
+    ) -> Result<(WgpuStorage, Shape)> {
+        let (s, shape) = self.cpu_fwd(
+            &s1.to_cpu_storage()?,
+            l1,
+            &s2.to_cpu_storage()?,
+            l2,
+            &s3.to_cpu_storage()?,
+            l3,
+        )?;
+        Ok((s1.device().storage_from_cpu_storage_owned(s)?, shape))
+    }
+
+    /// The forward pass on the meta device, this should only return the dtype of the result
+    /// and its shape without doing any computation.
+    fn meta_fwd(
+        &self,
+        _: &MetaStorage,
+        _: &Layout,
+        _: &MetaStorage,
+        _: &Layout,
+        _: &MetaStorage,
+        _: &Layout,
+    ) -> Result<(MetaStorage, Shape)> {
+        Err(crate::Error::NotSupportedOnMetaDevice { op: self.name() }.bt())
+    }
+
     fn bwd(
         &self,
         _arg1: &Tensor,
@@ -270,6 +347,15 @@ pub trait InplaceOp1 {
             format!("no metal implementation for {}", self.name()).into(),
         ))
     }
+
+    /// The forward pass, as run on a wgpu device. By default the storages are copied to the cpu
+    /// to run `cpu_fwd` and the result is copied back to the device.
+    fn wgpu_fwd(&self, storage: &mut WgpuStorage, layout: &Layout) -> Result<()> {
+        let mut s = storage.to_cpu_storage()?;
+        self.cpu_fwd(&mut s, layout)?;
+        *storage = storage.device().storage_from_cpu_storage_owned(s)?;
+        Ok(())
+    }
 }
 
 pub trait InplaceOp2 {
@@ -301,6 +387,21 @@ pub trait InplaceOp2 {
             format!("no metal implementation for {}", self.name()).into(),
         ))
     }
+
+    /// The forward pass, as run on a wgpu device. By default the storages are copied to the cpu
+    /// to run `cpu_fwd` and the result is copied back to the device.
+    fn wgpu_fwd(
+        &self,
+        s1: &mut WgpuStorage,
+        l1: &Layout,
+        s2: &WgpuStorage,
+        l2: &Layout,
+    ) -> Result<()> {
+        let mut s = s1.to_cpu_storage()?;
+        self.cpu_fwd(&mut s, l1, &s2.to_cpu_storage()?, l2)?;
+        *s1 = s1.device().storage_from_cpu_storage_owned(s)?;
+        Ok(())
+    }
 }
 
 pub trait InplaceOp3 {
@@ -349,6 +450,30 @@ pub trait InplaceOp3 {
             format!("no metal implementation for {}", self.name()).into(),
         ))
     }
+
+    /// The forward pass, as run on a wgpu device. By default the storages are copied to the cpu
+    /// to run `cpu_fwd` and the result is copied back to the device.
+    fn wgpu_fwd(
+        &self,
+        s1: &mut WgpuStorage,
+        l1: &Layout,
+        s2: &WgpuStorage,
+        l2: &Layout,
+        s3: &WgpuStorage,
+        l3: &Layout,
+    ) -> Result<()> {
+        let mut s = s1.to_cpu_storage()?;
+        self.cpu_fwd(
+            &mut s,
+            l1,
+            &s2.to_cpu_storage()?,
+            l2,
+            &s3.to_cpu_storage()?,
+            l3,
+        )?;
+        *s1 = s1.device().storage_from_cpu_storage_owned(s)?;
+        Ok(())
+    }
 }
 
 impl Tensor {
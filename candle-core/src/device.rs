@@ -1,5 +1,6 @@
 use crate::backend::BackendDevice;
 use crate::cpu_backend::CpuDevice;
+use crate::meta_backend::MetaDevice;
 use crate::{CpuStorage, DType, Result, Shape, Storage, WithDType};
 
 /// A `DeviceLocation` represents a physical device whereas multiple `Device`
@@ -9,6 +10,8 @@ pub enum DeviceLocation {
     Cpu,
     Cuda { gpu_id: usize },
     Metal { gpu_id: usize },
+    Wgpu { gpu_id: usize },
+    Meta,
 }
 
 #[derive(Debug, Clone)]
@@ -16,6 +19,10 @@ pub enum Device {
     Cpu,
     Cuda(crate::CudaDevice),
     Metal(crate::MetalDevice),
+    Wgpu(crate::WgpuDevice),
+    /// Tensors on the meta device only have a shape and a dtype, operations on them compute the
+    /// shape and dtype of their outputs without allocating memory nor doing any computation.
+    Meta,
 }
 
 pub trait NdArray {
@@ -138,19 +145,32 @@ impl Device {
         Ok(Self::Metal(crate::MetalDevice::new(ordinal)?))
     }
 
+    /// Creates a wgpu device using the `ordinal`-th adapter. This blocks until the device is
+    /// ready so it cannot be used in the browser, use [`Self::new_wgpu_async`] there.
+    pub fn new_wgpu(ordinal: usize) -> Result<Self> {
+        Ok(Self::Wgpu(crate::WgpuDevice::new(ordinal)?))
+    }
+
+    pub async fn new_wgpu_async(ordinal: usize) -> Result<Self> {
+        Ok(Self::Wgpu(crate::WgpuDevice::new_async(ordinal).await?))
+    }
+
     pub fn set_seed(&self, seed: u64) -> Result<()> {
         match self {
             Self::Cpu => CpuDevice.set_seed(seed),
             Self::Cuda(c) => c.set_seed(seed),
             Self::Metal(m) => m.set_seed(seed),
+            Self::Wgpu(w) => w.set_seed(seed),
+            Self::Meta => MetaDevice.set_seed(seed),
         }
     }
 
     pub fn same_device(&self, rhs: &Self) -> bool {
         match (self, rhs) {
-            (Self::Cpu, Self::Cpu) => true,
+            (Self::Cpu, Self::Cpu) | (Self::Meta, Self::Meta) => true,
             (Self::Cuda(lhs), Self::Cuda(rhs)) => lhs.same_device(rhs),
             (Self::Metal(lhs), Self::Metal(rhs)) => lhs.same_device(rhs),
+            (Self::Wgpu(lhs), Self::Wgpu(rhs)) => lhs.same_device(rhs),
             _ => false,
         }
     }
@@ -160,6 +180,8 @@ impl Device {
             Self::Cpu => DeviceLocation::Cpu,
             Self::Cuda(device) => device.location(),
             Device::Metal(device) => device.location(),
+            Device::Wgpu(device) => device.location(),
+            Device::Meta => DeviceLocation::Meta,
         }
     }
 
@@ -175,10 +197,18 @@ impl Device {
         matches!(self, Self::Metal(_))
     }
 
+    pub fn is_wgpu(&self) -> bool {
+        matches!(self, Self::Wgpu(_))
+    }
+
+    pub fn is_meta(&self) -> bool {
+        matches!(self, Self::Meta)
+    }
+
     pub fn supports_bf16(&self) -> bool {
         match self {
-            Self::Cuda(_) | Self::Metal(_) => true,
-            Self::Cpu => false,
+            Self::Cuda(_) | Self::Metal(_) | Self::Meta => true,
+            Self::Cpu | Self::Wgpu(_) => false,
         }
     }
 
@@ -199,6 +229,26 @@ impl Device {
         }
     }
 
+    /// Returns a wgpu device if one can be created, e.g. when the browser supports WebGPU, and
+    /// falls back to the cpu otherwise.
+    pub fn wgpu_if_available(ordinal: usize) -> Result<Self> {
+        if crate::utils::wgpu_is_available() {
+            Ok(Self::new_wgpu(ordinal).unwrap_or(Self::Cpu))
+        } else {
+            Ok(Self::Cpu)
+        }
+    }
+
+    /// Same as [`Self::wgpu_if_available`] without blocking, this is the version to use in the
+    /// browser.
+    pub async fn wgpu_if_available_async(ordinal: usize) -> Result<Self> {
+        if crate::utils::wgpu_is_available() {
+            Ok(Self::new_wgpu_async(ordinal).await.unwrap_or(Self::Cpu))
+        } else {
+            Ok(Self::Cpu)
+        }
+    }
+
     pub(crate) fn rand_uniform_f64(
         &self,
         lo: f64,
@@ -225,6 +275,14 @@ impl Device {
                 let storage = device.rand_uniform(shape, dtype, lo, up)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Wgpu(device) => {
+                let storage = device.rand_uniform(shape, dtype, lo, up)?;
+                Ok(Storage::Wgpu(storage))
+            }
+            Device::Meta => {
+                let storage = MetaDevice.rand_uniform(shape, dtype, lo, up)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -263,6 +321,14 @@ impl Device {
                 let storage = device.rand_normal(shape, dtype, mean, std)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Wgpu(device) => {
+                let storage = device.rand_normal(shape, dtype, mean, std)?;
+                Ok(Storage::Wgpu(storage))
+            }
+            Device::Meta => {
+                let storage = MetaDevice.rand_normal(shape, dtype, mean, std)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -289,6 +355,14 @@ impl Device {
                 let storage = device.ones_impl(shape, dtype)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Wgpu(device) => {
+                let storage = device.ones_impl(shape, dtype)?;
+                Ok(Storage::Wgpu(storage))
+            }
+            Device::Meta => {
+                let storage = MetaDevice.ones_impl(shape, dtype)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -306,6 +380,14 @@ impl Device {
                 let storage = device.zeros_impl(shape, dtype)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Wgpu(device) => {
+                let storage = device.zeros_impl(shape, dtype)?;
+                Ok(Storage::Wgpu(storage))
+            }
+            Device::Meta => {
+                let storage = MetaDevice.zeros_impl(shape, dtype)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -323,6 +405,14 @@ impl Device {
                 let storage = device.alloc_uninit(shape, dtype)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Wgpu(device) => {
+                let storage = device.alloc_uninit(shape, dtype)?;
+                Ok(Storage::Wgpu(storage))
+            }
+            Device::Meta => {
+                let storage = MetaDevice.alloc_uninit(shape, dtype)?;
+                Ok(Storage::Meta(storage))
+            }
         }
     }
 
@@ -337,6 +427,11 @@ impl Device {
                 let storage = device.storage_from_slice(data)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Wgpu(device) => {
+                let storage = device.storage_from_slice(data)?;
+                Ok(Storage::Wgpu(storage))
+            }
+            Device::Meta => Ok(Storage::Meta(MetaDevice.storage_from_slice(data)?)),
         }
     }
 
@@ -353,6 +448,17 @@ impl Device {
                 let storage = device.storage_from_cpu_storage_owned(storage)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Wgpu(device) => {
+                let storage = array.to_cpu_storage();
+                let storage = device.storage_from_cpu_storage_owned(storage)?;
+                Ok(Storage::Wgpu(storage))
+            }
+            Device::Meta => {
+                let storage = array.to_cpu_storage();
+                Ok(Storage::Meta(
+                    MetaDevice.storage_from_cpu_storage_owned(storage)?,
+                ))
+            }
         }
     }
 
@@ -369,6 +475,12 @@ impl Device {
                 let storage = device.storage_from_cpu_storage_owned(storage)?;
                 Ok(Storage::Metal(storage))
             }
+            Device::Wgpu(device) => {
+                let storage = S::to_cpu_storage_owned(data);
+                let storage = device.storage_from_cpu_storage_owned(storage)?;
+                Ok(Storage::Wgpu(storage))
+            }
+            Device::Meta => Ok(Storage::Meta(MetaDevice.storage_from_slice(&data)?)),
         }
     }
 
@@ -377,6 +489,8 @@ impl Device {
             Self::Cpu => Ok(()),
             Self::Cuda(d) => d.synchronize(),
             Self::Metal(d) => d.synchronize(),
+            Self::Wgpu(d) => d.synchronize(),
+            Self::Meta => Ok(()),
         }
     }
 }
@@ -17,16 +17,22 @@ impl Tensor {
             crate::DeviceLocation::Metal { gpu_id } => {
                 format!(", metal:{}", gpu_id)
             }
+            crate::DeviceLocation::Wgpu { gpu_id } => {
+                format!(", wgpu:{}", gpu_id)
+            }
+            crate::DeviceLocation::Meta => ", meta".to_owned(),
         };
 
+        // Meta tensors have no values so only their dims are displayed.
+        let has_values = !self.device().is_meta();
         write!(f, "Tensor[")?;
         match self.dims() {
-            [] => {
+            [] if has_values => {
                 if let Ok(v) = self.to_scalar::<T>() {
                     write!(f, "{v}")?
                 }
             }
-            [s] if *s < 10 => {
+            [s] if *s < 10 && has_values => {
                 if let Ok(vs) = self.to_vec1::<T>() {
                     for (i, v) in vs.iter().enumerate() {
                         if i > 0 {
@@ -440,6 +446,14 @@ fn get_summarized_data(t: &Tensor, edge_items: usize) -> Result<Tensor> {
 
 impl std::fmt::Display for Tensor {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.device().is_meta() {
+            return write!(
+                f,
+                "Tensor[{:?}, {}, meta]",
+                self.dims(),
+                self.dtype().as_str()
+            );
+        }
         let po = PRINT_OPTS.lock().unwrap();
         let summarize = self.elem_count() > po.threshold;
         let to_display = if summarize {
@@ -507,6 +521,10 @@ impl std::fmt::Display for Tensor {
             crate::DeviceLocation::Metal { gpu_id } => {
                 format!(", metal:{}", gpu_id)
             }
+            crate::DeviceLocation::Wgpu { gpu_id } => {
+                format!(", wgpu:{}", gpu_id)
+            }
+            crate::DeviceLocation::Meta => ", meta".to_owned(),
         };
 
         write!(
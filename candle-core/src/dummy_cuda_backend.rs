@@ -274,3 +274,23 @@ pub fn gemm_reduced_precision_f32() -> bool {
 /// This bool controls whether reduced precision reductions (e.g., with tf32 accumulation type) are
 /// allowed with f32 GEMMs.
 pub fn set_gemm_reduced_precision_f32(_b: bool) {}
+
+/// This bool controls whether the cudnn convolution algorithms are selected by benchmarking all
+/// the available ones the first time a convolution shape is encountered rather than by using the
+/// cudnn heuristics.
+pub fn cudnn_benchmark() -> bool {
+    false
+}
+
+/// This bool controls whether the cudnn convolution algorithms are selected by benchmarking all
+/// the available ones the first time a convolution shape is encountered rather than by using the
+/// cudnn heuristics.
+pub fn set_cudnn_benchmark(_b: bool) {}
+
+/// The maximum workspace size in bytes that a benchmarked cudnn convolution algorithm can use.
+pub fn cudnn_workspace_limit() -> usize {
+    usize::MAX
+}
+
+/// The maximum workspace size in bytes that a benchmarked cudnn convolution algorithm can use.
+pub fn set_cudnn_workspace_limit(_n: usize) {}
@@ -1,4 +1,4 @@
-use crate::{DType, DeviceLocation, Layout, MetalError, Shape};
+use crate::{DType, DeviceLocation, Layout, MetalError, Shape, WgpuError};
 
 #[derive(Debug, Clone)]
 pub struct MatMulUnexpectedStriding {
@@ -155,6 +155,12 @@ pub enum Error {
     #[error("the candle crate has not been built with metal support")]
     NotCompiledWithMetalSupport,
 
+    #[error("the candle crate has not been built with wgpu support")]
+    NotCompiledWithWgpuSupport,
+
+    #[error("{op} is not supported on the meta device, meta tensors do not hold any data")]
+    NotSupportedOnMetaDevice { op: &'static str },
+
     #[error("cannot find tensor {path}")]
     CannotFindTensor { path: String },
 
@@ -165,12 +171,19 @@ pub enum Error {
     #[error("Metal error {0}")]
     Metal(#[from] MetalError),
 
+    #[error("wgpu error {0}")]
+    Wgpu(#[from] WgpuError),
+
     #[error(transparent)]
     TryFromIntError(#[from] core::num::TryFromIntError),
 
     #[error("npy/npz error {0}")]
     Npy(String),
 
+    /// Invalid or truncated gguf file.
+    #[error("gguf error: {0}")]
+    Gguf(#[from] crate::quantized::gguf_file::GgufError),
+
     /// Zip file format error.
     #[error(transparent)]
     Zip(#[from] zip::result::ZipError),
@@ -201,6 +214,18 @@ pub enum Error {
         path: std::path::PathBuf,
     },
 
+    /// Adding the op that failed together with its inputs to an error.
+    #[error("{inner}\n  in {op} with inputs:{}", fmt_inputs(.inputs))]
+    WithOp {
+        inner: Box<Self>,
+        op: &'static str,
+        inputs: Vec<TensorInfo>,
+    },
+
+    /// Adding some user provided context to an error, see [`Context`].
+    #[error("{context}: {inner}")]
+    Context { inner: Box<Self>, context: String },
+
     #[error("{inner}\n{backtrace}")]
     WithBacktrace {
         inner: Box<Self>,
@@ -212,6 +237,38 @@ pub enum Error {
     Msg(String),
 }
 
+/// Details on a tensor involved in a failing op.
+#[derive(Debug, Clone)]
+pub struct TensorInfo {
+    pub shape: Shape,
+    pub dtype: DType,
+    pub device: DeviceLocation,
+    /// The tensor name, e.g. the path of a weight loaded through a `VarBuilder`.
+    pub name: Option<String>,
+    /// The location where the tensor was created, only tracked in debug builds.
+    pub location: Option<&'static std::panic::Location<'static>>,
+}
+
+impl std::fmt::Display for TensorInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{name} ")?
+        }
+        write!(f, "{:?} {}", self.shape.dims(), self.dtype.as_str())?;
+        if self.device != DeviceLocation::Cpu {
+            write!(f, " {:?}", self.device)?
+        }
+        if let Some(location) = self.location {
+            write!(f, " created at {location}")?
+        }
+        Ok(())
+    }
+}
+
+fn fmt_inputs(inputs: &[TensorInfo]) -> String {
+    inputs.iter().map(|i| format!("\n    {i}")).collect()
+}
+
 pub type Result<T> = std::result::Result<T, Error>;
 
 impl Error {
@@ -239,6 +296,30 @@ impl Error {
         }
     }
 
+    /// Adds the op that failed and its inputs to the error. The innermost op is kept if the
+    /// error already has this information, and the backtrace, if any, stays at the end.
+    pub fn with_op(self, op: &'static str, inputs: Vec<TensorInfo>) -> Self {
+        match self {
+            Self::WithOp { .. } => self,
+            Self::WithBacktrace { inner, backtrace } => Self::WithBacktrace {
+                inner: Box::new(inner.with_op(op, inputs)),
+                backtrace,
+            },
+            inner => Self::WithOp {
+                inner: Box::new(inner),
+                op,
+                inputs,
+            },
+        }
+    }
+
+    pub fn context<C: std::fmt::Display>(self, context: C) -> Self {
+        Self::Context {
+            inner: Box::new(self),
+            context: context.to_string(),
+        }
+    }
+
     pub fn with_path<P: AsRef<std::path::Path>>(self, p: P) -> Self {
         Self::WithPath {
             inner: Box::new(self),
@@ -267,3 +348,45 @@ pub fn zip<T, U>(r1: Result<T>, r2: Result<U>) -> Result<(T, U)> {
         (_, Err(e)) => Err(e),
     }
 }
+
+/// Adds some context to errors, e.g. the name of the layer being run, similar to
+/// `anyhow::Context`.
+///
+/// ```rust
+/// use candle_core::{Context, Device, Tensor};
+/// let a = Tensor::zeros((2, 3), candle_core::DType::F32, &Device::Cpu)?;
+/// let err = a.matmul(&a).context("layer 3").unwrap_err();
+/// assert!(err.to_string().starts_with("layer 3: shape mismatch in matmul"));
+/// # Ok::<(), candle_core::Error>(())
+/// ```
+pub trait Context<T> {
+    fn context<C: std::fmt::Display>(self, context: C) -> Result<T>;
+
+    fn with_context<C: std::fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T>;
+}
+
+impl<T> Context<T> for Result<T> {
+    fn context<C: std::fmt::Display>(self, context: C) -> Result<T> {
+        self.map_err(|e| e.context(context))
+    }
+
+    fn with_context<C: std::fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        self.map_err(|e| e.context(f()))
+    }
+}
+
+impl<T> Context<T> for Option<T> {
+    fn context<C: std::fmt::Display>(self, context: C) -> Result<T> {
+        match self {
+            Some(v) => Ok(v),
+            None => Err(Error::Msg(context.to_string()).bt()),
+        }
+    }
+
+    fn with_context<C: std::fmt::Display, F: FnOnce() -> C>(self, f: F) -> Result<T> {
+        match self {
+            Some(v) => Ok(v),
+            None => Err(Error::Msg(f().to_string()).bt()),
+        }
+    }
+}
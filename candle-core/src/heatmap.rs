@@ -0,0 +1,330 @@
+//! Export 2D and 3D tensors as PNG images, e.g. to inspect attention maps, feature maps or
+//! diffusion latents while debugging a model.
+//!
+//! The values are first normalized to `[0, 1]` and then mapped to pixels using a colormap. 3D
+//! tensors of shape `(n, h, w)` are laid out as a grid of `n` tiles, e.g. one per attention head.
+//!
+//! ```no_run
+//! # fn main() -> candle_core::Result<()> {
+//! use candle_core::heatmap::{Colormap, Heatmap, Normalization};
+//! # let attn = candle_core::Tensor::zeros((8, 16, 16), candle_core::DType::F32, &candle_core::Device::Cpu)?;
+//! // attn has shape (num_heads, seq_len, seq_len).
+//! Heatmap::new()
+//!     .with_colormap(Colormap::Viridis)
+//!     .with_normalization(Normalization::PerTile)
+//!     .save(&attn, "attn.png")?;
+//! // Or with the default settings.
+//! attn.save_heatmap("attn.png")?;
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! The PNG files are written without compression so that no additional dependency is required.
+use crate::{bail, DType, Result, Tensor};
+use std::io::Write;
+use std::path::Path;
+
+/// How tensor values are mapped to `[0, 1]` before applying the colormap. Non-finite values are
+/// ignored when computing ranges and are rendered as 0.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Normalization {
+    /// Use the min and max values over the whole tensor.
+    MinMax,
+    /// Use the min and max values of each tile separately.
+    PerTile,
+    /// Use a fixed range, values outside of it are clamped.
+    Range { min: f64, max: f64 },
+    /// Map `[-m, m]` to `[0, 1]` where `m` is the max absolute value, so that zero is always in
+    /// the middle of the colormap. This works best with a diverging colormap.
+    Symmetric,
+}
+
+/// The mapping from normalized values to colors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Colormap {
+    /// 8-bit grayscale, 0 is black and 1 is white.
+    Gray,
+    /// The perceptually uniform matplotlib default, from dark purple to yellow.
+    Viridis,
+    /// A diverging colormap from blue to white to red.
+    Coolwarm,
+}
+
+const VIRIDIS: [[u8; 3]; 9] = [
+    [68, 1, 84],
+    [71, 44, 122],
+    [59, 81, 139],
+    [44, 113, 142],
+    [33, 144, 141],
+    [39, 173, 129],
+    [92, 200, 99],
+    [170, 220, 50],
+    [253, 231, 37],
+];
+
+const COOLWARM: [[u8; 3]; 5] = [
+    [59, 76, 192],
+    [141, 176, 254],
+    [221, 221, 221],
+    [244, 154, 123],
+    [180, 4, 38],
+];
+
+fn interpolate(stops: &[[u8; 3]], v: f32) -> [u8; 3] {
+    let pos = v * (stops.len() - 1) as f32;
+    let i = (pos.floor() as usize).min(stops.len() - 2);
+    let frac = pos - i as f32;
+    let mut rgb = [0u8; 3];
+    for (c, rgb) in rgb.iter_mut().enumerate() {
+        let (lo, hi) = (stops[i][c] as f32, stops[i + 1][c] as f32);
+        *rgb = (lo + (hi - lo) * frac).round() as u8
+    }
+    rgb
+}
+
+impl Colormap {
+    /// The number of bytes used per pixel.
+    pub fn channels(&self) -> usize {
+        match self {
+            Self::Gray => 1,
+            Self::Viridis | Self::Coolwarm => 3,
+        }
+    }
+
+    /// Appends the pixel for value `v`, this value is clamped to `[0, 1]`.
+    fn push(&self, v: f32, dst: &mut Vec<u8>) {
+        let v = if v.is_nan() { 0. } else { v.clamp(0., 1.) };
+        match self {
+            Self::Gray => dst.push((v * 255.).round() as u8),
+            Self::Viridis => dst.extend_from_slice(&interpolate(&VIRIDIS, v)),
+            Self::Coolwarm => dst.extend_from_slice(&interpolate(&COOLWARM, v)),
+        }
+    }
+}
+
+/// An 8-bit image, the pixels are stored row by row with `channels` bytes per pixel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub channels: usize,
+    pub data: Vec<u8>,
+}
+
+impl Image {
+    /// Writes the image in the PNG format.
+    pub fn write_png<W: Write>(&self, w: &mut W) -> Result<()> {
+        let color_type = match self.channels {
+            1 => 0u8,
+            3 => 2,
+            4 => 6,
+            c => bail!("unsupported number of channels for png {c}"),
+        };
+        if self.data.len() != self.width * self.height * self.channels {
+            bail!(
+                "unexpected data size {} for a {}x{}x{} image",
+                self.data.len(),
+                self.width,
+                self.height,
+                self.channels
+            )
+        }
+        let mut ihdr = Vec::with_capacity(13);
+        ihdr.extend_from_slice(&(self.width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(self.height as u32).to_be_bytes());
+        ihdr.extend_from_slice(&[8, color_type, 0, 0, 0]);
+
+        // Each scanline starts with the filter type, 0 meaning no filtering.
+        let row_len = self.width * self.channels;
+        let mut raw = Vec::with_capacity((row_len + 1) * self.height);
+        for row in self.data.chunks(row_len.max(1)).take(self.height) {
+            raw.push(0);
+            raw.extend_from_slice(row)
+        }
+
+        w.write_all(b"\x89PNG\r\n\x1a\n")?;
+        write_chunk(w, b"IHDR", &ihdr)?;
+        write_chunk(w, b"IDAT", &zlib_stored(&raw))?;
+        write_chunk(w, b"IEND", &[])?;
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, p: P) -> Result<()> {
+        let p = p.as_ref();
+        let file = std::fs::File::create(p).map_err(|e| crate::Error::from(e).with_path(p))?;
+        let mut w = std::io::BufWriter::new(file);
+        self.write_png(&mut w)?;
+        w.flush()?;
+        Ok(())
+    }
+}
+
+fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for chunk in chunks {
+        for &b in chunk.iter() {
+            crc ^= b as u32;
+            for _ in 0..8 {
+                let mask = (crc & 1).wrapping_neg();
+                crc = (crc >> 1) ^ (0xedb8_8320 & mask)
+            }
+        }
+    }
+    !crc
+}
+
+fn write_chunk<W: Write>(w: &mut W, kind: &[u8; 4], data: &[u8]) -> Result<()> {
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(kind)?;
+    w.write_all(data)?;
+    w.write_all(&crc32(&[kind, data]).to_be_bytes())?;
+    Ok(())
+}
+
+// A zlib stream using uncompressed deflate blocks.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    const MAX_BLOCK: usize = 65535;
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_BLOCK * 5 + 11);
+    out.extend_from_slice(&[0x78, 0x01]);
+    let n_blocks = data.len().div_ceil(MAX_BLOCK).max(1);
+    for i in 0..n_blocks {
+        let block = &data[i * MAX_BLOCK..((i + 1) * MAX_BLOCK).min(data.len())];
+        let len = block.len() as u16;
+        out.push((i + 1 == n_blocks) as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    let (mut a, mut b) = (1u32, 0u32);
+    for &v in data {
+        a = (a + v as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    out.extend_from_slice(&((b << 16) | a).to_be_bytes());
+    out
+}
+
+/// Converts tensors to images, see the [module level documentation](self).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Heatmap {
+    colormap: Colormap,
+    normalization: Normalization,
+    padding: usize,
+}
+
+impl Default for Heatmap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heatmap {
+    /// A heatmap using a grayscale colormap with min-max normalization.
+    pub fn new() -> Self {
+        Self {
+            colormap: Colormap::Gray,
+            normalization: Normalization::MinMax,
+            padding: 1,
+        }
+    }
+
+    pub fn with_colormap(mut self, colormap: Colormap) -> Self {
+        self.colormap = colormap;
+        self
+    }
+
+    pub fn with_normalization(mut self, normalization: Normalization) -> Self {
+        self.normalization = normalization;
+        self
+    }
+
+    /// The number of pixels between two tiles when exporting 3D tensors, this defaults to 1.
+    pub fn with_padding(mut self, padding: usize) -> Self {
+        self.padding = padding;
+        self
+    }
+
+    /// Converts a 2D tensor `(h, w)` or a 3D tensor `(n, h, w)` to an image, the tiles of 3D
+    /// tensors are arranged in a grid with `ceil(sqrt(n))` columns.
+    pub fn to_image(&self, t: &Tensor) -> Result<Image> {
+        let (n, h, w) = match t.dims() {
+            &[h, w] => (1, h, w),
+            &[n, h, w] => (n, h, w),
+            dims => bail!("heatmaps require a 2D or 3D tensor, got shape {dims:?}"),
+        };
+        let values = t.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?;
+        let cols = (n as f64).sqrt().ceil().max(1.) as usize;
+        let rows = n.div_ceil(cols).max(1);
+        let pad = self.padding;
+        let width = cols * w + (cols - 1) * pad;
+        let height = rows * h + (rows - 1) * pad;
+        let global_range = self.range(&values);
+
+        let channels = self.colormap.channels();
+        // Padding pixels are left black.
+        let mut data = vec![0u8; width * height * channels];
+        let mut pixels = Vec::with_capacity(w * channels);
+        for (tile_idx, tile) in values.chunks(h * w).enumerate().take(n) {
+            let (lo, hi) = match self.normalization {
+                Normalization::PerTile => self.range(tile),
+                _ => global_range,
+            };
+            let scale = if hi > lo { 1. / (hi - lo) } else { 0. };
+            let (tile_row, tile_col) = (tile_idx / cols, tile_idx % cols);
+            for (y, row) in tile.chunks(w).enumerate() {
+                pixels.clear();
+                for &v in row {
+                    self.colormap.push((v - lo) * scale, &mut pixels)
+                }
+                let py = tile_row * (h + pad) + y;
+                let px = tile_col * (w + pad);
+                let start = (py * width + px) * channels;
+                data[start..start + pixels.len()].copy_from_slice(&pixels)
+            }
+        }
+        Ok(Image {
+            width,
+            height,
+            channels,
+            data,
+        })
+    }
+
+    fn range(&self, values: &[f32]) -> (f32, f32) {
+        let finite = || values.iter().copied().filter(|v| v.is_finite());
+        match self.normalization {
+            Normalization::Range { min, max } => (min as f32, max as f32),
+            Normalization::Symmetric => {
+                let m = finite().fold(0f32, |m, v| m.max(v.abs()));
+                (-m, m)
+            }
+            Normalization::MinMax | Normalization::PerTile => {
+                let lo = finite().fold(f32::INFINITY, f32::min);
+                let hi = finite().fold(f32::NEG_INFINITY, f32::max);
+                if lo > hi {
+                    (0., 0.)
+                } else {
+                    (lo, hi)
+                }
+            }
+        }
+    }
+
+    /// Writes the tensor as a PNG image.
+    pub fn write_png<W: Write>(&self, t: &Tensor, w: &mut W) -> Result<()> {
+        self.to_image(t)?.write_png(w)
+    }
+
+    /// Saves the tensor as a PNG image.
+    pub fn save<P: AsRef<Path>>(&self, t: &Tensor, p: P) -> Result<()> {
+        self.to_image(t)?.save(p)
+    }
+}
+
+impl Tensor {
+    /// Saves a 2D or 3D tensor as a grayscale PNG image using min-max normalization, see
+    /// [`Heatmap`] for more options.
+    pub fn save_heatmap<P: AsRef<Path>>(&self, p: P) -> Result<()> {
+        Heatmap::new().save(self, p)
+    }
+}
@@ -180,6 +180,32 @@ impl Layout {
         })
     }
 
+    /// The stride between consecutive matrixes when the batch dimensions of a matmul operand,
+    /// i.e. all the dimensions but the last two, can be merged into a single dimension. This
+    /// includes the batch dimensions that are all broadcasted, which have a stride of 0, but not
+    /// a mix of broadcasted and regular dimensions.
+    pub(crate) fn matmul_batch_stride(&self) -> Option<usize> {
+        let rank = self.dims().len();
+        let mut batch_stride = None;
+        let mut expected = None;
+        for (&dim, &stride) in self.dims()[..rank.saturating_sub(2)]
+            .iter()
+            .zip(self.stride())
+            .rev()
+        {
+            if dim == 1 {
+                continue;
+            }
+            match expected {
+                Some(e) if stride != e => return None,
+                Some(_) => {}
+                None => batch_stride = Some(stride),
+            }
+            expected = Some(stride * dim);
+        }
+        Some(batch_stride.unwrap_or(0))
+    }
+
     pub(crate) fn strided_index(&self) -> crate::StridedIndex {
         crate::StridedIndex::from_layout(self)
     }
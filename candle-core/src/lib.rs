@@ -15,7 +15,7 @@
 //! ## Features
 //!
 //! - Simple syntax (looks and feels like PyTorch)
-//! - CPU and Cuda backends (and M1 support)
+//! - CPU and Cuda backends (and M1 support), WebGPU through wgpu
 //! - Enable serverless (CPU) small and fast deployments
 //! - Model training
 //! - Distributed computing (NCCL).
@@ -49,9 +49,12 @@ pub mod display;
 mod dtype;
 pub mod dummy_cuda_backend;
 mod dummy_metal_backend;
+mod dummy_wgpu_backend;
 pub mod error;
+pub mod heatmap;
 mod indexer;
 pub mod layout;
+pub mod meta_backend;
 #[cfg(feature = "metal")]
 pub mod metal_backend;
 #[cfg(feature = "mkl")]
@@ -69,9 +72,15 @@ pub mod streaming;
 mod strided_index;
 mod tensor;
 mod tensor_cat;
+mod tensor_count;
+mod tensor_pad;
 pub mod test_utils;
+pub mod testing;
+pub mod typed;
 pub mod utils;
 mod variable;
+#[cfg(feature = "wgpu")]
+pub mod wgpu_backend;
 
 #[cfg(feature = "cudnn")]
 pub use cuda_backend::cudnn;
@@ -80,14 +89,16 @@ pub use cpu_backend::{CpuStorage, CpuStorageRef};
 pub use custom_op::{CustomOp1, CustomOp2, CustomOp3, InplaceOp1, InplaceOp2, InplaceOp3};
 pub use device::{Device, DeviceLocation, NdArray};
 pub use dtype::{DType, DTypeParseError, FloatDType, IntDType, WithDType};
-pub use error::{Error, Result};
+pub use error::{Context, Error, Result};
 pub use indexer::{IndexOp, TensorIndexer};
 pub use layout::Layout;
+pub use meta_backend::{MetaDevice, MetaStorage};
 pub use shape::{Shape, D};
 pub use storage::Storage;
 pub use streaming::{StreamTensor, StreamingBinOp, StreamingModule};
 pub use strided_index::{StridedBlocks, StridedIndex};
 pub use tensor::{Tensor, TensorId};
+pub use tensor_pad::PadMode;
 pub use variable::Var;
 
 #[cfg(feature = "cuda")]
@@ -104,6 +115,12 @@ pub use metal_backend::{MetalDevice, MetalError, MetalStorage};
 #[cfg(not(feature = "metal"))]
 pub use dummy_metal_backend::{MetalDevice, MetalError, MetalStorage};
 
+#[cfg(feature = "wgpu")]
+pub use wgpu_backend::{WgpuDevice, WgpuError, WgpuStorage};
+
+#[cfg(not(feature = "wgpu"))]
+pub use dummy_wgpu_backend::{WgpuDevice, WgpuError, WgpuStorage};
+
 #[cfg(feature = "mkl")]
 extern crate intel_mkl_src;
 
@@ -0,0 +1,267 @@
+//! The meta device, tensors on this device only track their shape and dtype.
+//!
+//! Running a model on meta tensors does not allocate memory nor compute anything, this can be
+//! used to check that a model is wired properly, to count its parameters or to estimate the
+//! memory it requires before loading the actual weights.
+//!
+//! ```rust
+//! use candle_core::{DType, Device, Tensor};
+//! # fn main() -> candle_core::Result<()> {
+//! let dev = Device::Meta;
+//! let xs = Tensor::zeros((1024, 4096), DType::BF16, &dev)?;
+//! let ws = Tensor::zeros((4096, 4096), DType::BF16, &dev)?;
+//! let ys = xs.matmul(&ws.t()?)?.sum_keepdim(1)?;
+//! assert_eq!(ys.dims(), [1024, 1]);
+//! // Reading the values of a meta tensor is an error.
+//! assert!(ys.to_vec2::<half::bf16>().is_err());
+//! # Ok(())
+//! # }
+//! ```
+use crate::backend::{BackendDevice, BackendStorage};
+use crate::op::{BinaryOpT, CmpOp, ReduceOp, UnaryOpT};
+use crate::{CpuStorage, DType, Error, Layout, Result, Shape};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetaDevice;
+
+/// The storage for meta tensors, only the dtype is tracked, the shape being part of the layout.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetaStorage {
+    dtype: DType,
+}
+
+impl MetaStorage {
+    pub fn new(dtype: DType) -> Self {
+        Self { dtype }
+    }
+}
+
+impl BackendStorage for MetaStorage {
+    type Device = MetaDevice;
+
+    fn try_clone(&self, _: &Layout) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    fn device(&self) -> &Self::Device {
+        &MetaDevice
+    }
+
+    fn to_cpu_storage(&self) -> Result<CpuStorage> {
+        Err(Error::NotSupportedOnMetaDevice { op: "to_cpu" }.bt())
+    }
+
+    fn affine(&self, _: &Layout, _: f64, _: f64) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn powf(&self, _: &Layout, _: f64) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn elu(&self, _: &Layout, _: f64) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn reduce_op(&self, op: ReduceOp, _: &Layout, _: &[usize]) -> Result<Self> {
+        match op {
+            ReduceOp::ArgMin | ReduceOp::ArgMax => Ok(Self::new(DType::U32)),
+            ReduceOp::Sum | ReduceOp::Min | ReduceOp::Max => Ok(*self),
+        }
+    }
+
+    fn cmp(&self, _: CmpOp, _: &Self, _: &Layout, _: &Layout) -> Result<Self> {
+        Ok(Self::new(DType::U8))
+    }
+
+    fn to_dtype(&self, _: &Layout, dtype: DType) -> Result<Self> {
+        Ok(Self::new(dtype))
+    }
+
+    fn unary_impl<B: UnaryOpT>(&self, _: &Layout) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn binary_impl<B: BinaryOpT>(&self, _: &Self, _: &Layout, _: &Layout) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn where_cond(&self, _: &Layout, t: &Self, _: &Layout, _: &Self, _: &Layout) -> Result<Self> {
+        Ok(*t)
+    }
+
+    fn conv1d(
+        &self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: &crate::conv::ParamsConv1D,
+    ) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn conv_transpose1d(
+        &self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: &crate::conv::ParamsConvTranspose1D,
+    ) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn conv2d(
+        &self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: &crate::conv::ParamsConv2D,
+    ) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn conv_transpose2d(
+        &self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: &crate::conv::ParamsConvTranspose2D,
+    ) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn avg_pool2d(&self, _: &Layout, _: (usize, usize), _: (usize, usize)) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn max_pool2d(&self, _: &Layout, _: (usize, usize), _: (usize, usize)) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn upsample_nearest1d(&self, _: &Layout, _: usize) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn upsample_nearest2d(&self, _: &Layout, _: usize, _: usize) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn gather(&self, _: &Layout, _: &Self, _: &Layout, _: usize) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn scatter_add(
+        &self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: usize,
+    ) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn index_select(&self, _: &Self, _: &Layout, _: &Layout, _: usize) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn index_add(
+        &self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: &Self,
+        _: &Layout,
+        _: usize,
+    ) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn matmul(
+        &self,
+        _: &Self,
+        _: (usize, usize, usize, usize),
+        _: &Layout,
+        _: &Layout,
+    ) -> Result<Self> {
+        Ok(*self)
+    }
+
+    fn copy_strided_src(&self, _: &mut Self, _: usize, _: &Layout) -> Result<()> {
+        Ok(())
+    }
+
+    fn copy2d(
+        &self,
+        _: &mut Self,
+        _: usize,
+        _: usize,
+        _: usize,
+        _: usize,
+        _: usize,
+        _: usize,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+impl BackendDevice for MetaDevice {
+    type Storage = MetaStorage;
+
+    fn new(_: usize) -> Result<Self> {
+        Ok(Self)
+    }
+
+    fn location(&self) -> crate::DeviceLocation {
+        crate::DeviceLocation::Meta
+    }
+
+    fn same_device(&self, _: &Self) -> bool {
+        true
+    }
+
+    fn zeros_impl(&self, _: &Shape, dtype: DType) -> Result<MetaStorage> {
+        Ok(MetaStorage::new(dtype))
+    }
+
+    fn ones_impl(&self, _: &Shape, dtype: DType) -> Result<MetaStorage> {
+        Ok(MetaStorage::new(dtype))
+    }
+
+    unsafe fn alloc_uninit(&self, _: &Shape, dtype: DType) -> Result<MetaStorage> {
+        Ok(MetaStorage::new(dtype))
+    }
+
+    fn storage_from_slice<T: crate::WithDType>(&self, _: &[T]) -> Result<MetaStorage> {
+        Ok(MetaStorage::new(T::DTYPE))
+    }
+
+    fn storage_from_cpu_storage(&self, storage: &CpuStorage) -> Result<MetaStorage> {
+        Ok(MetaStorage::new(storage.dtype()))
+    }
+
+    fn storage_from_cpu_storage_owned(&self, storage: CpuStorage) -> Result<MetaStorage> {
+        Ok(MetaStorage::new(storage.dtype()))
+    }
+
+    fn rand_uniform(&self, _: &Shape, dtype: DType, _: f64, _: f64) -> Result<MetaStorage> {
+        Ok(MetaStorage::new(dtype))
+    }
+
+    fn rand_normal(&self, _: &Shape, dtype: DType, _: f64, _: f64) -> Result<MetaStorage> {
+        Ok(MetaStorage::new(dtype))
+    }
+
+    fn set_seed(&self, _: u64) -> Result<()> {
+        Ok(())
+    }
+
+    fn synchronize(&self) -> Result<()> {
+        Ok(())
+    }
+}
@@ -152,7 +152,9 @@ impl MetalDevice {
         &self.device
     }
 
-    fn drop_unused_buffers(&self) -> Result<()> {
+    /// Releases the cached buffers that are not used by any tensor anymore, this is useful to
+    /// reduce the memory footprint when the system is under memory pressure.
+    pub fn drop_unused_buffers(&self) -> Result<()> {
         let mut buffers = self.buffers.write().map_err(MetalError::from)?;
         for subbuffers in buffers.values_mut() {
             let newbuffers = subbuffers
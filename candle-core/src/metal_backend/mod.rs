@@ -65,6 +65,20 @@ impl From<String> for MetalError {
     }
 }
 
+/// The strides of a matmul operand with its batch dimensions merged into a single one, the gemm
+/// kernels read the batch stride from the third to last dimension.
+fn matmul_strides(l: &Layout) -> Result<[usize; 3]> {
+    let stride = l.stride();
+    let rank = stride.len();
+    match l.matmul_batch_stride() {
+        Some(batch_stride) => Ok([batch_stride, stride[rank - 2], stride[rank - 1]]),
+        None => Err(MetalError::Message(format!(
+            "matmul: the batch dimensions cannot be merged, {l:?}"
+        ))
+        .into()),
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MetalStorage {
     /// The actual buffer containing the data.
@@ -834,10 +848,10 @@ impl BackendStorage for MetalStorage {
             // Make the kernel contiguous if not already the case.
             let mut kernel_c = self.device().zeros_impl(kernel_l.shape(), kernel.dtype())?;
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, l_out, n)).transpose(1, 2)?;
         let mut res_t = self.device().zeros_impl(res_l.shape(), res.dtype())?;
@@ -1025,10 +1039,10 @@ impl BackendStorage for MetalStorage {
             // Make the kernel contiguous if not already the case.
             let mut kernel_c = self.device().zeros_impl(kernel_l.shape(), kernel.dtype())?;
             kernel.copy_strided_src(&mut kernel_c, 0, kernel_l)?;
-            let kernel_l = Layout::contiguous_with_offset((1, n, k), kernel_l.start_offset())
+            let kernel_l = Layout::contiguous((1, n, k))
                 .transpose(1, 2)?
                 .broadcast_as((b, k, n))?;
-            col.matmul(kernel, (b, m, n, k), &col_l, &kernel_l)?
+            col.matmul(&kernel_c, (b, m, n, k), &col_l, &kernel_l)?
         };
         let res_l = Layout::contiguous((b, h_out, w_out, n))
             .transpose(1, 2)?
@@ -1432,6 +1446,7 @@ impl BackendStorage for MetalStorage {
         rhs_l: &Layout,
     ) -> Result<Self> {
         let buffer = self.device.new_buffer(b * m * n, self.dtype, "matmul")?;
+        let (lhs_stride, rhs_stride) = (matmul_strides(lhs_l)?, matmul_strides(rhs_l)?);
         let command_buffer = self.device.command_buffer()?;
         command_buffer.set_label("matmul");
         if self.dtype == DType::BF16 {
@@ -1441,10 +1456,10 @@ impl BackendStorage for MetalStorage {
                 &self.device.kernels,
                 candle_metal_kernels::GemmDType::BF16,
                 (b, m, n, k),
-                lhs_l.stride(),
+                &lhs_stride,
                 lhs_l.start_offset() * self.dtype.size_in_bytes(),
                 &self.buffer,
-                rhs_l.stride(),
+                &rhs_stride,
                 rhs_l.start_offset() * rhs.dtype.size_in_bytes(),
                 &rhs.buffer,
                 &buffer,
@@ -1468,10 +1483,10 @@ impl BackendStorage for MetalStorage {
                 &self.device.kernels,
                 dtype,
                 (b, m, n, k),
-                lhs_l.stride(),
+                &lhs_stride,
                 lhs_l.start_offset() * self.dtype.size_in_bytes(),
                 &self.buffer,
-                rhs_l.stride(),
+                &rhs_stride,
                 rhs_l.start_offset() * rhs.dtype.size_in_bytes(),
                 &rhs.buffer,
                 &buffer,
@@ -1494,10 +1509,10 @@ impl BackendStorage for MetalStorage {
                 &self.device.kernels,
                 name,
                 (b, m, n, k),
-                lhs_l.stride(),
+                &lhs_stride,
                 lhs_l.start_offset() * self.dtype.size_in_bytes(),
                 &self.buffer,
-                rhs_l.stride(),
+                &rhs_stride,
                 rhs_l.start_offset() * rhs.dtype.size_in_bytes(),
                 &rhs.buffer,
                 &buffer,
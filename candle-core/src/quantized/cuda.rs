@@ -43,6 +43,11 @@ fn pad(p: usize, q: usize) -> usize {
     ceil_div(p, q) * q
 }
 
+// There are no kernels for the i-quants yet, these are dequantized on the cpu.
+fn has_kernels(dtype: GgmlDType) -> bool {
+    !matches!(dtype, GgmlDType::IQ4NL | GgmlDType::IQ2XS | GgmlDType::IQ3S)
+}
+
 fn quantize_q8_1(
     src: &CudaView<f32>,
     dst: &mut CudaSlice<u8>,
@@ -435,6 +440,9 @@ impl QCudaStorage {
             GgmlDType::Q5K => deq::<crate::quantized::BlockQ5K>(&buffer, block_len, &mut out)?,
             GgmlDType::Q6K => deq::<crate::quantized::BlockQ6K>(&buffer, block_len, &mut out)?,
             GgmlDType::Q8K => deq::<crate::quantized::BlockQ8K>(&buffer, block_len, &mut out)?,
+            GgmlDType::IQ4NL => deq::<crate::quantized::BlockIQ4NL>(&buffer, block_len, &mut out)?,
+            GgmlDType::IQ2XS => deq::<crate::quantized::BlockIQ2XS>(&buffer, block_len, &mut out)?,
+            GgmlDType::IQ3S => deq::<crate::quantized::BlockIQ3S>(&buffer, block_len, &mut out)?,
         }
 
         self.device
@@ -442,6 +450,13 @@ impl QCudaStorage {
     }
 
     pub fn dequantize_f16(&self, elem_count: usize) -> Result<CudaStorage> {
+        if !has_kernels(self.dtype) {
+            use crate::backend::BackendStorage;
+            let layout = crate::Layout::contiguous(elem_count);
+            return self
+                .dequantize(elem_count)?
+                .to_dtype(&layout, crate::DType::F16);
+        }
         dequantize_f16(&self.data, self.dtype, elem_count, self.device())
     }
 
@@ -475,6 +490,28 @@ impl QCudaStorage {
         self.data.len
     }
 
+    /// Overwrites the quantized data with `data`. The copy runs on its own stream so that it
+    /// overlaps with the kernels submitted afterwards on the default stream, it only starts once
+    /// the kernels already submitted, which may still read the previous data, have completed.
+    pub fn copy_from_host(&mut self, data: &[u8]) -> Result<()> {
+        use cudarc::driver::{result, DevicePtrMut};
+        if data.len() != self.data.len {
+            crate::bail!(
+                "copy_from_host: got {} bytes for a storage of {} bytes",
+                data.len(),
+                self.data.len
+            )
+        }
+        let stream = self.device.fork_default_stream().w()?;
+        stream.wait_for_default().w()?;
+        let mut dst = self.data.inner.slice_mut(..self.data.len);
+        unsafe {
+            result::memcpy_htod_async(*dst.device_ptr_mut(), data, stream.stream).w()?;
+            result::stream::synchronize(stream.stream).w()?;
+        }
+        Ok(())
+    }
+
     pub fn fwd(
         &self,
         self_shape: &crate::Shape,
@@ -486,11 +523,15 @@ impl QCudaStorage {
         } else {
             8
         };
-        let use_vec_kernel = match layout.shape().dims() {
-            [b, m, _k] => b * m <= max_bm,
-            [b, _k] => *b <= max_bm,
-            _ => false,
-        };
+        // The quantized weights are used directly by fused kernels, a matrix-vector kernel for a
+        // few rows and a tiled matmul kernel otherwise, the lhs being quantized to q8_1.
+        // There are no kernels for the i-quants yet, these are dequantized before the matmul.
+        let use_vec_kernel = has_kernels(self.dtype)
+            && match layout.shape().dims() {
+                [b, m, _k] => b * m <= max_bm,
+                [b, _k] => *b <= max_bm,
+                _ => false,
+            };
         if use_vec_kernel {
             self.dequantize_matmul_vec(self_shape, storage, layout)
         } else {
@@ -557,7 +598,9 @@ impl QCudaStorage {
             crate::bail!("mismatch on matmul dim {self_shape:?} {:?}", layout.shape())
         }
 
-        let out = if FORCE_DMMV.load(std::sync::atomic::Ordering::Relaxed) {
+        let dequantize_first =
+            FORCE_DMMV.load(std::sync::atomic::Ordering::Relaxed) || !has_kernels(self.dtype);
+        let out = if dequantize_first {
             let data_f32 = self.dequantize(n * k)?;
             let rhs_l = crate::Layout::new((k, n).into(), vec![1, k], 0).broadcast_as((b, k, n))?;
             storage.matmul(&data_f32, (b, m, n, k), layout, &rhs_l)?
@@ -708,6 +751,45 @@ mod test {
         Ok(())
     }
 
+    // The fused kernels give the same results as a matmul on the dequantized weights, up to the
+    // q8_1 quantization of the lhs.
+    #[test]
+    fn cuda_fused_k_quants() -> Result<()> {
+        let dev = CudaDevice::new(0)?;
+        let (n, k) = (8, 512);
+        let ws: Vec<f32> = (0..n * k).map(|v| ((v % 37) as f32 - 18.) / 18.).collect();
+        for dtype in [GgmlDType::Q4K, GgmlDType::Q6K] {
+            let w = dev.htod_sync_copy(&ws).w()?;
+            let mut w_q = QCudaStorage::zeros(&dev, n * k, dtype)?;
+            w_q.quantize(&CudaStorage::wrap_cuda_slice(w, dev.clone()))?;
+            let w_deq = w_q.dequantize(n * k)?;
+            let w_deq = dev
+                .dtoh_sync_copy(&w_deq.as_cuda_slice::<f32>()?.slice(..))
+                .w()?;
+            // A single row and a few rows use the matrix-vector kernels, more rows use mmq.
+            for m in [1, 3, 32] {
+                let ys: Vec<f32> = (0..m * k).map(|v| ((v % 13) as f32 - 6.) / 6.).collect();
+                let y = dev.htod_sync_copy(&ys).w()?;
+                let y = CudaStorage::wrap_cuda_slice(y, dev.clone());
+                let layout = crate::Layout::contiguous((m, k));
+                let (out, out_shape) = w_q.fwd(&(n, k).into(), &y, &layout)?;
+                assert_eq!(out_shape.dims(), [m, n]);
+                let out = dev
+                    .dtoh_sync_copy(&out.as_cuda_slice::<f32>()?.slice(..))
+                    .w()?;
+                for row in 0..m {
+                    for col in 0..n {
+                        let expected: f32 =
+                            (0..k).map(|i| ys[row * k + i] * w_deq[col * k + i]).sum();
+                        let diff = (out[row * n + col] - expected).abs();
+                        assert!(diff < 0.5, "{dtype:?} {m} {row} {col} {diff}");
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
     // The following test used to fail under compute-sanitizer until #2526.
     #[test]
     fn cuda_mm_q8_1_pad() -> Result<()> {
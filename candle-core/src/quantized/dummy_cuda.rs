@@ -36,6 +36,10 @@ impl QCudaStorage {
         0
     }
 
+    pub fn copy_from_host(&mut self, _data: &[u8]) -> Result<()> {
+        Err(Error::NotCompiledWithCudaSupport)
+    }
+
     pub fn fwd(
         &self,
         _self_shape: &crate::Shape,
@@ -32,6 +32,10 @@ impl QMetalStorage {
         0
     }
 
+    pub fn copy_from_host(&mut self, _data: &[u8]) -> Result<()> {
+        Err(Error::NotCompiledWithMetalSupport)
+    }
+
     pub fn fwd(
         &self,
         _self_shape: &crate::Shape,
@@ -127,9 +127,13 @@ fn from_raw_data<T: super::GgmlType + Send + Sync + 'static>(
     let n_blocks = size_in_bytes / std::mem::size_of::<T>();
     let data = unsafe { std::slice::from_raw_parts(raw_data_ptr as *const T, n_blocks) };
     let data: QStorage = match device {
-        Device::Cpu => QStorage::Cpu(Box::new(data.to_vec())),
+        Device::Cpu | Device::Wgpu(_) => QStorage::Cpu(Box::new(data.to_vec())),
         Device::Metal(metal) => super::metal::load_quantized(metal, data)?,
         Device::Cuda(cuda) => super::cuda::load_quantized(cuda, data)?,
+        Device::Meta => {
+            let op = "load_quantized";
+            return Err(crate::Error::NotSupportedOnMetaDevice { op }.bt());
+        }
     };
     super::QTensor::new(data, dims)
 }
@@ -183,6 +187,15 @@ pub fn qtensor_from_ggml(
         GgmlDType::Q6K => {
             from_raw_data::<k_quants::BlockQ6K>(raw_data, size_in_bytes, dims, device)
         }
+        GgmlDType::IQ4NL => {
+            from_raw_data::<k_quants::BlockIQ4NL>(raw_data, size_in_bytes, dims, device)
+        }
+        GgmlDType::IQ2XS => {
+            from_raw_data::<k_quants::BlockIQ2XS>(raw_data, size_in_bytes, dims, device)
+        }
+        GgmlDType::IQ3S => {
+            from_raw_data::<k_quants::BlockIQ3S>(raw_data, size_in_bytes, dims, device)
+        }
         _ => crate::bail!("quantized type {ggml_dtype:?} is not supported yet"),
     }
 }
@@ -5,10 +5,64 @@
 use super::{GgmlDType, QTensor};
 use crate::{Device, Result};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 
 pub const DEFAULT_ALIGNMENT: u64 = 32;
 
+/// The errors reported when validating the header of a gguf file, these are detected before
+/// reading any tensor data so that corrupt or truncated files fail early with a precise message.
+#[derive(thiserror::Error, Debug, Clone, PartialEq, Eq)]
+pub enum GgufError {
+    #[error("invalid magic 0x{0:08x}, not a gguf file")]
+    InvalidMagic(u32),
+
+    #[error("unsupported gguf version {0}")]
+    UnsupportedVersion(u32),
+
+    #[error("truncated header, the file only has {file_size} bytes")]
+    TruncatedHeader { file_size: u64 },
+
+    /// A length or count read from the header cannot fit in the file, this usually indicates a
+    /// corrupt header.
+    #[error("invalid {what} {len}, the file only has {file_size} bytes")]
+    InvalidLength {
+        what: &'static str,
+        len: u64,
+        file_size: u64,
+    },
+
+    #[error("invalid alignment {0}, expected a power of two")]
+    InvalidAlignment(u64),
+
+    #[error("duplicate tensor {0}")]
+    DuplicateTensor(String),
+
+    #[error("tensor {name}: invalid shape {shape:?} for dtype {ggml_dtype:?}")]
+    InvalidTensorShape {
+        name: String,
+        ggml_dtype: GgmlDType,
+        shape: Vec<usize>,
+    },
+
+    #[error("tensor {name}: offset {offset} is not a multiple of the alignment {alignment}")]
+    MisalignedTensor {
+        name: String,
+        offset: u64,
+        alignment: u64,
+    },
+
+    #[error("tensor {name}: data range {start}..{end} exceeds the file size {file_size}")]
+    TensorOutOfBounds {
+        name: String,
+        start: u64,
+        end: u64,
+        file_size: u64,
+    },
+
+    #[error("tensor {name}: data overlaps with tensor {other}")]
+    OverlappingTensors { name: String, other: String },
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum Magic {
     Gguf,
@@ -19,7 +73,7 @@ impl TryFrom<u32> for Magic {
     fn try_from(value: u32) -> Result<Self> {
         let magic = match value {
             0x46554747 | 0x47475546 => Self::Gguf,
-            _ => crate::bail!("unknown magic 0x{value:08x}"),
+            _ => Err(GgufError::InvalidMagic(value))?,
         };
         Ok(magic)
     }
@@ -41,7 +95,7 @@ impl VersionedMagic {
             (Magic::Gguf, 1) => Self::GgufV1,
             (Magic::Gguf, 2) => Self::GgufV2,
             (Magic::Gguf, 3) => Self::GgufV3,
-            _ => crate::bail!("gguf: unsupported magic/version {magic:?}/{version}"),
+            _ => Err(GgufError::UnsupportedVersion(version))?,
         };
         Ok(versioned_magic)
     }
@@ -89,13 +143,35 @@ pub struct Content {
     pub tensor_data_offset: u64,
 }
 
-fn read_string<R: std::io::Read>(reader: &mut R, magic: &VersionedMagic) -> Result<String> {
+/// Reads a length or a count, these are u32 in v1 and u64 afterwards. As each element takes at
+/// least one byte, a value larger than the file size denotes a corrupt header and is rejected
+/// before anything gets allocated.
+fn read_len<R: std::io::Read>(
+    reader: &mut R,
+    magic: &VersionedMagic,
+    what: &'static str,
+    file_size: u64,
+) -> Result<usize> {
     let len = match magic {
-        VersionedMagic::GgufV1 => reader.read_u32::<LittleEndian>()? as usize,
-        VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => {
-            reader.read_u64::<LittleEndian>()? as usize
-        }
+        VersionedMagic::GgufV1 => reader.read_u32::<LittleEndian>()? as u64,
+        VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => reader.read_u64::<LittleEndian>()?,
     };
+    if len > file_size {
+        Err(GgufError::InvalidLength {
+            what,
+            len,
+            file_size,
+        })?
+    }
+    Ok(len as usize)
+}
+
+fn read_string<R: std::io::Read>(
+    reader: &mut R,
+    magic: &VersionedMagic,
+    file_size: u64,
+) -> Result<String> {
+    let len = read_len(reader, magic, "string length", file_size)?;
     let mut v = vec![0u8; len];
     reader.read_exact(&mut v)?;
     // GGUF strings are supposed to be non-null terminated but in practice this happens.
@@ -139,7 +215,7 @@ pub enum ValueType {
     Array,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum Value {
     U8(u8),
     I8(i8),
@@ -276,6 +352,7 @@ impl Value {
         reader: &mut R,
         value_type: ValueType,
         magic: &VersionedMagic,
+        file_size: u64,
     ) -> Result<Self> {
         let v = match value_type {
             ValueType::U8 => Self::U8(reader.read_u8()?),
@@ -293,19 +370,14 @@ impl Value {
                 1 => Self::Bool(true),
                 b => crate::bail!("unexpected bool value {b}"),
             },
-            ValueType::String => Self::String(read_string(reader, magic)?),
+            ValueType::String => Self::String(read_string(reader, magic, file_size)?),
             ValueType::Array => {
                 let value_type = reader.read_u32::<LittleEndian>()?;
                 let value_type = ValueType::from_u32(value_type)?;
-                let len = match magic {
-                    VersionedMagic::GgufV1 => reader.read_u32::<LittleEndian>()? as usize,
-                    VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => {
-                        reader.read_u64::<LittleEndian>()? as usize
-                    }
-                };
+                let len = read_len(reader, magic, "array length", file_size)?;
                 let mut vs = Vec::with_capacity(len);
                 for _ in 0..len {
-                    vs.push(Value::read(reader, value_type, magic)?)
+                    vs.push(Value::read(reader, value_type, magic, file_size)?)
                 }
                 Self::Array(vs)
             }
@@ -393,34 +465,52 @@ impl ValueType {
 }
 
 impl Content {
+    /// Reads the header of a gguf file. The header is validated against the file size: a
+    /// truncated or corrupt file results in a [`GgufError`] identifying the offending field or
+    /// tensor rather than in a failure when reading the tensor data.
     pub fn read<R: std::io::Seek + std::io::Read>(reader: &mut R) -> Result<Self> {
-        let magic = VersionedMagic::read(reader)?;
-
-        let tensor_count = match magic {
-            VersionedMagic::GgufV1 => reader.read_u32::<LittleEndian>()? as usize,
-            VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => {
-                reader.read_u64::<LittleEndian>()? as usize
+        let start = reader.stream_position()?;
+        let file_size = reader.seek(std::io::SeekFrom::End(0))?;
+        reader.seek(std::io::SeekFrom::Start(start))?;
+        match Self::read_header(reader, file_size) {
+            Err(crate::Error::Io(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                Err(GgufError::TruncatedHeader { file_size })?
             }
-        };
-        let metadata_kv_count = match magic {
-            VersionedMagic::GgufV1 => reader.read_u32::<LittleEndian>()? as usize,
-            VersionedMagic::GgufV2 | VersionedMagic::GgufV3 => {
-                reader.read_u64::<LittleEndian>()? as usize
+            Err(err) => Err(err),
+            Ok(content) => {
+                content.validate(file_size)?;
+                Ok(content)
             }
-        };
+        }
+    }
+
+    fn read_header<R: std::io::Seek + std::io::Read>(
+        reader: &mut R,
+        file_size: u64,
+    ) -> Result<Self> {
+        let magic = VersionedMagic::read(reader)?;
+        let tensor_count = read_len(reader, &magic, "tensor count", file_size)?;
+        let metadata_kv_count = read_len(reader, &magic, "metadata count", file_size)?;
 
         let mut metadata = HashMap::new();
         for _idx in 0..metadata_kv_count {
-            let key = read_string(reader, &magic)?;
+            let key = read_string(reader, &magic, file_size)?;
             let value_type = reader.read_u32::<LittleEndian>()?;
             let value_type = ValueType::from_u32(value_type)?;
-            let value = Value::read(reader, value_type, &magic)?;
+            let value = Value::read(reader, value_type, &magic, file_size)?;
             metadata.insert(key, value);
         }
         let mut tensor_infos = HashMap::new();
         for _idx in 0..tensor_count {
-            let tensor_name = read_string(reader, &magic)?;
+            let tensor_name = read_string(reader, &magic, file_size)?;
             let n_dimensions = reader.read_u32::<LittleEndian>()?;
+            if n_dimensions as u64 > file_size {
+                Err(GgufError::InvalidLength {
+                    what: "number of dimensions",
+                    len: n_dimensions as u64,
+                    file_size,
+                })?
+            }
 
             let mut dimensions: Vec<usize> = match magic {
                 VersionedMagic::GgufV1 => {
@@ -439,26 +529,21 @@ impl Content {
             let ggml_dtype = reader.read_u32::<LittleEndian>()?;
             let ggml_dtype = GgmlDType::from_u32(ggml_dtype)?;
             let offset = reader.read_u64::<LittleEndian>()?;
-            tensor_infos.insert(
-                tensor_name,
-                TensorInfo {
-                    shape: crate::Shape::from(dimensions),
-                    offset,
-                    ggml_dtype,
-                },
-            );
+            let info = TensorInfo {
+                shape: crate::Shape::from(dimensions),
+                offset,
+                ggml_dtype,
+            };
+            if tensor_infos.insert(tensor_name.clone(), info).is_some() {
+                Err(GgufError::DuplicateTensor(tensor_name))?
+            }
         }
         let position = reader.stream_position()?;
-        let alignment = match metadata.get("general.alignment") {
-            Some(Value::U8(v)) => *v as u64,
-            Some(Value::U16(v)) => *v as u64,
-            Some(Value::U32(v)) => *v as u64,
-            Some(Value::I8(v)) if *v >= 0 => *v as u64,
-            Some(Value::I16(v)) if *v >= 0 => *v as u64,
-            Some(Value::I32(v)) if *v >= 0 => *v as u64,
-            _ => DEFAULT_ALIGNMENT,
-        };
-        let tensor_data_offset = (position + alignment - 1) / alignment * alignment;
+        let alignment = Self::alignment(&metadata);
+        if !alignment.is_power_of_two() {
+            Err(GgufError::InvalidAlignment(alignment))?
+        }
+        let tensor_data_offset = position.div_ceil(alignment) * alignment;
         Ok(Self {
             magic,
             metadata,
@@ -467,6 +552,164 @@ impl Content {
         })
     }
 
+    fn alignment(metadata: &HashMap<String, Value>) -> u64 {
+        metadata
+            .get("general.alignment")
+            .map_or(DEFAULT_ALIGNMENT, alignment)
+    }
+
+    /// Checks that the tensor shapes are compatible with their dtype and that the tensor data is
+    /// aligned, does not overlap, and fits within a file of `file_size` bytes.
+    pub fn validate(&self, file_size: u64) -> std::result::Result<(), GgufError> {
+        let alignment = Self::alignment(&self.metadata);
+        let mut ranges = Vec::with_capacity(self.tensor_infos.len());
+        for (name, info) in self.tensor_infos.iter() {
+            let ggml_dtype = info.ggml_dtype;
+            let invalid_shape = || GgufError::InvalidTensorShape {
+                name: name.clone(),
+                ggml_dtype,
+                shape: info.shape.dims().to_vec(),
+            };
+            let elem_count = info
+                .shape
+                .dims()
+                .iter()
+                .try_fold(1usize, |acc, &d| acc.checked_mul(d))
+                .ok_or_else(invalid_shape)?;
+            if elem_count % ggml_dtype.block_size() != 0 {
+                return Err(invalid_shape());
+            }
+            let size_in_bytes = (elem_count / ggml_dtype.block_size())
+                .checked_mul(ggml_dtype.type_size())
+                .ok_or_else(invalid_shape)? as u64;
+            if info.offset % alignment != 0 {
+                return Err(GgufError::MisalignedTensor {
+                    name: name.clone(),
+                    offset: info.offset,
+                    alignment,
+                });
+            }
+            let start = self.tensor_data_offset.checked_add(info.offset);
+            let end = start.and_then(|start| start.checked_add(size_in_bytes));
+            match (start, end) {
+                (Some(start), Some(end)) if end <= file_size => ranges.push((start, end, name)),
+                _ => {
+                    return Err(GgufError::TensorOutOfBounds {
+                        name: name.clone(),
+                        start: start.unwrap_or(u64::MAX),
+                        end: end.unwrap_or(u64::MAX),
+                        file_size,
+                    })
+                }
+            }
+        }
+        ranges.sort();
+        for w in ranges.windows(2) {
+            let ((_, prev_end, prev_name), (start, _, name)) = (w[0], w[1]);
+            if start < prev_end {
+                return Err(GgufError::OverlappingTensors {
+                    name: name.clone(),
+                    other: prev_name.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the content of a model that may be split in multiple files, the tensors can then be
+    /// retrieved with [`Self::tensor`] using the same reader. The metadata is the one from the
+    /// first split, the tensor infos are merged and their offsets point into the concatenation of
+    /// the splits.
+    pub fn read_split<R: std::io::Seek + std::io::Read>(
+        reader: &mut SplitReader<R>,
+    ) -> Result<Self> {
+        let num_splits = reader.num_splits();
+        let mut content: Option<Self> = None;
+        for (idx, (split, &start)) in reader
+            .readers
+            .iter_mut()
+            .zip(reader.starts.iter())
+            .enumerate()
+        {
+            split.seek(std::io::SeekFrom::Start(0))?;
+            let c = Self::read(split)?;
+            let split_count = c.metadata.get("split.count").and_then(split_value);
+            let split_no = c.metadata.get("split.no").and_then(split_value);
+            let (split_count, split_no) = (split_count.unwrap_or(1), split_no.unwrap_or(0));
+            if split_count != num_splits || split_no != idx {
+                crate::bail!(
+                    "gguf: unexpected split {split_no}/{split_count} at position {idx}/{num_splits}"
+                )
+            }
+            let tensor_infos = c.tensor_infos.into_iter().map(|(name, info)| {
+                let offset = start + c.tensor_data_offset + info.offset;
+                (name, TensorInfo { offset, ..info })
+            });
+            match content.as_mut() {
+                None => {
+                    content = Some(Self {
+                        magic: c.magic,
+                        metadata: c.metadata,
+                        tensor_infos: tensor_infos.collect(),
+                        tensor_data_offset: 0,
+                    })
+                }
+                Some(content) => {
+                    for (name, info) in tensor_infos {
+                        if content.tensor_infos.insert(name.clone(), info).is_some() {
+                            crate::bail!("gguf: tensor {name} appears in multiple splits")
+                        }
+                    }
+                }
+            }
+        }
+        // The constructor ensures that there is at least one split.
+        let content = content.expect("no split");
+        if let Some(count) = content.metadata.get("split.tensors.count") {
+            let count = match split_value(count) {
+                Some(count) => count,
+                None => crate::bail!("gguf: unexpected split.tensors.count {count:?}"),
+            };
+            if count != content.tensor_infos.len() {
+                crate::bail!(
+                    "gguf: expected {count} tensors over the splits, got {}",
+                    content.tensor_infos.len()
+                )
+            }
+        }
+        Ok(content)
+    }
+
+    /// Returns the header of the file, with the metadata and tensor descriptions sorted by name.
+    pub fn header(&self) -> Header {
+        let mut tensors = self
+            .tensor_infos
+            .iter()
+            .map(|(name, info)| {
+                let elem_count = info.shape.elem_count();
+                let size_in_bytes =
+                    elem_count / info.ggml_dtype.block_size() * info.ggml_dtype.type_size();
+                TensorDesc {
+                    name: name.clone(),
+                    ggml_dtype: info.ggml_dtype,
+                    shape: info.shape.clone(),
+                    offset: self.tensor_data_offset + info.offset,
+                    size_in_bytes,
+                }
+            })
+            .collect::<Vec<_>>();
+        tensors.sort_by(|a, b| a.name.cmp(&b.name));
+        Header {
+            magic: self.magic,
+            metadata: self
+                .metadata
+                .iter()
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect(),
+            tensors,
+        }
+    }
+
     pub fn tensor<R: std::io::Seek + std::io::Read>(
         &self,
         reader: &mut R,
@@ -481,6 +724,308 @@ impl Content {
     }
 }
 
+/// Returns the prefix, the zero based index and the number of splits for a file name following the
+/// `<prefix>-00001-of-00003.gguf` convention used for split files.
+fn parse_split_name(name: &str) -> Option<(&str, usize, usize)> {
+    let name = name.strip_suffix(".gguf")?;
+    let (rest, count) = name.rsplit_once("-of-")?;
+    let (prefix, index) = rest.rsplit_once('-')?;
+    let is_number = |s: &str| s.len() == 5 && s.bytes().all(|b| b.is_ascii_digit());
+    if !is_number(count) || !is_number(index) {
+        return None;
+    }
+    let (index, count) = (index.parse::<usize>().ok()?, count.parse::<usize>().ok()?);
+    if index == 0 || index > count {
+        return None;
+    }
+    Some((prefix, index - 1, count))
+}
+
+/// Returns the file names of all the splits when `name` is one of the splits of a model, e.g.
+/// `model-00001-of-00003.gguf`, and `name` itself otherwise.
+pub fn split_file_names(name: &str) -> Vec<String> {
+    match parse_split_name(name) {
+        None => vec![name.to_string()],
+        Some((prefix, _, count)) => (1..=count)
+            .map(|i| format!("{prefix}-{i:05}-of-{count:05}.gguf"))
+            .collect(),
+    }
+}
+
+// The split metadata is written as u16 for the indexes and i32 for the tensor count.
+fn split_value(v: &Value) -> Option<usize> {
+    match v {
+        Value::I8(v) => usize::try_from(*v).ok(),
+        Value::I16(v) => usize::try_from(*v).ok(),
+        Value::I32(v) => usize::try_from(*v).ok(),
+        Value::I64(v) => usize::try_from(*v).ok(),
+        v => v.to_u64().ok().map(|v| v as usize),
+    }
+}
+
+/// A reader over the concatenation of the splits of a gguf file, to be used with
+/// [`Content::read_split`]. A model that is not split can be read in the same way.
+pub struct SplitReader<R = std::fs::File> {
+    readers: Vec<R>,
+    starts: Vec<u64>,
+    len: u64,
+    pos: u64,
+}
+
+impl SplitReader<std::fs::File> {
+    /// Opens all the splits of the model that `p` is part of, `p` can be any of the splits.
+    pub fn open<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        let file_name = p.file_name().and_then(|f| f.to_str()).unwrap_or_default();
+        let readers = split_file_names(file_name)
+            .iter()
+            .map(|name| {
+                let p = p.with_file_name(name);
+                std::fs::File::open(&p).map_err(|e| crate::Error::from(e).with_path(p))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Self::new(readers)
+    }
+}
+
+impl<R: std::io::Seek + std::io::Read> SplitReader<R> {
+    /// Creates a reader from the splits, in order.
+    pub fn new(mut readers: Vec<R>) -> Result<Self> {
+        if readers.is_empty() {
+            crate::bail!("gguf: no split to read from")
+        }
+        let mut starts = Vec::with_capacity(readers.len());
+        let mut len = 0;
+        for reader in readers.iter_mut() {
+            starts.push(len);
+            len += reader.seek(std::io::SeekFrom::End(0))?;
+        }
+        Ok(Self {
+            readers,
+            starts,
+            len,
+            pos: 0,
+        })
+    }
+
+    pub fn num_splits(&self) -> usize {
+        self.readers.len()
+    }
+}
+
+impl<R: std::io::Seek + std::io::Read> std::io::Read for SplitReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.pos >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let idx = self.starts.partition_point(|&s| s <= self.pos) - 1;
+        let end = self.starts.get(idx + 1).copied().unwrap_or(self.len);
+        let max_len = usize::min(buf.len(), (end - self.pos) as usize);
+        let reader = &mut self.readers[idx];
+        reader.seek(std::io::SeekFrom::Start(self.pos - self.starts[idx]))?;
+        let n = reader.read(&mut buf[..max_len])?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: std::io::Seek + std::io::Read> std::io::Seek for SplitReader<R> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        let pos = match pos {
+            std::io::SeekFrom::Start(p) => Some(p),
+            std::io::SeekFrom::End(d) => self.len.checked_add_signed(d),
+            std::io::SeekFrom::Current(d) => self.pos.checked_add_signed(d),
+        };
+        match pos {
+            Some(pos) => {
+                self.pos = pos;
+                Ok(pos)
+            }
+            None => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "invalid seek to a negative position",
+            )),
+        }
+    }
+}
+
+impl std::fmt::Display for Value {
+    /// Long arrays, e.g. the tokenizer vocabulary, only have their first elements displayed.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const MAX_ARRAY_ELEMS: usize = 8;
+        match self {
+            Self::U8(v) => write!(f, "{v}"),
+            Self::I8(v) => write!(f, "{v}"),
+            Self::U16(v) => write!(f, "{v}"),
+            Self::I16(v) => write!(f, "{v}"),
+            Self::U32(v) => write!(f, "{v}"),
+            Self::I32(v) => write!(f, "{v}"),
+            Self::U64(v) => write!(f, "{v}"),
+            Self::I64(v) => write!(f, "{v}"),
+            Self::F32(v) => write!(f, "{v}"),
+            Self::F64(v) => write!(f, "{v}"),
+            Self::Bool(v) => write!(f, "{v}"),
+            Self::String(v) => write!(f, "{v:?}"),
+            Self::Array(vs) => {
+                write!(f, "[")?;
+                for (i, v) in vs.iter().take(MAX_ARRAY_ELEMS).enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "{v}")?;
+                }
+                if vs.len() > MAX_ARRAY_ELEMS {
+                    write!(f, ", ... ({} elements)", vs.len())?;
+                }
+                write!(f, "]")
+            }
+        }
+    }
+}
+
+/// The description of a tensor stored in a gguf file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TensorDesc {
+    pub name: String,
+    pub ggml_dtype: GgmlDType,
+    pub shape: crate::Shape,
+    pub offset: u64,
+    pub size_in_bytes: usize,
+}
+
+/// The header of a gguf file with the metadata and the tensors sorted by name, mostly useful to
+/// inspect or compare files.
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub magic: VersionedMagic,
+    pub metadata: BTreeMap<String, Value>,
+    pub tensors: Vec<TensorDesc>,
+}
+
+/// A difference between two gguf headers, see [`Header::diff`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum HeaderDiff {
+    Magic(VersionedMagic, VersionedMagic),
+    MetadataLhsOnly(String, Value),
+    MetadataRhsOnly(String, Value),
+    Metadata(String, Value, Value),
+    TensorLhsOnly(TensorDesc),
+    TensorRhsOnly(TensorDesc),
+    /// The tensor exists on both sides with a different dtype or shape.
+    Tensor(TensorDesc, TensorDesc),
+}
+
+impl std::fmt::Display for HeaderDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let tensor = |t: &TensorDesc| format!("{:?} {:?}", t.ggml_dtype, t.shape);
+        match self {
+            Self::Magic(lhs, rhs) => write!(f, "~ version: {lhs:?} -> {rhs:?}"),
+            Self::MetadataLhsOnly(k, v) => write!(f, "- {k}: {v}"),
+            Self::MetadataRhsOnly(k, v) => write!(f, "+ {k}: {v}"),
+            Self::Metadata(k, lhs, rhs) => write!(f, "~ {k}: {lhs} -> {rhs}"),
+            Self::TensorLhsOnly(t) => write!(f, "- {}: {}", t.name, tensor(t)),
+            Self::TensorRhsOnly(t) => write!(f, "+ {}: {}", t.name, tensor(t)),
+            Self::Tensor(lhs, rhs) => {
+                write!(f, "~ {}: {} -> {}", lhs.name, tensor(lhs), tensor(rhs))
+            }
+        }
+    }
+}
+
+impl Header {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        self.metadata.get(key)
+    }
+
+    /// The `general.architecture` metadata.
+    pub fn architecture(&self) -> Option<&str> {
+        match self.metadata.get("general.architecture") {
+            Some(Value::String(arch)) => Some(arch.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Returns the architecture specific metadata, e.g. `block_count` for `llama.block_count`.
+    pub fn arch_get(&self, key: &str) -> Option<&Value> {
+        let arch = self.architecture()?;
+        self.metadata.get(&format!("{arch}.{key}"))
+    }
+
+    pub fn tensor(&self, name: &str) -> Option<&TensorDesc> {
+        self.tensors.iter().find(|t| t.name == name)
+    }
+
+    pub fn total_size_in_bytes(&self) -> usize {
+        self.tensors.iter().map(|t| t.size_in_bytes).sum()
+    }
+
+    /// The number of tensors and their total size for each dtype, by decreasing size.
+    pub fn dtype_summary(&self) -> Vec<(GgmlDType, usize, usize)> {
+        let mut summary: Vec<(GgmlDType, usize, usize)> = vec![];
+        for t in self.tensors.iter() {
+            match summary.iter_mut().find(|s| s.0 == t.ggml_dtype) {
+                Some(s) => {
+                    s.1 += 1;
+                    s.2 += t.size_in_bytes
+                }
+                None => summary.push((t.ggml_dtype, 1, t.size_in_bytes)),
+            }
+        }
+        summary.sort_by_key(|s| std::cmp::Reverse(s.2));
+        summary
+    }
+
+    /// Lists the differences from `self` to `rhs`, tensor offsets are not compared.
+    pub fn diff(&self, rhs: &Self) -> Vec<HeaderDiff> {
+        let mut diffs = vec![];
+        if self.magic != rhs.magic {
+            diffs.push(HeaderDiff::Magic(self.magic, rhs.magic))
+        }
+        for (k, v) in self.metadata.iter() {
+            match rhs.metadata.get(k) {
+                None => diffs.push(HeaderDiff::MetadataLhsOnly(k.clone(), v.clone())),
+                Some(rv) if rv != v => {
+                    diffs.push(HeaderDiff::Metadata(k.clone(), v.clone(), rv.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        for (k, v) in rhs.metadata.iter() {
+            if !self.metadata.contains_key(k) {
+                diffs.push(HeaderDiff::MetadataRhsOnly(k.clone(), v.clone()))
+            }
+        }
+        for t in self.tensors.iter() {
+            match rhs.tensor(&t.name) {
+                None => diffs.push(HeaderDiff::TensorLhsOnly(t.clone())),
+                Some(rt) if rt.ggml_dtype != t.ggml_dtype || rt.shape != t.shape => {
+                    diffs.push(HeaderDiff::Tensor(t.clone(), rt.clone()))
+                }
+                Some(_) => {}
+            }
+        }
+        for t in rhs.tensors.iter() {
+            if self.tensor(&t.name).is_none() {
+                diffs.push(HeaderDiff::TensorRhsOnly(t.clone()))
+            }
+        }
+        diffs
+    }
+}
+
+/// The alignment set by a `general.alignment` metadata value, values of other types are ignored.
+fn alignment(value: &Value) -> u64 {
+    match value {
+        Value::U8(v) => *v as u64,
+        Value::U16(v) => *v as u64,
+        Value::U32(v) => *v as u64,
+        Value::I8(v) if *v >= 0 => *v as u64,
+        Value::I16(v) if *v >= 0 => *v as u64,
+        Value::I32(v) if *v >= 0 => *v as u64,
+        _ => DEFAULT_ALIGNMENT,
+    }
+}
+
 fn write_string<W: std::io::Write>(w: &mut W, str: &str) -> Result<()> {
     let bytes = str.as_bytes();
     w.write_u64::<LittleEndian>(bytes.len() as u64)?;
@@ -488,13 +1033,68 @@ fn write_string<W: std::io::Write>(w: &mut W, str: &str) -> Result<()> {
     Ok(())
 }
 
+/// The number of padding bytes after `size` bytes to reach a multiple of `alignment`.
+fn padding(size: u64, alignment: u64) -> u64 {
+    size.div_ceil(alignment) * alignment - size
+}
+
+/// The maximum length of a tensor name, longer names are rejected by llama.cpp.
+pub const MAX_TENSOR_NAME_LEN: usize = 64;
+
+/// Writes a gguf v3 file with the given metadata and tensors, e.g. to export a model quantized
+/// or fine-tuned with candle so that it can be used by llama.cpp and the other gguf tools.
+///
+/// The tensor data is aligned on the `general.alignment` metadata when set, on
+/// [`DEFAULT_ALIGNMENT`] otherwise. The metadata keys and the tensor names must be unique, the
+/// tensors are written in the order of `tensors`.
+///
+/// ```rust
+/// use candle_core::quantized::{gguf_file, GgmlDType, QTensor};
+/// use candle_core::{Device, Tensor};
+/// # fn main() -> candle_core::Result<()> {
+/// let w = Tensor::randn(0f32, 1., (4, 64), &Device::Cpu)?;
+/// let w = QTensor::quantize(&w, GgmlDType::Q8_0)?;
+/// let arch = gguf_file::Value::String("llama".to_string());
+/// let mut file = std::io::Cursor::new(vec![]);
+/// gguf_file::write(&mut file, &[("general.architecture", &arch)], &[("w", &w)])?;
+///
+/// file.set_position(0);
+/// let content = gguf_file::Content::read(&mut file)?;
+/// assert_eq!(content.magic, gguf_file::VersionedMagic::GgufV3);
+/// assert_eq!(content.tensor(&mut file, "w", &Device::Cpu)?.dtype(), GgmlDType::Q8_0);
+/// # Ok(())
+/// # }
+/// ```
 pub fn write<W: std::io::Seek + std::io::Write>(
     w: &mut W,
     metadata: &[(&str, &Value)],
     tensors: &[(&str, &QTensor)],
 ) -> Result<()> {
+    let mut keys = std::collections::HashSet::new();
+    for (name, _) in metadata.iter() {
+        if !keys.insert(*name) {
+            crate::bail!("duplicate metadata key {name}")
+        }
+    }
+    let mut names = std::collections::HashSet::new();
+    for (name, _) in tensors.iter() {
+        if !names.insert(*name) {
+            Err(GgufError::DuplicateTensor(name.to_string()))?
+        }
+        if name.len() > MAX_TENSOR_NAME_LEN {
+            crate::bail!("tensor name {name} is longer than {MAX_TENSOR_NAME_LEN} bytes")
+        }
+    }
+    let alignment = metadata
+        .iter()
+        .find(|(name, _)| *name == "general.alignment")
+        .map_or(DEFAULT_ALIGNMENT, |(_, v)| alignment(v));
+    if !alignment.is_power_of_two() {
+        Err(GgufError::InvalidAlignment(alignment))?
+    }
+
     w.write_u32::<LittleEndian>(0x46554747)?;
-    w.write_u32::<LittleEndian>(2)?; // version 2.
+    w.write_u32::<LittleEndian>(3)?; // version 3.
     w.write_u64::<LittleEndian>(tensors.len() as u64)?;
     w.write_u64::<LittleEndian>(metadata.len() as u64)?;
     for (name, value) in metadata.iter() {
@@ -502,7 +1102,7 @@ pub fn write<W: std::io::Seek + std::io::Write>(
         w.write_u32::<LittleEndian>(value.value_type().to_u32())?;
         value.write(w)?;
     }
-    let mut offset = 0usize;
+    let mut offset = 0u64;
     let mut offsets = Vec::with_capacity(tensors.len());
     for (name, tensor) in tensors.iter() {
         write_string(w, name)?;
@@ -512,28 +1112,24 @@ pub fn write<W: std::io::Seek + std::io::Write>(
             w.write_u64::<LittleEndian>(dim as u64)?;
         }
         w.write_u32::<LittleEndian>(tensor.dtype().to_u32())?;
-        w.write_u64::<LittleEndian>(offset as u64)?;
+        w.write_u64::<LittleEndian>(offset)?;
         offsets.push(offset);
-        let size_in_bytes = tensor.storage_size_in_bytes();
-        let padding = 31 - (31 + size_in_bytes) % 32;
-        offset += size_in_bytes + padding;
-    }
-    let pos = w.stream_position()? as usize;
-    let padding = 31 - (31 + pos) % 32;
-    w.write_all(&vec![0u8; padding])?;
-    let tensor_start_pos = w.stream_position()? as usize;
+        let size_in_bytes = tensor.storage_size_in_bytes() as u64;
+        offset += size_in_bytes + padding(size_in_bytes, alignment);
+    }
+    let pos = w.stream_position()?;
+    w.write_all(&vec![0u8; padding(pos, alignment) as usize])?;
+    let tensor_start_pos = w.stream_position()?;
     for (offset, (_name, tensor)) in offsets.iter().zip(tensors.iter()) {
-        let pos = w.stream_position()? as usize;
+        let pos = w.stream_position()?;
         if tensor_start_pos + offset != pos {
             crate::bail!(
                 "internal error, unexpected current position {tensor_start_pos} {offset} {pos}"
             )
         }
         let data = tensor.data()?;
-        let size_in_bytes = data.len();
         w.write_all(&data)?;
-        let padding = 31 - (31 + size_in_bytes) % 32;
-        w.write_all(&vec![0u8; padding])?;
+        w.write_all(&vec![0u8; padding(data.len() as u64, alignment) as usize])?;
     }
     Ok(())
 }
@@ -0,0 +1,102 @@
+//! Support for the importance matrix files produced by the llama.cpp `imatrix` tool.
+//!
+//! An importance matrix records, for each weight of a model, the mean squared value of each of
+//! its input channels over a calibration dataset. Quantizing with
+//! [`QTensor::quantize_imatrix`](super::QTensor::quantize_imatrix) weights the quantization
+//! error of each column of the weights by this importance, which reduces the impact of the
+//! quantization on the model outputs, in particular for the low bit formats.
+//!
+//! The file starts with the number of entries as an `i32`, then each entry has the tensor name
+//! as an `i32` length followed by the utf8 bytes, the number of calls as an `i32` and the sums of
+//! the squared activations as an `i32` length followed by the `f32` values, all little endian.
+//! The dataset information that llama.cpp may append after the entries is ignored.
+//!
+//! ```rust
+//! use candle_core::quantized::imatrix_file::{self, Entry};
+//! # fn main() -> candle_core::Result<()> {
+//! let entry = Entry { ncall: 4, values: vec![4., 8.] };
+//! let mut buffer = std::io::Cursor::new(vec![]);
+//! imatrix_file::write(&mut buffer, &[("blk.0.attn_q.weight", &entry)])?;
+//! buffer.set_position(0);
+//! let entries = imatrix_file::read(&mut buffer)?;
+//! assert_eq!(entries["blk.0.attn_q.weight"].importance(), [1., 2.]);
+//! # Ok(())
+//! # }
+//! ```
+use crate::Result;
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use std::collections::HashMap;
+
+/// The statistics collected for the inputs of a weight.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    /// The number of times the weight has been used.
+    pub ncall: u32,
+    /// The sum over the calls of the mean squared value of each input channel.
+    pub values: Vec<f32>,
+}
+
+impl Entry {
+    /// The importance of each input channel, i.e. its mean squared value.
+    pub fn importance(&self) -> Vec<f32> {
+        let ncall = self.ncall.max(1) as f32;
+        self.values.iter().map(|v| v / ncall).collect()
+    }
+}
+
+fn read_len<R: std::io::Read>(reader: &mut R, what: &str) -> Result<usize> {
+    let len = reader.read_i32::<LittleEndian>()?;
+    if len < 0 {
+        crate::bail!("imatrix: invalid {what} {len}")
+    }
+    Ok(len as usize)
+}
+
+/// Reads the entries of an importance matrix file, indexed by tensor name.
+pub fn read<R: std::io::Read>(reader: &mut R) -> Result<HashMap<String, Entry>> {
+    let n_entries = read_len(reader, "number of entries")?;
+    let mut entries = HashMap::with_capacity(n_entries);
+    for _ in 0..n_entries {
+        let len = read_len(reader, "name length")?;
+        let mut name = vec![0u8; len];
+        reader.read_exact(&mut name)?;
+        let name = String::from_utf8_lossy(&name).into_owned();
+        let ncall = read_len(reader, "number of calls")? as u32;
+        let nval = read_len(reader, "number of values")?;
+        let mut values = vec![0f32; nval];
+        reader.read_f32_into::<LittleEndian>(&mut values)?;
+        if entries
+            .insert(name.clone(), Entry { ncall, values })
+            .is_some()
+        {
+            crate::bail!("imatrix: duplicate entry {name}")
+        }
+    }
+    Ok(entries)
+}
+
+/// Writes the `entries` in the llama.cpp format.
+pub fn write<W: std::io::Write>(writer: &mut W, entries: &[(&str, &Entry)]) -> Result<()> {
+    writer.write_i32::<LittleEndian>(entries.len() as i32)?;
+    for (name, entry) in entries.iter() {
+        writer.write_i32::<LittleEndian>(name.len() as i32)?;
+        writer.write_all(name.as_bytes())?;
+        writer.write_i32::<LittleEndian>(entry.ncall as i32)?;
+        writer.write_i32::<LittleEndian>(entry.values.len() as i32)?;
+        for &v in entry.values.iter() {
+            writer.write_f32::<LittleEndian>(v)?;
+        }
+    }
+    Ok(())
+}
+
+/// Loads an importance matrix file and returns the importance of the input channels of each
+/// tensor, see [`Entry::importance`].
+pub fn load<P: AsRef<std::path::Path>>(p: P) -> Result<HashMap<String, Vec<f32>>> {
+    let mut reader = std::io::BufReader::new(std::fs::File::open(p)?);
+    let entries = read(&mut reader)?;
+    Ok(entries
+        .into_iter()
+        .map(|(name, entry)| (name, entry.importance()))
+        .collect())
+}
@@ -1,12 +1,14 @@
+use super::iq_grids::{IQ2XS_GRID, IQ3S_GRID, KMASK_IQ2XS, KSIGNS_IQ2XS};
 use super::utils::{
     get_scale_min_k4, group_for_dequantization, group_for_quantization, make_q3_quants,
-    make_qkx1_quants, make_qx_quants, nearest_int,
+    make_qkx1_quants, make_qkx3_quants, make_qp_quants, make_qx_quants, nearest_int,
 };
 use super::GgmlDType;
 use crate::Result;
 use byteorder::{ByteOrder, LittleEndian};
 use half::f16;
 use rayon::prelude::*;
+use std::sync::OnceLock;
 
 // Default to QK_K 256 rather than 64.
 pub const QK_K: usize = 256;
@@ -18,6 +20,12 @@ pub const QK5_0: usize = 32;
 pub const QK5_1: usize = 32;
 pub const QK8_0: usize = 32;
 pub const QK8_1: usize = 32;
+pub const QK4_NL: usize = 32;
+
+// The non-linear levels of IQ4_NL, denser around zero where most of the weights are.
+const KVALUES_IQ4NL: [i8; 16] = [
+    -127, -104, -83, -65, -49, -35, -22, -10, 1, 13, 25, 38, 53, 69, 89, 113,
+];
 
 pub trait GgmlType: Sized + Clone + Send + Sync {
     const DTYPE: GgmlDType;
@@ -31,6 +39,20 @@ pub trait GgmlType: Sized + Clone + Send + Sync {
     fn to_float(xs: &[Self], ys: &mut [f32]) -> Result<()>;
     fn from_float(xs: &[f32], ys: &mut [Self]) -> Result<()>;
 
+    /// Quantizes the rows of `xs`, each with `n_per_row` values, weighting the quantization error
+    /// of each column by its importance, usually the mean squared value of the corresponding input
+    /// activation. The types without an importance aware quantization ignore `imatrix` and use
+    /// [`Self::from_float`].
+    fn from_float_imatrix(
+        xs: &[f32],
+        ys: &mut [Self],
+        imatrix: &[f32],
+        n_per_row: usize,
+    ) -> Result<()> {
+        let _ = (imatrix, n_per_row);
+        Self::from_float(xs, ys)
+    }
+
     /// Dot product used as a building block for quantized mat-mul.
     /// n is the number of elements to be considered.
     fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32>;
@@ -92,6 +114,34 @@ pub struct BlockQ8_1 {
 }
 const _: () = assert!(std::mem::size_of::<BlockQ8_1>() == 36);
 
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockIQ4NL {
+    pub(crate) d: f16,
+    pub(crate) qs: [u8; QK4_NL / 2],
+}
+const _: () = assert!(std::mem::size_of::<BlockIQ4NL>() == 18);
+
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockIQ2XS {
+    pub(crate) d: f16,
+    pub(crate) qs: [u16; QK_K / 8],
+    pub(crate) scales: [u8; QK_K / 32],
+}
+const _: () = assert!(std::mem::size_of::<BlockIQ2XS>() == 74);
+
+#[derive(Debug, Clone, PartialEq)]
+#[repr(C)]
+pub struct BlockIQ3S {
+    pub(crate) d: f16,
+    pub(crate) qs: [u8; QK_K / 4],
+    pub(crate) qh: [u8; QK_K / 32],
+    pub(crate) signs: [u8; QK_K / 8],
+    pub(crate) scales: [u8; QK_K / 64],
+}
+const _: () = assert!(std::mem::size_of::<BlockIQ3S>() == 110);
+
 #[derive(Debug, Clone, PartialEq)]
 #[repr(C)]
 pub struct BlockQ2K {
@@ -1149,6 +1199,132 @@ impl GgmlType for BlockQ3K {
     }
 }
 
+// The importance of the values of block `block_idx`, the blocks being laid out row by row.
+fn imatrix_block<T: GgmlType>(
+    imatrix: &[f32],
+    n_per_row: usize,
+    block_idx: usize,
+) -> Result<&[f32]> {
+    if imatrix.len() != n_per_row || n_per_row % T::BLCK_SIZE != 0 {
+        crate::bail!(
+            "quantize {:?}: the importance matrix has {} values for rows of {n_per_row}",
+            T::DTYPE,
+            imatrix.len()
+        )
+    }
+    let start = (block_idx * T::BLCK_SIZE) % n_per_row;
+    Ok(&imatrix[start..start + T::BLCK_SIZE])
+}
+
+// The scales and mins of the 8 sub-blocks of a Q4K or Q5K block, in the 6 bits packed layout,
+// together with the block scale and min. The error on each value is weighted by its importance
+// `qw`, scaled by the magnitude of the value.
+fn make_k4_scales_imatrix(nmax: i32, x: &[f32], qw: &[f32]) -> ([u8; K_SCALE_SIZE], f16, f16) {
+    let sum_x2: f32 = x.iter().map(|v| v * v).sum();
+    let sigma2 = 2. * sum_x2 / QK_K as f32;
+    let mut sw = [0f32; QK_K / 32];
+    let mut mins = [0f32; QK_K / 32];
+    let mut scales = [0f32; QK_K / 32];
+    let mut weights = [0f32; 32];
+    for j in 0..QK_K / 32 {
+        let x = &x[32 * j..32 * (j + 1)];
+        let qw = &qw[32 * j..32 * (j + 1)];
+        for ((w, &q), &x) in weights.iter_mut().zip(qw.iter()).zip(x.iter()) {
+            *w = q * (sigma2 + x * x).sqrt()
+        }
+        sw[j] = weights.iter().sum();
+        (scales[j], mins[j]) = make_qkx3_quants(nmax, x, &weights, -0.9, 0.05, 36);
+    }
+    let mut ls = [0u8; QK_K / 32];
+    let mut lm = [0u8; QK_K / 32];
+    let d_block = make_qp_quants(63, &scales, &mut ls, &sw);
+    let m_block = make_qp_quants(63, &mins, &mut lm, &sw);
+    let mut packed = [0u8; K_SCALE_SIZE];
+    for j in 0..QK_K / 32 {
+        let (ls, lm) = (ls[j], lm[j]);
+        if j < 4 {
+            packed[j] = ls;
+            packed[j + 4] = lm;
+        } else {
+            packed[j + 4] = (ls & 0xF) | ((lm & 0xF) << 4);
+            packed[j - 4] |= (ls >> 4) << 6;
+            packed[j] |= (lm >> 4) << 6;
+        }
+    }
+    (packed, f16::from_f32(d_block), f16::from_f32(m_block))
+}
+
+impl BlockQ4K {
+    // Quantizes the values of the block once its scales are set.
+    fn quantize_values(&mut self, x: &[f32]) {
+        let mut l: [u8; QK_K] = [0; QK_K];
+
+        for j in 0..QK_K / 32 {
+            let (sc, m) = get_scale_min_k4(j, &self.scales);
+            let d = self.d.to_f32() * sc as f32;
+            if d != 0.0 {
+                let dm = self.dmin.to_f32() * m as f32;
+                for ii in 0..32 {
+                    let l_val = nearest_int((x[32 * j + ii] + dm) / d);
+                    l[32 * j + ii] = l_val.clamp(0, 15) as u8;
+                }
+            }
+        }
+
+        let q = &mut self.qs;
+        for j in (0..QK_K).step_by(64) {
+            for l_val in 0..32 {
+                let offset_index = (j / 64) * 32 + l_val;
+                q[offset_index] = l[j + l_val] | (l[j + l_val + 32] << 4);
+            }
+        }
+    }
+}
+
+impl BlockQ5K {
+    // Quantizes the values of the block once its scales are set.
+    fn quantize_values(&mut self, x: &[f32]) {
+        let mut l: [u8; QK_K] = [0; QK_K];
+        for j in 0..QK_K / 32 {
+            let (sc, m) = get_scale_min_k4(j, &self.scales);
+            let d = self.d.to_f32() * sc as f32;
+            if d == 0.0 {
+                continue;
+            }
+            let dm = self.dmin.to_f32() * m as f32;
+            for ii in 0..32 {
+                let ll = nearest_int((x[32 * j + ii] + dm) / d);
+                l[32 * j + ii] = ll.clamp(0, 31) as u8;
+            }
+        }
+
+        let qh = &mut self.qh;
+        let ql = &mut self.qs;
+        qh.fill(0);
+
+        let mut m1 = 1;
+        let mut m2 = 2;
+        for n in (0..QK_K).step_by(64) {
+            let offset = (n / 64) * 32;
+            for j in 0..32 {
+                let mut l1 = l[n + j];
+                if l1 > 15 {
+                    l1 -= 16;
+                    qh[j] |= m1;
+                }
+                let mut l2 = l[n + j + 32];
+                if l2 > 15 {
+                    l2 -= 16;
+                    qh[j] |= m2;
+                }
+                ql[offset + j] = l1 | (l2 << 4);
+            }
+            m1 <<= 2;
+            m2 <<= 2;
+        }
+    }
+}
+
 impl GgmlType for BlockQ4K {
     const DTYPE: GgmlDType = GgmlDType::Q4K;
     const BLCK_SIZE: usize = QK_K;
@@ -1284,28 +1460,22 @@ impl GgmlType for BlockQ4K {
 
             block.d = f16::from_f32(max_scale / 63.0);
             block.dmin = f16::from_f32(max_min / 63.0);
+            block.quantize_values(x);
+        }
+        Ok(())
+    }
 
-            let mut l: [u8; QK_K] = [0; QK_K];
-
-            for j in 0..QK_K / 32 {
-                let (sc, m) = get_scale_min_k4(j, &block.scales);
-                let d = block.d.to_f32() * sc as f32;
-                if d != 0.0 {
-                    let dm = block.dmin.to_f32() * m as f32;
-                    for ii in 0..32 {
-                        let l_val = nearest_int((x[32 * j + ii] + dm) / d);
-                        l[32 * j + ii] = l_val.clamp(0, 15) as u8;
-                    }
-                }
-            }
-
-            let q = &mut block.qs;
-            for j in (0..QK_K).step_by(64) {
-                for l_val in 0..32 {
-                    let offset_index = (j / 64) * 32 + l_val;
-                    q[offset_index] = l[j + l_val] | (l[j + l_val + 32] << 4);
-                }
-            }
+    // https://github.com/ggerganov/llama.cpp/blob/a5e7dbd6141128bfa3c40a19c2945a181df625d3/ggml/src/ggml-quants.c#L2793
+    fn from_float_imatrix(
+        xs: &[f32],
+        ys: &mut [Self],
+        imatrix: &[f32],
+        n_per_row: usize,
+    ) -> Result<()> {
+        for (block_idx, (block, x)) in group_for_quantization(xs, ys)?.into_iter().enumerate() {
+            let qw = imatrix_block::<Self>(imatrix, n_per_row, block_idx)?;
+            (block.scales, block.d, block.dmin) = make_k4_scales_imatrix(15, x, qw);
+            block.quantize_values(x);
         }
         Ok(())
     }
@@ -1480,47 +1650,24 @@ impl GgmlType for BlockQ5K {
             }
             block.d = f16::from_f32(max_scale / 63.0);
             block.dmin = f16::from_f32(max_min / 63.0);
+            block.quantize_values(x);
+        }
 
-            let mut l: [u8; QK_K] = [0; QK_K];
-            for j in 0..QK_K / 32 {
-                let (sc, m) = get_scale_min_k4(j, &block.scales);
-                let d = block.d.to_f32() * sc as f32;
-                if d == 0.0 {
-                    continue;
-                }
-                let dm = block.dmin.to_f32() * m as f32;
-                for ii in 0..32 {
-                    let ll = nearest_int((x[32 * j + ii] + dm) / d);
-                    l[32 * j + ii] = ll.clamp(0, 31) as u8;
-                }
-            }
-
-            let qh = &mut block.qh;
-            let ql = &mut block.qs;
-            qh.fill(0);
+        Ok(())
+    }
 
-            let mut m1 = 1;
-            let mut m2 = 2;
-            for n in (0..QK_K).step_by(64) {
-                let offset = (n / 64) * 32;
-                for j in 0..32 {
-                    let mut l1 = l[n + j];
-                    if l1 > 15 {
-                        l1 -= 16;
-                        qh[j] |= m1;
-                    }
-                    let mut l2 = l[n + j + 32];
-                    if l2 > 15 {
-                        l2 -= 16;
-                        qh[j] |= m2;
-                    }
-                    ql[offset + j] = l1 | (l2 << 4);
-                }
-                m1 <<= 2;
-                m2 <<= 2;
-            }
+    // https://github.com/ggerganov/llama.cpp/blob/a5e7dbd6141128bfa3c40a19c2945a181df625d3/ggml/src/ggml-quants.c#L3022
+    fn from_float_imatrix(
+        xs: &[f32],
+        ys: &mut [Self],
+        imatrix: &[f32],
+        n_per_row: usize,
+    ) -> Result<()> {
+        for (block_idx, (block, x)) in group_for_quantization(xs, ys)?.into_iter().enumerate() {
+            let qw = imatrix_block::<Self>(imatrix, n_per_row, block_idx)?;
+            (block.scales, block.d, block.dmin) = make_k4_scales_imatrix(31, x, qw);
+            block.quantize_values(x);
         }
-
         Ok(())
     }
 
@@ -1838,6 +1985,746 @@ impl GgmlType for BlockQ8K {
     }
 }
 
+// The index of the IQ4_NL level closest to `x`.
+fn best_index_iq4nl(x: f32) -> usize {
+    let values = &KVALUES_IQ4NL;
+    if x <= values[0] as f32 {
+        return 0;
+    }
+    if x >= values[15] as f32 {
+        return 15;
+    }
+    let (mut ml, mut mu) = (0, 15);
+    while mu - ml > 1 {
+        let mav = (ml + mu) / 2;
+        if x < values[mav] as f32 {
+            mu = mav
+        } else {
+            ml = mav
+        }
+    }
+    if x - (values[mu - 1] as f32) < values[mu] as f32 - x {
+        mu - 1
+    } else {
+        mu
+    }
+}
+
+impl BlockIQ4NL {
+    // https://github.com/ggerganov/llama.cpp/blob/0e4802b2ecbaab04b4f829fde4a3096ca19c84b5/ggml/src/ggml-quants.c#L14464
+    // The block scale minimizes the error weighted by `weights`, the scales mapping the extreme
+    // value to the levels around the lowest one are tried.
+    fn quantize_block(x: &[f32], weights: &[f32]) -> Self {
+        const NTRY: i32 = 7;
+        let mut block = Self {
+            d: f16::ZERO,
+            qs: [0u8; QK4_NL / 2],
+        };
+        let (mut amax, mut max) = (0f32, 0f32);
+        for &v in x.iter() {
+            if v.abs() > amax {
+                amax = v.abs();
+                max = v;
+            }
+        }
+        if amax < 1e-15 {
+            return block;
+        }
+        let values = &KVALUES_IQ4NL;
+        let weighted_sums = |id: f32| {
+            let (mut sumqx, mut sumq2) = (0f32, 0f32);
+            for (&v, &w) in x.iter().zip(weights.iter()) {
+                let q = values[best_index_iq4nl(id * v)] as f32;
+                sumqx += w * q * v;
+                sumq2 += w * q * q;
+            }
+            (sumqx, sumq2)
+        };
+        let (sumqx, sumq2) = weighted_sums(-(values[0] as f32) / max);
+        let mut d = if sumq2 > 0. { sumqx / sumq2 } else { 0. };
+        let mut best = d * sumqx;
+        for itry in -NTRY..=NTRY {
+            let id = (itry as f32 + values[0] as f32) / max;
+            let (sumqx, sumq2) = weighted_sums(id);
+            if sumq2 > 0. && sumqx * sumqx > best * sumq2 {
+                d = sumqx / sumq2;
+                best = d * sumqx;
+            }
+        }
+        let id = if d != 0. { 1. / d } else { 0. };
+        for j in 0..QK4_NL / 2 {
+            let l0 = best_index_iq4nl(id * x[j]) as u8;
+            let l1 = best_index_iq4nl(id * x[j + QK4_NL / 2]) as u8;
+            block.qs[j] = l0 | (l1 << 4);
+        }
+        block.d = f16::from_f32(d);
+        block
+    }
+}
+
+impl GgmlType for BlockIQ4NL {
+    const DTYPE: GgmlDType = GgmlDType::IQ4NL;
+    const BLCK_SIZE: usize = QK4_NL;
+    type VecDotType = BlockQ8_0;
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) -> Result<()> {
+        let k = ys.len();
+        if k % QK4_NL != 0 {
+            crate::bail!("dequantize_row_iq4_nl: {k} is not divisible by {QK4_NL}")
+        }
+        for (x, ys) in xs.iter().zip(ys.chunks_exact_mut(QK4_NL)) {
+            let d = x.d.to_f32();
+            for (j, &q) in x.qs.iter().enumerate() {
+                ys[j] = d * KVALUES_IQ4NL[(q & 0xF) as usize] as f32;
+                ys[j + QK4_NL / 2] = d * KVALUES_IQ4NL[(q >> 4) as usize] as f32;
+            }
+        }
+        Ok(())
+    }
+
+    fn from_float(xs: &[f32], ys: &mut [Self]) -> Result<()> {
+        if xs.len() != ys.len() * QK4_NL {
+            crate::bail!(
+                "quantize_row_iq4_nl: size mismatch {} {}",
+                xs.len(),
+                ys.len()
+            )
+        }
+        for (x, y) in xs.chunks_exact(QK4_NL).zip(ys.iter_mut()) {
+            let weights = x.iter().map(|v| v * v).collect::<Vec<_>>();
+            *y = Self::quantize_block(x, &weights)
+        }
+        Ok(())
+    }
+
+    fn from_float_imatrix(
+        xs: &[f32],
+        ys: &mut [Self],
+        imatrix: &[f32],
+        n_per_row: usize,
+    ) -> Result<()> {
+        if xs.len() != ys.len() * QK4_NL {
+            crate::bail!(
+                "quantize_row_iq4_nl: size mismatch {} {}",
+                xs.len(),
+                ys.len()
+            )
+        }
+        for (block_idx, (x, y)) in xs.chunks_exact(QK4_NL).zip(ys.iter_mut()).enumerate() {
+            let qw = imatrix_block::<Self>(imatrix, n_per_row, block_idx)?;
+            let sigma2 = x.iter().map(|v| v * v).sum::<f32>() / QK4_NL as f32;
+            let weights = x
+                .iter()
+                .zip(qw.iter())
+                .map(|(v, w)| w * (sigma2 + v * v).sqrt())
+                .collect::<Vec<_>>();
+            *y = Self::quantize_block(x, &weights)
+        }
+        Ok(())
+    }
+
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        if n % QK4_NL != 0 {
+            crate::bail!("vec_dot_iq4_nl_q8_0: {n} is not divisible by {QK4_NL}")
+        }
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut sum_i = 0i32;
+            for (j, &q) in x.qs.iter().enumerate() {
+                let v0 = KVALUES_IQ4NL[(q & 0xF) as usize] as i32;
+                let v1 = KVALUES_IQ4NL[(q >> 4) as usize] as i32;
+                sum_i += v0 * y.qs[j] as i32 + v1 * y.qs[j + QK4_NL / 2] as i32
+            }
+            sumf += sum_i as f32 * f16::to_f32(x.d) * f16::to_f32(y.d)
+        }
+        Ok(sumf)
+    }
+}
+
+// The codebook of a lattice quantization, each entry holding the odd levels `2l + 1` of N values.
+// As in `iq2xs_init_impl` and `iq3xs_init_impl` from ggml, the points that are not in the
+// codebook are mapped to the entries at the `nwant` closest distances.
+struct IqLattice<const N: usize> {
+    grid: Vec<[i8; N]>,
+    bits: usize,
+    // The index in `grid` of each point, the levels being packed on `bits` bits, or
+    // `-(offset + 1)` for the points that are not in the codebook, `neighbours[offset]` being
+    // their number of neighbours and the following values the neighbour indexes.
+    map: Vec<i32>,
+    neighbours: Vec<u16>,
+}
+
+impl<const N: usize> IqLattice<N> {
+    // Only the points with levels up to `max_level` get some neighbours, the quantization never
+    // looks up the other ones.
+    fn new(grid: Vec<[i8; N]>, bits: usize, max_level: usize, nwant: usize) -> Self {
+        let mut lattice = Self {
+            grid,
+            bits,
+            map: vec![-1; 1 << (bits * N)],
+            neighbours: vec![],
+        };
+        for (index, entry) in lattice.grid.iter().enumerate() {
+            let u = lattice.pack(&entry.map(|q| (q - 1) / 2));
+            lattice.map[u] = index as i32
+        }
+        let mut dist2 = Vec::with_capacity(lattice.grid.len());
+        for u in 0..lattice.map.len() {
+            if lattice.map[u] >= 0 {
+                continue;
+            }
+            let levels: [usize; N] = std::array::from_fn(|k| (u >> (bits * k)) & ((1 << bits) - 1));
+            if levels.iter().any(|&l| l > max_level) {
+                continue;
+            }
+            dist2.clear();
+            for (index, entry) in lattice.grid.iter().enumerate() {
+                let d2: i32 = entry
+                    .iter()
+                    .zip(levels.iter())
+                    .map(|(&q, &l)| (q as i32 - 2 * l as i32 - 1).pow(2))
+                    .sum();
+                dist2.push((d2, index as u16))
+            }
+            dist2.sort_unstable();
+            let offset = lattice.neighbours.len();
+            lattice.map[u] = -(offset as i32 + 1);
+            lattice.neighbours.push(0);
+            let (mut d2, mut nhave) = (dist2[0].0, 1);
+            for &(d, index) in dist2.iter() {
+                if d > d2 {
+                    if nhave == nwant {
+                        break;
+                    }
+                    d2 = d;
+                    nhave += 1;
+                }
+                lattice.neighbours.push(index)
+            }
+            lattice.neighbours[offset] = (lattice.neighbours.len() - offset - 1) as u16;
+        }
+        lattice
+    }
+
+    fn pack(&self, levels: &[i8]) -> usize {
+        levels
+            .iter()
+            .enumerate()
+            .fold(0, |u, (k, &l)| u | ((l as usize) << (self.bits * k)))
+    }
+
+    // The index of the codebook entry for `levels`, if these are on the grid.
+    fn grid_index(&self, levels: &[i8]) -> Option<usize> {
+        let index = self.map[self.pack(levels)];
+        (index >= 0).then_some(index as usize)
+    }
+
+    // Replaces `levels`, which are not on the grid, with the neighbouring codebook entry that
+    // minimizes the weighted squared error on `xval`.
+    fn best_neighbour(&self, xval: &[f32], weight: &[f32], scale: f32, levels: &mut [i8]) {
+        let offset = (-self.map[self.pack(levels)] - 1) as usize;
+        let num_neighbours = self.neighbours[offset] as usize;
+        let mut best_d2 = f32::MAX;
+        let mut best_index = 0;
+        for &index in self.neighbours[offset + 1..=offset + num_neighbours].iter() {
+            let d2: f32 = self.grid[index as usize]
+                .iter()
+                .zip(xval.iter().zip(weight.iter()))
+                .map(|(&q, (&x, &w))| w * (scale * q as f32 - x).powi(2))
+                .sum();
+            if d2 < best_d2 {
+                best_d2 = d2;
+                best_index = index as usize
+            }
+        }
+        for (l, &q) in levels.iter_mut().zip(self.grid[best_index].iter()) {
+            *l = (q - 1) / 2
+        }
+    }
+}
+
+fn iq2xs_lattice() -> &'static IqLattice<8> {
+    static LATTICE: OnceLock<IqLattice<8>> = OnceLock::new();
+    LATTICE.get_or_init(|| {
+        // The magnitudes 8, 25 and 43 of the dequantization grid stand for the levels 0, 1 and 2.
+        let grid = IQ2XS_GRID
+            .iter()
+            .map(|g| {
+                g.to_le_bytes().map(|b| match b {
+                    8 => 1,
+                    25 => 3,
+                    _ => 5,
+                })
+            })
+            .collect();
+        IqLattice::new(grid, 2, 2, 2)
+    })
+}
+
+fn iq3s_lattice() -> &'static IqLattice<4> {
+    static LATTICE: OnceLock<IqLattice<4>> = OnceLock::new();
+    LATTICE.get_or_init(|| {
+        let grid = IQ3S_GRID
+            .iter()
+            .map(|g| g.to_le_bytes().map(|b| b as i8))
+            .collect();
+        IqLattice::new(grid, 3, 7, 3)
+    })
+}
+
+// The weighted sums of `x * q` and `q * q` for the odd values `q = 2l + 1`.
+fn iq_weighted_sums(levels: &[i8], xval: &[f32], weight: &[f32]) -> (f32, f32) {
+    let (mut sumqx, mut sumq2) = (0f32, 0f32);
+    for ((&l, &x), &w) in levels.iter().zip(xval.iter()).zip(weight.iter()) {
+        let q = 2. * l as f32 + 1.;
+        sumqx += w * x * q;
+        sumq2 += w * q * q;
+    }
+    (sumqx, sumq2)
+}
+
+impl BlockIQ2XS {
+    // https://github.com/ggerganov/llama.cpp/blob/0e4802b2ecbaab04b4f829fde4a3096ca19c84b5/ggml/src/ggml-quants.c
+    // The values are quantized by groups of 16 with their own scale, each half of 8 values being
+    // projected on the codebook. The signs are applied separately, the value with the smallest
+    // weighted magnitude being flipped if needed so that only 7 sign bits have to be stored.
+    fn quantize_block(x: &[f32], qw: &[f32]) -> Result<Self> {
+        const KMAX_Q: i32 = 3;
+        let lattice = iq2xs_lattice();
+        let mut block = Self {
+            d: f16::ZERO,
+            qs: [0u16; QK_K / 8],
+            scales: [0u8; QK_K / 32],
+        };
+        let sigma2 = x.iter().map(|v| v * v).sum::<f32>() / QK_K as f32;
+        let mut scales = [0f32; QK_K / 16];
+        let mut max_scale = 0f32;
+        for (ib, (xb, qw)) in x.chunks_exact(16).zip(qw.chunks_exact(16)).enumerate() {
+            let weight: [f32; 16] =
+                std::array::from_fn(|i| qw[i] * (sigma2 + xb[i] * xb[i]).sqrt());
+            let waux = weight.map(f32::sqrt);
+            let mut xval = [0f32; 16];
+            let mut block_signs = [0u8; 2];
+            for k in 0..2 {
+                let mut s = 0u8;
+                for i in 0..8 {
+                    xval[8 * k + i] = xb[8 * k + i].abs();
+                    if xb[8 * k + i] < 0. {
+                        s |= 1 << i
+                    }
+                }
+                if s.count_ones() % 2 == 1 {
+                    let mut imin = 0;
+                    let mut min = weight[8 * k] * xb[8 * k] * xb[8 * k];
+                    for i in 1..8 {
+                        let ax = weight[8 * k + i] * xb[8 * k + i] * xb[8 * k + i];
+                        if ax < min {
+                            min = ax;
+                            imin = i;
+                        }
+                    }
+                    xval[8 * k + imin] = -xval[8 * k + imin];
+                    s ^= 1 << imin;
+                }
+                block_signs[k] = s & 127;
+            }
+            let max = xval.iter().fold(xval[0], |m, &v| m.max(v));
+            if max < 1e-15 {
+                continue;
+            }
+            let mut best = 0f32;
+            let mut scale = max / (2 * KMAX_Q - 1) as f32;
+            let mut levels = [0i8; 16];
+            let mut is_on_grid = [true; 2];
+            for is in -9..=9 {
+                let id = ((2 * KMAX_Q - 1) as f32 + is as f32 * 0.1) / max;
+                let mut laux = [0i8; 16];
+                let mut is_on_grid_aux = [true; 2];
+                for (k, on_grid) in is_on_grid_aux.iter_mut().enumerate() {
+                    let range = 8 * k..8 * k + 8;
+                    for i in range.clone() {
+                        let l = nearest_int(0.5 * (id * xval[i] - 1.));
+                        laux[i] = l.clamp(0, KMAX_Q - 1) as i8;
+                    }
+                    if lattice.grid_index(&laux[range.clone()]).is_none() {
+                        *on_grid = false;
+                        let (xval, waux) = (&xval[range.clone()], &waux[range.clone()]);
+                        lattice.best_neighbour(xval, waux, 1. / id, &mut laux[range]);
+                    }
+                }
+                let (sumqx, sumq2) = iq_weighted_sums(&laux, &xval, &weight);
+                if sumq2 > 0. && sumqx * sumqx > best * sumq2 {
+                    scale = sumqx / sumq2;
+                    best = scale * sumqx;
+                    levels = laux;
+                    is_on_grid = is_on_grid_aux;
+                }
+            }
+            if is_on_grid.contains(&false) && scale > 0. {
+                let id = 1. / scale;
+                for (k, &on_grid) in is_on_grid.iter().enumerate() {
+                    if on_grid {
+                        continue;
+                    }
+                    let range = 8 * k..8 * k + 8;
+                    for i in range.clone() {
+                        let l = nearest_int(0.5 * (id * xval[i] - 1.));
+                        levels[i] = l.clamp(0, KMAX_Q - 1) as i8;
+                    }
+                    if lattice.grid_index(&levels[range.clone()]).is_none() {
+                        let (xval, waux) = (&xval[range.clone()], &waux[range.clone()]);
+                        lattice.best_neighbour(xval, waux, scale, &mut levels[range]);
+                    }
+                }
+                let (sumqx, sumq2) = iq_weighted_sums(&levels, &xval, &weight);
+                if sumq2 > 0. {
+                    scale = sumqx / sumq2
+                }
+            }
+            if scale < 0. {
+                scale = -scale;
+                for s in block_signs.iter_mut() {
+                    *s = !*s & 127
+                }
+            }
+            for k in 0..2 {
+                let levels = &levels[8 * k..8 * k + 8];
+                let grid_index = match lattice.grid_index(levels) {
+                    Some(grid_index) => grid_index,
+                    None => crate::bail!("quantize_row_iq2_xs: {levels:?} is not on the grid"),
+                };
+                block.qs[2 * ib + k] = grid_index as u16 | ((block_signs[k] as u16) << 9);
+            }
+            scales[ib] = scale;
+            max_scale = max_scale.max(scale);
+        }
+        if max_scale == 0. {
+            block.qs = [0u16; QK_K / 8];
+            return Ok(block);
+        }
+        let d = max_scale / 31.;
+        block.d = f16::from_f32(d);
+        let id = 1. / d;
+        for (ib, &scale) in scales.iter().enumerate() {
+            let l = nearest_int(0.5 * (id * scale - 1.)).clamp(0, 15) as u8;
+            block.scales[ib / 2] |= l << (4 * (ib % 2));
+        }
+        Ok(block)
+    }
+}
+
+impl GgmlType for BlockIQ2XS {
+    const DTYPE: GgmlDType = GgmlDType::IQ2XS;
+    const BLCK_SIZE: usize = QK_K;
+    type VecDotType = BlockQ8K;
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) -> Result<()> {
+        let k = ys.len();
+        if k % QK_K != 0 {
+            crate::bail!("dequantize_row_iq2_xs: {k} is not divisible by {QK_K}")
+        }
+        for (x, ys) in xs.iter().zip(ys.chunks_exact_mut(QK_K)) {
+            let d = x.d.to_f32();
+            for (ib, ys) in ys.chunks_exact_mut(16).enumerate() {
+                let scale = (x.scales[ib / 2] >> (4 * (ib % 2))) & 0xF;
+                let db = d * (0.5 + scale as f32) * 0.25;
+                for (l, ys) in ys.chunks_exact_mut(8).enumerate() {
+                    let q = x.qs[2 * ib + l];
+                    let grid = IQ2XS_GRID[(q & 511) as usize].to_le_bytes();
+                    let signs = KSIGNS_IQ2XS[(q >> 9) as usize];
+                    for (j, y) in ys.iter_mut().enumerate() {
+                        let v = db * grid[j] as f32;
+                        *y = if signs & KMASK_IQ2XS[j] != 0 { -v } else { v };
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Without an importance matrix, all the values are given the same importance.
+    fn from_float(xs: &[f32], ys: &mut [Self]) -> Result<()> {
+        if xs.len() != ys.len() * QK_K {
+            crate::bail!(
+                "quantize_row_iq2_xs: size mismatch {} {}",
+                xs.len(),
+                ys.len()
+            )
+        }
+        for (x, y) in xs.chunks_exact(QK_K).zip(ys.iter_mut()) {
+            *y = Self::quantize_block(x, &[1f32; QK_K])?
+        }
+        Ok(())
+    }
+
+    fn from_float_imatrix(
+        xs: &[f32],
+        ys: &mut [Self],
+        imatrix: &[f32],
+        n_per_row: usize,
+    ) -> Result<()> {
+        if xs.len() != ys.len() * QK_K {
+            crate::bail!(
+                "quantize_row_iq2_xs: size mismatch {} {}",
+                xs.len(),
+                ys.len()
+            )
+        }
+        for (block_idx, (x, y)) in xs.chunks_exact(QK_K).zip(ys.iter_mut()).enumerate() {
+            let qw = imatrix_block::<Self>(imatrix, n_per_row, block_idx)?;
+            *y = Self::quantize_block(x, qw)?
+        }
+        Ok(())
+    }
+
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        if n % QK_K != 0 {
+            crate::bail!("vec_dot_iq2_xs_q8k: {n} is not divisible by {QK_K}")
+        }
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut bsum = 0i32;
+            for (ib, q8) in y.qs.chunks_exact(16).enumerate() {
+                let scale = (x.scales[ib / 2] >> (4 * (ib % 2))) & 0xF;
+                let mut sumi = 0i32;
+                for (l, q8) in q8.chunks_exact(8).enumerate() {
+                    let q = x.qs[2 * ib + l];
+                    let grid = IQ2XS_GRID[(q & 511) as usize].to_le_bytes();
+                    let signs = KSIGNS_IQ2XS[(q >> 9) as usize];
+                    for (j, &q8) in q8.iter().enumerate() {
+                        let v = grid[j] as i32 * q8 as i32;
+                        sumi += if signs & KMASK_IQ2XS[j] != 0 { -v } else { v };
+                    }
+                }
+                bsum += sumi * (2 * scale as i32 + 1);
+            }
+            sumf += x.d.to_f32() * y.d * bsum as f32;
+        }
+        Ok(0.125 * sumf)
+    }
+}
+
+impl BlockIQ3S {
+    // https://github.com/ggerganov/llama.cpp/blob/0e4802b2ecbaab04b4f829fde4a3096ca19c84b5/ggml/src/ggml-quants.c
+    // The values are quantized by groups of 32 with their own scale, each quarter of 4 values being
+    // projected on the codebook, and all the signs are stored. Without `qw`, the error on each value
+    // is weighted by its squared magnitude.
+    fn quantize_block(x: &[f32], qw: Option<&[f32]>) -> Result<Self> {
+        const KMAX_Q: i32 = 8;
+        let lattice = iq3s_lattice();
+        let mut block = Self {
+            d: f16::ZERO,
+            qs: [0u8; QK_K / 4],
+            qh: [0u8; QK_K / 32],
+            signs: [0u8; QK_K / 8],
+            scales: [0u8; QK_K / 64],
+        };
+        let sigma2 = 2. * x.iter().map(|v| v * v).sum::<f32>() / QK_K as f32;
+        let mut scales = [0f32; QK_K / 32];
+        let mut max_scale = 0f32;
+        for (ib, xb) in x.chunks_exact(32).enumerate() {
+            let weight: [f32; 32] = match qw {
+                Some(qw) => {
+                    std::array::from_fn(|i| qw[32 * ib + i] * (sigma2 + xb[i] * xb[i]).sqrt())
+                }
+                None => std::array::from_fn(|i| xb[i] * xb[i]),
+            };
+            let waux = weight.map(f32::sqrt);
+            let xval = xb.iter().map(|v| v.abs()).collect::<Vec<_>>();
+            let mut block_signs = [0u8; 4];
+            for (i, &v) in xb.iter().enumerate() {
+                if v < 0. {
+                    block_signs[i / 8] |= 1 << (i % 8)
+                }
+            }
+            let max = xval.iter().fold(0f32, |m, &v| m.max(v));
+            if max == 0. {
+                continue;
+            }
+            let mut best = 0f32;
+            let mut scale = max / (2 * KMAX_Q - 1) as f32;
+            let mut levels = [0i8; 32];
+            let mut is_on_grid = [false; 8];
+            for is in -9..=9 {
+                let id = ((2 * KMAX_Q - 1) as f32 + is as f32 * 0.2) / max;
+                let mut laux = [0i8; 32];
+                let mut is_on_grid_aux = [true; 8];
+                for (k, on_grid) in is_on_grid_aux.iter_mut().enumerate() {
+                    let range = 4 * k..4 * k + 4;
+                    for i in range.clone() {
+                        let l = nearest_int(0.5 * (id * xval[i] - 1.));
+                        laux[i] = l.clamp(0, KMAX_Q - 1) as i8;
+                    }
+                    if lattice.grid_index(&laux[range.clone()]).is_none() {
+                        *on_grid = false;
+                        let (xval, waux) = (&xval[range.clone()], &waux[range.clone()]);
+                        lattice.best_neighbour(xval, waux, 1. / id, &mut laux[range]);
+                    }
+                }
+                let (sumqx, sumq2) = iq_weighted_sums(&laux, &xval, &weight);
+                if sumq2 > 0. && sumqx * sumqx > best * sumq2 {
+                    scale = sumqx / sumq2;
+                    best = scale * sumqx;
+                    levels = laux;
+                    is_on_grid = is_on_grid_aux;
+                }
+            }
+            if is_on_grid.contains(&false) && scale > 0. {
+                let id = 1. / scale;
+                for k in 0..8 {
+                    let range = 4 * k..4 * k + 4;
+                    for i in range.clone() {
+                        let l = nearest_int(0.5 * (id * xval[i] - 1.));
+                        levels[i] = l.clamp(0, KMAX_Q - 1) as i8;
+                    }
+                    if lattice.grid_index(&levels[range.clone()]).is_none() {
+                        let (xval, waux) = (&xval[range.clone()], &waux[range.clone()]);
+                        lattice.best_neighbour(xval, waux, scale, &mut levels[range]);
+                    }
+                }
+                let (sumqx, sumq2) = iq_weighted_sums(&levels, &xval, &weight);
+                if sumq2 > 0. {
+                    scale = sumqx / sumq2
+                }
+            }
+            if scale < 0. {
+                scale = -scale;
+                for s in block_signs.iter_mut() {
+                    *s = !*s
+                }
+            }
+            for k in 0..8 {
+                let levels = &levels[4 * k..4 * k + 4];
+                let grid_index = match lattice.grid_index(levels) {
+                    Some(grid_index) => grid_index,
+                    None => crate::bail!("quantize_row_iq3_s: {levels:?} is not on the grid"),
+                };
+                block.qs[8 * ib + k] = grid_index as u8;
+                block.qh[ib] |= ((grid_index >> 8) as u8) << k;
+            }
+            block.signs[4 * ib..4 * ib + 4].copy_from_slice(&block_signs);
+            scales[ib] = scale;
+            max_scale = max_scale.max(scale);
+        }
+        if max_scale == 0. {
+            return Ok(block);
+        }
+        let d = max_scale / 31.;
+        block.d = f16::from_f32(d * 1.033);
+        let id = 1. / d;
+        for (ib, &scale) in scales.iter().enumerate() {
+            let l = nearest_int(0.5 * (id * scale - 1.)).clamp(0, 15) as u8;
+            block.scales[ib / 2] |= l << (4 * (ib % 2));
+        }
+        Ok(block)
+    }
+}
+
+impl GgmlType for BlockIQ3S {
+    const DTYPE: GgmlDType = GgmlDType::IQ3S;
+    const BLCK_SIZE: usize = QK_K;
+    type VecDotType = BlockQ8K;
+
+    fn to_float(xs: &[Self], ys: &mut [f32]) -> Result<()> {
+        let k = ys.len();
+        if k % QK_K != 0 {
+            crate::bail!("dequantize_row_iq3_s: {k} is not divisible by {QK_K}")
+        }
+        for (x, ys) in xs.iter().zip(ys.chunks_exact_mut(QK_K)) {
+            let d = x.d.to_f32();
+            for (ib, ys) in ys.chunks_exact_mut(32).enumerate() {
+                let scale = (x.scales[ib / 2] >> (4 * (ib % 2))) & 0xF;
+                let db = d * (1 + 2 * scale as i32) as f32;
+                // Each byte of `qs` indexes the grid for 4 values, the ninth bit coming from `qh`.
+                for (k, ys) in ys.chunks_exact_mut(4).enumerate() {
+                    let index = x.qs[8 * ib + k] as usize | (((x.qh[ib] >> k) & 1) as usize) << 8;
+                    let grid = IQ3S_GRID[index].to_le_bytes();
+                    let signs = x.signs[4 * ib + k / 2];
+                    for (j, y) in ys.iter_mut().enumerate() {
+                        let v = db * grid[j] as f32;
+                        let mask = KMASK_IQ2XS[4 * (k % 2) + j];
+                        *y = if signs & mask != 0 { -v } else { v };
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn from_float(xs: &[f32], ys: &mut [Self]) -> Result<()> {
+        if xs.len() != ys.len() * QK_K {
+            crate::bail!(
+                "quantize_row_iq3_s: size mismatch {} {}",
+                xs.len(),
+                ys.len()
+            )
+        }
+        for (x, y) in xs.chunks_exact(QK_K).zip(ys.iter_mut()) {
+            *y = Self::quantize_block(x, None)?
+        }
+        Ok(())
+    }
+
+    fn from_float_imatrix(
+        xs: &[f32],
+        ys: &mut [Self],
+        imatrix: &[f32],
+        n_per_row: usize,
+    ) -> Result<()> {
+        if xs.len() != ys.len() * QK_K {
+            crate::bail!(
+                "quantize_row_iq3_s: size mismatch {} {}",
+                xs.len(),
+                ys.len()
+            )
+        }
+        for (block_idx, (x, y)) in xs.chunks_exact(QK_K).zip(ys.iter_mut()).enumerate() {
+            let qw = imatrix_block::<Self>(imatrix, n_per_row, block_idx)?;
+            *y = Self::quantize_block(x, Some(qw))?
+        }
+        Ok(())
+    }
+
+    fn vec_dot(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        Self::vec_dot_unopt(n, xs, ys)
+    }
+
+    fn vec_dot_unopt(n: usize, xs: &[Self], ys: &[Self::VecDotType]) -> Result<f32> {
+        if n % QK_K != 0 {
+            crate::bail!("vec_dot_iq3_s_q8k: {n} is not divisible by {QK_K}")
+        }
+        let mut sumf = 0f32;
+        for (x, y) in xs.iter().zip(ys.iter()) {
+            let mut bsum = 0i32;
+            for (ib, q8) in y.qs.chunks_exact(32).enumerate() {
+                let scale = (x.scales[ib / 2] >> (4 * (ib % 2))) & 0xF;
+                let mut sumi = 0i32;
+                for (k, q8) in q8.chunks_exact(4).enumerate() {
+                    let index = x.qs[8 * ib + k] as usize | (((x.qh[ib] >> k) & 1) as usize) << 8;
+                    let grid = IQ3S_GRID[index].to_le_bytes();
+                    let signs = x.signs[4 * ib + k / 2];
+                    for (j, &q8) in q8.iter().enumerate() {
+                        let v = grid[j] as i32 * q8 as i32;
+                        let mask = KMASK_IQ2XS[4 * (k % 2) + j];
+                        sumi += if signs & mask != 0 { -v } else { v };
+                    }
+                }
+                bsum += sumi * (2 * scale as i32 + 1);
+            }
+            sumf += x.d.to_f32() * y.d * bsum as f32;
+        }
+        Ok(sumf)
+    }
+}
+
 // https://github.com/ggerganov/llama.cpp/blob/b5ffb2849d23afe73647f68eec7b68187af09be6/ggml.c#L10605
 pub fn matmul<T: GgmlType>(
     mkn: (usize, usize, usize),
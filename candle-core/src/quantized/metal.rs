@@ -103,6 +103,18 @@ impl QMetalStorage {
                 let vec: Vec<crate::quantized::BlockQ8K> = read_to_vec(&buffer, block_len);
                 crate::quantized::BlockQ8K::to_float(&vec, &mut out)?;
             }
+            GgmlDType::IQ4NL => {
+                let vec: Vec<crate::quantized::BlockIQ4NL> = read_to_vec(&buffer, block_len);
+                crate::quantized::BlockIQ4NL::to_float(&vec, &mut out)?;
+            }
+            GgmlDType::IQ2XS => {
+                let vec: Vec<crate::quantized::BlockIQ2XS> = read_to_vec(&buffer, block_len);
+                crate::quantized::BlockIQ2XS::to_float(&vec, &mut out)?;
+            }
+            GgmlDType::IQ3S => {
+                let vec: Vec<crate::quantized::BlockIQ3S> = read_to_vec(&buffer, block_len);
+                crate::quantized::BlockIQ3S::to_float(&vec, &mut out)?;
+            }
         }
 
         let buffer = self.device.new_buffer_with_data(&out)?;
@@ -130,6 +142,26 @@ impl QMetalStorage {
         self.buffer.length() as usize
     }
 
+    /// Overwrites the quantized data with `data`, the copy being queued after the kernels that
+    /// may still read the previous data.
+    pub fn copy_from_host(&mut self, data: &[u8]) -> Result<()> {
+        if data.len() != self.storage_size_in_bytes() {
+            crate::bail!(
+                "copy_from_host: got {} bytes for a storage of {} bytes",
+                data.len(),
+                self.storage_size_in_bytes()
+            )
+        }
+        let staging = self.device.new_buffer_with_data(data)?;
+        let command_buffer = self.device.command_buffer()?;
+        command_buffer.set_label("copy_from_host");
+        let blit = command_buffer.new_blit_command_encoder();
+        blit.set_label("blit_from_host");
+        blit.copy_from_buffer(&staging, 0, &self.buffer, 0, staging.length());
+        blit.end_encoding();
+        self.device.wait_until_completed()
+    }
+
     pub fn fwd(
         &self,
         self_shape: &Shape,
@@ -163,6 +195,17 @@ impl QMetalStorage {
         }
         dst_shape.push(n);
         let dst_shape = Shape::from(dst_shape);
+        let kernel_dtype = match candle_metal_kernels::GgmlDType::try_from(self.dtype) {
+            Ok(kernel_dtype) => kernel_dtype,
+            Err(_) => {
+                // There are no kernels for the i-quants yet, the weights are dequantized first.
+                let weights = self.dequantize(n * k)?;
+                let rhs_l =
+                    crate::Layout::new((k, n).into(), vec![1, k], 0).broadcast_as((1, k, n))?;
+                let dst_storage = storage.matmul(&weights, (1, m, n, k), layout, &rhs_l)?;
+                return Ok((dst_storage, dst_shape));
+            }
+        };
         let device = storage.device().clone();
         let dst = device.new_buffer(dst_shape.elem_count(), DType::F32, "qmatmul")?;
         let command_buffer = device.command_buffer()?;
@@ -173,7 +216,7 @@ impl QMetalStorage {
                 device.device(),
                 &command_buffer,
                 device.kernels(),
-                self.dtype.into(),
+                kernel_dtype,
                 (1, 1, n, k),
                 storage.buffer(),
                 (layout.start_offset() + batch_id * k) * storage.dtype().size_in_bytes(),
@@ -208,9 +251,11 @@ fn read_to_vec<T: Clone>(buffer: &Buffer, n: usize) -> Vec<T> {
     slice.to_vec()
 }
 
-impl From<GgmlDType> for candle_metal_kernels::GgmlDType {
-    fn from(value: GgmlDType) -> Self {
-        match value {
+impl TryFrom<GgmlDType> for candle_metal_kernels::GgmlDType {
+    type Error = crate::Error;
+
+    fn try_from(value: GgmlDType) -> Result<Self> {
+        let dtype = match value {
             GgmlDType::Q4_0 => candle_metal_kernels::GgmlDType::Q4_0,
             GgmlDType::Q4_1 => candle_metal_kernels::GgmlDType::Q4_1,
             GgmlDType::Q5_0 => candle_metal_kernels::GgmlDType::Q5_0,
@@ -225,6 +270,10 @@ impl From<GgmlDType> for candle_metal_kernels::GgmlDType {
             GgmlDType::Q8K => candle_metal_kernels::GgmlDType::Q8K,
             GgmlDType::F16 => candle_metal_kernels::GgmlDType::F16,
             GgmlDType::F32 => candle_metal_kernels::GgmlDType::F32,
-        }
+            GgmlDType::IQ4NL | GgmlDType::IQ2XS | GgmlDType::IQ3S => {
+                crate::bail!("no metal kernels for {value:?}")
+            }
+        };
+        Ok(dtype)
     }
 }
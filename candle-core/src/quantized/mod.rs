@@ -1,3 +1,4 @@
+use crate::backend::BackendStorage;
 use crate::{CpuStorage, DType, Device, Result, Shape, Storage, Tensor};
 use k_quants::*;
 use std::borrow::Cow;
@@ -8,6 +9,8 @@ mod dummy_cuda;
 mod dummy_metal;
 pub mod ggml_file;
 pub mod gguf_file;
+pub mod imatrix_file;
+mod iq_grids;
 pub mod k_quants;
 #[cfg(feature = "metal")]
 pub mod metal;
@@ -39,7 +42,9 @@ pub struct QTensor {
 impl Device {
     fn qzeros(&self, elem_count: usize, dtype: GgmlDType) -> Result<QStorage> {
         match self {
-            Device::Cpu => {
+            // The wgpu backend does not have quantized kernels, the quantized matmuls run on
+            // the cpu.
+            Device::Cpu | Device::Wgpu(_) => {
                 let storage = dtype.cpu_zeros(elem_count);
                 Ok(QStorage::Cpu(storage))
             }
@@ -51,6 +56,7 @@ impl Device {
                 let storage = cuda::QCudaStorage::zeros(cuda, elem_count, dtype)?;
                 Ok(QStorage::Cuda(storage))
             }
+            Device::Meta => Err(crate::Error::NotSupportedOnMetaDevice { op: "qzeros" }.bt()),
         }
     }
 }
@@ -99,6 +105,9 @@ impl QStorage {
             (QStorage::Cpu(storage), Storage::Cpu(src)) => {
                 storage.from_float(src.as_slice::<f32>()?)?;
             }
+            (QStorage::Cpu(storage), Storage::Wgpu(src)) => {
+                storage.from_float(src.to_cpu_storage()?.as_slice::<f32>()?)?;
+            }
             (QStorage::Metal(storage), Storage::Metal(src)) => storage.quantize(src)?,
             (QStorage::Cuda(storage), Storage::Cuda(src)) => storage.quantize(src)?,
             _ => crate::bail!("Invalid dequantize storage locations do not match"),
@@ -114,6 +123,25 @@ impl QStorage {
         }
     }
 
+    fn copy_from_host(&mut self, data: &[u8]) -> Result<()> {
+        match self {
+            QStorage::Cpu(storage) => {
+                let size_in_bytes = storage.storage_size_in_bytes();
+                if data.len() != size_in_bytes {
+                    crate::bail!(
+                        "copy_from_host: got {} bytes for a storage of {size_in_bytes} bytes",
+                        data.len()
+                    )
+                }
+                let data_ptr = storage.as_mut_ptr();
+                unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), data_ptr, size_in_bytes) };
+                Ok(())
+            }
+            QStorage::Metal(storage) => storage.copy_from_host(data),
+            QStorage::Cuda(storage) => storage.copy_from_host(data),
+        }
+    }
+
     fn data(&self) -> Result<Cow<[u8]>> {
         match self {
             QStorage::Cpu(storage) => {
@@ -145,6 +173,9 @@ pub enum GgmlDType {
     Q5K,
     Q6K,
     Q8K,
+    IQ4NL,
+    IQ2XS,
+    IQ3S,
 }
 
 impl GgmlDType {
@@ -164,6 +195,16 @@ impl GgmlDType {
             13 => Self::Q5K,
             14 => Self::Q6K,
             15 => Self::Q8K,
+            17 => Self::IQ2XS,
+            20 => Self::IQ4NL,
+            21 => Self::IQ3S,
+            16 | 18 | 19 | 22 | 23 => {
+                let names = [
+                    "IQ2_XXS", "IQ2_XS", "IQ3_XXS", "IQ1_S", "IQ4_NL", "IQ3_S", "IQ2_S", "IQ4_XS",
+                ];
+                let name = names[u as usize - 16];
+                crate::bail!("the {name} dtype is not supported yet")
+            }
             _ => crate::bail!("unknown dtype for tensor {u}"),
         };
         Ok(dtype)
@@ -185,6 +226,9 @@ impl GgmlDType {
             Self::Q5K => 13,
             Self::Q6K => 14,
             Self::Q8K => 15,
+            Self::IQ4NL => 20,
+            Self::IQ2XS => 17,
+            Self::IQ3S => 21,
         }
     }
 
@@ -205,6 +249,15 @@ impl GgmlDType {
             Self::Q5K => Box::new(vec![BlockQ5K::zeros(); elem_count / BlockQ5K::BLCK_SIZE]),
             Self::Q6K => Box::new(vec![BlockQ6K::zeros(); elem_count / BlockQ6K::BLCK_SIZE]),
             Self::Q8K => Box::new(vec![BlockQ8K::zeros(); elem_count / BlockQ8K::BLCK_SIZE]),
+            Self::IQ4NL => Box::new(vec![
+                BlockIQ4NL::zeros();
+                elem_count / BlockIQ4NL::BLCK_SIZE
+            ]),
+            Self::IQ2XS => Box::new(vec![
+                BlockIQ2XS::zeros();
+                elem_count / BlockIQ2XS::BLCK_SIZE
+            ]),
+            Self::IQ3S => Box::new(vec![BlockIQ3S::zeros(); elem_count / BlockIQ3S::BLCK_SIZE]),
         }
     }
     /// The type size for blocks in bytes.
@@ -226,6 +279,9 @@ impl GgmlDType {
             Self::Q5K => std::mem::size_of::<BlockQ5K>(),
             Self::Q6K => std::mem::size_of::<BlockQ6K>(),
             Self::Q8K => std::mem::size_of::<BlockQ8K>(),
+            Self::IQ4NL => std::mem::size_of::<BlockIQ4NL>(),
+            Self::IQ2XS => std::mem::size_of::<BlockIQ2XS>(),
+            Self::IQ3S => std::mem::size_of::<BlockIQ3S>(),
         }
     }
 
@@ -240,7 +296,15 @@ impl GgmlDType {
             Self::Q5_1 => k_quants::QK5_1,
             Self::Q8_0 => k_quants::QK8_0,
             Self::Q8_1 => k_quants::QK8_1,
-            Self::Q2K | Self::Q3K | Self::Q4K | Self::Q5K | Self::Q6K | Self::Q8K => k_quants::QK_K,
+            Self::IQ4NL => k_quants::QK4_NL,
+            Self::Q2K
+            | Self::Q3K
+            | Self::Q4K
+            | Self::Q5K
+            | Self::Q6K
+            | Self::Q8K
+            | Self::IQ2XS
+            | Self::IQ3S => k_quants::QK_K,
         }
     }
 }
@@ -252,9 +316,12 @@ pub trait QuantizedType: Send + Sync {
     fn dequantize(&self, elem_count: usize) -> Result<CpuStorage>;
     fn storage_size_in_bytes(&self) -> usize;
     fn as_ptr(&self) -> *const u8;
+    fn as_mut_ptr(&mut self) -> *mut u8;
     fn block_size(&self) -> usize;
     #[allow(clippy::wrong_self_convention)]
     fn from_float(&mut self, xs: &[f32]) -> Result<()>;
+    #[allow(clippy::wrong_self_convention)]
+    fn from_float_imatrix(&mut self, xs: &[f32], imatrix: &[f32], n_per_row: usize) -> Result<()>;
     fn size(&self) -> usize;
 }
 
@@ -271,6 +338,10 @@ impl<T: k_quants::GgmlType + Send + Sync> QuantizedType for Vec<T> {
         T::from_float(xs, self)
     }
 
+    fn from_float_imatrix(&mut self, xs: &[f32], imatrix: &[f32], n_per_row: usize) -> Result<()> {
+        T::from_float_imatrix(xs, self, imatrix, n_per_row)
+    }
+
     fn dtype(&self) -> GgmlDType {
         T::DTYPE
     }
@@ -292,6 +363,10 @@ impl<T: k_quants::GgmlType + Send + Sync> QuantizedType for Vec<T> {
     fn as_ptr(&self) -> *const u8 {
         self.as_ptr() as *const u8
     }
+
+    fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.as_mut_ptr() as *mut u8
+    }
 }
 
 impl std::fmt::Debug for QTensor {
@@ -341,6 +416,36 @@ impl QTensor {
         })
     }
 
+    /// Quantizes `src` weighting the quantization error of each column, i.e. each element of
+    /// the last dimension, by its importance in `imatrix`, see
+    /// [`imatrix_file`](crate::quantized::imatrix_file). Only the k-quants with 4 or 5 bits and
+    /// the i-quants use the importance, the other types are quantized as with [`Self::quantize`].
+    ///
+    /// The quantization always runs on the cpu and the returned tensor is on the cpu.
+    pub fn quantize_imatrix(src: &Tensor, imatrix: &[f32], dtype: GgmlDType) -> Result<Self> {
+        let shape = src.shape();
+        let block_size = dtype.block_size();
+        check_shape(shape, block_size)?;
+        let n_per_row = shape.dims()[shape.rank() - 1];
+        if imatrix.len() != n_per_row {
+            crate::bail!(
+                "the importance matrix has {} values but the tensor has shape {shape:?}",
+                imatrix.len()
+            )
+        }
+        let src = src
+            .to_device(&Device::Cpu)?
+            .to_dtype(crate::DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let mut storage = dtype.cpu_zeros(shape.elem_count());
+        storage.from_float_imatrix(&src, imatrix, n_per_row)?;
+        Ok(Self {
+            storage: QStorage::Cpu(storage),
+            shape: shape.clone(),
+        })
+    }
+
     pub fn dtype(&self) -> GgmlDType {
         self.storage.dtype()
     }
@@ -387,6 +492,33 @@ impl QTensor {
     pub fn data(&self) -> Result<Cow<'_, [u8]>> {
         self.storage.data()
     }
+
+    /// Overwrites the data of this tensor with the one of `src`, a cpu tensor with the same dtype
+    /// and shape, reusing the storage of this tensor which can be on any device. This avoids a
+    /// device allocation when the weights are streamed to a device in preallocated buffers.
+    pub fn copy_from(&mut self, src: &QTensor) -> Result<()> {
+        if src.dtype() != self.dtype() || src.shape() != self.shape() {
+            crate::bail!(
+                "copy_from: cannot copy a {:?} {:?} tensor into a {:?} {:?} tensor",
+                src.dtype(),
+                src.shape(),
+                self.dtype(),
+                self.shape()
+            )
+        }
+        self.storage.copy_from_host(&src.data()?)
+    }
+
+    /// A copy of this cpu tensor on `device`.
+    pub fn to_device(&self, device: &Device) -> Result<QTensor> {
+        let storage = device.qzeros(self.shape.elem_count(), self.dtype())?;
+        let mut dst = Self {
+            storage,
+            shape: self.shape.clone(),
+        };
+        dst.copy_from(self)?;
+        Ok(dst)
+    }
 }
 
 #[derive(Clone, Debug)]
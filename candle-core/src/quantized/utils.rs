@@ -324,3 +324,143 @@ pub(super) fn make_q3_quants(x: &[f32], nmax: i32, do_rmse: bool) -> f32 {
     }
     1.0 / iscale
 }
+
+// Weighted version of `make_qkx1_quants`, the candidate scales are searched on a grid and the
+// best one is refined by least squares.
+// https://github.com/ggerganov/llama.cpp/blob/a5e7dbd6141128bfa3c40a19c2945a181df625d3/ggml/src/ggml-quants.c#L1891
+pub(super) fn make_qkx3_quants(
+    nmax: i32,
+    x: &[f32],
+    weights: &[f32],
+    rmin: f32,
+    rdelta: f32,
+    nstep: usize,
+) -> (f32, f32) {
+    let n = x.len();
+    let mut min = x.iter().fold(x[0], |m, &v| m.min(v));
+    let max = x.iter().fold(x[0], |m, &v| m.max(v));
+    let sum_w: f32 = weights.iter().sum();
+    let sum_x: f32 = weights.iter().zip(x.iter()).map(|(w, x)| w * x).sum();
+    if min > 0. {
+        min = 0.
+    }
+    if max <= min {
+        return (0., -min);
+    }
+    let mut iscale = nmax as f32 / (max - min);
+    let mut scale = 1. / iscale;
+    let mut best_mse = 0f32;
+    for (&x, &w) in x.iter().zip(weights.iter()) {
+        let l = nearest_int(iscale * (x - min)).clamp(0, nmax);
+        let diff = scale * l as f32 + min - x;
+        best_mse += w * diff * diff;
+    }
+    let mut laux = vec![0u8; n];
+    for is in 0..=nstep {
+        iscale = (rmin + rdelta * is as f32 + nmax as f32) / (max - min);
+        let mut sum_l = 0f32;
+        let mut sum_l2 = 0f32;
+        let mut sum_xl = 0f32;
+        for ((&x, &w), laux) in x.iter().zip(weights.iter()).zip(laux.iter_mut()) {
+            let l = nearest_int(iscale * (x - min)).clamp(0, nmax);
+            *laux = l as u8;
+            let l = l as f32;
+            sum_l += w * l;
+            sum_l2 += w * l * l;
+            sum_xl += w * l * x;
+        }
+        let d = sum_w * sum_l2 - sum_l * sum_l;
+        if d > 0. {
+            let mut this_scale = (sum_w * sum_xl - sum_x * sum_l) / d;
+            let mut this_min = (sum_l2 * sum_x - sum_l * sum_xl) / d;
+            if this_min > 0. {
+                this_min = 0.;
+                this_scale = sum_xl / sum_l2;
+            }
+            let mut mse = 0f32;
+            for ((&x, &w), &l) in x.iter().zip(weights.iter()).zip(laux.iter()) {
+                let diff = this_scale * l as f32 + this_min - x;
+                mse += w * diff * diff;
+            }
+            if mse < best_mse {
+                best_mse = mse;
+                scale = this_scale;
+                min = this_min;
+            }
+        }
+    }
+    (scale, -min)
+}
+
+// Quantizes the positive values `x` to `[0, nmax]` with a single scale minimizing the weighted
+// squared error, the quantized values are written in `ls` and the scale is returned.
+// https://github.com/ggerganov/llama.cpp/blob/a5e7dbd6141128bfa3c40a19c2945a181df625d3/ggml/src/ggml-quants.c#L1980
+pub(super) fn make_qp_quants(nmax: i32, x: &[f32], ls: &mut [u8], weights: &[f32]) -> f32 {
+    let max = x.iter().fold(0f32, |m, &v| m.max(v));
+    if max == 0. {
+        ls.fill(0);
+        return 0.;
+    }
+    let mse = |iscale: f32| -> f32 {
+        let scale = 1. / iscale;
+        x.iter()
+            .zip(weights.iter())
+            .map(|(&x, &w)| {
+                let l = nearest_int(iscale * x).min(nmax);
+                let diff = x - scale * l as f32;
+                w * diff * diff
+            })
+            .sum()
+    };
+    let mut iscale = nmax as f32 / max;
+    let mut best_mse = mse(iscale);
+    for is in -4..=4 {
+        if is == 0 {
+            continue;
+        }
+        let iscale_is = (0.1 * is as f32 + nmax as f32) / max;
+        let mse = mse(iscale_is);
+        if mse < best_mse {
+            best_mse = mse;
+            iscale = iscale_is;
+        }
+    }
+    let mut sumlx = 0f32;
+    let mut suml2 = 0f32;
+    for ((&x, &w), l) in x.iter().zip(weights.iter()).zip(ls.iter_mut()) {
+        let li = nearest_int(iscale * x).min(nmax);
+        *l = li as u8;
+        let li = li as f32;
+        sumlx += w * x * li;
+        suml2 += w * li * li;
+    }
+    for _itry in 0..5 {
+        let mut n_changed = 0;
+        for ((&x, &w), l) in x.iter().zip(weights.iter()).zip(ls.iter_mut()) {
+            let li = *l as f32;
+            let mut slx = sumlx - w * x * li;
+            let mut sl2 = suml2 - w * li * li;
+            if slx > 0. && sl2 > 0. {
+                let new_l = nearest_int(x * sl2 / slx).min(nmax);
+                if new_l != *l as i32 {
+                    slx += w * x * new_l as f32;
+                    sl2 += w * (new_l * new_l) as f32;
+                    if slx * slx * suml2 > sumlx * sumlx * sl2 {
+                        *l = new_l as u8;
+                        sumlx = slx;
+                        suml2 = sl2;
+                        n_changed += 1;
+                    }
+                }
+            }
+        }
+        if n_changed == 0 {
+            break;
+        }
+    }
+    if suml2 > 0. {
+        sumlx / suml2
+    } else {
+        0.
+    }
+}
@@ -189,6 +189,17 @@ impl crate::CustomOp1 for ArgSort {
         let dst = crate::MetalStorage::new(dst, device.clone(), el, DType::U32);
         Ok((dst, layout.shape().clone()))
     }
+
+    fn meta_fwd(
+        &self,
+        _storage: &crate::MetaStorage,
+        layout: &crate::Layout,
+    ) -> Result<(crate::MetaStorage, crate::Shape)> {
+        Ok((
+            crate::MetaStorage::new(crate::DType::U32),
+            layout.shape().clone(),
+        ))
+    }
 }
 
 #[allow(unused)]
@@ -236,4 +247,82 @@ impl Tensor {
         let sorted = self.gather(&asort, crate::D::Minus1)?;
         Ok((sorted, asort))
     }
+
+    /// Sorts the values along `dim` in ascending order, returns the sorted values with `dim`
+    /// moved to the last position.
+    fn sort_along(&self, dim: usize) -> Result<Tensor> {
+        let t = self.transpose(dim, self.rank() - 1)?.contiguous()?;
+        Ok(t.sort_last_dim(true)?.0)
+    }
+
+    /// Returns the median of the values along `dim`, for an even number of values this is the
+    /// lower of the two middle values as in pytorch. The reduced dimension is kept with a single
+    /// element.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[[3f32, 1., 2.], [4., 1., 5.]], &Device::Cpu)?;
+    /// let m = a.median_keepdim(0)?;
+    /// assert_eq!(m.to_vec2::<f32>()?, &[[3., 1., 2.]]);
+    /// let m = a.median(1)?;
+    /// assert_eq!(m.to_vec1::<f32>()?, &[2., 4.]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn median_keepdim<D: crate::shape::Dim>(&self, dim: D) -> Result<Tensor> {
+        let dim = dim.to_index(self.shape(), "median")?;
+        let size = self.dim(dim)?;
+        if size == 0 {
+            crate::bail!("median: empty dim {dim}")
+        }
+        let sorted = self.sort_along(dim)?;
+        sorted
+            .narrow(crate::D::Minus1, (size - 1) / 2, 1)?
+            .transpose(dim, self.rank() - 1)
+    }
+
+    /// Returns the median of the values along `dim`, see [`Tensor::median_keepdim`], the reduced
+    /// dimension is squeezed.
+    pub fn median<D: crate::shape::Dim>(&self, dim: D) -> Result<Tensor> {
+        let dim = dim.to_index(self.shape(), "median")?;
+        self.median_keepdim(dim)?.squeeze(dim)
+    }
+
+    /// Returns the most frequent value along `dim`, the smallest one when several values are
+    /// equally frequent. The reduced dimension is kept with a single element.
+    ///
+    /// The occurrences are counted by comparing all the pairs of values so this uses memory
+    /// quadratic in the size of `dim`.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[[3u32, 1, 3, 2], [4, 2, 4, 2]], &Device::Cpu)?;
+    /// let m = a.mode(1)?;
+    /// assert_eq!(m.to_vec1::<u32>()?, &[3, 2]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn mode_keepdim<D: crate::shape::Dim>(&self, dim: D) -> Result<Tensor> {
+        let dim = dim.to_index(self.shape(), "mode")?;
+        if self.dim(dim)? == 0 {
+            crate::bail!("mode: empty dim {dim}")
+        }
+        let sorted = self.sort_along(dim)?;
+        let rank = sorted.rank();
+        let counts = sorted
+            .unsqueeze(rank)?
+            .broadcast_eq(&sorted.unsqueeze(rank - 1)?)?
+            .to_dtype(crate::DType::U32)?
+            .sum(rank)?;
+        // The values being sorted, the first maximum count is the smallest most frequent value.
+        let index = counts.argmax_keepdim(crate::D::Minus1)?;
+        sorted
+            .gather(&index, crate::D::Minus1)?
+            .transpose(dim, rank - 1)
+    }
+
+    /// Returns the most frequent value along `dim`, see [`Tensor::mode_keepdim`], the reduced
+    /// dimension is squeezed.
+    pub fn mode<D: crate::shape::Dim>(&self, dim: D) -> Result<Tensor> {
+        let dim = dim.to_index(self.shape(), "mode")?;
+        self.mode_keepdim(dim)?.squeeze(dim)
+    }
 }
@@ -1,6 +1,9 @@
-use crate::backend::BackendStorage;
+use crate::backend::{BackendDevice, BackendStorage};
+use crate::meta_backend::MetaStorage;
 use crate::op::{self, CmpOp, ReduceOp};
-use crate::{CpuStorage, CudaStorage, DType, Device, Error, Layout, MetalStorage, Result, Shape};
+use crate::{
+    CpuStorage, CudaStorage, DType, Device, Error, Layout, MetalStorage, Result, Shape, WgpuStorage,
+};
 use crate::{CustomOp1, CustomOp2, CustomOp3, InplaceOp1, InplaceOp2, InplaceOp3};
 
 // We do not want to implement Clone on Storage as cloning may fail because of
@@ -10,6 +13,8 @@ pub enum Storage {
     Cpu(CpuStorage),
     Cuda(CudaStorage),
     Metal(MetalStorage),
+    Wgpu(WgpuStorage),
+    Meta(MetaStorage),
 }
 
 impl Storage {
@@ -24,6 +29,14 @@ impl Storage {
                 let storage = storage.try_clone(layout)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Wgpu(storage) => {
+                let storage = storage.try_clone(layout)?;
+                Ok(Self::Wgpu(storage))
+            }
+            Self::Meta(storage) => {
+                let storage = storage.try_clone(layout)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -32,6 +45,8 @@ impl Storage {
             Self::Cpu(_) => Device::Cpu,
             Self::Cuda(storage) => Device::Cuda(storage.device().clone()),
             Self::Metal(storage) => Device::Metal(storage.device().clone()),
+            Self::Wgpu(storage) => Device::Wgpu(storage.device().clone()),
+            Self::Meta(_) => Device::Meta,
         }
     }
 
@@ -40,6 +55,8 @@ impl Storage {
             Self::Cpu(storage) => storage.dtype(),
             Self::Cuda(storage) => storage.dtype(),
             Self::Metal(storage) => storage.dtype(),
+            Self::Wgpu(storage) => storage.dtype(),
+            Self::Meta(storage) => storage.dtype(),
         }
     }
 
@@ -48,8 +65,8 @@ impl Storage {
         let rhs_device = rhs.device();
         let lhs = lhs_device.location();
         let rhs = rhs_device.location();
-        let same_device = if self.device().is_metal() {
-            // On metal, we require the device to be exactly the same rather than
+        let same_device = if self.device().is_metal() || self.device().is_wgpu() {
+            // On metal and wgpu, we require the device to be exactly the same rather than
             // having the same location. In cuda this is not necessary as all CudaDevice on the
             // same GPU will use the same cuda stream.
             lhs_device.same_device(&rhs_device)
@@ -87,6 +104,14 @@ impl Storage {
                 let storage = storage.affine(layout, mul, add)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Wgpu(storage) => {
+                let storage = storage.affine(layout, mul, add)?;
+                Ok(Self::Wgpu(storage))
+            }
+            Self::Meta(storage) => {
+                let storage = storage.affine(layout, mul, add)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -104,6 +129,14 @@ impl Storage {
                 let storage = storage.powf(layout, alpha)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Wgpu(storage) => {
+                let storage = storage.powf(layout, alpha)?;
+                Ok(Self::Wgpu(storage))
+            }
+            Self::Meta(storage) => {
+                let storage = storage.powf(layout, alpha)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -121,6 +154,14 @@ impl Storage {
                 let storage = storage.elu(layout, alpha)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Wgpu(storage) => {
+                let storage = storage.elu(layout, alpha)?;
+                Ok(Self::Wgpu(storage))
+            }
+            Self::Meta(storage) => {
+                let storage = storage.elu(layout, alpha)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -146,6 +187,14 @@ impl Storage {
                 let storage = lhs.cmp(op, rhs, lhs_layout, rhs_layout)?;
                 Ok(Self::Metal(storage))
             }
+            (Self::Wgpu(lhs), Self::Wgpu(rhs)) => {
+                let storage = lhs.cmp(op, rhs, lhs_layout, rhs_layout)?;
+                Ok(Self::Wgpu(storage))
+            }
+            (Self::Meta(lhs), Self::Meta(rhs)) => {
+                let storage = lhs.cmp(op, rhs, lhs_layout, rhs_layout)?;
+                Ok(Self::Meta(storage))
+            }
             (lhs, rhs) => {
                 // Should not happen because of the same device check above but we're defensive
                 // anyway.
@@ -173,6 +222,14 @@ impl Storage {
                 let storage = storage.reduce_op(op, layout, s)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Wgpu(storage) => {
+                let storage = storage.reduce_op(op, layout, s)?;
+                Ok(Self::Wgpu(storage))
+            }
+            Self::Meta(storage) => {
+                let storage = storage.reduce_op(op, layout, s)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -190,6 +247,14 @@ impl Storage {
                 let storage = storage.to_dtype(layout, dtype)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Wgpu(storage) => {
+                let storage = storage.to_dtype(layout, dtype)?;
+                Ok(Self::Wgpu(storage))
+            }
+            Self::Meta(storage) => {
+                let storage = storage.to_dtype(layout, dtype)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -207,6 +272,14 @@ impl Storage {
                 let (storage, shape) = c.metal_fwd(storage, l)?;
                 Ok((Self::Metal(storage), shape))
             }
+            Self::Wgpu(storage) => {
+                let (storage, shape) = c.wgpu_fwd(storage, l)?;
+                Ok((Self::Wgpu(storage), shape))
+            }
+            Self::Meta(storage) => {
+                let (storage, shape) = c.meta_fwd(storage, l)?;
+                Ok((Self::Meta(storage), shape))
+            }
         }
     }
 
@@ -231,6 +304,14 @@ impl Storage {
                 let (s, shape) = c.metal_fwd(s1, l1, s2, l2)?;
                 Ok((Self::Metal(s), shape))
             }
+            (Self::Wgpu(s1), Self::Wgpu(s2)) => {
+                let (s, shape) = c.wgpu_fwd(s1, l1, s2, l2)?;
+                Ok((Self::Wgpu(s), shape))
+            }
+            (Self::Meta(s1), Self::Meta(s2)) => {
+                let (s, shape) = c.meta_fwd(s1, l1, s2, l2)?;
+                Ok((Self::Meta(s), shape))
+            }
             _ => unreachable!(),
         }
     }
@@ -259,6 +340,14 @@ impl Storage {
                 let (s, shape) = c.metal_fwd(s1, l1, s2, l2, s3, l3)?;
                 Ok((Self::Metal(s), shape))
             }
+            (Self::Wgpu(s1), Self::Wgpu(s2), Self::Wgpu(s3)) => {
+                let (s, shape) = c.wgpu_fwd(s1, l1, s2, l2, s3, l3)?;
+                Ok((Self::Wgpu(s), shape))
+            }
+            (Self::Meta(s1), Self::Meta(s2), Self::Meta(s3)) => {
+                let (s, shape) = c.meta_fwd(s1, l1, s2, l2, s3, l3)?;
+                Ok((Self::Meta(s), shape))
+            }
             _ => unreachable!(),
         }
     }
@@ -268,6 +357,9 @@ impl Storage {
             Self::Cpu(storage) => c.cpu_fwd(storage, l),
             Self::Cuda(storage) => c.cuda_fwd(storage, l),
             Self::Metal(storage) => c.metal_fwd(storage, l),
+            Self::Wgpu(storage) => c.wgpu_fwd(storage, l),
+            // In place ops do not change the shape or dtype of meta tensors.
+            Self::Meta(_) => Ok(()),
         }
     }
 
@@ -283,6 +375,8 @@ impl Storage {
             (Self::Cpu(s1), Self::Cpu(s2)) => c.cpu_fwd(s1, l1, s2, l2),
             (Self::Cuda(s1), Self::Cuda(s2)) => c.cuda_fwd(s1, l1, s2, l2),
             (Self::Metal(s1), Self::Metal(s2)) => c.metal_fwd(s1, l1, s2, l2),
+            (Self::Wgpu(s1), Self::Wgpu(s2)) => c.wgpu_fwd(s1, l1, s2, l2),
+            (Self::Meta(_), Self::Meta(_)) => Ok(()),
             _ => unreachable!(),
         }
     }
@@ -304,6 +398,8 @@ impl Storage {
             (Self::Metal(s1), Self::Metal(s2), Self::Metal(s3)) => {
                 c.metal_fwd(s1, l1, s2, l2, s3, l3)
             }
+            (Self::Wgpu(s1), Self::Wgpu(s2), Self::Wgpu(s3)) => c.wgpu_fwd(s1, l1, s2, l2, s3, l3),
+            (Self::Meta(_), Self::Meta(_), Self::Meta(_)) => Ok(()),
             _ => unreachable!(),
         }
     }
@@ -322,6 +418,14 @@ impl Storage {
                 let storage = storage.unary_impl::<B>(layout)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Wgpu(storage) => {
+                let storage = storage.unary_impl::<B>(layout)?;
+                Ok(Self::Wgpu(storage))
+            }
+            Self::Meta(storage) => {
+                let storage = storage.unary_impl::<B>(layout)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -346,6 +450,14 @@ impl Storage {
                 let storage = lhs.binary_impl::<B>(rhs, lhs_layout, rhs_layout)?;
                 Ok(Self::Metal(storage))
             }
+            (Self::Wgpu(lhs), Self::Wgpu(rhs)) => {
+                let storage = lhs.binary_impl::<B>(rhs, lhs_layout, rhs_layout)?;
+                Ok(Self::Wgpu(storage))
+            }
+            (Self::Meta(lhs), Self::Meta(rhs)) => {
+                let storage = lhs.binary_impl::<B>(rhs, lhs_layout, rhs_layout)?;
+                Ok(Self::Meta(storage))
+            }
             (lhs, rhs) => {
                 // Should not happen because of the same device check above but we're defensive
                 // anyway.
@@ -381,6 +493,14 @@ impl Storage {
                 let s = inp.conv1d(l, kernel, kernel_l, params)?;
                 Ok(Self::Metal(s))
             }
+            (Storage::Wgpu(inp), Storage::Wgpu(kernel)) => {
+                let s = inp.conv1d(l, kernel, kernel_l, params)?;
+                Ok(Self::Wgpu(s))
+            }
+            (Storage::Meta(inp), Storage::Meta(kernel)) => {
+                let s = inp.conv1d(l, kernel, kernel_l, params)?;
+                Ok(Self::Meta(s))
+            }
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
@@ -412,6 +532,14 @@ impl Storage {
                 let s = inp.conv_transpose1d(l, kernel, kernel_l, params)?;
                 Ok(Self::Metal(s))
             }
+            (Storage::Wgpu(inp), Storage::Wgpu(kernel)) => {
+                let s = inp.conv_transpose1d(l, kernel, kernel_l, params)?;
+                Ok(Self::Wgpu(s))
+            }
+            (Storage::Meta(inp), Storage::Meta(kernel)) => {
+                let s = inp.conv_transpose1d(l, kernel, kernel_l, params)?;
+                Ok(Self::Meta(s))
+            }
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
@@ -443,6 +571,14 @@ impl Storage {
                 let s = inp.conv2d(l, kernel, kernel_l, params)?;
                 Ok(Self::Metal(s))
             }
+            (Storage::Wgpu(inp), Storage::Wgpu(kernel)) => {
+                let s = inp.conv2d(l, kernel, kernel_l, params)?;
+                Ok(Self::Wgpu(s))
+            }
+            (Storage::Meta(inp), Storage::Meta(kernel)) => {
+                let s = inp.conv2d(l, kernel, kernel_l, params)?;
+                Ok(Self::Meta(s))
+            }
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
@@ -474,6 +610,14 @@ impl Storage {
                 let s = inp.conv_transpose2d(l, kernel, kernel_l, params)?;
                 Ok(Self::Metal(s))
             }
+            (Storage::Wgpu(inp), Storage::Wgpu(kernel)) => {
+                let s = inp.conv_transpose2d(l, kernel, kernel_l, params)?;
+                Ok(Self::Wgpu(s))
+            }
+            (Storage::Meta(inp), Storage::Meta(kernel)) => {
+                let s = inp.conv_transpose2d(l, kernel, kernel_l, params)?;
+                Ok(Self::Meta(s))
+            }
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
@@ -502,6 +646,14 @@ impl Storage {
                 let storage = storage.avg_pool2d(layout, kernel_size, stride)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Wgpu(storage) => {
+                let storage = storage.avg_pool2d(layout, kernel_size, stride)?;
+                Ok(Self::Wgpu(storage))
+            }
+            Self::Meta(storage) => {
+                let storage = storage.avg_pool2d(layout, kernel_size, stride)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -524,6 +676,14 @@ impl Storage {
                 let storage = storage.max_pool2d(layout, kernel_size, stride)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Wgpu(storage) => {
+                let storage = storage.max_pool2d(layout, kernel_size, stride)?;
+                Ok(Self::Wgpu(storage))
+            }
+            Self::Meta(storage) => {
+                let storage = storage.max_pool2d(layout, kernel_size, stride)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -541,6 +701,14 @@ impl Storage {
                 let storage = storage.upsample_nearest1d(layout, sz)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Wgpu(storage) => {
+                let storage = storage.upsample_nearest1d(layout, sz)?;
+                Ok(Self::Wgpu(storage))
+            }
+            Self::Meta(storage) => {
+                let storage = storage.upsample_nearest1d(layout, sz)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -558,6 +726,14 @@ impl Storage {
                 let storage = storage.upsample_nearest2d(layout, h, w)?;
                 Ok(Self::Metal(storage))
             }
+            Self::Wgpu(storage) => {
+                let storage = storage.upsample_nearest2d(layout, h, w)?;
+                Ok(Self::Wgpu(storage))
+            }
+            Self::Meta(storage) => {
+                let storage = storage.upsample_nearest2d(layout, h, w)?;
+                Ok(Self::Meta(storage))
+            }
         }
     }
 
@@ -585,6 +761,14 @@ impl Storage {
                 let storage = cond.where_cond(layout, t, layout_t, f, layout_f)?;
                 Ok(Self::Metal(storage))
             }
+            (Self::Wgpu(cond), Self::Wgpu(t), Self::Wgpu(f)) => {
+                let storage = cond.where_cond(layout, t, layout_t, f, layout_f)?;
+                Ok(Self::Wgpu(storage))
+            }
+            (Self::Meta(cond), Self::Meta(t), Self::Meta(f)) => {
+                let storage = cond.where_cond(layout, t, layout_t, f, layout_f)?;
+                Ok(Self::Meta(storage))
+            }
             (_, lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
@@ -615,6 +799,14 @@ impl Storage {
                 let storage = s.gather(l, indexes, indexes_l, d)?;
                 Ok(Self::Metal(storage))
             }
+            (Self::Wgpu(s), Self::Wgpu(indexes)) => {
+                let storage = s.gather(l, indexes, indexes_l, d)?;
+                Ok(Self::Wgpu(storage))
+            }
+            (Self::Meta(s), Self::Meta(indexes)) => {
+                let storage = s.gather(l, indexes, indexes_l, d)?;
+                Ok(Self::Meta(storage))
+            }
             _ => unreachable!(),
         }
     }
@@ -643,6 +835,14 @@ impl Storage {
                 let storage = s.scatter_add(l, indexes, indexes_l, source, source_l, d)?;
                 Ok(Self::Metal(storage))
             }
+            (Self::Wgpu(s), Self::Wgpu(indexes), Self::Wgpu(source)) => {
+                let storage = s.scatter_add(l, indexes, indexes_l, source, source_l, d)?;
+                Ok(Self::Wgpu(storage))
+            }
+            (Self::Meta(s), Self::Meta(indexes), Self::Meta(source)) => {
+                let storage = s.scatter_add(l, indexes, indexes_l, source, source_l, d)?;
+                Ok(Self::Meta(storage))
+            }
             _ => unreachable!(),
         }
     }
@@ -671,6 +871,14 @@ impl Storage {
                 let storage = s.index_add(l, indexes, indexes_l, source, source_l, d)?;
                 Ok(Self::Metal(storage))
             }
+            (Self::Wgpu(s), Self::Wgpu(indexes), Self::Wgpu(source)) => {
+                let storage = s.index_add(l, indexes, indexes_l, source, source_l, d)?;
+                Ok(Self::Wgpu(storage))
+            }
+            (Self::Meta(s), Self::Meta(indexes), Self::Meta(source)) => {
+                let storage = s.index_add(l, indexes, indexes_l, source, source_l, d)?;
+                Ok(Self::Meta(storage))
+            }
             _ => unreachable!(),
         }
     }
@@ -696,6 +904,14 @@ impl Storage {
                 let storage = lhs.index_select(rhs, lhs_l, rhs_l, d)?;
                 Ok(Self::Metal(storage))
             }
+            (Self::Wgpu(lhs), Self::Wgpu(rhs)) => {
+                let storage = lhs.index_select(rhs, lhs_l, rhs_l, d)?;
+                Ok(Self::Wgpu(storage))
+            }
+            (Self::Meta(lhs), Self::Meta(rhs)) => {
+                let storage = lhs.index_select(rhs, lhs_l, rhs_l, d)?;
+                Ok(Self::Meta(storage))
+            }
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
@@ -716,17 +932,29 @@ impl Storage {
         self.same_dtype(rhs, "matmul")?;
         match (self, rhs) {
             (Self::Cpu(lhs), Self::Cpu(rhs)) => {
+                // The gemm kernel supports arbitrary batch strides, the blas libraries do not.
+                #[cfg(any(feature = "mkl", feature = "accelerate"))]
+                let storage = matmul_merged_batches(lhs, rhs, bmnk, lhs_layout, rhs_layout)?;
+                #[cfg(all(not(feature = "mkl"), not(feature = "accelerate")))]
                 let storage = lhs.matmul(rhs, bmnk, lhs_layout, rhs_layout)?;
                 Ok(Self::Cpu(storage))
             }
             (Self::Cuda(lhs), Self::Cuda(rhs)) => {
-                let storage = lhs.matmul(rhs, bmnk, lhs_layout, rhs_layout)?;
+                let storage = matmul_merged_batches(lhs, rhs, bmnk, lhs_layout, rhs_layout)?;
                 Ok(Self::Cuda(storage))
             }
             (Self::Metal(lhs), Self::Metal(rhs)) => {
-                let storage = lhs.matmul(rhs, bmnk, lhs_layout, rhs_layout)?;
+                let storage = matmul_merged_batches(lhs, rhs, bmnk, lhs_layout, rhs_layout)?;
                 Ok(Self::Metal(storage))
             }
+            (Self::Wgpu(lhs), Self::Wgpu(rhs)) => {
+                let storage = lhs.matmul(rhs, bmnk, lhs_layout, rhs_layout)?;
+                Ok(Self::Wgpu(storage))
+            }
+            (Self::Meta(lhs), Self::Meta(rhs)) => {
+                let storage = lhs.matmul(rhs, bmnk, lhs_layout, rhs_layout)?;
+                Ok(Self::Meta(storage))
+            }
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
@@ -749,6 +977,8 @@ impl Storage {
             (Self::Metal(src), Self::Metal(dst)) => {
                 Ok(src.copy_strided_src(dst, dst_offset, src_l)?)
             }
+            (Self::Wgpu(src), Self::Wgpu(dst)) => Ok(src.copy_strided_src(dst, dst_offset, src_l)?),
+            (Self::Meta(src), Self::Meta(dst)) => Ok(src.copy_strided_src(dst, dst_offset, src_l)?),
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
@@ -777,6 +1007,12 @@ impl Storage {
             (Self::Metal(src), Self::Metal(dst)) => {
                 Ok(src.copy2d(dst, d1, d2, src_s, dst_s, src_o, dst_o)?)
             }
+            (Self::Wgpu(src), Self::Wgpu(dst)) => {
+                Ok(src.copy2d(dst, d1, d2, src_s, dst_s, src_o, dst_o)?)
+            }
+            (Self::Meta(src), Self::Meta(dst)) => {
+                Ok(src.copy2d(dst, d1, d2, src_s, dst_s, src_o, dst_o)?)
+            }
             (lhs, rhs) => Err(Error::DeviceMismatchBinaryOp {
                 lhs: lhs.device().location(),
                 rhs: rhs.device().location(),
@@ -786,3 +1022,38 @@ impl Storage {
         }
     }
 }
+
+/// Runs a matmul with a kernel that requires the batch dimensions of each operand to use a single
+/// stride, the operands for which this is not the case, e.g. when only some of the batch
+/// dimensions are broadcasted, are first copied to a contiguous storage.
+fn matmul_merged_batches<B>(
+    lhs: &B,
+    rhs: &B,
+    bmnk: (usize, usize, usize, usize),
+    lhs_layout: &Layout,
+    rhs_layout: &Layout,
+) -> Result<B>
+where
+    B: BackendStorage,
+    B::Device: BackendDevice<Storage = B>,
+{
+    let merged = |s: &B, l: &Layout| -> Result<Option<(B, Layout)>> {
+        if l.matmul_batch_stride().is_some() {
+            return Ok(None);
+        }
+        let mut dst = unsafe { s.device().alloc_uninit(l.shape(), s.dtype())? };
+        s.copy_strided_src(&mut dst, 0, l)?;
+        Ok(Some((dst, Layout::contiguous(l.shape()))))
+    };
+    let lhs_c = merged(lhs, lhs_layout)?;
+    let rhs_c = merged(rhs, rhs_layout)?;
+    let (lhs, lhs_layout) = match &lhs_c {
+        Some((s, l)) => (s, l),
+        None => (lhs, lhs_layout),
+    };
+    let (rhs, rhs_layout) = match &rhs_c {
+        Some((s, l)) => (s, l),
+        None => (rhs, rhs_layout),
+    };
+    lhs.matmul(rhs, bmnk, lhs_layout, rhs_layout)
+}
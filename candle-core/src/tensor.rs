@@ -5,7 +5,9 @@ use crate::op::{BackpropOp, BinaryOp, CmpOp, Op, ReduceOp, UnaryOp};
 use crate::scalar::TensorOrScalar;
 use crate::shape::{Dim, Dims};
 use crate::{bail, storage::Storage, DType, Device, Error, Layout, Result, Shape};
-use std::sync::{Arc, RwLock};
+#[cfg(debug_assertions)]
+use std::sync::atomic::{AtomicPtr, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
 
 /// Unique identifier for tensors.
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -40,6 +42,47 @@ pub struct Tensor_ {
     is_variable: bool,
     dtype: DType,
     device: Device,
+    provenance: Provenance,
+}
+
+/// The name of a tensor and the location where it was created, these are only used to give more
+/// context on errors.
+#[derive(Debug, Default)]
+struct Provenance {
+    name: OnceLock<Arc<str>>,
+    #[cfg(debug_assertions)]
+    location: AtomicPtr<std::panic::Location<'static>>,
+}
+
+impl Provenance {
+    // Tensors derived from a named tensor, e.g. a transposed weight, keep its name.
+    fn derive(&self) -> Self {
+        let name = OnceLock::new();
+        if let Some(n) = self.name.get() {
+            let _ = name.set(n.clone());
+        }
+        Self {
+            name,
+            ..Default::default()
+        }
+    }
+}
+
+/// Adds the op and its inputs to errors and, in debug builds, records the caller as the location
+/// where the result was created.
+#[track_caller]
+fn track_op(res: Result<Tensor>, op: &'static str, inputs: &[&Tensor]) -> Result<Tensor> {
+    match res {
+        Ok(t) => {
+            // Ops may return one of their inputs, e.g. narrow over the full dim.
+            if inputs.iter().any(|i| i.id == t.id) {
+                Ok(t)
+            } else {
+                Ok(t.tracked())
+            }
+        }
+        Err(e) => Err(e.with_op(op, inputs.iter().map(|t| t.info()).collect())),
+    }
 }
 
 impl AsRef<Tensor> for Tensor {
@@ -93,18 +136,23 @@ macro_rules! unary_op {
 
 macro_rules! binary_op {
     ($fn_name:ident, $op_name:ident) => {
+        #[track_caller]
         pub fn $fn_name(&self, rhs: &Self) -> Result<Self> {
-            let shape = self.same_shape_binary_op(rhs, stringify!($fn_name))?;
-            if shape.elem_count() == 0 {
-                return Ok(self.clone());
-            }
-            let storage = self.storage().binary_impl::<crate::op::$op_name>(
-                &*rhs.storage(),
-                self.layout(),
-                rhs.layout(),
-            )?;
-            let op = BackpropOp::new2(self, rhs, |t1, t2| Op::Binary(t1, t2, BinaryOp::$op_name));
-            Ok(from_storage(storage, shape.clone(), op, false))
+            let res = (|| -> Result<Self> {
+                let shape = self.same_shape_binary_op(rhs, stringify!($fn_name))?;
+                if shape.elem_count() == 0 {
+                    return Ok(self.clone());
+                }
+                let storage = self.storage().binary_impl::<crate::op::$op_name>(
+                    &*rhs.storage(),
+                    self.layout(),
+                    rhs.layout(),
+                )?;
+                let op =
+                    BackpropOp::new2(self, rhs, |t1, t2| Op::Binary(t1, t2, BinaryOp::$op_name));
+                Ok(from_storage(storage, shape.clone(), op, false))
+            })();
+            track_op(res, stringify!($fn_name), &[self, rhs])
         }
     };
 }
@@ -136,21 +184,25 @@ macro_rules! binary_op_scalar {
 
 macro_rules! broadcast_binary_op {
     ($fn_name:ident, $inner_fn_name:ident) => {
+        #[track_caller]
         pub fn $fn_name(&self, rhs: &Self) -> Result<Self> {
-            let lhs = self;
-            let shape = lhs
-                .shape()
-                .broadcast_shape_binary_op(rhs.shape(), stringify!($fn_name))?;
-            let l_broadcast = shape != *lhs.shape();
-            let r_broadcast = shape != *rhs.shape();
-            match (l_broadcast, r_broadcast) {
-                (true, true) => lhs
-                    .broadcast_as(&shape)?
-                    .$inner_fn_name(&rhs.broadcast_as(&shape)?),
-                (false, true) => lhs.$inner_fn_name(&rhs.broadcast_as(&shape)?),
-                (true, false) => lhs.broadcast_as(&shape)?.$inner_fn_name(rhs),
-                (false, false) => lhs.$inner_fn_name(rhs),
-            }
+            let res = (|| -> Result<Self> {
+                let lhs = self;
+                let shape = lhs
+                    .shape()
+                    .broadcast_shape_binary_op(rhs.shape(), stringify!($fn_name))?;
+                let l_broadcast = shape != *lhs.shape();
+                let r_broadcast = shape != *rhs.shape();
+                match (l_broadcast, r_broadcast) {
+                    (true, true) => lhs
+                        .broadcast_as(&shape)?
+                        .$inner_fn_name(&rhs.broadcast_as(&shape)?),
+                    (false, true) => lhs.$inner_fn_name(&rhs.broadcast_as(&shape)?),
+                    (true, false) => lhs.broadcast_as(&shape)?.$inner_fn_name(rhs),
+                    (false, false) => lhs.$inner_fn_name(rhs),
+                }
+            })();
+            track_op(res, stringify!($fn_name), &[self, rhs])
         }
     };
 }
@@ -172,6 +224,7 @@ pub(crate) fn from_storage<S: Into<Shape>>(
         is_variable,
         dtype,
         device,
+        provenance: Provenance::default(),
     };
     Tensor(Arc::new(tensor_))
 }
@@ -198,8 +251,9 @@ impl Tensor {
     /// // a == b
     /// # Ok::<(), candle_core::Error>(())
     /// ```
+    #[track_caller]
     pub fn ones<S: Into<Shape>>(shape: S, dtype: DType, device: &Device) -> Result<Self> {
-        Self::ones_impl(shape, dtype, device, false)
+        Ok(Self::ones_impl(shape, dtype, device, false)?.tracked())
     }
 
     /// Creates a new tensor filled with ones with same shape, dtype, and device as the other tensor.
@@ -238,8 +292,9 @@ impl Tensor {
     /// // a == b
     /// # Ok::<(), candle_core::Error>(())
     /// ```
+    #[track_caller]
     pub fn zeros<S: Into<Shape>>(shape: S, dtype: DType, device: &Device) -> Result<Self> {
-        Self::zeros_impl(shape, dtype, device, false)
+        Ok(Self::zeros_impl(shape, dtype, device, false)?.tracked())
     }
 
     /// Creates a new tensor filled with ones with same shape, dtype, and device as the other
@@ -284,13 +339,14 @@ impl Tensor {
     }
 
     /// Creates a new tensor initialized with values sampled uniformly between `lo` and `up`.
+    #[track_caller]
     pub fn rand<S: Into<Shape>, T: crate::FloatDType>(
         lo: T,
         up: T,
         s: S,
         device: &Device,
     ) -> Result<Self> {
-        Self::rand_impl(lo, up, s, device, false)
+        Ok(Self::rand_impl(lo, up, s, device, false)?.tracked())
     }
 
     pub fn rand_like(&self, lo: f64, up: f64) -> Result<Self> {
@@ -337,13 +393,14 @@ impl Tensor {
 
     /// Creates a new tensor initialized with values sampled from a normal distribution with the
     /// specified `mean` and standard deviation `std`.
+    #[track_caller]
     pub fn randn<S: Into<Shape>, T: crate::FloatDType>(
         mean: T,
         std: T,
         s: S,
         device: &Device,
     ) -> Result<Self> {
-        Self::randn_impl(mean, std, s, device, false)
+        Ok(Self::randn_impl(mean, std, s, device, false)?.tracked())
     }
 
     pub(crate) fn new_impl<A: crate::device::NdArray>(
@@ -363,9 +420,10 @@ impl Tensor {
     }
 
     /// Creates a new tensor on the specified device using the content and shape of the input.
+    #[track_caller]
     pub fn new<A: crate::device::NdArray>(array: A, device: &Device) -> Result<Self> {
         let shape = array.shape()?;
-        Self::new_impl(array, shape, device, false)
+        Ok(Self::new_impl(array, shape, device, false)?.tracked())
     }
 
     /// Returns a new tensor with all the elements having the same specified value. Note that
@@ -413,6 +471,7 @@ impl Tensor {
     /// assert_eq!(a.to_vec1::<f64>()?, &[2., 3., 4.]);
     /// # Ok::<(), candle_core::Error>(())
     /// ```
+    #[track_caller]
     pub fn arange<D: crate::WithDType>(start: D, end: D, device: &Device) -> Result<Self> {
         Self::arange_step(start, end, D::one(), device)
     }
@@ -426,6 +485,7 @@ impl Tensor {
     /// assert_eq!(a.to_vec1::<f64>()?, &[2.0, 2.5, 3.0, 3.5]);
     /// # Ok::<(), candle_core::Error>(())
     /// ```
+    #[track_caller]
     pub fn arange_step<D: crate::WithDType>(
         start: D,
         end: D,
@@ -449,7 +509,7 @@ impl Tensor {
             }
         }
         let len = data.len();
-        Self::from_vec_impl(data, len, device, false)
+        Ok(Self::from_vec_impl(data, len, device, false)?.tracked())
     }
 
     pub(crate) fn from_vec_impl<S: Into<Shape>, D: crate::WithDType>(
@@ -481,12 +541,13 @@ impl Tensor {
     /// ]);
     /// # Ok::<(), candle_core::Error>(())
     /// ```
+    #[track_caller]
     pub fn from_vec<S: Into<Shape>, D: crate::WithDType>(
         data: Vec<D>,
         shape: S,
         device: &Device,
     ) -> Result<Self> {
-        Self::from_vec_impl(data, shape, device, false)
+        Ok(Self::from_vec_impl(data, shape, device, false)?.tracked())
     }
 
     /// Creates a new tensor initialized with values from the input slice. The number of elements
@@ -502,6 +563,7 @@ impl Tensor {
     /// ]);
     /// # Ok::<(), candle_core::Error>(())
     /// ```
+    #[track_caller]
     pub fn from_slice<S: Into<Shape>, D: crate::WithDType>(
         array: &[D],
         shape: S,
@@ -515,7 +577,7 @@ impl Tensor {
         }
         let storage = device.storage_from_slice(array)?;
         let none = BackpropOp::none();
-        Ok(from_storage(storage, shape, none, false))
+        Ok(from_storage(storage, shape, none, false).tracked())
     }
 
     pub(crate) fn same_shape_binary_op(&self, rhs: &Self, op: &'static str) -> Result<&Shape> {
@@ -608,6 +670,8 @@ impl Tensor {
             Storage::Cpu(cpu_storage) => from_cpu_storage(cpu_storage),
             Storage::Cuda(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
             Storage::Metal(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Wgpu(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Meta(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
         }
     }
 
@@ -807,43 +871,48 @@ impl Tensor {
     /// ]);
     /// # Ok::<(), candle_core::Error>(())
     /// ```
+    #[track_caller]
     pub fn narrow<D: Dim>(&self, dim: D, start: usize, len: usize) -> Result<Self> {
-        let dims = self.dims();
-        let dim = dim.to_index(self.shape(), "narrow")?;
-        let err = |msg| {
-            Err::<(), _>(
-                Error::NarrowInvalidArgs {
-                    shape: self.shape().clone(),
-                    dim,
-                    start,
-                    len,
-                    msg,
-                }
-                .bt(),
-            )
-        };
-        if start > dims[dim] {
-            err("start > dim_len")?
-        }
-        if start.saturating_add(len) > dims[dim] {
-            err("start + len > dim_len")?
-        }
-        if start == 0 && dims[dim] == len {
-            Ok(self.clone())
-        } else {
-            let op = BackpropOp::new1(self, |t| Op::Narrow(t, dim, start, len));
-            let layout = self.layout().narrow(dim, start, len)?;
-            let tensor_ = Tensor_ {
-                id: TensorId::new(),
-                storage: self.storage.clone(),
-                layout,
-                op,
-                is_variable: false,
-                dtype: self.dtype,
-                device: self.device.clone(),
+        let res = (|| -> Result<Self> {
+            let dims = self.dims();
+            let dim = dim.to_index(self.shape(), "narrow")?;
+            let err = |msg| {
+                Err::<(), _>(
+                    Error::NarrowInvalidArgs {
+                        shape: self.shape().clone(),
+                        dim,
+                        start,
+                        len,
+                        msg,
+                    }
+                    .bt(),
+                )
             };
-            Ok(Tensor(Arc::new(tensor_)))
-        }
+            if start > dims[dim] {
+                err("start > dim_len")?
+            }
+            if start.saturating_add(len) > dims[dim] {
+                err("start + len > dim_len")?
+            }
+            if start == 0 && dims[dim] == len {
+                Ok(self.clone())
+            } else {
+                let op = BackpropOp::new1(self, |t| Op::Narrow(t, dim, start, len));
+                let layout = self.layout().narrow(dim, start, len)?;
+                let tensor_ = Tensor_ {
+                    id: TensorId::new(),
+                    storage: self.storage.clone(),
+                    layout,
+                    op,
+                    is_variable: false,
+                    dtype: self.dtype,
+                    device: self.device.clone(),
+                    provenance: self.provenance.derive(),
+                };
+                Ok(Tensor(Arc::new(tensor_)))
+            }
+        })();
+        track_op(res, "narrow", &[self])
     }
 
     fn squeeze_dims(self, dims: &[usize]) -> Result<Self> {
@@ -996,18 +1065,65 @@ impl Tensor {
         self.sum_impl(mean_dims, false)? * scale
     }
 
-    /// Returns the unbiased variance over the selected dimension.
-    pub fn var_keepdim<D: Dim>(&self, dim: D) -> Result<Self> {
-        let dim = dim.to_index(self.shape(), "var")?;
-        let mean = self.mean_keepdim(dim)?;
+    /// Returns the variance over the selected dimensions, the sum of the squared deviations is
+    /// divided by the number of reduced elements minus `correction`, 1 giving the unbiased
+    /// estimator and 0 the population variance. When `keepdim` is false, the reduced dimensions
+    /// are squeezed.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[[1f32, 2., 3., 4.], [2., 2., 2., 2.]], &Device::Cpu)?;
+    /// let v = a.var_with_correction(1, 0, false)?;
+    /// assert_eq!(v.to_vec1::<f32>()?, &[1.25, 0.]);
+    /// let v = a.var_with_correction((0, 1), 0, true)?;
+    /// assert_eq!(v.to_vec2::<f32>()?, &[[0.6875]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn var_with_correction<D: Dims>(
+        &self,
+        var_dims: D,
+        correction: usize,
+        keepdim: bool,
+    ) -> Result<Self> {
+        let var_dims = var_dims.to_indexes(self.shape(), "var")?;
+        let reduced_dim: usize = var_dims.iter().map(|i| self.dims()[*i]).product();
+        let mean = self.mean_keepdim(var_dims.as_slice())?;
         let squares = self.broadcast_sub(&mean)?.sqr()?;
-        squares.sum_impl(dim, true)? / (self.dim(dim)? - 1) as f64
+        // As in pytorch, a correction larger than the number of elements results in inf or nan.
+        let scale = 1f64 / reduced_dim.saturating_sub(correction) as f64;
+        squares.sum_impl(var_dims, keepdim)? * scale
+    }
+
+    /// Returns the unbiased variance over the selected dimensions.
+    pub fn var_keepdim<D: Dims>(&self, var_dims: D) -> Result<Self> {
+        self.var_with_correction(var_dims, 1, true)
+    }
+
+    /// Returns the unbiased variance over the selected dimensions, which are squeezed.
+    pub fn var<D: Dims>(&self, var_dims: D) -> Result<Self> {
+        self.var_with_correction(var_dims, 1, false)
     }
 
-    /// Returns the unbiased variance over the selected dimension.
-    pub fn var<D: Dim>(&self, dim: D) -> Result<Self> {
-        let dim = dim.to_index(self.shape(), "var")?;
-        self.var_keepdim(dim)?.squeeze(dim)
+    /// Returns the standard deviation over the selected dimensions, the square root of
+    /// [`Self::var_with_correction`].
+    pub fn std_with_correction<D: Dims>(
+        &self,
+        std_dims: D,
+        correction: usize,
+        keepdim: bool,
+    ) -> Result<Self> {
+        self.var_with_correction(std_dims, correction, keepdim)?
+            .sqrt()
+    }
+
+    /// Returns the unbiased standard deviation over the selected dimensions.
+    pub fn std_keepdim<D: Dims>(&self, std_dims: D) -> Result<Self> {
+        self.std_with_correction(std_dims, 1, true)
+    }
+
+    /// Returns the unbiased standard deviation over the selected dimensions, which are squeezed.
+    pub fn std<D: Dims>(&self, std_dims: D) -> Result<Self> {
+        self.std_with_correction(std_dims, 1, false)
     }
 
     /// Gathers the maximum value across the selected dimension. The resulting shape has the same
@@ -1032,6 +1148,9 @@ impl Tensor {
         self.reduce_impl(dim, false, ReduceOp::Min)
     }
 
+    /// Returns the index of the maximum value across the selected dimension, the first one when
+    /// there are ties. The indices use `u32` elements and the selected dimension has a single
+    /// element.
     pub fn argmax_keepdim<D: Dim>(&self, dim: D) -> Result<Self> {
         self.reduce_impl(dim, true, ReduceOp::ArgMax)
     }
@@ -1041,6 +1160,9 @@ impl Tensor {
         self.reduce_impl(dim, false, ReduceOp::ArgMax)
     }
 
+    /// Returns the index of the minimum value across the selected dimension, the first one when
+    /// there are ties. The indices use `u32` elements and the selected dimension has a single
+    /// element.
     pub fn argmin_keepdim<D: Dim>(&self, dim: D) -> Result<Self> {
         self.reduce_impl(dim, true, ReduceOp::ArgMin)
     }
@@ -1050,6 +1172,60 @@ impl Tensor {
         self.reduce_impl(dim, false, ReduceOp::ArgMin)
     }
 
+    fn reduce_with_indices<D: Dim>(
+        &self,
+        dim: D,
+        keepdim: bool,
+        op: ReduceOp,
+    ) -> Result<(Self, Self)> {
+        let dim = dim.to_index(self.shape(), op.name())?;
+        // The values are gathered at the indices rather than reduced a second time so that they
+        // are consistent with the indices on ties, and the gradients flow back to the selected
+        // elements only.
+        // The gather only supports contiguous inputs.
+        let indices = self.reduce_impl(dim, true, op)?;
+        let values = self.contiguous()?.gather(&indices, dim)?;
+        if keepdim {
+            Ok((values, indices))
+        } else {
+            Ok((values.squeeze(dim)?, indices.squeeze(dim)?))
+        }
+    }
+
+    /// Returns both the maximum values across the selected dimension and their indices, as
+    /// returned by `max_keepdim` and `argmax_keepdim`. This is a convenience wrapper around
+    /// `argmax_keepdim` followed by a `gather` of the values at these indices, so it does not
+    /// save any work compared to calling both and copies non-contiguous inputs.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[[3f32, 7., 7.], [2., 1., 0.]], &Device::Cpu)?;
+    /// let (values, indices) = a.max_with_indices_keepdim(1)?;
+    /// assert_eq!(values.to_vec2::<f32>()?, &[[7.], [2.]]);
+    /// assert_eq!(indices.to_vec2::<u32>()?, &[[1], [0]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn max_with_indices_keepdim<D: Dim>(&self, dim: D) -> Result<(Self, Self)> {
+        self.reduce_with_indices(dim, true, ReduceOp::ArgMax)
+    }
+
+    /// Similar to `max_with_indices_keepdim` but the target dimension is squeezed.
+    pub fn max_with_indices<D: Dim>(&self, dim: D) -> Result<(Self, Self)> {
+        self.reduce_with_indices(dim, false, ReduceOp::ArgMax)
+    }
+
+    /// Returns both the minimum values across the selected dimension and their indices, as
+    /// returned by `min_keepdim` and `argmin_keepdim`. Similar to `max_with_indices_keepdim`,
+    /// this is a convenience wrapper around `argmin_keepdim` followed by a `gather`.
+    pub fn min_with_indices_keepdim<D: Dim>(&self, dim: D) -> Result<(Self, Self)> {
+        self.reduce_with_indices(dim, true, ReduceOp::ArgMin)
+    }
+
+    /// Similar to `min_with_indices_keepdim` but the target dimension is squeezed.
+    pub fn min_with_indices<D: Dim>(&self, dim: D) -> Result<(Self, Self)> {
+        self.reduce_with_indices(dim, false, ReduceOp::ArgMin)
+    }
+
     /// Element-wise comparison between two tensors, e.g. equality, greater than, ... The actual
     /// comparison operation is specified by the `op` argument.
     ///
@@ -1109,6 +1285,78 @@ impl Tensor {
         self.maximum(min)?.minimum(max)
     }
 
+    /// Element-wise check for nan values, the returned tensor uses value 1 where `self` is nan and
+    /// 0 otherwise. Integer tensors never contain nan values.
+    pub fn isnan(&self) -> Result<Self> {
+        // Nan is the only value that is not equal to itself.
+        self.ne(self)
+    }
+
+    /// Element-wise check for infinite values, the returned tensor uses value 1 where `self` is
+    /// either `inf` or `-inf` and 0 otherwise.
+    pub fn isinf(&self) -> Result<Self> {
+        if !self.dtype().is_float() {
+            return self.zeros_like()?.to_dtype(DType::U8);
+        }
+        self.abs()?.eq(f64::INFINITY)
+    }
+
+    /// Replaces the nan, `inf` and `-inf` values with the given values, which are converted to
+    /// the dtype of the tensor, e.g. to sanitize logits before sampling. Integer tensors are
+    /// returned unchanged.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let t = Tensor::new(&[f32::NAN, 1., f32::INFINITY, f32::NEG_INFINITY], &Device::Cpu)?;
+    /// let t = t.nan_to_num(0., 1e4, -1e4)?;
+    /// assert_eq!(t.to_vec1::<f32>()?, &[0., 1., 1e4, -1e4]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn nan_to_num(&self, nan: f64, posinf: f64, neginf: f64) -> Result<Self> {
+        if !self.dtype().is_float() {
+            return Ok(self.clone());
+        }
+        let full = |v: f64| self.zeros_like()?.affine(0., v);
+        let t = self.isnan()?.where_cond(&full(nan)?, self)?;
+        let t = t.eq(f64::INFINITY)?.where_cond(&full(posinf)?, &t)?;
+        t.eq(f64::NEG_INFINITY)?.where_cond(&full(neginf)?, &t)
+    }
+
+    /// Element-wise comparison with a tolerance, the returned tensor uses value 1 where
+    /// `|self - other| <= atol + rtol * |other|` and 0 otherwise, as for numpy's `isclose`.
+    /// Nan values are never close, infinite values are only close to themselves.
+    pub fn isclose(&self, other: &Self, rtol: f64, atol: f64) -> Result<Self> {
+        let (lhs, rhs) = if self.dtype().is_float() {
+            (self.clone(), other.clone())
+        } else {
+            (self.to_dtype(DType::F32)?, other.to_dtype(DType::F32)?)
+        };
+        let diff = lhs.sub(&rhs)?.abs()?;
+        let tolerance = rhs.abs()?.affine(rtol, atol)?;
+        // The tolerance is infinite for infinite values so these are compared for equality.
+        rhs.isinf()?
+            .where_cond(&lhs.eq(&rhs)?, &diff.le(&tolerance)?)
+    }
+
+    /// Returns true if all the elements of the two tensors are close, see [`Self::isclose`]. The
+    /// comparison is done on the device, only the result is copied back.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[1f32, 2., 3.], &Device::Cpu)?;
+    /// let b = Tensor::new(&[1f32, 2.0001, 3.], &Device::Cpu)?;
+    /// assert!(a.allclose(&b, 1e-4, 1e-5)?);
+    /// assert!(!a.allclose(&b, 0., 1e-5)?);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn allclose(&self, other: &Self, rtol: f64, atol: f64) -> Result<bool> {
+        let close = self.isclose(other, rtol, atol)?;
+        if close.elem_count() == 0 {
+            return Ok(true);
+        }
+        Ok(close.flatten_all()?.min(0)?.to_scalar::<u8>()? == 1)
+    }
+
     /// Interpolate the input tensor to the `target_size` size, taking the value of the nearest element.
     ///
     /// The input tensor should have three dimensions, `(batch, channels, l)`, the returned
@@ -1234,49 +1482,53 @@ impl Tensor {
     /// * `rhs` - A tensor with dimensions `b1, b2, ..., bi, k, n`.
     ///
     /// The resulting tensor has dimensions `b1, b2, ..., bi, m, n`.
+    #[track_caller]
     pub fn matmul(&self, rhs: &Self) -> Result<Self> {
-        let a_dims = self.shape().dims();
-        let b_dims = rhs.shape().dims();
+        let res = (|| -> Result<Self> {
+            let a_dims = self.shape().dims();
+            let b_dims = rhs.shape().dims();
 
-        let dim = a_dims.len();
+            let dim = a_dims.len();
 
-        if dim < 2 || b_dims.len() != dim {
-            Err(Error::ShapeMismatchBinaryOp {
-                lhs: self.shape().clone(),
-                rhs: rhs.shape().clone(),
-                op: "matmul",
+            if dim < 2 || b_dims.len() != dim {
+                Err(Error::ShapeMismatchBinaryOp {
+                    lhs: self.shape().clone(),
+                    rhs: rhs.shape().clone(),
+                    op: "matmul",
+                }
+                .bt())?
             }
-            .bt())?
-        }
 
-        let m = a_dims[dim - 2];
-        let k = a_dims[dim - 1];
-        let k2 = b_dims[dim - 2];
-        let n = b_dims[dim - 1];
+            let m = a_dims[dim - 2];
+            let k = a_dims[dim - 1];
+            let k2 = b_dims[dim - 2];
+            let n = b_dims[dim - 1];
 
-        let c_shape = Shape::from(&a_dims[..dim - 2]).extend(&[m, n]);
-        if c_shape.elem_count() == 0 || k == 0 {
-            return Tensor::zeros(c_shape, self.dtype(), self.device());
-        }
-        let batching: usize = a_dims[..dim - 2].iter().product();
-        let batching_b: usize = b_dims[..dim - 2].iter().product();
-        if k != k2 || batching != batching_b {
-            Err(Error::ShapeMismatchBinaryOp {
-                lhs: self.shape().clone(),
-                rhs: rhs.shape().clone(),
-                op: "matmul",
+            let c_shape = Shape::from(&a_dims[..dim - 2]).extend(&[m, n]);
+            if c_shape.elem_count() == 0 || k == 0 {
+                return Tensor::zeros(c_shape, self.dtype(), self.device());
+            }
+            let batching: usize = a_dims[..dim - 2].iter().product();
+            let batching_b: usize = b_dims[..dim - 2].iter().product();
+            if k != k2 || batching != batching_b {
+                Err(Error::ShapeMismatchBinaryOp {
+                    lhs: self.shape().clone(),
+                    rhs: rhs.shape().clone(),
+                    op: "matmul",
+                }
+                .bt())?
             }
-            .bt())?
-        }
 
-        let storage = self.storage().matmul(
-            &rhs.storage(),
-            (batching, m, n, k),
-            self.layout(),
-            rhs.layout(),
-        )?;
-        let op = BackpropOp::new2(self, rhs, Op::Matmul);
-        Ok(from_storage(storage, c_shape, op, false))
+            let storage = self.storage().matmul(
+                &rhs.storage(),
+                (batching, m, n, k),
+                self.layout(),
+                rhs.layout(),
+            )?;
+            let op = BackpropOp::new2(self, rhs, Op::Matmul);
+            Ok(from_storage(storage, c_shape, op, false))
+        })();
+        track_op(res, "matmul", &[self, rhs])
     }
 
     /// Matrix-multiplication with broadcasting support.
@@ -1284,38 +1536,46 @@ impl Tensor {
     /// Compared to `matmul` the two matrixes are allowed to have different dimensions as long as
     /// they are compatible for broadcast. E.g. if `self` has shape `(j, 1, n, k)` and `rhs` has
     /// shape `(l, k, m)`, the output will have shape `(j, l, n, m)`.
+    #[track_caller]
     pub fn broadcast_matmul(&self, rhs: &Self) -> Result<Self> {
-        let lhs = self;
-        let (l_shape, r_shape) = lhs.shape().broadcast_shape_matmul(rhs.shape())?;
-        let l_broadcast = l_shape != *lhs.shape();
-        let r_broadcast = r_shape != *rhs.shape();
-        // TODO: Avoid concretising the broadcasted matrixes via contiguous.
-        match (l_broadcast, r_broadcast) {
-            (true, true) => lhs
-                .broadcast_as(&l_shape)?
-                .contiguous()?
-                .matmul(&rhs.broadcast_as(&r_shape)?.contiguous()?),
-            (false, true) => lhs.matmul(&rhs.broadcast_as(&r_shape)?.contiguous()?),
-            (true, false) => lhs.broadcast_as(&l_shape)?.contiguous()?.matmul(rhs),
-            (false, false) => lhs.matmul(rhs),
-        }
+        let res = (|| -> Result<Self> {
+            let lhs = self;
+            let (l_shape, r_shape) = lhs.shape().broadcast_shape_matmul(rhs.shape())?;
+            let l_broadcast = l_shape != *lhs.shape();
+            let r_broadcast = r_shape != *rhs.shape();
+            // The broadcasted batch dimensions have a stride of 0 and are handled by the
+            // backends, the operands only get copied when their batch dimensions cannot be merged.
+            match (l_broadcast, r_broadcast) {
+                (true, true) => lhs
+                    .broadcast_as(&l_shape)?
+                    .matmul(&rhs.broadcast_as(&r_shape)?),
+                (false, true) => lhs.matmul(&rhs.broadcast_as(&r_shape)?),
+                (true, false) => lhs.broadcast_as(&l_shape)?.matmul(rhs),
+                (false, false) => lhs.matmul(rhs),
+            }
+        })();
+        track_op(res, "broadcast_matmul", &[self, rhs])
     }
 
     /// Returns a tensor with the same shape as the input tensor, the values are taken from
     /// `on_true` if the input tensor value is not zero, and `on_false` at the positions where the
     /// input tensor is equal to zero.
+    #[track_caller]
     pub fn where_cond(&self, on_true: &Self, on_false: &Self) -> Result<Self> {
-        let _shap = self.same_shape_binary_op(on_true, "where_cond")?;
-        let shape = self.same_shape_binary_op(on_false, "where_cond")?;
-        let storage = self.storage().where_cond(
-            self.layout(),
-            &on_true.storage(),
-            on_true.layout(),
-            &on_false.storage(),
-            on_false.layout(),
-        )?;
-        let op = BackpropOp::new3(self, on_true, on_false, Op::WhereCond);
-        Ok(from_storage(storage, shape, op, false))
+        let res = (|| -> Result<Self> {
+            let _shap = self.same_shape_binary_op(on_true, "where_cond")?;
+            let shape = self.same_shape_binary_op(on_false, "where_cond")?;
+            let storage = self.storage().where_cond(
+                self.layout(),
+                &on_true.storage(),
+                on_true.layout(),
+                &on_false.storage(),
+                on_false.layout(),
+            )?;
+            let op = BackpropOp::new3(self, on_true, on_false, Op::WhereCond);
+            Ok(from_storage(storage, shape, op, false))
+        })();
+        track_op(res, "where_cond", &[self, on_true, on_false])
     }
 
     /// Returns a tensor with the values from the `self` tensor at the index corresponding to the
@@ -1526,35 +1786,39 @@ impl Tensor {
     ///
     /// The resulting tensor has the same shape as `indexes` and use values from `self` indexed on
     /// dimension `dim` by the values in `indexes`.
+    #[track_caller]
     pub fn gather<D: Dim>(&self, indexes: &Self, dim: D) -> Result<Self> {
-        let dim = dim.to_index(self.shape(), "gather")?;
-        let self_dims = self.dims();
-        let indexes_dims = indexes.dims();
-        let mismatch = if indexes_dims.len() != self_dims.len() {
-            true
-        } else {
-            let mut mismatch = false;
-            for (i, (&d1, &d2)) in self_dims.iter().zip(indexes_dims.iter()).enumerate() {
-                if i != dim && d1 != d2 {
-                    mismatch = true;
-                    break;
+        let res = (|| -> Result<Self> {
+            let dim = dim.to_index(self.shape(), "gather")?;
+            let self_dims = self.dims();
+            let indexes_dims = indexes.dims();
+            let mismatch = if indexes_dims.len() != self_dims.len() {
+                true
+            } else {
+                let mut mismatch = false;
+                for (i, (&d1, &d2)) in self_dims.iter().zip(indexes_dims.iter()).enumerate() {
+                    if i != dim && d1 != d2 {
+                        mismatch = true;
+                        break;
+                    }
                 }
+                mismatch
+            };
+            if mismatch {
+                Err(Error::ShapeMismatchBinaryOp {
+                    op: "gather",
+                    lhs: self.shape().clone(),
+                    rhs: indexes.shape().clone(),
+                }
+                .bt())?
             }
-            mismatch
-        };
-        if mismatch {
-            Err(Error::ShapeMismatchBinaryOp {
-                op: "gather",
-                lhs: self.shape().clone(),
-                rhs: indexes.shape().clone(),
-            }
-            .bt())?
-        }
-        let storage =
-            self.storage()
-                .gather(self.layout(), &indexes.storage(), indexes.layout(), dim)?;
-        let op = BackpropOp::new2(self, indexes, |t1, t2| Op::Gather(t1, t2, dim));
-        Ok(from_storage(storage, indexes.shape(), op, false))
+            let storage =
+                self.storage()
+                    .gather(self.layout(), &indexes.storage(), indexes.layout(), dim)?;
+            let op = BackpropOp::new2(self, indexes, |t1, t2| Op::Gather(t1, t2, dim));
+            Ok(from_storage(storage, indexes.shape(), op, false))
+        })();
+        track_op(res, "gather", &[self, indexes])
     }
 
     /// Select values for the input tensor at the target indexes across the specified dimension.
@@ -1564,27 +1828,31 @@ impl Tensor {
     /// the output has length the length of `indexes` and the values are taken from `self` using
     /// the index from `indexes`. Other dimensions have the same number of elements as the input
     /// tensor.
+    #[track_caller]
     pub fn index_select<D: Dim>(&self, indexes: &Self, dim: D) -> Result<Self> {
-        let dim = dim.to_index(self.shape(), "index-select")?;
-        let indexes_len = match indexes.dims() {
-            [l] => *l,
-            _ => Err(Error::ShapeMismatchBinaryOp {
-                lhs: self.shape().clone(),
-                rhs: indexes.shape().clone(),
-                op: "index-select",
-            }
-            .bt())?,
-        };
-        let storage = self.storage().index_select(
-            &indexes.storage(),
-            self.layout(),
-            indexes.layout(),
-            dim,
-        )?;
-        let mut dims = self.dims().to_vec();
-        dims[dim] = indexes_len;
-        let op = BackpropOp::new2(self, indexes, |t1, t2| Op::IndexSelect(t1, t2, dim));
-        Ok(from_storage(storage, dims, op, false))
+        let res = (|| -> Result<Self> {
+            let dim = dim.to_index(self.shape(), "index-select")?;
+            let indexes_len = match indexes.dims() {
+                [l] => *l,
+                _ => Err(Error::ShapeMismatchBinaryOp {
+                    lhs: self.shape().clone(),
+                    rhs: indexes.shape().clone(),
+                    op: "index-select",
+                }
+                .bt())?,
+            };
+            let storage = self.storage().index_select(
+                &indexes.storage(),
+                self.layout(),
+                indexes.layout(),
+                dim,
+            )?;
+            let mut dims = self.dims().to_vec();
+            dims[dim] = indexes_len;
+            let op = BackpropOp::new2(self, indexes, |t1, t2| Op::IndexSelect(t1, t2, dim));
+            Ok(from_storage(storage, dims, op, false))
+        })();
+        track_op(res, "index_select", &[self, indexes])
     }
 
     /// Returns an iterator over position of the elements in the storage when ranging over the
@@ -1623,6 +1891,8 @@ impl Tensor {
             Storage::Cpu(storage) => from_cpu_storage(storage),
             Storage::Cuda(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
             Storage::Metal(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Wgpu(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Meta(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
         }
     }
 
@@ -1654,6 +1924,8 @@ impl Tensor {
             Storage::Cpu(storage) => from_cpu_storage(storage),
             Storage::Cuda(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
             Storage::Metal(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Wgpu(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Meta(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
         }
     }
 
@@ -1695,6 +1967,8 @@ impl Tensor {
             Storage::Cpu(storage) => from_cpu_storage(storage),
             Storage::Cuda(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
             Storage::Metal(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Wgpu(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
+            Storage::Meta(storage) => from_cpu_storage(&storage.to_cpu_storage()?),
         }
     }
 
@@ -1759,6 +2033,53 @@ impl Tensor {
         &self.op
     }
 
+    /// The name of this tensor if any, e.g. the path of a weight loaded via a `VarBuilder`. The
+    /// name is included in errors for the ops using this tensor or tensors derived from it.
+    pub fn name(&self) -> Option<&str> {
+        self.provenance.name.get().map(|n| n.as_ref())
+    }
+
+    /// Sets the name of this tensor, this has no effect if the tensor already has a name.
+    pub fn set_name<S: AsRef<str>>(&self, name: S) {
+        let _ = self.provenance.name.set(name.as_ref().into());
+    }
+
+    /// The location where this tensor was created, this is only tracked in debug builds.
+    pub fn location(&self) -> Option<&'static std::panic::Location<'static>> {
+        #[cfg(debug_assertions)]
+        {
+            let location = self.provenance.location.load(Ordering::Relaxed);
+            // Safety: only pointers to static locations are stored.
+            unsafe { location.as_ref() }
+        }
+        #[cfg(not(debug_assertions))]
+        None
+    }
+
+    /// Records the caller as the location where this tensor was created, in debug builds only.
+    #[track_caller]
+    fn tracked(self) -> Self {
+        #[cfg(debug_assertions)]
+        {
+            let location = std::panic::Location::caller();
+            self.provenance
+                .location
+                .store(location as *const _ as *mut _, Ordering::Relaxed);
+        }
+        self
+    }
+
+    /// The details on this tensor that are reported in errors.
+    pub fn info(&self) -> crate::error::TensorInfo {
+        crate::error::TensorInfo {
+            shape: self.shape().clone(),
+            dtype: self.dtype(),
+            device: self.device().location(),
+            name: self.name().map(|n| n.to_string()),
+            location: self.location(),
+        }
+    }
+
     /// Computes the sum of all the elements in this tensor and returns a tensor holding this
     /// scalar with zero dimensions.
     ///
@@ -1916,6 +2237,7 @@ impl Tensor {
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            provenance: self.provenance.derive(),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -1952,6 +2274,7 @@ impl Tensor {
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            provenance: self.provenance.derive(),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -1978,6 +2301,7 @@ impl Tensor {
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            provenance: self.provenance.derive(),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -1998,6 +2322,7 @@ impl Tensor {
                 is_variable: false,
                 dtype: self.dtype,
                 device: self.device.clone(),
+                provenance: self.provenance.derive(),
             };
             Tensor(Arc::new(tensor_))
         }
@@ -2015,8 +2340,12 @@ impl Tensor {
                 (Storage::Cpu(storage), Device::Metal(metal)) => {
                     Storage::Metal(metal.storage_from_cpu_storage(storage)?)
                 }
+                (Storage::Cpu(storage), Device::Wgpu(wgpu)) => {
+                    Storage::Wgpu(wgpu.storage_from_cpu_storage(storage)?)
+                }
                 (Storage::Cuda(storage), Device::Cpu) => Storage::Cpu(storage.to_cpu_storage()?),
                 (Storage::Metal(storage), Device::Cpu) => Storage::Cpu(storage.to_cpu_storage()?),
+                (Storage::Wgpu(storage), Device::Cpu) => Storage::Cpu(storage.to_cpu_storage()?),
                 (Storage::Cuda(storage), Device::Cuda(cuda)) => {
                     // TODO: Avoid passing through the cpu storage here, especially if the gpu ids
                     // are the same.
@@ -2024,6 +2353,8 @@ impl Tensor {
                     Storage::Cuda(cuda.storage_from_cpu_storage(&cpu_storage)?)
                 }
                 (Storage::Cpu(storage), Device::Cpu) => Storage::Cpu(storage.clone()),
+                // Moving a tensor to the meta device drops its data.
+                (storage, Device::Meta) => Storage::Meta(crate::MetaStorage::new(storage.dtype())),
                 _ => {
                     bail!(
                         "not implemented yet, self.device: {:?}, device: {:?}",
@@ -2041,11 +2372,37 @@ impl Tensor {
                 is_variable: false,
                 dtype: self.dtype,
                 device: device.clone(),
+                provenance: self.provenance.derive(),
             };
             Ok(Tensor(Arc::new(tensor_)))
         }
     }
 
+    /// Copies the tensor to the cpu, the same as `to_device(&Device::Cpu)` except that for wgpu
+    /// tensors this does not block while waiting for the data. This is required to read wgpu
+    /// tensors in the browser.
+    pub async fn to_cpu_async(&self) -> Result<Tensor> {
+        let pending = match &*self.storage() {
+            Storage::Wgpu(storage) => Some(storage.to_cpu_storage_async()),
+            _ => None,
+        };
+        let storage = match pending {
+            None => return self.to_device(&Device::Cpu),
+            Some(pending) => pending.await?,
+        };
+        let tensor_ = Tensor_ {
+            id: TensorId::new(),
+            storage: Arc::new(RwLock::new(Storage::Cpu(storage))),
+            layout: self.layout.clone(),
+            op: BackpropOp::new1(self, Op::ToDevice),
+            is_variable: false,
+            dtype: self.dtype,
+            device: Device::Cpu,
+            provenance: self.provenance.derive(),
+        };
+        Ok(Tensor(Arc::new(tensor_)))
+    }
+
     /// Returns a new tensor duplicating data from the original tensor. New dimensions are inserted
     /// on the left.
     pub fn broadcast_left<S: Into<Shape>>(&self, left_shape: S) -> Result<Self> {
@@ -2071,11 +2428,25 @@ impl Tensor {
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            provenance: self.provenance.derive(),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
 
     /// An alias for broadcast_as.
+    ///
+    /// No data gets copied, the returned tensor shares the storage of `self` and the expanded
+    /// dimensions have a stride of 0. Binary and ternary ops use such tensors directly.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let a = Tensor::new(&[[1f32], [2.]], &Device::Cpu)?;
+    /// let b = a.expand((2, 3))?;
+    /// assert_eq!(b.stride(), &[1, 0]);
+    /// assert!(!b.is_contiguous());
+    /// assert_eq!(b.to_vec2::<f32>()?, &[[1., 1., 1.], [2., 2., 2.]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
     pub fn expand<S: Into<Shape>>(&self, shape: S) -> Result<Self> {
         self.broadcast_as(shape)
     }
@@ -2160,34 +2531,39 @@ impl Tensor {
     ///
     /// # Ok::<(), candle_core::Error>(())
     /// ```
+    #[track_caller]
     pub fn reshape<S: crate::shape::ShapeWithOneHole>(&self, s: S) -> Result<Tensor> {
-        let shape = s.into_shape(self.elem_count())?;
-        if shape.elem_count() != self.elem_count() {
-            return Err(Error::ShapeMismatchBinaryOp {
-                lhs: self.shape().clone(),
-                rhs: shape,
-                op: "reshape",
+        let res = (|| -> Result<Tensor> {
+            let shape = s.into_shape(self.elem_count())?;
+            if shape.elem_count() != self.elem_count() {
+                return Err(Error::ShapeMismatchBinaryOp {
+                    lhs: self.shape().clone(),
+                    rhs: shape,
+                    op: "reshape",
+                }
+                .bt());
             }
-            .bt());
-        }
-        let op = BackpropOp::new1(self, Op::Reshape);
-        if self.is_contiguous() {
-            let tensor_ = Tensor_ {
-                id: TensorId::new(),
-                storage: self.storage.clone(),
-                layout: Layout::contiguous_with_offset(shape, self.layout.start_offset()),
-                op,
-                is_variable: false,
-                dtype: self.dtype,
-                device: self.device.clone(),
-            };
-            Ok(Tensor(Arc::new(tensor_)))
-        } else {
-            let mut storage = unsafe { self.device().alloc_uninit(&shape, self.dtype())? };
-            self.storage()
-                .copy_strided_src(&mut storage, 0, self.layout())?;
-            Ok(from_storage(storage, shape, op, false))
-        }
+            let op = BackpropOp::new1(self, Op::Reshape);
+            if self.is_contiguous() {
+                let tensor_ = Tensor_ {
+                    id: TensorId::new(),
+                    storage: self.storage.clone(),
+                    layout: Layout::contiguous_with_offset(shape, self.layout.start_offset()),
+                    op,
+                    is_variable: false,
+                    dtype: self.dtype,
+                    device: self.device.clone(),
+                    provenance: self.provenance.derive(),
+                };
+                Ok(Tensor(Arc::new(tensor_)))
+            } else {
+                let mut storage = unsafe { self.device().alloc_uninit(&shape, self.dtype())? };
+                self.storage()
+                    .copy_strided_src(&mut storage, 0, self.layout())?;
+                Ok(from_storage(storage, shape, op, false))
+            }
+        })();
+        track_op(res, "reshape", &[self])
     }
 
     /// Creates a new tensor with the specified dimension removed if its size was one.
@@ -2221,6 +2597,7 @@ impl Tensor {
                 is_variable: false,
                 dtype: self.dtype,
                 device: self.device.clone(),
+                provenance: self.provenance.derive(),
             };
             Ok(Tensor(Arc::new(tensor_)))
         } else {
@@ -2259,6 +2636,7 @@ impl Tensor {
             is_variable: false,
             dtype: self.dtype,
             device: self.device.clone(),
+            provenance: self.provenance.derive(),
         };
         Ok(Tensor(Arc::new(tensor_)))
     }
@@ -2517,8 +2895,7 @@ impl Tensor {
         mask.where_cond(/* on_true= */ &src, /* on_false= */ self)
     }
 
-    /// Returns log(sum(exp(tensor), dim)).
-    pub fn log_sum_exp<D: Dims>(&self, sum_dims: D) -> Result<Self> {
+    fn log_sum_exp_impl<D: Dims>(&self, sum_dims: D, keepdim: bool) -> Result<Self> {
         let sum_dims = sum_dims.to_indexes(self.shape(), "log-sum-exp")?;
         if sum_dims.is_empty() {
             return Ok(self.clone());
@@ -2528,10 +2905,33 @@ impl Tensor {
             .try_fold(self.max_keepdim(sum_dims[0])?, |max, &dim| {
                 max.max_keepdim(dim)
             })?;
+        // The result does not depend on the shift so no gradient flows through it, and the
+        // infinite maximums, e.g. when all the values are -inf, are not subtracted to avoid
+        // getting nan.
+        let max = max.detach();
+        let max = max
+            .abs()?
+            .lt(f64::INFINITY)?
+            .where_cond(&max, &max.zeros_like()?)?;
         let exp = self.broadcast_sub(&max)?.exp()?;
-        let sum = exp.sum(sum_dims.clone())?;
+        let sum = exp.sum_impl(sum_dims.as_slice(), keepdim)?;
+        let max = if keepdim {
+            max
+        } else {
+            max.squeeze_dims(&sum_dims)?
+        };
+        sum.log()? + max
+    }
+
+    /// Returns log(sum(exp(tensor), dim)), computed in a numerically stable way by shifting the
+    /// values by their maximum.
+    pub fn log_sum_exp<D: Dims>(&self, sum_dims: D) -> Result<Self> {
+        self.log_sum_exp_impl(sum_dims, false)
+    }
 
-        sum.log()? + max.squeeze_dims(&sum_dims)
+    /// Similar to `log_sum_exp` but the reduced dimensions are kept with a single element.
+    pub fn log_sum_exp_keepdim<D: Dims>(&self, sum_dims: D) -> Result<Self> {
+        self.log_sum_exp_impl(sum_dims, true)
     }
 
     /// Pointwise pow operation.
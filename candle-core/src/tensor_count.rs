@@ -0,0 +1,121 @@
+use crate::{bail, DType, Result, Tensor};
+
+fn check_indices(t: &Tensor, op: &'static str) -> Result<()> {
+    match t.dtype() {
+        DType::U8 | DType::U32 | DType::I64 => Ok(()),
+        dtype => bail!("{op}: unsupported dtype {dtype:?}, expected u8, u32 or i64"),
+    }
+}
+
+impl Tensor {
+    /// One-hot encodes a tensor of class indices, the result has an additional last dimension of
+    /// size `num_classes` and uses the dtype of the indices, 1 being set at the position of the
+    /// index and 0 elsewhere. The indices outside of `0..num_classes`, e.g. -1 for padding,
+    /// result in vectors of zeros.
+    ///
+    /// The encoding is computed on the device, see `candle_nn::encoding::one_hot` for custom on
+    /// and off values.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let t = Tensor::new(&[2i64, 0, -1], &Device::Cpu)?;
+    /// let t = t.one_hot(3)?;
+    /// assert_eq!(t.to_vec2::<i64>()?, &[[0, 0, 1], [1, 0, 0], [0, 0, 0]]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn one_hot(&self, num_classes: usize) -> Result<Self> {
+        check_indices(self, "one-hot")?;
+        let classes = Tensor::arange(0i64, num_classes as i64, self.device())?;
+        self.to_dtype(DType::I64)?
+            .unsqueeze(crate::D::Minus1)?
+            .broadcast_eq(&classes)?
+            .to_dtype(self.dtype())
+    }
+
+    /// Counts the occurrences of each value of a tensor of indices, returns a `u32` tensor with
+    /// `num_bins` elements, the value at index `i` being the number of elements equal to `i`.
+    /// All the values must be in `0..num_bins`.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let t = Tensor::new(&[[1u32, 3], [1, 0]], &Device::Cpu)?;
+    /// assert_eq!(t.bincount(5)?.to_vec1::<u32>()?, &[1, 2, 0, 1, 0]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn bincount(&self, num_bins: usize) -> Result<Self> {
+        check_indices(self, "bincount")?;
+        let indices = self.flatten_all()?;
+        let ones = Tensor::ones(indices.elem_count(), DType::U32, self.device())?;
+        Tensor::zeros(num_bins, DType::U32, self.device())?.index_add(&indices, &ones, 0)
+    }
+
+    /// Returns the sorted unique values of the tensor as a single dimension tensor.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let t = Tensor::new(&[[3u32, 1, 3], [7, 1, 1]], &Device::Cpu)?;
+    /// assert_eq!(t.unique()?.to_vec1::<u32>()?, &[1, 3, 7]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn unique(&self) -> Result<Self> {
+        Ok(self.unique_with_counts()?.0)
+    }
+
+    /// Returns the sorted unique values of the tensor, the inverse indices and the counts.
+    ///
+    /// The inverse indices have the shape of the tensor and contain the index of each element in
+    /// the unique values, the counts contain the number of occurrences of each unique value, both
+    /// use `u32` elements.
+    ///
+    /// The values are sorted on the device but the number of unique values depends on the data,
+    /// so the mask of the positions where the sorted values change is copied back to the host.
+    ///
+    /// ```rust
+    /// use candle_core::{Tensor, Device};
+    /// let t = Tensor::new(&[[3u32, 1, 3], [7, 1, 1]], &Device::Cpu)?;
+    /// let (values, inverse, counts) = t.unique_with_counts()?;
+    /// assert_eq!(values.to_vec1::<u32>()?, &[1, 3, 7]);
+    /// assert_eq!(inverse.to_vec2::<u32>()?, &[[1, 0, 1], [2, 0, 0]]);
+    /// assert_eq!(counts.to_vec1::<u32>()?, &[3, 2, 1]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn unique_with_counts(&self) -> Result<(Self, Self, Self)> {
+        let flat = self.flatten_all()?;
+        let n = flat.elem_count();
+        if n == 0 {
+            bail!("unique: empty tensor")
+        }
+        let (sorted, perm) = flat.contiguous()?.sort_last_dim(true)?;
+        let is_new = sorted
+            .narrow(0, 1, n - 1)?
+            .ne(&sorted.narrow(0, 0, n - 1)?)?
+            .to_vec1::<u8>()?;
+        // The start of the runs of equal values in the sorted tensor and the index of the run
+        // each sorted element belongs to.
+        let mut starts = vec![0u32];
+        let mut groups = Vec::with_capacity(n);
+        groups.push(0u32);
+        for (i, &is_new) in is_new.iter().enumerate() {
+            if is_new != 0 {
+                starts.push(i as u32 + 1)
+            }
+            groups.push(starts.len() as u32 - 1)
+        }
+        let counts = starts
+            .iter()
+            .zip(starts[1..].iter().chain([n as u32].iter()))
+            .map(|(start, end)| end - start)
+            .collect::<Vec<_>>();
+
+        let device = self.device();
+        let values = sorted.index_select(&Tensor::new(starts.as_slice(), device)?, 0)?;
+        let groups = Tensor::new(groups.as_slice(), device)?;
+        // Scatter the run indices back to the original positions of the sorted elements.
+        let inverse = groups
+            .zeros_like()?
+            .scatter_add(&perm, &groups, 0)?
+            .reshape(self.shape())?;
+        let counts = Tensor::new(counts.as_slice(), device)?;
+        Ok((values, inverse, counts))
+    }
+}
@@ -0,0 +1,107 @@
+use crate::{bail, shape::Dim, Result, Tensor};
+
+/// How the values added by [`Tensor::pad`] are computed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PadMode {
+    /// Pads with a constant value, converted to the dtype of the tensor.
+    Constant(f64),
+    /// Mirrors the values without repeating the edges, padding `[1, 2, 3]` with 2 elements on
+    /// both sides gives `[3, 2, 1, 2, 3, 2, 1]`. The padding must be smaller than the dimension.
+    Reflect,
+    /// Repeats the edge values, `[1, 1, 1, 2, 3, 3, 3]`.
+    Replicate,
+    /// Wraps around the dimension, `[2, 3, 1, 2, 3, 1, 2]`.
+    Circular,
+}
+
+impl PadMode {
+    /// The index in the source dimension of size `size` for the position `pos` of the padded
+    /// dimension, `pos` being relative to the start of the source values.
+    fn src_index(&self, pos: i64, size: i64) -> i64 {
+        match self {
+            Self::Constant(_) => pos,
+            Self::Reflect => {
+                let pos = pos.abs();
+                if pos < size {
+                    pos
+                } else {
+                    2 * (size - 1) - pos
+                }
+            }
+            Self::Replicate => pos.clamp(0, size - 1),
+            Self::Circular => pos.rem_euclid(size),
+        }
+    }
+}
+
+impl Tensor {
+    /// Pads the tensor along dimension `dim`, adding `left` elements before the values and
+    /// `right` elements after them. The non-constant modes gather the padded values from the
+    /// tensor so the gradients flow back to the elements they are copied from.
+    ///
+    /// ```rust
+    /// # use candle_core::{Tensor, Device, PadMode};
+    /// let t = Tensor::new(&[1f32, 2., 3.], &Device::Cpu)?;
+    /// let p = t.pad(0, 2, 1, PadMode::Reflect)?;
+    /// assert_eq!(p.to_vec1::<f32>()?, [3., 2., 1., 2., 3., 2.]);
+    /// let p = t.pad(0, 1, 2, PadMode::Constant(-1.))?;
+    /// assert_eq!(p.to_vec1::<f32>()?, [-1., 1., 2., 3., -1., -1.]);
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn pad<D: Dim>(&self, dim: D, left: usize, right: usize, mode: PadMode) -> Result<Self> {
+        let dim = dim.to_index(self.shape(), "pad")?;
+        if left == 0 && right == 0 {
+            return Ok(self.clone());
+        }
+        let size = self.dim(dim)?;
+        match mode {
+            PadMode::Constant(value) => {
+                if value == 0. {
+                    return self.pad_with_zeros(dim, left, right);
+                }
+                let mut dims = self.dims().to_vec();
+                let mut pad = |len: usize| {
+                    dims[dim] = len;
+                    Tensor::zeros(dims.as_slice(), self.dtype(), self.device())?.affine(0., value)
+                };
+                let mut parts = vec![];
+                if left > 0 {
+                    parts.push(pad(left)?)
+                }
+                parts.push(self.clone());
+                if right > 0 {
+                    parts.push(pad(right)?)
+                }
+                return Tensor::cat(&parts, dim);
+            }
+            PadMode::Reflect if left >= size || right >= size => {
+                bail!("pad: reflect padding ({left}, {right}) too large for size {size}")
+            }
+            PadMode::Reflect | PadMode::Replicate | PadMode::Circular if size == 0 => {
+                bail!("pad: cannot use {mode:?} padding on the empty dim {dim}")
+            }
+            PadMode::Reflect | PadMode::Replicate | PadMode::Circular => {}
+        }
+        let (size, left) = (size as i64, left as i64);
+        let indexes: Vec<u32> = (-left..size + right as i64)
+            .map(|pos| mode.src_index(pos, size) as u32)
+            .collect();
+        let indexes = Tensor::new(indexes.as_slice(), self.device())?;
+        self.index_select(&indexes, dim)
+    }
+
+    /// Pads the last `pads.len()` dimensions, `pads[i]` being the `(left, right)` padding of the
+    /// dimension `rank - pads.len() + i`, e.g. `[(1, 1), (2, 2)]` pads the height and the width
+    /// of an image batch.
+    pub fn pad_last_dims(&self, pads: &[(usize, usize)], mode: PadMode) -> Result<Self> {
+        let rank = self.rank();
+        if pads.len() > rank {
+            bail!("pad: {} paddings for a tensor of rank {rank}", pads.len())
+        }
+        let mut t = self.clone();
+        for (i, &(left, right)) in pads.iter().enumerate() {
+            t = t.pad(rank - pads.len() + i, left, right, mode)?
+        }
+        Ok(t)
+    }
+}
@@ -0,0 +1,299 @@
+//! Utilities to compare tensors against reference values, typically activations dumped from a
+//! PyTorch implementation when porting a model.
+//!
+//! The reference tensors can be stored in npy, npz or safetensors files. On the python side,
+//! `np.save("x.npy", x.numpy())` or `safetensors.torch.save_file({"x": x}, "ref.safetensors")`
+//! produce such files.
+//!
+//! ```no_run
+//! # fn main() -> candle_core::Result<()> {
+//! use candle_core::testing::{References, Tolerance};
+//! # let hidden_states = candle_core::Tensor::zeros(4, candle_core::DType::F32, &candle_core::Device::Cpu)?;
+//! let refs = References::load("reference.safetensors")?;
+//! refs.assert_allclose("layer0.hidden_states", &hidden_states, Tolerance::default())?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::{bail, DType, Device, Error, Result, Shape, Tensor};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The tolerances used to compare two values, `actual` is close to `expected` when
+/// `|actual - expected| <= atol + rtol * |expected|`, as for numpy's `allclose`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Tolerance {
+    pub atol: f64,
+    pub rtol: f64,
+}
+
+impl Default for Tolerance {
+    fn default() -> Self {
+        Self {
+            atol: 1e-5,
+            rtol: 1e-4,
+        }
+    }
+}
+
+impl Tolerance {
+    pub fn new(atol: f64, rtol: f64) -> Self {
+        Self { atol, rtol }
+    }
+
+    /// Suitable tolerances when comparing values computed using `dtype`.
+    pub fn for_dtype(dtype: DType) -> Self {
+        match dtype {
+            DType::F16 => Self::new(1e-3, 1e-2),
+            DType::BF16 => Self::new(1e-2, 5e-2),
+            DType::F64 => Self::new(1e-8, 1e-6),
+            _ => Self::default(),
+        }
+    }
+
+    pub fn with_atol(mut self, atol: f64) -> Self {
+        self.atol = atol;
+        self
+    }
+
+    pub fn with_rtol(mut self, rtol: f64) -> Self {
+        self.rtol = rtol;
+        self
+    }
+
+    pub fn is_close(&self, actual: f64, expected: f64) -> bool {
+        if actual.is_nan() || expected.is_nan() {
+            return actual.is_nan() && expected.is_nan();
+        }
+        if actual == expected {
+            // Handles matching infinities.
+            return true;
+        }
+        (actual - expected).abs() <= self.atol + self.rtol * expected.abs()
+    }
+}
+
+/// The location and values of the largest error between two tensors.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorLocation {
+    /// The error, absolute or relative depending on the field of [`Diff`].
+    pub error: f64,
+    /// The multi-dimensional index of the element.
+    pub index: Vec<usize>,
+    pub actual: f64,
+    pub expected: f64,
+}
+
+impl std::fmt::Display for ErrorLocation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:.3e} at {:?} (actual {}, expected {})",
+            self.error, self.index, self.actual, self.expected
+        )
+    }
+}
+
+/// A summary of the differences between two tensors of the same shape.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diff {
+    pub shape: Shape,
+    pub tolerance: Tolerance,
+    /// The number of elements that are not close given the tolerance.
+    pub mismatches: usize,
+    /// The index of the first mismatched element.
+    pub first_mismatch: Option<Vec<usize>>,
+    /// The largest absolute error, `None` for empty tensors.
+    pub max_abs_error: Option<ErrorLocation>,
+    /// The largest relative error, ignoring the elements whose expected value is zero.
+    pub max_rel_error: Option<ErrorLocation>,
+}
+
+impl Diff {
+    /// Compares `actual` against `expected`, the tensors are compared on the cpu as f64 values.
+    pub fn new(actual: &Tensor, expected: &Tensor, tolerance: Tolerance) -> Result<Self> {
+        if actual.shape() != expected.shape() {
+            return Err(Error::ShapeMismatchBinaryOp {
+                lhs: actual.shape().clone(),
+                rhs: expected.shape().clone(),
+                op: "diff",
+            }
+            .bt());
+        }
+        let values = |t: &Tensor| {
+            t.to_device(&Device::Cpu)?
+                .to_dtype(DType::F64)?
+                .flatten_all()?
+                .to_vec1::<f64>()
+        };
+        let (a, e) = (values(actual)?, values(expected)?);
+        let dims = actual.dims();
+        let mut mismatches = 0;
+        let mut first_mismatch = None;
+        let mut max_abs: Option<(f64, usize)> = None;
+        let mut max_rel: Option<(f64, usize)> = None;
+        for (i, (&a, &e)) in a.iter().zip(e.iter()).enumerate() {
+            if !tolerance.is_close(a, e) {
+                mismatches += 1;
+                if first_mismatch.is_none() {
+                    first_mismatch = Some(unravel_index(i, dims))
+                }
+            }
+            let abs = if a == e { 0. } else { (a - e).abs() };
+            // Nan errors are reported as the largest ones.
+            let abs = if abs.is_nan() { f64::INFINITY } else { abs };
+            if max_abs.is_none_or(|(m, _)| abs > m) {
+                max_abs = Some((abs, i))
+            }
+            if e != 0. {
+                let rel = abs / e.abs();
+                if max_rel.is_none_or(|(m, _)| rel > m) {
+                    max_rel = Some((rel, i))
+                }
+            }
+        }
+        let location = |(error, i): (f64, usize)| ErrorLocation {
+            error,
+            index: unravel_index(i, dims),
+            actual: a[i],
+            expected: e[i],
+        };
+        Ok(Self {
+            shape: actual.shape().clone(),
+            tolerance,
+            mismatches,
+            first_mismatch,
+            max_abs_error: max_abs.map(location),
+            max_rel_error: max_rel.map(location),
+        })
+    }
+
+    pub fn is_close(&self) -> bool {
+        self.mismatches == 0
+    }
+}
+
+impl std::fmt::Display for Diff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}/{} elements mismatch for shape {:?} (atol {:e}, rtol {:e})",
+            self.mismatches,
+            self.shape.elem_count(),
+            self.shape,
+            self.tolerance.atol,
+            self.tolerance.rtol
+        )?;
+        if let Some(index) = &self.first_mismatch {
+            write!(f, "\n  first mismatch at {index:?}")?
+        }
+        if let Some(abs) = &self.max_abs_error {
+            write!(f, "\n  max abs error {abs}")?
+        }
+        if let Some(rel) = &self.max_rel_error {
+            write!(f, "\n  max rel error {rel}")?
+        }
+        Ok(())
+    }
+}
+
+fn unravel_index(mut i: usize, dims: &[usize]) -> Vec<usize> {
+    let mut index = vec![0; dims.len()];
+    for (idx, &d) in index.iter_mut().zip(dims.iter()).rev() {
+        *idx = i % d;
+        i /= d;
+    }
+    index
+}
+
+/// Returns an error with a summary of the differences if `actual` and `expected` do not have the
+/// same shape or if some of their elements are not close.
+pub fn assert_allclose(actual: &Tensor, expected: &Tensor, tolerance: Tolerance) -> Result<()> {
+    let diff = Diff::new(actual, expected, tolerance)?;
+    if !diff.is_close() {
+        bail!("tensors are not close: {diff}")
+    }
+    Ok(())
+}
+
+/// A set of named reference tensors.
+#[derive(Debug, Clone, Default)]
+pub struct References {
+    tensors: HashMap<String, Tensor>,
+}
+
+impl References {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads the reference tensors from a safetensors, npz or npy file. A npy file contains a
+    /// single tensor which is named after the file stem.
+    pub fn load<P: AsRef<Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        let load = || -> Result<Self> {
+            let tensors = match p.extension().and_then(|e| e.to_str()) {
+                Some("safetensors") => crate::safetensors::load(p, &Device::Cpu)?,
+                Some("npz") => Tensor::read_npz(p)?.into_iter().collect(),
+                Some("npy") => {
+                    let name = p.file_stem().and_then(|s| s.to_str()).unwrap_or_default();
+                    HashMap::from([(name.to_string(), Tensor::read_npy(p)?)])
+                }
+                _ => bail!("unsupported reference file, expected safetensors, npz or npy"),
+            };
+            Ok(Self { tensors })
+        };
+        load().map_err(|e| e.with_path(p))
+    }
+
+    /// Saves the tensors in a safetensors file, e.g. to compare candle activations with the
+    /// reference ones in python.
+    pub fn save<P: AsRef<Path>>(&self, p: P) -> Result<()> {
+        crate::safetensors::save(&self.tensors, p)
+    }
+
+    pub fn insert<S: Into<String>>(&mut self, name: S, tensor: &Tensor) -> Result<()> {
+        let tensor = tensor.to_device(&Device::Cpu)?;
+        self.tensors.insert(name.into(), tensor);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Result<&Tensor> {
+        match self.tensors.get(name) {
+            Some(tensor) => Ok(tensor),
+            None => Err(Error::CannotFindTensor {
+                path: name.to_string(),
+            }
+            .bt()),
+        }
+    }
+
+    /// The names of the tensors, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = self.tensors.keys().map(|k| k.as_str()).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// Compares `actual` with the reference tensor `name`.
+    pub fn diff(&self, name: &str, actual: &Tensor, tolerance: Tolerance) -> Result<Diff> {
+        let expected = self.get(name)?;
+        if actual.shape() != expected.shape() {
+            bail!(
+                "{name} has shape {:?}, the reference has shape {:?}",
+                actual.shape(),
+                expected.shape()
+            )
+        }
+        Diff::new(actual, expected, tolerance)
+    }
+
+    /// Returns an error with a summary of the differences if `actual` is not close to the
+    /// reference tensor `name`.
+    pub fn assert_allclose(&self, name: &str, actual: &Tensor, tolerance: Tolerance) -> Result<()> {
+        let diff = self.diff(name, actual, tolerance)?;
+        if !diff.is_close() {
+            bail!("{name} is not close to the reference: {diff}")
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,382 @@
+//! Tensors with a rank known at compile time.
+//!
+//! [`Tensor1`], [`Tensor2`], [`Tensor3`] and [`Tensor4`] wrap a [`Tensor`] and encode its rank in
+//! the type. Each dimension is either a [`Const`] size checked when the typed tensor is created,
+//! or a `usize` for sizes only known at runtime such as the batch size. Operations like `matmul`
+//! or `t` then produce tensors with the appropriate types so that rank mismatches and mismatches
+//! between constant dimensions are caught when compiling.
+//!
+//! ```rust
+//! use candle_core::typed::{Const, Tensor2};
+//! use candle_core::{DType, Device};
+//! # fn main() -> candle_core::Result<()> {
+//! let dev = &Device::Cpu;
+//! let xs: Tensor2<usize, Const<4>> = Tensor2::zeros((3, Const), DType::F32, dev)?;
+//! let ws: Tensor2<Const<4>, Const<8>> = Tensor2::ones((Const, Const), DType::F32, dev)?;
+//! let ys = xs.matmul(&ws)?;
+//! assert_eq!(ys.dims(), (3, Const::<8>));
+//! // The untyped api remains available through `Deref`.
+//! assert_eq!(ys.sum_all()?.to_scalar::<f32>()?, 0.);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Multiplying tensors with incompatible constant dimensions does not compile.
+//!
+//! ```compile_fail
+//! use candle_core::typed::{Const, Tensor2};
+//! use candle_core::{DType, Device};
+//! # fn main() -> candle_core::Result<()> {
+//! let dev = &Device::Cpu;
+//! let xs: Tensor2<usize, Const<4>> = Tensor2::zeros((3, Const), DType::F32, dev)?;
+//! let ws: Tensor2<Const<5>, Const<8>> = Tensor2::ones((Const, Const), DType::F32, dev)?;
+//! let ys = xs.matmul(&ws)?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::{bail, DType, Device, Error, Module, Result, Shape, Tensor};
+
+/// A dimension of a typed tensor.
+pub trait Dim: Copy + PartialEq + std::fmt::Debug {
+    /// Returns `None` if `size` is not a valid value for this dimension.
+    fn from_size(size: usize) -> Option<Self>;
+
+    fn size(&self) -> usize;
+}
+
+/// A dimension with a size known at runtime.
+impl Dim for usize {
+    fn from_size(size: usize) -> Option<Self> {
+        Some(size)
+    }
+
+    fn size(&self) -> usize {
+        *self
+    }
+}
+
+/// A dimension with a size known at compile time.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Const<const N: usize>;
+
+impl<const N: usize> Dim for Const<N> {
+    fn from_size(size: usize) -> Option<Self> {
+        (size == N).then_some(Self)
+    }
+
+    fn size(&self) -> usize {
+        N
+    }
+}
+
+/// Common operations on the typed tensors.
+pub trait TypedTensor: Sized + std::ops::Deref<Target = Tensor> {
+    const RANK: usize;
+
+    /// Wraps `tensor`, returns an error if its rank or its dimensions do not match the type.
+    fn from_tensor(tensor: Tensor) -> Result<Self>;
+
+    fn tensor(&self) -> &Tensor;
+
+    fn into_tensor(self) -> Tensor;
+
+    /// Applies an operation that preserves the shape, e.g. an activation function, and checks
+    /// the shape of the result.
+    fn map<F: FnOnce(&Tensor) -> Result<Tensor>>(&self, f: F) -> Result<Self> {
+        let tensor = f(self.tensor())?;
+        if tensor.shape() != self.shape() {
+            Err(Error::UnexpectedShape {
+                msg: "map should preserve the shape".to_string(),
+                expected: self.shape().clone(),
+                got: tensor.shape().clone(),
+            }
+            .bt())?
+        }
+        Self::from_tensor(tensor)
+    }
+
+    /// Applies a module, the type of the output has to be specified and is checked.
+    fn apply<M: Module, T: TypedTensor>(&self, m: &M) -> Result<T> {
+        T::from_tensor(m.forward(self.tensor())?)
+    }
+
+    fn add(&self, rhs: &Self) -> Result<Self> {
+        Self::from_tensor(self.tensor().add(rhs.tensor())?)
+    }
+
+    fn sub(&self, rhs: &Self) -> Result<Self> {
+        Self::from_tensor(self.tensor().sub(rhs.tensor())?)
+    }
+
+    fn mul(&self, rhs: &Self) -> Result<Self> {
+        Self::from_tensor(self.tensor().mul(rhs.tensor())?)
+    }
+
+    fn div(&self, rhs: &Self) -> Result<Self> {
+        Self::from_tensor(self.tensor().div(rhs.tensor())?)
+    }
+
+    fn affine(&self, mul: f64, add: f64) -> Result<Self> {
+        Self::from_tensor(self.tensor().affine(mul, add)?)
+    }
+
+    fn to_dtype(&self, dtype: DType) -> Result<Self> {
+        Self::from_tensor(self.tensor().to_dtype(dtype)?)
+    }
+
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        Self::from_tensor(self.tensor().to_device(device)?)
+    }
+
+    fn contiguous(&self) -> Result<Self> {
+        Self::from_tensor(self.tensor().contiguous()?)
+    }
+}
+
+impl Tensor {
+    /// Converts this tensor to a typed tensor, checking its rank and constant dimensions.
+    ///
+    /// ```rust
+    /// use candle_core::typed::{Const, Tensor2};
+    /// use candle_core::{Device, Tensor};
+    /// let t = Tensor::new(&[[1f32, 2.], [3., 4.], [5., 6.]], &Device::Cpu)?;
+    /// let t: Tensor2<usize, Const<2>> = t.typed()?;
+    /// assert_eq!(t.dims(), (3, Const));
+    /// assert!(t.t()?.typed::<Tensor2<usize, Const<2>>>().is_err());
+    /// # Ok::<(), candle_core::Error>(())
+    /// ```
+    pub fn typed<T: TypedTensor>(&self) -> Result<T> {
+        T::from_tensor(self.clone())
+    }
+}
+
+fn check_dim<D: Dim>(shape: &Shape, index: usize) -> Result<D> {
+    let size = shape.dims()[index];
+    match D::from_size(size) {
+        Some(dim) => Ok(dim),
+        None => bail!(
+            "unexpected size {size} for dim {index} of {shape:?}, expected {}",
+            std::any::type_name::<D>()
+        ),
+    }
+}
+
+macro_rules! typed_tensor {
+    ($name:ident, $rank:expr, $($dim:ident: $idx:tt),+) => {
+        #[derive(Clone, Debug)]
+        pub struct $name<$($dim: Dim),+> {
+            tensor: Tensor,
+            dims: ($($dim,)+),
+        }
+
+        impl<$($dim: Dim),+> $name<$($dim),+> {
+            pub fn dims(&self) -> ($($dim,)+) {
+                self.dims
+            }
+
+            pub fn zeros(dims: ($($dim,)+), dtype: DType, device: &Device) -> Result<Self> {
+                let shape = ($(dims.$idx.size(),)+);
+                Self::from_tensor(Tensor::zeros(shape, dtype, device)?)
+            }
+
+            pub fn ones(dims: ($($dim,)+), dtype: DType, device: &Device) -> Result<Self> {
+                let shape = ($(dims.$idx.size(),)+);
+                Self::from_tensor(Tensor::ones(shape, dtype, device)?)
+            }
+
+            pub fn randn(dims: ($($dim,)+), mean: f32, std: f32, device: &Device) -> Result<Self> {
+                let shape = ($(dims.$idx.size(),)+);
+                Self::from_tensor(Tensor::randn(mean, std, shape, device)?)
+            }
+        }
+
+        impl<$($dim: Dim),+> TypedTensor for $name<$($dim),+> {
+            const RANK: usize = $rank;
+
+            fn from_tensor(tensor: Tensor) -> Result<Self> {
+                let shape = tensor.shape();
+                if shape.rank() != Self::RANK {
+                    Err(Error::UnexpectedNumberOfDims {
+                        expected: Self::RANK,
+                        got: shape.rank(),
+                        shape: shape.clone(),
+                    }
+                    .bt())?
+                }
+                let dims = ($(check_dim::<$dim>(shape, $idx)?,)+);
+                Ok(Self { tensor, dims })
+            }
+
+            fn tensor(&self) -> &Tensor {
+                &self.tensor
+            }
+
+            fn into_tensor(self) -> Tensor {
+                self.tensor
+            }
+        }
+
+        impl<$($dim: Dim),+> std::ops::Deref for $name<$($dim),+> {
+            type Target = Tensor;
+
+            fn deref(&self) -> &Tensor {
+                &self.tensor
+            }
+        }
+
+        impl<$($dim: Dim),+> AsRef<Tensor> for $name<$($dim),+> {
+            fn as_ref(&self) -> &Tensor {
+                &self.tensor
+            }
+        }
+
+        impl<$($dim: Dim),+> From<$name<$($dim),+>> for Tensor {
+            fn from(t: $name<$($dim),+>) -> Tensor {
+                t.tensor
+            }
+        }
+    };
+}
+
+typed_tensor!(Tensor1, 1, A: 0);
+typed_tensor!(Tensor2, 2, A: 0, B: 1);
+typed_tensor!(Tensor3, 3, A: 0, B: 1, C: 2);
+typed_tensor!(Tensor4, 4, A: 0, B: 1, C: 2, D: 3);
+
+// The results of the following operations have dimensions inferred from the inputs so they can
+// be built without checking the shape again.
+impl<A: Dim> Tensor1<A> {
+    /// The dot product of two vectors.
+    pub fn dot(&self, rhs: &Self) -> Result<Tensor> {
+        self.tensor.mul(&rhs.tensor)?.sum_all()
+    }
+}
+
+impl<A: Dim, B: Dim> Tensor2<A, B> {
+    pub fn t(&self) -> Result<Tensor2<B, A>> {
+        let (a, b) = self.dims;
+        let tensor = self.tensor.t()?;
+        Ok(Tensor2 {
+            tensor,
+            dims: (b, a),
+        })
+    }
+
+    pub fn matmul<C: Dim>(&self, rhs: &Tensor2<B, C>) -> Result<Tensor2<A, C>> {
+        let tensor = self.tensor.matmul(&rhs.tensor)?;
+        Ok(Tensor2 {
+            tensor,
+            dims: (self.dims.0, rhs.dims.1),
+        })
+    }
+
+    /// The matrix-vector product.
+    pub fn matvec(&self, rhs: &Tensor1<B>) -> Result<Tensor1<A>> {
+        let tensor = self.tensor.matmul(&rhs.tensor.unsqueeze(1)?)?.squeeze(1)?;
+        Ok(Tensor1 {
+            tensor,
+            dims: (self.dims.0,),
+        })
+    }
+
+    /// Adds `rhs` to each row.
+    pub fn broadcast_add_row(&self, rhs: &Tensor1<B>) -> Result<Self> {
+        let tensor = self.tensor.broadcast_add(&rhs.tensor)?;
+        Ok(Self {
+            tensor,
+            dims: self.dims,
+        })
+    }
+
+    /// Sums over the first dimension.
+    pub fn sum0(&self) -> Result<Tensor1<B>> {
+        let tensor = self.tensor.sum(0)?;
+        Ok(Tensor1 {
+            tensor,
+            dims: (self.dims.1,),
+        })
+    }
+
+    /// Sums over the last dimension.
+    pub fn sum1(&self) -> Result<Tensor1<A>> {
+        let tensor = self.tensor.sum(1)?;
+        Ok(Tensor1 {
+            tensor,
+            dims: (self.dims.0,),
+        })
+    }
+}
+
+impl<A: Dim, B: Dim, C: Dim> Tensor3<A, B, C> {
+    /// Swaps the last two dimensions.
+    pub fn t(&self) -> Result<Tensor3<A, C, B>> {
+        let (a, b, c) = self.dims;
+        let tensor = self.tensor.t()?;
+        Ok(Tensor3 {
+            tensor,
+            dims: (a, c, b),
+        })
+    }
+
+    /// The batched matrix product.
+    pub fn matmul<D: Dim>(&self, rhs: &Tensor3<A, C, D>) -> Result<Tensor3<A, B, D>> {
+        let tensor = self.tensor.matmul(&rhs.tensor)?;
+        let (a, b, _) = self.dims;
+        Ok(Tensor3 {
+            tensor,
+            dims: (a, b, rhs.dims.2),
+        })
+    }
+
+    /// Multiplies each matrix of the batch by `rhs`.
+    pub fn broadcast_matmul<D: Dim>(&self, rhs: &Tensor2<C, D>) -> Result<Tensor3<A, B, D>> {
+        let tensor = self.tensor.broadcast_matmul(&rhs.tensor)?;
+        let (a, b, _) = self.dims;
+        Ok(Tensor3 {
+            tensor,
+            dims: (a, b, rhs.dims.1),
+        })
+    }
+
+    pub fn get(&self, index: usize) -> Result<Tensor2<B, C>> {
+        let tensor = self.tensor.get(index)?;
+        let (_, b, c) = self.dims;
+        Ok(Tensor2 {
+            tensor,
+            dims: (b, c),
+        })
+    }
+}
+
+impl<A: Dim, B: Dim, C: Dim, D: Dim> Tensor4<A, B, C, D> {
+    /// Swaps the second and third dimensions, e.g. to go from `(batch, seq, heads, head_dim)`
+    /// to `(batch, heads, seq, head_dim)`.
+    pub fn transpose12(&self) -> Result<Tensor4<A, C, B, D>> {
+        let (a, b, c, d) = self.dims;
+        let tensor = self.tensor.transpose(1, 2)?;
+        Ok(Tensor4 {
+            tensor,
+            dims: (a, c, b, d),
+        })
+    }
+
+    /// Swaps the last two dimensions.
+    pub fn t(&self) -> Result<Tensor4<A, B, D, C>> {
+        let (a, b, c, d) = self.dims;
+        let tensor = self.tensor.t()?;
+        Ok(Tensor4 {
+            tensor,
+            dims: (a, b, d, c),
+        })
+    }
+
+    /// The batched matrix product over the last two dimensions.
+    pub fn matmul<E: Dim>(&self, rhs: &Tensor4<A, B, D, E>) -> Result<Tensor4<A, B, C, E>> {
+        let tensor = self.tensor.matmul(&rhs.tensor)?;
+        let (a, b, c, _) = self.dims;
+        Ok(Tensor4 {
+            tensor,
+            dims: (a, b, c, rhs.dims.3),
+        })
+    }
+}
@@ -27,6 +27,10 @@ pub fn metal_is_available() -> bool {
     cfg!(feature = "metal")
 }
 
+pub fn wgpu_is_available() -> bool {
+    cfg!(feature = "wgpu")
+}
+
 pub fn with_avx() -> bool {
     cfg!(target_feature = "avx")
 }
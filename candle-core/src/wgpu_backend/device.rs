@@ -0,0 +1,437 @@
+use crate::Result;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use super::WgpuError;
+
+/// Unique identifier for wgpu devices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct DeviceId(usize);
+
+impl DeviceId {
+    pub(crate) fn new() -> Self {
+        use std::sync::atomic;
+        static COUNTER: atomic::AtomicUsize = atomic::AtomicUsize::new(1);
+        Self(COUNTER.fetch_add(1, atomic::Ordering::Relaxed))
+    }
+}
+
+/// The number of threads per workgroup used by the element-wise kernels.
+pub(crate) const WORKGROUP_SIZE: u32 = 64;
+const MAX_WORKGROUPS: u32 = 65535;
+
+type PipelineMap = HashMap<String, Arc<wgpu::ComputePipeline>>;
+
+/// A WebGPU device, this is used both for native builds through Vulkan/Metal/DX12/GL and for
+/// wasm builds through the browser WebGPU api.
+#[derive(Clone)]
+pub struct WgpuDevice {
+    pub(crate) id: DeviceId,
+    pub(crate) ordinal: usize,
+    pub(crate) device: Arc<wgpu::Device>,
+    pub(crate) queue: Arc<wgpu::Queue>,
+    pub(crate) adapter_info: Arc<wgpu::AdapterInfo>,
+    pub(crate) limits: Arc<wgpu::Limits>,
+    /// Compiled pipelines, indexed by kernel name.
+    pipelines: Arc<Mutex<PipelineMap>>,
+    /// Random numbers are generated on the cpu and uploaded to the device.
+    pub(crate) rng: Arc<Mutex<rand::rngs::StdRng>>,
+}
+
+impl std::fmt::Debug for WgpuDevice {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "WgpuDevice({:?}, {})", self.id, self.adapter_info.name)
+    }
+}
+
+impl WgpuDevice {
+    /// Creates the device using the adapter at index `ordinal`. This is the only way to create a
+    /// device on wasm as blocking on the adapter and device requests is not possible there.
+    pub async fn new_async(ordinal: usize) -> Result<Self> {
+        use rand::SeedableRng;
+
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = Self::adapter(&instance, ordinal).await?;
+        let limits = adapter.limits();
+        let (device, queue) = adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: Some("candle"),
+                    required_features: wgpu::Features::empty(),
+                    required_limits: limits.clone(),
+                    memory_hints: wgpu::MemoryHints::Performance,
+                },
+                None,
+            )
+            .await
+            .map_err(|e| WgpuError::Message(format!("cannot create device: {e}")))?;
+        Ok(Self {
+            id: DeviceId::new(),
+            ordinal,
+            device: Arc::new(device),
+            queue: Arc::new(queue),
+            adapter_info: Arc::new(adapter.get_info()),
+            limits: Arc::new(limits),
+            pipelines: Arc::new(Mutex::new(HashMap::new())),
+            rng: Arc::new(Mutex::new(rand::rngs::StdRng::from_entropy())),
+        })
+    }
+
+    // Adapters are sorted so that the discrete gpus come first, the ones that cannot run compute
+    // shaders, e.g. some WebGL2 level GL drivers, are skipped.
+    #[cfg(not(target_arch = "wasm32"))]
+    async fn adapter(instance: &wgpu::Instance, ordinal: usize) -> Result<wgpu::Adapter> {
+        let mut adapters = instance
+            .enumerate_adapters(wgpu::Backends::all())
+            .into_iter()
+            .filter(|a| {
+                a.get_downlevel_capabilities()
+                    .flags
+                    .contains(wgpu::DownlevelFlags::COMPUTE_SHADERS)
+            })
+            .collect::<Vec<_>>();
+        adapters.sort_by_key(|a| match a.get_info().device_type {
+            wgpu::DeviceType::DiscreteGpu => 0,
+            wgpu::DeviceType::IntegratedGpu => 1,
+            wgpu::DeviceType::VirtualGpu => 2,
+            wgpu::DeviceType::Other => 3,
+            wgpu::DeviceType::Cpu => 4,
+        });
+        if ordinal >= adapters.len() {
+            Err(WgpuError::Message(format!(
+                "no wgpu adapter with ordinal {ordinal}, {} available",
+                adapters.len()
+            )))?
+        }
+        Ok(adapters.swap_remove(ordinal))
+    }
+
+    // Browsers only expose a single adapter.
+    #[cfg(target_arch = "wasm32")]
+    async fn adapter(instance: &wgpu::Instance, ordinal: usize) -> Result<wgpu::Adapter> {
+        if ordinal != 0 {
+            Err(WgpuError::Message(format!(
+                "no wgpu adapter with ordinal {ordinal}"
+            )))?
+        }
+        let options = wgpu::RequestAdapterOptions {
+            power_preference: wgpu::PowerPreference::HighPerformance,
+            ..Default::default()
+        };
+        let adapter = instance.request_adapter(&options).await;
+        Ok(adapter.ok_or_else(|| WgpuError::Message("webgpu is not available".to_string()))?)
+    }
+
+    pub fn id(&self) -> DeviceId {
+        self.id
+    }
+
+    /// Information about the adapter, e.g. its name and the backend in use.
+    pub fn adapter_info(&self) -> &wgpu::AdapterInfo {
+        &self.adapter_info
+    }
+
+    pub fn device(&self) -> &wgpu::Device {
+        &self.device
+    }
+
+    pub fn queue(&self) -> &wgpu::Queue {
+        &self.queue
+    }
+
+    fn check_size(&self, size: u64) -> Result<()> {
+        let max_size = u64::min(
+            self.limits.max_buffer_size,
+            self.limits.max_storage_buffer_binding_size as u64,
+        );
+        if size > max_size {
+            Err(WgpuError::Message(format!(
+                "buffer of {size} bytes exceeds the device limit of {max_size} bytes"
+            )))?
+        }
+        Ok(())
+    }
+
+    /// Allocates a zero initialized buffer of `words` 32 bits words.
+    pub(crate) fn alloc(&self, words: usize) -> Result<Arc<wgpu::Buffer>> {
+        let size = (words.max(1) * 4) as u64;
+        self.check_size(size)?;
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Ok(Arc::new(buffer))
+    }
+
+    /// Creates a buffer holding the `len` words produced by `words`.
+    pub(crate) fn upload(
+        &self,
+        len: usize,
+        words: impl Iterator<Item = u32>,
+    ) -> Result<Arc<wgpu::Buffer>> {
+        let size = (len.max(1) * 4) as u64;
+        self.check_size(size)?;
+        let buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::STORAGE
+                | wgpu::BufferUsages::COPY_SRC
+                | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: true,
+        });
+        {
+            let mut view = buffer.slice(..).get_mapped_range_mut();
+            for (dst, w) in view.chunks_exact_mut(4).zip(words) {
+                dst.copy_from_slice(&w.to_le_bytes())
+            }
+        }
+        buffer.unmap();
+        Ok(Arc::new(buffer))
+    }
+
+    /// Copies `words` 32 bits words between two buffers.
+    pub(crate) fn copy_buffer(
+        &self,
+        src: &wgpu::Buffer,
+        src_offset: usize,
+        dst: &wgpu::Buffer,
+        dst_offset: usize,
+        words: usize,
+    ) {
+        if words == 0 {
+            return;
+        }
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(
+            src,
+            (src_offset * 4) as u64,
+            dst,
+            (dst_offset * 4) as u64,
+            (words * 4) as u64,
+        );
+        self.queue.submit(Some(encoder.finish()));
+    }
+
+    fn pipeline(
+        &self,
+        name: &str,
+        source: impl FnOnce() -> String,
+    ) -> Result<Arc<wgpu::ComputePipeline>> {
+        let mut pipelines = self.pipelines.lock().map_err(WgpuError::from)?;
+        if let Some(pipeline) = pipelines.get(name) {
+            return Ok(pipeline.clone());
+        }
+        let module = self
+            .device
+            .create_shader_module(wgpu::ShaderModuleDescriptor {
+                label: Some(name),
+                source: wgpu::ShaderSource::Wgsl(source().into()),
+            });
+        let pipeline = self
+            .device
+            .create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                label: Some(name),
+                layout: None,
+                module: &module,
+                entry_point: Some("main"),
+                compilation_options: Default::default(),
+                cache: None,
+            });
+        let pipeline = Arc::new(pipeline);
+        pipelines.insert(name.to_string(), pipeline.clone());
+        Ok(pipeline)
+    }
+
+    /// Runs the kernel `name`, `source` is only called the first time the kernel is used. The
+    /// `info` words are bound at index 0 and `buffers` use the following indexes.
+    pub(crate) fn dispatch(
+        &self,
+        name: &str,
+        source: impl FnOnce() -> String,
+        info: &[u32],
+        buffers: &[&wgpu::Buffer],
+        workgroups: (u32, u32, u32),
+    ) -> Result<()> {
+        let pipeline = self.pipeline(name, source)?;
+        let info = self.upload(info.len(), info.iter().copied())?;
+        let entries = std::iter::once(info.as_ref())
+            .chain(buffers.iter().copied())
+            .enumerate()
+            .map(|(binding, buffer)| wgpu::BindGroupEntry {
+                binding: binding as u32,
+                resource: buffer.as_entire_binding(),
+            })
+            .collect::<Vec<_>>();
+        let bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            label: None,
+            layout: &pipeline.get_bind_group_layout(0),
+            entries: &entries,
+        });
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        {
+            let mut pass = encoder.begin_compute_pass(&Default::default());
+            pass.set_pipeline(&pipeline);
+            pass.set_bind_group(0, &bind_group, &[]);
+            let (x, y, z) = workgroups;
+            pass.dispatch_workgroups(x, y, z);
+        }
+        self.queue.submit(Some(encoder.finish()));
+        Ok(())
+    }
+
+    /// Runs the kernel `name` with one thread per element, the kernels recover the element index
+    /// with `thread_index`.
+    pub(crate) fn dispatch_elementwise(
+        &self,
+        name: &str,
+        source: impl FnOnce() -> String,
+        info: &[u32],
+        buffers: &[&wgpu::Buffer],
+        elem_count: usize,
+    ) -> Result<()> {
+        if elem_count == 0 {
+            return Ok(());
+        }
+        let workgroups = elem_count.div_ceil(WORKGROUP_SIZE as usize);
+        self.dispatch(name, source, info, buffers, grid(workgroups)?)
+    }
+
+    fn staging(&self, buffer: &wgpu::Buffer, words: usize) -> wgpu::Buffer {
+        let size = (words.max(1) * 4) as u64;
+        let staging = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size,
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let mut encoder = self.device.create_command_encoder(&Default::default());
+        encoder.copy_buffer_to_buffer(buffer, 0, &staging, 0, size);
+        self.queue.submit(Some(encoder.finish()));
+        staging
+    }
+
+    /// Reads back the first `words` words of `buffer`, this blocks until the device is done.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub(crate) fn read(&self, buffer: &wgpu::Buffer, words: usize) -> Result<Vec<u32>> {
+        let staging = self.staging(buffer, words);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        staging.slice(..).map_async(wgpu::MapMode::Read, move |r| {
+            let _ = sender.send(r);
+        });
+        self.device.poll(wgpu::Maintain::Wait);
+        receiver
+            .recv()
+            .map_err(|e| WgpuError::Message(e.to_string()))?
+            .map_err(|e| WgpuError::Message(e.to_string()))?;
+        Ok(read_mapped(&staging, words))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub(crate) fn read(&self, _: &wgpu::Buffer, _: usize) -> Result<Vec<u32>> {
+        Err(WgpuError::Message(
+            "blocking reads are not possible on wasm, use Tensor::to_cpu_async".to_string(),
+        ))?
+    }
+
+    /// Reads back the first `words` words of `buffer` without blocking. The copy is scheduled
+    /// immediately so later changes to `buffer` are not visible in the result.
+    pub(crate) fn read_async(
+        &self,
+        buffer: &wgpu::Buffer,
+        words: usize,
+    ) -> impl Future<Output = Result<Vec<u32>>> + 'static {
+        let staging = self.staging(buffer, words);
+        let device = self.device.clone();
+        async move {
+            let mapped = MapFuture::default();
+            let state = mapped.0.clone();
+            staging
+                .slice(..)
+                .map_async(wgpu::MapMode::Read, move |r| MapFuture::complete(&state, r));
+            // Native backends only make progress when polled, browsers do it on their own.
+            #[cfg(not(target_arch = "wasm32"))]
+            device.poll(wgpu::Maintain::Wait);
+            #[cfg(target_arch = "wasm32")]
+            let _ = device;
+            mapped
+                .await
+                .map_err(|e| WgpuError::Message(e.to_string()))?;
+            Ok(read_mapped(&staging, words))
+        }
+    }
+}
+
+/// Splits `workgroups` over the x and y dimensions to stay within the dispatch limits.
+pub(crate) fn grid(workgroups: usize) -> Result<(u32, u32, u32)> {
+    let max = MAX_WORKGROUPS as usize;
+    if workgroups <= max {
+        return Ok((workgroups as u32, 1, 1));
+    }
+    let y = workgroups.div_ceil(max);
+    if y > max {
+        Err(WgpuError::Message(format!(
+            "too many workgroups to dispatch: {workgroups}"
+        )))?
+    }
+    Ok((MAX_WORKGROUPS, y as u32, 1))
+}
+
+fn read_mapped(staging: &wgpu::Buffer, words: usize) -> Vec<u32> {
+    let data = {
+        let view = staging.slice(..).get_mapped_range();
+        view.chunks_exact(4)
+            .take(words)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    };
+    staging.unmap();
+    data
+}
+
+type MapResult = std::result::Result<(), wgpu::BufferAsyncError>;
+
+#[derive(Default)]
+struct MapState {
+    result: Option<MapResult>,
+    waker: Option<Waker>,
+}
+
+// Resolves when the map_async callback has been called.
+#[derive(Default)]
+struct MapFuture(Arc<Mutex<MapState>>);
+
+impl MapFuture {
+    fn complete(state: &Mutex<MapState>, result: MapResult) {
+        if let Ok(mut state) = state.lock() {
+            state.result = Some(result);
+            if let Some(waker) = state.waker.take() {
+                waker.wake()
+            }
+        }
+    }
+}
+
+impl Future for MapFuture {
+    type Output = MapResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = match self.0.lock() {
+            Ok(state) => state,
+            Err(_) => return Poll::Ready(Err(wgpu::BufferAsyncError)),
+        };
+        match state.result.take() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                state.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
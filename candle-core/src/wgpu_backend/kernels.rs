@@ -0,0 +1,399 @@
+//! WGSL sources for the wgpu kernels.
+//!
+//! All the kernels take an `info` array of u32 at binding 0 followed by their data buffers. Data is
+//! stored as 32 bits words, u8 values use one word per element. Strided layouts are encoded in
+//! `info` as `rank, start_offset, dims.., strides..`, see `push_layout`.
+use crate::{DType, Layout};
+
+use super::device::WORKGROUP_SIZE;
+
+/// The tile size used by the matmul kernel.
+pub(crate) const TILE: u32 = 16;
+
+/// Appends `layout` to `info` and returns its position.
+pub(crate) fn push_layout(info: &mut Vec<u32>, layout: &Layout) -> u32 {
+    let pos = info.len() as u32;
+    info.push(layout.dims().len() as u32);
+    info.push(layout.start_offset() as u32);
+    info.extend(layout.dims().iter().map(|&d| d as u32));
+    info.extend(layout.stride().iter().map(|&s| s as u32));
+    pos
+}
+
+/// The WGSL type used to store `dtype`, u8 values are widened to u32.
+pub(crate) fn wgsl_type(dtype: DType) -> &'static str {
+    match dtype {
+        DType::F32 => "f32",
+        _ => "u32",
+    }
+}
+
+fn header() -> String {
+    format!(
+        r#"
+@group(0) @binding(0) var<storage, read> info: array<u32>;
+
+fn thread_index(gid: vec3<u32>, nwg: vec3<u32>) -> u32 {{
+    return gid.x + gid.y * nwg.x * {WORKGROUP_SIZE}u;
+}}
+
+// Offset of the i-th element of the layout stored at `pos` in info.
+fn strided_offset(i: u32, pos: u32) -> u32 {{
+    let rank = info[pos];
+    var offset = info[pos + 1u];
+    var idx = i;
+    for (var d = rank; d > 0u; d--) {{
+        let dim = info[pos + 1u + d];
+        let stride = info[pos + 1u + rank + d];
+        offset += (idx % dim) * stride;
+        idx /= dim;
+    }}
+    return offset;
+}}
+
+fn tanh_(x: f32) -> f32 {{
+    // tanh overflows for large inputs on some implementations.
+    return tanh(clamp(x, -15.0, 15.0));
+}}
+
+fn erf_(x: f32) -> f32 {{
+    // Abramowitz and Stegun 7.1.26, the maximum error is 1.5e-7.
+    let t = 1.0 / (1.0 + 0.3275911 * abs(x));
+    let y = 1.0 - (((((1.061405429 * t - 1.453152027) * t) + 1.421413741) * t - 0.284496736) * t + 0.254829592) * t * exp(-x * x);
+    return sign(x) * y;
+}}
+"#
+    )
+}
+
+fn main_signature() -> String {
+    format!(
+        "@compute @workgroup_size({WORKGROUP_SIZE})\n\
+         fn main(@builtin(global_invocation_id) gid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>)"
+    )
+}
+
+/// The WGSL expression for the f32 unary op `name` applied to `x`.
+pub(crate) fn unary_expr(name: &str) -> Option<&'static str> {
+    let expr = match name {
+        "exp" => "exp(x)",
+        "log" => "log(x)",
+        "sin" => "sin(x)",
+        "cos" => "cos(x)",
+        "tanh" => "tanh_(x)",
+        "neg" => "-x",
+        "recip" => "1.0 / x",
+        "sqr" => "x * x",
+        "sqrt" => "sqrt(x)",
+        "gelu" => "0.5 * x * (1.0 + tanh_(0.7978845608 * (x + 0.044715 * x * x * x)))",
+        "gelu_erf" => "0.5 * x * (1.0 + erf_(x * 0.70710678118))",
+        "erf" => "erf_(x)",
+        "silu" => "x / (1.0 + exp(-x))",
+        "abs" => "abs(x)",
+        "ceil" => "ceil(x)",
+        "floor" => "floor(x)",
+        // WGSL rounds half to even, candle rounds half away from zero.
+        "round" => "sign(x) * floor(abs(x) + 0.5)",
+        "relu" => "max(x, 0.0)",
+        "sign" => "sign(x)",
+        _ => return None,
+    };
+    Some(expr)
+}
+
+/// The WGSL expression converting `x` from `src` to `dst`.
+pub(crate) fn cast_expr(src: DType, dst: DType) -> Option<&'static str> {
+    let expr = match (src, dst) {
+        (DType::F32, DType::F32) | (DType::U32, DType::U32) | (DType::U8, DType::U8) => "x",
+        (DType::U8, DType::U32) => "x",
+        (DType::U32, DType::U8) => "x & 255u",
+        (DType::F32, DType::U32) => "u32(max(x, 0.0))",
+        (DType::F32, DType::U8) => "min(u32(max(x, 0.0)), 255u)",
+        (DType::U32, DType::F32) | (DType::U8, DType::F32) => "f32(x)",
+        _ => return None,
+    };
+    Some(expr)
+}
+
+/// Element-wise map, info: `n, p0, p1, layout`. `p0` and `p1` are f32 parameters that can be
+/// used in `expr`.
+pub(crate) fn unary(src_ty: &str, dst_ty: &str, expr: &str) -> String {
+    format!(
+        r#"{header}
+@group(0) @binding(1) var<storage, read> src: array<{src_ty}>;
+@group(0) @binding(2) var<storage, read_write> dst: array<{dst_ty}>;
+
+{main} {{
+    let i = thread_index(gid, nwg);
+    if (i >= info[0]) {{ return; }}
+    let p0 = bitcast<f32>(info[1]);
+    let p1 = bitcast<f32>(info[2]);
+    let x = src[strided_offset(i, 3u)];
+    dst[i] = {expr};
+}}
+"#,
+        header = header(),
+        main = main_signature(),
+    )
+}
+
+/// The WGSL expression for a binary op or comparison on `a` and `b`.
+pub(crate) fn binary_expr(name: &str) -> Option<&'static str> {
+    let expr = match name {
+        "add" => "a + b",
+        "sub" => "a - b",
+        "mul" => "a * b",
+        "div" => "a / b",
+        "minimum" => "min(a, b)",
+        "maximum" => "max(a, b)",
+        "eq" => "select(0u, 1u, a == b)",
+        "ne" => "select(0u, 1u, a != b)",
+        "lt" => "select(0u, 1u, a < b)",
+        "le" => "select(0u, 1u, a <= b)",
+        "gt" => "select(0u, 1u, a > b)",
+        "ge" => "select(0u, 1u, a >= b)",
+        _ => return None,
+    };
+    Some(expr)
+}
+
+/// Element-wise binary op, info: `n, lhs_pos, rhs_pos, lhs_layout, rhs_layout`.
+pub(crate) fn binary(ty: &str, dst_ty: &str, expr: &str) -> String {
+    format!(
+        r#"{header}
+@group(0) @binding(1) var<storage, read> lhs: array<{ty}>;
+@group(0) @binding(2) var<storage, read> rhs: array<{ty}>;
+@group(0) @binding(3) var<storage, read_write> dst: array<{dst_ty}>;
+
+{main} {{
+    let i = thread_index(gid, nwg);
+    if (i >= info[0]) {{ return; }}
+    let a = lhs[strided_offset(i, info[1])];
+    let b = rhs[strided_offset(i, info[2])];
+    dst[i] = {expr};
+}}
+"#,
+        header = header(),
+        main = main_signature(),
+    )
+}
+
+/// info: `n, cond_pos, t_pos, f_pos, cond_layout, t_layout, f_layout`.
+pub(crate) fn where_cond(ty: &str) -> String {
+    format!(
+        r#"{header}
+@group(0) @binding(1) var<storage, read> cond: array<u32>;
+@group(0) @binding(2) var<storage, read> on_true: array<{ty}>;
+@group(0) @binding(3) var<storage, read> on_false: array<{ty}>;
+@group(0) @binding(4) var<storage, read_write> dst: array<{ty}>;
+
+{main} {{
+    let i = thread_index(gid, nwg);
+    if (i >= info[0]) {{ return; }}
+    if (cond[strided_offset(i, info[1])] != 0u) {{
+        dst[i] = on_true[strided_offset(i, info[2])];
+    }} else {{
+        dst[i] = on_false[strided_offset(i, info[3])];
+    }}
+}}
+"#,
+        header = header(),
+        main = main_signature(),
+    )
+}
+
+/// Copies a strided layout to a contiguous destination, info: `n, dst_offset, layout`.
+pub(crate) fn copy_strided() -> String {
+    format!(
+        r#"{header}
+@group(0) @binding(1) var<storage, read> src: array<u32>;
+@group(0) @binding(2) var<storage, read_write> dst: array<u32>;
+
+{main} {{
+    let i = thread_index(gid, nwg);
+    if (i >= info[0]) {{ return; }}
+    dst[info[1] + i] = src[strided_offset(i, 2u)];
+}}
+"#,
+        header = header(),
+        main = main_signature(),
+    )
+}
+
+/// info: `n, d2, src_stride1, dst_stride1, src_offset, dst_offset`.
+pub(crate) fn copy2d() -> String {
+    format!(
+        r#"{header}
+@group(0) @binding(1) var<storage, read> src: array<u32>;
+@group(0) @binding(2) var<storage, read_write> dst: array<u32>;
+
+{main} {{
+    let i = thread_index(gid, nwg);
+    if (i >= info[0]) {{ return; }}
+    let row = i / info[1];
+    let col = i % info[1];
+    dst[info[5] + row * info[3] + col] = src[info[4] + row * info[2] + col];
+}}
+"#,
+        header = header(),
+        main = main_signature(),
+    )
+}
+
+/// Shared by index_select and gather, the source element at `(left, id, right)` is read for
+/// each output element, info: `n, ids_dim, src_dim, right, src_pos, ids_pos, layouts`. When
+/// `gather` is false, ids are indexed by the middle coordinate only.
+pub(crate) fn indexing(gather: bool) -> String {
+    let ids_index = if gather { "i" } else { "(i / right) % ids_dim" };
+    format!(
+        r#"{header}
+@group(0) @binding(1) var<storage, read> src: array<u32>;
+@group(0) @binding(2) var<storage, read> ids: array<u32>;
+@group(0) @binding(3) var<storage, read_write> dst: array<u32>;
+
+{main} {{
+    let i = thread_index(gid, nwg);
+    if (i >= info[0]) {{ return; }}
+    let ids_dim = info[1];
+    let src_dim = info[2];
+    let right = info[3];
+    let left = i / (ids_dim * right);
+    let id = ids[strided_offset({ids_index}, info[5])];
+    let src_index = (left * src_dim + id) * right + i % right;
+    dst[i] = src[strided_offset(src_index, info[4])];
+}}
+"#,
+        header = header(),
+        main = main_signature(),
+    )
+}
+
+/// One workgroup per output element, info: `n, kept_pos, reduced_pos, reduced_count, layouts`.
+/// The reduced layout has a zero start offset and is added to the kept offset.
+pub(crate) fn reduce(ty: &str, dst_ty: &str, op: &str) -> String {
+    let (combine, output) = match op {
+        "sum" => ("acc = acc + v;", "acc"),
+        "min" | "argmin" => (
+            "if (!found || v < acc || (v == acc && r < acc_idx)) { acc = v; acc_idx = r; }",
+            if op == "min" { "acc" } else { "acc_idx" },
+        ),
+        _ => (
+            "if (!found || v > acc || (v == acc && r < acc_idx)) { acc = v; acc_idx = r; }",
+            if op == "max" { "acc" } else { "acc_idx" },
+        ),
+    };
+    let merge = match op {
+        "sum" => "shared_acc[lid] = shared_acc[lid] + shared_acc[lid + s];".to_string(),
+        _ => {
+            let cmp = if op == "min" || op == "argmin" {
+                "<"
+            } else {
+                ">"
+            };
+            format!(
+                "let v = shared_acc[lid + s];
+            let r = shared_idx[lid + s];
+            let acc = shared_acc[lid];
+            let acc_idx = shared_idx[lid];
+            if (shared_found[lid + s] != 0u && (shared_found[lid] == 0u || v {cmp} acc || (v == acc && r < acc_idx))) {{
+                shared_acc[lid] = v;
+                shared_idx[lid] = r;
+                shared_found[lid] = 1u;
+            }}"
+            )
+        }
+    };
+    let zero = if ty == "f32" { "0.0" } else { "0u" };
+    format!(
+        r#"{header}
+@group(0) @binding(1) var<storage, read> src: array<{ty}>;
+@group(0) @binding(2) var<storage, read_write> dst: array<{dst_ty}>;
+
+var<workgroup> shared_acc: array<{ty}, {WORKGROUP_SIZE}>;
+var<workgroup> shared_idx: array<u32, {WORKGROUP_SIZE}>;
+var<workgroup> shared_found: array<u32, {WORKGROUP_SIZE}>;
+
+@compute @workgroup_size({WORKGROUP_SIZE})
+fn main(@builtin(workgroup_id) wid: vec3<u32>, @builtin(num_workgroups) nwg: vec3<u32>, @builtin(local_invocation_index) lid: u32) {{
+    let o = wid.x + wid.y * nwg.x;
+    let base = strided_offset(min(o, info[0] - 1u), info[1]);
+    var acc: {ty} = {zero};
+    var acc_idx = 0u;
+    var found = false;
+    for (var r = lid; r < info[3]; r += {WORKGROUP_SIZE}u) {{
+        let v = src[base + strided_offset(r, info[2])];
+        {combine}
+        found = true;
+    }}
+    shared_acc[lid] = acc;
+    shared_idx[lid] = acc_idx;
+    shared_found[lid] = select(0u, 1u, found);
+    workgroupBarrier();
+    for (var s = {half}u; s > 0u; s >>= 1u) {{
+        if (lid < s) {{
+            {merge}
+        }}
+        workgroupBarrier();
+    }}
+    if (lid == 0u && o < info[0]) {{
+        let acc = shared_acc[0];
+        let acc_idx = shared_idx[0];
+        dst[o] = {output};
+    }}
+}}
+"#,
+        header = header(),
+        half = WORKGROUP_SIZE / 2,
+    )
+}
+
+/// Batched matmul with arbitrary strides, info: `batch_offset, m, n, k, lhs_start, lhs_bs,
+/// lhs_ms, lhs_ks, rhs_start, rhs_bs, rhs_ks, rhs_ns`.
+pub(crate) fn matmul() -> String {
+    format!(
+        r#"
+@group(0) @binding(0) var<storage, read> info: array<u32>;
+@group(0) @binding(1) var<storage, read> lhs: array<f32>;
+@group(0) @binding(2) var<storage, read> rhs: array<f32>;
+@group(0) @binding(3) var<storage, read_write> dst: array<f32>;
+
+var<workgroup> tile_a: array<array<f32, {TILE}>, {TILE}>;
+var<workgroup> tile_b: array<array<f32, {TILE}>, {TILE}>;
+
+@compute @workgroup_size({TILE}, {TILE})
+fn main(@builtin(workgroup_id) wid: vec3<u32>, @builtin(local_invocation_id) lid: vec3<u32>) {{
+    let batch = info[0] + wid.z;
+    let m = info[1];
+    let n = info[2];
+    let k = info[3];
+    let row = wid.y * {TILE}u + lid.y;
+    let col = wid.x * {TILE}u + lid.x;
+    let lhs_base = info[4] + batch * info[5];
+    let rhs_base = info[8] + batch * info[9];
+    var acc = 0.0;
+    for (var t = 0u; t < k; t += {TILE}u) {{
+        let ka = t + lid.x;
+        if (row < m && ka < k) {{
+            tile_a[lid.y][lid.x] = lhs[lhs_base + row * info[6] + ka * info[7]];
+        }} else {{
+            tile_a[lid.y][lid.x] = 0.0;
+        }}
+        let kb = t + lid.y;
+        if (col < n && kb < k) {{
+            tile_b[lid.y][lid.x] = rhs[rhs_base + kb * info[10] + col * info[11]];
+        }} else {{
+            tile_b[lid.y][lid.x] = 0.0;
+        }}
+        workgroupBarrier();
+        for (var j = 0u; j < {TILE}u; j++) {{
+            acc += tile_a[lid.y][j] * tile_b[j][lid.x];
+        }}
+        workgroupBarrier();
+    }}
+    if (row < m && col < n) {{
+        dst[(batch * m + row) * n + col] = acc;
+    }}
+}}
+"#
+    )
+}
@@ -0,0 +1,719 @@
+//! A WebGPU backend based on wgpu, the same code runs natively and in browsers when compiled to
+//! wasm.
+//!
+//! Only f32, u32 and u8 tensors can be stored on the device. The element-wise ops, reductions,
+//! indexing and f32 matmuls run as WGSL compute shaders, the remaining ops such as convolutions
+//! are computed on the cpu by copying the data back and forth.
+use crate::backend::{BackendDevice, BackendStorage};
+use crate::conv::{ParamsConv1D, ParamsConv2D, ParamsConvTranspose1D, ParamsConvTranspose2D};
+use crate::op::{BinaryOpT, CmpOp, ReduceOp, UnaryOpT};
+use crate::{CpuStorage, DType, Layout, Result, Shape};
+use std::sync::{Arc, PoisonError};
+
+mod device;
+mod kernels;
+pub use device::{DeviceId, WgpuDevice};
+
+use kernels::{push_layout, wgsl_type};
+
+/// wgpu related errors
+#[derive(thiserror::Error, Debug)]
+pub enum WgpuError {
+    #[error("{0}")]
+    Message(String),
+}
+
+impl From<String> for WgpuError {
+    fn from(e: String) -> Self {
+        WgpuError::Message(e)
+    }
+}
+
+impl<T> From<PoisonError<T>> for WgpuError {
+    fn from(p: PoisonError<T>) -> Self {
+        WgpuError::Message(p.to_string())
+    }
+}
+
+fn check_dtype(dtype: DType) -> Result<()> {
+    match dtype {
+        DType::F32 | DType::U32 | DType::U8 => Ok(()),
+        _ => Err(crate::Error::UnsupportedDTypeForOp(dtype, "wgpu")),
+    }
+}
+
+fn words_to_cpu(words: Vec<u32>, dtype: DType) -> Result<CpuStorage> {
+    let storage = match dtype {
+        DType::F32 => CpuStorage::F32(words.into_iter().map(f32::from_bits).collect()),
+        DType::U32 => CpuStorage::U32(words),
+        DType::U8 => CpuStorage::U8(words.into_iter().map(|w| w as u8).collect()),
+        _ => Err(crate::Error::UnsupportedDTypeForOp(dtype, "wgpu"))?,
+    };
+    Ok(storage)
+}
+
+fn cmp_name(op: CmpOp) -> &'static str {
+    match op {
+        CmpOp::Eq => "eq",
+        CmpOp::Ne => "ne",
+        CmpOp::Le => "le",
+        CmpOp::Ge => "ge",
+        CmpOp::Lt => "lt",
+        CmpOp::Gt => "gt",
+    }
+}
+
+// Returns the start offset, the batch stride and the strides of the two last dimensions when
+// the batch dimensions can be merged into a single one.
+fn matmul_strides(l: &Layout) -> Option<[usize; 4]> {
+    let dims = l.dims();
+    let stride = l.stride();
+    let rank = dims.len();
+    let mut batch_stride = None;
+    let mut expected = None;
+    for i in (0..rank - 2).rev() {
+        if dims[i] == 1 {
+            continue;
+        }
+        match expected {
+            Some(e) if stride[i] != e => return None,
+            Some(_) => {}
+            None => batch_stride = Some(stride[i]),
+        }
+        expected = Some(stride[i] * dims[i]);
+    }
+    Some([
+        l.start_offset(),
+        batch_stride.unwrap_or(0),
+        stride[rank - 2],
+        stride[rank - 1],
+    ])
+}
+
+#[derive(Debug, Clone)]
+pub struct WgpuStorage {
+    /// The buffer containing the data, one 32 bits word per element.
+    buffer: Arc<wgpu::Buffer>,
+    /// a reference to the device owning this buffer
+    device: WgpuDevice,
+    /// The count of allocated elements in the buffer
+    count: usize,
+    /// The dtype is kept since buffers are untyped.
+    dtype: DType,
+}
+
+impl WgpuStorage {
+    pub fn new(buffer: Arc<wgpu::Buffer>, device: WgpuDevice, count: usize, dtype: DType) -> Self {
+        Self {
+            buffer,
+            device,
+            count,
+            dtype,
+        }
+    }
+
+    pub fn buffer(&self) -> &wgpu::Buffer {
+        &self.buffer
+    }
+
+    /// Copies the data back to the cpu without blocking, this is the only way to read the data
+    /// on wasm.
+    pub fn to_cpu_storage_async(
+        &self,
+    ) -> impl std::future::Future<Output = Result<CpuStorage>> + 'static {
+        let dtype = self.dtype;
+        let words = self.device.read_async(&self.buffer, self.count);
+        async move { words_to_cpu(words.await?, dtype) }
+    }
+
+    fn alloc(&self, count: usize, dtype: DType) -> Result<Self> {
+        let buffer = self.device.alloc(count)?;
+        Ok(Self::new(buffer, self.device.clone(), count, dtype))
+    }
+
+    // Runs an op on the cpu for the cases that do not have a kernel.
+    fn cpu_fallback(&self, f: impl FnOnce(&CpuStorage) -> Result<CpuStorage>) -> Result<Self> {
+        let storage = f(&self.to_cpu_storage()?)?;
+        self.device.storage_from_cpu_storage_owned(storage)
+    }
+
+    fn map(
+        &self,
+        layout: &Layout,
+        name: &str,
+        expr: &str,
+        (p0, p1): (f32, f32),
+        dtype: DType,
+    ) -> Result<Self> {
+        let el_count = layout.shape().elem_count();
+        let mut info = vec![el_count as u32, p0.to_bits(), p1.to_bits()];
+        push_layout(&mut info, layout);
+        let (src_ty, dst_ty) = (wgsl_type(self.dtype), wgsl_type(dtype));
+        let out = self.alloc(el_count, dtype)?;
+        self.device.dispatch_elementwise(
+            &format!("{name}_{:?}_{:?}", self.dtype, dtype),
+            || kernels::unary(src_ty, dst_ty, expr),
+            &info,
+            &[&self.buffer, &out.buffer],
+            el_count,
+        )?;
+        Ok(out)
+    }
+
+    fn binary(
+        &self,
+        name: &str,
+        rhs: &Self,
+        lhs_l: &Layout,
+        rhs_l: &Layout,
+        dtype: DType,
+    ) -> Result<Option<Self>> {
+        let expr = match kernels::binary_expr(name) {
+            None => return Ok(None),
+            // u8 values are stored as u32 and have to wrap.
+            Some(expr) if self.dtype == DType::U8 && dtype == DType::U8 => {
+                format!("({expr}) & 255u")
+            }
+            Some(expr) => expr.to_string(),
+        };
+        let el_count = lhs_l.shape().elem_count();
+        let mut info = vec![el_count as u32, 0, 0];
+        info[1] = push_layout(&mut info, lhs_l);
+        info[2] = push_layout(&mut info, rhs_l);
+        let (ty, dst_ty) = (wgsl_type(self.dtype), wgsl_type(dtype));
+        let out = self.alloc(el_count, dtype)?;
+        self.device.dispatch_elementwise(
+            &format!("{name}_{:?}_{:?}", self.dtype, dtype),
+            || kernels::binary(ty, dst_ty, &expr),
+            &info,
+            &[&self.buffer, &rhs.buffer, &out.buffer],
+            el_count,
+        )?;
+        Ok(Some(out))
+    }
+
+    fn contiguous(&self, layout: &Layout) -> Result<(Self, Layout)> {
+        let mut out = self.alloc(layout.shape().elem_count(), self.dtype)?;
+        self.copy_strided_src(&mut out, 0, layout)?;
+        Ok((out, Layout::contiguous(layout.shape())))
+    }
+
+    fn indexing(
+        &self,
+        gather: bool,
+        ids: &Self,
+        src_l: &Layout,
+        ids_l: &Layout,
+        dim: usize,
+        out_shape: &Shape,
+    ) -> Result<Self> {
+        let src_dims = src_l.dims();
+        let ids_dim = if gather {
+            ids_l.dims()[dim]
+        } else {
+            ids_l.shape().elem_count()
+        };
+        let right = src_dims[dim + 1..].iter().product::<usize>();
+        let el_count = out_shape.elem_count();
+        let mut info = vec![
+            el_count as u32,
+            ids_dim as u32,
+            src_dims[dim] as u32,
+            right as u32,
+            0,
+            0,
+        ];
+        info[4] = push_layout(&mut info, src_l);
+        info[5] = push_layout(&mut info, ids_l);
+        let out = self.alloc(el_count, self.dtype)?;
+        let name = if gather { "gather" } else { "index_select" };
+        self.device.dispatch_elementwise(
+            name,
+            || kernels::indexing(gather),
+            &info,
+            &[&self.buffer, &ids.buffer, &out.buffer],
+            el_count,
+        )?;
+        Ok(out)
+    }
+}
+
+impl BackendStorage for WgpuStorage {
+    type Device = WgpuDevice;
+
+    fn try_clone(&self, _: &Layout) -> Result<Self> {
+        let out = self.alloc(self.count, self.dtype)?;
+        self.device
+            .copy_buffer(&self.buffer, 0, &out.buffer, 0, self.count);
+        Ok(out)
+    }
+
+    fn dtype(&self) -> DType {
+        self.dtype
+    }
+
+    fn device(&self) -> &Self::Device {
+        &self.device
+    }
+
+    fn to_cpu_storage(&self) -> Result<CpuStorage> {
+        let words = self.device.read(&self.buffer, self.count)?;
+        words_to_cpu(words, self.dtype)
+    }
+
+    fn affine(&self, layout: &Layout, mul: f64, add: f64) -> Result<Self> {
+        match self.dtype {
+            DType::F32 => self.map(
+                layout,
+                "affine",
+                "x * p0 + p1",
+                (mul as f32, add as f32),
+                self.dtype,
+            ),
+            _ => self.cpu_fallback(|s| s.affine(layout, mul, add)),
+        }
+    }
+
+    fn powf(&self, layout: &Layout, e: f64) -> Result<Self> {
+        match self.dtype {
+            DType::F32 => self.map(layout, "powf", "pow(x, p0)", (e as f32, 0.), self.dtype),
+            _ => self.cpu_fallback(|s| s.powf(layout, e)),
+        }
+    }
+
+    fn elu(&self, layout: &Layout, alpha: f64) -> Result<Self> {
+        match self.dtype {
+            DType::F32 => {
+                let expr = "select(p0 * (exp(x) - 1.0), x, x >= 0.0)";
+                self.map(layout, "elu", expr, (alpha as f32, 0.), self.dtype)
+            }
+            _ => self.cpu_fallback(|s| s.elu(layout, alpha)),
+        }
+    }
+
+    fn reduce_op(&self, op: ReduceOp, layout: &Layout, reduce_dims: &[usize]) -> Result<Self> {
+        let (mut kept, mut reduced) = ((vec![], vec![]), (vec![], vec![]));
+        for (d, (&dim, &stride)) in layout.dims().iter().zip(layout.stride()).enumerate() {
+            let dst = if reduce_dims.contains(&d) {
+                &mut reduced
+            } else {
+                &mut kept
+            };
+            dst.0.push(dim);
+            dst.1.push(stride);
+        }
+        let el_count = kept.0.iter().product::<usize>();
+        let reduced_count = reduced.0.iter().product::<usize>();
+        let dtype = match op {
+            ReduceOp::ArgMin | ReduceOp::ArgMax => DType::U32,
+            ReduceOp::Sum | ReduceOp::Min | ReduceOp::Max => self.dtype,
+        };
+        let mut info = vec![el_count as u32, 0, 0, reduced_count as u32];
+        info[1] = push_layout(
+            &mut info,
+            &Layout::new(kept.0.into(), kept.1, layout.start_offset()),
+        );
+        info[2] = push_layout(&mut info, &Layout::new(reduced.0.into(), reduced.1, 0));
+        let out = self.alloc(el_count, dtype)?;
+        if el_count > 0 {
+            let (ty, dst_ty) = (wgsl_type(self.dtype), wgsl_type(dtype));
+            self.device.dispatch(
+                &format!("reduce_{}_{ty}", op.name()),
+                || kernels::reduce(ty, dst_ty, op.name()),
+                &info,
+                &[&self.buffer, &out.buffer],
+                device::grid(el_count)?,
+            )?;
+        }
+        Ok(out)
+    }
+
+    fn cmp(&self, op: CmpOp, rhs: &Self, lhs_l: &Layout, rhs_l: &Layout) -> Result<Self> {
+        match self.binary(cmp_name(op), rhs, lhs_l, rhs_l, DType::U8)? {
+            Some(out) => Ok(out),
+            None => {
+                let rhs = rhs.to_cpu_storage()?;
+                self.cpu_fallback(|s| s.cmp(op, &rhs, lhs_l, rhs_l))
+            }
+        }
+    }
+
+    fn to_dtype(&self, layout: &Layout, dtype: DType) -> Result<Self> {
+        match kernels::cast_expr(self.dtype, dtype) {
+            Some(expr) => self.map(layout, &format!("to_{dtype:?}"), expr, (0., 0.), dtype),
+            None => self.cpu_fallback(|s| s.to_dtype(layout, dtype)),
+        }
+    }
+
+    fn unary_impl<B: UnaryOpT>(&self, layout: &Layout) -> Result<Self> {
+        match (self.dtype, kernels::unary_expr(B::NAME)) {
+            (DType::F32, Some(expr)) => self.map(layout, B::NAME, expr, (0., 0.), self.dtype),
+            _ => self.cpu_fallback(|s| s.unary_impl::<B>(layout)),
+        }
+    }
+
+    fn binary_impl<B: BinaryOpT>(
+        &self,
+        rhs: &Self,
+        lhs_l: &Layout,
+        rhs_l: &Layout,
+    ) -> Result<Self> {
+        match self.binary(B::NAME, rhs, lhs_l, rhs_l, self.dtype)? {
+            Some(out) => Ok(out),
+            None => {
+                let rhs = rhs.to_cpu_storage()?;
+                self.cpu_fallback(|s| s.binary_impl::<B>(&rhs, lhs_l, rhs_l))
+            }
+        }
+    }
+
+    fn where_cond(
+        &self,
+        layout: &Layout,
+        t: &Self,
+        t_l: &Layout,
+        f: &Self,
+        f_l: &Layout,
+    ) -> Result<Self> {
+        let el_count = layout.shape().elem_count();
+        let mut info = vec![el_count as u32, 0, 0, 0];
+        info[1] = push_layout(&mut info, layout);
+        info[2] = push_layout(&mut info, t_l);
+        info[3] = push_layout(&mut info, f_l);
+        let ty = wgsl_type(t.dtype);
+        let out = self.alloc(el_count, t.dtype)?;
+        self.device.dispatch_elementwise(
+            &format!("where_{ty}"),
+            || kernels::where_cond(ty),
+            &info,
+            &[&self.buffer, &t.buffer, &f.buffer, &out.buffer],
+            el_count,
+        )?;
+        Ok(out)
+    }
+
+    fn conv1d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &ParamsConv1D,
+    ) -> Result<Self> {
+        let kernel = kernel.to_cpu_storage()?;
+        self.cpu_fallback(|s| s.conv1d(l, &kernel, kernel_l, params))
+    }
+
+    fn conv_transpose1d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &ParamsConvTranspose1D,
+    ) -> Result<Self> {
+        let kernel = kernel.to_cpu_storage()?;
+        self.cpu_fallback(|s| s.conv_transpose1d(l, &kernel, kernel_l, params))
+    }
+
+    fn conv2d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &ParamsConv2D,
+    ) -> Result<Self> {
+        let kernel = kernel.to_cpu_storage()?;
+        self.cpu_fallback(|s| s.conv2d(l, &kernel, kernel_l, params))
+    }
+
+    fn conv_transpose2d(
+        &self,
+        l: &Layout,
+        kernel: &Self,
+        kernel_l: &Layout,
+        params: &ParamsConvTranspose2D,
+    ) -> Result<Self> {
+        let kernel = kernel.to_cpu_storage()?;
+        self.cpu_fallback(|s| s.conv_transpose2d(l, &kernel, kernel_l, params))
+    }
+
+    fn avg_pool2d(&self, l: &Layout, k: (usize, usize), stride: (usize, usize)) -> Result<Self> {
+        self.cpu_fallback(|s| s.avg_pool2d(l, k, stride))
+    }
+
+    fn max_pool2d(&self, l: &Layout, k: (usize, usize), stride: (usize, usize)) -> Result<Self> {
+        self.cpu_fallback(|s| s.max_pool2d(l, k, stride))
+    }
+
+    fn upsample_nearest1d(&self, l: &Layout, sz: usize) -> Result<Self> {
+        self.cpu_fallback(|s| s.upsample_nearest1d(l, sz))
+    }
+
+    fn upsample_nearest2d(&self, l: &Layout, h: usize, w: usize) -> Result<Self> {
+        self.cpu_fallback(|s| s.upsample_nearest2d(l, h, w))
+    }
+
+    fn gather(&self, l: &Layout, ids: &Self, ids_l: &Layout, dim: usize) -> Result<Self> {
+        if ids.dtype == DType::F32 {
+            let ids = ids.to_cpu_storage()?;
+            return self.cpu_fallback(|s| s.gather(l, &ids, ids_l, dim));
+        }
+        self.indexing(true, ids, l, ids_l, dim, ids_l.shape())
+    }
+
+    fn scatter_add(
+        &self,
+        l: &Layout,
+        ids: &Self,
+        ids_l: &Layout,
+        src: &Self,
+        src_l: &Layout,
+        dim: usize,
+    ) -> Result<Self> {
+        let (ids, src) = (ids.to_cpu_storage()?, src.to_cpu_storage()?);
+        self.cpu_fallback(|s| s.scatter_add(l, &ids, ids_l, &src, src_l, dim))
+    }
+
+    fn index_select(&self, ids: &Self, l: &Layout, ids_l: &Layout, dim: usize) -> Result<Self> {
+        if ids.dtype == DType::F32 {
+            let ids = ids.to_cpu_storage()?;
+            return self.cpu_fallback(|s| s.index_select(&ids, l, ids_l, dim));
+        }
+        let mut dims = l.dims().to_vec();
+        dims[dim] = ids_l.shape().elem_count();
+        self.indexing(false, ids, l, ids_l, dim, &dims.into())
+    }
+
+    fn index_add(
+        &self,
+        l: &Layout,
+        ids: &Self,
+        ids_l: &Layout,
+        src: &Self,
+        src_l: &Layout,
+        dim: usize,
+    ) -> Result<Self> {
+        let (ids, src) = (ids.to_cpu_storage()?, src.to_cpu_storage()?);
+        self.cpu_fallback(|s| s.index_add(l, &ids, ids_l, &src, src_l, dim))
+    }
+
+    fn matmul(
+        &self,
+        rhs: &Self,
+        (b, m, n, k): (usize, usize, usize, usize),
+        lhs_l: &Layout,
+        rhs_l: &Layout,
+    ) -> Result<Self> {
+        if self.dtype != DType::F32 {
+            let rhs = rhs.to_cpu_storage()?;
+            return self.cpu_fallback(|s| s.matmul(&rhs, (b, m, n, k), lhs_l, rhs_l));
+        }
+        let (lhs, lhs_strides) = match matmul_strides(lhs_l) {
+            Some(strides) => (None, strides),
+            None => {
+                let (lhs, l) = self.contiguous(lhs_l)?;
+                (Some(lhs), matmul_strides(&l).expect("contiguous layout"))
+            }
+        };
+        let (rhs_c, rhs_strides) = match matmul_strides(rhs_l) {
+            Some(strides) => (None, strides),
+            None => {
+                let (rhs, l) = rhs.contiguous(rhs_l)?;
+                (Some(rhs), matmul_strides(&l).expect("contiguous layout"))
+            }
+        };
+        let lhs = lhs.as_ref().unwrap_or(self);
+        let rhs = rhs_c.as_ref().unwrap_or(rhs);
+        let out = self.alloc(b * m * n, self.dtype)?;
+        if b * m * n == 0 {
+            return Ok(out);
+        }
+        let tile = kernels::TILE as usize;
+        let max_batch = u16::MAX as usize;
+        for batch_offset in (0..b).step_by(max_batch) {
+            let batches = usize::min(max_batch, b - batch_offset);
+            let mut info = vec![batch_offset as u32, m as u32, n as u32, k as u32];
+            info.extend(lhs_strides.iter().map(|&s| s as u32));
+            info.extend(rhs_strides.iter().map(|&s| s as u32));
+            self.device.dispatch(
+                "matmul_f32",
+                kernels::matmul,
+                &info,
+                &[&lhs.buffer, &rhs.buffer, &out.buffer],
+                (
+                    n.div_ceil(tile) as u32,
+                    m.div_ceil(tile) as u32,
+                    batches as u32,
+                ),
+            )?;
+        }
+        Ok(out)
+    }
+
+    fn copy_strided_src(&self, dst: &mut Self, dst_offset: usize, src_l: &Layout) -> Result<()> {
+        let el_count = src_l.shape().elem_count();
+        if src_l.is_contiguous() {
+            self.device.copy_buffer(
+                &self.buffer,
+                src_l.start_offset(),
+                &dst.buffer,
+                dst_offset,
+                el_count,
+            );
+            return Ok(());
+        }
+        let mut info = vec![el_count as u32, dst_offset as u32];
+        push_layout(&mut info, src_l);
+        self.device.dispatch_elementwise(
+            "copy_strided",
+            kernels::copy_strided,
+            &info,
+            &[&self.buffer, &dst.buffer],
+            el_count,
+        )
+    }
+
+    fn copy2d(
+        &self,
+        dst: &mut Self,
+        d1: usize,
+        d2: usize,
+        src_s: usize,
+        dst_s: usize,
+        src_o: usize,
+        dst_o: usize,
+    ) -> Result<()> {
+        if d1 * d2 == 0 {
+            return Ok(());
+        }
+        if d1 == 1 || (src_s == d2 && dst_s == d2) {
+            self.device
+                .copy_buffer(&self.buffer, src_o, &dst.buffer, dst_o, d1 * d2);
+            return Ok(());
+        }
+        let info = [d1 * d2, d2, src_s, dst_s, src_o, dst_o].map(|v| v as u32);
+        self.device.dispatch_elementwise(
+            "copy2d",
+            kernels::copy2d,
+            &info,
+            &[&self.buffer, &dst.buffer],
+            d1 * d2,
+        )
+    }
+}
+
+impl BackendDevice for WgpuDevice {
+    type Storage = WgpuStorage;
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn new(ordinal: usize) -> Result<Self> {
+        pollster::block_on(Self::new_async(ordinal))
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    fn new(_: usize) -> Result<Self> {
+        Err(WgpuError::Message(
+            "wgpu devices have to be created with Device::new_wgpu_async on wasm".to_string(),
+        ))?
+    }
+
+    fn location(&self) -> crate::DeviceLocation {
+        crate::DeviceLocation::Wgpu {
+            gpu_id: self.ordinal,
+        }
+    }
+
+    fn same_device(&self, rhs: &Self) -> bool {
+        self.id == rhs.id
+    }
+
+    fn zeros_impl(&self, shape: &Shape, dtype: DType) -> Result<WgpuStorage> {
+        check_dtype(dtype)?;
+        let el_count = shape.elem_count();
+        let buffer = self.alloc(el_count)?;
+        Ok(WgpuStorage::new(buffer, self.clone(), el_count, dtype))
+    }
+
+    fn ones_impl(&self, shape: &Shape, dtype: DType) -> Result<WgpuStorage> {
+        check_dtype(dtype)?;
+        let el_count = shape.elem_count();
+        let one = match dtype {
+            DType::F32 => 1f32.to_bits(),
+            _ => 1,
+        };
+        let buffer = self.upload(el_count, std::iter::repeat(one))?;
+        Ok(WgpuStorage::new(buffer, self.clone(), el_count, dtype))
+    }
+
+    unsafe fn alloc_uninit(&self, shape: &Shape, dtype: DType) -> Result<WgpuStorage> {
+        // wgpu buffers are always zero initialized.
+        self.zeros_impl(shape, dtype)
+    }
+
+    fn storage_from_slice<T: crate::WithDType>(&self, s: &[T]) -> Result<WgpuStorage> {
+        self.storage_from_cpu_storage(&T::to_cpu_storage(s))
+    }
+
+    fn storage_from_cpu_storage(&self, storage: &CpuStorage) -> Result<WgpuStorage> {
+        let (buffer, count) = match storage {
+            CpuStorage::F32(v) => (
+                self.upload(v.len(), v.iter().map(|v| v.to_bits()))?,
+                v.len(),
+            ),
+            CpuStorage::U32(v) => (self.upload(v.len(), v.iter().copied())?, v.len()),
+            CpuStorage::U8(v) => (self.upload(v.len(), v.iter().map(|&v| v as u32))?, v.len()),
+            _ => Err(crate::Error::UnsupportedDTypeForOp(storage.dtype(), "wgpu"))?,
+        };
+        Ok(WgpuStorage::new(
+            buffer,
+            self.clone(),
+            count,
+            storage.dtype(),
+        ))
+    }
+
+    fn storage_from_cpu_storage_owned(&self, storage: CpuStorage) -> Result<WgpuStorage> {
+        self.storage_from_cpu_storage(&storage)
+    }
+
+    fn rand_uniform(&self, shape: &Shape, dtype: DType, min: f64, max: f64) -> Result<WgpuStorage> {
+        use rand::prelude::*;
+
+        if dtype != DType::F32 {
+            Err(crate::Error::UnsupportedDTypeForOp(dtype, "rand_uniform"))?
+        }
+        let uniform = rand::distributions::Uniform::new(min as f32, max as f32);
+        let mut rng = self.rng.lock().map_err(WgpuError::from)?;
+        let data = (0..shape.elem_count())
+            .map(|_| rng.sample::<f32, _>(uniform))
+            .collect::<Vec<_>>();
+        self.storage_from_cpu_storage_owned(CpuStorage::F32(data))
+    }
+
+    fn rand_normal(&self, shape: &Shape, dtype: DType, mean: f64, std: f64) -> Result<WgpuStorage> {
+        use rand::prelude::*;
+
+        if dtype != DType::F32 {
+            Err(crate::Error::UnsupportedDTypeForOp(dtype, "rand_normal"))?
+        }
+        let normal =
+            rand_distr::Normal::new(mean as f32, std as f32).map_err(crate::Error::wrap)?;
+        let mut rng = self.rng.lock().map_err(WgpuError::from)?;
+        let data = (0..shape.elem_count())
+            .map(|_| rng.sample::<f32, _>(normal))
+            .collect::<Vec<_>>();
+        self.storage_from_cpu_storage_owned(CpuStorage::F32(data))
+    }
+
+    fn set_seed(&self, seed: u64) -> Result<()> {
+        use rand::SeedableRng;
+
+        *self.rng.lock().map_err(WgpuError::from)? = rand::rngs::StdRng::seed_from_u64(seed);
+        Ok(())
+    }
+
+    fn synchronize(&self) -> Result<()> {
+        #[cfg(not(target_arch = "wasm32"))]
+        self.device.poll(wgpu::Maintain::Wait);
+        Ok(())
+    }
+}
@@ -0,0 +1,360 @@
+//! Runs the tensor ops on fuzzed shapes and dtypes, on the cpu and on the accelerated backends
+//! enabled at compile time, and reports all the results that diverge beyond the dtype tolerance.
+//!
+//! The cpu results are also compared with the ones obtained on non-contiguous copies of the
+//! inputs so that the strided code paths are checked without any gpu. The number of trials per op
+//! and the seed can be set with the `CANDLE_FUZZ_TRIALS` and `CANDLE_FUZZ_SEED` environment
+//! variables.
+use candle_core::testing::{Diff, Tolerance};
+use candle_core::{DType, Device, Result, Tensor, D};
+use rand::{Rng, SeedableRng};
+
+const FLOAT: &[DType] = &[DType::F32, DType::F64, DType::F16, DType::BF16];
+const F32_F64: &[DType] = &[DType::F32, DType::F64];
+const ALL: &[DType] = &[
+    DType::U8,
+    DType::U32,
+    DType::I64,
+    DType::F32,
+    DType::F64,
+    DType::F16,
+    DType::BF16,
+];
+
+struct Fuzzer {
+    rng: rand::rngs::StdRng,
+}
+
+impl Fuzzer {
+    /// A random shape with up to 4 dimensions.
+    fn shape(&mut self) -> Vec<usize> {
+        let rank = self.rng.gen_range(1..=4);
+        (0..rank).map(|_| self.rng.gen_range(1..=7)).collect()
+    }
+
+    fn dim(&mut self, max: usize) -> usize {
+        self.rng.gen_range(1..=max)
+    }
+
+    /// A random tensor, floats are in `[-2, 2)` and integers in `[0, 10)`.
+    fn tensor(&mut self, shape: &[usize], dtype: DType) -> Result<Tensor> {
+        let n = shape.iter().product();
+        let data = if dtype.is_float() {
+            (0..n)
+                .map(|_| self.rng.gen_range(-2f64..2.))
+                .collect::<Vec<_>>()
+        } else {
+            (0..n)
+                .map(|_| self.rng.gen_range(0..10) as f64)
+                .collect::<Vec<_>>()
+        };
+        Tensor::from_vec(data, shape, &Device::Cpu)?.to_dtype(dtype)
+    }
+
+    fn indexes(&mut self, n: usize, max: usize) -> Result<Tensor> {
+        let ids = (0..n)
+            .map(|_| self.rng.gen_range(0..max as u32))
+            .collect::<Vec<_>>();
+        Tensor::new(ids, &Device::Cpu)
+    }
+}
+
+type Inputs = fn(&mut Fuzzer, DType) -> Result<Vec<Tensor>>;
+
+struct Op {
+    name: &'static str,
+    dtypes: &'static [DType],
+    inputs: Inputs,
+    f: fn(&[Tensor]) -> Result<Tensor>,
+}
+
+fn unary(f: &mut Fuzzer, dtype: DType) -> Result<Vec<Tensor>> {
+    let shape = f.shape();
+    Ok(vec![f.tensor(&shape, dtype)?])
+}
+
+fn binary(f: &mut Fuzzer, dtype: DType) -> Result<Vec<Tensor>> {
+    let shape = f.shape();
+    Ok(vec![f.tensor(&shape, dtype)?, f.tensor(&shape, dtype)?])
+}
+
+/// Two tensors, some dimensions of the second one being set to 1.
+fn broadcast(f: &mut Fuzzer, dtype: DType) -> Result<Vec<Tensor>> {
+    let shape = f.shape();
+    let rhs_shape = shape
+        .iter()
+        .map(|&d| if f.rng.gen_bool(0.5) { 1 } else { d })
+        .collect::<Vec<_>>();
+    Ok(vec![f.tensor(&shape, dtype)?, f.tensor(&rhs_shape, dtype)?])
+}
+
+fn matmul(f: &mut Fuzzer, dtype: DType) -> Result<Vec<Tensor>> {
+    let (b, m, k, n) = (f.dim(3), f.dim(17), f.dim(33), f.dim(17));
+    Ok(vec![
+        f.tensor(&[b, m, k], dtype)?,
+        f.tensor(&[b, k, n], dtype)?,
+    ])
+}
+
+fn image(f: &mut Fuzzer, dtype: DType) -> Result<Vec<Tensor>> {
+    let shape = [f.dim(2), f.dim(3), f.dim(6) + 3, f.dim(6) + 3];
+    Ok(vec![f.tensor(&shape, dtype)?])
+}
+
+fn conv1d(f: &mut Fuzzer, dtype: DType) -> Result<Vec<Tensor>> {
+    let (b, c_in, c_out, l, k) = (f.dim(2), f.dim(4), f.dim(4), f.dim(9) + 3, f.dim(3));
+    Ok(vec![
+        f.tensor(&[b, c_in, l], dtype)?,
+        f.tensor(&[c_out, c_in, k], dtype)?,
+    ])
+}
+
+fn conv2d(f: &mut Fuzzer, dtype: DType) -> Result<Vec<Tensor>> {
+    let (b, c_in, c_out, h, w, k) = (
+        f.dim(2),
+        f.dim(3),
+        f.dim(4),
+        f.dim(6) + 3,
+        f.dim(6) + 3,
+        f.dim(3),
+    );
+    Ok(vec![
+        f.tensor(&[b, c_in, h, w], dtype)?,
+        f.tensor(&[c_out, c_in, k, k], dtype)?,
+    ])
+}
+
+fn select(f: &mut Fuzzer, dtype: DType) -> Result<Vec<Tensor>> {
+    let shape = f.shape();
+    let n = f.dim(9);
+    Ok(vec![f.tensor(&shape, dtype)?, f.indexes(n, shape[0])?])
+}
+
+fn gather(f: &mut Fuzzer, dtype: DType) -> Result<Vec<Tensor>> {
+    let shape = f.shape();
+    let mut ids_shape = shape.clone();
+    let last = ids_shape.len() - 1;
+    ids_shape[last] = f.dim(5);
+    let ids = f.indexes(ids_shape.iter().product(), shape[last])?;
+    Ok(vec![f.tensor(&shape, dtype)?, ids.reshape(ids_shape)?])
+}
+
+/// A positive version of the input, for the ops that are only defined on positive values.
+fn pos(t: &Tensor) -> Result<Tensor> {
+    t.abs()? + 0.5
+}
+
+fn ops() -> Vec<Op> {
+    macro_rules! op {
+        ($name:expr, $dtypes:expr, $inputs:expr, |$xs:ident| $body:expr) => {
+            Op {
+                name: $name,
+                dtypes: $dtypes,
+                inputs: $inputs,
+                f: |$xs: &[Tensor]| $body,
+            }
+        };
+    }
+    vec![
+        op!("neg", FLOAT, unary, |xs| xs[0].neg()),
+        op!("abs", FLOAT, unary, |xs| xs[0].abs()),
+        op!("exp", FLOAT, unary, |xs| xs[0].exp()),
+        op!("log", FLOAT, unary, |xs| pos(&xs[0])?.log()),
+        op!("sin", FLOAT, unary, |xs| xs[0].sin()),
+        op!("cos", FLOAT, unary, |xs| xs[0].cos()),
+        op!("tanh", FLOAT, unary, |xs| xs[0].tanh()),
+        op!("sqr", FLOAT, unary, |xs| xs[0].sqr()),
+        op!("sqrt", FLOAT, unary, |xs| pos(&xs[0])?.sqrt()),
+        op!("recip", FLOAT, unary, |xs| pos(&xs[0])?.recip()),
+        op!("gelu", FLOAT, unary, |xs| xs[0].gelu()),
+        op!("gelu_erf", FLOAT, unary, |xs| xs[0].gelu_erf()),
+        op!("erf", FLOAT, unary, |xs| xs[0].erf()),
+        op!("silu", FLOAT, unary, |xs| xs[0].silu()),
+        op!("relu", FLOAT, unary, |xs| xs[0].relu()),
+        op!("floor", FLOAT, unary, |xs| xs[0].floor()),
+        op!("ceil", FLOAT, unary, |xs| xs[0].ceil()),
+        op!("affine", FLOAT, unary, |xs| xs[0].affine(0.5, -1.)),
+        op!("powf", FLOAT, unary, |xs| pos(&xs[0])?.powf(1.7)),
+        op!("elu", FLOAT, unary, |xs| xs[0].elu(0.5)),
+        op!("clamp", FLOAT, unary, |xs| xs[0].clamp(-1f32, 1f32)),
+        op!("to_f32", ALL, unary, |xs| xs[0].to_dtype(DType::F32)),
+        op!("to_f16", FLOAT, unary, |xs| xs[0].to_dtype(DType::F16)),
+        op!("to_u32", FLOAT, unary, |xs| {
+            xs[0].abs()?.affine(4., 0.)?.to_dtype(DType::U32)
+        }),
+        op!("add", ALL, binary, |xs| &xs[0] + &xs[1]),
+        op!("sub", FLOAT, binary, |xs| &xs[0] - &xs[1]),
+        op!("mul", ALL, binary, |xs| &xs[0] * &xs[1]),
+        op!("div", FLOAT, binary, |xs| &xs[0] / pos(&xs[1])?),
+        op!("maximum", ALL, binary, |xs| xs[0].maximum(&xs[1])),
+        op!("minimum", ALL, binary, |xs| xs[0].minimum(&xs[1])),
+        op!("ge", ALL, binary, |xs| xs[0].ge(&xs[1])),
+        op!("lt", ALL, binary, |xs| xs[0].lt(&xs[1])),
+        op!("where_cond", FLOAT, binary, |xs| {
+            xs[0].ge(0f64)?.where_cond(&xs[0], &xs[1])
+        }),
+        op!("broadcast_add", ALL, broadcast, |xs| xs[0]
+            .broadcast_add(&xs[1])),
+        op!("broadcast_mul", FLOAT, broadcast, |xs| xs[0]
+            .broadcast_mul(&xs[1])),
+        op!("broadcast_div", FLOAT, broadcast, |xs| xs[0]
+            .broadcast_div(&pos(&xs[1])?)),
+        op!("sum_last", ALL, unary, |xs| xs[0].sum_keepdim(D::Minus1)),
+        op!("sum_first", FLOAT, unary, |xs| xs[0].sum_keepdim(0)),
+        op!("mean_last", FLOAT, unary, |xs| xs[0]
+            .mean_keepdim(D::Minus1)),
+        op!("max_last", ALL, unary, |xs| xs[0].max_keepdim(D::Minus1)),
+        op!("min_first", ALL, unary, |xs| xs[0].min_keepdim(0)),
+        // Ties are likely with half precision or integer values, the index could then differ.
+        op!("argmax_last", F32_F64, unary, |xs| xs[0]
+            .argmax_keepdim(D::Minus1)),
+        op!("argmin_first", F32_F64, unary, |xs| xs[0].argmin_keepdim(0)),
+        op!("sort_last", FLOAT, unary, |xs| Ok(xs[0]
+            .contiguous()?
+            .sort_last_dim(true)?
+            .0)),
+        op!("cumsum", F32_F64, unary, |xs| xs[0].cumsum(D::Minus1)),
+        op!("transpose", ALL, unary, |xs| match xs[0].rank() {
+            1 => Ok(xs[0].clone()),
+            _ => xs[0].t()?.contiguous(),
+        }),
+        op!("narrow", ALL, unary, |xs| {
+            let d = xs[0].dim(D::Minus1)?;
+            xs[0].narrow(D::Minus1, d / 2, d - d / 2)
+        }),
+        op!("cat_first", ALL, binary, |xs| Tensor::cat(
+            &[&xs[0], &xs[1]],
+            0
+        )),
+        op!("cat_last", ALL, binary, |xs| Tensor::cat(
+            &[&xs[0], &xs[1]],
+            D::Minus1
+        )),
+        op!("index_select", ALL, select, |xs| xs[0]
+            .index_select(&xs[1], 0)),
+        op!("gather", ALL, gather, |xs| xs[0].gather(&xs[1], D::Minus1)),
+        op!("matmul", FLOAT, matmul, |xs| xs[0].matmul(&xs[1])),
+        op!("matmul_t", FLOAT, matmul, |xs| {
+            let rhs = xs[1].transpose(1, 2)?.contiguous()?.transpose(1, 2)?;
+            xs[0].matmul(&rhs)
+        }),
+        op!("conv1d", FLOAT, conv1d, |xs| xs[0]
+            .conv1d(&xs[1], 1, 1, 1, 1)),
+        op!("conv2d", FLOAT, conv2d, |xs| xs[0]
+            .conv2d(&xs[1], 1, 1, 1, 1)),
+        op!("avg_pool2d", FLOAT, image, |xs| xs[0].avg_pool2d(2)),
+        op!("max_pool2d", FLOAT, image, |xs| xs[0].max_pool2d(2)),
+        op!("upsample_nearest2d", FLOAT, image, |xs| {
+            let (_, _, h, w) = xs[0].dims4()?;
+            xs[0].upsample_nearest2d(2 * h, w + 1)
+        }),
+    ]
+}
+
+/// A non-contiguous copy of `t` with the same values.
+fn strided(t: &Tensor) -> Result<Tensor> {
+    if t.rank() < 2 {
+        return Ok(t.clone());
+    }
+    t.t()?.contiguous()?.t()
+}
+
+fn env_or(name: &str, default: u64) -> u64 {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// The devices to compare with the cpu, `None` standing for non-contiguous inputs on the cpu.
+fn devices() -> Vec<(String, Option<Device>)> {
+    #[allow(unused_mut)]
+    let mut devices = vec![("cpu-strided".to_string(), None)];
+    #[cfg(feature = "cuda")]
+    match Device::new_cuda(0) {
+        Ok(dev) => devices.push(("cuda".to_string(), Some(dev))),
+        Err(err) => eprintln!("skipping cuda: {err}"),
+    }
+    #[cfg(feature = "metal")]
+    match Device::new_metal(0) {
+        Ok(dev) => devices.push(("metal".to_string(), Some(dev))),
+        Err(err) => eprintln!("skipping metal: {err}"),
+    }
+    #[cfg(feature = "wgpu")]
+    match Device::new_wgpu(0) {
+        Ok(dev) => devices.push(("wgpu".to_string(), Some(dev))),
+        Err(err) => eprintln!("skipping wgpu: {err}"),
+    }
+    devices
+}
+
+#[test]
+fn backend_consistency() -> Result<()> {
+    let trials = env_or("CANDLE_FUZZ_TRIALS", 3);
+    let seed = env_or("CANDLE_FUZZ_SEED", 299792458);
+    let mut fuzzer = Fuzzer {
+        rng: rand::rngs::StdRng::seed_from_u64(seed),
+    };
+    let devices = devices();
+    let mut divergences = vec![];
+    let mut unsupported = vec![];
+    for op in ops() {
+        for &dtype in op.dtypes {
+            for _ in 0..trials {
+                let inputs = (op.inputs)(&mut fuzzer, dtype)?;
+                let shapes = inputs.iter().map(|t| t.dims().to_vec()).collect::<Vec<_>>();
+                let expected = match (op.f)(&inputs) {
+                    Ok(expected) => expected,
+                    // Not all the dtypes are supported on the cpu, e.g. for convolutions.
+                    Err(_) => continue,
+                };
+                for (name, device) in devices.iter() {
+                    let inputs = inputs
+                        .iter()
+                        .map(|t| match device {
+                            None => strided(t),
+                            Some(device) => t.to_device(device),
+                        })
+                        .collect::<Result<Vec<_>>>()?;
+                    let what = format!("{} {dtype:?} {shapes:?} on {name}", op.name);
+                    let actual = match (op.f)(&inputs) {
+                        Ok(actual) => actual,
+                        // Some ops only support contiguous inputs and the accelerated backends
+                        // do not support all the dtypes, these are reported but not failures.
+                        Err(err) => {
+                            unsupported.push(format!("{what}: {err}"));
+                            continue;
+                        }
+                    };
+                    if actual.dtype() != expected.dtype() {
+                        divergences.push(format!(
+                            "{what}: dtype {:?}, expected {:?}",
+                            actual.dtype(),
+                            expected.dtype()
+                        ));
+                        continue;
+                    }
+                    let tolerance = Tolerance::for_dtype(dtype);
+                    match Diff::new(&actual, &expected, tolerance) {
+                        Ok(diff) if diff.is_close() => {}
+                        Ok(diff) => divergences.push(format!("{what}: {diff}")),
+                        Err(err) => divergences.push(format!("{what}: {err}")),
+                    }
+                }
+            }
+        }
+    }
+    if !unsupported.is_empty() {
+        eprintln!("{} unsupported runs:", unsupported.len());
+        for u in unsupported.iter() {
+            eprintln!("  {u}")
+        }
+    }
+    assert!(
+        divergences.is_empty(),
+        "{} divergent results, seed {seed}:\n{}",
+        divergences.len(),
+        divergences.join("\n")
+    );
+    Ok(())
+}
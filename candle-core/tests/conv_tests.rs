@@ -830,6 +830,82 @@ fn conv2d_grad(dev: &Device) -> Result<()> {
     Ok(())
 }
 
+// The kernels are made non-contiguous by transposing their last two dimensions twice.
+fn conv_strided_kernel(dev: &Device) -> Result<()> {
+    let t = Tensor::randn(0f32, 1., (1, 2, 7), dev)?;
+    let w = Tensor::randn(0f32, 1., (3, 2, 3), dev)?;
+    let w_strided = w.t()?.contiguous()?.t()?;
+    assert!(!w_strided.is_contiguous());
+    let diff = (t.conv1d(&w, 1, 1, 1, 1)? - t.conv1d(&w_strided, 1, 1, 1, 1)?)?;
+    assert_eq!(diff.abs()?.sum_all()?.to_vec0::<f32>()?, 0.);
+
+    let t = Tensor::randn(0f32, 1., (1, 2, 5, 6), dev)?;
+    let w = Tensor::randn(0f32, 1., (3, 2, 3, 3), dev)?;
+    let w_strided = w.t()?.contiguous()?.t()?;
+    let diff = (t.conv2d(&w, 1, 1, 1, 1)? - t.conv2d(&w_strided, 1, 1, 1, 1)?)?;
+    assert_eq!(diff.abs()?.sum_all()?.to_vec0::<f32>()?, 0.);
+    Ok(())
+}
+
+// A reference conv2d with no stride nor dilation computed as a sum of matmuls, one per kernel
+// position.
+fn conv2d_reference(t: &Tensor, w: &Tensor, padding: usize) -> Result<Tensor> {
+    let (b, c_in, h, w_) = t.dims4()?;
+    let (c_out, _, k_h, k_w) = w.dims4()?;
+    let t = t
+        .pad_with_zeros(2, padding, padding)?
+        .pad_with_zeros(3, padding, padding)?;
+    let (out_h, out_w) = (h + 2 * padding - k_h + 1, w_ + 2 * padding - k_w + 1);
+    let mut res = Tensor::zeros((b, c_out, out_h * out_w), t.dtype(), t.device())?;
+    for i in 0..k_h {
+        for j in 0..k_w {
+            let xs =
+                t.narrow(2, i, out_h)?
+                    .narrow(3, j, out_w)?
+                    .reshape((b, c_in, out_h * out_w))?;
+            let ws = w.i((.., .., i, j))?.contiguous()?;
+            res = (res + ws.broadcast_matmul(&xs)?)?;
+        }
+    }
+    Ok(res.reshape((b, c_out, out_h, out_w))?)
+}
+
+// 3x3 kernels use winograd on the cpu with 2x2 output tiles for small outputs and 4x4 tiles
+// otherwise, 1x1 kernels use a plain matmul.
+fn conv2d_fast_paths(dev: &Device) -> Result<()> {
+    for (b, c_in, c_out, h, w, k, padding) in [
+        (1, 2, 3, 5, 6, 3, 1),
+        (8, 3, 4, 7, 5, 3, 0),
+        (2, 4, 5, 17, 13, 3, 1),
+        (3, 3, 2, 10, 11, 3, 2),
+        (2, 3, 4, 6, 7, 1, 0),
+    ] {
+        let t = Tensor::randn(0f32, 1., (b, c_in, h, w), dev)?;
+        let ws = Tensor::randn(0f32, 1., (c_out, c_in, k, k), dev)?;
+        let expected = conv2d_reference(&t, &ws, padding)?;
+        let res = t.conv2d(&ws, padding, 1, 1, 1)?;
+        assert_eq!(res.dims(), expected.dims());
+        let diff = (res - &expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(
+            diff.to_vec0::<f32>()? < 1e-4,
+            "{b} {c_in} {c_out} {h} {w} {k} {padding}"
+        );
+
+        // Non-contiguous input.
+        let t_strided = t.transpose(2, 3)?.contiguous()?.transpose(2, 3)?;
+        let res = t_strided.conv2d(&ws, padding, 1, 1, 1)?;
+        let diff = (res - &expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_vec0::<f32>()? < 1e-4);
+
+        let t = t.to_dtype(candle_core::DType::F64)?;
+        let ws = ws.to_dtype(candle_core::DType::F64)?;
+        let expected = expected.to_dtype(candle_core::DType::F64)?;
+        let diff = (t.conv2d(&ws, padding, 1, 1, 1)? - expected)?;
+        assert!(diff.abs()?.flatten_all()?.max(0)?.to_vec0::<f64>()? < 1e-4);
+    }
+    Ok(())
+}
+
 test_device!(conv1d, conv1d_cpu, conv1d_gpu, conv1d_metal);
 test_device!(
     conv1d_small,
@@ -862,3 +938,15 @@ test_device!(
     conv2d_grad_gpu,
     conv2_grad_metal
 );
+test_device!(
+    conv_strided_kernel,
+    conv_strided_kernel_cpu,
+    conv_strided_kernel_gpu,
+    conv_strided_kernel_metal
+);
+test_device!(
+    conv2d_fast_paths,
+    conv2d_fast_paths_cpu,
+    conv2d_fast_paths_gpu,
+    conv2d_fast_paths_metal
+);
@@ -0,0 +1,80 @@
+use candle_core::{Context, DType, Device, Error, Result, Tensor};
+
+#[test]
+fn op_provenance() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::zeros((2, 3), DType::F32, dev)?;
+    let ws = Tensor::zeros((4, 5), DType::F32, dev)?;
+    ws.set_name("model.layers.3.mlp.weight");
+    assert_eq!(ws.name(), Some("model.layers.3.mlp.weight"));
+    // Names can only be set once.
+    ws.set_name("other");
+    assert_eq!(ws.name(), Some("model.layers.3.mlp.weight"));
+
+    // Derived tensors keep the name, the result of ops do not.
+    let ws_t = ws.t()?;
+    assert_eq!(ws_t.name(), Some("model.layers.3.mlp.weight"));
+    assert_eq!(xs.matmul(&xs.t()?)?.name(), None);
+
+    let err = xs.matmul(&ws_t).unwrap_err();
+    let msg = err.to_string();
+    assert!(msg.contains("in matmul with inputs:"), "{msg}");
+    assert!(msg.contains("[2, 3] f32"), "{msg}");
+    assert!(
+        msg.contains("model.layers.3.mlp.weight [5, 4] f32"),
+        "{msg}"
+    );
+    match err {
+        Error::WithOp { op, inputs, .. } => {
+            assert_eq!(op, "matmul");
+            assert_eq!(inputs.len(), 2);
+            assert_eq!(inputs[1].shape.dims(), [5, 4]);
+        }
+        Error::WithBacktrace { inner, .. } => {
+            assert!(matches!(*inner, Error::WithOp { op: "matmul", .. }))
+        }
+        err => panic!("unexpected error {err:?}"),
+    }
+
+    // The innermost op is reported.
+    let msg = xs.broadcast_add(&ws).unwrap_err().to_string();
+    assert!(msg.contains("in broadcast_add with inputs:"), "{msg}");
+    let msg = xs.reshape((4, 2)).unwrap_err().to_string();
+    assert!(msg.contains("in reshape with inputs:"), "{msg}");
+    Ok(())
+}
+
+#[cfg(debug_assertions)]
+#[test]
+fn op_location() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[1f32, 2., 3.], dev)?;
+    let location = xs.location().unwrap();
+    assert!(location.file().ends_with("error_tests.rs"));
+    let ys = (&xs + &xs)?.reshape((3, 1))?;
+    assert_eq!(ys.location().unwrap().line(), location.line() + 3);
+    let msg = xs.matmul(&ys).unwrap_err().to_string();
+    assert!(msg.contains("created at"), "{msg}");
+    assert!(msg.contains("error_tests.rs"), "{msg}");
+    Ok(())
+}
+
+#[test]
+fn context() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::zeros((2, 3), DType::F32, dev)?;
+    let msg = xs
+        .matmul(&xs)
+        .with_context(|| format!("layer {}", 12))
+        .unwrap_err()
+        .to_string();
+    assert!(
+        msg.starts_with("layer 12: shape mismatch in matmul"),
+        "{msg}"
+    );
+    let v: Option<usize> = None;
+    let msg = v.context("missing value").unwrap_err().to_string();
+    assert!(msg.starts_with("missing value"), "{msg}");
+    assert_eq!(Some(1).context("unused")?, 1);
+    Ok(())
+}
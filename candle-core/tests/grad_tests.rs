@@ -505,6 +505,46 @@ fn binary_grad(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn pad_grad(device: &Device) -> Result<()> {
+    use candle_core::PadMode;
+    let x = Var::new(&[1f32, 2., 3., 4.], device)?;
+    let x = x.as_tensor();
+    // Each padded value adds to the gradient of the element it is copied from.
+    for (mode, expected) in [
+        (PadMode::Reflect, [1f32, 2., 3., 1.]),
+        (PadMode::Replicate, [3., 1., 1., 2.]),
+        (PadMode::Circular, [2., 1., 2., 2.]),
+        (PadMode::Constant(5.), [1., 1., 1., 1.]),
+    ] {
+        let y = x.pad(0, 2, 1, mode)?;
+        let grads = y.sum_all()?.backward()?;
+        let grad_x = grads.get(x).context("no grad for x")?;
+        assert_eq!(grad_x.to_vec1::<f32>()?, expected, "{mode:?}");
+    }
+    Ok(())
+}
+
+fn max_with_indices_grad(device: &Device) -> Result<()> {
+    let x = Var::new(&[[3f32, 7., 7., 1.], [2., 0., 5., 5.]], device)?;
+    let x = x.as_tensor();
+    // Unlike `max`, only the selected elements get a gradient on ties.
+    let (values, _indices) = x.max_with_indices(1)?;
+    let grads = values.sum_all()?.backward()?;
+    let grad_x = grads.get(x).context("no grad for x")?;
+    assert_eq!(
+        grad_x.to_vec2::<f32>()?,
+        [[0., 1., 0., 0.], [0., 0., 1., 0.]]
+    );
+    let (values, _indices) = x.min_with_indices_keepdim(1)?;
+    let grads = values.sum_all()?.backward()?;
+    let grad_x = grads.get(x).context("no grad for x")?;
+    assert_eq!(
+        grad_x.to_vec2::<f32>()?,
+        [[0., 0., 0., 1.], [0., 1., 0., 0.]]
+    );
+    Ok(())
+}
+
 test_device!(
     simple_grad,
     simple_grad_cpu,
@@ -531,3 +571,10 @@ test_device!(
     binary_grad_gpu,
     binary_grad_metal
 );
+test_device!(pad_grad, pad_grad_cpu, pad_grad_gpu, pad_grad_metal);
+test_device!(
+    max_with_indices_grad,
+    max_with_indices_grad_cpu,
+    max_with_indices_grad_gpu,
+    max_with_indices_grad_metal
+);
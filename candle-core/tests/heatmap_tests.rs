@@ -0,0 +1,71 @@
+use candle_core::heatmap::{Colormap, Heatmap, Normalization};
+use candle_core::{Device, Result, Tensor};
+
+#[test]
+fn heatmap_pixels() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::new(&[[0f32, 1.], [2., 4.]], dev)?;
+    let img = Heatmap::new().to_image(&t)?;
+    assert_eq!((img.width, img.height, img.channels), (2, 2, 1));
+    assert_eq!(img.data, [0, 64, 128, 255]);
+
+    let img = Heatmap::new()
+        .with_normalization(Normalization::Range { min: 0., max: 2. })
+        .to_image(&t)?;
+    assert_eq!(img.data, [0, 128, 255, 255]);
+
+    let t = Tensor::new(&[[-2f32, 0., 1.]], dev)?;
+    let img = Heatmap::new()
+        .with_normalization(Normalization::Symmetric)
+        .with_colormap(Colormap::Coolwarm)
+        .to_image(&t)?;
+    assert_eq!(img.channels, 3);
+    assert_eq!(&img.data[..3], [59, 76, 192]);
+    assert_eq!(&img.data[3..6], [221, 221, 221]);
+
+    // Non-finite values are ignored by the normalization.
+    let t = Tensor::new(&[[f32::NAN, 1., f32::INFINITY, 3.]], dev)?;
+    let img = Heatmap::new().to_image(&t)?;
+    assert_eq!(img.data, [0, 0, 255, 255]);
+    Ok(())
+}
+
+#[test]
+fn heatmap_tiles() -> Result<()> {
+    let dev = &Device::Cpu;
+    // Three 2x2 tiles are laid out on a 2x2 grid with 1 pixel of padding.
+    let t = Tensor::arange(0f32, 12., dev)?.reshape((3, 2, 2))?;
+    let img = Heatmap::new()
+        .with_normalization(Normalization::PerTile)
+        .to_image(&t)?;
+    assert_eq!((img.width, img.height), (5, 5));
+    assert_eq!(&img.data[..5], [0, 85, 0, 0, 85]);
+    assert_eq!(&img.data[5..10], [170, 255, 0, 170, 255]);
+    assert_eq!(&img.data[10..15], [0; 5]);
+    assert_eq!(&img.data[15..20], [0, 85, 0, 0, 0]);
+    assert!(Heatmap::new().to_image(&t.unsqueeze(0)?).is_err());
+    Ok(())
+}
+
+#[test]
+fn heatmap_png() -> Result<()> {
+    let t = Tensor::ones((3, 5), candle_core::DType::F32, &Device::Cpu)?;
+    let mut png = vec![];
+    Heatmap::new()
+        .with_colormap(Colormap::Viridis)
+        .write_png(&t, &mut png)?;
+    assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+    // IHDR: width 5, height 3, 8 bits rgb.
+    assert_eq!(&png[12..16], b"IHDR");
+    assert_eq!(&png[16..29], [0, 0, 0, 5, 0, 0, 0, 3, 8, 2, 0, 0, 0]);
+    // IDAT: a zlib header, a single stored block, and the adler32 checksum.
+    let idat_len = u32::from_be_bytes([png[33], png[34], png[35], png[36]]) as usize;
+    assert_eq!(&png[37..41], b"IDAT");
+    assert_eq!(idat_len, 2 + 5 + 3 * (1 + 5 * 3) + 4);
+    // The IEND chunk has a well known checksum.
+    assert_eq!(
+        &png[png.len() - 12..],
+        [0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xae, 0x42, 0x60, 0x82]
+    );
+    Ok(())
+}
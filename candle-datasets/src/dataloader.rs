@@ -0,0 +1,366 @@
+//! A generic data loader that prepares batches on background threads.
+//!
+//! Samples are fetched from an indexed [`Dataset`], grouped into batches by a collation function,
+//! and moved to the target device by a pool of worker threads so that data preparation overlaps
+//! with the training step. Batches are always returned in the same order for a given seed and
+//! epoch, whatever the number of workers.
+use candle::{Device, Result, Tensor};
+use rand::{seq::SliceRandom, SeedableRng};
+use std::sync::mpsc::{sync_channel, Receiver};
+use std::sync::Arc;
+
+/// A dataset that supports random access to its samples.
+pub trait Dataset: Send + Sync {
+    type Item: Send;
+
+    fn len(&self) -> usize;
+
+    fn get(&self, index: usize) -> Result<Self::Item>;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Clone + Send + Sync> Dataset for Vec<T> {
+    type Item = T;
+
+    fn len(&self) -> usize {
+        self.as_slice().len()
+    }
+
+    fn get(&self, index: usize) -> Result<T> {
+        match self.as_slice().get(index) {
+            Some(v) => Ok(v.clone()),
+            None => candle::bail!(
+                "index {index} out of range for dataset of size {}",
+                self.len()
+            ),
+        }
+    }
+}
+
+/// The samples are the slices of the tensor along its first dimension.
+impl Dataset for Tensor {
+    type Item = Tensor;
+
+    fn len(&self) -> usize {
+        self.dims().first().copied().unwrap_or(0)
+    }
+
+    fn get(&self, index: usize) -> Result<Tensor> {
+        Tensor::get(self, index)
+    }
+}
+
+/// Inputs and targets, sliced jointly along their first dimension.
+impl Dataset for (Tensor, Tensor) {
+    type Item = (Tensor, Tensor);
+
+    fn len(&self) -> usize {
+        self.0.dims().first().copied().unwrap_or(0)
+    }
+
+    fn get(&self, index: usize) -> Result<(Tensor, Tensor)> {
+        Ok((self.0.get(index)?, self.1.get(index)?))
+    }
+}
+
+/// A batch that can be moved to a device.
+pub trait Batch: Send + Sized {
+    fn to_device(&self, device: &Device) -> Result<Self>;
+}
+
+impl Batch for Tensor {
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        Tensor::to_device(self, device)
+    }
+}
+
+impl Batch for (Tensor, Tensor) {
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        Ok((self.0.to_device(device)?, self.1.to_device(device)?))
+    }
+}
+
+impl Batch for Vec<Tensor> {
+    fn to_device(&self, device: &Device) -> Result<Self> {
+        self.iter().map(|t| t.to_device(device)).collect()
+    }
+}
+
+/// Collates samples by stacking them along a new first dimension.
+pub fn stack1(items: Vec<Tensor>) -> Result<Tensor> {
+    Tensor::stack(&items, 0)
+}
+
+/// Collates pairs of samples by stacking each element along a new first dimension.
+pub fn stack2(items: Vec<(Tensor, Tensor)>) -> Result<(Tensor, Tensor)> {
+    let (xs, ys): (Vec<_>, Vec<_>) = items.into_iter().unzip();
+    Ok((Tensor::stack(&xs, 0)?, Tensor::stack(&ys, 0)?))
+}
+
+pub struct DataLoader<D, F> {
+    dataset: Arc<D>,
+    collate: Arc<F>,
+    batch_size: usize,
+    shuffle: bool,
+    seed: u64,
+    drop_last: bool,
+    num_workers: usize,
+    prefetch: usize,
+    device: Device,
+}
+
+impl<D, B, F> DataLoader<D, F>
+where
+    D: Dataset + 'static,
+    B: Batch + 'static,
+    F: Fn(Vec<D::Item>) -> Result<B> + Send + Sync + 'static,
+{
+    /// Creates a data loader with the default settings: a batch size of 16, no shuffling, two
+    /// workers prefetching two batches each, and batches left on the cpu.
+    pub fn new(dataset: D, collate: F) -> Self {
+        Self {
+            dataset: Arc::new(dataset),
+            collate: Arc::new(collate),
+            batch_size: 16,
+            shuffle: false,
+            seed: 0,
+            drop_last: false,
+            num_workers: 2,
+            prefetch: 2,
+            device: Device::Cpu,
+        }
+    }
+
+    /// The number of samples per batch, which must be positive. A zero batch size is reported
+    /// as an error by [`Self::num_batches`] and [`Self::iter`].
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Shuffles the samples at each epoch, the permutation only depends on the seed and on the
+    /// epoch index.
+    pub fn shuffle(mut self, seed: u64) -> Self {
+        self.shuffle = true;
+        self.seed = seed;
+        self
+    }
+
+    /// Drops the last batch if it is smaller than the batch size.
+    pub fn drop_last(mut self, drop_last: bool) -> Self {
+        self.drop_last = drop_last;
+        self
+    }
+
+    /// The number of background threads, when set to 0 the batches are prepared on the calling
+    /// thread.
+    pub fn num_workers(mut self, num_workers: usize) -> Self {
+        self.num_workers = num_workers;
+        self
+    }
+
+    /// The number of batches that each worker prepares ahead of time.
+    pub fn prefetch(mut self, prefetch: usize) -> Self {
+        self.prefetch = prefetch;
+        self
+    }
+
+    /// The device the batches are moved to. The copy happens on the worker threads so that it
+    /// overlaps with the computation on the main thread. The batches are not staged in pinned
+    /// host memory as candle has no api to allocate it, the copies to cuda devices use the
+    /// pageable memory of the collated tensors.
+    pub fn device(mut self, device: &Device) -> Self {
+        self.device = device.clone();
+        self
+    }
+
+    pub fn dataset(&self) -> &D {
+        self.dataset.as_ref()
+    }
+
+    fn check_batch_size(&self) -> Result<()> {
+        if self.batch_size == 0 {
+            candle::bail!("dataloader: the batch size must be positive")
+        }
+        Ok(())
+    }
+
+    pub fn num_batches(&self) -> Result<usize> {
+        self.check_batch_size()?;
+        let len = self.dataset.len();
+        let num_batches = if self.drop_last {
+            len / self.batch_size
+        } else {
+            len.div_ceil(self.batch_size)
+        };
+        Ok(num_batches)
+    }
+
+    fn batch_indexes(&self, epoch: u64) -> Vec<Vec<usize>> {
+        let mut indexes = (0..self.dataset.len()).collect::<Vec<_>>();
+        if self.shuffle {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(self.seed.wrapping_add(epoch));
+            indexes.shuffle(&mut rng);
+        }
+        indexes
+            .chunks(self.batch_size)
+            .filter(|c| !self.drop_last || c.len() == self.batch_size)
+            .map(|c| c.to_vec())
+            .collect()
+    }
+
+    fn load_batch(dataset: &D, collate: &F, indexes: &[usize], device: &Device) -> Result<B> {
+        let items = indexes
+            .iter()
+            .map(|&i| dataset.get(i))
+            .collect::<Result<Vec<_>>>()?;
+        let batch = collate(items)?;
+        if device.is_cpu() {
+            Ok(batch)
+        } else {
+            batch.to_device(device)
+        }
+    }
+
+    /// Returns an iterator over the batches for the given epoch. The worker threads are stopped
+    /// when the iterator is dropped.
+    pub fn iter(&self, epoch: u64) -> Result<DataLoaderIter<B>> {
+        self.check_batch_size()?;
+        let batches = self.batch_indexes(epoch);
+        let num_batches = batches.len();
+        if self.num_workers == 0 {
+            let dataset = self.dataset.clone();
+            let collate = self.collate.clone();
+            let device = self.device.clone();
+            let iter = batches
+                .into_iter()
+                .map(move |indexes| Self::load_batch(&dataset, &collate, &indexes, &device));
+            return Ok(DataLoaderIter {
+                inner: Inner::Sync(Box::new(iter)),
+                index: 0,
+                num_batches,
+            });
+        }
+        // Batch i is prepared by worker i % num_workers, reading the workers in a round-robin
+        // way returns the batches in order.
+        let mut receivers = Vec::with_capacity(self.num_workers);
+        for worker_index in 0..self.num_workers {
+            let (sender, receiver) = sync_channel(self.prefetch.max(1));
+            let dataset = self.dataset.clone();
+            let collate = self.collate.clone();
+            let device = self.device.clone();
+            let batches = batches
+                .iter()
+                .skip(worker_index)
+                .step_by(self.num_workers)
+                .cloned()
+                .collect::<Vec<_>>();
+            std::thread::spawn(move || {
+                for indexes in batches {
+                    let batch = Self::load_batch(&dataset, &collate, &indexes, &device);
+                    if sender.send(batch).is_err() {
+                        // The iterator has been dropped.
+                        break;
+                    }
+                }
+            });
+            receivers.push(receiver)
+        }
+        Ok(DataLoaderIter {
+            inner: Inner::Workers(receivers),
+            index: 0,
+            num_batches,
+        })
+    }
+}
+
+enum Inner<B> {
+    Sync(Box<dyn Iterator<Item = Result<B>>>),
+    Workers(Vec<Receiver<Result<B>>>),
+}
+
+pub struct DataLoaderIter<B> {
+    inner: Inner<B>,
+    index: usize,
+    num_batches: usize,
+}
+
+impl<B> Iterator for DataLoaderIter<B> {
+    type Item = Result<B>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.index >= self.num_batches {
+            return None;
+        }
+        let batch = match &mut self.inner {
+            Inner::Sync(iter) => iter.next()?,
+            Inner::Workers(receivers) => {
+                let receiver = &receivers[self.index % receivers.len()];
+                match receiver.recv() {
+                    Ok(batch) => batch,
+                    Err(_) => Err(candle::Error::Msg("dataloader worker panicked".to_string())),
+                }
+            }
+        };
+        self.index += 1;
+        Some(batch)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let remaining = self.num_batches - self.index;
+        (remaining, Some(remaining))
+    }
+}
+
+impl<B> ExactSizeIterator for DataLoaderIter<B> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Loader = DataLoader<Tensor, fn(Vec<Tensor>) -> Result<Tensor>>;
+
+    fn collect(loader: &Loader) -> Vec<Vec<u32>> {
+        loader
+            .iter(3)
+            .unwrap()
+            .map(|b| b.and_then(|b| b.to_vec1::<u32>()))
+            .collect::<Result<Vec<_>>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn dataloader() -> Result<()> {
+        let dataset = Tensor::arange(0u32, 10, &Device::Cpu)?;
+        let loader = DataLoader::new(dataset, stack1 as fn(Vec<Tensor>) -> Result<Tensor>)
+            .batch_size(4)
+            .num_workers(0);
+        assert_eq!(loader.num_batches()?, 3);
+        assert_eq!(
+            collect(&loader),
+            [vec![0, 1, 2, 3], vec![4, 5, 6, 7], vec![8, 9]]
+        );
+
+        let loader = loader.drop_last(true).shuffle(42);
+        let sync = collect(&loader);
+        assert_eq!(sync.len(), 2);
+        for num_workers in [1, 3] {
+            let loader = DataLoader::new(
+                loader.dataset().clone(),
+                stack1 as fn(Vec<Tensor>) -> Result<Tensor>,
+            )
+            .batch_size(4)
+            .drop_last(true)
+            .shuffle(42)
+            .num_workers(num_workers);
+            assert_eq!(collect(&loader), sync);
+        }
+        let loader = loader.batch_size(0);
+        assert!(loader.num_batches().is_err());
+        assert!(loader.iter(0).is_err());
+        Ok(())
+    }
+}
@@ -1,7 +1,11 @@
 //! Datasets & Dataloaders for Candle
 pub mod batcher;
+pub mod dataloader;
 pub mod hub;
 pub mod nlp;
+pub mod streaming;
 pub mod vision;
+pub mod webdataset;
 
 pub use batcher::Batcher;
+pub use dataloader::DataLoader;
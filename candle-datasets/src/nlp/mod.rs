@@ -1 +1,2 @@
+pub mod packing;
 pub mod tinystories;
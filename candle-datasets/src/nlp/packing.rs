@@ -0,0 +1,251 @@
+//! Packing of tokenized text into fixed length sequences for language model training.
+//!
+//! The documents of a corpus are tokenized, separated by an end-of-sequence token and written as
+//! a flat stream of little-endian `u32` tokens. [`PackedDataset`] memory-maps such a file and
+//! returns consecutive windows of `seq_len + 1` tokens as `(inputs, targets)` pairs, optionally
+//! with segment ids so that attention can be restricted to the current document.
+use candle::{Device, Error, Result, Tensor};
+use std::io::Write;
+
+/// Writes documents as a flat token stream, appending `eos_token` after each document.
+pub struct TokenWriter<W: Write> {
+    writer: W,
+    eos_token: u32,
+    num_tokens: usize,
+}
+
+impl<W: Write> TokenWriter<W> {
+    pub fn new(writer: W, eos_token: u32) -> Self {
+        Self {
+            writer,
+            eos_token,
+            num_tokens: 0,
+        }
+    }
+
+    pub fn write_document(&mut self, tokens: &[u32]) -> Result<()> {
+        for &token in tokens.iter().chain(std::iter::once(&self.eos_token)) {
+            self.writer.write_all(&token.to_le_bytes())?;
+        }
+        self.num_tokens += tokens.len() + 1;
+        Ok(())
+    }
+
+    /// The number of tokens written so far, including the separators.
+    pub fn num_tokens(&self) -> usize {
+        self.num_tokens
+    }
+
+    pub fn finish(mut self) -> Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Tokenizes some documents and writes the resulting token stream to `path`, returning the total
+/// number of tokens.
+pub fn tokenize_to_file<P, I, S>(
+    tokenizer: &tokenizers::Tokenizer,
+    documents: I,
+    eos_token: u32,
+    path: P,
+) -> Result<usize>
+where
+    P: AsRef<std::path::Path>,
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let file = std::io::BufWriter::new(std::fs::File::create(path)?);
+    let mut writer = TokenWriter::new(file, eos_token);
+    for document in documents {
+        let encoding = tokenizer
+            .encode(document.as_ref(), false)
+            .map_err(|e| Error::Msg(format!("tokenizer error: {e}")))?;
+        writer.write_document(encoding.get_ids())?;
+    }
+    let num_tokens = writer.num_tokens();
+    writer.finish()?;
+    Ok(num_tokens)
+}
+
+/// Packs documents into sequences of exactly `seq_len` tokens, documents are separated by
+/// `eos_token` and may span multiple sequences.
+pub struct Packer {
+    seq_len: usize,
+    eos_token: u32,
+    tokens: Vec<u32>,
+}
+
+impl Packer {
+    pub fn new(seq_len: usize, eos_token: u32) -> Self {
+        Self {
+            seq_len,
+            eos_token,
+            tokens: Vec::with_capacity(seq_len),
+        }
+    }
+
+    /// Adds a document and returns the sequences that have been completed.
+    pub fn push(&mut self, document: &[u32]) -> Vec<Vec<u32>> {
+        let mut sequences = vec![];
+        for &token in document.iter().chain(std::iter::once(&self.eos_token)) {
+            self.tokens.push(token);
+            if self.tokens.len() == self.seq_len {
+                sequences.push(std::mem::replace(
+                    &mut self.tokens,
+                    Vec::with_capacity(self.seq_len),
+                ))
+            }
+        }
+        sequences
+    }
+
+    /// Returns the last incomplete sequence, padded with `pad_token`.
+    pub fn finish(self, pad_token: u32) -> Option<Vec<u32>> {
+        if self.tokens.is_empty() {
+            return None;
+        }
+        let mut tokens = self.tokens;
+        tokens.resize(self.seq_len, pad_token);
+        Some(tokens)
+    }
+}
+
+/// Returns the segment ids of a packed sequence, the segment id is incremented after each
+/// `eos_token` so that tokens from different documents get different ids.
+pub fn segment_ids(tokens: &[u32], eos_token: u32) -> Vec<u32> {
+    let mut segment_id = 0;
+    tokens
+        .iter()
+        .map(|&token| {
+            let id = segment_id;
+            if token == eos_token {
+                segment_id += 1
+            }
+            id
+        })
+        .collect()
+}
+
+/// A memory-mapped token stream split into non-overlapping windows of `seq_len + 1` tokens.
+pub struct PackedDataset {
+    tokens: memmap2::Mmap,
+    seq_len: usize,
+    eos_token: u32,
+    device: Device,
+}
+
+impl PackedDataset {
+    pub fn new<P: AsRef<std::path::Path>>(
+        path: P,
+        seq_len: usize,
+        eos_token: u32,
+        device: &Device,
+    ) -> Result<Self> {
+        let file = std::fs::File::open(path)?;
+        let tokens = unsafe { memmap2::MmapOptions::new().map(&file)? };
+        if tokens.len() % 4 > 0 {
+            candle::bail!("token file size {} is not a multiple of 4", tokens.len())
+        }
+        Ok(Self {
+            tokens,
+            seq_len,
+            eos_token,
+            device: device.clone(),
+        })
+    }
+
+    pub fn num_tokens(&self) -> usize {
+        self.tokens.len() / 4
+    }
+
+    pub fn len(&self) -> usize {
+        self.num_tokens().saturating_sub(1) / self.seq_len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn window(&self, index: usize) -> Result<Vec<u32>> {
+        if index >= self.len() {
+            candle::bail!("index {index} out of range for {} sequences", self.len())
+        }
+        let start = index * self.seq_len * 4;
+        let bytes = &self.tokens[start..start + (self.seq_len + 1) * 4];
+        let tokens = bytes
+            .chunks_exact(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect();
+        Ok(tokens)
+    }
+
+    /// Returns the inputs and the targets, i.e. the inputs shifted by one, for the sequence at
+    /// `index`. Both tensors have shape `(seq_len,)`.
+    pub fn get(&self, index: usize) -> Result<(Tensor, Tensor)> {
+        let tokens = self.window(index)?;
+        let inputs = Tensor::new(&tokens[..self.seq_len], &self.device)?;
+        let targets = Tensor::new(&tokens[1..], &self.device)?;
+        Ok((inputs, targets))
+    }
+
+    /// Same as [`PackedDataset::get`] with the segment ids of the inputs as a third tensor.
+    pub fn get_with_segment_ids(&self, index: usize) -> Result<(Tensor, Tensor, Tensor)> {
+        let tokens = self.window(index)?;
+        let inputs = &tokens[..self.seq_len];
+        let segment_ids = segment_ids(inputs, self.eos_token);
+        let segment_ids = Tensor::new(segment_ids.as_slice(), &self.device)?;
+        let targets = Tensor::new(&tokens[1..], &self.device)?;
+        let inputs = Tensor::new(inputs, &self.device)?;
+        Ok((inputs, targets, segment_ids))
+    }
+}
+
+impl crate::dataloader::Dataset for PackedDataset {
+    type Item = (Tensor, Tensor);
+
+    fn len(&self) -> usize {
+        PackedDataset::len(self)
+    }
+
+    fn get(&self, index: usize) -> Result<Self::Item> {
+        PackedDataset::get(self, index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packer() {
+        let mut packer = Packer::new(4, 0);
+        assert_eq!(packer.push(&[1, 2]), Vec::<Vec<u32>>::new());
+        assert_eq!(packer.push(&[3, 4, 5, 6, 7]), [[1, 2, 0, 3], [4, 5, 6, 7]]);
+        assert_eq!(packer.finish(9), Some(vec![0, 9, 9, 9]));
+        assert_eq!(segment_ids(&[1, 2, 0, 3, 0, 4], 0), [0, 0, 0, 1, 1, 2]);
+    }
+
+    #[test]
+    fn packed_dataset() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("candle-packing-{}.bin", std::process::id()));
+        let mut writer = TokenWriter::new(std::fs::File::create(&path)?, 0);
+        writer.write_document(&[1, 2, 3])?;
+        writer.write_document(&[4, 5, 6, 7, 8])?;
+        assert_eq!(writer.num_tokens(), 10);
+        writer.finish()?;
+
+        let dataset = PackedDataset::new(&path, 4, 0, &Device::Cpu)?;
+        assert_eq!(dataset.len(), 2);
+        let (inputs, targets, segment_ids) = dataset.get_with_segment_ids(0)?;
+        assert_eq!(inputs.to_vec1::<u32>()?, [1, 2, 3, 0]);
+        assert_eq!(targets.to_vec1::<u32>()?, [2, 3, 0, 4]);
+        assert_eq!(segment_ids.to_vec1::<u32>()?, [0, 0, 0, 0]);
+        let (inputs, targets) = dataset.get(1)?;
+        assert_eq!(inputs.to_vec1::<u32>()?, [4, 5, 6, 7]);
+        assert_eq!(targets.to_vec1::<u32>()?, [5, 6, 7, 8]);
+        assert!(dataset.get(2).is_err());
+        std::fs::remove_file(&path)?;
+        Ok(())
+    }
+}
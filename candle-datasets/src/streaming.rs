@@ -0,0 +1,354 @@
+//! Streaming reader for parquet datasets.
+//!
+//! Rows are read lazily, one row group at a time, so that corpora larger than the available
+//! memory can be iterated over. The reader supports column projection, approximate shuffling
+//! through a fixed size shuffle buffer, and sharding of the row groups across workers.
+use candle::{Error, Result};
+use hf_hub::{
+    api::sync::{Api, ApiRepo},
+    Repo, RepoType,
+};
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::file::serialized_reader::ReadOptionsBuilder;
+use parquet::record::reader::RowIter;
+use parquet::record::Row;
+use parquet::schema::types::Type;
+use rand::{Rng, SeedableRng};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Clone)]
+enum Source {
+    Local(PathBuf),
+    Hub {
+        repo: Arc<ApiRepo>,
+        filename: String,
+    },
+}
+
+impl Source {
+    fn path(&self) -> Result<PathBuf> {
+        match self {
+            Self::Local(path) => Ok(path.clone()),
+            Self::Hub { repo, filename } => repo
+                .get(filename)
+                .map_err(|e| Error::Msg(format!("Api error: {e}"))),
+        }
+    }
+}
+
+/// A parquet dataset that is read row by row.
+///
+/// ```no_run
+/// # fn main() -> candle::Result<()> {
+/// let api = hf_hub::api::sync::Api::new().unwrap();
+/// let dataset = candle_datasets::streaming::StreamingDataset::from_hub(
+///     &api,
+///     "roneneldan/TinyStories",
+///     "train",
+/// )?
+/// .columns(&["text"])
+/// .shuffle(10_000, 42)
+/// .shard(0, 4)?;
+/// for row in dataset.iter() {
+///     let row = row?;
+///     println!("{row}");
+/// }
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct StreamingDataset {
+    sources: Vec<Source>,
+    columns: Option<Vec<String>>,
+    shuffle_buffer_size: usize,
+    seed: u64,
+    shard_index: usize,
+    num_shards: usize,
+}
+
+impl StreamingDataset {
+    fn new(sources: Vec<Source>) -> Self {
+        Self {
+            sources,
+            columns: None,
+            shuffle_buffer_size: 0,
+            seed: 0,
+            shard_index: 0,
+            num_shards: 1,
+        }
+    }
+
+    /// Creates a dataset from local parquet files, these are read in the given order unless
+    /// shuffling is enabled.
+    pub fn from_files<P: Into<PathBuf>>(files: impl IntoIterator<Item = P>) -> Self {
+        let sources = files.into_iter().map(|p| Source::Local(p.into())).collect();
+        Self::new(sources)
+    }
+
+    /// Creates a dataset from the parquet conversion of a hub dataset. Only the files whose path
+    /// contain `split` as a directory component are used, e.g. `default/train/0000.parquet`
+    /// for the `train` split. The files are only downloaded when the iteration reaches them.
+    pub fn from_hub(api: &Api, dataset_id: &str, split: &str) -> Result<Self> {
+        let repo = Repo::with_revision(
+            dataset_id.to_string(),
+            RepoType::Dataset,
+            "refs/convert/parquet".to_string(),
+        );
+        let repo = Arc::new(api.repo(repo));
+        let info = repo
+            .info()
+            .map_err(|e| Error::Msg(format!("Api error: {e}")))?;
+        let mut filenames = info
+            .siblings
+            .into_iter()
+            .map(|s| s.rfilename)
+            .filter(|f| f.ends_with(".parquet") && f.split('/').any(|c| c == split))
+            .collect::<Vec<_>>();
+        if filenames.is_empty() {
+            candle::bail!("no parquet files found for split {split} in {dataset_id}")
+        }
+        filenames.sort();
+        let sources = filenames
+            .into_iter()
+            .map(|filename| Source::Hub {
+                repo: repo.clone(),
+                filename,
+            })
+            .collect();
+        Ok(Self::new(sources))
+    }
+
+    /// Only read the given top-level columns.
+    pub fn columns<S: AsRef<str>>(mut self, columns: &[S]) -> Self {
+        self.columns = Some(columns.iter().map(|c| c.as_ref().to_string()).collect());
+        self
+    }
+
+    /// Shuffles the rows using a buffer of `buffer_size` rows, each returned row being drawn at
+    /// random from this buffer. The file order is also shuffled. A buffer size of 0 disables
+    /// shuffling.
+    pub fn shuffle(mut self, buffer_size: usize, seed: u64) -> Self {
+        self.shuffle_buffer_size = buffer_size;
+        self.seed = seed;
+        self
+    }
+
+    /// Only return the rows of the row groups assigned to worker `shard_index` out of
+    /// `num_shards` workers. Row groups are assigned in a round-robin way across all the files.
+    /// This returns an error if `shard_index` is not lower than `num_shards`.
+    pub fn shard(mut self, shard_index: usize, num_shards: usize) -> Result<Self> {
+        if shard_index >= num_shards {
+            candle::bail!("invalid shard index {shard_index} for {num_shards} shards")
+        }
+        self.shard_index = shard_index;
+        self.num_shards = num_shards;
+        Ok(self)
+    }
+
+    pub fn num_files(&self) -> usize {
+        self.sources.len()
+    }
+
+    pub fn iter(&self) -> StreamingIter {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(self.seed);
+        let mut sources = self.sources.clone();
+        if self.shuffle_buffer_size > 0 {
+            use rand::seq::SliceRandom;
+            sources.shuffle(&mut rng);
+        }
+        sources.reverse();
+        StreamingIter {
+            sources,
+            columns: self.columns.clone(),
+            shard_index: self.shard_index,
+            num_shards: self.num_shards,
+            row_group_offset: 0,
+            rows: None,
+            buffer: Vec::with_capacity(self.shuffle_buffer_size),
+            buffer_size: self.shuffle_buffer_size,
+            rng,
+        }
+    }
+}
+
+impl IntoIterator for &StreamingDataset {
+    type Item = Result<Row>;
+    type IntoIter = StreamingIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+pub struct StreamingIter {
+    // Remaining sources, in reverse order.
+    sources: Vec<Source>,
+    columns: Option<Vec<String>>,
+    shard_index: usize,
+    num_shards: usize,
+    row_group_offset: usize,
+    rows: Option<RowIter<'static>>,
+    buffer: Vec<Row>,
+    buffer_size: usize,
+    rng: rand::rngs::StdRng,
+}
+
+fn projection(schema: &Type, columns: &[String]) -> Result<Type> {
+    let fields = columns
+        .iter()
+        .map(
+            |c| match schema.get_fields().iter().find(|f| f.name() == c) {
+                Some(f) => Ok(f.clone()),
+                None => candle::bail!("no column {c} in parquet schema"),
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
+    Type::group_type_builder(schema.name())
+        .with_fields(fields)
+        .build()
+        .map_err(Error::wrap)
+}
+
+impl StreamingIter {
+    fn open(&mut self, source: &Source) -> Result<RowIter<'static>> {
+        let path = source.path()?;
+        let num_row_groups = SerializedFileReader::new(std::fs::File::open(&path)?)
+            .map_err(Error::wrap)?
+            .metadata()
+            .num_row_groups();
+        let (offset, shard_index, num_shards) =
+            (self.row_group_offset, self.shard_index, self.num_shards);
+        self.row_group_offset += num_row_groups;
+        let options = ReadOptionsBuilder::new()
+            .with_predicate(Box::new(move |_, i| {
+                (offset + i) % num_shards == shard_index
+            }))
+            .build();
+        let reader = SerializedFileReader::new_with_options(std::fs::File::open(&path)?, options)
+            .map_err(Error::wrap)?;
+        let proj = match &self.columns {
+            None => None,
+            Some(columns) => Some(projection(
+                reader.metadata().file_metadata().schema(),
+                columns,
+            )?),
+        };
+        RowIter::from_file_into(Box::new(reader))
+            .project(proj)
+            .map_err(Error::wrap)
+    }
+
+    fn next_row(&mut self) -> Option<Result<Row>> {
+        loop {
+            if let Some(rows) = self.rows.as_mut() {
+                match rows.next() {
+                    Some(row) => return Some(row.map_err(Error::wrap)),
+                    None => self.rows = None,
+                }
+            }
+            let source = self.sources.pop()?;
+            match self.open(&source) {
+                Ok(rows) => self.rows = Some(rows),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+impl Iterator for StreamingIter {
+    type Item = Result<Row>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.buffer_size == 0 {
+            return self.next_row();
+        }
+        while self.buffer.len() < self.buffer_size {
+            match self.next_row() {
+                Some(Ok(row)) => self.buffer.push(row),
+                Some(Err(err)) => return Some(Err(err)),
+                None => break,
+            }
+        }
+        if self.buffer.is_empty() {
+            return None;
+        }
+        let index = self.rng.gen_range(0..self.buffer.len());
+        Some(Ok(self.buffer.swap_remove(index)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parquet::data_type::Int64Type;
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::record::RowAccessor;
+
+    // Writes a file with two columns, `id` and `twice`, and one row group per chunk.
+    fn write_file(path: &std::path::Path, chunks: &[std::ops::Range<i64>]) -> Result<()> {
+        let schema = parquet::schema::parser::parse_message_type(
+            "message schema { REQUIRED INT64 id; REQUIRED INT64 twice; }",
+        )
+        .map_err(Error::wrap)?;
+        let props = Arc::new(WriterProperties::builder().build());
+        let file = std::fs::File::create(path)?;
+        let mut writer =
+            SerializedFileWriter::new(file, Arc::new(schema), props).map_err(Error::wrap)?;
+        for chunk in chunks {
+            let mut row_group = writer.next_row_group().map_err(Error::wrap)?;
+            for mul in [1, 2] {
+                let values = chunk.clone().map(|v| v * mul).collect::<Vec<_>>();
+                let mut col = row_group.next_column().map_err(Error::wrap)?.unwrap();
+                col.typed::<Int64Type>()
+                    .write_batch(&values, None, None)
+                    .map_err(Error::wrap)?;
+                col.close().map_err(Error::wrap)?;
+            }
+            row_group.close().map_err(Error::wrap)?;
+        }
+        writer.close().map_err(Error::wrap)?;
+        Ok(())
+    }
+
+    fn ids(dataset: &StreamingDataset) -> Result<Vec<i64>> {
+        dataset
+            .iter()
+            .map(|row| row.and_then(|row| row.get_long(0).map_err(Error::wrap)))
+            .collect()
+    }
+
+    #[test]
+    fn streaming_dataset() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("candle-streaming-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let (f1, f2) = (dir.join("0000.parquet"), dir.join("0001.parquet"));
+        write_file(&f1, &[0..3, 3..6])?;
+        write_file(&f2, &[6..8, 8..9])?;
+        let dataset = StreamingDataset::from_files([&f1, &f2]);
+        assert_eq!(ids(&dataset)?, (0..9).collect::<Vec<_>>());
+
+        let dataset = dataset.columns(&["twice"]);
+        let row = dataset.iter().nth(4).unwrap()?;
+        assert_eq!(row.len(), 1);
+        assert_eq!(row.get_long(0).map_err(Error::wrap)?, 8);
+
+        let dataset = StreamingDataset::from_files([&f1, &f2]);
+        let shard0 = ids(&dataset.clone().shard(0, 2)?)?;
+        let shard1 = ids(&dataset.clone().shard(1, 2)?)?;
+        assert_eq!(shard0, [0, 1, 2, 6, 7]);
+        assert_eq!(shard1, [3, 4, 5, 8]);
+        assert!(dataset.clone().shard(0, 0).is_err());
+        assert!(dataset.clone().shard(2, 2).is_err());
+
+        let shuffled = dataset.clone().shuffle(4, 42);
+        let mut s1 = ids(&shuffled)?;
+        assert_eq!(s1, ids(&shuffled)?);
+        assert_ne!(s1, (0..9).collect::<Vec<_>>());
+        s1.sort();
+        assert_eq!(s1, (0..9).collect::<Vec<_>>());
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,303 @@
+//! Data augmentation for images, implemented as tensor operations.
+//!
+//! All the transforms operate on images with shape `(channels, height, width)` or on batches
+//! with shape `(batch, channels, height, width)` and run on the device of their input, so that
+//! the augmentation can happen on the gpu. The random parameters are drawn on the host from the
+//! rng passed as argument. Apart from [`to_f32`], the transforms expect float images with values
+//! in `[0, 1]`.
+use candle::{DType, Device, Result, Tensor, D};
+use rand::Rng;
+use rand_distr::Distribution;
+
+/// Converts `u8` images with values in `[0, 255]` to `f32` images with values in `[0, 1]`.
+pub fn to_f32(img: &Tensor) -> Result<Tensor> {
+    img.to_dtype(DType::F32)? / 255.
+}
+
+fn hw(img: &Tensor) -> Result<(usize, usize)> {
+    let dims = img.dims();
+    match dims.len() {
+        3 | 4 => Ok((dims[dims.len() - 2], dims[dims.len() - 1])),
+        _ => candle::bail!("expected an image with 3 or 4 dims, got {:?}", img.shape()),
+    }
+}
+
+/// Resizes the image using nearest neighbor interpolation.
+pub fn resize(img: &Tensor, height: usize, width: usize) -> Result<Tensor> {
+    match img.rank() {
+        3 => img
+            .unsqueeze(0)?
+            .upsample_nearest2d(height, width)?
+            .squeeze(0),
+        _ => img.upsample_nearest2d(height, width),
+    }
+}
+
+/// Crops the region starting at `(top, left)` with the given size.
+pub fn crop(img: &Tensor, top: usize, left: usize, height: usize, width: usize) -> Result<Tensor> {
+    img.narrow(D::Minus2, top, height)?
+        .narrow(D::Minus1, left, width)
+}
+
+/// Crops the center of the image.
+pub fn center_crop(img: &Tensor, height: usize, width: usize) -> Result<Tensor> {
+    let (h, w) = hw(img)?;
+    if height > h || width > w {
+        candle::bail!("cannot crop ({height}, {width}) from an image of size ({h}, {w})")
+    }
+    crop(img, (h - height) / 2, (w - width) / 2, height, width)
+}
+
+/// Crops a random region of the image and resizes it to `(height, width)`. The area of the
+/// region is a fraction of the original area sampled uniformly in `scale`, and its aspect ratio
+/// is sampled log-uniformly in `ratio`, ImageNet training typically uses `(0.08, 1.0)` and
+/// `(3/4, 4/3)`.
+pub fn random_resized_crop<R: Rng + ?Sized>(
+    img: &Tensor,
+    height: usize,
+    width: usize,
+    scale: (f64, f64),
+    ratio: (f64, f64),
+    rng: &mut R,
+) -> Result<Tensor> {
+    let (h, w) = hw(img)?;
+    let area = (h * w) as f64;
+    let (log_r0, log_r1) = (ratio.0.ln(), ratio.1.ln());
+    for _ in 0..10 {
+        let target_area = area * rng.gen_range(scale.0..=scale.1);
+        let aspect_ratio = rng.gen_range(log_r0..=log_r1).exp();
+        let crop_w = (target_area * aspect_ratio).sqrt().round() as usize;
+        let crop_h = (target_area / aspect_ratio).sqrt().round() as usize;
+        if 0 < crop_w && crop_w <= w && 0 < crop_h && crop_h <= h {
+            let top = rng.gen_range(0..=h - crop_h);
+            let left = rng.gen_range(0..=w - crop_w);
+            let img = crop(img, top, left, crop_h, crop_w)?;
+            return resize(&img, height, width);
+        }
+    }
+    // Fallback to a center crop with the aspect ratio clamped to the allowed range.
+    let in_ratio = w as f64 / h as f64;
+    let (crop_h, crop_w) = if in_ratio < ratio.0 {
+        ((w as f64 / ratio.0).round() as usize, w)
+    } else if in_ratio > ratio.1 {
+        (h, (h as f64 * ratio.1).round() as usize)
+    } else {
+        (h, w)
+    };
+    let img = center_crop(img, crop_h, crop_w)?;
+    resize(&img, height, width)
+}
+
+fn flip(img: &Tensor, dim: D) -> Result<Tensor> {
+    let size = img.dim(dim)?;
+    let indexes = (0..size as u32).rev().collect::<Vec<_>>();
+    let indexes = Tensor::from_vec(indexes, size, img.device())?;
+    img.index_select(&indexes, dim)
+}
+
+pub fn horizontal_flip(img: &Tensor) -> Result<Tensor> {
+    flip(img, D::Minus1)
+}
+
+pub fn vertical_flip(img: &Tensor) -> Result<Tensor> {
+    flip(img, D::Minus2)
+}
+
+/// Flips the image horizontally with probability `p`.
+pub fn random_horizontal_flip<R: Rng + ?Sized>(
+    img: &Tensor,
+    p: f64,
+    rng: &mut R,
+) -> Result<Tensor> {
+    if rng.gen_bool(p) {
+        horizontal_flip(img)
+    } else {
+        Ok(img.clone())
+    }
+}
+
+/// Normalizes each channel as `(img - mean) / std`.
+pub fn normalize(img: &Tensor, mean: &[f32], std: &[f32]) -> Result<Tensor> {
+    let shape = match img.rank() {
+        3 => (mean.len(), 1, 1).into(),
+        _ => candle::Shape::from((1, mean.len(), 1, 1)),
+    };
+    let mean = Tensor::new(mean, img.device())?.reshape(&shape)?;
+    let std = Tensor::new(std, img.device())?.reshape(&shape)?;
+    img.broadcast_sub(&mean)?.broadcast_div(&std)
+}
+
+/// The ImageNet mean and standard deviation, used with [`normalize`].
+pub const IMAGENET_MEAN: [f32; 3] = [0.485f32, 0.456, 0.406];
+pub const IMAGENET_STD: [f32; 3] = [0.229f32, 0.224, 0.225];
+
+fn grayscale(img: &Tensor) -> Result<Tensor> {
+    let channel_dim = img.rank() - 3;
+    if img.dim(channel_dim)? != 3 {
+        candle::bail!("expected an rgb image, got {:?}", img.shape())
+    }
+    let r = img.narrow(channel_dim, 0, 1)?;
+    let g = img.narrow(channel_dim, 1, 1)?;
+    let b = img.narrow(channel_dim, 2, 1)?;
+    ((r * 0.299)? + (g * 0.587)?)? + (b * 0.114)?
+}
+
+fn blend(img: &Tensor, other: &Tensor, factor: f64) -> Result<Tensor> {
+    ((img * factor)?.broadcast_add(&(other * (1. - factor))?)?).clamp(0f32, 1f32)
+}
+
+pub fn adjust_brightness(img: &Tensor, factor: f64) -> Result<Tensor> {
+    (img * factor)?.clamp(0f32, 1f32)
+}
+
+pub fn adjust_contrast(img: &Tensor, factor: f64) -> Result<Tensor> {
+    let rank = img.rank();
+    let mean = grayscale(img)?.mean_keepdim((rank - 3, rank - 2, rank - 1))?;
+    blend(img, &mean, factor)
+}
+
+pub fn adjust_saturation(img: &Tensor, factor: f64) -> Result<Tensor> {
+    blend(img, &grayscale(img)?, factor)
+}
+
+/// Randomly changes the brightness, contrast and saturation of rgb images, the factors are
+/// sampled uniformly in `[1 - v, 1 + v]` for each of the given values.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ColorJitter {
+    pub brightness: f64,
+    pub contrast: f64,
+    pub saturation: f64,
+}
+
+impl ColorJitter {
+    pub fn apply<R: Rng + ?Sized>(&self, img: &Tensor, rng: &mut R) -> Result<Tensor> {
+        let mut factor = |v: f64| {
+            if v > 0. {
+                Some(rng.gen_range((1. - v).max(0.)..=1. + v))
+            } else {
+                None
+            }
+        };
+        let (b, c, s) = (
+            factor(self.brightness),
+            factor(self.contrast),
+            factor(self.saturation),
+        );
+        let mut img = img.clone();
+        if let Some(b) = b {
+            img = adjust_brightness(&img, b)?
+        }
+        if let Some(c) = c {
+            img = adjust_contrast(&img, c)?
+        }
+        if let Some(s) = s {
+            img = adjust_saturation(&img, s)?
+        }
+        Ok(img)
+    }
+}
+
+fn sample_beta<R: Rng + ?Sized>(alpha: f64, rng: &mut R) -> Result<f64> {
+    let beta = rand_distr::Beta::new(alpha, alpha).map_err(candle::Error::wrap)?;
+    Ok(beta.sample(rng))
+}
+
+fn random_permutation<R: Rng + ?Sized>(n: usize, device: &Device, rng: &mut R) -> Result<Tensor> {
+    use rand::seq::SliceRandom;
+    let mut perm = (0..n as u32).collect::<Vec<_>>();
+    perm.shuffle(rng);
+    Tensor::from_vec(perm, n, device)
+}
+
+/// MixUp: mixes each sample of a batch with another random sample of the same batch. The
+/// `labels` are one-hot (or soft) labels with shape `(batch, num_classes)` and are mixed with
+/// the same weights. The mixing weight is sampled from `Beta(alpha, alpha)`.
+pub fn mixup<R: Rng + ?Sized>(
+    images: &Tensor,
+    labels: &Tensor,
+    alpha: f64,
+    rng: &mut R,
+) -> Result<(Tensor, Tensor)> {
+    let lambda = sample_beta(alpha, rng)?;
+    let perm = random_permutation(images.dim(0)?, images.device(), rng)?;
+    let images = ((images * lambda)? + (images.index_select(&perm, 0)? * (1. - lambda))?)?;
+    let labels = ((labels * lambda)? + (labels.index_select(&perm, 0)? * (1. - lambda))?)?;
+    Ok((images, labels))
+}
+
+/// CutMix: replaces a random box of each image of a batch with the same box from another random
+/// image of the batch. The labels are mixed proportionally to the area of the box, whose
+/// relative area is sampled from `Beta(alpha, alpha)`.
+pub fn cutmix<R: Rng + ?Sized>(
+    images: &Tensor,
+    labels: &Tensor,
+    alpha: f64,
+    rng: &mut R,
+) -> Result<(Tensor, Tensor)> {
+    let (b_sz, _c, h, w) = images.dims4()?;
+    let device = images.device();
+    let lambda = sample_beta(alpha, rng)?;
+    let cut = (1. - lambda).sqrt();
+    let (cut_h, cut_w) = ((h as f64 * cut) as usize, (w as f64 * cut) as usize);
+    let (cy, cx) = (rng.gen_range(0..h), rng.gen_range(0..w));
+    let (y0, y1) = (cy.saturating_sub(cut_h / 2), (cy + cut_h / 2).min(h));
+    let (x0, x1) = (cx.saturating_sub(cut_w / 2), (cx + cut_w / 2).min(w));
+    let ys = Tensor::arange(0u32, h as u32, device)?.reshape((h, 1))?;
+    let xs = Tensor::arange(0u32, w as u32, device)?.reshape((1, w))?;
+    let in_y = ys.ge(y0 as u32)?.mul(&ys.lt(y1 as u32)?)?;
+    let in_x = xs.ge(x0 as u32)?.mul(&xs.lt(x1 as u32)?)?;
+    let mask = in_y.broadcast_mul(&in_x)?.reshape((1, 1, h, w))?;
+    let mask = mask.broadcast_as(images.shape())?;
+    let perm = random_permutation(b_sz, device, rng)?;
+    let images = mask.where_cond(&images.index_select(&perm, 0)?, images)?;
+    // Adjust lambda to the actual area of the box after clipping.
+    let lambda = 1. - ((y1 - y0) * (x1 - x0)) as f64 / (h * w) as f64;
+    let labels = ((labels * lambda)? + (labels.index_select(&perm, 0)? * (1. - lambda))?)?;
+    Ok((images, labels))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn transforms() -> Result<()> {
+        let dev = Device::Cpu;
+        let img = Tensor::arange(0f32, 12., &dev)?.reshape((1, 3, 4))?;
+        assert_eq!(
+            horizontal_flip(&img)?.to_vec3::<f32>()?,
+            [[[3., 2., 1., 0.], [7., 6., 5., 4.], [11., 10., 9., 8.]]]
+        );
+        assert_eq!(
+            vertical_flip(&img)?.to_vec3::<f32>()?,
+            [[[8., 9., 10., 11.], [4., 5., 6., 7.], [0., 1., 2., 3.]]]
+        );
+        assert_eq!(center_crop(&img, 1, 2)?.to_vec3::<f32>()?, [[[5., 6.]]]);
+        let normalized = normalize(&img, &[1.], &[2.])?;
+        assert_eq!(normalized.to_vec3::<f32>()?[0][0], [-0.5, 0., 0.5, 1.]);
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let crop = random_resized_crop(&img, 2, 2, (0.08, 1.0), (0.75, 1.33), &mut rng)?;
+        assert_eq!(crop.dims(), [1, 2, 2]);
+
+        let images = Tensor::rand(0f32, 1., (4, 3, 8, 8), &dev)?;
+        let jittered = ColorJitter {
+            brightness: 0.4,
+            contrast: 0.4,
+            saturation: 0.4,
+        }
+        .apply(&images, &mut rng)?;
+        assert_eq!(jittered.dims(), [4, 3, 8, 8]);
+
+        let labels = Tensor::eye(4, DType::F32, &dev)?;
+        type Mix = fn(&Tensor, &Tensor, f64, &mut rand::rngs::StdRng) -> Result<(Tensor, Tensor)>;
+        for f in [mixup as Mix, cutmix as Mix] {
+            let (images, labels) = f(&images, &labels, 1.0, &mut rng)?;
+            assert_eq!(images.dims(), [4, 3, 8, 8]);
+            let sums = labels.sum(1)?.to_vec1::<f32>()?;
+            assert!(sums.iter().all(|s| (s - 1.).abs() < 1e-5), "{sums:?}");
+        }
+        Ok(())
+    }
+}
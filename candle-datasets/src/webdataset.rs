@@ -0,0 +1,349 @@
+//! Reader for the WebDataset format.
+//!
+//! A WebDataset is a set of tar shards, each shard containing samples stored as consecutive files
+//! sharing the same key, e.g. `000123.jpg` and `000123.json`. The key of a file is its path up to
+//! the first dot of its name, the rest being the extension used to identify the file within the
+//! sample. Shards are read sequentially so the dataset can be streamed from disk.
+//!
+//! See <https://github.com/webdataset/webdataset> for more details on the format.
+use candle::{Device, Result, Tensor};
+use rand::{seq::SliceRandom, SeedableRng};
+use std::collections::HashMap;
+use std::io::{BufReader, Read};
+use std::path::PathBuf;
+
+const BLOCK_SIZE: usize = 512;
+
+/// A single sample, mapping extensions such as `jpg` or `json` to the raw file contents.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub key: String,
+    pub shard: PathBuf,
+    pub files: HashMap<String, Vec<u8>>,
+}
+
+impl Sample {
+    pub fn get(&self, extension: &str) -> Result<&[u8]> {
+        match self.files.get(extension) {
+            Some(bytes) => Ok(bytes.as_slice()),
+            None => candle::bail!("no {extension} file in sample {}", self.key),
+        }
+    }
+
+    pub fn text(&self, extension: &str) -> Result<&str> {
+        std::str::from_utf8(self.get(extension)?).map_err(candle::Error::wrap)
+    }
+
+    /// Decodes an image file to a `u8` tensor with shape `(3, height, width)`.
+    pub fn rgb_image(&self, extension: &str, device: &Device) -> Result<Tensor> {
+        let img = image::load_from_memory(self.get(extension)?)
+            .map_err(candle::Error::wrap)?
+            .to_rgb8();
+        let (width, height) = (img.width() as usize, img.height() as usize);
+        Tensor::from_vec(img.into_raw(), (height, width, 3), device)?.permute((2, 0, 1))
+    }
+}
+
+fn parse_octal(bytes: &[u8]) -> Result<usize> {
+    let s = std::str::from_utf8(bytes).map_err(candle::Error::wrap)?;
+    let s = s.trim_matches(|c: char| c == '\0' || c.is_whitespace());
+    if s.is_empty() {
+        return Ok(0);
+    }
+    usize::from_str_radix(s, 8).map_err(|e| candle::Error::Msg(format!("invalid tar size: {e}")))
+}
+
+fn cstr(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// A minimal reader for the regular files of a ustar archive, supporting GNU long names.
+struct TarReader<R: Read> {
+    reader: R,
+}
+
+impl<R: Read> TarReader<R> {
+    fn read_data(&mut self, size: usize) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; size];
+        self.reader.read_exact(&mut data)?;
+        let padding = (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE;
+        std::io::copy(
+            &mut (&mut self.reader).take(padding as u64),
+            &mut std::io::sink(),
+        )?;
+        Ok(data)
+    }
+
+    fn next_file(&mut self) -> Result<Option<(String, Vec<u8>)>> {
+        let mut long_name = None;
+        loop {
+            let mut header = [0u8; BLOCK_SIZE];
+            match self.reader.read_exact(&mut header) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+                Err(e) => return Err(e.into()),
+            }
+            if header.iter().all(|&b| b == 0) {
+                return Ok(None);
+            }
+            let size = parse_octal(&header[124..136])?;
+            let data = self.read_data(size)?;
+            match header[156] {
+                b'0' | b'\0' => {
+                    let name = match long_name.take() {
+                        Some(name) => name,
+                        None => {
+                            let name = cstr(&header[0..100]);
+                            let prefix = if &header[257..262] == b"ustar" {
+                                cstr(&header[345..500])
+                            } else {
+                                String::new()
+                            };
+                            if prefix.is_empty() {
+                                name
+                            } else {
+                                format!("{prefix}/{name}")
+                            }
+                        }
+                    };
+                    return Ok(Some((name, data)));
+                }
+                b'L' => long_name = Some(cstr(&data)),
+                // Directories, links and extended headers are skipped.
+                _ => {}
+            }
+        }
+    }
+}
+
+fn split_key(name: &str) -> (&str, &str) {
+    let basename_start = name.rfind('/').map_or(0, |i| i + 1);
+    match name[basename_start..].find('.') {
+        Some(i) => (&name[..basename_start + i], &name[basename_start + i + 1..]),
+        None => (name, ""),
+    }
+}
+
+/// A WebDataset made of a list of tar shards.
+#[derive(Debug, Clone)]
+pub struct WebDataset {
+    shards: Vec<PathBuf>,
+    shuffle_seed: Option<u64>,
+    worker_index: usize,
+    num_workers: usize,
+}
+
+impl WebDataset {
+    pub fn new<P: Into<PathBuf>>(shards: impl IntoIterator<Item = P>) -> Self {
+        Self {
+            shards: shards.into_iter().map(|p| p.into()).collect(),
+            shuffle_seed: None,
+            worker_index: 0,
+            num_workers: 1,
+        }
+    }
+
+    /// Creates a dataset from all the `.tar` files in a directory, sorted by name.
+    pub fn from_dir<P: AsRef<std::path::Path>>(dir: P) -> Result<Self> {
+        let mut shards = vec![];
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|e| e == "tar") {
+                shards.push(path)
+            }
+        }
+        shards.sort();
+        Ok(Self::new(shards))
+    }
+
+    /// Shuffles the order of the shards, the order depends on the seed and on the epoch.
+    pub fn shuffle_shards(mut self, seed: u64) -> Self {
+        self.shuffle_seed = Some(seed);
+        self
+    }
+
+    /// Only read the shards assigned to a worker, shards are assigned in a round-robin way after
+    /// shuffling.
+    pub fn split_by_worker(mut self, worker_index: usize, num_workers: usize) -> Self {
+        self.worker_index = worker_index;
+        self.num_workers = num_workers;
+        self
+    }
+
+    pub fn shards(&self) -> &[PathBuf] {
+        &self.shards
+    }
+
+    pub fn iter(&self, epoch: u64) -> WebDatasetIter {
+        let mut shards = self.shards.clone();
+        if let Some(seed) = self.shuffle_seed {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(seed.wrapping_add(epoch));
+            shards.shuffle(&mut rng);
+        }
+        let mut shards = shards
+            .into_iter()
+            .skip(self.worker_index)
+            .step_by(self.num_workers.max(1))
+            .collect::<Vec<_>>();
+        shards.reverse();
+        WebDatasetIter {
+            shards,
+            current: None,
+            pending: None,
+        }
+    }
+
+    /// Iterates over the samples for the given epoch, applying `decode` to each of them.
+    pub fn decoded<T, F>(&self, epoch: u64, mut decode: F) -> impl Iterator<Item = Result<T>>
+    where
+        F: FnMut(Sample) -> Result<T>,
+    {
+        self.iter(epoch).map(move |s| s.and_then(&mut decode))
+    }
+}
+
+pub struct WebDatasetIter {
+    // Remaining shards, in reverse order.
+    shards: Vec<PathBuf>,
+    current: Option<(PathBuf, TarReader<BufReader<std::fs::File>>)>,
+    // A file that has been read but belongs to the next sample.
+    pending: Option<(String, String, Vec<u8>)>,
+}
+
+impl WebDatasetIter {
+    fn next_file(&mut self) -> Result<Option<(String, String, Vec<u8>)>> {
+        loop {
+            if let Some((_, reader)) = self.current.as_mut() {
+                if let Some((name, data)) = reader.next_file()? {
+                    let (key, ext) = split_key(&name);
+                    return Ok(Some((key.to_string(), ext.to_string(), data)));
+                }
+                self.current = None;
+                return Ok(None);
+            }
+            let shard = match self.shards.pop() {
+                None => return Ok(None),
+                Some(shard) => shard,
+            };
+            let reader = BufReader::new(std::fs::File::open(&shard)?);
+            self.current = Some((shard, TarReader { reader }))
+        }
+    }
+
+    fn next_sample(&mut self) -> Result<Option<Sample>> {
+        loop {
+            let first = match self.pending.take() {
+                Some(file) => Some(file),
+                None => self.next_file()?,
+            };
+            let (key, ext, data) = match first {
+                Some(file) => file,
+                // End of the current shard, move to the next one if any.
+                None if self.shards.is_empty() => return Ok(None),
+                None => continue,
+            };
+            let shard = self
+                .current
+                .as_ref()
+                .map(|(p, _)| p.clone())
+                .unwrap_or_default();
+            let mut files = HashMap::new();
+            files.insert(ext, data);
+            // Samples never span multiple shards.
+            while self.current.is_some() {
+                match self.next_file()? {
+                    Some((k, ext, data)) if k == key => {
+                        files.insert(ext, data);
+                    }
+                    Some(file) => {
+                        self.pending = Some(file);
+                        break;
+                    }
+                    None => break,
+                }
+            }
+            return Ok(Some(Sample { key, shard, files }));
+        }
+    }
+}
+
+impl Iterator for WebDatasetIter {
+    type Item = Result<Sample>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_sample().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(name: &str, size: usize, typeflag: u8) -> Vec<u8> {
+        let mut header = vec![0u8; BLOCK_SIZE];
+        header[..name.len()].copy_from_slice(name.as_bytes());
+        let size = format!("{size:011o}");
+        header[124..135].copy_from_slice(size.as_bytes());
+        header[156] = typeflag;
+        header[257..262].copy_from_slice(b"ustar");
+        header
+    }
+
+    fn write_tar(path: &std::path::Path, files: &[(&str, &str)]) -> Result<()> {
+        let mut bytes = vec![];
+        for (name, content) in files {
+            if name.len() > 20 {
+                bytes.extend(header("././@LongLink", name.len(), b'L'));
+                bytes.extend(name.as_bytes());
+                bytes.resize(bytes.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+            }
+            bytes.extend(header(&name[..name.len().min(20)], content.len(), b'0'));
+            bytes.extend(content.as_bytes());
+            bytes.resize(bytes.len().div_ceil(BLOCK_SIZE) * BLOCK_SIZE, 0);
+        }
+        bytes.extend([0u8; 2 * BLOCK_SIZE]);
+        std::fs::write(path, bytes)?;
+        Ok(())
+    }
+
+    #[test]
+    fn webdataset() -> Result<()> {
+        assert_eq!(split_key("a/b.c/000.seg.json"), ("a/b.c/000", "seg.json"));
+        let dir = std::env::temp_dir().join(format!("candle-wds-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        write_tar(
+            &dir.join("shard-0.tar"),
+            &[
+                ("s/000.txt", "hello"),
+                ("s/000.cls", "1"),
+                ("s/001.txt", "world"),
+            ],
+        )?;
+        write_tar(
+            &dir.join("shard-1.tar"),
+            &[
+                ("some/long/directory/name/002.txt", "foo"),
+                ("s/003.cls", "3"),
+            ],
+        )?;
+        let dataset = WebDataset::from_dir(&dir)?;
+        let samples = dataset.iter(0).collect::<Result<Vec<_>>>()?;
+        let keys = samples.iter().map(|s| s.key.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            keys,
+            ["s/000", "s/001", "some/long/directory/name/002", "s/003"]
+        );
+        assert_eq!(samples[0].text("txt")?, "hello");
+        assert_eq!(samples[0].text("cls")?, "1");
+        assert!(samples[1].get("cls").is_err());
+
+        let worker1 = dataset.clone().split_by_worker(1, 2);
+        let keys = worker1
+            .decoded(0, |s| Ok(s.key))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(keys, ["some/long/directory/name/002", "s/003"]);
+        std::fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,164 @@
+use anyhow::Result;
+use candle::quantized::{imatrix_file, GgmlDType};
+use candle::Device;
+use candle_transformers::models::llama::LlamaConfig;
+use candle_transformers::quantize::{convert_llama, Quantizer};
+use clap::{Parser, ValueEnum};
+use hf_hub::{api::sync::Api, Repo, RepoType};
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum Quantization {
+    #[value(name = "q4_0")]
+    Q4_0,
+    #[value(name = "q5_0")]
+    Q5_0,
+    #[value(name = "q8_0")]
+    Q8_0,
+    Q2k,
+    Q3k,
+    Q4k,
+    Q5k,
+    Q6k,
+    #[value(name = "iq4_nl")]
+    Iq4Nl,
+    #[value(name = "iq2_xs")]
+    Iq2Xs,
+    #[value(name = "iq3_s")]
+    Iq3S,
+    F16,
+    F32,
+}
+
+impl Quantization {
+    fn dtype(&self) -> GgmlDType {
+        match self {
+            Self::Q4_0 => GgmlDType::Q4_0,
+            Self::Q5_0 => GgmlDType::Q5_0,
+            Self::Q8_0 => GgmlDType::Q8_0,
+            Self::Q2k => GgmlDType::Q2K,
+            Self::Q3k => GgmlDType::Q3K,
+            Self::Q4k => GgmlDType::Q4K,
+            Self::Q5k => GgmlDType::Q5K,
+            Self::Q6k => GgmlDType::Q6K,
+            Self::Iq4Nl => GgmlDType::IQ4NL,
+            Self::Iq2Xs => GgmlDType::IQ2XS,
+            Self::Iq3S => GgmlDType::IQ3S,
+            Self::F16 => GgmlDType::F16,
+            Self::F32 => GgmlDType::F32,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The model to quantize from the hub, e.g. `meta-llama/Llama-3.2-1B`.
+    #[arg(long)]
+    model_id: Option<String>,
+
+    #[arg(long, default_value = "main")]
+    revision: String,
+
+    /// Local safetensors files to quantize instead of downloading them from the hub, comma
+    /// separated.
+    #[arg(long)]
+    weight_files: Option<String>,
+
+    /// The config.json file matching the local weight files.
+    #[arg(long)]
+    config: Option<String>,
+
+    /// The generated gguf file.
+    #[arg(long)]
+    out_file: std::path::PathBuf,
+
+    /// The quantization applied to the weights.
+    #[arg(long, value_enum, default_value = "q4k")]
+    quantization: Quantization,
+
+    /// The quantization applied to the output projection.
+    #[arg(long, value_enum, default_value = "q6k")]
+    output_quantization: Quantization,
+
+    /// An importance matrix, as produced by the llama.cpp `imatrix` tool, to reduce the
+    /// quantization error on the channels with the largest activations.
+    #[arg(long)]
+    imatrix: Option<std::path::PathBuf>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let (config, weight_files) = match (&args.weight_files, &args.config) {
+        (Some(weight_files), Some(config)) => {
+            let weight_files = weight_files
+                .split(',')
+                .map(std::path::PathBuf::from)
+                .collect::<Vec<_>>();
+            (std::path::PathBuf::from(config), weight_files)
+        }
+        (None, None) => {
+            let model_id = match &args.model_id {
+                Some(model_id) => model_id.clone(),
+                None => anyhow::bail!("either --model-id or --weight-files must be set"),
+            };
+            let api = Api::new()?;
+            let api = api.repo(Repo::with_revision(
+                model_id,
+                RepoType::Model,
+                args.revision.clone(),
+            ));
+            let weight_files = match api.get("model.safetensors") {
+                Ok(file) => vec![file],
+                Err(_) => {
+                    candle_examples::hub_load_safetensors(&api, "model.safetensors.index.json")?
+                }
+            };
+            (api.get("config.json")?, weight_files)
+        }
+        _ => anyhow::bail!("--weight-files and --config have to be used together"),
+    };
+    let config: LlamaConfig = serde_json::from_slice(&std::fs::read(config)?)?;
+
+    let mut quantizer = Quantizer::new(args.quantization.dtype())
+        .with_output_dtype(args.output_quantization.dtype());
+    if let Some(imatrix) = &args.imatrix {
+        let imatrix = imatrix_file::load(imatrix)?;
+        println!("loaded importance matrix for {} tensors", imatrix.len());
+        quantizer = quantizer.with_imatrix(imatrix);
+    }
+
+    // Open the out file early so as to fail directly on missing directories etc.
+    let mut out_file = std::fs::File::create(&args.out_file)?;
+    let start = std::time::Instant::now();
+    let tensors = unsafe { candle::safetensors::MmapedSafetensors::multi(&weight_files)? };
+    let names = tensors
+        .tensors()
+        .into_iter()
+        .map(|(name, _)| name)
+        .collect::<Vec<_>>();
+    println!("quantizing {} tensors", names.len());
+    let converted = convert_llama(
+        &config,
+        &names,
+        |name| tensors.load(name, &Device::Cpu),
+        &quantizer,
+    )?;
+    let mut by_dtype = std::collections::BTreeMap::new();
+    for (_, tensor) in converted.tensors.iter() {
+        let entry = by_dtype
+            .entry(format!("{:?}", tensor.dtype()))
+            .or_insert((0, 0));
+        entry.0 += 1;
+        entry.1 += tensor.storage_size_in_bytes();
+    }
+    for (dtype, (count, size)) in by_dtype.iter() {
+        println!("  {dtype}: {count} tensors, {:.2}MB", *size as f64 / 1e6);
+    }
+    converted.write(&mut out_file)?;
+    println!(
+        "wrote {:?} in {:.2}s",
+        args.out_file,
+        start.elapsed().as_secs_f32()
+    );
+    Ok(())
+}
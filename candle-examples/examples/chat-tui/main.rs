@@ -0,0 +1,722 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Receiver, Sender};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+use clap::Parser;
+use ratatui::crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style, Stylize};
+use ratatui::text::{Line, Span};
+use ratatui::widgets::{Block, Borders, Gauge, Paragraph};
+use ratatui::{DefaultTerminal, Frame};
+use tokenizers::Tokenizer;
+
+use candle::quantized::gguf_file;
+use candle::{Device, Tensor};
+use candle_examples::chat::{ChatSession, Role};
+use candle_examples::registry::{ModelEntry, Registry};
+use candle_examples::token_output_stream::TokenOutputStream;
+use candle_transformers::generation::events::{generate, GenerationConfig, GenerationEvents};
+use candle_transformers::generation::{GenerationReport, LogitsProcessor, Sampling};
+use candle_transformers::models::quantized_auto::{ModelInfo, ModelWeights};
+use candle_transformers::models::quantized_llama::MAX_SEQ_LEN;
+
+const HELP: &str = "enter: send, esc: stop, ctrl-r: regenerate, pgup/pgdn: scroll, ctrl-c: quit, \
+/model NAME, /temp T, /reset, /save PATH";
+
+#[derive(Parser, Debug, Clone)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The model to start with, as listed in the registry.
+    #[arg(long, default_value = "7b-mistral-instruct-v0.2")]
+    which: String,
+
+    /// A local gguf file for the initial model, rather than downloading it from the registry.
+    #[arg(long)]
+    model: Option<String>,
+
+    /// A local tokenizer file for the initial model.
+    #[arg(long)]
+    tokenizer: Option<String>,
+
+    /// A json manifest with additional models, see `registry/quantized.json` for the format.
+    #[arg(long)]
+    registry: Option<String>,
+
+    /// The temperature used to generate samples, use 0 for greedy sampling. This can be changed
+    /// at runtime with `/temp`.
+    #[arg(long, default_value_t = 0.8)]
+    temperature: f64,
+
+    /// Nucleus sampling probability cutoff.
+    #[arg(long)]
+    top_p: Option<f64>,
+
+    /// The seed to use when generating random samples, it is incremented on each regeneration.
+    #[arg(long, default_value_t = 299792458)]
+    seed: u64,
+
+    /// The maximum number of tokens of an answer.
+    #[arg(short = 'n', long, default_value_t = 1000)]
+    sample_len: usize,
+
+    /// Penalty to be applied for repeating tokens, 1. means no penalty.
+    #[arg(long, default_value_t = 1.1)]
+    repeat_penalty: f32,
+
+    /// The context size to consider for the repeat penalty.
+    #[arg(long, default_value_t = 64)]
+    repeat_last_n: usize,
+
+    /// The prompt is processed in chunks of this many tokens, the progress bar being updated
+    /// after each chunk.
+    #[arg(long, default_value_t = 128)]
+    prompt_chunk_size: usize,
+
+    /// The system prompt, formatted with the model chat template.
+    #[arg(long)]
+    system_prompt: Option<String>,
+
+    /// A json file used to resume the conversation, it is updated after each answer.
+    #[arg(long)]
+    session: Option<String>,
+
+    /// Run on CPU rather than GPU even if a GPU is available.
+    #[arg(long)]
+    cpu: bool,
+}
+
+/// The requests sent from the UI to the model thread.
+enum Request {
+    Load {
+        entry: ModelEntry,
+        model: Option<String>,
+        tokenizer: Option<String>,
+    },
+    Generate {
+        prompt_tokens: Vec<u32>,
+        sampling: Sampling,
+        seed: u64,
+    },
+}
+
+/// The events sent from the model thread to the UI.
+enum ModelEvent {
+    Loaded {
+        entry: ModelEntry,
+        tokenizer: Tokenizer,
+        max_seq_len: usize,
+    },
+    PromptProgress(usize, usize),
+    /// A generated token, with the text it completes if any.
+    Token(String),
+    Done {
+        answer: String,
+        report: GenerationReport,
+    },
+    Error(String),
+}
+
+struct Model {
+    weights: ModelWeights,
+    tos: TokenOutputStream,
+    stop_tokens: Vec<u32>,
+}
+
+fn load(
+    entry: &ModelEntry,
+    model: Option<&str>,
+    tokenizer: Option<&str>,
+    device: &Device,
+) -> Result<(Model, usize)> {
+    let downloader = candle_examples::hub::Downloader::new();
+    let model_path = match model {
+        Some(model) => PathBuf::from(model),
+        None => entry.weights(&downloader)?,
+    };
+    let tokenizer_path = match tokenizer {
+        Some(tokenizer) => PathBuf::from(tokenizer),
+        None => entry.tokenizer(&downloader)?,
+    };
+    let tokenizer = Tokenizer::from_file(tokenizer_path).map_err(anyhow::Error::msg)?;
+    if model_path.extension().and_then(|v| v.to_str()) != Some("gguf") {
+        anyhow::bail!(
+            "only gguf models are supported, got {}",
+            model_path.display()
+        )
+    }
+    let mut file = gguf_file::SplitReader::open(&model_path)?;
+    let content =
+        gguf_file::Content::read_split(&mut file).map_err(|e| e.with_path(&model_path))?;
+    let info = ModelInfo::from_gguf(&content)?;
+    let max_seq_len = info
+        .context_length
+        .map_or(MAX_SEQ_LEN, |c| c.min(MAX_SEQ_LEN));
+    // Stop on the end of sequence and end of turn tokens from the gguf metadata, and on the
+    // registry one.
+    let mut stop_tokens = info.stop_token_ids();
+    match tokenizer.token_to_id(&entry.eos_token) {
+        Some(eos_token) if !stop_tokens.contains(&eos_token) => stop_tokens.push(eos_token),
+        Some(_) => {}
+        None if stop_tokens.is_empty() => {
+            anyhow::bail!("cannot find the eos token {}", entry.eos_token)
+        }
+        None => {}
+    }
+    let weights = ModelWeights::from_gguf(content, &mut file, device)?;
+    let model = Model {
+        weights,
+        tos: TokenOutputStream::new(tokenizer),
+        stop_tokens,
+    };
+    Ok((model, max_seq_len))
+}
+
+/// Forwards the generation progress and the decoded text to the UI.
+struct UiEvents<'a> {
+    events: &'a Sender<ModelEvent>,
+    tos: &'a mut TokenOutputStream,
+    stop: &'a AtomicBool,
+    error: Option<candle::Error>,
+    report: Option<GenerationReport>,
+}
+
+impl GenerationEvents for UiEvents<'_> {
+    fn on_prompt_progress(&mut self, processed: usize, total: usize) {
+        let _ = self
+            .events
+            .send(ModelEvent::PromptProgress(processed, total));
+    }
+
+    fn on_token(&mut self, token: u32, _latency: Duration) -> bool {
+        match self.tos.next_token(token) {
+            Ok(text) => {
+                let _ = self
+                    .events
+                    .send(ModelEvent::Token(text.unwrap_or_default()));
+            }
+            Err(err) => {
+                self.error = Some(err);
+                return false;
+            }
+        }
+        !self.stop.load(Ordering::Relaxed)
+    }
+
+    fn on_done(&mut self, report: &GenerationReport) {
+        self.report = Some(report.clone())
+    }
+}
+
+/// Runs the model requests until the UI goes away.
+fn model_thread(
+    args: &Args,
+    device: &Device,
+    requests: Receiver<Request>,
+    events: Sender<ModelEvent>,
+    stop: &AtomicBool,
+) {
+    let mut model = None;
+    for request in requests {
+        let res = match request {
+            Request::Load {
+                entry,
+                model: path,
+                tokenizer,
+            } => {
+                // Drop the current model first so that both models are never in memory.
+                model = None;
+                load(&entry, path.as_deref(), tokenizer.as_deref(), device).map(
+                    |(loaded, max_seq_len)| {
+                        let tokenizer = loaded.tos.tokenizer().clone();
+                        model = Some(loaded);
+                        ModelEvent::Loaded {
+                            entry,
+                            tokenizer,
+                            max_seq_len,
+                        }
+                    },
+                )
+            }
+            Request::Generate {
+                prompt_tokens,
+                sampling,
+                seed,
+            } => match model.as_mut() {
+                None => Err(anyhow::Error::msg("no model is loaded")),
+                Some(model) => run_generation(
+                    args,
+                    device,
+                    model,
+                    &prompt_tokens,
+                    sampling,
+                    seed,
+                    &events,
+                    stop,
+                ),
+            },
+        };
+        let event = res.unwrap_or_else(|err| ModelEvent::Error(err.to_string()));
+        if events.send(event).is_err() {
+            break;
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+fn run_generation(
+    args: &Args,
+    device: &Device,
+    model: &mut Model,
+    prompt_tokens: &[u32],
+    sampling: Sampling,
+    seed: u64,
+    events: &Sender<ModelEvent>,
+    stop: &AtomicBool,
+) -> Result<ModelEvent> {
+    stop.store(false, Ordering::Relaxed);
+    let Model {
+        weights,
+        tos,
+        stop_tokens,
+    } = model;
+    let mut history = vec![];
+    let mut forward = |xs: &Tensor, pos: usize| -> candle::Result<Tensor> {
+        let logits = weights.forward(xs, pos)?.squeeze(0)?;
+        history.extend(xs.flatten_all()?.to_vec1::<u32>()?);
+        if args.repeat_penalty == 1. {
+            return Ok(logits);
+        }
+        let start_at = history.len().saturating_sub(args.repeat_last_n);
+        candle_transformers::utils::apply_repeat_penalty(
+            &logits,
+            args.repeat_penalty,
+            &history[start_at..],
+        )
+    };
+    let config = GenerationConfig::new(args.sample_len)
+        .with_stop_tokens(stop_tokens.clone())
+        .with_prompt_chunk_size(Some(args.prompt_chunk_size.max(1)));
+    let mut lp = LogitsProcessor::from_sampling(seed, sampling);
+    tos.clear();
+    let mut ui_events = UiEvents {
+        events,
+        tos,
+        stop,
+        error: None,
+        report: None,
+    };
+    let tokens = generate(
+        &mut forward,
+        prompt_tokens,
+        &mut lp,
+        device,
+        &config,
+        &mut ui_events,
+    )?;
+    if let Some(err) = ui_events.error {
+        return Err(err.into());
+    }
+    // The streamed text is replaced by the decoding of the whole answer.
+    let answer = ui_events
+        .tos
+        .tokenizer()
+        .decode(&tokens, true)
+        .map_err(anyhow::Error::msg)?;
+    Ok(ModelEvent::Done {
+        answer,
+        report: ui_events.report.unwrap_or_default(),
+    })
+}
+
+/// Splits `text` in lines of at most `width` characters.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = width.max(1);
+    let mut lines = vec![];
+    for line in text.split('\n') {
+        let chars = line.chars().collect::<Vec<_>>();
+        if chars.is_empty() {
+            lines.push(String::new())
+        }
+        for chunk in chars.chunks(width) {
+            lines.push(chunk.iter().collect())
+        }
+    }
+    lines
+}
+
+enum Status {
+    Loading(String),
+    Idle,
+    Prompt(usize, usize),
+    Generating { start: Instant, tokens: usize },
+}
+
+struct App {
+    args: Args,
+    registry: Registry,
+    session: ChatSession,
+    /// The answer being generated.
+    partial: Option<String>,
+    input: String,
+    status: Status,
+    /// The current model, with its tokenizer and context length.
+    model: Option<(ModelEntry, Tokenizer, usize)>,
+    temperature: f64,
+    seed: u64,
+    /// The number of lines scrolled up from the bottom of the conversation.
+    scroll: usize,
+    message: String,
+    last_report: Option<GenerationReport>,
+    requests: Sender<Request>,
+    stop: Arc<AtomicBool>,
+}
+
+impl App {
+    fn sampling(&self) -> Sampling {
+        let temperature = self.temperature;
+        if temperature <= 0. {
+            Sampling::ArgMax
+        } else {
+            match self.args.top_p {
+                None => Sampling::All { temperature },
+                Some(p) => Sampling::TopP { p, temperature },
+            }
+        }
+    }
+
+    fn is_busy(&self) -> bool {
+        !matches!(self.status, Status::Idle)
+    }
+
+    fn load_model(&mut self, name: &str, model: Option<String>, tokenizer: Option<String>) {
+        match self.registry.get(name) {
+            Ok(entry) => {
+                self.status = Status::Loading(name.to_string());
+                let entry = entry.clone();
+                let _ = self.requests.send(Request::Load {
+                    entry,
+                    model,
+                    tokenizer,
+                });
+            }
+            Err(err) => self.message = err.to_string(),
+        }
+    }
+
+    /// Generates an answer to the last user turn of the session.
+    fn generate(&mut self) -> Result<()> {
+        let (entry, tokenizer, max_seq_len) = match &self.model {
+            Some(model) => model,
+            None => anyhow::bail!("no model is loaded"),
+        };
+        let count_tokens = |text: &str| Ok(entry.encode(tokenizer, text)?.len());
+        let max_tokens = max_seq_len.saturating_sub(self.args.sample_len + 10);
+        let removed = self.session.trim_oldest(entry, max_tokens, count_tokens)?;
+        if removed > 0 {
+            self.message = format!("removed {removed} turns from the conversation history")
+        }
+        let prompt = self.session.encode(entry, tokenizer)?;
+        self.partial = Some(String::new());
+        self.scroll = 0;
+        self.status = Status::Prompt(0, prompt.len());
+        let request = Request::Generate {
+            prompt_tokens: prompt,
+            sampling: self.sampling(),
+            seed: self.seed,
+        };
+        let _ = self.requests.send(request);
+        Ok(())
+    }
+
+    /// Removes the last answer and generates a new one with a different seed.
+    fn regenerate(&mut self) -> Result<()> {
+        match self.session.turns.last() {
+            Some(turn) if turn.role == Role::Assistant => {
+                self.session.turns.pop();
+                self.seed = self.seed.wrapping_add(1);
+                self.generate()
+            }
+            _ => anyhow::bail!("there is no answer to regenerate"),
+        }
+    }
+
+    fn save_session(&self) -> Result<()> {
+        if let Some(path) = &self.args.session {
+            self.session.save(path)?
+        }
+        Ok(())
+    }
+
+    fn on_model_event(&mut self, event: ModelEvent) -> Result<()> {
+        match event {
+            ModelEvent::Loaded {
+                entry,
+                tokenizer,
+                max_seq_len,
+            } => {
+                self.message = format!("loaded {}", entry.name);
+                self.model = Some((entry, tokenizer, max_seq_len));
+                self.status = Status::Idle;
+            }
+            ModelEvent::PromptProgress(processed, total) => {
+                self.status = Status::Prompt(processed, total)
+            }
+            ModelEvent::Token(text) => {
+                if let Some(partial) = self.partial.as_mut() {
+                    partial.push_str(&text)
+                }
+                self.status = match self.status {
+                    Status::Generating { start, tokens } => Status::Generating {
+                        start,
+                        tokens: tokens + 1,
+                    },
+                    _ => Status::Generating {
+                        start: Instant::now(),
+                        tokens: 1,
+                    },
+                }
+            }
+            ModelEvent::Done { answer, report } => {
+                self.partial = None;
+                self.session.push(Role::Assistant, answer);
+                self.last_report = Some(report);
+                self.status = Status::Idle;
+                self.save_session()?
+            }
+            ModelEvent::Error(err) => {
+                self.partial = None;
+                self.status = Status::Idle;
+                self.message = err
+            }
+        }
+        Ok(())
+    }
+
+    fn on_command(&mut self, command: &str) -> Result<()> {
+        let (command, arg) = command.split_once(' ').unwrap_or((command, ""));
+        let arg = arg.trim();
+        match command {
+            "/model" => self.load_model(arg, None, None),
+            "/temp" => {
+                self.temperature = arg.parse()?;
+                self.message = format!("temperature set to {}", self.temperature)
+            }
+            "/reset" => {
+                self.session.clear();
+                self.message = "conversation reset".to_string()
+            }
+            "/save" => {
+                self.session.save(arg)?;
+                self.message = format!("conversation saved to {arg}")
+            }
+            _ => anyhow::bail!("unknown command {command}, {HELP}"),
+        }
+        Ok(())
+    }
+
+    /// Handles a key press, returns `false` when the application should exit.
+    fn on_key(&mut self, code: KeyCode, modifiers: KeyModifiers) -> Result<bool> {
+        let ctrl = modifiers.contains(KeyModifiers::CONTROL);
+        match code {
+            KeyCode::Char('c') if ctrl => return Ok(false),
+            KeyCode::Char('r') if ctrl => {
+                if !self.is_busy() {
+                    self.regenerate()?
+                }
+            }
+            KeyCode::Char(c) => self.input.push(c),
+            KeyCode::Backspace => {
+                self.input.pop();
+            }
+            KeyCode::Esc => self.stop.store(true, Ordering::Relaxed),
+            KeyCode::PageUp => self.scroll += 10,
+            KeyCode::PageDown => self.scroll = self.scroll.saturating_sub(10),
+            KeyCode::Up => self.scroll += 1,
+            KeyCode::Down => self.scroll = self.scroll.saturating_sub(1),
+            KeyCode::Enter if !self.is_busy() => {
+                let input = std::mem::take(&mut self.input);
+                let input = input.trim();
+                if input.starts_with('/') {
+                    self.on_command(input)?
+                } else if !input.is_empty() {
+                    self.session.push(Role::User, input.to_string());
+                    self.generate()?
+                }
+            }
+            _ => {}
+        }
+        Ok(true)
+    }
+
+    fn conversation(&self, width: usize) -> Vec<Line<'static>> {
+        let mut lines = vec![];
+        let turns = self
+            .session
+            .turns
+            .iter()
+            .map(|t| (t.role, t.content.as_str()))
+            .chain(self.partial.as_deref().map(|p| (Role::Assistant, p)));
+        for (role, content) in turns {
+            let (name, color) = match role {
+                Role::System => ("system", Color::DarkGray),
+                Role::User => ("you", Color::Cyan),
+                Role::Assistant => ("assistant", Color::Green),
+            };
+            lines.push(Line::from(Span::styled(
+                name,
+                Style::new().fg(color).bold(),
+            )));
+            for line in wrap(content.trim(), width) {
+                lines.push(Line::from(line))
+            }
+            lines.push(Line::default())
+        }
+        lines
+    }
+
+    fn status_line(&self) -> String {
+        let model = match &self.model {
+            Some((entry, _, _)) => entry.name.as_str(),
+            None => "no model",
+        };
+        let speed = match (&self.status, &self.last_report) {
+            (Status::Generating { start, tokens }, _) => {
+                let elapsed = start.elapsed().as_secs_f64();
+                if elapsed > 0. {
+                    format!("{:.1} token/s", *tokens as f64 / elapsed)
+                } else {
+                    String::new()
+                }
+            }
+            (_, Some(report)) => format!(
+                "{:.1} token/s, prompt {:.1} token/s",
+                report.generated_tokens_per_second(),
+                report.prompt_tokens_per_second()
+            ),
+            (_, None) => String::new(),
+        };
+        let state = match &self.status {
+            Status::Loading(name) => format!("loading {name}"),
+            Status::Idle => self.message.clone(),
+            Status::Prompt(..) => "processing the prompt".to_string(),
+            Status::Generating { .. } => "generating, esc to stop".to_string(),
+        };
+        format!("{model} | temp {:.2} | {speed} | {state}", self.temperature)
+    }
+
+    fn draw(&self, frame: &mut Frame) {
+        let [conversation, status, input] = Layout::vertical([
+            Constraint::Min(3),
+            Constraint::Length(1),
+            Constraint::Length(3),
+        ])
+        .areas(frame.area());
+
+        let block = Block::default().borders(Borders::ALL).title(" chat ");
+        let inner = block.inner(conversation);
+        let lines = self.conversation(inner.width as usize);
+        // Scrolling is counted from the bottom so that the conversation follows the new tokens.
+        let height = inner.height as usize;
+        let max_scroll = lines.len().saturating_sub(height);
+        let top = max_scroll - self.scroll.min(max_scroll);
+        let paragraph = Paragraph::new(lines).block(block).scroll((top as u16, 0));
+        frame.render_widget(paragraph, conversation);
+
+        match self.status {
+            Status::Prompt(processed, total) if total > 0 => {
+                let gauge = Gauge::default()
+                    .gauge_style(Style::new().fg(Color::Yellow))
+                    .ratio(processed as f64 / total as f64)
+                    .label(format!("prompt {processed}/{total}"));
+                frame.render_widget(gauge, status)
+            }
+            _ => frame.render_widget(Paragraph::new(self.status_line()).reversed(), status),
+        }
+
+        let block = Block::default()
+            .borders(Borders::ALL)
+            .title(format!(" {HELP} "));
+        frame.render_widget(Paragraph::new(self.input.as_str()).block(block), input);
+    }
+}
+
+fn run(terminal: &mut DefaultTerminal, app: &mut App, events: &Receiver<ModelEvent>) -> Result<()> {
+    loop {
+        while let Ok(event) = events.try_recv() {
+            if let Err(err) = app.on_model_event(event) {
+                app.message = err.to_string()
+            }
+        }
+        terminal.draw(|frame| app.draw(frame))?;
+        if !event::poll(Duration::from_millis(50))? {
+            continue;
+        }
+        if let Event::Key(key) = event::read()? {
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            match app.on_key(key.code, key.modifiers) {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(err) => app.message = err.to_string(),
+            }
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let mut registry = Registry::from_json(include_str!("../../registry/quantized.json"))?;
+    if let Some(path) = &args.registry {
+        registry.extend(Registry::load(path)?)
+    }
+    let session = match &args.session {
+        Some(path) if std::path::Path::new(path).exists() => ChatSession::load(path)?,
+        _ => ChatSession::new(args.system_prompt.clone()),
+    };
+    let device = candle_examples::device(args.cpu)?;
+
+    let (request_tx, request_rx) = std::sync::mpsc::channel();
+    let (event_tx, event_rx) = std::sync::mpsc::channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    let model_args = args.clone();
+    let model_stop = stop.clone();
+    std::thread::spawn(move || {
+        model_thread(&model_args, &device, request_rx, event_tx, &model_stop)
+    });
+
+    let mut app = App {
+        temperature: args.temperature,
+        seed: args.seed,
+        registry,
+        session,
+        partial: None,
+        input: String::new(),
+        status: Status::Idle,
+        model: None,
+        scroll: 0,
+        message: String::new(),
+        last_report: None,
+        requests: request_tx,
+        stop,
+        args,
+    };
+    let which = app.args.which.clone();
+    let (model, tokenizer) = (app.args.model.clone(), app.args.tokenizer.clone());
+    app.load_model(&which, model, tokenizer);
+
+    let mut terminal = ratatui::init();
+    let res = run(&mut terminal, &mut app, &event_rx);
+    ratatui::restore();
+    res
+}
@@ -0,0 +1,107 @@
+use anyhow::Result;
+use candle::quantized::gguf_file::{Content, Header, SplitReader};
+use clap::{Parser, Subcommand};
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Print the metadata and the tensors of a gguf file.
+    Dump {
+        file: std::path::PathBuf,
+
+        /// Do not list the tensors, only the summary per dtype.
+        #[arg(long)]
+        no_tensors: bool,
+
+        /// Only print the metadata entries whose key starts with this prefix.
+        #[arg(long)]
+        prefix: Option<String>,
+    },
+
+    /// Print the differences between the headers of two gguf files.
+    Diff {
+        lhs: std::path::PathBuf,
+        rhs: std::path::PathBuf,
+    },
+}
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Command,
+}
+
+fn format_size(size_in_bytes: usize) -> String {
+    if size_in_bytes < 1_000 {
+        format!("{}B", size_in_bytes)
+    } else if size_in_bytes < 1_000_000 {
+        format!("{:.2}KB", size_in_bytes as f64 / 1e3)
+    } else if size_in_bytes < 1_000_000_000 {
+        format!("{:.2}MB", size_in_bytes as f64 / 1e6)
+    } else {
+        format!("{:.2}GB", size_in_bytes as f64 / 1e9)
+    }
+}
+
+// Split files are read as a single model so that the complete tensor list is available.
+fn read_header(p: &std::path::Path) -> Result<Header> {
+    let mut reader = SplitReader::open(p)?;
+    let content = Content::read_split(&mut reader).map_err(|e| e.with_path(p))?;
+    Ok(content.header())
+}
+
+fn dump(header: &Header, no_tensors: bool, prefix: Option<&str>) {
+    println!("version: {:?}", header.magic);
+    if let Some(arch) = header.architecture() {
+        println!("architecture: {arch}");
+    }
+    println!("metadata entries ({})", header.metadata.len());
+    for (key, value) in header.metadata.iter() {
+        if prefix.is_none_or(|p| key.starts_with(p)) {
+            println!("  {key}: {value}");
+        }
+    }
+    println!(
+        "tensors ({}, {})",
+        header.tensors.len(),
+        format_size(header.total_size_in_bytes())
+    );
+    for (dtype, count, size) in header.dtype_summary() {
+        println!("  {dtype:?}: {count} tensors, {}", format_size(size));
+    }
+    if !no_tensors {
+        for t in header.tensors.iter() {
+            println!(
+                "  {}: {:?} {:?} {}",
+                t.name,
+                t.ggml_dtype,
+                t.shape,
+                format_size(t.size_in_bytes)
+            );
+        }
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    match args.command {
+        Command::Dump {
+            file,
+            no_tensors,
+            prefix,
+        } => {
+            let header = read_header(&file)?;
+            dump(&header, no_tensors, prefix.as_deref())
+        }
+        Command::Diff { lhs, rhs } => {
+            let diffs = read_header(&lhs)?.diff(&read_header(&rhs)?);
+            if diffs.is_empty() {
+                println!("no differences");
+            }
+            for diff in diffs.iter() {
+                println!("{diff}")
+            }
+        }
+    }
+    Ok(())
+}
@@ -164,20 +164,10 @@ fn main() -> Result<()> {
 
     println!("Starting the inference loop:");
     print!("{prompt}");
-    let mut logits_processor = {
-        let temperature = args.temperature;
-        let sampling = if temperature <= 0. {
-            Sampling::ArgMax
-        } else {
-            match (args.top_k, args.top_p) {
-                (None, None) => Sampling::All { temperature },
-                (Some(k), None) => Sampling::TopK { k, temperature },
-                (None, Some(p)) => Sampling::TopP { p, temperature },
-                (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
-            }
-        };
-        LogitsProcessor::from_sampling(args.seed, sampling)
-    };
+    let mut logits_processor = LogitsProcessor::from_sampling(
+        args.seed,
+        Sampling::new(args.temperature, args.top_k, args.top_p),
+    );
 
     let mut start_gen = std::time::Instant::now();
     let mut index_pos = 0;
@@ -25,6 +25,7 @@ use candle_transformers::models::llama as model;
 use model::{Llama, LlamaConfig};
 
 const EOS_TOKEN: &str = "</s>";
+const EOT_TOKEN: &str = "<|eot_id|>";
 const DEFAULT_PROMPT: &str = "My favorite theorem is ";
 
 #[derive(Clone, Debug, Copy, PartialEq, Eq, ValueEnum)]
@@ -84,6 +85,10 @@ struct Args {
     #[arg(long)]
     dtype: Option<String>,
 
+    /// The dtype used for the kv cache, defaults to the model dtype.
+    #[arg(long)]
+    kv_dtype: Option<String>,
+
     /// Enable tracing (generates a trace-timestamp.json file).
     #[arg(long)]
     tracing: bool,
@@ -125,13 +130,20 @@ fn main() -> Result<()> {
     };
 
     let device = candle_examples::device(args.cpu)?;
+    let parse_dtype = |dtype: &str| match dtype {
+        "f16" => Ok(DType::F16),
+        "bf16" => Ok(DType::BF16),
+        "f32" => Ok(DType::F32),
+        dtype => bail!("Unsupported dtype {dtype}"),
+    };
     let dtype = match args.dtype.as_deref() {
-        Some("f16") => DType::F16,
-        Some("bf16") => DType::BF16,
-        Some("f32") => DType::F32,
-        Some(dtype) => bail!("Unsupported dtype {dtype}"),
+        Some(dtype) => parse_dtype(dtype)?,
         None => DType::F16,
     };
+    let model_config = match args.kv_dtype.as_deref() {
+        Some(kv_dtype) => model::ModelConfig::new(dtype).with_kv_dtype(parse_dtype(kv_dtype)?),
+        None => model::ModelConfig::new(dtype),
+    };
     let (llama, tokenizer_filename, mut cache, config) = {
         let api = Api::new()?;
         let model_id = args.model_id.unwrap_or_else(|| match args.which {
@@ -173,7 +185,8 @@ fn main() -> Result<()> {
                 vec![api.get("model.safetensors")?]
             }
         };
-        let cache = model::Cache::new(!args.no_kv_cache, dtype, &config, &device)?;
+        let cache =
+            model::Cache::from_model_config(!args.no_kv_cache, &model_config, &config, &device)?;
 
         let vb = unsafe { VarBuilder::from_mmaped_safetensors(&filenames, dtype, &device)? };
         (Llama::load(vb, &config)?, tokenizer_filename, cache, config)
@@ -184,6 +197,12 @@ fn main() -> Result<()> {
             .token_to_id(EOS_TOKEN)
             .map(model::LlamaEosToks::Single)
     });
+    // The llama 3 instruct models end their turns with <|eot_id|>.
+    let eos_token_id = match (eos_token_id, tokenizer.token_to_id(EOT_TOKEN)) {
+        (Some(eos), Some(eot)) => Some(eos.with_token(eot)),
+        (None, Some(eot)) => Some(model::LlamaEosToks::Single(eot)),
+        (eos, None) => eos,
+    };
     let prompt = args.prompt.as_ref().map_or(DEFAULT_PROMPT, |p| p.as_str());
     let mut tokens = tokenizer
         .encode(prompt, true)
@@ -194,20 +213,10 @@ fn main() -> Result<()> {
 
     println!("starting the inference loop");
     print!("{prompt}");
-    let mut logits_processor = {
-        let temperature = args.temperature;
-        let sampling = if temperature <= 0. {
-            Sampling::ArgMax
-        } else {
-            match (args.top_k, args.top_p) {
-                (None, None) => Sampling::All { temperature },
-                (Some(k), None) => Sampling::TopK { k, temperature },
-                (None, Some(p)) => Sampling::TopP { p, temperature },
-                (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
-            }
-        };
-        LogitsProcessor::from_sampling(args.seed, sampling)
-    };
+    let mut logits_processor = LogitsProcessor::from_sampling(
+        args.seed,
+        Sampling::new(args.temperature, args.top_k, args.top_p),
+    );
 
     let mut start_gen = std::time::Instant::now();
     let mut index_pos = 0;
@@ -241,14 +250,8 @@ fn main() -> Result<()> {
         token_generated += 1;
         tokens.push(next_token);
 
-        match eos_token_id {
-            Some(model::LlamaEosToks::Single(eos_tok_id)) if next_token == eos_tok_id => {
-                break;
-            }
-            Some(model::LlamaEosToks::Multiple(ref eos_ids)) if eos_ids.contains(&next_token) => {
-                break;
-            }
-            _ => (),
+        if matches!(&eos_token_id, Some(eos) if eos.contains(next_token)) {
+            break;
         }
         if let Some(t) = tokenizer.next_token(next_token)? {
             print!("{t}");
@@ -84,10 +84,10 @@ fn main() -> Result<()> {
                 drop(stream);
                 pcms.concat()
             } else {
-                let (pcm, sample_rate) = audio_io::pcm_decode(args.in_file)?;
+                let (pcm, sample_rate) = candle_examples::audio::pcm_decode(args.in_file)?;
                 if sample_rate != 24_000 {
                     println!("WARNING: mimi uses a 24khz sample rate, input uses {sample_rate}, resampling...");
-                    audio_io::resample(&pcm, sample_rate as usize, 24_000)?
+                    candle_examples::audio::resample(&pcm, sample_rate as usize, 24_000)?
                 } else {
                     pcm
                 }
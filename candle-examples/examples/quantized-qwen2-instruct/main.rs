@@ -70,9 +70,10 @@ struct Args {
     #[arg(long)]
     tracing: bool,
 
-    /// Process prompt elements separately.
-    #[arg(long)]
-    split_prompt: bool,
+    /// Process the prompt in chunks of this many tokens to limit the memory usage, one token at a
+    /// time if no value is specified.
+    #[arg(long, num_args = 0..=1, default_missing_value = "1")]
+    split_prompt: Option<usize>,
 
     /// Run on CPU rather than GPU even if a GPU is available.
     #[arg(long)]
@@ -224,36 +225,20 @@ fn main() -> anyhow::Result<()> {
     let tokens = tokens.get_ids();
     let to_sample = args.sample_len.saturating_sub(1);
     let mut all_tokens = vec![];
-    let mut logits_processor = {
-        let temperature = args.temperature;
-        let sampling = if temperature <= 0. {
-            Sampling::ArgMax
-        } else {
-            match (args.top_k, args.top_p) {
-                (None, None) => Sampling::All { temperature },
-                (Some(k), None) => Sampling::TopK { k, temperature },
-                (None, Some(p)) => Sampling::TopP { p, temperature },
-                (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
-            }
-        };
-        LogitsProcessor::from_sampling(args.seed, sampling)
-    };
+    let mut logits_processor = LogitsProcessor::from_sampling(
+        args.seed,
+        Sampling::new(args.temperature, args.top_k, args.top_p),
+    );
     let start_prompt_processing = std::time::Instant::now();
-    let mut next_token = if !args.split_prompt {
-        let input = Tensor::new(tokens, &device)?.unsqueeze(0)?;
-        let logits = model.forward(&input, 0)?;
-        let logits = logits.squeeze(0)?;
-        logits_processor.sample(&logits)?
-    } else {
-        let mut next_token = 0;
-        for (pos, token) in tokens.iter().enumerate() {
-            let input = Tensor::new(&[*token], &device)?.unsqueeze(0)?;
-            let logits = model.forward(&input, pos)?;
-            let logits = logits.squeeze(0)?;
-            next_token = logits_processor.sample(&logits)?
-        }
-        next_token
-    };
+    let chunk_size = args.split_prompt.unwrap_or(tokens.len().max(1));
+    let logits = candle_transformers::generation::forward_chunked(
+        tokens,
+        chunk_size,
+        0,
+        &device,
+        |input, pos| model.forward(input, pos),
+    )?;
+    let mut next_token = logits_processor.sample(&logits.squeeze(0)?)?;
     let prompt_dt = start_prompt_processing.elapsed();
     all_tokens.push(next_token);
     if let Some(t) = tos.next_token(next_token)? {
@@ -4,20 +4,45 @@ extern crate intel_mkl_src;
 #[cfg(feature = "accelerate")]
 extern crate accelerate_src;
 
-use clap::{Parser, ValueEnum};
+use clap::Parser;
 use std::io::Write;
 use tokenizers::Tokenizer;
 
 use candle::quantized::{ggml_file, gguf_file};
 use candle::Tensor;
-use candle_transformers::generation::{LogitsProcessor, Sampling};
-
+use candle_transformers::generation::ensemble::{check_shared_vocab, ContrastiveDecoding};
+use candle_transformers::generation::events::GenerationConfig;
+use candle_transformers::generation::grammar::GrammarConstraint;
+use candle_transformers::generation::speculative::SpeculativeGenerator;
+use candle_transformers::generation::steering::Steering;
+use candle_transformers::generation::watermark::Watermark;
+use candle_transformers::generation::{
+    GenerationReport, GenerationTimer, LogitsProcessor, Sampling,
+};
+
+use candle_examples::chat::{ChatSession, Role};
+use candle_examples::infill::{infill, FimFormat, FimPrompt};
+use candle_examples::prompt::{encode_segments, Segment};
+use candle_examples::registry::{ModelEntry, Registry};
+use candle_examples::repl::Input;
 use candle_examples::token_output_stream::TokenOutputStream;
+use candle_transformers::models::quantized_auto::{
+    Architecture, MemoryReport, ModelInfo, ModelWeights,
+};
 use candle_transformers::models::quantized_llama as model;
-use model::ModelWeights;
+use candle_transformers::pipeline_parallel::DeviceMap;
 
 const DEFAULT_PROMPT: &str = "My favorite theorem is ";
 
+/// The options that cannot be combined with speculative decoding.
+const SPECULATIVE_CONFLICTS: [&str; 5] = [
+    "amateur_which",
+    "amateur_model",
+    "grammar",
+    "json_schema",
+    "watermark_key",
+];
+
 #[derive(Debug)]
 enum Prompt {
     Interactive,
@@ -25,164 +50,6 @@ enum Prompt {
     One(String),
 }
 
-#[derive(Clone, Debug, Copy, PartialEq, Eq, ValueEnum)]
-enum Which {
-    #[value(name = "7b")]
-    L7b,
-    #[value(name = "13b")]
-    L13b,
-    #[value(name = "70b")]
-    L70b,
-    #[value(name = "7b-chat")]
-    L7bChat,
-    #[value(name = "13b-chat")]
-    L13bChat,
-    #[value(name = "70b-chat")]
-    L70bChat,
-    #[value(name = "7b-code")]
-    L7bCode,
-    #[value(name = "13b-code")]
-    L13bCode,
-    #[value(name = "32b-code")]
-    L34bCode,
-    #[value(name = "7b-leo")]
-    Leo7b,
-    #[value(name = "13b-leo")]
-    Leo13b,
-    #[value(name = "7b-mistral")]
-    Mistral7b,
-    #[value(name = "7b-mistral-instruct")]
-    Mistral7bInstruct,
-    #[value(name = "7b-mistral-instruct-v0.2")]
-    Mistral7bInstructV02,
-    #[value(name = "7b-zephyr-a")]
-    Zephyr7bAlpha,
-    #[value(name = "7b-zephyr-b")]
-    Zephyr7bBeta,
-    #[value(name = "7b-open-chat-3.5")]
-    OpenChat35,
-    #[value(name = "7b-starling-a")]
-    Starling7bAlpha,
-    #[value(name = "mixtral")]
-    Mixtral,
-    #[value(name = "mixtral-instruct")]
-    MixtralInstruct,
-    #[value(name = "llama3-8b")]
-    L8b,
-    #[value(name = "phi3")]
-    Phi3,
-}
-
-impl Which {
-    fn is_mistral(&self) -> bool {
-        match self {
-            Self::L7b
-            | Self::L13b
-            | Self::L70b
-            | Self::L7bChat
-            | Self::L13bChat
-            | Self::L70bChat
-            | Self::L7bCode
-            | Self::L13bCode
-            | Self::L34bCode
-            | Self::Leo7b
-            | Self::Leo13b
-            | Self::L8b
-            | Self::Phi3 => false,
-            // Zephyr and OpenChat are fine tuned versions of mistral and should be treated in the
-            // same way. Starling is a fine tuned version of OpenChat.
-            Self::OpenChat35
-            | Self::Starling7bAlpha
-            | Self::Zephyr7bAlpha
-            | Self::Zephyr7bBeta
-            | Self::Mixtral
-            | Self::MixtralInstruct
-            | Self::Mistral7b
-            | Self::Mistral7bInstruct
-            | Self::Mistral7bInstructV02 => true,
-        }
-    }
-
-    fn is_zephyr(&self) -> bool {
-        match self {
-            Self::L7b
-            | Self::L13b
-            | Self::L70b
-            | Self::L7bChat
-            | Self::L13bChat
-            | Self::L70bChat
-            | Self::L7bCode
-            | Self::L13bCode
-            | Self::L34bCode
-            | Self::Leo7b
-            | Self::Leo13b
-            | Self::Mixtral
-            | Self::MixtralInstruct
-            | Self::Mistral7b
-            | Self::Mistral7bInstruct
-            | Self::Mistral7bInstructV02
-            | Self::OpenChat35
-            | Self::Starling7bAlpha
-            | Self::L8b
-            | Self::Phi3 => false,
-            Self::Zephyr7bAlpha | Self::Zephyr7bBeta => true,
-        }
-    }
-
-    fn is_open_chat(&self) -> bool {
-        match self {
-            Self::L7b
-            | Self::L13b
-            | Self::L70b
-            | Self::L7bChat
-            | Self::L13bChat
-            | Self::L70bChat
-            | Self::L7bCode
-            | Self::L13bCode
-            | Self::L34bCode
-            | Self::Leo7b
-            | Self::Leo13b
-            | Self::Mixtral
-            | Self::MixtralInstruct
-            | Self::Mistral7b
-            | Self::Mistral7bInstruct
-            | Self::Mistral7bInstructV02
-            | Self::Zephyr7bAlpha
-            | Self::Zephyr7bBeta
-            | Self::L8b
-            | Self::Phi3 => false,
-            Self::OpenChat35 | Self::Starling7bAlpha => true,
-        }
-    }
-
-    fn tokenizer_repo(&self) -> &'static str {
-        match self {
-            Self::L7b
-            | Self::L13b
-            | Self::L70b
-            | Self::L7bChat
-            | Self::L13bChat
-            | Self::L70bChat
-            | Self::L7bCode
-            | Self::L13bCode
-            | Self::L34bCode => "hf-internal-testing/llama-tokenizer",
-            Self::Leo7b => "LeoLM/leo-hessianai-7b",
-            Self::Leo13b => "LeoLM/leo-hessianai-13b",
-            Self::Mixtral => "mistralai/Mixtral-8x7B-v0.1",
-            Self::MixtralInstruct => "mistralai/Mixtral-8x7B-Instruct-v0.1",
-            Self::Mistral7b
-            | Self::Mistral7bInstruct
-            | Self::Mistral7bInstructV02
-            | Self::Zephyr7bAlpha
-            | Self::Zephyr7bBeta => "mistralai/Mistral-7B-v0.1",
-            Self::OpenChat35 => "openchat/openchat_3.5",
-            Self::Starling7bAlpha => "berkeley-nest/Starling-LM-7B-alpha",
-            Self::L8b => "meta-llama/Meta-Llama-3-8B",
-            Self::Phi3 => "microsoft/Phi-3-mini-4k-instruct",
-        }
-    }
-}
-
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
@@ -204,6 +71,11 @@ struct Args {
     #[arg(long)]
     tokenizer: Option<String>,
 
+    /// Build the tokenizer from the gguf metadata rather than downloading a tokenizer.json, this
+    /// is supported for the byte level BPE tokenizers, e.g. llama 3.
+    #[arg(long)]
+    gguf_tokenizer: bool,
+
     /// The temperature used to generate samples, use 0 for greedy sampling.
     #[arg(long, default_value_t = 0.8)]
     temperature: f64,
@@ -228,9 +100,10 @@ struct Args {
     #[arg(long)]
     verbose_prompt: bool,
 
-    /// Process prompt elements separately.
-    #[arg(long)]
-    split_prompt: bool,
+    /// Process the prompt in chunks of this many tokens to limit the memory usage, one token at a
+    /// time if no value is specified.
+    #[arg(long, num_args = 0..=1, default_missing_value = "1")]
+    split_prompt: Option<usize>,
 
     /// Run on CPU rather than GPU even if a GPU is available.
     #[arg(long)]
@@ -244,9 +117,39 @@ struct Args {
     #[arg(long, default_value_t = 64)]
     repeat_last_n: usize,
 
-    /// The model size to use.
+    /// The code before the part to generate, the model fills in the middle between this prefix
+    /// and `--in-suffix` rather than continuing the prompt.
+    #[arg(long)]
+    in_prefix: Option<String>,
+
+    /// The code after the part to generate, see `--in-prefix`.
+    #[arg(long)]
+    in_suffix: Option<String>,
+
+    /// The infilling special tokens: codellama, starcoder or deepseek-coder. Detected from the
+    /// tokenizer vocabulary by default.
+    #[arg(long)]
+    fim_format: Option<FimFormat>,
+
+    /// The system prompt used in chat mode, formatted with the model chat template.
+    #[arg(long)]
+    system_prompt: Option<String>,
+
+    /// A json file used to resume the chat conversation, it is updated after each answer.
+    #[arg(long)]
+    session: Option<String>,
+
+    /// How to shorten the chat history when it gets close to the context length.
+    #[arg(long, value_enum, default_value_t = HistoryTrim::Drop)]
+    history_trim: HistoryTrim,
+
+    /// The model to use, as listed in the registry.
     #[arg(long, default_value = "7b")]
-    which: Which,
+    which: String,
+
+    /// A json manifest with additional models, see `registry/quantized.json` for the format.
+    #[arg(long)]
+    registry: Option<String>,
 
     /// Group-Query Attention, use 8 for the 70B version of LLaMAv2.
     #[arg(long)]
@@ -255,113 +158,384 @@ struct Args {
     /// Use the slower dmmv cuda kernel.
     #[arg(long)]
     force_dmmv: bool,
+
+    /// Caps the context length of gguf models, which defaults to the one from the file metadata,
+    /// e.g. 8192 for llama 3 and 131072 for llama 3.1.
+    #[arg(long)]
+    max_seq_len: Option<usize>,
+
+    /// A memory budget in MB for gguf models, the context length is reduced to fit in this
+    /// budget and the model is not loaded if it does not fit at all.
+    #[arg(long)]
+    memory_budget: Option<usize>,
+
+    /// Splits the layers of a gguf llama model across devices, e.g. `0-19:cuda:0,20-31:cpu`, the
+    /// layers that are not listed being on the default device.
+    #[arg(long)]
+    device_map: Option<String>,
+
+    /// With `--device-map`, the number of prompt tokens per micro batch so that the devices
+    /// process different parts of the prompt concurrently.
+    #[arg(long)]
+    micro_batch_size: Option<usize>,
+
+    /// With `--device-map`, runs the layers mapped to the cpu on the default device instead,
+    /// their weights being uploaded ahead of their use in this number of device buffers.
+    #[arg(long)]
+    offload_slots: Option<usize>,
+
+    /// Use contrastive decoding with this smaller model, as listed in the registry, as the
+    /// amateur. It must use the same tokenizer as the main model.
+    #[arg(long)]
+    amateur_which: Option<String>,
+
+    /// The GGUF file for the amateur model, rather than downloading it from the registry.
+    #[arg(long)]
+    amateur_model: Option<String>,
+
+    /// Contrastive decoding plausibility threshold, only the tokens with a probability of at
+    /// least alpha times the max probability of the main model are considered.
+    #[arg(long, default_value_t = 0.1)]
+    cd_alpha: f64,
+
+    /// Contrastive decoding strength, the amateur log-probs are scaled by beta.
+    #[arg(long, default_value_t = 1.0)]
+    cd_beta: f64,
+
+    /// Watermark the generated text using this secret key, the detection score is printed after
+    /// each answer.
+    #[arg(long)]
+    watermark_key: Option<u64>,
+
+    /// A safetensors file with steering vectors added to the hidden states of some layers, the
+    /// vectors are named `layers.{idx}`.
+    #[arg(long)]
+    steering: Option<String>,
+
+    /// The scale applied to all the steering vectors.
+    #[arg(long, default_value_t = 1.0)]
+    steering_scale: f64,
+
+    /// Only steer these layers, e.g. `--steering-layers 10,12`, all the layers of the steering
+    /// file are used by default.
+    #[arg(long, value_delimiter = ',')]
+    steering_layers: Option<Vec<usize>>,
+
+    /// A GBNF grammar file, the answers are constrained to match the grammar.
+    #[arg(long, conflicts_with = "json_schema")]
+    grammar: Option<String>,
+
+    /// A JSON schema file, the answers are constrained to be JSON values following the schema.
+    #[arg(long)]
+    json_schema: Option<String>,
+
+    /// Use speculative decoding with this smaller model, as listed in the registry, as the draft
+    /// model. It must use the same tokenizer as the main model, both being llama models. The
+    /// repeat penalty is not applied.
+    #[arg(long, conflicts_with_all = SPECULATIVE_CONFLICTS)]
+    draft_which: Option<String>,
+
+    /// The GGUF file for the draft model, rather than downloading it from the registry.
+    #[arg(long, conflicts_with_all = SPECULATIVE_CONFLICTS)]
+    draft_model: Option<String>,
+
+    /// The number of tokens proposed by the draft model for each forward pass of the main model.
+    #[arg(long, default_value_t = 4)]
+    num_draft_tokens: usize,
 }
 
 impl Args {
-    fn tokenizer(&self) -> anyhow::Result<Tokenizer> {
+    fn registry(&self) -> anyhow::Result<Registry> {
+        let mut registry = Registry::from_json(include_str!("../../registry/quantized.json"))?;
+        if let Some(path) = &self.registry {
+            registry.extend(Registry::load(path)?)
+        }
+        Ok(registry)
+    }
+
+    fn tokenizer(
+        &self,
+        entry: &ModelEntry,
+        model_path: &std::path::Path,
+    ) -> anyhow::Result<Tokenizer> {
+        if self.gguf_tokenizer && self.tokenizer.is_none() {
+            let mut file = gguf_file::SplitReader::open(model_path)?;
+            let content =
+                gguf_file::Content::read_split(&mut file).map_err(|e| e.with_path(model_path))?;
+            return Ok(candle_examples::gguf_tokenizer::from_gguf(&content)?);
+        }
         let tokenizer_path = match &self.tokenizer {
             Some(config) => std::path::PathBuf::from(config),
-            None => {
-                let api = hf_hub::api::sync::Api::new()?;
-                let repo = self.which.tokenizer_repo();
-                let api = api.model(repo.to_string());
-                api.get("tokenizer.json")?
-            }
+            None => entry.tokenizer(&candle_examples::hub::Downloader::new())?,
         };
         Tokenizer::from_file(tokenizer_path).map_err(anyhow::Error::msg)
     }
 
-    fn model(&self) -> anyhow::Result<std::path::PathBuf> {
-        let model_path = match &self.model {
+    fn logits_processor(&self) -> LogitsProcessor {
+        let sampling = Sampling::new(self.temperature, self.top_k, self.top_p);
+        LogitsProcessor::from_sampling(self.seed, sampling)
+    }
+
+    fn model(
+        &self,
+        entry: &ModelEntry,
+        model: &Option<String>,
+    ) -> anyhow::Result<std::path::PathBuf> {
+        let model_path = match model {
             Some(config) => std::path::PathBuf::from(config),
             None => {
-                let (repo, filename) = match self.which {
-                    Which::L7b => ("TheBloke/Llama-2-7B-GGML", "llama-2-7b.ggmlv3.q4_0.bin"),
-                    Which::L13b => ("TheBloke/Llama-2-13B-GGML", "llama-2-13b.ggmlv3.q4_0.bin"),
-                    Which::L70b => ("TheBloke/Llama-2-70B-GGML", "llama-2-70b.ggmlv3.q4_0.bin"),
-                    Which::L7bChat => (
-                        "TheBloke/Llama-2-7B-Chat-GGML",
-                        "llama-2-7b-chat.ggmlv3.q4_0.bin",
-                    ),
-                    Which::L13bChat => (
-                        "TheBloke/Llama-2-13B-Chat-GGML",
-                        "llama-2-13b-chat.ggmlv3.q4_0.bin",
-                    ),
-                    Which::L70bChat => (
-                        "TheBloke/Llama-2-70B-Chat-GGML",
-                        "llama-2-70b-chat.ggmlv3.q4_0.bin",
-                    ),
-                    Which::L7bCode => ("TheBloke/CodeLlama-7B-GGUF", "codellama-7b.Q8_0.gguf"),
-                    Which::L13bCode => ("TheBloke/CodeLlama-13B-GGUF", "codellama-13b.Q8_0.gguf"),
-                    Which::L34bCode => ("TheBloke/CodeLlama-34B-GGUF", "codellama-34b.Q8_0.gguf"),
-                    Which::Leo7b => (
-                        "TheBloke/leo-hessianai-7B-GGUF",
-                        "leo-hessianai-7b.Q4_K_M.gguf",
-                    ),
-                    Which::Leo13b => (
-                        "TheBloke/leo-hessianai-13B-GGUF",
-                        "leo-hessianai-13b.Q4_K_M.gguf",
-                    ),
-                    Which::Mixtral => (
-                        "TheBloke/Mixtral-8x7B-v0.1-GGUF",
-                        "mixtral-8x7b-v0.1.Q4_K_M.gguf",
-                    ),
-                    Which::MixtralInstruct => (
-                        "TheBloke/Mixtral-8x7B-Instruct-v0.1-GGUF",
-                        "mixtral-8x7b-instruct-v0.1.Q4_K_M.gguf",
-                    ),
-                    Which::Mistral7b => (
-                        "TheBloke/Mistral-7B-v0.1-GGUF",
-                        "mistral-7b-v0.1.Q4_K_S.gguf",
-                    ),
-                    Which::Mistral7bInstruct => (
-                        "TheBloke/Mistral-7B-Instruct-v0.1-GGUF",
-                        "mistral-7b-instruct-v0.1.Q4_K_S.gguf",
-                    ),
-                    Which::Mistral7bInstructV02 => (
-                        "TheBloke/Mistral-7B-Instruct-v0.2-GGUF",
-                        "mistral-7b-instruct-v0.2.Q4_K_S.gguf",
-                    ),
-                    Which::Zephyr7bAlpha => (
-                        "TheBloke/zephyr-7B-alpha-GGUF",
-                        "zephyr-7b-alpha.Q4_K_M.gguf",
-                    ),
-                    Which::Zephyr7bBeta => {
-                        ("TheBloke/zephyr-7B-beta-GGUF", "zephyr-7b-beta.Q4_K_M.gguf")
-                    }
-                    Which::OpenChat35 => ("TheBloke/openchat_3.5-GGUF", "openchat_3.5.Q4_K_M.gguf"),
-                    Which::Starling7bAlpha => (
-                        "TheBloke/Starling-LM-7B-alpha-GGUF",
-                        "starling-lm-7b-alpha.Q4_K_M.gguf",
-                    ),
-                    // TODO: swap to TheBloke model when available
-                    Which::L8b => (
-                        "QuantFactory/Meta-Llama-3-8B-GGUF",
-                        "Meta-Llama-3-8B.Q4_K_S.gguf",
-                    ),
-                    Which::Phi3 => (
-                        "microsoft/Phi-3-mini-4k-instruct-gguf",
-                        "Phi-3-mini-4k-instruct-q4.gguf",
-                    ),
-                };
-                let revision = if self.which == Which::Phi3 {
-                    "5eef2ce24766d31909c0b269fe90c817a8f263fb"
-                } else {
-                    "main"
-                };
-                let api = hf_hub::api::sync::Api::new()?;
-                api.repo(hf_hub::Repo::with_revision(
-                    repo.to_string(),
-                    hf_hub::RepoType::Model,
-                    revision.to_string(),
-                ))
-                .get(filename)?
+                // The weights are large, download them in parallel with some progress report.
+                let downloader = candle_examples::hub::Downloader::new()
+                    .with_progress(candle_examples::hub::stderr_progress());
+                entry.weights(&downloader)?
             }
         };
         Ok(model_path)
     }
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum HistoryTrim {
+    /// Drop the oldest exchanges.
+    Drop,
+    /// Replace the oldest exchanges with a summary generated by the model.
+    Summarize,
+    /// Keep the kv cache between the turns so that only the new ones are processed, the oldest
+    /// cached positions after the first prompt being dropped when the context is full. This is
+    /// only supported for llama models.
+    Shift,
+}
+
+struct Generator<'a> {
+    model: &'a mut ModelWeights,
+    amateur: Option<&'a mut ModelWeights>,
+    /// The draft model for speculative decoding.
+    draft: Option<&'a mut ModelWeights>,
+    /// The fraction of the draft tokens accepted during the last speculative generation.
+    acceptance_rate: Option<f64>,
+    device: &'a candle::Device,
+    args: &'a Args,
+    /// The generation stops after any of these tokens.
+    stop_tokens: Vec<u32>,
+    /// Constrains the generated tokens to match a grammar.
+    grammar: Option<GrammarConstraint>,
+    /// The tokens in the kv cache of the models.
+    context: Vec<u32>,
+    /// The last generated token, not yet in the kv cache.
+    last_token: Option<u32>,
+    /// The number of tokens at the start of the context that are never dropped.
+    keep: usize,
+    max_seq_len: usize,
+}
+
+impl Generator<'_> {
+    /// Returns the logits for the last position of `input`, when an amateur model is used the
+    /// contrastive decoding scores are returned instead.
+    fn forward(&mut self, input: &Tensor, pos: usize) -> candle::Result<Tensor> {
+        let logits = self.model.forward(input, pos)?.squeeze(0)?;
+        match self.amateur.as_mut() {
+            None => Ok(logits),
+            Some(amateur) => {
+                let amateur_logits = amateur.forward(input, pos)?.squeeze(0)?;
+                ContrastiveDecoding::new(self.args.cd_alpha, self.args.cd_beta)
+                    .logits(&logits, &amateur_logits)
+            }
+        }
+    }
+
+    /// Samples the next token, only among the tokens allowed by the grammar if any.
+    fn sample(
+        &mut self,
+        logits_processor: &mut LogitsProcessor,
+        logits: &Tensor,
+    ) -> candle::Result<u32> {
+        match self.grammar.as_mut() {
+            None => logits_processor.sample(logits),
+            Some(grammar) => {
+                let token = logits_processor.sample(&grammar.apply(logits)?)?;
+                grammar.accept(token)?;
+                Ok(token)
+            }
+        }
+    }
+
+    /// Drops the oldest positions of the kv cache after the kept ones so that `n` more tokens
+    /// fit in the context.
+    fn make_room(&mut self, n: usize) -> candle::Result<()> {
+        let len = self.context.len();
+        if len + n <= self.max_seq_len {
+            return Ok(());
+        }
+        // As in llama.cpp, half of the positions after the kept ones are dropped so that the
+        // context is not shifted again for each of the next tokens.
+        let keep = self.keep.min(len);
+        let discard = ((len - keep) / 2).max(len + n - self.max_seq_len);
+        if keep + discard > len {
+            candle::bail!(
+                "{n} tokens do not fit in the context of {} tokens",
+                self.max_seq_len
+            )
+        }
+        self.model.shift_context(keep, discard)?;
+        if let Some(amateur) = self.amateur.as_mut() {
+            amateur.shift_context(keep, discard)?
+        }
+        self.context.drain(keep..keep + discard);
+        Ok(())
+    }
+
+    /// Generates up to `sample_len` tokens after the prompt, `on_token` being called on each
+    /// generated token. The generation stops after the end of sequence token.
+    fn generate<F>(
+        &mut self,
+        prompt_tokens: &[u32],
+        sample_len: usize,
+        on_token: F,
+    ) -> candle::Result<(Vec<u32>, GenerationReport)>
+    where
+        F: FnMut(u32) -> candle::Result<()>,
+    {
+        self.reset();
+        self.generate_continued(prompt_tokens, sample_len, on_token)
+    }
+
+    /// Forgets the tokens of the kv cache, the next prompt being processed from the start.
+    fn reset(&mut self) {
+        self.context.clear();
+        self.last_token = None
+    }
+
+    /// Similar to `generate` but the prompt comes after the tokens already in the kv cache, the
+    /// first prompt of the conversation being kept when the context gets shifted.
+    fn generate_continued<F>(
+        &mut self,
+        prompt_tokens: &[u32],
+        sample_len: usize,
+        mut on_token: F,
+    ) -> candle::Result<(Vec<u32>, GenerationReport)>
+    where
+        F: FnMut(u32) -> candle::Result<()>,
+    {
+        if self.draft.is_some() {
+            return self.generate_speculative(prompt_tokens, sample_len, on_token);
+        }
+        if self.context.is_empty() {
+            self.keep = prompt_tokens.len().min(self.max_seq_len / 4)
+        }
+        // The last token of the previous answer has not been processed yet.
+        let prompt_tokens = self
+            .last_token
+            .take()
+            .into_iter()
+            .chain(prompt_tokens.iter().copied())
+            .collect::<Vec<_>>();
+        let prompt_tokens = prompt_tokens.as_slice();
+        let args = self.args;
+        let mut logits_processor = args.logits_processor();
+        if let Some(grammar) = self.grammar.as_mut() {
+            grammar.reset()
+        }
+
+        let mut timer = GenerationTimer::start();
+        let chunk_size = args.split_prompt.unwrap_or(prompt_tokens.len().max(1));
+        self.make_room(prompt_tokens.len())?;
+        let logits = candle_transformers::generation::forward_chunked(
+            prompt_tokens,
+            chunk_size,
+            self.context.len(),
+            self.device,
+            |input, pos| self.forward(input, pos),
+        )?;
+        self.context.extend_from_slice(prompt_tokens);
+        let watermark = args.watermark_key.map(Watermark::new);
+        let logits = match (&watermark, prompt_tokens.last()) {
+            (Some(watermark), Some(&prev_token)) => watermark.apply(&logits, prev_token)?,
+            _ => logits,
+        };
+        let mut next_token = self.sample(&mut logits_processor, &logits)?;
+        timer.prompt_processed(prompt_tokens.len());
+        let mut all_tokens = vec![next_token];
+        on_token(next_token)?;
+
+        for _ in 0..sample_len {
+            if self.stop_tokens.contains(&next_token) {
+                break;
+            }
+            self.make_room(1)?;
+            let input = Tensor::new(&[next_token], self.device)?.unsqueeze(0)?;
+            let logits = self.forward(&input, self.context.len())?;
+            self.context.push(next_token);
+            let logits = if args.repeat_penalty == 1. {
+                logits
+            } else {
+                let start_at = all_tokens.len().saturating_sub(args.repeat_last_n);
+                candle_transformers::utils::apply_repeat_penalty(
+                    &logits,
+                    args.repeat_penalty,
+                    &all_tokens[start_at..],
+                )?
+            };
+            let logits = match &watermark {
+                Some(watermark) => watermark.apply(&logits, next_token)?,
+                None => logits,
+            };
+            next_token = self.sample(&mut logits_processor, &logits)?;
+            all_tokens.push(next_token);
+            on_token(next_token)?;
+            timer.token_generated();
+        }
+        self.last_token = Some(next_token);
+        Ok((all_tokens, timer.finish()))
+    }
+
+    /// Similar to `generate` but the tokens are proposed by the draft model and verified by the
+    /// main model, several tokens being generated for each forward pass of the main model.
+    fn generate_speculative<F>(
+        &mut self,
+        prompt_tokens: &[u32],
+        sample_len: usize,
+        mut on_token: F,
+    ) -> candle::Result<(Vec<u32>, GenerationReport)>
+    where
+        F: FnMut(u32) -> candle::Result<()>,
+    {
+        let args = self.args;
+        let draft = match self.draft.as_mut() {
+            Some(draft) => draft,
+            None => candle::bail!("no draft model"),
+        };
+        let model = &mut *self.model;
+        let sampling = Sampling::new(args.temperature, args.top_k, args.top_p);
+        let mut generator = SpeculativeGenerator::new(
+            |input: &Tensor, pos: usize| draft.forward(input, pos),
+            |input: &Tensor, pos: usize| model.forward_all(input, pos),
+            args.num_draft_tokens,
+            sampling,
+            args.seed,
+            self.device,
+        );
+        let mut timer = GenerationTimer::start();
+        generator.set_prompt(prompt_tokens)?;
+        let mut all_tokens = vec![];
+        'generation: while all_tokens.len() <= sample_len {
+            for token in generator.step()? {
+                if all_tokens.is_empty() {
+                    timer.prompt_processed(prompt_tokens.len())
+                } else {
+                    timer.token_generated()
+                }
+                all_tokens.push(token);
+                on_token(token)?;
+                if self.stop_tokens.contains(&token) || all_tokens.len() > sample_len {
+                    break 'generation;
+                }
+            }
+        }
+        self.acceptance_rate = Some(generator.acceptance_rate());
+        Ok((all_tokens, timer.finish()))
+    }
+}
+
 fn format_size(size_in_bytes: usize) -> String {
     if size_in_bytes < 1_000 {
         format!("{}B", size_in_bytes)
@@ -374,6 +548,137 @@ fn format_size(size_in_bytes: usize) -> String {
     }
 }
 
+fn load_model(
+    model_path: &std::path::Path,
+    entry: &ModelEntry,
+    args: &Args,
+    device: &candle::Device,
+) -> anyhow::Result<(ModelWeights, Option<ModelInfo>, usize)> {
+    let start = std::time::Instant::now();
+    // The gguf metadata is used to select the model implementation, the ggml files only
+    // contain llama models.
+    let mut max_seq_len = model::MAX_SEQ_LEN;
+    let (model, info) = match model_path.extension().and_then(|v| v.to_str()) {
+        Some("gguf") => {
+            // Models split in multiple files are read as a single one.
+            let mut file = gguf_file::SplitReader::open(model_path)?;
+            let model =
+                gguf_file::Content::read_split(&mut file).map_err(|e| e.with_path(model_path))?;
+            let mut total_size_in_bytes = 0;
+            for (_, tensor) in model.tensor_infos.iter() {
+                let elem_count = tensor.shape.elem_count();
+                total_size_in_bytes +=
+                    elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
+            }
+            println!(
+                "loaded {:?} tensors ({}) in {:.2}s",
+                model.tensor_infos.len(),
+                &format_size(total_size_in_bytes),
+                start.elapsed().as_secs_f32(),
+            );
+            let info = ModelInfo::from_gguf(&model)?;
+            println!("architecture: {:?}", info.architecture);
+            if let Some(context_length) = info.context_length {
+                max_seq_len = context_length
+            }
+            if let Some(len) = args.max_seq_len {
+                max_seq_len = max_seq_len.min(len)
+            }
+            let memory = MemoryReport::from_gguf(&model, device, max_seq_len)?;
+            println!("estimated memory: {memory}");
+            if let Some(budget) = args.memory_budget {
+                match memory.max_context_length(budget * 1_000_000) {
+                    None => anyhow::bail!("the model does not fit in {budget}MB"),
+                    Some(len) if len < max_seq_len => {
+                        println!("context length reduced to {len} to fit in {budget}MB");
+                        max_seq_len = len
+                    }
+                    Some(_) => {}
+                }
+            }
+            let config = model::ModelConfig::default().with_max_seq_len(Some(max_seq_len));
+            let model = match &args.device_map {
+                None => ModelWeights::from_gguf_with_config(model, &mut file, device, config)?,
+                Some(spec) => {
+                    if !matches!(
+                        info.architecture,
+                        Architecture::Llama | Architecture::Mixtral
+                    ) {
+                        anyhow::bail!("--device-map is only supported for llama models")
+                    }
+                    let device_map = DeviceMap::parse(spec, device)?;
+                    let mut model = model::ModelWeights::from_gguf_with_device_map(
+                        model,
+                        &mut file,
+                        &device_map,
+                        config,
+                    )?;
+                    if let Some(n_slots) = args.offload_slots {
+                        model.offload_layers(device, n_slots)?;
+                    }
+                    println!("pipeline stages: {:?}", model.stages());
+                    model.set_micro_batch_size(args.micro_batch_size);
+                    ModelWeights::Llama(model)
+                }
+            };
+            (model, Some(info))
+        }
+        Some("ggml" | "bin") | Some(_) | None => {
+            let mut file = std::fs::File::open(model_path)?;
+            let model =
+                ggml_file::Content::read(&mut file, device).map_err(|e| e.with_path(model_path))?;
+            let mut total_size_in_bytes = 0;
+            for (_, tensor) in model.tensors.iter() {
+                let elem_count = tensor.shape().elem_count();
+                total_size_in_bytes +=
+                    elem_count * tensor.dtype().type_size() / tensor.dtype().block_size();
+            }
+            println!(
+                "loaded {:?} tensors ({}) in {:.2}s",
+                model.tensors.len(),
+                &format_size(total_size_in_bytes),
+                start.elapsed().as_secs_f32(),
+            );
+            println!("params: {:?}", model.hparams);
+            let model = model::ModelWeights::from_ggml(model, args.gqa.unwrap_or(entry.gqa))?;
+            (ModelWeights::Llama(model), None)
+        }
+    };
+    Ok((model, info, max_seq_len))
+}
+
+/// Loads a model used alongside the main one, given by its registry entry or its file, and
+/// checks that it uses the same tokenizer as the main model.
+fn load_companion_model(
+    registry: &Registry,
+    entry: &ModelEntry,
+    which: &Option<String>,
+    model: &Option<String>,
+    tokenizer: &Tokenizer,
+    args: &Args,
+    device: &candle::Device,
+) -> anyhow::Result<Option<ModelWeights>> {
+    if which.is_none() && model.is_none() {
+        return Ok(None);
+    }
+    let companion_entry = match which {
+        Some(which) => registry.get(which)?,
+        None => entry,
+    };
+    let companion_path = args.model(companion_entry, model)?;
+    // The predictions of the models are compared for each token id so both models must use the
+    // same tokenizer.
+    if args.tokenizer.is_none() && companion_entry.name != entry.name {
+        let companion_tokenizer = args.tokenizer(companion_entry, &companion_path)?;
+        check_shared_vocab(
+            &tokenizer.get_vocab(true),
+            &companion_tokenizer.get_vocab(true),
+        )?
+    }
+    let (companion, _, _) = load_model(&companion_path, companion_entry, args, device)?;
+    Ok(Some(companion))
+}
+
 fn main() -> anyhow::Result<()> {
     use tracing_chrome::ChromeLayerBuilder;
     use tracing_subscriber::prelude::*;
@@ -406,74 +711,61 @@ fn main() -> anyhow::Result<()> {
         args.temperature, args.repeat_penalty, args.repeat_last_n
     );
 
-    let model_path = args.model()?;
-    let mut file = std::fs::File::open(&model_path)?;
-    let start = std::time::Instant::now();
+    let registry = args.registry()?;
+    let entry = registry.get(&args.which)?;
+    let model_path = args.model(entry, &args.model)?;
     let device = candle_examples::device(args.cpu)?;
 
-    let mut model = match model_path.extension().and_then(|v| v.to_str()) {
-        Some("gguf") => {
-            let model = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(model_path))?;
-            let mut total_size_in_bytes = 0;
-            for (_, tensor) in model.tensor_infos.iter() {
-                let elem_count = tensor.shape.elem_count();
-                total_size_in_bytes +=
-                    elem_count * tensor.ggml_dtype.type_size() / tensor.ggml_dtype.block_size();
-            }
-            println!(
-                "loaded {:?} tensors ({}) in {:.2}s",
-                model.tensor_infos.len(),
-                &format_size(total_size_in_bytes),
-                start.elapsed().as_secs_f32(),
-            );
-            ModelWeights::from_gguf(model, &mut file, &device)?
-        }
-        Some("ggml" | "bin") | Some(_) | None => {
-            let model = ggml_file::Content::read(&mut file, &device)
-                .map_err(|e| e.with_path(model_path))?;
-            let mut total_size_in_bytes = 0;
-            for (_, tensor) in model.tensors.iter() {
-                let elem_count = tensor.shape().elem_count();
-                total_size_in_bytes +=
-                    elem_count * tensor.dtype().type_size() / tensor.dtype().block_size();
-            }
-            println!(
-                "loaded {:?} tensors ({}) in {:.2}s",
-                model.tensors.len(),
-                &format_size(total_size_in_bytes),
-                start.elapsed().as_secs_f32(),
-            );
-            println!("params: {:?}", model.hparams);
-            let default_gqa = match args.which {
-                Which::L7b
-                | Which::L13b
-                | Which::L7bChat
-                | Which::L13bChat
-                | Which::L7bCode
-                | Which::L13bCode
-                | Which::L34bCode
-                | Which::Leo7b
-                | Which::Leo13b
-                | Which::L8b
-                | Which::Phi3 => 1,
-                Which::Mixtral
-                | Which::MixtralInstruct
-                | Which::Mistral7b
-                | Which::Mistral7bInstruct
-                | Which::Mistral7bInstructV02
-                | Which::Zephyr7bAlpha
-                | Which::Zephyr7bBeta
-                | Which::L70b
-                | Which::L70bChat
-                | Which::OpenChat35
-                | Which::Starling7bAlpha => 8,
-            };
-            ModelWeights::from_ggml(model, args.gqa.unwrap_or(default_gqa))?
-        }
-    };
+    let (mut model, info, max_seq_len) = load_model(&model_path, entry, &args, &device)?;
     println!("model built");
+    if let Some(steering) = &args.steering {
+        let mut steering =
+            Steering::load(steering, &device)?.with_global_scale(args.steering_scale);
+        if let Some(layers) = &args.steering_layers {
+            steering = steering.with_layers(layers)
+        }
+        println!("steering layers {:?}", steering.layers());
+        model.set_steering(Some(steering))
+    }
 
-    let tokenizer = args.tokenizer()?;
+    let tokenizer = args.tokenizer(entry, &model_path)?;
+    let mut amateur = load_companion_model(
+        &registry,
+        entry,
+        &args.amateur_which,
+        &args.amateur_model,
+        &tokenizer,
+        &args,
+        &device,
+    )?;
+    if amateur.is_some() {
+        println!(
+            "contrastive decoding with alpha {} beta {}",
+            args.cd_alpha, args.cd_beta
+        );
+    }
+    let mut draft = load_companion_model(
+        &registry,
+        entry,
+        &args.draft_which,
+        &args.draft_model,
+        &tokenizer,
+        &args,
+        &device,
+    )?;
+    if let Some(draft) = &draft {
+        // Only the llama models drop the rejected draft tokens from their kv cache.
+        if !matches!(draft, ModelWeights::Llama(_)) || !matches!(model, ModelWeights::Llama(_)) {
+            anyhow::bail!("speculative decoding is only supported for llama models")
+        }
+        if args.history_trim == HistoryTrim::Shift {
+            anyhow::bail!("speculative decoding does not support --history-trim shift")
+        }
+        println!(
+            "speculative decoding with {} draft tokens",
+            args.num_draft_tokens
+        );
+    }
     let mut tos = TokenOutputStream::new(tokenizer);
     let prompt = match args.prompt.as_deref() {
         Some("chat") => Prompt::Chat,
@@ -482,152 +774,228 @@ fn main() -> anyhow::Result<()> {
         None => Prompt::One(DEFAULT_PROMPT.to_string()),
     };
 
-    let mut pre_prompt_tokens = vec![];
-    for prompt_index in 0.. {
+    // Stop on the end of sequence and end of turn tokens from the gguf metadata, e.g. both
+    // <|end_of_text|> and <|eot_id|> for llama 3, and on the registry one.
+    let mut stop_tokens = info.as_ref().map_or(vec![], |i| i.stop_token_ids());
+    match tos.tokenizer().token_to_id(&entry.eos_token) {
+        Some(eos_token) if !stop_tokens.contains(&eos_token) => stop_tokens.push(eos_token),
+        Some(_) => {}
+        None if stop_tokens.is_empty() => {
+            anyhow::bail!("cannot find the eos token {}", entry.eos_token)
+        }
+        None => {}
+    }
+    let mut generator = Generator {
+        model: &mut model,
+        amateur: amateur.as_mut(),
+        draft: draft.as_mut(),
+        acceptance_rate: None,
+        device: &device,
+        args: &args,
+        stop_tokens: stop_tokens.clone(),
+        grammar: None,
+        context: vec![],
+        last_token: None,
+        keep: 0,
+        max_seq_len,
+    };
+    let grammar = match (&args.grammar, &args.json_schema) {
+        (Some(path), _) => Some((path, false)),
+        (None, Some(path)) => Some((path, true)),
+        (None, None) => None,
+    };
+    if let Some((path, json_schema)) = grammar {
+        let constraint = candle_examples::grammar::load_constraint(
+            path,
+            json_schema,
+            tos.tokenizer(),
+            &stop_tokens,
+        )?;
+        println!("constraining the answers with {path}");
+        generator.grammar = Some(constraint)
+    }
+    if args.in_prefix.is_some() || args.in_suffix.is_some() {
+        let format = match args.fim_format {
+            Some(format) => format,
+            None => match FimFormat::detect(tos.tokenizer()) {
+                Some(format) => format,
+                None => anyhow::bail!("the tokenizer has no infilling tokens, see --fim-format"),
+            },
+        };
+        let prefix = args.in_prefix.as_deref().unwrap_or_default();
+        let suffix = args.in_suffix.as_deref().unwrap_or_default();
+        let prompt = FimPrompt::new(format, prefix, suffix);
+        if args.verbose_prompt {
+            println!("infill prompt: {:?}", prompt.encode(tos.tokenizer())?)
+        }
+        let config = GenerationConfig::new(args.sample_len)
+            .with_stop_tokens(stop_tokens)
+            .with_prompt_chunk_size(args.split_prompt);
+        let mut forward = |input: &Tensor, pos| generator.forward(input, pos);
+        let middle = infill(
+            &mut forward,
+            tos.tokenizer(),
+            &prompt,
+            &mut args.logits_processor(),
+            &device,
+            &config,
+            (),
+        )?;
+        println!("{prefix}{middle}{suffix}");
+        return Ok(());
+    }
+
+    let to_sample = args.sample_len.saturating_sub(1);
+    let max_tokens = max_seq_len.saturating_sub(to_sample + 10);
+    // The token output stream is used while generating, so use a separate tokenizer to count the
+    // tokens of the conversation and to summarize it.
+    let tokenizer = tos.tokenizer().clone();
+    let count_tokens = |text: &str| Ok(entry.encode(&tokenizer, text)?.len());
+
+    let mut repl = candle_examples::repl::Repl::stdin();
+    if !matches!(prompt, Prompt::One(_)) {
+        println!("{}", candle_examples::repl::HELP);
+    }
+    let mut session = match &args.session {
+        Some(path) if std::path::Path::new(path).exists() => {
+            let session = ChatSession::load(path)?;
+            println!("resuming the conversation from {path}");
+            session
+        }
+        _ => ChatSession::new(args.system_prompt.clone()),
+    };
+    loop {
         let prompt_str = match &prompt {
-            Prompt::One(prompt) => prompt.clone(),
-            Prompt::Interactive | Prompt::Chat => {
-                let is_interactive = matches!(prompt, Prompt::Interactive);
-                print!("> ");
-                std::io::stdout().flush()?;
-                let mut prompt = String::new();
-                std::io::stdin().read_line(&mut prompt)?;
-                if prompt.ends_with('\n') {
-                    prompt.pop();
-                    if prompt.ends_with('\r') {
-                        prompt.pop();
-                    }
+            Prompt::One(prompt) => {
+                let prompt_str = match args.system_prompt.as_deref() {
+                    Some(system) => entry.format_prompt(prompt, true, Some(system)),
+                    None => prompt.clone(),
+                };
+                print!("{}", &prompt_str);
+                prompt_str
+            }
+            Prompt::Interactive | Prompt::Chat => match repl.next_input()? {
+                Input::Exit => break,
+                Input::Reset => {
+                    session.clear();
+                    generator.reset();
+                    println!("conversation reset");
+                    continue;
                 }
-                if args.which.is_open_chat() {
-                    format!("GPT4 Correct User: {prompt}<|end_of_turn|>GPT4 Correct Assistant:")
-                } else if args.which.is_zephyr() {
-                    if prompt_index == 0 || is_interactive {
-                        format!("<|system|>\n</s>\n<|user|>\n{prompt}</s>\n<|assistant|>",)
+                Input::Save(path) => {
+                    session.save(&path)?;
+                    println!("conversation saved to {}", path.display());
+                    continue;
+                }
+                Input::Prompt(user_prompt) => {
+                    // Each prompt starts a new conversation in interactive mode.
+                    if matches!(prompt, Prompt::Interactive) {
+                        session.clear();
+                        generator.reset()
+                    }
+                    session.push(Role::User, user_prompt.clone());
+                    let removed = match args.history_trim {
+                        HistoryTrim::Drop => {
+                            session.trim_oldest(entry, max_tokens, count_tokens)?
+                        }
+                        HistoryTrim::Summarize => {
+                            let summarize = |transcript: &str| {
+                                let prompt = format!("Summarize the following conversation in a few sentences.\n\n{transcript}");
+                                let prompt = entry.format_prompt(&prompt, true, None);
+                                let tokens = entry.encode(&tokenizer, &prompt)?;
+                                // The summary is free text, not constrained by the grammar.
+                                let grammar = generator.grammar.take();
+                                let generated = generator.generate(&tokens, 256, |_| Ok(()));
+                                generator.grammar = grammar;
+                                let (tokens, _) = generated?;
+                                tokenizer
+                                    .decode(&tokens, true)
+                                    .map_err(|e| candle::Error::Msg(e.to_string()))
+                            };
+                            session.summarize_oldest(entry, max_tokens, count_tokens, summarize)?
+                        }
+                        // The context is shifted while generating instead.
+                        HistoryTrim::Shift => 0,
+                    };
+                    if removed > 0 {
+                        println!("removed {removed} turns from the conversation history");
+                    }
+                    if args.history_trim == HistoryTrim::Shift && !generator.context.is_empty() {
+                        // Only the new turn is processed, the previous ones are in the kv cache.
+                        entry.format_prompt(&user_prompt, false, None)
                     } else {
-                        format!("<|user|>\n{prompt}</s>\n<|assistant|>")
+                        session.format(entry)
                     }
-                } else if args.which.is_mistral() {
-                    format!("[INST] {prompt} [/INST]")
-                } else {
-                    prompt
                 }
-            }
+            },
+        };
+        let continued = args.history_trim == HistoryTrim::Shift && !generator.context.is_empty();
+        let tokens = if continued {
+            encode_segments(tos.tokenizer(), &[Segment::text(&prompt_str)])?
+        } else {
+            entry.encode(tos.tokenizer(), &prompt_str)?
         };
-        print!("{}", &prompt_str);
-        let tokens = tos
-            .tokenizer()
-            .encode(prompt_str, true)
-            .map_err(anyhow::Error::msg)?;
         if args.verbose_prompt {
-            for (token, id) in tokens.get_tokens().iter().zip(tokens.get_ids().iter()) {
+            for &id in tokens.iter() {
+                let token = tos.tokenizer().id_to_token(id).unwrap_or_default();
                 let token = token.replace('▁', " ").replace("<0x0A>", "\n");
                 println!("{id:7} -> '{token}'");
             }
         }
 
-        let prompt_tokens = [&pre_prompt_tokens, tokens.get_ids()].concat();
-        let to_sample = args.sample_len.saturating_sub(1);
-        let prompt_tokens = if prompt_tokens.len() + to_sample > model::MAX_SEQ_LEN - 10 {
-            let to_remove = prompt_tokens.len() + to_sample + 10 - model::MAX_SEQ_LEN;
-            prompt_tokens[prompt_tokens.len().saturating_sub(to_remove)..].to_vec()
+        let prompt_tokens = tokens.as_slice();
+        let prompt_tokens = if args.history_trim != HistoryTrim::Shift
+            && prompt_tokens.len() + to_sample > max_seq_len - 10
+        {
+            let to_remove = prompt_tokens.len() + to_sample + 10 - max_seq_len;
+            &prompt_tokens[prompt_tokens.len().saturating_sub(to_remove)..]
         } else {
             prompt_tokens
         };
-        let mut all_tokens = vec![];
-        let mut logits_processor = {
-            let temperature = args.temperature;
-            let sampling = if temperature <= 0. {
-                Sampling::ArgMax
-            } else {
-                match (args.top_k, args.top_p) {
-                    (None, None) => Sampling::All { temperature },
-                    (Some(k), None) => Sampling::TopK { k, temperature },
-                    (None, Some(p)) => Sampling::TopP { p, temperature },
-                    (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
-                }
-            };
-            LogitsProcessor::from_sampling(args.seed, sampling)
-        };
-
-        let start_prompt_processing = std::time::Instant::now();
-        let mut next_token = if !args.split_prompt {
-            let input = Tensor::new(prompt_tokens.as_slice(), &device)?.unsqueeze(0)?;
-            let logits = model.forward(&input, 0)?;
-            let logits = logits.squeeze(0)?;
-            logits_processor.sample(&logits)?
-        } else {
-            let mut next_token = 0;
-            for (pos, token) in prompt_tokens.iter().enumerate() {
-                let input = Tensor::new(&[*token], &device)?.unsqueeze(0)?;
-                let logits = model.forward(&input, pos)?;
-                let logits = logits.squeeze(0)?;
-                next_token = logits_processor.sample(&logits)?
-            }
-            next_token
-        };
-        let prompt_dt = start_prompt_processing.elapsed();
-        all_tokens.push(next_token);
-        if let Some(t) = tos.next_token(next_token)? {
-            print!("{t}");
-            std::io::stdout().flush()?;
-        }
-
-        let eos_token = match args.which {
-            Which::L8b => "<|end_of_text|>",
-            _ => match args.which.is_open_chat() {
-                true => "<|end_of_turn|>",
-                false => "</s>",
-            },
-        };
-
-        let eos_token = *tos.tokenizer().get_vocab(true).get(eos_token).unwrap();
-        let start_post_prompt = std::time::Instant::now();
-        let mut sampled = 0;
-        for index in 0..to_sample {
-            let input = Tensor::new(&[next_token], &device)?.unsqueeze(0)?;
-            let logits = model.forward(&input, prompt_tokens.len() + index)?;
-            let logits = logits.squeeze(0)?;
-            let logits = if args.repeat_penalty == 1. {
-                logits
-            } else {
-                let start_at = all_tokens.len().saturating_sub(args.repeat_last_n);
-                candle_transformers::utils::apply_repeat_penalty(
-                    &logits,
-                    args.repeat_penalty,
-                    &all_tokens[start_at..],
-                )?
-            };
-            next_token = logits_processor.sample(&logits)?;
-            all_tokens.push(next_token);
-            if let Some(t) = tos.next_token(next_token)? {
+        let on_token = |token: u32| -> candle::Result<()> {
+            if let Some(t) = tos.next_token(token)? {
                 print!("{t}");
                 std::io::stdout().flush()?;
             }
-            sampled += 1;
-            if next_token == eos_token {
-                break;
-            };
-        }
-        if let Some(rest) = tos.decode_rest().map_err(candle::Error::msg)? {
+            Ok(())
+        };
+        let (tokens, report) = if continued {
+            generator.generate_continued(prompt_tokens, to_sample, on_token)?
+        } else {
+            generator.generate(prompt_tokens, to_sample, on_token)?
+        };
+        if let Some(rest) = tos
+            .decode_rest()
+            .map_err(|e| candle::Error::Msg(e.to_string()))?
+        {
             print!("{rest}");
         }
         std::io::stdout().flush()?;
-        let dt = start_post_prompt.elapsed();
-        println!(
-            "\n\n{:4} prompt tokens processed: {:.2} token/s",
-            prompt_tokens.len(),
-            prompt_tokens.len() as f64 / prompt_dt.as_secs_f64(),
-        );
-        println!(
-            "{sampled:4} tokens generated: {:.2} token/s",
-            sampled as f64 / dt.as_secs_f64(),
-        );
+        tos.clear();
+        println!("\n\n{report}");
+        if let Some(rate) = generator.acceptance_rate {
+            println!("draft acceptance rate: {:.1}%", 100. * rate);
+        }
+        if let Some(key) = args.watermark_key {
+            let detection = Watermark::new(key).detect(&tokens);
+            println!(
+                "watermark z-score: {:.2} ({}/{} green tokens)",
+                detection.z_score, detection.num_green, detection.num_tokens
+            );
+        }
 
         match prompt {
             Prompt::One(_) => break,
             Prompt::Interactive => {}
             Prompt::Chat => {
-                pre_prompt_tokens = [prompt_tokens.as_slice(), all_tokens.as_slice()].concat()
+                let answer = tos
+                    .tokenizer()
+                    .decode(&tokens, true)
+                    .map_err(anyhow::Error::msg)?;
+                session.push(Role::Assistant, answer);
+                if let Some(path) = &args.session {
+                    session.save(path)?
+                }
             }
         }
     }
@@ -0,0 +1,319 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::{Error as E, Result};
+use clap::Parser;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use candle::quantized::gguf_file;
+use candle::{DType, Device, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::generation::LogitsProcessor;
+use candle_transformers::models::bert::{BertModel, Config};
+use candle_transformers::models::quantized_auto::{ModelInfo, ModelWeights};
+use candle_transformers::vector_index::{FlatIndex, HnswConfig, HnswIndex, Metric, VectorIndex};
+use tokenizers::{PaddingParams, Tokenizer, TruncationParams};
+
+use candle_examples::registry::Registry;
+use candle_examples::repl::{Input, Repl};
+use candle_examples::token_output_stream::TokenOutputStream;
+
+const SYSTEM_PROMPT: &str = "Answer the question using only the following context. If the \
+context does not contain the answer, say that you do not know.";
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The documents to index, text or markdown files or directories containing such files.
+    #[arg(long, required = true, num_args = 1..)]
+    docs: Vec<String>,
+
+    /// The question to answer, the questions are read from stdin if not specified.
+    #[arg(long)]
+    question: Option<String>,
+
+    /// The number of words in each chunk of the documents.
+    #[arg(long, default_value_t = 128)]
+    chunk_size: usize,
+
+    /// The number of words shared by two consecutive chunks.
+    #[arg(long, default_value_t = 32)]
+    chunk_overlap: usize,
+
+    /// The number of chunks retrieved for each question.
+    #[arg(long, default_value_t = 3)]
+    top_k: usize,
+
+    /// Use an approximate hnsw index rather than an exact flat one, this is only worth it for
+    /// large document collections.
+    #[arg(long)]
+    hnsw: bool,
+
+    /// The embedding model, a bert model from the hub.
+    #[arg(long, default_value = "sentence-transformers/all-MiniLM-L6-v2")]
+    embedding_model: String,
+
+    #[arg(long, default_value = "refs/pr/21")]
+    embedding_revision: String,
+
+    /// The generation model to use, as listed in the quantized registry.
+    #[arg(long, default_value = "7b-mistral-instruct-v0.2")]
+    which: String,
+
+    /// A local gguf file to use rather than the one from the registry.
+    #[arg(long)]
+    model: Option<String>,
+
+    /// The tokenizer for the generation model in json format.
+    #[arg(long)]
+    tokenizer: Option<String>,
+
+    /// The length of the answers to generate (in tokens).
+    #[arg(short = 'n', long, default_value_t = 512)]
+    sample_len: usize,
+
+    /// The temperature used to generate samples, use 0 for greedy sampling.
+    #[arg(long, default_value_t = 0.)]
+    temperature: f64,
+
+    /// The seed to use when generating random samples.
+    #[arg(long, default_value_t = 299792458)]
+    seed: u64,
+
+    /// Run on CPU rather than GPU even if a GPU is available.
+    #[arg(long)]
+    cpu: bool,
+}
+
+/// Splits a text in chunks of `size` words, consecutive chunks sharing `overlap` words.
+fn chunk_text(text: &str, size: usize, overlap: usize) -> Vec<String> {
+    let words = text.split_whitespace().collect::<Vec<_>>();
+    let step = size.saturating_sub(overlap).max(1);
+    let mut chunks = vec![];
+    let mut start = 0;
+    while start < words.len() {
+        let end = usize::min(start + size, words.len());
+        chunks.push(words[start..end].join(" "));
+        if end == words.len() {
+            break;
+        }
+        start += step
+    }
+    chunks
+}
+
+fn collect_files(path: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+    if path.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|e| Ok(e?.path()))
+            .collect::<Result<Vec<_>>>()?;
+        entries.sort();
+        for entry in entries {
+            collect_files(&entry, files)?
+        }
+    } else if matches!(
+        path.extension().and_then(|e| e.to_str()),
+        Some("txt" | "md")
+    ) {
+        files.push(path.to_path_buf())
+    }
+    Ok(())
+}
+
+struct Chunk {
+    source: PathBuf,
+    text: String,
+}
+
+struct Embedder {
+    model: BertModel,
+    tokenizer: Tokenizer,
+}
+
+impl Embedder {
+    fn load(args: &Args, device: &Device) -> Result<Self> {
+        let repo = hf_hub::Repo::with_revision(
+            args.embedding_model.clone(),
+            hf_hub::RepoType::Model,
+            args.embedding_revision.clone(),
+        );
+        let repo = candle_examples::hub::Downloader::new().repo(repo);
+        let config = std::fs::read_to_string(repo.get("config.json")?)?;
+        let config: Config = serde_json::from_str(&config)?;
+        let mut tokenizer = Tokenizer::from_file(repo.get("tokenizer.json")?).map_err(E::msg)?;
+        tokenizer
+            .with_padding(Some(PaddingParams::default()))
+            .with_truncation(Some(TruncationParams {
+                // The position embeddings of the bert models cover 512 tokens.
+                max_length: 512,
+                ..Default::default()
+            }))
+            .map_err(E::msg)?;
+        let weights = repo.get("model.safetensors")?;
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights], DType::F32, device)? };
+        let model = BertModel::load(vb, &config)?;
+        Ok(Self { model, tokenizer })
+    }
+
+    /// Returns the l2 normalized embeddings of the texts, using a mean pooling over the tokens
+    /// that are not padding.
+    fn embed(&self, texts: &[&str]) -> Result<Tensor> {
+        let device = &self.model.device;
+        let encodings = self
+            .tokenizer
+            .encode_batch(texts.to_vec(), true)
+            .map_err(E::msg)?;
+        let token_ids = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_ids(), device))
+            .collect::<candle::Result<Vec<_>>>()?;
+        let mask = encodings
+            .iter()
+            .map(|e| Tensor::new(e.get_attention_mask(), device))
+            .collect::<candle::Result<Vec<_>>>()?;
+        let token_ids = Tensor::stack(&token_ids, 0)?;
+        let mask = Tensor::stack(&mask, 0)?;
+        let token_type_ids = token_ids.zeros_like()?;
+        let embeddings = self
+            .model
+            .forward(&token_ids, &token_type_ids, Some(&mask))?;
+        let mask = mask.to_dtype(DType::F32)?.unsqueeze(2)?;
+        let sum = embeddings.broadcast_mul(&mask)?.sum(1)?;
+        let embeddings = sum.broadcast_div(&mask.sum(1)?)?;
+        let norm = embeddings.sqr()?.sum_keepdim(1)?.sqrt()?;
+        Ok(embeddings.broadcast_div(&norm)?)
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let device = candle_examples::device(args.cpu)?;
+
+    let mut files = vec![];
+    for doc in args.docs.iter() {
+        collect_files(Path::new(doc), &mut files)?
+    }
+    let mut chunks = vec![];
+    for file in files.iter() {
+        let text = std::fs::read_to_string(file)?;
+        for text in chunk_text(&text, args.chunk_size, args.chunk_overlap) {
+            chunks.push(Chunk {
+                source: file.clone(),
+                text,
+            })
+        }
+    }
+    if chunks.is_empty() {
+        anyhow::bail!("no text found in {:?}", args.docs)
+    }
+
+    let start = std::time::Instant::now();
+    let embedder = Embedder::load(&args, &device)?;
+    let embeddings = chunks
+        .chunks(32)
+        .map(|batch| {
+            let texts = batch.iter().map(|c| c.text.as_str()).collect::<Vec<_>>();
+            embedder.embed(&texts)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let embeddings = Tensor::cat(&embeddings, 0)?;
+    let dim = embeddings.dim(1)?;
+    // The embeddings are already normalized, the dot product is the cosine similarity.
+    let mut index: Box<dyn VectorIndex> = if args.hnsw {
+        Box::new(HnswIndex::new(dim, Metric::Dot, HnswConfig::default()))
+    } else {
+        Box::new(FlatIndex::new(dim, Metric::Dot, &device))
+    };
+    index.add(&embeddings)?;
+    println!(
+        "indexed {} chunks from {} files in {:.2}s",
+        chunks.len(),
+        files.len(),
+        start.elapsed().as_secs_f32()
+    );
+
+    let registry = Registry::from_json(include_str!("../../registry/quantized.json"))?;
+    let entry = registry.get(&args.which)?;
+    let model_path = match &args.model {
+        Some(model) => PathBuf::from(model),
+        None => {
+            let downloader = candle_examples::hub::Downloader::new()
+                .with_progress(candle_examples::hub::stderr_progress());
+            entry.weights(&downloader)?
+        }
+    };
+    let tokenizer_path = match &args.tokenizer {
+        Some(tokenizer) => PathBuf::from(tokenizer),
+        None => entry.tokenizer(&candle_examples::hub::Downloader::new())?,
+    };
+    let mut file = gguf_file::SplitReader::open(&model_path)?;
+    let content =
+        gguf_file::Content::read_split(&mut file).map_err(|e| e.with_path(&model_path))?;
+    let info = ModelInfo::from_gguf(&content)?;
+    let mut model = ModelWeights::from_gguf(content, &mut file, &device)?;
+    let mut tos = TokenOutputStream::new(Tokenizer::from_file(tokenizer_path).map_err(E::msg)?);
+    let eos_token = match info.eos_token_id {
+        Some(eos_token) => eos_token,
+        None => match tos.get_token(&entry.eos_token) {
+            Some(eos_token) => eos_token,
+            None => anyhow::bail!("cannot find the eos token {}", entry.eos_token),
+        },
+    };
+    println!("model built");
+
+    let mut repl = Repl::stdin();
+    loop {
+        let question = match &args.question {
+            Some(question) => question.clone(),
+            None => match repl.next_input()? {
+                Input::Prompt(question) => question,
+                Input::Exit => break,
+                Input::Reset | Input::Save(_) => continue,
+            },
+        };
+
+        let query = embedder.embed(&[question.as_str()])?.squeeze(0)?;
+        let mut context = vec![];
+        for &(i, score) in index.search(&query, args.top_k)?[0].iter() {
+            let chunk = &chunks[i];
+            println!("[{}] {score:.3} {}", i, chunk.source.display());
+            context.push(format!("[{}]\n{}", chunk.source.display(), chunk.text))
+        }
+        let system = format!("{SYSTEM_PROMPT}\n\n{}", context.join("\n\n"));
+        let prompt = entry.format_prompt(&question, true, Some(&system));
+        let prompt_tokens = tos.tokenizer().encode(prompt, true).map_err(E::msg)?;
+        let prompt_tokens = prompt_tokens.get_ids();
+
+        let temperature = (args.temperature > 0.).then_some(args.temperature);
+        let mut logits_processor = LogitsProcessor::new(args.seed, temperature, None);
+        let input = Tensor::new(prompt_tokens, &device)?.unsqueeze(0)?;
+        let logits = model.forward(&input, 0)?.squeeze(0)?;
+        let mut next_token = logits_processor.sample(&logits)?;
+        for index in 0..args.sample_len {
+            if next_token == eos_token {
+                break;
+            }
+            if let Some(t) = tos.next_token(next_token)? {
+                print!("{t}");
+                std::io::stdout().flush()?;
+            }
+            let input = Tensor::new(&[next_token], &device)?.unsqueeze(0)?;
+            let logits = model.forward(&input, prompt_tokens.len() + index)?;
+            next_token = logits_processor.sample(&logits.squeeze(0)?)?;
+        }
+        if let Some(rest) = tos.decode_rest().map_err(E::msg)? {
+            print!("{rest}");
+        }
+        println!();
+        tos.clear();
+
+        if args.question.is_some() {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -11,6 +11,7 @@ use candle_transformers::models::t5;
 use anyhow::{Error as E, Result};
 use candle::{DType, Device, Tensor};
 use candle_nn::VarBuilder;
+use candle_transformers::generation::beam_search::{beam_search, BeamSearchConfig};
 use candle_transformers::generation::LogitsProcessor;
 use clap::{Parser, ValueEnum};
 use hf_hub::{api::sync::Api, Repo, RepoType};
@@ -95,6 +96,14 @@ struct Args {
     /// The model to be used.
     #[arg(long, default_value = "t5-small")]
     which: Which,
+
+    /// Use beam search with this number of beams rather than sampling.
+    #[arg(long)]
+    num_beams: Option<usize>,
+
+    /// The beam search length penalty, positive values favor longer outputs.
+    #[arg(long, default_value_t = 1.0)]
+    length_penalty: f64,
 }
 
 struct T5ModelBuilder {
@@ -246,6 +255,24 @@ fn main() -> Result<()> {
                 let encoder_output = model.encode(&input_token_ids)?;
                 let start = std::time::Instant::now();
 
+                if let Some(num_beams) = args.num_beams {
+                    let config = BeamSearchConfig::new(num_beams, 512)
+                        .with_eos_tokens(vec![builder.config.eos_token_id as u32])
+                        .with_length_penalty(args.length_penalty);
+                    // Each beam is decoded from scratch so the kv cache is cleared first.
+                    let mut decode = |tokens: &Tensor, _pos: usize| -> candle::Result<Tensor> {
+                        model.clear_kv_cache();
+                        model.decode(tokens, &encoder_output)
+                    };
+                    let hypotheses = beam_search(&mut decode, &output_token_ids, device, &config)?;
+                    for hypothesis in hypotheses.iter() {
+                        let text = tokenizer.decode(&hypothesis.tokens, true).map_err(E::msg)?;
+                        println!("{:.3} {text}", hypothesis.score);
+                    }
+                    println!("took {:?}", start.elapsed());
+                    return Ok(());
+                }
+
                 for index in 0.. {
                     if output_token_ids.len() > 512 {
                         break;
@@ -0,0 +1,224 @@
+//! Conversation history for the chat modes of the examples.
+//!
+//! A [`ChatSession`] stores the role tagged turns of a conversation and formats them with the
+//! chat templates of a registry entry. When the conversation gets close to the model context
+//! length, the oldest exchanges can either be dropped or replaced by a summary.
+use crate::registry::ModelEntry;
+use candle::Result;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    System,
+    User,
+    Assistant,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct Turn {
+    pub role: Role,
+    pub content: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+pub struct ChatSession {
+    #[serde(default)]
+    pub turns: Vec<Turn>,
+    /// A summary of the turns that have been removed from the history.
+    #[serde(default)]
+    pub summary: Option<String>,
+}
+
+impl ChatSession {
+    pub fn new(system: Option<String>) -> Self {
+        let mut session = Self::default();
+        if let Some(system) = system {
+            session.push(Role::System, system)
+        }
+        session
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        let json = std::fs::read_to_string(p)?;
+        serde_json::from_str(&json).map_err(|e| candle::Error::wrap(e).with_path(p))
+    }
+
+    pub fn save<P: AsRef<std::path::Path>>(&self, p: P) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).map_err(candle::Error::wrap)?;
+        std::fs::write(p, json)?;
+        Ok(())
+    }
+
+    pub fn push(&mut self, role: Role, content: String) {
+        self.turns.push(Turn { role, content })
+    }
+
+    /// Starts a new conversation, only the system turns are kept.
+    pub fn clear(&mut self) {
+        self.turns.retain(|t| t.role == Role::System);
+        self.summary = None
+    }
+
+    /// The system turns followed by the summary of the removed turns.
+    pub fn system_prompt(&self) -> Option<String> {
+        let mut system = self
+            .turns
+            .iter()
+            .filter(|t| t.role == Role::System)
+            .map(|t| t.content.clone())
+            .collect::<Vec<_>>();
+        if let Some(summary) = &self.summary {
+            system.push(format!("Summary of the conversation so far: {summary}"))
+        }
+        if system.is_empty() {
+            None
+        } else {
+            Some(system.join("\n\n"))
+        }
+    }
+
+    /// Formats the conversation using the chat templates of `entry`, the assistant turns are
+    /// terminated by the end of sequence token.
+    pub fn format(&self, entry: &ModelEntry) -> String {
+        let system = self.system_prompt();
+        let mut text = String::new();
+        let mut first_turn = true;
+        for turn in self.turns.iter() {
+            match turn.role {
+                Role::System => {}
+                Role::User => {
+                    text.push_str(&entry.format_prompt(
+                        &turn.content,
+                        first_turn,
+                        system.as_deref(),
+                    ));
+                    first_turn = false
+                }
+                Role::Assistant => {
+                    text.push_str(&turn.content);
+                    text.push_str(&entry.eos_token)
+                }
+            }
+        }
+        text
+    }
+
+    /// Formats the conversation and encodes it with a single BOS token.
+    pub fn encode(
+        &self,
+        entry: &ModelEntry,
+        tokenizer: &tokenizers::Tokenizer,
+    ) -> Result<Vec<u32>> {
+        entry.encode(tokenizer, &self.format(entry))
+    }
+
+    /// The number of user and assistant turns that have to be removed, oldest first, for the
+    /// formatted conversation to use at most `max_tokens` tokens. The last user turn is never
+    /// removed.
+    fn turns_to_remove<F>(
+        &self,
+        entry: &ModelEntry,
+        max_tokens: usize,
+        count_tokens: &mut F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&str) -> Result<usize>,
+    {
+        let mut session = self.clone();
+        let mut removed = 0;
+        while count_tokens(&session.format(entry))? > max_tokens {
+            // Remove a user turn together with the assistant answer that follows it.
+            let mut exchange = session
+                .turns
+                .iter()
+                .enumerate()
+                .filter(|(_, t)| t.role != Role::System)
+                .map(|(i, _)| i)
+                .take(2)
+                .collect::<Vec<_>>();
+            if exchange.len() == 2 && session.turns[exchange[1]].role == Role::User {
+                exchange.pop();
+            }
+            let remaining = session
+                .turns
+                .iter()
+                .filter(|t| t.role != Role::System)
+                .count();
+            if remaining <= exchange.len() {
+                break;
+            }
+            for &i in exchange.iter().rev() {
+                session.turns.remove(i);
+            }
+            removed += exchange.len()
+        }
+        Ok(removed)
+    }
+
+    fn remove_oldest(&mut self, n: usize) -> Vec<Turn> {
+        let mut removed = vec![];
+        let mut turns = vec![];
+        for turn in self.turns.drain(..) {
+            if turn.role != Role::System && removed.len() < n {
+                removed.push(turn)
+            } else {
+                turns.push(turn)
+            }
+        }
+        self.turns = turns;
+        removed
+    }
+
+    /// Drops the oldest exchanges until the formatted conversation fits in `max_tokens` tokens,
+    /// `count_tokens` returning the number of tokens for a text. Returns the number of removed
+    /// turns.
+    pub fn trim_oldest<F>(
+        &mut self,
+        entry: &ModelEntry,
+        max_tokens: usize,
+        mut count_tokens: F,
+    ) -> Result<usize>
+    where
+        F: FnMut(&str) -> Result<usize>,
+    {
+        let n = self.turns_to_remove(entry, max_tokens, &mut count_tokens)?;
+        self.remove_oldest(n);
+        Ok(n)
+    }
+
+    /// Replaces the oldest exchanges with a summary until the formatted conversation fits in
+    /// `max_tokens` tokens. `summarize` is given a transcript of the removed turns, including the
+    /// previous summary, and typically uses the model to summarize it. If the conversation still
+    /// does not fit with the summary, more turns are dropped. Returns the number of removed turns.
+    pub fn summarize_oldest<F, S>(
+        &mut self,
+        entry: &ModelEntry,
+        max_tokens: usize,
+        mut count_tokens: F,
+        summarize: S,
+    ) -> Result<usize>
+    where
+        F: FnMut(&str) -> Result<usize>,
+        S: FnOnce(&str) -> Result<String>,
+    {
+        let n = self.turns_to_remove(entry, max_tokens, &mut count_tokens)?;
+        if n == 0 {
+            return Ok(0);
+        }
+        let mut transcript = vec![];
+        if let Some(summary) = &self.summary {
+            transcript.push(format!("Summary: {summary}"))
+        }
+        for turn in self.remove_oldest(n) {
+            let role = match turn.role {
+                Role::System => "System",
+                Role::User => "User",
+                Role::Assistant => "Assistant",
+            };
+            transcript.push(format!("{role}: {}", turn.content))
+        }
+        self.summary = Some(summarize(&transcript.join("\n"))?);
+        Ok(n + self.trim_oldest(entry, max_tokens, count_tokens)?)
+    }
+}
@@ -0,0 +1,154 @@
+//! Tokenizers rebuilt from the gguf metadata.
+//!
+//! The gguf files converted by llama.cpp include the tokenizer vocabulary, the BPE merges and the
+//! token types. For the byte level BPE tokenizers, `tokenizer.ggml.model` being `gpt2`, such as
+//! the tiktoken based llama 3 tokenizer, [`from_gguf`] rebuilds a [`Tokenizer`] so that no
+//! separate `tokenizer.json` has to be downloaded, e.g. from the gated llama 3 repos.
+use candle::quantized::gguf_file;
+use candle::Result;
+use serde_json::json;
+use tokenizers::Tokenizer;
+
+/// The pre-tokenizer regex of the llama 3 tokenizer, `tokenizer.ggml.pre` being `llama-bpe`.
+const LLAMA3_PATTERN: &str = r"(?i:'s|'t|'re|'ve|'m|'ll|'d)|[^\r\n\p{L}\p{N}]?\p{L}+|\p{N}{1,3}| ?[^\s\p{L}\p{N}]+[\r\n]*|\s*[\r\n]+|\s+(?!\S)|\s+";
+
+// The gguf token types.
+const TOKEN_TYPE_CONTROL: i32 = 3;
+const TOKEN_TYPE_USER_DEFINED: i32 = 4;
+
+fn strings(ct: &gguf_file::Content, key: &str) -> Result<Vec<String>> {
+    match ct.metadata.get(key) {
+        None => candle::bail!("cannot find {key} in metadata"),
+        Some(v) => v.to_vec()?.iter().map(|v| v.to_string().cloned()).collect(),
+    }
+}
+
+/// Builds the tokenizer of a gguf file using a byte level BPE tokenizer. The control tokens, e.g.
+/// `<|begin_of_text|>` or `<|eot_id|>`, are special tokens and the BOS token is added when
+/// encoding with the special tokens, unless `tokenizer.ggml.add_bos_token` is false.
+pub fn from_gguf(ct: &gguf_file::Content) -> Result<Tokenizer> {
+    let get = |key: &str| ct.metadata.get(key);
+    let model = get("tokenizer.ggml.model").and_then(|v| v.to_string().ok());
+    match model.map(|m| m.as_str()) {
+        Some("gpt2") => {}
+        Some(model) => candle::bail!("unsupported gguf tokenizer model {model}"),
+        None => candle::bail!("cannot find tokenizer.ggml.model in metadata"),
+    }
+    let tokens = strings(ct, "tokenizer.ggml.tokens")?;
+    let merges = strings(ct, "tokenizer.ggml.merges")?;
+    let token_types = match get("tokenizer.ggml.token_type") {
+        Some(v) => v
+            .to_vec()?
+            .iter()
+            .map(|v| v.to_i32())
+            .collect::<Result<Vec<_>>>()?,
+        None => vec![],
+    };
+
+    let split = match get("tokenizer.ggml.pre").and_then(|v| v.to_string().ok()) {
+        Some(pre) if pre == "llama-bpe" => Some(LLAMA3_PATTERN),
+        Some(pre) if pre != "default" && pre != "gpt2" => {
+            candle::bail!("unsupported gguf pre-tokenizer {pre}, use a tokenizer.json")
+        }
+        _ => None,
+    };
+    let pre_tokenizer = match split {
+        Some(pattern) => json!({
+            "type": "Sequence",
+            "pretokenizers": [
+                {
+                    "type": "Split",
+                    "pattern": { "Regex": pattern },
+                    "behavior": "Isolated",
+                    "invert": false,
+                },
+                {
+                    "type": "ByteLevel",
+                    "add_prefix_space": false,
+                    "trim_offsets": true,
+                    "use_regex": false,
+                },
+            ],
+        }),
+        None => json!({
+            "type": "ByteLevel",
+            "add_prefix_space": false,
+            "trim_offsets": true,
+            "use_regex": true,
+        }),
+    };
+
+    let added_tokens = tokens
+        .iter()
+        .zip(token_types.iter())
+        .enumerate()
+        .filter(|(_, (_, &t))| t == TOKEN_TYPE_CONTROL || t == TOKEN_TYPE_USER_DEFINED)
+        .map(|(id, (content, &t))| {
+            json!({
+                "id": id,
+                "content": content,
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": t == TOKEN_TYPE_CONTROL,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let bos = get("tokenizer.ggml.bos_token_id").and_then(|v| v.to_u32().ok());
+    let add_bos = get("tokenizer.ggml.add_bos_token")
+        .and_then(|v| v.to_bool().ok())
+        .unwrap_or(true);
+    let post_processor = match bos.and_then(|id| Some((id, tokens.get(id as usize)?))) {
+        Some((id, bos)) if add_bos => json!({
+            "type": "TemplateProcessing",
+            "single": [
+                { "SpecialToken": { "id": bos, "type_id": 0 } },
+                { "Sequence": { "id": "A", "type_id": 0 } },
+            ],
+            "pair": [
+                { "SpecialToken": { "id": bos, "type_id": 0 } },
+                { "Sequence": { "id": "A", "type_id": 0 } },
+                { "SpecialToken": { "id": bos, "type_id": 1 } },
+                { "Sequence": { "id": "B", "type_id": 1 } },
+            ],
+            "special_tokens": { (bos.as_str()): { "id": bos, "ids": [id], "tokens": [bos] } },
+        }),
+        _ => serde_json::Value::Null,
+    };
+
+    let vocab = tokens
+        .iter()
+        .enumerate()
+        .map(|(id, token)| (token.clone(), json!(id)))
+        .collect::<serde_json::Map<_, _>>();
+    let json = json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": added_tokens,
+        "normalizer": null,
+        "pre_tokenizer": pre_tokenizer,
+        "post_processor": post_processor,
+        "decoder": {
+            "type": "ByteLevel",
+            "add_prefix_space": true,
+            "trim_offsets": true,
+            "use_regex": true,
+        },
+        "model": {
+            "type": "BPE",
+            "dropout": null,
+            "unk_token": null,
+            "continuing_subword_prefix": null,
+            "end_of_word_suffix": null,
+            "fuse_unk": false,
+            "byte_fallback": false,
+            "ignore_merges": split.is_some(),
+            "vocab": vocab,
+            "merges": merges,
+        },
+    });
+    Tokenizer::from_bytes(json.to_string()).map_err(|e| candle::Error::Msg(e.to_string()))
+}
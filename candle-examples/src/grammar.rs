@@ -0,0 +1,54 @@
+//! Helpers for the grammar constrained generation of the examples, see
+//! [`candle_transformers::generation::grammar`].
+use candle::Result;
+use candle_transformers::generation::grammar::{Grammar, GrammarConstraint};
+use candle_transformers::generation::json_schema;
+use tokenizers::Tokenizer;
+
+/// Returns the text of each token of the vocabulary, indexed by token id.
+///
+/// Decoding a token on its own drops the leading space of sentencepiece tokenizers, so each
+/// token is decoded after a prefix token and the text of the prefix is removed. The special
+/// tokens decode to empty strings and are never allowed by the grammar.
+pub fn token_strings(tokenizer: &Tokenizer) -> Result<Vec<String>> {
+    let map_err = |e: tokenizers::Error| candle::Error::Msg(format!("tokenizer error: {e}"));
+    let prefix = match tokenizer
+        .encode("a", false)
+        .map_err(map_err)?
+        .get_ids()
+        .last()
+    {
+        Some(&prefix) => prefix,
+        None => candle::bail!("cannot encode the prefix token"),
+    };
+    let prefix_text = tokenizer.decode(&[prefix], true).map_err(map_err)?;
+    (0..tokenizer.get_vocab_size(true) as u32)
+        .map(|id| {
+            let text = tokenizer.decode(&[prefix, id], true).map_err(map_err)?;
+            Ok(text
+                .strip_prefix(prefix_text.as_str())
+                .unwrap_or_default()
+                .to_string())
+        })
+        .collect()
+}
+
+/// Loads a GBNF grammar file, or a JSON schema file when `json_schema` is set, and returns the
+/// constraint using the tokenizer vocabulary.
+pub fn load_constraint<P: AsRef<std::path::Path>>(
+    path: P,
+    json_schema: bool,
+    tokenizer: &Tokenizer,
+    eos_tokens: &[u32],
+) -> Result<GrammarConstraint> {
+    let path = path.as_ref();
+    let src = std::fs::read_to_string(path)?;
+    let grammar = if json_schema {
+        let schema =
+            serde_json::from_str(&src).map_err(|e| candle::Error::wrap(e).with_path(path))?;
+        json_schema::to_grammar(&schema)?
+    } else {
+        Grammar::parse(&src)?
+    };
+    GrammarConstraint::new(grammar, &token_strings(tokenizer)?, eos_tokens)
+}
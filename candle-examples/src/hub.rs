@@ -0,0 +1,239 @@
+//! Downloading files from the hugging face hub.
+//!
+//! This is a small layer on top of the `hf-hub` cache: files end up in the same location as with
+//! `hf_hub::api::sync::Api` so both can be used interchangeably. With the `hub` feature, large
+//! files are fetched with parallel range requests, interrupted downloads resume from the chunks
+//! already on disk, the content is checked against its sha256 and progress is reported through a
+//! callback. Without it, the downloads go through the `hf-hub` api and the custom endpoint, chunk
+//! settings and progress callback are not used.
+//!
+//! When the `HF_HUB_OFFLINE` environment variable is set, no network request is made and files
+//! are only looked up in the cache.
+use candle::Result;
+use hf_hub::{Cache, Repo};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+#[cfg(feature = "hub")]
+mod transfer;
+#[cfg(feature = "hub")]
+pub use transfer::sha256_file;
+
+pub const DEFAULT_ENDPOINT: &str = "https://huggingface.co";
+pub const DEFAULT_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// The state of a download, as passed to the progress callback.
+#[derive(Debug, Clone, Copy)]
+pub struct Progress<'a> {
+    pub filename: &'a str,
+    /// The number of bytes available so far, including the ones from a previous run.
+    pub downloaded: usize,
+    pub total: usize,
+}
+
+type ProgressFn = Arc<dyn Fn(Progress) + Send + Sync>;
+
+/// Returns true if `HF_HUB_OFFLINE` is set to a truthy value.
+pub fn offline_from_env() -> bool {
+    match std::env::var("HF_HUB_OFFLINE") {
+        Ok(v) => matches!(
+            v.trim().to_ascii_lowercase().as_str(),
+            "1" | "on" | "yes" | "true"
+        ),
+        Err(_) => false,
+    }
+}
+
+/// A progress callback printing a single status line on stderr.
+pub fn stderr_progress() -> impl Fn(Progress) + Send + Sync {
+    let last = Mutex::new((String::new(), 0usize));
+    move |p: Progress| {
+        let mut last = match last.lock() {
+            Ok(last) => last,
+            Err(_) => return,
+        };
+        let percent = (p.downloaded * 100).checked_div(p.total).unwrap_or(100);
+        if last.0 == p.filename && last.1 == percent {
+            return;
+        }
+        *last = (p.filename.to_string(), percent);
+        eprint!(
+            "\r{}: {:.1}/{:.1}MB ({percent}%)",
+            p.filename,
+            p.downloaded as f64 / 1e6,
+            p.total as f64 / 1e6
+        );
+        if p.downloaded >= p.total {
+            eprintln!()
+        }
+    }
+}
+
+/// Downloads files from the hub into the local `hf-hub` cache.
+#[derive(Clone)]
+#[cfg_attr(not(feature = "hub"), allow(dead_code))]
+pub struct Downloader {
+    endpoint: String,
+    cache: Cache,
+    token: Option<String>,
+    num_workers: usize,
+    chunk_size: usize,
+    offline: bool,
+    progress: Option<ProgressFn>,
+}
+
+impl Downloader {
+    /// Creates a downloader using the default cache, `HF_ENDPOINT` if set, the token from the
+    /// `HF_TOKEN` environment variable or from the cache, and `HF_HUB_OFFLINE`.
+    pub fn new() -> Self {
+        let cache = Cache::default();
+        let token = std::env::var("HF_TOKEN").ok().or_else(|| cache.token());
+        let endpoint =
+            std::env::var("HF_ENDPOINT").unwrap_or_else(|_| DEFAULT_ENDPOINT.to_string());
+        Self {
+            endpoint,
+            cache,
+            token,
+            num_workers: 8,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            offline: offline_from_env(),
+            progress: None,
+        }
+    }
+
+    pub fn with_endpoint(mut self, endpoint: &str) -> Self {
+        self.endpoint = endpoint.trim_end_matches('/').to_string();
+        self
+    }
+
+    pub fn with_cache_dir(mut self, cache_dir: PathBuf) -> Self {
+        self.cache = Cache::new(cache_dir);
+        self
+    }
+
+    pub fn with_token(mut self, token: Option<String>) -> Self {
+        self.token = token;
+        self
+    }
+
+    /// The number of chunks fetched concurrently for a single file.
+    pub fn with_num_workers(mut self, num_workers: usize) -> Self {
+        self.num_workers = num_workers.max(1);
+        self
+    }
+
+    /// The size of the range requests. Changing this invalidates the chunks of the partial
+    /// downloads made with a different value.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// In offline mode, only the files that are already in the cache can be retrieved.
+    pub fn with_offline(mut self, offline: bool) -> Self {
+        self.offline = offline;
+        self
+    }
+
+    pub fn with_progress(mut self, progress: impl Fn(Progress) + Send + Sync + 'static) -> Self {
+        self.progress = Some(Arc::new(progress));
+        self
+    }
+
+    pub fn is_offline(&self) -> bool {
+        self.offline
+    }
+
+    pub fn repo(&self, repo: Repo) -> DownloadRepo {
+        DownloadRepo {
+            downloader: self.clone(),
+            repo,
+        }
+    }
+
+    pub fn model(&self, model_id: &str) -> DownloadRepo {
+        self.repo(Repo::model(model_id.to_string()))
+    }
+}
+
+impl Default for Downloader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A repo on the hub, obtained through [`Downloader::repo`].
+pub struct DownloadRepo {
+    downloader: Downloader,
+    repo: Repo,
+}
+
+impl DownloadRepo {
+    /// Returns the path to `filename` in the local cache, downloading it if needed.
+    pub fn get(&self, filename: &str) -> Result<PathBuf> {
+        self.get_with_sha256(filename, None)
+    }
+
+    /// Same as [`Self::get`], the content of freshly downloaded files is checked against the
+    /// `sha256` hex digest. Without it, lfs files are checked against the hash the hub reports
+    /// when the `hub` feature is enabled.
+    pub fn get_with_sha256(&self, filename: &str, sha256: Option<&str>) -> Result<PathBuf> {
+        let cache_repo = self.downloader.cache.repo(self.repo.clone());
+        if let Some(path) = cache_repo.get(filename) {
+            return Ok(path);
+        }
+        if self.downloader.offline {
+            candle::bail!(
+                "{filename} from {} is not in the cache and HF_HUB_OFFLINE is set",
+                self.repo.folder_name()
+            )
+        }
+        self.download(filename, sha256)
+    }
+
+    /// Downloads `filename` even if it is already present in the cache.
+    #[cfg(feature = "hub")]
+    pub fn download(&self, filename: &str, sha256: Option<&str>) -> Result<PathBuf> {
+        transfer::download(&self.downloader, &self.repo, filename, sha256)
+    }
+
+    /// Downloads `filename` through the `hf-hub` api, checking the content against a `sha256`
+    /// requires the `hub` feature.
+    #[cfg(not(feature = "hub"))]
+    pub fn download(&self, filename: &str, sha256: Option<&str>) -> Result<PathBuf> {
+        let d = &self.downloader;
+        if d.offline {
+            candle::bail!("cannot download {filename}, HF_HUB_OFFLINE is set")
+        }
+        if sha256.is_some() {
+            candle::bail!("checking the sha256 of {filename} requires the hub feature")
+        }
+        let api = hf_hub::api::sync::ApiBuilder::from_cache(d.cache.clone())
+            .with_token(d.token.clone())
+            .with_progress(d.progress.is_some())
+            .build()
+            .map_err(candle::Error::wrap)?;
+        api.repo(self.repo.clone())
+            .download(filename)
+            .map_err(candle::Error::wrap)
+    }
+
+    /// Retrieves the safetensors files listed in a json index file, see
+    /// [`crate::hub_load_safetensors`].
+    pub fn get_safetensors(&self, json_file: &str) -> Result<Vec<PathBuf>> {
+        let json_file = self.get(json_file)?;
+        let json: serde_json::Value = serde_json::from_reader(std::fs::File::open(&json_file)?)
+            .map_err(candle::Error::wrap)?;
+        let weight_map = match json.get("weight_map") {
+            Some(serde_json::Value::Object(map)) => map,
+            _ => candle::bail!("no weight map in {json_file:?}"),
+        };
+        let mut files = weight_map
+            .values()
+            .filter_map(|v| v.as_str())
+            .collect::<Vec<_>>();
+        files.sort();
+        files.dedup();
+        files.into_iter().map(|f| self.get(f)).collect()
+    }
+}
@@ -0,0 +1,288 @@
+//! The parallel and resumable downloads, enabled by the `hub` feature.
+use super::{Downloader, Progress};
+use candle::Result;
+use hf_hub::Repo;
+use sha2::{Digest, Sha256};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug)]
+struct Metadata {
+    commit_hash: String,
+    etag: String,
+    size: usize,
+}
+
+impl Metadata {
+    // For lfs files, the etag is the sha256 of the content.
+    fn sha256(&self) -> Option<&str> {
+        let is_sha256 = self.etag.len() == 64 && self.etag.bytes().all(|b| b.is_ascii_hexdigit());
+        is_sha256.then_some(self.etag.as_str())
+    }
+}
+
+impl Downloader {
+    fn agent(&self, redirects: u32) -> ureq::Agent {
+        let user_agent = format!("candle-examples/{}", env!("CARGO_PKG_VERSION"));
+        ureq::AgentBuilder::new()
+            .user_agent(&user_agent)
+            .redirects(redirects)
+            .build()
+    }
+
+    fn request(&self, agent: &ureq::Agent, method: &str, url: &str) -> ureq::Request {
+        let request = agent.request(method, url);
+        match &self.token {
+            Some(token) => request.set("Authorization", &format!("Bearer {token}")),
+            None => request,
+        }
+    }
+
+    fn metadata(&self, url: &str) -> Result<Metadata> {
+        let response = self
+            .request(&self.agent(0), "HEAD", url)
+            .call()
+            .map_err(candle::Error::wrap)?;
+        let header = |name: &str| response.header(name).map(|v| v.to_string());
+        let commit_hash = match header("x-repo-commit") {
+            Some(commit_hash) => commit_hash,
+            None => candle::bail!("missing x-repo-commit header for {url}"),
+        };
+        let etag = match header("x-linked-etag").or_else(|| header("etag")) {
+            Some(etag) => etag.replace('"', ""),
+            None => candle::bail!("missing etag header for {url}"),
+        };
+        let size = header("x-linked-size")
+            .or_else(|| header("content-length"))
+            .and_then(|s| s.parse().ok());
+        let size = match size {
+            Some(size) => size,
+            None => candle::bail!("missing size header for {url}"),
+        };
+        Ok(Metadata {
+            commit_hash,
+            etag,
+            size,
+        })
+    }
+
+    fn report(&self, filename: &str, downloaded: usize, total: usize) {
+        if let Some(progress) = &self.progress {
+            progress(Progress {
+                filename,
+                downloaded,
+                total,
+            })
+        }
+    }
+
+    // Downloads `url` to `dst`, the chunks are stored next to `dst` until they are all available.
+    fn fetch(&self, url: &str, filename: &str, size: usize, dst: &Path) -> Result<()> {
+        let chunk_size = self.chunk_size.min(size.max(1));
+        let num_chunks = size.div_ceil(chunk_size);
+        let part_path = |index: usize| {
+            let mut path = dst.as_os_str().to_owned();
+            path.push(format!(".part-{chunk_size}-{index}"));
+            PathBuf::from(path)
+        };
+        let part_len = |index: usize| usize::min(chunk_size, size - index * chunk_size);
+        let downloaded = (0..num_chunks)
+            .map(|i| {
+                let len = std::fs::metadata(part_path(i)).map_or(0, |m| m.len() as usize);
+                len.min(part_len(i))
+            })
+            .sum::<usize>();
+        let downloaded = AtomicUsize::new(downloaded);
+        self.report(filename, downloaded.load(Ordering::Relaxed), size);
+
+        let next_chunk = AtomicUsize::new(0);
+        let failed = AtomicBool::new(false);
+        let errors = Mutex::new(vec![]);
+        let num_workers = self.num_workers.min(num_chunks).max(1);
+        let agent = self.agent(5);
+        std::thread::scope(|s| {
+            for _ in 0..num_workers {
+                s.spawn(|| loop {
+                    let index = next_chunk.fetch_add(1, Ordering::Relaxed);
+                    if index >= num_chunks || failed.load(Ordering::Relaxed) {
+                        break;
+                    }
+                    let start = index * chunk_size;
+                    let res = self.download_chunk(
+                        &agent,
+                        url,
+                        &part_path(index),
+                        (start, start + part_len(index)),
+                        |n| {
+                            let d = downloaded.fetch_add(n, Ordering::Relaxed) + n;
+                            self.report(filename, d, size)
+                        },
+                    );
+                    if let Err(err) = res {
+                        failed.store(true, Ordering::Relaxed);
+                        if let Ok(mut errors) = errors.lock() {
+                            errors.push(err)
+                        }
+                        break;
+                    }
+                });
+            }
+        });
+        if let Some(err) = errors.into_inner().ok().and_then(|mut e| e.pop()) {
+            return Err(err);
+        }
+
+        // All the chunks are there, assemble them in a temporary file that gets moved in place.
+        let mut tmp_path = dst.as_os_str().to_owned();
+        tmp_path.push(".incomplete");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        for index in 0..num_chunks {
+            let mut part = std::fs::File::open(part_path(index))?;
+            std::io::copy(&mut part, &mut file)?;
+        }
+        file.flush()?;
+        drop(file);
+        std::fs::rename(&tmp_path, dst)?;
+        for index in 0..num_chunks {
+            let _ = std::fs::remove_file(part_path(index));
+        }
+        Ok(())
+    }
+
+    // Downloads the `[start, end)` range in `path`, resuming from the bytes already there.
+    fn download_chunk(
+        &self,
+        agent: &ureq::Agent,
+        url: &str,
+        path: &Path,
+        (start, end): (usize, usize),
+        mut on_bytes: impl FnMut(usize),
+    ) -> Result<()> {
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?;
+        let mut current = file.metadata()?.len() as usize;
+        if current >= end - start {
+            return Ok(());
+        }
+        let range = format!("bytes={}-{}", start + current, end - 1);
+        let response = self
+            .request(agent, "GET", url)
+            .set("Range", &range)
+            .call()
+            .map_err(candle::Error::wrap)?;
+        if response.status() != 206 {
+            // The server ignored the range, this only works if the chunk is the whole file.
+            let len = response
+                .header("content-length")
+                .and_then(|l| l.parse().ok());
+            if start + current != 0 || len != Some(end) {
+                candle::bail!("{url} does not support range requests")
+            }
+            file.set_len(0)?;
+        }
+        let mut reader = response.into_reader().take((end - start - current) as u64);
+        let mut buf = vec![0u8; 1 << 16];
+        loop {
+            let n = reader.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            file.write_all(&buf[..n])?;
+            current += n;
+            on_bytes(n);
+        }
+        if current != end - start {
+            candle::bail!("incomplete download for {url}, got {current} bytes")
+        }
+        Ok(())
+    }
+}
+
+/// Downloads `filename` from `repo` even if it is already present in the cache.
+pub(super) fn download(
+    d: &Downloader,
+    repo: &Repo,
+    filename: &str,
+    sha256: Option<&str>,
+) -> Result<PathBuf> {
+    if d.offline {
+        candle::bail!("cannot download {filename}, HF_HUB_OFFLINE is set")
+    }
+    let url = format!(
+        "{}/{}/resolve/{}/{filename}",
+        d.endpoint,
+        repo.url(),
+        repo.url_revision()
+    );
+    let metadata = d.metadata(&url)?;
+    let repo_dir = d.cache.path().join(repo.folder_name());
+    let blob_path = repo_dir.join("blobs").join(&metadata.etag);
+    std::fs::create_dir_all(repo_dir.join("blobs"))?;
+    if !blob_path.exists() {
+        d.fetch(&url, filename, metadata.size, &blob_path)?;
+        if let Some(sha256) = sha256.or(metadata.sha256()) {
+            let hash = sha256_file(&blob_path)?;
+            if !hash.eq_ignore_ascii_case(sha256) {
+                std::fs::remove_file(&blob_path)?;
+                candle::bail!("sha256 mismatch for {filename}, expected {sha256}, got {hash}")
+            }
+        }
+    } else {
+        d.report(filename, metadata.size, metadata.size);
+    }
+
+    let pointer_path = repo_dir
+        .join("snapshots")
+        .join(&metadata.commit_hash)
+        .join(filename);
+    if let Some(parent) = pointer_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    link(&blob_path, &pointer_path, filename)?;
+    d.cache
+        .repo(repo.clone())
+        .create_ref(&metadata.commit_hash)?;
+    Ok(pointer_path)
+}
+
+/// Returns the hex encoded sha256 of a file content.
+pub fn sha256_file<P: AsRef<Path>>(p: P) -> Result<String> {
+    let mut file = std::fs::File::open(p)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher)?;
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect())
+}
+
+// Snapshot files are relative symlinks to the blobs, as done by the python library.
+#[cfg(unix)]
+fn link(blob_path: &Path, pointer_path: &Path, filename: &str) -> Result<()> {
+    let _ = std::fs::remove_file(pointer_path);
+    let mut target = PathBuf::from("..");
+    for _ in 0..1 + filename.matches('/').count() {
+        target.push("..")
+    }
+    if let Some(name) = blob_path.file_name() {
+        target.push("blobs");
+        target.push(name)
+    }
+    std::os::unix::fs::symlink(target, pointer_path)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn link(blob_path: &Path, pointer_path: &Path, _filename: &str) -> Result<()> {
+    let _ = std::fs::remove_file(pointer_path);
+    if std::fs::hard_link(blob_path, pointer_path).is_err() {
+        std::fs::copy(blob_path, pointer_path)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,318 @@
+//! Loading images as tensors and saving tensors as images.
+//!
+//! Images are represented as `u8` tensors with shape `(3, height, width)`, or
+//! `(batch, 3, height, width)` for batches, the layout used by most vision models. Models usually
+//! expect `f32` inputs, the conversion and normalization being left to the caller, see
+//! [`crate::imagenet`] for the imagenet normalization.
+//!
+//! ```no_run
+//! use candle_examples::image_io::{Crop, ImageLoader, Resize};
+//! # fn main() -> candle::Result<()> {
+//! let loader = ImageLoader::new()
+//!     .with_size(224, 224)
+//!     .with_resize(Resize::Fill(Crop::Center));
+//! // A tensor of shape (2, 3, 224, 224).
+//! let batch = loader.load_batch(&["cat.jpg", "dog.png"])?;
+//! let img = (batch.get(0)?.to_dtype(candle::DType::F32)? / 255.)?;
+//! candle_examples::image_io::save_image(&img, "cat.png")?;
+//! # Ok(())
+//! # }
+//! ```
+use candle::{DType, Device, Result, Tensor};
+use image::DynamicImage;
+use std::path::Path;
+
+pub use image::imageops::FilterType;
+
+/// The part of the image that is kept when cropping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Crop {
+    Center,
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    BottomRight,
+}
+
+impl Crop {
+    /// The top-left corner of a `target_w`x`target_h` region within a `w`x`h` image.
+    fn offsets(&self, (w, h): (u32, u32), (target_w, target_h): (u32, u32)) -> (u32, u32) {
+        let (dx, dy) = (w.saturating_sub(target_w), h.saturating_sub(target_h));
+        match self {
+            Self::Center => (dx / 2, dy / 2),
+            Self::TopLeft => (0, 0),
+            Self::TopRight => (dx, 0),
+            Self::BottomLeft => (0, dy),
+            Self::BottomRight => (dx, dy),
+        }
+    }
+}
+
+/// How images are brought to the target size of an [`ImageLoader`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resize {
+    /// Resize to the exact target size, the aspect ratio is not preserved.
+    Exact,
+    /// Resize while preserving the aspect ratio so that the image covers the target size, the
+    /// parts that do not fit are cropped.
+    Fill(Crop),
+    /// Resize while preserving the aspect ratio so that the image fits in the target size. The
+    /// resulting image can be smaller than the target size along one dimension.
+    Fit,
+    /// Only crop the image, images smaller than the target size are left unchanged along the
+    /// corresponding dimension.
+    Crop(Crop),
+}
+
+/// Decodes an image, applying the orientation from its EXIF metadata if any so that photos taken
+/// by phones or cameras are not rotated.
+pub fn open<P: AsRef<Path>>(p: P) -> Result<DynamicImage> {
+    use image::ImageDecoder;
+
+    let p = p.as_ref();
+    let mut decoder = image::ImageReader::open(p)
+        .map_err(|e| candle::Error::from(e).with_path(p))?
+        .with_guessed_format()?
+        .into_decoder()
+        .map_err(candle::Error::wrap)?;
+    let orientation = decoder.orientation().map_err(candle::Error::wrap)?;
+    let mut img = DynamicImage::from_decoder(decoder).map_err(candle::Error::wrap)?;
+    img.apply_orientation(orientation);
+    Ok(img)
+}
+
+/// Crops `img` to `width`x`height`, dimensions smaller than the target are left unchanged.
+pub fn crop(img: &DynamicImage, width: usize, height: usize, crop: Crop) -> DynamicImage {
+    let (w, h) = (img.width(), img.height());
+    let (target_w, target_h) = ((width as u32).min(w), (height as u32).min(h));
+    let (x, y) = crop.offsets((w, h), (target_w, target_h));
+    img.crop_imm(x, y, target_w, target_h)
+}
+
+/// Crops the center of `img` to `width`x`height`.
+pub fn center_crop(img: &DynamicImage, width: usize, height: usize) -> DynamicImage {
+    crop(img, width, height, Crop::Center)
+}
+
+/// Resizes `img` to fit `width`x`height`, see [`Resize`] for the available modes.
+pub fn resize(
+    img: &DynamicImage,
+    width: usize,
+    height: usize,
+    mode: Resize,
+    filter: FilterType,
+) -> DynamicImage {
+    let (width, height) = (width as u32, height as u32);
+    match mode {
+        Resize::Exact => img.resize_exact(width, height, filter),
+        Resize::Fit => img.resize(width, height, filter),
+        Resize::Fill(c) => {
+            // Scale so that both dimensions are at least the target ones.
+            let (w, h) = (img.width() as f64, img.height() as f64);
+            let ratio = f64::max(width as f64 / w, height as f64 / h);
+            let new_w = ((w * ratio).round() as u32).max(width);
+            let new_h = ((h * ratio).round() as u32).max(height);
+            let img = img.resize_exact(new_w, new_h, filter);
+            crop(&img, width as usize, height as usize, c)
+        }
+        Resize::Crop(c) => crop(img, width as usize, height as usize, c),
+    }
+}
+
+/// Converts an image to a `u8` tensor of shape `(3, height, width)`.
+pub fn image_to_tensor(img: &DynamicImage) -> Result<Tensor> {
+    let (height, width) = (img.height() as usize, img.width() as usize);
+    let data = img.to_rgb8().into_raw();
+    Tensor::from_vec(data, (height, width, 3), &Device::Cpu)?.permute((2, 0, 1))
+}
+
+/// Converts a tensor to an image. The tensor can have shape `(3, height, width)` for rgb
+/// images, or `(1, height, width)` and `(height, width)` for grayscale images.
+///
+/// `u8` tensors are used as is, other dtypes are expected to hold values in `[0, 1]`. These
+/// values are clamped and scaled to `[0, 255]` so that out of range values, e.g. from a diffusion
+/// model, do not wrap around.
+pub fn tensor_to_image(img: &Tensor) -> Result<DynamicImage> {
+    let (channels, height, width) = match img.dims() {
+        &[h, w] => (1, h, w),
+        &[c, h, w] => (c, h, w),
+        dims => candle::bail!("expected a (channels, height, width) tensor, got {dims:?}"),
+    };
+    let img = match img.dtype() {
+        DType::U8 => img.clone(),
+        _ => (img.to_dtype(DType::F32)?.clamp(0f32, 1f32)? * 255.)?
+            .round()?
+            .to_dtype(DType::U8)?,
+    };
+    let pixels = img
+        .reshape((channels, height, width))?
+        .permute((1, 2, 0))?
+        .flatten_all()?
+        .to_vec1::<u8>()?;
+    let (width, height) = (width as u32, height as u32);
+    let img = match channels {
+        1 => image::GrayImage::from_raw(width, height, pixels).map(DynamicImage::from),
+        3 => image::RgbImage::from_raw(width, height, pixels).map(DynamicImage::from),
+        c => candle::bail!("expected 1 or 3 channels, got {c}"),
+    };
+    match img {
+        Some(img) => Ok(img),
+        None => candle::bail!("unexpected number of pixels for a {width}x{height} image"),
+    }
+}
+
+/// Loads images as tensors, optionally resizing them to a common size.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageLoader {
+    size: Option<(usize, usize)>,
+    resize: Resize,
+    filter: FilterType,
+    exif_orientation: bool,
+}
+
+impl Default for ImageLoader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ImageLoader {
+    /// A loader that keeps the original image size and applies the EXIF orientation.
+    pub fn new() -> Self {
+        Self {
+            size: None,
+            resize: Resize::Fill(Crop::Center),
+            filter: FilterType::Triangle,
+            exif_orientation: true,
+        }
+    }
+
+    /// The size of the returned images, this is required for batch loading.
+    pub fn with_size(mut self, height: usize, width: usize) -> Self {
+        self.size = Some((height, width));
+        self
+    }
+
+    /// How images are brought to the target size, defaults to [`Resize::Fill`] with a center
+    /// crop.
+    pub fn with_resize(mut self, resize: Resize) -> Self {
+        self.resize = resize;
+        self
+    }
+
+    /// The filter used when resizing, defaults to [`FilterType::Triangle`].
+    pub fn with_filter(mut self, filter: FilterType) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Whether to apply the orientation from the EXIF metadata, defaults to `true`.
+    pub fn with_exif_orientation(mut self, exif_orientation: bool) -> Self {
+        self.exif_orientation = exif_orientation;
+        self
+    }
+
+    /// Loads and resizes an image.
+    pub fn load_image<P: AsRef<Path>>(&self, p: P) -> Result<DynamicImage> {
+        let img = if self.exif_orientation {
+            open(p)?
+        } else {
+            let p = p.as_ref();
+            image::ImageReader::open(p)
+                .map_err(|e| candle::Error::from(e).with_path(p))?
+                .with_guessed_format()?
+                .decode()
+                .map_err(candle::Error::wrap)?
+        };
+        let img = match self.size {
+            None => img,
+            Some((height, width)) => resize(&img, width, height, self.resize, self.filter),
+        };
+        Ok(img)
+    }
+
+    /// Loads an image as a `u8` tensor of shape `(3, height, width)`.
+    pub fn load<P: AsRef<Path>>(&self, p: P) -> Result<Tensor> {
+        image_to_tensor(&self.load_image(p)?)
+    }
+
+    /// Loads multiple images as a `u8` tensor of shape `(batch, 3, height, width)`. This requires
+    /// a target size and a resize mode that produces images of this exact size.
+    pub fn load_batch<P: AsRef<Path>>(&self, ps: &[P]) -> Result<Tensor> {
+        let (height, width) = match self.size {
+            Some(size) => size,
+            None => candle::bail!("batch loading requires a target size, use with_size"),
+        };
+        let imgs = ps
+            .iter()
+            .map(|p| {
+                let p = p.as_ref();
+                let img = self.load(p)?;
+                let (_, h, w) = img.dims3()?;
+                if (h, w) != (height, width) {
+                    candle::bail!("{p:?} has size {h}x{w} after resizing, expected {height}x{width}, use Resize::Exact or Resize::Fill")
+                }
+                Ok(img)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::stack(&imgs, 0)
+    }
+}
+
+/// Loads an image as a `u8` tensor of shape `(3, height, width)`, optionally resizing it so that
+/// its longest side is `resize_longest`. This also returns the original height and width.
+pub fn load_image<P: AsRef<Path>>(
+    p: P,
+    resize_longest: Option<usize>,
+) -> Result<(Tensor, usize, usize)> {
+    let img = open(p)?;
+    let (initial_h, initial_w) = (img.height() as usize, img.width() as usize);
+    let img = match resize_longest {
+        None => img,
+        Some(resize_longest) => {
+            let (height, width) = (initial_h, initial_w);
+            let (height, width) = if height < width {
+                let h = (resize_longest * height) / width;
+                (h, resize_longest)
+            } else {
+                let w = (resize_longest * width) / height;
+                (resize_longest, w)
+            };
+            resize(&img, width, height, Resize::Exact, FilterType::CatmullRom)
+        }
+    };
+    Ok((image_to_tensor(&img)?, initial_h, initial_w))
+}
+
+/// Loads an image as a `u8` tensor of shape `(3, height, width)`, the image is resized to cover
+/// the target size and center cropped.
+pub fn load_image_and_resize<P: AsRef<Path>>(p: P, width: usize, height: usize) -> Result<Tensor> {
+    ImageLoader::new().with_size(height, width).load(p)
+}
+
+/// Saves a tensor as an image, the format is derived from the file extension. See
+/// [`tensor_to_image`] for the supported shapes and dtypes.
+pub fn save_image<P: AsRef<Path>>(img: &Tensor, p: P) -> Result<()> {
+    let p = p.as_ref();
+    // Only 8-bit rgb and grayscale images are produced, these are supported by all the formats.
+    let img = tensor_to_image(img)?;
+    img.save(p)
+        .map_err(|e| candle::Error::wrap(e).with_path(p))?;
+    Ok(())
+}
+
+/// Saves a tensor as an image after resizing it to cover `h`x`w` and center cropping it.
+pub fn save_image_resize<P: AsRef<Path>>(img: &Tensor, p: P, h: usize, w: usize) -> Result<()> {
+    let p = p.as_ref();
+    let img = tensor_to_image(img)?;
+    let img = resize(
+        &img,
+        w,
+        h,
+        Resize::Fill(Crop::Center),
+        FilterType::CatmullRom,
+    );
+    img.save(p)
+        .map_err(|e| candle::Error::wrap(e).with_path(p))?;
+    Ok(())
+}
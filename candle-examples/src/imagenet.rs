@@ -13,17 +13,9 @@ pub fn load_image_with_std_mean<P: AsRef<std::path::Path>>(
     mean: &[f32; 3],
     std: &[f32; 3],
 ) -> Result<Tensor> {
-    let img = image::ImageReader::open(p)?
-        .decode()
-        .map_err(candle::Error::wrap)?
-        .resize_to_fill(
-            res as u32,
-            res as u32,
-            image::imageops::FilterType::Triangle,
-        );
-    let img = img.to_rgb8();
-    let data = img.into_raw();
-    let data = Tensor::from_vec(data, (res, res, 3), &Device::Cpu)?.permute((2, 0, 1))?;
+    let data = crate::image_io::ImageLoader::new()
+        .with_size(res, res)
+        .load(p)?;
     let mean = Tensor::new(mean, &Device::Cpu)?.reshape((3, 1, 1))?;
     let std = Tensor::new(std, &Device::Cpu)?.reshape((3, 1, 1))?;
     (data.to_dtype(candle::DType::F32)? / 255.)?
@@ -0,0 +1,170 @@
+//! Fill-in-the-middle generation for code models.
+//!
+//! Code models trained for infilling generate the code between a prefix and a suffix, e.g. the
+//! code before and after the cursor of an editor. The prompt arranges the prefix and the suffix
+//! around special tokens that differ between model families, [`FimFormat`] describes these
+//! arrangements and [`infill`] runs the generation.
+use candle::{Device, Result};
+use candle_transformers::generation::ensemble::CausalLM;
+use candle_transformers::generation::events::{generate, GenerationConfig, GenerationEvents};
+use candle_transformers::generation::LogitsProcessor;
+use tokenizers::Tokenizer;
+
+/// The special tokens used by a model family for infilling, the prompts use the
+/// prefix-suffix-middle order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FimFormat {
+    /// `<s>▁<PRE>{prefix}▁<SUF>{suffix}▁<MID>`, the 7b and 13b models only.
+    CodeLlama,
+    /// `<fim_prefix>{prefix}<fim_suffix>{suffix}<fim_middle>`, StarCoder and StarCoder2.
+    StarCoder,
+    /// `<｜begin▁of▁sentence｜><｜fim▁begin｜>{prefix}<｜fim▁hole｜>{suffix}<｜fim▁end｜>`.
+    DeepSeekCoder,
+}
+
+impl FimFormat {
+    pub const ALL: [Self; 3] = [Self::CodeLlama, Self::StarCoder, Self::DeepSeekCoder];
+
+    /// The beginning of sequence token the prompt starts with, if any.
+    pub fn bos_token(&self) -> Option<&'static str> {
+        match self {
+            Self::CodeLlama => Some("<s>"),
+            Self::StarCoder => None,
+            Self::DeepSeekCoder => Some("<｜begin▁of▁sentence｜>"),
+        }
+    }
+
+    /// The tokens placed before the prefix, before the suffix, and before the generated middle.
+    pub fn fim_tokens(&self) -> [&'static str; 3] {
+        match self {
+            Self::CodeLlama => ["▁<PRE>", "▁<SUF>", "▁<MID>"],
+            Self::StarCoder => ["<fim_prefix>", "<fim_suffix>", "<fim_middle>"],
+            Self::DeepSeekCoder => ["<｜fim▁begin｜>", "<｜fim▁hole｜>", "<｜fim▁end｜>"],
+        }
+    }
+
+    /// The tokens ending the generated middle, the ones missing from a tokenizer are ignored.
+    pub fn stop_tokens(&self) -> &'static [&'static str] {
+        match self {
+            Self::CodeLlama => &["▁<EOT>", "</s>"],
+            Self::StarCoder => &["<|endoftext|>", "<file_sep>"],
+            Self::DeepSeekCoder => &["<｜end▁of▁sentence｜>", "<|EOT|>"],
+        }
+    }
+
+    /// Returns the format whose infilling tokens are in the vocabulary of `tokenizer`.
+    pub fn detect(tokenizer: &Tokenizer) -> Option<Self> {
+        Self::ALL.into_iter().find(|format| {
+            format
+                .fim_tokens()
+                .iter()
+                .all(|t| tokenizer.token_to_id(t).is_some())
+        })
+    }
+}
+
+impl std::str::FromStr for FimFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "codellama" => Ok(Self::CodeLlama),
+            "starcoder" => Ok(Self::StarCoder),
+            "deepseek-coder" => Ok(Self::DeepSeekCoder),
+            _ => Err(format!(
+                "unknown infill format {s}, use codellama, starcoder or deepseek-coder"
+            )),
+        }
+    }
+}
+
+fn token_id(tokenizer: &Tokenizer, format: FimFormat, token: &str) -> Result<u32> {
+    match tokenizer.token_to_id(token) {
+        Some(id) => Ok(id),
+        None => candle::bail!("the tokenizer has no {token} token, is it a {format:?} model?"),
+    }
+}
+
+/// The code before and after the part to generate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FimPrompt {
+    pub format: FimFormat,
+    pub prefix: String,
+    pub suffix: String,
+}
+
+impl FimPrompt {
+    pub fn new(format: FimFormat, prefix: &str, suffix: &str) -> Self {
+        Self {
+            format,
+            prefix: prefix.to_string(),
+            suffix: suffix.to_string(),
+        }
+    }
+
+    /// Encodes the prompt, the prefix and the suffix are encoded without the special tokens so
+    /// that a template-like text in the code is not turned into control tokens.
+    pub fn encode(&self, tokenizer: &Tokenizer) -> Result<Vec<u32>> {
+        let [prefix_token, suffix_token, middle_token] = self.format.fim_tokens();
+        let encode = |text: &str| {
+            let encoding = tokenizer
+                .encode(text, false)
+                .map_err(|e| candle::Error::Msg(e.to_string()))?;
+            Ok::<_, candle::Error>(encoding.get_ids().to_vec())
+        };
+        let mut tokens = vec![];
+        if let Some(bos) = self.format.bos_token() {
+            tokens.push(token_id(tokenizer, self.format, bos)?)
+        }
+        tokens.push(token_id(tokenizer, self.format, prefix_token)?);
+        tokens.extend(encode(&self.prefix)?);
+        tokens.push(token_id(tokenizer, self.format, suffix_token)?);
+        tokens.extend(encode(&self.suffix)?);
+        tokens.push(token_id(tokenizer, self.format, middle_token)?);
+        Ok(tokens)
+    }
+
+    /// The ids of the stop tokens of the format that are in the vocabulary of `tokenizer`.
+    pub fn stop_tokens(&self, tokenizer: &Tokenizer) -> Vec<u32> {
+        self.format
+            .stop_tokens()
+            .iter()
+            .filter_map(|t| tokenizer.token_to_id(t))
+            .collect()
+    }
+}
+
+/// Generates the code between the prefix and the suffix of `prompt` and returns it decoded.
+///
+/// The stop tokens of the format are added to the ones of `config` and are not part of the
+/// result. `model` should use a fresh kv cache, see [`generate`].
+pub fn infill<M: CausalLM, E: GenerationEvents>(
+    model: &mut M,
+    tokenizer: &Tokenizer,
+    prompt: &FimPrompt,
+    logits_processor: &mut LogitsProcessor,
+    device: &Device,
+    config: &GenerationConfig,
+    events: E,
+) -> Result<String> {
+    let prompt_tokens = prompt.encode(tokenizer)?;
+    let mut config = config.clone();
+    config.stop_tokens.extend(prompt.stop_tokens(tokenizer));
+    let mut tokens = generate(
+        model,
+        &prompt_tokens,
+        logits_processor,
+        device,
+        &config,
+        events,
+    )?;
+    if tokens
+        .last()
+        .is_some_and(|t| config.stop_tokens.contains(t))
+    {
+        tokens.pop();
+    }
+    tokenizer
+        .decode(&tokens, true)
+        .map_err(|e| candle::Error::Msg(e.to_string()))
+}
@@ -0,0 +1,75 @@
+//! Encoding of the prompts with control over the special tokens.
+//!
+//! The tokenizers of most models add a beginning of sequence token when encoding with
+//! `add_special_tokens`, while some chat templates already start with this token. Encoding such a
+//! prompt with the special tokens results in two BOS tokens, the models do not fail on these but
+//! their outputs silently degrade. [`encode_prompt`] only adds the special tokens when the prompt
+//! does not already start with the BOS token, and [`encode_segments`] controls them per segment.
+use candle::Result;
+use tokenizers::Tokenizer;
+
+/// A part of a prompt, encoded on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Segment<'a> {
+    pub text: &'a str,
+    /// Whether the tokenizer adds its special tokens, e.g. BOS, to this segment.
+    pub add_special_tokens: bool,
+}
+
+impl<'a> Segment<'a> {
+    /// A segment encoded without the special tokens.
+    pub fn text(text: &'a str) -> Self {
+        Self {
+            text,
+            add_special_tokens: false,
+        }
+    }
+
+    /// A segment encoded with the special tokens of the tokenizer.
+    pub fn with_special_tokens(text: &'a str) -> Self {
+        Self {
+            text,
+            add_special_tokens: true,
+        }
+    }
+}
+
+fn encode(tokenizer: &Tokenizer, text: &str, add_special_tokens: bool) -> Result<Vec<u32>> {
+    let encoding = tokenizer
+        .encode(text, add_special_tokens)
+        .map_err(|e| candle::Error::Msg(e.to_string()))?;
+    Ok(encoding.get_ids().to_vec())
+}
+
+/// Encodes the segments one after the other and concatenates their tokens. As each segment is
+/// encoded separately, sentencepiece tokenizers add their leading space to every segment.
+pub fn encode_segments(tokenizer: &Tokenizer, segments: &[Segment]) -> Result<Vec<u32>> {
+    let mut tokens = vec![];
+    for segment in segments.iter() {
+        tokens.extend(encode(tokenizer, segment.text, segment.add_special_tokens)?)
+    }
+    Ok(tokens)
+}
+
+/// Removes the repetitions of `bos` at the start of `tokens`, returns the number of removed
+/// tokens.
+pub fn dedup_bos(tokens: &mut Vec<u32>, bos: u32) -> usize {
+    let repeated = tokens.iter().take_while(|&&t| t == bos).count();
+    if repeated > 1 {
+        tokens.drain(..repeated - 1);
+        repeated - 1
+    } else {
+        0
+    }
+}
+
+/// Encodes a prompt, e.g. formatted with a chat template. The special tokens are only added when
+/// the prompt does not already start with `bos_token`, and a leading BOS is never repeated.
+pub fn encode_prompt(tokenizer: &Tokenizer, prompt: &str, bos_token: &str) -> Result<Vec<u32>> {
+    let add_special_tokens = bos_token.is_empty() || !prompt.starts_with(bos_token);
+    let mut tokens = encode(tokenizer, prompt, add_special_tokens)?;
+    if let Some(bos) = tokenizer.token_to_id(bos_token) {
+        dedup_bos(&mut tokens, bos);
+    }
+    Ok(tokens)
+}
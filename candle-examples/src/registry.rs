@@ -0,0 +1,198 @@
+//! A data driven registry of the models used by the examples.
+//!
+//! The models are described in json manifests, see `candle-examples/registry`, listing for each
+//! model the architecture, where to find the weights and the tokenizer, the chat template and the
+//! end of sequence token. Adding a model to an example only requires a new manifest entry, either
+//! in the manifest bundled with the example or in a user provided file.
+use crate::hub::Downloader;
+use candle::Result;
+use std::path::PathBuf;
+
+fn default_revision() -> String {
+    "main".to_string()
+}
+
+fn default_tokenizer_file() -> String {
+    "tokenizer.json".to_string()
+}
+
+fn default_bos_token() -> String {
+    "<s>".to_string()
+}
+
+fn default_eos_token() -> String {
+    "</s>".to_string()
+}
+
+fn default_gqa() -> usize {
+    1
+}
+
+#[derive(Debug, Clone, serde::Deserialize, serde::Serialize)]
+pub struct ModelEntry {
+    /// The name used to select the model on the command line.
+    pub name: String,
+    /// The architecture, used by the examples that support multiple model implementations.
+    pub arch: String,
+    pub repo: String,
+    #[serde(default = "default_revision")]
+    pub revision: String,
+    /// The weight file within the repo.
+    pub filename: String,
+    /// The repo to get the tokenizer from, defaults to `repo`.
+    #[serde(default)]
+    pub tokenizer_repo: Option<String>,
+    #[serde(default = "default_tokenizer_file")]
+    pub tokenizer_file: String,
+    /// The template applied to the user prompts in chat mode, `{prompt}` is replaced by the
+    /// prompt. Without a template, the prompts are used as is.
+    #[serde(default)]
+    pub chat_template: Option<String>,
+    /// The template used for the first turn of a conversation, defaults to `chat_template`. It
+    /// can include the system message using `{system}`.
+    #[serde(default)]
+    pub first_chat_template: Option<String>,
+    /// How a system prompt is formatted, `{system}` is replaced by the system prompt. The result
+    /// replaces `{system}` in the first turn template, or is prepended to the first turn if the
+    /// template does not include it. Defaults to the system prompt followed by an empty line.
+    #[serde(default)]
+    pub system_template: Option<String>,
+    /// The beginning of sequence token, it is not added again when a formatted prompt already
+    /// starts with it.
+    #[serde(default = "default_bos_token")]
+    pub bos_token: String,
+    #[serde(default = "default_eos_token")]
+    pub eos_token: String,
+    /// The number of query heads per key/value head, only used for ggml files that do not
+    /// include this information.
+    #[serde(default = "default_gqa")]
+    pub gqa: usize,
+}
+
+impl ModelEntry {
+    fn repo(&self, repo: &str, revision: &str) -> hf_hub::Repo {
+        hf_hub::Repo::with_revision(
+            repo.to_string(),
+            hf_hub::RepoType::Model,
+            revision.to_string(),
+        )
+    }
+
+    /// Returns the path to the weight file, downloading it if needed. For gguf files split in
+    /// multiple parts, all the parts are downloaded and the path to the first one is returned.
+    pub fn weights(&self, downloader: &Downloader) -> Result<PathBuf> {
+        let repo = downloader.repo(self.repo(&self.repo, &self.revision));
+        let files = candle::quantized::gguf_file::split_file_names(&self.filename)
+            .iter()
+            .map(|f| repo.get(f))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(files[0].clone())
+    }
+
+    /// Returns the path to the tokenizer file, downloading it if needed.
+    pub fn tokenizer(&self, downloader: &Downloader) -> Result<PathBuf> {
+        let repo = match &self.tokenizer_repo {
+            Some(repo) => self.repo(repo, "main"),
+            None => self.repo(&self.repo, &self.revision),
+        };
+        downloader.repo(repo).get(&self.tokenizer_file)
+    }
+
+    /// Applies the chat template to a user prompt, the system prompt is only used on the first
+    /// turn of a conversation.
+    pub fn format_prompt(&self, prompt: &str, first_turn: bool, system: Option<&str>) -> String {
+        let template = if first_turn {
+            self.first_chat_template
+                .as_ref()
+                .or(self.chat_template.as_ref())
+        } else {
+            self.chat_template.as_ref()
+        };
+        let system = match system {
+            Some(system) if first_turn => {
+                let template = self.system_template.as_deref().unwrap_or("{system}\n\n");
+                fill_template(template, &[("system", system)])
+            }
+            _ => String::new(),
+        };
+        match template {
+            Some(template) if template.contains("{system}") => {
+                fill_template(template, &[("system", &system), ("prompt", prompt)])
+            }
+            Some(template) => system + &fill_template(template, &[("prompt", prompt)]),
+            None => system + prompt,
+        }
+    }
+
+    /// Encodes a formatted prompt, adding the BOS token unless the prompt already starts with it.
+    pub fn encode(&self, tokenizer: &tokenizers::Tokenizer, prompt: &str) -> Result<Vec<u32>> {
+        crate::prompt::encode_prompt(tokenizer, prompt, &self.bos_token)
+    }
+}
+
+/// Replaces the `{name}` placeholders of `template` in a single pass, so that the substituted
+/// values are never themselves expanded.
+fn fill_template(template: &str, values: &[(&str, &str)]) -> String {
+    let mut result = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let value = values.iter().find(|(name, _)| {
+            rest[1..].starts_with(name) && rest[1 + name.len()..].starts_with('}')
+        });
+        match value {
+            Some((name, value)) => {
+                result.push_str(value);
+                rest = &rest[name.len() + 2..]
+            }
+            None => {
+                result.push('{');
+                rest = &rest[1..]
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+#[derive(Debug, Clone, Default, serde::Deserialize, serde::Serialize)]
+pub struct Registry {
+    pub models: Vec<ModelEntry>,
+}
+
+impl Registry {
+    pub fn from_json(json: &str) -> Result<Self> {
+        serde_json::from_str(json).map_err(candle::Error::wrap)
+    }
+
+    pub fn load<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        let json = std::fs::read_to_string(p)?;
+        Self::from_json(&json).map_err(|e| e.with_path(p))
+    }
+
+    /// Adds the models from `other`, replacing the entries that have the same name.
+    pub fn extend(&mut self, other: Self) {
+        for model in other.models {
+            match self.models.iter_mut().find(|m| m.name == model.name) {
+                Some(m) => *m = model,
+                None => self.models.push(model),
+            }
+        }
+    }
+
+    pub fn names(&self) -> Vec<&str> {
+        self.models.iter().map(|m| m.name.as_str()).collect()
+    }
+
+    pub fn get(&self, name: &str) -> Result<&ModelEntry> {
+        match self.models.iter().find(|m| m.name == name) {
+            Some(model) => Ok(model),
+            None => candle::bail!(
+                "unknown model {name}, available models: {}",
+                self.names().join(", ")
+            ),
+        }
+    }
+}
@@ -0,0 +1,128 @@
+//! Input handling for the interactive and chat modes of the examples.
+//!
+//! A prompt can span multiple lines, either by ending lines with a backslash or by using a
+//! heredoc: a `<<END` line starts a block that runs until a line containing only `END`. Lines
+//! starting with a slash are commands, see [`HELP`].
+use std::io::{BufRead, Write};
+use std::path::PathBuf;
+
+pub const HELP: &str = "\
+/load <file>  include the content of a file before the next prompt
+/save <file>  save the conversation to a file
+/reset        start a new conversation
+/help         show this message
+/exit         quit, ctrl-d also works
+end a line with \\ to continue the prompt on the next line, or use <<END to enter lines until END";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Prompt(String),
+    Save(PathBuf),
+    Reset,
+    Exit,
+}
+
+/// Reads the user inputs, the `/load` and `/help` commands are handled here and never returned.
+pub struct Repl<R> {
+    reader: R,
+    loaded: Vec<String>,
+}
+
+impl Repl<std::io::StdinLock<'static>> {
+    pub fn stdin() -> Self {
+        Self::new(std::io::stdin().lock())
+    }
+}
+
+impl<R: BufRead> Repl<R> {
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader,
+            loaded: vec![],
+        }
+    }
+
+    fn read_line(&mut self, prompt: &str) -> std::io::Result<Option<String>> {
+        print!("{prompt}");
+        std::io::stdout().flush()?;
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if line.ends_with('\n') {
+            line.pop();
+            if line.ends_with('\r') {
+                line.pop();
+            }
+        }
+        Ok(Some(line))
+    }
+
+    /// Reads the next input, returns [`Input::Exit`] at the end of the input stream.
+    pub fn next_input(&mut self) -> std::io::Result<Input> {
+        loop {
+            let line = match self.read_line("> ")? {
+                None => return Ok(Input::Exit),
+                Some(line) => line,
+            };
+            if let Some(command) = line.strip_prefix('/') {
+                let (command, arg) = match command.split_once(' ') {
+                    Some((command, arg)) => (command, arg.trim()),
+                    None => (command, ""),
+                };
+                match (command, arg) {
+                    ("exit" | "quit", _) => return Ok(Input::Exit),
+                    ("reset", _) => {
+                        self.loaded.clear();
+                        return Ok(Input::Reset);
+                    }
+                    ("save", file) if !file.is_empty() => return Ok(Input::Save(file.into())),
+                    ("load", file) if !file.is_empty() => match std::fs::read_to_string(file) {
+                        Ok(content) => {
+                            println!("loaded {file} ({} bytes)", content.len());
+                            self.loaded.push(content)
+                        }
+                        Err(err) => println!("cannot load {file}: {err}"),
+                    },
+                    ("help", _) => println!("{HELP}"),
+                    _ => println!("unknown command /{command}, use /help to list the commands"),
+                }
+                continue;
+            }
+            let prompt = match self.read_multiline(line)? {
+                None => return Ok(Input::Exit),
+                Some(prompt) => prompt,
+            };
+            if prompt.trim().is_empty() && self.loaded.is_empty() {
+                continue;
+            }
+            let mut parts = std::mem::take(&mut self.loaded);
+            parts.push(prompt);
+            return Ok(Input::Prompt(parts.join("\n")));
+        }
+    }
+
+    fn read_multiline(&mut self, first_line: String) -> std::io::Result<Option<String>> {
+        if let Some(end) = first_line.strip_prefix("<<").map(|s| s.trim().to_string()) {
+            let mut lines = vec![];
+            loop {
+                match self.read_line("")? {
+                    None => return Ok(None),
+                    Some(line) if line == end => return Ok(Some(lines.join("\n"))),
+                    Some(line) => lines.push(line),
+                }
+            }
+        }
+        let mut lines = vec![];
+        let mut line = first_line;
+        while let Some(l) = line.strip_suffix('\\') {
+            lines.push(l.to_string());
+            line = match self.read_line(". ")? {
+                None => return Ok(None),
+                Some(line) => line,
+            };
+        }
+        lines.push(line);
+        Ok(Some(lines.join("\n")))
+    }
+}
@@ -0,0 +1,193 @@
+//! Decoding video frames as tensors using ffmpeg, this requires the `video` feature and the
+//! ffmpeg libraries to be installed.
+//!
+//! Frames are sampled at a target frame rate and returned as `u8` tensors of shape
+//! `(3, height, width)`, the same layout as the images returned by [`crate::image_io`].
+//!
+//! ```no_run
+//! use candle_examples::video::VideoReader;
+//! # fn main() -> candle::Result<()> {
+//! // Sample 16 frames at 4 frames per second, resized to 224x224, e.g. for video classification.
+//! let mut reader = VideoReader::new("video.mp4")?
+//!     .with_fps(4.)
+//!     .with_size(224, 224);
+//! let frames = reader.read_frames(Some(16))?;
+//! // frames has shape (16, 3, 224, 224).
+//! # Ok(())
+//! # }
+//! ```
+use candle::{Device, Result, Tensor};
+use ffmpeg_next as ffmpeg;
+
+fn wrap(err: ffmpeg::Error) -> candle::Error {
+    candle::Error::wrap(err)
+}
+
+/// Decodes the frames of the best video stream of a file.
+pub struct VideoReader {
+    input: ffmpeg::format::context::Input,
+    decoder: ffmpeg::decoder::Video,
+    stream_index: usize,
+    time_base: f64,
+    avg_fps: f64,
+    scaler: Option<ffmpeg::software::scaling::Context>,
+    size: Option<(usize, usize)>,
+    fps: Option<f64>,
+    // The timestamp in seconds of the next frame to return when sampling at a target fps.
+    next_ts: f64,
+    decoded_frames: usize,
+    eof: bool,
+}
+
+impl VideoReader {
+    pub fn new<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
+        ffmpeg::init().map_err(wrap)?;
+        let p = p.as_ref();
+        let input = ffmpeg::format::input(p).map_err(|e| wrap(e).with_path(p))?;
+        let stream = match input.streams().best(ffmpeg::media::Type::Video) {
+            Some(stream) => stream,
+            None => candle::bail!("no video stream in {p:?}"),
+        };
+        let stream_index = stream.index();
+        let time_base = f64::from(stream.time_base());
+        let avg_fps = f64::from(stream.avg_frame_rate());
+        let context =
+            ffmpeg::codec::context::Context::from_parameters(stream.parameters()).map_err(wrap)?;
+        let decoder = context.decoder().video().map_err(wrap)?;
+        Ok(Self {
+            input,
+            decoder,
+            stream_index,
+            time_base,
+            avg_fps,
+            scaler: None,
+            size: None,
+            fps: None,
+            next_ts: 0.,
+            decoded_frames: 0,
+            eof: false,
+        })
+    }
+
+    /// Only return frames at the given rate rather than all the decoded frames.
+    pub fn with_fps(mut self, fps: f64) -> Self {
+        self.fps = Some(fps);
+        self
+    }
+
+    /// Resize the frames to `height`x`width`, the aspect ratio is not preserved.
+    pub fn with_size(mut self, height: usize, width: usize) -> Self {
+        self.size = Some((height, width));
+        self.scaler = None;
+        self
+    }
+
+    /// The height and width of the frames in the video.
+    pub fn dims(&self) -> (usize, usize) {
+        (
+            self.decoder.height() as usize,
+            self.decoder.width() as usize,
+        )
+    }
+
+    /// The average frame rate of the video stream.
+    pub fn avg_fps(&self) -> f64 {
+        self.avg_fps
+    }
+
+    /// Returns the next sampled frame as a tensor of shape `(3, height, width)` together with
+    /// its timestamp in seconds, `None` is returned at the end of the stream.
+    pub fn next_frame(&mut self) -> Result<Option<(Tensor, f64)>> {
+        let mut decoded = ffmpeg::frame::Video::empty();
+        loop {
+            if self.decoder.receive_frame(&mut decoded).is_ok() {
+                let ts = match decoded.timestamp() {
+                    Some(ts) => ts as f64 * self.time_base,
+                    None if self.avg_fps > 0. => self.decoded_frames as f64 / self.avg_fps,
+                    None => self.decoded_frames as f64,
+                };
+                self.decoded_frames += 1;
+                if let Some(fps) = self.fps {
+                    if ts < self.next_ts {
+                        continue;
+                    }
+                    // Skip ahead rather than returning a burst of frames after a gap.
+                    while self.next_ts <= ts {
+                        self.next_ts += 1. / fps
+                    }
+                }
+                return Ok(Some((self.to_tensor(&decoded)?, ts)));
+            }
+            if self.eof {
+                return Ok(None);
+            }
+            match self.input.packets().next() {
+                Some((stream, packet)) => {
+                    if stream.index() == self.stream_index {
+                        self.decoder.send_packet(&packet).map_err(wrap)?
+                    }
+                }
+                None => {
+                    self.decoder.send_eof().map_err(wrap)?;
+                    self.eof = true
+                }
+            }
+        }
+    }
+
+    fn to_tensor(&mut self, frame: &ffmpeg::frame::Video) -> Result<Tensor> {
+        let (height, width) = self
+            .size
+            .unwrap_or((frame.height() as usize, frame.width() as usize));
+        let scaler = match &mut self.scaler {
+            Some(scaler) => scaler,
+            None => {
+                let scaler = ffmpeg::software::scaling::Context::get(
+                    frame.format(),
+                    frame.width(),
+                    frame.height(),
+                    ffmpeg::format::Pixel::RGB24,
+                    width as u32,
+                    height as u32,
+                    ffmpeg::software::scaling::Flags::BILINEAR,
+                )
+                .map_err(wrap)?;
+                self.scaler.insert(scaler)
+            }
+        };
+        let mut rgb = ffmpeg::frame::Video::empty();
+        scaler.run(frame, &mut rgb).map_err(wrap)?;
+        // Rows can be padded for alignment.
+        let stride = rgb.stride(0);
+        let data = rgb.data(0);
+        let mut pixels = Vec::with_capacity(height * width * 3);
+        for row in 0..height {
+            pixels.extend_from_slice(&data[row * stride..row * stride + width * 3])
+        }
+        Tensor::from_vec(pixels, (height, width, 3), &Device::Cpu)?.permute((2, 0, 1))
+    }
+
+    /// Reads up to `max_frames` frames, or all the remaining ones if `None`, as a `u8` tensor of
+    /// shape `(frames, 3, height, width)`.
+    pub fn read_frames(&mut self, max_frames: Option<usize>) -> Result<Tensor> {
+        let mut frames = vec![];
+        while let Some((frame, _ts)) = self.next_frame()? {
+            frames.push(frame);
+            if max_frames == Some(frames.len()) {
+                break;
+            }
+        }
+        if frames.is_empty() {
+            candle::bail!("no frames decoded")
+        }
+        Tensor::stack(&frames, 0)
+    }
+}
+
+impl Iterator for VideoReader {
+    type Item = Result<Tensor>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_frame().map(|f| f.map(|(f, _)| f)).transpose()
+    }
+}
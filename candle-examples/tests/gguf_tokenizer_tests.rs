@@ -0,0 +1,87 @@
+use candle::quantized::gguf_file::{self, Value};
+use candle::Result;
+use candle_examples::gguf_tokenizer::from_gguf;
+
+fn strings(values: &[&str]) -> Value {
+    Value::Array(
+        values
+            .iter()
+            .map(|v| Value::String(v.to_string()))
+            .collect(),
+    )
+}
+
+/// The metadata of a tiny llama 3 like tokenizer, `Ġ` being the byte level encoding of a space.
+fn metadata(pre: &str) -> Vec<(&'static str, Value)> {
+    let tokens = [
+        "<|begin_of_text|>",
+        "<|eot_id|>",
+        "h",
+        "e",
+        "l",
+        "o",
+        "Ġ",
+        "w",
+        "r",
+        "d",
+        "he",
+        "ll",
+        "hell",
+        "hello",
+        "Ġw",
+        "or",
+    ];
+    let mut token_types = vec![Value::I32(3), Value::I32(3)];
+    token_types.resize(tokens.len(), Value::I32(1));
+    vec![
+        ("tokenizer.ggml.model", Value::String("gpt2".to_string())),
+        ("tokenizer.ggml.pre", Value::String(pre.to_string())),
+        ("tokenizer.ggml.tokens", strings(&tokens)),
+        ("tokenizer.ggml.token_type", Value::Array(token_types)),
+        (
+            "tokenizer.ggml.merges",
+            strings(&["h e", "l l", "he ll", "hell o", "Ġ w", "o r"]),
+        ),
+        ("tokenizer.ggml.bos_token_id", Value::U32(0)),
+    ]
+}
+
+fn read(metadata: &[(&str, Value)]) -> Result<gguf_file::Content> {
+    let metadata = metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    let mut buffer = std::io::Cursor::new(vec![]);
+    gguf_file::write(&mut buffer, &metadata, &[])?;
+    gguf_file::Content::read(&mut std::io::Cursor::new(buffer.into_inner()))
+}
+
+#[test]
+fn llama3_tokenizer() -> Result<()> {
+    let tokenizer = from_gguf(&read(&metadata("llama-bpe"))?)?;
+    let encoding = tokenizer
+        .encode("hello world<|eot_id|>", true)
+        .map_err(|e| candle::Error::Msg(e.to_string()))?;
+    assert_eq!(encoding.get_ids(), [0, 13, 14, 15, 4, 9, 1]);
+    assert_eq!(tokenizer.token_to_id("<|eot_id|>"), Some(1));
+    let decoded = tokenizer
+        .decode(encoding.get_ids(), true)
+        .map_err(|e| candle::Error::Msg(e.to_string()))?;
+    assert_eq!(decoded, "hello world");
+
+    // The BOS token is not added when disabled in the metadata.
+    let mut metadata = metadata("llama-bpe");
+    metadata.push(("tokenizer.ggml.add_bos_token", Value::Bool(false)));
+    let tokenizer = from_gguf(&read(&metadata)?)?;
+    let encoding = tokenizer
+        .encode("hello", true)
+        .map_err(|e| candle::Error::Msg(e.to_string()))?;
+    assert_eq!(encoding.get_ids(), [13]);
+    Ok(())
+}
+
+#[test]
+fn unsupported_tokenizers() -> Result<()> {
+    assert!(from_gguf(&read(&metadata("qwen2"))?).is_err());
+    let mut metadata = metadata("default");
+    metadata[0].1 = Value::String("llama".to_string());
+    assert!(from_gguf(&read(&metadata)?).is_err());
+    Ok(())
+}
@@ -0,0 +1,172 @@
+use candle::Result;
+use candle_examples::chat::{ChatSession, Role};
+use candle_examples::prompt::{dedup_bos, encode_prompt, encode_segments, Segment};
+use candle_examples::registry::{ModelEntry, Registry};
+use tokenizers::Tokenizer;
+
+/// Splits `text` in the words of a whitespace tokenizer, the special tokens being split on their
+/// own first.
+fn words(text: &str, special_tokens: &[&str]) -> Vec<String> {
+    let mut words = vec![];
+    let mut rest = text;
+    loop {
+        let next = special_tokens
+            .iter()
+            .filter_map(|&s| rest.find(s).map(|i| (i, s)))
+            .min_by_key(|&(i, s)| (i, std::cmp::Reverse(s.len())));
+        match next {
+            Some((i, special)) => {
+                words.extend(rest[..i].split_whitespace().map(String::from));
+                words.push(special.to_string());
+                rest = &rest[i + special.len()..]
+            }
+            None => {
+                words.extend(rest.split_whitespace().map(String::from));
+                return words;
+            }
+        }
+    }
+}
+
+/// A word level tokenizer covering `texts`, adding `bos` in front of the encoded sequences like
+/// the tokenizers of the registry models.
+fn tokenizer(bos: &str, eos: &str, texts: &[&str]) -> Result<Tokenizer> {
+    let mut vocab = vec!["[UNK]".to_string(), bos.to_string(), eos.to_string()];
+    for text in texts {
+        for word in words(text, &[bos, eos]) {
+            if !vocab.contains(&word) {
+                vocab.push(word)
+            }
+        }
+    }
+    let vocab = vocab
+        .iter()
+        .enumerate()
+        .map(|(id, w)| (w.clone(), serde_json::json!(id)))
+        .collect::<serde_json::Map<_, _>>();
+    let added_token = |id: usize, content: &str| {
+        serde_json::json!({
+            "id": id, "content": content, "single_word": false, "lstrip": false,
+            "rstrip": false, "normalized": false, "special": true,
+        })
+    };
+    let json = serde_json::json!({
+        "version": "1.0",
+        "truncation": null,
+        "padding": null,
+        "added_tokens": [added_token(1, bos), added_token(2, eos)],
+        "normalizer": null,
+        "pre_tokenizer": { "type": "WhitespaceSplit" },
+        "post_processor": {
+            "type": "TemplateProcessing",
+            "single": [
+                { "SpecialToken": { "id": bos, "type_id": 0 } },
+                { "Sequence": { "id": "A", "type_id": 0 } },
+            ],
+            "pair": [
+                { "SpecialToken": { "id": bos, "type_id": 0 } },
+                { "Sequence": { "id": "A", "type_id": 0 } },
+                { "Sequence": { "id": "B", "type_id": 1 } },
+            ],
+            "special_tokens": { bos: { "id": bos, "ids": [1], "tokens": [bos] } },
+        },
+        "decoder": null,
+        "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "[UNK]" },
+    });
+    Tokenizer::from_bytes(json.to_string()).map_err(|e| candle::Error::Msg(e.to_string()))
+}
+
+fn session() -> ChatSession {
+    let mut session = ChatSession::new(Some("You are a helpful assistant.".to_string()));
+    session.push(Role::User, "What is the capital of France?".to_string());
+    session.push(Role::Assistant, "Paris.".to_string());
+    session.push(Role::User, "And of Italy?".to_string());
+    session
+}
+
+/// Encodes the session, checks that it starts with a single BOS and that decoding gives back the
+/// formatted conversation.
+fn round_trip(entry: &ModelEntry) -> Result<()> {
+    let session = session();
+    let text = session.format(entry);
+    let tokenizer = tokenizer(&entry.bos_token, &entry.eos_token, &[&text])?;
+    let bos = tokenizer.token_to_id(&entry.bos_token).unwrap();
+    let tokens = session.encode(entry, &tokenizer)?;
+    assert_eq!(tokens[0], bos, "{}", entry.name);
+    assert_eq!(
+        tokens.iter().filter(|&&t| t == bos).count(),
+        1,
+        "{}",
+        entry.name
+    );
+    assert!(!tokens.contains(&0), "{}", entry.name);
+    let decoded = tokenizer
+        .decode(&tokens, false)
+        .map_err(|e| candle::Error::Msg(e.to_string()))?;
+    let special_tokens = [entry.bos_token.as_str(), entry.eos_token.as_str()];
+    // As in `encode_prompt`, the BOS is only added when the text does not start with it.
+    let expected = if text.starts_with(entry.bos_token.as_str()) {
+        text.clone()
+    } else {
+        format!("{} {text}", entry.bos_token)
+    };
+    assert_eq!(
+        words(&decoded, &special_tokens),
+        words(&expected, &special_tokens),
+        "{}",
+        entry.name
+    );
+    Ok(())
+}
+
+#[test]
+fn registry_round_trip() -> Result<()> {
+    let registry = Registry::from_json(include_str!("../registry/quantized.json"))?;
+    for entry in registry.models.iter() {
+        round_trip(entry)?;
+        // The same templates with an explicit BOS must not result in a second one.
+        let mut entry = entry.clone();
+        let bos = entry.bos_token.clone();
+        let template = entry
+            .first_chat_template
+            .as_deref()
+            .or(entry.chat_template.as_deref())
+            .unwrap_or("{prompt}");
+        // Some templates, e.g. llama3, already start with the BOS.
+        let template = template.strip_prefix(bos.as_str()).unwrap_or(template);
+        let template = if template.contains("{system}") {
+            format!("{bos}{template}")
+        } else {
+            format!("{bos}{{system}}{template}")
+        };
+        entry.first_chat_template = Some(template);
+        round_trip(&entry)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn special_tokens_per_segment() -> Result<()> {
+    let tokenizer = tokenizer("<s>", "</s>", &["[INST] hello [/INST] world"])?;
+    let tokens = encode_segments(
+        &tokenizer,
+        &[
+            Segment::with_special_tokens("[INST] hello [/INST]"),
+            Segment::text("world </s>"),
+            Segment::text("[INST] hello"),
+        ],
+    )?;
+    assert_eq!(tokens, [1, 3, 4, 5, 6, 2, 3, 4]);
+
+    assert_eq!(encode_prompt(&tokenizer, "hello", "<s>")?, [1, 4]);
+    assert_eq!(encode_prompt(&tokenizer, "<s>hello", "<s>")?, [1, 4]);
+    assert_eq!(encode_prompt(&tokenizer, "<s> <s> hello", "<s>")?, [1, 4]);
+    // Without a BOS token, the special tokens are always added.
+    assert_eq!(encode_prompt(&tokenizer, "hello", "")?, [1, 4]);
+
+    let mut tokens = vec![1, 1, 1, 4, 1];
+    assert_eq!(dedup_bos(&mut tokens, 1), 2);
+    assert_eq!(tokens, [1, 4, 1]);
+    assert_eq!(dedup_bos(&mut tokens, 1), 0);
+    Ok(())
+}
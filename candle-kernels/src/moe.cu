@@ -0,0 +1,75 @@
+#include "cuda_utils.cuh"
+#include <stdint.h>
+
+#define GROUPED_MATMUL_TILE 16
+
+// Grouped matmul for mixture-of-experts layers. The rows of `xs` are split in consecutive
+// groups, the rows of group `g` being `offsets[g]..offsets[g + 1]`, and each group is
+// multiplied by its own weight matrix `ws[g]` of shape (out_dim, in_dim):
+//   dst[r, o] = sum_i xs[r, i] * ws[g, o, i]
+// All the groups are processed by a single launch, `blockIdx.z` being the group and
+// `blockIdx.y` the tile of rows within the group. The grid is sized for the largest group so
+// the blocks past the end of the smaller groups exit early. The accumulation is done in f32.
+template <typename T>
+__device__ void grouped_matmul(
+    const T *xs,
+    const T *ws,
+    T *dst,
+    const uint32_t *offsets,
+    const uint32_t in_dim,
+    const uint32_t out_dim) {
+  __shared__ float xs_tile[GROUPED_MATMUL_TILE][GROUPED_MATMUL_TILE + 1];
+  __shared__ float ws_tile[GROUPED_MATMUL_TILE][GROUPED_MATMUL_TILE + 1];
+
+  const uint32_t group = blockIdx.z;
+  const uint32_t start = offsets[group];
+  const uint32_t end = offsets[group + 1];
+  const uint32_t tile_row = start + blockIdx.y * GROUPED_MATMUL_TILE;
+  // This condition is uniform over the block so returning is fine w.r.t. __syncthreads.
+  if (tile_row >= end) {
+    return;
+  }
+  const uint32_t row = tile_row + threadIdx.y;
+  const uint32_t col = blockIdx.x * GROUPED_MATMUL_TILE + threadIdx.x;
+  // The output column whose weights are loaded by this thread.
+  const uint32_t w_row = blockIdx.x * GROUPED_MATMUL_TILE + threadIdx.y;
+  const T *w = ws + (size_t)group * out_dim * in_dim;
+
+  float acc = 0.0f;
+  for (uint32_t k0 = 0; k0 < in_dim; k0 += GROUPED_MATMUL_TILE) {
+    const uint32_t k = k0 + threadIdx.x;
+    xs_tile[threadIdx.y][threadIdx.x] =
+        (row < end && k < in_dim) ? static_cast<float>(xs[(size_t)row * in_dim + k]) : 0.0f;
+    ws_tile[threadIdx.y][threadIdx.x] =
+        (w_row < out_dim && k < in_dim) ? static_cast<float>(w[(size_t)w_row * in_dim + k]) : 0.0f;
+    __syncthreads();
+    for (uint32_t i = 0; i < GROUPED_MATMUL_TILE; ++i) {
+      acc += xs_tile[threadIdx.y][i] * ws_tile[threadIdx.x][i];
+    }
+    __syncthreads();
+  }
+  if (row < end && col < out_dim) {
+    dst[(size_t)row * out_dim + col] = static_cast<T>(acc);
+  }
+}
+
+#define GROUPED_MATMUL_OP(TYPENAME, FN_NAME) \
+  extern "C" __global__ void FN_NAME( \
+      const TYPENAME *xs, \
+      const TYPENAME *ws, \
+      TYPENAME *dst, \
+      const uint32_t *offsets, \
+      const uint32_t in_dim, \
+      const uint32_t out_dim) { \
+    grouped_matmul<TYPENAME>(xs, ws, dst, offsets, in_dim, out_dim); \
+  } \
+
+#if __CUDA_ARCH__ >= 800
+GROUPED_MATMUL_OP(__nv_bfloat16, grouped_matmul_bf16)
+#endif
+
+#if __CUDA_ARCH__ >= 530
+GROUPED_MATMUL_OP(__half, grouped_matmul_f16)
+#endif
+
+GROUPED_MATMUL_OP(float, grouped_matmul_f32)
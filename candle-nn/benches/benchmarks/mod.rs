@@ -25,6 +25,8 @@ impl BenchDevice for Device {
                 #[cfg(not(feature = "metal"))]
                 panic!("Metal device without metal feature enabled: {:?}", device)
             }
+            Device::Wgpu(_) => self.synchronize(),
+            Device::Meta => Ok(()),
         }
     }
 
@@ -42,6 +44,8 @@ impl BenchDevice for Device {
             }
             Device::Cuda(_) => format!("cuda_{}", name.into()),
             Device::Metal(_) => format!("metal_{}", name.into()),
+            Device::Wgpu(_) => format!("wgpu_{}", name.into()),
+            Device::Meta => format!("meta_{}", name.into()),
         }
     }
 }
@@ -0,0 +1,188 @@
+//! Position biases added to the attention scores.
+//!
+//! The biases returned here have shape `(1, num_heads, q_len, kv_len)` so that they can be
+//! broadcast-added to the attention scores. The queries are the last `q_len` positions of the
+//! `kv_len` keys, as is the case when using a kv cache.
+use candle::{Device, Module, Result, Tensor};
+
+/// The ALiBi slopes of the attention heads, see "Train Short, Test Long: Attention with Linear
+/// Biases Enables Input Length Extrapolation" <https://arxiv.org/abs/2108.12409>.
+///
+/// The slopes form a geometric sequence from `2^(-bias_max / num_heads)` to `2^(-bias_max)`,
+/// `bias_max` being 8 in the paper and in BLOOM. When `num_heads` is not a power of two, the
+/// slopes of the closest power of two are used and the remaining ones are interleaved from the
+/// slopes of the next power of two.
+pub fn alibi_slopes(num_heads: usize, bias_max: f64) -> Vec<f32> {
+    let num_heads2 = num_heads.next_power_of_two();
+    let slopes = (1..=num_heads2)
+        .map(|v| 2f64.powf(-bias_max * v as f64 / num_heads2 as f64) as f32)
+        .collect::<Vec<_>>();
+    if num_heads2 == num_heads {
+        slopes
+    } else {
+        slopes
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .chain(slopes.iter().step_by(2))
+            .take(num_heads)
+            .copied()
+            .collect()
+    }
+}
+
+/// The ALiBi bias `-slope * |i - j|` between the query at position `i` and the key at position
+/// `j`, with shape `(1, num_heads, q_len, kv_len)` and dtype f32. For causal attention this
+/// only differs from the bias relative to the last key by a constant on each row, so the
+/// attention weights are the same.
+///
+/// ```rust
+/// use candle::Device;
+/// let bias = candle_nn::attention::alibi_bias(&[0.5, 0.25], 1, 3, &Device::Cpu)?;
+/// assert_eq!(bias.squeeze(0)?.to_vec3::<f32>()?, [[[-1., -0.5, 0.]], [[-0.5, -0.25, 0.]]]);
+/// # Ok::<(), candle::Error>(())
+/// ```
+pub fn alibi_bias(slopes: &[f32], q_len: usize, kv_len: usize, device: &Device) -> Result<Tensor> {
+    if q_len > kv_len {
+        candle::bail!("alibi: q_len {q_len} is larger than kv_len {kv_len}")
+    }
+    let q_start = kv_len - q_len;
+    let distances: Vec<f32> = (q_start..kv_len)
+        .flat_map(|i| (0..kv_len).map(move |j| -(i.abs_diff(j) as f32)))
+        .collect();
+    let distances = Tensor::from_vec(distances, (1, 1, q_len, kv_len), device)?;
+    let slopes = Tensor::from_slice(slopes, (1, slopes.len(), 1, 1), device)?;
+    distances.broadcast_mul(&slopes)
+}
+
+/// The bucket of a relative position `key_position - query_position` as done in T5.
+///
+/// Half of the buckets are used for the exact small distances, the other half for distances
+/// growing logarithmically up to `max_distance`, the larger distances sharing the last bucket.
+/// When `bidirectional` is set the buckets are split between the positive and negative
+/// relative positions, otherwise the positive ones, i.e. the keys after the query, all map to
+/// bucket 0.
+pub fn relative_position_bucket(
+    relative_position: i64,
+    bidirectional: bool,
+    num_buckets: usize,
+    max_distance: usize,
+) -> u32 {
+    let (num_buckets, offset, distance) = if bidirectional {
+        let num_buckets = num_buckets / 2;
+        let offset = if relative_position > 0 {
+            num_buckets
+        } else {
+            0
+        };
+        (
+            num_buckets,
+            offset,
+            relative_position.unsigned_abs() as usize,
+        )
+    } else {
+        (num_buckets, 0, (-relative_position).max(0) as usize)
+    };
+    let max_exact = num_buckets / 2;
+    let bucket = if distance < max_exact {
+        distance
+    } else {
+        let log_ratio = (distance as f32 / max_exact as f32).ln()
+            / (max_distance as f32 / max_exact as f32).ln();
+        let bucket = max_exact + (log_ratio * (num_buckets - max_exact) as f32) as usize;
+        bucket.min(num_buckets - 1)
+    };
+    (offset + bucket) as u32
+}
+
+/// The buckets of the relative positions between the queries and the keys, as a u32 tensor of
+/// shape `(q_len, kv_len)`, see [`relative_position_bucket`].
+pub fn relative_position_buckets(
+    q_len: usize,
+    kv_len: usize,
+    bidirectional: bool,
+    num_buckets: usize,
+    max_distance: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    if q_len > kv_len {
+        candle::bail!("relative-position-bias: q_len {q_len} is larger than kv_len {kv_len}")
+    }
+    let q_start = kv_len - q_len;
+    let buckets: Vec<u32> = (q_start..kv_len)
+        .flat_map(|i| {
+            (0..kv_len).map(move |j| {
+                let relative_position = j as i64 - i as i64;
+                relative_position_bucket(
+                    relative_position,
+                    bidirectional,
+                    num_buckets,
+                    max_distance,
+                )
+            })
+        })
+        .collect();
+    Tensor::from_vec(buckets, (q_len, kv_len), device)
+}
+
+/// A learned bias for each attention head and relative position bucket, as used by T5. The
+/// embedding maps the `num_buckets` buckets to the biases of the `num_heads` heads.
+#[derive(Debug, Clone)]
+pub struct RelativePositionBias<M = crate::Embedding> {
+    embedding: M,
+    num_buckets: usize,
+    max_distance: usize,
+    bidirectional: bool,
+}
+
+impl<M: Module> RelativePositionBias<M> {
+    pub fn new(embedding: M, num_buckets: usize, max_distance: usize, bidirectional: bool) -> Self {
+        Self {
+            embedding,
+            num_buckets,
+            max_distance,
+            bidirectional,
+        }
+    }
+
+    pub fn embedding(&self) -> &M {
+        &self.embedding
+    }
+
+    pub fn bidirectional(&self) -> bool {
+        self.bidirectional
+    }
+
+    /// The bias of shape `(1, num_heads, q_len, kv_len)`.
+    pub fn bias(&self, q_len: usize, kv_len: usize, device: &Device) -> Result<Tensor> {
+        let buckets = relative_position_buckets(
+            q_len,
+            kv_len,
+            self.bidirectional,
+            self.num_buckets,
+            self.max_distance,
+            device,
+        )?;
+        self.embedding
+            .forward(&buckets)?
+            .permute((2, 0, 1))?
+            .unsqueeze(0)
+    }
+}
+
+/// Creates a relative position bias whose embedding is named `vb`.
+pub fn relative_position_bias(
+    num_buckets: usize,
+    max_distance: usize,
+    num_heads: usize,
+    bidirectional: bool,
+    vb: crate::VarBuilder,
+) -> Result<RelativePositionBias> {
+    let embedding = crate::embedding(num_buckets, num_heads, vb)?;
+    Ok(RelativePositionBias::new(
+        embedding,
+        num_buckets,
+        max_distance,
+        bidirectional,
+    ))
+}
@@ -0,0 +1,252 @@
+//! Attention masks.
+//!
+//! The masks are u8 tensors where 1 marks the keys that a query cannot attend to, with shape
+//! `(1, 1, q_len, kv_len)`, or `(batch, 1, q_len, kv_len)` when padding is involved, so that
+//! they can be broadcast to the attention scores. As for the position biases of
+//! [`attention`](crate::attention), the queries are the last `q_len` positions of the `kv_len`
+//! keys, which is the case when using a kv cache. [`additive`] converts a mask to a bias to be
+//! added to the scores, and [`MaskCache`] avoids rebuilding the same masks at each forward pass.
+//!
+//! ```rust
+//! use candle::Device;
+//! use candle_nn::attention_mask::{sliding_window_mask, padding_mask, combine, PaddingSide};
+//! # fn main() -> candle::Result<()> {
+//! let device = &Device::Cpu;
+//! // 2 new tokens attending to 3 cached ones, each query seeing the last 3 positions.
+//! let mask = sliding_window_mask(2, 5, 3, device)?;
+//! assert_eq!(mask.squeeze(0)?.squeeze(0)?.to_vec2::<u8>()?, [[1, 0, 0, 0, 1], [1, 1, 0, 0, 0]]);
+//! // A batch of two left padded sequences.
+//! let padding = padding_mask(&[5, 3], 5, PaddingSide::Left, device)?;
+//! assert_eq!(combine(&mask, &padding)?.dims(), [2, 1, 2, 5]);
+//! # Ok(())
+//! # }
+//! ```
+use candle::{DType, Device, Result, Tensor};
+use std::collections::HashMap;
+
+fn build<F: Fn(usize, usize) -> bool>(
+    q_len: usize,
+    kv_len: usize,
+    device: &Device,
+    masked: F,
+) -> Result<Tensor> {
+    if q_len > kv_len {
+        candle::bail!("attention mask: q_len {q_len} is larger than kv_len {kv_len}")
+    }
+    let q_start = kv_len - q_len;
+    let masked = &masked;
+    let mask: Vec<u8> = (q_start..kv_len)
+        .flat_map(|i| (0..kv_len).map(move |j| u8::from(masked(i, j))))
+        .collect();
+    Tensor::from_vec(mask, (1, 1, q_len, kv_len), device)
+}
+
+/// The causal mask, the query at position `i` attends to the keys at positions `j <= i`.
+pub fn causal_mask(q_len: usize, kv_len: usize, device: &Device) -> Result<Tensor> {
+    build(q_len, kv_len, device, |i, j| j > i)
+}
+
+/// The causal mask restricted to the last `window` positions, the query itself included, i.e.
+/// the query at position `i` attends to the keys at positions `i - window < j <= i`.
+pub fn sliding_window_mask(
+    q_len: usize,
+    kv_len: usize,
+    window: usize,
+    device: &Device,
+) -> Result<Tensor> {
+    if window == 0 {
+        candle::bail!("attention mask: the sliding window must not be empty")
+    }
+    build(q_len, kv_len, device, |i, j| j > i || i - j >= window)
+}
+
+/// Where the padding of the sequences shorter than the batch is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PaddingSide {
+    /// The padding comes before the tokens, as is usual for generation so that the last
+    /// positions of all the sequences are aligned.
+    Left,
+    Right,
+}
+
+/// Masks the padding keys of a batch of sequences with `lengths` tokens padded to `kv_len`, the
+/// mask has shape `(batch, 1, 1, kv_len)`.
+pub fn padding_mask(
+    lengths: &[usize],
+    kv_len: usize,
+    side: PaddingSide,
+    device: &Device,
+) -> Result<Tensor> {
+    let mut mask = Vec::with_capacity(lengths.len() * kv_len);
+    for &len in lengths {
+        if len > kv_len {
+            candle::bail!("attention mask: sequence length {len} is larger than kv_len {kv_len}")
+        }
+        let padded = match side {
+            PaddingSide::Left => 0..kv_len - len,
+            PaddingSide::Right => len..kv_len,
+        };
+        mask.extend((0..kv_len).map(|j| u8::from(padded.contains(&j))))
+    }
+    Tensor::from_vec(mask, (lengths.len(), 1, 1, kv_len), device)
+}
+
+/// Masks the keys masked by either `lhs` or `rhs`, the masks are broadcast together.
+pub fn combine(lhs: &Tensor, rhs: &Tensor) -> Result<Tensor> {
+    lhs.broadcast_maximum(rhs)
+}
+
+/// The bias corresponding to a mask, `-inf` for the masked keys and 0 for the others.
+pub fn additive(mask: &Tensor, dtype: DType) -> Result<Tensor> {
+    let device = mask.device();
+    let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?
+        .to_dtype(dtype)?
+        .broadcast_as(mask.shape())?;
+    let zeros = Tensor::zeros(mask.shape(), dtype, device)?;
+    mask.where_cond(&neg_inf, &zeros)
+}
+
+/// The keys that a query can attend to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MaskKind {
+    /// All the keys, e.g. for an encoder.
+    Full,
+    Causal,
+    /// The causal mask restricted to the last `window` positions, see [`sliding_window_mask`].
+    SlidingWindow(usize),
+}
+
+impl MaskKind {
+    pub fn mask(&self, q_len: usize, kv_len: usize, device: &Device) -> Result<Tensor> {
+        match *self {
+            Self::Full => build(q_len, kv_len, device, |_, _| false),
+            Self::Causal => causal_mask(q_len, kv_len, device),
+            Self::SlidingWindow(window) => sliding_window_mask(q_len, kv_len, window, device),
+        }
+    }
+
+    /// Whether some keys are masked for `q_len` queries, e.g. no mask is needed for the causal
+    /// attention of a single query.
+    pub fn needs_mask(&self, q_len: usize, kv_len: usize) -> bool {
+        match *self {
+            Self::Full => false,
+            Self::Causal => q_len > 1,
+            Self::SlidingWindow(window) => q_len > 1 || kv_len > window,
+        }
+    }
+}
+
+/// Caches the masks of a given kind on a device, and their additive versions by dtype.
+///
+/// The masks only depend on the distance between the query and key positions, so the mask for
+/// `kv_len` keys is the last `kv_len` columns of the mask for more keys with the same number of
+/// queries. A single mask is kept for each number of queries and its number of keys doubles when
+/// it is extended, the returned masks being views of these masks, so that generating one token
+/// at a time does not allocate a new mask at each step.
+///
+/// ```rust
+/// use candle::{DType, Device};
+/// use candle_nn::attention_mask::{MaskCache, MaskKind};
+/// # fn main() -> candle::Result<()> {
+/// let mut masks = MaskCache::new(MaskKind::SlidingWindow(4), &Device::Cpu);
+/// let bias = masks.additive(3, 3, DType::F32)?;
+/// assert_eq!(bias.dims(), [1, 1, 3, 3]);
+/// let mask = masks.mask(1, 6)?;
+/// assert_eq!(mask.flatten_all()?.to_vec1::<u8>()?, [1, 1, 0, 0, 0, 0]);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct MaskCache {
+    kind: MaskKind,
+    device: Device,
+    masks: HashMap<usize, Tensor>,
+    additive: HashMap<(usize, DType), Tensor>,
+}
+
+/// The last `kv_len` columns of `mask` if it is large enough.
+fn tail(mask: Option<&Tensor>, kv_len: usize) -> Result<Option<Tensor>> {
+    match mask {
+        Some(mask) => {
+            let len = mask.dim(3)?;
+            if len >= kv_len {
+                Ok(Some(mask.narrow(3, len - kv_len, kv_len)?))
+            } else {
+                Ok(None)
+            }
+        }
+        None => Ok(None),
+    }
+}
+
+impl MaskCache {
+    pub fn new(kind: MaskKind, device: &Device) -> Self {
+        Self {
+            kind,
+            device: device.clone(),
+            masks: HashMap::new(),
+            additive: HashMap::new(),
+        }
+    }
+
+    pub fn kind(&self) -> MaskKind {
+        self.kind
+    }
+
+    /// The number of keys of the mask to build so that it covers `kv_len` keys, doubling the
+    /// size of the current mask.
+    fn capacity(current: Option<&Tensor>, kv_len: usize) -> usize {
+        let current = current.and_then(|m| m.dim(3).ok()).unwrap_or(0);
+        kv_len.max(2 * current)
+    }
+
+    /// The mask for `q_len` queries attending to `kv_len` keys, with shape
+    /// `(1, 1, q_len, kv_len)`.
+    pub fn mask(&mut self, q_len: usize, kv_len: usize) -> Result<Tensor> {
+        if q_len > kv_len {
+            candle::bail!("attention mask: q_len {q_len} is larger than kv_len {kv_len}")
+        }
+        let current = self.masks.get(&q_len);
+        if let Some(mask) = tail(current, kv_len)? {
+            return Ok(mask);
+        }
+        let capacity = Self::capacity(current, kv_len);
+        let mask = self.kind.mask(q_len, capacity, &self.device)?;
+        self.masks.insert(q_len, mask.clone());
+        mask.narrow(3, capacity - kv_len, kv_len)
+    }
+
+    /// The additive version of [`Self::mask`].
+    pub fn additive(&mut self, q_len: usize, kv_len: usize, dtype: DType) -> Result<Tensor> {
+        if q_len > kv_len {
+            candle::bail!("attention mask: q_len {q_len} is larger than kv_len {kv_len}")
+        }
+        let current = self.additive.get(&(q_len, dtype));
+        if let Some(mask) = tail(current, kv_len)? {
+            return Ok(mask);
+        }
+        let capacity = Self::capacity(current, kv_len);
+        let mask = additive(&self.kind.mask(q_len, capacity, &self.device)?, dtype)?;
+        self.additive.insert((q_len, dtype), mask.clone());
+        mask.narrow(3, capacity - kv_len, kv_len)
+    }
+
+    /// The mask for a batch of padded sequences, with shape `(batch, 1, q_len, kv_len)`. This is
+    /// not cached as the lengths usually change with each batch.
+    pub fn with_padding(
+        &mut self,
+        q_len: usize,
+        kv_len: usize,
+        lengths: &[usize],
+        side: PaddingSide,
+    ) -> Result<Tensor> {
+        let padding = padding_mask(lengths, kv_len, side, &self.device)?;
+        combine(&self.mask(q_len, kv_len)?, &padding)
+    }
+
+    /// Drops the cached masks, e.g. after a long prompt whose masks will not be reused.
+    pub fn clear(&mut self) {
+        self.masks.clear();
+        self.additive.clear()
+    }
+}
@@ -0,0 +1,429 @@
+//! Post-training calibration of quantization ranges.
+//!
+//! A calibration pass runs a model on a small representative dataset and collects statistics on
+//! some of its activations: the per-channel ranges, the mean squared values, and a histogram of
+//! the absolute values. These statistics are then used to pick the quantization ranges, clipping
+//! the rare outliers usually reduces the quantization error of the other values, and to weight
+//! the quantization error of the weights by the magnitude of the corresponding inputs.
+//!
+//! ```rust
+//! use candle::{DType, Device, Tensor};
+//! use candle_nn::calibration::{Calibrator, RangeMethod};
+//! use candle_nn::qat::QuantConfig;
+//! # fn main() -> candle::Result<()> {
+//! let dev = &Device::Cpu;
+//! let dataset = (0..4).map(|i| Tensor::randn(0f32, 1. + i as f32, (8, 16), dev));
+//! let mut calibrator = Calibrator::new();
+//! calibrator.run(dataset, |xs, calibrator| {
+//!     // Observe the activations of interest while running the model.
+//!     calibrator.observe("input", xs)?;
+//!     calibrator.observe("hidden", &xs.relu()?)
+//! })?;
+//! let method = RangeMethod::Percentile(0.999);
+//! let params = calibrator.quant_params("hidden", method, &QuantConfig::uint8())?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::qat::{Granularity, QuantConfig, QuantParams};
+use candle::quantized::{GgmlDType, QTensor};
+use candle::{DType, Device, Result, Tensor, D};
+use std::collections::HashMap;
+
+/// How the quantization range is derived from the activation statistics.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RangeMethod {
+    /// The observed min and max, no value is clipped.
+    MinMax,
+    /// Clips the absolute values to the given quantile, e.g. 0.999.
+    Percentile(f64),
+    /// Clips the absolute values to the threshold minimizing the mean squared quantization error
+    /// for a symmetric integer format with the given number of bits.
+    Mse { bits: usize },
+}
+
+/// The statistics collected for an activation, the channels being the last dimension.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ActivationStats {
+    pub min: Vec<f32>,
+    pub max: Vec<f32>,
+    /// The sum of the squared values for each channel.
+    pub sq_sum: Vec<f64>,
+    /// The number of values observed for each channel.
+    pub count: usize,
+    /// The histogram of the absolute values over `[0, hist_max]`.
+    pub hist: Vec<f64>,
+    pub hist_max: f32,
+}
+
+impl ActivationStats {
+    fn new(channels: usize, num_bins: usize) -> Self {
+        Self {
+            min: vec![f32::INFINITY; channels],
+            max: vec![f32::NEG_INFINITY; channels],
+            sq_sum: vec![0.; channels],
+            count: 0,
+            hist: vec![0.; num_bins],
+            hist_max: 0.,
+        }
+    }
+
+    pub fn channels(&self) -> usize {
+        self.min.len()
+    }
+
+    fn observe(&mut self, values: &[f32]) {
+        let channels = self.channels();
+        let absmax = values
+            .iter()
+            .filter(|v| v.is_finite())
+            .fold(0f32, |m, v| m.max(v.abs()));
+        if absmax > self.hist_max {
+            self.grow_hist(absmax)
+        }
+        let num_bins = self.hist.len();
+        let bin_scale = if self.hist_max > 0. {
+            num_bins as f32 / self.hist_max
+        } else {
+            0.
+        };
+        for row in values.chunks_exact(channels) {
+            for (c, &v) in row.iter().enumerate() {
+                if !v.is_finite() {
+                    continue;
+                }
+                self.min[c] = self.min[c].min(v);
+                self.max[c] = self.max[c].max(v);
+                self.sq_sum[c] += (v as f64) * (v as f64);
+                let bin = ((v.abs() * bin_scale) as usize).min(num_bins - 1);
+                self.hist[bin] += 1.
+            }
+        }
+        self.count += values.len() / channels
+    }
+
+    // Extends the histogram range to cover `absmax`, the existing counts are moved to the bins
+    // containing the centers of their previous bins.
+    fn grow_hist(&mut self, absmax: f32) {
+        // Grow by at least a factor of two to limit the number of rebinnings.
+        let new_max = if self.hist_max > 0. {
+            absmax.max(2. * self.hist_max)
+        } else {
+            absmax
+        };
+        let num_bins = self.hist.len();
+        let mut hist = vec![0.; num_bins];
+        let old_width = self.hist_max / num_bins as f32;
+        for (i, &count) in self.hist.iter().enumerate() {
+            if count > 0. {
+                let center = (i as f32 + 0.5) * old_width;
+                let bin = ((center / new_max * num_bins as f32) as usize).min(num_bins - 1);
+                hist[bin] += count
+            }
+        }
+        self.hist = hist;
+        self.hist_max = new_max
+    }
+
+    /// The mean squared value of each channel, the importance of the corresponding weights.
+    pub fn mean_sq(&self) -> Vec<f32> {
+        let count = self.count.max(1) as f64;
+        self.sq_sum.iter().map(|v| (v / count) as f32).collect()
+    }
+
+    /// The threshold on the absolute values for the given method, `None` if nothing is clipped.
+    pub fn clip_value(&self, method: RangeMethod) -> Result<Option<f32>> {
+        let total: f64 = self.hist.iter().sum();
+        if total == 0. {
+            return Ok(None);
+        }
+        let width = self.hist_max / self.hist.len() as f32;
+        let clip = match method {
+            RangeMethod::MinMax => return Ok(None),
+            RangeMethod::Percentile(p) => {
+                if !(0. ..=1.).contains(&p) {
+                    candle::bail!("calibration percentile must be between 0 and 1, got {p}")
+                }
+                let mut cumulative = 0.;
+                let mut clip = self.hist_max;
+                for (i, &count) in self.hist.iter().enumerate() {
+                    cumulative += count;
+                    if cumulative >= p * total {
+                        clip = (i + 1) as f32 * width;
+                        break;
+                    }
+                }
+                clip
+            }
+            RangeMethod::Mse { bits } => {
+                if !(2..=16).contains(&bits) {
+                    candle::bail!("calibration bits must be between 2 and 16, got {bits}")
+                }
+                let levels = ((1u64 << bits) - 1) as f64;
+                let mut best = (f64::INFINITY, self.hist_max);
+                for i in 1..=self.hist.len() {
+                    let clip = i as f64 * width as f64;
+                    // The rounding error is uniform within a step for the values in range, the
+                    // clipped values are moved to the threshold.
+                    let step = 2. * clip / levels;
+                    let rounding_err = step * step / 12.;
+                    let mut err = 0.;
+                    for (j, &count) in self.hist.iter().enumerate() {
+                        let center = (j as f64 + 0.5) * width as f64;
+                        err += if center > clip {
+                            count * (center - clip) * (center - clip)
+                        } else {
+                            count * rounding_err
+                        };
+                    }
+                    if err < best.0 {
+                        best = (err, clip as f32)
+                    }
+                }
+                best.1
+            }
+        };
+        Ok(Some(clip))
+    }
+
+    /// The per-channel ranges, clipped according to `method`.
+    pub fn range(&self, method: RangeMethod) -> Result<(Vec<f32>, Vec<f32>)> {
+        if self.count == 0 {
+            candle::bail!("no activation has been observed")
+        }
+        let (mut min, mut max) = (self.min.clone(), self.max.clone());
+        if let Some(clip) = self.clip_value(method)? {
+            min.iter_mut().for_each(|v| *v = v.max(-clip));
+            max.iter_mut().for_each(|v| *v = v.min(clip));
+        }
+        Ok((min, max))
+    }
+}
+
+/// Collects activation statistics during a calibration pass, see the
+/// [module level documentation](self).
+#[derive(Debug, Clone)]
+pub struct Calibrator {
+    stats: HashMap<String, ActivationStats>,
+    num_bins: usize,
+}
+
+impl Default for Calibrator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Calibrator {
+    pub fn new() -> Self {
+        Self {
+            stats: HashMap::new(),
+            num_bins: 2048,
+        }
+    }
+
+    /// The number of bins of the histograms used for the percentile and MSE methods, this
+    /// defaults to 2048.
+    pub fn with_num_bins(mut self, num_bins: usize) -> Self {
+        self.num_bins = num_bins.max(1);
+        self
+    }
+
+    /// Runs `f` on each sample of the dataset, `f` is expected to run the model and to call
+    /// [`Calibrator::observe`] on the activations to calibrate.
+    pub fn run<I, F>(&mut self, dataset: I, mut f: F) -> Result<()>
+    where
+        I: IntoIterator<Item = Result<Tensor>>,
+        F: FnMut(&Tensor, &mut Self) -> Result<()>,
+    {
+        for xs in dataset {
+            f(&xs?, self)?
+        }
+        Ok(())
+    }
+
+    /// Updates the statistics of the activation `name` with the values of `xs`, the last
+    /// dimension being the channels.
+    pub fn observe(&mut self, name: &str, xs: &Tensor) -> Result<()> {
+        let channels = xs.dim(D::Minus1)?;
+        let values = xs
+            .detach()
+            .to_dtype(DType::F32)?
+            .flatten_all()?
+            .to_vec1::<f32>()?;
+        let num_bins = self.num_bins;
+        let stats = self
+            .stats
+            .entry(name.to_string())
+            .or_insert_with(|| ActivationStats::new(channels, num_bins));
+        if stats.channels() != channels {
+            candle::bail!(
+                "activation {name} has {channels} channels, previously {}",
+                stats.channels()
+            )
+        }
+        stats.observe(&values);
+        Ok(())
+    }
+
+    pub fn stats(&self, name: &str) -> Option<&ActivationStats> {
+        self.stats.get(name)
+    }
+
+    /// The names of the observed activations, sorted.
+    pub fn names(&self) -> Vec<&str> {
+        let mut names = self.stats.keys().map(|k| k.as_str()).collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn get(&self, name: &str) -> Result<&ActivationStats> {
+        match self.stats.get(name) {
+            Some(stats) => Ok(stats),
+            None => candle::bail!("no statistics for activation {name}"),
+        }
+    }
+
+    /// The per-channel min and max of the activation `name` as two tensors of shape
+    /// `(channels,)`, clipped according to `method`.
+    pub fn range(
+        &self,
+        name: &str,
+        method: RangeMethod,
+        device: &Device,
+    ) -> Result<(Tensor, Tensor)> {
+        let (min, max) = self.get(name)?.range(method)?;
+        Ok((Tensor::new(min, device)?, Tensor::new(max, device)?))
+    }
+
+    /// The quantization parameters for the activation `name`. Per-channel configurations use
+    /// one scale per channel, the last dimension, whatever their axis.
+    pub fn quant_params(
+        &self,
+        name: &str,
+        method: RangeMethod,
+        config: &QuantConfig,
+    ) -> Result<QuantParams> {
+        let (min, max) = self.range(name, method, &Device::Cpu)?;
+        let (min, max) = match config.granularity {
+            Granularity::PerChannel { .. } => (min, max),
+            Granularity::PerTensor => (min.min_keepdim(0)?, max.max_keepdim(0)?),
+        };
+        QuantParams::from_min_max(&min, &max, config)
+    }
+
+    /// The mean squared values of the channels of the activation `name`, with shape
+    /// `(channels,)`. This measures how much the quantization error of the weights applied to
+    /// each channel impacts the output, see [`quantize_with_importance`].
+    pub fn importance(&self, name: &str, device: &Device) -> Result<Tensor> {
+        Tensor::new(self.get(name)?.mean_sq(), device)
+    }
+
+    /// Saves the statistics to a safetensors file, the tensors for activation `name` are named
+    /// `{name}.min`, `{name}.max`, `{name}.sq_sum` and `{name}.hist`.
+    pub fn save<P: AsRef<std::path::Path>>(&self, p: P) -> Result<()> {
+        let mut tensors = HashMap::new();
+        for (name, s) in self.stats.iter() {
+            let dev = &Device::Cpu;
+            tensors.insert(format!("{name}.min"), Tensor::new(s.min.as_slice(), dev)?);
+            tensors.insert(format!("{name}.max"), Tensor::new(s.max.as_slice(), dev)?);
+            tensors.insert(
+                format!("{name}.sq_sum"),
+                Tensor::new(s.sq_sum.as_slice(), dev)?,
+            );
+            let mut hist = vec![s.count as f64, s.hist_max as f64];
+            hist.extend_from_slice(&s.hist);
+            tensors.insert(format!("{name}.hist"), Tensor::new(hist, dev)?);
+        }
+        candle::safetensors::save(&tensors, p)
+    }
+
+    /// Loads statistics saved with [`Calibrator::save`].
+    pub fn load<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        let tensors = candle::safetensors::load(p, &Device::Cpu)?;
+        let mut stats = HashMap::new();
+        let mut num_bins = 2048;
+        for name in tensors.keys() {
+            let name = match name.strip_suffix(".hist") {
+                Some(name) => name,
+                None => continue,
+            };
+            let get = |suffix: &str| match tensors.get(&format!("{name}.{suffix}")) {
+                Some(t) => Ok(t),
+                None => candle::bail!("missing {name}.{suffix} in {p:?}"),
+            };
+            let hist = get("hist")?.to_vec1::<f64>()?;
+            if hist.len() < 3 {
+                candle::bail!("invalid histogram for {name} in {p:?}")
+            }
+            num_bins = hist.len() - 2;
+            let s = ActivationStats {
+                min: get("min")?.to_vec1::<f32>()?,
+                max: get("max")?.to_vec1::<f32>()?,
+                sq_sum: get("sq_sum")?.to_vec1::<f64>()?,
+                count: hist[0] as usize,
+                hist_max: hist[1] as f32,
+                hist: hist[2..].to_vec(),
+            };
+            stats.insert(name.to_string(), s);
+        }
+        Ok(Self { stats, num_bins })
+    }
+}
+
+/// Quantizes the weights of a linear layer, with shape `(out_dim, in_dim)`, to a GGML format
+/// using per input channel scales derived from the activation `importance`, as returned by
+/// [`Calibrator::importance`].
+///
+/// The weights of the input channels with large activations are scaled up before quantization
+/// so that they get a lower relative error, as in AWQ <https://arxiv.org/abs/2306.00978>. The
+/// scales are `importance^(alpha / 2)`, `alpha` being picked among `grid_size` values in
+/// `[0, 1)` to minimize the importance weighted quantization error. As `alpha = 0` corresponds to
+/// the plain round-to-nearest quantization, the error is never worse than without calibration.
+///
+/// This returns the quantized scaled weights together with the scales, of shape `(in_dim,)`: the
+/// inputs of the layer have to be divided by these scales, which is usually folded in the
+/// weights of the preceding normalization layer.
+pub fn quantize_with_importance(
+    weight: &Tensor,
+    importance: &Tensor,
+    dtype: GgmlDType,
+    grid_size: usize,
+) -> Result<(QTensor, Tensor)> {
+    let (_out_dim, in_dim) = weight.dims2()?;
+    if importance.dims1()? != in_dim {
+        candle::bail!(
+            "importance has shape {:?}, expected ({in_dim},)",
+            importance.shape()
+        )
+    }
+    let weight = weight.detach().to_dtype(DType::F32)?;
+    let importance = importance
+        .to_dtype(DType::F32)?
+        .to_device(weight.device())?
+        .maximum(f32::EPSILON as f64)?;
+    let mut best: Option<(f32, QTensor, Tensor)> = None;
+    for i in 0..grid_size.max(1) {
+        let alpha = i as f64 / grid_size.max(1) as f64;
+        let scales = importance.powf(alpha / 2.)?;
+        // Normalize the scales so that they are centered around 1.
+        let norm = (scales.max(0)? * scales.min(0)?)?.sqrt()?;
+        let scales = scales.broadcast_div(&norm)?;
+        let qweight = QTensor::quantize(&weight.broadcast_mul(&scales)?, dtype)?;
+        let dequantized = qweight
+            .dequantize(weight.device())?
+            .broadcast_div(&scales)?;
+        let err = (dequantized - &weight)?
+            .sqr()?
+            .broadcast_mul(&importance)?
+            .sum_all()?
+            .to_scalar::<f32>()?;
+        if best.as_ref().is_none_or(|(best_err, _, _)| err < *best_err) {
+            best = Some((err, qweight, scales))
+        }
+    }
+    match best {
+        Some((_, qweight, scales)) => Ok((qweight, scales)),
+        None => candle::bail!("no quantization candidate"),
+    }
+}
@@ -0,0 +1,202 @@
+//! Knowledge distillation from a teacher model to a student model.
+//!
+//! The student is trained to match the temperature-scaled distribution of the teacher logits,
+//! see <https://arxiv.org/abs/1503.02531>, optionally combined with the usual cross-entropy on
+//! the ground truth labels and with a mean squared error between intermediate activations.
+use candle::{DType, Result, Tensor, D};
+
+/// The KL divergence between the temperature-scaled teacher and student distributions,
+/// `KL(softmax(teacher / T) || softmax(student / T)) * T^2`, averaged over the samples.
+///
+/// Both logit tensors have the same shape, the last dimension being the classes, e.g.
+/// `B, T, V` for language models in which case the average is over all the tokens. The teacher
+/// logits are detached so no gradient flows through them.
+pub fn kl_div_loss(
+    student_logits: &Tensor,
+    teacher_logits: &Tensor,
+    temperature: f64,
+) -> Result<Tensor> {
+    if student_logits.shape() != teacher_logits.shape() {
+        candle::bail!(
+            "shape mismatch between student {:?} and teacher {:?} logits",
+            student_logits.shape(),
+            teacher_logits.shape()
+        )
+    }
+    let num_classes = student_logits.dim(D::Minus1)?;
+    let student = student_logits
+        .to_dtype(DType::F32)?
+        .reshape(((), num_classes))?;
+    let teacher = teacher_logits
+        .detach()
+        .to_dtype(DType::F32)?
+        .reshape(((), num_classes))?;
+    let num_samples = student.dim(0)?;
+    let student_log_probs = crate::ops::log_softmax(&(student / temperature)?, D::Minus1)?;
+    let teacher_log_probs = crate::ops::log_softmax(&(teacher / temperature)?, D::Minus1)?;
+    let kl = (teacher_log_probs.exp()? * (&teacher_log_probs - student_log_probs)?)?;
+    kl.sum_all()?
+        .affine(temperature * temperature / num_samples as f64, 0.)
+}
+
+/// The mean squared error between pairs of student and teacher activations, averaged over the
+/// pairs. The teacher activations are detached, the student activations should have already
+/// been projected to the teacher dimension if they differ.
+pub fn hidden_mse_loss(pairs: &[(Tensor, Tensor)]) -> Result<Tensor> {
+    if pairs.is_empty() {
+        candle::bail!("hidden_mse_loss requires at least one pair of activations")
+    }
+    let mut losses = Vec::with_capacity(pairs.len());
+    for (student, teacher) in pairs.iter() {
+        let teacher = teacher.detach().to_dtype(student.dtype())?;
+        losses.push(crate::loss::mse(student, &teacher)?.to_dtype(DType::F32)?)
+    }
+    Tensor::stack(&losses, 0)?.mean_all()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DistillationConfig {
+    /// The softmax temperature, higher values produce softer distributions.
+    pub temperature: f64,
+    /// The weight of the distillation loss, the cross-entropy on the labels if any uses a
+    /// weight of `1 - alpha`.
+    pub alpha: f64,
+    /// The weight of the intermediate activations loss.
+    pub hidden_weight: f64,
+}
+
+impl Default for DistillationConfig {
+    fn default() -> Self {
+        Self {
+            temperature: 2.0,
+            alpha: 0.5,
+            hidden_weight: 0.0,
+        }
+    }
+}
+
+/// The outputs of a model that are used for distillation, the logits and optionally some
+/// intermediate activations.
+#[derive(Debug, Clone)]
+pub struct ModelOutput {
+    pub logits: Tensor,
+    pub hidden_states: Vec<Tensor>,
+}
+
+impl ModelOutput {
+    pub fn new(logits: Tensor) -> Self {
+        Self {
+            logits,
+            hidden_states: vec![],
+        }
+    }
+
+    pub fn with_hidden_states(logits: Tensor, hidden_states: Vec<Tensor>) -> Self {
+        Self {
+            logits,
+            hidden_states,
+        }
+    }
+}
+
+/// The total loss of a distillation step together with its components, the components are
+/// detached and can be used for logging.
+#[derive(Debug, Clone)]
+pub struct DistillationLoss {
+    pub loss: Tensor,
+    pub kd_loss: Tensor,
+    pub ce_loss: Option<Tensor>,
+    pub hidden_loss: Option<Tensor>,
+}
+
+/// Combines the distillation, cross-entropy and intermediate activation losses.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Distiller {
+    config: DistillationConfig,
+}
+
+impl Distiller {
+    pub fn new(config: DistillationConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &DistillationConfig {
+        &self.config
+    }
+
+    /// Computes the loss for already computed outputs, `targets` are the ground truth labels as
+    /// a u32 tensor with the shape of the logits minus the last dimension. The hidden states of
+    /// the student and teacher are matched pairwise.
+    pub fn loss(
+        &self,
+        student: &ModelOutput,
+        teacher: &ModelOutput,
+        targets: Option<&Tensor>,
+    ) -> Result<DistillationLoss> {
+        let cfg = &self.config;
+        let kd_loss = kl_div_loss(&student.logits, &teacher.logits, cfg.temperature)?;
+        let mut loss = kd_loss.affine(cfg.alpha, 0.)?;
+        let ce_loss = match targets {
+            None => None,
+            Some(targets) => {
+                let num_classes = student.logits.dim(D::Minus1)?;
+                let logits = student
+                    .logits
+                    .to_dtype(DType::F32)?
+                    .reshape(((), num_classes))?;
+                let ce_loss = crate::loss::cross_entropy(&logits, &targets.flatten_all()?)?;
+                loss = (loss + ce_loss.affine(1. - cfg.alpha, 0.)?)?;
+                Some(ce_loss.detach())
+            }
+        };
+        let hidden_loss = if cfg.hidden_weight > 0. {
+            if student.hidden_states.len() != teacher.hidden_states.len() {
+                candle::bail!(
+                    "student and teacher have a different number of hidden states, {} vs {}",
+                    student.hidden_states.len(),
+                    teacher.hidden_states.len()
+                )
+            }
+            let pairs = student
+                .hidden_states
+                .iter()
+                .cloned()
+                .zip(teacher.hidden_states.iter().cloned())
+                .collect::<Vec<_>>();
+            let hidden_loss = hidden_mse_loss(&pairs)?;
+            loss = (loss + hidden_loss.affine(cfg.hidden_weight, 0.)?)?;
+            Some(hidden_loss.detach())
+        } else {
+            None
+        };
+        Ok(DistillationLoss {
+            loss,
+            kd_loss: kd_loss.detach(),
+            ce_loss,
+            hidden_loss,
+        })
+    }
+
+    /// Runs the teacher and the student on a batch and computes the loss. The teacher outputs
+    /// are detached so that the backward pass only goes through the student, the teacher runs
+    /// first so that its activations can be freed before the student forward pass.
+    pub fn step<B, T, S>(
+        &self,
+        batch: &B,
+        targets: Option<&Tensor>,
+        mut teacher: T,
+        mut student: S,
+    ) -> Result<DistillationLoss>
+    where
+        T: FnMut(&B) -> Result<ModelOutput>,
+        S: FnMut(&B) -> Result<ModelOutput>,
+    {
+        let teacher = teacher(batch)?;
+        let teacher = ModelOutput {
+            logits: teacher.logits.detach(),
+            hidden_states: teacher.hidden_states.iter().map(|t| t.detach()).collect(),
+        };
+        let student = student(batch)?;
+        self.loss(&student, &teacher, targets)
+    }
+}
@@ -1,6 +1,10 @@
 pub mod activation;
+pub mod attention;
+pub mod attention_mask;
 pub mod batch_norm;
+pub mod calibration;
 pub mod conv;
+pub mod distillation;
 pub mod embedding;
 pub mod encoding;
 pub mod func;
@@ -9,12 +13,20 @@ pub mod init;
 pub mod kv_cache;
 pub mod layer_norm;
 pub mod linear;
+pub mod logger;
 pub mod loss;
+pub mod metrics;
+pub mod moe;
 pub mod ops;
 pub mod optim;
+pub mod pruning;
+pub mod qat;
+pub mod rlhf;
 pub mod rnn;
 pub mod rotary_emb;
 pub mod sequential;
+pub mod sparsity;
+pub mod trainer;
 pub mod var_builder;
 pub mod var_map;
 
@@ -32,7 +44,7 @@ pub use init::Init;
 pub use layer_norm::{layer_norm, rms_norm, LayerNorm, LayerNormConfig, RmsNorm};
 pub use linear::{linear, linear_b, linear_no_bias, Linear};
 pub use ops::Dropout;
-pub use optim::{AdamW, Optimizer, ParamsAdamW, SGD};
+pub use optim::{AdamW, GradAccumulation, Optimizer, ParamsAdamW, SGD};
 pub use rnn::{gru, lstm, GRUConfig, LSTMConfig, GRU, LSTM, RNN};
 pub use sequential::{seq, Sequential};
 pub use var_builder::VarBuilder;
@@ -41,6 +41,10 @@ impl Linear {
 
 impl super::Module for Linear {
     fn forward(&self, x: &Tensor) -> candle::Result<Tensor> {
+        // On cuda the bias addition is fused in the cublasLt matmul.
+        if crate::ops::use_cublaslt(x) {
+            return crate::ops::fused_linear(x, &self.weight, self.bias.as_ref(), None);
+        }
         let w = match *x.dims() {
             [b1, b2, _, _] => self.weight.broadcast_left((b1, b2))?.t()?,
             [bsize, _, _] => self.weight.broadcast_left(bsize)?.t()?,
@@ -0,0 +1,424 @@
+//! Logging of training metrics.
+//!
+//! Scalars and histograms can be written to TensorBoard event files, to CSV files, or to JSONL
+//! files. All the writers implement the [`Logger`] trait, the `step` argument is typically the
+//! optimizer step count, e.g. [`crate::AdamW::step_count`].
+use candle::{DType, Result, Tensor, Var};
+use std::io::Write;
+
+/// A sink for training metrics.
+pub trait Logger {
+    fn log_scalar(&mut self, tag: &str, value: f64, step: usize) -> Result<()>;
+
+    /// Logs the distribution of the values of a tensor, e.g. weights or gradients.
+    fn log_histogram(&mut self, tag: &str, values: &Tensor, step: usize) -> Result<()>;
+
+    fn flush(&mut self) -> Result<()>;
+
+    /// Logs a histogram of the weights of each variable, using the variable names as tags.
+    fn log_weights(&mut self, vars: &crate::VarMap, step: usize) -> Result<()> {
+        let vars = vars.data().lock().unwrap();
+        let mut names = vars.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            self.log_histogram(&format!("weights/{name}"), vars[name].as_tensor(), step)?
+        }
+        Ok(())
+    }
+
+    /// Logs a histogram of the gradients of each variable that has one.
+    fn log_gradients(
+        &mut self,
+        vars: &crate::VarMap,
+        grads: &candle::backprop::GradStore,
+        step: usize,
+    ) -> Result<()> {
+        let vars = vars.data().lock().unwrap();
+        let mut names = vars.keys().collect::<Vec<_>>();
+        names.sort();
+        for name in names {
+            let var: &Var = &vars[name];
+            if let Some(grad) = grads.get(var) {
+                self.log_histogram(&format!("gradients/{name}"), grad, step)?
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Summary statistics of a tensor, used by the writers that do not support histograms.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub min: f64,
+    pub max: f64,
+    pub num: f64,
+    pub sum: f64,
+    pub sum_squares: f64,
+}
+
+impl Stats {
+    pub fn new(values: &[f64]) -> Self {
+        let mut stats = Self {
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            num: values.len() as f64,
+            sum: 0.,
+            sum_squares: 0.,
+        };
+        for &v in values.iter() {
+            stats.min = stats.min.min(v);
+            stats.max = stats.max.max(v);
+            stats.sum += v;
+            stats.sum_squares += v * v;
+        }
+        stats
+    }
+
+    pub fn mean(&self) -> f64 {
+        self.sum / self.num
+    }
+
+    pub fn std(&self) -> f64 {
+        let mean = self.mean();
+        (self.sum_squares / self.num - mean * mean).max(0.).sqrt()
+    }
+}
+
+fn flatten_f64(values: &Tensor) -> Result<Vec<f64>> {
+    values.flatten_all()?.to_dtype(DType::F64)?.to_vec1::<f64>()
+}
+
+fn wall_time() -> f64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs_f64())
+        .unwrap_or(0.)
+}
+
+/// Writes the metrics as CSV rows with the `wall_time,step,tag,value` columns. Histograms are
+/// written as their min, max, mean and standard deviation using the `tag/min`... tags.
+pub struct CsvLogger<W: Write> {
+    writer: W,
+}
+
+impl CsvLogger<std::io::BufWriter<std::fs::File>> {
+    pub fn create<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let file = std::fs::File::create(path)?;
+        Self::new(std::io::BufWriter::new(file))
+    }
+}
+
+impl<W: Write> CsvLogger<W> {
+    pub fn new(mut writer: W) -> Result<Self> {
+        writeln!(writer, "wall_time,step,tag,value")?;
+        Ok(Self { writer })
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+fn csv_escape(s: &str) -> std::borrow::Cow<'_, str> {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\"")).into()
+    } else {
+        s.into()
+    }
+}
+
+impl<W: Write> Logger for CsvLogger<W> {
+    fn log_scalar(&mut self, tag: &str, value: f64, step: usize) -> Result<()> {
+        let tag = csv_escape(tag);
+        writeln!(self.writer, "{},{step},{tag},{value}", wall_time())?;
+        Ok(())
+    }
+
+    fn log_histogram(&mut self, tag: &str, values: &Tensor, step: usize) -> Result<()> {
+        let stats = Stats::new(&flatten_f64(values)?);
+        self.log_scalar(&format!("{tag}/min"), stats.min, step)?;
+        self.log_scalar(&format!("{tag}/max"), stats.max, step)?;
+        self.log_scalar(&format!("{tag}/mean"), stats.mean(), step)?;
+        self.log_scalar(&format!("{tag}/std"), stats.std(), step)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Writes one json object per line, e.g. `{"wall_time":1.0,"step":3,"tag":"loss","value":0.5}`.
+/// Histograms are written with their summary statistics.
+pub struct JsonlLogger<W: Write> {
+    writer: W,
+}
+
+impl JsonlLogger<std::io::BufWriter<std::fs::File>> {
+    pub fn create<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let file = std::fs::File::create(path)?;
+        Ok(Self::new(std::io::BufWriter::new(file)))
+    }
+}
+
+impl<W: Write> JsonlLogger<W> {
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn json_number(v: f64) -> String {
+    if v.is_finite() {
+        format!("{v}")
+    } else {
+        "null".to_string()
+    }
+}
+
+impl<W: Write> Logger for JsonlLogger<W> {
+    fn log_scalar(&mut self, tag: &str, value: f64, step: usize) -> Result<()> {
+        writeln!(
+            self.writer,
+            "{{\"wall_time\":{},\"step\":{step},\"tag\":{},\"value\":{}}}",
+            json_number(wall_time()),
+            json_string(tag),
+            json_number(value)
+        )?;
+        Ok(())
+    }
+
+    fn log_histogram(&mut self, tag: &str, values: &Tensor, step: usize) -> Result<()> {
+        let stats = Stats::new(&flatten_f64(values)?);
+        writeln!(
+            self.writer,
+            "{{\"wall_time\":{},\"step\":{step},\"tag\":{},\"min\":{},\"max\":{},\"mean\":{},\"std\":{}}}",
+            json_number(wall_time()),
+            json_string(tag),
+            json_number(stats.min),
+            json_number(stats.max),
+            json_number(stats.mean()),
+            json_number(stats.std()),
+        )?;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+// CRC-32C (Castagnoli), as used by the TFRecord format.
+fn crc32c(data: &[u8]) -> u32 {
+    const fn table() -> [u32; 256] {
+        let mut table = [0u32; 256];
+        let mut i = 0;
+        while i < 256 {
+            let mut crc = i as u32;
+            let mut j = 0;
+            while j < 8 {
+                crc = if crc & 1 == 1 {
+                    (crc >> 1) ^ 0x82F63B78
+                } else {
+                    crc >> 1
+                };
+                j += 1;
+            }
+            table[i] = crc;
+            i += 1;
+        }
+        table
+    }
+    const TABLE: [u32; 256] = table();
+    let mut crc = !0u32;
+    for &b in data.iter() {
+        crc = TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+fn masked_crc32c(data: &[u8]) -> u32 {
+    let crc = crc32c(data);
+    (crc.rotate_right(15)).wrapping_add(0xa282ead8)
+}
+
+// Minimal protobuf encoding for the Event and Summary messages.
+#[derive(Default)]
+struct Proto(Vec<u8>);
+
+impl Proto {
+    fn varint(&mut self, mut v: u64) {
+        while v >= 0x80 {
+            self.0.push((v as u8) | 0x80);
+            v >>= 7;
+        }
+        self.0.push(v as u8)
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint((field << 3) | wire_type)
+    }
+
+    fn double(&mut self, field: u64, v: f64) {
+        self.key(field, 1);
+        self.0.extend_from_slice(&v.to_le_bytes())
+    }
+
+    fn float(&mut self, field: u64, v: f32) {
+        self.key(field, 5);
+        self.0.extend_from_slice(&v.to_le_bytes())
+    }
+
+    fn int64(&mut self, field: u64, v: i64) {
+        self.key(field, 0);
+        self.varint(v as u64)
+    }
+
+    fn bytes(&mut self, field: u64, v: &[u8]) {
+        self.key(field, 2);
+        self.varint(v.len() as u64);
+        self.0.extend_from_slice(v)
+    }
+
+    fn packed_doubles(&mut self, field: u64, vs: &[f64]) {
+        let bytes = vs.iter().flat_map(|v| v.to_le_bytes()).collect::<Vec<_>>();
+        self.bytes(field, &bytes)
+    }
+}
+
+/// Writes TensorBoard event files, these can be visualized by running `tensorboard --logdir`
+/// on the log directory.
+pub struct TensorBoardLogger {
+    writer: std::io::BufWriter<std::fs::File>,
+    num_buckets: usize,
+}
+
+impl TensorBoardLogger {
+    /// Creates a new event file in `logdir`, the directory is created if needed.
+    pub fn new<P: AsRef<std::path::Path>>(logdir: P) -> Result<Self> {
+        let logdir = logdir.as_ref();
+        std::fs::create_dir_all(logdir)?;
+        let hostname = std::env::var("HOSTNAME").unwrap_or_else(|_| "localhost".to_string());
+        let filename = format!(
+            "events.out.tfevents.{}.{hostname}.{}",
+            wall_time() as u64,
+            std::process::id()
+        );
+        let file = std::fs::File::create(logdir.join(filename))?;
+        let mut logger = Self {
+            writer: std::io::BufWriter::new(file),
+            num_buckets: 30,
+        };
+        let mut event = Proto::default();
+        event.double(1, wall_time());
+        event.bytes(3, b"brain.Event:2");
+        logger.write_record(&event.0)?;
+        Ok(logger)
+    }
+
+    /// The number of buckets used for histograms, defaults to 30.
+    pub fn set_num_buckets(&mut self, num_buckets: usize) {
+        self.num_buckets = num_buckets.max(1)
+    }
+
+    fn write_record(&mut self, data: &[u8]) -> Result<()> {
+        let len = (data.len() as u64).to_le_bytes();
+        self.writer.write_all(&len)?;
+        self.writer.write_all(&masked_crc32c(&len).to_le_bytes())?;
+        self.writer.write_all(data)?;
+        self.writer.write_all(&masked_crc32c(data).to_le_bytes())?;
+        Ok(())
+    }
+
+    fn write_summary_value(&mut self, value: Proto, step: usize) -> Result<()> {
+        let mut summary = Proto::default();
+        summary.bytes(1, &value.0);
+        let mut event = Proto::default();
+        event.double(1, wall_time());
+        event.int64(2, step as i64);
+        event.bytes(5, &summary.0);
+        self.write_record(&event.0)
+    }
+}
+
+impl Logger for TensorBoardLogger {
+    fn log_scalar(&mut self, tag: &str, value: f64, step: usize) -> Result<()> {
+        let mut v = Proto::default();
+        v.bytes(1, tag.as_bytes());
+        v.float(2, value as f32);
+        self.write_summary_value(v, step)
+    }
+
+    fn log_histogram(&mut self, tag: &str, values: &Tensor, step: usize) -> Result<()> {
+        let values = flatten_f64(values)?;
+        let stats = Stats::new(&values);
+        let (bucket_limits, buckets) = if values.is_empty() {
+            (vec![], vec![])
+        } else {
+            let num_buckets = self.num_buckets;
+            let width = (stats.max - stats.min) / num_buckets as f64;
+            let mut buckets = vec![0f64; num_buckets];
+            for &v in values.iter() {
+                let index = if width > 0. {
+                    (((v - stats.min) / width) as usize).min(num_buckets - 1)
+                } else {
+                    0
+                };
+                buckets[index] += 1.
+            }
+            let bucket_limits = (1..=num_buckets)
+                .map(|i| {
+                    if i == num_buckets {
+                        stats.max
+                    } else {
+                        stats.min + width * i as f64
+                    }
+                })
+                .collect::<Vec<_>>();
+            (bucket_limits, buckets)
+        };
+        let mut histo = Proto::default();
+        histo.double(1, stats.min);
+        histo.double(2, stats.max);
+        histo.double(3, stats.num);
+        histo.double(4, stats.sum);
+        histo.double(5, stats.sum_squares);
+        histo.packed_doubles(6, &bucket_limits);
+        histo.packed_doubles(7, &buckets);
+        let mut v = Proto::default();
+        v.bytes(1, tag.as_bytes());
+        v.bytes(5, &histo.0);
+        self.write_summary_value(v, step)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+impl Drop for TensorBoardLogger {
+    fn drop(&mut self) {
+        let _ = self.writer.flush();
+    }
+}
@@ -1,15 +1,7 @@
 use candle::{Result, Tensor};
 
-/// The negative log likelihood loss.
-///
-/// Arguments
-///
-/// * [inp]: The input tensor of dimensions `N, C` where `N` is the batch size and `C` the number
-///          of categories. This is expected to contain log probabilities.
-/// * [target]: The ground truth labels as a tensor of u32 of dimension `N`.
-///
-/// The resulting tensor is a scalar containing the average value over the batch.
-pub fn nll(inp: &Tensor, target: &Tensor) -> Result<Tensor> {
+/// Checks that `inp` has dimensions `N, C` and `target` dimension `N`, and returns `N`.
+fn batch_size(inp: &Tensor, target: &Tensor) -> Result<usize> {
     let b_sz = match target.dims() {
         &[b_sz] => b_sz,
         dims => candle::bail!("the target tensor should have a single dimension ({dims:?})"),
@@ -20,8 +12,22 @@ pub fn nll(inp: &Tensor, target: &Tensor) -> Result<Tensor> {
                 candle::bail!("batch size mismatch between inp ({inp_b_sz}) and target ({b_sz})")
             }
         }
-        dims => candle::bail!("the target tensor should have two dimensions ({dims:?})"),
+        dims => candle::bail!("the input tensor should have two dimensions ({dims:?})"),
     }
+    Ok(b_sz)
+}
+
+/// The negative log likelihood loss.
+///
+/// Arguments
+///
+/// * [inp]: The input tensor of dimensions `N, C` where `N` is the batch size and `C` the number
+///          of categories. This is expected to contain log probabilities.
+/// * [target]: The ground truth labels as a tensor of u32 of dimension `N`.
+///
+/// The resulting tensor is a scalar containing the average value over the batch.
+pub fn nll(inp: &Tensor, target: &Tensor) -> Result<Tensor> {
+    let b_sz = batch_size(inp, target)?;
     inp.gather(&target.unsqueeze(1)?, 1)?
         .sum_all()?
         .affine(-1f64 / b_sz as f64, 0.)
@@ -44,6 +50,96 @@ pub fn cross_entropy(inp: &Tensor, target: &Tensor) -> Result<Tensor> {
     nll(&inp, target)
 }
 
+/// The cross-entropy loss computed by chunks of `chunk_size` categories.
+///
+/// This returns the same value as `cross_entropy` but the log-probabilities are never
+/// materialized, only the log-sum-exp of each row and the logits of the targets. This reduces
+/// the memory usage for models with very large vocabularies.
+pub fn cross_entropy_chunked(inp: &Tensor, target: &Tensor, chunk_size: usize) -> Result<Tensor> {
+    let b_sz = batch_size(inp, target)?;
+    let lse = crate::ops::log_sum_exp_chunked(inp, 1, chunk_size)?;
+    let target_logits = inp.gather(&target.unsqueeze(1)?, 1)?;
+    (lse - target_logits)?
+        .sum_all()?
+        .affine(1f64 / b_sz as f64, 0.)
+}
+
+/// The cross-entropy loss of a final linear layer without bias, fused so that the full logits
+/// are never materialized, neither in the forward pass nor in the backward pass.
+///
+/// Arguments
+///
+/// * [xs]: The hidden states of dimensions `N, H` where `N` is the batch size, sequences should
+///         be flattened into the batch dimension.
+/// * [weight]: The weight of the linear layer of dimensions `C, H` where `C` is the number of
+///             categories, e.g. the vocabulary size.
+/// * [target]: The ground truth labels as a tensor of u32 of dimension `N`.
+/// * [chunk_size]: The number of categories for which the logits are computed at once.
+///
+/// The result is the same as `cross_entropy(&xs.matmul(&weight.t()?)?, target)`. When `xs` or
+/// `weight` is tracked for backpropagation, the gradients are computed eagerly, chunk by chunk,
+/// and attached to the returned loss. This requires computing the logits twice.
+pub fn linear_cross_entropy(
+    xs: &Tensor,
+    weight: &Tensor,
+    target: &Tensor,
+    chunk_size: usize,
+) -> Result<Tensor> {
+    let b_sz = batch_size(xs, target)?;
+    let (num_categories, hidden_size) = weight.dims2()?;
+    if xs.dim(1)? != hidden_size {
+        candle::bail!(
+            "hidden size mismatch between xs {:?} and weight {:?}",
+            xs.shape(),
+            weight.shape()
+        )
+    }
+    if chunk_size == 0 {
+        candle::bail!("linear_cross_entropy: chunk_size must be positive")
+    }
+    let (xs_d, weight_d) = (xs.detach(), weight.detach());
+    let chunks = (0..num_categories)
+        .step_by(chunk_size)
+        .map(|start| weight_d.narrow(0, start, chunk_size.min(num_categories - start)))
+        .collect::<Result<Vec<_>>>()?;
+    let lse = chunks
+        .iter()
+        .map(|w| xs_d.matmul(&w.t()?)?.log_sum_exp_keepdim(1))
+        .collect::<Result<Vec<_>>>()?;
+    let lse = Tensor::cat(&lse, 1)?.log_sum_exp_keepdim(1)?;
+    let target_weight = weight_d.index_select(target, 0)?;
+    let target_logits = (&xs_d * &target_weight)?.sum_keepdim(1)?;
+    let scale = 1f64 / b_sz as f64;
+    let loss = (&lse - target_logits)?.sum_all()?.affine(scale, 0.)?;
+    if !xs.track_op() && !weight.track_op() {
+        return Ok(loss);
+    }
+
+    // The gradient of the loss with respect to the logits is (softmax - one_hot(target)) / N.
+    let mut grad_xs = target_weight.affine(-scale, 0.)?;
+    let mut grad_weight = Vec::with_capacity(chunks.len());
+    for w in chunks.iter() {
+        let probs = xs_d
+            .matmul(&w.t()?)?
+            .broadcast_sub(&lse)?
+            .exp()?
+            .affine(scale, 0.)?;
+        grad_xs = (grad_xs + probs.matmul(w)?)?;
+        if weight.track_op() {
+            grad_weight.push(probs.t()?.matmul(&xs_d)?)
+        }
+    }
+    // The differences with the detached tensors are zero so these terms do not change the value
+    // of the loss, only its gradients.
+    let mut loss = (loss + ((xs - &xs_d)? * grad_xs)?.sum_all()?)?;
+    if weight.track_op() {
+        let grad_weight =
+            Tensor::cat(&grad_weight, 0)?.index_add(target, &xs_d.affine(-scale, 0.)?, 0)?;
+        loss = (loss + ((weight - &weight_d)? * grad_weight)?.sum_all()?)?;
+    }
+    Ok(loss)
+}
+
 /// The mean squared error loss.
 pub fn mse(inp: &Tensor, target: &Tensor) -> Result<Tensor> {
     (inp - target)?.sqr()?.mean_all()
@@ -0,0 +1,361 @@
+//! Evaluation metrics.
+//!
+//! The metrics accumulate statistics over multiple calls to `update`, typically once per batch,
+//! and `compute` returns the value of the metric over everything seen so far.
+use candle::{DType, Result, Tensor, D};
+use std::collections::HashMap;
+use std::hash::Hash;
+
+/// Top-k classification accuracy.
+#[derive(Debug, Clone, Default)]
+pub struct Accuracy {
+    k: usize,
+    correct: u64,
+    total: u64,
+}
+
+impl Accuracy {
+    /// The prediction for a sample is considered correct if the target is among the `k` classes
+    /// with the highest scores, `k = 1` gives the usual accuracy.
+    pub fn new(k: usize) -> Self {
+        Self {
+            k,
+            correct: 0,
+            total: 0,
+        }
+    }
+
+    /// Arguments
+    ///
+    /// * [logits]: The scores of dimensions `N, C` where `N` is the batch size and `C` the number
+    ///   of classes.
+    /// * [targets]: The ground truth labels as a tensor of u32 of dimension `N`.
+    pub fn update(&mut self, logits: &Tensor, targets: &Tensor) -> Result<()> {
+        let (b_sz, num_classes) = logits.dims2()?;
+        let k = self.k.min(num_classes);
+        let top_k = if k == 1 {
+            logits.argmax_keepdim(D::Minus1)?
+        } else {
+            logits
+                .to_dtype(DType::F32)?
+                .contiguous()?
+                .arg_sort_last_dim(false)?
+                .narrow(D::Minus1, 0, k)?
+        };
+        let targets = targets.to_dtype(DType::U32)?.reshape((b_sz, 1))?;
+        let correct = top_k
+            .broadcast_eq(&targets)?
+            .to_dtype(DType::U32)?
+            .sum_all()?
+            .to_vec0::<u32>()?;
+        self.correct += correct as u64;
+        self.total += b_sz as u64;
+        Ok(())
+    }
+
+    pub fn compute(&self) -> f64 {
+        if self.total == 0 {
+            0.
+        } else {
+            self.correct as f64 / self.total as f64
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.correct = 0;
+        self.total = 0;
+    }
+}
+
+/// Per-class precision, recall, and F1 score for single-label classification.
+#[derive(Debug, Clone)]
+pub struct ClassificationMetrics {
+    true_positives: Vec<u64>,
+    false_positives: Vec<u64>,
+    false_negatives: Vec<u64>,
+}
+
+fn ratio(num: u64, den: u64) -> f64 {
+    if den == 0 {
+        0.
+    } else {
+        num as f64 / den as f64
+    }
+}
+
+fn f1(precision: f64, recall: f64) -> f64 {
+    if precision + recall == 0. {
+        0.
+    } else {
+        2. * precision * recall / (precision + recall)
+    }
+}
+
+impl ClassificationMetrics {
+    pub fn new(num_classes: usize) -> Self {
+        Self {
+            true_positives: vec![0; num_classes],
+            false_positives: vec![0; num_classes],
+            false_negatives: vec![0; num_classes],
+        }
+    }
+
+    /// Updates the statistics with the predicted and ground truth labels, both being tensors of
+    /// dimension `N`.
+    pub fn update(&mut self, predictions: &Tensor, targets: &Tensor) -> Result<()> {
+        let predictions = predictions.to_dtype(DType::U32)?.to_vec1::<u32>()?;
+        let targets = targets.to_dtype(DType::U32)?.to_vec1::<u32>()?;
+        if predictions.len() != targets.len() {
+            candle::bail!(
+                "size mismatch between predictions ({}) and targets ({})",
+                predictions.len(),
+                targets.len()
+            )
+        }
+        let num_classes = self.true_positives.len();
+        for (&p, &t) in predictions.iter().zip(targets.iter()) {
+            let (p, t) = (p as usize, t as usize);
+            if p >= num_classes || t >= num_classes {
+                candle::bail!("label out of range for {num_classes} classes ({p}, {t})")
+            }
+            if p == t {
+                self.true_positives[p] += 1
+            } else {
+                self.false_positives[p] += 1;
+                self.false_negatives[t] += 1;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn precision(&self, class: usize) -> f64 {
+        let tp = self.true_positives[class];
+        ratio(tp, tp + self.false_positives[class])
+    }
+
+    pub fn recall(&self, class: usize) -> f64 {
+        let tp = self.true_positives[class];
+        ratio(tp, tp + self.false_negatives[class])
+    }
+
+    pub fn f1(&self, class: usize) -> f64 {
+        f1(self.precision(class), self.recall(class))
+    }
+
+    /// The unweighted mean of the per-class F1 scores.
+    pub fn macro_f1(&self) -> f64 {
+        let num_classes = self.true_positives.len();
+        if num_classes == 0 {
+            return 0.;
+        }
+        (0..num_classes).map(|c| self.f1(c)).sum::<f64>() / num_classes as f64
+    }
+
+    /// The F1 score computed from the counts summed over all classes.
+    pub fn micro_f1(&self) -> f64 {
+        let tp = self.true_positives.iter().sum::<u64>();
+        let fp = self.false_positives.iter().sum::<u64>();
+        let fn_ = self.false_negatives.iter().sum::<u64>();
+        f1(ratio(tp, tp + fp), ratio(tp, tp + fn_))
+    }
+
+    pub fn reset(&mut self) {
+        let num_classes = self.true_positives.len();
+        *self = Self::new(num_classes)
+    }
+}
+
+fn ngram_counts<T: Hash + Eq>(tokens: &[T], n: usize) -> HashMap<&[T], usize> {
+    let mut counts = HashMap::new();
+    for ngram in tokens.windows(n) {
+        *counts.entry(ngram).or_insert(0) += 1
+    }
+    counts
+}
+
+/// Corpus level BLEU score, computed with uniform weights over the n-gram orders and the
+/// standard brevity penalty.
+#[derive(Debug, Clone)]
+pub struct Bleu {
+    max_order: usize,
+    matches: Vec<usize>,
+    possible: Vec<usize>,
+    candidate_len: usize,
+    reference_len: usize,
+}
+
+impl Default for Bleu {
+    fn default() -> Self {
+        Self::new(4)
+    }
+}
+
+impl Bleu {
+    pub fn new(max_order: usize) -> Self {
+        Self {
+            max_order,
+            matches: vec![0; max_order],
+            possible: vec![0; max_order],
+            candidate_len: 0,
+            reference_len: 0,
+        }
+    }
+
+    /// Adds a candidate translation together with its reference translations, both being
+    /// sequences of tokens.
+    pub fn update<T: Hash + Eq, R: AsRef<[T]>>(&mut self, candidate: &[T], references: &[R]) {
+        self.candidate_len += candidate.len();
+        // Use the length of the reference closest to the candidate length.
+        self.reference_len += references
+            .iter()
+            .map(|r| r.as_ref().len())
+            .min_by_key(|&l| (l.abs_diff(candidate.len()), l))
+            .unwrap_or(0);
+        for n in 1..=self.max_order {
+            let mut max_ref_counts: HashMap<&[T], usize> = HashMap::new();
+            for reference in references.iter() {
+                for (ngram, count) in ngram_counts(reference.as_ref(), n) {
+                    let c = max_ref_counts.entry(ngram).or_insert(0);
+                    *c = usize::max(*c, count)
+                }
+            }
+            for (ngram, count) in ngram_counts(candidate, n) {
+                let ref_count = max_ref_counts.get(ngram).copied().unwrap_or(0);
+                self.matches[n - 1] += usize::min(count, ref_count);
+            }
+            self.possible[n - 1] += candidate.len().saturating_sub(n - 1);
+        }
+    }
+
+    /// Returns the BLEU score between 0 and 1.
+    pub fn compute(&self) -> f64 {
+        if self.candidate_len == 0 || self.matches.contains(&0) {
+            return 0.;
+        }
+        let log_precision = self
+            .matches
+            .iter()
+            .zip(self.possible.iter())
+            .map(|(&m, &p)| (m as f64 / p as f64).ln())
+            .sum::<f64>()
+            / self.max_order as f64;
+        let brevity_penalty = if self.candidate_len > self.reference_len {
+            1.
+        } else {
+            (1. - self.reference_len as f64 / self.candidate_len as f64).exp()
+        };
+        brevity_penalty * log_precision.exp()
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new(self.max_order)
+    }
+}
+
+fn lcs_len<T: Eq>(a: &[T], b: &[T]) -> usize {
+    let mut prev = vec![0; b.len() + 1];
+    let mut curr = vec![0; b.len() + 1];
+    for x in a.iter() {
+        for (j, y) in b.iter().enumerate() {
+            curr[j + 1] = if x == y {
+                prev[j] + 1
+            } else {
+                usize::max(prev[j + 1], curr[j])
+            }
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev[b.len()]
+}
+
+/// ROUGE-L F-measure, based on the longest common subsequence and averaged over the samples.
+#[derive(Debug, Clone, Default)]
+pub struct RougeL {
+    sum: f64,
+    count: usize,
+}
+
+impl RougeL {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn update<T: Eq>(&mut self, candidate: &[T], reference: &[T]) {
+        let lcs = lcs_len(candidate, reference) as f64;
+        let precision = if candidate.is_empty() {
+            0.
+        } else {
+            lcs / candidate.len() as f64
+        };
+        let recall = if reference.is_empty() {
+            0.
+        } else {
+            lcs / reference.len() as f64
+        };
+        self.sum += f1(precision, recall);
+        self.count += 1;
+    }
+
+    pub fn compute(&self) -> f64 {
+        if self.count == 0 {
+            0.
+        } else {
+            self.sum / self.count as f64
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default()
+    }
+}
+
+/// Levenshtein distance between two sequences.
+pub fn edit_distance<T: Eq>(a: &[T], b: &[T]) -> usize {
+    let mut prev = (0..=b.len()).collect::<Vec<_>>();
+    let mut curr = vec![0; b.len() + 1];
+    for (i, x) in a.iter().enumerate() {
+        curr[0] = i + 1;
+        for (j, y) in b.iter().enumerate() {
+            let substitution = prev[j] + usize::from(x != y);
+            curr[j + 1] = substitution.min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        std::mem::swap(&mut prev, &mut curr);
+    }
+    prev[b.len()]
+}
+
+/// Word error rate: the number of word substitutions, deletions, and insertions needed to turn
+/// the hypotheses into the references, divided by the number of reference words.
+#[derive(Debug, Clone, Default)]
+pub struct WordErrorRate {
+    errors: usize,
+    words: usize,
+}
+
+impl WordErrorRate {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a hypothesis and its reference, both are split on whitespaces.
+    pub fn update(&mut self, hypothesis: &str, reference: &str) {
+        let hypothesis = hypothesis.split_whitespace().collect::<Vec<_>>();
+        let reference = reference.split_whitespace().collect::<Vec<_>>();
+        self.update_tokens(&hypothesis, &reference)
+    }
+
+    /// Same as [`WordErrorRate::update`] for already tokenized sequences, this can be used with
+    /// characters to compute the character error rate.
+    pub fn update_tokens<T: Eq>(&mut self, hypothesis: &[T], reference: &[T]) {
+        self.errors += edit_distance(hypothesis, reference);
+        self.words += reference.len();
+    }
+
+    pub fn compute(&self) -> f64 {
+        ratio(self.errors as u64, self.words as u64)
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::default()
+    }
+}
@@ -0,0 +1,596 @@
+//! Mixture-of-experts routing.
+//!
+//! A [`Router`] scores the experts for each token and selects the `top_k` most likely ones. The
+//! resulting [`Routing`] groups the tokens by expert: [`Routing::dispatch`] gathers the hidden
+//! states of the tokens of each expert so that each expert runs once on all its tokens, and
+//! [`Routing::combine`] sums the expert outputs back in token order, weighted by the routing
+//! weights. The routing weights are differentiable so the router can be trained, usually with
+//! the auxiliary [`Routing::load_balancing_loss`] added to the model loss.
+//!
+//! When the experts have the same architecture, their weights can be stacked and applied to
+//! all the tokens at once with [`grouped_matmul`], see [`Routing::dispatch_grouped`].
+//!
+//! ```rust
+//! use candle::{Device, Tensor};
+//! use candle_nn::moe::{Router, RouterConfig};
+//! # fn main() -> candle::Result<()> {
+//! let dev = Device::Cpu;
+//! let gate = candle_nn::Linear::new(Tensor::randn(0f32, 1., (4, 8), &dev)?, None);
+//! let router = Router::new(gate, RouterConfig::new(4, 2));
+//! let experts = (0..4)
+//!     .map(|_| Ok(candle_nn::Linear::new(Tensor::randn(0f32, 1., (8, 8), &dev)?, None)))
+//!     .collect::<candle::Result<Vec<_>>>()?;
+//! let xs = Tensor::randn(0f32, 1., (2, 5, 8), &dev)?;
+//! let routing = router.route(&xs, false)?;
+//! let ys = routing.forward_experts(&xs, &experts)?;
+//! assert_eq!(ys.dims(), &[2, 5, 8]);
+//! # Ok(())
+//! # }
+//! ```
+use candle::{DType, Module, Result, Tensor, D};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RouterConfig {
+    pub num_experts: usize,
+    /// The number of experts each token is routed to.
+    pub top_k: usize,
+    /// Rescales the weights of the selected experts so that they sum to one, as done by Mixtral.
+    /// Otherwise the weights are the router probabilities, as done by Switch Transformers.
+    pub normalize_weights: bool,
+    /// The standard deviation of the gaussian noise added to the router logits when training,
+    /// this encourages the exploration of the experts. No noise is added when this is 0.
+    pub noise_std: f64,
+    /// Limits the number of tokens per expert to `capacity_factor * num_tokens * top_k /
+    /// num_experts`, the tokens above the capacity of an expert are dropped for this expert.
+    /// The assignments to the first choice experts are kept first.
+    pub capacity_factor: Option<f64>,
+}
+
+impl RouterConfig {
+    pub fn new(num_experts: usize, top_k: usize) -> Self {
+        Self {
+            num_experts,
+            top_k,
+            normalize_weights: true,
+            noise_std: 0.,
+            capacity_factor: None,
+        }
+    }
+
+    pub fn with_normalize_weights(mut self, normalize_weights: bool) -> Self {
+        self.normalize_weights = normalize_weights;
+        self
+    }
+
+    pub fn with_noise_std(mut self, noise_std: f64) -> Self {
+        self.noise_std = noise_std;
+        self
+    }
+
+    pub fn with_capacity_factor(mut self, capacity_factor: Option<f64>) -> Self {
+        self.capacity_factor = capacity_factor;
+        self
+    }
+
+    /// The maximum number of tokens per expert for a batch of `num_tokens` tokens.
+    pub fn capacity(&self, num_tokens: usize) -> Option<usize> {
+        self.capacity_factor.map(|factor| {
+            let capacity = factor * (num_tokens * self.top_k) as f64 / self.num_experts as f64;
+            (capacity.ceil() as usize).max(1)
+        })
+    }
+}
+
+/// A softmax top-k router, the gate is a linear layer from the hidden size to the number of
+/// experts.
+#[derive(Debug, Clone)]
+pub struct Router {
+    gate: crate::Linear,
+    config: RouterConfig,
+}
+
+impl Router {
+    pub fn new(gate: crate::Linear, config: RouterConfig) -> Self {
+        Self { gate, config }
+    }
+
+    pub fn gate(&self) -> &crate::Linear {
+        &self.gate
+    }
+
+    pub fn config(&self) -> &RouterConfig {
+        &self.config
+    }
+
+    /// Routes the tokens of `xs`, the last dimension being the hidden size. The noise is only
+    /// added to the router logits when `train` is set.
+    pub fn route(&self, xs: &Tensor, train: bool) -> Result<Routing> {
+        let RouterConfig {
+            num_experts,
+            top_k,
+            normalize_weights,
+            noise_std,
+            ..
+        } = self.config;
+        if top_k == 0 || top_k > num_experts {
+            candle::bail!("moe router: invalid top_k {top_k} for {num_experts} experts")
+        }
+        let hidden_size = xs.dim(D::Minus1)?;
+        let xs = xs.reshape(((), hidden_size))?;
+        let num_tokens = xs.dim(0)?;
+        if num_tokens == 0 {
+            candle::bail!("moe router: no tokens to route")
+        }
+        let logits = self.gate.forward(&xs)?.to_dtype(DType::F32)?;
+        let logits = if train && noise_std > 0. {
+            (&logits + logits.randn_like(0., noise_std)?)?
+        } else {
+            logits
+        };
+        // The fused softmax has no backward pass, the routing weights and the load balancing
+        // loss have to be differentiable with respect to the gate.
+        let probs = crate::ops::softmax(&logits, D::Minus1)?;
+        let experts = probs
+            .detach()
+            .arg_sort_last_dim(false)?
+            .narrow(D::Minus1, 0, top_k)?
+            .contiguous()?;
+        let weights = probs.gather(&experts, D::Minus1)?;
+        let weights = if normalize_weights {
+            weights.broadcast_div(&weights.sum_keepdim(D::Minus1)?)?
+        } else {
+            weights
+        };
+
+        // The assignments are grouped by expert, the first choices of all the tokens coming
+        // first so that they are kept when the capacity is exceeded.
+        let experts_v = experts.to_vec2::<u32>()?;
+        let capacity = self.config.capacity(num_tokens).unwrap_or(usize::MAX);
+        let mut assignments = vec![vec![]; num_experts];
+        let mut num_dropped = 0;
+        for slot in 0..top_k {
+            for (token, token_experts) in experts_v.iter().enumerate() {
+                let assigned = &mut assignments[token_experts[slot] as usize];
+                if assigned.len() < capacity {
+                    assigned.push((token as u32, (token * top_k + slot) as u32))
+                } else {
+                    num_dropped += 1
+                }
+            }
+        }
+        let flat_weights = weights.flatten_all()?;
+        let mut groups = vec![];
+        for (expert, mut assigned) in assignments.into_iter().enumerate() {
+            if assigned.is_empty() {
+                continue;
+            }
+            assigned.sort_unstable();
+            let (token_indices, weight_indices): (Vec<u32>, Vec<u32>) =
+                assigned.into_iter().unzip();
+            let weight_indices = Tensor::new(weight_indices, xs.device())?;
+            groups.push(ExpertGroup {
+                expert,
+                token_indices: Tensor::new(token_indices, xs.device())?,
+                weights: flat_weights
+                    .index_select(&weight_indices, 0)?
+                    .unsqueeze(1)?,
+            })
+        }
+        Ok(Routing {
+            probs,
+            experts,
+            weights,
+            groups,
+            num_tokens,
+            num_dropped,
+        })
+    }
+}
+
+/// Creates a router whose gate is a linear layer without bias named `vb`.
+pub fn router(hidden_size: usize, config: RouterConfig, vb: crate::VarBuilder) -> Result<Router> {
+    let gate = crate::linear_no_bias(hidden_size, config.num_experts, vb)?;
+    Ok(Router::new(gate, config))
+}
+
+/// The tokens routed to an expert.
+#[derive(Debug, Clone)]
+pub struct ExpertGroup {
+    pub expert: usize,
+    /// The indices of the tokens, in increasing order, as a u32 tensor.
+    pub token_indices: Tensor,
+    /// The routing weights of the tokens for this expert, with shape `(num_tokens, 1)`.
+    pub weights: Tensor,
+}
+
+/// The assignment of a batch of tokens to the experts.
+#[derive(Debug, Clone)]
+pub struct Routing {
+    /// The router probabilities, with shape `(num_tokens, num_experts)`.
+    pub probs: Tensor,
+    /// The selected experts of each token, with shape `(num_tokens, top_k)`.
+    pub experts: Tensor,
+    /// The weights of the selected experts, with shape `(num_tokens, top_k)`.
+    pub weights: Tensor,
+    groups: Vec<ExpertGroup>,
+    num_tokens: usize,
+    num_dropped: usize,
+}
+
+impl Routing {
+    /// The tokens of each expert, the experts without tokens are skipped.
+    pub fn groups(&self) -> &[ExpertGroup] {
+        &self.groups
+    }
+
+    pub fn num_tokens(&self) -> usize {
+        self.num_tokens
+    }
+
+    /// The number of token to expert assignments dropped because of the expert capacity.
+    pub fn num_dropped(&self) -> usize {
+        self.num_dropped
+    }
+
+    /// Gathers the hidden states of the tokens of each group, `xs` having the shape given to
+    /// [`Router::route`].
+    pub fn dispatch(&self, xs: &Tensor) -> Result<Vec<Tensor>> {
+        let xs = xs.reshape((self.num_tokens, ()))?;
+        self.groups
+            .iter()
+            .map(|group| xs.index_select(&group.token_indices, 0))
+            .collect()
+    }
+
+    /// Sums the outputs of the experts for each token weighted by the routing weights,
+    /// `expert_outputs` being aligned with the groups. Returns a tensor of shape
+    /// `(num_tokens, hidden_size)`, the tokens dropped by all their experts being zeros.
+    pub fn combine(&self, expert_outputs: &[Tensor]) -> Result<Tensor> {
+        if expert_outputs.len() != self.groups.len() {
+            candle::bail!(
+                "moe combine: got {} outputs for {} expert groups",
+                expert_outputs.len(),
+                self.groups.len()
+            )
+        }
+        let (dtype, device, hidden_size) = match expert_outputs.first() {
+            Some(ys) => (ys.dtype(), ys.device(), ys.dim(D::Minus1)?),
+            None => candle::bail!("moe combine: no expert outputs"),
+        };
+        let mut ys = Tensor::zeros((self.num_tokens, hidden_size), dtype, device)?;
+        for (group, outputs) in self.groups.iter().zip(expert_outputs.iter()) {
+            let outputs = outputs.broadcast_mul(&group.weights.to_dtype(dtype)?)?;
+            ys = ys.index_add(&group.token_indices, &outputs, 0)?;
+        }
+        Ok(ys)
+    }
+
+    /// The number of tokens routed to each of the `num_experts` experts, including the experts
+    /// without tokens, as expected by [`grouped_matmul`].
+    pub fn group_sizes(&self) -> Result<Vec<usize>> {
+        let mut sizes = vec![0; self.probs.dim(1)?];
+        for group in self.groups.iter() {
+            sizes[group.expert] = group.token_indices.dim(0)?
+        }
+        Ok(sizes)
+    }
+
+    /// Gathers the hidden states of the tokens of all the groups in a single tensor of shape
+    /// `(num_assignments, hidden_size)`, the tokens of each expert being consecutive.
+    ///
+    /// ```rust
+    /// use candle::{Device, Tensor};
+    /// use candle_nn::moe::{grouped_matmul, Router, RouterConfig};
+    /// # fn main() -> candle::Result<()> {
+    /// let dev = Device::Cpu;
+    /// let gate = candle_nn::Linear::new(Tensor::randn(0f32, 1., (4, 8), &dev)?, None);
+    /// let router = Router::new(gate, RouterConfig::new(4, 2));
+    /// // The weights of the four experts, with shape (num_experts, out_dim, in_dim).
+    /// let ws = Tensor::randn(0f32, 1., (4, 8, 8), &dev)?;
+    /// let xs = Tensor::randn(0f32, 1., (10, 8), &dev)?;
+    /// let routing = router.route(&xs, false)?;
+    /// let ys = grouped_matmul(&routing.dispatch_grouped(&xs)?, &ws, &routing.group_sizes()?)?;
+    /// let ys = routing.combine_grouped(&ys)?;
+    /// assert_eq!(ys.dims(), &[10, 8]);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn dispatch_grouped(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = xs.reshape((self.num_tokens, ()))?;
+        let token_indices: Vec<&Tensor> = self.groups.iter().map(|g| &g.token_indices).collect();
+        xs.index_select(&Tensor::cat(&token_indices, 0)?, 0)
+    }
+
+    /// Same as [`Self::combine`] for the outputs of the experts on the tensor returned by
+    /// [`Self::dispatch_grouped`].
+    pub fn combine_grouped(&self, expert_outputs: &Tensor) -> Result<Tensor> {
+        let mut outputs = Vec::with_capacity(self.groups.len());
+        let mut offset = 0;
+        for group in self.groups.iter() {
+            let size = group.token_indices.dim(0)?;
+            outputs.push(expert_outputs.narrow(0, offset, size)?);
+            offset += size
+        }
+        self.combine(&outputs)
+    }
+
+    /// Runs each expert on its tokens and combines their outputs, the result has the same
+    /// shape as `xs` assuming that the experts preserve the hidden size.
+    pub fn forward_experts<M: Module>(&self, xs: &Tensor, experts: &[M]) -> Result<Tensor> {
+        let mut outputs = Vec::with_capacity(self.groups.len());
+        for (group, xs) in self.groups.iter().zip(self.dispatch(xs)?) {
+            match experts.get(group.expert) {
+                Some(expert) => outputs.push(expert.forward(&xs)?),
+                None => candle::bail!("moe: no module for expert {}", group.expert),
+            }
+        }
+        self.combine(&outputs)?.reshape(xs.shape())
+    }
+
+    /// The auxiliary loss of Switch Transformers that encourages a uniform load across the
+    /// experts, `num_experts * sum_e f_e * p_e` where `f_e` is the fraction of the assignments
+    /// made to expert `e` and `p_e` the average router probability of this expert. This is 1
+    /// for a perfectly balanced routing, only `p_e` is differentiable.
+    pub fn load_balancing_loss(&self) -> Result<Tensor> {
+        let (num_tokens, num_experts) = self.probs.dims2()?;
+        let top_k = self.experts.dim(1)?;
+        let mut counts = vec![0f32; num_experts];
+        for expert in self.experts.flatten_all()?.to_vec1::<u32>()? {
+            counts[expert as usize] += 1.
+        }
+        let fractions = Tensor::new(counts, self.probs.device())?
+            .affine(1. / (num_tokens * top_k) as f64, 0.)?;
+        let mean_probs = self.probs.mean(0)?;
+        (fractions * mean_probs)?
+            .sum_all()?
+            .affine(num_experts as f64, 0.)
+    }
+}
+
+/// Reference implementation of [`grouped_matmul`] running one matmul per group.
+pub fn grouped_matmul_slow(xs: &Tensor, ws: &Tensor, group_sizes: &[usize]) -> Result<Tensor> {
+    let (num_rows, _) = xs.dims2()?;
+    let (_, out_dim, _) = ws.dims3()?;
+    let mut ys = Vec::with_capacity(group_sizes.len());
+    let mut offset = 0;
+    for (group, &size) in group_sizes.iter().enumerate() {
+        if size > 0 {
+            ys.push(xs.narrow(0, offset, size)?.matmul(&ws.get(group)?.t()?)?);
+        }
+        offset += size
+    }
+    if ys.is_empty() {
+        Tensor::zeros((num_rows, out_dim), xs.dtype(), xs.device())
+    } else {
+        Tensor::cat(&ys, 0)
+    }
+}
+
+#[derive(Debug, Clone)]
+struct GroupedMatmul {
+    group_sizes: Vec<usize>,
+}
+
+impl candle::CustomOp2 for GroupedMatmul {
+    fn name(&self) -> &'static str {
+        "grouped-matmul"
+    }
+
+    fn cpu_fwd(
+        &self,
+        s1: &candle::CpuStorage,
+        l1: &candle::Layout,
+        s2: &candle::CpuStorage,
+        l2: &candle::Layout,
+    ) -> Result<(candle::CpuStorage, candle::Shape)> {
+        use rayon::prelude::*;
+
+        fn inner<
+            T: candle::WithDType
+                + num_traits::Float
+                + num_traits::AsPrimitive<f32>
+                + num_traits::FromPrimitive,
+        >(
+            xs: &[T],
+            l1: &candle::Layout,
+            ws: &[T],
+            l2: &candle::Layout,
+            group_sizes: &[usize],
+        ) -> Result<(candle::CpuStorage, candle::Shape)> {
+            let xs = match l1.contiguous_offsets() {
+                None => candle::bail!("grouped-matmul input has to be contiguous"),
+                Some((o1, o2)) => &xs[o1..o2],
+            };
+            let ws = match l2.contiguous_offsets() {
+                None => candle::bail!("grouped-matmul weights have to be contiguous"),
+                Some((o1, o2)) => &ws[o1..o2],
+            };
+            let (num_rows, in_dim) = l1.shape().dims2()?;
+            let (_, out_dim, _) = l2.shape().dims3()?;
+            let row_groups: Vec<usize> = group_sizes
+                .iter()
+                .enumerate()
+                .flat_map(|(group, &size)| std::iter::repeat(group).take(size))
+                .collect();
+            // Each row is handled by a single thread, the weights of its group being read row
+            // by row so that the dot products run over contiguous memory.
+            let mut dst = vec![T::zero(); num_rows * out_dim];
+            dst.par_chunks_mut(out_dim)
+                .zip(xs.par_chunks(in_dim))
+                .zip(row_groups.par_iter())
+                .for_each(|((dst, xs), &group)| {
+                    let ws = &ws[group * out_dim * in_dim..(group + 1) * out_dim * in_dim];
+                    for (d, ws) in dst.iter_mut().zip(ws.chunks_exact(in_dim)) {
+                        let v = xs
+                            .iter()
+                            .zip(ws.iter())
+                            .map(|(&x, &w)| x.as_() * w.as_())
+                            .sum::<f32>();
+                        *d = T::from_f32(v).unwrap_or_else(T::nan)
+                    }
+                });
+            let storage = candle::WithDType::to_cpu_storage_owned(dst);
+            Ok((storage, candle::Shape::from((num_rows, out_dim))))
+        }
+
+        use candle::backend::BackendStorage;
+        use candle::CpuStorage as C;
+        let group_sizes = &self.group_sizes;
+        match (s1, s2) {
+            (C::BF16(s1), C::BF16(s2)) => inner::<half::bf16>(s1, l1, s2, l2, group_sizes),
+            (C::F16(s1), C::F16(s2)) => inner::<half::f16>(s1, l1, s2, l2, group_sizes),
+            (C::F32(s1), C::F32(s2)) => inner::<f32>(s1, l1, s2, l2, group_sizes),
+            _ => candle::bail!("unsupported dtype for grouped-matmul {:?}", s1.dtype()),
+        }
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &candle::Layout,
+        s2: &candle::CudaStorage,
+        l2: &candle::Layout,
+    ) -> Result<(candle::CudaStorage, candle::Shape)> {
+        use candle::cuda_backend::cudarc::driver::{
+            CudaSlice, DeviceRepr, LaunchAsync, LaunchConfig,
+        };
+        use candle::cuda_backend::{kernel_name, kernels, Map2, WrapErr};
+        use candle::{CudaDevice, Layout, WithDType};
+
+        const TILE: usize = 16;
+
+        struct S<'a> {
+            group_sizes: &'a [usize],
+        }
+        impl Map2 for S<'_> {
+            fn f<T: DeviceRepr + WithDType>(
+                &self,
+                xs: &CudaSlice<T>,
+                l1: &Layout,
+                ws: &CudaSlice<T>,
+                l2: &Layout,
+                dev: &CudaDevice,
+            ) -> Result<CudaSlice<T>> {
+                let xs = match l1.contiguous_offsets() {
+                    None => candle::bail!("grouped-matmul input has to be contiguous"),
+                    Some((o1, o2)) => xs.slice(o1..o2),
+                };
+                let ws = match l2.contiguous_offsets() {
+                    None => candle::bail!("grouped-matmul weights have to be contiguous"),
+                    Some((o1, o2)) => ws.slice(o1..o2),
+                };
+                let (num_rows, in_dim) = l1.shape().dims2()?;
+                let (num_groups, out_dim, _) = l2.shape().dims3()?;
+                let mut offsets = Vec::with_capacity(num_groups + 1);
+                offsets.push(0u32);
+                for &size in self.group_sizes.iter() {
+                    offsets.push(offsets[offsets.len() - 1] + size as u32)
+                }
+                let offsets = dev.htod_sync_copy(&offsets).w()?;
+                let max_group_size = self.group_sizes.iter().copied().max().unwrap_or(0);
+                // All the groups are handled by a single launch, see moe.cu.
+                let cfg = LaunchConfig {
+                    grid_dim: (
+                        out_dim.div_ceil(TILE) as u32,
+                        max_group_size.div_ceil(TILE) as u32,
+                        num_groups as u32,
+                    ),
+                    block_dim: (TILE as u32, TILE as u32, 1),
+                    shared_mem_bytes: 0,
+                };
+                let func =
+                    dev.get_or_load_func(&kernel_name::<T>("grouped_matmul"), kernels::MOE)?;
+                // SAFETY: Set later by running the kernel, the groups cover all the rows.
+                let dst = unsafe { dev.alloc::<T>(num_rows * out_dim) }.w()?;
+                let params = (&xs, &ws, &dst, &offsets, in_dim as u32, out_dim as u32);
+                // SAFETY: ffi.
+                unsafe { func.launch(cfg, params) }.w()?;
+                Ok(dst)
+            }
+        }
+
+        use candle::backend::BackendStorage;
+        let dev = s1.device();
+        let group_sizes = &self.group_sizes;
+        let slice = S { group_sizes }.map(&s1.slice, l1, &s2.slice, l2, dev)?;
+        let dst = candle::cuda_backend::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        let (num_rows, _) = l1.shape().dims2()?;
+        let (_, out_dim, _) = l2.shape().dims3()?;
+        Ok((dst, candle::Shape::from((num_rows, out_dim))))
+    }
+
+    fn bwd(
+        &self,
+        xs: &Tensor,
+        ws: &Tensor,
+        _res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>)> {
+        let (_, out_dim, in_dim) = ws.dims3()?;
+        let grad_xs = grouped_matmul(grad_res, &ws.transpose(1, 2)?, &self.group_sizes)?;
+        let mut grad_ws = Vec::with_capacity(self.group_sizes.len());
+        let mut offset = 0;
+        for &size in self.group_sizes.iter() {
+            let grad_w = if size == 0 {
+                Tensor::zeros((out_dim, in_dim), ws.dtype(), ws.device())?
+            } else {
+                let grad_res = grad_res.narrow(0, offset, size)?;
+                grad_res.t()?.matmul(&xs.narrow(0, offset, size)?)?
+            };
+            grad_ws.push(grad_w);
+            offset += size
+        }
+        Ok((Some(grad_xs), Some(Tensor::stack(&grad_ws, 0)?)))
+    }
+}
+
+/// Multiplies groups of consecutive rows of `xs` by their own weight matrix, typically the
+/// tokens routed to each expert by their expert weights.
+///
+/// `xs` has shape `(num_rows, in_dim)` and `ws` has shape `(num_groups, out_dim, in_dim)`,
+/// the first `group_sizes[0]` rows are multiplied by `ws[0].t()`, the next `group_sizes[1]` rows
+/// by `ws[1].t()` and so on, empty groups being allowed. The result has shape
+/// `(num_rows, out_dim)`.
+///
+/// On cuda devices all the groups are computed by a single kernel launch and on cpu the rows
+/// are processed in parallel, the accumulation being done in f32 in both cases. The other
+/// devices and dtypes use [`grouped_matmul_slow`].
+///
+/// ```rust
+/// use candle::{Device, Tensor};
+/// let xs = Tensor::new(&[[1f32, 2.], [3., 4.], [5., 6.]], &Device::Cpu)?;
+/// let ws = Tensor::new(&[[[1f32, 0.], [0., 1.]], [[0., 1.], [1., 0.]]], &Device::Cpu)?;
+/// let ys = candle_nn::moe::grouped_matmul(&xs, &ws, &[1, 2])?;
+/// assert_eq!(ys.to_vec2::<f32>()?, [[1., 2.], [4., 3.], [6., 5.]]);
+/// # Ok::<(), candle::Error>(())
+/// ```
+pub fn grouped_matmul(xs: &Tensor, ws: &Tensor, group_sizes: &[usize]) -> Result<Tensor> {
+    let (num_rows, in_dim) = xs.dims2()?;
+    let (num_groups, out_dim, w_in_dim) = ws.dims3()?;
+    if in_dim != w_in_dim
+        || num_groups != group_sizes.len()
+        || group_sizes.iter().sum::<usize>() != num_rows
+    {
+        candle::bail!(
+            "shape mismatch in grouped-matmul xs: {:?} ws: {:?} group sizes: {group_sizes:?}",
+            xs.shape(),
+            ws.shape()
+        )
+    }
+    let use_op = (xs.device().is_cpu() || xs.device().is_cuda())
+        && matches!(xs.dtype(), DType::F32 | DType::F16 | DType::BF16);
+    if !use_op {
+        return grouped_matmul_slow(xs, ws, group_sizes);
+    }
+    if xs.elem_count() == 0 || ws.elem_count() == 0 {
+        return Tensor::zeros((num_rows, out_dim), xs.dtype(), xs.device());
+    }
+    let op = GroupedMatmul {
+        group_sizes: group_sizes.to_vec(),
+    };
+    xs.contiguous()?.apply_op2(&ws.contiguous()?, op)
+}
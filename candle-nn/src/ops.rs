@@ -34,6 +34,41 @@ pub fn log_softmax<D: candle::shape::Dim>(xs: &Tensor, d: D) -> Result<Tensor> {
     Ok(log_sm)
 }
 
+/// Returns log(sum(exp(xs))) over dimension `d`, keeping the reduced dimension. The values are
+/// processed in chunks of `chunk_size` elements along `d` so that the exponentials are never
+/// materialized for the whole dimension, which matters for large vocabularies.
+pub fn log_sum_exp_chunked<D: candle::shape::Dim>(
+    xs: &Tensor,
+    d: D,
+    chunk_size: usize,
+) -> Result<Tensor> {
+    let d = d.to_index(xs.shape(), "log-sum-exp-chunked")?;
+    if chunk_size == 0 {
+        candle::bail!("log-sum-exp-chunked: chunk_size must be positive")
+    }
+    let dim_size = xs.dim(d)?;
+    let lse = (0..dim_size)
+        .step_by(chunk_size)
+        .map(|start| {
+            xs.narrow(d, start, chunk_size.min(dim_size - start))?
+                .log_sum_exp_keepdim(d)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Tensor::cat(&lse, d)?.log_sum_exp_keepdim(d)
+}
+
+/// Similar to `log_softmax` but the normalization is computed by chunks of `chunk_size` elements
+/// along `d`, see `log_sum_exp_chunked`. The only tensor with the full size of the input is the
+/// result.
+pub fn log_softmax_chunked<D: candle::shape::Dim>(
+    xs: &Tensor,
+    d: D,
+    chunk_size: usize,
+) -> Result<Tensor> {
+    let lse = log_sum_exp_chunked(xs, d, chunk_size)?;
+    xs.broadcast_sub(&lse)
+}
+
 pub fn silu(xs: &Tensor) -> Result<Tensor> {
     xs.silu()
 }
@@ -221,6 +256,14 @@ impl candle::CustomOp1 for Sigmoid {
         Ok((new_storage, layout.shape().clone()))
     }
 
+    fn meta_fwd(
+        &self,
+        storage: &candle::MetaStorage,
+        layout: &Layout,
+    ) -> Result<(candle::MetaStorage, Shape)> {
+        Ok((*storage, layout.shape().clone()))
+    }
+
     fn bwd(&self, _arg: &Tensor, res: &Tensor, grad_res: &Tensor) -> Result<Option<Tensor>> {
         // d/dx sigmoid(x) = (1 - sigmoid(x)) * sigmoid(x)
         let d_dx_sigmoid = res.ones_like()?.sub(res)?.mul(res)?;
@@ -425,9 +468,21 @@ impl candle::CustomOp1 for SoftmaxLastDim {
             candle::MetalStorage::new(output, device.clone(), elem_count, storage.dtype());
         Ok((newstorage, layout.shape().clone()))
     }
+
+    fn meta_fwd(
+        &self,
+        storage: &candle::MetaStorage,
+        layout: &Layout,
+    ) -> Result<(candle::MetaStorage, Shape)> {
+        Ok((*storage, layout.shape().clone()))
+    }
 }
 
 pub fn softmax_last_dim(xs: &Tensor) -> Result<Tensor> {
+    // There is no dedicated wgpu kernel, the generic version avoids going through the cpu.
+    if xs.device().is_wgpu() {
+        return softmax(xs, D::Minus1);
+    }
     xs.apply_op1_no_bwd(&SoftmaxLastDim)
 }
 
@@ -612,6 +667,16 @@ impl candle::CustomOp2 for RmsNorm {
         let newstorage = candle::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
         Ok((newstorage, l1.shape().clone()))
     }
+
+    fn meta_fwd(
+        &self,
+        s1: &candle::MetaStorage,
+        l1: &Layout,
+        _: &candle::MetaStorage,
+        _: &Layout,
+    ) -> Result<(candle::MetaStorage, Shape)> {
+        Ok((*s1, l1.shape().clone()))
+    }
 }
 
 pub fn rms_norm_slow(x: &Tensor, alpha: &Tensor, eps: f32) -> Result<Tensor> {
@@ -637,6 +702,9 @@ pub fn rms_norm(xs: &Tensor, alpha: &Tensor, eps: f32) -> Result<Tensor> {
             alpha.shape()
         )
     }
+    if xs.device().is_wgpu() {
+        return rms_norm_slow(xs, alpha, eps);
+    }
     xs.apply_op2_no_bwd(alpha, &RmsNorm { eps })
 }
 
@@ -851,6 +919,18 @@ impl candle::CustomOp3 for LayerNorm {
         let newstorage = candle::MetalStorage::new(output, device.clone(), elem_count, s1.dtype());
         Ok((newstorage, l1.shape().clone()))
     }
+
+    fn meta_fwd(
+        &self,
+        s1: &candle::MetaStorage,
+        l1: &Layout,
+        _: &candle::MetaStorage,
+        _: &Layout,
+        _: &candle::MetaStorage,
+        _: &Layout,
+    ) -> Result<(candle::MetaStorage, Shape)> {
+        Ok((*s1, l1.shape().clone()))
+    }
 }
 
 pub fn layer_norm_slow(x: &Tensor, alpha: &Tensor, beta: &Tensor, eps: f32) -> Result<Tensor> {
@@ -885,9 +965,365 @@ pub fn layer_norm(xs: &Tensor, alpha: &Tensor, beta: &Tensor, eps: f32) -> Resul
             beta.shape()
         )
     }
+    if xs.device().is_wgpu() {
+        return layer_norm_slow(xs, alpha, beta, eps);
+    }
     xs.apply_op3_no_bwd(alpha, beta, &LayerNorm { eps })
 }
 
+/// The activations that can be fused with the matmul in [`fused_linear`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FusedActivation {
+    Relu,
+    /// The tanh approximation of gelu, as computed by [`Tensor::gelu`].
+    Gelu,
+}
+
+impl FusedActivation {
+    fn apply(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Relu => xs.relu(),
+            Self::Gelu => xs.gelu(),
+        }
+    }
+
+    /// The derivative of the activation evaluated at `xs`.
+    fn grad(&self, xs: &Tensor) -> Result<Tensor> {
+        match self {
+            Self::Relu => xs.gt(0.)?.to_dtype(xs.dtype()),
+            Self::Gelu => {
+                const SQRT_TWO_OVER_PI: f64 = 0.7978845608028654;
+                let xs2 = xs.sqr()?;
+                let tanh = ((xs * (xs2.affine(0.044715, 1.)? * SQRT_TWO_OVER_PI)?)?).tanh()?;
+                let d_inner = (xs2.affine(3. * 0.044715, 1.)? * SQRT_TWO_OVER_PI)?;
+                let d_tanh = (tanh.sqr()?.affine(-1., 1.)? * d_inner)?;
+                ((tanh + 1.)? * 0.5)? + ((xs * d_tanh)? * 0.5)?
+            }
+        }
+    }
+}
+
+pub fn fused_linear_slow(
+    xs: &Tensor,
+    w: &Tensor,
+    bias: Option<&Tensor>,
+    act: Option<FusedActivation>,
+) -> Result<Tensor> {
+    let ys = xs.broadcast_matmul(&w.t()?)?;
+    let ys = match bias {
+        None => ys,
+        Some(bias) => ys.broadcast_add(bias)?,
+    };
+    match act {
+        None => Ok(ys),
+        Some(act) => act.apply(&ys),
+    }
+}
+
+#[derive(Debug, Clone)]
+struct FusedLinear {
+    act: Option<FusedActivation>,
+}
+
+impl FusedLinear {
+    fn bwd_(
+        &self,
+        xs: &Tensor,
+        w: &Tensor,
+        bias: Option<&Tensor>,
+        grad_res: &Tensor,
+    ) -> Result<(Tensor, Tensor, Tensor)> {
+        let grad = match self.act {
+            None => grad_res.clone(),
+            Some(act) => {
+                let pre_act = fused_linear_slow(xs, w, bias, None)?;
+                (grad_res * act.grad(&pre_act)?)?
+            }
+        };
+        let (out_dim, in_dim) = w.dims2()?;
+        let grad_xs = grad.broadcast_matmul(w)?;
+        let grad_2d = grad.reshape(((), out_dim))?;
+        let grad_w = grad_2d.t()?.matmul(&xs.reshape(((), in_dim))?)?;
+        let grad_bias = grad_2d.sum(0)?;
+        Ok((grad_xs, grad_w, grad_bias))
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd_(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+        bias: Option<(&candle::CudaStorage, &Layout)>,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        use candle::backend::BackendStorage;
+        use candle::cuda_backend::cudarc::cublaslt::{
+            Activation, CudaBlasLT, Matmul, MatmulConfig,
+        };
+        use candle::cuda_backend::cudarc::driver::{CudaSlice, CudaView};
+        use candle::cuda_backend::{CudaStorageSlice as S, WrapErr};
+        use candle::CudaDevice;
+        use std::collections::HashMap;
+        use std::sync::{Arc, Mutex};
+
+        // The cublasLt handles and their workspaces are created lazily, once per device.
+        static BLAS_LT: Mutex<Option<HashMap<candle::cuda_backend::DeviceId, Arc<CudaBlasLT>>>> =
+            Mutex::new(None);
+
+        fn view<'a, T>(s: &'a CudaSlice<T>, l: &Layout, name: &str) -> Result<CudaView<'a, T>> {
+            match l.contiguous_offsets() {
+                None => candle::bail!("fused-linear {name} has to be contiguous"),
+                Some((o1, o2)) => Ok(s.slice(o1..o2)),
+            }
+        }
+
+        fn launch<T>(
+            blas_lt: &CudaBlasLT,
+            cfg: MatmulConfig,
+            (xs, l1): (&CudaSlice<T>, &Layout),
+            (w, l2): (&CudaSlice<T>, &Layout),
+            bias: Option<(&CudaSlice<T>, &Layout)>,
+            act: Option<&Activation>,
+            dev: &CudaDevice,
+        ) -> Result<CudaSlice<T>>
+        where
+            CudaBlasLT: Matmul<T>,
+            T: candle::cuda_backend::cudarc::driver::DeviceRepr
+                + candle::cuda_backend::cudarc::driver::ValidAsZeroBits,
+        {
+            let xs = view(xs, l1, "input")?;
+            let w = view(w, l2, "weight")?;
+            let bias = match bias {
+                None => None,
+                Some((b, l)) => Some(view(b, l, "bias")?),
+            };
+            let mut dst = dev.alloc_zeros::<T>((cfg.m * cfg.n) as usize).w()?;
+            // SAFETY: the layouts have been checked to be contiguous and the shapes to match.
+            unsafe { blas_lt.matmul(cfg, &w, &xs, &mut dst, bias.as_ref(), act) }
+                .map_err(candle::Error::wrap)?;
+            Ok(dst)
+        }
+
+        let dev = &s1.device;
+        let blas_lt = {
+            let mut blas_lt = BLAS_LT
+                .lock()
+                .map_err(|e| candle::Error::Msg(format!("cublaslt lock: {e}")))?;
+            let blas_lt = blas_lt.get_or_insert_with(HashMap::new);
+            match blas_lt.get(&dev.id()) {
+                Some(b) => b.clone(),
+                None => {
+                    let b =
+                        Arc::new(CudaBlasLT::new(dev.cuda_device()).map_err(candle::Error::wrap)?);
+                    blas_lt.insert(dev.id(), b.clone());
+                    b
+                }
+            }
+        };
+        let (out_dim, in_dim) = l2.shape().dims2()?;
+        let dims = l1.dims();
+        let n_rows = l1.shape().elem_count() / in_dim;
+        // cublasLt uses column major matrices, the output is computed as `w^T xs`, see
+        // https://docs.nvidia.com/cuda/cublas/#cublasltmatmul
+        let cfg = MatmulConfig {
+            transa: true,
+            transb: false,
+            m: out_dim as u64,
+            n: n_rows as u64,
+            k: in_dim as u64,
+            alpha: 1.,
+            lda: in_dim as i64,
+            ldb: in_dim as i64,
+            beta: 0.,
+            ldc: out_dim as i64,
+            stride_a: None,
+            stride_b: None,
+            stride_c: None,
+            stride_bias: None,
+            batch_size: None,
+        };
+        let act = match self.act {
+            None => None,
+            Some(FusedActivation::Relu) => Some(Activation::Relu),
+            Some(FusedActivation::Gelu) => Some(Activation::Gelu),
+        };
+        let act = act.as_ref();
+        let slice = match (&s1.slice, &s2.slice, bias.map(|(b, l)| (&b.slice, l))) {
+            (S::F32(xs), S::F32(w), None) => {
+                S::F32(launch(&blas_lt, cfg, (xs, l1), (w, l2), None, act, dev)?)
+            }
+            (S::F32(xs), S::F32(w), Some((S::F32(b), lb))) => S::F32(launch(
+                &blas_lt,
+                cfg,
+                (xs, l1),
+                (w, l2),
+                Some((b, lb)),
+                act,
+                dev,
+            )?),
+            (S::F16(xs), S::F16(w), None) => {
+                S::F16(launch(&blas_lt, cfg, (xs, l1), (w, l2), None, act, dev)?)
+            }
+            (S::F16(xs), S::F16(w), Some((S::F16(b), lb))) => S::F16(launch(
+                &blas_lt,
+                cfg,
+                (xs, l1),
+                (w, l2),
+                Some((b, lb)),
+                act,
+                dev,
+            )?),
+            (S::BF16(xs), S::BF16(w), None) => {
+                S::BF16(launch(&blas_lt, cfg, (xs, l1), (w, l2), None, act, dev)?)
+            }
+            (S::BF16(xs), S::BF16(w), Some((S::BF16(b), lb))) => S::BF16(launch(
+                &blas_lt,
+                cfg,
+                (xs, l1),
+                (w, l2),
+                Some((b, lb)),
+                act,
+                dev,
+            )?),
+            _ => candle::bail!("unsupported dtype for fused-linear {:?}", s1.dtype()),
+        };
+        let mut out_dims = dims.to_vec();
+        *out_dims.last_mut().unwrap() = out_dim;
+        let dst = candle::CudaStorage {
+            slice,
+            device: dev.clone(),
+        };
+        Ok((dst, Shape::from_dims(&out_dims)))
+    }
+}
+
+impl candle::CustomOp2 for FusedLinear {
+    fn name(&self) -> &'static str {
+        "fused-linear"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        candle::bail!("fused-linear is only implemented on cuda, use fused_linear_slow")
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        self.cuda_fwd_(s1, l1, s2, l2, None)
+    }
+
+    fn bwd(
+        &self,
+        xs: &Tensor,
+        w: &Tensor,
+        _res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>)> {
+        let (grad_xs, grad_w, _) = self.bwd_(xs, w, None, grad_res)?;
+        Ok((Some(grad_xs), Some(grad_w)))
+    }
+}
+
+impl candle::CustomOp3 for FusedLinear {
+    fn name(&self) -> &'static str {
+        "fused-linear"
+    }
+
+    fn cpu_fwd(
+        &self,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+        _: &CpuStorage,
+        _: &Layout,
+    ) -> Result<(CpuStorage, Shape)> {
+        candle::bail!("fused-linear is only implemented on cuda, use fused_linear_slow")
+    }
+
+    #[cfg(feature = "cuda")]
+    fn cuda_fwd(
+        &self,
+        s1: &candle::CudaStorage,
+        l1: &Layout,
+        s2: &candle::CudaStorage,
+        l2: &Layout,
+        s3: &candle::CudaStorage,
+        l3: &Layout,
+    ) -> Result<(candle::CudaStorage, Shape)> {
+        self.cuda_fwd_(s1, l1, s2, l2, Some((s3, l3)))
+    }
+
+    fn bwd(
+        &self,
+        xs: &Tensor,
+        w: &Tensor,
+        bias: &Tensor,
+        _res: &Tensor,
+        grad_res: &Tensor,
+    ) -> Result<(Option<Tensor>, Option<Tensor>, Option<Tensor>)> {
+        let (grad_xs, grad_w, grad_bias) = self.bwd_(xs, w, Some(bias), grad_res)?;
+        Ok((Some(grad_xs), Some(grad_w), Some(grad_bias)))
+    }
+}
+
+/// Whether [`fused_linear`] uses cublasLt for this input.
+pub(crate) fn use_cublaslt(xs: &Tensor) -> bool {
+    xs.device().is_cuda()
+        && match xs.dtype() {
+            DType::F16 | DType::BF16 => true,
+            DType::F32 => candle::cuda::gemm_reduced_precision_f32(),
+            _ => false,
+        }
+}
+
+/// Computes `act(xs @ w.t() + bias)` where `xs` has shape `(.., in_dim)` and `w` has shape
+/// `(out_dim, in_dim)`.
+///
+/// On cuda devices this uses a single cublasLt matmul with the bias addition and the activation
+/// fused in its epilogue, the algorithm being selected by the cublasLt heuristics. This is used
+/// for f16 and bf16 and for f32 when reduced precision is enabled with
+/// [`candle::cuda::set_gemm_reduced_precision_f32`] as cublasLt uses tf32 in this case. The
+/// other devices and dtypes use [`fused_linear_slow`].
+pub fn fused_linear(
+    xs: &Tensor,
+    w: &Tensor,
+    bias: Option<&Tensor>,
+    act: Option<FusedActivation>,
+) -> Result<Tensor> {
+    let (out_dim, in_dim) = w.dims2()?;
+    if xs.dim(D::Minus1)? != in_dim || bias.is_some_and(|b| b.dims1().ok() != Some(out_dim)) {
+        candle::bail!(
+            "shape mismatch in fused-linear xs: {:?} w: {:?} bias: {:?}",
+            xs.shape(),
+            w.shape(),
+            bias.map(|b| b.shape())
+        )
+    }
+    if !use_cublaslt(xs) {
+        return fused_linear_slow(xs, w, bias, act);
+    }
+    let op = FusedLinear { act };
+    let xs = xs.contiguous()?;
+    let w = w.contiguous()?;
+    match bias {
+        None => xs.apply_op2(&w, op),
+        Some(bias) => xs.apply_op3(&w, &bias.contiguous()?, op),
+    }
+}
+
 // https://pytorch.org/docs/stable/generated/torch.nn.PixelShuffle.html
 pub fn pixel_shuffle(xs: &Tensor, upscale_factor: usize) -> Result<Tensor> {
     let (b_size, c, h, w) = xs.dims4()?;
@@ -198,4 +198,124 @@ impl AdamW {
     pub fn set_params(&mut self, params: ParamsAdamW) {
         self.params = params;
     }
+
+    /// The number of optimization steps performed so far.
+    pub fn step_count(&self) -> usize {
+        self.step_t
+    }
+}
+
+/// Adds the gradients of `vars` from `grads`, multiplied by `scale`, to the ones in `acc`.
+pub(crate) fn accumulate_grads(
+    acc: &mut candle::backprop::GradStore,
+    grads: &candle::backprop::GradStore,
+    vars: &[Var],
+    scale: f64,
+) -> Result<()> {
+    for var in vars.iter() {
+        if let Some(grad) = grads.get(var) {
+            let grad = if scale == 1. {
+                grad.clone()
+            } else {
+                grad.affine(scale, 0.)?
+            };
+            let grad = match acc.remove(var) {
+                Some(prev) => (prev + grad)?,
+                None => grad,
+            };
+            acc.insert(var, grad);
+        }
+    }
+    Ok(())
+}
+
+/// Wraps an optimizer so that the gradients are accumulated over `accum_steps` micro-batches.
+///
+/// Each call to `step` or `backward_step` adds the gradients divided by `accum_steps` to the
+/// accumulated gradients, the inner optimizer only updates the variables every `accum_steps`
+/// calls, after which the accumulated gradients are reset. The resulting update is the same as
+/// the one for the mean of the micro-batch losses.
+pub struct GradAccumulation<O: Optimizer> {
+    optimizer: O,
+    vars: Vec<Var>,
+    accum_steps: usize,
+    micro_step: usize,
+    grads: candle::backprop::GradStore,
+}
+
+impl<O: Optimizer> Optimizer for GradAccumulation<O> {
+    type Config = (O::Config, usize);
+
+    fn new(vars: Vec<Var>, (config, accum_steps): Self::Config) -> Result<Self> {
+        let optimizer = O::new(vars.clone(), config)?;
+        Self::from_optimizer(optimizer, vars, accum_steps)
+    }
+
+    fn learning_rate(&self) -> f64 {
+        self.optimizer.learning_rate()
+    }
+
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.optimizer.set_learning_rate(lr)
+    }
+
+    fn step(&mut self, grads: &candle::backprop::GradStore) -> Result<()> {
+        let scale = 1. / self.accum_steps as f64;
+        accumulate_grads(&mut self.grads, grads, &self.vars, scale)?;
+        self.micro_step += 1;
+        if self.micro_step == self.accum_steps {
+            let grads = std::mem::replace(&mut self.grads, candle::backprop::GradStore::new());
+            self.micro_step = 0;
+            self.optimizer.step(&grads)?;
+        }
+        Ok(())
+    }
+}
+
+impl<O: Optimizer> GradAccumulation<O> {
+    /// Wraps an existing optimizer, `vars` should be the variables that it optimizes.
+    pub fn from_optimizer(optimizer: O, vars: Vec<Var>, accum_steps: usize) -> Result<Self> {
+        if accum_steps == 0 {
+            candle::bail!("the number of accumulation steps must be positive")
+        }
+        Ok(Self {
+            optimizer,
+            vars,
+            accum_steps,
+            micro_step: 0,
+            grads: candle::backprop::GradStore::new(),
+        })
+    }
+
+    pub fn accum_steps(&self) -> usize {
+        self.accum_steps
+    }
+
+    /// The number of micro-batches accumulated since the last update of the variables.
+    pub fn micro_step(&self) -> usize {
+        self.micro_step
+    }
+
+    /// Returns true if the next call to `step` updates the variables.
+    pub fn is_sync_step(&self) -> bool {
+        self.micro_step + 1 == self.accum_steps
+    }
+
+    /// Discards the gradients accumulated since the last update.
+    pub fn zero_grad(&mut self) {
+        self.grads = candle::backprop::GradStore::new();
+        self.micro_step = 0;
+    }
+
+    pub fn inner(&self) -> &O {
+        &self.optimizer
+    }
+
+    pub fn inner_mut(&mut self) -> &mut O {
+        &mut self.optimizer
+    }
+
+    pub fn into_inner(self) -> O {
+        self.optimizer
+    }
 }
@@ -0,0 +1,126 @@
+//! Structured pruning.
+//!
+//! Structured pruning removes whole channels of the weight matrices, e.g. attention heads or
+//! hidden units of an MLP, so that the pruned model is a genuinely smaller dense network rather
+//! than a sparse one. The channels to keep are selected using importance scores, either based on
+//! the weight magnitudes only or on the weights combined with activation statistics collected
+//! with a [`Calibrator`](crate::calibration::Calibrator) as in Wanda,
+//! <https://arxiv.org/abs/2306.11695>.
+//!
+//! The pruned weights can be loaded in a [`VarMap`](crate::VarMap) with
+//! [`VarMap::from_tensors`](crate::VarMap::from_tensors) to fine-tune the pruned model, e.g.
+//! using the original model as a teacher with [`crate::distillation`].
+//!
+//! ```rust
+//! use candle::{Device, Tensor};
+//! use candle_nn::pruning;
+//! # fn main() -> candle::Result<()> {
+//! let dev = &Device::Cpu;
+//! // An MLP with 8 hidden units, linear weights have shape (out_dim, in_dim).
+//! let up = Tensor::randn(0f32, 1., (8, 4), dev)?;
+//! let down = Tensor::randn(0f32, 1., (4, 8), dev)?;
+//! let scores = pruning::mlp_scores(&[&up], &down, None)?;
+//! let keep = pruning::top_k(&scores, 6)?;
+//! let up = pruning::select_rows(&up, &keep)?;
+//! let down = pruning::select_cols(&down, &keep)?;
+//! assert_eq!(up.dims(), [6, 4]);
+//! assert_eq!(down.dims(), [4, 6]);
+//! # Ok(())
+//! # }
+//! ```
+use candle::{DType, Result, Tensor, D};
+
+/// The L2 norms of the rows of a 2D weight, the output channels of a linear layer.
+pub fn row_norms(weight: &Tensor) -> Result<Tensor> {
+    weight.to_dtype(DType::F32)?.sqr()?.sum(1)?.sqrt()
+}
+
+/// The L2 norms of the columns of a 2D weight, the input channels of a linear layer.
+pub fn col_norms(weight: &Tensor) -> Result<Tensor> {
+    weight.to_dtype(DType::F32)?.sqr()?.sum(0)?.sqrt()
+}
+
+/// The importance of the hidden units of an MLP, with shape `(hidden,)`.
+///
+/// `inputs` are the weights producing the hidden units, e.g. the gate and up projections with
+/// shape `(hidden, dim)`, and `output` is the weight consuming them with shape `(dim, hidden)`.
+/// Without activation statistics, the score of a unit is the product of the norms of its weights.
+/// When the mean squared values of the hidden units are given, the score is the expected norm of
+/// the unit contribution to the output, `|W_out[:, i]| * sqrt(E[h_i^2])`.
+pub fn mlp_scores(inputs: &[&Tensor], output: &Tensor, mean_sq: Option<&Tensor>) -> Result<Tensor> {
+    let out_norms = col_norms(output)?;
+    match mean_sq {
+        Some(mean_sq) => out_norms * mean_sq.to_dtype(DType::F32)?.sqrt()?,
+        None => {
+            let mut scores = out_norms;
+            for w in inputs {
+                scores = (scores * row_norms(w)?)?
+            }
+            Ok(scores)
+        }
+    }
+}
+
+/// The importance of the attention heads, with shape `(num_heads,)`, computed from the output
+/// projection with shape `(dim, num_heads * head_dim)`.
+///
+/// Without activation statistics, the score of a head is the norm of its slice of the output
+/// projection. With `mean_sq`, the mean squared values of the output projection inputs, the
+/// columns are weighted by the corresponding activations.
+pub fn head_scores(o_proj: &Tensor, num_heads: usize, mean_sq: Option<&Tensor>) -> Result<Tensor> {
+    let sq_norms = col_norms(o_proj)?.sqr()?;
+    let sq_norms = match mean_sq {
+        Some(mean_sq) => (sq_norms * mean_sq.to_dtype(DType::F32)?)?,
+        None => sq_norms,
+    };
+    sq_norms.reshape((num_heads, ()))?.sum(1)?.sqrt()
+}
+
+/// Sums the scores of consecutive groups of `group_size` values, e.g. to score the key-value
+/// groups of grouped-query attention from the scores of the query heads.
+pub fn group_scores(scores: &Tensor, group_size: usize) -> Result<Tensor> {
+    scores.reshape(((), group_size))?.sum(D::Minus1)
+}
+
+/// The indices of the `k` largest scores, in increasing order so that the relative order of the
+/// kept channels is preserved.
+pub fn top_k(scores: &Tensor, k: usize) -> Result<Vec<u32>> {
+    let scores = scores.to_dtype(DType::F32)?.to_vec1::<f32>()?;
+    if k > scores.len() {
+        candle::bail!("cannot keep {k} channels out of {}", scores.len())
+    }
+    let mut indices = (0..scores.len() as u32).collect::<Vec<_>>();
+    indices.sort_by(|&i, &j| scores[j as usize].total_cmp(&scores[i as usize]));
+    indices.truncate(k);
+    indices.sort();
+    Ok(indices)
+}
+
+/// Expands the indices of groups of `group_size` channels to the indices of the channels, e.g.
+/// the indices of attention heads to the indices of the rows of the query projection.
+pub fn expand_groups(groups: &[u32], group_size: usize) -> Vec<u32> {
+    groups
+        .iter()
+        .flat_map(|&g| (0..group_size as u32).map(move |i| g * group_size as u32 + i))
+        .collect()
+}
+
+fn select(xs: &Tensor, dim: usize, indices: &[u32]) -> Result<Tensor> {
+    let indices = Tensor::new(indices, xs.device())?;
+    xs.index_select(&indices, dim)
+}
+
+/// Keeps the given rows, the output channels of a linear weight.
+pub fn select_rows(weight: &Tensor, indices: &[u32]) -> Result<Tensor> {
+    select(weight, 0, indices)
+}
+
+/// Keeps the given columns, the input channels of a linear weight.
+pub fn select_cols(weight: &Tensor, indices: &[u32]) -> Result<Tensor> {
+    select(weight, 1, indices)
+}
+
+/// Keeps the given entries of a 1D tensor, e.g. a bias.
+pub fn select_entries(xs: &Tensor, indices: &[u32]) -> Result<Tensor> {
+    select(xs, 0, indices)
+}
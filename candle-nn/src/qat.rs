@@ -0,0 +1,511 @@
+//! Quantization-aware training.
+//!
+//! Fake-quantization rounds values to the grid of a low bit-width integer format while keeping
+//! them as floats, so that a model can be trained, or fine-tuned, to be robust to the
+//! quantization error. The gradients are computed with the straight-through estimator: the
+//! rounding is ignored in the backward pass and the gradient is zeroed for the values clamped to
+//! the integer range.
+//!
+//! The quantization parameters are derived from the range of the observed values, these ranges
+//! are tracked by [`FakeQuantize`] layers during training, similarly to the running stats of
+//! batch normalization, and frozen in eval mode.
+//!
+//! ```rust
+//! use candle::{DType, Device, Tensor};
+//! use candle_nn::qat::{qat_linear, QuantConfig};
+//! use candle_nn::{ModuleT, VarBuilder, VarMap};
+//! # fn main() -> candle::Result<()> {
+//! let varmap = VarMap::new();
+//! let vb = VarBuilder::from_varmap(&varmap, DType::F32, &Device::Cpu);
+//! let layer = qat_linear(64, 8, QuantConfig::int8(), Some(QuantConfig::uint8()), vb)?;
+//! let xs = Tensor::ones((3, 64), DType::F32, &Device::Cpu)?;
+//! // Train with `train = true` so that the observers track the activation ranges.
+//! let ys = layer.forward_t(&xs, true)?;
+//! // Export the weights as Q8_0 for the quantized models.
+//! let qweight = layer.to_qtensor(candle::quantized::GgmlDType::Q8_0)?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::{Init, Linear, Module, ModuleT, VarBuilder};
+use candle::quantized::{GgmlDType, QTensor};
+use candle::{DType, Result, Tensor, Var};
+
+/// Whether the tensors are quantized with a single scale or with one scale per channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Granularity {
+    PerTensor,
+    /// One scale and zero point for each index along `axis`, e.g. `axis = 0` for the output
+    /// channels of linear and convolution weights.
+    PerChannel {
+        axis: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantConfig {
+    /// The bit-width of the integer format, between 2 and 16.
+    pub bits: usize,
+    /// Symmetric quantization uses a signed integer range and a zero point of 0, asymmetric
+    /// quantization uses an unsigned range and a zero point so that the whole range is used.
+    pub symmetric: bool,
+    pub granularity: Granularity,
+    /// When set, the observed ranges are an exponential moving average of the batch ranges with
+    /// this weight for the new values, otherwise they are the running min and max.
+    pub averaging_constant: Option<f64>,
+}
+
+impl Default for QuantConfig {
+    fn default() -> Self {
+        Self::int8()
+    }
+}
+
+impl QuantConfig {
+    /// Symmetric per-tensor 8-bit quantization, usually used for the weights.
+    pub fn int8() -> Self {
+        Self {
+            bits: 8,
+            symmetric: true,
+            granularity: Granularity::PerTensor,
+            averaging_constant: None,
+        }
+    }
+
+    /// Asymmetric per-tensor 8-bit quantization, usually used for the activations.
+    pub fn uint8() -> Self {
+        Self {
+            symmetric: false,
+            averaging_constant: Some(0.01),
+            ..Self::int8()
+        }
+    }
+
+    pub fn with_bits(mut self, bits: usize) -> Self {
+        self.bits = bits;
+        self
+    }
+
+    pub fn with_per_channel(mut self, axis: usize) -> Self {
+        self.granularity = Granularity::PerChannel { axis };
+        self
+    }
+
+    pub fn with_averaging_constant(mut self, averaging_constant: Option<f64>) -> Self {
+        self.averaging_constant = averaging_constant;
+        self
+    }
+
+    /// The smallest and largest integer values.
+    pub fn qrange(&self) -> (f64, f64) {
+        let levels = (1u64 << self.bits) as f64;
+        if self.symmetric {
+            (-levels / 2., levels / 2. - 1.)
+        } else {
+            (0., levels - 1.)
+        }
+    }
+
+    fn check(&self) -> Result<()> {
+        if !(2..=16).contains(&self.bits) {
+            candle::bail!(
+                "quantization bits must be between 2 and 16, got {}",
+                self.bits
+            )
+        }
+        Ok(())
+    }
+}
+
+/// The scales and zero points mapping floats to integers, `q = round(x / scale) + zero_point`.
+#[derive(Debug, Clone)]
+pub struct QuantParams {
+    /// The scales with shape `(1,)` for per-tensor quantization or `(channels,)`.
+    pub scale: Tensor,
+    /// The zero points, with the same shape as the scales and holding integer values.
+    pub zero_point: Tensor,
+    pub qmin: f64,
+    pub qmax: f64,
+    pub granularity: Granularity,
+}
+
+impl QuantParams {
+    /// Computes the parameters so that `[min, max]` is representable, the range is extended to
+    /// include zero so that zero is represented exactly.
+    pub fn from_min_max(min: &Tensor, max: &Tensor, config: &QuantConfig) -> Result<Self> {
+        config.check()?;
+        let (qmin, qmax) = config.qrange();
+        let min = min.to_dtype(DType::F32)?.minimum(0f64)?;
+        let max = max.to_dtype(DType::F32)?.maximum(0f64)?;
+        let (scale, zero_point) = if config.symmetric {
+            let amax = max.maximum(&min.neg()?)?;
+            let scale = (amax / ((qmax - qmin) / 2.))?.maximum(f32::EPSILON as f64)?;
+            let zero_point = scale.zeros_like()?;
+            (scale, zero_point)
+        } else {
+            let scale = ((max - &min)? / (qmax - qmin))?.maximum(f32::EPSILON as f64)?;
+            let zero_point = (min / &scale)?
+                .affine(-1., qmin)?
+                .round()?
+                .clamp(qmin, qmax)?;
+            (scale, zero_point)
+        };
+        Ok(Self {
+            scale,
+            zero_point,
+            qmin,
+            qmax,
+            granularity: config.granularity,
+        })
+    }
+
+    // Reshapes the scales and zero points so that they broadcast along the channel axis of xs.
+    fn broadcast_params(&self, xs: &Tensor) -> Result<(Tensor, Tensor)> {
+        match self.granularity {
+            Granularity::PerTensor => Ok((self.scale.clone(), self.zero_point.clone())),
+            Granularity::PerChannel { axis } => {
+                let mut shape = vec![1; xs.rank()];
+                shape[axis] = xs.dim(axis)?;
+                Ok((
+                    self.scale.reshape(shape.as_slice())?,
+                    self.zero_point.reshape(shape.as_slice())?,
+                ))
+            }
+        }
+    }
+
+    /// Quantizes `xs` to integers returned as an `i64` tensor.
+    pub fn quantize(&self, xs: &Tensor) -> Result<Tensor> {
+        let (scale, zero_point) = self.broadcast_params(xs)?;
+        xs.to_dtype(DType::F32)?
+            .broadcast_div(&scale)?
+            .round()?
+            .broadcast_add(&zero_point)?
+            .clamp(self.qmin, self.qmax)?
+            .to_dtype(DType::I64)
+    }
+
+    /// Maps integers, as returned by [`QuantParams::quantize`], back to `f32` values.
+    pub fn dequantize(&self, q: &Tensor) -> Result<Tensor> {
+        let (scale, zero_point) = self.broadcast_params(q)?;
+        q.to_dtype(DType::F32)?
+            .broadcast_sub(&zero_point)?
+            .broadcast_mul(&scale)
+    }
+
+    /// Fake-quantizes `xs`, see [`fake_quantize`].
+    pub fn fake_quantize(&self, xs: &Tensor) -> Result<Tensor> {
+        let (scale, zero_point) = self.broadcast_params(xs)?;
+        fake_quantize(xs, &scale, &zero_point, self.qmin, self.qmax)
+    }
+}
+
+/// Rounds `xs` to the closest value representable with the given quantization parameters, the
+/// scales and zero points have to be broadcastable to the shape of `xs`.
+///
+/// The gradient is propagated as is for the values within the quantization range and is zero
+/// for the clamped values, i.e. the straight-through estimator.
+pub fn fake_quantize(
+    xs: &Tensor,
+    scale: &Tensor,
+    zero_point: &Tensor,
+    qmin: f64,
+    qmax: f64,
+) -> Result<Tensor> {
+    let dtype = xs.dtype();
+    let xs = xs.to_dtype(DType::F32)?;
+    let (scale, zero_point) = (scale.detach(), zero_point.detach());
+    let q = xs
+        .detach()
+        .broadcast_div(&scale)?
+        .round()?
+        .broadcast_add(&zero_point)?;
+    let in_range = (q.ge(qmin)? * q.le(qmax)?)?.to_dtype(DType::F32)?;
+    let dq = q
+        .clamp(qmin, qmax)?
+        .broadcast_sub(&zero_point)?
+        .broadcast_mul(&scale)?;
+    // The forward value is dq while the gradient goes through the in range values of xs.
+    let xs = (xs * in_range)?;
+    (&xs + (dq - &xs)?.detach())?.to_dtype(dtype)
+}
+
+/// Fake-quantizes `xs` using a single scale and zero point.
+pub fn fake_quantize_per_tensor(
+    xs: &Tensor,
+    scale: f64,
+    zero_point: f64,
+    qmin: f64,
+    qmax: f64,
+) -> Result<Tensor> {
+    let scale = Tensor::new(scale as f32, xs.device())?;
+    let zero_point = Tensor::new(zero_point as f32, xs.device())?;
+    fake_quantize(xs, &scale, &zero_point, qmin, qmax)
+}
+
+/// Fake-quantizes `xs` using one scale and zero point per index along `axis`, `scale` and
+/// `zero_point` having shape `(channels,)`.
+pub fn fake_quantize_per_channel(
+    xs: &Tensor,
+    scale: &Tensor,
+    zero_point: &Tensor,
+    axis: usize,
+    qmin: f64,
+    qmax: f64,
+) -> Result<Tensor> {
+    let mut shape = vec![1; xs.rank()];
+    shape[axis] = xs.dim(axis)?;
+    let scale = scale.to_dtype(DType::F32)?.reshape(shape.as_slice())?;
+    let zero_point = zero_point.to_dtype(DType::F32)?.reshape(shape.as_slice())?;
+    fake_quantize(xs, &scale, &zero_point, qmin, qmax)
+}
+
+/// The min and max of `xs` per tensor, with shape `(1,)`, or per channel.
+fn min_max(xs: &Tensor, granularity: Granularity) -> Result<(Tensor, Tensor)> {
+    let xs = xs.detach().to_dtype(DType::F32)?;
+    let xs = match granularity {
+        Granularity::PerTensor => xs.flatten_all()?.unsqueeze(0)?,
+        Granularity::PerChannel { axis } => xs.transpose(0, axis)?.flatten_from(1)?,
+    };
+    Ok((xs.min(1)?, xs.max(1)?))
+}
+
+/// Tracks the range of its inputs in training mode and fake-quantizes them.
+///
+/// The observed ranges are stored as variables, like the batch normalization running stats, so
+/// that they are saved with the other weights of the model. Before any value has been observed,
+/// the inputs are returned unchanged.
+#[derive(Debug, Clone)]
+pub struct FakeQuantize {
+    min: Var,
+    max: Var,
+    config: QuantConfig,
+}
+
+impl FakeQuantize {
+    /// Creates a layer from previously observed ranges, with shape `(1,)` for per-tensor
+    /// quantization or `(channels,)`.
+    pub fn new(min: Tensor, max: Tensor, config: QuantConfig) -> Result<Self> {
+        config.check()?;
+        if min.dims() != max.dims() || min.rank() != 1 {
+            candle::bail!(
+                "fake-quantize ranges must be 1D with the same shape, got {:?} and {:?}",
+                min.shape(),
+                max.shape()
+            )
+        }
+        Ok(Self {
+            min: Var::from_tensor(&min)?,
+            max: Var::from_tensor(&max)?,
+            config,
+        })
+    }
+
+    pub fn config(&self) -> &QuantConfig {
+        &self.config
+    }
+
+    pub fn min(&self) -> &Tensor {
+        self.min.as_tensor()
+    }
+
+    pub fn max(&self) -> &Tensor {
+        self.max.as_tensor()
+    }
+
+    /// Whether some values have been observed, unobserved ranges are `[inf, -inf]`.
+    pub fn is_initialized(&self) -> Result<bool> {
+        let min = self.min.as_tensor().get(0)?.to_dtype(DType::F32)?;
+        Ok(min.to_scalar::<f32>()?.is_finite())
+    }
+
+    /// Updates the observed ranges with the values of `xs`.
+    pub fn observe(&self, xs: &Tensor) -> Result<()> {
+        let (min, max) = min_max(xs, self.config.granularity)?;
+        if min.dims() != self.min.dims() {
+            candle::bail!(
+                "fake-quantize expected {} channels, got {} for input {:?}",
+                self.min.elem_count(),
+                min.elem_count(),
+                xs.shape()
+            )
+        }
+        let dtype = self.min.dtype();
+        let (min, max) = if !self.is_initialized()? {
+            (min, max)
+        } else {
+            let running_min = self.min.as_detached_tensor().to_dtype(DType::F32)?;
+            let running_max = self.max.as_detached_tensor().to_dtype(DType::F32)?;
+            match self.config.averaging_constant {
+                None => (running_min.minimum(&min)?, running_max.maximum(&max)?),
+                Some(c) => (
+                    ((running_min * (1. - c))? + (min * c)?)?,
+                    ((running_max * (1. - c))? + (max * c)?)?,
+                ),
+            }
+        };
+        self.min.set(&min.to_dtype(dtype)?)?;
+        self.max.set(&max.to_dtype(dtype)?)?;
+        Ok(())
+    }
+
+    /// The quantization parameters for the observed ranges.
+    pub fn quant_params(&self) -> Result<QuantParams> {
+        QuantParams::from_min_max(
+            &self.min.as_detached_tensor(),
+            &self.max.as_detached_tensor(),
+            &self.config,
+        )
+    }
+
+    /// Fake-quantizes `xs` with the current ranges without updating them.
+    pub fn fake_quantize(&self, xs: &Tensor) -> Result<Tensor> {
+        if !self.is_initialized()? {
+            return Ok(xs.clone());
+        }
+        self.quant_params()?.fake_quantize(xs)
+    }
+}
+
+impl ModuleT for FakeQuantize {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
+        if train {
+            self.observe(xs)?
+        }
+        self.fake_quantize(xs)
+    }
+}
+
+fn num_ranges(num_channels: usize, config: &QuantConfig) -> usize {
+    match config.granularity {
+        Granularity::PerTensor => 1,
+        Granularity::PerChannel { .. } => num_channels,
+    }
+}
+
+/// Creates a [`FakeQuantize`] layer, the ranges are stored as `"min"` and `"max"`.
+/// `num_channels` is only used for per-channel quantization.
+pub fn fake_quantize_layer(
+    num_channels: usize,
+    config: QuantConfig,
+    vb: VarBuilder,
+) -> Result<FakeQuantize> {
+    let size = num_ranges(num_channels, &config);
+    let min = vb.get_with_hints(size, "min", Init::Const(f64::INFINITY))?;
+    let max = vb.get_with_hints(size, "max", Init::Const(f64::NEG_INFINITY))?;
+    FakeQuantize::new(min, max, config)
+}
+
+// The weights are quantized per output channel and their range follows the current values.
+fn weight_ranges(config: QuantConfig) -> QuantConfig {
+    config
+        .with_per_channel(0)
+        .with_averaging_constant(Some(1.0))
+}
+
+/// A linear layer with fake-quantized weights and optionally fake-quantized inputs.
+///
+/// The weights are quantized per output channel using the range of the current weights, this
+/// range is updated at each training step.
+#[derive(Debug, Clone)]
+pub struct QatLinear {
+    linear: Linear,
+    weight_fq: FakeQuantize,
+    input_fq: Option<FakeQuantize>,
+}
+
+impl QatLinear {
+    pub fn new(linear: Linear, weight_fq: FakeQuantize, input_fq: Option<FakeQuantize>) -> Self {
+        Self {
+            linear,
+            weight_fq,
+            input_fq,
+        }
+    }
+
+    /// Starts quantization-aware training from a trained linear layer.
+    pub fn from_linear(
+        linear: Linear,
+        weight_config: QuantConfig,
+        input_config: Option<QuantConfig>,
+    ) -> Result<Self> {
+        let weight = linear.weight();
+        let weight_config = weight_ranges(weight_config);
+        let (min, max) = min_max(weight, weight_config.granularity)?;
+        let weight_fq = FakeQuantize::new(min, max, weight_config)?;
+        let input_fq = match input_config {
+            None => None,
+            Some(config) => {
+                let size = num_ranges(weight.dim(1)?, &config);
+                let min = Tensor::full(f32::INFINITY, size, weight.device())?;
+                let max = Tensor::full(f32::NEG_INFINITY, size, weight.device())?;
+                Some(FakeQuantize::new(min, max, config)?)
+            }
+        };
+        Ok(Self::new(linear, weight_fq, input_fq))
+    }
+
+    pub fn weight_fake_quantize(&self) -> &FakeQuantize {
+        &self.weight_fq
+    }
+
+    pub fn input_fake_quantize(&self) -> Option<&FakeQuantize> {
+        self.input_fq.as_ref()
+    }
+
+    /// The fake-quantized weights.
+    pub fn quantized_weight(&self) -> Result<Tensor> {
+        self.weight_fq.fake_quantize(self.linear.weight())
+    }
+
+    /// The weights as integers together with their quantization parameters, e.g. to export the
+    /// layer to an int8 format.
+    pub fn int_weight(&self) -> Result<(Tensor, QuantParams)> {
+        let params = self.weight_fq.quant_params()?;
+        Ok((params.quantize(self.linear.weight())?, params))
+    }
+
+    /// A plain linear layer using the fake-quantized weights.
+    pub fn to_linear(&self) -> Result<Linear> {
+        let weight = self.quantized_weight()?.detach();
+        let bias = self.linear.bias().map(|b| b.detach());
+        Ok(Linear::new(weight, bias))
+    }
+
+    /// Quantizes the fake-quantized weights to a GGML format, e.g. to be written to a GGUF file.
+    /// The 8-bit symmetric weights are best matched by [`GgmlDType::Q8_0`].
+    pub fn to_qtensor(&self, dtype: GgmlDType) -> Result<QTensor> {
+        QTensor::quantize(&self.quantized_weight()?.detach(), dtype)
+    }
+}
+
+impl ModuleT for QatLinear {
+    fn forward_t(&self, xs: &Tensor, train: bool) -> Result<Tensor> {
+        let xs = match &self.input_fq {
+            None => xs.clone(),
+            Some(fq) => fq.forward_t(xs, train)?,
+        };
+        if train {
+            self.weight_fq.observe(self.linear.weight())?
+        }
+        let weight = self.quantized_weight()?;
+        Linear::new(weight, self.linear.bias().cloned()).forward(&xs)
+    }
+}
+
+/// Creates a [`QatLinear`] layer with the same weight names as [`crate::linear`], the observed
+/// ranges being stored under `"weight_fq"` and `"input_fq"`.
+pub fn qat_linear(
+    in_dim: usize,
+    out_dim: usize,
+    weight_config: QuantConfig,
+    input_config: Option<QuantConfig>,
+    vb: VarBuilder,
+) -> Result<QatLinear> {
+    let linear = crate::linear(in_dim, out_dim, vb.clone())?;
+    let weight_fq = fake_quantize_layer(out_dim, weight_ranges(weight_config), vb.pp("weight_fq"))?;
+    let input_fq = match input_config {
+        None => None,
+        Some(config) => Some(fake_quantize_layer(in_dim, config, vb.pp("input_fq"))?),
+    };
+    Ok(QatLinear::new(linear, weight_fq, input_fq))
+}
@@ -0,0 +1,224 @@
+//! Losses and helpers for preference and reinforcement learning fine-tuning of language models.
+//!
+//! This includes the Direct Preference Optimization loss, see
+//! <https://arxiv.org/abs/2305.18290>, and the clipped objectives used by Proximal Policy
+//! Optimization, <https://arxiv.org/abs/1707.06347>, together with the computation of the
+//! log-probabilities of sequences under a frozen reference model.
+use candle::{DType, Device, Result, Tensor, D};
+
+/// A numerically stable `log(sigmoid(xs))`.
+pub fn log_sigmoid(xs: &Tensor) -> Result<Tensor> {
+    // log(sigmoid(x)) = min(x, 0) - log(1 + exp(-|x|))
+    let log1p = (xs.abs()?.neg()?.exp()? + 1.)?.log()?;
+    xs.minimum(0.)? - log1p
+}
+
+/// Returns the log-probabilities of the target tokens.
+///
+/// Arguments
+///
+/// * [logits]: The logits of dimensions `B, T, V` where `V` is the vocabulary size, the logits
+///   at position `t` are the predictions for `targets[t]`.
+/// * [targets]: The target tokens as a tensor of u32 of dimension `B, T`.
+///
+/// The resulting tensor has dimensions `B, T` and uses f32.
+pub fn token_log_probs(logits: &Tensor, targets: &Tensor) -> Result<Tensor> {
+    let (b_sz, seq_len, _vocab) = logits.dims3()?;
+    let targets_dims = targets.dims2()?;
+    if targets_dims != (b_sz, seq_len) {
+        candle::bail!("shape mismatch between logits {logits:?} and targets {targets_dims:?}")
+    }
+    let log_probs = crate::ops::log_softmax(&logits.to_dtype(DType::F32)?, D::Minus1)?;
+    log_probs
+        .gather(&targets.unsqueeze(D::Minus1)?, D::Minus1)?
+        .squeeze(D::Minus1)
+}
+
+/// Returns the sum of the log-probabilities of the target tokens for each sequence, the tokens
+/// where `mask` is 0, e.g. the prompt or the padding, are ignored. The resulting tensor has
+/// dimension `B`.
+pub fn sequence_log_probs(
+    logits: &Tensor,
+    targets: &Tensor,
+    mask: Option<&Tensor>,
+) -> Result<Tensor> {
+    let log_probs = token_log_probs(logits, targets)?;
+    let log_probs = match mask {
+        None => log_probs,
+        Some(mask) => (log_probs * mask.to_dtype(DType::F32)?)?,
+    };
+    log_probs.sum(D::Minus1)
+}
+
+fn masked_mean(xs: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+    match mask {
+        None => xs.mean_all(),
+        Some(mask) => {
+            let mask = mask.to_dtype(xs.dtype())?;
+            (xs * &mask)?.sum_all()? / mask.sum_all()?.clamp(1f32, f32::INFINITY)?
+        }
+    }
+}
+
+/// The Direct Preference Optimization loss, averaged over the batch.
+///
+/// All the inputs have dimension `B` and contain the sequence log-probabilities of the chosen and
+/// rejected completions, under the policy being trained and under the frozen reference model.
+/// `beta` controls the deviation from the reference model, typical values are between 0.1 and
+/// 0.5. With `label_smoothing` greater than 0, the preference labels are assumed to be flipped
+/// with this probability (conservative DPO).
+pub fn dpo_loss(
+    policy_chosen: &Tensor,
+    policy_rejected: &Tensor,
+    reference_chosen: &Tensor,
+    reference_rejected: &Tensor,
+    beta: f64,
+    label_smoothing: f64,
+) -> Result<Tensor> {
+    let chosen = (policy_chosen - reference_chosen)?;
+    let rejected = (policy_rejected - reference_rejected)?;
+    let logits = ((chosen - rejected)? * beta)?;
+    let loss = log_sigmoid(&logits)?.affine(-(1. - label_smoothing), 0.)?;
+    let loss = if label_smoothing > 0. {
+        (loss - log_sigmoid(&logits.neg()?)?.affine(label_smoothing, 0.)?)?
+    } else {
+        loss
+    };
+    loss.mean_all()
+}
+
+/// The implicit rewards of the DPO objective, `beta * (policy - reference)`, for either the
+/// chosen or the rejected completions. These are typically logged to monitor the training.
+pub fn dpo_rewards(policy: &Tensor, reference: &Tensor, beta: f64) -> Result<Tensor> {
+    (policy - reference)?.affine(beta, 0.)
+}
+
+/// The clipped surrogate objective of PPO, returned as a loss to minimize.
+///
+/// Arguments
+///
+/// * [log_probs]: The log-probabilities of the sampled tokens under the current policy.
+/// * [old_log_probs]: The log-probabilities of the same tokens under the policy that generated
+///   them.
+/// * [advantages]: The advantage estimates of the tokens, e.g. computed with [`gae`].
+/// * [mask]: When set, only the tokens where the mask is not 0 contribute to the loss.
+/// * [clip_eps]: The clipping range of the probability ratio, typically 0.2.
+///
+/// The first three tensors should have the same shape, usually `B, T`.
+pub fn ppo_policy_loss(
+    log_probs: &Tensor,
+    old_log_probs: &Tensor,
+    advantages: &Tensor,
+    mask: Option<&Tensor>,
+    clip_eps: f64,
+) -> Result<Tensor> {
+    let ratio = (log_probs - old_log_probs.detach())?.exp()?;
+    let advantages = advantages.detach();
+    let unclipped = (&ratio * &advantages)?;
+    let clipped = (ratio.clamp(1. - clip_eps, 1. + clip_eps)? * &advantages)?;
+    let objective = unclipped.minimum(&clipped)?;
+    masked_mean(&objective.neg()?, mask)
+}
+
+/// The clipped value function loss of PPO, the new value predictions are prevented from moving
+/// further than `clip_eps` from the old ones.
+pub fn ppo_value_loss(
+    values: &Tensor,
+    old_values: &Tensor,
+    returns: &Tensor,
+    mask: Option<&Tensor>,
+    clip_eps: f64,
+) -> Result<Tensor> {
+    let old_values = old_values.detach();
+    let returns = returns.detach();
+    let clipped = (&old_values + (values - &old_values)?.clamp(-clip_eps, clip_eps)?)?;
+    let loss = (values - &returns)?
+        .sqr()?
+        .maximum(&(clipped - &returns)?.sqr()?)?;
+    masked_mean(&loss, mask)?.affine(0.5, 0.)
+}
+
+/// An estimate of the per-token KL divergence between the policy and the reference model,
+/// `exp(r) - r - 1` with `r = reference_log_probs - log_probs`. This estimator is unbiased and
+/// always positive, it is commonly used as a penalty on the rewards.
+pub fn approx_kl(log_probs: &Tensor, reference_log_probs: &Tensor) -> Result<Tensor> {
+    let r = (reference_log_probs - log_probs)?;
+    (r.exp()? - &r)? - 1.
+}
+
+/// Generalized Advantage Estimation for a single trajectory, returns the advantages and the
+/// returns (advantages plus values) for each step.
+pub fn gae(rewards: &[f32], values: &[f32], gamma: f32, lambda: f32) -> (Vec<f32>, Vec<f32>) {
+    let len = rewards.len();
+    let mut advantages = vec![0f32; len];
+    let mut last_advantage = 0f32;
+    for t in (0..len).rev() {
+        let next_value = values.get(t + 1).copied().unwrap_or(0.);
+        let delta = rewards[t] + gamma * next_value - values[t];
+        last_advantage = delta + gamma * lambda * last_advantage;
+        advantages[t] = last_advantage
+    }
+    let returns = advantages
+        .iter()
+        .zip(values.iter())
+        .map(|(a, v)| a + v)
+        .collect();
+    (advantages, returns)
+}
+
+/// Computes the log-probabilities of several completions of a shared prompt under a frozen
+/// model, the prompt is only processed once and its kv-cache is reused for each completion.
+///
+/// `forward(model, tokens, seqlen_offset)` should run the model on a tensor of dimensions
+/// `1, T` and return the logits for all the positions, with dimensions `1, T, V`. Each
+/// completion runs on a clone of the model made after processing the prompt. Cloning does not
+/// copy the kv-cache, the cloned tensors share their storage, so the model must not modify the
+/// cached prompt positions when processing the completion. This is the case when the cache is
+/// extended by concatenation, or with [`crate::kv_cache`] which only writes past the current
+/// sequence length of each clone.
+///
+/// Returns the sum of the log-probabilities of the tokens of each completion, the prompt should
+/// not be empty.
+pub fn reference_log_probs<M, F>(
+    model: &mut M,
+    prompt: &[u32],
+    completions: &[&[u32]],
+    device: &Device,
+    mut forward: F,
+) -> Result<Vec<f32>>
+where
+    M: Clone,
+    F: FnMut(&mut M, &Tensor, usize) -> Result<Tensor>,
+{
+    if prompt.is_empty() {
+        candle::bail!("reference_log_probs requires a non-empty prompt")
+    }
+    let input = Tensor::new(prompt, device)?.unsqueeze(0)?;
+    let logits = forward(model, &input, 0)?.detach();
+    let (_, prompt_len, _) = logits.dims3()?;
+    if prompt_len != prompt.len() {
+        candle::bail!("forward should return the logits for all positions, got {prompt_len}")
+    }
+    // The predictions for the first token of the completions.
+    let last_logits = logits.narrow(1, prompt_len - 1, 1)?;
+    let mut all_log_probs = Vec::with_capacity(completions.len());
+    for &completion in completions.iter() {
+        if completion.is_empty() {
+            all_log_probs.push(0.);
+            continue;
+        }
+        let logits = if completion.len() == 1 {
+            last_logits.clone()
+        } else {
+            let mut model = model.clone();
+            let input = &completion[..completion.len() - 1];
+            let input = Tensor::new(input, device)?.unsqueeze(0)?;
+            let logits = forward(&mut model, &input, prompt_len)?.detach();
+            Tensor::cat(&[&last_logits, &logits], 1)?
+        };
+        let targets = Tensor::new(completion, device)?.unsqueeze(0)?;
+        let log_probs = sequence_log_probs(&logits, &targets, None)?;
+        all_log_probs.push(log_probs.squeeze(0)?.to_scalar::<f32>()?)
+    }
+    Ok(all_log_probs)
+}
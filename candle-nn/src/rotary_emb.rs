@@ -196,6 +196,18 @@ impl candle::CustomOp3 for RotaryEmbI {
         let out = candle::MetalStorage::new(output, device.clone(), el, src.dtype());
         Ok((out, l_src.shape().clone()))
     }
+
+    fn meta_fwd(
+        &self,
+        s1: &candle::MetaStorage,
+        l1: &Layout,
+        _: &candle::MetaStorage,
+        _: &Layout,
+        _: &candle::MetaStorage,
+        _: &Layout,
+    ) -> Result<(candle::MetaStorage, Shape)> {
+        Ok((*s1, l1.shape().clone()))
+    }
 }
 
 pub fn rope_i(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
@@ -223,6 +235,9 @@ pub fn rope_i(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
     if !sin.is_contiguous() {
         candle::bail!("sin has to be contiguous in rope")
     }
+    if xs.device().is_wgpu() {
+        return rope_i_slow(xs, cos, sin);
+    }
     xs.apply_op3_no_bwd(cos, sin, &RotaryEmbI)
 }
 
@@ -450,6 +465,18 @@ impl candle::CustomOp3 for RotaryEmb {
         let out = candle::MetalStorage::new(output, device.clone(), el, src.dtype());
         Ok((out, l_src.shape().clone()))
     }
+
+    fn meta_fwd(
+        &self,
+        s1: &candle::MetaStorage,
+        l1: &Layout,
+        _: &candle::MetaStorage,
+        _: &Layout,
+        _: &candle::MetaStorage,
+        _: &Layout,
+    ) -> Result<(candle::MetaStorage, Shape)> {
+        Ok((*s1, l1.shape().clone()))
+    }
 }
 
 pub fn rope(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
@@ -477,6 +504,9 @@ pub fn rope(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
     if !sin.is_contiguous() {
         candle::bail!("sin has to be contiguous in rope")
     }
+    if xs.device().is_wgpu() {
+        return rope_slow(xs, cos, sin);
+    }
     xs.apply_op3_no_bwd(cos, sin, &RotaryEmb)
 }
 
@@ -699,6 +729,18 @@ impl candle::CustomOp3 for RotaryEmbThd {
         let out = candle::MetalStorage::new(output, device.clone(), el, src.dtype());
         Ok((out, l_src.shape().clone()))
     }
+
+    fn meta_fwd(
+        &self,
+        s1: &candle::MetaStorage,
+        l1: &Layout,
+        _: &candle::MetaStorage,
+        _: &Layout,
+        _: &candle::MetaStorage,
+        _: &Layout,
+    ) -> Result<(candle::MetaStorage, Shape)> {
+        Ok((*s1, l1.shape().clone()))
+    }
 }
 
 pub fn rope_thd(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
@@ -726,5 +768,252 @@ pub fn rope_thd(xs: &Tensor, cos: &Tensor, sin: &Tensor) -> Result<Tensor> {
     if !sin.is_contiguous() {
         candle::bail!("sin has to be contiguous in rope")
     }
+    if xs.device().is_wgpu() {
+        return rope_slow(&xs.transpose(1, 2)?, cos, sin)?.transpose(1, 2);
+    }
     xs.apply_op3_no_bwd(cos, sin, &RotaryEmbThd)
 }
+
+/// How the rotary frequencies are adjusted to extend the context of a model.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RopeScaling {
+    None,
+    /// Linear position interpolation, the positions are divided by `factor`.
+    Linear {
+        factor: f32,
+    },
+    /// The llama 3.1 scaling, the low frequencies are divided by `factor`, the high frequencies
+    /// are kept, and the ones in between are smoothly interpolated.
+    Llama3 {
+        factor: f32,
+        low_freq_factor: f32,
+        high_freq_factor: f32,
+        original_max_position_embeddings: usize,
+    },
+    /// Each frequency is divided by its factor, e.g. the `rope_freqs` of the llama 3.1 gguf files
+    /// or the longrope factors of phi-3. There is one factor per pair of dimensions.
+    FrequencyFactors(Vec<f32>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RotaryConfig {
+    /// The number of dimensions rotated, usually the head dimension.
+    pub dim: usize,
+    pub theta: f32,
+    pub scaling: RopeScaling,
+    /// Whether the rotated pairs are interleaved, see [`rope_i`], or are the two halves of the
+    /// dimensions, see [`rope`].
+    pub interleaved: bool,
+}
+
+impl RotaryConfig {
+    pub fn new(dim: usize, theta: f32) -> Self {
+        Self {
+            dim,
+            theta,
+            scaling: RopeScaling::None,
+            interleaved: false,
+        }
+    }
+
+    pub fn with_scaling(mut self, scaling: RopeScaling) -> Self {
+        self.scaling = scaling;
+        self
+    }
+
+    pub fn with_interleaved(mut self, interleaved: bool) -> Self {
+        self.interleaved = interleaved;
+        self
+    }
+
+    /// The inverse frequencies of the pairs of dimensions after scaling.
+    pub fn inv_freq(&self) -> Result<Vec<f32>> {
+        let inv_freq = (0..self.dim)
+            .step_by(2)
+            .map(|i| 1f32 / self.theta.powf(i as f32 / self.dim as f32));
+        let inv_freq = match &self.scaling {
+            RopeScaling::None => inv_freq.collect(),
+            RopeScaling::Linear { factor } => inv_freq.map(|f| f / factor).collect(),
+            RopeScaling::Llama3 {
+                factor,
+                low_freq_factor,
+                high_freq_factor,
+                original_max_position_embeddings,
+            } => {
+                let original_len = *original_max_position_embeddings as f32;
+                let low_freq_wavelen = original_len / low_freq_factor;
+                let high_freq_wavelen = original_len / high_freq_factor;
+                inv_freq
+                    .map(|freq| {
+                        let wavelen = 2. * std::f32::consts::PI / freq;
+                        if wavelen < high_freq_wavelen {
+                            freq
+                        } else if wavelen > low_freq_wavelen {
+                            freq / factor
+                        } else {
+                            let smooth = (original_len / wavelen - low_freq_factor)
+                                / (high_freq_factor - low_freq_factor);
+                            (1. - smooth) * freq / factor + smooth * freq
+                        }
+                    })
+                    .collect()
+            }
+            RopeScaling::FrequencyFactors(factors) => {
+                if factors.len() != self.dim / 2 {
+                    candle::bail!(
+                        "expected {} rope frequency factors, got {}",
+                        self.dim / 2,
+                        factors.len()
+                    )
+                }
+                inv_freq.zip(factors.iter()).map(|(f, s)| f / s).collect()
+            }
+        };
+        Ok(inv_freq)
+    }
+}
+
+struct RotaryTable {
+    device: candle::Device,
+    dtype: candle::DType,
+    cos: Tensor,
+    sin: Tensor,
+}
+
+/// The cos and sin tables of the rotary embeddings, computed lazily for the positions that are
+/// used and extended when longer sequences come in.
+///
+/// A table is kept per device and dtype, and the clones of a cache share their tables so that a
+/// single cache can be used by all the layers of a model, or by the models sharing the same
+/// rotary configuration. The tables grow by doubling their length, so that a generation only
+/// recomputes them a logarithmic number of times.
+///
+/// ```rust
+/// use candle::{DType, Device, Tensor};
+/// use candle_nn::rotary_emb::{RotaryConfig, RotaryEmbeddingCache};
+/// # fn main() -> candle::Result<()> {
+/// let cache = RotaryEmbeddingCache::new(RotaryConfig::new(8, 10000.))?;
+/// // The queries of 2 heads for 3 tokens starting at position 5.
+/// let q = Tensor::zeros((1, 2, 3, 8), DType::F32, &Device::Cpu)?;
+/// let q = cache.apply(&q, 5)?;
+/// assert_eq!(cache.len(DType::F32, &Device::Cpu), 8);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct RotaryEmbeddingCache {
+    config: RotaryConfig,
+    inv_freq: std::sync::Arc<Vec<f32>>,
+    tables: std::sync::Arc<std::sync::Mutex<Vec<RotaryTable>>>,
+}
+
+impl std::fmt::Debug for RotaryEmbeddingCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RotaryEmbeddingCache")
+            .field("config", &self.config)
+            .finish()
+    }
+}
+
+impl RotaryEmbeddingCache {
+    pub fn new(config: RotaryConfig) -> Result<Self> {
+        let inv_freq = config.inv_freq()?;
+        Ok(Self {
+            config,
+            inv_freq: std::sync::Arc::new(inv_freq),
+            tables: Default::default(),
+        })
+    }
+
+    pub fn config(&self) -> &RotaryConfig {
+        &self.config
+    }
+
+    /// The number of positions currently in the table for `dtype` and `device`.
+    pub fn len(&self, dtype: candle::DType, device: &candle::Device) -> usize {
+        let tables = self.tables.lock().unwrap();
+        tables
+            .iter()
+            .find(|t| t.dtype == dtype && t.device.same_device(device))
+            .map_or(0, |t| t.cos.dim(0).unwrap_or(0))
+    }
+
+    /// Computes the tables for the positions up to `len` ahead of time, e.g. when loading a
+    /// model with a known context length.
+    pub fn reserve(&self, len: usize, dtype: candle::DType, device: &candle::Device) -> Result<()> {
+        self.cos_sin(0, len, dtype, device).map(|_| ())
+    }
+
+    /// The cos and sin values for the positions `index_pos..index_pos + seq_len`, with shape
+    /// `(seq_len, dim / 2)`.
+    pub fn cos_sin(
+        &self,
+        index_pos: usize,
+        seq_len: usize,
+        dtype: candle::DType,
+        device: &candle::Device,
+    ) -> Result<(Tensor, Tensor)> {
+        let end = index_pos + seq_len;
+        let mut tables = self.tables.lock().unwrap();
+        let idx = tables
+            .iter()
+            .position(|t| t.dtype == dtype && t.device.same_device(device));
+        let idx = match idx {
+            Some(idx) if tables[idx].cos.dim(0)? >= end => idx,
+            idx => {
+                let len = idx.map_or(0, |idx| tables[idx].cos.dim(0).unwrap_or(0));
+                let len = end.max(2 * len).max(1);
+                let (cos, sin) = self.compute(len, dtype, device)?;
+                let table = RotaryTable {
+                    device: device.clone(),
+                    dtype,
+                    cos,
+                    sin,
+                };
+                match idx {
+                    Some(idx) => {
+                        tables[idx] = table;
+                        idx
+                    }
+                    None => {
+                        tables.push(table);
+                        tables.len() - 1
+                    }
+                }
+            }
+        };
+        let table = &tables[idx];
+        let cos = table.cos.narrow(0, index_pos, seq_len)?;
+        let sin = table.sin.narrow(0, index_pos, seq_len)?;
+        Ok((cos, sin))
+    }
+
+    fn compute(
+        &self,
+        len: usize,
+        dtype: candle::DType,
+        device: &candle::Device,
+    ) -> Result<(Tensor, Tensor)> {
+        let inv_freq = Tensor::new(self.inv_freq.as_slice(), device)?;
+        let freqs = Tensor::arange(0, len as u32, device)?
+            .to_dtype(candle::DType::F32)?
+            .reshape((len, 1))?
+            .matmul(&inv_freq.reshape((1, self.inv_freq.len()))?)?;
+        let cos = freqs.cos()?.to_dtype(dtype)?;
+        let sin = freqs.sin()?.to_dtype(dtype)?;
+        Ok((cos, sin))
+    }
+
+    /// Applies the rotary embeddings to `xs` of shape `(batch, heads, seq_len, dim)` whose first
+    /// position is `index_pos`, the tables having the dtype and device of `xs`.
+    pub fn apply(&self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, _n_head, seq_len, _dim) = xs.dims4()?;
+        let (cos, sin) = self.cos_sin(index_pos, seq_len, xs.dtype(), xs.device())?;
+        let xs = xs.contiguous()?;
+        if self.config.interleaved {
+            rope_i(&xs, &cos, &sin)
+        } else {
+            rope(&xs, &cos, &sin)
+        }
+    }
+}
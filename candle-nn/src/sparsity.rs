@@ -0,0 +1,255 @@
+//! 2:4 semi-structured sparsity.
+//!
+//! A weight is 2:4 sparse when each group of 4 consecutive values along its input dimension
+//! contains at most 2 non-zero values. Such weights can be stored in a compressed form, the kept
+//! values and their position within each group, which halves the memory used by the values and
+//! is the layout used by the sparse tensor cores of Ampere and later GPUs.
+//!
+//! [`prune_2_4`] produces compliant weights from dense ones, either based on the magnitudes only
+//! or weighting them with activation statistics as in Wanda, <https://arxiv.org/abs/2306.11695>.
+//! The pruned weights can be compressed with [`SemiStructuredTensor::compress`] and used in a
+//! [`SparseLinear`] layer. The matmul currently decompresses the weight on the fly on all the
+//! devices, the cuSPARSELt library not being exposed by cudarc.
+//!
+//! ```rust
+//! use candle::{Device, Tensor};
+//! use candle_nn::sparsity::{prune_2_4, SparseLinear};
+//! use candle_nn::{Linear, Module};
+//! # fn main() -> candle::Result<()> {
+//! let dev = &Device::Cpu;
+//! let w = Tensor::new(&[[1f32, -4., 3., 2.], [0.5, 0.1, -0.2, 0.3]], dev)?;
+//! let w = prune_2_4(&w, None)?;
+//! assert_eq!(w.to_vec2::<f32>()?, [[0., -4., 3., 0.], [0.5, 0., 0., 0.3]]);
+//! let layer = SparseLinear::from_linear(&Linear::new(w, None))?;
+//! let ys = layer.forward(&Tensor::new(&[[1f32, 1., 1., 1.]], dev)?)?;
+//! assert_eq!(ys.to_vec2::<f32>()?, [[-1., 0.8]]);
+//! # Ok(())
+//! # }
+//! ```
+use crate::Module;
+use candle::{DType, Result, Tensor, D};
+
+const N: usize = 2;
+const M: usize = 4;
+
+fn check_dims(xs: &Tensor, m: usize) -> Result<(usize, usize)> {
+    let (out_dim, in_dim) = xs.dims2()?;
+    if in_dim % m != 0 {
+        candle::bail!("the input dimension {in_dim} is not divisible by {m}")
+    }
+    Ok((out_dim, in_dim))
+}
+
+/// The indices of the `n` largest scores in each group of `m` consecutive scores along the last
+/// dimension, with shape `(out_dim, in_dim / m, n)`.
+fn top_n_in_groups(scores: &Tensor, n: usize, m: usize) -> Result<Tensor> {
+    let (out_dim, in_dim) = check_dims(scores, m)?;
+    scores
+        .to_dtype(DType::F32)?
+        .reshape((out_dim, in_dim / m, m))?
+        .arg_sort_last_dim(false)?
+        .narrow(D::Minus1, 0, n)?
+        .contiguous()
+}
+
+/// A mask with the same shape as `scores`, `(out_dim, in_dim)`, keeping the `n` largest scores in
+/// each group of `m` consecutive scores along the input dimension.
+pub fn n_m_mask(scores: &Tensor, n: usize, m: usize) -> Result<Tensor> {
+    if n > m {
+        candle::bail!("cannot keep {n} values out of {m}")
+    }
+    let dims = scores.dims();
+    let indices = top_n_in_groups(scores, n, m)?;
+    let positions = Tensor::arange(0u32, m as u32, scores.device())?;
+    indices
+        .unsqueeze(D::Minus1)?
+        .broadcast_eq(&positions.reshape((1, 1, 1, m))?)?
+        .to_dtype(DType::F32)?
+        .sum(2)?
+        .reshape(dims)
+}
+
+/// Prunes a linear weight with shape `(out_dim, in_dim)` to the 2:4 pattern.
+///
+/// The values with the largest magnitude are kept in each group. When `mean_sq`, the mean squared
+/// values of the layer inputs with shape `(in_dim,)`, is given the magnitudes are weighted by the
+/// norms of the inputs.
+pub fn prune_2_4(weight: &Tensor, mean_sq: Option<&Tensor>) -> Result<Tensor> {
+    let scores = weight.to_dtype(DType::F32)?.abs()?;
+    let scores = match mean_sq {
+        Some(mean_sq) => scores.broadcast_mul(&mean_sq.to_dtype(DType::F32)?.sqrt()?)?,
+        None => scores,
+    };
+    let mask = n_m_mask(&scores, N, M)?.to_dtype(weight.dtype())?;
+    weight * mask
+}
+
+/// Returns true if each group of 4 consecutive values along the last dimension of the 2D weight
+/// has at most 2 non-zero values.
+pub fn is_2_4_sparse(weight: &Tensor) -> Result<bool> {
+    let (out_dim, in_dim) = match weight.dims2() {
+        Ok((out_dim, in_dim)) if in_dim % M == 0 => (out_dim, in_dim),
+        _ => return Ok(false),
+    };
+    let non_zeros = weight
+        .ne(0f32)?
+        .to_dtype(DType::U32)?
+        .reshape((out_dim, in_dim / M, M))?
+        .sum(D::Minus1)?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<u32>()?;
+    Ok(non_zeros as usize <= N)
+}
+
+/// A 2:4 sparse weight with shape `(out_dim, in_dim)` in compressed form.
+///
+/// `values` has shape `(out_dim, in_dim / 2)` and contains the two kept values of each group,
+/// `indices` has the same shape and contains their positions within the group in increasing
+/// order, as `u8`.
+#[derive(Clone, Debug)]
+pub struct SemiStructuredTensor {
+    values: Tensor,
+    indices: Tensor,
+    in_dim: usize,
+}
+
+impl SemiStructuredTensor {
+    /// Compresses a 2D weight, this fails if the weight is not 2:4 sparse.
+    pub fn compress(weight: &Tensor) -> Result<Self> {
+        if !is_2_4_sparse(weight)? {
+            candle::bail!(
+                "weight with shape {:?} is not 2:4 sparse, use prune_2_4 first",
+                weight.shape()
+            )
+        }
+        let (out_dim, in_dim) = weight.dims2()?;
+        // Groups with less than two non-zero values also keep some zeros.
+        let indices = top_n_in_groups(&weight.abs()?, N, M)?;
+        let first = indices.min_keepdim(D::Minus1)?;
+        let second = indices.max_keepdim(D::Minus1)?;
+        let indices = Tensor::cat(&[first, second], D::Minus1)?;
+        let values = weight
+            .reshape((out_dim, in_dim / M, M))?
+            .gather(&indices, D::Minus1)?
+            .reshape((out_dim, in_dim / M * N))?;
+        let indices = indices
+            .reshape((out_dim, in_dim / M * N))?
+            .to_dtype(DType::U8)?;
+        Ok(Self {
+            values,
+            indices,
+            in_dim,
+        })
+    }
+
+    pub fn values(&self) -> &Tensor {
+        &self.values
+    }
+
+    pub fn indices(&self) -> &Tensor {
+        &self.indices
+    }
+
+    /// The shape of the dense weight, `(out_dim, in_dim)`.
+    pub fn dims(&self) -> (usize, usize) {
+        (self.values.dim(0).unwrap_or(0), self.in_dim)
+    }
+
+    pub fn dtype(&self) -> DType {
+        self.values.dtype()
+    }
+
+    /// The metadata packed as in the cuSPARSELt layout, the four 2 bits indices of two
+    /// consecutive groups are stored in a single byte, the first index in the lowest bits. The
+    /// result has shape `(out_dim, in_dim / 8)`.
+    pub fn packed_indices(&self) -> Result<Tensor> {
+        let (out_dim, in_dim) = self.dims();
+        if in_dim % (2 * M) != 0 {
+            candle::bail!("the input dimension {in_dim} is not divisible by {}", 2 * M)
+        }
+        let shifts = Tensor::new(&[1f32, 4., 16., 64.], self.indices.device())?;
+        self.indices
+            .to_dtype(DType::F32)?
+            .reshape((out_dim, in_dim / (2 * M), 2 * N))?
+            .broadcast_mul(&shifts)?
+            .sum(D::Minus1)?
+            .to_dtype(DType::U8)
+    }
+
+    /// Decompresses the weight to a dense tensor with shape `(out_dim, in_dim)`.
+    pub fn to_dense(&self) -> Result<Tensor> {
+        let (out_dim, in_dim) = self.dims();
+        let groups = in_dim / M;
+        let positions = Tensor::arange(0u8, M as u8, self.indices.device())?;
+        let one_hot = self
+            .indices
+            .reshape((out_dim, groups, N, 1))?
+            .broadcast_eq(&positions.reshape((1, 1, 1, M))?)?
+            .to_dtype(self.values.dtype())?;
+        self.values
+            .reshape((out_dim, groups, N, 1))?
+            .broadcast_mul(&one_hot)?
+            .sum(2)?
+            .reshape((out_dim, in_dim))
+    }
+}
+
+/// A linear layer with a 2:4 sparse weight stored in compressed form.
+#[derive(Clone, Debug)]
+pub struct SparseLinear {
+    weight: SemiStructuredTensor,
+    bias: Option<Tensor>,
+}
+
+impl SparseLinear {
+    pub fn new(weight: SemiStructuredTensor, bias: Option<Tensor>) -> Self {
+        Self { weight, bias }
+    }
+
+    /// Compresses the weight of a linear layer, this fails if the weight is not 2:4 sparse.
+    pub fn from_linear(linear: &crate::Linear) -> Result<Self> {
+        let weight = SemiStructuredTensor::compress(linear.weight())?;
+        Ok(Self::new(weight, linear.bias().cloned()))
+    }
+
+    /// Prunes the weight of a linear layer to the 2:4 pattern and compresses it, see
+    /// [`prune_2_4`].
+    pub fn prune_linear(linear: &crate::Linear, mean_sq: Option<&Tensor>) -> Result<Self> {
+        let weight = prune_2_4(linear.weight(), mean_sq)?;
+        let weight = SemiStructuredTensor::compress(&weight)?;
+        Ok(Self::new(weight, linear.bias().cloned()))
+    }
+
+    pub fn weight(&self) -> &SemiStructuredTensor {
+        &self.weight
+    }
+
+    pub fn bias(&self) -> Option<&Tensor> {
+        self.bias.as_ref()
+    }
+
+    pub fn to_linear(&self) -> Result<crate::Linear> {
+        Ok(crate::Linear::new(
+            self.weight.to_dense()?,
+            self.bias.clone(),
+        ))
+    }
+}
+
+impl Module for SparseLinear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        self.to_linear()?.forward(xs)
+    }
+}
+
+/// Loads a linear layer from a checkpoint whose weight is already 2:4 sparse and compresses it.
+pub fn sparse_linear(
+    in_dim: usize,
+    out_dim: usize,
+    bias: bool,
+    vb: crate::VarBuilder,
+) -> Result<SparseLinear> {
+    let linear = crate::linear_b(in_dim, out_dim, bias, vb)?;
+    SparseLinear::from_linear(&linear)
+}
@@ -0,0 +1,503 @@
+//! A training loop handling the boilerplate shared by most training scripts.
+//!
+//! The [`Trainer`] owns the variables and the optimizer, and takes care of the learning rate
+//! schedule, gradient accumulation, gradient clipping, loss scaling for mixed precision
+//! training, evaluation, checkpointing, and of calling the registered [`Callback`]s. The model
+//! itself is provided as a closure computing the loss of a batch.
+//!
+//! ```no_run
+//! # use candle::{Device, Result, Tensor};
+//! # use candle_nn::{trainer::{Trainer, TrainerConfig, WarmupCosine}, AdamW, Optimizer, VarMap};
+//! # fn main() -> Result<()> {
+//! # let batches = |_epoch: usize| -> Vec<Result<(Tensor, Tensor)>> { vec![] };
+//! let varmap = VarMap::new();
+//! // ... build the model using a VarBuilder on top of varmap.
+//! let opt = AdamW::new_lr(varmap.all_vars(), 1e-3)?;
+//! let config = TrainerConfig {
+//!     epochs: 3,
+//!     grad_accum_steps: 4,
+//!     max_grad_norm: Some(1.0),
+//!     ..Default::default()
+//! };
+//! let mut trainer =
+//!     Trainer::new(varmap, opt, config).with_scheduler(WarmupCosine::new(100, 10_000, 0.));
+//! trainer.fit(batches, |(xs, ys): (Tensor, Tensor)| {
+//!     // Forward pass of the model returning the loss.
+//!     (xs - ys)?.sqr()?.mean_all()
+//! })?;
+//! # Ok(())
+//! # }
+//! ```
+use crate::logger::Logger;
+use crate::{Optimizer, VarMap};
+use candle::backprop::GradStore;
+use candle::{DType, Result, Tensor};
+use std::path::PathBuf;
+
+/// A learning rate schedule, returning the learning rate to use at a given optimizer step.
+pub trait LrScheduler {
+    fn learning_rate(&self, step: usize, base_lr: f64) -> f64;
+}
+
+impl<F: Fn(usize, f64) -> f64> LrScheduler for F {
+    fn learning_rate(&self, step: usize, base_lr: f64) -> f64 {
+        self(step, base_lr)
+    }
+}
+
+/// Linear warmup from 0 to the base learning rate followed by a cosine decay to `min_lr`.
+#[derive(Debug, Clone, Copy)]
+pub struct WarmupCosine {
+    pub warmup_steps: usize,
+    pub total_steps: usize,
+    pub min_lr: f64,
+}
+
+impl WarmupCosine {
+    pub fn new(warmup_steps: usize, total_steps: usize, min_lr: f64) -> Self {
+        Self {
+            warmup_steps,
+            total_steps,
+            min_lr,
+        }
+    }
+}
+
+impl LrScheduler for WarmupCosine {
+    fn learning_rate(&self, step: usize, base_lr: f64) -> f64 {
+        if step < self.warmup_steps {
+            return base_lr * (step + 1) as f64 / self.warmup_steps as f64;
+        }
+        let decay_steps = self.total_steps.saturating_sub(self.warmup_steps).max(1);
+        let progress = ((step - self.warmup_steps) as f64 / decay_steps as f64).min(1.);
+        let cosine = 0.5 * (1. + (std::f64::consts::PI * progress).cos());
+        self.min_lr + (base_lr - self.min_lr) * cosine
+    }
+}
+
+/// Linear warmup from 0 to the base learning rate followed by a linear decay to 0.
+#[derive(Debug, Clone, Copy)]
+pub struct WarmupLinear {
+    pub warmup_steps: usize,
+    pub total_steps: usize,
+}
+
+impl WarmupLinear {
+    pub fn new(warmup_steps: usize, total_steps: usize) -> Self {
+        Self {
+            warmup_steps,
+            total_steps,
+        }
+    }
+}
+
+impl LrScheduler for WarmupLinear {
+    fn learning_rate(&self, step: usize, base_lr: f64) -> f64 {
+        if step < self.warmup_steps {
+            return base_lr * (step + 1) as f64 / self.warmup_steps as f64;
+        }
+        let decay_steps = self.total_steps.saturating_sub(self.warmup_steps).max(1);
+        let remaining = decay_steps.saturating_sub(step - self.warmup_steps);
+        base_lr * remaining as f64 / decay_steps as f64
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct TrainerConfig {
+    pub epochs: usize,
+    /// The number of micro-batches whose gradients are accumulated before each optimizer step.
+    pub grad_accum_steps: usize,
+    /// When set, the gradients are rescaled so that their global L2 norm is at most this value.
+    pub max_grad_norm: Option<f64>,
+    /// Initial loss scale for dynamic loss scaling, typically used when the model runs in f16.
+    /// Steps with non-finite gradients are skipped and the scale is halved, the scale is doubled
+    /// after `loss_scale_window` consecutive successful steps.
+    pub loss_scale: Option<f64>,
+    pub loss_scale_window: usize,
+    /// Directory where the weights are saved, at the end of each epoch and every
+    /// `checkpoint_every` optimizer steps if set.
+    pub checkpoint_dir: Option<PathBuf>,
+    pub checkpoint_every: Option<usize>,
+    /// Stop training after this number of optimizer steps.
+    pub max_steps: Option<usize>,
+}
+
+impl Default for TrainerConfig {
+    fn default() -> Self {
+        Self {
+            epochs: 1,
+            grad_accum_steps: 1,
+            max_grad_norm: None,
+            loss_scale: None,
+            loss_scale_window: 2000,
+            checkpoint_dir: None,
+            checkpoint_every: None,
+            max_steps: None,
+        }
+    }
+}
+
+/// The progress of the training, passed to the callbacks.
+#[derive(Debug, Clone, Default)]
+pub struct TrainerState {
+    pub epoch: usize,
+    /// The number of optimizer steps performed so far.
+    pub step: usize,
+    /// The loss averaged over the micro-batches of the last optimizer step.
+    pub loss: f64,
+    pub learning_rate: f64,
+    /// The global gradient norm of the last optimizer step, before clipping.
+    pub grad_norm: Option<f64>,
+    pub loss_scale: Option<f64>,
+    /// The number of optimizer steps skipped because of non-finite gradients.
+    pub skipped_steps: usize,
+}
+
+/// Hooks called by the [`Trainer`], all the methods default to doing nothing.
+pub trait Callback {
+    fn on_step_end(&mut self, _state: &TrainerState) -> Result<()> {
+        Ok(())
+    }
+
+    fn on_epoch_end(&mut self, _state: &TrainerState, _eval_loss: Option<f64>) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returning true stops the training at the end of the current step.
+    fn should_stop(&self, _state: &TrainerState) -> bool {
+        false
+    }
+}
+
+/// A callback writing the loss, learning rate and gradient norm to a [`Logger`].
+pub struct LoggerCallback<L: Logger> {
+    pub logger: L,
+    pub log_every: usize,
+}
+
+impl<L: Logger> LoggerCallback<L> {
+    pub fn new(logger: L, log_every: usize) -> Self {
+        Self { logger, log_every }
+    }
+}
+
+impl<L: Logger> Callback for LoggerCallback<L> {
+    fn on_step_end(&mut self, state: &TrainerState) -> Result<()> {
+        if state.step % self.log_every.max(1) != 0 {
+            return Ok(());
+        }
+        self.logger
+            .log_scalar("train/loss", state.loss, state.step)?;
+        self.logger
+            .log_scalar("train/learning_rate", state.learning_rate, state.step)?;
+        if let Some(grad_norm) = state.grad_norm {
+            self.logger
+                .log_scalar("train/grad_norm", grad_norm, state.step)?;
+        }
+        Ok(())
+    }
+
+    fn on_epoch_end(&mut self, state: &TrainerState, eval_loss: Option<f64>) -> Result<()> {
+        if let Some(eval_loss) = eval_loss {
+            self.logger.log_scalar("eval/loss", eval_loss, state.step)?;
+        }
+        self.logger.flush()
+    }
+}
+
+pub struct Trainer<O: Optimizer> {
+    varmap: VarMap,
+    optimizer: O,
+    base_lr: f64,
+    scheduler: Option<Box<dyn LrScheduler>>,
+    callbacks: Vec<Box<dyn Callback>>,
+    config: TrainerConfig,
+    state: TrainerState,
+    grads: Option<GradStore>,
+    micro_step: usize,
+    loss_sum: f64,
+    good_steps: usize,
+}
+
+impl<O: Optimizer> Trainer<O> {
+    /// Creates a trainer for the variables of `varmap`, the optimizer should have been created
+    /// with the same variables.
+    pub fn new(varmap: VarMap, optimizer: O, config: TrainerConfig) -> Self {
+        let base_lr = optimizer.learning_rate();
+        let state = TrainerState {
+            learning_rate: base_lr,
+            loss_scale: config.loss_scale,
+            ..Default::default()
+        };
+        Self {
+            varmap,
+            optimizer,
+            base_lr,
+            scheduler: None,
+            callbacks: vec![],
+            config,
+            state,
+            grads: None,
+            micro_step: 0,
+            loss_sum: 0.,
+            good_steps: 0,
+        }
+    }
+
+    pub fn with_scheduler<S: LrScheduler + 'static>(mut self, scheduler: S) -> Self {
+        self.scheduler = Some(Box::new(scheduler));
+        self
+    }
+
+    pub fn with_callback<C: Callback + 'static>(mut self, callback: C) -> Self {
+        self.callbacks.push(Box::new(callback));
+        self
+    }
+
+    pub fn state(&self) -> &TrainerState {
+        &self.state
+    }
+
+    pub fn config(&self) -> &TrainerConfig {
+        &self.config
+    }
+
+    pub fn varmap(&self) -> &VarMap {
+        &self.varmap
+    }
+
+    pub fn optimizer(&self) -> &O {
+        &self.optimizer
+    }
+
+    pub fn optimizer_mut(&mut self) -> &mut O {
+        &mut self.optimizer
+    }
+
+    pub fn into_inner(self) -> (VarMap, O) {
+        (self.varmap, self.optimizer)
+    }
+
+    /// Runs the backward pass for the loss of a micro-batch and performs an optimizer step once
+    /// `grad_accum_steps` micro-batches have been accumulated. Returns true if a step was taken.
+    pub fn train_step(&mut self, loss: &Tensor) -> Result<bool> {
+        let loss_value = loss.to_dtype(DType::F64)?.to_scalar::<f64>()?;
+        self.train_step_impl(loss, loss_value)
+    }
+
+    fn train_step_impl(&mut self, loss: &Tensor, loss_value: f64) -> Result<bool> {
+        let accum_steps = self.config.grad_accum_steps.max(1);
+        self.loss_sum += loss_value;
+        let scale = self.state.loss_scale.unwrap_or(1.) / accum_steps as f64;
+        let grads = loss.affine(scale, 0.)?.backward()?;
+        self.accumulate(grads)?;
+        self.micro_step += 1;
+        if self.micro_step < accum_steps {
+            return Ok(false);
+        }
+        self.state.loss = self.loss_sum / accum_steps as f64;
+        self.micro_step = 0;
+        self.loss_sum = 0.;
+        let grads = self.grads.take();
+        match grads {
+            Some(grads) => self.optimizer_step(grads)?,
+            None => candle::bail!("no gradients were accumulated"),
+        }
+        for callback in self.callbacks.iter_mut() {
+            callback.on_step_end(&self.state)?
+        }
+        if let (Some(dir), Some(every)) =
+            (&self.config.checkpoint_dir, self.config.checkpoint_every)
+        {
+            if self.state.step % every.max(1) == 0 {
+                let path = dir.join(format!("step-{}.safetensors", self.state.step));
+                self.save_checkpoint(path)?
+            }
+        }
+        Ok(true)
+    }
+
+    fn accumulate(&mut self, grads: GradStore) -> Result<()> {
+        match self.grads.as_mut() {
+            None => self.grads = Some(grads),
+            Some(acc) => crate::optim::accumulate_grads(acc, &grads, &self.varmap.all_vars(), 1.)?,
+        }
+        Ok(())
+    }
+
+    fn optimizer_step(&mut self, mut grads: GradStore) -> Result<()> {
+        let vars = self.varmap.all_vars();
+        let needs_norm = self.config.max_grad_norm.is_some() || self.state.loss_scale.is_some();
+        if let Some(loss_scale) = self.state.loss_scale {
+            for var in vars.iter() {
+                if let Some(grad) = grads.remove(var) {
+                    grads.insert(var, grad.affine(1. / loss_scale, 0.)?);
+                }
+            }
+        }
+        self.state.grad_norm = if needs_norm {
+            let mut sum_squares = 0f64;
+            for var in vars.iter() {
+                if let Some(grad) = grads.get(var) {
+                    sum_squares += grad
+                        .to_dtype(DType::F64)?
+                        .sqr()?
+                        .sum_all()?
+                        .to_scalar::<f64>()?;
+                }
+            }
+            Some(sum_squares.sqrt())
+        } else {
+            None
+        };
+        if let Some(loss_scale) = self.state.loss_scale {
+            if !self.state.grad_norm.is_some_and(f64::is_finite) {
+                self.state.loss_scale = Some(loss_scale / 2.);
+                self.state.skipped_steps += 1;
+                self.good_steps = 0;
+                return Ok(());
+            }
+            self.good_steps += 1;
+            if self.good_steps >= self.config.loss_scale_window {
+                self.state.loss_scale = Some(loss_scale * 2.);
+                self.good_steps = 0;
+            }
+        }
+        if let (Some(max_norm), Some(norm)) = (self.config.max_grad_norm, self.state.grad_norm) {
+            if norm > max_norm {
+                let factor = max_norm / (norm + 1e-6);
+                for var in vars.iter() {
+                    if let Some(grad) = grads.remove(var) {
+                        grads.insert(var, grad.affine(factor, 0.)?);
+                    }
+                }
+            }
+        }
+        if let Some(scheduler) = self.scheduler.as_ref() {
+            let lr = scheduler.learning_rate(self.state.step, self.base_lr);
+            self.optimizer.set_learning_rate(lr);
+        }
+        self.state.learning_rate = self.optimizer.learning_rate();
+        self.optimizer.step(&grads)?;
+        self.state.step += 1;
+        Ok(())
+    }
+
+    fn should_stop(&self) -> bool {
+        self.config
+            .max_steps
+            .is_some_and(|max_steps| self.state.step >= max_steps)
+            || self.callbacks.iter().any(|c| c.should_stop(&self.state))
+    }
+
+    /// Trains on the batches of one epoch, returns the average loss over the batches. Gradients
+    /// from an incomplete accumulation at the end of the epoch are discarded.
+    pub fn train_epoch<B, I, F>(&mut self, batches: I, mut loss_fn: F) -> Result<f64>
+    where
+        I: IntoIterator<Item = Result<B>>,
+        F: FnMut(B) -> Result<Tensor>,
+    {
+        let (mut sum, mut count) = (0f64, 0usize);
+        for batch in batches {
+            let loss = loss_fn(batch?)?;
+            let loss_value = loss.to_dtype(DType::F64)?.to_scalar::<f64>()?;
+            sum += loss_value;
+            count += 1;
+            let stepped = self.train_step_impl(&loss, loss_value)?;
+            if stepped && self.should_stop() {
+                break;
+            }
+        }
+        self.grads = None;
+        self.micro_step = 0;
+        self.loss_sum = 0.;
+        Ok(if count == 0 { 0. } else { sum / count as f64 })
+    }
+
+    /// Returns the average loss over some batches without updating the weights.
+    pub fn evaluate<B, I, F>(&self, batches: I, mut loss_fn: F) -> Result<f64>
+    where
+        I: IntoIterator<Item = Result<B>>,
+        F: FnMut(B) -> Result<Tensor>,
+    {
+        let (mut sum, mut count) = (0f64, 0usize);
+        for batch in batches {
+            let loss = loss_fn(batch?)?.detach();
+            sum += loss.to_dtype(DType::F64)?.to_scalar::<f64>()?;
+            count += 1;
+        }
+        Ok(if count == 0 { 0. } else { sum / count as f64 })
+    }
+
+    /// Runs the training for the configured number of epochs, `train` returns the batches to use
+    /// for a given epoch.
+    pub fn fit<B, I, T, F>(&mut self, train: T, loss_fn: F) -> Result<()>
+    where
+        I: IntoIterator<Item = Result<B>>,
+        T: FnMut(usize) -> I,
+        F: FnMut(B) -> Result<Tensor>,
+    {
+        self.fit_impl(train, None::<fn() -> Vec<Result<B>>>, loss_fn)
+    }
+
+    /// Same as [`Trainer::fit`], the average loss on the batches returned by `eval` is computed
+    /// at the end of each epoch and passed to the callbacks.
+    pub fn fit_with_eval<B, I, T, J, E, F>(&mut self, train: T, eval: E, loss_fn: F) -> Result<()>
+    where
+        I: IntoIterator<Item = Result<B>>,
+        J: IntoIterator<Item = Result<B>>,
+        T: FnMut(usize) -> I,
+        E: FnMut() -> J,
+        F: FnMut(B) -> Result<Tensor>,
+    {
+        self.fit_impl(train, Some(eval), loss_fn)
+    }
+
+    fn fit_impl<B, I, T, J, E, F>(
+        &mut self,
+        mut train: T,
+        mut eval: Option<E>,
+        mut loss_fn: F,
+    ) -> Result<()>
+    where
+        I: IntoIterator<Item = Result<B>>,
+        J: IntoIterator<Item = Result<B>>,
+        T: FnMut(usize) -> I,
+        E: FnMut() -> J,
+        F: FnMut(B) -> Result<Tensor>,
+    {
+        for epoch in self.state.epoch..self.config.epochs {
+            self.state.epoch = epoch;
+            self.train_epoch(train(epoch), &mut loss_fn)?;
+            let eval_loss = match eval.as_mut() {
+                Some(eval) => Some(self.evaluate(eval(), &mut loss_fn)?),
+                None => None,
+            };
+            if let Some(dir) = &self.config.checkpoint_dir {
+                let path = dir.join(format!("epoch-{epoch}.safetensors"));
+                self.save_checkpoint(path)?
+            }
+            for callback in self.callbacks.iter_mut() {
+                callback.on_epoch_end(&self.state, eval_loss)?
+            }
+            if self.should_stop() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Saves the weights to a safetensors file, creating the parent directory if needed.
+    pub fn save_checkpoint<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?
+        }
+        self.varmap.save(path)
+    }
+
+    /// Loads the weights from a safetensors file written by [`Trainer::save_checkpoint`].
+    pub fn load_checkpoint<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<()> {
+        self.varmap.load(path)
+    }
+}
@@ -209,9 +209,13 @@ impl<'a, B: Backend> VarBuilderArgs<'a, B> {
         dtype: DType,
     ) -> Result<Tensor> {
         let path = self.path(name);
-        self.data
+        let tensor = self
+            .data
             .backend
-            .get(s.into(), &path, hints, dtype, &self.data.device)
+            .get(s.into(), &path, hints, dtype, &self.data.device)?;
+        // Name the weight so that errors in the ops using it report its path.
+        tensor.set_name(&path);
+        Ok(tensor)
     }
 }
 
@@ -586,6 +590,24 @@ impl<'a> VarBuilder<'a> {
             _phantom: std::marker::PhantomData,
         }
     }
+
+    /// Gets a VarBuilder that loads the tensors of a checkpoint with different naming conventions,
+    /// renaming the requested tensors and slicing the fused ones as described by `remap`.
+    pub fn remap(self, remap: KeyRemap) -> Self {
+        let dtype = self.dtype();
+        let device = self.device().clone();
+        let path = self.path.clone();
+        // The requested names already include the path.
+        let backend = Remap::new(self.root(), remap);
+        let backend: Box<dyn SimpleBackend + 'a> = Box::new(backend);
+        let data = TensorData { backend, device };
+        Self {
+            data: Arc::new(data),
+            dtype,
+            path,
+            _phantom: std::marker::PhantomData,
+        }
+    }
 }
 
 pub struct ShardedSafeTensors(candle::safetensors::MmapedSafetensors);
@@ -752,3 +774,213 @@ impl Renamer for Box<dyn Fn(&str) -> String + Sync + Send> {
         std::borrow::Cow::Owned(self(v))
     }
 }
+
+/// A fused weight stored as a single tensor in a checkpoint, e.g. `qkv_proj`, that a model loads
+/// as separate parts, e.g. `q_proj`, `k_proj` and `v_proj`.
+#[derive(Debug, Clone)]
+struct Split {
+    fused: String,
+    // The name of each part and its size along `dim`, `None` for parts of equal sizes.
+    parts: Vec<(String, Option<usize>)>,
+    dim: usize,
+}
+
+impl Split {
+    /// The name of the fused tensor and the index of the part for a requested name, the part
+    /// being matched against the components of the dot separated name.
+    fn fused_name(&self, name: &str) -> Option<(String, usize)> {
+        let components = name.split('.').collect::<Vec<_>>();
+        for (i, component) in components.iter().enumerate() {
+            if let Some(part_idx) = self.parts.iter().position(|(p, _)| p == component) {
+                let mut components = components.clone();
+                components[i] = &self.fused;
+                return Some((components.join("."), part_idx));
+            }
+        }
+        None
+    }
+
+    /// The offset and size of a part along `dim`, and the shape of the fused tensor, given the
+    /// shape requested for the part.
+    fn narrow(&self, part_idx: usize, s: &Shape) -> Result<(usize, usize, Shape)> {
+        let dims = s.dims();
+        if self.dim >= dims.len() {
+            candle::bail!(
+                "remap: cannot split {} along dim {} for shape {s:?}",
+                self.fused,
+                self.dim
+            )
+        }
+        let size = dims[self.dim];
+        let (offset, total) = match self.parts[part_idx].1 {
+            None => (part_idx * size, self.parts.len() * size),
+            Some(expected) => {
+                if expected != size {
+                    candle::bail!(
+                        "remap: {} has size {expected} in {} but {size} is requested",
+                        self.parts[part_idx].0,
+                        self.fused
+                    )
+                }
+                let sizes = self.parts.iter().map(|(_, s)| s.unwrap_or(0));
+                (sizes.clone().take(part_idx).sum(), sizes.sum())
+            }
+        };
+        let mut fused_dims = dims.to_vec();
+        fused_dims[self.dim] = total;
+        Ok((offset, size, Shape::from(fused_dims)))
+    }
+}
+
+/// Rules mapping the tensor names requested by a model to the names used in a checkpoint with
+/// different naming conventions, see [`VarBuilder::remap`].
+///
+/// A requested name is first rewritten by each regex rule in order. The resulting name is looked
+/// up in the checkpoint as is or with one of the prefixes of the checkpoint. When neither exists
+/// and the name refers to a part of a fused tensor, this part is sliced from the fused tensor.
+///
+/// ```rust
+/// use candle::{DType, Device, Tensor};
+/// use candle_nn::var_builder::KeyRemap;
+/// # fn main() -> candle::Result<()> {
+/// let qkv = Tensor::arange(0f32, 24., &Device::Cpu)?.reshape((6, 4))?;
+/// let tensors = [("model.h.0.attn.qkv_proj.weight".to_string(), qkv)];
+/// let vb = candle_nn::VarBuilder::from_tensors(tensors.into(), DType::F32, &Device::Cpu);
+/// let remap = KeyRemap::new()
+///     .with_regex(r"^layers\.(\d+)\.self_attn\.", "h.$1.attn.")?
+///     .with_checkpoint_prefix("model.")
+///     .with_split_sizes("qkv_proj", &[("q_proj", 4), ("k_proj", 1), ("v_proj", 1)], 0);
+/// let vb = vb.remap(remap);
+/// let k = vb.get((1, 4), "layers.0.self_attn.k_proj.weight")?;
+/// assert_eq!(k.to_vec2::<f32>()?, [[16., 17., 18., 19.]]);
+/// assert!(vb.contains_tensor("layers.0.self_attn.v_proj.weight"));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct KeyRemap {
+    regexes: Vec<(fancy_regex::Regex, String)>,
+    prefixes: Vec<String>,
+    splits: Vec<Split>,
+}
+
+impl KeyRemap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replaces the matches of `pattern` in the requested names with `replacement`, which can
+    /// refer to the capture groups as `$1` or `${name}`.
+    pub fn with_regex(mut self, pattern: &str, replacement: &str) -> Result<Self> {
+        let regex = fancy_regex::Regex::new(pattern).map_err(Error::wrap)?;
+        self.regexes.push((regex, replacement.to_string()));
+        Ok(self)
+    }
+
+    /// The names in the checkpoint may start with `prefix`, e.g. `model.` or the `module.` of
+    /// the checkpoints saved from a data parallel wrapper, which is not part of the requested
+    /// names.
+    pub fn with_checkpoint_prefix(mut self, prefix: &str) -> Self {
+        self.prefixes.push(prefix.to_string());
+        self
+    }
+
+    /// The checkpoint stores the `parts` concatenated along `dim` in a single tensor named
+    /// `fused`, all the parts having the same size, e.g. `gate_up_proj` for `gate_proj` and
+    /// `up_proj`.
+    pub fn with_split(mut self, fused: &str, parts: &[&str], dim: usize) -> Self {
+        self.splits.push(Split {
+            fused: fused.to_string(),
+            parts: parts.iter().map(|p| (p.to_string(), None)).collect(),
+            dim,
+        });
+        self
+    }
+
+    /// Similar to [`Self::with_split`] for parts of different sizes along `dim`, e.g. the query,
+    /// key and value projections of a model using grouped query attention.
+    pub fn with_split_sizes(mut self, fused: &str, parts: &[(&str, usize)], dim: usize) -> Self {
+        self.splits.push(Split {
+            fused: fused.to_string(),
+            parts: parts
+                .iter()
+                .map(|(p, s)| (p.to_string(), Some(*s)))
+                .collect(),
+            dim,
+        });
+        self
+    }
+
+    /// Applies the regex rules to a requested name.
+    pub fn rewrite(&self, name: &str) -> String {
+        let mut name = name.to_string();
+        for (regex, replacement) in self.regexes.iter() {
+            name = regex.replace_all(&name, replacement.as_str()).into_owned();
+        }
+        name
+    }
+
+    /// The name of `name` in the checkpoint, with or without a prefix.
+    fn resolve(&self, inner: &VarBuilder, name: &str) -> Option<String> {
+        if inner.contains_tensor(name) {
+            return Some(name.to_string());
+        }
+        self.prefixes
+            .iter()
+            .map(|prefix| format!("{prefix}{name}"))
+            .find(|name| inner.contains_tensor(name))
+    }
+
+    /// The fused tensor in the checkpoint containing `name`, and the split it comes from.
+    fn resolve_split(&self, inner: &VarBuilder, name: &str) -> Option<(String, usize, &Split)> {
+        self.splits.iter().find_map(|split| {
+            let (fused, part_idx) = split.fused_name(name)?;
+            let fused = self.resolve(inner, &fused)?;
+            Some((fused, part_idx, split))
+        })
+    }
+}
+
+pub struct Remap<'a> {
+    inner: VarBuilder<'a>,
+    remap: KeyRemap,
+}
+
+impl<'a> SimpleBackend for Remap<'a> {
+    fn get(
+        &self,
+        s: Shape,
+        name: &str,
+        h: crate::Init,
+        dtype: DType,
+        dev: &Device,
+    ) -> Result<Tensor> {
+        let name = self.remap.rewrite(name);
+        let tensor = if let Some(name) = self.remap.resolve(&self.inner, &name) {
+            self.inner.get_with_hints_dtype(s, &name, h, dtype)?
+        } else if let Some((fused, part_idx, split)) = self.remap.resolve_split(&self.inner, &name)
+        {
+            let (offset, size, fused_shape) = split.narrow(part_idx, &s)?;
+            self.inner
+                .get_with_hints_dtype(fused_shape, &fused, h, dtype)?
+                .narrow(split.dim, offset, size)?
+                .contiguous()?
+        } else {
+            // Let the inner backend report the missing tensor, or initialize it.
+            self.inner.get_with_hints_dtype(s, &name, h, dtype)?
+        };
+        tensor.to_device(dev)
+    }
+
+    fn contains_tensor(&self, name: &str) -> bool {
+        let name = self.remap.rewrite(name);
+        self.remap.resolve(&self.inner, &name).is_some()
+            || self.remap.resolve_split(&self.inner, &name).is_some()
+    }
+}
+
+impl<'a> Remap<'a> {
+    pub fn new(inner: VarBuilder<'a>, remap: KeyRemap) -> Self {
+        Self { inner, remap }
+    }
+}
@@ -19,6 +19,23 @@ impl VarMap {
         Self { data }
     }
 
+    /// Create a `VarMap` holding a copy of the given tensors as variables, e.g. to fine-tune
+    /// some pretrained or pruned weights.
+    pub fn from_tensors<I: IntoIterator<Item = (K, V)>, K: AsRef<str>, V: AsRef<Tensor>>(
+        tensors: I,
+    ) -> Result<Self> {
+        let varmap = Self::new();
+        {
+            let mut tensor_data = varmap.data.lock().unwrap();
+            for (name, tensor) in tensors {
+                // Detaching ensures that the storage is copied even for variables.
+                let var = Var::from_tensor(&tensor.as_ref().detach())?;
+                tensor_data.insert(name.as_ref().to_string(), var);
+            }
+        }
+        Ok(varmap)
+    }
+
     /// Retrieve all the variables currently stored in the map.
     pub fn all_vars(&self) -> Vec<Var> {
         let tensor_data = self.data.lock().unwrap();
@@ -26,6 +43,23 @@ impl VarMap {
         tensor_data.values().map(|c| c.clone()).collect::<Vec<_>>()
     }
 
+    /// The total number of elements in the variables, i.e. the number of parameters of the
+    /// models built using this map. Using the meta device, this can be computed without
+    /// allocating the weights.
+    pub fn num_elements(&self) -> usize {
+        let tensor_data = self.data.lock().unwrap();
+        tensor_data.values().map(|v| v.elem_count()).sum()
+    }
+
+    /// The memory required to store the variables, in bytes.
+    pub fn size_in_bytes(&self) -> usize {
+        let tensor_data = self.data.lock().unwrap();
+        tensor_data
+            .values()
+            .map(|v| v.elem_count() * v.dtype().size_in_bytes())
+            .sum()
+    }
+
     /// Save the map in the safetensors format.
     pub fn save<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
         let tensor_data = self.data.lock().unwrap();
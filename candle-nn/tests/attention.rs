@@ -0,0 +1,86 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{Device, Tensor};
+use candle_nn::attention::{
+    alibi_bias, alibi_slopes, relative_position_bucket, relative_position_buckets,
+    RelativePositionBias,
+};
+use candle_nn::Embedding;
+
+#[test]
+fn alibi() -> Result<()> {
+    let slopes = alibi_slopes(8, 8.);
+    let expected: Vec<f32> = (1..=8).map(|i| 2f32.powi(-i)).collect();
+    assert_eq!(slopes, expected);
+
+    // 12 heads use the 8 slopes above followed by 4 of the slopes for 16 heads.
+    let slopes = alibi_slopes(12, 8.);
+    assert_eq!(slopes[..8], expected);
+    let extra: Vec<f32> = [1., 3., 5., 7.]
+        .iter()
+        .map(|&i| 2f32.powf(-i / 2.))
+        .collect();
+    for (slope, extra) in slopes[8..].iter().zip(extra) {
+        assert!((slope - extra).abs() < 1e-6)
+    }
+
+    let bias = alibi_bias(&[0.5, 0.25], 2, 3, &Device::Cpu)?;
+    assert_eq!(bias.dims(), &[1, 2, 2, 3]);
+    assert_eq!(
+        bias.squeeze(0)?.to_vec3::<f32>()?,
+        [
+            [[-0.5, 0., -0.5], [-1., -0.5, 0.]],
+            [[-0.25, 0., -0.25], [-0.5, -0.25, 0.]]
+        ]
+    );
+    assert!(alibi_bias(&[0.5], 3, 2, &Device::Cpu).is_err());
+    Ok(())
+}
+
+#[test]
+fn relative_position_buckets_t5() -> Result<()> {
+    // The values for the default T5 configuration, 32 buckets and a max distance of 128.
+    let bucket = |relative_position, bidirectional| {
+        relative_position_bucket(relative_position, bidirectional, 32, 128)
+    };
+    let buckets: Vec<u32> = [0, -1, 1, -8, -20, -200, 200]
+        .iter()
+        .map(|&p| bucket(p, true))
+        .collect();
+    assert_eq!(buckets, [0, 1, 17, 8, 10, 15, 31]);
+    let buckets: Vec<u32> = [0, 5, -5, -16, -40, -200]
+        .iter()
+        .map(|&p| bucket(p, false))
+        .collect();
+    assert_eq!(buckets, [0, 0, 5, 16, 23, 31]);
+
+    let buckets = relative_position_buckets(2, 3, true, 32, 128, &Device::Cpu)?;
+    assert_eq!(buckets.to_vec2::<u32>()?, [[1, 0, 17], [2, 1, 0]]);
+    Ok(())
+}
+
+#[test]
+fn relative_position_bias() -> Result<()> {
+    let dev = &Device::Cpu;
+    // The bias of bucket b for head h is 10 * b + h.
+    let embeddings = Tensor::arange(0f32, 32., dev)?
+        .unsqueeze(1)?
+        .affine(10., 0.)?
+        .broadcast_add(&Tensor::new(&[0f32, 1.], dev)?)?;
+    let bias = RelativePositionBias::new(Embedding::new(embeddings, 2), 32, 128, false);
+    let bias = bias.bias(2, 3, dev)?;
+    assert_eq!(bias.dims(), &[1, 2, 2, 3]);
+    assert_eq!(
+        bias.squeeze(0)?.to_vec3::<f32>()?,
+        [
+            [[10., 0., 0.], [20., 10., 0.]],
+            [[11., 1., 1.], [21., 11., 1.]]
+        ]
+    );
+    Ok(())
+}
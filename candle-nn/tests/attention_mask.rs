@@ -0,0 +1,94 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_nn::attention_mask::{
+    additive, causal_mask, combine, padding_mask, sliding_window_mask, MaskCache, MaskKind,
+    PaddingSide,
+};
+
+fn to_vec2(mask: &Tensor) -> Result<Vec<Vec<u8>>> {
+    mask.squeeze(0)?.squeeze(0)?.to_vec2::<u8>()
+}
+
+#[test]
+fn masks() -> Result<()> {
+    let device = &Device::Cpu;
+    assert_eq!(
+        to_vec2(&causal_mask(3, 3, device)?)?,
+        [[0, 1, 1], [0, 0, 1], [0, 0, 0]]
+    );
+    // The queries are the last positions.
+    assert_eq!(
+        to_vec2(&causal_mask(2, 4, device)?)?,
+        [[0, 0, 0, 1], [0, 0, 0, 0]]
+    );
+    assert!(causal_mask(3, 2, device).is_err());
+    assert_eq!(
+        to_vec2(&sliding_window_mask(3, 3, 2, device)?)?,
+        [[0, 1, 1], [0, 0, 1], [1, 0, 0]]
+    );
+    assert_eq!(
+        to_vec2(&sliding_window_mask(1, 4, 2, device)?)?,
+        [[1, 1, 0, 0]]
+    );
+    assert!(sliding_window_mask(1, 4, 0, device).is_err());
+
+    let padding = padding_mask(&[3, 1], 3, PaddingSide::Left, device)?;
+    assert_eq!(padding.dims(), [2, 1, 1, 3]);
+    assert_eq!(padding.flatten_all()?.to_vec1::<u8>()?, [0, 0, 0, 1, 1, 0]);
+    let padding = padding_mask(&[3, 1], 3, PaddingSide::Right, device)?;
+    assert_eq!(padding.flatten_all()?.to_vec1::<u8>()?, [0, 0, 0, 0, 1, 1]);
+    assert!(padding_mask(&[4], 3, PaddingSide::Left, device).is_err());
+
+    let mask = combine(&causal_mask(3, 3, device)?, &padding)?;
+    assert_eq!(mask.dims(), [2, 1, 3, 3]);
+    assert_eq!(
+        mask.i(1)?.squeeze(0)?.to_vec2::<u8>()?,
+        [[0, 1, 1], [0, 1, 1], [0, 1, 1]]
+    );
+
+    let bias = additive(&causal_mask(2, 2, device)?, DType::F32)?;
+    assert_eq!(
+        bias.squeeze(0)?.squeeze(0)?.to_vec2::<f32>()?,
+        [[0., f32::NEG_INFINITY], [0., 0.]]
+    );
+    Ok(())
+}
+
+#[test]
+fn mask_cache() -> Result<()> {
+    let device = &Device::Cpu;
+    let mut masks = MaskCache::new(MaskKind::SlidingWindow(3), device);
+    for kv_len in 1..12 {
+        let expected = sliding_window_mask(1, kv_len, 3, device)?;
+        assert_eq!(to_vec2(&masks.mask(1, kv_len)?)?, to_vec2(&expected)?);
+        let bias = masks.additive(1, kv_len, DType::F16)?;
+        assert_eq!(bias.dtype(), DType::F16);
+        let expected = additive(&expected, DType::F32)?;
+        assert_eq!(
+            bias.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?,
+            expected.flatten_all()?.to_vec1::<f32>()?
+        );
+    }
+    for q_len in 1..5 {
+        let expected = sliding_window_mask(q_len, 7, 3, device)?;
+        assert_eq!(to_vec2(&masks.mask(q_len, 7)?)?, to_vec2(&expected)?);
+    }
+    let mask = masks.with_padding(2, 3, &[3, 2], PaddingSide::Left)?;
+    assert_eq!(mask.dims(), [2, 1, 2, 3]);
+    assert_eq!(
+        mask.i(1)?.squeeze(0)?.to_vec2::<u8>()?,
+        [[1, 0, 1], [1, 0, 0]]
+    );
+
+    assert!(!MaskKind::Causal.needs_mask(1, 10));
+    assert!(MaskKind::Causal.needs_mask(2, 10));
+    assert!(!MaskKind::SlidingWindow(10).needs_mask(1, 10));
+    assert!(MaskKind::SlidingWindow(10).needs_mask(1, 11));
+    assert!(!MaskKind::Full.needs_mask(5, 10));
+    Ok(())
+}
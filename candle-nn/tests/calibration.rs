@@ -0,0 +1,93 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::quantized::GgmlDType;
+use candle::{Device, Tensor};
+use candle_nn::calibration::{quantize_with_importance, Calibrator, RangeMethod};
+use candle_nn::qat::QuantConfig;
+
+#[test]
+fn calibration_ranges() -> Result<()> {
+    let dev = &Device::Cpu;
+    // 1000 values in [-1, 1] on the first channel, with a single outlier on the second one.
+    let mut values = vec![];
+    for i in 0..1000 {
+        let v = i as f32 / 500. - 1.;
+        values.push(v);
+        values.push(if i == 0 { 100. } else { v / 2. });
+    }
+    let xs = Tensor::from_vec(values, (2, 500, 2), dev)?;
+    let mut calibrator = Calibrator::new();
+    calibrator.run(
+        [Ok(xs.narrow(0, 0, 1)?), Ok(xs.narrow(0, 1, 1)?)],
+        |xs, c| c.observe("x", xs),
+    )?;
+    assert_eq!(calibrator.names(), ["x"]);
+    let stats = calibrator.stats("x").unwrap();
+    assert_eq!(stats.count, 1000);
+    assert_eq!(stats.hist.iter().sum::<f64>(), 2000.);
+
+    let (min, max) = calibrator.range("x", RangeMethod::MinMax, dev)?;
+    assert_eq!(min.to_vec1::<f32>()?, [-1., -0.499]);
+    assert_eq!(max.to_vec1::<f32>()?, [0.998, 100.]);
+    // The outlier is clipped by the percentile method, and partially by the MSE method with a low
+    // bit-width.
+    let (_, max) = calibrator.range("x", RangeMethod::Percentile(0.999), dev)?;
+    let max = max.to_vec1::<f32>()?;
+    assert!(max[0] < 1.1 && max[1] < 1.1, "{max:?}");
+    let (_, max) = calibrator.range("x", RangeMethod::Mse { bits: 4 }, dev)?;
+    let max = max.to_vec1::<f32>()?;
+    assert!(max[0] <= 1.1 && max[1] < 50., "{max:?}");
+    assert!(calibrator
+        .range("x", RangeMethod::Percentile(1.5), dev)
+        .is_err());
+
+    let params = calibrator.quant_params("x", RangeMethod::MinMax, &QuantConfig::uint8())?;
+    assert_eq!(params.scale.dims(), [1]);
+    let config = QuantConfig::int8().with_per_channel(2);
+    let params = calibrator.quant_params("x", RangeMethod::Percentile(0.999), &config)?;
+    assert_eq!(params.scale.dims(), [2]);
+    let importance = calibrator.importance("x", dev)?.to_vec1::<f32>()?;
+    assert!((importance[0] - 0.333).abs() < 0.01, "{importance:?}");
+    assert!(importance[1] > 10., "{importance:?}");
+    assert!(calibrator
+        .observe("x", &Tensor::zeros(3, candle::DType::F32, dev)?)
+        .is_err());
+    assert!(calibrator.importance("y", dev).is_err());
+
+    let file = std::env::temp_dir().join("candle_calibration_test.safetensors");
+    calibrator.save(&file)?;
+    let loaded = Calibrator::load(&file)?;
+    std::fs::remove_file(&file)?;
+    assert_eq!(loaded.stats("x"), calibrator.stats("x"));
+    Ok(())
+}
+
+#[test]
+fn importance_weighted_quantization() -> Result<()> {
+    let dev = &Device::Cpu;
+    let weight = Tensor::randn(0f32, 1., (8, 64), dev)?;
+    // A few input channels have much larger activations than the others.
+    let importance = (0..64)
+        .map(|i| if i % 16 == 0 { 100f32 } else { 0.01 })
+        .collect::<Vec<_>>();
+    let importance = Tensor::new(importance, dev)?;
+    let err = |w: &Tensor| -> Result<f32> {
+        let e = (w - &weight)?.sqr()?.broadcast_mul(&importance)?;
+        Ok(e.sum_all()?.to_scalar::<f32>()?)
+    };
+    let rtn = candle::quantized::QTensor::quantize(&weight, GgmlDType::Q4_0)?.dequantize(dev)?;
+    let (qweight, scales) = quantize_with_importance(&weight, &importance, GgmlDType::Q4_0, 10)?;
+    assert_eq!(scales.dims(), [64]);
+    let dequantized = qweight.dequantize(dev)?.broadcast_div(&scales)?;
+    assert!(err(&dequantized)? <= err(&rtn)?);
+    assert!(
+        quantize_with_importance(&weight, &importance.narrow(0, 0, 8)?, GgmlDType::Q4_0, 10)
+            .is_err()
+    );
+    Ok(())
+}
@@ -0,0 +1,89 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::test_utils::to_vec0_round;
+use candle::{Device, Tensor, Var};
+use candle_nn::distillation::{
+    hidden_mse_loss, kl_div_loss, DistillationConfig, Distiller, ModelOutput,
+};
+use candle_nn::{Optimizer, SGD};
+
+#[test]
+fn kl_div() -> Result<()> {
+    let dev = &Device::Cpu;
+    let logits = Tensor::new(&[[1f32, 2., 3.], [0., 0., 5.]], dev)?;
+    assert_eq!(to_vec0_round(&kl_div_loss(&logits, &logits, 2.)?, 4)?, 0.);
+    let student = Tensor::new(&[[0f32, 0.]], dev)?;
+    let teacher = Tensor::new(&[[2f32, 0.]], dev)?;
+    // With T = 2 the teacher distribution is sigmoid(1) = [0.7311, 0.2689] and the student one
+    // is uniform, KL = 0.7311 ln(1.4621) + 0.2689 ln(0.5379) = 0.1109, times T^2.
+    assert_eq!(
+        to_vec0_round(&kl_div_loss(&student, &teacher, 2.)?, 4)?,
+        0.4438
+    );
+    assert!(kl_div_loss(&student, &logits, 2.).is_err());
+
+    let pairs = [
+        (
+            Tensor::new(&[1f32, 2.], dev)?,
+            Tensor::new(&[1f32, 0.], dev)?,
+        ),
+        (Tensor::new(&[0f32], dev)?, Tensor::new(&[0f32], dev)?),
+    ];
+    assert_eq!(hidden_mse_loss(&pairs)?.to_scalar::<f32>()?, 1.);
+    Ok(())
+}
+
+#[test]
+fn distill_step() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::new(&[[1f32, 0.], [0., 1.], [1., 1.]], dev)?;
+    let teacher_w = Tensor::new(&[[2f32, -1., 0.5], [0., 1., -1.]], dev)?;
+    let student_w = Var::zeros((2, 3), candle::DType::F32, dev)?;
+    let distiller = Distiller::new(DistillationConfig {
+        temperature: 1.,
+        alpha: 1.,
+        hidden_weight: 1.,
+    });
+    let mut sgd = SGD::new(vec![student_w.clone()], 1.)?;
+    let mut losses = vec![];
+    for _step in 0..50 {
+        let loss = distiller.step(
+            &xs,
+            None,
+            |xs| {
+                let logits = xs.matmul(&teacher_w)?;
+                Ok(ModelOutput::with_hidden_states(
+                    logits.clone(),
+                    vec![logits],
+                ))
+            },
+            |xs| {
+                let logits = xs.matmul(student_w.as_tensor())?;
+                Ok(ModelOutput::with_hidden_states(
+                    logits.clone(),
+                    vec![logits],
+                ))
+            },
+        )?;
+        assert!(loss.ce_loss.is_none());
+        losses.push(loss.loss.to_scalar::<f32>()?);
+        sgd.backward_step(&loss.loss)?;
+    }
+    assert!(losses[49] < losses[0] * 0.1, "{losses:?}");
+
+    let targets = Tensor::new(&[0u32, 1, 0], dev)?;
+    let student = ModelOutput::new(xs.matmul(student_w.as_tensor())?);
+    let teacher = ModelOutput::new(xs.matmul(&teacher_w)?);
+    let distiller = Distiller::new(DistillationConfig::default());
+    let loss = distiller.loss(&student, &teacher, Some(&targets))?;
+    let expected =
+        (loss.kd_loss.to_scalar::<f32>()? + loss.ce_loss.unwrap().to_scalar::<f32>()?) * 0.5;
+    assert!((loss.loss.to_scalar::<f32>()? - expected).abs() < 1e-6);
+    assert!(loss.hidden_loss.is_none());
+    Ok(())
+}
@@ -0,0 +1,69 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{Device, Result, Tensor};
+use candle_nn::logger::{CsvLogger, JsonlLogger, Logger, Stats, TensorBoardLogger};
+
+#[test]
+fn csv_and_jsonl() -> Result<()> {
+    let values = Tensor::new(&[1f32, 2., 3., 4.], &Device::Cpu)?;
+    let mut csv = CsvLogger::new(vec![])?;
+    csv.log_scalar("loss", 0.5, 3)?;
+    csv.log_histogram("w", &values, 4)?;
+    let csv = String::from_utf8(csv.into_inner()).unwrap();
+    let lines = csv
+        .lines()
+        .map(|l| l.split_once(',').map_or(l, |(_, rest)| rest))
+        .collect::<Vec<_>>();
+    assert_eq!(
+        lines,
+        [
+            "step,tag,value",
+            "3,loss,0.5",
+            "4,w/min,1",
+            "4,w/max,4",
+            "4,w/mean,2.5",
+            "4,w/std,1.118033988749895"
+        ]
+    );
+
+    let mut jsonl = JsonlLogger::new(vec![]);
+    jsonl.log_scalar("a \"tag\"", f64::NAN, 1)?;
+    let jsonl = String::from_utf8(jsonl.into_inner()).unwrap();
+    assert!(jsonl.ends_with(",\"step\":1,\"tag\":\"a \\\"tag\\\"\",\"value\":null}\n"));
+
+    let stats = Stats::new(&[1., 2., 3., 4.]);
+    assert_eq!((stats.min, stats.max, stats.mean()), (1., 4., 2.5));
+    Ok(())
+}
+
+#[test]
+fn tensorboard() -> Result<()> {
+    let logdir = std::env::temp_dir().join(format!("candle-tb-{}", std::process::id()));
+    let mut logger = TensorBoardLogger::new(&logdir)?;
+    logger.log_scalar("loss", 0.5, 1)?;
+    logger.log_histogram("w", &Tensor::randn(0f32, 1., 100, &Device::Cpu)?, 1)?;
+    logger.flush()?;
+    let files = std::fs::read_dir(&logdir)?.collect::<std::io::Result<Vec<_>>>()?;
+    assert_eq!(files.len(), 1);
+    assert!(files[0]
+        .file_name()
+        .to_string_lossy()
+        .starts_with("events.out.tfevents."));
+    // Check the record framing: u64 length, u32 crc, data, u32 crc.
+    let bytes = std::fs::read(files[0].path())?;
+    let mut offset = 0;
+    let mut num_records = 0;
+    while offset < bytes.len() {
+        let len = u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap()) as usize;
+        offset += 12 + len + 4;
+        num_records += 1;
+    }
+    assert_eq!(offset, bytes.len());
+    assert_eq!(num_records, 3);
+    std::fs::remove_dir_all(&logdir)?;
+    Ok(())
+}
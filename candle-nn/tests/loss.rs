@@ -5,7 +5,7 @@ extern crate intel_mkl_src;
 extern crate accelerate_src;
 
 use candle::test_utils::to_vec0_round;
-use candle::{Device, Result, Tensor};
+use candle::{Device, Result, Tensor, Var};
 
 /* Equivalent python code:
 import torch
@@ -37,6 +37,10 @@ fn nll_and_cross_entropy() -> Result<()> {
     assert_eq!(to_vec0_round(&loss, 4)?, 1.1312);
     let loss = candle_nn::loss::cross_entropy(&input, &target)?;
     assert_eq!(to_vec0_round(&loss, 4)?, 1.1312);
+    for chunk_size in [1, 2, 5, 8] {
+        let loss = candle_nn::loss::cross_entropy_chunked(&input, &target, chunk_size)?;
+        assert_eq!(to_vec0_round(&loss, 4)?, 1.1312);
+    }
     Ok(())
 }
 
@@ -86,3 +90,39 @@ fn binary_cross_entropy_with_logit() -> Result<()> {
     assert_eq!(to_vec0_round(&loss, 4)?, 0.8224);
     Ok(())
 }
+
+#[test]
+fn linear_cross_entropy() -> Result<()> {
+    let cpu = Device::Cpu;
+    let xs = Var::randn(0f32, 1f32, (4, 6), &cpu)?;
+    let weight = Var::randn(0f32, 1f32, (7, 6), &cpu)?;
+    // Repeated targets accumulate in the gradient of the weight.
+    let target = Tensor::new(&[1u32, 6, 1, 0], &cpu)?;
+    let max_diff = |a: &Tensor, b: &Tensor| -> Result<f32> {
+        (a - b)?.abs()?.flatten_all()?.max(0)?.to_vec0::<f32>()
+    };
+
+    let logits = xs.matmul(&weight.t()?)?;
+    let expected = candle_nn::loss::cross_entropy(&logits, &target)?;
+    let expected_grads = expected.backward()?;
+    for chunk_size in [1, 3, 7, 10] {
+        let loss = candle_nn::loss::linear_cross_entropy(&xs, &weight, &target, chunk_size)?;
+        assert!(max_diff(&loss, &expected)? < 1e-5);
+        let grads = loss.backward()?;
+        for var in [&xs, &weight] {
+            let grad = grads.get(var).unwrap();
+            assert!(max_diff(grad, expected_grads.get(var).unwrap())? < 1e-5);
+        }
+    }
+
+    // Without backpropagation only the loss gets computed.
+    let loss = candle_nn::loss::linear_cross_entropy(
+        &xs.as_detached_tensor(),
+        &weight.as_detached_tensor(),
+        &target,
+        3,
+    )?;
+    assert!(max_diff(&loss, &expected)? < 1e-5);
+    assert!(candle_nn::loss::linear_cross_entropy(&xs, &weight, &target, 0).is_err());
+    Ok(())
+}
@@ -0,0 +1,69 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use candle::{Device, Result, Tensor};
+use candle_nn::metrics::{Accuracy, Bleu, ClassificationMetrics, RougeL, WordErrorRate};
+
+#[test]
+fn accuracy() -> Result<()> {
+    let logits = Tensor::new(
+        &[[0.1f32, 0.8, 0.1], [0.6, 0.3, 0.1], [0.2, 0.3, 0.5]],
+        &Device::Cpu,
+    )?;
+    let targets = Tensor::new(&[1u32, 1, 0], &Device::Cpu)?;
+    let mut top1 = Accuracy::new(1);
+    let mut top2 = Accuracy::new(2);
+    top1.update(&logits, &targets)?;
+    top2.update(&logits, &targets)?;
+    assert!((top1.compute() - 1. / 3.).abs() < 1e-6);
+    assert!((top2.compute() - 2. / 3.).abs() < 1e-6);
+    // Accumulate over a second batch.
+    top1.update(&logits.narrow(0, 0, 1)?, &targets.narrow(0, 0, 1)?)?;
+    assert_eq!(top1.compute(), 0.5);
+    top1.reset();
+    assert_eq!(top1.compute(), 0.);
+    Ok(())
+}
+
+#[test]
+fn precision_recall_f1() -> Result<()> {
+    let mut metrics = ClassificationMetrics::new(3);
+    let predictions = Tensor::new(&[0u32, 1, 1, 2], &Device::Cpu)?;
+    let targets = Tensor::new(&[0u32, 1, 2, 2], &Device::Cpu)?;
+    metrics.update(&predictions, &targets)?;
+    assert_eq!(metrics.precision(1), 0.5);
+    assert_eq!(metrics.recall(1), 1.0);
+    assert_eq!(metrics.recall(2), 0.5);
+    assert!((metrics.f1(2) - 2. / 3.).abs() < 1e-6);
+    assert!((metrics.macro_f1() - 7. / 9.).abs() < 1e-6);
+    assert_eq!(metrics.micro_f1(), 0.75);
+    Ok(())
+}
+
+#[test]
+fn bleu_rouge_wer() {
+    let reference = "the cat is on the mat".split(' ').collect::<Vec<_>>();
+    let mut bleu = Bleu::default();
+    bleu.update(&reference, &[&reference]);
+    assert!((bleu.compute() - 1.).abs() < 1e-6);
+
+    let candidate = "the the the the the the the".split(' ').collect::<Vec<_>>();
+    let mut bleu = Bleu::new(1);
+    bleu.update(&candidate, &[&reference]);
+    assert!((bleu.compute() - 2. / 7.).abs() < 1e-6);
+    bleu.reset();
+    assert_eq!(bleu.compute(), 0.);
+
+    let mut rouge = RougeL::new();
+    rouge.update(&['a', 'b', 'c', 'd'], &['a', 'c', 'd', 'e']);
+    assert!((rouge.compute() - 0.75).abs() < 1e-6);
+
+    let mut wer = WordErrorRate::new();
+    wer.update("the cat sat", "the cat sat down");
+    assert_eq!(wer.compute(), 0.25);
+    wer.update("a b", "a c");
+    assert!((wer.compute() - 2. / 6.).abs() < 1e-6);
+}
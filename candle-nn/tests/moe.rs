@@ -0,0 +1,212 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Module, Tensor, Var};
+use candle_nn::moe::{grouped_matmul, grouped_matmul_slow, Router, RouterConfig};
+use candle_nn::Linear;
+
+fn router(config: RouterConfig) -> Result<Router> {
+    let gate = Tensor::new(&[[1f32, 0.], [0., 1.], [-1., -1.]], &Device::Cpu)?;
+    Ok(Router::new(Linear::new(gate, None), config))
+}
+
+fn tokens() -> Result<Tensor> {
+    // The router logits are [2, 0, -2], [0, 2, -2], [-1, -0.5, 1.5] and [1, 0.5, -1.5].
+    Ok(Tensor::new(
+        &[[2f32, 0.], [0., 2.], [-1., -0.5], [1., 0.5]],
+        &Device::Cpu,
+    )?)
+}
+
+fn token_indices(routing: &candle_nn::moe::Routing) -> Result<Vec<(usize, Vec<u32>)>> {
+    Ok(routing
+        .groups()
+        .iter()
+        .map(|g| Ok((g.expert, g.token_indices.to_vec1::<u32>()?)))
+        .collect::<Result<Vec<_>>>()?)
+}
+
+#[test]
+fn moe_routing() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = tokens()?;
+    let routing = router(RouterConfig::new(3, 2))?.route(&xs, false)?;
+    assert_eq!(
+        routing.experts.to_vec2::<u32>()?,
+        [[0, 1], [1, 0], [2, 1], [0, 1]]
+    );
+    let weights = routing.weights.to_vec2::<f32>()?;
+    for (token_weights, expected) in weights.iter().zip([2f32, 2., 2., 0.5]) {
+        // The weights are normalized over the selected experts.
+        let first = 1. / (1. + (-expected).exp());
+        assert!((token_weights[0] - first).abs() < 1e-5);
+        assert!((token_weights[1] - (1. - first)).abs() < 1e-5);
+    }
+    assert_eq!(
+        token_indices(&routing)?,
+        [(0, vec![0, 1, 3]), (1, vec![0, 1, 2, 3]), (2, vec![2])]
+    );
+    assert_eq!(routing.num_dropped(), 0);
+
+    // The experts outputs are combined with the routing weights.
+    let experts = (0..3)
+        .map(|_| Ok(Linear::new(Tensor::randn(0f32, 1., (2, 2), dev)?, None)))
+        .collect::<Result<Vec<_>>>()?;
+    let ys = routing.forward_experts(&xs, &experts)?;
+    let experts_v = routing.experts.to_vec2::<u32>()?;
+    for token in 0..4 {
+        let x = xs.narrow(0, token, 1)?;
+        let mut expected = x.zeros_like()?;
+        for slot in 0..2 {
+            let expert = &experts[experts_v[token][slot] as usize];
+            expected = (expected + (expert.forward(&x)? * weights[token][slot] as f64)?)?;
+        }
+        let diff = (ys.narrow(0, token, 1)? - expected)?.abs()?.sum_all()?;
+        assert!(diff.to_vec0::<f32>()? < 1e-5);
+    }
+
+    // Switch Transformers style routing, a single expert weighted by its probability.
+    let routing =
+        router(RouterConfig::new(3, 1).with_normalize_weights(false))?.route(&xs, false)?;
+    assert_eq!(routing.experts.to_vec2::<u32>()?, [[0], [1], [2], [0]]);
+    let probs = routing.probs.to_vec2::<f32>()?;
+    assert_eq!(
+        routing.weights.flatten_all()?.to_vec1::<f32>()?,
+        [probs[0][0], probs[1][1], probs[2][2], probs[3][0]]
+    );
+    Ok(())
+}
+
+#[test]
+fn moe_capacity() -> Result<()> {
+    let xs = tokens()?;
+    let config = RouterConfig::new(3, 2).with_capacity_factor(Some(0.5));
+    assert_eq!(config.capacity(4), Some(2));
+    let routing = router(config)?.route(&xs, false)?;
+    // The first choices are assigned first, then the second choices while there is capacity.
+    assert_eq!(
+        token_indices(&routing)?,
+        [(0, vec![0, 3]), (1, vec![0, 1]), (2, vec![2])]
+    );
+    assert_eq!(routing.num_dropped(), 3);
+    let weights = routing.weights.to_vec2::<f32>()?;
+    assert_eq!(
+        routing.groups()[1]
+            .weights
+            .flatten_all()?
+            .to_vec1::<f32>()?,
+        [weights[0][1], weights[1][0]]
+    );
+    Ok(())
+}
+
+#[test]
+fn moe_load_balancing_loss() -> Result<()> {
+    let dev = &Device::Cpu;
+    // A gate of zeros results in uniform probabilities.
+    let gate = Var::zeros((4, 3), DType::F32, dev)?;
+    let router = Router::new(
+        Linear::new(gate.as_tensor().clone(), None),
+        RouterConfig::new(4, 2),
+    );
+    let xs = Tensor::randn(0f32, 1., (2, 5, 3), dev)?;
+    let routing = router.route(&xs, true)?;
+    assert_eq!(routing.num_tokens(), 10);
+    let loss = routing.load_balancing_loss()?.to_vec0::<f32>()?;
+    assert!((loss - 1.).abs() < 1e-5, "{loss}");
+
+    // The loss and the routing weights are differentiable with respect to the gate.
+    let gate = Var::randn(0f32, 1., (4, 3), dev)?;
+    let router = Router::new(
+        Linear::new(gate.as_tensor().clone(), None),
+        RouterConfig::new(4, 2).with_noise_std(0.1),
+    );
+    let routing = router.route(&xs, true)?;
+    let ys = routing.combine(&routing.dispatch(&xs)?)?;
+    let loss = (routing.load_balancing_loss()? + ys.sqr()?.sum_all()?)?;
+    let grads = loss.backward()?;
+    let grad = grads.get(&gate).unwrap();
+    assert!(grad.abs()?.sum_all()?.to_vec0::<f32>()? > 0.);
+    Ok(())
+}
+
+#[test]
+fn moe_grouped_matmul() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::randn(0f32, 1., (9, 5), dev)?;
+    let ws = Tensor::randn(0f32, 1., (4, 3, 5), dev)?;
+    // The third expert has no tokens.
+    let group_sizes = [2, 4, 0, 3];
+    let ys = grouped_matmul(&xs, &ws, &group_sizes)?;
+    let expected = grouped_matmul_slow(&xs, &ws, &group_sizes)?;
+    assert_eq!(ys.dims(), &[9, 3]);
+    let diff = (&ys - &expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_vec0::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+
+    let ys = grouped_matmul(
+        &xs.to_dtype(DType::BF16)?,
+        &ws.to_dtype(DType::BF16)?,
+        &group_sizes,
+    )?;
+    let diff = (ys.to_dtype(DType::F32)? - &expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_vec0::<f32>()?;
+    assert!(diff < 0.1, "{diff}");
+
+    assert!(grouped_matmul(&xs, &ws, &[2, 4, 3]).is_err());
+    assert!(grouped_matmul(&xs, &ws, &[2, 4, 1, 3]).is_err());
+
+    // The gradients match the ones of the reference implementation.
+    let xs = Var::from_tensor(&xs)?;
+    let ws = Var::from_tensor(&ws)?;
+    let grads = grouped_matmul(&xs, &ws, &group_sizes)?
+        .sqr()?
+        .sum_all()?
+        .backward()?;
+    let expected = grouped_matmul_slow(&xs, &ws, &group_sizes)?
+        .sqr()?
+        .sum_all()?
+        .backward()?;
+    for var in [&xs, &ws] {
+        let grad = grads.get(var).unwrap();
+        let diff = (grad - expected.get(var).unwrap())?
+            .abs()?
+            .flatten_all()?
+            .max(0)?;
+        assert!(diff.to_vec0::<f32>()? < 1e-4);
+    }
+    let grad_ws = grads.get(&ws).unwrap().get(2)?;
+    assert_eq!(grad_ws.abs()?.sum_all()?.to_vec0::<f32>()?, 0.);
+
+    // The grouped matmul can be used for the experts of a routing.
+    let routing = router(RouterConfig::new(3, 2))?.route(&tokens()?, false)?;
+    let ws = Tensor::randn(0f32, 1., (3, 2, 2), dev)?;
+    assert_eq!(routing.group_sizes()?, [3, 4, 1]);
+    let ys = grouped_matmul(
+        &routing.dispatch_grouped(&tokens()?)?,
+        &ws,
+        &routing.group_sizes()?,
+    )?;
+    let ys = routing.combine_grouped(&ys)?;
+    let experts = (0..3)
+        .map(|i| Ok(Linear::new(ws.get(i)?, None)))
+        .collect::<Result<Vec<_>>>()?;
+    let expected = routing.forward_experts(&tokens()?, &experts)?;
+    let diff = (ys - expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_vec0::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+    Ok(())
+}
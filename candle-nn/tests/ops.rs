@@ -112,6 +112,22 @@ fn softmax_numerical_stability() -> Result<()> {
     Ok(())
 }
 
+#[test]
+fn log_softmax_chunked() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Tensor::randn(0f32, 1f32, (3, 7, 5), dev)?;
+    for (dim, chunk_size) in [(1, 3), (1, 7), (2, 1), (2, 2), (0, 16)] {
+        let expected = candle_nn::ops::log_softmax(&xs, dim)?;
+        let log_sm = candle_nn::ops::log_softmax_chunked(&xs, dim, chunk_size)?;
+        let diff = (log_sm - expected)?.abs()?.flatten_all()?.max(0)?;
+        assert!(diff.to_vec0::<f32>()? < 1e-5);
+    }
+    let xs = Tensor::new(&[1234f32, 0., -1000.], dev)?;
+    let log_sm = candle_nn::ops::log_softmax_chunked(&xs, 0, 2)?;
+    assert_eq!(log_sm.to_vec1::<f32>()?, &[0f32, -1234., -2234.]);
+    Ok(())
+}
+
 fn ropei(device: &Device) -> Result<()> {
     use rand::{rngs::StdRng, Rng, SeedableRng};
 
@@ -206,6 +222,43 @@ fn sigmoid(device: &Device) -> Result<()> {
     Ok(())
 }
 
+fn fused_linear(device: &Device) -> Result<()> {
+    use candle::{DType, Var};
+    use candle_nn::ops::FusedActivation;
+    let xs = Tensor::new(&[[[1f32, -2., 3.], [0.5, 0.1, -1.]]], device)?;
+    let w = Tensor::new(&[[0.5f32, 1., -1.], [2., 0., 1.]], device)?;
+    let b = Tensor::new(&[0.1f32, -0.2], device)?;
+    let ys = candle_nn::ops::fused_linear(&xs, &w, Some(&b), None)?;
+    assert_eq!(to_vec3_round(&ys, 4)?, &[[[-4.4, 4.8], [1.45, -0.2]]]);
+    let ys = candle_nn::ops::fused_linear(&xs, &w, None, Some(FusedActivation::Relu))?;
+    assert_eq!(to_vec3_round(&ys, 4)?, &[[[0., 5.], [1.35, 0.]]]);
+
+    // The half precision version uses cublasLt on cuda, compare it with the slow version
+    // including the gradients.
+    for act in [
+        None,
+        Some(FusedActivation::Relu),
+        Some(FusedActivation::Gelu),
+    ] {
+        let xs = Var::from_tensor(&xs.to_dtype(DType::F16)?)?;
+        let w = Var::from_tensor(&w.to_dtype(DType::F16)?)?;
+        let b = Var::from_tensor(&b.to_dtype(DType::F16)?)?;
+        let ys1 = candle_nn::ops::fused_linear(&xs, &w, Some(&b), act)?;
+        let ys2 = candle_nn::ops::fused_linear_slow(&xs, &w, Some(&b), act)?;
+        let diff = (&ys1 - &ys2)?.abs()?.to_dtype(DType::F32)?.sum_all()?;
+        assert!(diff.to_vec0::<f32>()? < 1e-2, "{act:?}");
+        let grads1 = ys1.sqr()?.sum_all()?.backward()?;
+        let grads2 = ys2.sqr()?.sum_all()?.backward()?;
+        for v in [&xs, &w, &b] {
+            let g1 = grads1.get(v).unwrap().to_dtype(DType::F32)?;
+            let g2 = grads2.get(v).unwrap().to_dtype(DType::F32)?;
+            let diff = (g1 - g2)?.abs()?.sum_all()?.to_vec0::<f32>()?;
+            assert!(diff < 5e-2, "{act:?} {diff}");
+        }
+    }
+    Ok(())
+}
+
 test_device!(ropei, ropei_cpu, ropei_gpu, ropei_metal);
 test_device!(rope, rope_cpu, rope_gpu, rope_metal);
 test_device!(rope_thd, rope_thd_cpu, rope_thd_gpu, rope_thd_metal);
@@ -213,3 +266,111 @@ test_device!(softmax, softmax_cpu, softmax_gpu, softmax_metal);
 test_device!(rms_norm, rms_norm_cpu, rms_norm_gpu, rms_norm_metal);
 test_device!(layer_norm, ln_cpu, ln_gpu, ln_metal);
 test_device!(sigmoid, sigmoid_cpu, sigmoid_gpu, sigmoid_metal);
+test_device!(
+    fused_linear,
+    fused_linear_cpu,
+    fused_linear_gpu,
+    fused_linear_metal
+);
+
+// The wgpu backend uses the generic versions of the fused ops.
+#[cfg(feature = "wgpu")]
+#[test]
+fn ops_wgpu() -> Result<()> {
+    let device = match Device::new_wgpu(0) {
+        Ok(device) => device,
+        // No adapter available.
+        Err(_) => return Ok(()),
+    };
+    ropei(&device)?;
+    rope(&device)?;
+    rope_thd(&device)?;
+    softmax(&device)?;
+    rms_norm(&device)?;
+    layer_norm(&device)
+}
+
+#[test]
+fn ops_meta() -> Result<()> {
+    use candle::{DType, Module};
+    let device = &Device::Meta;
+    let varmap = candle_nn::VarMap::new();
+    let vb = candle_nn::VarBuilder::from_varmap(&varmap, DType::BF16, device);
+    let emb = candle_nn::embedding(32000, 4096, vb.pp("emb"))?;
+    let norm = candle_nn::rms_norm(4096, 1e-5, vb.pp("norm"))?;
+    let proj = candle_nn::linear_no_bias(4096, 11008, vb.pp("proj"))?;
+    let n_params = 32000 * 4096 + 4096 + 4096 * 11008;
+    assert_eq!(varmap.num_elements(), n_params);
+    assert_eq!(varmap.size_in_bytes(), 2 * n_params);
+
+    let ids = Tensor::zeros((2, 7), DType::U32, device)?;
+    let xs = norm.forward(&emb.forward(&ids)?)?;
+    let (cos, sin) = (
+        Tensor::zeros((7, 64), DType::BF16, device)?,
+        Tensor::zeros((7, 64), DType::BF16, device)?,
+    );
+    let xs = xs.reshape((2, 7, 32, 128))?.transpose(1, 2)?.contiguous()?;
+    let xs = candle_nn::rotary_emb::rope(&xs, &cos, &sin)?;
+    let xs = xs.transpose(1, 2)?.reshape((2, 7, 4096))?;
+    let ys = candle_nn::ops::softmax_last_dim(&proj.forward(&xs)?)?;
+    assert_eq!(ys.dims(), [2, 7, 11008]);
+    assert_eq!(ys.dtype(), DType::BF16);
+    assert!(ys.to_vec3::<half::bf16>().is_err());
+    Ok(())
+}
+
+#[test]
+fn rotary_embedding_cache() -> Result<()> {
+    use candle::DType;
+    use candle_nn::rotary_emb::{RopeScaling, RotaryConfig, RotaryEmbeddingCache};
+
+    let device = &Device::Cpu;
+    let config = RotaryConfig::new(4, 100.);
+    assert_eq!(config.inv_freq()?, [1., 0.1]);
+    let cache = RotaryEmbeddingCache::new(config)?;
+    assert_eq!(cache.len(DType::F32, device), 0);
+    let (cos, sin) = cache.cos_sin(2, 3, DType::F32, device)?;
+    assert_eq!(cos.dims(), [3, 2]);
+    let positions = Tensor::new(&[[2f32], [3.], [4.]], device)?;
+    let freqs = positions.broadcast_mul(&Tensor::new(&[1f32, 0.1], device)?)?;
+    let diff = ((cos - freqs.cos()?)?.abs()? + (sin - freqs.sin()?)?.abs()?)?;
+    assert!(diff.sum_all()?.to_vec0::<f32>()? < 1e-5);
+    assert_eq!(cache.len(DType::F32, device), 5);
+
+    // The table doubles when extended, and is shared with the clones.
+    let shared = cache.clone();
+    shared.cos_sin(5, 1, DType::F32, device)?;
+    assert_eq!(cache.len(DType::F32, device), 10);
+    cache.cos_sin(0, 25, DType::F32, device)?;
+    assert_eq!(shared.len(DType::F32, device), 25);
+    // There is one table per dtype.
+    assert_eq!(cache.len(DType::F16, device), 0);
+    let (cos, _sin) = cache.cos_sin(0, 4, DType::F16, device)?;
+    assert_eq!(cos.dtype(), DType::F16);
+
+    // Applying the cache is the same as using the rope op on the tables.
+    let xs = Tensor::arange(0f32, 48., device)?.reshape((1, 2, 6, 4))?;
+    let (cos, sin) = cache.cos_sin(3, 6, DType::F32, device)?;
+    let expected = candle_nn::rotary_emb::rope(&xs, &cos, &sin)?;
+    let diff = (cache.apply(&xs, 3)? - expected)?.abs()?.sum_all()?;
+    assert_eq!(diff.to_vec0::<f32>()?, 0.);
+
+    // Scaling.
+    let linear = RotaryConfig::new(4, 100.).with_scaling(RopeScaling::Linear { factor: 2. });
+    assert_eq!(linear.inv_freq()?, [0.5, 0.05]);
+    let factors = RopeScaling::FrequencyFactors(vec![1., 4.]);
+    let config = RotaryConfig::new(4, 100.).with_scaling(factors);
+    assert_eq!(config.inv_freq()?, [1., 0.025]);
+    let config = config.with_scaling(RopeScaling::FrequencyFactors(vec![1.]));
+    assert!(config.inv_freq().is_err());
+    // The short wavelengths are kept and the long ones are scaled.
+    let llama3 = RopeScaling::Llama3 {
+        factor: 8.,
+        low_freq_factor: 1.,
+        high_freq_factor: 4.,
+        original_max_position_embeddings: 64,
+    };
+    let config = RotaryConfig::new(4, 10000.).with_scaling(llama3);
+    assert_eq!(config.inv_freq()?, [1., 0.01 / 8.]);
+    Ok(())
+}
@@ -8,7 +8,7 @@ use candle::test_utils::{to_vec0_round, to_vec2_round};
 
 use anyhow::Result;
 use candle::{DType, Device, Tensor, Var};
-use candle_nn::{AdamW, Linear, Module, Optimizer, ParamsAdamW, SGD};
+use candle_nn::{AdamW, GradAccumulation, Linear, Module, Optimizer, ParamsAdamW, SGD};
 
 #[test]
 fn sgd_optim() -> Result<()> {
@@ -158,3 +158,46 @@ fn adamw_linear_regression_varmap() -> Result<()> {
     assert_eq!(to_vec0_round(lin.bias().unwrap(), 4)?, 1.);
     Ok(())
 }
+
+#[test]
+fn grad_accumulation() -> Result<()> {
+    let xs = Tensor::new(&[[2f32, 1.], [7., 4.], [-4., 12.], [5., 8.]], &Device::Cpu)?;
+    let ys = Tensor::new(&[[5f32], [23.], [7.], [21.]], &Device::Cpu)?;
+    let loss = |w: &Var, xs: &Tensor, ys: &Tensor| -> candle::Result<Tensor> {
+        (xs.matmul(&w.t()?)? - ys)?.sqr()?.mean_all()
+    };
+
+    // One step on the full batch.
+    let w1 = Var::new(&[[0f32, 0.]], &Device::Cpu)?;
+    let mut opt = AdamW::new_lr(vec![w1.clone()], 0.1)?;
+    for _step in 0..5 {
+        opt.backward_step(&loss(&w1, &xs, &ys)?)?;
+    }
+
+    // The same steps, accumulating the gradients over two micro-batches.
+    let w2 = Var::new(&[[0f32, 0.]], &Device::Cpu)?;
+    let params = ParamsAdamW {
+        lr: 0.1,
+        ..Default::default()
+    };
+    let mut opt = GradAccumulation::<AdamW>::new(vec![w2.clone()], (params, 2))?;
+    for _step in 0..5 {
+        for i in 0..2 {
+            assert_eq!(opt.micro_step(), i);
+            let (xs, ys) = (xs.narrow(0, 2 * i, 2)?, ys.narrow(0, 2 * i, 2)?);
+            opt.backward_step(&loss(&w2, &xs, &ys)?)?;
+        }
+    }
+    assert_eq!(opt.inner().step_count(), 5);
+    assert_eq!(
+        to_vec2_round(w1.as_tensor(), 4)?,
+        to_vec2_round(w2.as_tensor(), 4)?
+    );
+
+    // Partial accumulations can be discarded.
+    opt.backward_step(&loss(&w2, &xs, &ys)?)?;
+    assert!(opt.is_sync_step());
+    opt.zero_grad();
+    assert_eq!(opt.micro_step(), 0);
+    Ok(())
+}
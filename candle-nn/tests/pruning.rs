@@ -0,0 +1,56 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::test_utils::to_vec1_round;
+use candle::{Device, Tensor};
+use candle_nn::{pruning, VarMap};
+
+#[test]
+fn pruning_scores() -> Result<()> {
+    let dev = &Device::Cpu;
+    let up = Tensor::new(&[[3f32, 4.], [0., 1.], [1., 0.]], dev)?;
+    let down = Tensor::new(&[[1f32, 2., 0.], [0., 0., 3.]], dev)?;
+    assert_eq!(to_vec1_round(&pruning::row_norms(&up)?, 4)?, [5., 1., 1.]);
+    assert_eq!(to_vec1_round(&pruning::col_norms(&down)?, 4)?, [1., 2., 3.]);
+    let scores = pruning::mlp_scores(&[&up], &down, None)?;
+    assert_eq!(to_vec1_round(&scores, 4)?, [5., 2., 3.]);
+    let mean_sq = Tensor::new(&[1f32, 4., 0.25], dev)?;
+    let scores = pruning::mlp_scores(&[&up], &down, Some(&mean_sq))?;
+    assert_eq!(to_vec1_round(&scores, 4)?, [1., 4., 1.5]);
+    assert_eq!(pruning::top_k(&scores, 2)?, [1, 2]);
+    assert!(pruning::top_k(&scores, 4).is_err());
+    assert_eq!(
+        pruning::select_cols(&down, &[0, 2])?.to_vec2::<f32>()?,
+        [[1., 0.], [0., 3.]]
+    );
+    assert_eq!(
+        pruning::select_rows(&up, &[1])?.to_vec2::<f32>()?,
+        [[0., 1.]]
+    );
+
+    // Two heads of dimension 2.
+    let o_proj = Tensor::new(&[[1f32, 0., 0., 2.], [0., 0., 0., 0.]], dev)?;
+    let scores = pruning::head_scores(&o_proj, 2, None)?;
+    assert_eq!(to_vec1_round(&scores, 4)?, [1., 2.]);
+    let scores = pruning::group_scores(&Tensor::new(&[1f32, 2., 3., 4.], dev)?, 2)?;
+    assert_eq!(scores.to_vec1::<f32>()?, [3., 7.]);
+    assert_eq!(pruning::expand_groups(&[0, 2], 3), [0, 1, 2, 6, 7, 8]);
+    Ok(())
+}
+
+#[test]
+fn varmap_from_tensors() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::new(&[1f32, 2.], dev)?;
+    let varmap = VarMap::from_tensors([("w", &w)])?;
+    let vars = varmap.all_vars();
+    assert_eq!(vars.len(), 1);
+    vars[0].set(&Tensor::new(&[3f32, 4.], dev)?)?;
+    // The variables do not share their storage with the original tensors.
+    assert_eq!(w.to_vec1::<f32>()?, [1., 2.]);
+    Ok(())
+}
@@ -0,0 +1,130 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::test_utils::to_vec1_round;
+use candle::{DType, Device, Tensor, Var};
+use candle_nn::qat::{
+    fake_quantize_per_channel, fake_quantize_per_tensor, qat_linear, QatLinear, QuantConfig,
+    QuantParams,
+};
+use candle_nn::{Linear, ModuleT, VarBuilder, VarMap};
+
+#[test]
+fn fake_quantize() -> Result<()> {
+    let dev = &Device::Cpu;
+    let xs = Var::new(&[-1.3f32, -0.2, 0.26, 0.74, 2.0], dev)?;
+    // Scale 0.5 with the range [-2, 1].
+    let ys = fake_quantize_per_tensor(&xs, 0.5, 0., -4., 2.)?;
+    assert_eq!(ys.to_vec1::<f32>()?, [-1.5, 0., 0.5, 0.5, 1.0]);
+    // Straight-through estimator, the clamped value gets no gradient.
+    let grads = ys.sum_all()?.backward()?;
+    let grad = grads.get(&xs).unwrap();
+    assert_eq!(grad.to_vec1::<f32>()?, [1., 1., 1., 1., 0.]);
+
+    let xs = Tensor::new(&[[0.3f32, 0.6], [3.3, -2.6]], dev)?;
+    let scale = Tensor::new(&[0.5f32, 2.], dev)?;
+    let zero_point = Tensor::new(&[0f32, 1.], dev)?;
+    let ys = fake_quantize_per_channel(&xs, &scale, &zero_point, 0, -8., 7.)?;
+    assert_eq!(ys.to_vec2::<f32>()?, [[0.5, 0.5], [4., -2.]]);
+    Ok(())
+}
+
+#[test]
+fn quant_params() -> Result<()> {
+    let dev = &Device::Cpu;
+    let config = QuantConfig::uint8();
+    assert_eq!(config.qrange(), (0., 255.));
+    assert_eq!(QuantConfig::int8().qrange(), (-128., 127.));
+    let min = Tensor::new(&[-1f32], dev)?;
+    let max = Tensor::new(&[1.55f32], dev)?;
+    let params = QuantParams::from_min_max(&min, &max, &config)?;
+    assert_eq!(to_vec1_round(&params.scale, 4)?, [0.01]);
+    assert_eq!(params.zero_point.to_vec1::<f32>()?, [100.]);
+    let xs = Tensor::new(&[-1f32, 0., 0.5, 2.], dev)?;
+    let q = params.quantize(&xs)?;
+    assert_eq!(q.to_vec1::<i64>()?, [0, 100, 150, 255]);
+    assert_eq!(
+        to_vec1_round(&params.dequantize(&q)?, 4)?,
+        [-1., 0., 0.5, 1.55]
+    );
+
+    let config = QuantConfig::int8().with_bits(4).with_per_channel(1);
+    let min = Tensor::new(&[-0.7f32, 0.5], dev)?;
+    let max = Tensor::new(&[0.3f32, 1.4], dev)?;
+    let params = QuantParams::from_min_max(&min, &max, &config)?;
+    assert_eq!(to_vec1_round(&params.scale, 4)?, [0.0933, 0.1867]);
+    let xs = Tensor::new(&[[-0.65f32, 1.4], [0.1, -3.]], dev)?;
+    let q = params.quantize(&xs)?;
+    assert_eq!(q.to_vec2::<i64>()?, [[-7, 7], [1, -8]]);
+    assert!(QuantParams::from_min_max(&min, &max, &config.with_bits(1)).is_err());
+    Ok(())
+}
+
+#[test]
+fn fake_quantize_layer() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let fq = candle_nn::qat::fake_quantize_layer(
+        2,
+        QuantConfig::uint8().with_averaging_constant(None),
+        vb,
+    )?;
+    let xs = Tensor::new(&[0.1f32, 0.2, 0.3], dev)?;
+    // Nothing observed yet.
+    assert!(!fq.is_initialized()?);
+    assert_eq!(fq.forward_t(&xs, false)?.to_vec1::<f32>()?, [0.1, 0.2, 0.3]);
+    fq.forward_t(&Tensor::new(&[-1f32, 0.5], dev)?, true)?;
+    fq.forward_t(&Tensor::new(&[-0.5f32, 2.], dev)?, true)?;
+    assert_eq!(fq.min().to_vec1::<f32>()?, [-1.]);
+    assert_eq!(fq.max().to_vec1::<f32>()?, [2.]);
+    // The ranges are stored in the varmap.
+    assert_eq!(varmap.all_vars().len(), 2);
+    // The range is frozen in eval mode.
+    let ys = fq.forward_t(&Tensor::new(&[3f32, 0.31], dev)?, false)?;
+    assert_eq!(to_vec1_round(&ys, 3)?, [2., 0.306]);
+    assert_eq!(fq.max().to_vec1::<f32>()?, [2.]);
+    Ok(())
+}
+
+#[test]
+fn qat_linear_layer() -> Result<()> {
+    let dev = &Device::Cpu;
+    let weight = Tensor::new(&[[1f32, -0.5, 0.26], [0.01, 0.02, -0.04]], dev)?;
+    let linear = Linear::new(weight.clone(), None);
+    let layer = QatLinear::from_linear(linear, QuantConfig::int8(), None)?;
+    // Per output channel symmetric quantization, the scales are the max absolute values divided
+    // by 127.5.
+    let qweight = layer.quantized_weight()?;
+    assert_eq!(
+        candle::test_utils::to_vec2_round(&qweight, 4)?,
+        [[0.9961, -0.502, 0.2588], [0.01, 0.0201, -0.0398]]
+    );
+    let (q, params) = layer.int_weight()?;
+    assert_eq!(q.to_vec2::<i64>()?, [[127, -64, 33], [32, 64, -127]]);
+    assert_eq!(params.scale.dims(), [2]);
+    let xs = Tensor::new(&[[1f32, 1., 1.]], dev)?;
+    let ys = layer.forward_t(&xs, false)?;
+    assert_eq!(
+        candle::test_utils::to_vec2_round(&ys, 4)?,
+        [[0.7529, -0.0097]]
+    );
+    let qtensor = layer.to_qtensor(candle::quantized::GgmlDType::F32)?;
+    assert_eq!(qtensor.shape().dims(), [2, 3]);
+
+    // Training a layer with quantized inputs.
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let layer = qat_linear(3, 2, QuantConfig::int8(), Some(QuantConfig::uint8()), vb)?;
+    let ys = layer.forward_t(&xs, true)?;
+    assert_eq!(ys.dims(), [1, 2]);
+    let grads = ys.sum_all()?.backward()?;
+    let weight = varmap.data().lock().unwrap()["weight"].clone();
+    assert!(grads.get(&weight).is_some());
+    assert!(layer.input_fake_quantize().unwrap().is_initialized()?);
+    Ok(())
+}
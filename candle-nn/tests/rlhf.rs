@@ -0,0 +1,115 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::test_utils::to_vec1_round;
+use candle::{Device, Tensor};
+use candle_nn::rlhf;
+
+#[test]
+fn log_sigmoid() -> Result<()> {
+    let xs = Tensor::new(&[-100f32, -1., 2., 1., 100.], &Device::Cpu)?;
+    let ys = rlhf::log_sigmoid(&xs)?;
+    assert_eq!(
+        to_vec1_round(&ys, 4)?,
+        [-100.0, -1.3133, -0.1269, -0.3133, 0.0]
+    );
+    Ok(())
+}
+
+#[test]
+fn dpo_and_ppo() -> Result<()> {
+    let dev = &Device::Cpu;
+    let pc = Tensor::new(&[-1f32, -2.], dev)?;
+    let pr = Tensor::new(&[-3f32, -2.], dev)?;
+    let rc = Tensor::new(&[-2f32, -2.], dev)?;
+    let rr = Tensor::new(&[-2f32, -2.], dev)?;
+    // The logits are 0.5 * 2 = 1 and 0.
+    let loss = rlhf::dpo_loss(&pc, &pr, &rc, &rr, 0.5, 0.)?;
+    assert_eq!(to_vec1_round(&loss.unsqueeze(0)?, 4)?, [0.5032]);
+    let loss = rlhf::dpo_loss(&pc, &pr, &rc, &rr, 0.5, 0.1)?;
+    assert_eq!(to_vec1_round(&loss.unsqueeze(0)?, 4)?, [0.5532]);
+    let rewards = rlhf::dpo_rewards(&pc, &rc, 0.5)?;
+    assert_eq!(rewards.to_vec1::<f32>()?, [0.5, 0.]);
+
+    let log_probs = Tensor::new(&[[0.5f32, 0., -0.5]], dev)?;
+    let old_log_probs = Tensor::zeros((1, 3), candle::DType::F32, dev)?;
+    let advantages = Tensor::new(&[[1f32, 1., -1.]], dev)?;
+    // The ratios are e^0.5 (clipped to 1.2), 1, and e^-0.5 (clipped to 0.8 for a negative
+    // advantage).
+    let loss = rlhf::ppo_policy_loss(&log_probs, &old_log_probs, &advantages, None, 0.2)?;
+    assert_eq!(to_vec1_round(&loss.unsqueeze(0)?, 4)?, [-0.4667]);
+    let mask = Tensor::new(&[[1f32, 1., 0.]], dev)?;
+    let loss = rlhf::ppo_policy_loss(&log_probs, &old_log_probs, &advantages, Some(&mask), 0.2)?;
+    assert_eq!(to_vec1_round(&loss.unsqueeze(0)?, 4)?, [-1.1]);
+
+    let values = Tensor::new(&[1f32, 0.], dev)?;
+    let old_values = Tensor::new(&[0f32, 0.], dev)?;
+    let returns = Tensor::new(&[1f32, 2.], dev)?;
+    let loss = rlhf::ppo_value_loss(&values, &old_values, &returns, None, 0.5)?;
+    // max((1-1)^2, (0.5-1)^2) = 0.25 and max(4, 4) = 4.
+    assert_eq!(to_vec1_round(&loss.unsqueeze(0)?, 4)?, [1.0625]);
+
+    let kl = rlhf::approx_kl(&log_probs, &log_probs)?;
+    assert_eq!(kl.to_vec2::<f32>()?, [[0., 0., 0.]]);
+
+    let (advantages, returns) = rlhf::gae(&[0., 0., 1.], &[0.5, 0.5, 0.5], 1., 1.);
+    assert_eq!(advantages, [0.5, 0.5, 0.5]);
+    assert_eq!(returns, [1., 1., 1.]);
+    Ok(())
+}
+
+// A toy language model where the logits only depend on the previous tokens, with a "kv-cache"
+// holding the tokens processed so far.
+#[derive(Clone)]
+struct Toy {
+    cache: Vec<u32>,
+}
+
+const VOCAB: usize = 5;
+
+impl Toy {
+    fn forward(&mut self, xs: &Tensor, offset: usize) -> candle::Result<Tensor> {
+        assert_eq!(offset, self.cache.len());
+        let mut logits = vec![];
+        for x in xs.squeeze(0)?.to_vec1::<u32>()? {
+            self.cache.push(x);
+            let sum = self.cache.iter().sum::<u32>();
+            logits.extend((0..VOCAB as u32).map(|v| ((sum + v) % 3) as f32));
+        }
+        Tensor::from_vec(logits, (1, self.cache.len() - offset, VOCAB), xs.device())
+    }
+}
+
+#[test]
+fn reference_log_probs() -> Result<()> {
+    let dev = &Device::Cpu;
+    let prompt = [1u32, 2, 3];
+    let completions: [&[u32]; 3] = [&[4, 0, 1], &[2], &[]];
+    let mut model = Toy { cache: vec![] };
+    let log_probs =
+        rlhf::reference_log_probs(&mut model, &prompt, &completions, dev, Toy::forward)?;
+    for (completion, log_probs) in completions.iter().zip(log_probs.iter()) {
+        // Process the full sequence in one go.
+        let tokens = [&prompt[..], completion].concat();
+        let mut model = Toy { cache: vec![] };
+        let input = Tensor::new(&tokens[..tokens.len() - 1], dev)?.unsqueeze(0)?;
+        let logits = model.forward(&input, 0)?;
+        let targets = Tensor::new(&tokens[1..], dev)?.unsqueeze(0)?;
+        let mut mask = vec![0u32; tokens.len() - 1];
+        mask[prompt.len() - 1..].fill(1);
+        let mask = Tensor::new(mask, dev)?.unsqueeze(0)?;
+        let expected = rlhf::sequence_log_probs(&logits, &targets, Some(&mask))?;
+        let expected = expected.squeeze(0)?.to_scalar::<f32>()?;
+        assert!(
+            (log_probs - expected).abs() < 1e-5,
+            "{log_probs} {expected}"
+        );
+    }
+    assert_eq!(log_probs[2], 0.);
+    assert!(log_probs[0] < 0.);
+    Ok(())
+}
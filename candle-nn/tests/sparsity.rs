@@ -0,0 +1,97 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use candle_nn::sparsity::{is_2_4_sparse, n_m_mask, prune_2_4, SemiStructuredTensor, SparseLinear};
+use candle_nn::{Linear, Module};
+
+#[test]
+fn prune_and_compress() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::new(
+        &[
+            [1f32, -4., 3., 2., 0., 0., 0., 7.],
+            [0.5, 0.1, -0.2, 0.3, 1., 2., 3., 4.],
+        ],
+        dev,
+    )?;
+    assert!(!is_2_4_sparse(&w)?);
+    let mask = n_m_mask(&w, 1, 4)?;
+    assert_eq!(
+        mask.to_vec2::<f32>()?,
+        [
+            [0., 0., 1., 0., 0., 0., 0., 1.],
+            [1., 0., 0., 0., 0., 0., 0., 1.]
+        ]
+    );
+    let pruned = prune_2_4(&w, None)?;
+    assert_eq!(
+        pruned.to_vec2::<f32>()?,
+        [
+            [0., -4., 3., 0., 0., 0., 0., 7.],
+            [0.5, 0., 0., 0.3, 0., 0., 3., 4.]
+        ]
+    );
+    assert!(is_2_4_sparse(&pruned)?);
+    // Weighting with the input activations changes the selection.
+    let mean_sq = Tensor::new(&[100f32, 1., 1., 1., 1., 1., 1., 1.], dev)?;
+    let pruned_wanda = prune_2_4(&w, Some(&mean_sq))?;
+    assert_eq!(
+        pruned_wanda.to_vec2::<f32>()?[0],
+        [1., -4., 0., 0., 0., 0., 0., 7.]
+    );
+
+    assert!(SemiStructuredTensor::compress(&w).is_err());
+    let sparse = SemiStructuredTensor::compress(&pruned)?;
+    assert_eq!(sparse.dims(), (2, 8));
+    assert_eq!(
+        sparse.values().to_vec2::<f32>()?,
+        [[-4., 3., 0., 7.], [0.5, 0.3, 3., 4.]]
+    );
+    // The first group of the first row only has a single non-zero value.
+    let indices = sparse.indices().to_vec2::<u8>()?;
+    assert_eq!(indices[1], [0, 3, 2, 3]);
+    assert_eq!(indices[0][0..2], [1, 2]);
+    assert_eq!(
+        sparse.packed_indices()?.to_vec2::<u8>()?,
+        [
+            [1 + (2 << 2) + (indices[0][2] << 4) + (3 << 6)],
+            [(3 << 2) + (2 << 4) + (3 << 6)]
+        ]
+    );
+    assert_eq!(
+        sparse.to_dense()?.to_vec2::<f32>()?,
+        pruned.to_vec2::<f32>()?
+    );
+    Ok(())
+}
+
+#[test]
+fn sparse_linear() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w = Tensor::randn(0f32, 1., (6, 16), dev)?;
+    let b = Tensor::randn(0f32, 1., 6, dev)?;
+    let linear = Linear::new(w, Some(b));
+    let sparse = SparseLinear::prune_linear(&linear, None)?;
+    let dense = sparse.to_linear()?;
+    assert!(is_2_4_sparse(dense.weight())?);
+    assert!(SparseLinear::from_linear(&linear).is_err());
+    let xs = Tensor::randn(0f32, 1., (2, 3, 16), dev)?;
+    let diff = (sparse.forward(&xs)? - dense.forward(&xs)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert_eq!(diff, 0.);
+
+    let sparse = SparseLinear::prune_linear(&linear.clone(), None)?;
+    let w = sparse.weight().to_dense()?.to_dtype(DType::F16)?;
+    let sparse = SemiStructuredTensor::compress(&w)?;
+    assert_eq!(sparse.dtype(), DType::F16);
+    assert_eq!(sparse.values().dims(), [6, 8]);
+    Ok(())
+}
@@ -0,0 +1,117 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use anyhow::Result;
+use candle::{DType, Device, Tensor};
+use candle_nn::trainer::{
+    Callback, LrScheduler, Trainer, TrainerConfig, TrainerState, WarmupCosine, WarmupLinear,
+};
+use candle_nn::{linear, Linear, Module, Optimizer, VarBuilder, VarMap, SGD};
+use std::sync::{Arc, Mutex};
+
+struct Steps(Arc<Mutex<Vec<usize>>>);
+
+impl Callback for Steps {
+    fn on_step_end(&mut self, state: &TrainerState) -> candle::Result<()> {
+        self.0.lock().unwrap().push(state.step);
+        Ok(())
+    }
+}
+
+#[test]
+fn trainer_linear_regression() -> Result<()> {
+    let dev = &Device::Cpu;
+    let w_gen = Tensor::new(&[[3f32, 1.]], dev)?;
+    let b_gen = Tensor::new(-2f32, dev)?;
+    let gen = Linear::new(w_gen, Some(b_gen));
+    let xs = Tensor::new(&[[2f32, 1.], [7., 4.], [-4., 12.], [5., 8.]], dev)?;
+    let ys = gen.forward(&xs)?;
+
+    let varmap = VarMap::new();
+    let model = linear(2, 1, VarBuilder::from_varmap(&varmap, DType::F32, dev))?;
+    let sgd = SGD::new(varmap.all_vars(), 0.004)?;
+    let config = TrainerConfig {
+        epochs: 1000,
+        grad_accum_steps: 2,
+        max_grad_norm: Some(100.),
+        ..Default::default()
+    };
+    let steps = Arc::new(Mutex::new(vec![]));
+    let mut trainer = Trainer::new(varmap, sgd, config).with_callback(Steps(steps.clone()));
+    // Each epoch is made of the two halves of the data, accumulating the gradients of both halves
+    // is the same as a single step on the whole batch.
+    let halves = [
+        (xs.narrow(0, 0, 2)?, ys.narrow(0, 0, 2)?),
+        (xs.narrow(0, 2, 2)?, ys.narrow(0, 2, 2)?),
+    ];
+    let batches = |_epoch| halves.iter().cloned().map(Ok);
+    trainer.fit_with_eval(
+        batches,
+        || vec![Ok((xs.clone(), ys.clone()))],
+        |(xs, ys): (Tensor, Tensor)| (model.forward(&xs)? - ys)?.sqr()?.sum_all(),
+    )?;
+    assert_eq!(trainer.state().step, 1000);
+    assert_eq!(steps.lock().unwrap().len(), 1000);
+    let loss = trainer.evaluate(vec![Ok((xs.clone(), ys.clone()))], |(xs, ys)| {
+        (model.forward(&xs)? - ys)?.sqr()?.sum_all()
+    })?;
+    assert!(loss < 0.1, "{loss}");
+
+    let config = TrainerConfig {
+        max_steps: Some(3),
+        epochs: 10,
+        ..Default::default()
+    };
+    let (varmap, sgd) = trainer.into_inner();
+    let mut trainer = Trainer::new(varmap, sgd, config);
+    trainer.fit(
+        |_| halves.iter().cloned().map(Ok),
+        |(xs, ys): (Tensor, Tensor)| (model.forward(&xs)? - ys)?.sqr()?.sum_all(),
+    )?;
+    assert_eq!(trainer.state().step, 3);
+    assert_eq!(trainer.state().epoch, 1);
+    Ok(())
+}
+
+#[test]
+fn trainer_loss_scale() -> Result<()> {
+    let dev = &Device::Cpu;
+    let varmap = VarMap::new();
+    let w = varmap.get((1,), "w", candle_nn::Init::Const(1.), DType::F32, dev)?;
+    let sgd = SGD::new(varmap.all_vars(), 0.1)?;
+    let config = TrainerConfig {
+        loss_scale: Some(1e38),
+        loss_scale_window: 2,
+        ..Default::default()
+    };
+    let mut trainer = Trainer::new(varmap, sgd, config);
+    // The gradient overflows with a scale of 1e38, the step is skipped and the scale halved.
+    let loss = w.affine(10., 0.)?.sum_all()?;
+    trainer.train_step(&loss)?;
+    assert_eq!(trainer.state().skipped_steps, 1);
+    assert_eq!(trainer.state().loss_scale, Some(5e37));
+    assert_eq!(w.to_vec1::<f32>()?, [1.]);
+
+    let loss = w.sum_all()?;
+    trainer.train_step(&loss)?;
+    assert_eq!(trainer.state().skipped_steps, 1);
+    assert_eq!(trainer.state().step, 1);
+    assert_eq!(w.to_vec1::<f32>()?, [0.9]);
+    assert_eq!(trainer.optimizer().learning_rate(), 0.1);
+    Ok(())
+}
+
+#[test]
+fn lr_schedulers() {
+    let cosine = WarmupCosine::new(10, 110, 0.1);
+    assert_eq!(cosine.learning_rate(0, 1.), 0.1);
+    assert_eq!(cosine.learning_rate(9, 1.), 1.);
+    assert!((cosine.learning_rate(60, 1.) - 0.55).abs() < 1e-9);
+    assert_eq!(cosine.learning_rate(200, 1.), 0.1);
+    let linear = WarmupLinear::new(0, 100);
+    assert_eq!(linear.learning_rate(25, 2.), 1.5);
+    assert_eq!(linear.learning_rate(200, 2.), 0.);
+}
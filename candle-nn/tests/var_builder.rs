@@ -0,0 +1,62 @@
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::var_builder::KeyRemap;
+use candle_nn::VarBuilder;
+use std::collections::HashMap;
+
+#[test]
+fn remap_keys() -> Result<()> {
+    let dev = &Device::Cpu;
+    let gate_up = Tensor::arange(0f32, 16., dev)?.reshape((4, 4))?;
+    let qkv = Tensor::arange(0f32, 12., dev)?.reshape((2, 6))?;
+    let norm = Tensor::ones(4, DType::F32, dev)?;
+    let tensors: HashMap<String, Tensor> = [
+        ("module.transformer.h.0.mlp.gate_up_proj.weight", gate_up),
+        ("module.transformer.h.0.attn.qkv.weight", qkv),
+        ("transformer.ln_f.weight", norm),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v))
+    .collect();
+    let remap = KeyRemap::new()
+        .with_regex(r"^model\.layers\.", "transformer.h.")?
+        .with_regex(r"\.self_attn\.", ".attn.")?
+        .with_regex(r"^model\.norm\.", "transformer.ln_f.")?
+        .with_checkpoint_prefix("module.")
+        .with_split("gate_up_proj", &["gate_proj", "up_proj"], 0)
+        .with_split_sizes("qkv", &[("q", 4), ("k", 1), ("v", 1)], 1);
+    assert_eq!(
+        remap.rewrite("model.layers.3.self_attn.q.weight"),
+        "transformer.h.3.attn.q.weight"
+    );
+    let vb = VarBuilder::from_tensors(tensors, DType::F32, dev).remap(remap);
+
+    // The names are rewritten and the prefix of the checkpoint is optional.
+    assert!(vb.contains_tensor("model.norm.weight"));
+    assert!(!vb.contains_tensor("model.layers.1.mlp.up_proj.weight"));
+    assert!(!vb.contains_tensor("model.layers.0.mlp.down_proj.weight"));
+    let norm = vb.pp("model.norm").get(4, "weight")?;
+    assert_eq!(norm.to_vec1::<f32>()?, [1., 1., 1., 1.]);
+
+    // Parts of equal sizes.
+    let vb_mlp = vb.pp("model.layers.0.mlp");
+    let up = vb_mlp.get((2, 4), "up_proj.weight")?;
+    assert_eq!(
+        up.to_vec2::<f32>()?,
+        [[8., 9., 10., 11.], [12., 13., 14., 15.]]
+    );
+    let gate = vb_mlp.get((2, 4), "gate_proj.weight")?;
+    assert_eq!(gate.to_vec2::<f32>()?, [[0., 1., 2., 3.], [4., 5., 6., 7.]]);
+
+    // Parts of different sizes along the last dimension.
+    let vb_attn = vb.pp("model.layers.0.self_attn");
+    let k = vb_attn.get((2, 1), "k.weight")?;
+    assert_eq!(k.to_vec2::<f32>()?, [[4.], [10.]]);
+    let v = vb_attn.get((2, 1), "v.weight")?;
+    assert_eq!(v.to_vec2::<f32>()?, [[5.], [11.]]);
+    assert!(vb_attn.get((2, 2), "k.weight").is_err());
+    assert!(vb
+        .get((2, 4), "model.layers.0.mlp.down_proj.weight")
+        .is_err());
+    assert!(KeyRemap::new().with_regex("(", "").is_err());
+    Ok(())
+}
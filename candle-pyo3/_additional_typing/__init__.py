@@ -1,4 +1,4 @@
-from typing import Union, Sequence
+from typing import Any, Dict, Optional, Tuple, Union, Sequence
 
 
 class Tensor:
@@ -6,6 +6,27 @@ class Tensor:
     This contains the type hints for the magic methodes of the `candle.Tensor` class.
     """
 
+    @property
+    def __array_interface__(self) -> Dict[str, Any]:
+        """
+        The numpy array interface, used by `numpy.asarray` to access the tensor memory without copying it.
+        """
+        pass
+
+    def __dlpack__(
+        self, *, stream: Optional[Any] = None, max_version: Optional[Any] = None, dl_device: Optional[Any] = None, copy: Optional[bool] = None
+    ) -> Any:
+        """
+        Exports the tensor as a DLPack capsule without copying its data, e.g. for `torch.from_dlpack`.
+        """
+        pass
+
+    def __dlpack_device__(self) -> Tuple[int, int]:
+        """
+        Returns the DLPack device type and device id of the tensor.
+        """
+        pass
+
     def __add__(self, rhs: Union["Tensor", "Scalar"]) -> "Tensor":
         """
         Add a scalar to a tensor or two tensors together.
@@ -22,6 +22,14 @@ class f32(DType):
 class f64(DType):
     pass
 
+@staticmethod
+def from_dlpack(data: Any) -> Tensor:
+    """
+    Creates a new tensor from a DLPack capsule or from an object implementing `__dlpack__`, e.g. a
+    torch tensor. Only cpu data is supported and the data is copied.
+    """
+    pass
+
 class i64(DType):
     pass
 
@@ -78,6 +86,44 @@ class DType:
     A `candle` dtype.
     """
 
+class GradStore:
+    """
+    The gradients computed by a backward pass, indexed by the tensors or variables they relate to.
+    """
+
+    def get(self, tensor: Tensor) -> Optional[Tensor]:
+        """
+        Returns the gradient of `tensor` if there is one.
+        """
+        pass
+
+    def insert(self, tensor: Tensor, grad: Tensor) -> None:
+        """
+        Sets the gradient of `tensor`, e.g. after clipping it.
+        """
+        pass
+
+    def remove(self, tensor: Tensor) -> Optional[Tensor]:
+        """
+        Removes and returns the gradient of `tensor` if there is one.
+        """
+        pass
+
+class QMatMul:
+    """
+    A matrix multiplication with a quantized right hand side, the weights are stored as a
+    `(out_features, in_features)` quantized tensor as in a linear layer.
+    """
+
+    def __init__(self, weight: QTensor):
+        pass
+
+    def forward(self, xs: Tensor) -> Tensor:
+        """
+        Multiplies `xs` by the transposed quantized weights.
+        """
+        pass
+
 class QTensor:
     """
     A quantized tensor.
@@ -130,6 +176,32 @@ class Tensor:
         """
         pass
 
+    @property
+    def __array_interface__(self) -> Dict[str, Any]:
+        """
+        The numpy array interface, used by `numpy.asarray` to access the tensor memory without copying it.
+        """
+        pass
+
+    def __dlpack__(
+        self,
+        *,
+        stream: Optional[Any] = None,
+        max_version: Optional[Any] = None,
+        dl_device: Optional[Any] = None,
+        copy: Optional[bool] = None
+    ) -> Any:
+        """
+        Exports the tensor as a DLPack capsule without copying its data, e.g. for `torch.from_dlpack`.
+        """
+        pass
+
+    def __dlpack_device__(self) -> Tuple[int, int]:
+        """
+        Returns the DLPack device type and device id of the tensor.
+        """
+        pass
+
     def __eq__(self, rhs: Union[Tensor, Scalar]) -> "Tensor":
         """
         Compare a tensor with a scalar or one tensor with another.
@@ -226,6 +298,13 @@ class Tensor:
         """
         pass
 
+    def backward(self) -> GradStore:
+        """
+        Runs the backward pass from this tensor, usually a scalar loss, and returns the gradients
+        of the variables and intermediate tensors it depends on.
+        """
+        pass
+
     def broadcast_add(self, rhs: Tensor) -> Tensor:
         """
         Adds the two tensors, while broadcasting the right-hand-side tensor to match the shape of the left-hand-side tensor.
@@ -404,6 +483,13 @@ class Tensor:
         """
         pass
 
+    def numpy(self) -> numpy.ndarray:
+        """
+        Converts the tensor to a numpy array. For cpu tensors the array shares the tensor memory
+        and is read-only, bf16 tensors are converted to f32.
+        """
+        pass
+
     def powf(self, p: float) -> Tensor:
         """
         Performs the `pow` operation on the tensor with the given exponent.
@@ -540,3 +626,25 @@ class Tensor:
         input tensor is equal to zero.
         """
         pass
+
+class Var(Tensor):
+    """
+    A tensor whose gradient is tracked and which can be updated in place by an optimizer. A `Var`
+    can be used anywhere a `Tensor` is expected.
+    """
+
+    def __init__(self, tensor: Tensor):
+        pass
+
+    def as_tensor(self) -> Tensor:
+        """
+        Returns a tensor sharing the data of the variable, the returned tensor is updated when the
+        variable is.
+        """
+        pass
+
+    def set(self, src: Tensor) -> None:
+        """
+        Sets the content of the variable to `src`, which must have the same shape and dtype.
+        """
+        pass
@@ -0,0 +1,5 @@
+# Generated content DO NOT EDIT
+from .. import generation
+
+LogitsProcessor = generation.LogitsProcessor
+QuantizedLlama = generation.QuantizedLlama
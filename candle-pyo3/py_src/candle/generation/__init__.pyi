@@ -0,0 +1,81 @@
+# Generated content DO NOT EDIT
+from typing import Any, Callable, Dict, List, Optional, Tuple, Union, Sequence
+from os import PathLike
+from candle.typing import _ArrayLike, Device, Scalar, Index, Shape
+from candle import Tensor, DType, QTensor
+
+class LogitsProcessor:
+    """
+    Samples tokens from logits. A temperature of 0 or less results in greedy sampling, `top_k`
+    and `top_p` restrict the sampling to the most likely tokens.
+    """
+
+    def __init__(
+        self, seed: int = 299792458, temperature: float = 0.8, top_p: Optional[float] = None, top_k: Optional[int] = None
+    ):
+        pass
+
+    def sample(self, logits: Tensor) -> int:
+        """
+        Samples a token from a one dimensional tensor of logits.
+        """
+        pass
+
+class QuantizedLlama:
+    """
+    A quantized llama model loaded from a GGUF file, together with an optional tokenizer.
+    """
+
+    def __init__(
+        self,
+        path: Union[str, PathLike],
+        tokenizer: Optional[Union[str, PathLike]] = None,
+        device: Optional[Device] = None,
+    ):
+        pass
+
+    def detokenize(self, tokens: List[int]) -> str:
+        """
+        Converts token ids back to text using the tokenizer.
+        """
+        pass
+
+    @property
+    def eos_token_id(self) -> Optional[int]:
+        """
+        The end of sequence token id as stored in the GGUF metadata.
+        """
+        pass
+
+    def forward(self, tokens: Tensor, index_pos: int) -> Tensor:
+        """
+        Runs the model on a `(batch, seq_len)` tensor of token ids, `index_pos` is the position of
+        the first token, using 0 resets the kv cache. Returns the logits for the last position.
+        """
+        pass
+
+    def generate(
+        self,
+        prompt: Union[str, List[int]],
+        max_tokens: int = 256,
+        temperature: float = 0.8,
+        top_p: Optional[float] = None,
+        top_k: Optional[int] = None,
+        seed: int = 299792458,
+        repeat_penalty: float = 1.1,
+        repeat_last_n: int = 64,
+        stop_token_ids: Optional[List[int]] = None,
+    ) -> Union[str, List[int]]:
+        """
+        Generates a completion of `prompt`. When the prompt is a string it is tokenized and the
+        completion is returned as a string, when it is a list of token ids the generated token ids
+        are returned. The generation stops after `max_tokens` tokens, on the end of sequence token
+        or on any of the `stop_token_ids`.
+        """
+        pass
+
+    def tokenize(self, text: str) -> List[int]:
+        """
+        Converts a text to token ids using the tokenizer.
+        """
+        pass
@@ -0,0 +1,5 @@
+# Generated content DO NOT EDIT
+from .. import optim
+
+AdamW = optim.AdamW
+SGD = optim.SGD
@@ -0,0 +1,67 @@
+# Generated content DO NOT EDIT
+from typing import Any, Callable, Dict, List, Optional, Tuple, Union, Sequence
+from os import PathLike
+from candle.typing import _ArrayLike, Device, Scalar, Index, Shape
+from candle import Tensor, DType, QTensor
+
+class AdamW:
+    """
+    The AdamW optimizer, Adam with decoupled weight decay.
+    """
+
+    def __init__(
+        self,
+        vars: List[Var],
+        lr: float = 0.001,
+        beta1: float = 0.9,
+        beta2: float = 0.999,
+        eps: float = 1e-8,
+        weight_decay: float = 0.01,
+    ):
+        pass
+
+    def backward_step(self, loss: Tensor) -> None:
+        """
+        Runs the backward pass on `loss` and updates the variables.
+        """
+        pass
+
+    @property
+    def learning_rate(self) -> float:
+        """
+        The learning rate.
+        """
+        pass
+
+    def step(self, grads: GradStore) -> None:
+        """
+        Updates the variables using the gradients in `grads`.
+        """
+        pass
+
+class SGD:
+    """
+    Stochastic gradient descent, without momentum.
+    """
+
+    def __init__(self, vars: List[Var], lr: float):
+        pass
+
+    def backward_step(self, loss: Tensor) -> None:
+        """
+        Runs the backward pass on `loss` and updates the variables.
+        """
+        pass
+
+    @property
+    def learning_rate(self) -> float:
+        """
+        The learning rate.
+        """
+        pass
+
+    def step(self, grads: GradStore) -> None:
+        """
+        Updates the variables using the gradients in `grads`.
+        """
+        pass
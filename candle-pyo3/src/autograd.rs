@@ -0,0 +1,96 @@
+//! Trainable variables and gradients.
+use ::candle::backprop::GradStore;
+use ::candle::Var;
+use pyo3::exceptions::PyKeyError;
+use pyo3::prelude::*;
+
+use crate::utils::wrap_err;
+use crate::PyTensor;
+
+#[derive(Clone, Debug)]
+#[pyclass(name = "Var", extends = PyTensor)]
+/// A tensor whose gradient is tracked and which can be updated in place by an optimizer. A `Var`
+/// can be used anywhere a `Tensor` is expected.
+pub(crate) struct PyVar(pub(crate) Var);
+
+#[pymethods]
+impl PyVar {
+    #[new]
+    #[pyo3(text_signature = "(self, tensor:Tensor)")]
+    /// Creates a new variable, the data of `tensor` is copied.
+    fn new(tensor: &PyTensor) -> PyResult<(Self, PyTensor)> {
+        let var = Var::from_tensor(tensor).map_err(wrap_err)?;
+        let tensor = PyTensor(var.as_tensor().clone());
+        Ok((Self(var), tensor))
+    }
+
+    /// Returns a tensor sharing the data of the variable, the returned tensor is updated when the
+    /// variable is.
+    /// &RETURNS&: Tensor
+    fn as_tensor(&self) -> PyTensor {
+        PyTensor(self.0.as_tensor().clone())
+    }
+
+    #[pyo3(text_signature = "(self, src:Tensor)")]
+    /// Sets the content of the variable to `src`, which must have the same shape and dtype.
+    /// &RETURNS&: None
+    fn set(&self, src: &PyTensor) -> PyResult<()> {
+        self.0.set(src).map_err(wrap_err)
+    }
+
+    fn __repr__(&self) -> String {
+        format!("Var({})", self.0.as_tensor())
+    }
+
+    fn __str__(&self) -> String {
+        self.__repr__()
+    }
+}
+
+#[derive(Debug)]
+#[pyclass(name = "GradStore")]
+/// The gradients computed by a backward pass, indexed by the tensors or variables they relate to.
+pub(crate) struct PyGradStore(pub(crate) GradStore);
+
+#[pymethods]
+impl PyGradStore {
+    #[pyo3(text_signature = "(self, tensor:Tensor)")]
+    /// Returns the gradient of `tensor` if there is one.
+    /// &RETURNS&: Optional[Tensor]
+    fn get(&self, tensor: &PyTensor) -> Option<PyTensor> {
+        self.0.get(tensor).map(|t| PyTensor(t.clone()))
+    }
+
+    #[pyo3(text_signature = "(self, tensor:Tensor)")]
+    /// Removes and returns the gradient of `tensor` if there is one.
+    /// &RETURNS&: Optional[Tensor]
+    fn remove(&mut self, tensor: &PyTensor) -> Option<PyTensor> {
+        self.0.remove(tensor).map(PyTensor)
+    }
+
+    #[pyo3(text_signature = "(self, tensor:Tensor, grad:Tensor)")]
+    /// Sets the gradient of `tensor`, e.g. after clipping it.
+    /// &RETURNS&: None
+    fn insert(&mut self, tensor: &PyTensor, grad: &PyTensor) {
+        self.0.insert(tensor, grad.0.clone());
+    }
+
+    fn __getitem__(&self, tensor: &PyTensor) -> PyResult<PyTensor> {
+        match self.0.get(tensor) {
+            Some(grad) => Ok(PyTensor(grad.clone())),
+            None => Err(PyKeyError::new_err("no gradient for this tensor")),
+        }
+    }
+
+    fn __contains__(&self, tensor: &PyTensor) -> bool {
+        self.0.get(tensor).is_some()
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.get_ids().count()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("GradStore(len={})", self.__len__())
+    }
+}
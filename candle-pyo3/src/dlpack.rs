@@ -0,0 +1,434 @@
+//! Interop with NumPy via the array interface and with other frameworks via DLPack.
+//!
+//! Exporting a tensor does not copy its data: the NumPy array or the DLPack capsule point to the
+//! candle storage and keep it alive. As candle storages always own their data, importing an
+//! array copies it into a new tensor with a single pass over the memory.
+use ::candle::{CpuStorage, DType, Device, Layout, Storage, Tensor, WithDType};
+use half::{bf16, f16};
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::ffi;
+use pyo3::prelude::*;
+use pyo3::types::{PyDict, PyTuple};
+use std::ffi::{c_void, CStr};
+
+use crate::utils::wrap_err;
+
+const DLTENSOR_NAME: &CStr = c"dltensor";
+const USED_DLTENSOR_NAME: &CStr = c"used_dltensor";
+
+const DL_CPU: i32 = 1;
+const DL_CUDA: i32 = 2;
+const DL_METAL: i32 = 8;
+const DL_WEBGPU: i32 = 15;
+const DL_EXT_DEV: i32 = 12;
+
+const DL_INT: u8 = 0;
+const DL_UINT: u8 = 1;
+const DL_FLOAT: u8 = 2;
+const DL_BFLOAT: u8 = 4;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct DLDevice {
+    device_type: i32,
+    device_id: i32,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct DLDataType {
+    code: u8,
+    bits: u8,
+    lanes: u16,
+}
+
+#[repr(C)]
+struct DLTensor {
+    data: *mut c_void,
+    device: DLDevice,
+    ndim: i32,
+    dtype: DLDataType,
+    shape: *mut i64,
+    strides: *mut i64,
+    byte_offset: u64,
+}
+
+#[repr(C)]
+struct DLManagedTensor {
+    dl_tensor: DLTensor,
+    manager_ctx: *mut c_void,
+    deleter: Option<unsafe extern "C" fn(*mut DLManagedTensor)>,
+}
+
+// The exported tensor, keeping the storage alive until the consumer calls the deleter.
+struct ExportCtx {
+    managed: DLManagedTensor,
+    _tensor: Tensor,
+    _shape: Vec<i64>,
+    _strides: Vec<i64>,
+}
+
+unsafe extern "C" fn deleter(managed: *mut DLManagedTensor) {
+    drop(Box::from_raw((*managed).manager_ctx as *mut ExportCtx))
+}
+
+unsafe extern "C" fn capsule_destructor(capsule: *mut ffi::PyObject) {
+    // The capsule has been renamed if a consumer took ownership of the tensor.
+    if ffi::PyCapsule_IsValid(capsule, DLTENSOR_NAME.as_ptr()) == 1 {
+        let managed = ffi::PyCapsule_GetPointer(capsule, DLTENSOR_NAME.as_ptr());
+        let managed = managed as *mut DLManagedTensor;
+        if let Some(deleter) = (*managed).deleter {
+            deleter(managed)
+        }
+    }
+}
+
+fn dl_dtype(dtype: DType) -> DLDataType {
+    let (code, bits) = match dtype {
+        DType::U8 => (DL_UINT, 8),
+        DType::U32 => (DL_UINT, 32),
+        DType::I64 => (DL_INT, 64),
+        DType::BF16 => (DL_BFLOAT, 16),
+        DType::F16 => (DL_FLOAT, 16),
+        DType::F32 => (DL_FLOAT, 32),
+        DType::F64 => (DL_FLOAT, 64),
+    };
+    DLDataType {
+        code,
+        bits,
+        lanes: 1,
+    }
+}
+
+fn cpu_ptr(storage: &CpuStorage) -> *const c_void {
+    match storage {
+        CpuStorage::U8(v) => v.as_ptr() as *const c_void,
+        CpuStorage::U32(v) => v.as_ptr() as *const c_void,
+        CpuStorage::I64(v) => v.as_ptr() as *const c_void,
+        CpuStorage::BF16(v) => v.as_ptr() as *const c_void,
+        CpuStorage::F16(v) => v.as_ptr() as *const c_void,
+        CpuStorage::F32(v) => v.as_ptr() as *const c_void,
+        CpuStorage::F64(v) => v.as_ptr() as *const c_void,
+    }
+}
+
+#[cfg(feature = "cuda")]
+fn cuda_ptr(storage: &::candle::CudaStorage) -> *const c_void {
+    use ::candle::cuda_backend::cudarc::driver::DevicePtr;
+    use ::candle::cuda_backend::CudaStorageSlice as S;
+    let ptr = match &storage.slice {
+        S::U8(s) => *s.device_ptr(),
+        S::U32(s) => *s.device_ptr(),
+        S::I64(s) => *s.device_ptr(),
+        S::BF16(s) => *s.device_ptr(),
+        S::F16(s) => *s.device_ptr(),
+        S::F32(s) => *s.device_ptr(),
+        S::F64(s) => *s.device_ptr(),
+    };
+    ptr as *const c_void
+}
+
+/// Returns a pointer to the first element of the tensor together with its device.
+fn data_ptr(tensor: &Tensor) -> PyResult<(*mut c_void, DLDevice, Layout)> {
+    let (storage, layout) = tensor.storage_and_layout();
+    let offset = layout.start_offset() * tensor.dtype().size_in_bytes();
+    let (ptr, device) = match &*storage {
+        Storage::Cpu(storage) => {
+            let device = DLDevice {
+                device_type: DL_CPU,
+                device_id: 0,
+            };
+            (cpu_ptr(storage), device)
+        }
+        #[cfg(feature = "cuda")]
+        Storage::Cuda(storage) => {
+            let device_id = match tensor.device().location() {
+                ::candle::DeviceLocation::Cuda { gpu_id } => gpu_id as i32,
+                _ => 0,
+            };
+            let device = DLDevice {
+                device_type: DL_CUDA,
+                device_id,
+            };
+            (cuda_ptr(storage), device)
+        }
+        _ => Err(PyValueError::new_err(format!(
+            "dlpack export is not supported for {:?} tensors",
+            tensor.device().location()
+        )))?,
+    };
+    let ptr = unsafe { (ptr as *const u8).add(offset) as *mut c_void };
+    Ok((ptr, device, layout.clone()))
+}
+
+/// Returns a DLPack capsule pointing to the tensor data.
+pub(crate) fn to_dlpack(py: Python<'_>, tensor: &Tensor) -> PyResult<PyObject> {
+    // Make sure that the pending kernels writing to this tensor have completed.
+    tensor.device().synchronize().map_err(wrap_err)?;
+    let (data, device, layout) = data_ptr(tensor)?;
+    let mut shape = layout.dims().iter().map(|&d| d as i64).collect::<Vec<_>>();
+    let mut strides = layout
+        .stride()
+        .iter()
+        .map(|&s| s as i64)
+        .collect::<Vec<_>>();
+    let dl_tensor = DLTensor {
+        data,
+        device,
+        ndim: shape.len() as i32,
+        dtype: dl_dtype(tensor.dtype()),
+        shape: shape.as_mut_ptr(),
+        strides: strides.as_mut_ptr(),
+        byte_offset: 0,
+    };
+    let ctx = Box::into_raw(Box::new(ExportCtx {
+        managed: DLManagedTensor {
+            dl_tensor,
+            manager_ctx: std::ptr::null_mut(),
+            deleter: Some(deleter),
+        },
+        _tensor: tensor.clone(),
+        _shape: shape,
+        _strides: strides,
+    }));
+    unsafe {
+        (*ctx).managed.manager_ctx = ctx as *mut c_void;
+        let managed = std::ptr::addr_of_mut!((*ctx).managed);
+        let capsule = ffi::PyCapsule_New(
+            managed as *mut c_void,
+            DLTENSOR_NAME.as_ptr(),
+            Some(capsule_destructor),
+        );
+        if capsule.is_null() {
+            deleter(managed);
+            return Err(PyErr::fetch(py));
+        }
+        Ok(PyObject::from_owned_ptr(py, capsule))
+    }
+}
+
+/// The `(device_type, device_id)` tuple returned by `__dlpack_device__`.
+pub(crate) fn dlpack_device(tensor: &Tensor) -> (i32, i32) {
+    match tensor.device().location() {
+        ::candle::DeviceLocation::Cpu => (DL_CPU, 0),
+        ::candle::DeviceLocation::Cuda { gpu_id } => (DL_CUDA, gpu_id as i32),
+        ::candle::DeviceLocation::Metal { gpu_id } => (DL_METAL, gpu_id as i32),
+        ::candle::DeviceLocation::Wgpu { gpu_id } => (DL_WEBGPU, gpu_id as i32),
+        // Meta tensors have no data so they cannot be exported anyway.
+        ::candle::DeviceLocation::Meta => (DL_EXT_DEV, 0),
+    }
+}
+
+// Copies a strided array of `S` elements to a contiguous vector of `T` elements, the strides
+// are in number of elements and can be negative.
+unsafe fn strided_copy<S: Copy, T>(
+    ptr: *const S,
+    shape: &[usize],
+    strides: &[isize],
+    f: impl Fn(S) -> T,
+) -> Vec<T> {
+    let numel = shape.iter().product::<usize>();
+    let mut out = Vec::with_capacity(numel);
+    if numel == 0 {
+        return out;
+    }
+    let mut contiguous = true;
+    let mut expected = 1isize;
+    for (&d, &s) in shape.iter().zip(strides.iter()).rev() {
+        if d > 1 && s != expected {
+            contiguous = false;
+        }
+        expected *= d as isize;
+    }
+    if contiguous {
+        let data = std::slice::from_raw_parts(ptr, numel);
+        out.extend(data.iter().map(|&v| f(v)));
+        return out;
+    }
+    let mut index = vec![0usize; shape.len()];
+    let mut offset = 0isize;
+    for _ in 0..numel {
+        out.push(f(*ptr.offset(offset)));
+        for dim in (0..shape.len()).rev() {
+            index[dim] += 1;
+            offset += strides[dim];
+            if index[dim] < shape[dim] {
+                break;
+            }
+            offset -= strides[dim] * shape[dim] as isize;
+            index[dim] = 0;
+        }
+    }
+    out
+}
+
+unsafe fn read<S: Copy, T: WithDType>(
+    ptr: *const c_void,
+    shape: &[usize],
+    strides: &[isize],
+    f: impl Fn(S) -> T,
+) -> PyResult<Tensor> {
+    let data = strided_copy(ptr as *const S, shape, strides, f);
+    Tensor::from_vec(data, shape, &Device::Cpu).map_err(wrap_err)
+}
+
+fn contiguous_strides(shape: &[usize]) -> Vec<isize> {
+    let mut strides = vec![1isize; shape.len()];
+    for i in (0..shape.len().saturating_sub(1)).rev() {
+        strides[i] = strides[i + 1] * shape[i + 1] as isize
+    }
+    strides
+}
+
+/// Creates a tensor from a DLPack capsule or from an object implementing `__dlpack__`, only CPU
+/// arrays are supported.
+pub(crate) fn from_dlpack(obj: &Bound<'_, PyAny>) -> PyResult<Tensor> {
+    let capsule = if obj.hasattr("__dlpack__")? {
+        obj.call_method0("__dlpack__")?
+    } else {
+        obj.clone()
+    };
+    let capsule_ptr = capsule.as_ptr();
+    unsafe {
+        if ffi::PyCapsule_IsValid(capsule_ptr, DLTENSOR_NAME.as_ptr()) != 1 {
+            Err(PyTypeError::new_err(
+                "expected a dlpack capsule or an object implementing __dlpack__",
+            ))?
+        }
+        let managed = ffi::PyCapsule_GetPointer(capsule_ptr, DLTENSOR_NAME.as_ptr());
+        let managed = managed as *mut DLManagedTensor;
+        let t = &(*managed).dl_tensor;
+        if t.device.device_type != DL_CPU {
+            Err(PyValueError::new_err(format!(
+                "only cpu tensors can be imported, got device type {}",
+                t.device.device_type
+            )))?
+        }
+        let shape = std::slice::from_raw_parts(t.shape, t.ndim as usize);
+        let shape = shape.iter().map(|&d| d as usize).collect::<Vec<_>>();
+        let strides = if t.strides.is_null() {
+            contiguous_strides(&shape)
+        } else {
+            let strides = std::slice::from_raw_parts(t.strides, t.ndim as usize);
+            strides.iter().map(|&s| s as isize).collect()
+        };
+        let ptr = (t.data as *const u8).add(t.byte_offset as usize) as *const c_void;
+        let dtype = (t.dtype.code, t.dtype.bits, t.dtype.lanes);
+        let tensor = match dtype {
+            (DL_UINT, 8, 1) => read(ptr, &shape, &strides, |v: u8| v),
+            (DL_UINT, 32, 1) => read(ptr, &shape, &strides, |v: u32| v),
+            (DL_INT, 64, 1) => read(ptr, &shape, &strides, |v: i64| v),
+            (DL_INT, 32, 1) => read(ptr, &shape, &strides, |v: i32| v as i64),
+            (DL_BFLOAT, 16, 1) => read(ptr, &shape, &strides, |v: bf16| v),
+            (DL_FLOAT, 16, 1) => read(ptr, &shape, &strides, |v: f16| v),
+            (DL_FLOAT, 32, 1) => read(ptr, &shape, &strides, |v: f32| v),
+            (DL_FLOAT, 64, 1) => read(ptr, &shape, &strides, |v: f64| v),
+            // Booleans are stored as bytes.
+            (6, 8, 1) => read(ptr, &shape, &strides, |v: u8| v),
+            dtype => Err(PyTypeError::new_err(format!(
+                "unsupported dlpack dtype (code, bits, lanes) {dtype:?}"
+            ))),
+        };
+        // The data has been copied, mark the capsule as consumed and release the producer data.
+        ffi::PyCapsule_SetName(capsule_ptr, USED_DLTENSOR_NAME.as_ptr());
+        if let Some(deleter) = (*managed).deleter {
+            deleter(managed)
+        }
+        tensor
+    }
+}
+
+/// The `__array_interface__` dictionary of a cpu tensor, this lets NumPy create an array that
+/// shares the tensor memory. The array is read-only as candle tensors are immutable.
+pub(crate) fn array_interface<'py>(
+    py: Python<'py>,
+    tensor: &Tensor,
+) -> PyResult<Bound<'py, PyDict>> {
+    let typestr = match tensor.dtype() {
+        DType::U8 => "|u1",
+        DType::U32 => "<u4",
+        DType::I64 => "<i8",
+        DType::F16 => "<f2",
+        DType::F32 => "<f4",
+        DType::F64 => "<f8",
+        DType::BF16 => Err(PyTypeError::new_err(
+            "numpy does not support bf16, convert the tensor to f32 first",
+        ))?,
+    };
+    if !tensor.device().is_cpu() {
+        Err(PyValueError::new_err(
+            "the array interface is only available for cpu tensors",
+        ))?
+    }
+    let (data, _device, layout) = data_ptr(tensor)?;
+    let elem_size = tensor.dtype().size_in_bytes();
+    let strides = layout.stride().iter().map(|&s| s * elem_size);
+    let dict = PyDict::new_bound(py);
+    dict.set_item("shape", PyTuple::new_bound(py, layout.dims()))?;
+    dict.set_item("typestr", typestr)?;
+    dict.set_item("data", (data as usize, true))?;
+    dict.set_item("strides", PyTuple::new_bound(py, strides))?;
+    dict.set_item("version", 3)?;
+    Ok(dict)
+}
+
+/// Creates a tensor from an object exposing `__array_interface__`, e.g. a NumPy array. Returns
+/// `None` if the object does not expose its data through a pointer.
+pub(crate) fn from_array_interface(obj: &Bound<'_, PyAny>) -> PyResult<Option<Tensor>> {
+    let interface = obj.getattr("__array_interface__")?;
+    let interface = interface.downcast::<PyDict>()?;
+    let data = match interface.get_item("data")? {
+        Some(data) if !data.is_none() => data,
+        _ => return Ok(None),
+    };
+    let (ptr, _readonly): (usize, bool) = match data.extract() {
+        Ok(data) => data,
+        Err(_) => return Ok(None),
+    };
+    let typestr: String = match interface.get_item("typestr")? {
+        Some(typestr) => typestr.extract()?,
+        None => Err(PyValueError::new_err("no typestr in __array_interface__"))?,
+    };
+    let shape: Vec<usize> = match interface.get_item("shape")? {
+        Some(shape) => shape.extract()?,
+        None => Err(PyValueError::new_err("no shape in __array_interface__"))?,
+    };
+    let elem_size = match typestr.get(2..).map(|s| s.parse::<usize>()) {
+        Some(Ok(elem_size)) if elem_size > 0 => elem_size,
+        _ => Err(PyTypeError::new_err(format!(
+            "unsupported typestr {typestr}"
+        )))?,
+    };
+    if typestr.starts_with('>') && elem_size > 1 {
+        Err(PyTypeError::new_err("big-endian arrays are not supported"))?
+    }
+    let strides = match interface.get_item("strides")? {
+        Some(strides) if !strides.is_none() => {
+            let strides: Vec<isize> = strides.extract()?;
+            if strides.iter().any(|s| s % elem_size as isize != 0) {
+                Err(PyValueError::new_err("unaligned strides are not supported"))?
+            }
+            strides.iter().map(|s| s / elem_size as isize).collect()
+        }
+        _ => contiguous_strides(&shape),
+    };
+    let ptr = ptr as *const c_void;
+    let tensor = unsafe {
+        match &typestr[1..] {
+            "u1" | "b1" => read(ptr, &shape, &strides, |v: u8| v),
+            "u2" => read(ptr, &shape, &strides, |v: u16| v as u32),
+            "u4" => read(ptr, &shape, &strides, |v: u32| v),
+            "i1" => read(ptr, &shape, &strides, |v: i8| v as i64),
+            "i2" => read(ptr, &shape, &strides, |v: i16| v as i64),
+            "i4" => read(ptr, &shape, &strides, |v: i32| v as i64),
+            "i8" => read(ptr, &shape, &strides, |v: i64| v),
+            "f2" => read(ptr, &shape, &strides, |v: f16| v),
+            "f4" => read(ptr, &shape, &strides, |v: f32| v),
+            "f8" => read(ptr, &shape, &strides, |v: f64| v),
+            _ => Err(PyTypeError::new_err(format!(
+                "unsupported array dtype {typestr}"
+            ))),
+        }
+    };
+    tensor.map(Some)
+}
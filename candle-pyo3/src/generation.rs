@@ -0,0 +1,295 @@
+//! Quantized model loading and text generation.
+use ::candle::quantized::{gguf_file, QMatMul};
+use ::candle::{DType, Device, Module, Tensor};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use candle_transformers::models::quantized_llama::ModelWeights;
+use pyo3::exceptions::{PyTypeError, PyValueError};
+use pyo3::prelude::*;
+use tokenizers::Tokenizer;
+
+use crate::utils::wrap_err;
+use crate::{PyDevice, PyQTensor, PyTensor};
+
+const DEFAULT_SEED: u64 = 299792458;
+
+fn sampling(temperature: f64, top_k: Option<usize>, top_p: Option<f64>) -> Sampling {
+    if temperature <= 1e-7 {
+        return Sampling::ArgMax;
+    }
+    match (top_k, top_p) {
+        (None, None) => Sampling::All { temperature },
+        (Some(k), None) => Sampling::TopK { k, temperature },
+        (None, Some(p)) => Sampling::TopP { p, temperature },
+        (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+    }
+}
+
+#[derive(Debug, Clone)]
+#[pyclass(name = "QMatMul")]
+/// A matrix multiplication with a quantized right hand side, the weights are stored as a
+/// `(out_features, in_features)` quantized tensor as in a linear layer.
+pub struct PyQMatMul(QMatMul);
+
+#[pymethods]
+impl PyQMatMul {
+    #[new]
+    #[pyo3(text_signature = "(self, weight:QTensor)")]
+    fn new(weight: &PyQTensor) -> PyResult<Self> {
+        let qmatmul = QMatMul::from_arc(weight.0.clone()).map_err(wrap_err)?;
+        Ok(Self(qmatmul))
+    }
+
+    #[pyo3(text_signature = "(self, xs:Tensor)")]
+    /// Multiplies `xs` by the transposed quantized weights.
+    /// &RETURNS&: Tensor
+    fn forward(&self, xs: &PyTensor) -> PyResult<PyTensor> {
+        Ok(PyTensor(self.0.forward(xs).map_err(wrap_err)?))
+    }
+
+    fn __call__(&self, xs: &PyTensor) -> PyResult<PyTensor> {
+        self.forward(xs)
+    }
+}
+
+#[derive(Debug, Clone)]
+#[pyclass(name = "LogitsProcessor")]
+/// Samples tokens from logits. A temperature of 0 or less results in greedy sampling, `top_k`
+/// and `top_p` restrict the sampling to the most likely tokens.
+pub struct PyLogitsProcessor(LogitsProcessor);
+
+#[pymethods]
+impl PyLogitsProcessor {
+    #[new]
+    #[pyo3(signature = (seed=DEFAULT_SEED, temperature=0.8, top_p=None, top_k=None))]
+    #[pyo3(
+        text_signature = "(self, seed:int=299792458, temperature:float=0.8, top_p:Optional[float]=None, top_k:Optional[int]=None)"
+    )]
+    fn new(seed: u64, temperature: f64, top_p: Option<f64>, top_k: Option<usize>) -> Self {
+        Self(LogitsProcessor::from_sampling(
+            seed,
+            sampling(temperature, top_k, top_p),
+        ))
+    }
+
+    #[pyo3(text_signature = "(self, logits:Tensor)")]
+    /// Samples a token from a one dimensional tensor of logits.
+    /// &RETURNS&: int
+    fn sample(&mut self, logits: &PyTensor, py: Python<'_>) -> PyResult<u32> {
+        let logits = logits.0.clone();
+        py.allow_threads(|| self.0.sample(&logits).map_err(wrap_err))
+    }
+}
+
+#[pyclass(name = "QuantizedLlama")]
+/// A quantized llama model loaded from a GGUF file, together with an optional tokenizer.
+pub struct PyQuantizedLlama {
+    model: ModelWeights,
+    tokenizer: Option<Tokenizer>,
+    eos_token_id: Option<u32>,
+    device: Device,
+}
+
+impl PyQuantizedLlama {
+    fn tokenizer(&self) -> PyResult<&Tokenizer> {
+        match self.tokenizer.as_ref() {
+            Some(tokenizer) => Ok(tokenizer),
+            None => Err(PyValueError::new_err(
+                "no tokenizer has been provided, use token ids instead",
+            )),
+        }
+    }
+
+    fn encode(&self, text: &str) -> PyResult<Vec<u32>> {
+        let encoding = self
+            .tokenizer()?
+            .encode(text, true)
+            .map_err(|e| PyValueError::new_err(format!("tokenizer error: {e}")))?;
+        Ok(encoding.get_ids().to_vec())
+    }
+
+    fn decode(&self, tokens: &[u32]) -> PyResult<String> {
+        self.tokenizer()?
+            .decode(tokens, true)
+            .map_err(|e| PyValueError::new_err(format!("tokenizer error: {e}")))
+    }
+
+    fn forward_logits(&mut self, tokens: &[u32], index_pos: usize) -> ::candle::Result<Tensor> {
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+        self.model
+            .forward(&input, index_pos)?
+            .squeeze(0)?
+            .to_dtype(DType::F32)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn generate_tokens(
+        &mut self,
+        prompt: &[u32],
+        max_tokens: usize,
+        mut logits_processor: LogitsProcessor,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        stop_token_ids: &[u32],
+    ) -> ::candle::Result<Vec<u32>> {
+        if prompt.is_empty() {
+            ::candle::bail!("the prompt should contain at least one token")
+        }
+        let mut all_tokens = prompt.to_vec();
+        let mut generated = Vec::with_capacity(max_tokens);
+        // Starting at position 0 resets the kv cache.
+        let mut logits = self.forward_logits(prompt, 0)?;
+        for _ in 0..max_tokens {
+            if repeat_penalty != 1. {
+                let start_at = all_tokens.len().saturating_sub(repeat_last_n);
+                logits = candle_transformers::utils::apply_repeat_penalty(
+                    &logits,
+                    repeat_penalty,
+                    &all_tokens[start_at..],
+                )?;
+            }
+            let next_token = logits_processor.sample(&logits)?;
+            all_tokens.push(next_token);
+            if self.eos_token_id == Some(next_token) || stop_token_ids.contains(&next_token) {
+                break;
+            }
+            generated.push(next_token);
+            if generated.len() == max_tokens {
+                break;
+            }
+            logits = self.forward_logits(&[next_token], all_tokens.len() - 1)?;
+        }
+        Ok(generated)
+    }
+}
+
+#[pymethods]
+impl PyQuantizedLlama {
+    #[new]
+    #[pyo3(signature = (path, tokenizer=None, device=None))]
+    #[pyo3(
+        text_signature = "(self, path:Union[str,PathLike], tokenizer:Optional[Union[str,PathLike]]=None, device:Optional[Device]=None)"
+    )]
+    fn new(path: &str, tokenizer: Option<&str>, device: Option<PyDevice>) -> PyResult<Self> {
+        let device = device.unwrap_or(PyDevice::Cpu).as_device()?;
+        let mut file = std::fs::File::open(path)?;
+        let content = gguf_file::Content::read(&mut file).map_err(wrap_err)?;
+        let eos_token_id = content
+            .metadata
+            .get("tokenizer.ggml.eos_token_id")
+            .and_then(|v| v.to_u32().ok());
+        let model = ModelWeights::from_gguf(content, &mut file, &device).map_err(wrap_err)?;
+        let tokenizer = match tokenizer {
+            None => None,
+            Some(tokenizer) => Some(
+                Tokenizer::from_file(tokenizer)
+                    .map_err(|e| PyValueError::new_err(format!("cannot load tokenizer: {e}")))?,
+            ),
+        };
+        Ok(Self {
+            model,
+            tokenizer,
+            eos_token_id,
+            device,
+        })
+    }
+
+    #[getter]
+    /// The end of sequence token id as stored in the GGUF metadata.
+    /// &RETURNS&: Optional[int]
+    fn eos_token_id(&self) -> Option<u32> {
+        self.eos_token_id
+    }
+
+    #[pyo3(text_signature = "(self, tokens:Tensor, index_pos:int)")]
+    /// Runs the model on a `(batch, seq_len)` tensor of token ids, `index_pos` is the position of
+    /// the first token, using 0 resets the kv cache. Returns the logits for the last position.
+    /// &RETURNS&: Tensor
+    fn forward(
+        &mut self,
+        tokens: &PyTensor,
+        index_pos: usize,
+        py: Python<'_>,
+    ) -> PyResult<PyTensor> {
+        let tokens = tokens.0.clone();
+        let logits =
+            py.allow_threads(|| self.model.forward(&tokens, index_pos).map_err(wrap_err))?;
+        Ok(PyTensor(logits))
+    }
+
+    #[pyo3(text_signature = "(self, text:str)")]
+    /// Converts a text to token ids using the tokenizer.
+    /// &RETURNS&: List[int]
+    fn tokenize(&self, text: &str) -> PyResult<Vec<u32>> {
+        self.encode(text)
+    }
+
+    #[pyo3(text_signature = "(self, tokens:List[int])")]
+    /// Converts token ids back to text using the tokenizer.
+    /// &RETURNS&: str
+    fn detokenize(&self, tokens: Vec<u32>) -> PyResult<String> {
+        self.decode(&tokens)
+    }
+
+    #[pyo3(signature = (
+        prompt,
+        max_tokens=256,
+        temperature=0.8,
+        top_p=None,
+        top_k=None,
+        seed=DEFAULT_SEED,
+        repeat_penalty=1.1,
+        repeat_last_n=64,
+        stop_token_ids=None,
+    ))]
+    #[pyo3(
+        text_signature = "(self, prompt:Union[str,List[int]], max_tokens:int=256, temperature:float=0.8, top_p:Optional[float]=None, top_k:Optional[int]=None, seed:int=299792458, repeat_penalty:float=1.1, repeat_last_n:int=64, stop_token_ids:Optional[List[int]]=None)"
+    )]
+    /// Generates a completion of `prompt`. When the prompt is a string it is tokenized and the
+    /// completion is returned as a string, when it is a list of token ids the generated token ids
+    /// are returned. The generation stops after `max_tokens` tokens, on the end of sequence token
+    /// or on any of the `stop_token_ids`.
+    /// &RETURNS&: Union[str,List[int]]
+    #[allow(clippy::too_many_arguments)]
+    fn generate(
+        &mut self,
+        prompt: &Bound<'_, PyAny>,
+        max_tokens: usize,
+        temperature: f64,
+        top_p: Option<f64>,
+        top_k: Option<usize>,
+        seed: u64,
+        repeat_penalty: f32,
+        repeat_last_n: usize,
+        stop_token_ids: Option<Vec<u32>>,
+        py: Python<'_>,
+    ) -> PyResult<PyObject> {
+        let (prompt_tokens, is_text) = if let Ok(text) = prompt.extract::<String>() {
+            (self.encode(&text)?, true)
+        } else if let Ok(tokens) = prompt.extract::<Vec<u32>>() {
+            (tokens, false)
+        } else {
+            return Err(PyTypeError::new_err(
+                "prompt should be a string or a list of token ids",
+            ));
+        };
+        let logits_processor =
+            LogitsProcessor::from_sampling(seed, sampling(temperature, top_k, top_p));
+        let stop_token_ids = stop_token_ids.unwrap_or_default();
+        let tokens = py.allow_threads(|| {
+            self.generate_tokens(
+                &prompt_tokens,
+                max_tokens,
+                logits_processor,
+                repeat_penalty,
+                repeat_last_n,
+                &stop_token_ids,
+            )
+            .map_err(wrap_err)
+        })?;
+        if is_text {
+            Ok(self.decode(&tokens)?.into_py(py))
+        } else {
+            Ok(tokens.into_py(py))
+        }
+    }
+}
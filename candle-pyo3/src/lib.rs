@@ -24,11 +24,20 @@ use utils::wrap_err;
 mod shape;
 use shape::{PyShape, PyShapeWithHole};
 
+mod autograd;
+use autograd::{PyGradStore, PyVar};
+
+mod dlpack;
+
+mod generation;
+
+mod optim;
+
 #[cfg(feature = "onnx")]
 mod onnx;
 
 #[derive(Clone, Debug)]
-#[pyclass(name = "Tensor")]
+#[pyclass(name = "Tensor", subclass)]
 /// A `candle` tensor.
 struct PyTensor(Tensor);
 
@@ -71,12 +80,15 @@ impl PyDType {
 
 static CUDA_DEVICE: std::sync::Mutex<Option<Device>> = std::sync::Mutex::new(None);
 static METAL_DEVICE: std::sync::Mutex<Option<Device>> = std::sync::Mutex::new(None);
+static WGPU_DEVICE: std::sync::Mutex<Option<Device>> = std::sync::Mutex::new(None);
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 enum PyDevice {
     Cpu,
     Cuda,
     Metal,
+    Wgpu,
+    Meta,
 }
 
 impl PyDevice {
@@ -85,6 +97,8 @@ impl PyDevice {
             Device::Cpu => Self::Cpu,
             Device::Cuda(_) => Self::Cuda,
             Device::Metal(_) => Self::Metal,
+            Device::Wgpu(_) => Self::Wgpu,
+            Device::Meta => Self::Meta,
         }
     }
 
@@ -109,6 +123,16 @@ impl PyDevice {
                 *device = Some(d.clone());
                 Ok(d)
             }
+            Self::Wgpu => {
+                let mut device = WGPU_DEVICE.lock().unwrap();
+                if let Some(device) = device.as_ref() {
+                    return Ok(device.clone());
+                };
+                let d = Device::new_wgpu(0).map_err(wrap_err)?;
+                *device = Some(d.clone());
+                Ok(d)
+            }
+            Self::Meta => Ok(Device::Meta),
         }
     }
 }
@@ -119,6 +143,8 @@ impl<'source> FromPyObject<'source> for PyDevice {
         let device = match device.as_str() {
             "cpu" => PyDevice::Cpu,
             "cuda" => PyDevice::Cuda,
+            "wgpu" => PyDevice::Wgpu,
+            "meta" => PyDevice::Meta,
             _ => Err(PyTypeError::new_err(format!("invalid device '{device}'")))?,
         };
         Ok(device)
@@ -131,6 +157,8 @@ impl ToPyObject for PyDevice {
             PyDevice::Cpu => "cpu",
             PyDevice::Cuda => "cuda",
             PyDevice::Metal => "metal",
+            PyDevice::Wgpu => "wgpu",
+            PyDevice::Meta => "meta",
         };
         str.to_object(py)
     }
@@ -234,6 +262,12 @@ impl PyTensor {
     /// Creates a new tensor from a Python value. The value can be a scalar or array-like object.
     fn new(py: Python<'_>, data: PyObject) -> PyResult<Self> {
         use Device::Cpu;
+        // Arrays exposing their memory, e.g. numpy arrays, are copied directly.
+        if data.bind(py).hasattr("__array_interface__")? {
+            if let Some(tensor) = dlpack::from_array_interface(data.bind(py))? {
+                return Ok(Self(tensor));
+            }
+        }
         let tensor = if let Ok(vs) = data.extract::<u32>(py) {
             Tensor::new(vs, &Cpu).map_err(wrap_err)?
         } else if let Ok(vs) = data.extract::<i64>(py) {
@@ -328,6 +362,57 @@ impl PyTensor {
         Ok(torch_tensor)
     }
 
+    /// Converts the tensor to a numpy array. For cpu tensors the array shares the tensor memory
+    /// and is read-only, bf16 tensors are converted to f32.
+    /// &RETURNS&: numpy.ndarray
+    fn numpy(&self, py: Python<'_>) -> PyResult<PyObject> {
+        let mut tensor = self.0.to_device(&Device::Cpu).map_err(wrap_err)?;
+        if tensor.dtype() == DType::BF16 {
+            tensor = tensor.to_dtype(DType::F32).map_err(wrap_err)?;
+        }
+        let array: PyObject = py
+            .import_bound("numpy")?
+            .getattr("asarray")?
+            .call1((PyTensor(tensor),))?
+            .extract()?;
+        Ok(array)
+    }
+
+    #[getter]
+    /// The numpy array interface, used by `numpy.asarray` to access the tensor memory without
+    /// copying it.
+    /// &RETURNS&: Dict[str, Any]
+    fn __array_interface__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyDict>> {
+        dlpack::array_interface(py, self)
+    }
+
+    #[pyo3(signature = (*, stream=None, max_version=None, dl_device=None, copy=None))]
+    /// Exports the tensor as a DLPack capsule without copying its data, e.g. for
+    /// `torch.from_dlpack`. Cpu and cuda tensors are supported.
+    /// &RETURNS&: PyCapsule
+    fn __dlpack__(
+        &self,
+        py: Python<'_>,
+        stream: Option<PyObject>,
+        max_version: Option<PyObject>,
+        dl_device: Option<PyObject>,
+        copy: Option<bool>,
+    ) -> PyResult<PyObject> {
+        let _ = (stream, max_version, dl_device);
+        if copy == Some(true) {
+            let tensor = self.0.copy().map_err(wrap_err)?;
+            dlpack::to_dlpack(py, &tensor)
+        } else {
+            dlpack::to_dlpack(py, self)
+        }
+    }
+
+    /// Returns the DLPack device type and device id of the tensor.
+    /// &RETURNS&: Tuple[int, int]
+    fn __dlpack_device__(&self) -> (i32, i32) {
+        dlpack::dlpack_device(self)
+    }
+
     #[getter]
     /// Gets the tensor's shape.
     /// &RETURNS&: Tuple[int]
@@ -947,6 +1032,13 @@ impl PyTensor {
         PyTensor(self.0.detach())
     }
 
+    /// Runs the backward pass from this tensor, usually a scalar loss, and returns the gradients
+    /// of the variables and intermediate tensors it depends on.
+    /// &RETURNS&: GradStore
+    fn backward(&self) -> PyResult<PyGradStore> {
+        Ok(PyGradStore(self.0.backward().map_err(wrap_err)?))
+    }
+
     /// Returns a copy of the tensor.
     /// &RETURNS&: Tensor
     fn copy(&self) -> PyResult<Self> {
@@ -1091,6 +1183,9 @@ impl PyTensor {
             "q8_0" => quantized::QTensor::quantize(self, quantized::GgmlDType::Q8_0),
             "q8_1" => quantized::QTensor::quantize(self, quantized::GgmlDType::Q8_1),
             "q8k" => quantized::QTensor::quantize(self, quantized::GgmlDType::Q8K),
+            "iq4_nl" => quantized::QTensor::quantize(self, quantized::GgmlDType::IQ4NL),
+            "iq2_xs" => quantized::QTensor::quantize(self, quantized::GgmlDType::IQ2XS),
+            "iq3_s" => quantized::QTensor::quantize(self, quantized::GgmlDType::IQ3S),
             "f16" => quantized::QTensor::quantize(self, quantized::GgmlDType::F16),
             "f32" => quantized::QTensor::quantize(self, quantized::GgmlDType::F32),
             dt => {
@@ -1135,6 +1230,15 @@ fn tensor(py: Python<'_>, data: PyObject) -> PyResult<PyTensor> {
     PyTensor::new(py, data)
 }
 
+#[pyfunction]
+#[pyo3(text_signature = "(data:Any)")]
+/// Creates a new tensor from a DLPack capsule or from an object implementing `__dlpack__`, e.g. a
+/// torch tensor. Only cpu data is supported and the data is copied.
+/// &RETURNS&: Tensor
+fn from_dlpack(data: &Bound<'_, PyAny>) -> PyResult<PyTensor> {
+    Ok(PyTensor(dlpack::from_dlpack(data)?))
+}
+
 #[pyfunction]
 #[pyo3(signature = (*shape,device=None), text_signature = "(*shape:Shape, device:Optional[Device]=None)")]
 /// Creates a new tensor with random values.
@@ -1578,6 +1682,20 @@ fn tanh(tensor: PyTensor) -> PyResult<PyTensor> {
     Ok(PyTensor(s))
 }
 
+fn candle_generation_m(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
+    use generation::{PyLogitsProcessor, PyQuantizedLlama};
+    m.add_class::<PyLogitsProcessor>()?;
+    m.add_class::<PyQuantizedLlama>()?;
+    Ok(())
+}
+
+fn candle_optim_m(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
+    use optim::{PyAdamW, PySGD};
+    m.add_class::<PyAdamW>()?;
+    m.add_class::<PySGD>()?;
+    Ok(())
+}
+
 fn candle_functional_m(_py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(silu, m)?)?;
     m.add_function(wrap_pyfunction!(softmax, m)?)?;
@@ -1605,6 +1723,12 @@ fn candle(py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     let nn = PyModule::new_bound(py, "functional")?;
     candle_functional_m(py, &nn)?;
     m.add_submodule(&nn)?;
+    let generation = PyModule::new_bound(py, "generation")?;
+    candle_generation_m(py, &generation)?;
+    m.add_submodule(&generation)?;
+    let optim = PyModule::new_bound(py, "optim")?;
+    candle_optim_m(py, &optim)?;
+    m.add_submodule(&optim)?;
     #[cfg(feature = "onnx")]
     {
         let onnx = PyModule::new_bound(py, "onnx")?;
@@ -1613,7 +1737,10 @@ fn candle(py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     }
     m.add_class::<PyTensor>()?;
     m.add_class::<PyQTensor>()?;
+    m.add_class::<generation::PyQMatMul>()?;
     m.add_class::<PyDType>()?;
+    m.add_class::<PyVar>()?;
+    m.add_class::<PyGradStore>()?;
     m.add("u8", PyDType(DType::U8))?;
     m.add("u32", PyDType(DType::U32))?;
     m.add("i64", PyDType(DType::I64))?;
@@ -1622,6 +1749,7 @@ fn candle(py: Python<'_>, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add("f32", PyDType(DType::F32))?;
     m.add("f64", PyDType(DType::F64))?;
     m.add_function(wrap_pyfunction!(cat, m)?)?;
+    m.add_function(wrap_pyfunction!(from_dlpack, m)?)?;
     m.add_function(wrap_pyfunction!(ones, m)?)?;
     m.add_function(wrap_pyfunction!(rand, m)?)?;
     m.add_function(wrap_pyfunction!(randn, m)?)?;
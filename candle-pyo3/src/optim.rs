@@ -0,0 +1,106 @@
+//! Optimizers updating variables from their gradients.
+use candle_nn::{AdamW, Optimizer, ParamsAdamW, SGD};
+use pyo3::prelude::*;
+
+use crate::autograd::{PyGradStore, PyVar};
+use crate::utils::wrap_err;
+use crate::PyTensor;
+
+fn to_vars(vars: Vec<PyRef<PyVar>>) -> Vec<::candle::Var> {
+    vars.iter().map(|v| v.0.clone()).collect()
+}
+
+#[pyclass(name = "SGD")]
+/// Stochastic gradient descent, without momentum.
+pub struct PySGD(SGD);
+
+#[pymethods]
+impl PySGD {
+    #[new]
+    #[pyo3(text_signature = "(self, vars:List[Var], lr:float)")]
+    fn new(vars: Vec<PyRef<PyVar>>, lr: f64) -> PyResult<Self> {
+        Ok(Self(SGD::new(to_vars(vars), lr).map_err(wrap_err)?))
+    }
+
+    #[pyo3(text_signature = "(self, grads:GradStore)")]
+    /// Updates the variables using the gradients in `grads`.
+    /// &RETURNS&: None
+    fn step(&mut self, grads: &PyGradStore) -> PyResult<()> {
+        self.0.step(&grads.0).map_err(wrap_err)
+    }
+
+    #[pyo3(text_signature = "(self, loss:Tensor)")]
+    /// Runs the backward pass on `loss` and updates the variables.
+    /// &RETURNS&: None
+    fn backward_step(&mut self, loss: &PyTensor) -> PyResult<()> {
+        self.0.backward_step(loss).map_err(wrap_err)
+    }
+
+    #[getter]
+    /// The learning rate.
+    /// &RETURNS&: float
+    fn learning_rate(&self) -> f64 {
+        self.0.learning_rate()
+    }
+
+    #[setter]
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.0.set_learning_rate(lr)
+    }
+}
+
+#[pyclass(name = "AdamW")]
+/// The AdamW optimizer, Adam with decoupled weight decay.
+pub struct PyAdamW(AdamW);
+
+#[pymethods]
+impl PyAdamW {
+    #[new]
+    #[pyo3(signature = (vars, lr=0.001, beta1=0.9, beta2=0.999, eps=1e-8, weight_decay=0.01))]
+    #[pyo3(
+        text_signature = "(self, vars:List[Var], lr:float=0.001, beta1:float=0.9, beta2:float=0.999, eps:float=1e-8, weight_decay:float=0.01)"
+    )]
+    fn new(
+        vars: Vec<PyRef<PyVar>>,
+        lr: f64,
+        beta1: f64,
+        beta2: f64,
+        eps: f64,
+        weight_decay: f64,
+    ) -> PyResult<Self> {
+        let params = ParamsAdamW {
+            lr,
+            beta1,
+            beta2,
+            eps,
+            weight_decay,
+        };
+        Ok(Self(AdamW::new(to_vars(vars), params).map_err(wrap_err)?))
+    }
+
+    #[pyo3(text_signature = "(self, grads:GradStore)")]
+    /// Updates the variables using the gradients in `grads`.
+    /// &RETURNS&: None
+    fn step(&mut self, grads: &PyGradStore) -> PyResult<()> {
+        self.0.step(&grads.0).map_err(wrap_err)
+    }
+
+    #[pyo3(text_signature = "(self, loss:Tensor)")]
+    /// Runs the backward pass on `loss` and updates the variables.
+    /// &RETURNS&: None
+    fn backward_step(&mut self, loss: &PyTensor) -> PyResult<()> {
+        self.0.backward_step(loss).map_err(wrap_err)
+    }
+
+    #[getter]
+    /// The learning rate.
+    /// &RETURNS&: float
+    fn learning_rate(&self) -> f64 {
+        self.0.learning_rate()
+    }
+
+    #[setter]
+    fn set_learning_rate(&mut self, lr: f64) {
+        self.0.set_learning_rate(lr)
+    }
+}
@@ -0,0 +1,50 @@
+import candle
+from candle import Tensor, Var
+from candle.optim import AdamW, SGD
+import pytest
+
+
+def test_var_is_a_tensor():
+    w = Var(candle.ones((2, 3)))
+    assert isinstance(w, Tensor)
+    assert w.shape == (2, 3)
+    assert (w * 2.0).values() == [[2.0, 2.0, 2.0], [2.0, 2.0, 2.0]]
+    w.set(candle.zeros((2, 3)))
+    assert w.values() == [[0.0, 0.0, 0.0], [0.0, 0.0, 0.0]]
+    assert w.as_tensor().values() == w.values()
+
+
+def test_backward():
+    x = Var(Tensor([1.0, 2.0, 3.0]))
+    y = (x * x).sum_all()
+    grads = y.backward()
+    assert x in grads
+    assert grads[x].values() == [2.0, 4.0, 6.0]
+    assert grads.get(candle.ones((3,))) is None
+    with pytest.raises(KeyError):
+        grads[candle.ones((3,))]
+    assert grads.remove(x).values() == [2.0, 4.0, 6.0]
+    assert x not in grads
+
+
+@pytest.mark.parametrize("make_optimizer", [lambda vs: SGD(vs, 0.1), lambda vs: AdamW(vs, lr=0.1, weight_decay=0.0)])
+def test_optimizer_fits_linear_regression(make_optimizer):
+    w = Var(candle.zeros((3, 1)))
+    xs = candle.randn((32, 3))
+    ys = xs.matmul(Tensor([[1.0], [-2.0], [0.5]]))
+    optimizer = make_optimizer([w])
+    losses = []
+    for _ in range(100):
+        loss = (xs.matmul(w) - ys).sqr().mean_all()
+        losses.append(loss.values())
+        optimizer.step(loss.backward())
+    assert losses[-1] < losses[0] * 0.1
+
+
+def test_optimizer_learning_rate():
+    w = Var(Tensor([1.0]))
+    sgd = SGD([w], 0.5)
+    assert sgd.learning_rate == 0.5
+    sgd.learning_rate = 0.25
+    sgd.backward_step((w * w).sum_all())
+    assert w.values() == [0.5]
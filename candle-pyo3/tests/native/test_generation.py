@@ -0,0 +1,34 @@
+import candle
+from candle import QMatMul
+from candle.generation import LogitsProcessor, QuantizedLlama
+from candle.testing import assert_almost_equal
+import pytest
+
+
+def test_qmatmul_matches_dequantized_matmul():
+    weight = candle.randn((8, 64)).quantize("f32")
+    xs = candle.randn((2, 64))
+    qmatmul = QMatMul(weight)
+    expected = xs.matmul(weight.dequantize().t())
+    assert qmatmul.forward(xs).shape == (2, 8)
+    assert_almost_equal(qmatmul(xs), expected)
+
+
+def test_logits_processor_argmax():
+    logits = candle.Tensor([0.1, 3.0, 0.2, -1.0])
+    assert LogitsProcessor(temperature=0).sample(logits) == 1
+    assert LogitsProcessor(temperature=1.0, top_k=1).sample(logits) == 1
+
+
+def test_logits_processor_is_seeded():
+    logits = candle.Tensor([0.1, 0.3, 0.2, 0.0, 0.4, 0.1])
+    samples = []
+    for _ in range(2):
+        processor = LogitsProcessor(seed=42, temperature=1.0, top_p=0.9)
+        samples.append([processor.sample(logits) for _ in range(16)])
+    assert samples[0] == samples[1]
+
+
+def test_quantized_llama_missing_file():
+    with pytest.raises(OSError):
+        QuantizedLlama("does_not_exist.gguf")
@@ -321,3 +321,37 @@ def test_tensor_can_be_divided():
         d = candle.rand((3, 4, 5))
         e = candle.rand((4, 6))
         f = d / e
+
+
+def test_tensor_dlpack_roundtrip():
+    t = Tensor([[3.0, 1, 4], [1, 5, 9]])
+    assert t.__dlpack_device__() == (1, 0)
+    assert candle.from_dlpack(t).values() == t.values()
+    # Non-contiguous tensors are exported with their strides.
+    assert candle.from_dlpack(t.t()).values() == t.t().values()
+    assert candle.from_dlpack(t.__dlpack__()).values() == t.values()
+    with pytest.raises(TypeError):
+        candle.from_dlpack(42)
+
+
+def test_tensor_numpy_interop():
+    np = pytest.importorskip("numpy")
+    t = Tensor([[3.0, 1, 4], [1, 5, 9]])
+    a = np.asarray(t)
+    assert a.dtype == np.float32
+    assert a.tolist() == t.values()
+    assert not a.flags.writeable
+    assert t.t().numpy().tolist() == t.t().values()
+    assert t.to_dtype(candle.bf16).numpy().dtype == np.float32
+
+    b = Tensor(np.arange(6, dtype=np.float64).reshape(2, 3)[:, ::2])
+    assert b.dtype == candle.f64
+    assert b.values() == [[0.0, 2.0], [3.0, 5.0]]
+    assert Tensor(np.array([1, 2], dtype=np.int32)).dtype == candle.i64
+
+
+def test_tensor_torch_dlpack():
+    torch = pytest.importorskip("torch")
+    t = Tensor([[3.0, 1, 4], [1, 5, 9]])
+    assert torch.from_dlpack(t).tolist() == t.values()
+    assert candle.from_dlpack(torch.arange(4)).values() == [0, 1, 2, 3]
@@ -0,0 +1,237 @@
+//! The models served by the inference server.
+use std::sync::Arc;
+
+use candle::quantized::gguf_file;
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::VarBuilder;
+use candle_transformers::generation::batching::BatchingConfig;
+use candle_transformers::generation::events::GenerationConfig;
+use candle_transformers::generation::stream::{self, ContinuousBatcher, StreamConfig, TokenStream};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use candle_transformers::models::quantized_auto::ModelInfo;
+use candle_transformers::models::{bert, quantized_llama};
+use tokenizers::Tokenizer;
+
+fn wrap_err(err: tokenizers::Error) -> candle::Error {
+    candle::Error::Msg(err.to_string())
+}
+
+pub fn load_tokenizer<P: AsRef<std::path::Path>>(path: P) -> Result<Tokenizer> {
+    let path = path.as_ref();
+    Tokenizer::from_file(path).map_err(|e| wrap_err(e).with_path(path))
+}
+
+/// The parameters of a generation request.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationParams {
+    pub max_tokens: usize,
+    pub sampling: Sampling,
+    pub seed: u64,
+    /// The penalty applied to the logits of the recent tokens, 1 disables it.
+    pub repeat_penalty: f32,
+    /// The number of recent tokens the repeat penalty applies to.
+    pub repeat_last_n: usize,
+    /// The tokens stopping the generation in addition to the end of sequence tokens.
+    pub stop_tokens: Vec<u32>,
+}
+
+impl Default for GenerationParams {
+    fn default() -> Self {
+        Self {
+            max_tokens: 256,
+            sampling: Sampling::ArgMax,
+            seed: 299792458,
+            repeat_penalty: 1.,
+            repeat_last_n: 64,
+            stop_tokens: vec![],
+        }
+    }
+}
+
+/// A text generation model, shared by the concurrent requests.
+pub trait TextGenerator: Send + Sync + 'static {
+    fn tokenizer(&self) -> &Tokenizer;
+
+    /// The tokens ending a sequence.
+    fn eos_token_ids(&self) -> &[u32];
+
+    /// Starts generating tokens after `prompt`, this is called from a tokio runtime and the
+    /// computations should happen on the blocking pool, see [`stream::generate`].
+    fn generate(&self, prompt: Vec<u32>, params: GenerationParams) -> TokenStream;
+
+    /// The maximum number of tokens in the kv cache of a generation, if known.
+    fn max_seq_len(&self) -> Option<usize> {
+        None
+    }
+}
+
+/// A model computing sentence embeddings.
+pub trait Embedder: Send + Sync + 'static {
+    fn tokenizer(&self) -> &Tokenizer;
+
+    /// The embeddings of `texts`, this is a blocking call.
+    fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>>;
+}
+
+/// A quantized llama model loaded from a gguf file. The weights are shared by all the requests,
+/// each of them using its own kv cache. With continuous batching the decode steps of the
+/// concurrent requests are batched together rather than run independently.
+pub struct QuantizedLlama {
+    model: Arc<quantized_llama::ModelWeights>,
+    tokenizer: Tokenizer,
+    eos_token_ids: Vec<u32>,
+    device: Device,
+    batcher: Option<ContinuousBatcher>,
+}
+
+impl QuantizedLlama {
+    pub fn new(
+        model: quantized_llama::ModelWeights,
+        tokenizer: Tokenizer,
+        eos_token_ids: Vec<u32>,
+        device: &Device,
+    ) -> Self {
+        Self {
+            model: Arc::new(model),
+            tokenizer,
+            eos_token_ids,
+            device: device.clone(),
+            batcher: None,
+        }
+    }
+
+    /// Runs the generations through a [`ContinuousBatcher`], see
+    /// [`batching`](candle_transformers::generation::batching).
+    pub fn with_continuous_batching(mut self, config: BatchingConfig) -> Self {
+        let batcher = ContinuousBatcher::new(self.model.clone(), self.device.clone(), config);
+        self.batcher = Some(batcher);
+        self
+    }
+
+    /// Loads the model and its tokenizer, the end of sequence and end of turn tokens are read
+    /// from the gguf metadata.
+    pub fn load<P: AsRef<std::path::Path>>(
+        gguf: P,
+        tokenizer: Tokenizer,
+        device: &Device,
+    ) -> Result<Self> {
+        let gguf = gguf.as_ref();
+        let mut file =
+            std::fs::File::open(gguf).map_err(|e| candle::Error::from(e).with_path(gguf))?;
+        let content = gguf_file::Content::read(&mut file).map_err(|e| e.with_path(gguf))?;
+        let eos_token_ids = ModelInfo::from_gguf(&content)?.stop_token_ids();
+        let model = quantized_llama::ModelWeights::from_gguf(content, &mut file, device)?;
+        Ok(Self::new(model, tokenizer, eos_token_ids, device))
+    }
+}
+
+impl TextGenerator for QuantizedLlama {
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn eos_token_ids(&self) -> &[u32] {
+        &self.eos_token_ids
+    }
+
+    fn max_seq_len(&self) -> Option<usize> {
+        Some(self.model.max_seq_len())
+    }
+
+    fn generate(&self, prompt: Vec<u32>, params: GenerationParams) -> TokenStream {
+        if let Some(batcher) = &self.batcher {
+            let logits_processor = LogitsProcessor::from_sampling(params.seed, params.sampling);
+            let mut stop_tokens = params.stop_tokens;
+            stop_tokens.extend_from_slice(&self.eos_token_ids);
+            let config = GenerationConfig::new(params.max_tokens)
+                .with_stop_tokens(stop_tokens)
+                .with_repeat_penalty(params.repeat_penalty, params.repeat_last_n);
+            return batcher.generate(prompt, logits_processor, config);
+        }
+        let model = self.model.clone();
+        let mut cache = model.new_cache();
+        let mut tokens = vec![];
+        let (repeat_penalty, repeat_last_n) = (params.repeat_penalty, params.repeat_last_n);
+        let forward = move |xs: &Tensor, index_pos: usize| -> Result<Tensor> {
+            let logits = model
+                .forward_with_cache(xs, index_pos, &mut cache)?
+                .squeeze(0)?
+                .to_dtype(DType::F32)?;
+            tokens.extend(xs.flatten_all()?.to_vec1::<u32>()?);
+            if repeat_penalty == 1. {
+                return Ok(logits);
+            }
+            let start_at = tokens.len().saturating_sub(repeat_last_n);
+            candle_transformers::utils::apply_repeat_penalty(
+                &logits,
+                repeat_penalty,
+                &tokens[start_at..],
+            )
+        };
+        let logits_processor = LogitsProcessor::from_sampling(params.seed, params.sampling);
+        let mut stop_tokens = params.stop_tokens;
+        stop_tokens.extend_from_slice(&self.eos_token_ids);
+        let config = StreamConfig::new(params.max_tokens).with_stop_tokens(stop_tokens);
+        stream::generate(
+            forward,
+            prompt,
+            logits_processor,
+            self.device.clone(),
+            config,
+        )
+    }
+}
+
+/// A BERT model whose embeddings are averaged over the tokens.
+pub struct BertEmbedder {
+    model: bert::BertModel,
+    tokenizer: Tokenizer,
+    device: Device,
+}
+
+impl BertEmbedder {
+    pub fn new(model: bert::BertModel, tokenizer: Tokenizer, device: &Device) -> Self {
+        Self {
+            model,
+            tokenizer,
+            device: device.clone(),
+        }
+    }
+
+    /// Loads a model from a directory containing `config.json`, `tokenizer.json`, and
+    /// `model.safetensors`.
+    pub fn load<P: AsRef<std::path::Path>>(dir: P, device: &Device) -> Result<Self> {
+        let dir = dir.as_ref();
+        let config = dir.join("config.json");
+        let config = std::fs::read_to_string(&config)
+            .map_err(|e| candle::Error::from(e).with_path(&config))?;
+        let config: bert::Config = serde_json::from_str(&config).map_err(candle::Error::wrap)?;
+        let tokenizer = load_tokenizer(dir.join("tokenizer.json"))?;
+        let weights = dir.join("model.safetensors");
+        let vb = unsafe { VarBuilder::from_mmaped_safetensors(&[weights], bert::DTYPE, device)? };
+        let model = bert::BertModel::load(vb, &config)?;
+        Ok(Self::new(model, tokenizer, device))
+    }
+}
+
+impl Embedder for BertEmbedder {
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn embed(&self, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+        texts
+            .iter()
+            .map(|text| {
+                let encoding = self
+                    .tokenizer
+                    .encode(text.as_str(), true)
+                    .map_err(wrap_err)?;
+                let input_ids = Tensor::new(encoding.get_ids(), &self.device)?.unsqueeze(0)?;
+                let token_type_ids = input_ids.zeros_like()?;
+                let embeddings = self.model.forward(&input_ids, &token_type_ids, None)?;
+                embeddings.mean(1)?.squeeze(0)?.to_vec1::<f32>()
+            })
+            .collect()
+    }
+}
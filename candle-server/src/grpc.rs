@@ -0,0 +1,355 @@
+//! The implementation of the `candle.v1.Inference` gRPC service.
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use candle_transformers::generation::Sampling;
+use tokenizers::Tokenizer;
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::{Request, Response, Status};
+
+use crate::backend::{Embedder, GenerationParams, TextGenerator};
+use crate::manager::ModelManager;
+use crate::metrics::Metrics;
+use crate::proto::inference_server::{Inference, InferenceServer};
+use crate::proto::{self, generate_request, FinishReason};
+use crate::scheduler::{Scheduler, SchedulerConfig, SchedulerError};
+
+fn internal(err: impl std::fmt::Display) -> Status {
+    Status::internal(err.to_string())
+}
+
+impl From<SchedulerError> for Status {
+    fn from(err: SchedulerError) -> Self {
+        match err {
+            SchedulerError::QueueFull { .. } => Status::resource_exhausted(err.to_string()),
+            SchedulerError::DeadlineExceeded => Status::deadline_exceeded(err.to_string()),
+        }
+    }
+}
+
+/// Incrementally decodes the generated tokens so that the text can be streamed.
+struct TextDecoder<'a> {
+    tokenizer: &'a Tokenizer,
+    tokens: Vec<u32>,
+    prev_index: usize,
+}
+
+impl<'a> TextDecoder<'a> {
+    fn new(tokenizer: &'a Tokenizer) -> Self {
+        Self {
+            tokenizer,
+            tokens: vec![],
+            prev_index: 0,
+        }
+    }
+
+    fn decode(&self, tokens: &[u32]) -> Result<String, Status> {
+        self.tokenizer.decode(tokens, true).map_err(internal)
+    }
+
+    /// The text completed by `token`, empty if the token does not complete a valid utf8
+    /// sequence yet.
+    fn next_token(&mut self, token: u32) -> Result<String, Status> {
+        let prev_text = self.decode(&self.tokens[self.prev_index..])?;
+        self.tokens.push(token);
+        let text = self.decode(&self.tokens[self.prev_index..])?;
+        if text.len() > prev_text.len() && !text.ends_with('\u{FFFD}') {
+            // Keep the last token as context as some tokenizers decode leading spaces
+            // differently at the start of a sequence.
+            self.prev_index = self.tokens.len() - 1;
+            Ok(text[prev_text.len()..].to_string())
+        } else {
+            Ok(String::new())
+        }
+    }
+}
+
+/// The gRPC service, serving an optional text generation model and an optional embedding model.
+#[derive(Clone)]
+pub struct InferenceService {
+    generator: Option<Arc<dyn TextGenerator>>,
+    embedder: Option<Arc<dyn Embedder>>,
+    default_max_tokens: usize,
+    models: Option<ModelManager>,
+    scheduler: Scheduler,
+    metrics: Metrics,
+}
+
+impl Default for InferenceService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl InferenceService {
+    pub fn new() -> Self {
+        let scheduler = Scheduler::default();
+        Self {
+            generator: None,
+            embedder: None,
+            default_max_tokens: 256,
+            models: None,
+            metrics: Metrics::new(scheduler.clone(), None),
+            scheduler,
+        }
+    }
+
+    /// The metrics depend on the scheduler and on the generation model.
+    fn with_new_metrics(mut self) -> Self {
+        let kv_cache_capacity = self.generator.as_ref().and_then(|g| g.max_seq_len());
+        self.metrics = Metrics::new(self.scheduler.clone(), kv_cache_capacity);
+        self
+    }
+
+    pub fn with_generator<G: TextGenerator>(mut self, generator: G) -> Self {
+        self.generator = Some(Arc::new(generator));
+        self.with_new_metrics()
+    }
+
+    pub fn with_embedder<E: Embedder>(mut self, embedder: E) -> Self {
+        self.embedder = Some(Arc::new(embedder));
+        self
+    }
+
+    /// The models that can be selected by name in the generation requests, the generator set with
+    /// [`Self::with_generator`] being used by the requests without a model name.
+    pub fn with_model_manager(mut self, models: ModelManager) -> Self {
+        self.models = Some(models);
+        self
+    }
+
+    /// The number of generated tokens when a request does not specify it.
+    pub fn with_default_max_tokens(mut self, default_max_tokens: usize) -> Self {
+        self.default_max_tokens = default_max_tokens;
+        self
+    }
+
+    /// The concurrency limits of the generation requests.
+    pub fn with_scheduler(mut self, config: SchedulerConfig) -> Self {
+        self.scheduler = Scheduler::new(config);
+        self.with_new_metrics()
+    }
+
+    pub fn scheduler(&self) -> &Scheduler {
+        &self.scheduler
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn into_server(self) -> InferenceServer<Self> {
+        InferenceServer::new(self)
+    }
+
+    fn generator(&self) -> Result<&Arc<dyn TextGenerator>, Status> {
+        self.generator
+            .as_ref()
+            .ok_or_else(|| Status::unimplemented("no text generation model is loaded"))
+    }
+
+    async fn generator_for(&self, model: &str) -> Result<Arc<dyn TextGenerator>, Status> {
+        if model.is_empty() {
+            return self.generator().cloned();
+        }
+        let unknown = || Status::not_found(format!("unknown model {model}"));
+        let models = self.models.as_ref().ok_or_else(unknown)?;
+        match models.get(model).await {
+            Some(generator) => generator.map_err(internal),
+            None => Err(unknown()),
+        }
+    }
+
+    fn tokenizer(&self) -> Result<&Tokenizer, Status> {
+        match (&self.generator, &self.embedder) {
+            (Some(generator), _) => Ok(generator.tokenizer()),
+            (None, Some(embedder)) => Ok(embedder.tokenizer()),
+            (None, None) => Err(Status::unimplemented("no model is loaded")),
+        }
+    }
+
+    /// Converts the sampling parameters of a request, the unset values use the defaults.
+    pub fn generation_params(&self, params: Option<proto::SamplingParams>) -> GenerationParams {
+        let params = params.unwrap_or_default();
+        let default = GenerationParams::default();
+        let temperature = params.temperature as f64;
+        let sampling = if temperature <= 1e-7 {
+            Sampling::ArgMax
+        } else {
+            let top_p = (params.top_p > 0. && params.top_p < 1.).then_some(params.top_p as f64);
+            let top_k = (params.top_k > 0).then_some(params.top_k as usize);
+            match (top_k, top_p) {
+                (None, None) => Sampling::All { temperature },
+                (Some(k), None) => Sampling::TopK { k, temperature },
+                (None, Some(p)) => Sampling::TopP { p, temperature },
+                (Some(k), Some(p)) => Sampling::TopKThenTopP { k, p, temperature },
+            }
+        };
+        let max_tokens = match params.max_tokens {
+            0 => self.default_max_tokens,
+            max_tokens => max_tokens as usize,
+        };
+        let repeat_penalty = match params.repeat_penalty {
+            0. => 1.,
+            repeat_penalty => repeat_penalty,
+        };
+        let repeat_last_n = match params.repeat_last_n {
+            0 => default.repeat_last_n,
+            repeat_last_n => repeat_last_n as usize,
+        };
+        GenerationParams {
+            max_tokens,
+            sampling,
+            seed: params.seed,
+            repeat_penalty,
+            repeat_last_n,
+            stop_tokens: params.stop_token_ids,
+        }
+    }
+}
+
+type GenerateStream = Pin<Box<dyn Stream<Item = Result<proto::GenerateResponse, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl Inference for InferenceService {
+    type GenerateStream = GenerateStream;
+
+    async fn generate(
+        &self,
+        request: Request<proto::GenerateRequest>,
+    ) -> Result<Response<Self::GenerateStream>, Status> {
+        let arrival = Instant::now();
+        let request = request.into_inner();
+        let generator = self.generator_for(&request.model).await?;
+        let prompt = match request.prompt {
+            Some(generate_request::Prompt::Text(text)) => generator
+                .tokenizer()
+                .encode(text, true)
+                .map_err(internal)?
+                .get_ids()
+                .to_vec(),
+            Some(generate_request::Prompt::TokenIds(ids)) => ids.ids,
+            None => vec![],
+        };
+        if prompt.is_empty() {
+            return Err(Status::invalid_argument("the prompt is empty"));
+        }
+        let params = self.generation_params(request.params);
+        let timeout =
+            (request.timeout_ms > 0).then(|| Duration::from_millis(request.timeout_ms as u64));
+        self.metrics.on_request();
+        // The permit is held until the end of the generation.
+        let permit = match self.scheduler.acquire(request.priority, timeout).await {
+            Ok(permit) => permit,
+            Err(err) => {
+                match err {
+                    SchedulerError::QueueFull { .. } => self.metrics.on_rejected(),
+                    SchedulerError::DeadlineExceeded => self.metrics.on_timeout(),
+                }
+                return Err(err.into());
+            }
+        };
+        let metrics = self.metrics.clone();
+        let mut generation_metrics = metrics.start_generation(prompt.len());
+        let start = Instant::now();
+        let max_tokens = params.max_tokens;
+        let mut stop_tokens = params.stop_tokens.clone();
+        stop_tokens.extend_from_slice(generator.eos_token_ids());
+        let mut tokens = generator.generate(prompt, params);
+
+        let (tx, rx) = tokio::sync::mpsc::channel(16);
+        tokio::spawn(async move {
+            let mut decoder = TextDecoder::new(generator.tokenizer());
+            let mut generated = 0;
+            loop {
+                let token = match permit.deadline() {
+                    None => tokens.next().await,
+                    Some(deadline) => {
+                        match tokio::time::timeout_at(deadline, tokens.next()).await {
+                            Ok(token) => token,
+                            Err(_) => {
+                                metrics.on_timeout();
+                                let status = Status::deadline_exceeded("the generation timed out");
+                                let _ = tx.send(Err(status)).await;
+                                break;
+                            }
+                        }
+                    }
+                };
+                let Some(token) = token else {
+                    generation_metrics.on_done(arrival.elapsed(), start.elapsed());
+                    break;
+                };
+                let response = token.map_err(internal).and_then(|token| {
+                    generated += 1;
+                    generation_metrics.on_token(arrival.elapsed());
+                    let finish_reason = if stop_tokens.contains(&token) {
+                        FinishReason::Stop
+                    } else if generated >= max_tokens {
+                        FinishReason::Length
+                    } else {
+                        FinishReason::Unspecified
+                    };
+                    Ok(proto::GenerateResponse {
+                        token_id: token,
+                        text: decoder.next_token(token)?,
+                        finish_reason: finish_reason as i32,
+                    })
+                });
+                let is_err = response.is_err();
+                // Dropping the token stream when the client has gone away stops the generation.
+                if tx.send(response).await.is_err() || is_err {
+                    break;
+                }
+            }
+            // The slot and the kv cache are released before the stream ends.
+            drop((permit, generation_metrics));
+        });
+        Ok(Response::new(Box::pin(ReceiverStream::new(rx))))
+    }
+
+    async fn embed(
+        &self,
+        request: Request<proto::EmbedRequest>,
+    ) -> Result<Response<proto::EmbedResponse>, Status> {
+        let embedder = match self.embedder.as_ref() {
+            Some(embedder) => embedder.clone(),
+            None => return Err(Status::unimplemented("no embedding model is loaded")),
+        };
+        let request = request.into_inner();
+        let embeddings = tokio::task::spawn_blocking(move || embedder.embed(&request.inputs))
+            .await
+            .map_err(internal)?
+            .map_err(internal)?;
+        let embeddings = embeddings
+            .into_iter()
+            .map(|mut values| {
+                if request.normalize {
+                    let norm = values.iter().map(|v| v * v).sum::<f32>().sqrt();
+                    if norm > 0. {
+                        values.iter_mut().for_each(|v| *v /= norm)
+                    }
+                }
+                proto::Embedding { values }
+            })
+            .collect();
+        Ok(Response::new(proto::EmbedResponse { embeddings }))
+    }
+
+    async fn tokenize(
+        &self,
+        request: Request<proto::TokenizeRequest>,
+    ) -> Result<Response<proto::TokenizeResponse>, Status> {
+        let request = request.into_inner();
+        let encoding = self
+            .tokenizer()?
+            .encode(request.text, request.add_special_tokens)
+            .map_err(internal)?;
+        Ok(Response::new(proto::TokenizeResponse {
+            token_ids: encoding.get_ids().to_vec(),
+            tokens: encoding.get_tokens().to_vec(),
+        }))
+    }
+}
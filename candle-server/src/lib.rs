@@ -0,0 +1,25 @@
+//! A gRPC inference server for candle models.
+//!
+//! The service is defined in `proto/candle.proto` and provides text generation with streamed
+//! tokens, embeddings, and tokenization. The models are plugged in through the
+//! [`TextGenerator`] and [`Embedder`] traits, [`QuantizedLlama`] and [`BertEmbedder`] being the
+//! implementations used by the reference server binary. The generation requests go through a
+//! [`Scheduler`] limiting the number of concurrent generations, and the activity of the service is
+//! recorded in its [`Metrics`]. A [`ModelManager`] can host several generation models loaded on
+//! demand within a memory budget.
+pub mod backend;
+pub mod grpc;
+pub mod manager;
+pub mod metrics;
+pub mod scheduler;
+
+/// The messages and service traits generated from `proto/candle.proto`.
+pub mod proto {
+    tonic::include_proto!("candle.v1");
+}
+
+pub use backend::{BertEmbedder, Embedder, GenerationParams, QuantizedLlama, TextGenerator};
+pub use grpc::InferenceService;
+pub use manager::ModelManager;
+pub use metrics::{Metrics, MetricsSnapshot};
+pub use scheduler::{Scheduler, SchedulerConfig};
@@ -0,0 +1,176 @@
+#[cfg(feature = "mkl")]
+extern crate intel_mkl_src;
+
+#[cfg(feature = "accelerate")]
+extern crate accelerate_src;
+
+use std::time::Duration;
+
+use anyhow::Result;
+use candle::Device;
+use candle_server::backend::load_tokenizer;
+use candle_server::{
+    BertEmbedder, InferenceService, ModelManager, QuantizedLlama, SchedulerConfig,
+};
+use candle_transformers::generation::batching::BatchingConfig;
+use clap::Parser;
+
+/// A reference gRPC server for a quantized llama model and an optional BERT embedding model.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    /// The gguf file of the text generation model.
+    #[arg(long)]
+    model: Option<String>,
+
+    /// The tokenizer of the text generation model.
+    #[arg(long)]
+    tokenizer: Option<String>,
+
+    /// A directory with the config.json, tokenizer.json, and model.safetensors files of a BERT
+    /// embedding model.
+    #[arg(long)]
+    embedding_model: Option<String>,
+
+    /// Additional generation models selected by name in the requests, as
+    /// `name=model.gguf,tokenizer.json`. These are loaded on their first request.
+    #[arg(long)]
+    named_model: Vec<String>,
+
+    /// The memory budget of the named models in MB, the least recently used models are unloaded
+    /// to stay within the budget.
+    #[arg(long, default_value_t = 16384)]
+    memory_budget_mb: usize,
+
+    #[arg(long, default_value = "127.0.0.1:50051")]
+    addr: std::net::SocketAddr,
+
+    /// The number of generated tokens when a request does not specify it.
+    #[arg(long, default_value_t = 256)]
+    max_tokens: usize,
+
+    /// The maximum number of generations running at the same time.
+    #[arg(long, default_value_t = 4)]
+    max_concurrent: usize,
+
+    /// The maximum number of queued generation requests, new requests are rejected when the
+    /// queue is full.
+    #[arg(long, default_value_t = 128)]
+    max_queue_len: usize,
+
+    /// Batch the decode steps of up to this many concurrent generations of a model together,
+    /// with continuous batching. The generations are run independently when not set.
+    #[arg(long)]
+    max_batch_size: Option<usize>,
+
+    /// The maximum number of prompt and generated tokens of the generations batched together.
+    #[arg(long)]
+    max_batch_tokens: Option<usize>,
+
+    /// The timeout in milliseconds of the generation requests that do not specify one.
+    #[arg(long)]
+    timeout_ms: Option<u64>,
+
+    /// Serve the Prometheus metrics on `/metrics` at this address.
+    #[arg(long)]
+    metrics_addr: Option<std::net::SocketAddr>,
+
+    /// Run on CPU rather than on GPU.
+    #[arg(long)]
+    cpu: bool,
+}
+
+fn device(cpu: bool) -> Result<Device> {
+    if cpu {
+        Ok(Device::Cpu)
+    } else if candle::utils::cuda_is_available() {
+        Ok(Device::new_cuda(0)?)
+    } else if candle::utils::metal_is_available() {
+        Ok(Device::new_metal(0)?)
+    } else {
+        Ok(Device::Cpu)
+    }
+}
+
+fn load_model(
+    model: &str,
+    tokenizer: &str,
+    batching: Option<&BatchingConfig>,
+    device: &Device,
+) -> candle::Result<QuantizedLlama> {
+    let tokenizer = load_tokenizer(tokenizer)?;
+    let model = QuantizedLlama::load(model, tokenizer, device)?;
+    match batching {
+        Some(config) => Ok(model.with_continuous_batching(config.clone())),
+        None => Ok(model),
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let device = device(args.cpu)?;
+    let batching = args.max_batch_size.map(|max_batch_size| {
+        BatchingConfig::default()
+            .with_max_batch_size(max_batch_size)
+            .with_max_batch_tokens(args.max_batch_tokens)
+    });
+    let scheduler = SchedulerConfig::default()
+        .with_max_concurrent(args.max_concurrent)
+        .with_max_queue_len(args.max_queue_len)
+        .with_default_timeout(args.timeout_ms.map(Duration::from_millis));
+    let mut service = InferenceService::new()
+        .with_default_max_tokens(args.max_tokens)
+        .with_scheduler(scheduler);
+    match (&args.model, &args.tokenizer) {
+        (Some(model), Some(tokenizer)) => {
+            let generator = load_model(model, tokenizer, batching.as_ref(), &device)?;
+            service = service.with_generator(generator);
+            println!("loaded generation model {model}");
+        }
+        (None, None) => {}
+        _ => anyhow::bail!("--model and --tokenizer should be specified together"),
+    }
+    if let Some(dir) = &args.embedding_model {
+        service = service.with_embedder(BertEmbedder::load(dir, &device)?);
+        println!("loaded embedding model {dir}");
+    }
+    if !args.named_model.is_empty() {
+        let models = ModelManager::new(args.memory_budget_mb * 1024 * 1024);
+        for spec in args.named_model.iter() {
+            let (name, model, tokenizer) = match spec.split_once('=') {
+                Some((name, files)) => match files.split_once(',') {
+                    Some((model, tokenizer)) => (name, model.to_string(), tokenizer.to_string()),
+                    None => anyhow::bail!("expected name=model.gguf,tokenizer.json, got {spec}"),
+                },
+                None => anyhow::bail!("expected name=model.gguf,tokenizer.json, got {spec}"),
+            };
+            let size_in_bytes = std::fs::metadata(&model)?.len() as usize;
+            let device = device.clone();
+            let batching = batching.clone();
+            models.register(name, size_in_bytes, move || {
+                load_model(&model, &tokenizer, batching.as_ref(), &device)
+            });
+            println!("registered generation model {name}");
+        }
+        service = service.with_model_manager(models);
+    }
+    if args.model.is_none() && args.embedding_model.is_none() && args.named_model.is_empty() {
+        anyhow::bail!("no model to serve, use --model, --named-model or --embedding-model")
+    }
+    if let Some(addr) = args.metrics_addr {
+        let metrics = service.metrics().clone();
+        tokio::spawn(async move {
+            if let Err(err) = candle_server::metrics::serve(addr, metrics).await {
+                eprintln!("metrics endpoint error: {err}")
+            }
+        });
+        println!("serving metrics on http://{addr}/metrics");
+    }
+    println!("listening on {}", args.addr);
+    tonic::transport::Server::builder()
+        .add_service(service.into_server())
+        .serve(args.addr)
+        .await?;
+    Ok(())
+}
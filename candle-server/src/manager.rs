@@ -0,0 +1,192 @@
+//! Hosting several generation models within a memory budget.
+//!
+//! The models are registered with a loader and their memory footprint, and are only loaded on
+//! their first request. When loading a model would exceed the budget the least recently used
+//! models are unloaded first. A model that is unloaded while some generations are still running
+//! is only freed once these generations complete, as they hold a reference to it.
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use candle::Result;
+
+use crate::backend::TextGenerator;
+
+type Loader = Box<dyn Fn() -> Result<Arc<dyn TextGenerator>> + Send + Sync>;
+
+struct Entry {
+    loader: Arc<Loader>,
+    size_in_bytes: usize,
+    model: Option<Arc<dyn TextGenerator>>,
+    /// The value of the manager clock when the model was last used.
+    last_used: u64,
+    /// Serializes the loads of the model so that concurrent first requests only load it once.
+    loading: Arc<tokio::sync::Mutex<()>>,
+}
+
+struct State {
+    entries: HashMap<String, Entry>,
+    clock: u64,
+}
+
+impl State {
+    fn loaded_bytes(&self) -> usize {
+        self.entries
+            .values()
+            .filter(|e| e.model.is_some())
+            .map(|e| e.size_in_bytes)
+            .sum()
+    }
+
+    fn touch(&mut self, name: &str) -> Option<Arc<dyn TextGenerator>> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entries.get_mut(name)?;
+        entry.last_used = clock;
+        entry.model.clone()
+    }
+
+    /// Unloads the least recently used models until `size_in_bytes` more bytes fit in `budget`.
+    fn evict_for(&mut self, size_in_bytes: usize, budget: usize) {
+        while self.loaded_bytes() + size_in_bytes > budget {
+            let lru = self
+                .entries
+                .values_mut()
+                .filter(|e| e.model.is_some())
+                .min_by_key(|e| e.last_used);
+            match lru {
+                Some(entry) => entry.model = None,
+                None => break,
+            }
+        }
+    }
+}
+
+/// A set of named generation models sharing a memory budget, cloning returns a handle to the
+/// same models.
+#[derive(Clone)]
+pub struct ModelManager {
+    state: Arc<Mutex<State>>,
+    budget_in_bytes: usize,
+}
+
+impl ModelManager {
+    /// Creates a manager whose loaded models use at most `budget_in_bytes` of memory. A model
+    /// larger than the budget can still be loaded, all the other models being unloaded.
+    pub fn new(budget_in_bytes: usize) -> Self {
+        let state = State {
+            entries: HashMap::new(),
+            clock: 0,
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+            budget_in_bytes,
+        }
+    }
+
+    pub fn budget_in_bytes(&self) -> usize {
+        self.budget_in_bytes
+    }
+
+    /// Registers a model without loading it, `size_in_bytes` is the memory used by the loaded
+    /// model, e.g. the size of its weights file. Registering a name again replaces the model.
+    pub fn register<F, G>(&self, name: &str, size_in_bytes: usize, loader: F)
+    where
+        F: Fn() -> Result<G> + Send + Sync + 'static,
+        G: TextGenerator,
+    {
+        let loader: Loader = Box::new(move || Ok(Arc::new(loader()?) as Arc<dyn TextGenerator>));
+        let entry = Entry {
+            loader: Arc::new(loader),
+            size_in_bytes,
+            model: None,
+            last_used: 0,
+            loading: Arc::new(tokio::sync::Mutex::new(())),
+        };
+        self.state
+            .lock()
+            .unwrap()
+            .entries
+            .insert(name.to_string(), entry);
+    }
+
+    /// The names of the registered models, sorted.
+    pub fn models(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut names = state.entries.keys().cloned().collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// The names of the currently loaded models, sorted.
+    pub fn loaded_models(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        let mut names = state
+            .entries
+            .iter()
+            .filter(|(_, e)| e.model.is_some())
+            .map(|(name, _)| name.clone())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    /// The memory used by the loaded models according to their registered sizes.
+    pub fn loaded_bytes(&self) -> usize {
+        self.state.lock().unwrap().loaded_bytes()
+    }
+
+    /// Unloads a model, returns `false` if it was not loaded.
+    pub fn unload(&self, name: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match state.entries.get_mut(name) {
+            Some(entry) => entry.model.take().is_some(),
+            None => false,
+        }
+    }
+
+    /// Returns the model named `name`, loading it on the blocking pool if needed. Returns `None`
+    /// if no such model has been registered.
+    pub async fn get(&self, name: &str) -> Option<Result<Arc<dyn TextGenerator>>> {
+        let loading = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(model) = state.touch(name) {
+                return Some(Ok(model));
+            }
+            state.entries.get(name)?.loading.clone()
+        };
+        let _guard = loading.lock().await;
+        // The model may have been loaded while waiting for the guard.
+        let (loader, size_in_bytes) = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(model) = state.touch(name) {
+                return Some(Ok(model));
+            }
+            let entry = state.entries.get(name)?;
+            let (loader, size_in_bytes) = (entry.loader.clone(), entry.size_in_bytes);
+            state.evict_for(size_in_bytes, self.budget_in_bytes);
+            (loader, size_in_bytes)
+        };
+        let load = loader.clone();
+        let model = match tokio::task::spawn_blocking(move || load()).await {
+            Ok(model) => model,
+            Err(err) => Err(candle::Error::wrap(err)),
+        };
+        let model = match model {
+            Ok(model) => model,
+            Err(err) => return Some(Err(err)),
+        };
+        let mut state = self.state.lock().unwrap();
+        // Other models may have been loaded concurrently.
+        state.evict_for(size_in_bytes, self.budget_in_bytes);
+        state.clock += 1;
+        let clock = state.clock;
+        // The model may have been unregistered or replaced while loading, it is still used for
+        // this request.
+        let entry = state.entries.get_mut(name);
+        if let Some(entry) = entry.filter(|e| Arc::ptr_eq(&e.loader, &loader)) {
+            entry.model = Some(model.clone());
+            entry.last_used = clock;
+        }
+        Some(Ok(model))
+    }
+}
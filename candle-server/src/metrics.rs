@@ -0,0 +1,367 @@
+//! Metrics of the inference server.
+//!
+//! The counters are updated by the gRPC service and can be read either programmatically with
+//! [`Metrics::snapshot`] or in the Prometheus text format, [`serve`] exposing the latter on a
+//! `/metrics` http endpoint.
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::scheduler::Scheduler;
+
+/// The upper bounds in seconds of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10., 30.,
+];
+
+/// A histogram with cumulative buckets as used by Prometheus.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    /// The upper bounds of the buckets, the last bucket being implicitly unbounded.
+    pub bounds: Vec<f64>,
+    /// The number of observations in each bucket, including the unbounded one.
+    pub counts: Vec<u64>,
+    pub sum: f64,
+    pub count: u64,
+}
+
+impl Histogram {
+    fn new(bounds: &[f64]) -> Self {
+        Self {
+            bounds: bounds.to_vec(),
+            counts: vec![0; bounds.len() + 1],
+            sum: 0.,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: f64) {
+        let idx = self.bounds.partition_point(|&b| b < value);
+        self.counts[idx] += 1;
+        self.sum += value;
+        self.count += 1;
+    }
+
+    /// The average of the observed values, 0 when there are none.
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            0.
+        } else {
+            self.sum / self.count as f64
+        }
+    }
+
+    fn write_prometheus(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        let mut cumulative = 0;
+        for (bound, count) in self.bounds.iter().zip(self.counts.iter()) {
+            cumulative += count;
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {cumulative}");
+        }
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {}", self.count);
+        let _ = writeln!(out, "{name}_sum {}", self.sum);
+        let _ = writeln!(out, "{name}_count {}", self.count);
+    }
+}
+
+/// The state of the metrics at some point in time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetricsSnapshot {
+    /// The number of generation requests, including the rejected ones.
+    pub requests: u64,
+    /// The number of requests rejected because the queue was full.
+    pub rejected_requests: u64,
+    /// The number of requests that timed out, either queued or while generating.
+    pub timed_out_requests: u64,
+    pub generated_tokens: u64,
+    pub prompt_tokens: u64,
+    /// The average generation speed of the completed requests.
+    pub tokens_per_second: f64,
+    /// The number of requests waiting for a running slot.
+    pub queue_depth: usize,
+    /// The number of running generations.
+    pub running: usize,
+    /// The fraction of the running slots in use.
+    pub batch_occupancy: f64,
+    /// The number of tokens held in the kv caches of the running generations.
+    pub kv_cache_tokens: u64,
+    /// The fraction of the kv cache capacity in use, `None` when the capacity is not known.
+    pub kv_cache_utilization: Option<f64>,
+    /// The time from the arrival of a request to its first generated token.
+    pub time_to_first_token: Histogram,
+    /// The time from the arrival of a request to its completion.
+    pub request_latency: Histogram,
+}
+
+impl MetricsSnapshot {
+    /// Renders the metrics in the Prometheus text exposition format.
+    pub fn to_prometheus(&self) -> String {
+        let mut metrics = vec![
+            (
+                "candle_requests_total",
+                "counter",
+                "Generation requests.",
+                self.requests as f64,
+            ),
+            (
+                "candle_rejected_requests_total",
+                "counter",
+                "Generation requests rejected because the queue was full.",
+                self.rejected_requests as f64,
+            ),
+            (
+                "candle_timed_out_requests_total",
+                "counter",
+                "Generation requests that timed out.",
+                self.timed_out_requests as f64,
+            ),
+            (
+                "candle_generated_tokens_total",
+                "counter",
+                "Generated tokens.",
+                self.generated_tokens as f64,
+            ),
+            (
+                "candle_prompt_tokens_total",
+                "counter",
+                "Prompt tokens.",
+                self.prompt_tokens as f64,
+            ),
+            (
+                "candle_tokens_per_second",
+                "gauge",
+                "Average generation speed of the completed requests.",
+                self.tokens_per_second,
+            ),
+            (
+                "candle_queue_depth",
+                "gauge",
+                "Requests waiting for a running slot.",
+                self.queue_depth as f64,
+            ),
+            (
+                "candle_running_requests",
+                "gauge",
+                "Running generations.",
+                self.running as f64,
+            ),
+            (
+                "candle_batch_occupancy",
+                "gauge",
+                "Fraction of the running slots in use.",
+                self.batch_occupancy,
+            ),
+            (
+                "candle_kv_cache_tokens",
+                "gauge",
+                "Tokens held in the kv caches.",
+                self.kv_cache_tokens as f64,
+            ),
+        ];
+        if let Some(utilization) = self.kv_cache_utilization {
+            metrics.push((
+                "candle_kv_cache_utilization",
+                "gauge",
+                "Fraction of the kv cache capacity in use.",
+                utilization,
+            ))
+        }
+        let mut out = String::new();
+        for (name, kind, help, value) in metrics {
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} {kind}");
+            let _ = writeln!(out, "{name} {value}");
+        }
+        self.time_to_first_token.write_prometheus(
+            &mut out,
+            "candle_time_to_first_token_seconds",
+            "Time from the arrival of a request to its first token.",
+        );
+        self.request_latency.write_prometheus(
+            &mut out,
+            "candle_request_latency_seconds",
+            "Time from the arrival of a request to its completion.",
+        );
+        out
+    }
+}
+
+struct Histograms {
+    time_to_first_token: Histogram,
+    request_latency: Histogram,
+    generation_seconds: f64,
+    completed_tokens: u64,
+}
+
+/// The metrics of a service, cloning returns a handle to the same metrics.
+#[derive(Clone)]
+pub struct Metrics {
+    requests: Arc<AtomicU64>,
+    rejected_requests: Arc<AtomicU64>,
+    timed_out_requests: Arc<AtomicU64>,
+    generated_tokens: Arc<AtomicU64>,
+    prompt_tokens: Arc<AtomicU64>,
+    kv_cache_tokens: Arc<AtomicU64>,
+    histograms: Arc<Mutex<Histograms>>,
+    scheduler: Scheduler,
+    /// The maximum number of tokens in the kv cache of a single generation.
+    kv_cache_capacity: Option<usize>,
+}
+
+impl Metrics {
+    pub fn new(scheduler: Scheduler, kv_cache_capacity: Option<usize>) -> Self {
+        let histograms = Histograms {
+            time_to_first_token: Histogram::new(&LATENCY_BUCKETS),
+            request_latency: Histogram::new(&LATENCY_BUCKETS),
+            generation_seconds: 0.,
+            completed_tokens: 0,
+        };
+        Self {
+            requests: Default::default(),
+            rejected_requests: Default::default(),
+            timed_out_requests: Default::default(),
+            generated_tokens: Default::default(),
+            prompt_tokens: Default::default(),
+            kv_cache_tokens: Default::default(),
+            histograms: Arc::new(Mutex::new(histograms)),
+            scheduler,
+            kv_cache_capacity,
+        }
+    }
+
+    pub(crate) fn on_request(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_rejected(&self) {
+        self.rejected_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn on_timeout(&self) {
+        self.timed_out_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Starts tracking a generation, the kv cache usage is released when the returned value is
+    /// dropped.
+    pub(crate) fn start_generation(&self, prompt_len: usize) -> GenerationMetrics {
+        self.prompt_tokens
+            .fetch_add(prompt_len as u64, Ordering::Relaxed);
+        self.kv_cache_tokens
+            .fetch_add(prompt_len as u64, Ordering::Relaxed);
+        GenerationMetrics {
+            metrics: self.clone(),
+            kv_cache_tokens: prompt_len as u64,
+            generated: 0,
+        }
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let stats = self.scheduler.stats();
+        let max_concurrent = self.scheduler.config().max_concurrent;
+        let batch_occupancy = if max_concurrent == 0 {
+            0.
+        } else {
+            stats.running as f64 / max_concurrent as f64
+        };
+        let kv_cache_tokens = self.kv_cache_tokens.load(Ordering::Relaxed);
+        let kv_cache_utilization = self
+            .kv_cache_capacity
+            .filter(|&c| c > 0 && max_concurrent > 0)
+            .map(|c| kv_cache_tokens as f64 / (c * max_concurrent) as f64);
+        let histograms = self.histograms.lock().unwrap();
+        let tokens_per_second = if histograms.generation_seconds > 0. {
+            histograms.completed_tokens as f64 / histograms.generation_seconds
+        } else {
+            0.
+        };
+        MetricsSnapshot {
+            requests: self.requests.load(Ordering::Relaxed),
+            rejected_requests: self.rejected_requests.load(Ordering::Relaxed),
+            timed_out_requests: self.timed_out_requests.load(Ordering::Relaxed),
+            generated_tokens: self.generated_tokens.load(Ordering::Relaxed),
+            prompt_tokens: self.prompt_tokens.load(Ordering::Relaxed),
+            tokens_per_second,
+            queue_depth: stats.queued,
+            running: stats.running,
+            batch_occupancy,
+            kv_cache_tokens,
+            kv_cache_utilization,
+            time_to_first_token: histograms.time_to_first_token.clone(),
+            request_latency: histograms.request_latency.clone(),
+        }
+    }
+}
+
+/// The metrics of a running generation.
+pub(crate) struct GenerationMetrics {
+    metrics: Metrics,
+    kv_cache_tokens: u64,
+    generated: u64,
+}
+
+impl GenerationMetrics {
+    /// Records a generated token, `elapsed` being the time since the arrival of the request.
+    pub(crate) fn on_token(&mut self, elapsed: Duration) {
+        if self.generated == 0 {
+            let mut histograms = self.metrics.histograms.lock().unwrap();
+            histograms
+                .time_to_first_token
+                .observe(elapsed.as_secs_f64());
+        }
+        self.generated += 1;
+        self.kv_cache_tokens += 1;
+        self.metrics
+            .generated_tokens
+            .fetch_add(1, Ordering::Relaxed);
+        self.metrics.kv_cache_tokens.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records the completion of the request, `generation` being the time spent generating.
+    pub(crate) fn on_done(&self, latency: Duration, generation: Duration) {
+        let mut histograms = self.metrics.histograms.lock().unwrap();
+        histograms.request_latency.observe(latency.as_secs_f64());
+        histograms.generation_seconds += generation.as_secs_f64();
+        histograms.completed_tokens += self.generated;
+    }
+}
+
+impl Drop for GenerationMetrics {
+    fn drop(&mut self) {
+        self.metrics
+            .kv_cache_tokens
+            .fetch_sub(self.kv_cache_tokens, Ordering::Relaxed);
+    }
+}
+
+/// Serves the metrics in the Prometheus text format on `GET /metrics`, this only returns on
+/// errors of the listener.
+pub async fn serve(addr: std::net::SocketAddr, metrics: Metrics) -> std::io::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            // The request line and headers of a scrape fit in a single small read.
+            let mut buf = [0u8; 4096];
+            let len = socket.read(&mut buf).await?;
+            let request = String::from_utf8_lossy(&buf[..len]);
+            let response = match request.split_whitespace().take(2).collect::<Vec<_>>()[..] {
+                ["GET", "/metrics"] => {
+                    let body = metrics.snapshot().to_prometheus();
+                    format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: text/plain; version=0.0.4\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}",
+                        body.len()
+                    )
+                }
+                _ => "HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n"
+                    .to_string(),
+            };
+            socket.write_all(response.as_bytes()).await?;
+            socket.shutdown().await
+        });
+    }
+}
@@ -0,0 +1,275 @@
+//! Admission control for the generation requests.
+//!
+//! At most `max_concurrent` requests run at the same time, the others wait in a queue ordered by
+//! priority, the requests with the same priority being served in arrival order. When the queue
+//! holds `max_queue_len` requests new ones are rejected right away so that the clients can back
+//! off or retry on another replica rather than piling up. Each request can have a deadline, a
+//! request whose deadline passes while queued is removed from the queue, and the generation
+//! of a running request is cancelled when its deadline passes.
+use std::collections::BinaryHeap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tokio::time::Instant;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchedulerError {
+    /// The queue is full, the request has not been admitted.
+    QueueFull { max_queue_len: usize },
+    /// The deadline passed before the request could start.
+    DeadlineExceeded,
+}
+
+impl std::fmt::Display for SchedulerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::QueueFull { max_queue_len } => {
+                write!(
+                    f,
+                    "the request queue is full ({max_queue_len} pending requests)"
+                )
+            }
+            Self::DeadlineExceeded => write!(f, "the deadline passed while the request was queued"),
+        }
+    }
+}
+
+impl std::error::Error for SchedulerError {}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SchedulerConfig {
+    /// The maximum number of requests running at the same time.
+    pub max_concurrent: usize,
+    /// The maximum number of requests waiting to run.
+    pub max_queue_len: usize,
+    /// The timeout of the requests that do not specify one, `None` for no timeout.
+    pub default_timeout: Option<Duration>,
+}
+
+impl Default for SchedulerConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 4,
+            max_queue_len: 128,
+            default_timeout: None,
+        }
+    }
+}
+
+impl SchedulerConfig {
+    pub fn with_max_concurrent(mut self, max_concurrent: usize) -> Self {
+        self.max_concurrent = max_concurrent;
+        self
+    }
+
+    pub fn with_max_queue_len(mut self, max_queue_len: usize) -> Self {
+        self.max_queue_len = max_queue_len;
+        self
+    }
+
+    pub fn with_default_timeout(mut self, default_timeout: Option<Duration>) -> Self {
+        self.default_timeout = default_timeout;
+        self
+    }
+}
+
+struct Pending {
+    priority: i32,
+    seq: u64,
+    start: oneshot::Sender<()>,
+}
+
+impl PartialEq for Pending {
+    fn eq(&self, other: &Self) -> bool {
+        (self.priority, self.seq) == (other.priority, other.seq)
+    }
+}
+
+impl Eq for Pending {}
+
+impl PartialOrd for Pending {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Pending {
+    // The max-heap pops the highest priority first, and the oldest request for a given priority.
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.priority
+            .cmp(&other.priority)
+            .then_with(|| other.seq.cmp(&self.seq))
+    }
+}
+
+struct State {
+    queue: BinaryHeap<Pending>,
+    running: usize,
+    next_seq: u64,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    config: SchedulerConfig,
+}
+
+impl Shared {
+    /// Hands the slot of a finished request over to the next queued request, or frees it.
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        while let Some(pending) = state.queue.pop() {
+            // The send fails if the request has been cancelled in the meantime.
+            if pending.start.send(()).is_ok() {
+                return;
+            }
+        }
+        state.running -= 1
+    }
+}
+
+/// A running slot, the slot is released when the permit is dropped.
+pub struct Permit {
+    shared: Arc<Shared>,
+    deadline: Option<Instant>,
+}
+
+impl Permit {
+    /// The instant at which the request should be cancelled.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.shared.release()
+    }
+}
+
+/// The number of running and queued requests.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SchedulerStats {
+    pub running: usize,
+    pub queued: usize,
+}
+
+/// Limits the number of concurrent requests, see the module documentation.
+#[derive(Clone)]
+pub struct Scheduler {
+    shared: Arc<Shared>,
+}
+
+impl Default for Scheduler {
+    fn default() -> Self {
+        Self::new(SchedulerConfig::default())
+    }
+}
+
+impl Scheduler {
+    pub fn new(config: SchedulerConfig) -> Self {
+        let state = State {
+            queue: BinaryHeap::new(),
+            running: 0,
+            next_seq: 0,
+        };
+        let shared = Shared {
+            state: Mutex::new(state),
+            config,
+        };
+        Self {
+            shared: Arc::new(shared),
+        }
+    }
+
+    pub fn config(&self) -> &SchedulerConfig {
+        &self.shared.config
+    }
+
+    pub fn stats(&self) -> SchedulerStats {
+        let state = self.shared.state.lock().unwrap();
+        // The cancelled requests are only removed from the queue when they would start.
+        let queued = state.queue.iter().filter(|p| !p.start.is_closed()).count();
+        SchedulerStats {
+            running: state.running,
+            queued,
+        }
+    }
+
+    /// Waits for a running slot. Higher priorities are served first, `timeout` overrides the
+    /// default timeout of the scheduler and is also used to compute the permit deadline.
+    pub async fn acquire(
+        &self,
+        priority: i32,
+        timeout: Option<Duration>,
+    ) -> Result<Permit, SchedulerError> {
+        let timeout = timeout.or(self.shared.config.default_timeout);
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let permit = || Permit {
+            shared: self.shared.clone(),
+            deadline,
+        };
+        let start = {
+            let mut state = self.shared.state.lock().unwrap();
+            let config = &self.shared.config;
+            // Drop the cancelled requests so that they neither hold a queue slot nor delay the
+            // requests that could start right away.
+            state.queue.retain(|p| !p.start.is_closed());
+            if state.running < config.max_concurrent && state.queue.is_empty() {
+                state.running += 1;
+                return Ok(permit());
+            }
+            if state.queue.len() >= config.max_queue_len {
+                return Err(SchedulerError::QueueFull {
+                    max_queue_len: config.max_queue_len,
+                });
+            }
+            let (tx, rx) = oneshot::channel();
+            let seq = state.next_seq;
+            state.next_seq += 1;
+            state.queue.push(Pending {
+                priority,
+                seq,
+                start: tx,
+            });
+            rx
+        };
+        let mut waiter = Waiter {
+            start,
+            shared: self.shared.clone(),
+            started: false,
+        };
+        let started = match deadline {
+            None => (&mut waiter.start).await,
+            Some(deadline) => tokio::time::timeout_at(deadline, &mut waiter.start)
+                .await
+                .map_err(|_| SchedulerError::DeadlineExceeded)?,
+        };
+        // The sender is only dropped without sending with the scheduler, which is kept alive by
+        // the waiter.
+        if started.is_err() {
+            return Err(SchedulerError::DeadlineExceeded);
+        }
+        waiter.started = true;
+        Ok(permit())
+    }
+}
+
+/// A queued request, if it is dropped while waiting, e.g. because its deadline passed or because
+/// the client went away, the slot that may have been handed over concurrently is released.
+struct Waiter {
+    start: oneshot::Receiver<()>,
+    shared: Arc<Shared>,
+    started: bool,
+}
+
+impl Drop for Waiter {
+    fn drop(&mut self) {
+        if !self.started {
+            self.start.close();
+            if self.start.try_recv().is_ok() {
+                self.shared.release()
+            }
+        }
+    }
+}
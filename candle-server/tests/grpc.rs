@@ -0,0 +1,317 @@
+use candle::{Device, Tensor};
+use candle_server::proto::inference_server::Inference;
+use candle_server::proto::{self, generate_request, FinishReason};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use candle_server::scheduler::SchedulerError;
+use candle_server::{
+    Embedder, GenerationParams, InferenceService, ModelManager, Scheduler, SchedulerConfig,
+    TextGenerator,
+};
+use candle_transformers::generation::stream::{self, StreamConfig, TokenStream};
+use candle_transformers::generation::{LogitsProcessor, Sampling};
+use tokenizers::Tokenizer;
+use tokio_stream::StreamExt;
+use tonic::{Code, Request};
+
+const VOCAB: [&str; 6] = ["[UNK]", "a", "b", "c", "d", "</s>"];
+
+fn tokenizer() -> Tokenizer {
+    let vocab = VOCAB
+        .iter()
+        .enumerate()
+        .map(|(i, w)| format!("\"{w}\": {i}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let json = format!(
+        r#"{{
+            "version": "1.0",
+            "model": {{ "type": "WordLevel", "vocab": {{ {vocab} }}, "unk_token": "[UNK]" }},
+            "pre_tokenizer": {{ "type": "Whitespace" }}
+        }}"#
+    );
+    json.parse().unwrap()
+}
+
+/// Generates the successor of the last token, the end of sequence token being the last one.
+struct Successor(Tokenizer);
+
+impl TextGenerator for Successor {
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.0
+    }
+
+    fn eos_token_ids(&self) -> &[u32] {
+        &[5]
+    }
+
+    fn generate(&self, prompt: Vec<u32>, params: GenerationParams) -> TokenStream {
+        let forward = |xs: &Tensor, _: usize| {
+            let tokens = xs.flatten_all()?.to_vec1::<u32>()?;
+            let next = (tokens[tokens.len() - 1] + 1) % VOCAB.len() as u32;
+            let logits = (0..VOCAB.len() as u32).map(|i| if i == next { 1f32 } else { 0. });
+            Tensor::new(logits.collect::<Vec<_>>().as_slice(), &Device::Cpu)
+        };
+        let lp = LogitsProcessor::from_sampling(params.seed, params.sampling);
+        let mut stop_tokens = params.stop_tokens;
+        stop_tokens.extend_from_slice(self.eos_token_ids());
+        let config = StreamConfig::new(params.max_tokens).with_stop_tokens(stop_tokens);
+        stream::generate(forward, prompt, lp, Device::Cpu, config)
+    }
+}
+
+struct Lengths(Tokenizer);
+
+impl Embedder for Lengths {
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.0
+    }
+
+    fn embed(&self, texts: &[String]) -> candle::Result<Vec<Vec<f32>>> {
+        Ok(texts.iter().map(|t| vec![t.len() as f32, 0.]).collect())
+    }
+}
+
+async fn generate(
+    service: &InferenceService,
+    prompt: generate_request::Prompt,
+    max_tokens: u32,
+) -> Result<Vec<proto::GenerateResponse>, tonic::Status> {
+    let request = proto::GenerateRequest {
+        prompt: Some(prompt),
+        params: Some(proto::SamplingParams {
+            max_tokens,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let stream = service.generate(Request::new(request)).await?.into_inner();
+    stream.collect::<Result<Vec<_>, _>>().await
+}
+
+#[tokio::test]
+async fn grpc_service() -> anyhow::Result<()> {
+    let service = InferenceService::new()
+        .with_generator(Successor(tokenizer()))
+        .with_embedder(Lengths(tokenizer()));
+
+    let prompt = generate_request::Prompt::Text("a b".to_string());
+    let responses = generate(&service, prompt, 2).await?;
+    let tokens = responses.iter().map(|r| r.token_id).collect::<Vec<_>>();
+    assert_eq!(tokens, [3, 4]);
+    assert_eq!(responses[0].text, "c");
+    assert_eq!(responses[1].text, " d");
+    assert_eq!(responses[0].finish_reason, FinishReason::Unspecified as i32);
+    assert_eq!(responses[1].finish_reason, FinishReason::Length as i32);
+
+    // The generation stops on the end of sequence token.
+    let prompt = generate_request::Prompt::TokenIds(proto::TokenIds { ids: vec![3] });
+    let responses = generate(&service, prompt, 10).await?;
+    let tokens = responses.iter().map(|r| r.token_id).collect::<Vec<_>>();
+    assert_eq!(tokens, [4, 5]);
+    assert_eq!(responses[1].finish_reason, FinishReason::Stop as i32);
+
+    let prompt = generate_request::Prompt::TokenIds(proto::TokenIds { ids: vec![] });
+    let err = generate(&service, prompt, 10).await.unwrap_err();
+    assert_eq!(err.code(), Code::InvalidArgument);
+
+    let request = proto::TokenizeRequest {
+        text: "a c x".to_string(),
+        add_special_tokens: false,
+    };
+    let response = service.tokenize(Request::new(request)).await?.into_inner();
+    assert_eq!(response.token_ids, [1, 3, 0]);
+    assert_eq!(response.tokens, ["a", "c", "[UNK]"]);
+
+    let request = proto::EmbedRequest {
+        inputs: vec!["abc".to_string(), "a".to_string()],
+        normalize: true,
+    };
+    let response = service.embed(Request::new(request)).await?.into_inner();
+    let embeddings = response.embeddings.into_iter().map(|e| e.values);
+    assert_eq!(embeddings.collect::<Vec<_>>(), [[1., 0.], [1., 0.]]);
+
+    // The rpcs of the missing models are not implemented.
+    let service = InferenceService::new().with_embedder(Lengths(tokenizer()));
+    let prompt = generate_request::Prompt::Text("a".to_string());
+    let err = generate(&service, prompt, 10).await.unwrap_err();
+    assert_eq!(err.code(), Code::Unimplemented);
+    let request = proto::TokenizeRequest {
+        text: "b".to_string(),
+        add_special_tokens: false,
+    };
+    let response = service.tokenize(Request::new(request)).await?.into_inner();
+    assert_eq!(response.token_ids, [2]);
+    Ok(())
+}
+
+#[test]
+fn sampling_params() {
+    let service = InferenceService::new().with_default_max_tokens(32);
+    let params = service.generation_params(None);
+    assert_eq!(params.max_tokens, 32);
+    assert_eq!(params.sampling, Sampling::ArgMax);
+    assert_eq!(params.repeat_penalty, 1.);
+
+    let params = service.generation_params(Some(proto::SamplingParams {
+        max_tokens: 8,
+        temperature: 0.5,
+        top_p: 0.9,
+        top_k: 40,
+        repeat_penalty: 1.1,
+        stop_token_ids: vec![7],
+        ..Default::default()
+    }));
+    assert_eq!(params.max_tokens, 8);
+    assert_eq!(
+        params.sampling,
+        Sampling::TopKThenTopP {
+            k: 40,
+            p: 0.9f32 as f64,
+            temperature: 0.5
+        }
+    );
+    assert_eq!(params.repeat_penalty, 1.1);
+    assert_eq!(params.stop_tokens, [7]);
+}
+
+#[tokio::test]
+async fn scheduler() -> anyhow::Result<()> {
+    let config = SchedulerConfig::default()
+        .with_max_concurrent(1)
+        .with_max_queue_len(2);
+    let scheduler = Scheduler::new(config);
+    let running = scheduler.acquire(0, None).await?;
+
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut tasks = vec![];
+    for priority in [0, 1] {
+        let s = scheduler.clone();
+        let tx = tx.clone();
+        tasks.push(tokio::spawn(async move {
+            let permit = s.acquire(priority, None).await.unwrap();
+            tx.send(priority).unwrap();
+            permit
+        }));
+        while scheduler.stats().queued <= priority as usize {
+            tokio::task::yield_now().await
+        }
+    }
+    // The queue is full.
+    let err = scheduler.acquire(2, None).await.err();
+    assert_eq!(err, Some(SchedulerError::QueueFull { max_queue_len: 2 }));
+
+    // The highest priority runs first, then the slot is handed over to the remaining request.
+    drop(running);
+    assert_eq!(rx.recv().await, Some(1));
+    drop(tasks.pop().unwrap().await?);
+    assert_eq!(rx.recv().await, Some(0));
+    let permit = tasks.pop().unwrap().await?;
+
+    // A queued request whose deadline passes does not keep its queue slot.
+    let err = scheduler
+        .acquire(0, Some(Duration::from_millis(10)))
+        .await
+        .err();
+    assert_eq!(err, Some(SchedulerError::DeadlineExceeded));
+    assert_eq!(scheduler.stats().queued, 0);
+    drop(permit);
+    let permit = scheduler.acquire(0, Some(Duration::from_secs(60))).await?;
+    assert!(permit.deadline().is_some());
+    assert_eq!(scheduler.stats().running, 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn generate_timeout() -> anyhow::Result<()> {
+    let config = SchedulerConfig::default().with_max_concurrent(1);
+    let service = InferenceService::new()
+        .with_generator(Successor(tokenizer()))
+        .with_scheduler(config);
+    let request = proto::GenerateRequest {
+        prompt: Some(generate_request::Prompt::TokenIds(proto::TokenIds {
+            ids: vec![1],
+        })),
+        timeout_ms: 10,
+        ..Default::default()
+    };
+    // The only slot is taken so the request times out while queued.
+    let permit = service.scheduler().acquire(0, None).await?;
+    let err = service.generate(Request::new(request)).await.err().unwrap();
+    assert_eq!(err.code(), Code::DeadlineExceeded);
+    drop(permit);
+    assert_eq!(service.scheduler().stats().running, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn metrics() -> anyhow::Result<()> {
+    let service = InferenceService::new().with_generator(Successor(tokenizer()));
+    let prompt = generate_request::Prompt::TokenIds(proto::TokenIds { ids: vec![1, 2] });
+    generate(&service, prompt, 2).await?;
+
+    let snapshot = service.metrics().snapshot();
+    assert_eq!(snapshot.requests, 1);
+    assert_eq!(snapshot.prompt_tokens, 2);
+    assert_eq!(snapshot.generated_tokens, 2);
+    assert_eq!(snapshot.time_to_first_token.count, 1);
+    assert_eq!(snapshot.request_latency.count, 1);
+    assert_eq!(snapshot.running, 0);
+    assert_eq!(snapshot.kv_cache_tokens, 0);
+    assert_eq!(snapshot.kv_cache_utilization, None);
+
+    let text = snapshot.to_prometheus();
+    assert!(text.contains("candle_generated_tokens_total 2\n"));
+    assert!(text.contains("candle_request_latency_seconds_bucket{le=\"+Inf\"} 1\n"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn model_manager() -> anyhow::Result<()> {
+    let loads = Arc::new(AtomicUsize::new(0));
+    let models = ModelManager::new(10);
+    for name in ["x", "y", "z"] {
+        let loads = loads.clone();
+        models.register(name, 4, move || {
+            loads.fetch_add(1, Ordering::SeqCst);
+            Ok(Successor(tokenizer()))
+        });
+    }
+    // The models are only loaded on their first request.
+    assert_eq!(models.loaded_models(), Vec::<String>::new());
+    models.get("x").await.unwrap()?;
+    models.get("y").await.unwrap()?;
+    models.get("x").await.unwrap()?;
+    assert_eq!(loads.load(Ordering::SeqCst), 2);
+    assert_eq!(models.loaded_bytes(), 8);
+
+    // Loading a third model evicts the least recently used one.
+    models.get("z").await.unwrap()?;
+    assert_eq!(models.loaded_models(), ["x", "z"]);
+    assert!(models.get("w").await.is_none());
+
+    let service = InferenceService::new().with_model_manager(models.clone());
+    let request = proto::GenerateRequest {
+        prompt: Some(generate_request::Prompt::TokenIds(proto::TokenIds {
+            ids: vec![1],
+        })),
+        model: "y".to_string(),
+        ..Default::default()
+    };
+    let stream = service.generate(Request::new(request)).await?.into_inner();
+    let responses = stream.collect::<Result<Vec<_>, _>>().await?;
+    assert_eq!(responses[0].token_id, 2);
+    assert_eq!(models.loaded_models(), ["y", "z"]);
+    assert_eq!(loads.load(Ordering::SeqCst), 4);
+
+    let request = proto::GenerateRequest {
+        prompt: Some(generate_request::Prompt::Text("a".to_string())),
+        model: "w".to_string(),
+        ..Default::default()
+    };
+    let err = service.generate(Request::new(request)).await.err().unwrap();
+    assert_eq!(err.code(), Code::NotFound);
+    Ok(())
+}
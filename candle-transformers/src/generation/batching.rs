@@ -0,0 +1,378 @@
+//! Continuous batching, i.e. iteration-level scheduling, for serving text generation.
+//!
+//! Rather than running each request to completion, or waiting for a whole batch of requests to
+//! finish before starting the next one, a [`Batcher`] runs a single decode step for all the
+//! running sequences at once. New sequences are admitted between the steps, their prompt being
+//! processed on its own before they join the batch, and the finished sequences are evicted right
+//! away so that their slot and their kv cache are available to the waiting ones. See "Orca: A
+//! Distributed Serving System for Transformer-Based Generative Models"
+//! <https://www.usenix.org/conference/osdi22/presentation/yu>.
+//!
+//! The model is plugged in through the [`BatchedLM`] trait, which is implemented for
+//! [`quantized_llama::ModelWeights`](crate::models::quantized_llama::ModelWeights).
+//! [`stream::ContinuousBatcher`](super::stream::ContinuousBatcher) runs a batcher on its own
+//! thread and returns the tokens of each request as an async stream.
+//!
+//! ```rust,no_run
+//! use candle::Device;
+//! use candle_transformers::generation::batching::{Batcher, BatchingConfig};
+//! use candle_transformers::generation::events::GenerationConfig;
+//! use candle_transformers::generation::LogitsProcessor;
+//! use candle_transformers::models::quantized_llama::ModelWeights;
+//! # fn run(model: ModelWeights) -> candle::Result<()> {
+//! let config = BatchingConfig::default().with_max_batch_size(8);
+//! let mut batcher = Batcher::new(model, Device::Cpu, config);
+//! for prompt in [vec![1, 15043], vec![1, 3681, 338]] {
+//!     let lp = LogitsProcessor::new(42, Some(0.8), None);
+//!     let config = GenerationConfig::new(32).with_stop_tokens(vec![2]);
+//!     batcher.add(prompt, lp, config, |token: candle::Result<u32>| {
+//!         println!("{token:?}");
+//!         true
+//!     });
+//! }
+//! while !batcher.is_idle() {
+//!     batcher.step();
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use super::events::GenerationConfig;
+use super::LogitsProcessor;
+use candle::{DType, Device, Result, Tensor};
+use std::collections::VecDeque;
+
+/// A causal language model processing a batch of sequences, each of them with its own kv cache.
+pub trait BatchedLM {
+    type Cache;
+
+    /// A new empty cache for a sequence.
+    fn new_cache(&self) -> Self::Cache;
+
+    /// Runs the model on the tokens `input_ids` of shape `(1, seq_len)` of a single sequence,
+    /// starting at position `index_pos`, and returns the logits for the last position with shape
+    /// `(1, vocab)` or `(vocab,)`. This is used to process the prompts.
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        index_pos: usize,
+        cache: &mut Self::Cache,
+    ) -> Result<Tensor>;
+
+    /// Runs the model on one token per sequence, `input_ids` having shape `(batch, 1)`, the token
+    /// of row `i` being at position `index_pos[i]`, and returns the logits with shape
+    /// `(batch, vocab)`.
+    fn forward_batch(
+        &self,
+        input_ids: &Tensor,
+        index_pos: &[usize],
+        caches: &mut [Self::Cache],
+    ) -> Result<Tensor>;
+}
+
+impl<M: BatchedLM + ?Sized> BatchedLM for std::sync::Arc<M> {
+    type Cache = M::Cache;
+
+    fn new_cache(&self) -> Self::Cache {
+        (**self).new_cache()
+    }
+
+    fn forward(
+        &self,
+        input_ids: &Tensor,
+        index_pos: usize,
+        cache: &mut Self::Cache,
+    ) -> Result<Tensor> {
+        (**self).forward(input_ids, index_pos, cache)
+    }
+
+    fn forward_batch(
+        &self,
+        input_ids: &Tensor,
+        index_pos: &[usize],
+        caches: &mut [Self::Cache],
+    ) -> Result<Tensor> {
+        (**self).forward_batch(input_ids, index_pos, caches)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchingConfig {
+    /// The maximum number of sequences decoded together.
+    pub max_batch_size: usize,
+    /// The maximum number of positions, prompt and generated tokens, that the running sequences
+    /// can use in total, `None` for no limit. The positions of a sequence are reserved when it
+    /// is admitted, a sequence exceeding the limit on its own is admitted when nothing else runs.
+    pub max_batch_tokens: Option<usize>,
+    /// The maximum number of prompts processed between two decode steps, so that a burst of new
+    /// requests does not stall the running sequences for too long.
+    pub max_prefills_per_step: usize,
+}
+
+impl Default for BatchingConfig {
+    fn default() -> Self {
+        Self {
+            max_batch_size: 8,
+            max_batch_tokens: None,
+            max_prefills_per_step: 1,
+        }
+    }
+}
+
+impl BatchingConfig {
+    pub fn with_max_batch_size(mut self, max_batch_size: usize) -> Self {
+        self.max_batch_size = max_batch_size;
+        self
+    }
+
+    pub fn with_max_batch_tokens(mut self, max_batch_tokens: Option<usize>) -> Self {
+        self.max_batch_tokens = max_batch_tokens;
+        self
+    }
+
+    pub fn with_max_prefills_per_step(mut self, max_prefills_per_step: usize) -> Self {
+        self.max_prefills_per_step = max_prefills_per_step;
+        self
+    }
+}
+
+type OnToken = Box<dyn FnMut(Result<u32>) -> bool + Send>;
+
+struct Sequence {
+    /// The prompt followed by the sampled tokens.
+    tokens: Vec<u32>,
+    prompt_len: usize,
+    logits_processor: LogitsProcessor,
+    config: GenerationConfig,
+    on_token: OnToken,
+}
+
+impl Sequence {
+    /// The number of positions reserved for the sequence.
+    fn reserved_len(&self) -> usize {
+        self.prompt_len + self.config.max_new_tokens
+    }
+
+    fn sample(&mut self, logits: Tensor) -> Result<u32> {
+        let logits = match logits.rank() {
+            1 => logits,
+            2 => logits.squeeze(0)?,
+            _ => candle::bail!("batcher: unexpected logits shape {:?}", logits.shape()),
+        };
+        let logits = logits.to_dtype(DType::F32)?;
+        let config = &self.config;
+        let logits = if config.repeat_penalty == 1. {
+            logits
+        } else {
+            let start_at = self.tokens.len().saturating_sub(config.repeat_last_n);
+            crate::utils::apply_repeat_penalty(
+                &logits,
+                config.repeat_penalty,
+                &self.tokens[start_at..],
+            )?
+        };
+        self.logits_processor.sample(&logits)
+    }
+
+    /// Reports a sampled token and returns whether the generation should continue.
+    fn push(&mut self, token: u32) -> bool {
+        self.tokens.push(token);
+        (self.on_token)(Ok(token))
+            && !self.config.stop_tokens.contains(&token)
+            && self.tokens.len() - self.prompt_len < self.config.max_new_tokens
+    }
+}
+
+/// Schedules the sequences of multiple requests on a model, see the module documentation.
+///
+/// The tokens of each sequence are reported to its `on_token` callback as they are sampled. When
+/// the model or the sampling fails, the error is reported as the last item and the sequence is
+/// evicted, a failure of a batched decode step evicting all the sequences of the batch.
+pub struct Batcher<M: BatchedLM> {
+    model: M,
+    device: Device,
+    config: BatchingConfig,
+    waiting: VecDeque<Sequence>,
+    // The running sequences and their caches, at the same index in both vectors so that the
+    // caches can be passed to the model as a slice.
+    running: Vec<Sequence>,
+    caches: Vec<M::Cache>,
+}
+
+impl<M: BatchedLM> Batcher<M> {
+    pub fn new(model: M, device: Device, config: BatchingConfig) -> Self {
+        Self {
+            model,
+            device,
+            config,
+            waiting: VecDeque::new(),
+            running: vec![],
+            caches: vec![],
+        }
+    }
+
+    pub fn config(&self) -> &BatchingConfig {
+        &self.config
+    }
+
+    pub fn model(&self) -> &M {
+        &self.model
+    }
+
+    /// Queues a request, it is admitted by a later [`Self::step`] in arrival order. `on_token`
+    /// is called with each sampled token, returning `false` cancels the request.
+    pub fn add<F>(
+        &mut self,
+        prompt: Vec<u32>,
+        logits_processor: LogitsProcessor,
+        config: GenerationConfig,
+        on_token: F,
+    ) where
+        F: FnMut(Result<u32>) -> bool + Send + 'static,
+    {
+        let prompt_len = prompt.len();
+        self.waiting.push_back(Sequence {
+            tokens: prompt,
+            prompt_len,
+            logits_processor,
+            config,
+            on_token: Box::new(on_token),
+        })
+    }
+
+    pub fn num_running(&self) -> usize {
+        self.running.len()
+    }
+
+    pub fn num_waiting(&self) -> usize {
+        self.waiting.len()
+    }
+
+    /// Whether there are neither running nor waiting sequences.
+    pub fn is_idle(&self) -> bool {
+        self.running.is_empty() && self.waiting.is_empty()
+    }
+
+    /// Admits the waiting sequences that fit in the batch, processing their prompts, then
+    /// samples one token for each running sequence and evicts the finished ones.
+    pub fn step(&mut self) {
+        self.admit();
+        self.decode();
+    }
+
+    fn reserved_len(&self) -> usize {
+        self.running.iter().map(|s| s.reserved_len()).sum()
+    }
+
+    fn admit(&mut self) {
+        let mut prefills = 0;
+        while prefills < self.config.max_prefills_per_step
+            && self.running.len() < self.config.max_batch_size
+        {
+            let seq = match self.waiting.front() {
+                None => break,
+                Some(seq) => seq,
+            };
+            // The sequences are admitted in order so that a long request is not starved by the
+            // shorter ones.
+            if let Some(max_batch_tokens) = self.config.max_batch_tokens {
+                if !self.running.is_empty()
+                    && self.reserved_len() + seq.reserved_len() > max_batch_tokens
+                {
+                    break;
+                }
+            }
+            let mut seq = match self.waiting.pop_front() {
+                None => break,
+                Some(seq) => seq,
+            };
+            prefills += 1;
+            let mut cache = self.model.new_cache();
+            match self.prefill(&mut seq, &mut cache) {
+                Ok(true) => {
+                    self.running.push(seq);
+                    self.caches.push(cache)
+                }
+                Ok(false) => {}
+                Err(err) => {
+                    (seq.on_token)(Err(err));
+                }
+            }
+        }
+    }
+
+    /// Processes the prompt of a sequence and samples its first token, returns whether the
+    /// sequence should keep running.
+    fn prefill(&self, seq: &mut Sequence, cache: &mut M::Cache) -> Result<bool> {
+        if seq.prompt_len == 0 {
+            candle::bail!("batcher: the prompt is empty")
+        }
+        if seq.config.max_new_tokens == 0 {
+            return Ok(false);
+        }
+        let chunk_size = match seq.config.prompt_chunk_size {
+            Some(0) => candle::bail!("batcher: the prompt chunk size must be positive"),
+            Some(chunk_size) => chunk_size,
+            None => seq.prompt_len,
+        };
+        let mut logits = None;
+        for (i, chunk) in seq.tokens.chunks(chunk_size).enumerate() {
+            let input_ids = Tensor::new(chunk, &self.device)?.unsqueeze(0)?;
+            logits = Some(self.model.forward(&input_ids, i * chunk_size, cache)?);
+        }
+        let logits = match logits {
+            Some(logits) => logits,
+            None => candle::bail!("batcher: the prompt is empty"),
+        };
+        let token = seq.sample(logits)?;
+        Ok(seq.push(token))
+    }
+
+    fn decode(&mut self) {
+        if self.running.is_empty() {
+            return;
+        }
+        let logits = match self.forward_batch() {
+            Ok(logits) => logits,
+            Err(err) => {
+                // The error is not `Clone`, each sequence gets its message.
+                let err = err.to_string();
+                for mut seq in self.running.drain(..) {
+                    (seq.on_token)(Err(candle::Error::Msg(err.clone())));
+                }
+                self.caches.clear();
+                return;
+            }
+        };
+        let mut finished = vec![];
+        for (i, seq) in self.running.iter_mut().enumerate() {
+            match logits.get(i).and_then(|logits| seq.sample(logits)) {
+                Ok(token) => {
+                    if !seq.push(token) {
+                        finished.push(i)
+                    }
+                }
+                Err(err) => {
+                    (seq.on_token)(Err(err));
+                    finished.push(i)
+                }
+            }
+        }
+        // Evicting from the back keeps the indexes of the remaining finished sequences valid.
+        for &i in finished.iter().rev() {
+            self.running.swap_remove(i);
+            self.caches.swap_remove(i);
+        }
+    }
+
+    fn forward_batch(&mut self) -> Result<Tensor> {
+        let last_tokens: Vec<u32> = self
+            .running
+            .iter()
+            .map(|s| s.tokens[s.tokens.len() - 1])
+            .collect();
+        let index_pos: Vec<usize> = self.running.iter().map(|s| s.tokens.len() - 1).collect();
+        let input_ids = Tensor::new(last_tokens.as_slice(), &self.device)?.unsqueeze(1)?;
+        self.model
+            .forward_batch(&input_ids, &index_pos, &mut self.caches)
+    }
+}
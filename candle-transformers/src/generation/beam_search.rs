@@ -0,0 +1,197 @@
+//! Beam search decoding.
+//!
+//! Rather than sampling a single sequence, beam search keeps the `num_beams` most likely partial
+//! sequences at each step and returns the best finished ones. This is the usual decoding method
+//! for translation and summarization models like T5.
+//!
+//! The running beams diverge from each other so they cannot share a kv cache: at each step the
+//! model is called with the whole sequence of each beam, the prompt followed by the tokens
+//! generated so far, at position 0. The model should start from an empty kv cache when called
+//! at position 0.
+//!
+//! ```rust,no_run
+//! use candle::{Device, Tensor};
+//! use candle_transformers::generation::beam_search::{beam_search, BeamSearchConfig};
+//! # fn run(
+//! #     mut forward: impl FnMut(&Tensor, usize) -> candle::Result<Tensor>,
+//! # ) -> candle::Result<()> {
+//! let config = BeamSearchConfig::new(4, 64)
+//!     .with_eos_tokens(vec![1])
+//!     .with_length_penalty(1.0);
+//! let hypotheses = beam_search(&mut forward, &[0], &Device::Cpu, &config)?;
+//! println!("{:?} {}", hypotheses[0].tokens, hypotheses[0].score);
+//! # Ok(())
+//! # }
+//! ```
+use super::ensemble::CausalLM;
+use candle::{DType, Device, Result, Tensor, D};
+
+/// The beam search parameters.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BeamSearchConfig {
+    /// The number of running beams, this is also the maximum number of returned hypotheses.
+    pub num_beams: usize,
+    pub max_new_tokens: usize,
+    /// The hypotheses are ranked by their log-probability divided by `len ^ length_penalty`,
+    /// positive values favor longer sequences and negative values shorter ones.
+    pub length_penalty: f64,
+    /// Stops as soon as `num_beams` hypotheses have finished. Otherwise the search continues
+    /// while a running beam can still get a better score than the finished hypotheses.
+    pub early_stopping: bool,
+    /// The tokens finishing an hypothesis, they are included in its tokens.
+    pub eos_tokens: Vec<u32>,
+}
+
+impl BeamSearchConfig {
+    pub fn new(num_beams: usize, max_new_tokens: usize) -> Self {
+        Self {
+            num_beams,
+            max_new_tokens,
+            length_penalty: 1.,
+            early_stopping: false,
+            eos_tokens: vec![],
+        }
+    }
+
+    pub fn with_length_penalty(mut self, length_penalty: f64) -> Self {
+        self.length_penalty = length_penalty;
+        self
+    }
+
+    pub fn with_early_stopping(mut self, early_stopping: bool) -> Self {
+        self.early_stopping = early_stopping;
+        self
+    }
+
+    pub fn with_eos_tokens(mut self, eos_tokens: Vec<u32>) -> Self {
+        self.eos_tokens = eos_tokens;
+        self
+    }
+
+    /// The ranking score of an hypothesis of `len` tokens.
+    fn score(&self, log_prob: f64, len: usize) -> f64 {
+        log_prob / (len.max(1) as f64).powf(self.length_penalty)
+    }
+}
+
+/// A generated sequence.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Hypothesis {
+    /// The generated tokens, ending with an end of sequence token unless the search reached
+    /// `max_new_tokens` first.
+    pub tokens: Vec<u32>,
+    /// The sum of the log-probabilities of the tokens.
+    pub log_prob: f64,
+    /// The log-probability normalized with the length penalty.
+    pub score: f64,
+}
+
+/// The `k` tokens with the largest log-probabilities, in no particular order.
+fn top_k(log_probs: &[f32], k: usize) -> Vec<(u32, f32)> {
+    let mut indices: Vec<usize> = (0..log_probs.len()).collect();
+    if k < indices.len() {
+        indices.select_nth_unstable_by(k, |&i, &j| log_probs[j].total_cmp(&log_probs[i]));
+        indices.truncate(k)
+    }
+    indices
+        .into_iter()
+        .map(|i| (i as u32, log_probs[i]))
+        .collect()
+}
+
+/// Runs a beam search after `prompt` and returns up to `config.num_beams` hypotheses, best
+/// first.
+///
+/// `model` returns the logits for the last position of its input, with a batch size of one.
+pub fn beam_search<M: CausalLM>(
+    model: &mut M,
+    prompt: &[u32],
+    device: &Device,
+    config: &BeamSearchConfig,
+) -> Result<Vec<Hypothesis>> {
+    let num_beams = config.num_beams;
+    if num_beams == 0 {
+        candle::bail!("beam search: the number of beams must be positive")
+    }
+    if prompt.is_empty() {
+        candle::bail!("beam search: empty prompt")
+    }
+    // The running beams, sorted by decreasing log-probability.
+    let mut beams: Vec<(Vec<u32>, f64)> = vec![(vec![], 0.)];
+    let mut finished: Vec<Hypothesis> = vec![];
+    let mut done = false;
+    for step in 0..config.max_new_tokens {
+        let mut candidates = vec![];
+        for (beam_idx, (tokens, log_prob)) in beams.iter().enumerate() {
+            let input = [prompt, tokens.as_slice()].concat();
+            let input = Tensor::new(input.as_slice(), device)?.unsqueeze(0)?;
+            let logits = model
+                .forward(&input, 0)?
+                .flatten_all()?
+                .to_dtype(DType::F32)?;
+            let log_probs = candle_nn::ops::log_softmax(&logits, D::Minus1)?.to_vec1::<f32>()?;
+            // Twice as many candidates as beams are kept so that enough of them continue when
+            // some finish.
+            for (token, lp) in top_k(&log_probs, 2 * num_beams) {
+                candidates.push((beam_idx, token, log_prob + lp as f64))
+            }
+        }
+        candidates.sort_by(|a, b| b.2.total_cmp(&a.2));
+
+        let mut next_beams = Vec::with_capacity(num_beams);
+        for (rank, &(beam_idx, token, log_prob)) in candidates.iter().enumerate() {
+            let mut tokens = beams[beam_idx].0.clone();
+            tokens.push(token);
+            if config.eos_tokens.contains(&token) {
+                // An end of sequence token only finishes an hypothesis if it is among the best
+                // candidates.
+                if rank < num_beams {
+                    let score = config.score(log_prob, step + 1);
+                    finished.push(Hypothesis {
+                        tokens,
+                        log_prob,
+                        score,
+                    })
+                }
+            } else {
+                next_beams.push((tokens, log_prob));
+                if next_beams.len() == num_beams {
+                    break;
+                }
+            }
+        }
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(num_beams);
+        beams = next_beams;
+
+        if finished.len() == num_beams {
+            done = config.early_stopping || {
+                // The log-probabilities can only decrease so this bounds the score of the
+                // running beams, a positive length penalty favoring the longest sequences.
+                let best = beams.first().map_or(f64::NEG_INFINITY, |b| b.1);
+                let len = if config.length_penalty > 0. {
+                    config.max_new_tokens
+                } else {
+                    step + 1
+                };
+                config.score(best, len) <= finished[num_beams - 1].score
+            }
+        }
+        if done || beams.is_empty() {
+            break;
+        }
+    }
+    if !done {
+        for (tokens, log_prob) in beams {
+            let score = config.score(log_prob, tokens.len());
+            finished.push(Hypothesis {
+                tokens,
+                log_prob,
+                score,
+            })
+        }
+        finished.sort_by(|a, b| b.score.total_cmp(&a.score));
+        finished.truncate(num_beams);
+    }
+    Ok(finished)
+}
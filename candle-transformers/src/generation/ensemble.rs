@@ -0,0 +1,267 @@
+//! Combining the logits of multiple models, e.g. different quantizations or fine-tunes of the
+//! same base model, that are run in lockstep on the same tokens.
+use candle::{DType, Result, Tensor, D};
+use std::collections::HashMap;
+
+/// A causal language model returning the logits for its last input position.
+///
+/// This is implemented for closures so that models with different forward signatures can be
+/// used, e.g. `|xs: &Tensor, pos: usize| model.forward(xs, pos, &mut cache)`. The logits can
+/// have shape `(vocab,)` or `(1, vocab)`.
+pub trait CausalLM {
+    fn forward(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor>;
+}
+
+impl<F: FnMut(&Tensor, usize) -> Result<Tensor>> CausalLM for F {
+    fn forward(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        self(input_ids, index_pos)
+    }
+}
+
+/// How the predictions of the ensemble members are combined.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Combination {
+    /// The weighted average of the temperature scaled logits. As the log-softmax only shifts the
+    /// logits, this is equivalent to a product of experts where each distribution is raised to
+    /// the power of its weight.
+    LogitAverage,
+    /// The weighted average of the probabilities, i.e. a mixture of the models.
+    ProbabilityAverage,
+    /// Contrastive decoding between an expert, the first member, and an amateur, the second
+    /// member. See [`contrastive_logits`], the weight of the amateur is used as `beta`.
+    Contrastive { alpha: f64 },
+}
+
+struct Member<'a> {
+    model: Box<dyn CausalLM + 'a>,
+    weight: f64,
+    temperature: f64,
+}
+
+/// Runs multiple models on the same inputs and combines their logits.
+///
+/// All the models are called with the same tokens and positions so that their kv caches stay in
+/// sync. The models must share a tokenizer, this can be checked with [`check_shared_vocab`].
+/// When the models have different vocabulary sizes, e.g. because of embeddings padded to a
+/// multiple of 64, only the common prefix of the vocabulary is kept.
+///
+/// The combined logits are log-probabilities that can be passed to a
+/// [`LogitsProcessor`](super::LogitsProcessor).
+pub struct Ensemble<'a> {
+    members: Vec<Member<'a>>,
+    combination: Combination,
+}
+
+impl<'a> Ensemble<'a> {
+    pub fn new(combination: Combination) -> Self {
+        Self {
+            members: vec![],
+            combination,
+        }
+    }
+
+    /// Adds a model with the given weight and temperature, the logits of the model are divided by
+    /// the temperature before being combined.
+    pub fn with_model<M: CausalLM + 'a>(mut self, model: M, weight: f64, temperature: f64) -> Self {
+        self.members.push(Member {
+            model: Box::new(model),
+            weight,
+            temperature,
+        });
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    pub fn combination(&self) -> Combination {
+        self.combination
+    }
+
+    /// Runs all the models on `input_ids` and returns the combined logits as a 1D tensor.
+    pub fn forward(&mut self, input_ids: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let mut logits = Vec::with_capacity(self.members.len());
+        for member in self.members.iter_mut() {
+            let l = member.model.forward(input_ids, index_pos)?;
+            let l = match l.rank() {
+                1 => l,
+                2 => l.squeeze(0)?,
+                _ => candle::bail!("ensemble: unexpected logits shape {:?}", l.shape()),
+            };
+            logits.push(l.to_dtype(DType::F32)?)
+        }
+        let weights = self.members.iter().map(|m| m.weight).collect::<Vec<_>>();
+        let temperatures = self
+            .members
+            .iter()
+            .map(|m| m.temperature)
+            .collect::<Vec<_>>();
+        combine_logits(&logits, &weights, &temperatures, self.combination)
+    }
+}
+
+/// Combines 1D logits from multiple models, see [`Combination`].
+pub fn combine_logits(
+    logits: &[Tensor],
+    weights: &[f64],
+    temperatures: &[f64],
+    combination: Combination,
+) -> Result<Tensor> {
+    if logits.is_empty() || logits.len() != weights.len() || logits.len() != temperatures.len() {
+        candle::bail!(
+            "combine_logits: got {} logits, {} weights and {} temperatures",
+            logits.len(),
+            weights.len(),
+            temperatures.len()
+        )
+    }
+    let vocab_size = logits
+        .iter()
+        .map(|l| l.dim(D::Minus1))
+        .collect::<Result<Vec<_>>>()?
+        .into_iter()
+        .min()
+        .unwrap_or(0);
+    let log_prs = logits
+        .iter()
+        .zip(temperatures.iter())
+        .map(|(l, &t)| {
+            let l = l.narrow(D::Minus1, 0, vocab_size)?.to_dtype(DType::F32)?;
+            candle_nn::ops::log_softmax(&(l / t)?, D::Minus1)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    let total_weight: f64 = weights.iter().sum();
+    match combination {
+        Combination::LogitAverage => {
+            let mut sum = (&log_prs[0] * (weights[0] / total_weight))?;
+            for (l, &w) in log_prs.iter().zip(weights.iter()).skip(1) {
+                sum = (sum + (l * (w / total_weight))?)?
+            }
+            candle_nn::ops::log_softmax(&sum, D::Minus1)
+        }
+        Combination::ProbabilityAverage => {
+            let mut sum = (log_prs[0].exp()? * (weights[0] / total_weight))?;
+            for (l, &w) in log_prs.iter().zip(weights.iter()).skip(1) {
+                sum = (sum + (l.exp()? * (w / total_weight))?)?
+            }
+            sum.log()
+        }
+        Combination::Contrastive { alpha } => {
+            if log_prs.len() != 2 {
+                candle::bail!(
+                    "contrastive decoding requires two models, got {}",
+                    log_prs.len()
+                )
+            }
+            contrastive_logits(&log_prs[0], &log_prs[1], alpha, weights[1])
+        }
+    }
+}
+
+/// The contrastive decoding scores from "Contrastive Decoding: Open-ended Text Generation as
+/// Optimization", <https://arxiv.org/abs/2210.15097>.
+///
+/// The score of a token is `log p_expert - beta * log p_amateur`. To avoid promoting
+/// implausible tokens that the amateur just happens to dislike even more, only the tokens with
+/// `p_expert >= alpha * max p_expert` are kept, the others get a score of `-inf`.
+///
+/// The inputs are log-probabilities with the vocabulary as last dimension.
+pub fn contrastive_logits(
+    expert_log_prs: &Tensor,
+    amateur_log_prs: &Tensor,
+    alpha: f64,
+    beta: f64,
+) -> Result<Tensor> {
+    let scores = (expert_log_prs - (amateur_log_prs * beta)?)?;
+    // The plausibility constraint, in log space.
+    let threshold = (expert_log_prs.max_keepdim(D::Minus1)? + alpha.ln())?;
+    let plausible = expert_log_prs.broadcast_ge(&threshold)?;
+    let neg_inf = Tensor::full(f32::NEG_INFINITY, scores.shape(), scores.device())?
+        .to_dtype(scores.dtype())?;
+    plausible.where_cond(&scores, &neg_inf)
+}
+
+/// Contrastive decoding as a decoding strategy: the next token logits of a large "expert" model
+/// are contrasted with the ones of a small "amateur" model, e.g. a small quantized model of the
+/// same family, to penalize the generic or repetitive continuations that the amateur also
+/// predicts. See [`contrastive_logits`] for the details.
+///
+/// The defaults, `alpha = 0.1` and `beta = 1`, are the values used in the original paper.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ContrastiveDecoding {
+    alpha: f64,
+    beta: f64,
+    amateur_temperature: f64,
+}
+
+impl Default for ContrastiveDecoding {
+    fn default() -> Self {
+        Self::new(0.1, 1.0)
+    }
+}
+
+impl ContrastiveDecoding {
+    pub fn new(alpha: f64, beta: f64) -> Self {
+        Self {
+            alpha,
+            beta,
+            amateur_temperature: 1.0,
+        }
+    }
+
+    /// A temperature applied to the amateur logits, higher values make the amateur distribution
+    /// flatter and hence the contrast weaker. Defaults to 1.
+    pub fn with_amateur_temperature(mut self, temperature: f64) -> Self {
+        self.amateur_temperature = temperature;
+        self
+    }
+
+    pub fn alpha(&self) -> f64 {
+        self.alpha
+    }
+
+    pub fn beta(&self) -> f64 {
+        self.beta
+    }
+
+    /// Computes the contrastive scores from the expert and amateur logits, these scores can then
+    /// be sampled from with a [`LogitsProcessor`](super::LogitsProcessor). The implausible
+    /// tokens have a score of `-inf`.
+    pub fn logits(&self, expert_logits: &Tensor, amateur_logits: &Tensor) -> Result<Tensor> {
+        combine_logits(
+            &[expert_logits.clone(), amateur_logits.clone()],
+            &[1.0, self.beta],
+            &[1.0, self.amateur_temperature],
+            Combination::Contrastive { alpha: self.alpha },
+        )
+    }
+}
+
+/// Checks that two tokenizer vocabularies, as returned by `Tokenizer::get_vocab(true)`, map the
+/// same tokens to the same ids. Logits from the corresponding models can only be combined if
+/// this is the case.
+pub fn check_shared_vocab(
+    vocab1: &HashMap<String, u32>,
+    vocab2: &HashMap<String, u32>,
+) -> Result<()> {
+    if vocab1.len() != vocab2.len() {
+        candle::bail!(
+            "the tokenizers have different vocabulary sizes, {} vs {}",
+            vocab1.len(),
+            vocab2.len()
+        )
+    }
+    for (token, id1) in vocab1.iter() {
+        match vocab2.get(token) {
+            Some(id2) if id1 == id2 => {}
+            Some(id2) => candle::bail!("token {token:?} has id {id1} and {id2} in the tokenizers"),
+            None => candle::bail!("token {token:?} is missing from the second tokenizer"),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,372 @@
+//! Progress callbacks for text generation.
+//!
+//! [`generate`] runs a blocking generation loop and reports its progress through a
+//! [`GenerationEvents`] implementation, so that a GUI or a TUI can render a progress bar while a
+//! long prompt is processed and display the tokens as they are sampled, rather than parsing the
+//! output of the examples. [`TextGeneration`] bundles a model with its sampling configuration
+//! for applications running several generations.
+//!
+//! ```rust,no_run
+//! use candle::{Device, Tensor};
+//! use candle_transformers::generation::events::{generate, GenerationConfig, GenerationEvents};
+//! use candle_transformers::generation::{GenerationReport, LogitsProcessor};
+//!
+//! struct Progress;
+//!
+//! impl GenerationEvents for Progress {
+//!     fn on_prompt_progress(&mut self, processed: usize, total: usize) {
+//!         println!("prompt {processed}/{total}")
+//!     }
+//!
+//!     fn on_token(&mut self, token: u32, _latency: std::time::Duration) -> bool {
+//!         println!("token {token}");
+//!         true
+//!     }
+//!
+//!     fn on_done(&mut self, report: &GenerationReport) {
+//!         println!("{report}")
+//!     }
+//! }
+//! # fn run(
+//! #     mut forward: impl FnMut(&Tensor, usize) -> candle::Result<Tensor>,
+//! # ) -> candle::Result<()> {
+//! let config = GenerationConfig::new(64).with_prompt_chunk_size(Some(512));
+//! let mut lp = LogitsProcessor::new(42, Some(0.8), None);
+//! let prompt = [1, 15043];
+//! let tokens = generate(&mut forward, &prompt, &mut lp, &Device::Cpu, &config, &mut Progress)?;
+//! # Ok(())
+//! # }
+//! ```
+use super::ensemble::CausalLM;
+use super::{GenerationReport, GenerationTimer, LogitsProcessor};
+use candle::{Device, Result, Tensor};
+use std::time::{Duration, Instant};
+
+/// Receives the progress of a generation, all the methods do nothing by default.
+pub trait GenerationEvents {
+    /// Called after each prompt chunk has been processed, with the number of processed prompt
+    /// tokens and the prompt length.
+    fn on_prompt_progress(&mut self, _processed: usize, _total: usize) {}
+
+    /// Called for each sampled token with the time taken to produce it, for the first token this
+    /// includes the prompt processing. Returning `false` stops the generation.
+    fn on_token(&mut self, _token: u32, _latency: Duration) -> bool {
+        true
+    }
+
+    /// Called once the generation has completed, successfully or not.
+    fn on_done(&mut self, _report: &GenerationReport) {}
+}
+
+impl GenerationEvents for () {}
+
+/// Calls a closure with each sampled token, the generation stops when it returns `false`.
+///
+/// ```rust
+/// use candle_transformers::generation::events::OnToken;
+/// let mut text = String::new();
+/// let events = OnToken(|token: u32| {
+///     text.push_str(&format!("{token} "));
+///     true
+/// });
+/// ```
+pub struct OnToken<F>(pub F);
+
+impl<F: FnMut(u32) -> bool> GenerationEvents for OnToken<F> {
+    fn on_token(&mut self, token: u32, _latency: Duration) -> bool {
+        (self.0)(token)
+    }
+}
+
+/// Sends the sampled tokens to a channel, e.g. to a thread serving a web UI, the generation
+/// stops when the receiver has been dropped.
+impl GenerationEvents for std::sync::mpsc::Sender<u32> {
+    fn on_token(&mut self, token: u32, _latency: Duration) -> bool {
+        self.send(token).is_ok()
+    }
+}
+
+/// Similar to the `Sender` implementation, blocking when the channel is full.
+impl GenerationEvents for std::sync::mpsc::SyncSender<u32> {
+    fn on_token(&mut self, token: u32, _latency: Duration) -> bool {
+        self.send(token).is_ok()
+    }
+}
+
+impl<E: GenerationEvents + ?Sized> GenerationEvents for &mut E {
+    fn on_prompt_progress(&mut self, processed: usize, total: usize) {
+        (**self).on_prompt_progress(processed, total)
+    }
+
+    fn on_token(&mut self, token: u32, latency: Duration) -> bool {
+        (**self).on_token(token, latency)
+    }
+
+    fn on_done(&mut self, report: &GenerationReport) {
+        (**self).on_done(report)
+    }
+}
+
+/// When to stop the generation, and how the prompt is split.
+#[derive(Debug, Clone, PartialEq)]
+pub struct GenerationConfig {
+    pub max_new_tokens: usize,
+    /// The generation stops after one of these tokens has been sampled, the stop token is
+    /// included in the output.
+    pub stop_tokens: Vec<u32>,
+    /// The prompt is processed in chunks of this size, reporting the progress after each chunk.
+    /// The whole prompt is processed at once when `None`.
+    pub prompt_chunk_size: Option<usize>,
+    /// The penalty applied to the logits of the tokens in the last `repeat_last_n` tokens of the
+    /// prompt and of the output, 1 meaning no penalty.
+    pub repeat_penalty: f32,
+    pub repeat_last_n: usize,
+}
+
+impl GenerationConfig {
+    pub fn new(max_new_tokens: usize) -> Self {
+        Self {
+            max_new_tokens,
+            stop_tokens: vec![],
+            prompt_chunk_size: None,
+            repeat_penalty: 1.,
+            repeat_last_n: 64,
+        }
+    }
+
+    pub fn with_stop_tokens(mut self, stop_tokens: Vec<u32>) -> Self {
+        self.stop_tokens = stop_tokens;
+        self
+    }
+
+    pub fn with_prompt_chunk_size(mut self, prompt_chunk_size: Option<usize>) -> Self {
+        self.prompt_chunk_size = prompt_chunk_size;
+        self
+    }
+
+    pub fn with_repeat_penalty(mut self, repeat_penalty: f32, repeat_last_n: usize) -> Self {
+        self.repeat_penalty = repeat_penalty;
+        self.repeat_last_n = repeat_last_n;
+        self
+    }
+}
+
+fn logits_1d(logits: Tensor) -> Result<Tensor> {
+    match logits.rank() {
+        1 => Ok(logits),
+        2 => logits.squeeze(0),
+        _ => candle::bail!("generate: unexpected logits shape {:?}", logits.shape()),
+    }
+}
+
+/// Generates up to `config.max_new_tokens` tokens after `prompt` and returns them.
+///
+/// `model` is called with the prompt chunks starting at position 0 and then with each sampled
+/// token, it should use a fresh kv cache. `events.on_done` is called before returning, including
+/// when the model or the sampling fails.
+pub fn generate<M: CausalLM, E: GenerationEvents>(
+    model: &mut M,
+    prompt: &[u32],
+    logits_processor: &mut LogitsProcessor,
+    device: &Device,
+    config: &GenerationConfig,
+    mut events: E,
+) -> Result<Vec<u32>> {
+    if prompt.is_empty() {
+        candle::bail!("generate: the prompt is empty")
+    }
+    let mut timer = GenerationTimer::start();
+    let mut tokens = vec![];
+    let res = generation_loop(
+        model,
+        prompt,
+        logits_processor,
+        device,
+        config,
+        &mut events,
+        &mut timer,
+        &mut tokens,
+    );
+    events.on_done(&timer.finish());
+    res.map(|()| tokens)
+}
+
+#[allow(clippy::too_many_arguments)]
+fn generation_loop<M: CausalLM, E: GenerationEvents>(
+    model: &mut M,
+    prompt: &[u32],
+    logits_processor: &mut LogitsProcessor,
+    device: &Device,
+    config: &GenerationConfig,
+    events: &mut E,
+    timer: &mut GenerationTimer,
+    tokens: &mut Vec<u32>,
+) -> Result<()> {
+    if config.max_new_tokens == 0 {
+        return Ok(());
+    }
+    let start = Instant::now();
+    let chunk_size = match config.prompt_chunk_size {
+        Some(0) => candle::bail!("generate: the prompt chunk size must be positive"),
+        Some(chunk_size) => chunk_size,
+        None => prompt.len(),
+    };
+    let mut logits = None;
+    let mut index_pos = 0;
+    for chunk in prompt.chunks(chunk_size) {
+        let input_ids = Tensor::new(chunk, device)?.unsqueeze(0)?;
+        logits = Some(model.forward(&input_ids, index_pos)?);
+        index_pos += chunk.len();
+        events.on_prompt_progress(index_pos, prompt.len());
+    }
+    let mut logits = match logits {
+        Some(logits) => logits,
+        None => candle::bail!("generate: the prompt is empty"),
+    };
+    // The context used for the repeat penalty, the prompt followed by the sampled tokens.
+    let mut context = prompt.to_vec();
+    let mut sample = |logits: Tensor, context: &[u32]| {
+        let logits = logits_1d(logits)?;
+        let logits = if config.repeat_penalty == 1. {
+            logits
+        } else {
+            let start_at = context.len().saturating_sub(config.repeat_last_n);
+            crate::utils::apply_repeat_penalty(
+                &logits,
+                config.repeat_penalty,
+                &context[start_at..],
+            )?
+        };
+        logits_processor.sample(&logits)
+    };
+    let mut token = sample(logits, &context)?;
+    timer.prompt_processed(prompt.len());
+    tokens.push(token);
+    if !events.on_token(token, start.elapsed()) || config.stop_tokens.contains(&token) {
+        return Ok(());
+    }
+    for _ in 1..config.max_new_tokens {
+        let start = Instant::now();
+        let input_ids = Tensor::new(&[token], device)?.unsqueeze(0)?;
+        logits = model.forward(&input_ids, index_pos)?;
+        index_pos += 1;
+        context.push(token);
+        token = sample(logits, &context)?;
+        timer.token_generated();
+        tokens.push(token);
+        if !events.on_token(token, start.elapsed()) || config.stop_tokens.contains(&token) {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// Records the report of a generation before forwarding it to the wrapped events.
+struct RecordReport<'a, E> {
+    events: E,
+    report: &'a mut Option<GenerationReport>,
+}
+
+impl<E: GenerationEvents> GenerationEvents for RecordReport<'_, E> {
+    fn on_prompt_progress(&mut self, processed: usize, total: usize) {
+        self.events.on_prompt_progress(processed, total)
+    }
+
+    fn on_token(&mut self, token: u32, latency: Duration) -> bool {
+        self.events.on_token(token, latency)
+    }
+
+    fn on_done(&mut self, report: &GenerationReport) {
+        *self.report = Some(report.clone());
+        self.events.on_done(report)
+    }
+}
+
+/// A reusable text generation driver, bundling a model with its sampling configuration so that
+/// applications only have to provide the prompts and a sink for the generated tokens, e.g. an
+/// [`OnToken`] closure or a channel `Sender`.
+///
+/// ```rust,no_run
+/// use candle::{Device, Tensor};
+/// use candle_transformers::generation::events::{GenerationConfig, OnToken, TextGeneration};
+/// use candle_transformers::generation::LogitsProcessor;
+/// # fn run(
+/// #     forward: impl FnMut(&Tensor, usize) -> candle::Result<Tensor>,
+/// # ) -> candle::Result<()> {
+/// let config = GenerationConfig::new(64)
+///     .with_stop_tokens(vec![2])
+///     .with_repeat_penalty(1.1, 64);
+/// let lp = LogitsProcessor::new(42, Some(0.8), None);
+/// let mut pipeline = TextGeneration::new(forward, lp, Device::Cpu, config);
+/// let (tx, rx) = std::sync::mpsc::channel();
+/// let tokens = pipeline.generate(&[1, 15043], tx)?;
+/// println!("{:?}", rx.iter().collect::<Vec<_>>());
+/// if let Some(report) = pipeline.report() {
+///     println!("{report}")
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct TextGeneration<M> {
+    model: M,
+    logits_processor: LogitsProcessor,
+    device: Device,
+    config: GenerationConfig,
+    report: Option<GenerationReport>,
+}
+
+impl<M: CausalLM> TextGeneration<M> {
+    pub fn new(
+        model: M,
+        logits_processor: LogitsProcessor,
+        device: Device,
+        config: GenerationConfig,
+    ) -> Self {
+        Self {
+            model,
+            logits_processor,
+            device,
+            config,
+            report: None,
+        }
+    }
+
+    /// Generates the tokens following `prompt`, see [`generate`]. The model is called starting
+    /// at position 0 so its kv cache should be reset between calls, most models do so when
+    /// called at position 0.
+    pub fn generate<E: GenerationEvents>(&mut self, prompt: &[u32], events: E) -> Result<Vec<u32>> {
+        let events = RecordReport {
+            events,
+            report: &mut self.report,
+        };
+        generate(
+            &mut self.model,
+            prompt,
+            &mut self.logits_processor,
+            &self.device,
+            &self.config,
+            events,
+        )
+    }
+
+    /// The timings of the last generation, `None` before the first one.
+    pub fn report(&self) -> Option<&GenerationReport> {
+        self.report.as_ref()
+    }
+
+    pub fn config(&self) -> &GenerationConfig {
+        &self.config
+    }
+
+    pub fn config_mut(&mut self) -> &mut GenerationConfig {
+        &mut self.config
+    }
+
+    pub fn model_mut(&mut self) -> &mut M {
+        &mut self.model
+    }
+
+    pub fn into_model(self) -> M {
+        self.model
+    }
+}
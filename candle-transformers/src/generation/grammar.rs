@@ -0,0 +1,721 @@
+//! Grammar constrained decoding.
+//!
+//! A [`Grammar`] describes the texts that the model is allowed to generate using the GBNF format
+//! of llama.cpp, <https://github.com/ggerganov/llama.cpp/blob/master/grammars/README.md>, e.g. to
+//! force a tool call to follow a given syntax. [`GrammarConstraint`] tracks the partial matches
+//! of the generated text and, before each sampling step, masks the logits of the tokens that
+//! cannot continue a valid text. JSON schemas can be converted to grammars with
+//! [`super::json_schema`].
+//!
+//! ```rust
+//! use candle::{DType, Device, Tensor};
+//! use candle_transformers::generation::grammar::{Grammar, GrammarConstraint};
+//! # fn main() -> candle::Result<()> {
+//! let grammar = Grammar::parse(r#"root ::= "yes" | "no""#)?;
+//! let vocab = ["y", "es", "no", "n", "maybe", "</s>"].map(String::from);
+//! let mut constraint = GrammarConstraint::new(grammar, &vocab, &[5])?;
+//! assert_eq!(constraint.allowed_tokens(), [0, 2, 3]);
+//! // The logits of the other tokens are set to -inf.
+//! let logits = Tensor::zeros(6, DType::F32, &Device::Cpu)?;
+//! let logits = constraint.apply(&logits)?;
+//! constraint.accept(0)?;
+//! assert_eq!(constraint.allowed_tokens(), [1]);
+//! constraint.accept(1)?;
+//! // The text is complete, only the end of sequence token can be sampled.
+//! assert_eq!(constraint.allowed_tokens(), [5]);
+//! # Ok(())
+//! # }
+//! ```
+use candle::{bail, Result, Tensor, D};
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Element {
+    /// Matches a character in one of the inclusive ranges, or in none of them when negated.
+    Chars {
+        ranges: Vec<(char, char)>,
+        negated: bool,
+    },
+    /// Matches one of the alternatives of a rule.
+    Rule(usize),
+}
+
+impl Element {
+    fn char(c: char) -> Self {
+        Self::Chars {
+            ranges: vec![(c, c)],
+            negated: false,
+        }
+    }
+}
+
+/// A position in an alternative of a rule.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Frame {
+    rule: usize,
+    alt: usize,
+    pos: usize,
+}
+
+/// The frames of the rules being matched, the innermost one being last. An empty stack means
+/// that the text matches the grammar.
+type Stack = Vec<Frame>;
+
+/// A context-free grammar in the GBNF format.
+///
+/// The rules are written `name ::= alternatives`, the alternatives being separated by `|` and
+/// made of string literals `"..."`, character classes `[a-z]` or `[^"]`, any character `.`,
+/// rule names, and groups `( ... )`, optionally followed by a repetition operator `*`, `+`, `?`,
+/// `{m}`, `{m,}` or `{m,n}`. Comments start with `#`. The generated text has to match the `root`
+/// rule. Left recursive rules are not supported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Grammar {
+    /// The alternatives of each rule, an alternative being a sequence of elements.
+    rules: Vec<Vec<Vec<Element>>>,
+    names: Vec<String>,
+    root: usize,
+}
+
+struct Parser {
+    chars: Vec<char>,
+    pos: usize,
+    ids: HashMap<String, usize>,
+    rules: Vec<Option<Vec<Vec<Element>>>>,
+    names: Vec<String>,
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '-' || c == '_'
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<char> {
+        match self.peek() {
+            Some(c) => {
+                self.pos += 1;
+                Ok(c)
+            }
+            None => bail!("grammar: unexpected end of input"),
+        }
+    }
+
+    fn skip_whitespaces(&mut self) {
+        while let Some(c) = self.peek() {
+            if c == '#' {
+                while self.peek().is_some_and(|c| c != '\n') {
+                    self.pos += 1
+                }
+            } else if c.is_whitespace() {
+                self.pos += 1
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn expect(&mut self, s: &str) -> Result<()> {
+        for expected in s.chars() {
+            if self.peek() != Some(expected) {
+                bail!("grammar: expected {s:?} at position {}", self.pos)
+            }
+            self.pos += 1
+        }
+        Ok(())
+    }
+
+    fn parse_name(&mut self) -> Option<String> {
+        let start = self.pos;
+        while self.peek().is_some_and(is_name_char) {
+            self.pos += 1
+        }
+        if self.pos == start {
+            None
+        } else {
+            Some(self.chars[start..self.pos].iter().collect())
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<usize> {
+        self.skip_whitespaces();
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1
+        }
+        let digits = self.chars[start..self.pos].iter().collect::<String>();
+        match digits.parse() {
+            Ok(n) => Ok(n),
+            Err(_) => bail!("grammar: expected a number at position {start}"),
+        }
+    }
+
+    /// Whether the input continues with the definition of a new rule, `name ::=`.
+    fn at_rule_definition(&mut self) -> bool {
+        let start = self.pos;
+        let res = self.parse_name().is_some() && {
+            self.skip_whitespaces();
+            self.chars[self.pos..].starts_with(&[':', ':', '='])
+        };
+        self.pos = start;
+        res
+    }
+
+    fn rule_id(&mut self, name: &str) -> usize {
+        match self.ids.get(name) {
+            Some(&id) => id,
+            None => {
+                let id = self.rules.len();
+                self.ids.insert(name.to_string(), id);
+                self.rules.push(None);
+                self.names.push(name.to_string());
+                id
+            }
+        }
+    }
+
+    /// Adds a rule for a group or a repetition in the definition of the rule `name`.
+    fn new_rule(&mut self, name: &str) -> usize {
+        let name = format!("{name}_{}", self.rules.len());
+        self.rule_id(&name)
+    }
+
+    fn parse_char(&mut self) -> Result<char> {
+        let c = self.next()?;
+        if c != '\\' {
+            return Ok(c);
+        }
+        let hex = |p: &mut Self, len: usize| -> Result<char> {
+            let mut v = 0;
+            for _ in 0..len {
+                match p.next()?.to_digit(16) {
+                    Some(d) => v = v * 16 + d,
+                    None => bail!("grammar: invalid hex escape at position {}", p.pos),
+                }
+            }
+            match char::from_u32(v) {
+                Some(c) => Ok(c),
+                None => bail!("grammar: invalid character {v:#x}"),
+            }
+        };
+        match self.next()? {
+            'n' => Ok('\n'),
+            't' => Ok('\t'),
+            'r' => Ok('\r'),
+            'x' => hex(self, 2),
+            'u' => hex(self, 4),
+            'U' => hex(self, 8),
+            c @ ('\\' | '"' | '[' | ']' | '-' | '^') => Ok(c),
+            c => bail!("grammar: unknown escape \\{c} at position {}", self.pos),
+        }
+    }
+
+    fn parse_primary(&mut self, name: &str) -> Result<Vec<Element>> {
+        let elements = match self.peek() {
+            Some('"') => {
+                self.pos += 1;
+                let mut elements = vec![];
+                while self.peek() != Some('"') {
+                    elements.push(Element::char(self.parse_char()?))
+                }
+                self.pos += 1;
+                elements
+            }
+            Some('[') => {
+                self.pos += 1;
+                let negated = self.peek() == Some('^');
+                if negated {
+                    self.pos += 1
+                }
+                let mut ranges = vec![];
+                while self.peek() != Some(']') {
+                    let start = self.parse_char()?;
+                    let end =
+                        if self.peek() == Some('-') && self.chars.get(self.pos + 1) != Some(&']') {
+                            self.pos += 1;
+                            self.parse_char()?
+                        } else {
+                            start
+                        };
+                    ranges.push((start, end))
+                }
+                self.pos += 1;
+                vec![Element::Chars { ranges, negated }]
+            }
+            Some('.') => {
+                self.pos += 1;
+                vec![Element::Chars {
+                    ranges: vec![],
+                    negated: true,
+                }]
+            }
+            Some('(') => {
+                self.pos += 1;
+                let mut alts = self.parse_alternatives(name)?;
+                self.skip_whitespaces();
+                self.expect(")")?;
+                if alts.len() == 1 {
+                    alts.remove(0)
+                } else {
+                    let id = self.new_rule(name);
+                    self.rules[id] = Some(alts);
+                    vec![Element::Rule(id)]
+                }
+            }
+            Some(c) if is_name_char(c) => {
+                let rule = self.parse_name().unwrap_or_default();
+                vec![Element::Rule(self.rule_id(&rule))]
+            }
+            _ => bail!("grammar: unexpected character at position {}", self.pos),
+        };
+        Ok(elements)
+    }
+
+    /// Returns the elements matching between `min` and `max` repetitions of `item`.
+    fn repeat(
+        &mut self,
+        name: &str,
+        item: Vec<Element>,
+        min: usize,
+        max: Option<usize>,
+    ) -> Vec<Element> {
+        let item = if item.len() == 1 {
+            item[0].clone()
+        } else {
+            let id = self.new_rule(name);
+            self.rules[id] = Some(vec![item]);
+            Element::Rule(id)
+        };
+        let mut elements = vec![item.clone(); min];
+        match max {
+            None => {
+                // The right recursion `r ::= item r | ` does not grow the matching stacks.
+                let id = self.new_rule(name);
+                self.rules[id] = Some(vec![vec![item, Element::Rule(id)], vec![]]);
+                elements.push(Element::Rule(id))
+            }
+            Some(max) => {
+                let mut optional = None;
+                for _ in min..max {
+                    let mut alt = vec![item.clone()];
+                    alt.extend(optional.map(Element::Rule));
+                    let id = self.new_rule(name);
+                    self.rules[id] = Some(vec![alt, vec![]]);
+                    optional = Some(id)
+                }
+                elements.extend(optional.map(Element::Rule))
+            }
+        }
+        elements
+    }
+
+    fn parse_sequence(&mut self, name: &str) -> Result<Vec<Element>> {
+        let mut elements = vec![];
+        loop {
+            self.skip_whitespaces();
+            match self.peek() {
+                None | Some('|') | Some(')') => break,
+                Some(c) if is_name_char(c) && self.at_rule_definition() => break,
+                _ => {}
+            }
+            let item = self.parse_primary(name)?;
+            let (min, max) = match self.peek() {
+                Some('*') => (0, None),
+                Some('+') => (1, None),
+                Some('?') => (0, Some(1)),
+                Some('{') => {
+                    self.pos += 1;
+                    let min = self.parse_number()?;
+                    self.skip_whitespaces();
+                    let max = if self.peek() == Some(',') {
+                        self.pos += 1;
+                        self.skip_whitespaces();
+                        if self.peek() == Some('}') {
+                            None
+                        } else {
+                            Some(self.parse_number()?)
+                        }
+                    } else {
+                        Some(min)
+                    };
+                    self.skip_whitespaces();
+                    if self.peek() != Some('}') {
+                        bail!("grammar: expected '}}' at position {}", self.pos)
+                    }
+                    if max.is_some_and(|max| max < min) {
+                        bail!("grammar: invalid repetition {{{min},{max:?}}} in rule {name}")
+                    }
+                    (min, max)
+                }
+                _ => {
+                    elements.extend(item);
+                    continue;
+                }
+            };
+            self.pos += 1;
+            let repeated = self.repeat(name, item, min, max);
+            elements.extend(repeated)
+        }
+        Ok(elements)
+    }
+
+    fn parse_alternatives(&mut self, name: &str) -> Result<Vec<Vec<Element>>> {
+        let mut alts = vec![self.parse_sequence(name)?];
+        while self.peek() == Some('|') {
+            self.pos += 1;
+            alts.push(self.parse_sequence(name)?)
+        }
+        Ok(alts)
+    }
+
+    fn parse(mut self) -> Result<Grammar> {
+        loop {
+            self.skip_whitespaces();
+            if self.peek().is_none() {
+                break;
+            }
+            let name = match self.parse_name() {
+                Some(name) => name,
+                None => bail!("grammar: expected a rule name at position {}", self.pos),
+            };
+            self.skip_whitespaces();
+            self.expect("::=")?;
+            let alts = self.parse_alternatives(&name)?;
+            let id = self.rule_id(&name);
+            if self.rules[id].is_some() {
+                bail!("grammar: rule {name} is defined twice")
+            }
+            self.rules[id] = Some(alts)
+        }
+        let root = match self.ids.get("root") {
+            Some(&root) => root,
+            None => bail!("grammar: no root rule"),
+        };
+        let rules = self
+            .rules
+            .into_iter()
+            .zip(self.names.iter())
+            .map(|(rule, name)| match rule {
+                Some(rule) => Ok(rule),
+                None => bail!("grammar: undefined rule {name}"),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let grammar = Grammar {
+            rules,
+            names: self.names,
+            root,
+        };
+        grammar.check_left_recursion()?;
+        Ok(grammar)
+    }
+}
+
+impl Grammar {
+    /// Parses a grammar in the GBNF format.
+    pub fn parse(src: &str) -> Result<Self> {
+        let parser = Parser {
+            chars: src.chars().collect(),
+            pos: 0,
+            ids: HashMap::new(),
+            rules: vec![],
+            names: vec![],
+        };
+        parser.parse()
+    }
+
+    fn check_left_recursion(&self) -> Result<()> {
+        // The rules that can match an empty text.
+        let mut nullable = vec![false; self.rules.len()];
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for (id, alts) in self.rules.iter().enumerate() {
+                if nullable[id] {
+                    continue;
+                }
+                let is_nullable = alts.iter().any(|alt| {
+                    alt.iter()
+                        .all(|e| matches!(e, Element::Rule(r) if nullable[*r]))
+                });
+                if is_nullable {
+                    nullable[id] = true;
+                    changed = true
+                }
+            }
+        }
+        // The rules that can be matched by a rule without consuming any character.
+        let left_calls = self
+            .rules
+            .iter()
+            .map(|alts| {
+                let mut calls = vec![];
+                for alt in alts.iter() {
+                    for element in alt.iter() {
+                        match element {
+                            Element::Rule(r) => {
+                                calls.push(*r);
+                                if !nullable[*r] {
+                                    break;
+                                }
+                            }
+                            Element::Chars { .. } => break,
+                        }
+                    }
+                }
+                calls
+            })
+            .collect::<Vec<_>>();
+        // A depth first search, 1 marking the rules being visited and 2 the visited ones.
+        fn visit(rule: usize, left_calls: &[Vec<usize>], state: &mut [u8]) -> Option<usize> {
+            match state[rule] {
+                1 => return Some(rule),
+                2 => return None,
+                _ => {}
+            }
+            state[rule] = 1;
+            for &callee in left_calls[rule].iter() {
+                if let Some(rule) = visit(callee, left_calls, state) {
+                    return Some(rule);
+                }
+            }
+            state[rule] = 2;
+            None
+        }
+        let mut state = vec![0u8; self.rules.len()];
+        for rule in 0..self.rules.len() {
+            if let Some(rule) = visit(rule, &left_calls, &mut state) {
+                bail!("grammar: rule {} is left recursive", self.names[rule])
+            }
+        }
+        Ok(())
+    }
+
+    fn element(&self, frame: &Frame) -> Option<&Element> {
+        self.rules[frame.rule][frame.alt].get(frame.pos)
+    }
+
+    /// Resolves the rule references at the top of the stack, adding to `out` the stacks whose
+    /// next element matches a character, or the empty stack if the text can end there.
+    fn expand(&self, mut stack: Stack, out: &mut Vec<Stack>) {
+        loop {
+            let element = stack.last().map(|frame| self.element(frame));
+            match element {
+                None | Some(Some(Element::Chars { .. })) => {
+                    out.push(stack);
+                    return;
+                }
+                Some(None) => {
+                    stack.pop();
+                }
+                Some(Some(&Element::Rule(rule))) => {
+                    // The caller is advanced before pushing the callee, and dropped when the
+                    // callee is its last element so that right recursions do not grow the stack.
+                    if let Some(frame) = stack.last_mut() {
+                        frame.pos += 1
+                    }
+                    if stack.last().is_some_and(|f| self.element(f).is_none()) {
+                        stack.pop();
+                    }
+                    for alt in 0..self.rules[rule].len() {
+                        let mut stack = stack.clone();
+                        stack.push(Frame { rule, alt, pos: 0 });
+                        self.expand(stack, out)
+                    }
+                    return;
+                }
+            }
+        }
+    }
+
+    fn initial_stacks(&self) -> Vec<Stack> {
+        let mut stacks = vec![];
+        for alt in 0..self.rules[self.root].len() {
+            let frame = Frame {
+                rule: self.root,
+                alt,
+                pos: 0,
+            };
+            self.expand(vec![frame], &mut stacks)
+        }
+        stacks.sort();
+        stacks.dedup();
+        stacks
+    }
+
+    /// Returns the stacks matching the texts of `stacks` followed by `c`.
+    fn advance(&self, stacks: &[Stack], c: char) -> Vec<Stack> {
+        let mut next = vec![];
+        for stack in stacks.iter() {
+            let matches = match stack.last().and_then(|frame| self.element(frame)) {
+                Some(Element::Chars { ranges, negated }) => {
+                    ranges.iter().any(|&(l, r)| l <= c && c <= r) != *negated
+                }
+                _ => false,
+            };
+            if matches {
+                let mut stack = stack.clone();
+                if let Some(frame) = stack.last_mut() {
+                    frame.pos += 1
+                }
+                self.expand(stack, &mut next)
+            }
+        }
+        next.sort();
+        next.dedup();
+        next
+    }
+
+    /// Returns true if the whole `text` matches the grammar.
+    pub fn matches(&self, text: &str) -> bool {
+        let mut stacks = self.initial_stacks();
+        for c in text.chars() {
+            stacks = self.advance(&stacks, c);
+        }
+        stacks.iter().any(|s| s.is_empty())
+    }
+}
+
+/// A node of the prefix tree of the vocabulary.
+#[derive(Debug, Clone, Default)]
+struct TrieNode {
+    children: Vec<(char, usize)>,
+    /// The tokens whose text ends at this node.
+    tokens: Vec<u32>,
+}
+
+/// Masks the logits of the tokens that would make the generated text diverge from a grammar.
+///
+/// The vocabulary is stored as a prefix tree so that the partial matches are shared between the
+/// tokens starting with the same characters. The text of each token is matched character by
+/// character, so the tokens only containing a part of a multi-byte character, e.g. some byte
+/// fallback tokens, should be decoded to an empty string and are never allowed.
+#[derive(Debug, Clone)]
+pub struct GrammarConstraint {
+    grammar: Grammar,
+    vocab: Vec<String>,
+    nodes: Vec<TrieNode>,
+    eos_tokens: Vec<u32>,
+    stacks: Vec<Stack>,
+}
+
+impl GrammarConstraint {
+    /// Creates a constraint for the vocabulary `vocab`, the decoded text of each token indexed
+    /// by token id. The `eos_tokens` are only allowed once the text matches the grammar.
+    pub fn new(grammar: Grammar, vocab: &[String], eos_tokens: &[u32]) -> Result<Self> {
+        let mut nodes = vec![TrieNode::default()];
+        for (token, text) in vocab.iter().enumerate() {
+            let token = token as u32;
+            if text.is_empty() || eos_tokens.contains(&token) {
+                continue;
+            }
+            let mut node = 0;
+            for c in text.chars() {
+                let child = nodes[node]
+                    .children
+                    .iter()
+                    .find(|(n, _)| *n == c)
+                    .map(|(_, child)| *child);
+                node = match child {
+                    Some(child) => child,
+                    None => {
+                        nodes.push(TrieNode::default());
+                        let child = nodes.len() - 1;
+                        nodes[node].children.push((c, child));
+                        child
+                    }
+                }
+            }
+            nodes[node].tokens.push(token)
+        }
+        let stacks = grammar.initial_stacks();
+        if stacks.is_empty() {
+            bail!("grammar: the root rule cannot match any text")
+        }
+        Ok(Self {
+            grammar,
+            vocab: vocab.to_vec(),
+            nodes,
+            eos_tokens: eos_tokens.to_vec(),
+            stacks,
+        })
+    }
+
+    /// Restarts the matching at the beginning of the grammar, e.g. for a new generation.
+    pub fn reset(&mut self) {
+        self.stacks = self.grammar.initial_stacks()
+    }
+
+    pub fn grammar(&self) -> &Grammar {
+        &self.grammar
+    }
+
+    /// Returns true if the text generated so far matches the grammar.
+    pub fn is_accepting(&self) -> bool {
+        self.stacks.iter().any(|s| s.is_empty())
+    }
+
+    fn visit(&self, node: usize, stacks: &[Stack], allowed: &mut Vec<u32>) {
+        for &(c, child) in self.nodes[node].children.iter() {
+            let stacks = self.grammar.advance(stacks, c);
+            if !stacks.is_empty() {
+                allowed.extend_from_slice(&self.nodes[child].tokens);
+                self.visit(child, &stacks, allowed)
+            }
+        }
+    }
+
+    /// Returns the sorted ids of the tokens that can follow the text generated so far.
+    pub fn allowed_tokens(&self) -> Vec<u32> {
+        let mut allowed = vec![];
+        self.visit(0, &self.stacks, &mut allowed);
+        if self.is_accepting() {
+            allowed.extend_from_slice(&self.eos_tokens)
+        }
+        allowed.sort();
+        allowed
+    }
+
+    /// Sets the logits of the tokens that cannot follow the text generated so far to `-inf`,
+    /// `logits` having the vocabulary as last dimension.
+    pub fn apply(&self, logits: &Tensor) -> Result<Tensor> {
+        let allowed = self.allowed_tokens();
+        if allowed.is_empty() {
+            bail!("grammar: no token can continue the generated text")
+        }
+        let vocab_size = logits.dim(D::Minus1)?;
+        let mut mask = vec![f32::NEG_INFINITY; vocab_size];
+        for &token in allowed.iter() {
+            if let Some(v) = mask.get_mut(token as usize) {
+                *v = 0.
+            }
+        }
+        let mask = Tensor::from_vec(mask, vocab_size, logits.device())?.to_dtype(logits.dtype())?;
+        logits.broadcast_add(&mask)
+    }
+
+    /// Advances the matching with a sampled token, failing if the token is not allowed.
+    pub fn accept(&mut self, token: u32) -> Result<()> {
+        if self.eos_tokens.contains(&token) {
+            if !self.is_accepting() {
+                bail!("grammar: the end of sequence token {token} is not allowed")
+            }
+            self.stacks.retain(|s| s.is_empty());
+            return Ok(());
+        }
+        let text = match self.vocab.get(token as usize) {
+            Some(text) => text,
+            None => bail!("grammar: token {token} is not in the vocabulary"),
+        };
+        let mut stacks = self.stacks.clone();
+        for c in text.chars() {
+            stacks = self.grammar.advance(&stacks, c);
+        }
+        if stacks.is_empty() {
+            bail!("grammar: token {token} {text:?} is not allowed")
+        }
+        self.stacks = stacks;
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+//! Conversion of JSON schemas to grammars, so that the generated text is a JSON value following
+//! the schema, see [`super::grammar`].
+//!
+//! The supported subset of JSON schema covers the usual tool calling and structured output
+//! schemas: the `object`, `array`, `string`, `number`, `integer`, `boolean` and `null` types,
+//! `properties`, `items`, `enum`, `const`, `anyOf` and `oneOf`. All the properties of an object
+//! are generated, in the order of the `properties` map which is sorted by name unless the
+//! `preserve_order` feature of `serde_json` is enabled. An empty schema or `true` matches any
+//! JSON value.
+//!
+//! ```rust
+//! use candle_transformers::generation::json_schema;
+//! # fn main() -> candle::Result<()> {
+//! let schema = serde_json::json!({
+//!     "type": "object",
+//!     "properties": {
+//!         "name": { "type": "string" },
+//!         "unit": { "enum": ["celsius", "fahrenheit"] },
+//!     },
+//! });
+//! let grammar = json_schema::to_grammar(&schema)?;
+//! assert!(grammar.matches(r#"{"name": "Paris", "unit": "celsius"}"#));
+//! assert!(!grammar.matches(r#"{"name": "Paris", "unit": "kelvin"}"#));
+//! # Ok(())
+//! # }
+//! ```
+use super::grammar::Grammar;
+use candle::{bail, Result};
+use serde_json::Value;
+
+/// The rules for the JSON primitives, the whitespaces are limited so that the model cannot get
+/// stuck generating them.
+const PRIMITIVES: &str = r#"
+ws ::= | " " | "\n" [ \t]{0,20}
+string ::= "\"" ( [^"\\\x7F\x00-\x1F] | "\\" ( ["\\/bfnrt] | "u" [0-9a-fA-F]{4} ) )* "\"" ws
+number ::= "-"? ( [0-9] | [1-9] [0-9]{1,15} ) ( "." [0-9]+ )? ( [eE] [-+]? [0-9]{1,4} )? ws
+integer ::= "-"? ( [0-9] | [1-9] [0-9]{1,15} ) ws
+boolean ::= ( "true" | "false" ) ws
+null ::= "null" ws
+value ::= object | array | string | number | boolean | null
+object ::= "{" ws ( string ":" ws value ( "," ws string ":" ws value )* )? "}" ws
+array ::= "[" ws ( value ( "," ws value )* )? "]" ws
+"#;
+
+/// Returns the GBNF string literal matching `s`.
+fn literal(s: &str) -> String {
+    let mut lit = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => lit.push_str("\\\""),
+            '\\' => lit.push_str("\\\\"),
+            '\n' => lit.push_str("\\n"),
+            '\r' => lit.push_str("\\r"),
+            '\t' => lit.push_str("\\t"),
+            c => lit.push(c),
+        }
+    }
+    lit.push('"');
+    lit
+}
+
+/// Returns the GBNF expression matching the serialized JSON value.
+fn json_literal(v: &Value) -> Result<String> {
+    let json = serde_json::to_string(v).map_err(candle::Error::wrap)?;
+    Ok(format!("{} ws", literal(&json)))
+}
+
+struct Converter {
+    rules: Vec<(String, String)>,
+}
+
+impl Converter {
+    /// Adds a rule and returns its name, a suffix is added when the name is already used.
+    fn add_rule(&mut self, name: &str, body: String) -> String {
+        let name = name
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '-' })
+            .collect::<String>();
+        let mut unique = name.clone();
+        let mut index = 0;
+        while self.rules.iter().any(|(n, _)| *n == unique) {
+            index += 1;
+            unique = format!("{name}{index}")
+        }
+        self.rules.push((unique.clone(), body));
+        unique
+    }
+
+    fn alternatives<'a>(
+        &mut self,
+        name: &str,
+        schemas: impl Iterator<Item = &'a Value>,
+    ) -> Result<String> {
+        let alts = schemas
+            .enumerate()
+            .map(|(i, schema)| self.visit(schema, &format!("{name}-{i}")))
+            .collect::<Result<Vec<_>>>()?;
+        if alts.is_empty() {
+            bail!("json schema: empty list of alternatives for {name}")
+        }
+        Ok(self.add_rule(name, alts.join(" | ")))
+    }
+
+    /// Returns the name of the rule matching the values of the schema.
+    fn visit(&mut self, schema: &Value, name: &str) -> Result<String> {
+        let obj = match schema {
+            Value::Bool(true) => return Ok("value".to_string()),
+            Value::Object(obj) => obj,
+            _ => bail!("json schema: unsupported schema {schema} for {name}"),
+        };
+        if obj.contains_key("$ref") {
+            bail!("json schema: $ref is not supported, in {name}")
+        }
+        if let Some(value) = obj.get("const") {
+            return Ok(self.add_rule(name, json_literal(value)?));
+        }
+        if let Some(values) = obj.get("enum") {
+            let values = match values {
+                Value::Array(values) if !values.is_empty() => values,
+                _ => bail!("json schema: enum should be a non-empty array, in {name}"),
+            };
+            let alts = values
+                .iter()
+                .map(json_literal)
+                .collect::<Result<Vec<_>>>()?;
+            return Ok(self.add_rule(name, alts.join(" | ")));
+        }
+        for key in ["anyOf", "oneOf"] {
+            if let Some(schemas) = obj.get(key) {
+                match schemas {
+                    Value::Array(schemas) => return self.alternatives(name, schemas.iter()),
+                    _ => bail!("json schema: {key} should be an array, in {name}"),
+                }
+            }
+        }
+        match obj.get("type") {
+            Some(Value::String(ty)) => self.typed(ty, obj, name),
+            Some(Value::Array(types)) => {
+                let alts = types
+                    .iter()
+                    .enumerate()
+                    .map(|(i, ty)| match ty {
+                        Value::String(ty) => self.typed(ty, obj, &format!("{name}-{i}")),
+                        _ => bail!("json schema: invalid type {ty}, in {name}"),
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(self.add_rule(name, alts.join(" | ")))
+            }
+            Some(ty) => bail!("json schema: invalid type {ty}, in {name}"),
+            None if obj.contains_key("properties") => self.typed("object", obj, name),
+            None => Ok("value".to_string()),
+        }
+    }
+
+    fn typed(
+        &mut self,
+        ty: &str,
+        obj: &serde_json::Map<String, Value>,
+        name: &str,
+    ) -> Result<String> {
+        match ty {
+            "object" => {
+                let properties = match obj.get("properties") {
+                    Some(Value::Object(properties)) if !properties.is_empty() => properties,
+                    _ => return Ok("object".to_string()),
+                };
+                let mut body = String::from(r#""{" ws"#);
+                for (i, (key, schema)) in properties.iter().enumerate() {
+                    let rule = self.visit(schema, &format!("{name}-{key}"))?;
+                    if i > 0 {
+                        body.push_str(r#" "," ws"#)
+                    }
+                    let key = literal(&serde_json::to_string(key).map_err(candle::Error::wrap)?);
+                    body.push_str(&format!(r#" {key} ws ":" ws {rule}"#))
+                }
+                body.push_str(r#" "}" ws"#);
+                Ok(self.add_rule(name, body))
+            }
+            "array" => match obj.get("items") {
+                Some(items) => {
+                    let item = self.visit(items, &format!("{name}-item"))?;
+                    let body = format!(r#""[" ws ( {item} ( "," ws {item} )* )? "]" ws"#);
+                    Ok(self.add_rule(name, body))
+                }
+                None => Ok("array".to_string()),
+            },
+            "string" | "number" | "integer" | "boolean" | "null" => Ok(ty.to_string()),
+            _ => bail!("json schema: unsupported type {ty}, in {name}"),
+        }
+    }
+}
+
+/// Returns the GBNF grammar matching the JSON values following `schema`.
+pub fn to_gbnf(schema: &Value) -> Result<String> {
+    // The primitive names are reserved so that the rules of the schema do not shadow them.
+    let mut converter = Converter {
+        rules: PRIMITIVES
+            .lines()
+            .filter_map(|line| line.split_once(" ::= "))
+            .map(|(name, body)| (name.to_string(), body.to_string()))
+            .collect(),
+    };
+    let root = converter.visit(schema, "root")?;
+    if root != "root" {
+        converter.add_rule("root", root);
+    }
+    let gbnf = converter
+        .rules
+        .iter()
+        .map(|(name, body)| format!("{name} ::= {body}\n"))
+        .collect();
+    Ok(gbnf)
+}
+
+/// Returns the grammar matching the JSON values following `schema`.
+pub fn to_grammar(schema: &Value) -> Result<Grammar> {
+    Grammar::parse(&to_gbnf(schema)?)
+}
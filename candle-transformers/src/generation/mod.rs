@@ -1,15 +1,126 @@
-use candle::{DType, Error, Result, Tensor};
-use rand::{distributions::Distribution, SeedableRng};
+use candle::{DType, Device, Error, Result, Tensor, D};
+use rand::{distributions::Distribution, Rng, SeedableRng};
 
+pub mod batching;
+pub mod beam_search;
+pub mod ensemble;
+pub mod events;
+pub mod grammar;
+pub mod json_schema;
+mod report;
+pub mod speculative;
+pub mod steering;
+#[cfg(feature = "tokio")]
+pub mod stream;
+pub mod watermark;
+pub use report::{peak_memory, GenerationReport, GenerationTimer};
+
+/// How the next token is selected, the temperature scales the logits before the softmax and the
+/// truncations are applied to the resulting probabilities.
 #[derive(Clone, PartialEq, Debug)]
 pub enum Sampling {
+    /// Greedy decoding, the most likely token is selected.
     ArgMax,
+    /// Samples from the whole distribution.
     All { temperature: f64 },
+    /// Samples from the `k` most likely tokens.
     TopK { k: usize, temperature: f64 },
+    /// Samples from the smallest set of tokens whose cumulative probability exceeds `p`.
     TopP { p: f64, temperature: f64 },
+    /// Keeps the `k` most likely tokens, then applies top-p sampling to them, as llama.cpp does
+    /// with its default sampler chain.
     TopKThenTopP { k: usize, p: f64, temperature: f64 },
 }
 
+impl Sampling {
+    /// Composes the sampling strategy from the usual command line options, a temperature of 0
+    /// or less meaning greedy decoding.
+    pub fn new(temperature: f64, top_k: Option<usize>, top_p: Option<f64>) -> Self {
+        if temperature <= 0. {
+            return Self::ArgMax;
+        }
+        match (top_k, top_p) {
+            (None, None) => Self::All { temperature },
+            (Some(k), None) => Self::TopK { k, temperature },
+            (None, Some(p)) => Self::TopP { p, temperature },
+            (Some(k), Some(p)) => Self::TopKThenTopP { k, p, temperature },
+        }
+    }
+
+    /// The normalized probabilities the tokens are sampled from for 1D `logits`, after the
+    /// temperature scaling and the truncations. Greedy decoding results in a one-hot
+    /// distribution on the most likely token.
+    pub fn distribution(&self, logits: &Tensor) -> Result<Vec<f32>> {
+        let logits = logits.to_dtype(DType::F32)?;
+        let temperature = match self {
+            Self::ArgMax => {
+                let mut prs = vec![0f32; logits.dim(D::Minus1)?];
+                let argmax = logits.argmax(D::Minus1)?.to_scalar::<u32>()?;
+                prs[argmax as usize] = 1.;
+                return Ok(prs);
+            }
+            Self::All { temperature }
+            | Self::TopP { temperature, .. }
+            | Self::TopK { temperature, .. }
+            | Self::TopKThenTopP { temperature, .. } => *temperature,
+        };
+        let logits = (&logits / temperature)?;
+        let mut prs: Vec<f32> = candle_nn::ops::softmax_last_dim(&logits)?.to_vec1()?;
+        match self {
+            Self::ArgMax | Self::All { .. } => {}
+            Self::TopP { p, .. } => {
+                if *p > 0. && *p < 1. {
+                    truncate_topp(&mut prs, *p as f32)
+                }
+            }
+            Self::TopK { k, .. } => truncate_topk(&mut prs, *k),
+            Self::TopKThenTopP { k, p, .. } => {
+                truncate_topk(&mut prs, *k);
+                let sum_p = prs.iter().sum::<f32>();
+                if *p > 0. && (*p as f32) < sum_p {
+                    truncate_topp(&mut prs, *p as f32)
+                }
+            }
+        }
+        let sum_p = prs.iter().sum::<f32>();
+        prs.iter_mut().for_each(|v| *v /= sum_p);
+        Ok(prs)
+    }
+}
+
+/// Sets to zero the probabilities outside of the `k` largest ones.
+fn truncate_topk(prs: &mut [f32], k: usize) {
+    if k < prs.len() {
+        let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
+        argsort_indices.select_nth_unstable_by(k, |&i, &j| prs[j].total_cmp(&prs[i]));
+        for &index in &argsort_indices[k..] {
+            prs[index] = 0.
+        }
+    }
+}
+
+/// Sets to zero the probabilities outside of the smallest set of tokens whose cumulative
+/// probability exceeds `top_p`, the same set as the one used by top-p sampling.
+fn truncate_topp(prs: &mut [f32], top_p: f32) {
+    let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
+    argsort_indices.sort_by(|&i, &j| prs[j].total_cmp(&prs[i]));
+    let mut cumsum = 0.;
+    for index in argsort_indices {
+        if cumsum >= top_p {
+            prs[index] = 0.0;
+        } else {
+            cumsum += prs[index];
+        }
+    }
+}
+
+/// Samples tokens from logits according to a [`Sampling`] strategy.
+///
+/// The processor owns a default RNG used by [`LogitsProcessor::sample`]. When serving multiple
+/// requests concurrently, the sampling configuration can be shared and each request can hold its
+/// own RNG via [`LogitsProcessor::sample_with_rng`], so that identical seeds produce identical
+/// outputs regardless of how the requests are scheduled.
+#[derive(Clone, Debug)]
 pub struct LogitsProcessor {
     rng: rand::rngs::StdRng,
     sampling: Sampling,
@@ -33,7 +144,17 @@ impl LogitsProcessor {
         Self::from_sampling(seed, sampling)
     }
 
-    fn sample_argmax(&mut self, logits: Tensor) -> Result<u32> {
+    /// Creates a fresh RNG seeded with `seed`, suitable for use with
+    /// [`LogitsProcessor::sample_with_rng`].
+    pub fn rng_from_seed(seed: u64) -> rand::rngs::StdRng {
+        rand::rngs::StdRng::seed_from_u64(seed)
+    }
+
+    pub fn sampling(&self) -> &Sampling {
+        &self.sampling
+    }
+
+    fn sample_argmax(logits: Tensor) -> Result<u32> {
         let logits_v: Vec<f32> = logits.to_vec1()?;
         let next_token = logits_v
             .iter()
@@ -44,16 +165,16 @@ impl LogitsProcessor {
         Ok(next_token)
     }
 
-    fn sample_multinomial(&mut self, prs: &Vec<f32>) -> Result<u32> {
+    fn sample_multinomial<R: Rng + ?Sized>(prs: &Vec<f32>, rng: &mut R) -> Result<u32> {
         let distr = rand::distributions::WeightedIndex::new(prs).map_err(Error::wrap)?;
-        let next_token = distr.sample(&mut self.rng) as u32;
+        let next_token = distr.sample(rng) as u32;
         Ok(next_token)
     }
 
     /// top-p sampling (or "nucleus sampling") samples from the smallest set of tokens that exceed
     /// probability top_p. This way we never sample tokens that have very low probabilities and are
     /// less likely to go "off the rails".
-    fn sample_topp(&mut self, prs: &mut Vec<f32>, top_p: f32) -> Result<u32> {
+    fn sample_topp<R: Rng + ?Sized>(prs: &mut Vec<f32>, top_p: f32, rng: &mut R) -> Result<u32> {
         let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
 
         // Sort by descending probability.
@@ -69,28 +190,33 @@ impl LogitsProcessor {
             }
         }
         // Sample with clamped probabilities.
-        self.sample_multinomial(prs)
+        Self::sample_multinomial(prs, rng)
     }
 
     // top-k sampling samples from the k tokens with the largest probabilities.
-    fn sample_topk(&mut self, prs: &mut Vec<f32>, top_k: usize) -> Result<u32> {
+    fn sample_topk<R: Rng + ?Sized>(prs: &mut Vec<f32>, top_k: usize, rng: &mut R) -> Result<u32> {
         if top_k >= prs.len() {
-            self.sample_multinomial(prs)
+            Self::sample_multinomial(prs, rng)
         } else {
             let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
             let (indices, _, _) =
                 argsort_indices.select_nth_unstable_by(top_k, |&i, &j| prs[j].total_cmp(&prs[i]));
             let prs = indices.iter().map(|&i| prs[i]).collect::<Vec<_>>();
-            let index = self.sample_multinomial(&prs)?;
+            let index = Self::sample_multinomial(&prs, rng)?;
             Ok(indices[index as usize] as u32)
         }
     }
 
     // top-k sampling samples from the k tokens with the largest probabilities.
     // then top-p sampling.
-    fn sample_topk_topp(&mut self, prs: &mut Vec<f32>, top_k: usize, top_p: f32) -> Result<u32> {
+    fn sample_topk_topp<R: Rng + ?Sized>(
+        prs: &mut Vec<f32>,
+        top_k: usize,
+        top_p: f32,
+        rng: &mut R,
+    ) -> Result<u32> {
         if top_k >= prs.len() {
-            self.sample_topp(prs, top_p)
+            Self::sample_topp(prs, top_p, rng)
         } else {
             let mut argsort_indices = (0..prs.len()).collect::<Vec<_>>();
             let (indices, _, _) =
@@ -98,9 +224,9 @@ impl LogitsProcessor {
             let mut prs = indices.iter().map(|&i| prs[i]).collect::<Vec<_>>();
             let sum_p = prs.iter().sum::<f32>();
             let index = if top_p <= 0.0 || top_p >= sum_p {
-                self.sample_multinomial(&prs)?
+                Self::sample_multinomial(&prs, rng)?
             } else {
-                self.sample_topp(&mut prs, top_p)?
+                Self::sample_topp(&mut prs, top_p, rng)?
             };
             Ok(indices[index as usize] as u32)
         }
@@ -111,40 +237,149 @@ impl LogitsProcessor {
     }
 
     pub fn sample_f(&mut self, logits: &Tensor, f: impl FnOnce(&mut [f32])) -> Result<u32> {
+        Self::sample_impl(&self.sampling, logits, f, &mut self.rng)
+    }
+
+    /// Samples a token using the provided RNG rather than the processor's own one, leaving the
+    /// processor untouched.
+    pub fn sample_with_rng<R: Rng + ?Sized>(&self, logits: &Tensor, rng: &mut R) -> Result<u32> {
+        self.sample_f_with_rng(logits, |_| {}, rng)
+    }
+
+    pub fn sample_f_with_rng<R: Rng + ?Sized>(
+        &self,
+        logits: &Tensor,
+        f: impl FnOnce(&mut [f32]),
+        rng: &mut R,
+    ) -> Result<u32> {
+        Self::sample_impl(&self.sampling, logits, f, rng)
+    }
+
+    fn sample_impl<R: Rng + ?Sized>(
+        sampling: &Sampling,
+        logits: &Tensor,
+        f: impl FnOnce(&mut [f32]),
+        rng: &mut R,
+    ) -> Result<u32> {
         let logits = logits.to_dtype(DType::F32)?;
-        let prs = |temperature: f64| -> Result<Vec<f32>> {
-            let logits = (&logits / temperature)?;
-            let prs = candle_nn::ops::softmax_last_dim(&logits)?;
-            let mut prs = prs.to_vec1()?;
-            f(&mut prs);
-            Ok(prs)
+        let temperature = match sampling {
+            Sampling::ArgMax => return Self::sample_argmax(logits),
+            Sampling::All { temperature }
+            | Sampling::TopP { temperature, .. }
+            | Sampling::TopK { temperature, .. }
+            | Sampling::TopKThenTopP { temperature, .. } => *temperature,
         };
+        let logits = (&logits / temperature)?;
+        let prs = candle_nn::ops::softmax_last_dim(&logits)?;
+        let mut prs = prs.to_vec1()?;
+        f(&mut prs);
+        Self::sample_prs(sampling, &mut prs, rng)
+    }
 
-        let next_token = match &self.sampling {
-            Sampling::ArgMax => self.sample_argmax(logits)?,
-            Sampling::All { temperature } => {
-                let prs = prs(*temperature)?;
-                self.sample_multinomial(&prs)?
-            }
-            Sampling::TopP { p, temperature } => {
-                let mut prs = prs(*temperature)?;
+    /// Samples a token from a vector of probabilities, these probabilities are expected to have
+    /// already been adjusted for the sampling temperature.
+    fn sample_prs<R: Rng + ?Sized>(
+        sampling: &Sampling,
+        prs: &mut Vec<f32>,
+        rng: &mut R,
+    ) -> Result<u32> {
+        let next_token = match sampling {
+            Sampling::ArgMax => prs
+                .iter()
+                .enumerate()
+                .max_by(|(_, u), (_, v)| u.total_cmp(v))
+                .map(|(i, _)| i as u32)
+                .unwrap_or(0),
+            Sampling::All { .. } => Self::sample_multinomial(prs, rng)?,
+            Sampling::TopP { p, .. } => {
                 if *p <= 0.0 || *p >= 1.0 {
                     // simply sample from the predicted probability distribution
-                    self.sample_multinomial(&prs)?
+                    Self::sample_multinomial(prs, rng)?
                 } else {
                     // top-p (nucleus) sampling, clamping the least likely tokens to zero
-                    self.sample_topp(&mut prs, *p as f32)?
+                    Self::sample_topp(prs, *p as f32, rng)?
                 }
             }
-            Sampling::TopK { k, temperature } => {
-                let mut prs = prs(*temperature)?;
-                self.sample_topk(&mut prs, *k)?
-            }
-            Sampling::TopKThenTopP { k, p, temperature } => {
-                let mut prs = prs(*temperature)?;
-                self.sample_topk_topp(&mut prs, *k, *p as f32)?
-            }
+            Sampling::TopK { k, .. } => Self::sample_topk(prs, *k, rng)?,
+            Sampling::TopKThenTopP { k, p, .. } => Self::sample_topk_topp(prs, *k, *p as f32, rng)?,
         };
         Ok(next_token)
     }
+
+    /// Samples one token per row of a `(batch, vocab)` logits tensor, row `i` being sampled with
+    /// the sampling parameters and rng of `processors[i]`.
+    ///
+    /// The temperature scaling, the softmax and the greedy argmax are computed on the logits
+    /// device for the whole batch at once, only the probabilities of the rows that actually
+    /// require random sampling are copied back to the host.
+    pub fn sample_batch(processors: &mut [Self], logits: &Tensor) -> Result<Vec<u32>> {
+        let (b_sz, _vocab) = logits.dims2()?;
+        if b_sz != processors.len() {
+            candle::bail!(
+                "sample_batch: got {} processors for a batch of {b_sz} logits",
+                processors.len()
+            )
+        }
+        let logits = logits.to_dtype(DType::F32)?;
+        let temperatures = processors
+            .iter()
+            .map(|p| match p.sampling {
+                Sampling::ArgMax => 1f32,
+                Sampling::All { temperature }
+                | Sampling::TopP { temperature, .. }
+                | Sampling::TopK { temperature, .. }
+                | Sampling::TopKThenTopP { temperature, .. } => temperature as f32,
+            })
+            .collect::<Vec<_>>();
+        let needs_prs = processors
+            .iter()
+            .any(|p| !matches!(p.sampling, Sampling::ArgMax));
+        let argmax: Vec<u32> = logits.argmax(D::Minus1)?.to_vec1()?;
+        if !needs_prs {
+            return Ok(argmax);
+        }
+        let temperatures = Tensor::from_vec(temperatures, (b_sz, 1), logits.device())?;
+        let prs = candle_nn::ops::softmax_last_dim(&logits.broadcast_div(&temperatures)?)?;
+        let mut prs: Vec<Vec<f32>> = prs.to_vec2()?;
+        let mut next_tokens = Vec::with_capacity(b_sz);
+        for ((processor, prs), argmax) in processors.iter_mut().zip(prs.iter_mut()).zip(argmax) {
+            let next_token = match processor.sampling {
+                Sampling::ArgMax => argmax,
+                _ => Self::sample_prs(&processor.sampling, prs, &mut processor.rng)?,
+            };
+            next_tokens.push(next_token)
+        }
+        Ok(next_tokens)
+    }
+}
+
+/// Runs a model on `tokens` in chunks of at most `chunk_size` tokens, the first chunk being at
+/// position `index_pos`. Each chunk attends to the previous ones through the model kv cache so
+/// that the peak activation memory is bounded by the chunk size rather than by the prompt length.
+///
+/// `forward` is called with the tokens of a chunk, as a `(1, chunk_len)` tensor, and with the
+/// position of its first token. The output of the last call is returned, typically the logits
+/// for the last prompt position.
+pub fn forward_chunked<F>(
+    tokens: &[u32],
+    chunk_size: usize,
+    index_pos: usize,
+    device: &Device,
+    mut forward: F,
+) -> Result<Tensor>
+where
+    F: FnMut(&Tensor, usize) -> Result<Tensor>,
+{
+    if chunk_size == 0 {
+        candle::bail!("forward_chunked: the chunk size must be positive")
+    }
+    let mut output = None;
+    for (chunk_idx, chunk) in tokens.chunks(chunk_size).enumerate() {
+        let input = Tensor::new(chunk, device)?.unsqueeze(0)?;
+        output = Some(forward(&input, index_pos + chunk_idx * chunk_size)?);
+    }
+    match output {
+        Some(output) => Ok(output),
+        None => candle::bail!("forward_chunked: no tokens to process"),
+    }
 }
@@ -0,0 +1,159 @@
+//! Timings and usage statistics for text generation.
+use std::time::{Duration, Instant};
+
+/// Statistics about a generation run, see [`GenerationTimer`] to collect them.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct GenerationReport {
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    /// The time spent processing the prompt, including the sampling of the first token.
+    pub prompt_eval_time: Duration,
+    /// The time taken by each token generated after the prompt.
+    pub token_latencies: Vec<Duration>,
+    /// The peak resident memory of the process in bytes, only available on linux. This does not
+    /// include the memory allocated on cuda or metal devices.
+    pub peak_memory: Option<usize>,
+}
+
+impl GenerationReport {
+    pub fn generation_time(&self) -> Duration {
+        self.token_latencies.iter().sum()
+    }
+
+    pub fn prompt_tokens_per_second(&self) -> f64 {
+        self.prompt_tokens as f64 / self.prompt_eval_time.as_secs_f64()
+    }
+
+    pub fn generated_tokens_per_second(&self) -> f64 {
+        self.token_latencies.len() as f64 / self.generation_time().as_secs_f64()
+    }
+
+    pub fn mean_latency(&self) -> Option<Duration> {
+        if self.token_latencies.is_empty() {
+            None
+        } else {
+            Some(self.generation_time() / self.token_latencies.len() as u32)
+        }
+    }
+
+    /// The latency below which `p` percent of the tokens have been generated, using the
+    /// nearest-rank method, e.g. `latency_percentile(50.)` for the median.
+    pub fn latency_percentile(&self, p: f64) -> Option<Duration> {
+        if self.token_latencies.is_empty() {
+            return None;
+        }
+        let mut latencies = self.token_latencies.clone();
+        latencies.sort();
+        let rank = (p.clamp(0., 100.) / 100. * latencies.len() as f64).ceil() as usize;
+        Some(latencies[rank.max(1) - 1])
+    }
+}
+
+impl std::fmt::Display for GenerationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{:4} prompt tokens processed: {:.2} token/s",
+            self.prompt_tokens,
+            self.prompt_tokens_per_second()
+        )?;
+        write!(
+            f,
+            "{:4} tokens generated: {:.2} token/s",
+            self.generated_tokens,
+            self.generated_tokens_per_second()
+        )?;
+        if let (Some(p50), Some(p90), Some(p99)) = (
+            self.latency_percentile(50.),
+            self.latency_percentile(90.),
+            self.latency_percentile(99.),
+        ) {
+            write!(
+                f,
+                "\nlatency p50 {:.2}ms, p90 {:.2}ms, p99 {:.2}ms",
+                p50.as_secs_f64() * 1e3,
+                p90.as_secs_f64() * 1e3,
+                p99.as_secs_f64() * 1e3
+            )?;
+        }
+        if let Some(peak_memory) = self.peak_memory {
+            write!(f, "\npeak memory {:.2}MB", peak_memory as f64 / 1e6)?;
+        }
+        Ok(())
+    }
+}
+
+/// Collects a [`GenerationReport`] while running a generation loop.
+///
+/// ```ignore
+/// let mut timer = GenerationTimer::start();
+/// // process the prompt and sample the first token
+/// timer.prompt_processed(prompt_tokens.len());
+/// for _ in 0..sample_len {
+///     // generate a token
+///     timer.token_generated();
+/// }
+/// println!("{}", timer.finish());
+/// ```
+#[derive(Debug, Clone)]
+pub struct GenerationTimer {
+    report: GenerationReport,
+    start: Instant,
+    last: Instant,
+}
+
+impl GenerationTimer {
+    pub fn start() -> Self {
+        let now = Instant::now();
+        Self {
+            report: GenerationReport::default(),
+            start: now,
+            last: now,
+        }
+    }
+
+    /// Records the end of the prompt processing, `prompt_tokens` is the prompt length. The first
+    /// token sampled from the prompt logits counts as a generated token.
+    pub fn prompt_processed(&mut self, prompt_tokens: usize) {
+        self.last = Instant::now();
+        self.report.prompt_tokens = prompt_tokens;
+        self.report.prompt_eval_time = self.last - self.start;
+        self.report.generated_tokens = 1;
+    }
+
+    /// Records the generation of a token after the prompt.
+    pub fn token_generated(&mut self) {
+        let now = Instant::now();
+        self.report.token_latencies.push(now - self.last);
+        self.report.generated_tokens += 1;
+        self.last = now;
+    }
+
+    pub fn report(&self) -> &GenerationReport {
+        &self.report
+    }
+
+    pub fn finish(mut self) -> GenerationReport {
+        self.report.peak_memory = peak_memory();
+        self.report
+    }
+}
+
+/// Returns the peak resident memory of the process in bytes.
+#[cfg(target_os = "linux")]
+pub fn peak_memory() -> Option<usize> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmHWM:"))?;
+    let kb = line
+        .trim_start_matches("VmHWM:")
+        .trim()
+        .trim_end_matches("kB")
+        .trim();
+    kb.parse::<usize>().ok().map(|kb| kb * 1024)
+}
+
+/// Returns the peak resident memory of the process in bytes.
+#[cfg(not(target_os = "linux"))]
+pub fn peak_memory() -> Option<usize> {
+    None
+}
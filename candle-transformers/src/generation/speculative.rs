@@ -0,0 +1,219 @@
+//! Speculative decoding with a draft model.
+//!
+//! A small draft model proposes `num_draft_tokens` tokens one at a time, these are then verified
+//! by the large target model in a single forward pass. Each draft token is accepted with
+//! probability `min(1, p / q)` where `p` and `q` are the probabilities of the token under the
+//! target and draft distributions. On the first rejection a token is sampled from the residual
+//! distribution `max(0, p - q)`, and when all the draft tokens are accepted a bonus token is
+//! sampled from the target distribution. The generated tokens follow the same distribution as
+//! when sampling from the target model alone, see "Fast Inference from Transformers via
+//! Speculative Decoding" <https://arxiv.org/abs/2211.17192>.
+//!
+//! The two models must share a tokenizer. Both of them are [`CausalLM`] with the following
+//! requirements:
+//! - the draft model returns the logits for the last position of its input, the target model
+//!   the logits for all the positions of its input, with shape `(1, seq_len, vocab)` or
+//!   `(seq_len, vocab)`.
+//! - calling a model at `index_pos` drops the positions after `index_pos` from its kv cache, so
+//!   that the rejected tokens are discarded, and calling it at position 0 starts a new sequence.
+//!
+//! ```rust,no_run
+//! use candle::{Device, Tensor};
+//! use candle_transformers::generation::speculative::SpeculativeGenerator;
+//! use candle_transformers::generation::Sampling;
+//! # fn run(
+//! #     draft: impl FnMut(&Tensor, usize) -> candle::Result<Tensor>,
+//! #     target: impl FnMut(&Tensor, usize) -> candle::Result<Tensor>,
+//! # ) -> candle::Result<()> {
+//! let sampling = Sampling::new(0.8, None, None);
+//! let mut generator = SpeculativeGenerator::new(draft, target, 4, sampling, 42, &Device::Cpu);
+//! let tokens = generator.generate(&[1, 15043], 100, &[2])?;
+//! println!("{tokens:?}, acceptance rate {:.2}", generator.acceptance_rate());
+//! # Ok(())
+//! # }
+//! ```
+use super::ensemble::CausalLM;
+use super::{LogitsProcessor, Sampling};
+use candle::{Device, Result, Tensor};
+use rand::{Rng, SeedableRng};
+
+/// Generates tokens from a target model, using a draft model to propose the tokens.
+pub struct SpeculativeGenerator<D: CausalLM, T: CausalLM> {
+    draft: D,
+    target: T,
+    num_draft_tokens: usize,
+    sampling: Sampling,
+    rng: rand::rngs::StdRng,
+    device: Device,
+    /// The prompt and the generated tokens.
+    tokens: Vec<u32>,
+    /// The number of tokens in the kv cache of each model, the following tokens still have to
+    /// be processed.
+    draft_len: usize,
+    target_len: usize,
+    num_drafted: usize,
+    num_accepted: usize,
+}
+
+impl<D: CausalLM, T: CausalLM> SpeculativeGenerator<D, T> {
+    pub fn new(
+        draft: D,
+        target: T,
+        num_draft_tokens: usize,
+        sampling: Sampling,
+        seed: u64,
+        device: &Device,
+    ) -> Self {
+        Self {
+            draft,
+            target,
+            num_draft_tokens,
+            sampling,
+            rng: rand::rngs::StdRng::seed_from_u64(seed),
+            device: device.clone(),
+            tokens: vec![],
+            draft_len: 0,
+            target_len: 0,
+            num_drafted: 0,
+            num_accepted: 0,
+        }
+    }
+
+    /// Starts a new sequence, the prompt is processed by the next call to [`Self::step`].
+    pub fn set_prompt(&mut self, prompt: &[u32]) -> Result<()> {
+        if prompt.is_empty() {
+            candle::bail!("speculative decoding: empty prompt")
+        }
+        self.tokens = prompt.to_vec();
+        self.draft_len = 0;
+        self.target_len = 0;
+        Ok(())
+    }
+
+    /// The prompt followed by the generated tokens.
+    pub fn tokens(&self) -> &[u32] {
+        &self.tokens
+    }
+
+    /// The fraction of the draft tokens that have been accepted by the target model.
+    pub fn acceptance_rate(&self) -> f64 {
+        self.num_accepted as f64 / self.num_drafted.max(1) as f64
+    }
+
+    /// Runs the draft model on the tokens that are not in its kv cache and returns the
+    /// distribution of the next token.
+    fn draft_forward(&mut self, tokens: &[u32]) -> Result<Vec<f32>> {
+        let input = Tensor::new(tokens, &self.device)?.unsqueeze(0)?;
+        let logits = self.draft.forward(&input, self.draft_len)?.flatten_all()?;
+        self.draft_len += tokens.len();
+        self.sampling.distribution(&logits)
+    }
+
+    /// Proposes `num_draft_tokens` tokens with the draft model, verifies them with the target
+    /// model and returns the new tokens: the accepted draft tokens followed by a token sampled
+    /// from the target model.
+    pub fn step(&mut self) -> Result<Vec<u32>> {
+        if self.tokens.is_empty() {
+            candle::bail!("speculative decoding: the prompt has not been set")
+        }
+        let num_tokens = self.tokens.len();
+        let mut draft_tokens = Vec::with_capacity(self.num_draft_tokens);
+        let mut draft_prs = Vec::with_capacity(self.num_draft_tokens);
+        for _ in 0..self.num_draft_tokens {
+            let prs = match draft_tokens.last() {
+                None => {
+                    let pending = self.tokens[self.draft_len..].to_vec();
+                    self.draft_forward(&pending)?
+                }
+                Some(&token) => self.draft_forward(&[token])?,
+            };
+            draft_tokens.push(LogitsProcessor::sample_multinomial(&prs, &mut self.rng)?);
+            draft_prs.push(prs)
+        }
+
+        // A single forward pass of the target model returns the distributions for all the draft
+        // tokens and for the token following them.
+        let input = [&self.tokens[self.target_len..], draft_tokens.as_slice()].concat();
+        let input_len = input.len();
+        let input = Tensor::new(input, &self.device)?.unsqueeze(0)?;
+        let logits = self.target.forward(&input, self.target_len)?;
+        let logits = match logits.rank() {
+            2 => logits,
+            3 => logits.squeeze(0)?,
+            _ => candle::bail!(
+                "speculative decoding: unexpected logits shape {:?}",
+                logits.shape()
+            ),
+        };
+        if logits.dim(0)? != input_len {
+            candle::bail!(
+                "speculative decoding: got {} target logits for {input_len} tokens",
+                logits.dim(0)?
+            )
+        }
+        let logits = logits.narrow(
+            0,
+            input_len - draft_tokens.len() - 1,
+            draft_tokens.len() + 1,
+        )?;
+
+        let mut new_tokens = Vec::with_capacity(draft_tokens.len() + 1);
+        let mut replacement = None;
+        for (i, (&token, q)) in draft_tokens.iter().zip(draft_prs.iter()).enumerate() {
+            let p = self.sampling.distribution(&logits.get(i)?)?;
+            if self.rng.gen::<f32>() * q[token as usize] < p[token as usize] {
+                new_tokens.push(token);
+                continue;
+            }
+            let mut residual: Vec<f32> = p.iter().zip(q).map(|(p, q)| (p - q).max(0.)).collect();
+            if residual.iter().all(|&v| v == 0.) {
+                // Only happens because of rounding errors when p and q are identical.
+                residual = p
+            }
+            replacement = Some(LogitsProcessor::sample_multinomial(
+                &residual,
+                &mut self.rng,
+            )?);
+            break;
+        }
+        let num_accepted = new_tokens.len();
+        let next_token = match replacement {
+            Some(token) => token,
+            None => {
+                let p = self.sampling.distribution(&logits.get(num_accepted)?)?;
+                LogitsProcessor::sample_multinomial(&p, &mut self.rng)?
+            }
+        };
+        new_tokens.push(next_token);
+        self.num_drafted += draft_tokens.len();
+        self.num_accepted += num_accepted;
+
+        self.tokens.extend_from_slice(&new_tokens);
+        // The last token is never in the kv caches, and the caches are rewound past the
+        // rejected tokens on the next forward pass.
+        self.target_len = self.tokens.len() - 1;
+        self.draft_len = self.draft_len.min(num_tokens + num_accepted);
+        Ok(new_tokens)
+    }
+
+    /// Generates up to `max_new_tokens` tokens after `prompt`, stopping after any of the
+    /// `stop_tokens`, and returns the generated tokens.
+    pub fn generate(
+        &mut self,
+        prompt: &[u32],
+        max_new_tokens: usize,
+        stop_tokens: &[u32],
+    ) -> Result<Vec<u32>> {
+        self.set_prompt(prompt)?;
+        let mut generated = vec![];
+        while generated.len() < max_new_tokens {
+            for token in self.step()? {
+                generated.push(token);
+                if stop_tokens.contains(&token) || generated.len() == max_new_tokens {
+                    return Ok(generated);
+                }
+            }
+        }
+        Ok(generated)
+    }
+}
@@ -0,0 +1,151 @@
+//! Activation steering, "Steering Language Models With Activation Engineering",
+//! <https://arxiv.org/abs/2308.10248>.
+//!
+//! A steering vector is added to the hidden states, the residual stream, at the output of some
+//! selected layers for all the positions. This shifts the generated text towards a concept, e.g.
+//! a sentiment or a topic, without any fine-tuning. Steering vectors are usually computed as the
+//! difference between the mean hidden states of two sets of prompts, see
+//! [`Steering::mean_difference`].
+//!
+//! The vectors are stored in safetensors files with one tensor of shape `(hidden_size,)` per
+//! layer, named `layers.{layer_idx}`.
+//!
+//! ```no_run
+//! use candle_transformers::generation::steering::Steering;
+//! # fn main() -> candle::Result<()> {
+//! # let device = candle::Device::Cpu;
+//! let steering = Steering::load("happy.safetensors", &device)?
+//!     // Amplify the vector of layer 12 and disable the one of layer 14.
+//!     .with_scale(12, 4.0)
+//!     .with_scale(14, 0.0);
+//! # Ok(())
+//! # }
+//! ```
+use candle::{DType, Device, Result, Tensor, D};
+use std::collections::BTreeMap;
+
+#[derive(Debug, Clone)]
+struct SteeringVector {
+    vector: Tensor,
+    scale: f64,
+}
+
+/// Steering vectors for some of the layers of a model, each with its own scale.
+#[derive(Debug, Clone, Default)]
+pub struct Steering {
+    layers: BTreeMap<usize, SteeringVector>,
+}
+
+impl Steering {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the steering vector for layer `layer_idx`, replacing the existing one if any. The
+    /// vector has shape `(hidden_size,)` and is multiplied by `scale` before being added.
+    pub fn with_vector(mut self, layer_idx: usize, vector: Tensor, scale: f64) -> Result<Self> {
+        let vector = vector.flatten_all()?;
+        self.layers
+            .insert(layer_idx, SteeringVector { vector, scale });
+        Ok(self)
+    }
+
+    /// Sets the scale of the vector for layer `layer_idx`, this has no effect if there is no
+    /// vector for this layer.
+    pub fn with_scale(mut self, layer_idx: usize, scale: f64) -> Self {
+        self.set_scale(layer_idx, scale);
+        self
+    }
+
+    pub fn set_scale(&mut self, layer_idx: usize, scale: f64) {
+        if let Some(v) = self.layers.get_mut(&layer_idx) {
+            v.scale = scale
+        }
+    }
+
+    /// Multiplies the scales of all the vectors by `scale`.
+    pub fn with_global_scale(mut self, scale: f64) -> Self {
+        for v in self.layers.values_mut() {
+            v.scale *= scale
+        }
+        self
+    }
+
+    /// Only keeps the vectors for the given layers.
+    pub fn with_layers(mut self, layers: &[usize]) -> Self {
+        self.layers.retain(|idx, _| layers.contains(idx));
+        self
+    }
+
+    pub fn scale(&self, layer_idx: usize) -> Option<f64> {
+        self.layers.get(&layer_idx).map(|v| v.scale)
+    }
+
+    /// The indexes of the steered layers in increasing order.
+    pub fn layers(&self) -> Vec<usize> {
+        self.layers.keys().copied().collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.layers.is_empty()
+    }
+
+    /// Loads steering vectors from a safetensors file, all the scales are set to 1.
+    pub fn load<P: AsRef<std::path::Path>>(p: P, device: &Device) -> Result<Self> {
+        let p = p.as_ref();
+        let tensors = candle::safetensors::load(p, device)?;
+        let mut steering = Self::new();
+        for (name, vector) in tensors {
+            let layer_idx = name
+                .strip_prefix("layers.")
+                .and_then(|idx| idx.parse::<usize>().ok());
+            match layer_idx {
+                Some(layer_idx) => steering = steering.with_vector(layer_idx, vector, 1.0)?,
+                None => candle::bail!("unexpected steering vector name {name} in {p:?}"),
+            }
+        }
+        Ok(steering)
+    }
+
+    /// Saves the steering vectors to a safetensors file, the scales are applied to the vectors.
+    pub fn save<P: AsRef<std::path::Path>>(&self, p: P) -> Result<()> {
+        let tensors = self
+            .layers
+            .iter()
+            .map(|(idx, v)| Ok((format!("layers.{idx}"), (&v.vector * v.scale)?)))
+            .collect::<Result<std::collections::HashMap<_, _>>>()?;
+        candle::safetensors::save(&tensors, p)
+    }
+
+    /// Computes a steering vector as the difference between the mean of `positive` and the mean
+    /// of `negative`, two sets of hidden states with the hidden dimension as last dimension, e.g.
+    /// of shape `(num_prompts, seq_len, hidden_size)`.
+    pub fn mean_difference(positive: &Tensor, negative: &Tensor) -> Result<Tensor> {
+        let mean = |xs: &Tensor| {
+            let hidden_size = xs.dim(D::Minus1)?;
+            xs.to_dtype(DType::F32)?.reshape(((), hidden_size))?.mean(0)
+        };
+        mean(positive)? - mean(negative)?
+    }
+
+    /// Adds the scaled steering vector for layer `layer_idx` to `xs`, the output hidden states of
+    /// this layer with shape `(batch, seq_len, hidden_size)`. `xs` is returned unchanged if there
+    /// is no vector for this layer.
+    pub fn apply(&self, layer_idx: usize, xs: &Tensor) -> Result<Tensor> {
+        match self.layers.get(&layer_idx) {
+            None => Ok(xs.clone()),
+            Some(v) if v.scale == 0. => Ok(xs.clone()),
+            Some(v) => {
+                let hidden_size = xs.dim(D::Minus1)?;
+                if v.vector.elem_count() != hidden_size {
+                    candle::bail!(
+                        "steering vector for layer {layer_idx} has {} elements, expected {hidden_size}",
+                        v.vector.elem_count()
+                    )
+                }
+                let delta = (v.vector.to_device(xs.device())? * v.scale)?.to_dtype(xs.dtype())?;
+                xs.broadcast_add(&delta)
+            }
+        }
+    }
+}
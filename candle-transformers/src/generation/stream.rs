@@ -0,0 +1,203 @@
+//! Async token generation, available with the `tokio` feature.
+//!
+//! [`generate`] runs the generation loop on the tokio blocking thread pool so that the async
+//! executor is never blocked by the model computations, and yields the sampled tokens through a
+//! [`TokenStream`]. This makes it easy to stream tokens from an async server, e.g. as server-sent
+//! events. Dropping the stream stops the generation after the current token.
+//!
+//! ```rust,no_run
+//! use candle::{Device, Tensor};
+//! use candle_transformers::generation::stream::{generate, StreamConfig};
+//! use candle_transformers::generation::LogitsProcessor;
+//! use tokio_stream::StreamExt;
+//! # async fn run(
+//! #     forward: impl FnMut(&Tensor, usize) -> candle::Result<Tensor> + Send + 'static,
+//! # ) -> candle::Result<()> {
+//! let config = StreamConfig::new(64).with_stop_tokens(vec![2]);
+//! let logits_processor = LogitsProcessor::new(42, Some(0.8), None);
+//! let mut tokens = generate(forward, vec![1, 15043], logits_processor, Device::Cpu, config);
+//! while let Some(token) = tokens.next().await {
+//!     println!("{}", token?);
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`ContinuousBatcher`] serves multiple requests on a shared model, the decode steps of the
+//! concurrent requests being batched together, see [`batching`](super::batching).
+use super::batching::{BatchedLM, Batcher, BatchingConfig};
+use super::ensemble::CausalLM;
+use super::events::{self, GenerationConfig, OnToken};
+use super::LogitsProcessor;
+use candle::{Device, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio_stream::wrappers::ReceiverStream;
+
+/// When to stop the generation, and how many tokens can be buffered when the stream consumer is
+/// slower than the model.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StreamConfig {
+    pub max_new_tokens: usize,
+    /// The generation stops after one of these tokens has been sampled, the stop token is
+    /// included in the stream.
+    pub stop_tokens: Vec<u32>,
+    pub buffer_size: usize,
+}
+
+impl StreamConfig {
+    pub fn new(max_new_tokens: usize) -> Self {
+        Self {
+            max_new_tokens,
+            stop_tokens: vec![],
+            buffer_size: 16,
+        }
+    }
+
+    pub fn with_stop_tokens(mut self, stop_tokens: Vec<u32>) -> Self {
+        self.stop_tokens = stop_tokens;
+        self
+    }
+
+    pub fn with_buffer_size(mut self, buffer_size: usize) -> Self {
+        self.buffer_size = buffer_size;
+        self
+    }
+}
+
+/// The stream of generated tokens returned by [`generate`]. A model or sampling error is
+/// yielded as the last item of the stream.
+#[derive(Debug)]
+pub struct TokenStream {
+    inner: ReceiverStream<Result<u32>>,
+}
+
+impl tokio_stream::Stream for TokenStream {
+    type Item = Result<u32>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+/// Generates up to `config.max_new_tokens` tokens after `prompt` on the tokio blocking pool.
+///
+/// `model` is called with the prompt at position 0 and then with each sampled token, it should
+/// use a fresh kv cache. This must be called from within a tokio runtime.
+pub fn generate<M>(
+    mut model: M,
+    prompt: Vec<u32>,
+    mut logits_processor: LogitsProcessor,
+    device: Device,
+    config: StreamConfig,
+) -> TokenStream
+where
+    M: CausalLM + Send + 'static,
+{
+    let (tx, rx) = tokio::sync::mpsc::channel(config.buffer_size.max(1));
+    tokio::task::spawn_blocking(move || {
+        let config =
+            GenerationConfig::new(config.max_new_tokens).with_stop_tokens(config.stop_tokens);
+        let res = events::generate(
+            &mut model,
+            &prompt,
+            &mut logits_processor,
+            &device,
+            &config,
+            // Stop when the consumer has gone away.
+            OnToken(|token| tx.blocking_send(Ok(token)).is_ok()),
+        );
+        if let Err(err) = res {
+            // The consumer may have gone away already, in which case there is no one to report
+            // the error to.
+            let _ = tx.blocking_send(Err(err));
+        }
+    });
+    TokenStream {
+        inner: ReceiverStream::new(rx),
+    }
+}
+
+struct BatchRequest {
+    prompt: Vec<u32>,
+    logits_processor: LogitsProcessor,
+    config: GenerationConfig,
+    tx: tokio::sync::mpsc::Sender<Result<u32>>,
+}
+
+/// Runs a [`Batcher`] on a dedicated thread, the requests being admitted as they arrive and
+/// their tokens being returned as [`TokenStream`]s. Dropping a stream cancels its request, the
+/// thread stops once all the clones of the batcher have been dropped and the remaining requests
+/// have completed.
+#[derive(Clone)]
+pub struct ContinuousBatcher {
+    requests: std::sync::mpsc::Sender<BatchRequest>,
+}
+
+impl ContinuousBatcher {
+    pub fn new<M>(model: M, device: Device, config: BatchingConfig) -> Self
+    where
+        M: BatchedLM + Send + 'static,
+        M::Cache: Send,
+    {
+        let (requests, rx) = std::sync::mpsc::channel::<BatchRequest>();
+        let mut batcher = Batcher::new(model, device, config);
+        std::thread::spawn(move || {
+            let add = |batcher: &mut Batcher<M>, request: BatchRequest| {
+                let tx = request.tx;
+                // The channel has room for all the tokens and the error, so that a slow
+                // consumer never blocks the other sequences of the batch.
+                let on_token = move |token: Result<u32>| tx.try_send(token).is_ok();
+                batcher.add(
+                    request.prompt,
+                    request.logits_processor,
+                    request.config,
+                    on_token,
+                )
+            };
+            loop {
+                // Wait for a request when there is nothing to run.
+                if batcher.is_idle() {
+                    match rx.recv() {
+                        Ok(request) => add(&mut batcher, request),
+                        Err(_) => break,
+                    }
+                }
+                while let Ok(request) = rx.try_recv() {
+                    add(&mut batcher, request)
+                }
+                batcher.step()
+            }
+        });
+        Self { requests }
+    }
+
+    /// Queues a request, the tokens are generated as described in [`events::generate`].
+    pub fn generate(
+        &self,
+        prompt: Vec<u32>,
+        logits_processor: LogitsProcessor,
+        config: GenerationConfig,
+    ) -> TokenStream {
+        let capacity = config.max_new_tokens.saturating_add(1);
+        let capacity = capacity.min(tokio::sync::Semaphore::MAX_PERMITS);
+        let (tx, rx) = tokio::sync::mpsc::channel(capacity);
+        let request = BatchRequest {
+            prompt,
+            logits_processor,
+            config,
+            tx,
+        };
+        if let Err(std::sync::mpsc::SendError(request)) = self.requests.send(request) {
+            let err = candle::Error::Msg("the batcher thread has stopped".to_string());
+            let _ = request.tx.try_send(Err(err));
+        }
+        TokenStream {
+            inner: ReceiverStream::new(rx),
+        }
+    }
+}
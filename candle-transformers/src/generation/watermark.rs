@@ -0,0 +1,146 @@
+//! Watermarking of generated text, following "A Watermark for Large Language Models",
+//! <https://arxiv.org/abs/2301.10226>.
+//!
+//! At each step, the vocabulary is split pseudo-randomly into a "green" and a "red" list based
+//! on the previous token and a secret key, and a bias is added to the logits of the green
+//! tokens. The resulting text contains more green tokens than expected by chance, this can be
+//! detected from the token ids alone, without access to the model, by anyone knowing the key.
+//!
+//! ```rust
+//! use candle::{Device, Tensor};
+//! use candle_transformers::generation::watermark::Watermark;
+//! # fn main() -> candle::Result<()> {
+//! let watermark = Watermark::new(42);
+//! let logits = Tensor::zeros(1000, candle::DType::F32, &Device::Cpu)?;
+//! // Bias the logits before sampling, 7 being the previous token.
+//! let logits = watermark.apply(&logits, 7)?;
+//! // Later on, score some generated tokens.
+//! let detection = watermark.detect(&[7, 12, 5, 998]);
+//! println!("z-score: {:.2}", detection.z_score);
+//! # Ok(())
+//! # }
+//! ```
+use candle::{Result, Tensor, D};
+
+// The splitmix64 finalizer, a fast hash with good statistical properties. This is used rather
+// than an rng from the rand crate so that the green lists are stable across versions.
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+/// The watermark parameters, the same parameters have to be used for generation and detection.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Watermark {
+    key: u64,
+    gamma: f64,
+    delta: f64,
+}
+
+/// The result of scoring a sequence of tokens for a watermark.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Detection {
+    /// The number of tokens that were scored.
+    pub num_tokens: usize,
+    /// The number of green tokens among the scored ones.
+    pub num_green: usize,
+    /// How many standard deviations the number of green tokens is above what would be expected
+    /// for text generated without the watermark.
+    pub z_score: f64,
+}
+
+impl Detection {
+    /// Whether the z-score exceeds `threshold`, the paper uses 4 which corresponds to a false
+    /// positive rate of about 3e-5.
+    pub fn is_watermarked(&self, threshold: f64) -> bool {
+        self.z_score > threshold
+    }
+}
+
+impl Watermark {
+    /// A watermark with the given secret key, using `gamma = 0.25` and `delta = 2`.
+    pub fn new(key: u64) -> Self {
+        Self {
+            key,
+            gamma: 0.25,
+            delta: 2.0,
+        }
+    }
+
+    /// The fraction of the vocabulary in the green list, smaller values give a stronger signal
+    /// per token.
+    pub fn with_gamma(mut self, gamma: f64) -> Self {
+        self.gamma = gamma;
+        self
+    }
+
+    /// The bias added to the logits of the green tokens, larger values make the watermark
+    /// easier to detect but degrade the text quality more.
+    pub fn with_delta(mut self, delta: f64) -> Self {
+        self.delta = delta;
+        self
+    }
+
+    pub fn gamma(&self) -> f64 {
+        self.gamma
+    }
+
+    pub fn delta(&self) -> f64 {
+        self.delta
+    }
+
+    /// Whether `token` is in the green list when following `prev_token`.
+    pub fn is_green(&self, prev_token: u32, token: u32) -> bool {
+        let seed = mix(self.key ^ mix(prev_token as u64));
+        let h = mix(seed ^ token as u64);
+        (h as f64) < self.gamma * u64::MAX as f64
+    }
+
+    /// Adds `delta` to the logits of the green tokens, `logits` has the vocabulary as last
+    /// dimension.
+    pub fn apply(&self, logits: &Tensor, prev_token: u32) -> Result<Tensor> {
+        let vocab_size = logits.dim(D::Minus1)?;
+        let bias = (0..vocab_size as u32)
+            .map(|token| {
+                if self.is_green(prev_token, token) {
+                    self.delta as f32
+                } else {
+                    0.
+                }
+            })
+            .collect::<Vec<_>>();
+        let bias = Tensor::from_vec(bias, vocab_size, logits.device())?.to_dtype(logits.dtype())?;
+        logits.broadcast_add(&bias)
+    }
+
+    /// Scores a sequence of tokens, each token after the first one is checked against the green
+    /// list derived from its predecessor. Repeated token pairs are only counted once as
+    /// repetitive text would otherwise inflate the score.
+    pub fn detect(&self, tokens: &[u32]) -> Detection {
+        let mut seen = std::collections::HashSet::new();
+        let mut num_tokens = 0;
+        let mut num_green = 0;
+        for pair in tokens.windows(2) {
+            if !seen.insert((pair[0], pair[1])) {
+                continue;
+            }
+            num_tokens += 1;
+            if self.is_green(pair[0], pair[1]) {
+                num_green += 1
+            }
+        }
+        let z_score = if num_tokens == 0 {
+            0.
+        } else {
+            let n = num_tokens as f64;
+            let expected = self.gamma * n;
+            (num_green as f64 - expected) / (n * self.gamma * (1. - self.gamma)).sqrt()
+        };
+        Detection {
+            num_tokens,
+            num_green,
+            z_score,
+        }
+    }
+}
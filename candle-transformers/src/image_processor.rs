@@ -0,0 +1,458 @@
+//! Image preprocessing following the `preprocessor_config.json` files of the HF image processors.
+//!
+//! The vision models expect their inputs to be resized, cropped, rescaled and normalized exactly
+//! as during training, small differences such as the resampling filter or the rounding of the
+//! resized dimensions degrading the results in ways that are hard to notice. A [`Processor`]
+//! reads the settings of the HF processor and applies them with candle ops to `(3, height, width)`
+//! images with values in `[0, 255]`, e.g. `u8` tensors.
+//!
+//! The resizing uses the same filters as PIL, including the antialiasing when downscaling, and
+//! rounds the resized values to integers as PIL does for 8 bits images.
+//!
+//! ```rust,no_run
+//! use candle_transformers::image_processor::Processor;
+//! # fn run(img: candle::Tensor) -> candle::Result<()> {
+//! let processor = Processor::load("preprocessor_config.json")?;
+//! // A (3, 224, 224) f32 tensor for a CLIP processor.
+//! let pixel_values = processor.preprocess(&img)?;
+//! # Ok(())
+//! # }
+//! ```
+use candle::{DType, Device, Result, Tensor};
+
+/// A size as found in the HF configs, either a single value or a dictionary.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Deserialize)]
+#[serde(untagged)]
+pub enum SizeConfig {
+    Int(usize),
+    Dict {
+        height: Option<usize>,
+        width: Option<usize>,
+        shortest_edge: Option<usize>,
+        longest_edge: Option<usize>,
+    },
+}
+
+/// A per channel value, HF configs use either a single value or one value per channel.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+#[serde(untagged)]
+pub enum ChannelValues {
+    Scalar(f32),
+    PerChannel(Vec<f32>),
+}
+
+impl ChannelValues {
+    fn to_vec(&self, channels: usize) -> Vec<f32> {
+        match self {
+            Self::Scalar(v) => vec![*v; channels],
+            Self::PerChannel(v) => v.clone(),
+        }
+    }
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_resample() -> u32 {
+    2
+}
+
+fn default_rescale_factor() -> f64 {
+    1. / 255.
+}
+
+/// The fields of a `preprocessor_config.json` file used for preprocessing, the other fields are
+/// ignored.
+#[derive(Debug, Clone, PartialEq, serde::Deserialize)]
+pub struct PreprocessorConfig {
+    #[serde(default = "default_true")]
+    pub do_resize: bool,
+    pub size: Option<SizeConfig>,
+    /// The PIL resampling filter, 0 nearest, 1 lanczos, 2 bilinear, 3 bicubic, 4 box and
+    /// 5 hamming.
+    #[serde(default = "default_resample")]
+    pub resample: u32,
+    #[serde(default)]
+    pub do_center_crop: bool,
+    pub crop_size: Option<SizeConfig>,
+    #[serde(default = "default_true")]
+    pub do_rescale: bool,
+    #[serde(default = "default_rescale_factor")]
+    pub rescale_factor: f64,
+    #[serde(default = "default_true")]
+    pub do_normalize: bool,
+    pub image_mean: Option<ChannelValues>,
+    pub image_std: Option<ChannelValues>,
+    /// The patch size of the vision encoder, used by [`Processor::patchify`].
+    pub patch_size: Option<SizeConfig>,
+}
+
+/// A resampling filter, with the same definitions as PIL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    Nearest,
+    Box,
+    Bilinear,
+    Hamming,
+    Bicubic,
+    Lanczos,
+}
+
+impl Filter {
+    /// The filter for a PIL resampling constant.
+    pub fn from_pil(resample: u32) -> Result<Self> {
+        let filter = match resample {
+            0 => Self::Nearest,
+            1 => Self::Lanczos,
+            2 => Self::Bilinear,
+            3 => Self::Bicubic,
+            4 => Self::Box,
+            5 => Self::Hamming,
+            _ => candle::bail!("unsupported resampling filter {resample}"),
+        };
+        Ok(filter)
+    }
+
+    fn support(&self) -> f64 {
+        match self {
+            Self::Nearest | Self::Box => 0.5,
+            Self::Bilinear | Self::Hamming => 1.,
+            Self::Bicubic => 2.,
+            Self::Lanczos => 3.,
+        }
+    }
+
+    fn eval(&self, x: f64) -> f64 {
+        fn sinc(x: f64) -> f64 {
+            if x == 0. {
+                1.
+            } else {
+                let x = x * std::f64::consts::PI;
+                x.sin() / x
+            }
+        }
+        match self {
+            Self::Nearest | Self::Box => {
+                if (-0.5..0.5).contains(&x) {
+                    1.
+                } else {
+                    0.
+                }
+            }
+            Self::Bilinear => (1. - x.abs()).max(0.),
+            Self::Hamming => {
+                if x.abs() >= 1. {
+                    0.
+                } else if x == 0. {
+                    1.
+                } else {
+                    let x = x * std::f64::consts::PI;
+                    x.sin() / x * (0.54 + 0.46 * x.cos())
+                }
+            }
+            Self::Bicubic => {
+                let a = -0.5;
+                let x = x.abs();
+                if x < 1. {
+                    ((a + 2.) * x - (a + 3.)) * x * x + 1.
+                } else if x < 2. {
+                    (((x - 5.) * x + 8.) * x - 4.) * a
+                } else {
+                    0.
+                }
+            }
+            Self::Lanczos => {
+                if x.abs() < 3. {
+                    sinc(x) * sinc(x / 3.)
+                } else {
+                    0.
+                }
+            }
+        }
+    }
+
+    /// The `(out_size, in_size)` matrix resampling a dimension of `in_size` pixels to `out_size`
+    /// pixels, following the PIL implementation.
+    pub fn resampling_matrix(&self, in_size: usize, out_size: usize) -> Vec<f32> {
+        let mut matrix = vec![0f32; out_size * in_size];
+        let scale = in_size as f64 / out_size as f64;
+        if *self == Self::Nearest {
+            for i in 0..out_size {
+                let src = (((i as f64 + 0.5) * scale) as usize).min(in_size - 1);
+                matrix[i * in_size + src] = 1.;
+            }
+            return matrix;
+        }
+        // Downscaling widens the filter to avoid aliasing.
+        let filter_scale = scale.max(1.);
+        let support = self.support() * filter_scale;
+        for i in 0..out_size {
+            let center = (i as f64 + 0.5) * scale;
+            let xmin = ((center - support + 0.5) as i64).max(0) as usize;
+            let xmax = ((center + support + 0.5) as i64).min(in_size as i64) as usize;
+            let weights = (xmin..xmax)
+                .map(|x| self.eval((x as f64 - center + 0.5) / filter_scale))
+                .collect::<Vec<_>>();
+            let total = weights.iter().sum::<f64>();
+            for (x, w) in (xmin..xmax).zip(weights) {
+                let w = if total == 0. { w } else { w / total };
+                matrix[i * in_size + x] = w as f32;
+            }
+        }
+        matrix
+    }
+}
+
+/// How the images are resized.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResizeStrategy {
+    /// Resize to this exact size, as `(height, width)`.
+    Exact(usize, usize),
+    /// Resize the shortest edge to this size, keeping the aspect ratio, the longest edge being
+    /// limited to `max_longest_edge`.
+    ShortestEdge {
+        shortest_edge: usize,
+        max_longest_edge: Option<usize>,
+    },
+    /// Resize the longest edge to this size, keeping the aspect ratio.
+    LongestEdge(usize),
+}
+
+impl ResizeStrategy {
+    /// The `(height, width)` of an image of size `(h, w)` once resized, using the same roundings
+    /// as the HF processors.
+    pub fn output_size(&self, (h, w): (usize, usize)) -> (usize, usize) {
+        match *self {
+            Self::Exact(height, width) => (height, width),
+            Self::ShortestEdge {
+                shortest_edge,
+                max_longest_edge,
+            } => {
+                let (short, long) = if w <= h { (w, h) } else { (h, w) };
+                let mut new_short = shortest_edge;
+                let mut new_long = shortest_edge * long / short;
+                if let Some(max) = max_longest_edge {
+                    if new_long > max {
+                        new_short = max * new_short / new_long;
+                        new_long = max
+                    }
+                }
+                if w <= h {
+                    (new_long, new_short)
+                } else {
+                    (new_short, new_long)
+                }
+            }
+            Self::LongestEdge(longest_edge) => {
+                let scale = longest_edge as f64 / h.max(w) as f64;
+                let round = |v: usize| ((v as f64 * scale + 0.5) as usize).max(1);
+                (round(h), round(w))
+            }
+        }
+    }
+}
+
+fn square_or_dict(size: SizeConfig) -> Result<(usize, usize)> {
+    match size {
+        SizeConfig::Int(s) => Ok((s, s)),
+        SizeConfig::Dict {
+            height: Some(height),
+            width: Some(width),
+            ..
+        } => Ok((height, width)),
+        _ => candle::bail!("expected a size with a height and a width, got {size:?}"),
+    }
+}
+
+/// Resizes a `(channels, height, width)` float tensor to `(channels, out_h, out_w)`.
+pub fn resize(img: &Tensor, out_h: usize, out_w: usize, filter: Filter) -> Result<Tensor> {
+    let (_c, h, w) = img.dims3()?;
+    let dev = img.device();
+    let rows = filter.resampling_matrix(h, out_h);
+    let rows = Tensor::from_vec(rows, (out_h, h), dev)?.to_dtype(img.dtype())?;
+    let cols = filter.resampling_matrix(w, out_w);
+    let cols = Tensor::from_vec(cols, (out_w, w), dev)?.to_dtype(img.dtype())?;
+    let img = img.broadcast_matmul(&cols.t()?)?;
+    rows.broadcast_matmul(&img)
+}
+
+/// Preprocesses images according to a [`PreprocessorConfig`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Processor {
+    pub resize: Option<(ResizeStrategy, Filter)>,
+    /// The `(height, width)` of the center crop.
+    pub center_crop: Option<(usize, usize)>,
+    pub rescale_factor: Option<f64>,
+    /// The per channel means and standard deviations.
+    pub normalize: Option<(Vec<f32>, Vec<f32>)>,
+    /// The `(height, width)` of the patches.
+    pub patch_size: Option<(usize, usize)>,
+}
+
+impl Processor {
+    pub fn from_config(config: &PreprocessorConfig) -> Result<Self> {
+        let center_crop = match (config.do_center_crop, config.crop_size) {
+            (true, Some(crop_size)) => Some(square_or_dict(crop_size)?),
+            (true, None) => candle::bail!("do_center_crop is set without a crop_size"),
+            (false, _) => None,
+        };
+        let resize = match (config.do_resize, config.size) {
+            (false, _) => None,
+            (true, None) => candle::bail!("do_resize is set without a size"),
+            (true, Some(size)) => {
+                let strategy = match size {
+                    // A single value is the shortest edge for the processors that crop the
+                    // resized image, e.g. CLIP, and the size of a square otherwise, e.g. ViT.
+                    SizeConfig::Int(s) if center_crop.is_some() => ResizeStrategy::ShortestEdge {
+                        shortest_edge: s,
+                        max_longest_edge: None,
+                    },
+                    SizeConfig::Int(s) => ResizeStrategy::Exact(s, s),
+                    SizeConfig::Dict {
+                        height: Some(height),
+                        width: Some(width),
+                        ..
+                    } => ResizeStrategy::Exact(height, width),
+                    SizeConfig::Dict {
+                        shortest_edge: Some(shortest_edge),
+                        longest_edge,
+                        ..
+                    } => ResizeStrategy::ShortestEdge {
+                        shortest_edge,
+                        max_longest_edge: longest_edge,
+                    },
+                    SizeConfig::Dict {
+                        longest_edge: Some(longest_edge),
+                        ..
+                    } => ResizeStrategy::LongestEdge(longest_edge),
+                    _ => candle::bail!("unsupported size {size:?}"),
+                };
+                Some((strategy, Filter::from_pil(config.resample)?))
+            }
+        };
+        let normalize = match (config.do_normalize, &config.image_mean, &config.image_std) {
+            (false, _, _) => None,
+            (true, Some(mean), Some(std)) => Some((mean.to_vec(3), std.to_vec(3))),
+            (true, _, _) => candle::bail!("do_normalize is set without image_mean and image_std"),
+        };
+        let patch_size = config.patch_size.map(square_or_dict).transpose()?;
+        Ok(Self {
+            resize,
+            center_crop,
+            rescale_factor: config.do_rescale.then_some(config.rescale_factor),
+            normalize,
+            patch_size,
+        })
+    }
+
+    pub fn from_json(json: &str) -> Result<Self> {
+        let config: PreprocessorConfig = serde_json::from_str(json).map_err(candle::Error::wrap)?;
+        Self::from_config(&config)
+    }
+
+    /// Reads a `preprocessor_config.json` file.
+    pub fn load<P: AsRef<std::path::Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        let json = std::fs::read_to_string(p).map_err(|e| candle::Error::from(e).with_path(p))?;
+        Self::from_json(&json).map_err(|e| e.with_path(p))
+    }
+
+    /// The `(height, width)` of the preprocessed image for an input of size `(h, w)`.
+    pub fn output_size(&self, (h, w): (usize, usize)) -> (usize, usize) {
+        let size = match &self.resize {
+            Some((strategy, _)) => strategy.output_size((h, w)),
+            None => (h, w),
+        };
+        self.center_crop.unwrap_or(size)
+    }
+
+    /// Preprocesses a `(channels, height, width)` image with values in `[0, 255]`, returns a
+    /// `f32` tensor.
+    pub fn preprocess(&self, img: &Tensor) -> Result<Tensor> {
+        let (c, h, w) = img.dims3()?;
+        let mut img = img.to_dtype(DType::F32)?;
+        if let Some((strategy, filter)) = &self.resize {
+            let (out_h, out_w) = strategy.output_size((h, w));
+            if (out_h, out_w) != (h, w) {
+                // PIL resizes 8 bits images to 8 bits images.
+                img = resize(&img, out_h, out_w, *filter)?
+                    .round()?
+                    .clamp(0f32, 255f32)?;
+            }
+        }
+        if let Some((crop_h, crop_w)) = self.center_crop {
+            img = center_crop(&img, crop_h, crop_w)?
+        }
+        if let Some(factor) = self.rescale_factor {
+            img = (img * factor)?
+        }
+        if let Some((mean, std)) = &self.normalize {
+            if mean.len() != c || std.len() != c {
+                candle::bail!(
+                    "expected {c} normalization values, got {} means and {} stds",
+                    mean.len(),
+                    std.len()
+                )
+            }
+            let mean = Tensor::from_slice(mean, (c, 1, 1), img.device())?;
+            let std = Tensor::from_slice(std, (c, 1, 1), img.device())?;
+            img = img.broadcast_sub(&mean)?.broadcast_div(&std)?
+        }
+        Ok(img)
+    }
+
+    /// Preprocesses images with the same output size and stacks them as a
+    /// `(batch, channels, height, width)` tensor.
+    pub fn preprocess_batch(&self, imgs: &[Tensor]) -> Result<Tensor> {
+        let imgs = imgs
+            .iter()
+            .map(|img| self.preprocess(img))
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::stack(&imgs, 0)
+    }
+
+    /// Splits a preprocessed `(channels, height, width)` image in non-overlapping patches,
+    /// returns a `(num_patches, channels * patch_h * patch_w)` tensor with the patches in row
+    /// major order.
+    pub fn patchify(&self, img: &Tensor) -> Result<Tensor> {
+        let (patch_h, patch_w) = match self.patch_size {
+            Some(patch_size) => patch_size,
+            None => candle::bail!("patchify: no patch size in the processor config"),
+        };
+        let (c, h, w) = img.dims3()?;
+        if h % patch_h != 0 || w % patch_w != 0 {
+            candle::bail!("patchify: the image size {h}x{w} is not a multiple of the patch size")
+        }
+        let (n_h, n_w) = (h / patch_h, w / patch_w);
+        img.reshape((c, n_h, patch_h, n_w, patch_w))?
+            .permute((1, 3, 0, 2, 4))?
+            .reshape((n_h * n_w, c * patch_h * patch_w))
+    }
+}
+
+/// Crops the center of a `(channels, height, width)` image, the image is padded with zeros when
+/// it is smaller than the crop.
+pub fn center_crop(img: &Tensor, crop_h: usize, crop_w: usize) -> Result<Tensor> {
+    let (_c, h, w) = img.dims3()?;
+    let mut img = img.clone();
+    if crop_h > h {
+        let top = (crop_h - h) / 2;
+        img = img.pad_with_zeros(1, top, crop_h - h - top)?
+    } else {
+        img = img.narrow(1, (h - crop_h) / 2, crop_h)?
+    }
+    if crop_w > w {
+        let left = (crop_w - w) / 2;
+        img = img.pad_with_zeros(2, left, crop_w - w - left)?
+    } else {
+        img = img.narrow(2, (w - crop_w) / 2, crop_w)?
+    }
+    Ok(img)
+}
+
+/// Builds a `(3, height, width)` `u8` image from interleaved RGB bytes, as returned by most image
+/// decoders.
+pub fn from_rgb8(data: &[u8], height: usize, width: usize, device: &Device) -> Result<Tensor> {
+    Tensor::from_slice(data, (height, width, 3), device)?.permute((2, 0, 1))
+}
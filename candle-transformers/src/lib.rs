@@ -1,7 +1,14 @@
 pub mod generation;
+pub mod image_processor;
 pub mod models;
 pub mod object_detection;
+pub mod offload;
+pub mod paged_kv_cache;
+pub mod pipeline_parallel;
 pub mod pipelines;
+pub mod prompt_cache;
+pub mod quantize;
 pub mod quantized_nn;
 pub mod quantized_var_builder;
 pub mod utils;
+pub mod vector_index;
@@ -1,7 +1,9 @@
 use super::with_tracing::{linear_no_bias as linear, Linear, RmsNorm};
 use candle::{DType, Device, IndexOp, Result, Tensor, D};
-use candle_nn::{embedding, Embedding, Module, VarBuilder};
-use std::{collections::HashMap, f32::consts::PI};
+use candle_nn::calibration::Calibrator;
+use candle_nn::rotary_emb::{RopeScaling, RotaryConfig, RotaryEmbeddingCache};
+use candle_nn::{embedding, pruning, Embedding, Module, VarBuilder};
+use std::collections::HashMap;
 
 pub const DEFAULT_MAX_SEQ_LEN: usize = 4096;
 
@@ -29,6 +31,30 @@ pub enum LlamaEosToks {
     Multiple(Vec<u32>),
 }
 
+impl LlamaEosToks {
+    pub fn contains(&self, token: u32) -> bool {
+        match self {
+            Self::Single(eos) => *eos == token,
+            Self::Multiple(eos) => eos.contains(&token),
+        }
+    }
+
+    /// Adds a token, e.g. `<|eot_id|>` which ends the turns of the llama 3 instruct models but is
+    /// not listed in the config of some of them.
+    pub fn with_token(self, token: u32) -> Self {
+        if self.contains(token) {
+            return self;
+        }
+        match self {
+            Self::Single(eos) => Self::Multiple(vec![eos, token]),
+            Self::Multiple(mut eos) => {
+                eos.push(token);
+                Self::Multiple(eos)
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct LlamaConfig {
     pub hidden_size: usize,
@@ -37,6 +63,8 @@ pub struct LlamaConfig {
     pub num_hidden_layers: usize,
     pub num_attention_heads: usize,
     pub num_key_value_heads: Option<usize>,
+    /// The dimension of the attention heads, `hidden_size / num_attention_heads` when not set.
+    pub head_dim: Option<usize>,
     pub rms_norm_eps: f64,
     #[serde(default = "default_rope")]
     pub rope_theta: f32,
@@ -66,6 +94,9 @@ impl LlamaConfig {
             num_hidden_layers: self.num_hidden_layers,
             num_attention_heads: self.num_attention_heads,
             num_key_value_heads: self.num_key_value_heads(),
+            head_dim: self
+                .head_dim
+                .unwrap_or(self.hidden_size / self.num_attention_heads),
             rms_norm_eps: self.rms_norm_eps,
             rope_theta: self.rope_theta,
             use_flash_attn,
@@ -86,6 +117,7 @@ pub struct Config {
     pub num_hidden_layers: usize,
     pub num_attention_heads: usize,
     pub num_key_value_heads: usize,
+    pub head_dim: usize,
     pub use_flash_attn: bool,
     pub rms_norm_eps: f64,
     pub rope_theta: f32,
@@ -105,6 +137,7 @@ impl Config {
             num_hidden_layers: 32,
             num_attention_heads: 32,
             num_key_value_heads: 32,
+            head_dim: 128,
             use_flash_attn,
             rms_norm_eps: 1e-6,
             rope_theta: 10_000.0,
@@ -124,6 +157,7 @@ impl Config {
             num_hidden_layers: 32,
             num_attention_heads: 32,
             num_key_value_heads: 32,
+            head_dim: 128,
             use_flash_attn,
             rms_norm_eps: 1e-5,
             rope_theta: 10_000.0,
@@ -136,88 +170,172 @@ impl Config {
     }
 }
 
+/// The dtypes used when running a model, as opposed to [`Config`] which describes the model
+/// architecture.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModelConfig {
+    /// The dtype used to store the keys and values in the kv cache.
+    pub kv_dtype: DType,
+    /// The dtype of the hidden states.
+    pub activation_dtype: DType,
+    /// Caps the context length below the one of the model, e.g. to avoid computing the rotary
+    /// embeddings of the quantized models for the 128k positions of llama 3.1 when only short
+    /// prompts are used.
+    pub max_seq_len: Option<usize>,
+}
+
+impl Default for ModelConfig {
+    fn default() -> Self {
+        Self::new(DType::F32)
+    }
+}
+
+impl ModelConfig {
+    pub fn new(dtype: DType) -> Self {
+        Self {
+            kv_dtype: dtype,
+            activation_dtype: dtype,
+            max_seq_len: None,
+        }
+    }
+
+    /// Uses a f16 kv cache on cuda and metal devices, and f32 on cpu, the activations are in f32.
+    pub fn for_device(device: &Device) -> Self {
+        let kv_dtype = if device.is_cpu() {
+            DType::F32
+        } else {
+            DType::F16
+        };
+        Self::default().with_kv_dtype(kv_dtype)
+    }
+
+    pub fn with_kv_dtype(self, kv_dtype: DType) -> Self {
+        Self { kv_dtype, ..self }
+    }
+
+    pub fn with_activation_dtype(self, activation_dtype: DType) -> Self {
+        Self {
+            activation_dtype,
+            ..self
+        }
+    }
+
+    pub fn with_max_seq_len(self, max_seq_len: Option<usize>) -> Self {
+        Self {
+            max_seq_len,
+            ..self
+        }
+    }
+
+    /// The context length used for a model trained with `context_length`.
+    pub fn seq_len(&self, context_length: usize) -> usize {
+        match self.max_seq_len {
+            Some(max_seq_len) => max_seq_len.min(context_length),
+            None => context_length,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Cache {
     masks: HashMap<usize, Tensor>,
     pub use_kv_cache: bool,
+    kv_dtype: DType,
     kvs: Vec<Option<(Tensor, Tensor)>>,
-    cos: Tensor,
-    sin: Tensor,
+    rotary: RotaryEmbeddingCache,
+    max_seq_len: usize,
     device: Device,
 }
 
-fn calculate_default_inv_freq(cfg: &Config) -> Vec<f32> {
-    let head_dim = cfg.hidden_size / cfg.num_attention_heads;
-    (0..head_dim)
-        .step_by(2)
-        .map(|i| 1f32 / cfg.rope_theta.powf(i as f32 / head_dim as f32))
-        .collect()
-}
-
 impl Cache {
+    /// Creates a cache where the keys and values are stored using `dtype`, the dtype of the model.
     pub fn new(use_kv_cache: bool, dtype: DType, config: &Config, device: &Device) -> Result<Self> {
-        // precompute freqs_cis
-        let theta = match &config.rope_scaling {
+        Self::from_model_config(use_kv_cache, &ModelConfig::new(dtype), config, device)
+    }
+
+    /// Creates a cache for a model running with the dtypes from `model_config`, the activation
+    /// dtype has to match the dtype of the model weights.
+    pub fn from_model_config(
+        use_kv_cache: bool,
+        model_config: &ModelConfig,
+        config: &Config,
+        device: &Device,
+    ) -> Result<Self> {
+        let scaling = match &config.rope_scaling {
             None
             | Some(Llama3RopeConfig {
                 rope_type: Llama3RopeType::Default,
                 ..
-            }) => calculate_default_inv_freq(config),
-            Some(rope_scaling) => {
-                let low_freq_wavelen = rope_scaling.original_max_position_embeddings as f32
-                    / rope_scaling.low_freq_factor;
-                let high_freq_wavelen = rope_scaling.original_max_position_embeddings as f32
-                    / rope_scaling.high_freq_factor;
-
-                calculate_default_inv_freq(config)
-                    .into_iter()
-                    .map(|freq| {
-                        let wavelen = 2. * PI / freq;
-                        if wavelen < high_freq_wavelen {
-                            freq
-                        } else if wavelen > low_freq_wavelen {
-                            freq / rope_scaling.factor
-                        } else {
-                            let smooth = (rope_scaling.original_max_position_embeddings as f32
-                                / wavelen
-                                - rope_scaling.low_freq_factor)
-                                / (rope_scaling.high_freq_factor - rope_scaling.low_freq_factor);
-                            (1. - smooth) * freq / rope_scaling.factor + smooth * freq
-                        }
-                    })
-                    .collect::<Vec<_>>()
-            }
+            }) => RopeScaling::None,
+            Some(rope_scaling) => RopeScaling::Llama3 {
+                factor: rope_scaling.factor,
+                low_freq_factor: rope_scaling.low_freq_factor,
+                high_freq_factor: rope_scaling.high_freq_factor,
+                original_max_position_embeddings: rope_scaling.original_max_position_embeddings,
+            },
         };
-
-        let theta = Tensor::new(theta, device)?;
-
-        let idx_theta = Tensor::arange(0, config.max_position_embeddings as u32, device)?
-            .to_dtype(DType::F32)?
-            .reshape((config.max_position_embeddings, 1))?
-            .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-        // This is different from the paper, see:
-        // https://github.com/huggingface/transformers/blob/6112b1c6442aaf7affd2b0676a1cd4eee30c45cf/src/transformers/models/llama/modeling_llama.py#L112
-        let cos = idx_theta.cos()?.to_dtype(dtype)?;
-        let sin = idx_theta.sin()?.to_dtype(dtype)?;
+        let rotary = RotaryConfig::new(config.head_dim, config.rope_theta).with_scaling(scaling);
+        // The rotary embeddings are computed for the positions that are used, up to the context
+        // length of the model.
+        let rotary = RotaryEmbeddingCache::new(rotary)?;
+        let max_seq_len = model_config.seq_len(config.max_position_embeddings);
         Ok(Self {
             masks: HashMap::new(),
             use_kv_cache,
+            kv_dtype: model_config.kv_dtype,
             kvs: vec![None; config.num_hidden_layers],
             device: device.clone(),
-            cos,
-            sin,
+            rotary,
+            max_seq_len,
         })
     }
 
-    fn mask(&mut self, t: usize) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
+    /// Saves the kv cache computed for `tokens`, e.g. for a long system prompt, see
+    /// [`prompt_cache`](crate::prompt_cache).
+    pub fn save_kv<P: AsRef<std::path::Path>>(&self, path: P, tokens: &[u32]) -> Result<()> {
+        crate::prompt_cache::save(path, &self.kvs, tokens)
+    }
+
+    /// Replaces the kv cache with one saved with [`Self::save_kv`] and returns the tokens it has
+    /// been computed for. The tokens of a prompt starting with `n` of these tokens, as returned by
+    /// [`prompt_cache::reusable_len`](crate::prompt_cache::reusable_len), can then be processed
+    /// from position `n`, the following cached positions being dropped.
+    pub fn load_kv<P: AsRef<std::path::Path>>(&mut self, path: P) -> Result<Vec<u32>> {
+        let (kvs, tokens) = crate::prompt_cache::load(path, &self.device)?;
+        if kvs.len() != self.kvs.len() {
+            candle::bail!(
+                "the prompt cache has {} layers but the model has {}",
+                kvs.len(),
+                self.kvs.len()
+            )
+        }
+        self.kvs = kvs
+            .into_iter()
+            .map(|kv| match kv {
+                Some((k, v)) => Ok(Some((
+                    k.to_dtype(self.kv_dtype)?,
+                    v.to_dtype(self.kv_dtype)?,
+                ))),
+                None => Ok(None),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(tokens)
+    }
+
+    /// The causal mask for `t` tokens attending to `kv_len` positions, the first `kv_len - t`
+    /// positions coming from the kv cache.
+    fn mask(&mut self, t: usize, kv_len: usize) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t).filter(|_| kv_len == t) {
             Ok(mask.clone())
         } else {
+            let offset = kv_len - t;
             let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .flat_map(|i| (0..kv_len).map(move |j| u8::from(j > i + offset)))
                 .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), &self.device)?;
-            self.masks.insert(t, mask.clone());
+            let mask = Tensor::from_slice(&mask, (t, kv_len), &self.device)?;
+            if kv_len == t {
+                self.masks.insert(t, mask.clone());
+            }
             Ok(mask)
         }
     }
@@ -258,9 +376,14 @@ impl CausalSelfAttention {
     fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize, cache: &Cache) -> Result<Tensor> {
         let _enter = self.span_rot.enter();
         let (_b_sz, _, seq_len, _hidden_size) = x.dims4()?;
-        let cos = cache.cos.narrow(0, index_pos, seq_len)?;
-        let sin = cache.sin.narrow(0, index_pos, seq_len)?;
-        candle_nn::rotary_emb::rope(x, &cos, &sin)
+        if index_pos + seq_len > cache.max_seq_len {
+            candle::bail!(
+                "position {} is beyond the context length {}",
+                index_pos + seq_len,
+                cache.max_seq_len
+            )
+        }
+        cache.rotary.apply(x, index_pos)
     }
 
     fn forward(
@@ -269,9 +392,10 @@ impl CausalSelfAttention {
         index_pos: usize,
         block_idx: usize,
         cache: &mut Cache,
+        calibrator: Option<&mut Calibrator>,
     ) -> Result<Tensor> {
         let _enter = self.span.enter();
-        let (b_sz, seq_len, hidden_size) = x.dims3()?;
+        let (b_sz, seq_len, _hidden_size) = x.dims3()?;
         let q = self.q_proj.forward(x)?;
         let k = self.k_proj.forward(x)?;
         let v = self.v_proj.forward(x)?;
@@ -291,10 +415,19 @@ impl CausalSelfAttention {
         let q = self.apply_rotary_emb(&q, index_pos, cache)?;
         let mut k = self.apply_rotary_emb(&k, index_pos, cache)?;
 
+        let in_dtype = q.dtype();
         if cache.use_kv_cache {
-            if let Some((cache_k, cache_v)) = &cache.kvs[block_idx] {
-                k = Tensor::cat(&[cache_k, &k], 2)?.contiguous()?;
-                v = Tensor::cat(&[cache_v, &v], 2)?.contiguous()?;
+            k = k.to_dtype(cache.kv_dtype)?;
+            v = v.to_dtype(cache.kv_dtype)?;
+            if let Some((cache_k, cache_v)) =
+                cache.kvs[block_idx].as_ref().filter(|_| index_pos > 0)
+            {
+                // The cached positions after index_pos are dropped, e.g. when the prompt only
+                // shares a prefix with a restored prompt cache.
+                let cache_k = cache_k.narrow(2, 0, index_pos.min(cache_k.dim(2)?))?;
+                let cache_v = cache_v.narrow(2, 0, index_pos.min(cache_v.dim(2)?))?;
+                k = Tensor::cat(&[&cache_k, &k], 2)?.contiguous()?;
+                v = Tensor::cat(&[&cache_v, &v], 2)?.contiguous()?;
                 let k_seq_len = k.dims()[1];
                 if k_seq_len > self.max_position_embeddings {
                     k = k
@@ -319,8 +452,8 @@ impl CausalSelfAttention {
             cache.kvs[block_idx] = Some((k.clone(), v.clone()))
         }
 
-        let k = self.repeat_kv(k)?;
-        let v = self.repeat_kv(v)?;
+        let k = self.repeat_kv(k.to_dtype(in_dtype)?)?;
+        let v = self.repeat_kv(v.to_dtype(in_dtype)?)?;
 
         let y = if self.use_flash_attn {
             // flash-attn expects (b_sz, seq_len, nheads, head_dim)
@@ -330,7 +463,6 @@ impl CausalSelfAttention {
             let softmax_scale = 1f32 / (self.head_dim as f32).sqrt();
             flash_attn(&q, &k, &v, softmax_scale, seq_len > 1)?.transpose(1, 2)?
         } else {
-            let in_dtype = q.dtype();
             let q = q.to_dtype(DType::F32)?;
             let k = k.to_dtype(DType::F32)?;
             let v = v.to_dtype(DType::F32)?;
@@ -338,14 +470,22 @@ impl CausalSelfAttention {
             let att = if seq_len == 1 {
                 att
             } else {
-                let mask = cache.mask(seq_len)?.broadcast_as(att.shape())?;
+                let kv_len = k.dim(2)?;
+                let mask = cache.mask(seq_len, kv_len)?.broadcast_as(att.shape())?;
                 masked_fill(&att, &mask, f32::NEG_INFINITY)?
             };
             let att = candle_nn::ops::softmax(&att, D::Minus1)?;
             // Convert to contiguous as matmul doesn't support strided vs for now.
             att.matmul(&v.contiguous()?)?.to_dtype(in_dtype)?
         };
-        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, hidden_size])?;
+        let y = y.transpose(1, 2)?.reshape((
+            b_sz,
+            seq_len,
+            self.num_attention_heads * self.head_dim,
+        ))?;
+        if let Some(calibrator) = calibrator {
+            calibrator.observe(&format!("model.layers.{block_idx}.self_attn.o_proj"), &y)?
+        }
         let y = self.o_proj.forward(&y)?;
         Ok(y)
     }
@@ -358,8 +498,8 @@ impl CausalSelfAttention {
         let span = tracing::span!(tracing::Level::TRACE, "attn");
         let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
         let size_in = cfg.hidden_size;
-        let size_q = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_attention_heads;
-        let size_kv = (cfg.hidden_size / cfg.num_attention_heads) * cfg.num_key_value_heads;
+        let size_q = cfg.head_dim * cfg.num_attention_heads;
+        let size_kv = cfg.head_dim * cfg.num_key_value_heads;
         let q_proj = linear(size_in, size_q, vb.pp("q_proj"))?;
         let k_proj = linear(size_in, size_kv, vb.pp("k_proj"))?;
         let v_proj = linear(size_in, size_kv, vb.pp("v_proj"))?;
@@ -371,7 +511,7 @@ impl CausalSelfAttention {
             o_proj,
             num_attention_heads: cfg.num_attention_heads,
             num_key_value_heads: cfg.num_key_value_heads,
-            head_dim: cfg.hidden_size / cfg.num_attention_heads,
+            head_dim: cfg.head_dim,
             use_flash_attn: cfg.use_flash_attn,
             span,
             span_rot,
@@ -396,9 +536,17 @@ struct Mlp {
 }
 
 impl Mlp {
-    fn forward(&self, x: &Tensor) -> Result<Tensor> {
+    fn forward(
+        &self,
+        x: &Tensor,
+        block_idx: usize,
+        calibrator: Option<&mut Calibrator>,
+    ) -> Result<Tensor> {
         let _enter = self.span.enter();
         let x = (candle_nn::ops::silu(&self.c_fc1.forward(x)?)? * self.c_fc2.forward(x)?)?;
+        if let Some(calibrator) = calibrator {
+            calibrator.observe(&format!("model.layers.{block_idx}.mlp.down_proj"), &x)?
+        }
         self.c_proj.forward(&x)
     }
 
@@ -434,13 +582,18 @@ impl Block {
         index_pos: usize,
         block_idx: usize,
         cache: &mut Cache,
+        mut calibrator: Option<&mut Calibrator>,
     ) -> Result<Tensor> {
         let _enter = self.span.enter();
         let residual = x;
         let x = self.rms_1.forward(x)?;
-        let x = (self.attn.forward(&x, index_pos, block_idx, cache)? + residual)?;
+        let attn = self
+            .attn
+            .forward(&x, index_pos, block_idx, cache, calibrator.as_deref_mut())?;
+        let x = (attn + residual)?;
         let residual = &x;
-        let x = (self.mlp.forward(&self.rms_2.forward(&x)?)? + residual)?;
+        let x = self.rms_2.forward(&x)?;
+        let x = (self.mlp.forward(&x, block_idx, calibrator)? + residual)?;
         Ok(x)
     }
 
@@ -487,7 +640,7 @@ impl Llama {
         let (_, seq_len, _) = input_embed.dims3()?;
         let mut x = input_embed.clone();
         for (block_idx, block) in self.blocks.iter().enumerate() {
-            x = block.forward(&x, index_pos, block_idx, cache)?;
+            x = block.forward(&x, index_pos, block_idx, cache, None)?;
         }
         let x = self.ln_f.forward(&x)?;
         let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
@@ -496,10 +649,33 @@ impl Llama {
     }
 
     pub fn forward(&self, x: &Tensor, index_pos: usize, cache: &mut Cache) -> Result<Tensor> {
+        self.forward_(x, index_pos, cache, None)
+    }
+
+    /// Runs the model while recording the inputs of the attention output projections and of the
+    /// MLP down projections in `calibrator`. The activations are named after the layer consuming
+    /// them, e.g. `model.layers.0.mlp.down_proj`, this is used by [`prune`].
+    pub fn forward_calibrate(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+        calibrator: &mut Calibrator,
+    ) -> Result<Tensor> {
+        self.forward_(x, index_pos, cache, Some(calibrator))
+    }
+
+    fn forward_(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        cache: &mut Cache,
+        mut calibrator: Option<&mut Calibrator>,
+    ) -> Result<Tensor> {
         let (_b_sz, seq_len) = x.dims2()?;
         let mut x = self.wte.forward(x)?;
         for (block_idx, block) in self.blocks.iter().enumerate() {
-            x = block.forward(&x, index_pos, block_idx, cache)?;
+            x = block.forward(&x, index_pos, block_idx, cache, calibrator.as_deref_mut())?;
         }
         let x = self.ln_f.forward(&x)?;
         let x = x.i((.., seq_len - 1, ..))?.contiguous()?;
@@ -527,3 +703,130 @@ impl Llama {
         })
     }
 }
+
+/// The sizes of a llama model after pruning with [`prune`], all the layers are pruned to the
+/// same sizes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PruningTarget {
+    /// The number of MLP hidden units to keep.
+    pub intermediate_size: usize,
+    /// The number of key-value heads to keep, the query heads sharing a key-value head are kept
+    /// or removed together.
+    pub num_key_value_heads: usize,
+}
+
+/// The weights and config of a pruned llama model.
+#[derive(Debug, Clone)]
+pub struct PrunedModel {
+    pub config: Config,
+    pub tensors: HashMap<String, Tensor>,
+}
+
+impl PrunedModel {
+    /// Updates the sizes in the original `config.json`, so that the pruned weights can be loaded
+    /// with the resulting config.
+    pub fn update_config_json(&self, config: &mut serde_json::Value) -> Result<()> {
+        let config = match config.as_object_mut() {
+            Some(config) => config,
+            None => candle::bail!("the llama config is not a json object"),
+        };
+        config.insert(
+            "intermediate_size".to_string(),
+            self.config.intermediate_size.into(),
+        );
+        config.insert(
+            "num_attention_heads".to_string(),
+            self.config.num_attention_heads.into(),
+        );
+        config.insert(
+            "num_key_value_heads".to_string(),
+            self.config.num_key_value_heads.into(),
+        );
+        config.insert("head_dim".to_string(), self.config.head_dim.into());
+        Ok(())
+    }
+}
+
+/// Structured pruning of a llama model, removing MLP hidden units and attention heads so that
+/// the resulting model is a smaller llama model.
+///
+/// `tensors` are the model weights using the usual names, e.g. as returned by
+/// `candle::safetensors::load`. The channels to remove are selected from the weight magnitudes
+/// or, when a calibrator is given, from the weights combined with the activations recorded with
+/// [`Llama::forward_calibrate`], see [`candle_nn::pruning`]. The pruned model usually needs
+/// some fine-tuning to recover its accuracy.
+pub fn prune(
+    tensors: &HashMap<String, Tensor>,
+    cfg: &Config,
+    target: PruningTarget,
+    calibrator: Option<&Calibrator>,
+) -> Result<PrunedModel> {
+    if target.intermediate_size > cfg.intermediate_size
+        || target.num_key_value_heads > cfg.num_key_value_heads
+        || target.num_key_value_heads == 0
+    {
+        candle::bail!(
+            "invalid pruning target {target:?} for intermediate size {} and {} kv heads",
+            cfg.intermediate_size,
+            cfg.num_key_value_heads
+        )
+    }
+    let get = |name: &str| match tensors.get(name) {
+        Some(t) => Ok(t),
+        None => candle::bail!("missing weight {name}"),
+    };
+    let importance = |name: &str, device: &Device| match calibrator {
+        None => Ok(None),
+        Some(c) => c.importance(name, device).map(Some),
+    };
+    let mut pruned = tensors.clone();
+    let group_size = cfg.num_attention_heads / cfg.num_key_value_heads;
+    for layer_idx in 0..cfg.num_hidden_layers {
+        let prefix = format!("model.layers.{layer_idx}");
+
+        let attn = format!("{prefix}.self_attn");
+        let o_proj = get(&format!("{attn}.o_proj.weight"))?;
+        let mean_sq = importance(&format!("{attn}.o_proj"), o_proj.device())?;
+        let scores = pruning::head_scores(o_proj, cfg.num_attention_heads, mean_sq.as_ref())?;
+        let scores = pruning::group_scores(&scores, group_size)?;
+        let kv_heads = pruning::top_k(&scores, target.num_key_value_heads)?;
+        let q_rows =
+            pruning::expand_groups(&pruning::expand_groups(&kv_heads, group_size), cfg.head_dim);
+        let kv_rows = pruning::expand_groups(&kv_heads, cfg.head_dim);
+        for (name, rows) in [
+            ("q_proj", &q_rows),
+            ("k_proj", &kv_rows),
+            ("v_proj", &kv_rows),
+        ] {
+            let name = format!("{attn}.{name}.weight");
+            let w = pruning::select_rows(get(&name)?, rows)?;
+            pruned.insert(name, w);
+        }
+        let o_proj = pruning::select_cols(o_proj, &q_rows)?;
+        pruned.insert(format!("{attn}.o_proj.weight"), o_proj);
+
+        let mlp = format!("{prefix}.mlp");
+        let gate = get(&format!("{mlp}.gate_proj.weight"))?;
+        let up = get(&format!("{mlp}.up_proj.weight"))?;
+        let down = get(&format!("{mlp}.down_proj.weight"))?;
+        let mean_sq = importance(&format!("{mlp}.down_proj"), down.device())?;
+        let scores = pruning::mlp_scores(&[gate, up], down, mean_sq.as_ref())?;
+        let units = pruning::top_k(&scores, target.intermediate_size)?;
+        let gate = pruning::select_rows(gate, &units)?;
+        let up = pruning::select_rows(up, &units)?;
+        let down = pruning::select_cols(down, &units)?;
+        pruned.insert(format!("{mlp}.gate_proj.weight"), gate);
+        pruned.insert(format!("{mlp}.up_proj.weight"), up);
+        pruned.insert(format!("{mlp}.down_proj.weight"), down);
+    }
+    let config = Config {
+        intermediate_size: target.intermediate_size,
+        num_attention_heads: target.num_key_value_heads * group_size,
+        num_key_value_heads: target.num_key_value_heads,
+        ..cfg.clone()
+    };
+    Ok(PrunedModel {
+        config,
+        tensors: pruned,
+    })
+}
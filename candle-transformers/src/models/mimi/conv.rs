@@ -223,7 +223,20 @@ fn get_extra_padding_for_conv1d(
 fn pad1d(xs: &Tensor, pad_l: usize, pad_r: usize, mode: PadMode) -> Result<Tensor> {
     match mode {
         PadMode::Constant => xs.pad_with_zeros(D::Minus1, pad_l, pad_r),
-        PadMode::Reflect => candle::bail!("pad-mode 'reflect' is not supported"),
+        PadMode::Reflect => {
+            // As in the python version, the inputs shorter than the padding are zero padded
+            // first so that the reflection fits.
+            let len = xs.dim(D::Minus1)?;
+            let max_pad = pad_l.max(pad_r);
+            let extra_pad = if len <= max_pad { max_pad + 1 - len } else { 0 };
+            let xs = xs.pad_with_zeros(D::Minus1, 0, extra_pad)?.pad(
+                D::Minus1,
+                pad_l,
+                pad_r,
+                candle::PadMode::Reflect,
+            )?;
+            xs.narrow(D::Minus1, 0, pad_l + len + pad_r)
+        }
         PadMode::Replicate => xs.pad_with_same(D::Minus1, pad_l, pad_r),
     }
 }
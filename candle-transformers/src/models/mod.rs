@@ -54,6 +54,7 @@ pub mod persimmon;
 pub mod phi;
 pub mod phi3;
 pub mod pixtral;
+pub mod quantized_auto;
 pub mod quantized_blip;
 pub mod quantized_blip_text;
 pub mod quantized_llama;
@@ -70,6 +71,7 @@ pub mod quantized_recurrent_gemma;
 pub mod quantized_rwkv_v5;
 pub mod quantized_rwkv_v6;
 pub mod quantized_stable_lm;
+pub mod quantized_starcoder2;
 pub mod quantized_t5;
 pub mod qwen2;
 pub mod qwen2_moe;
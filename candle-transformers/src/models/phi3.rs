@@ -5,7 +5,25 @@ use candle::{DType, Device, Module, Result, Tensor, D};
 use candle_nn::VarBuilder;
 use std::sync::Arc;
 
+/// The rotary embeddings scaling of the 128k context models.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Deserialize)]
+pub enum RopeScalingType {
+    #[serde(rename = "longrope", alias = "su")]
+    LongRope,
+}
+
+/// The per frequency factors dividing the rotary embeddings frequencies, the short ones are used
+/// within the original context length and the long ones past it.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct RopeScaling {
+    #[serde(rename = "type")]
+    pub scaling_type: RopeScalingType,
+    pub short_factor: Vec<f64>,
+    pub long_factor: Vec<f64>,
+}
+
 // https://huggingface.co/microsoft/Phi-3-mini-4k-instruct/blob/main/config.json
+// https://huggingface.co/microsoft/Phi-3-mini-128k-instruct/blob/main/config.json
 #[derive(Debug, Clone, serde::Deserialize)]
 pub struct Config {
     pub vocab_size: usize,
@@ -19,8 +37,10 @@ pub struct Config {
     pub rope_theta: f64,
     pub bos_token_id: Option<u32>,
     pub eos_token_id: Option<u32>,
-    pub rope_scaling: Option<String>,
+    pub rope_scaling: Option<RopeScaling>,
     pub max_position_embeddings: usize,
+    /// The context length the model was pre-trained with, before the long rope extension.
+    pub original_max_position_embeddings: Option<usize>,
 }
 
 impl Config {
@@ -33,25 +53,63 @@ impl Config {
 pub struct RotaryEmbedding {
     sin: Tensor,
     cos: Tensor,
+    /// The tables using the long factors, for the positions past `original_max_seq_len`.
+    long: Option<(Tensor, Tensor)>,
+    original_max_seq_len: usize,
 }
 
 impl RotaryEmbedding {
     pub fn new(dtype: DType, cfg: &Config, dev: &Device) -> Result<Self> {
         let dim = cfg.head_dim();
         let max_seq_len = cfg.max_position_embeddings;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / cfg.rope_theta.powf(i as f64 / dim as f64) as f32)
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?.to_dtype(dtype)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(dtype)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
+        let original_max_seq_len = cfg.original_max_position_embeddings.unwrap_or(max_seq_len);
+        // The long rope models scale the embeddings up when the context is extended beyond the
+        // original one.
+        let scale = max_seq_len as f64 / original_max_seq_len as f64;
+        let scale = if scale <= 1. || cfg.rope_scaling.is_none() {
+            1.
+        } else {
+            (1. + scale.ln() / (original_max_seq_len as f64).ln()).sqrt()
+        };
+        let tables = |factors: Option<&[f64]>| -> Result<(Tensor, Tensor)> {
+            if let Some(factors) = factors.filter(|f| f.len() != dim / 2) {
+                candle::bail!("unexpected rope scaling factors length {}", factors.len())
+            }
+            let inv_freq: Vec<_> = (0..dim)
+                .step_by(2)
+                .enumerate()
+                .map(|(idx, i)| {
+                    let factor = factors.map_or(1., |f| f[idx]);
+                    1f32 / (factor * cfg.rope_theta.powf(i as f64 / dim as f64)) as f32
+                })
+                .collect();
+            let inv_freq_len = inv_freq.len();
+            // The positions go up to 128k so the tables are computed in f32.
+            let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
+            let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
+                .to_dtype(DType::F32)?
+                .reshape((max_seq_len, 1))?;
+            let freqs = t.matmul(&inv_freq)?;
+            let sin = (freqs.sin()? * scale)?.to_dtype(dtype)?;
+            let cos = (freqs.cos()? * scale)?.to_dtype(dtype)?;
+            Ok((sin, cos))
+        };
+        let (sin, cos, long) = match &cfg.rope_scaling {
+            None => {
+                let (sin, cos) = tables(None)?;
+                (sin, cos, None)
+            }
+            Some(scaling) => {
+                let (sin, cos) = tables(Some(scaling.short_factor.as_slice()))?;
+                let long = tables(Some(scaling.long_factor.as_slice()))?;
+                (sin, cos, Some(long))
+            }
+        };
         Ok(Self {
-            sin: freqs.sin()?,
-            cos: freqs.cos()?,
+            sin,
+            cos,
+            long,
+            original_max_seq_len,
         })
     }
 
@@ -62,8 +120,12 @@ impl RotaryEmbedding {
         seqlen_offset: usize,
     ) -> Result<(Tensor, Tensor)> {
         let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
-        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
-        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
+        let (sin, cos) = match &self.long {
+            Some((sin, cos)) if seqlen_offset + seq_len > self.original_max_seq_len => (sin, cos),
+            _ => (&self.sin, &self.cos),
+        };
+        let cos = cos.narrow(0, seqlen_offset, seq_len)?;
+        let sin = sin.narrow(0, seqlen_offset, seq_len)?;
         let q_embed = candle_nn::rotary_emb::rope(&q.contiguous()?, &cos, &sin)?;
         let k_embed = candle_nn::rotary_emb::rope(&k.contiguous()?, &cos, &sin)?;
         Ok((q_embed, k_embed))
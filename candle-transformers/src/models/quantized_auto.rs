@@ -0,0 +1,400 @@
+//! Automatic selection of the quantized model implementation for a GGUF file.
+//!
+//! The `general.architecture` metadata key of a GGUF file identifies the model family and the
+//! hyper-parameters are stored under keys prefixed by this architecture name. This module uses
+//! them to pick the matching quantized implementation so that callers do not have to know in
+//! advance what kind of model a file contains.
+use crate::generation::steering::Steering;
+use crate::models::{
+    quantized_llama, quantized_phi, quantized_phi3, quantized_qwen2, quantized_starcoder2,
+};
+use candle::quantized::{gguf_file, GgmlDType};
+use candle::{DType, Device, DeviceLocation, Result, Tensor};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Architecture {
+    /// Llama and the models using the same gguf layout such as mistral, codellama or
+    /// deepseek-coder.
+    Llama,
+    /// A llama model with mixture of experts layers, e.g. mixtral.
+    Mixtral,
+    Phi2,
+    Phi3,
+    Qwen2,
+    StarCoder2,
+}
+
+impl Architecture {
+    pub fn from_gguf(ct: &gguf_file::Content) -> Result<Self> {
+        let arch = match ct.metadata.get("general.architecture") {
+            None => candle::bail!("cannot find general.architecture in metadata"),
+            Some(v) => v.to_string()?,
+        };
+        let arch = match arch.as_str() {
+            "llama" => {
+                let n_expert = ct
+                    .metadata
+                    .get("llama.expert_count")
+                    .and_then(|v| v.to_u32().ok())
+                    .unwrap_or(0);
+                if n_expert > 1 {
+                    Self::Mixtral
+                } else {
+                    Self::Llama
+                }
+            }
+            "phi2" => Self::Phi2,
+            "phi3" => Self::Phi3,
+            "qwen2" => Self::Qwen2,
+            "starcoder2" => Self::StarCoder2,
+            arch => candle::bail!("unsupported architecture {arch} in gguf file"),
+        };
+        Ok(arch)
+    }
+
+    /// The prefix used for the hyper-parameters in the gguf metadata.
+    pub fn gguf_prefix(&self) -> &'static str {
+        match self {
+            Self::Llama | Self::Mixtral => "llama",
+            Self::Phi2 => "phi2",
+            Self::Phi3 => "phi3",
+            Self::Qwen2 => "qwen2",
+            Self::StarCoder2 => "starcoder2",
+        }
+    }
+}
+
+/// The settings that can be read from the gguf metadata in addition to the architecture.
+#[derive(Debug, Clone)]
+pub struct ModelInfo {
+    pub architecture: Architecture,
+    /// The `general.name` metadata.
+    pub name: Option<String>,
+    pub context_length: Option<usize>,
+    pub bos_token_id: Option<u32>,
+    pub eos_token_id: Option<u32>,
+    /// The end of turn token, e.g. `<|eot_id|>` for the llama 3 instruct models.
+    pub eot_token_id: Option<u32>,
+    /// The `tokenizer.ggml.model` metadata, `llama` for sentencepiece tokenizers and `gpt2` for
+    /// the byte level BPE ones such as the tiktoken based llama 3 tokenizer.
+    pub tokenizer_model: Option<String>,
+    /// The jinja chat template, if included in the file.
+    pub chat_template: Option<String>,
+}
+
+impl ModelInfo {
+    pub fn from_gguf(ct: &gguf_file::Content) -> Result<Self> {
+        let architecture = Architecture::from_gguf(ct)?;
+        let get_u32 = |s: &str| ct.metadata.get(s).and_then(|v| v.to_u32().ok());
+        let get_string = |s: &str| ct.metadata.get(s).and_then(|v| v.to_string().ok()).cloned();
+        let prefix = architecture.gguf_prefix();
+        // Older llama 3 files do not include the end of turn token id.
+        let eot_token_id = get_u32("tokenizer.ggml.eot_token_id").or_else(|| {
+            let tokens = ct.metadata.get("tokenizer.ggml.tokens")?.to_vec().ok()?;
+            let eot = tokens
+                .iter()
+                .position(|t| t.to_string().is_ok_and(|t| t == "<|eot_id|>"))?;
+            Some(eot as u32)
+        });
+        Ok(Self {
+            architecture,
+            name: get_string("general.name"),
+            context_length: get_u32(&format!("{prefix}.context_length")).map(|v| v as usize),
+            bos_token_id: get_u32("tokenizer.ggml.bos_token_id"),
+            eos_token_id: get_u32("tokenizer.ggml.eos_token_id"),
+            eot_token_id,
+            tokenizer_model: get_string("tokenizer.ggml.model"),
+            chat_template: get_string("tokenizer.chat_template"),
+        })
+    }
+
+    /// The end of sequence and end of turn tokens, the generation should stop on any of them.
+    pub fn stop_token_ids(&self) -> Vec<u32> {
+        let mut ids = self.eos_token_id.into_iter().collect::<Vec<_>>();
+        if let Some(eot) = self.eot_token_id.filter(|eot| !ids.contains(eot)) {
+            ids.push(eot)
+        }
+        ids
+    }
+}
+
+/// A quantized model whose implementation has been selected based on the gguf metadata.
+pub enum ModelWeights {
+    Llama(quantized_llama::ModelWeights),
+    Phi2(quantized_phi::ModelWeights),
+    Phi3(quantized_phi3::ModelWeights),
+    Qwen2(quantized_qwen2::ModelWeights),
+    StarCoder2(quantized_starcoder2::ModelWeights),
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        Self::from_gguf_with_config(ct, reader, device, quantized_llama::ModelConfig::default())
+    }
+
+    /// Loads the model using `config` for the llama models, e.g. to cap their context length,
+    /// the phi-3 models only use its `max_seq_len` and the other architectures their defaults.
+    pub fn from_gguf_with_config<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        config: quantized_llama::ModelConfig,
+    ) -> Result<Self> {
+        let model = match Architecture::from_gguf(&ct)? {
+            Architecture::Llama | Architecture::Mixtral => Self::Llama(
+                quantized_llama::ModelWeights::from_gguf_with_config(ct, reader, device, config)?,
+            ),
+            Architecture::Phi2 => {
+                Self::Phi2(quantized_phi::ModelWeights::from_gguf(ct, reader, device)?)
+            }
+            Architecture::Phi3 => {
+                Self::Phi3(quantized_phi3::ModelWeights::from_gguf_with_max_seq_len(
+                    false,
+                    ct,
+                    reader,
+                    device,
+                    config.max_seq_len,
+                )?)
+            }
+            Architecture::Qwen2 => Self::Qwen2(quantized_qwen2::ModelWeights::from_gguf(
+                ct, reader, device,
+            )?),
+            Architecture::StarCoder2 => Self::StarCoder2(
+                quantized_starcoder2::ModelWeights::from_gguf(ct, reader, device)?,
+            ),
+        };
+        Ok(model)
+    }
+
+    /// Returns the logits for the last position of `x`, a tensor of token ids with shape
+    /// `(batch, seq_len)`, `index_pos` being the position of the first token.
+    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        match self {
+            Self::Llama(m) => m.forward(x, index_pos),
+            Self::Phi2(m) => m.forward(x, index_pos),
+            Self::Phi3(m) => m.forward(x, index_pos),
+            Self::Qwen2(m) => m.forward(x, index_pos),
+            Self::StarCoder2(m) => m.forward(x, index_pos),
+        }
+    }
+
+    /// Returns the logits for all the positions of `x`, with shape `(batch, seq_len, vocab)`.
+    /// This is only supported by the llama models.
+    pub fn forward_all(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        match self {
+            Self::Llama(m) => m.forward_all(x, index_pos),
+            _ => candle::bail!("forward_all is only supported for llama models"),
+        }
+    }
+
+    /// Drops the cached positions `keep..keep + discard` of the kv cache, see
+    /// [`quantized_llama::ModelWeights::shift_cache`]. This is only supported by the llama
+    /// models.
+    pub fn shift_context(&mut self, keep: usize, discard: usize) -> Result<()> {
+        match self {
+            Self::Llama(m) => m.shift_context(keep, discard),
+            _ => candle::bail!("context shifting is only supported for llama models"),
+        }
+    }
+
+    /// Adds steering vectors to the hidden states at the output of the layers, `None` disables
+    /// steering.
+    pub fn set_steering(&mut self, steering: Option<Steering>) {
+        match self {
+            Self::Llama(m) => m.set_steering(steering),
+            Self::Phi2(m) => m.set_steering(steering),
+            Self::Phi3(m) => m.set_steering(steering),
+            Self::Qwen2(m) => m.set_steering(steering),
+            Self::StarCoder2(m) => m.set_steering(steering),
+        }
+    }
+}
+
+/// The model dimensions that drive the kv-cache and activation memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct MemoryDims {
+    n_layer: usize,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    embedding_length: usize,
+    feed_forward_length: usize,
+    vocab_size: usize,
+    max_context_length: Option<usize>,
+}
+
+/// An estimate of the memory used by a quantized model on a device, in bytes.
+///
+/// The estimate is computed from the gguf header only so that applications can refuse to load a
+/// model, or reduce the context length, before running out of memory. It is a rough upper bound:
+/// the activations are estimated for a prompt that fills the whole context in a single forward
+/// pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryReport {
+    pub device: DeviceLocation,
+    pub context_length: usize,
+    pub kv_dtype: DType,
+    /// The weights as stored on the device, some of them are dequantized when loading the model.
+    pub weights: usize,
+    pub kv_cache: usize,
+    pub activations: usize,
+    dims: MemoryDims,
+}
+
+impl MemoryReport {
+    pub fn from_gguf(
+        ct: &gguf_file::Content,
+        device: &Device,
+        context_length: usize,
+    ) -> Result<Self> {
+        let prefix = Architecture::from_gguf(ct)?.gguf_prefix();
+        let md_get = |s: &str| {
+            let key = format!("{prefix}.{s}");
+            ct.metadata
+                .get(&key)
+                .and_then(|v| v.to_u64().ok())
+                .map(|v| v as usize)
+        };
+        let md_req = |s: &str| match md_get(s) {
+            Some(v) => Ok(v),
+            None => candle::bail!("cannot find {prefix}.{s} in metadata"),
+        };
+        let n_head = md_req("attention.head_count")?;
+        let embedding_length = md_req("embedding_length")?;
+        let tok_embd = ct.tensor_infos.get("token_embd.weight");
+        let vocab_size = match tok_embd {
+            Some(info) => info.shape.dims()[0],
+            None => candle::bail!("cannot find token_embd.weight in gguf file"),
+        };
+        let dims = MemoryDims {
+            n_layer: md_req("block_count")?,
+            n_head,
+            n_kv_head: md_get("attention.head_count_kv").unwrap_or(n_head),
+            head_dim: embedding_length / n_head.max(1),
+            embedding_length,
+            feed_forward_length: md_get("feed_forward_length").unwrap_or(4 * embedding_length),
+            vocab_size,
+            max_context_length: md_get("context_length"),
+        };
+
+        let mut weights = 0;
+        for (name, info) in ct.tensor_infos.iter() {
+            let elem_count = info.shape.elem_count();
+            let dtype = info.ggml_dtype;
+            // The token embeddings and the f16/f32 weights are dequantized to f32 on load.
+            weights += match dtype {
+                _ if name == "token_embd.weight" => elem_count * DType::F32.size_in_bytes(),
+                GgmlDType::F32 | GgmlDType::F16 => elem_count * DType::F32.size_in_bytes(),
+                _ => elem_count / dtype.block_size() * dtype.type_size(),
+            };
+        }
+        // Without an output layer, the quantized token embeddings are also used for the logits.
+        if !ct.tensor_infos.contains_key("output.weight") {
+            if let Some(info) = tok_embd {
+                let dtype = info.ggml_dtype;
+                weights += info.shape.elem_count() / dtype.block_size() * dtype.type_size()
+            }
+        }
+        let mut report = Self {
+            device: device.location(),
+            context_length,
+            // All the quantized models use f32 activations and kv caches.
+            kv_dtype: DType::F32,
+            weights,
+            kv_cache: 0,
+            activations: 0,
+            dims,
+        };
+        report.update();
+        Ok(report)
+    }
+
+    fn update(&mut self) {
+        let d = &self.dims;
+        let (ctx, kv_size) = (self.context_length, self.kv_dtype.size_in_bytes());
+        let f32_size = DType::F32.size_in_bytes();
+        let kv_per_layer = 2 * d.n_kv_head * d.head_dim * ctx * kv_size;
+        self.kv_cache = d.n_layer * kv_per_layer;
+        // The hidden states and their normalized version, the attention and mlp outputs, the
+        // query/key/value projections, the attention scores and their softmax, the mlp
+        // intermediate values, and the last position logits. The kv-cache of a layer is copied
+        // when appending the new keys and values.
+        let hidden = 4 * ctx * d.embedding_length * f32_size;
+        let qkv = ctx * (d.n_head + 2 * d.n_kv_head) * d.head_dim * f32_size;
+        let attn = 2 * d.n_head * ctx * ctx * f32_size;
+        let mlp = 3 * ctx * d.feed_forward_length * f32_size;
+        let logits = d.vocab_size * f32_size;
+        self.activations = hidden + qkv + attn + mlp + logits + kv_per_layer;
+    }
+
+    /// Returns the estimate for a different context length.
+    pub fn with_context_length(&self, context_length: usize) -> Self {
+        let mut report = self.clone();
+        report.context_length = context_length;
+        report.update();
+        report
+    }
+
+    /// Returns the estimate for a different kv-cache dtype.
+    pub fn with_kv_dtype(&self, kv_dtype: DType) -> Self {
+        let mut report = self.clone();
+        report.kv_dtype = kv_dtype;
+        report.update();
+        report
+    }
+
+    pub fn total(&self) -> usize {
+        self.weights + self.kv_cache + self.activations
+    }
+
+    pub fn fits(&self, budget: usize) -> bool {
+        self.total() <= budget
+    }
+
+    /// The largest context length for which the model fits in `budget` bytes, bounded by the
+    /// context length the model was trained with if available. Returns `None` if the model does
+    /// not fit even with a single token of context.
+    pub fn max_context_length(&self, budget: usize) -> Option<usize> {
+        if !self.with_context_length(1).fits(budget) {
+            return None;
+        }
+        let mut lo = 1;
+        let mut hi = self
+            .dims
+            .max_context_length
+            .unwrap_or(self.context_length)
+            .max(1);
+        // The estimate is increasing with the context length, so bisect on it.
+        while lo < hi {
+            let mid = lo + (hi - lo).div_ceil(2);
+            if self.with_context_length(mid).fits(budget) {
+                lo = mid
+            } else {
+                hi = mid - 1
+            }
+        }
+        Some(lo)
+    }
+}
+
+impl std::fmt::Display for MemoryReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mb = |v: usize| v as f64 / 1e6;
+        write!(
+            f,
+            "weights {:.2}MB, kv-cache {:.2}MB, activations {:.2}MB, total {:.2}MB \
+            (context {}, {:?} kv-cache, {:?})",
+            mb(self.weights),
+            mb(self.kv_cache),
+            mb(self.activations),
+            mb(self.total()),
+            self.context_length,
+            self.kv_dtype,
+            self.device,
+        )
+    }
+}
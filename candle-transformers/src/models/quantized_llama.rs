@@ -1,11 +1,18 @@
 use std::collections::HashMap;
+use std::sync::Arc;
 
+use crate::generation::steering::Steering;
+use crate::offload::{copy_to_device, Prefetcher};
+use crate::pipeline_parallel::{run_stages, DeviceMap};
 use crate::quantized_nn::RmsNorm;
 use candle::quantized::QTensor;
 use candle::quantized::{ggml_file, gguf_file};
 use candle::{DType, Device, IndexOp, Result, Tensor};
 use candle_nn::{Embedding, Module};
 
+pub use super::llama::ModelConfig;
+
+/// The context length used for the ggml files and the gguf files without a context length.
 pub const MAX_SEQ_LEN: usize = 4096;
 
 // QMatMul wrapper adding some tracing.
@@ -24,7 +31,11 @@ impl QMatMul {
 
     fn forward(&self, xs: &Tensor) -> Result<Tensor> {
         let _enter = self.span.enter();
-        self.inner.forward(xs)
+        // The quantized kernels only support f32 inputs.
+        let in_dtype = xs.dtype();
+        self.inner
+            .forward(&xs.to_dtype(DType::F32)?)?
+            .to_dtype(in_dtype)
     }
 }
 
@@ -139,7 +150,9 @@ struct LayerWeights {
     cos: Tensor,
     sin: Tensor,
     neg_inf: Tensor,
-    kv_cache: Option<(Tensor, Tensor)>,
+    // The device of the weights, the kv cache, and the hidden states of the layer.
+    device: Device,
+    config: ModelConfig,
     span_attn: tracing::Span,
     span_rot: tracing::Span,
     span_mlp: tracing::Span,
@@ -152,6 +165,33 @@ fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Ten
 }
 
 impl LayerWeights {
+    /// The matmuls of the layer, always in the same order.
+    fn matmuls_mut(&mut self) -> Vec<&mut QMatMul> {
+        let mut matmuls = vec![
+            &mut self.attention_wq,
+            &mut self.attention_wk,
+            &mut self.attention_wv,
+            &mut self.attention_wo,
+        ];
+        let mlps = match &mut self.mlp_or_moe {
+            MlpOrMoe::Mlp(mlp) => std::slice::from_mut(mlp),
+            MlpOrMoe::MoE {
+                feed_forward_gate_inp,
+                experts,
+                ..
+            } => {
+                matmuls.push(feed_forward_gate_inp);
+                experts.as_mut_slice()
+            }
+        };
+        for mlp in mlps.iter_mut() {
+            matmuls.push(&mut mlp.feed_forward_w1);
+            matmuls.push(&mut mlp.feed_forward_w2);
+            matmuls.push(&mut mlp.feed_forward_w3);
+        }
+        matmuls
+    }
+
     fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
         let _enter = self.span_rot.enter();
         let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
@@ -162,17 +202,14 @@ impl LayerWeights {
         candle_nn::rotary_emb::rope_i(&x.contiguous()?, &cos, &sin)
     }
 
-    fn forward_attn(
-        &mut self,
-        x: &Tensor,
-        mask: Option<&Tensor>,
-        index_pos: usize,
-    ) -> Result<Tensor> {
-        let _enter = self.span_attn.enter();
-        let (b_sz, seq_len, n_embd) = x.dims3()?;
-        let q = self.attention_wq.forward(x)?;
-        let k = self.attention_wk.forward(x)?;
-        let v = self.attention_wv.forward(x)?;
+    /// The queries, keys and values for `x`, with shape `(b_sz, n_head, seq_len, head_dim)`,
+    /// before the rotary embeddings.
+    fn qkv(&self, x: &Tensor) -> Result<(Tensor, Tensor, Tensor)> {
+        let (b_sz, seq_len, _n_embd) = x.dims3()?;
+        let dtype = self.config.activation_dtype;
+        let q = self.attention_wq.forward(x)?.to_dtype(dtype)?;
+        let k = self.attention_wk.forward(x)?.to_dtype(dtype)?;
+        let v = self.attention_wv.forward(x)?.to_dtype(dtype)?;
 
         let q = q
             .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
@@ -187,15 +224,36 @@ impl LayerWeights {
             // actually a no-op except when processing the initial prompt so has no significant
             // impact on performance.
             .contiguous()?;
+        Ok((q, k, v))
+    }
 
-        let q = self.apply_rotary_emb(&q, index_pos)?;
-        let k = self.apply_rotary_emb(&k, index_pos)?;
+    /// Applies the rotary embeddings to the new keys and appends them and the values to the kv
+    /// cache, returns the queries with the rotary embeddings and all the keys and values.
+    fn rotate_and_cache(
+        &self,
+        q: &Tensor,
+        k: &Tensor,
+        v: &Tensor,
+        index_pos: usize,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+    ) -> Result<(Tensor, Tensor, Tensor)> {
+        let q = self.apply_rotary_emb(q, index_pos)?;
+        let k = self
+            .apply_rotary_emb(k, index_pos)?
+            .to_dtype(self.config.kv_dtype)?;
+        let v = v.to_dtype(self.config.kv_dtype)?;
 
-        let (k, v) = match &self.kv_cache {
+        let (k, v) = match kv_cache {
             None => (k, v),
             Some((k_cache, v_cache)) => {
                 if index_pos == 0 {
                     (k, v)
+                } else if k_cache.dim(2)? > index_pos {
+                    // The positions after index_pos are dropped, e.g. the draft tokens rejected
+                    // by speculative decoding.
+                    let k = Tensor::cat(&[&k_cache.narrow(2, 0, index_pos)?, &k], 2)?;
+                    let v = Tensor::cat(&[&v_cache.narrow(2, 0, index_pos)?, &v], 2)?;
+                    (k, v)
                 } else {
                     let k = Tensor::cat(&[k_cache, &k], 2)?;
                     let v = Tensor::cat(&[v_cache, &v], 2)?;
@@ -203,27 +261,237 @@ impl LayerWeights {
                 }
             }
         };
-        self.kv_cache = Some((k.clone(), v.clone()));
+        *kv_cache = Some((k.clone(), v.clone()));
+        Ok((q, k, v))
+    }
+
+    /// Drops the cached positions `keep..keep + discard`, the following keys are rotated back by
+    /// `discard` positions so that they are at the positions they would have been computed for.
+    fn shift_kv(
+        &self,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+        keep: usize,
+        discard: usize,
+    ) -> Result<()> {
+        let (k, v) = match kv_cache {
+            Some(kv) => kv,
+            None => return Ok(()),
+        };
+        let seq_len = k.dim(2)?;
+        let rest = seq_len - keep - discard;
+        let shifted_k = if rest == 0 {
+            None
+        } else {
+            // A rotation by the angles of position p + discard is the rotation of position p
+            // followed by the rotation of position discard, so the inverse rotation of position
+            // discard moves the keys back.
+            let half_dim = self.cos.dim(1)?;
+            let cos = self
+                .cos
+                .narrow(0, discard, 1)?
+                .broadcast_as((rest, half_dim))?
+                .contiguous()?;
+            let sin = self
+                .sin
+                .narrow(0, discard, 1)?
+                .neg()?
+                .broadcast_as((rest, half_dim))?
+                .contiguous()?;
+            let k = k
+                .narrow(2, keep + discard, rest)?
+                .to_dtype(self.config.activation_dtype)?
+                .contiguous()?;
+            let k = candle_nn::rotary_emb::rope_i(&k, &cos, &sin)?;
+            Some(k.to_dtype(self.config.kv_dtype)?)
+        };
+        let shifted_v = v.narrow(2, keep + discard, rest)?;
+        let (k, v) = match shifted_k {
+            None if keep == 0 => {
+                *kv_cache = None;
+                return Ok(());
+            }
+            None => (k.narrow(2, 0, keep)?, v.narrow(2, 0, keep)?),
+            Some(shifted_k) if keep == 0 => (shifted_k, shifted_v),
+            Some(shifted_k) => (
+                Tensor::cat(&[&k.narrow(2, 0, keep)?, &shifted_k], 2)?,
+                Tensor::cat(&[&v.narrow(2, 0, keep)?, &shifted_v], 2)?,
+            ),
+        };
+        *kv_cache = Some((k.contiguous()?, v.contiguous()?));
+        Ok(())
+    }
 
+    /// The attention of the queries over the keys and values followed by the output projection,
+    /// `mask` is broadcasted to the attention scores.
+    fn attend(&self, q: &Tensor, k: &Tensor, v: &Tensor, mask: Option<&Tensor>) -> Result<Tensor> {
+        let (b_sz, _n_head, seq_len, _head_dim) = q.dims4()?;
+        let dtype = self.config.activation_dtype;
         // Support for MQA, useful for 70B models and mistral.
-        let k = crate::utils::repeat_kv(k, self.n_head / self.n_kv_head)?;
-        let v = crate::utils::repeat_kv(v, self.n_head / self.n_kv_head)?;
+        let k = crate::utils::repeat_kv(k.to_dtype(dtype)?, self.n_head / self.n_kv_head)?;
+        let v = crate::utils::repeat_kv(v.to_dtype(dtype)?, self.n_head / self.n_kv_head)?;
 
         let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
         let att = match mask {
             None => att,
             Some(mask) => {
-                let mask = mask.broadcast_as(att.shape())?;
+                let mask = mask.to_device(att.device())?.broadcast_as(att.shape())?;
                 masked_fill(&att, &mask, &self.neg_inf)?
             }
         };
         let att = candle_nn::ops::softmax_last_dim(&att)?;
         // Convert to contiguous as matmul doesn't support strided vs for now.
         let y = att.matmul(&v.contiguous()?)?;
-        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        let y = y
+            .transpose(1, 2)?
+            .reshape((b_sz, seq_len, self.n_head * self.head_dim))?;
         let y = self.attention_wo.forward(&y)?;
         Ok(y)
     }
+
+    fn forward_attn(
+        &self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+        kv_cache: &mut Option<(Tensor, Tensor)>,
+    ) -> Result<Tensor> {
+        let _enter = self.span_attn.enter();
+        let (q, k, v) = self.qkv(x)?;
+        let (q, k, v) = self.rotate_and_cache(&q, &k, &v, index_pos, kv_cache)?;
+        self.attend(&q, &k, &v, mask)
+    }
+
+    /// Similar to `forward_attn` for a batch of sequences, each of them having its own position
+    /// and kv cache. The keys and values of the shorter sequences are padded with zeros, `mask`
+    /// has shape `(b_sz, 1, seq_len, kv_len)` and masks the padding.
+    fn forward_attn_batch(
+        &self,
+        x: &Tensor,
+        mask: &Tensor,
+        index_pos: &[usize],
+        kv_caches: &mut [&mut Option<(Tensor, Tensor)>],
+    ) -> Result<Tensor> {
+        let _enter = self.span_attn.enter();
+        let (q, k, v) = self.qkv(x)?;
+        let kv_len = mask.dim(3)?;
+        let mut qs = Vec::with_capacity(index_pos.len());
+        let mut ks = Vec::with_capacity(index_pos.len());
+        let mut vs = Vec::with_capacity(index_pos.len());
+        for (i, (&index_pos, kv_cache)) in index_pos.iter().zip(kv_caches.iter_mut()).enumerate() {
+            let (q, k, v) = self.rotate_and_cache(
+                &q.narrow(0, i, 1)?,
+                &k.narrow(0, i, 1)?,
+                &v.narrow(0, i, 1)?,
+                index_pos,
+                kv_cache,
+            )?;
+            let padding = kv_len - k.dim(2)?;
+            qs.push(q);
+            ks.push(k.pad_with_zeros(2, 0, padding)?);
+            vs.push(v.pad_with_zeros(2, 0, padding)?);
+        }
+        let q = Tensor::cat(&qs, 0)?;
+        let k = Tensor::cat(&ks, 0)?;
+        let v = Tensor::cat(&vs, 0)?;
+        self.attend(&q, &k, &v, Some(mask))
+    }
+}
+
+/// The inference state of a sequence: the kv cache of each layer and the cached attention masks.
+///
+/// The model weights are never modified by [`ModelWeights::forward_with_cache`] so a single copy
+/// of the weights, e.g. in an `Arc`, can serve multiple sequences concurrently, each of them
+/// using its own cache.
+#[derive(Debug, Clone, Default)]
+pub struct KvCache {
+    layers: Vec<Option<(Tensor, Tensor)>>,
+    masks: HashMap<usize, Tensor>,
+}
+
+impl KvCache {
+    pub fn new(n_layers: usize) -> Self {
+        Self {
+            layers: vec![None; n_layers],
+            masks: HashMap::new(),
+        }
+    }
+
+    /// Drops the kv cache of all the layers, and the cached attention masks, to release memory.
+    pub fn clear(&mut self) {
+        self.layers.iter_mut().for_each(|kv| *kv = None);
+        self.masks.clear()
+    }
+
+    /// The number of positions in the cache.
+    pub fn seq_len(&self) -> usize {
+        match self.layers.first() {
+            Some(Some((k, _))) => k.dim(2).unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// Saves the kv cache computed for `tokens`, e.g. for a long system prompt, see
+    /// [`prompt_cache`](crate::prompt_cache).
+    pub fn save<P: AsRef<std::path::Path>>(&self, path: P, tokens: &[u32]) -> Result<()> {
+        crate::prompt_cache::save(path, &self.layers, tokens)
+    }
+
+    /// Loads a kv cache saved with [`Self::save`] and the tokens it has been computed for. The
+    /// tokens of a prompt starting with `n` of these tokens, as returned by
+    /// [`prompt_cache::reusable_len`](crate::prompt_cache::reusable_len), can then be processed
+    /// from position `n`, the following cached positions being dropped.
+    ///
+    /// ```rust,no_run
+    /// use candle::{Device, Tensor};
+    /// use candle_transformers::models::quantized_llama::{KvCache, ModelWeights};
+    /// use candle_transformers::prompt_cache;
+    /// # fn run(model: &ModelWeights, prompt: &[u32]) -> candle::Result<()> {
+    /// let (mut cache, cached_tokens) = KvCache::load("prompt.safetensors", &Device::Cpu)?;
+    /// let n = prompt_cache::reusable_len(&cached_tokens, prompt);
+    /// let input = Tensor::new(&prompt[n..], &Device::Cpu)?.unsqueeze(0)?;
+    /// let logits = model.forward_with_cache(&input, n, &mut cache)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn load<P: AsRef<std::path::Path>>(path: P, device: &Device) -> Result<(Self, Vec<u32>)> {
+        let (layers, tokens) = crate::prompt_cache::load(path, device)?;
+        let cache = Self {
+            layers,
+            masks: HashMap::new(),
+        };
+        Ok((cache, tokens))
+    }
+
+    /// The causal mask for `t` tokens starting at `index_pos`, the previous positions are
+    /// available in the kv cache so that a prompt can be processed in multiple chunks.
+    fn mask(&mut self, t: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t).filter(|_| index_pos == 0) {
+            Ok(mask.clone())
+        } else {
+            let mask: Vec<_> = (0..t)
+                .flat_map(|i| (0..index_pos + t).map(move |j| u8::from(j > i + index_pos)))
+                .collect();
+            let mask = Tensor::from_slice(&mask, (t, index_pos + t), device)?;
+            if index_pos == 0 {
+                self.masks.insert(t, mask.clone());
+            }
+            Ok(mask)
+        }
+    }
+}
+
+/// The attention mask for a batch of `seq_len` tokens per sequence, the tokens of sequence `i`
+/// starting at `index_pos[i]`. The keys are padded to the longest sequence, the padding being
+/// masked like the future positions. The mask has shape `(batch, 1, seq_len, kv_len)`.
+fn batch_mask(seq_len: usize, index_pos: &[usize], device: &Device) -> Result<Tensor> {
+    let kv_len = index_pos.iter().max().copied().unwrap_or(0) + seq_len;
+    let mask: Vec<u8> = index_pos
+        .iter()
+        .flat_map(|&index_pos| {
+            (0..seq_len).flat_map(move |i| (0..kv_len).map(move |j| u8::from(j > i + index_pos)))
+        })
+        .collect();
+    Tensor::from_slice(&mask, (index_pos.len(), 1, seq_len, kv_len), device)
 }
 
 #[derive(Debug, Clone)]
@@ -232,35 +500,59 @@ pub struct ModelWeights {
     layers: Vec<LayerWeights>,
     norm: RmsNorm,
     output: QMatMul,
-    masks: HashMap<usize, Tensor>,
+    // The cache used by `forward`, `forward_with_cache` uses a cache provided by the caller.
+    cache: KvCache,
+    config: ModelConfig,
+    max_seq_len: usize,
     span: tracing::Span,
     span_output: tracing::Span,
+    steering: Option<Steering>,
+    // Splits the prompts in micro batches of this size when the layers are on multiple devices.
+    micro_batch_size: Option<usize>,
+    // Uploads the weights of the offloaded layers, see `offload_layers`.
+    prefetcher: Option<Arc<Prefetcher>>,
+}
+
+fn inv_freq(head_dim: usize, freq_base: f32) -> Vec<f32> {
+    (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+        .collect()
 }
 
+/// The rotary embeddings for the positions up to `seq_len`.
 fn precomput_freqs_cis(
-    head_dim: usize,
-    freq_base: f32,
+    inv_freq: &[f32],
+    seq_len: usize,
+    dtype: DType,
     device: &Device,
 ) -> Result<(Tensor, Tensor)> {
-    let theta: Vec<_> = (0..head_dim)
-        .step_by(2)
-        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
-        .collect();
-    let theta = Tensor::new(theta.as_slice(), device)?;
-    let idx_theta = Tensor::arange(0, MAX_SEQ_LEN as u32, device)?
+    let theta = Tensor::new(inv_freq, device)?;
+    let idx_theta = Tensor::arange(0, seq_len as u32, device)?
         .to_dtype(DType::F32)?
-        .reshape((MAX_SEQ_LEN, 1))?
+        .reshape((seq_len, 1))?
         .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-    let cos = idx_theta.cos()?;
-    let sin = idx_theta.sin()?;
+    let cos = idx_theta.cos()?.to_dtype(dtype)?;
+    let sin = idx_theta.sin()?.to_dtype(dtype)?;
     Ok((cos, sin))
 }
 
 impl ModelWeights {
-    pub fn from_ggml(mut ct: ggml_file::Content, gqa: usize) -> Result<Self> {
+    pub fn from_ggml(ct: ggml_file::Content, gqa: usize) -> Result<Self> {
+        Self::from_ggml_with_config(ct, gqa, ModelConfig::default())
+    }
+
+    pub fn from_ggml_with_config(
+        mut ct: ggml_file::Content,
+        gqa: usize,
+        config: ModelConfig,
+    ) -> Result<Self> {
+        let dtype = config.activation_dtype;
         let head_dim = (ct.hparams.n_embd / ct.hparams.n_head) as usize;
-        let (cos, sin) = precomput_freqs_cis(head_dim, 10000., &ct.device)?;
-        let neg_inf = Tensor::new(f32::NEG_INFINITY, &ct.device)?;
+        let max_seq_len = config.seq_len(MAX_SEQ_LEN);
+        let inv_freq = inv_freq(head_dim, 10000.);
+        let (cos, sin) = precomput_freqs_cis(&inv_freq, max_seq_len, dtype, &ct.device)?;
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, &ct.device)?.to_dtype(dtype)?;
         let tok_embeddings = ct.remove("tok_embeddings.weight")?;
         let tok_embeddings = tok_embeddings.dequantize(&ct.device)?;
         let norm = RmsNorm::from_qtensor(ct.remove("norm.weight")?, 1e-5)?;
@@ -301,7 +593,8 @@ impl ModelWeights {
                 cos: cos.clone(),
                 sin: sin.clone(),
                 neg_inf: neg_inf.clone(),
-                kv_cache: None,
+                device: ct.device.clone(),
+                config,
                 span_attn,
                 span_rot,
                 span_mlp,
@@ -309,14 +602,20 @@ impl ModelWeights {
         }
         let span = tracing::span!(tracing::Level::TRACE, "model");
         let span_output = tracing::span!(tracing::Level::TRACE, "output");
+        let cache = KvCache::new(layers.len());
         Ok(Self {
             tok_embeddings: Embedding::new(tok_embeddings, ct.hparams.n_embd as usize),
             layers,
             norm,
             output: QMatMul::from_qtensor(output)?,
-            masks: HashMap::new(),
+            cache,
+            config,
+            max_seq_len,
             span,
             span_output,
+            steering: None,
+            micro_batch_size: None,
+            prefetcher: None,
         })
     }
 
@@ -325,6 +624,30 @@ impl ModelWeights {
         reader: &mut R,
         device: &Device,
     ) -> Result<Self> {
+        Self::from_gguf_with_config(ct, reader, device, ModelConfig::default())
+    }
+
+    /// Loads the model with the kv cache and activation dtypes from `config`. The quantized
+    /// matmuls and the normalization layers always run in f32.
+    pub fn from_gguf_with_config<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        config: ModelConfig,
+    ) -> Result<Self> {
+        Self::from_gguf_with_device_map(ct, reader, &DeviceMap::new(device), config)
+    }
+
+    /// Loads the layers of the model on the devices from `device_map`, the embeddings being on
+    /// the device of the first layer and the output on the one of the last layer. See
+    /// [`pipeline_parallel`](crate::pipeline_parallel) and [`Self::set_micro_batch_size`].
+    pub fn from_gguf_with_device_map<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device_map: &DeviceMap,
+        config: ModelConfig,
+    ) -> Result<Self> {
+        let dtype = config.activation_dtype;
         let md_get = |s: &str| match ct.metadata.get(s) {
             None => candle::bail!("cannot find {s} in metadata"),
             Some(v) => Ok(v),
@@ -348,19 +671,68 @@ impl ModelWeights {
         let rope_freq_base = md_get("llama.rope.freq_base")
             .and_then(|m| m.to_f32())
             .unwrap_or(10000f32);
-        let (cos, sin) = precomput_freqs_cis(rope_dim, rope_freq_base, device)?;
-        let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+        let context_length = md_get("llama.context_length")
+            .and_then(|m| m.to_u32())
+            .map_or(MAX_SEQ_LEN, |v| v as usize);
+        let max_seq_len = config.seq_len(context_length);
+        // Linear rope scaling, the key used by recent gguf files or the legacy one, e.g. for
+        // the 16k context of deepseek-coder.
+        let rope_linear_scale = match md_get("llama.rope.scaling.type") {
+            Ok(v) if v.to_string()? == "linear" => md_get("llama.rope.scaling.factor")?.to_f32()?,
+            Ok(_) => 1.,
+            Err(_) => md_get("llama.rope.scale_linear")
+                .and_then(|m| m.to_f32())
+                .unwrap_or(1.),
+        };
+        let mut inv_freq = inv_freq(rope_dim, rope_freq_base);
+        for f in inv_freq.iter_mut() {
+            *f /= rope_linear_scale
+        }
+        // The llama 3.1 frequency scaling is stored as per frequency factors.
+        if ct.tensor_infos.contains_key("rope_freqs.weight") {
+            let factors = ct
+                .tensor(reader, "rope_freqs.weight", &Device::Cpu)?
+                .dequantize(&Device::Cpu)?
+                .to_vec1::<f32>()?;
+            if factors.len() != inv_freq.len() {
+                candle::bail!(
+                    "unexpected rope_freqs length {}, expected {}",
+                    factors.len(),
+                    inv_freq.len()
+                )
+            }
+            for (f, factor) in inv_freq.iter_mut().zip(factors.iter()) {
+                *f /= factor
+            }
+        }
+        // The rotary embeddings are computed once per device.
+        let mut tables: Vec<(Device, Tensor, Tensor, Tensor)> = vec![];
+        let mut tables_for = |device: &Device| -> Result<(Tensor, Tensor, Tensor)> {
+            if let Some((_, cos, sin, neg_inf)) =
+                tables.iter().find(|(d, ..)| d.same_device(device))
+            {
+                return Ok((cos.clone(), sin.clone(), neg_inf.clone()));
+            }
+            let (cos, sin) = precomput_freqs_cis(&inv_freq, max_seq_len, dtype, device)?;
+            let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?.to_dtype(dtype)?;
+            tables.push((device.clone(), cos.clone(), sin.clone(), neg_inf.clone()));
+            Ok((cos, sin, neg_inf))
+        };
 
-        let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
-        let tok_embeddings = tok_embeddings.dequantize(device)?;
+        let first_device = device_map.device(0);
+        let last_device = device_map.device(block_count.saturating_sub(1));
+        let tok_embeddings = ct.tensor(reader, "token_embd.weight", first_device)?;
+        let tok_embeddings = tok_embeddings.dequantize(first_device)?;
         let norm = RmsNorm::from_qtensor(
-            ct.tensor(reader, "output_norm.weight", device)?,
+            ct.tensor(reader, "output_norm.weight", last_device)?,
             rms_norm_eps,
         )?;
-        let output = ct.tensor(reader, "output.weight", device)?;
+        let output = ct.tensor(reader, "output.weight", last_device)?;
         let mut layers = Vec::with_capacity(block_count);
         for layer_idx in 0..block_count {
             let prefix = format!("blk.{layer_idx}");
+            let device = device_map.device(layer_idx);
+            let (cos, sin, neg_inf) = tables_for(device)?;
             let attention_wq = ct.tensor(reader, &format!("{prefix}.attn_q.weight"), device)?;
             let attention_wk = ct.tensor(reader, &format!("{prefix}.attn_k.weight"), device)?;
             let attention_wv = ct.tensor(reader, &format!("{prefix}.attn_v.weight"), device)?;
@@ -418,10 +790,11 @@ impl ModelWeights {
                 n_head: head_count,
                 n_kv_head: head_count_kv,
                 head_dim: embedding_length / head_count,
-                cos: cos.clone(),
-                sin: sin.clone(),
-                neg_inf: neg_inf.clone(),
-                kv_cache: None,
+                cos,
+                sin,
+                neg_inf,
+                device: device.clone(),
+                config,
                 span_attn,
                 span_rot,
                 span_mlp,
@@ -429,57 +802,421 @@ impl ModelWeights {
         }
         let span = tracing::span!(tracing::Level::TRACE, "model");
         let span_output = tracing::span!(tracing::Level::TRACE, "output");
+        let cache = KvCache::new(layers.len());
         Ok(Self {
             tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
             layers,
             norm,
             output: QMatMul::from_qtensor(output)?,
-            masks: HashMap::new(),
+            cache,
+            config,
+            max_seq_len,
             span,
             span_output,
+            steering: None,
+            micro_batch_size: None,
+            prefetcher: None,
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
-            Ok(mask.clone())
-        } else {
-            let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
-                .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
-            self.masks.insert(t, mask.clone());
-            Ok(mask)
-        }
+    /// The number of positions the rotary embeddings are computed for, the context length of the
+    /// model capped by the config.
+    pub fn max_seq_len(&self) -> usize {
+        self.max_seq_len
+    }
+
+    /// Drops the kv cache of all the layers, and the cached attention masks, to release memory.
+    pub fn clear_kv_cache(&mut self) {
+        self.cache.clear()
     }
 
+    /// A new empty cache to be used with [`Self::forward_with_cache`].
+    pub fn new_cache(&self) -> KvCache {
+        KvCache::new(self.layers.len())
+    }
+
+    /// Adds steering vectors to the hidden states at the output of the layers, `None` disables
+    /// steering.
+    pub fn set_steering(&mut self, steering: Option<Steering>) {
+        self.steering = steering
+    }
+
+    /// Runs the model using its internal kv cache.
     pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let mut cache = std::mem::take(&mut self.cache);
+        let res = self.forward_with_cache(x, index_pos, &mut cache);
+        self.cache = cache;
+        res
+    }
+
+    /// Similar to [`Self::forward`] but returns the logits for all the positions of `x`, with
+    /// shape `(batch, seq_len, vocab)`, e.g. to verify the draft tokens of speculative decoding.
+    pub fn forward_all(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let mut cache = std::mem::take(&mut self.cache);
+        let res = self.forward_impl(x, index_pos, &mut cache, true);
+        self.cache = cache;
+        res
+    }
+
+    /// Runs the model using the kv cache of a sequence, created with [`Self::new_cache`]. This
+    /// only borrows the weights immutably so that multiple sequences can be processed in
+    /// parallel.
+    pub fn forward_with_cache(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        cache: &mut KvCache,
+    ) -> Result<Tensor> {
+        self.forward_impl(x, index_pos, cache, false)
+    }
+
+    fn forward_impl(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        cache: &mut KvCache,
+        all_positions: bool,
+    ) -> Result<Tensor> {
         let (_b_sz, seq_len) = x.dims2()?;
+        self.check_cache(cache)?;
+        match self.micro_batch_size {
+            Some(size) if size < seq_len && !all_positions && self.stages().len() > 1 => {
+                return self.forward_pipelined(x, index_pos, cache, size)
+            }
+            _ => {}
+        }
         let mask = if seq_len == 1 {
             None
         } else {
-            Some(self.mask(seq_len, x.device())?)
+            Some(cache.mask(seq_len, index_pos, x.device())?)
         };
-        let _enter = self.span.enter();
-        let mut layer_in = self.tok_embeddings.forward(x)?;
-        for layer in self.layers.iter_mut() {
-            let x = layer_in;
-            let residual = &x;
-            let x = layer.attention_norm.forward(&x)?;
-            let attn = layer.forward_attn(&x, mask.as_ref(), index_pos)?;
-            let x = (attn + residual)?;
-
-            // MLP
-            let _enter = layer.span_mlp.enter();
-            let residual = &x;
-            let x = layer.ffn_norm.forward(&x)?;
-            let x = layer.mlp_or_moe.forward(&x)?;
-            let x = (x + residual)?;
-            layer_in = x
+        let x = self.forward_layers(x, |layer_idx, layer, x| {
+            let kv_cache = &mut cache.layers[layer_idx];
+            layer.forward_attn(x, mask.as_ref(), index_pos, kv_cache)
+        })?;
+        let x = if all_positions {
+            x
+        } else {
+            x.i((.., seq_len - 1, ..))?
+        };
+        let _enter = self.span_output.enter();
+        self.output.forward(&x)
+    }
+
+    /// Runs the model on a batch of sequences, each of them with its own position and kv cache.
+    ///
+    /// `x` has shape `(batch, seq_len)`, the tokens of row `i` start at position `index_pos[i]`
+    /// and attend to the previous positions of `caches[i]`. All the rows have the same number of
+    /// tokens, e.g. one token per sequence when sampling, prompts of different lengths can be
+    /// processed with [`Self::forward_with_cache`] first. The logits for the last position of
+    /// each sequence are returned, with shape `(batch, vocab)`.
+    pub fn forward_batch(
+        &self,
+        x: &Tensor,
+        index_pos: &[usize],
+        caches: &mut [KvCache],
+    ) -> Result<Tensor> {
+        let (b_sz, seq_len) = x.dims2()?;
+        if index_pos.len() != b_sz || caches.len() != b_sz {
+            candle::bail!(
+                "forward_batch: got {} positions and {} caches for a batch of {b_sz}",
+                index_pos.len(),
+                caches.len()
+            )
+        }
+        for cache in caches.iter() {
+            self.check_cache(cache)?
         }
-        let x = self.norm.forward(&layer_in)?;
+        let mask = batch_mask(seq_len, index_pos, x.device())?;
+        let x = self.forward_layers(x, |layer_idx, layer, x| {
+            let mut kv_caches = caches
+                .iter_mut()
+                .map(|cache| &mut cache.layers[layer_idx])
+                .collect::<Vec<_>>();
+            layer.forward_attn_batch(x, &mask, index_pos, &mut kv_caches)
+        })?;
         let x = x.i((.., seq_len - 1, ..))?;
         let _enter = self.span_output.enter();
         self.output.forward(&x)
     }
+
+    /// Drops the cached positions `keep..keep + discard` of `cache` so that a long conversation
+    /// can go on without processing it again once the context is full, the first `keep`
+    /// positions, e.g. the system prompt, being preserved. The next tokens are then processed from
+    /// position `cache.seq_len()`, i.e. `discard` positions earlier than before the shift.
+    ///
+    /// The keys after the dropped positions are rotated back by `discard` positions, the
+    /// attention is then the same as for the remaining tokens except for the dropped context
+    /// that the hidden states of the later positions have attended to.
+    pub fn shift_cache(&self, cache: &mut KvCache, keep: usize, discard: usize) -> Result<()> {
+        self.check_cache(cache)?;
+        let seq_len = cache.seq_len();
+        if keep + discard > seq_len {
+            candle::bail!(
+                "context shift: cannot drop {discard} positions after {keep} with {seq_len} cached"
+            )
+        }
+        if discard == 0 {
+            return Ok(());
+        }
+        for (layer, kv_cache) in self.layers.iter().zip(cache.layers.iter_mut()) {
+            layer.shift_kv(kv_cache, keep, discard)?
+        }
+        Ok(())
+    }
+
+    /// Similar to [`Self::shift_cache`] for the internal kv cache used by [`Self::forward`].
+    pub fn shift_context(&mut self, keep: usize, discard: usize) -> Result<()> {
+        let mut cache = std::mem::take(&mut self.cache);
+        let res = self.shift_cache(&mut cache, keep, discard);
+        self.cache = cache;
+        res
+    }
+
+    fn check_cache(&self, cache: &KvCache) -> Result<()> {
+        if cache.layers.len() != self.layers.len() {
+            candle::bail!(
+                "the cache has {} layers but the model has {}",
+                cache.layers.len(),
+                self.layers.len()
+            )
+        }
+        Ok(())
+    }
+
+    /// The hidden states for the token ids `x`, on the device of the first layer.
+    fn embed(&self, x: &Tensor) -> Result<Tensor> {
+        let device = self.tok_embeddings.embeddings().device();
+        self.tok_embeddings
+            .forward(&x.to_device(device)?)?
+            .to_dtype(self.config.activation_dtype)
+    }
+
+    /// Runs layer `layer_idx` on the hidden states `x`, which are moved to the device of the
+    /// layer if needed, `attn` computes the attention given the normalized input.
+    fn forward_layer<F>(&self, layer_idx: usize, x: Tensor, attn: F) -> Result<Tensor>
+    where
+        F: FnOnce(&LayerWeights, &Tensor) -> Result<Tensor>,
+    {
+        let prefetcher = match &self.prefetcher {
+            Some(prefetcher) if prefetcher.contains(layer_idx) => prefetcher,
+            _ => return self.run_layer(layer_idx, &self.layers[layer_idx], x, attn),
+        };
+        // The weights of an offloaded layer are only on the device while the layer runs, the
+        // copy of the layer is dropped before releasing the slot so that its buffers are reused.
+        let slot = prefetcher.fetch(layer_idx)?;
+        let mut layer = self.layers[layer_idx].clone();
+        for (matmul, weight) in layer.matmuls_mut().into_iter().zip(slot.weights()) {
+            matmul.inner = weight.clone()
+        }
+        let x = self.run_layer(layer_idx, &layer, x, attn);
+        drop(layer);
+        prefetcher.release(slot);
+        x
+    }
+
+    fn run_layer<F>(
+        &self,
+        layer_idx: usize,
+        layer: &LayerWeights,
+        x: Tensor,
+        attn: F,
+    ) -> Result<Tensor>
+    where
+        F: FnOnce(&LayerWeights, &Tensor) -> Result<Tensor>,
+    {
+        let dtype = self.config.activation_dtype;
+        let x = x.to_device(&layer.device)?;
+        let residual = &x;
+        let x = layer.attention_norm.forward(&x.to_dtype(DType::F32)?)?;
+        let attn = attn(layer, &x)?;
+        let x = (attn + residual)?;
+
+        // MLP
+        let _enter = layer.span_mlp.enter();
+        let residual = &x;
+        let x = layer.ffn_norm.forward(&x.to_dtype(DType::F32)?)?;
+        let x = layer.mlp_or_moe.forward(&x)?.to_dtype(dtype)?;
+        let x = (x + residual)?;
+        match &self.steering {
+            Some(steering) => steering.apply(layer_idx, &x),
+            None => Ok(x),
+        }
+    }
+
+    /// Runs the embeddings and the layers on the token ids `x` and returns the normalized hidden
+    /// states, `attn` computes the attention of a layer given its index and its normalized input.
+    fn forward_layers<F>(&self, x: &Tensor, mut attn: F) -> Result<Tensor>
+    where
+        F: FnMut(usize, &LayerWeights, &Tensor) -> Result<Tensor>,
+    {
+        let _enter = self.span.enter();
+        let mut x = self.embed(x)?;
+        for layer_idx in 0..self.layers.len() {
+            x = self.forward_layer(layer_idx, x, |layer, x| attn(layer_idx, layer, x))?;
+        }
+        self.norm.forward(&x.to_dtype(DType::F32)?)
+    }
+
+    /// The ranges of consecutive layers on the same device.
+    pub fn stages(&self) -> Vec<std::ops::Range<usize>> {
+        let mut stages: Vec<std::ops::Range<usize>> = vec![];
+        for (layer_idx, layer) in self.layers.iter().enumerate() {
+            match stages.last_mut() {
+                Some(stage) if self.layers[stage.start].device.same_device(&layer.device) => {
+                    stage.end = layer_idx + 1
+                }
+                _ => stages.push(layer_idx..layer_idx + 1),
+            }
+        }
+        stages
+    }
+
+    /// Runs all the layers on `device`, typically a GPU. The weights of the layers on the cpu, e.g.
+    /// mapped to the cpu with [`Self::from_gguf_with_device_map`], stay in host memory and are
+    /// uploaded to `device` while the previous layers run, in a ring of `n_slots` buffers, see
+    /// [`offload`](crate::offload). With two slots, the upload of a layer overlaps with the
+    /// computations of the previous one.
+    ///
+    /// The kv cache of the offloaded layers is then on `device` so the internal cache is cleared,
+    /// the caches created with [`Self::new_cache`] have to be created again.
+    pub fn offload_layers(&mut self, device: &Device, n_slots: usize) -> Result<()> {
+        if self.prefetcher.is_some() {
+            candle::bail!("offload: the layers are already offloaded")
+        }
+        let source = self
+            .layers
+            .iter()
+            .find(|layer| layer.device.same_device(device))
+            .or(self.layers.first());
+        let (cos, sin, neg_inf) = match source {
+            Some(layer) => (
+                layer.cos.to_device(device)?,
+                layer.sin.to_device(device)?,
+                layer.neg_inf.to_device(device)?,
+            ),
+            None => return Ok(()),
+        };
+        let mut offloaded = vec![];
+        for (layer_idx, layer) in self.layers.iter_mut().enumerate() {
+            if layer.device.same_device(device) {
+                continue;
+            }
+            if !layer.device.is_cpu() {
+                candle::bail!(
+                    "offload: layer {layer_idx} is on {:?}, only the cpu layers can be offloaded",
+                    layer.device
+                )
+            }
+            let matmuls = layer
+                .matmuls_mut()
+                .into_iter()
+                .map(|matmul| matmul.inner.clone())
+                .collect();
+            offloaded.push((layer_idx, matmuls));
+            layer.attention_norm = layer.attention_norm.to_device(device)?;
+            layer.ffn_norm = layer.ffn_norm.to_device(device)?;
+            layer.cos = cos.clone();
+            layer.sin = sin.clone();
+            layer.neg_inf = neg_inf.clone();
+            layer.device = device.clone();
+        }
+        if offloaded.is_empty() {
+            return Ok(());
+        }
+        // The output follows the last layer.
+        if offloaded.last().map(|(layer_idx, _)| *layer_idx) == Some(self.layers.len() - 1) {
+            self.norm = self.norm.to_device(device)?;
+            self.output.inner = copy_to_device(&self.output.inner, device)?;
+        }
+        self.prefetcher = Some(Arc::new(Prefetcher::new(offloaded, device, n_slots)?));
+        self.cache.clear();
+        Ok(())
+    }
+
+    /// When the layers are on multiple devices, splits the prompts longer than `micro_batch_size`
+    /// tokens in micro batches so that the devices process different micro batches concurrently,
+    /// see [`pipeline_parallel`](crate::pipeline_parallel). `None`, the default, processes the
+    /// whole prompt on one device after the other.
+    pub fn set_micro_batch_size(&mut self, micro_batch_size: Option<usize>) {
+        self.micro_batch_size = micro_batch_size.filter(|&size| size > 0)
+    }
+
+    /// Similar to [`Self::forward_impl`] for the logits of the last position, the micro batches
+    /// of `micro_batch_size` tokens flowing through the stages of the pipeline.
+    fn forward_pipelined(
+        &self,
+        x: &Tensor,
+        index_pos: usize,
+        cache: &mut KvCache,
+        micro_batch_size: usize,
+    ) -> Result<Tensor> {
+        let _enter = self.span.enter();
+        let (_b_sz, seq_len) = x.dims2()?;
+        let inputs = (0..seq_len)
+            .step_by(micro_batch_size)
+            .map(|start| {
+                let len = micro_batch_size.min(seq_len - start);
+                Ok((index_pos + start, x.narrow(1, start, len)?))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // Each stage owns the kv cache of its layers.
+        let mut kv_caches = cache.layers.as_mut_slice();
+        let mut stages = vec![];
+        for (stage_idx, layers) in self.stages().into_iter().enumerate() {
+            let (stage_caches, rest) = std::mem::take(&mut kv_caches).split_at_mut(layers.len());
+            kv_caches = rest;
+            stages.push(
+                move |_: usize, (index_pos, x): (usize, Tensor)| -> Result<_> {
+                    let mut x = if stage_idx == 0 { self.embed(&x)? } else { x };
+                    let t = x.dim(1)?;
+                    let mask = if t == 1 {
+                        None
+                    } else {
+                        let device = &self.layers[layers.start].device;
+                        Some(candle_nn::attention_mask::causal_mask(
+                            t,
+                            index_pos + t,
+                            device,
+                        )?)
+                    };
+                    for (layer_idx, kv_cache) in layers.clone().zip(stage_caches.iter_mut()) {
+                        x = self.forward_layer(layer_idx, x, |layer, x| {
+                            layer.forward_attn(x, mask.as_ref(), index_pos, kv_cache)
+                        })?;
+                    }
+                    Ok((index_pos, x))
+                },
+            );
+        }
+        let outputs = run_stages(stages, inputs)?;
+        let x = match outputs.last() {
+            Some((_, x)) => x.i((.., x.dim(1)? - 1, ..))?,
+            None => candle::bail!("empty input"),
+        };
+        let x = self.norm.forward(&x.to_dtype(DType::F32)?)?;
+        let _enter = self.span_output.enter();
+        self.output.forward(&x)
+    }
+}
+
+impl crate::generation::batching::BatchedLM for ModelWeights {
+    type Cache = KvCache;
+
+    fn new_cache(&self) -> KvCache {
+        ModelWeights::new_cache(self)
+    }
+
+    fn forward(&self, input_ids: &Tensor, index_pos: usize, cache: &mut KvCache) -> Result<Tensor> {
+        self.forward_with_cache(input_ids, index_pos, cache)
+    }
+
+    fn forward_batch(
+        &self,
+        input_ids: &Tensor,
+        index_pos: &[usize],
+        caches: &mut [KvCache],
+    ) -> Result<Tensor> {
+        ModelWeights::forward_batch(self, input_ids, index_pos, caches)
+    }
 }
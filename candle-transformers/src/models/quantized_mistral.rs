@@ -1,53 +1,12 @@
 use crate::quantized_nn::{linear_no_bias, Embedding, Linear, RmsNorm};
 pub use crate::quantized_var_builder::VarBuilder;
-use candle::{DType, Device, Module, Result, Tensor, D};
+use candle::{DType, Module, Result, Tensor};
+use candle_nn::attention_mask::{MaskCache, MaskKind};
+use candle_nn::rotary_emb::{RotaryConfig, RotaryEmbeddingCache};
 use candle_nn::Activation;
-use std::sync::Arc;
 
 pub use crate::models::mistral::Config;
 
-#[derive(Debug, Clone)]
-struct RotaryEmbedding {
-    sin: Tensor,
-    cos: Tensor,
-}
-
-impl RotaryEmbedding {
-    fn new(cfg: &Config, dev: &Device) -> Result<Self> {
-        let rope_theta = cfg.rope_theta as f32;
-        let dim = cfg.hidden_size / cfg.num_attention_heads;
-        let max_seq_len = cfg.max_position_embeddings;
-        let inv_freq: Vec<_> = (0..dim)
-            .step_by(2)
-            .map(|i| 1f32 / rope_theta.powf(i as f32 / dim as f32))
-            .collect();
-        let inv_freq_len = inv_freq.len();
-        let inv_freq = Tensor::from_vec(inv_freq, (1, inv_freq_len), dev)?;
-        let t = Tensor::arange(0u32, max_seq_len as u32, dev)?
-            .to_dtype(DType::F32)?
-            .reshape((max_seq_len, 1))?;
-        let freqs = t.matmul(&inv_freq)?;
-        Ok(Self {
-            sin: freqs.sin()?,
-            cos: freqs.cos()?,
-        })
-    }
-
-    fn apply_rotary_emb_qkv(
-        &self,
-        q: &Tensor,
-        k: &Tensor,
-        seqlen_offset: usize,
-    ) -> Result<(Tensor, Tensor)> {
-        let (_b_sz, _h, seq_len, _n_embd) = q.dims4()?;
-        let cos = self.cos.narrow(0, seqlen_offset, seq_len)?;
-        let sin = self.sin.narrow(0, seqlen_offset, seq_len)?;
-        let q_embed = candle_nn::rotary_emb::rope(q, &cos, &sin)?;
-        let k_embed = candle_nn::rotary_emb::rope(k, &cos, &sin)?;
-        Ok((q_embed, k_embed))
-    }
-}
-
 #[derive(Debug, Clone)]
 #[allow(clippy::upper_case_acronyms)]
 struct MLP {
@@ -92,12 +51,13 @@ struct Attention {
     num_kv_groups: usize,
     head_dim: usize,
     hidden_size: usize,
-    rotary_emb: Arc<RotaryEmbedding>,
+    rotary_emb: RotaryEmbeddingCache,
+    sliding_window: Option<usize>,
     kv_cache: Option<(Tensor, Tensor)>,
 }
 
 impl Attention {
-    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+    fn new(rotary_emb: RotaryEmbeddingCache, cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let hidden_sz = cfg.hidden_size;
         let num_heads = cfg.num_attention_heads;
         let num_kv_heads = cfg.num_key_value_heads;
@@ -118,6 +78,7 @@ impl Attention {
             head_dim,
             hidden_size: hidden_sz,
             rotary_emb,
+            sliding_window: cfg.sliding_window,
             kv_cache: None,
         })
     }
@@ -146,9 +107,8 @@ impl Attention {
             .reshape((b_sz, q_len, self.num_kv_heads, self.head_dim))?
             .transpose(1, 2)?;
 
-        let (query_states, key_states) =
-            self.rotary_emb
-                .apply_rotary_emb_qkv(&query_states, &key_states, seqlen_offset)?;
+        let query_states = self.rotary_emb.apply(&query_states, seqlen_offset)?;
+        let key_states = self.rotary_emb.apply(&key_states, seqlen_offset)?;
 
         let (key_states, value_states) = match &self.kv_cache {
             None => (key_states, value_states),
@@ -158,7 +118,19 @@ impl Attention {
                 (key_states, value_states)
             }
         };
-        self.kv_cache = Some((key_states.clone(), value_states.clone()));
+        // The next positions never attend further than the sliding window.
+        let kv_len = key_states.dim(2)?;
+        self.kv_cache = match self.sliding_window {
+            Some(window) if kv_len > window => Some((
+                key_states
+                    .narrow(2, kv_len - window, window)?
+                    .contiguous()?,
+                value_states
+                    .narrow(2, kv_len - window, window)?
+                    .contiguous()?,
+            )),
+            _ => Some((key_states.clone(), value_states.clone())),
+        };
 
         let key_states = crate::utils::repeat_kv(key_states, self.num_kv_groups)?;
         let value_states = crate::utils::repeat_kv(value_states, self.num_kv_groups)?;
@@ -180,6 +152,14 @@ impl Attention {
             .apply(&self.o_proj)
     }
 
+    /// The number of cached positions.
+    fn cached_len(&self) -> Result<usize> {
+        match &self.kv_cache {
+            None => Ok(0),
+            Some((k, _)) => k.dim(2),
+        }
+    }
+
     fn clear_kv_cache(&mut self) {
         self.kv_cache = None
     }
@@ -194,7 +174,7 @@ struct DecoderLayer {
 }
 
 impl DecoderLayer {
-    fn new(rotary_emb: Arc<RotaryEmbedding>, cfg: &Config, vb: VarBuilder) -> Result<Self> {
+    fn new(rotary_emb: RotaryEmbeddingCache, cfg: &Config, vb: VarBuilder) -> Result<Self> {
         let self_attn = Attention::new(rotary_emb, cfg, vb.pp("self_attn"))?;
         let mlp = MLP::new(cfg, vb.pp("mlp"))?;
         let input_layernorm =
@@ -238,8 +218,7 @@ pub struct Model {
     layers: Vec<DecoderLayer>,
     norm: RmsNorm,
     lm_head: Linear,
-    sliding_window: Option<usize>,
-    device: Device,
+    masks: MaskCache,
 }
 
 impl Model {
@@ -247,7 +226,10 @@ impl Model {
         let vb_m = vb.pp("model");
         let embed_tokens =
             Embedding::new(cfg.vocab_size, cfg.hidden_size, vb_m.pp("embed_tokens"))?;
-        let rotary_emb = Arc::new(RotaryEmbedding::new(cfg, vb_m.device())?);
+        // The rotary tables are shared by the layers and computed for the positions in use.
+        let head_dim = cfg.hidden_size / cfg.num_attention_heads;
+        let rotary = RotaryConfig::new(head_dim, cfg.rope_theta as f32);
+        let rotary_emb = RotaryEmbeddingCache::new(rotary)?;
         let mut layers = Vec::with_capacity(cfg.num_hidden_layers);
         let vb_l = vb_m.pp("layers");
         for layer_idx in 0..cfg.num_hidden_layers {
@@ -256,51 +238,32 @@ impl Model {
         }
         let norm = RmsNorm::new(cfg.hidden_size, cfg.rms_norm_eps, vb_m.pp("norm"))?;
         let lm_head = linear_no_bias(cfg.hidden_size, cfg.vocab_size, vb.pp("lm_head"))?;
+        let mask_kind = match cfg.sliding_window {
+            Some(window) => MaskKind::SlidingWindow(window),
+            None => MaskKind::Causal,
+        };
         Ok(Self {
             embed_tokens,
             layers,
             norm,
             lm_head,
-            sliding_window: cfg.sliding_window,
-            device: vb.device().clone(),
+            masks: MaskCache::new(mask_kind, vb.device()),
         })
     }
 
-    fn prepare_decoder_attention_mask(
-        &self,
-        tgt_len: usize,
-        seqlen_offset: usize,
-    ) -> Result<Tensor> {
-        let sliding_window = self.sliding_window.unwrap_or(tgt_len + 1);
-        let mask: Vec<_> = (0..tgt_len)
-            .flat_map(|i| {
-                (0..tgt_len).map(move |j| {
-                    if i < j || j + sliding_window < i {
-                        f32::NEG_INFINITY
-                    } else {
-                        0.
-                    }
-                })
-            })
-            .collect();
-        let mask = Tensor::from_slice(&mask, (tgt_len, tgt_len), &self.device)?;
-        let mask = if seqlen_offset > 0 {
-            let mask0 = Tensor::zeros((tgt_len, seqlen_offset), DType::F32, &self.device)?;
-            Tensor::cat(&[&mask0, &mask], D::Minus1)?
-        } else {
-            mask
-        };
-        mask.expand((1, 1, tgt_len, tgt_len + seqlen_offset))?
-            .to_dtype(DType::F32)
-    }
-
     pub fn forward(&mut self, input_ids: &Tensor, seqlen_offset: usize) -> Result<Tensor> {
         let (_b_size, seq_len) = input_ids.dims2()?;
-        let attention_mask = if seq_len <= 1 {
-            None
+        // The kv cache only holds the positions in the sliding window, these are the last ones
+        // before the new tokens so the mask only depends on the number of cached positions.
+        let cached_len = match self.layers.first() {
+            Some(layer) => layer.self_attn.cached_len()?,
+            None => 0,
+        };
+        let kv_len = cached_len + seq_len;
+        let attention_mask = if self.masks.kind().needs_mask(seq_len, kv_len) {
+            Some(self.masks.additive(seq_len, kv_len, DType::F32)?)
         } else {
-            let mask = self.prepare_decoder_attention_mask(seq_len, seqlen_offset)?;
-            Some(mask)
+            None
         };
         let mut xs = self.embed_tokens.forward(input_ids)?;
         for layer in self.layers.iter_mut() {
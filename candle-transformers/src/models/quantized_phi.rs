@@ -1,5 +1,6 @@
 use std::collections::HashMap;
 
+use crate::generation::steering::Steering;
 use candle::quantized::gguf_file;
 use candle::quantized::QTensor;
 use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
@@ -152,6 +153,7 @@ pub struct ModelWeights {
     masks: HashMap<usize, Tensor>,
     span: tracing::Span,
     span_output: tracing::Span,
+    steering: Option<Steering>,
 }
 
 fn precomput_freqs_cis(
@@ -249,37 +251,51 @@ impl ModelWeights {
             masks: HashMap::new(),
             span,
             span_output,
+            steering: None,
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
+    /// The causal mask for `t` tokens starting at `index_pos`, the previous positions are
+    /// available in the kv cache so that a prompt can be processed in multiple chunks.
+    fn mask(&mut self, t: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t).filter(|_| index_pos == 0) {
             Ok(mask.clone())
         } else {
             let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .flat_map(|i| (0..index_pos + t).map(move |j| u8::from(j > i + index_pos)))
                 .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
-            self.masks.insert(t, mask.clone());
+            let mask = Tensor::from_slice(&mask, (t, index_pos + t), device)?;
+            if index_pos == 0 {
+                self.masks.insert(t, mask.clone());
+            }
             Ok(mask)
         }
     }
 
+    /// Adds steering vectors to the hidden states at the output of the layers, `None` disables
+    /// steering.
+    pub fn set_steering(&mut self, steering: Option<Steering>) {
+        self.steering = steering
+    }
+
     pub fn forward(&mut self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
         let (_b_sz, seq_len) = xs.dims2()?;
         let mask = if seq_len == 1 {
             None
         } else {
-            Some(self.mask(seq_len, xs.device())?)
+            Some(self.mask(seq_len, index_pos, xs.device())?)
         };
         let _enter = self.span.enter();
         let mut xs = self.tok_embeddings.forward(xs)?;
-        for layer in self.layers.iter_mut() {
+        for (layer_idx, layer) in self.layers.iter_mut().enumerate() {
             let residual = &xs;
             let xs_norm = xs.apply(&layer.attn_norm)?;
             let attn_outputs = layer.forward_attn(&xs_norm, mask.as_ref(), index_pos)?;
             let feed_forward_hidden_states = layer.mlp.forward(&xs_norm)?;
-            xs = (attn_outputs + feed_forward_hidden_states + residual)?
+            xs = (attn_outputs + feed_forward_hidden_states + residual)?;
+            if let Some(steering) = &self.steering {
+                xs = steering.apply(layer_idx, &xs)?
+            }
         }
         let xs = xs.apply(&self.output_norm)?.i((.., seq_len - 1, ..))?;
         let _enter = self.span_output.enter();
@@ -1,9 +1,10 @@
 use std::collections::HashMap;
 
+use crate::generation::steering::Steering;
 use candle::quantized::gguf_file;
 use candle::quantized::QTensor;
 use candle::{DType, Device, IndexOp, Module, Result, Tensor, D};
-use candle_nn::{kv_cache::KvCache, Embedding, RmsNorm};
+use candle_nn::{Embedding, RmsNorm};
 
 #[derive(Debug, Clone)]
 struct QLinear {
@@ -65,10 +66,9 @@ struct LayerWeights {
     n_head: usize,
     n_kv_head: usize,
     head_dim: usize,
-    cos: Tensor,
-    sin: Tensor,
+    rotary: RotaryEmbedding,
     neg_inf: Tensor,
-    kv_cache: KvCache,
+    kv_cache: Option<(Tensor, Tensor)>,
     use_flash_attn: bool,
     span_attn: tracing::Span,
     span_rot: tracing::Span,
@@ -84,8 +84,9 @@ impl LayerWeights {
     fn apply_rotary_emb(&self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
         let _enter = self.span_rot.enter();
         let (_b_sz, _h, seq_len, _n_embd) = xs.dims4()?;
-        let cos = self.cos.narrow(0, index_pos, seq_len)?;
-        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        let (cos, sin) = self.rotary.tables(index_pos + seq_len);
+        let cos = cos.narrow(0, index_pos, seq_len)?;
+        let sin = sin.narrow(0, index_pos, seq_len)?;
         candle_nn::rotary_emb::rope(&xs.contiguous()?, &cos, &sin)
     }
 
@@ -112,7 +113,7 @@ impl LayerWeights {
             .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
             .transpose(1, 2)?;
         let k = k
-            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
             .transpose(1, 2)?;
         let v = v
             .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
@@ -121,7 +122,17 @@ impl LayerWeights {
         let q = self.apply_rotary_emb(&q, index_pos)?.contiguous()?;
         let k = self.apply_rotary_emb(&k, index_pos)?;
 
-        let (k, v) = self.kv_cache.append(&k.contiguous()?, &v.contiguous()?)?;
+        // The cache grows with the sequence rather than being allocated for the whole context,
+        // which would not fit in memory for the 128k models.
+        let (k, v) = match &self.kv_cache {
+            Some((k_cache, v_cache)) if index_pos > 0 => {
+                let k = Tensor::cat(&[k_cache, &k.contiguous()?], 2)?;
+                let v = Tensor::cat(&[v_cache, &v.contiguous()?], 2)?;
+                (k, v)
+            }
+            _ => (k.contiguous()?, v.contiguous()?),
+        };
+        self.kv_cache = Some((k.clone(), v.clone()));
 
         let k = crate::utils::repeat_kv(k, self.n_head / self.n_kv_head)?;
         let v = crate::utils::repeat_kv(v, self.n_head / self.n_kv_head)?;
@@ -179,25 +190,55 @@ pub struct ModelWeights {
     masks: HashMap<usize, Tensor>,
     span: tracing::Span,
     span_output: tracing::Span,
+    steering: Option<Steering>,
+}
+
+/// The rotary embeddings, the 128k context models use the long rope scaling whose frequency
+/// factors differ within and past the original context length.
+#[derive(Debug, Clone)]
+struct RotaryEmbedding {
+    cos: Tensor,
+    sin: Tensor,
+    long: Option<(Tensor, Tensor)>,
+    original_max_seq_len: usize,
+}
+
+impl RotaryEmbedding {
+    /// The tables to use when the sequence, including the cached positions, has `seq_len` tokens.
+    fn tables(&self, seq_len: usize) -> (&Tensor, &Tensor) {
+        match &self.long {
+            Some((cos, sin)) if seq_len > self.original_max_seq_len => (cos, sin),
+            _ => (&self.cos, &self.sin),
+        }
+    }
 }
 
 fn precomput_freqs_cis(
     head_dim: usize,
     max_seq_len: usize,
     freq_base: f32,
+    factors: Option<&[f32]>,
+    attn_factor: f32,
     device: &Device,
 ) -> Result<(Tensor, Tensor)> {
+    if let Some(factors) = factors.filter(|f| f.len() != head_dim / 2) {
+        candle::bail!("unexpected rope factors length {}", factors.len())
+    }
     let theta: Vec<_> = (0..head_dim)
         .step_by(2)
-        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+        .enumerate()
+        .map(|(idx, i)| {
+            let factor = factors.map_or(1., |f| f[idx]);
+            1f32 / (factor * freq_base.powf(i as f32 / head_dim as f32))
+        })
         .collect();
     let theta = Tensor::new(theta.as_slice(), device)?;
     let idx_theta = Tensor::arange(0, max_seq_len as u32, device)?
         .to_dtype(DType::F32)?
         .reshape((max_seq_len, 1))?
         .matmul(&theta.reshape((1, theta.elem_count()))?)?;
-    let cos = idx_theta.cos()?;
-    let sin = idx_theta.sin()?;
+    let cos = (idx_theta.cos()? * attn_factor as f64)?;
+    let sin = (idx_theta.sin()? * attn_factor as f64)?;
     Ok((cos, sin))
 }
 
@@ -207,6 +248,18 @@ impl ModelWeights {
         ct: gguf_file::Content,
         reader: &mut R,
         device: &Device,
+    ) -> Result<Self> {
+        Self::from_gguf_with_max_seq_len(use_flash_attn, ct, reader, device, None)
+    }
+
+    /// Loads the model with its context length capped to `max_seq_len`, this avoids computing
+    /// the rotary embeddings for the 128k positions of the long rope models.
+    pub fn from_gguf_with_max_seq_len<R: std::io::Seek + std::io::Read>(
+        use_flash_attn: bool,
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+        max_seq_len: Option<usize>,
     ) -> Result<Self> {
         let md_get = |s: &str| match ct.metadata.get(s) {
             None => candle::bail!("cannot find {s} in metadata"),
@@ -218,12 +271,64 @@ impl ModelWeights {
         let head_count_kv = md_get("phi3.attention.head_count_kv")?.to_u32()? as usize;
         let block_count = md_get("phi3.block_count")?.to_u32()? as usize;
         let embedding_length = md_get("phi3.embedding_length")?.to_u32()? as usize;
-        let max_seq_len = md_get("phi3.context_length")?.to_u32()? as usize;
+        let context_length = md_get("phi3.context_length")?.to_u32()? as usize;
+        let max_seq_len = max_seq_len.map_or(context_length, |v| v.min(context_length));
         let head_dim = embedding_length / head_count;
         let i_size = md_get("phi3.feed_forward_length")?.to_u32()? as usize;
         let rope_dim = md_get("phi3.rope.dimension_count")?.to_u32()? as usize;
         let rms_eps = md_get("phi3.attention.layer_norm_rms_epsilon")?.to_f32()? as f64;
-        let (cos, sin) = precomput_freqs_cis(rope_dim, max_seq_len, 10_000., device)?;
+        let rope_freq_base = md_get("phi3.rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(10_000.);
+        let rotary = if ct.tensor_infos.contains_key("rope_factors_long.weight") {
+            // The long rope models, llama.cpp stores the frequency factors as tensors.
+            let original_max_seq_len =
+                md_get("phi3.rope.scaling.original_context_length")?.to_u32()? as usize;
+            let attn_factor = match md_get("phi3.rope.scaling.attn_factor") {
+                Ok(v) => v.to_f32()?,
+                Err(_) => {
+                    let scale = context_length as f32 / original_max_seq_len as f32;
+                    if scale <= 1. {
+                        1.
+                    } else {
+                        (1. + scale.ln() / (original_max_seq_len as f32).ln()).sqrt()
+                    }
+                }
+            };
+            let mut factors = |name: &str| -> Result<Vec<f32>> {
+                ct.tensor(reader, name, device)?
+                    .dequantize(device)?
+                    .to_vec1::<f32>()
+            };
+            let short_factors = factors("rope_factors_short.weight")?;
+            let long_factors = factors("rope_factors_long.weight")?;
+            let table = |factors: &[f32]| {
+                precomput_freqs_cis(
+                    rope_dim,
+                    max_seq_len,
+                    rope_freq_base,
+                    Some(factors),
+                    attn_factor,
+                    device,
+                )
+            };
+            let (cos, sin) = table(&short_factors)?;
+            RotaryEmbedding {
+                cos,
+                sin,
+                long: Some(table(&long_factors)?),
+                original_max_seq_len,
+            }
+        } else {
+            let (cos, sin) =
+                precomput_freqs_cis(rope_dim, max_seq_len, rope_freq_base, None, 1., device)?;
+            RotaryEmbedding {
+                cos,
+                sin,
+                long: None,
+                original_max_seq_len: context_length,
+            }
+        };
         let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
 
         let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
@@ -251,7 +356,6 @@ impl ModelWeights {
             )?;
             let span_attn = tracing::span!(tracing::Level::TRACE, "attn");
             let span_rot = tracing::span!(tracing::Level::TRACE, "attn-rot");
-            let kv_cache = KvCache::new(2, max_seq_len);
             layers.push(LayerWeights {
                 attn_qkv: QLinear::new(&ct, reader, &format!("{prefix}.attn_qkv"), device)?,
                 attn_output: QLinear::new(&ct, reader, &format!("{prefix}.attn_output"), device)?,
@@ -261,10 +365,9 @@ impl ModelWeights {
                 n_head: head_count,
                 n_kv_head: head_count_kv,
                 head_dim,
-                cos: cos.clone(),
-                sin: sin.clone(),
+                rotary: rotary.clone(),
                 neg_inf: neg_inf.clone(),
-                kv_cache,
+                kv_cache: None,
                 use_flash_attn,
                 span_attn,
                 span_rot,
@@ -280,32 +383,43 @@ impl ModelWeights {
             masks: HashMap::new(),
             span,
             span_output,
+            steering: None,
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
+    /// The causal mask for `t` tokens starting at `index_pos`, the previous positions are
+    /// available in the kv cache so that a prompt can be processed in multiple chunks.
+    fn mask(&mut self, t: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t).filter(|_| index_pos == 0) {
             Ok(mask.clone())
         } else {
             let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .flat_map(|i| (0..index_pos + t).map(move |j| u8::from(j > i + index_pos)))
                 .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
-            self.masks.insert(t, mask.clone());
+            let mask = Tensor::from_slice(&mask, (t, index_pos + t), device)?;
+            if index_pos == 0 {
+                self.masks.insert(t, mask.clone());
+            }
             Ok(mask)
         }
     }
 
+    /// Adds steering vectors to the hidden states at the output of the layers, `None` disables
+    /// steering.
+    pub fn set_steering(&mut self, steering: Option<Steering>) {
+        self.steering = steering
+    }
+
     pub fn forward(&mut self, xs: &Tensor, index_pos: usize) -> Result<Tensor> {
         let (_b_sz, seq_len) = xs.dims2()?;
         let mask = if seq_len == 1 {
             None
         } else {
-            Some(self.mask(seq_len, xs.device())?)
+            Some(self.mask(seq_len, index_pos, xs.device())?)
         };
         let _enter = self.span.enter();
         let mut xs = self.tok_embeddings.forward(xs)?;
-        for layer in self.layers.iter_mut() {
+        for (layer_idx, layer) in self.layers.iter_mut().enumerate() {
             let residual = &xs;
             let ys = xs.apply(&layer.attn_norm)?;
             let ys = layer.forward_attn(&ys, mask.as_ref(), index_pos)?;
@@ -313,7 +427,10 @@ impl ModelWeights {
             let residual = &ys;
             let ys = ys.apply(&layer.ffn_norm)?;
             let ys = layer.mlp.forward(&ys)?;
-            xs = (ys + residual)?
+            xs = (ys + residual)?;
+            if let Some(steering) = &self.steering {
+                xs = steering.apply(layer_idx, &xs)?
+            }
         }
         let xs = xs.apply(&self.output_norm)?.i((.., seq_len - 1, ..))?;
         let _enter = self.span_output.enter();
@@ -1,3 +1,4 @@
+use crate::generation::steering::Steering;
 use crate::{quantized_nn::RmsNorm, utils::repeat_kv};
 use candle::{
     quantized::{gguf_file, QMatMul},
@@ -140,6 +141,7 @@ pub struct ModelWeights {
     masks: HashMap<usize, Tensor>,
     span: tracing::Span,
     span_output: tracing::Span,
+    steering: Option<Steering>,
 }
 
 fn precomput_freqs_cis(
@@ -275,32 +277,43 @@ impl ModelWeights {
             masks: HashMap::new(),
             span,
             span_output,
+            steering: None,
         })
     }
 
-    fn mask(&mut self, t: usize, device: &Device) -> Result<Tensor> {
-        if let Some(mask) = self.masks.get(&t) {
+    /// The causal mask for `t` tokens starting at `index_pos`, the previous positions are
+    /// available in the kv cache so that a prompt can be processed in multiple chunks.
+    fn mask(&mut self, t: usize, index_pos: usize, device: &Device) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t).filter(|_| index_pos == 0) {
             Ok(mask.clone())
         } else {
             let mask: Vec<_> = (0..t)
-                .flat_map(|i| (0..t).map(move |j| u8::from(j > i)))
+                .flat_map(|i| (0..index_pos + t).map(move |j| u8::from(j > i + index_pos)))
                 .collect();
-            let mask = Tensor::from_slice(&mask, (t, t), device)?;
-            self.masks.insert(t, mask.clone());
+            let mask = Tensor::from_slice(&mask, (t, index_pos + t), device)?;
+            if index_pos == 0 {
+                self.masks.insert(t, mask.clone());
+            }
             Ok(mask)
         }
     }
 
+    /// Adds steering vectors to the hidden states at the output of the layers, `None` disables
+    /// steering.
+    pub fn set_steering(&mut self, steering: Option<Steering>) {
+        self.steering = steering
+    }
+
     pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
         let (_b_sz, seq_len) = x.dims2()?;
         let mask = if seq_len == 1 {
             None
         } else {
-            Some(self.mask(seq_len, x.device())?)
+            Some(self.mask(seq_len, index_pos, x.device())?)
         };
         let _enter = self.span.enter();
         let mut layer_in = self.tok_embeddings.forward(x)?;
-        for layer in self.layers.iter_mut() {
+        for (layer_idx, layer) in self.layers.iter_mut().enumerate() {
             let x = layer_in;
             let residual = &x;
             let x = layer.attention_norm.forward(&x)?;
@@ -313,7 +326,10 @@ impl ModelWeights {
             let x = layer.ffn_norm.forward(&x)?;
             let x = layer.mlp.forward(&x)?;
             let x = (x + residual)?;
-            layer_in = x
+            layer_in = match &self.steering {
+                Some(steering) => steering.apply(layer_idx, &x)?,
+                None => x,
+            }
         }
         let x = self.norm.forward(&layer_in)?;
         let x = x.i((.., seq_len - 1, ..))?;
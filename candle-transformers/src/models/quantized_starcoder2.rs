@@ -0,0 +1,371 @@
+//! Quantized StarCoder2 models loaded from gguf files.
+//!
+//! StarCoder2 uses grouped query attention restricted to a sliding window, layer norms and
+//! linear layers with biases, and a gelu mlp. The kv cache only keeps the positions within the
+//! sliding window.
+use crate::generation::steering::Steering;
+use candle::quantized::{gguf_file, QMatMul, QTensor};
+use candle::{DType, Device, IndexOp, Module, Result, Tensor};
+use candle_nn::{Embedding, LayerNorm};
+use std::collections::HashMap;
+
+/// The sliding window used when the gguf metadata does not specify one, all the released
+/// StarCoder2 models use this value.
+pub const DEFAULT_SLIDING_WINDOW: usize = 4096;
+
+#[derive(Debug, Clone)]
+struct QLinear {
+    inner: QMatMul,
+    bias: Option<Tensor>,
+}
+
+impl QLinear {
+    fn new<R: std::io::Read + std::io::Seek>(
+        ct: &gguf_file::Content,
+        r: &mut R,
+        name: &str,
+        device: &Device,
+    ) -> Result<Self> {
+        let w = ct.tensor(r, &format!("{name}.weight"), device)?;
+        let inner = QMatMul::from_qtensor(w)?;
+        let bias = match ct.tensor(r, &format!("{name}.bias"), device) {
+            Ok(b) => Some(b.dequantize(device)?),
+            Err(_) => None,
+        };
+        Ok(Self { inner, bias })
+    }
+}
+
+impl Module for QLinear {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        let xs = self.inner.forward(xs)?;
+        match &self.bias {
+            Some(bias) => xs.broadcast_add(bias),
+            None => Ok(xs),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Mlp {
+    ffn_up: QLinear,
+    ffn_down: QLinear,
+}
+
+impl Module for Mlp {
+    fn forward(&self, xs: &Tensor) -> Result<Tensor> {
+        xs.apply(&self.ffn_up)?.gelu()?.apply(&self.ffn_down)
+    }
+}
+
+fn layer_norm(w: QTensor, b: QTensor, eps: f64) -> Result<LayerNorm> {
+    let w = w.dequantize(&w.device())?;
+    let b = b.dequantize(&b.device())?;
+    Ok(LayerNorm::new(w, b, eps))
+}
+
+#[derive(Debug, Clone)]
+struct LayerWeights {
+    attn_q: QLinear,
+    attn_k: QLinear,
+    attn_v: QLinear,
+    attn_output: QLinear,
+    attn_norm: LayerNorm,
+    mlp: Mlp,
+    ffn_norm: LayerNorm,
+    n_head: usize,
+    n_kv_head: usize,
+    head_dim: usize,
+    sliding_window: usize,
+    cos: Tensor,
+    sin: Tensor,
+    neg_inf: Tensor,
+    kv_cache: Option<(Tensor, Tensor)>,
+    span_attn: tracing::Span,
+    span_rot: tracing::Span,
+    span_mlp: tracing::Span,
+}
+
+fn masked_fill(on_false: &Tensor, mask: &Tensor, on_true: &Tensor) -> Result<Tensor> {
+    let shape = mask.shape();
+    let m = mask.where_cond(&on_true.broadcast_as(shape.dims())?, on_false)?;
+    Ok(m)
+}
+
+impl LayerWeights {
+    fn apply_rotary_emb(&self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let _enter = self.span_rot.enter();
+        let (_b_sz, _n_head, seq_len, _n_embd) = x.dims4()?;
+        let cos = self.cos.narrow(0, index_pos, seq_len)?;
+        let sin = self.sin.narrow(0, index_pos, seq_len)?;
+        candle_nn::rotary_emb::rope(&x.contiguous()?, &cos, &sin)
+    }
+
+    fn forward_attn(
+        &mut self,
+        x: &Tensor,
+        mask: Option<&Tensor>,
+        index_pos: usize,
+    ) -> Result<Tensor> {
+        let _enter = self.span_attn.enter();
+        let (b_sz, seq_len, n_embd) = x.dims3()?;
+
+        let q = self
+            .attn_q
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let k = self
+            .attn_k
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?;
+        let v = self
+            .attn_v
+            .forward(x)?
+            .reshape((b_sz, seq_len, self.n_kv_head, self.head_dim))?
+            .transpose(1, 2)?
+            .contiguous()?;
+
+        let q = self.apply_rotary_emb(&q, index_pos)?;
+        let k = self.apply_rotary_emb(&k, index_pos)?;
+
+        let (k, v) = match &self.kv_cache {
+            Some((k_cache, v_cache)) if index_pos > 0 => {
+                let k = Tensor::cat(&[k_cache, &k], 2)?;
+                let v = Tensor::cat(&[v_cache, &v], 2)?;
+                (k, v)
+            }
+            _ => (k, v),
+        };
+        // The next positions never attend further than the sliding window.
+        let kv_len = k.dim(2)?;
+        self.kv_cache = if kv_len > self.sliding_window {
+            let start = kv_len - self.sliding_window;
+            Some((
+                k.narrow(2, start, self.sliding_window)?.contiguous()?,
+                v.narrow(2, start, self.sliding_window)?.contiguous()?,
+            ))
+        } else {
+            Some((k.clone(), v.clone()))
+        };
+
+        let k = crate::utils::repeat_kv(k, self.n_head / self.n_kv_head)?;
+        let v = crate::utils::repeat_kv(v, self.n_head / self.n_kv_head)?;
+
+        let att = (q.matmul(&k.t()?)? / (self.head_dim as f64).sqrt())?;
+        let att = match mask {
+            None => att,
+            Some(mask) => {
+                let mask = mask.broadcast_as(att.shape())?;
+                masked_fill(&att, &mask, &self.neg_inf)?
+            }
+        };
+        let att = candle_nn::ops::softmax_last_dim(&att)?;
+        // Convert to contiguous as matmul doesn't support strided vs for now.
+        let y = att.matmul(&v.contiguous()?)?;
+        let y = y.transpose(1, 2)?.reshape(&[b_sz, seq_len, n_embd])?;
+        self.attn_output.forward(&y)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ModelWeights {
+    tok_embeddings: Embedding,
+    layers: Vec<LayerWeights>,
+    output_norm: LayerNorm,
+    output: QMatMul,
+    sliding_window: usize,
+    masks: HashMap<usize, Tensor>,
+    span: tracing::Span,
+    span_output: tracing::Span,
+    steering: Option<Steering>,
+}
+
+fn precomput_freqs_cis(
+    head_dim: usize,
+    freq_base: f32,
+    context_length: usize,
+    device: &Device,
+) -> Result<(Tensor, Tensor)> {
+    let theta: Vec<_> = (0..head_dim)
+        .step_by(2)
+        .map(|i| 1f32 / freq_base.powf(i as f32 / head_dim as f32))
+        .collect();
+    let theta = Tensor::new(theta.as_slice(), device)?;
+    let idx_theta = Tensor::arange(0, context_length as u32, device)?
+        .to_dtype(DType::F32)?
+        .reshape((context_length, 1))?
+        .matmul(&theta.reshape((1, theta.elem_count()))?)?;
+    let cos = idx_theta.cos()?;
+    let sin = idx_theta.sin()?;
+    Ok((cos, sin))
+}
+
+impl ModelWeights {
+    pub fn from_gguf<R: std::io::Seek + std::io::Read>(
+        ct: gguf_file::Content,
+        reader: &mut R,
+        device: &Device,
+    ) -> Result<Self> {
+        let md_get = |s: &str| match ct.metadata.get(s) {
+            None => candle::bail!("cannot find {s} in metadata"),
+            Some(v) => Ok(v),
+        };
+
+        let head_count = md_get("starcoder2.attention.head_count")?.to_u32()? as usize;
+        let head_count_kv = md_get("starcoder2.attention.head_count_kv")?.to_u32()? as usize;
+        let embedding_length = md_get("starcoder2.embedding_length")?.to_u32()? as usize;
+        let context_length = md_get("starcoder2.context_length")?.to_u32()? as usize;
+        let block_count = md_get("starcoder2.block_count")?.to_u32()? as usize;
+        let ln_eps = md_get("starcoder2.attention.layer_norm_epsilon")?.to_f32()? as f64;
+        let rope_freq_base = md_get("starcoder2.rope.freq_base")
+            .and_then(|m| m.to_f32())
+            .unwrap_or(10000f32);
+        let sliding_window = md_get("starcoder2.attention.sliding_window")
+            .and_then(|m| m.to_u32())
+            .map_or(DEFAULT_SLIDING_WINDOW, |v| v as usize);
+
+        let head_dim = embedding_length / head_count;
+        let neg_inf = Tensor::new(f32::NEG_INFINITY, device)?;
+
+        let tok_embeddings = ct.tensor(reader, "token_embd.weight", device)?;
+        let tok_embeddings = tok_embeddings.dequantize(device)?;
+        let output_norm = layer_norm(
+            ct.tensor(reader, "output_norm.weight", device)?,
+            ct.tensor(reader, "output_norm.bias", device)?,
+            ln_eps,
+        )?;
+        let output = match ct.tensor(reader, "output.weight", device) {
+            Ok(v) => QMatMul::from_qtensor(v)?,
+            // The output layer is tied to the token embeddings.
+            _ => QMatMul::from_qtensor(ct.tensor(reader, "token_embd.weight", device)?)?,
+        };
+
+        let (cos, sin) = precomput_freqs_cis(head_dim, rope_freq_base, context_length, device)?;
+
+        let mut layers = Vec::with_capacity(block_count);
+        for layer_idx in 0..block_count {
+            let prefix = format!("blk.{layer_idx}");
+            let mlp = Mlp {
+                ffn_up: QLinear::new(&ct, reader, &format!("{prefix}.ffn_up"), device)?,
+                ffn_down: QLinear::new(&ct, reader, &format!("{prefix}.ffn_down"), device)?,
+            };
+            let attn_norm = layer_norm(
+                ct.tensor(reader, &format!("{prefix}.attn_norm.weight"), device)?,
+                ct.tensor(reader, &format!("{prefix}.attn_norm.bias"), device)?,
+                ln_eps,
+            )?;
+            let ffn_norm = layer_norm(
+                ct.tensor(reader, &format!("{prefix}.ffn_norm.weight"), device)?,
+                ct.tensor(reader, &format!("{prefix}.ffn_norm.bias"), device)?,
+                ln_eps,
+            )?;
+            layers.push(LayerWeights {
+                attn_q: QLinear::new(&ct, reader, &format!("{prefix}.attn_q"), device)?,
+                attn_k: QLinear::new(&ct, reader, &format!("{prefix}.attn_k"), device)?,
+                attn_v: QLinear::new(&ct, reader, &format!("{prefix}.attn_v"), device)?,
+                attn_output: QLinear::new(&ct, reader, &format!("{prefix}.attn_output"), device)?,
+                attn_norm,
+                mlp,
+                ffn_norm,
+                n_head: head_count,
+                n_kv_head: head_count_kv,
+                head_dim,
+                sliding_window,
+                cos: cos.clone(),
+                sin: sin.clone(),
+                neg_inf: neg_inf.clone(),
+                kv_cache: None,
+                span_attn: tracing::span!(tracing::Level::TRACE, "attn"),
+                span_rot: tracing::span!(tracing::Level::TRACE, "attn-rot"),
+                span_mlp: tracing::span!(tracing::Level::TRACE, "attn-mlp"),
+            });
+        }
+
+        Ok(Self {
+            tok_embeddings: Embedding::new(tok_embeddings, embedding_length),
+            layers,
+            output_norm,
+            output,
+            sliding_window,
+            masks: HashMap::new(),
+            span: tracing::span!(tracing::Level::TRACE, "model"),
+            span_output: tracing::span!(tracing::Level::TRACE, "output"),
+            steering: None,
+        })
+    }
+
+    pub fn sliding_window(&self) -> usize {
+        self.sliding_window
+    }
+
+    /// The mask for `t` tokens starting at `index_pos`, the kv cache holding the keys from
+    /// position `kv_start`. A token attends to itself and to the `sliding_window` previous ones.
+    fn mask(
+        &mut self,
+        t: usize,
+        index_pos: usize,
+        kv_start: usize,
+        device: &Device,
+    ) -> Result<Tensor> {
+        if let Some(mask) = self.masks.get(&t).filter(|_| index_pos == 0) {
+            return Ok(mask.clone());
+        }
+        let sliding_window = self.sliding_window;
+        let kv_len = index_pos - kv_start + t;
+        let mask: Vec<_> = (0..t)
+            .flat_map(|i| {
+                (0..kv_len).map(move |j| {
+                    let (q_pos, k_pos) = (index_pos + i, kv_start + j);
+                    u8::from(k_pos > q_pos || k_pos + sliding_window < q_pos)
+                })
+            })
+            .collect();
+        let mask = Tensor::from_slice(&mask, (t, kv_len), device)?;
+        if index_pos == 0 {
+            self.masks.insert(t, mask.clone());
+        }
+        Ok(mask)
+    }
+
+    /// Adds steering vectors to the hidden states at the output of the layers, `None` disables
+    /// steering.
+    pub fn set_steering(&mut self, steering: Option<Steering>) {
+        self.steering = steering
+    }
+
+    pub fn forward(&mut self, x: &Tensor, index_pos: usize) -> Result<Tensor> {
+        let (_b_sz, seq_len) = x.dims2()?;
+        // A single token attends to the whole kv cache, which is bounded by the sliding window.
+        let mask = if seq_len == 1 {
+            None
+        } else {
+            let kv_start = index_pos.saturating_sub(self.sliding_window);
+            Some(self.mask(seq_len, index_pos, kv_start, x.device())?)
+        };
+        let _enter = self.span.enter();
+        let mut layer_in = self.tok_embeddings.forward(x)?;
+        for (layer_idx, layer) in self.layers.iter_mut().enumerate() {
+            let x = layer_in;
+            let residual = &x;
+            let x = layer.attn_norm.forward(&x)?;
+            let attn = layer.forward_attn(&x, mask.as_ref(), index_pos)?;
+            let x = (attn + residual)?;
+
+            let _enter = layer.span_mlp.enter();
+            let residual = &x;
+            let x = layer.ffn_norm.forward(&x)?;
+            let x = layer.mlp.forward(&x)?;
+            let x = (x + residual)?;
+            layer_in = match &self.steering {
+                Some(steering) => steering.apply(layer_idx, &x)?,
+                None => x,
+            }
+        }
+        let x = self.output_norm.forward(&layer_in)?;
+        let x = x.i((.., seq_len - 1, ..))?;
+        let _enter = self.span_output.enter();
+        self.output.forward(&x)
+    }
+}
@@ -3,6 +3,7 @@
 
 use crate::models::with_tracing::{linear_no_bias, Embedding, Linear};
 use candle::{DType, Device, Module, Result, Tensor, D};
+use candle_nn::attention::RelativePositionBias;
 use candle_nn::{Activation, VarBuilder};
 use serde::Deserialize;
 use std::sync::Arc;
@@ -309,9 +310,7 @@ struct T5Attention {
     o: Linear,
     n_heads: usize,
     d_kv: usize,
-    relative_attention_bias: Option<Embedding>,
-    relative_attention_num_buckets: usize,
-    relative_attention_max_distance: usize,
+    relative_attention_bias: Option<RelativePositionBias<Embedding>>,
     inner_dim: usize,
     use_cache: bool,
     kv_cache: Option<(Tensor, Tensor)>,
@@ -339,7 +338,13 @@ impl T5Attention {
                 cfg.num_heads,
                 vb.pp("relative_attention_bias"),
             )?;
-            Some(emb)
+            // The decoder self-attention is causal so its buckets are unidirectional.
+            Some(RelativePositionBias::new(
+                emb,
+                cfg.relative_attention_num_buckets,
+                cfg.relative_attention_max_distance,
+                !decoder,
+            ))
         } else {
             None
         };
@@ -351,8 +356,6 @@ impl T5Attention {
             n_heads: cfg.num_heads,
             d_kv: cfg.d_kv,
             relative_attention_bias,
-            relative_attention_num_buckets: cfg.relative_attention_num_buckets,
-            relative_attention_max_distance: cfg.relative_attention_max_distance,
             inner_dim,
             use_cache: cfg.use_cache && decoder,
             kv_cache: None,
@@ -428,51 +431,8 @@ impl T5Attention {
             None => match &self.relative_attention_bias {
                 None => (scores, None),
                 Some(relative_attention_bias) => {
-                    // This only handles the bidirectional case.
                     let kv_len = k.dim(2)?;
-                    let (q_start, q_end) = match self.use_cache {
-                        true => ((kv_len - q_len) as u32, kv_len as u32),
-                        false => (0_u32, kv_len as u32),
-                    };
-                    let num_buckets = self.relative_attention_num_buckets as u32 / 2;
-                    let max_exact = num_buckets / 2;
-                    let relative_position = (q_start..q_end)
-                        .map(|i| {
-                            (0..kv_len as u32)
-                                .map(|j| {
-                                    if i < j {
-                                        if j - i < max_exact {
-                                            j - i + num_buckets
-                                        } else {
-                                            let b = f32::log(
-                                                (j - i) as f32 / max_exact as f32,
-                                                self.relative_attention_max_distance as f32
-                                                    / max_exact as f32,
-                                            ) * (num_buckets - max_exact) as f32;
-                                            u32::min(
-                                                max_exact + num_buckets + b as u32,
-                                                self.relative_attention_num_buckets as u32 - 1,
-                                            )
-                                        }
-                                    } else if i - j < max_exact {
-                                        i - j
-                                    } else {
-                                        let b = f32::log(
-                                            (i - j) as f32 / max_exact as f32,
-                                            self.relative_attention_max_distance as f32
-                                                / max_exact as f32,
-                                        ) * (num_buckets - max_exact) as f32;
-                                        u32::min(max_exact + b as u32, num_buckets - 1)
-                                    }
-                                })
-                                .collect::<Vec<u32>>()
-                        })
-                        .collect::<Vec<Vec<_>>>();
-                    let relative_buckets = Tensor::new(relative_position, q.device())?;
-                    let position_bias = relative_attention_bias
-                        .forward(&relative_buckets)?
-                        .permute((2, 0, 1))?
-                        .unsqueeze(0)?;
+                    let position_bias = relative_attention_bias.bias(q_len, kv_len, q.device())?;
                     (scores.broadcast_add(&position_bias)?, Some(position_bias))
                     // TODO: position_bias_masked?
                 }
@@ -0,0 +1,219 @@
+//! Layer offloading, the weights of the layers that do not fit on a device staying in host memory
+//! and being uploaded to the device when the layer runs.
+//!
+//! Uploading the weights of a layer right before running it leaves the device idle during the
+//! copy. A [`Prefetcher`] instead uploads the weights of the next layers on a background thread
+//! while the current layer computes. The uploads go to a ring of `n_slots` device buffers: once a
+//! layer has run, its slot is released and receives the weights of the offloaded layer `n_slots`
+//! positions later, the offloaded layers being visited cyclically as each forward pass runs them
+//! in order. The quantized weights are copied into the buffers of the slot with
+//! [`QTensor::copy_from`](candle::quantized::QTensor::copy_from) rather than allocated again, on
+//! CUDA this copy runs on its own stream.
+//!
+//! When the layers are not run in order, e.g. after a failed forward pass, the slot received is
+//! for another layer and the weights of the requested layer are uploaded synchronously, so the
+//! results are always correct but the upload is not overlapped with the computations.
+//!
+//! ```rust
+//! use candle::quantized::{GgmlDType, QMatMul, QTensor};
+//! use candle::{Device, Tensor};
+//! use candle_transformers::offload::Prefetcher;
+//! # fn main() -> candle::Result<()> {
+//! let weight = |v: f32| -> candle::Result<QMatMul> {
+//!     let w = Tensor::full(v, (4, 32), &Device::Cpu)?;
+//!     QMatMul::from_qtensor(QTensor::quantize(&w, GgmlDType::Q8_0)?)
+//! };
+//! // Layers 2 and 3 are offloaded and their weights are uploaded to a single slot.
+//! let layers = vec![(2, vec![weight(1.)?]), (3, vec![weight(2.)?])];
+//! let prefetcher = Prefetcher::new(layers, &Device::Cpu, 1)?;
+//! assert!(prefetcher.contains(3) && !prefetcher.contains(1));
+//! let slot = prefetcher.fetch(2)?;
+//! assert_eq!(slot.layer_idx(), 2);
+//! // Releasing the slot starts the upload of layer 3.
+//! prefetcher.release(slot);
+//! assert_eq!(prefetcher.fetch(3)?.layer_idx(), 3);
+//! # Ok(())
+//! # }
+//! ```
+use candle::quantized::QMatMul;
+use candle::{Device, Result};
+use std::sync::{mpsc, Arc, Mutex};
+
+/// A copy of the weight `w`, a cpu weight, on `device`.
+pub fn copy_to_device(w: &QMatMul, device: &Device) -> Result<QMatMul> {
+    match w {
+        QMatMul::QTensor(w) => Ok(QMatMul::QTensor(Arc::new(w.to_device(device)?))),
+        QMatMul::Tensor(w) => Ok(QMatMul::Tensor(w.to_device(device)?)),
+        QMatMul::TensorF16(w) => Ok(QMatMul::TensorF16(w.to_device(device)?)),
+    }
+}
+
+/// Copies `w` to `device`, reusing the buffer of `reuse` when it has the same dtype and shape and
+/// is not used anymore.
+fn upload_weight(w: &QMatMul, reuse: Option<QMatMul>, device: &Device) -> Result<QMatMul> {
+    if let (QMatMul::QTensor(src), Some(QMatMul::QTensor(mut dst))) = (w, reuse) {
+        let reusable = dst.dtype() == src.dtype() && dst.shape() == src.shape();
+        if let Some(dst_mut) = Arc::get_mut(&mut dst).filter(|_| reusable) {
+            dst_mut.copy_from(src)?;
+            return Ok(QMatMul::QTensor(dst));
+        }
+    }
+    copy_to_device(w, device)
+}
+
+/// The weights of an offloaded layer on the device, in one of the slots of the ring.
+#[derive(Debug)]
+pub struct Slot {
+    // The position of the layer in the offloaded layers.
+    pos: usize,
+    layer_idx: usize,
+    weights: Vec<QMatMul>,
+}
+
+impl Slot {
+    /// The index of the layer in the model.
+    pub fn layer_idx(&self) -> usize {
+        self.layer_idx
+    }
+
+    /// The weights of the layer, in the order they were provided to [`Prefetcher::new`].
+    pub fn weights(&self) -> &[QMatMul] {
+        &self.weights
+    }
+}
+
+type HostLayers = Arc<Vec<(usize, Vec<QMatMul>)>>;
+
+/// Uploads the weights of the offloaded layer at position `pos`, in `slot` if possible. A slot
+/// that already holds this layer is returned as is.
+fn upload(host: &HostLayers, pos: usize, slot: Option<Slot>, device: &Device) -> Result<Slot> {
+    let reuse = match slot {
+        Some(slot) if slot.pos == pos => return Ok(slot),
+        Some(slot) => slot.weights,
+        None => vec![],
+    };
+    let (layer_idx, weights) = &host[pos];
+    let mut reuse = reuse.into_iter();
+    let weights = weights
+        .iter()
+        .map(|w| upload_weight(w, reuse.next(), device))
+        .collect::<Result<Vec<_>>>()?;
+    Ok(Slot {
+        pos,
+        layer_idx: *layer_idx,
+        weights,
+    })
+}
+
+#[derive(Debug)]
+struct State {
+    // The uploads to run, the position of a layer and the slot to reuse.
+    jobs: mpsc::Sender<(usize, Option<Slot>)>,
+    uploaded: mpsc::Receiver<Result<Slot>>,
+    // The number of uploads scheduled and not fetched yet.
+    in_flight: usize,
+}
+
+/// Uploads the weights of the offloaded layers to a device ahead of their use.
+#[derive(Debug)]
+pub struct Prefetcher {
+    host: HostLayers,
+    device: Device,
+    n_slots: usize,
+    state: Mutex<State>,
+}
+
+impl Prefetcher {
+    /// The offloaded `layers` are given as their index in the model and their cpu weights, in
+    /// the order they run. At most `n_slots` layers have their weights on `device` at a time, the
+    /// uploads of the first ones start right away.
+    pub fn new(
+        layers: Vec<(usize, Vec<QMatMul>)>,
+        device: &Device,
+        n_slots: usize,
+    ) -> Result<Self> {
+        if n_slots == 0 {
+            candle::bail!("offload: at least one slot is required")
+        }
+        let n_slots = n_slots.min(layers.len());
+        let host = Arc::new(layers);
+        let (jobs, jobs_rx) = mpsc::channel::<(usize, Option<Slot>)>();
+        let (uploaded_tx, uploaded) = mpsc::channel();
+        {
+            let host = host.clone();
+            let device = device.clone();
+            std::thread::spawn(move || {
+                // The thread stops once the prefetcher is dropped.
+                for (pos, slot) in jobs_rx {
+                    if uploaded_tx.send(upload(&host, pos, slot, &device)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        for pos in 0..n_slots {
+            if jobs.send((pos, None)).is_err() {
+                candle::bail!("offload: the prefetcher thread has stopped")
+            }
+        }
+        let state = State {
+            jobs,
+            uploaded,
+            in_flight: n_slots,
+        };
+        Ok(Self {
+            host,
+            device: device.clone(),
+            n_slots,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn position(&self, layer_idx: usize) -> Option<usize> {
+        self.host.iter().position(|(idx, _)| *idx == layer_idx)
+    }
+
+    /// Whether the layer `layer_idx` of the model is offloaded.
+    pub fn contains(&self, layer_idx: usize) -> bool {
+        self.position(layer_idx).is_some()
+    }
+
+    /// The device the weights are uploaded to.
+    pub fn device(&self) -> &Device {
+        &self.device
+    }
+
+    /// Waits for the weights of the layer `layer_idx` to be on the device. The slot has to be
+    /// released once the layer has run so that it receives the weights of a next layer.
+    pub fn fetch(&self, layer_idx: usize) -> Result<Slot> {
+        let pos = match self.position(layer_idx) {
+            Some(pos) => pos,
+            None => candle::bail!("offload: layer {layer_idx} is not offloaded"),
+        };
+        let slot = {
+            let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+            if state.in_flight == 0 {
+                // All the slots are in use, e.g. they have been dropped rather than released.
+                None
+            } else {
+                state.in_flight -= 1;
+                match state.uploaded.recv() {
+                    Ok(slot) => Some(slot?),
+                    Err(_) => candle::bail!("offload: the prefetcher thread has stopped"),
+                }
+            }
+        };
+        upload(&self.host, pos, slot, &self.device)
+    }
+
+    /// Returns a slot to the ring, its buffers receive the weights of the offloaded layer
+    /// `n_slots` positions after its layer. The weights of the slot must not be used anymore
+    /// for its buffers to be reused.
+    pub fn release(&self, slot: Slot) {
+        let pos = (slot.pos + self.n_slots) % self.host.len();
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        if state.jobs.send((pos, Some(slot))).is_ok() {
+            state.in_flight += 1
+        }
+    }
+}
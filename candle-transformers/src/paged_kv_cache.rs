@@ -0,0 +1,403 @@
+//! A paged key-value cache, as used by paged attention.
+//!
+//! The keys and values of all the sequences live in a single pool of fixed-size blocks of
+//! `block_size` positions, shared by all the layers. Each sequence has a [`BlockTable`] mapping
+//! its logical blocks to the physical blocks of the pool, so that the memory of a sequence
+//! grows one block at a time rather than being pre-allocated for the maximum sequence length,
+//! and the blocks of the finished sequences are reused by the other ones. Forked sequences
+//! share their blocks, a shared block being copied when one of the sequences writes to it.
+//! See "Efficient Memory Management for Large Language Model Serving with PagedAttention"
+//! <https://arxiv.org/abs/2309.06180>.
+//!
+//! Each position of the pool is a slot, slot `block * block_size + i` holding position `i` of
+//! `block`. A forward pass first reserves the slots of its new tokens with
+//! [`PagedKvCache::append_slots`], then each layer writes the keys and values of these tokens
+//! and reads the ones of the whole sequence, e.g. with [`PagedKvCache::append`].
+//!
+//! ```rust
+//! use candle::{DType, Device, Tensor};
+//! use candle_transformers::paged_kv_cache::{PagedKvCache, PagedKvCacheConfig};
+//! # fn main() -> candle::Result<()> {
+//! let config = PagedKvCacheConfig {
+//!     num_layers: 2,
+//!     num_blocks: 16,
+//!     block_size: 4,
+//!     num_kv_heads: 2,
+//!     head_dim: 8,
+//! };
+//! let mut cache = PagedKvCache::new(config, DType::F32, &Device::Cpu)?;
+//! cache.add_sequence(0)?;
+//! // A forward pass on 6 tokens, the keys and values have shape (1, heads, seq_len, head_dim).
+//! cache.append_slots(0, 6)?;
+//! for layer in 0..2 {
+//!     let k = Tensor::zeros((1, 2, 6, 8), DType::F32, &Device::Cpu)?;
+//!     let (k, _v) = cache.append(layer, 0, &k, &k)?;
+//!     assert_eq!(k.dims(), &[1, 2, 6, 8]);
+//! }
+//! assert_eq!(cache.block_table(0).unwrap().blocks().len(), 2);
+//! cache.remove_sequence(0)?;
+//! assert_eq!(cache.num_free_blocks(), 16);
+//! # Ok(())
+//! # }
+//! ```
+use candle::{DType, Device, Result, Tensor};
+use std::collections::HashMap;
+
+/// Tracks the free blocks of the pool and the number of sequences using each block.
+#[derive(Debug, Clone)]
+pub struct BlockAllocator {
+    free: Vec<usize>,
+    ref_counts: Vec<usize>,
+}
+
+impl BlockAllocator {
+    pub fn new(num_blocks: usize) -> Self {
+        Self {
+            // The blocks are allocated in increasing order.
+            free: (0..num_blocks).rev().collect(),
+            ref_counts: vec![0; num_blocks],
+        }
+    }
+
+    pub fn num_blocks(&self) -> usize {
+        self.ref_counts.len()
+    }
+
+    pub fn num_free(&self) -> usize {
+        self.free.len()
+    }
+
+    /// Returns a free block with a reference count of 1, or `None` if all the blocks are used.
+    pub fn allocate(&mut self) -> Option<usize> {
+        let block = self.free.pop()?;
+        self.ref_counts[block] = 1;
+        Some(block)
+    }
+
+    /// Adds a reference to an allocated block.
+    pub fn share(&mut self, block: usize) -> Result<()> {
+        match self.ref_counts.get_mut(block) {
+            Some(count) if *count > 0 => *count += 1,
+            _ => candle::bail!("paged kv-cache: cannot share unallocated block {block}"),
+        }
+        Ok(())
+    }
+
+    /// Removes a reference to a block, the block is freed when it has no references left.
+    pub fn release(&mut self, block: usize) -> Result<()> {
+        match self.ref_counts.get_mut(block) {
+            Some(count) if *count > 0 => {
+                *count -= 1;
+                if *count == 0 {
+                    self.free.push(block)
+                }
+            }
+            _ => candle::bail!("paged kv-cache: cannot release unallocated block {block}"),
+        }
+        Ok(())
+    }
+
+    pub fn ref_count(&self, block: usize) -> usize {
+        self.ref_counts.get(block).copied().unwrap_or(0)
+    }
+}
+
+/// The physical blocks used by a sequence, position `p` being stored at position
+/// `p % block_size` of block `blocks[p / block_size]`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockTable {
+    blocks: Vec<usize>,
+    len: usize,
+}
+
+impl BlockTable {
+    pub fn blocks(&self) -> &[usize] {
+        &self.blocks
+    }
+
+    /// The number of positions of the sequence, including the reserved ones.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PagedKvCacheConfig {
+    pub num_layers: usize,
+    /// The number of blocks in the pool, the pool holds `num_blocks * block_size` positions.
+    pub num_blocks: usize,
+    /// The number of positions per block.
+    pub block_size: usize,
+    pub num_kv_heads: usize,
+    pub head_dim: usize,
+}
+
+// This is not `Clone` as the clones would share the storage of the pool.
+#[derive(Debug)]
+pub struct PagedKvCache {
+    config: PagedKvCacheConfig,
+    allocator: BlockAllocator,
+    /// The keys and values of each layer, with shape `(num_blocks * block_size, num_kv_heads,
+    /// head_dim)`. These are modified in place.
+    layers: Vec<(Tensor, Tensor)>,
+    sequences: HashMap<usize, BlockTable>,
+}
+
+impl PagedKvCache {
+    pub fn new(config: PagedKvCacheConfig, dtype: DType, device: &Device) -> Result<Self> {
+        if config.block_size == 0 {
+            candle::bail!("paged kv-cache: block_size has to be positive")
+        }
+        let shape = (
+            config.num_blocks * config.block_size,
+            config.num_kv_heads,
+            config.head_dim,
+        );
+        let layers = (0..config.num_layers)
+            .map(|_| {
+                let k = Tensor::zeros(shape, dtype, device)?;
+                let v = Tensor::zeros(shape, dtype, device)?;
+                Ok((k, v))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Self {
+            config,
+            allocator: BlockAllocator::new(config.num_blocks),
+            layers,
+            sequences: HashMap::new(),
+        })
+    }
+
+    pub fn config(&self) -> &PagedKvCacheConfig {
+        &self.config
+    }
+
+    pub fn num_free_blocks(&self) -> usize {
+        self.allocator.num_free()
+    }
+
+    pub fn block_table(&self, seq_id: usize) -> Option<&BlockTable> {
+        self.sequences.get(&seq_id)
+    }
+
+    fn table(&self, seq_id: usize) -> Result<&BlockTable> {
+        match self.sequences.get(&seq_id) {
+            Some(table) => Ok(table),
+            None => candle::bail!("paged kv-cache: unknown sequence {seq_id}"),
+        }
+    }
+
+    /// The keys and values of a layer, see [`Self::gather`] to read them.
+    pub fn layer(&self, layer: usize) -> Result<&(Tensor, Tensor)> {
+        match self.layers.get(layer) {
+            Some(kv) => Ok(kv),
+            None => candle::bail!("paged kv-cache: unknown layer {layer}"),
+        }
+    }
+
+    /// Registers a new empty sequence, no blocks are allocated until slots are appended.
+    pub fn add_sequence(&mut self, seq_id: usize) -> Result<()> {
+        if self.sequences.contains_key(&seq_id) {
+            candle::bail!("paged kv-cache: sequence {seq_id} already exists")
+        }
+        self.sequences.insert(seq_id, BlockTable::default());
+        Ok(())
+    }
+
+    /// Creates `child` as a copy of `parent`, e.g. to sample several continuations of a prompt.
+    /// The blocks are shared and only copied when written to.
+    pub fn fork_sequence(&mut self, parent: usize, child: usize) -> Result<()> {
+        if self.sequences.contains_key(&child) {
+            candle::bail!("paged kv-cache: sequence {child} already exists")
+        }
+        let table = self.table(parent)?.clone();
+        for &block in table.blocks.iter() {
+            self.allocator.share(block)?
+        }
+        self.sequences.insert(child, table);
+        Ok(())
+    }
+
+    /// Removes a sequence and frees the blocks that are not used by other sequences.
+    pub fn remove_sequence(&mut self, seq_id: usize) -> Result<()> {
+        let table = match self.sequences.remove(&seq_id) {
+            Some(table) => table,
+            None => candle::bail!("paged kv-cache: unknown sequence {seq_id}"),
+        };
+        for block in table.blocks {
+            self.allocator.release(block)?
+        }
+        Ok(())
+    }
+
+    /// Drops the positions after `len` from a sequence, e.g. the rejected tokens of speculative
+    /// decoding, and frees the blocks that are not needed anymore.
+    pub fn truncate(&mut self, seq_id: usize, len: usize) -> Result<()> {
+        let block_size = self.config.block_size;
+        let table = match self.sequences.get_mut(&seq_id) {
+            Some(table) => table,
+            None => candle::bail!("paged kv-cache: unknown sequence {seq_id}"),
+        };
+        if len >= table.len {
+            return Ok(());
+        }
+        table.len = len;
+        let dropped = table.blocks.split_off(len.div_ceil(block_size));
+        for block in dropped {
+            self.allocator.release(block)?
+        }
+        Ok(())
+    }
+
+    /// The last block of a sequence when it is partially filled and shared with other
+    /// sequences, this block has to be copied before appending to the sequence.
+    fn shared_last_block(&self, table: &BlockTable) -> Option<usize> {
+        if table.len % self.config.block_size == 0 {
+            return None;
+        }
+        let block = *table.blocks.last()?;
+        (self.allocator.ref_count(block) > 1).then_some(block)
+    }
+
+    /// The number of blocks to allocate to append `num_tokens` positions to a sequence.
+    pub fn num_new_blocks(&self, seq_id: usize, num_tokens: usize) -> Result<usize> {
+        let table = self.table(seq_id)?;
+        if num_tokens == 0 {
+            return Ok(0);
+        }
+        let num_blocks = (table.len + num_tokens).div_ceil(self.config.block_size);
+        let copy = self.shared_last_block(table).is_some() as usize;
+        Ok(num_blocks - table.blocks.len() + copy)
+    }
+
+    /// Whether there are enough free blocks to append `num_tokens` positions to a sequence.
+    pub fn can_append(&self, seq_id: usize, num_tokens: usize) -> Result<bool> {
+        Ok(self.num_new_blocks(seq_id, num_tokens)? <= self.allocator.num_free())
+    }
+
+    /// Reserves `num_tokens` new positions at the end of a sequence, allocating blocks as
+    /// needed, and returns their slots. This fails without modifying the cache if there are not
+    /// enough free blocks.
+    pub fn append_slots(&mut self, seq_id: usize, num_tokens: usize) -> Result<Vec<u32>> {
+        let num_new_blocks = self.num_new_blocks(seq_id, num_tokens)?;
+        if num_new_blocks > self.allocator.num_free() {
+            candle::bail!(
+                "paged kv-cache: out of blocks, {num_new_blocks} needed, {} free",
+                self.allocator.num_free()
+            )
+        }
+        let block_size = self.config.block_size;
+        let mut table = self.table(seq_id)?.clone();
+        if let Some(shared) = self.shared_last_block(&table) {
+            let block = self.allocate()?;
+            let len = table.len % block_size;
+            for (k, v) in self.layers.iter() {
+                // The source is copied first as it lives in the same storage as the target.
+                let src = k.narrow(0, shared * block_size, len)?.copy()?;
+                k.slice_set(&src, 0, block * block_size)?;
+                let src = v.narrow(0, shared * block_size, len)?.copy()?;
+                v.slice_set(&src, 0, block * block_size)?;
+            }
+            self.allocator.release(shared)?;
+            *table.blocks.last_mut().unwrap() = block;
+        }
+        while table.blocks.len() * block_size < table.len + num_tokens {
+            table.blocks.push(self.allocate()?)
+        }
+        let slots = (table.len..table.len + num_tokens)
+            .map(|pos| (table.blocks[pos / block_size] * block_size + pos % block_size) as u32)
+            .collect();
+        table.len += num_tokens;
+        self.sequences.insert(seq_id, table);
+        Ok(slots)
+    }
+
+    fn allocate(&mut self) -> Result<usize> {
+        match self.allocator.allocate() {
+            Some(block) => Ok(block),
+            None => candle::bail!("paged kv-cache: out of blocks"),
+        }
+    }
+
+    /// The slots of all the positions of a sequence.
+    pub fn slots(&self, seq_id: usize) -> Result<Vec<u32>> {
+        let table = self.table(seq_id)?;
+        let block_size = self.config.block_size;
+        let slots = (0..table.len)
+            .map(|pos| (table.blocks[pos / block_size] * block_size + pos % block_size) as u32)
+            .collect();
+        Ok(slots)
+    }
+
+    /// Writes the keys and values of some tokens to their slots, `k` and `v` having shape
+    /// `(num_tokens, num_kv_heads, head_dim)`. The tokens can belong to different sequences.
+    pub fn write(&self, layer: usize, slots: &[u32], k: &Tensor, v: &Tensor) -> Result<()> {
+        let (k_cache, v_cache) = self.layer(layer)?;
+        if k.dim(0)? != slots.len() || v.dim(0)? != slots.len() {
+            candle::bail!(
+                "paged kv-cache: got {} slots for keys {:?} and values {:?}",
+                slots.len(),
+                k.shape(),
+                v.shape()
+            )
+        }
+        let k = k.contiguous()?;
+        let v = v.contiguous()?;
+        // The consecutive slots are written with a single copy, this is usually one copy per
+        // block.
+        let mut start = 0;
+        while start < slots.len() {
+            let mut end = start + 1;
+            while end < slots.len() && slots[end] == slots[end - 1] + 1 {
+                end += 1
+            }
+            let slot = slots[start] as usize;
+            k_cache.slice_set(&k.narrow(0, start, end - start)?, 0, slot)?;
+            v_cache.slice_set(&v.narrow(0, start, end - start)?, 0, slot)?;
+            start = end
+        }
+        Ok(())
+    }
+
+    /// Reads the keys and values of some slots, `slots` being a u32 tensor. The returned tensors
+    /// have shape `(num_slots, num_kv_heads, head_dim)`.
+    pub fn gather(&self, layer: usize, slots: &Tensor) -> Result<(Tensor, Tensor)> {
+        let (k_cache, v_cache) = self.layer(layer)?;
+        let k = k_cache.index_select(slots, 0)?;
+        let v = v_cache.index_select(slots, 0)?;
+        Ok((k, v))
+    }
+
+    /// Writes the keys and values of the last positions of a sequence and returns the keys and
+    /// values of the whole sequence, similar to [`candle_nn::kv_cache::KvCache::append`]. The
+    /// slots of the new positions must have been reserved with [`Self::append_slots`]. `k` and
+    /// `v` have shape `(1, num_kv_heads, seq_len, head_dim)`, and so do the returned tensors.
+    pub fn append(
+        &self,
+        layer: usize,
+        seq_id: usize,
+        k: &Tensor,
+        v: &Tensor,
+    ) -> Result<(Tensor, Tensor)> {
+        let seq_len = k.dim(2)?;
+        let slots = self.slots(seq_id)?;
+        if seq_len > slots.len() {
+            candle::bail!(
+                "paged kv-cache: {seq_len} tokens for sequence {seq_id} with {} reserved slots",
+                slots.len()
+            )
+        }
+        let k = k.squeeze(0)?.transpose(0, 1)?;
+        let v = v.squeeze(0)?.transpose(0, 1)?;
+        self.write(layer, &slots[slots.len() - seq_len..], &k, &v)?;
+        let slots = Tensor::new(slots, k.device())?;
+        let (k, v) = self.gather(layer, &slots)?;
+        let k = k.transpose(0, 1)?.unsqueeze(0)?.contiguous()?;
+        let v = v.transpose(0, 1)?.unsqueeze(0)?.contiguous()?;
+        Ok((k, v))
+    }
+}
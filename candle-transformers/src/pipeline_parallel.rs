@@ -0,0 +1,166 @@
+//! Pipeline parallelism, the layers of a model being split across multiple devices.
+//!
+//! A [`DeviceMap`] assigns each layer to a device, e.g. the first layers of a model too large for
+//! a GPU on this GPU and the remaining ones on the CPU, or half of the layers on each of two
+//! CUDA cards. The consecutive layers on the same device form a stage and the hidden states are
+//! moved to the next device between the stages.
+//!
+//! Running the stages one after the other leaves all the devices but one idle. When processing a
+//! prompt, [`run_stages`] splits it in micro batches of consecutive tokens and runs each stage on
+//! its own thread: while a stage processes a micro batch the previous stage already processes the
+//! next one. As with a prompt processed in chunks, the tokens of a micro batch attend to the
+//! previous ones through the kv cache of each layer, which stays on the device of its layer.
+//!
+//! ```rust
+//! use candle::Device;
+//! use candle_transformers::pipeline_parallel::DeviceMap;
+//! # fn main() -> candle::Result<()> {
+//! let map = DeviceMap::parse("0-19:cpu,20-31:cpu", &Device::Cpu)?;
+//! // Both ranges are on the same device so there is a single stage.
+//! assert_eq!(map.stages(32).len(), 1);
+//! assert!(DeviceMap::parse("0-19:tpu", &Device::Cpu).is_err());
+//! # Ok(())
+//! # }
+//! ```
+use candle::{Device, Result};
+use std::ops::Range;
+
+/// Parses a device name, `cpu`, `cuda:{ordinal}` or `metal:{ordinal}`, the ordinal defaulting to
+/// 0.
+pub fn parse_device(name: &str) -> Result<Device> {
+    let (kind, ordinal) = match name.split_once(':') {
+        Some((kind, ordinal)) => match ordinal.parse::<usize>() {
+            Ok(ordinal) => (kind, ordinal),
+            Err(_) => candle::bail!("device map: invalid device ordinal in {name}"),
+        },
+        None => (name, 0),
+    };
+    match kind {
+        "cpu" => Ok(Device::Cpu),
+        "cuda" => Device::new_cuda(ordinal),
+        "metal" => Device::new_metal(ordinal),
+        _ => candle::bail!("device map: unknown device {name}, expected cpu, cuda or metal"),
+    }
+}
+
+/// The device of each layer of a model, the layers that are not mapped explicitly are on the
+/// default device.
+#[derive(Debug, Clone)]
+pub struct DeviceMap {
+    default: Device,
+    layers: Vec<(Range<usize>, Device)>,
+}
+
+impl DeviceMap {
+    /// All the layers on `default`.
+    pub fn new(default: &Device) -> Self {
+        Self {
+            default: default.clone(),
+            layers: vec![],
+        }
+    }
+
+    /// Places the `layers` on `device`, overriding the previous mappings of these layers.
+    pub fn with_layers(mut self, layers: Range<usize>, device: &Device) -> Self {
+        self.layers.push((layers, device.clone()));
+        self
+    }
+
+    /// Parses a comma separated list of inclusive layer ranges with their device, e.g.
+    /// `0-19:cuda:0,20-31:cpu` or `0-15:cuda:0,16-31:cuda:1`, a single layer being written as
+    /// `7:cpu`.
+    pub fn parse(spec: &str, default: &Device) -> Result<Self> {
+        let mut map = Self::new(default);
+        for entry in spec.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (layers, device) = match entry.split_once(':') {
+                Some(v) => v,
+                None => candle::bail!("device map: expected layers:device, got {entry}"),
+            };
+            let parse = |v: &str| match v.trim().parse::<usize>() {
+                Ok(v) => Ok(v),
+                Err(_) => candle::bail!("device map: invalid layer {v} in {entry}"),
+            };
+            let (start, end) = match layers.split_once('-') {
+                Some((start, end)) => (parse(start)?, parse(end)?),
+                None => (parse(layers)?, parse(layers)?),
+            };
+            if end < start {
+                candle::bail!("device map: empty layer range in {entry}")
+            }
+            map = map.with_layers(start..end + 1, &parse_device(device.trim())?)
+        }
+        Ok(map)
+    }
+
+    /// The device used for the layers that are not mapped explicitly.
+    pub fn default_device(&self) -> &Device {
+        &self.default
+    }
+
+    /// The device of layer `layer_idx`.
+    pub fn device(&self, layer_idx: usize) -> &Device {
+        self.layers
+            .iter()
+            .rev()
+            .find(|(layers, _)| layers.contains(&layer_idx))
+            .map_or(&self.default, |(_, device)| device)
+    }
+
+    /// The stages of a model with `n_layers` layers, i.e. the ranges of consecutive layers on the
+    /// same device.
+    pub fn stages(&self, n_layers: usize) -> Vec<(Range<usize>, Device)> {
+        let mut stages: Vec<(Range<usize>, Device)> = vec![];
+        for layer_idx in 0..n_layers {
+            let device = self.device(layer_idx);
+            match stages.last_mut() {
+                Some((layers, d)) if d.same_device(device) => layers.end = layer_idx + 1,
+                _ => stages.push((layer_idx..layer_idx + 1, device.clone())),
+            }
+        }
+        stages
+    }
+}
+
+/// Runs each of the `inputs` through all the `stages` in order, each stage on its own thread so
+/// that the stages process different micro batches concurrently. A stage is called with the index
+/// of the micro batch and the output of the previous stage, the micro batches being processed in
+/// order by every stage. The outputs of the last stage are returned in the order of the inputs.
+///
+/// The first error stops the pipeline and is returned.
+pub fn run_stages<T, F>(stages: Vec<F>, inputs: Vec<T>) -> Result<Vec<T>>
+where
+    T: Send,
+    F: FnMut(usize, T) -> Result<T> + Send,
+{
+    use std::sync::mpsc;
+    let n_inputs = inputs.len();
+    std::thread::scope(|scope| {
+        let (input_tx, mut rx) = mpsc::channel::<Result<(usize, T)>>();
+        for mut stage in stages {
+            let (tx, next_rx) = mpsc::channel();
+            let stage_rx = std::mem::replace(&mut rx, next_rx);
+            scope.spawn(move || {
+                for msg in stage_rx {
+                    let msg = msg.and_then(|(idx, xs)| Ok((idx, stage(idx, xs)?)));
+                    let failed = msg.is_err();
+                    // The receiver is only dropped when a later stage has failed.
+                    if tx.send(msg).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+        }
+        for input in inputs.into_iter().enumerate() {
+            if input_tx.send(Ok(input)).is_err() {
+                break;
+            }
+        }
+        drop(input_tx);
+        let mut outputs = Vec::with_capacity(n_inputs);
+        for msg in rx {
+            let (_idx, xs) = msg?;
+            outputs.push(xs)
+        }
+        Ok(outputs)
+    })
+}
@@ -0,0 +1,91 @@
+//! Saving the kv cache of a prompt to disk, similar to the `--prompt-cache` option of llama.cpp.
+//!
+//! Processing a long system prompt can take longer than generating the answer, the kv cache
+//! computed for the prompt can be saved once and restored by the later runs so that only the
+//! tokens after the cached prefix have to be processed. The cache is stored as a safetensors file
+//! holding the keys and values of each layer, `layers.{i}.k` and `layers.{i}.v` with shape
+//! `(batch, kv_heads, seq_len, head_dim)`, and the `tokens` they have been computed for.
+//!
+//! The caches of the models are saved and restored with
+//! [`quantized_llama::KvCache::save`](crate::models::quantized_llama::KvCache::save) and
+//! [`llama::Cache::save_kv`](crate::models::llama::Cache::save_kv), the files are only valid for
+//! the model and the dtypes used to compute them.
+use candle::{DType, Device, Result, Tensor};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// The keys and values of each layer, `None` for the layers that have not been computed.
+pub type KvCache = Vec<Option<(Tensor, Tensor)>>;
+
+/// Saves the keys and values of each layer, computed for `tokens`. All the layers must have
+/// been computed, for the same number of positions as there are tokens.
+pub fn save<P: AsRef<Path>>(
+    path: P,
+    layers: &[Option<(Tensor, Tensor)>],
+    tokens: &[u32],
+) -> Result<()> {
+    let path = path.as_ref();
+    if layers.is_empty() {
+        candle::bail!("prompt cache: there are no layers to save")
+    }
+    let mut tensors = HashMap::new();
+    for (i, kv) in layers.iter().enumerate() {
+        let (k, v) = match kv {
+            Some(kv) => kv,
+            None => candle::bail!("prompt cache: layer {i} has not been computed"),
+        };
+        let seq_len = k.dim(2)?;
+        if seq_len != tokens.len() || v.dim(2)? != tokens.len() {
+            candle::bail!(
+                "prompt cache: layer {i} has {seq_len} positions for {} tokens",
+                tokens.len()
+            )
+        }
+        tensors.insert(format!("layers.{i}.k"), k.clone());
+        tensors.insert(format!("layers.{i}.v"), v.clone());
+    }
+    tensors.insert("tokens".to_string(), Tensor::new(tokens, &Device::Cpu)?);
+    candle::safetensors::save(&tensors, path).map_err(|e| e.with_path(path))
+}
+
+/// Loads the keys and values of each layer saved with [`save`] on `device`, together with the
+/// tokens they have been computed for.
+pub fn load<P: AsRef<Path>>(path: P, device: &Device) -> Result<(KvCache, Vec<u32>)> {
+    let path = path.as_ref();
+    let mut tensors = candle::safetensors::load(path, device).map_err(|e| e.with_path(path))?;
+    let tokens = match tensors.remove("tokens") {
+        Some(tokens) => tokens.to_dtype(DType::U32)?.to_vec1::<u32>()?,
+        None => candle::bail!("prompt cache: no tokens in {path:?}"),
+    };
+    let mut layers = vec![];
+    while let Some(k) = tensors.remove(&format!("layers.{}.k", layers.len())) {
+        let v = match tensors.remove(&format!("layers.{}.v", layers.len())) {
+            Some(v) => v,
+            None => candle::bail!("prompt cache: no values for layer {}", layers.len()),
+        };
+        if k.dim(2)? != tokens.len() || v.dim(2)? != tokens.len() {
+            candle::bail!(
+                "prompt cache: layer {} does not match the {} tokens",
+                layers.len(),
+                tokens.len()
+            )
+        }
+        layers.push(Some((k, v)))
+    }
+    if layers.is_empty() || !tensors.is_empty() {
+        candle::bail!("prompt cache: unexpected tensors in {path:?}")
+    }
+    Ok((layers, tokens))
+}
+
+/// The number of cached positions that can be reused for `prompt`, the length of the common
+/// prefix of the cached tokens and of the prompt. The last token of the prompt is never reused so
+/// that the model has at least one token to process, and to return the logits for.
+pub fn reusable_len(cached: &[u32], prompt: &[u32]) -> usize {
+    let common = cached
+        .iter()
+        .zip(prompt.iter())
+        .take_while(|(c, p)| c == p)
+        .count();
+    common.min(prompt.len().saturating_sub(1))
+}
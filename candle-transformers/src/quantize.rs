@@ -0,0 +1,272 @@
+//! Conversion of safetensors checkpoints to quantized gguf files.
+//!
+//! A [`Quantizer`] picks the GGML dtype of each tensor, as done by the llama.cpp `quantize` tool:
+//! the 2d weights use the requested dtype, the output projection uses `Q6K` by default, and the
+//! other tensors, e.g. the normalization weights, stay in `f32`. When an importance matrix is
+//! provided, see [`imatrix_file`](candle::quantized::imatrix_file), the weights are quantized so
+//! as to minimize the error on the channels with the largest activations.
+//!
+//! [`convert_llama`] converts the weights of a llama model from the Hugging Face layout, together
+//! with its `config.json`, to a gguf file that can be loaded with
+//! [`quantized_llama`](crate::models::quantized_llama).
+//!
+//! ```rust
+//! use candle::quantized::GgmlDType;
+//! use candle_transformers::quantize::{llama_tensor_name, Quantizer};
+//! let quantizer = Quantizer::new(GgmlDType::Q4K);
+//! let name = llama_tensor_name("model.layers.3.self_attn.q_proj.weight").unwrap();
+//! assert_eq!(name, "blk.3.attn_q.weight");
+//! assert_eq!(quantizer.dtype_for(&name, &[4096, 4096]), GgmlDType::Q4K);
+//! assert_eq!(quantizer.dtype_for("output.weight", &[32000, 4096]), GgmlDType::Q6K);
+//! assert_eq!(quantizer.dtype_for("blk.3.attn_norm.weight", &[4096]), GgmlDType::F32);
+//! ```
+use crate::models::llama::{Llama3RopeType, LlamaConfig, LlamaEosToks};
+use candle::quantized::gguf_file::{self, Value};
+use candle::quantized::{GgmlDType, QTensor};
+use candle::{Device, Result, Tensor};
+use candle_nn::rotary_emb::{RopeScaling, RotaryConfig};
+use rayon::prelude::*;
+use std::collections::HashMap;
+
+/// Selects the dtype of each tensor and quantizes it.
+#[derive(Debug, Clone)]
+pub struct Quantizer {
+    dtype: GgmlDType,
+    output_dtype: GgmlDType,
+    imatrix: HashMap<String, Vec<f32>>,
+}
+
+impl Quantizer {
+    pub fn new(dtype: GgmlDType) -> Self {
+        Self {
+            dtype,
+            output_dtype: GgmlDType::Q6K,
+            imatrix: HashMap::new(),
+        }
+    }
+
+    /// The dtype of the output projection, `output.weight`, `Q6K` by default.
+    pub fn with_output_dtype(mut self, dtype: GgmlDType) -> Self {
+        self.output_dtype = dtype;
+        self
+    }
+
+    /// The importance of the input channels of the weights, indexed by gguf tensor name, as
+    /// returned by [`imatrix_file::load`](candle::quantized::imatrix_file::load).
+    pub fn with_imatrix(mut self, imatrix: HashMap<String, Vec<f32>>) -> Self {
+        self.imatrix = imatrix;
+        self
+    }
+
+    /// The dtype used for the tensor `name` with shape `dims`. The weights whose rows cannot be
+    /// split in blocks of the requested dtype fall back to `Q8_0`, or `F16` if the rows are not
+    /// a multiple of 32 either.
+    pub fn dtype_for(&self, name: &str, dims: &[usize]) -> GgmlDType {
+        if dims.len() != 2 || !name.ends_with(".weight") {
+            return GgmlDType::F32;
+        }
+        let dtype = if name == "output.weight" {
+            self.output_dtype
+        } else {
+            self.dtype
+        };
+        let row = dims[1];
+        if row % dtype.block_size() == 0 {
+            dtype
+        } else if row % GgmlDType::Q8_0.block_size() == 0 {
+            GgmlDType::Q8_0
+        } else {
+            GgmlDType::F16
+        }
+    }
+
+    /// Quantizes the tensor `name`, using its importance matrix if available.
+    pub fn quantize(&self, name: &str, tensor: &Tensor) -> Result<QTensor> {
+        let dtype = self.dtype_for(name, tensor.dims());
+        match self.imatrix.get(name) {
+            Some(imatrix) => QTensor::quantize_imatrix(tensor, imatrix, dtype),
+            None => QTensor::quantize(tensor, dtype),
+        }
+    }
+}
+
+/// The metadata and quantized tensors of a converted model.
+#[derive(Debug)]
+pub struct Converted {
+    pub metadata: Vec<(String, Value)>,
+    pub tensors: Vec<(String, QTensor)>,
+}
+
+impl Converted {
+    /// Writes the converted model as a gguf file.
+    pub fn write<W: std::io::Seek + std::io::Write>(&self, w: &mut W) -> Result<()> {
+        let metadata = self
+            .metadata
+            .iter()
+            .map(|(k, v)| (k.as_str(), v))
+            .collect::<Vec<_>>();
+        let tensors = self
+            .tensors
+            .iter()
+            .map(|(k, v)| (k.as_str(), v))
+            .collect::<Vec<_>>();
+        gguf_file::write(w, &metadata, &tensors)
+    }
+}
+
+/// The gguf name of a tensor of a Hugging Face llama checkpoint, `None` for the tensors that are
+/// not stored in gguf files such as the rotary embedding frequencies.
+pub fn llama_tensor_name(name: &str) -> Option<String> {
+    let name = match name {
+        "model.embed_tokens.weight" => "token_embd.weight".to_string(),
+        "model.norm.weight" => "output_norm.weight".to_string(),
+        "lm_head.weight" => "output.weight".to_string(),
+        _ => {
+            let name = name.strip_prefix("model.layers.")?;
+            let (layer_idx, name) = name.split_once('.')?;
+            let layer_idx = layer_idx.parse::<usize>().ok()?;
+            let name = match name {
+                "input_layernorm.weight" => "attn_norm.weight",
+                "post_attention_layernorm.weight" => "ffn_norm.weight",
+                "self_attn.q_proj.weight" => "attn_q.weight",
+                "self_attn.k_proj.weight" => "attn_k.weight",
+                "self_attn.v_proj.weight" => "attn_v.weight",
+                "self_attn.o_proj.weight" => "attn_output.weight",
+                "mlp.gate_proj.weight" => "ffn_gate.weight",
+                "mlp.up_proj.weight" => "ffn_up.weight",
+                "mlp.down_proj.weight" => "ffn_down.weight",
+                _ => return None,
+            };
+            format!("blk.{layer_idx}.{name}")
+        }
+    };
+    Some(name)
+}
+
+/// The gguf metadata describing a llama model.
+pub fn llama_metadata(cfg: &LlamaConfig) -> Vec<(String, Value)> {
+    let head_dim = cfg
+        .head_dim
+        .unwrap_or(cfg.hidden_size / cfg.num_attention_heads);
+    let u32 = |v: usize| Value::U32(v as u32);
+    let mut metadata = vec![
+        ("general.architecture", Value::String("llama".to_string())),
+        ("general.quantization_version", Value::U32(2)),
+        ("llama.vocab_size", u32(cfg.vocab_size)),
+        ("llama.context_length", u32(cfg.max_position_embeddings)),
+        ("llama.embedding_length", u32(cfg.hidden_size)),
+        ("llama.feed_forward_length", u32(cfg.intermediate_size)),
+        ("llama.block_count", u32(cfg.num_hidden_layers)),
+        ("llama.attention.head_count", u32(cfg.num_attention_heads)),
+        (
+            "llama.attention.head_count_kv",
+            u32(cfg.num_key_value_heads()),
+        ),
+        (
+            "llama.attention.layer_norm_rms_epsilon",
+            Value::F32(cfg.rms_norm_eps as f32),
+        ),
+        ("llama.rope.dimension_count", u32(head_dim)),
+        ("llama.rope.freq_base", Value::F32(cfg.rope_theta)),
+    ];
+    if let Some(bos_token_id) = cfg.bos_token_id {
+        metadata.push(("tokenizer.ggml.bos_token_id", Value::U32(bos_token_id)))
+    }
+    let eos_token_id = match &cfg.eos_token_id {
+        Some(LlamaEosToks::Single(id)) => Some(*id),
+        Some(LlamaEosToks::Multiple(ids)) => ids.first().copied(),
+        None => None,
+    };
+    if let Some(eos_token_id) = eos_token_id {
+        metadata.push(("tokenizer.ggml.eos_token_id", Value::U32(eos_token_id)))
+    }
+    metadata
+        .into_iter()
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+}
+
+/// The per frequency factors of the llama 3.1 rope scaling, stored as the `rope_freqs.weight`
+/// tensor in gguf files.
+fn llama_rope_freqs(cfg: &LlamaConfig) -> Result<Option<Vec<f32>>> {
+    let scaling = match &cfg.rope_scaling {
+        Some(scaling) if matches!(scaling.rope_type, Llama3RopeType::Llama3) => scaling,
+        _ => return Ok(None),
+    };
+    let head_dim = cfg
+        .head_dim
+        .unwrap_or(cfg.hidden_size / cfg.num_attention_heads);
+    let rotary = RotaryConfig::new(head_dim, cfg.rope_theta);
+    let inv_freq = rotary.inv_freq()?;
+    let scaled = rotary
+        .with_scaling(RopeScaling::Llama3 {
+            factor: scaling.factor,
+            low_freq_factor: scaling.low_freq_factor,
+            high_freq_factor: scaling.high_freq_factor,
+            original_max_position_embeddings: scaling.original_max_position_embeddings,
+        })
+        .inv_freq()?;
+    let factors = inv_freq.iter().zip(scaled.iter()).map(|(f, s)| f / s);
+    Ok(Some(factors.collect()))
+}
+
+/// The Hugging Face checkpoints use the rotary embeddings on the two halves of each head while
+/// gguf files rotate interleaved pairs, so the rows of the query and key projections are
+/// permuted accordingly.
+fn permute_qk(w: &Tensor, n_head: usize) -> Result<Tensor> {
+    let (out_dim, in_dim) = w.dims2()?;
+    if out_dim % (2 * n_head) != 0 {
+        candle::bail!("quantize: cannot split {out_dim} rows in {n_head} heads")
+    }
+    w.reshape((n_head, 2, out_dim / n_head / 2, in_dim))?
+        .transpose(1, 2)?
+        .reshape((out_dim, in_dim))
+}
+
+/// Converts the tensors `names` of a Hugging Face llama checkpoint, loaded with `load`, to gguf.
+/// The tensors are quantized in parallel. When the output projection is tied to the embeddings,
+/// the embeddings are also used as the output projection.
+pub fn convert_llama<F>(
+    cfg: &LlamaConfig,
+    names: &[String],
+    load: F,
+    quantizer: &Quantizer,
+) -> Result<Converted>
+where
+    F: Fn(&str) -> Result<Tensor> + Sync,
+{
+    let mut jobs = vec![];
+    for name in names.iter() {
+        match llama_tensor_name(name) {
+            Some(gguf_name) => jobs.push((name.as_str(), gguf_name)),
+            None if name.ends_with("rotary_emb.inv_freq") => {}
+            None => candle::bail!("quantize: unexpected tensor {name}"),
+        }
+    }
+    if !names.iter().any(|name| name == "lm_head.weight") {
+        jobs.push(("model.embed_tokens.weight", "output.weight".to_string()))
+    }
+    let mut tensors = jobs
+        .par_iter()
+        .map(|(name, gguf_name)| {
+            let tensor = load(name)?;
+            let tensor = if gguf_name.ends_with(".attn_q.weight") {
+                permute_qk(&tensor, cfg.num_attention_heads)?
+            } else if gguf_name.ends_with(".attn_k.weight") {
+                permute_qk(&tensor, cfg.num_key_value_heads())?
+            } else {
+                tensor
+            };
+            Ok((gguf_name.clone(), quantizer.quantize(gguf_name, &tensor)?))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(factors) = llama_rope_freqs(cfg)? {
+        let factors = Tensor::new(factors, &Device::Cpu)?;
+        let factors = QTensor::quantize(&factors, GgmlDType::F32)?;
+        tensors.push(("rope_freqs.weight".to_string(), factors))
+    }
+    Ok(Converted {
+        metadata: llama_metadata(cfg),
+        tensors,
+    })
+}
@@ -1,7 +1,7 @@
 use crate::models::with_tracing::QMatMul;
 use crate::quantized_var_builder::VarBuilder;
 use candle::quantized::QTensor;
-use candle::{Module, Result, Tensor};
+use candle::{Device, Module, Result, Tensor};
 
 #[derive(Debug, Clone)]
 pub struct Embedding {
@@ -110,6 +110,15 @@ impl RmsNorm {
         let weight = weight.dequantize(&weight.device())?;
         Ok(Self { weight, eps, span })
     }
+
+    /// A copy of this layer with its weight on `device`.
+    pub fn to_device(&self, device: &Device) -> Result<Self> {
+        Ok(Self {
+            weight: self.weight.to_device(device)?,
+            eps: self.eps,
+            span: self.span.clone(),
+        })
+    }
 }
 
 impl Module for RmsNorm {
@@ -0,0 +1,607 @@
+//! Vector indexes for semantic search over modest corpora.
+//!
+//! [`FlatIndex`] scores the queries against all the stored vectors with a matrix multiplication
+//! and returns exact results. [`HnswIndex`] builds a hierarchical navigable small world graph,
+//! see [Malkov and Yashunin](https://arxiv.org/abs/1603.09320), and returns approximate results
+//! while only visiting a small fraction of the vectors. Both indexes can be saved to and loaded
+//! from safetensors files.
+use candle::{DType, Device, Result, Tensor};
+use rand::{Rng, SeedableRng};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, HashSet};
+use std::path::Path;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Metric {
+    /// The cosine similarity, the vectors and queries are l2 normalized when added or searched.
+    Cosine,
+    /// The dot product.
+    Dot,
+}
+
+impl Metric {
+    fn to_u32(self) -> u32 {
+        match self {
+            Self::Cosine => 0,
+            Self::Dot => 1,
+        }
+    }
+
+    fn from_u32(v: u32) -> Result<Self> {
+        match v {
+            0 => Ok(Self::Cosine),
+            1 => Ok(Self::Dot),
+            v => candle::bail!("unknown metric {v} in vector index"),
+        }
+    }
+
+    /// Converts the vectors to a `(n, dim)` f32 tensor, normalizing them for the cosine metric.
+    fn prepare(&self, vectors: &Tensor, dim: usize) -> Result<Tensor> {
+        let vectors = match vectors.rank() {
+            1 => vectors.unsqueeze(0)?,
+            _ => vectors.clone(),
+        };
+        let (_, d) = vectors.dims2()?;
+        if d != dim {
+            candle::bail!(
+                "expected vectors of dimension {dim}, got shape {:?}",
+                vectors.shape()
+            )
+        }
+        let vectors = vectors.to_dtype(DType::F32)?;
+        match self {
+            Self::Cosine => {
+                let norm = vectors.sqr()?.sum_keepdim(1)?.sqrt()?;
+                vectors.broadcast_div(&norm.clamp(1e-12f32, f32::INFINITY)?)
+            }
+            Self::Dot => Ok(vectors),
+        }
+    }
+}
+
+/// The matches of a query, as `(id, score)` pairs sorted by decreasing score. The ids are the
+/// positions of the vectors in insertion order, the scores the similarities with the query.
+pub type Matches = Vec<(usize, f32)>;
+
+pub trait VectorIndex {
+    /// Adds vectors of shape `(n, dim)` or `(dim,)` to the index.
+    fn add(&mut self, vectors: &Tensor) -> Result<()>;
+
+    /// Returns the `k` best matches for each query, `queries` having a shape `(n, dim)` or
+    /// `(dim,)` for a single query.
+    fn search(&self, queries: &Tensor, k: usize) -> Result<Vec<Matches>>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn save(&self, p: &Path) -> Result<()>;
+}
+
+fn load_u32s(tensors: &HashMap<String, Tensor>, name: &str) -> Result<Vec<u32>> {
+    match tensors.get(name) {
+        Some(t) => t.flatten_all()?.to_vec1::<u32>(),
+        None => candle::bail!("cannot find {name} in vector index file"),
+    }
+}
+
+fn load_vectors(tensors: &HashMap<String, Tensor>) -> Result<Tensor> {
+    match tensors.get("vectors") {
+        Some(t) => Ok(t.clone()),
+        None => candle::bail!("cannot find vectors in vector index file"),
+    }
+}
+
+/// An exact index, the similarities between the queries and all the stored vectors are computed
+/// with a single matrix multiplication on the index device.
+#[derive(Debug, Clone)]
+pub struct FlatIndex {
+    metric: Metric,
+    dim: usize,
+    vectors: Option<Tensor>,
+    device: Device,
+}
+
+impl FlatIndex {
+    pub fn new(dim: usize, metric: Metric, device: &Device) -> Self {
+        Self {
+            metric,
+            dim,
+            vectors: None,
+            device: device.clone(),
+        }
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    /// The stored vectors, normalized for the cosine metric.
+    pub fn vectors(&self) -> Option<&Tensor> {
+        self.vectors.as_ref()
+    }
+
+    pub fn load<P: AsRef<Path>>(p: P, device: &Device) -> Result<Self> {
+        let p = p.as_ref();
+        let load = || {
+            let tensors = candle::safetensors::load(p, device)?;
+            let metric = match load_u32s(&tensors, "metric")?.as_slice() {
+                [metric] => Metric::from_u32(*metric)?,
+                _ => candle::bail!("invalid metric in vector index file"),
+            };
+            let vectors = load_vectors(&tensors)?;
+            let (n, dim) = vectors.dims2()?;
+            Ok(Self {
+                metric,
+                dim,
+                vectors: (n > 0).then_some(vectors),
+                device: device.clone(),
+            })
+        };
+        load().map_err(|e: candle::Error| e.with_path(p))
+    }
+}
+
+impl VectorIndex for FlatIndex {
+    fn add(&mut self, vectors: &Tensor) -> Result<()> {
+        let vectors = self.metric.prepare(vectors, self.dim)?;
+        let vectors = vectors.to_device(&self.device)?;
+        let vectors = match &self.vectors {
+            None => vectors,
+            Some(v) => Tensor::cat(&[v, &vectors], 0)?,
+        };
+        self.vectors = Some(vectors);
+        Ok(())
+    }
+
+    fn search(&self, queries: &Tensor, k: usize) -> Result<Vec<Matches>> {
+        let queries = self.metric.prepare(queries, self.dim)?;
+        let vectors = match &self.vectors {
+            None => return Ok(vec![vec![]; queries.dim(0)?]),
+            Some(vectors) => vectors,
+        };
+        let k = usize::min(k, vectors.dim(0)?);
+        let scores = queries.to_device(&self.device)?.matmul(&vectors.t()?)?;
+        // The sorting kernels of the gpu backends only handle small rows, the scores are sorted
+        // on the cpu.
+        let (scores, ids) = scores.to_device(&Device::Cpu)?.sort_last_dim(false)?;
+        let scores = scores.narrow(1, 0, k)?.to_vec2::<f32>()?;
+        let ids = ids.narrow(1, 0, k)?.to_vec2::<u32>()?;
+        let matches = ids
+            .into_iter()
+            .zip(scores)
+            .map(|(ids, scores)| ids.into_iter().map(|i| i as usize).zip(scores).collect())
+            .collect();
+        Ok(matches)
+    }
+
+    fn len(&self) -> usize {
+        self.vectors.as_ref().map_or(0, |v| v.dims()[0])
+    }
+
+    fn save(&self, p: &Path) -> Result<()> {
+        let vectors = match &self.vectors {
+            Some(vectors) => vectors.clone(),
+            None => Tensor::zeros((0, self.dim), DType::F32, &Device::Cpu)?,
+        };
+        let metric = Tensor::new(&[self.metric.to_u32()], &Device::Cpu)?;
+        candle::safetensors::save(
+            &HashMap::from([("vectors", vectors), ("metric", metric)]),
+            p,
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HnswConfig {
+    /// The number of neighbors of each node on the upper layers, twice as many are kept on the
+    /// bottom layer.
+    pub m: usize,
+    /// The size of the candidate list when inserting a vector, larger values give a better graph
+    /// at the cost of a slower construction.
+    pub ef_construction: usize,
+    /// The size of the candidate list when searching, larger values give a better recall at the
+    /// cost of slower queries. It is at least the number of requested matches.
+    pub ef_search: usize,
+    /// The seed used to sample the layers of the inserted vectors.
+    pub seed: u64,
+}
+
+impl Default for HnswConfig {
+    fn default() -> Self {
+        Self {
+            m: 16,
+            ef_construction: 200,
+            ef_search: 64,
+            seed: 299792458,
+        }
+    }
+}
+
+impl HnswConfig {
+    pub fn with_m(mut self, m: usize) -> Self {
+        self.m = m;
+        self
+    }
+
+    pub fn with_ef_construction(mut self, ef_construction: usize) -> Self {
+        self.ef_construction = ef_construction;
+        self
+    }
+
+    pub fn with_ef_search(mut self, ef_search: usize) -> Self {
+        self.ef_search = ef_search;
+        self
+    }
+
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+}
+
+/// A node of the search, ordered by distance to the query.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    dist: f32,
+    id: usize,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.dist
+            .total_cmp(&other.dist)
+            .then(self.id.cmp(&other.id))
+    }
+}
+
+/// An approximate index based on a hierarchical navigable small world graph. The vectors and the
+/// graph are kept on the cpu.
+#[derive(Debug, Clone)]
+pub struct HnswIndex {
+    config: HnswConfig,
+    metric: Metric,
+    dim: usize,
+    vectors: Vec<f32>,
+    /// The neighbors of each node, for each of the layers the node belongs to.
+    neighbors: Vec<Vec<Vec<u32>>>,
+    entry_point: Option<usize>,
+    rng: rand::rngs::StdRng,
+}
+
+impl HnswIndex {
+    pub fn new(dim: usize, metric: Metric, config: HnswConfig) -> Self {
+        Self {
+            config,
+            metric,
+            dim,
+            vectors: vec![],
+            neighbors: vec![],
+            entry_point: None,
+            rng: rand::rngs::StdRng::seed_from_u64(config.seed),
+        }
+    }
+
+    pub fn dim(&self) -> usize {
+        self.dim
+    }
+
+    pub fn metric(&self) -> Metric {
+        self.metric
+    }
+
+    pub fn config(&self) -> &HnswConfig {
+        &self.config
+    }
+
+    pub fn set_ef_search(&mut self, ef_search: usize) {
+        self.config.ef_search = ef_search
+    }
+
+    fn vector(&self, id: usize) -> &[f32] {
+        &self.vectors[id * self.dim..(id + 1) * self.dim]
+    }
+
+    /// The distance used to navigate the graph, the opposite of the similarity.
+    fn dist(&self, query: &[f32], id: usize) -> f32 {
+        -query
+            .iter()
+            .zip(self.vector(id))
+            .map(|(a, b)| a * b)
+            .sum::<f32>()
+    }
+
+    fn top_layer(&self) -> usize {
+        self.entry_point
+            .map_or(0, |ep| self.neighbors[ep].len().saturating_sub(1))
+    }
+
+    fn max_neighbors(&self, layer: usize) -> usize {
+        if layer == 0 {
+            2 * self.config.m
+        } else {
+            self.config.m
+        }
+    }
+
+    /// Returns the `ef` nodes of `layer` closest to `query` found by a best first search from
+    /// `entry_points`, sorted by increasing distance.
+    fn search_layer(
+        &self,
+        query: &[f32],
+        entry_points: &[Candidate],
+        ef: usize,
+        layer: usize,
+    ) -> Vec<Candidate> {
+        let mut visited = entry_points.iter().map(|c| c.id).collect::<HashSet<_>>();
+        let mut candidates = entry_points
+            .iter()
+            .map(|&c| Reverse(c))
+            .collect::<BinaryHeap<_>>();
+        let mut results = entry_points.iter().copied().collect::<BinaryHeap<_>>();
+        while let Some(Reverse(candidate)) = candidates.pop() {
+            match results.peek() {
+                Some(furthest) if candidate.dist > furthest.dist && results.len() >= ef => break,
+                _ => {}
+            }
+            for &id in self.neighbors[candidate.id][layer].iter() {
+                let id = id as usize;
+                if !visited.insert(id) {
+                    continue;
+                }
+                let dist = self.dist(query, id);
+                let candidate = Candidate { dist, id };
+                match results.peek() {
+                    Some(furthest) if results.len() >= ef && dist >= furthest.dist => {}
+                    _ => {
+                        candidates.push(Reverse(candidate));
+                        results.push(candidate);
+                        if results.len() > ef {
+                            results.pop();
+                        }
+                    }
+                }
+            }
+        }
+        results.into_sorted_vec()
+    }
+
+    /// Descends the upper layers greedily and returns the closest node found on `layer`.
+    fn descend(&self, query: &[f32], entry_point: usize, layer: usize) -> Candidate {
+        let mut ep = Candidate {
+            dist: self.dist(query, entry_point),
+            id: entry_point,
+        };
+        for l in (layer + 1..=self.top_layer()).rev() {
+            ep = self.search_layer(query, &[ep], 1, l)[0]
+        }
+        ep
+    }
+
+    fn insert(&mut self, vector: &[f32]) {
+        let id = self.neighbors.len();
+        let ml = 1. / (self.config.m.max(2) as f64).ln();
+        let u: f64 = self.rng.gen_range(f64::EPSILON..1.0);
+        let layer = (-u.ln() * ml).floor() as usize;
+        self.vectors.extend_from_slice(vector);
+        self.neighbors.push(vec![vec![]; layer + 1]);
+        let entry_point = match self.entry_point {
+            None => {
+                self.entry_point = Some(id);
+                return;
+            }
+            Some(entry_point) => entry_point,
+        };
+        let top_layer = self.top_layer();
+        let mut eps = vec![self.descend(vector, entry_point, layer)];
+        for l in (0..=usize::min(layer, top_layer)).rev() {
+            let found = self.search_layer(vector, &eps, self.config.ef_construction, l);
+            let max_neighbors = self.max_neighbors(l);
+            for n in self.select_neighbors(&found, max_neighbors) {
+                self.neighbors[id][l].push(n as u32);
+                self.neighbors[n][l].push(id as u32);
+                if self.neighbors[n][l].len() > max_neighbors {
+                    self.prune(n, l, max_neighbors)
+                }
+            }
+            eps = found;
+        }
+        if layer > top_layer {
+            self.entry_point = Some(id)
+        }
+    }
+
+    /// Selects up to `m` neighbors among `candidates`, sorted by increasing distance. A candidate
+    /// is preferred when it is closer to the base node than to the already selected neighbors so
+    /// that the neighbors cover multiple directions, the remaining slots are then filled with the
+    /// closest discarded candidates.
+    fn select_neighbors(&self, candidates: &[Candidate], m: usize) -> Vec<usize> {
+        let mut selected: Vec<usize> = Vec::with_capacity(m);
+        let mut discarded = vec![];
+        for c in candidates.iter() {
+            if selected.len() >= m {
+                break;
+            }
+            let vector = self.vector(c.id);
+            if selected.iter().all(|&s| c.dist < self.dist(vector, s)) {
+                selected.push(c.id)
+            } else {
+                discarded.push(c.id)
+            }
+        }
+        let missing = m.saturating_sub(selected.len());
+        selected.extend(discarded.into_iter().take(missing));
+        selected
+    }
+
+    /// Only keeps `max_neighbors` neighbors of `id` on `layer`.
+    fn prune(&mut self, id: usize, layer: usize, max_neighbors: usize) {
+        let vector = self.vector(id);
+        let mut neighbors = self.neighbors[id][layer]
+            .iter()
+            .map(|&n| Candidate {
+                dist: self.dist(vector, n as usize),
+                id: n as usize,
+            })
+            .collect::<Vec<_>>();
+        neighbors.sort();
+        let neighbors = self.select_neighbors(&neighbors, max_neighbors);
+        self.neighbors[id][layer] = neighbors.into_iter().map(|n| n as u32).collect()
+    }
+
+    fn search_one(&self, query: &[f32], k: usize) -> Matches {
+        let entry_point = match self.entry_point {
+            None => return vec![],
+            Some(entry_point) => entry_point,
+        };
+        let ep = self.descend(query, entry_point, 0);
+        let ef = usize::max(self.config.ef_search, k);
+        self.search_layer(query, &[ep], ef, 0)
+            .into_iter()
+            .take(k)
+            .map(|c| (c.id, -c.dist))
+            .collect()
+    }
+
+    pub fn load<P: AsRef<Path>>(p: P) -> Result<Self> {
+        let p = p.as_ref();
+        let load = || {
+            let tensors = candle::safetensors::load(p, &Device::Cpu)?;
+            let header = load_u32s(&tensors, "header")?;
+            let [metric, m, ef_construction, ef_search, seed_lo, seed_hi] = header.as_slice()
+            else {
+                candle::bail!("invalid header in vector index file")
+            };
+            let config = HnswConfig {
+                m: *m as usize,
+                ef_construction: *ef_construction as usize,
+                ef_search: *ef_search as usize,
+                seed: (*seed_hi as u64) << 32 | *seed_lo as u64,
+            };
+            let vectors = load_vectors(&tensors)?;
+            let (n, dim) = vectors.dims2()?;
+            let vectors = vectors.flatten_all()?.to_vec1::<f32>()?;
+            let layers = load_u32s(&tensors, "layers")?;
+            let counts = load_u32s(&tensors, "neighbor_counts")?;
+            let flat_neighbors = load_u32s(&tensors, "neighbors")?;
+            if layers.len() != n || counts.len() != layers.iter().sum::<u32>() as usize {
+                candle::bail!("inconsistent graph in vector index file")
+            }
+            let mut counts = counts.into_iter();
+            let mut offset = 0;
+            let mut neighbors = Vec::with_capacity(n);
+            for &num_layers in layers.iter() {
+                let mut node = Vec::with_capacity(num_layers as usize);
+                for count in counts.by_ref().take(num_layers as usize) {
+                    let end = offset + count as usize;
+                    match flat_neighbors.get(offset..end) {
+                        Some(ids) if ids.iter().all(|&id| (id as usize) < n) => {
+                            node.push(ids.to_vec())
+                        }
+                        _ => candle::bail!("invalid neighbors in vector index file"),
+                    }
+                    offset = end
+                }
+                neighbors.push(node)
+            }
+            let entry_point = (0..n).max_by_key(|&i| (neighbors[i].len(), Reverse(i)));
+            // The random generator state is not saved, reseed it so that adding vectors after
+            // loading the index remains deterministic.
+            let rng = rand::rngs::StdRng::seed_from_u64(config.seed.wrapping_add(n as u64));
+            Ok(Self {
+                config,
+                metric: Metric::from_u32(*metric)?,
+                dim,
+                vectors,
+                neighbors,
+                entry_point,
+                rng,
+            })
+        };
+        load().map_err(|e: candle::Error| e.with_path(p))
+    }
+}
+
+impl VectorIndex for HnswIndex {
+    fn add(&mut self, vectors: &Tensor) -> Result<()> {
+        let vectors = self.metric.prepare(vectors, self.dim)?;
+        for vector in vectors.to_vec2::<f32>()? {
+            self.insert(&vector)
+        }
+        Ok(())
+    }
+
+    fn search(&self, queries: &Tensor, k: usize) -> Result<Vec<Matches>> {
+        let queries = self.metric.prepare(queries, self.dim)?;
+        let matches = queries
+            .to_vec2::<f32>()?
+            .iter()
+            .map(|query| self.search_one(query, k))
+            .collect();
+        Ok(matches)
+    }
+
+    fn len(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    fn save(&self, p: &Path) -> Result<()> {
+        let n = self.len();
+        let cpu = &Device::Cpu;
+        let header = [
+            self.metric.to_u32(),
+            self.config.m as u32,
+            self.config.ef_construction as u32,
+            self.config.ef_search as u32,
+            self.config.seed as u32,
+            (self.config.seed >> 32) as u32,
+        ];
+        let layers = self
+            .neighbors
+            .iter()
+            .map(|n| n.len() as u32)
+            .collect::<Vec<_>>();
+        let counts = self
+            .neighbors
+            .iter()
+            .flatten()
+            .map(|n| n.len() as u32)
+            .collect::<Vec<_>>();
+        let neighbors = self
+            .neighbors
+            .iter()
+            .flatten()
+            .flatten()
+            .copied()
+            .collect::<Vec<_>>();
+        let u32s = |v: Vec<u32>| {
+            let len = v.len();
+            Tensor::from_vec(v, len, cpu)
+        };
+        let tensors = HashMap::from([
+            ("header", Tensor::new(&header, cpu)?),
+            (
+                "vectors",
+                Tensor::from_vec(self.vectors.clone(), (n, self.dim), cpu)?,
+            ),
+            ("layers", u32s(layers)?),
+            ("neighbor_counts", u32s(counts)?),
+            ("neighbors", u32s(neighbors)?),
+        ]);
+        candle::safetensors::save(&tensors, p)
+    }
+}
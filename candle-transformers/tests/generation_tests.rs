@@ -54,3 +54,899 @@ fn sample_with_top_k() -> Result<()> {
     assert_eq!(token, 2);
     Ok(())
 }
+
+#[test]
+fn sampling_from_options() -> Result<()> {
+    use candle_transformers::generation::Sampling;
+
+    assert_eq!(Sampling::new(0., Some(5), Some(0.9)), Sampling::ArgMax);
+    assert_eq!(
+        Sampling::new(0.8, None, None),
+        Sampling::All { temperature: 0.8 }
+    );
+    assert_eq!(
+        Sampling::new(0.8, Some(5), None),
+        Sampling::TopK {
+            k: 5,
+            temperature: 0.8
+        }
+    );
+    assert_eq!(
+        Sampling::new(0.8, None, Some(0.9)),
+        Sampling::TopP {
+            p: 0.9,
+            temperature: 0.8
+        }
+    );
+    let sampling = Sampling::new(1., Some(2), Some(0.5));
+    assert_eq!(
+        sampling,
+        Sampling::TopKThenTopP {
+            k: 2,
+            p: 0.5,
+            temperature: 1.
+        }
+    );
+    // The most likely token has a probability above 0.5 so it is the only one kept.
+    let mut logits_process = LogitsProcessor::from_sampling(42, sampling);
+    let logits = Tensor::new(&[0.1f32, 0.2, 0.3, 5.0], &Device::Cpu)?;
+    for _ in 0..10 {
+        assert_eq!(logits_process.sample(&logits)?, 3);
+    }
+    Ok(())
+}
+
+#[test]
+fn sample_with_rng_is_deterministic() -> Result<()> {
+    let logits_process = LogitsProcessor::new(0, Some(1.0), None);
+    let logits = Tensor::new(&[0.1f32, 0.2, 0.3, 0.4], &Device::Cpu)?;
+    let mut rng_a1 = LogitsProcessor::rng_from_seed(42);
+    let mut rng_a2 = LogitsProcessor::rng_from_seed(42);
+    let mut rng_b = LogitsProcessor::rng_from_seed(1337);
+    let mut tokens_a1 = vec![];
+    let mut tokens_a2 = vec![];
+    for _ in 0..16 {
+        // Interleave the requests, the results for a given seed should not depend on it.
+        tokens_a1.push(logits_process.sample_with_rng(&logits, &mut rng_a1)?);
+        logits_process.sample_with_rng(&logits, &mut rng_b)?;
+    }
+    for _ in 0..16 {
+        tokens_a2.push(logits_process.sample_with_rng(&logits, &mut rng_a2)?);
+    }
+    assert_eq!(tokens_a1, tokens_a2);
+
+    // Cloned processors share the sampling config and carry their own rng state.
+    let mut p1 = LogitsProcessor::new(42, Some(1.0), None);
+    let mut p2 = p1.clone();
+    let t1 = (0..16)
+        .map(|_| p1.sample(&logits))
+        .collect::<Result<Vec<_>>>()?;
+    let t2 = (0..16)
+        .map(|_| p2.sample(&logits))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(t1, t2);
+    Ok(())
+}
+
+#[test]
+fn sample_batch() -> Result<()> {
+    use candle_transformers::generation::Sampling;
+    let logits = Tensor::new(
+        &[
+            [0.1f32, 0.2, 0.3, 0.4],
+            [0.4, 0.3, 0.2, 0.1],
+            [0.1, 0.2, 0.3, 0.4],
+        ],
+        &Device::Cpu,
+    )?;
+    let mut processors = vec![
+        LogitsProcessor::new(42, None, None),
+        LogitsProcessor::new(42, None, None),
+        LogitsProcessor::from_sampling(
+            42,
+            Sampling::TopK {
+                k: 2,
+                temperature: 1.0,
+            },
+        ),
+    ];
+    let mut reference = processors.clone();
+    let tokens = LogitsProcessor::sample_batch(&mut processors, &logits)?;
+    let expected = (0..3)
+        .map(|i| reference[i].sample(&logits.get(i)?))
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(tokens, expected);
+    assert_eq!(&tokens[..2], [3, 0]);
+
+    let err = LogitsProcessor::sample_batch(&mut processors[..2], &logits);
+    assert!(err.is_err());
+    Ok(())
+}
+
+#[test]
+fn forward_chunked() -> Result<()> {
+    use candle_transformers::generation::forward_chunked;
+    let dev = &Device::Cpu;
+    let mut calls = vec![];
+    let last = forward_chunked(&[1, 2, 3, 4, 5, 6, 7], 3, 2, dev, |input, pos| {
+        calls.push((input.to_vec2::<u32>()?, pos));
+        input.sum_all()
+    })?;
+    assert_eq!(
+        calls,
+        [
+            (vec![vec![1, 2, 3]], 2),
+            (vec![vec![4, 5, 6]], 5),
+            (vec![vec![7]], 8)
+        ]
+    );
+    assert_eq!(last.to_scalar::<u32>()?, 7);
+    assert!(forward_chunked(&[], 3, 0, dev, |input, _| Ok(input.clone())).is_err());
+    assert!(forward_chunked(&[1], 0, 0, dev, |input, _| Ok(input.clone())).is_err());
+    Ok(())
+}
+
+#[test]
+fn generation_report() {
+    use candle_transformers::generation::{GenerationReport, GenerationTimer};
+    use std::time::Duration;
+    let ms = Duration::from_millis;
+    let report = GenerationReport {
+        prompt_tokens: 10,
+        generated_tokens: 5,
+        prompt_eval_time: ms(500),
+        token_latencies: vec![ms(40), ms(10), ms(30), ms(20)],
+        peak_memory: None,
+    };
+    assert_eq!(report.generation_time(), ms(100));
+    assert_eq!(report.prompt_tokens_per_second(), 20.);
+    assert_eq!(report.generated_tokens_per_second(), 40.);
+    assert_eq!(report.mean_latency(), Some(ms(25)));
+    assert_eq!(report.latency_percentile(50.), Some(ms(20)));
+    assert_eq!(report.latency_percentile(90.), Some(ms(40)));
+    assert_eq!(report.latency_percentile(0.), Some(ms(10)));
+    assert_eq!(GenerationReport::default().latency_percentile(50.), None);
+
+    let mut timer = GenerationTimer::start();
+    timer.prompt_processed(3);
+    timer.token_generated();
+    timer.token_generated();
+    let report = timer.finish();
+    assert_eq!(report.prompt_tokens, 3);
+    assert_eq!(report.generated_tokens, 3);
+    assert_eq!(report.token_latencies.len(), 2);
+    #[cfg(target_os = "linux")]
+    assert!(report.peak_memory.is_some());
+}
+
+#[test]
+fn generation_events() -> Result<()> {
+    use candle_transformers::generation::events::{generate, GenerationConfig, GenerationEvents};
+    use candle_transformers::generation::GenerationReport;
+    use std::time::Duration;
+
+    #[derive(Default)]
+    struct Recorder {
+        progress: Vec<(usize, usize)>,
+        tokens: Vec<u32>,
+        report: Option<GenerationReport>,
+        max_tokens: usize,
+    }
+
+    impl GenerationEvents for Recorder {
+        fn on_prompt_progress(&mut self, processed: usize, total: usize) {
+            self.progress.push((processed, total))
+        }
+
+        fn on_token(&mut self, token: u32, _latency: Duration) -> bool {
+            self.tokens.push(token);
+            self.tokens.len() < self.max_tokens
+        }
+
+        fn on_done(&mut self, report: &GenerationReport) {
+            self.report = Some(report.clone())
+        }
+    }
+
+    let positions = std::cell::RefCell::new(vec![]);
+    let mut model = |xs: &Tensor, pos: usize| -> Result<Tensor> {
+        let tokens = xs.squeeze(0)?.to_vec1::<u32>()?;
+        positions.borrow_mut().push(pos);
+        let next = (tokens[tokens.len() - 1] + 1) % 10;
+        let logits = (0..10).map(|i| if i == next { 1f32 } else { 0. });
+        Tensor::new(logits.collect::<Vec<_>>().as_slice(), &Device::Cpu)
+    };
+    let mut lp = LogitsProcessor::new(0, None, None);
+    let config = GenerationConfig::new(4).with_prompt_chunk_size(Some(2));
+    let mut events = Recorder {
+        max_tokens: usize::MAX,
+        ..Default::default()
+    };
+    let tokens = generate(
+        &mut model,
+        &[1, 2, 3],
+        &mut lp,
+        &Device::Cpu,
+        &config,
+        &mut events,
+    )?;
+    assert_eq!(tokens, [4, 5, 6, 7]);
+    assert_eq!(*positions.borrow(), [0, 2, 3, 4, 5]);
+    assert_eq!(events.progress, [(2, 3), (3, 3)]);
+    assert_eq!(events.tokens, tokens);
+    let report = events.report.unwrap();
+    assert_eq!(report.prompt_tokens, 3);
+    assert_eq!(report.generated_tokens, 4);
+
+    // The generation stops on the stop tokens and when the callback returns false.
+    let config = GenerationConfig::new(10).with_stop_tokens(vec![3]);
+    let tokens = generate(&mut model, &[1], &mut lp, &Device::Cpu, &config, ())?;
+    assert_eq!(tokens, [2, 3]);
+    let mut events = Recorder {
+        max_tokens: 2,
+        ..Default::default()
+    };
+    let config = GenerationConfig::new(10);
+    let tokens = generate(
+        &mut model,
+        &[5],
+        &mut lp,
+        &Device::Cpu,
+        &config,
+        &mut events,
+    )?;
+    assert_eq!(tokens, [6, 7]);
+    assert_eq!(events.report.unwrap().generated_tokens, 2);
+    Ok(())
+}
+
+#[test]
+fn text_generation() -> Result<()> {
+    use candle_transformers::generation::events::{GenerationConfig, OnToken, TextGeneration};
+
+    // A model always preferring token 1 over token 2.
+    let model = |_: &Tensor, _: usize| -> Result<Tensor> {
+        Tensor::new(&[0f32, 2., 1.5, 0.], &Device::Cpu)
+    };
+    let lp = LogitsProcessor::new(0, None, None);
+    let mut pipeline = TextGeneration::new(model, lp, Device::Cpu, GenerationConfig::new(4));
+    assert!(pipeline.report().is_none());
+    assert_eq!(pipeline.generate(&[0], ())?, [1, 1, 1, 1]);
+    assert_eq!(pipeline.report().unwrap().generated_tokens, 4);
+
+    // With the repeat penalty, the last token is avoided.
+    *pipeline.config_mut() = GenerationConfig::new(4).with_repeat_penalty(2., 1);
+    let mut streamed = vec![];
+    let tokens = pipeline.generate(
+        &[1],
+        OnToken(|token| {
+            streamed.push(token);
+            true
+        }),
+    )?;
+    assert_eq!(tokens, [2, 1, 2, 1]);
+    assert_eq!(streamed, tokens);
+
+    // The tokens can be sent to a channel, dropping the receiver stops the generation.
+    let (tx, rx) = std::sync::mpsc::channel();
+    let tokens = pipeline.generate(&[3], tx)?;
+    assert_eq!(rx.iter().collect::<Vec<_>>(), tokens);
+    let (tx, rx) = std::sync::mpsc::channel();
+    drop(rx);
+    assert_eq!(pipeline.generate(&[3], tx)?.len(), 1);
+    assert_eq!(pipeline.report().unwrap().generated_tokens, 1);
+    Ok(())
+}
+
+#[test]
+fn beam_search() -> Result<()> {
+    use candle_transformers::generation::beam_search::{beam_search, BeamSearchConfig};
+    use candle_transformers::generation::ensemble::CausalLM;
+
+    fn search(model: &mut impl CausalLM, config: &BeamSearchConfig) -> Result<Vec<Vec<u32>>> {
+        let hypotheses = beam_search(model, &[3], &Device::Cpu, config)?;
+        Ok(hypotheses.into_iter().map(|h| h.tokens).collect())
+    }
+
+    // Token 0 ends the sequences, the greedy choice of token 1 leads to a less likely sequence
+    // than token 2.
+    let inputs = std::cell::RefCell::new(vec![]);
+    let mut model = |xs: &Tensor, pos: usize| -> Result<Tensor> {
+        assert_eq!(pos, 0);
+        let tokens = xs.squeeze(0)?.to_vec1::<u32>()?;
+        let probs = match tokens[tokens.len() - 1] {
+            3 => [0.05f32, 0.5, 0.45, 1e-6],
+            1 => [0.3, 0.25, 0.2, 0.25],
+            2 => [0.9, 0.04, 0.03, 0.03],
+            _ => [0.25; 4],
+        };
+        inputs.borrow_mut().push(tokens);
+        Tensor::new(&probs, &Device::Cpu)?.log()
+    };
+
+    let config = BeamSearchConfig::new(1, 10)
+        .with_eos_tokens(vec![0])
+        .with_length_penalty(0.);
+    assert_eq!(search(&mut model, &config)?, [[1, 0]]);
+    // The search stops once no running beam can beat the finished hypothesis.
+    assert_eq!(*inputs.borrow(), [vec![3], vec![3, 1]]);
+
+    let config = BeamSearchConfig::new(2, 10)
+        .with_eos_tokens(vec![0])
+        .with_length_penalty(0.);
+    let hypotheses = beam_search(&mut model, &[3], &Device::Cpu, &config)?;
+    assert_eq!(hypotheses.len(), 2);
+    assert_eq!(hypotheses[0].tokens, [2, 0]);
+    assert!((hypotheses[0].log_prob.exp() - 0.405).abs() < 1e-4);
+    assert_eq!(hypotheses[1].tokens, [1, 0]);
+    assert!((hypotheses[1].log_prob.exp() - 0.15).abs() < 1e-4);
+
+    // With the default length penalty, a longer but less likely sequence ranks second.
+    let config = BeamSearchConfig::new(2, 10).with_eos_tokens(vec![0]);
+    let hypotheses = beam_search(&mut model, &[3], &Device::Cpu, &config)?;
+    assert_eq!(hypotheses[0].tokens, [2, 0]);
+    assert!((hypotheses[0].score - 0.405f64.ln() / 2.).abs() < 1e-4);
+    assert_eq!(hypotheses[1].tokens, [1, 3, 2, 0]);
+
+    // The running beams are returned when reaching the maximum number of tokens.
+    let config = BeamSearchConfig::new(2, 1).with_eos_tokens(vec![0]);
+    assert_eq!(search(&mut model, &config)?, [[1], [2]]);
+
+    // A negative length penalty favors the shorter hypotheses.
+    let config = BeamSearchConfig::new(3, 10)
+        .with_eos_tokens(vec![0])
+        .with_length_penalty(-4.);
+    assert_eq!(search(&mut model, &config)?[0], [0]);
+
+    assert!(search(&mut model, &BeamSearchConfig::new(0, 10)).is_err());
+    assert!(beam_search(&mut model, &[], &Device::Cpu, &BeamSearchConfig::new(2, 10)).is_err());
+    Ok(())
+}
+
+#[test]
+fn ensemble_logits() -> Result<()> {
+    use candle_transformers::generation::ensemble::{
+        check_shared_vocab, combine_logits, Combination, Ensemble,
+    };
+    let dev = &Device::Cpu;
+    let l1 = Tensor::new(&[0f32, 1., 2.], dev)?;
+    let l2 = Tensor::new(&[2f32, 1., 0., 5.], dev)?;
+
+    // Equal weights on opposite logits give a uniform distribution, the padded vocab entry of the
+    // second model is dropped.
+    let combined = combine_logits(
+        &[l1.clone(), l2.clone()],
+        &[1., 1.],
+        &[1., 1.],
+        Combination::LogitAverage,
+    )?;
+    let prs: Vec<f32> = combined.exp()?.to_vec1()?;
+    assert_eq!(prs.len(), 3);
+    for p in prs {
+        assert!((p - 1. / 3.).abs() < 1e-5)
+    }
+    let combined = combine_logits(
+        &[l1.clone(), l2.clone()],
+        &[1., 0.],
+        &[1., 1.],
+        Combination::ProbabilityAverage,
+    )?;
+    let expected = candle_nn::ops::log_softmax(&l1, 0)?;
+    let diff = (combined - expected)?.abs()?.max(0)?.to_scalar::<f32>()?;
+    assert!(diff < 1e-5);
+
+    // Both models see the same inputs and positions.
+    let mut positions = vec![];
+    let mut ensemble = Ensemble::new(Combination::LogitAverage)
+        .with_model(
+            |_: &Tensor, pos: usize| {
+                positions.push(pos);
+                Tensor::new(&[[0f32, 1., 2.]], dev)
+            },
+            1.,
+            1.,
+        )
+        .with_model(
+            |_: &Tensor, _: usize| Tensor::new(&[0f32, 1., 2.], dev),
+            1.,
+            0.5,
+        );
+    let xs = Tensor::new(&[[1u32, 2]], dev)?;
+    let logits = ensemble.forward(&xs, 0)?;
+    let logits = ensemble.forward(&xs, 2)?.add(&logits)?;
+    assert_eq!(logits.dims(), [3]);
+    drop(ensemble);
+    assert_eq!(positions, [0, 2]);
+
+    let vocab = |tokens: &[(&str, u32)]| {
+        tokens
+            .iter()
+            .map(|(t, i)| (t.to_string(), *i))
+            .collect::<std::collections::HashMap<_, _>>()
+    };
+    let v1 = vocab(&[("a", 0), ("b", 1)]);
+    assert!(check_shared_vocab(&v1, &vocab(&[("b", 1), ("a", 0)])).is_ok());
+    assert!(check_shared_vocab(&v1, &vocab(&[("a", 1), ("b", 0)])).is_err());
+    assert!(check_shared_vocab(&v1, &vocab(&[("a", 0)])).is_err());
+    Ok(())
+}
+
+#[test]
+fn contrastive_logits() -> Result<()> {
+    use candle_transformers::generation::ensemble::contrastive_logits;
+    let dev = &Device::Cpu;
+    let expert = Tensor::new(&[0.5f32, 0.3, 0.15, 0.05], dev)?.log()?;
+    let amateur = Tensor::new(&[0.7f32, 0.1, 0.1, 0.1], dev)?.log()?;
+    let scores: Vec<f32> = contrastive_logits(&expert, &amateur, 0.2, 1.0)?.to_vec1()?;
+    // The last token is below the plausibility threshold 0.2 * 0.5.
+    assert_eq!(scores[3], f32::NEG_INFINITY);
+    // The second token is preferred as the amateur is confident about the first one.
+    let best = scores
+        .iter()
+        .enumerate()
+        .max_by(|a, b| a.1.total_cmp(b.1))
+        .map(|v| v.0);
+    assert_eq!(best, Some(1));
+    Ok(())
+}
+
+#[test]
+fn contrastive_decoding() -> Result<()> {
+    use candle_transformers::generation::ensemble::ContrastiveDecoding;
+    let dev = &Device::Cpu;
+    let expert = Tensor::new(&[[2f32, 1.5, 0., -3.]], dev)?.squeeze(0)?;
+    let amateur = Tensor::new(&[3f32, 0., 0., 0.], dev)?;
+    let cd = ContrastiveDecoding::default();
+    assert_eq!((cd.alpha(), cd.beta()), (0.1, 1.0));
+    let mut processor = LogitsProcessor::new(0, None, None);
+    assert_eq!(processor.sample(&expert)?, 0);
+    let scores = cd.logits(&expert, &amateur)?;
+    assert_eq!(processor.sample(&scores)?, 1);
+    // A flat amateur does not change the ranking.
+    let scores = cd.with_amateur_temperature(1e6).logits(&expert, &amateur)?;
+    assert_eq!(processor.sample(&scores)?, 0);
+    Ok(())
+}
+
+#[test]
+fn watermark() -> Result<()> {
+    use candle_transformers::generation::watermark::Watermark;
+    let dev = &Device::Cpu;
+    let vocab_size = 5000;
+    let watermark = Watermark::new(1337);
+    let green = (0..vocab_size)
+        .filter(|&t| watermark.is_green(3, t))
+        .count();
+    assert!((green as f64 / vocab_size as f64 - 0.25).abs() < 0.02);
+    // The green lists depend on the previous token and on the key.
+    assert!((0..100).any(|t| watermark.is_green(3, t) != watermark.is_green(4, t)));
+    assert!((0..100).any(|t| watermark.is_green(3, t) != Watermark::new(1).is_green(3, t)));
+
+    let logits = Tensor::zeros(vocab_size as usize, candle::DType::F32, dev)?;
+    let biased: Vec<f32> = watermark.apply(&logits, 3)?.to_vec1()?;
+    for (t, v) in biased.iter().enumerate() {
+        let expected = if watermark.is_green(3, t as u32) {
+            2.
+        } else {
+            0.
+        };
+        assert_eq!(*v, expected)
+    }
+
+    // Sample with and without the watermark from the same flat-ish distribution.
+    let mut processor = LogitsProcessor::new(42, Some(1.0), None);
+    let logits = Tensor::randn(0f32, 0.5, vocab_size as usize, dev)?;
+    let mut marked = vec![0u32];
+    let mut unmarked = vec![0u32];
+    for _ in 0..200 {
+        let prev = *marked.last().unwrap();
+        marked.push(processor.sample(&watermark.apply(&logits, prev)?)?);
+        unmarked.push(processor.sample(&logits)?);
+    }
+    let detection = watermark.detect(&marked);
+    assert!(detection.is_watermarked(4.), "{detection:?}");
+    let detection = watermark.detect(&unmarked);
+    assert!(!detection.is_watermarked(4.), "{detection:?}");
+    // Repeated pairs are only scored once.
+    assert_eq!(watermark.detect(&[1, 2, 1, 2, 1, 2]).num_tokens, 2);
+    assert_eq!(watermark.detect(&[1]).z_score, 0.);
+    Ok(())
+}
+
+#[test]
+fn steering() -> Result<()> {
+    use candle_transformers::generation::steering::Steering;
+
+    let device = &Device::Cpu;
+    let xs = Tensor::zeros((1, 2, 3), candle::DType::F32, device)?;
+    let steering = Steering::new()
+        .with_vector(0, Tensor::new(&[1f32, 2., 3.], device)?, 2.0)?
+        .with_vector(2, Tensor::new(&[1f32, 1., 1.], device)?, 1.0)?
+        .with_scale(2, -1.0);
+    assert_eq!(steering.layers(), [0, 2]);
+    assert_eq!(steering.scale(2), Some(-1.0));
+    assert_eq!(
+        steering.apply(0, &xs)?.to_vec3::<f32>()?,
+        [[[2., 4., 6.], [2., 4., 6.]]]
+    );
+    assert_eq!(
+        steering.apply(1, &xs)?.to_vec3::<f32>()?,
+        [[[0., 0., 0.], [0., 0., 0.]]]
+    );
+    assert_eq!(
+        steering.apply(2, &xs)?.to_vec3::<f32>()?,
+        [[[-1., -1., -1.], [-1., -1., -1.]]]
+    );
+    let wrong_size = Tensor::zeros((1, 2, 4), candle::DType::F32, device)?;
+    assert!(steering.apply(0, &wrong_size).is_err());
+
+    let file = std::env::temp_dir().join("candle_steering_test.safetensors");
+    steering.save(&file)?;
+    let loaded = Steering::load(&file, device)?;
+    std::fs::remove_file(&file)?;
+    assert_eq!(loaded.layers(), [0, 2]);
+    assert_eq!(
+        loaded.apply(0, &xs)?.to_vec3::<f32>()?,
+        steering.apply(0, &xs)?.to_vec3::<f32>()?
+    );
+    let loaded = loaded.with_layers(&[2]).with_global_scale(3.0);
+    assert_eq!(loaded.layers(), [2]);
+    assert_eq!(loaded.scale(2), Some(3.0));
+
+    let positive = Tensor::new(&[[[1f32, 0.], [3., 2.]]], device)?;
+    let negative = Tensor::new(&[[1f32, 1.]], device)?;
+    let v = Steering::mean_difference(&positive, &negative)?;
+    assert_eq!(v.to_vec1::<f32>()?, [1., 0.]);
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn token_stream() -> Result<()> {
+    use candle_transformers::generation::stream::{generate, StreamConfig};
+    use tokio_stream::StreamExt;
+
+    // A model predicting the successor of the last token, checking the positions.
+    let model = |mut expected_pos: usize| {
+        move |xs: &Tensor, pos: usize| -> Result<Tensor> {
+            assert_eq!(pos, expected_pos);
+            let tokens = xs.squeeze(0)?.to_vec1::<u32>()?;
+            expected_pos += tokens.len();
+            let next = (tokens[tokens.len() - 1] + 1) % 10;
+            let logits = (0..10).map(|i| if i == next { 1f32 } else { 0. });
+            Tensor::new(logits.collect::<Vec<_>>().as_slice(), &Device::Cpu)?.unsqueeze(0)
+        }
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let tokens = runtime.block_on(async {
+        let config = StreamConfig::new(5).with_stop_tokens(vec![7]);
+        let lp = LogitsProcessor::new(0, None, None);
+        let stream = generate(model(0), vec![1, 2, 3], lp, Device::Cpu, config);
+        stream.collect::<Result<Vec<_>>>().await
+    })?;
+    assert_eq!(tokens, [4, 5, 6, 7]);
+
+    let tokens = runtime.block_on(async {
+        let lp = LogitsProcessor::new(0, None, None);
+        let stream = generate(model(0), vec![8], lp, Device::Cpu, StreamConfig::new(3));
+        stream.collect::<Result<Vec<_>>>().await
+    })?;
+    assert_eq!(tokens, [9, 0, 1]);
+
+    // Errors are reported through the stream.
+    let res = runtime.block_on(async {
+        let lp = LogitsProcessor::new(0, None, None);
+        let model = |_: &Tensor, _: usize| -> Result<Tensor> { candle::bail!("model error") };
+        let stream = generate(model, vec![8], lp, Device::Cpu, StreamConfig::new(3));
+        stream.collect::<Vec<_>>().await
+    });
+    assert!(res.len() == 1 && res[0].is_err());
+    Ok(())
+}
+
+/// A bigram model whose next token probabilities are the rows of `probs`. The positions after
+/// `index_pos` are dropped as with a kv cache, the logits are returned for all the positions of
+/// the input when `all_positions` is set and for the last one otherwise.
+fn bigram_model(
+    probs: [[f32; 4]; 4],
+    all_positions: bool,
+) -> impl FnMut(&Tensor, usize) -> Result<Tensor> {
+    let mut cache: Vec<u32> = vec![];
+    move |xs: &Tensor, index_pos: usize| {
+        assert!(cache.len() >= index_pos, "missing positions in the cache");
+        cache.truncate(index_pos);
+        let tokens = xs.squeeze(0)?.to_vec1::<u32>()?;
+        cache.extend_from_slice(&tokens);
+        let logits = tokens
+            .iter()
+            .map(|&t| Tensor::new(&probs[t as usize], &Device::Cpu)?.log())
+            .collect::<Result<Vec<_>>>()?;
+        if all_positions {
+            Tensor::stack(&logits, 0)?.unsqueeze(0)
+        } else {
+            Ok(logits[logits.len() - 1].clone())
+        }
+    }
+}
+
+#[test]
+fn speculative_decoding() -> Result<()> {
+    use candle_transformers::generation::speculative::SpeculativeGenerator;
+    use candle_transformers::generation::Sampling;
+
+    let target = [
+        [0.1, 0.6, 0.2, 0.1],
+        [0.1, 0.1, 0.7, 0.1],
+        [0.5, 0.1, 0.1, 0.3],
+        [0.1, 0.2, 0.3, 0.4],
+    ];
+    // The greedy predictions of the draft model only differ after token 2.
+    let draft = [
+        [0.2, 0.5, 0.2, 0.1],
+        [0.2, 0.1, 0.6, 0.1],
+        [0.1, 0.1, 0.2, 0.6],
+        [0.25, 0.25, 0.25, 0.25],
+    ];
+
+    let mut generator = SpeculativeGenerator::new(
+        bigram_model(draft, false),
+        bigram_model(target, true),
+        3,
+        Sampling::ArgMax,
+        42,
+        &Device::Cpu,
+    );
+    // The greedy decoding of the target model, two of each three draft tokens are accepted.
+    let tokens = generator.generate(&[0], 8, &[])?;
+    assert_eq!(tokens, [1, 2, 0, 1, 2, 0, 1, 2]);
+    assert!((generator.acceptance_rate() - 2. / 3.).abs() < 1e-6);
+    assert_eq!(generator.tokens(), [0, 1, 2, 0, 1, 2, 0, 1, 2, 0]);
+    assert_eq!(generator.generate(&[3, 1], 8, &[0])?, [2, 0]);
+    assert!(generator.generate(&[], 8, &[]).is_err());
+
+    // The sampled tokens follow the distribution of the target model.
+    let mut generator = SpeculativeGenerator::new(
+        bigram_model(draft, false),
+        bigram_model(target, true),
+        2,
+        Sampling::All { temperature: 1. },
+        42,
+        &Device::Cpu,
+    );
+    let num_samples = 2000;
+    let mut counts = [[0usize; 4]; 2];
+    for _ in 0..num_samples {
+        let tokens = generator.generate(&[0], 2, &[])?;
+        counts[0][tokens[0] as usize] += 1;
+        counts[1][tokens[1] as usize] += 1;
+    }
+    for token in 0..4 {
+        let expected = [
+            target[0][token],
+            (0..4).map(|t| target[0][t] * target[t][token]).sum(),
+        ];
+        for (count, expected) in counts.iter().zip(expected) {
+            let freq = count[token] as f32 / num_samples as f32;
+            assert!((freq - expected).abs() < 0.05, "{token} {freq} {expected}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn sampling_distribution() -> Result<()> {
+    use candle_transformers::generation::Sampling;
+
+    let logits = Tensor::new(&[0.1f32, 0.6, 0.2, 0.1], &Device::Cpu)?.log()?;
+    let round = |prs: Vec<f32>| {
+        prs.iter()
+            .map(|p| (p * 1e4).round() / 1e4)
+            .collect::<Vec<_>>()
+    };
+    let distribution =
+        |sampling: Sampling| Ok::<_, candle::Error>(round(sampling.distribution(&logits)?));
+    assert_eq!(distribution(Sampling::ArgMax)?, [0., 1., 0., 0.]);
+    assert_eq!(
+        distribution(Sampling::All { temperature: 1. })?,
+        [0.1, 0.6, 0.2, 0.1]
+    );
+    assert_eq!(
+        distribution(Sampling::TopK {
+            k: 2,
+            temperature: 1.
+        })?,
+        [0., 0.75, 0.25, 0.]
+    );
+    assert_eq!(
+        distribution(Sampling::TopP {
+            p: 0.7,
+            temperature: 1.
+        })?,
+        [0., 0.75, 0.25, 0.]
+    );
+    assert_eq!(
+        distribution(Sampling::TopKThenTopP {
+            k: 3,
+            p: 0.5,
+            temperature: 1.
+        })?,
+        [0., 1., 0., 0.]
+    );
+    Ok(())
+}
+
+/// A model predicting the successor of the last token modulo 10, the cache of a sequence holding
+/// its tokens so that the positions can be checked. The batch sizes are recorded.
+struct SuccessorModel {
+    batch_sizes: std::sync::Arc<std::sync::Mutex<Vec<usize>>>,
+}
+
+impl SuccessorModel {
+    fn logits(token: u32) -> Vec<f32> {
+        (0..10)
+            .map(|i| if i == (token + 1) % 10 { 1. } else { 0. })
+            .collect()
+    }
+}
+
+impl candle_transformers::generation::batching::BatchedLM for SuccessorModel {
+    type Cache = Vec<u32>;
+
+    fn new_cache(&self) -> Vec<u32> {
+        vec![]
+    }
+
+    fn forward(&self, xs: &Tensor, index_pos: usize, cache: &mut Vec<u32>) -> Result<Tensor> {
+        assert_eq!(cache.len(), index_pos);
+        let tokens = xs.squeeze(0)?.to_vec1::<u32>()?;
+        cache.extend_from_slice(&tokens);
+        Tensor::new(Self::logits(tokens[tokens.len() - 1]), &Device::Cpu)?.unsqueeze(0)
+    }
+
+    fn forward_batch(
+        &self,
+        xs: &Tensor,
+        index_pos: &[usize],
+        caches: &mut [Vec<u32>],
+    ) -> Result<Tensor> {
+        let tokens = xs.squeeze(1)?.to_vec1::<u32>()?;
+        self.batch_sizes.lock().unwrap().push(tokens.len());
+        let logits = tokens
+            .iter()
+            .zip(index_pos.iter().zip(caches.iter_mut()))
+            .map(|(&token, (&index_pos, cache))| {
+                assert_eq!(cache.len(), index_pos);
+                cache.push(token);
+                Tensor::new(Self::logits(token), &Device::Cpu)
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Tensor::stack(&logits, 0)
+    }
+}
+
+#[test]
+fn continuous_batching() -> Result<()> {
+    use candle_transformers::generation::batching::{Batcher, BatchingConfig};
+    use candle_transformers::generation::events::GenerationConfig;
+    use std::sync::{Arc, Mutex};
+
+    let batch_sizes = Arc::new(Mutex::new(vec![]));
+    let model = SuccessorModel {
+        batch_sizes: batch_sizes.clone(),
+    };
+    let config = BatchingConfig::default()
+        .with_max_batch_size(2)
+        .with_max_prefills_per_step(2);
+    let mut batcher = Batcher::new(model, Device::Cpu, config);
+    let outputs: Vec<Arc<Mutex<Vec<Result<u32>>>>> =
+        (0..4).map(|_| Arc::new(Mutex::new(vec![]))).collect();
+    let requests = [
+        (vec![1, 2], GenerationConfig::new(3)),
+        (vec![5], GenerationConfig::new(6).with_stop_tokens(vec![8])),
+        (
+            vec![0, 1, 2, 3, 4],
+            GenerationConfig::new(2).with_prompt_chunk_size(Some(2)),
+        ),
+        (vec![], GenerationConfig::new(2)),
+    ];
+    for ((prompt, config), output) in requests.into_iter().zip(outputs.iter()) {
+        let output = output.clone();
+        let lp = LogitsProcessor::new(0, None, None);
+        batcher.add(prompt, lp, config, move |token| {
+            output.lock().unwrap().push(token);
+            true
+        });
+    }
+    assert_eq!((batcher.num_running(), batcher.num_waiting()), (0, 4));
+
+    // The first two requests are admitted, the other ones wait for a free slot.
+    batcher.step();
+    assert_eq!((batcher.num_running(), batcher.num_waiting()), (2, 2));
+    let mut steps = 1;
+    while !batcher.is_idle() {
+        batcher.step();
+        steps += 1;
+    }
+    let outputs: Vec<_> = outputs
+        .iter()
+        .map(|o| std::mem::take(&mut *o.lock().unwrap()))
+        .collect();
+    let tokens = |i: usize| {
+        outputs[i]
+            .iter()
+            .map(|t| t.as_ref().map_err(|e| e.to_string()).copied())
+            .collect::<std::result::Result<Vec<_>, _>>()
+    };
+    assert_eq!(tokens(0), Ok(vec![3, 4, 5]));
+    assert_eq!(tokens(1), Ok(vec![6, 7, 8]));
+    // The third request is admitted once the first two have finished.
+    assert_eq!(tokens(2), Ok(vec![5, 6]));
+    // The empty prompt is reported as an error.
+    assert!(outputs[3].len() == 1 && outputs[3][0].is_err());
+    assert_eq!(steps, 3);
+    assert_eq!(*batch_sizes.lock().unwrap(), [2, 2, 1]);
+
+    // With a token budget of 8 positions, the second sequence waits for the first one.
+    let model = SuccessorModel {
+        batch_sizes: batch_sizes.clone(),
+    };
+    batch_sizes.lock().unwrap().clear();
+    let config = BatchingConfig::default()
+        .with_max_batch_tokens(Some(8))
+        .with_max_prefills_per_step(4);
+    let mut batcher = Batcher::new(model, Device::Cpu, config);
+    for prompt in [vec![1, 2], vec![3, 4]] {
+        let lp = LogitsProcessor::new(0, None, None);
+        batcher.add(prompt, lp, GenerationConfig::new(3), |_| true);
+    }
+    batcher.step();
+    assert_eq!((batcher.num_running(), batcher.num_waiting()), (1, 1));
+    while !batcher.is_idle() {
+        batcher.step()
+    }
+    assert_eq!(*batch_sizes.lock().unwrap(), [1, 1, 1, 1]);
+
+    // Returning false from the callback cancels the request.
+    let mut batcher = Batcher::new(
+        SuccessorModel { batch_sizes },
+        Device::Cpu,
+        BatchingConfig::default(),
+    );
+    let lp = LogitsProcessor::new(0, None, None);
+    batcher.add(vec![1], lp, GenerationConfig::new(10), |_| false);
+    batcher.step();
+    assert!(batcher.is_idle());
+    Ok(())
+}
+
+#[cfg(feature = "tokio")]
+#[test]
+fn continuous_batcher_stream() -> Result<()> {
+    use candle_transformers::generation::batching::BatchingConfig;
+    use candle_transformers::generation::events::GenerationConfig;
+    use candle_transformers::generation::stream::ContinuousBatcher;
+    use tokio_stream::StreamExt;
+
+    let model = SuccessorModel {
+        batch_sizes: Default::default(),
+    };
+    let batcher = ContinuousBatcher::new(model, Device::Cpu, BatchingConfig::default());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .unwrap();
+    let (a, b) = runtime.block_on(async {
+        let lp = LogitsProcessor::new(0, None, None);
+        let a = batcher.generate(vec![1, 2, 3], lp, GenerationConfig::new(4));
+        let lp = LogitsProcessor::new(0, None, None);
+        let config = GenerationConfig::new(5).with_stop_tokens(vec![1]);
+        let b = batcher.generate(vec![8], lp, config);
+        let a = a.collect::<Result<Vec<_>>>().await;
+        let b = b.collect::<Result<Vec<_>>>().await;
+        (a, b)
+    });
+    assert_eq!(a?, [4, 5, 6, 7]);
+    assert_eq!(b?, [9, 0, 1]);
+    Ok(())
+}
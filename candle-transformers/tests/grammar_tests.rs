@@ -0,0 +1,156 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::generation::grammar::{Grammar, GrammarConstraint};
+use candle_transformers::generation::json_schema;
+
+#[test]
+fn grammar_matches() -> Result<()> {
+    let grammar = Grammar::parse(
+        r#"
+        # Simple arithmetic expressions.
+        root ::= expr
+        expr ::= term ( [-+] term )*
+        term ::= num | "(" expr ")"
+        num  ::= "-"? [0-9]+ ( "." [0-9]{1,2} )?
+        "#,
+    )?;
+    for text in ["1", "12+3", "(1-2.5)+-3", "((7))", "0.25"] {
+        assert!(grammar.matches(text), "{text}")
+    }
+    for text in ["", "1+", "(1", "1.234", "a", "1.", "1 + 2"] {
+        assert!(!grammar.matches(text), "{text}")
+    }
+
+    let grammar = Grammar::parse(
+        r#"root ::= "a"{2} "b"{1,} "c"{0,2} [^x-z]? . ("d" | "ef")* "\n\x41\u00e9\"""#,
+    )?;
+    assert!(grammar.matches("aab!dd\nAé\""));
+    assert!(grammar.matches("aabbbccwqefd\nAé\""));
+    assert!(!grammar.matches("abq\nAé\""));
+    assert!(!grammar.matches("aabccccq\nAé\""));
+    assert!(!grammar.matches("aabyz\nAé\""));
+
+    // Rules can be defined over several lines, and after being used.
+    let grammar = Grammar::parse("root ::= a\n  | b b\na ::= \"x\"\nb ::= [y]")?;
+    assert!(grammar.matches("x") && grammar.matches("yy"));
+    assert!(!grammar.matches("y") && !grammar.matches("xy"));
+    Ok(())
+}
+
+#[test]
+fn grammar_errors() {
+    for src in [
+        "a ::= \"a\"",
+        "root ::= a",
+        "root ::= \"a\"\nroot ::= \"b\"",
+        "root ::= root \"a\" | \"a\"",
+        "root ::= b\nb ::= \"c\"? root",
+        "root ::= \"a",
+        "root ::= [a-",
+        "root ::= \"a\"{3,1}",
+        "root ::= (\"a\"",
+        "root ::= \"\\q\"",
+    ] {
+        assert!(Grammar::parse(src).is_err(), "{src}")
+    }
+}
+
+fn vocab(tokens: &[&str]) -> Vec<String> {
+    tokens.iter().map(|t| t.to_string()).collect()
+}
+
+#[test]
+fn grammar_constraint() -> Result<()> {
+    let grammar = Grammar::parse(r#"root ::= "[" ( [0-9]+ ( "," [0-9]+ )* )? "]""#)?;
+    let vocab = vocab(&["</s>", "[", "]", "[]", "1", "23", "4,", ",", "a", "", "[1"]);
+    let mut constraint = GrammarConstraint::new(grammar, &vocab, &[0])?;
+    assert!(!constraint.is_accepting());
+    assert_eq!(constraint.allowed_tokens(), [1, 3, 10]);
+    constraint.accept(10)?;
+    assert_eq!(constraint.allowed_tokens(), [2, 4, 5, 6, 7]);
+    assert!(constraint.accept(8).is_err());
+    // A failed token leaves the state untouched.
+    constraint.accept(6)?;
+    assert_eq!(constraint.allowed_tokens(), [4, 5, 6]);
+    assert!(constraint.accept(0).is_err());
+    constraint.accept(5)?;
+    constraint.accept(2)?;
+    assert!(constraint.is_accepting());
+    assert_eq!(constraint.allowed_tokens(), [0]);
+    constraint.accept(0)?;
+    assert_eq!(constraint.allowed_tokens(), [0]);
+
+    constraint.reset();
+    assert_eq!(constraint.allowed_tokens(), [1, 3, 10]);
+    let logits = Tensor::new(
+        &[[1f32, 2., 3., 4., 5., 6., 7., 8., 9., 10., 11., 12.]],
+        &Device::Cpu,
+    )?;
+    let logits = constraint.apply(&logits)?;
+    let inf = f32::NEG_INFINITY;
+    assert_eq!(
+        logits.to_vec2::<f32>()?,
+        [[inf, 2., inf, 4., inf, inf, inf, inf, inf, inf, 11., inf]]
+    );
+    Ok(())
+}
+
+#[test]
+fn json_schema_grammar() -> Result<()> {
+    let schema = serde_json::json!({
+        "type": "object",
+        "properties": {
+            "args": {
+                "type": "array",
+                "items": { "type": ["integer", "null"] },
+            },
+            "name": { "const": "get_weather" },
+            "options": {
+                "properties": {
+                    "unit": { "enum": ["celsius", "fahrenheit"] },
+                    "verbose": { "type": "boolean" },
+                },
+            },
+            "tag": { "anyOf": [{ "type": "string" }, { "type": "number" }] },
+        },
+    });
+    let grammar = json_schema::to_grammar(&schema)?;
+    for text in [
+        r#"{"args": [1, null, -3], "name": "get_weather", "options": {"unit": "celsius", "verbose": true}, "tag": "a\"b"}"#,
+        r#"{"args":[],"name":"get_weather","options":{"unit":"fahrenheit","verbose":false},"tag":1.5e3}"#,
+        "{\n  \"args\": [2],\n  \"name\": \"get_weather\",\n  \"options\": {\"unit\": \"celsius\", \"verbose\": false},\n  \"tag\": -0.5\n}",
+    ] {
+        assert!(grammar.matches(text), "{text}")
+    }
+    for text in [
+        r#"{"args": [1.5], "name": "get_weather", "options": {"unit": "celsius", "verbose": true}, "tag": 1}"#,
+        r#"{"args": [], "name": "get_time", "options": {"unit": "celsius", "verbose": true}, "tag": 1}"#,
+        r#"{"args": [], "name": "get_weather", "options": {"unit": "kelvin", "verbose": true}, "tag": 1}"#,
+        r#"{"name": "get_weather", "args": [], "options": {"unit": "celsius", "verbose": true}, "tag": 1}"#,
+        r#"{"args": [], "name": "get_weather", "options": {"unit": "celsius", "verbose": true}, "tag": null}"#,
+    ] {
+        assert!(!grammar.matches(text), "{text}")
+    }
+
+    // An empty schema matches any JSON value.
+    let grammar = json_schema::to_grammar(&serde_json::json!({}))?;
+    for text in [
+        r#"{"a": [1, {"b": null}], "c": "d"}"#,
+        "[]",
+        "true",
+        "-12.5",
+        "\"x\"",
+    ] {
+        assert!(grammar.matches(text), "{text}")
+    }
+    assert!(!grammar.matches("{a: 1}"));
+
+    for schema in [
+        serde_json::json!({ "$ref": "#/definitions/a" }),
+        serde_json::json!({ "type": "date" }),
+        serde_json::json!({ "enum": [] }),
+        serde_json::json!(1),
+    ] {
+        assert!(json_schema::to_gbnf(&schema).is_err(), "{schema}")
+    }
+    Ok(())
+}
@@ -0,0 +1,104 @@
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_transformers::image_processor::{center_crop, Filter, Processor, ResizeStrategy};
+
+#[test]
+fn resampling_matrix() {
+    // Upscaling with a bilinear filter, the values match PIL.
+    let m = Filter::Bilinear.resampling_matrix(2, 4);
+    assert_eq!(m, [1., 0., 0.75, 0.25, 0.25, 0.75, 0., 1.]);
+    // Downscaling with a box filter averages the neighbouring pixels.
+    let m = Filter::Box.resampling_matrix(4, 2);
+    assert_eq!(m, [0.5, 0.5, 0., 0., 0., 0., 0.5, 0.5]);
+    let m = Filter::Nearest.resampling_matrix(4, 2);
+    assert_eq!(m, [0., 1., 0., 0., 0., 0., 0., 1.]);
+    for filter in [Filter::Bicubic, Filter::Lanczos, Filter::Hamming] {
+        let m = filter.resampling_matrix(7, 3);
+        for row in m.chunks(7) {
+            assert!((row.iter().sum::<f32>() - 1.).abs() < 1e-5)
+        }
+    }
+}
+
+#[test]
+fn resize_strategy() {
+    let s = ResizeStrategy::ShortestEdge {
+        shortest_edge: 224,
+        max_longest_edge: None,
+    };
+    assert_eq!(s.output_size((20, 30)), (224, 336));
+    assert_eq!(s.output_size((30, 20)), (336, 224));
+    let s = ResizeStrategy::ShortestEdge {
+        shortest_edge: 800,
+        max_longest_edge: Some(1000),
+    };
+    assert_eq!(s.output_size((100, 200)), (500, 1000));
+    assert_eq!(
+        ResizeStrategy::LongestEdge(64).output_size((30, 100)),
+        (19, 64)
+    );
+    assert_eq!(ResizeStrategy::Exact(8, 4).output_size((30, 100)), (8, 4));
+}
+
+#[test]
+fn clip_processor() -> Result<()> {
+    let processor = Processor::from_json(
+        r#"{
+            "crop_size": 8,
+            "do_center_crop": true,
+            "do_normalize": true,
+            "do_resize": true,
+            "image_mean": [0.5, 0.5, 0.5],
+            "image_std": [0.5, 0.25, 0.5],
+            "resample": 3,
+            "size": 8,
+            "patch_size": {"height": 4, "width": 4}
+        }"#,
+    )?;
+    assert_eq!(processor.output_size((10, 20)), (8, 8));
+    // A constant image is not changed by the resizing.
+    let img = Tensor::full(51u8, (3, 10, 20), &Device::Cpu)?;
+    let pixel_values = processor.preprocess(&img)?;
+    assert_eq!(pixel_values.dims(), [3, 8, 8]);
+    assert_eq!(pixel_values.dtype(), DType::F32);
+    let values = pixel_values.i((.., 0, 0))?.to_vec1::<f32>()?;
+    let expected = [-0.6, -1.2, -0.6];
+    for (v, e) in values.iter().zip(expected) {
+        assert!((v - e).abs() < 1e-5, "{values:?}")
+    }
+    let batch = processor.preprocess_batch(&[img.clone(), img])?;
+    assert_eq!(batch.dims(), [2, 3, 8, 8]);
+
+    let patches = processor.patchify(&pixel_values)?;
+    assert_eq!(patches.dims(), [4, 48]);
+    Ok(())
+}
+
+#[test]
+fn crop_and_patchify() -> Result<()> {
+    let img = Tensor::arange(0f32, 16., &Device::Cpu)?.reshape((1, 4, 4))?;
+    let crop = center_crop(&img, 2, 6)?;
+    assert_eq!(
+        crop.squeeze(0)?.to_vec2::<f32>()?,
+        [[0., 4., 5., 6., 7., 0.], [0., 8., 9., 10., 11., 0.]]
+    );
+
+    let processor = Processor {
+        resize: None,
+        center_crop: None,
+        rescale_factor: None,
+        normalize: None,
+        patch_size: Some((2, 2)),
+    };
+    let patches = processor.patchify(&img)?;
+    assert_eq!(
+        patches.to_vec2::<f32>()?,
+        [
+            [0., 1., 4., 5.],
+            [2., 3., 6., 7.],
+            [8., 9., 12., 13.],
+            [10., 11., 14., 15.]
+        ]
+    );
+    assert_eq!(processor.patchify(&crop)?.dims(), [3, 4]);
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use candle::quantized::{GgmlDType, QMatMul, QTensor};
+use candle::{Device, Result, Tensor};
+use candle_transformers::offload::{Prefetcher, Slot};
+
+// Each weight of a layer is filled with `10 * layer_idx + weight_idx`.
+fn layer(layer_idx: usize) -> Result<(usize, Vec<QMatMul>)> {
+    let weights = (0..2)
+        .map(|i| {
+            let v = (10 * layer_idx + i) as f32;
+            let w = Tensor::full(v, (4, 32), &Device::Cpu)?;
+            QMatMul::from_qtensor(QTensor::quantize(&w, GgmlDType::Q8_0)?)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    Ok((layer_idx, weights))
+}
+
+fn values(weights: &[QMatMul]) -> Result<Vec<f32>> {
+    weights
+        .iter()
+        .map(|w| match w {
+            QMatMul::QTensor(w) => w
+                .dequantize(&Device::Cpu)?
+                .flatten_all()?
+                .max(0)?
+                .to_scalar::<f32>(),
+            _ => candle::bail!("unexpected weight {w:?}"),
+        })
+        .collect()
+}
+
+fn weight_ptr(slot: &Slot) -> *const QTensor {
+    match &slot.weights()[0] {
+        QMatMul::QTensor(w) => std::sync::Arc::as_ptr(w),
+        _ => std::ptr::null(),
+    }
+}
+
+#[test]
+fn prefetch_ring() -> Result<()> {
+    let dev = &Device::Cpu;
+    let layers = [1, 2, 4]
+        .into_iter()
+        .map(layer)
+        .collect::<Result<Vec<_>>>()?;
+    let prefetcher = Prefetcher::new(layers, dev, 2)?;
+    assert!(prefetcher.contains(4) && !prefetcher.contains(3));
+    assert!(prefetcher.fetch(3).is_err());
+
+    let mut ptrs = vec![];
+    for _pass in 0..3 {
+        for layer_idx in [1, 2, 4] {
+            let slot = prefetcher.fetch(layer_idx)?;
+            assert_eq!(slot.layer_idx(), layer_idx);
+            assert_eq!(values(slot.weights())?, values(&layer(layer_idx)?.1)?);
+            ptrs.push(weight_ptr(&slot));
+            prefetcher.release(slot);
+        }
+    }
+    // The two slots are reused from one layer to the next.
+    assert_ne!(ptrs[0], ptrs[1]);
+    for (i, ptr) in ptrs.iter().enumerate() {
+        assert_eq!(*ptr, ptrs[i % 2], "{i}");
+    }
+
+    // Running the layers out of order, or dropping the slots, still uploads the right weights.
+    for layer_idx in [4, 2, 2, 1, 4] {
+        let slot = prefetcher.fetch(layer_idx)?;
+        assert_eq!(values(slot.weights())?, values(&layer(layer_idx)?.1)?);
+    }
+    assert!(Prefetcher::new(vec![layer(0)?], dev, 0).is_err());
+    Ok(())
+}
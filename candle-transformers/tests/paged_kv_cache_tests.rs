@@ -0,0 +1,116 @@
+use candle::{DType, Device, IndexOp, Result, Tensor};
+use candle_transformers::paged_kv_cache::{BlockAllocator, PagedKvCache, PagedKvCacheConfig};
+
+fn cache(num_blocks: usize) -> Result<PagedKvCache> {
+    let config = PagedKvCacheConfig {
+        num_layers: 2,
+        num_blocks,
+        block_size: 4,
+        num_kv_heads: 2,
+        head_dim: 3,
+    };
+    PagedKvCache::new(config, DType::F32, &Device::Cpu)
+}
+
+/// Keys of shape (1, 2, seq_len, 3) whose values encode the position and the layer.
+fn keys(start: usize, seq_len: usize, layer: usize) -> Result<Tensor> {
+    let pos = Tensor::arange(start as f32, (start + seq_len) as f32, &Device::Cpu)?;
+    pos.reshape((1, 1, seq_len, 1))?
+        .broadcast_as((1, 2, seq_len, 3))?
+        .affine(1., 100. * layer as f64)
+}
+
+fn vec3(xs: &Tensor) -> Result<Vec<Vec<Vec<f32>>>> {
+    xs.squeeze(0)?.to_vec3::<f32>()
+}
+
+#[test]
+fn block_allocator() -> Result<()> {
+    let mut allocator = BlockAllocator::new(2);
+    assert_eq!(allocator.allocate(), Some(0));
+    assert_eq!(allocator.allocate(), Some(1));
+    assert_eq!(allocator.allocate(), None);
+    allocator.share(1)?;
+    assert_eq!(allocator.ref_count(1), 2);
+    allocator.release(1)?;
+    assert_eq!(allocator.num_free(), 0);
+    allocator.release(1)?;
+    assert_eq!(allocator.num_free(), 1);
+    assert!(allocator.release(1).is_err());
+    assert!(allocator.share(1).is_err());
+    Ok(())
+}
+
+#[test]
+fn paged_kv_cache() -> Result<()> {
+    let mut cache = cache(4)?;
+    cache.add_sequence(0)?;
+    cache.add_sequence(1)?;
+    assert!(cache.add_sequence(1).is_err());
+
+    // Interleaved sequences get interleaved blocks.
+    assert_eq!(cache.append_slots(0, 3)?, [0, 1, 2]);
+    assert_eq!(cache.append_slots(1, 5)?, [4, 5, 6, 7, 8]);
+    assert_eq!(cache.append_slots(0, 2)?, [3, 12]);
+    assert_eq!(cache.block_table(0).unwrap().blocks(), [0, 3]);
+    assert_eq!(cache.num_free_blocks(), 0);
+    assert_eq!(cache.num_new_blocks(0, 3)?, 0);
+    assert!(!cache.can_append(0, 4)?);
+    assert!(cache.append_slots(0, 4).is_err());
+    assert_eq!(cache.block_table(0).unwrap().len(), 5);
+
+    for layer in 0..2 {
+        // The prompt of the first sequence is written in two steps.
+        cache.write(
+            layer,
+            &cache.slots(0)?[..3],
+            &keys(0, 3, layer)?.squeeze(0)?.transpose(0, 1)?,
+            &keys(0, 3, layer)?.squeeze(0)?.transpose(0, 1)?,
+        )?;
+        let (k, v) = cache.append(layer, 0, &keys(3, 2, layer)?, &keys(3, 2, layer)?)?;
+        assert_eq!(k.dims(), &[1, 2, 5, 3]);
+        assert_eq!(vec3(&k)?, vec3(&keys(0, 5, layer)?)?);
+        assert_eq!(vec3(&v)?, vec3(&keys(0, 5, layer)?)?);
+        let (k, _) = cache.append(layer, 1, &keys(0, 5, layer)?, &keys(0, 5, layer)?)?;
+        assert_eq!(vec3(&k)?, vec3(&keys(0, 5, layer)?)?);
+    }
+
+    cache.remove_sequence(1)?;
+    assert_eq!(cache.num_free_blocks(), 2);
+    cache.truncate(0, 3)?;
+    assert_eq!(cache.block_table(0).unwrap().blocks(), [0]);
+    assert_eq!(cache.num_free_blocks(), 3);
+    assert!(cache.remove_sequence(1).is_err());
+    Ok(())
+}
+
+#[test]
+fn paged_kv_cache_fork() -> Result<()> {
+    let mut cache = cache(4)?;
+    cache.add_sequence(0)?;
+    cache.append_slots(0, 6)?;
+    for layer in 0..2 {
+        cache.append(layer, 0, &keys(0, 6, layer)?, &keys(0, 6, layer)?)?;
+    }
+    cache.fork_sequence(0, 1)?;
+    assert_eq!(cache.num_free_blocks(), 2);
+    // The partially filled last block is shared so it is copied before being written to.
+    assert_eq!(cache.num_new_blocks(1, 1)?, 1);
+    assert_eq!(cache.append_slots(1, 1)?, [10]);
+    assert_eq!(cache.block_table(1).unwrap().blocks(), [0, 2]);
+    // The block is not shared anymore, the first sequence can write to it.
+    assert_eq!(cache.num_new_blocks(0, 1)?, 0);
+    assert_eq!(cache.append_slots(0, 1)?, [6]);
+    for layer in 0..2 {
+        let (k0, _) = cache.append(layer, 0, &keys(6, 1, layer)?, &keys(6, 1, layer)?)?;
+        let new_key = keys(0, 1, layer)?.affine(1., 42.)?;
+        let (k1, _) = cache.append(layer, 1, &new_key, &new_key)?;
+        assert_eq!(vec3(&k0)?, vec3(&keys(0, 7, layer)?)?);
+        assert_eq!(vec3(&k1.i((.., .., ..6))?)?, vec3(&keys(0, 6, layer)?)?);
+        assert_eq!(vec3(&k1.i((.., .., 6..))?)?, vec3(&new_key)?);
+    }
+    cache.remove_sequence(0)?;
+    cache.remove_sequence(1)?;
+    assert_eq!(cache.num_free_blocks(), 4);
+    Ok(())
+}
@@ -0,0 +1,95 @@
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::phi3::{Config, Model, RopeScalingType, RotaryEmbedding};
+
+fn config(rope_scaling: &str) -> Result<Config> {
+    let json = format!(
+        r#"{{
+            "vocab_size": 16,
+            "hidden_act": "silu",
+            "hidden_size": 8,
+            "intermediate_size": 12,
+            "num_hidden_layers": 1,
+            "num_attention_heads": 2,
+            "num_key_value_heads": 1,
+            "rms_norm_eps": 1e-5,
+            "rope_theta": 10000.0,
+            "bos_token_id": 1,
+            "eos_token_id": 2,
+            "max_position_embeddings": 16,
+            "original_max_position_embeddings": 4,
+            "rope_scaling": {rope_scaling}
+        }}"#
+    );
+    serde_json::from_str(&json).map_err(|e| candle::Error::Msg(e.to_string()))
+}
+
+#[test]
+fn longrope_config() -> Result<()> {
+    let cfg = config("null")?;
+    assert!(cfg.rope_scaling.is_none());
+    // The first 128k models used the "su" name.
+    for name in ["longrope", "su"] {
+        let scaling = format!(
+            r#"{{ "type": "{name}", "short_factor": [1.0, 1.5], "long_factor": [2.0, 4.0] }}"#
+        );
+        let cfg = config(&scaling)?;
+        let scaling = cfg.rope_scaling.unwrap();
+        assert_eq!(scaling.scaling_type, RopeScalingType::LongRope);
+        assert_eq!(scaling.long_factor, [2.0, 4.0]);
+    }
+    assert!(config(r#"{ "type": "yarn", "short_factor": [], "long_factor": [] }"#).is_err());
+    Ok(())
+}
+
+#[test]
+fn longrope_embeddings() -> Result<()> {
+    let dev = &Device::Cpu;
+    let plain = RotaryEmbedding::new(DType::F32, &config("null")?, dev)?;
+    let cfg =
+        config(r#"{ "type": "longrope", "short_factor": [1.0, 1.0], "long_factor": [2.0, 2.0] }"#)?;
+    let longrope = RotaryEmbedding::new(DType::F32, &cfg, dev)?;
+    let q = Tensor::randn(0f32, 1., (1, 2, 1, 4), dev)?;
+    let k = Tensor::randn(0f32, 1., (1, 1, 1, 4), dev)?;
+    // The long factors halve the frequencies past the original context length of 4, and the
+    // embeddings are scaled by sqrt(1 + ln(16 / 4) / ln(4)).
+    let (q_long, k_long) = longrope.apply_rotary_emb_qkv(&q, &k, 6)?;
+    let (q_plain, k_plain) = plain.apply_rotary_emb_qkv(&q, &k, 3)?;
+    let scale = 2f64.sqrt();
+    let diff = (q_long - (&q_plain * scale)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+    let diff = (k_long - (&k_plain * scale)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+    // Within the original context length, the short factors are used.
+    let (q_short, _) = longrope.apply_rotary_emb_qkv(&q, &k, 3)?;
+    let diff = (q_short - (&q_plain * scale)?)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+    Ok(())
+}
+
+#[test]
+fn longrope_model() -> Result<()> {
+    let dev = &Device::Cpu;
+    let cfg = config(r#"{ "type": "su", "short_factor": [1.0, 1.5], "long_factor": [2.0, 4.0] }"#)?;
+    let varmap = VarMap::new();
+    let vb = VarBuilder::from_varmap(&varmap, DType::F32, dev);
+    let mut model = Model::new(&cfg, vb)?;
+    let input = Tensor::new(&[[1u32, 5, 3, 7, 9, 2]], dev)?;
+    let logits = model.forward(&input, 0)?;
+    assert_eq!(logits.dims(), [1, 1, 16]);
+    let logits = model.forward(&Tensor::new(&[[4u32]], dev)?, 6)?;
+    assert_eq!(logits.dims(), [1, 1, 16]);
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::pipeline_parallel::{parse_device, run_stages, DeviceMap};
+
+#[test]
+fn device_map() -> Result<()> {
+    let cpu = &Device::Cpu;
+    let map = DeviceMap::parse("0-3:cpu, 6:cpu", cpu)?;
+    assert!(map.device(2).is_cpu());
+    assert!(map.device(10).is_cpu());
+    // All the layers are on the same device.
+    let stages = map.stages(8);
+    assert_eq!(stages.len(), 1);
+    assert_eq!(stages[0].0, 0..8);
+    assert!(DeviceMap::new(cpu).stages(0).is_empty());
+
+    assert!(parse_device("cpu")?.is_cpu());
+    assert!(parse_device("gpu:0").is_err());
+    assert!(parse_device("cuda:x").is_err());
+    for spec in ["0-3", "3-1:cpu", "a-3:cpu", "0-3:tpu"] {
+        assert!(DeviceMap::parse(spec, cpu).is_err(), "{spec}");
+    }
+    Ok(())
+}
+
+#[test]
+fn pipeline_stages() -> Result<()> {
+    let dev = &Device::Cpu;
+    // Each stage records the micro batches it has processed, in order.
+    let mut seen = vec![vec![]; 3];
+    let stages = seen
+        .iter_mut()
+        .enumerate()
+        .map(|(stage_idx, seen)| {
+            move |idx: usize, xs: Tensor| -> Result<Tensor> {
+                seen.push(idx);
+                (xs * 10.)? + stage_idx as f64
+            }
+        })
+        .collect::<Vec<_>>();
+    let inputs = (0..5)
+        .map(|i| Tensor::new(&[i as f32], dev))
+        .collect::<Result<Vec<_>>>()?;
+    let outputs = run_stages(stages, inputs)?;
+    let outputs = outputs
+        .iter()
+        .map(|t| t.to_vec1::<f32>())
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        outputs,
+        [[12.], [1012.], [2012.], [3012.], [4012.]].map(|v| v.to_vec())
+    );
+    for seen in seen.iter() {
+        assert_eq!(seen, &[0, 1, 2, 3, 4]);
+    }
+
+    // The first error stops the pipeline.
+    let stages = (0..2)
+        .map(|stage_idx| {
+            move |idx: usize, v: u32| -> Result<u32> {
+                if stage_idx == 1 && idx == 2 {
+                    candle::bail!("stage {stage_idx} failed on {idx}")
+                }
+                Ok(v + 1)
+            }
+        })
+        .collect::<Vec<_>>();
+    let err = run_stages(stages, (0..5).collect()).unwrap_err();
+    assert!(err.to_string().contains("stage 1 failed on 2"), "{err}");
+    // Without stages the inputs are returned as is.
+    let no_stages = Vec::<fn(usize, u32) -> Result<u32>>::new();
+    assert_eq!(run_stages(no_stages, vec![1, 2])?, [1, 2]);
+    Ok(())
+}
@@ -0,0 +1,56 @@
+use candle::{DType, Device, Result, Tensor};
+use candle_transformers::prompt_cache;
+
+#[test]
+fn save_and_load() -> Result<()> {
+    let device = Device::Cpu;
+    let path = std::env::temp_dir().join(format!(
+        "candle-prompt-cache-{}.safetensors",
+        std::process::id()
+    ));
+    let tokens = [1u32, 15043, 3186];
+    let layers = (0..2)
+        .map(|i| {
+            let k = Tensor::arange(0f32, 24., &device)?.reshape((1, 2, 3, 4))?;
+            let v = (&k + i as f64)?.to_dtype(DType::F16)?;
+            Ok(Some((k, v)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+    prompt_cache::save(&path, &layers, &tokens)?;
+    let (loaded, loaded_tokens) = prompt_cache::load(&path, &device)?;
+    std::fs::remove_file(&path)?;
+    assert_eq!(loaded_tokens, tokens);
+    assert_eq!(loaded.len(), 2);
+    for (kv, loaded) in layers.iter().zip(loaded.iter()) {
+        let ((k, v), (loaded_k, loaded_v)) = (kv.as_ref().unwrap(), loaded.as_ref().unwrap());
+        assert_eq!(loaded_v.dtype(), DType::F16);
+        assert_eq!(
+            k.flatten_all()?.to_vec1::<f32>()?,
+            loaded_k.flatten_all()?.to_vec1::<f32>()?
+        );
+        assert_eq!(
+            v.to_dtype(DType::F32)?.flatten_all()?.to_vec1::<f32>()?,
+            loaded_v
+                .to_dtype(DType::F32)?
+                .flatten_all()?
+                .to_vec1::<f32>()?
+        );
+    }
+
+    // The layers must have been computed for the tokens.
+    assert!(prompt_cache::save(&path, &layers, &tokens[..2]).is_err());
+    assert!(prompt_cache::save(&path, &[layers[0].clone(), None], &tokens).is_err());
+    assert!(prompt_cache::save(&path, &[], &tokens).is_err());
+    Ok(())
+}
+
+#[test]
+fn reusable_len() {
+    assert_eq!(prompt_cache::reusable_len(&[1, 2, 3], &[1, 2, 3, 4, 5]), 3);
+    assert_eq!(prompt_cache::reusable_len(&[1, 2, 3], &[1, 2, 4]), 2);
+    // The last token of the prompt is always processed.
+    assert_eq!(prompt_cache::reusable_len(&[1, 2, 3], &[1, 2, 3]), 2);
+    assert_eq!(prompt_cache::reusable_len(&[1, 2, 3], &[1, 2]), 1);
+    assert_eq!(prompt_cache::reusable_len(&[1, 2, 3], &[4]), 0);
+    assert_eq!(prompt_cache::reusable_len(&[], &[]), 0);
+}
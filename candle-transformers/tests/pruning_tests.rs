@@ -0,0 +1,99 @@
+use candle::{DType, Device, Result, Tensor};
+use candle_nn::calibration::Calibrator;
+use candle_nn::{VarBuilder, VarMap};
+use candle_transformers::models::llama::{self, Cache, Config, Llama, PruningTarget};
+
+fn tiny_config() -> Config {
+    Config {
+        hidden_size: 16,
+        intermediate_size: 32,
+        vocab_size: 50,
+        num_hidden_layers: 2,
+        num_attention_heads: 4,
+        num_key_value_heads: 2,
+        head_dim: 4,
+        rms_norm_eps: 1e-5,
+        rope_theta: 10_000.,
+        max_position_embeddings: 64,
+        ..Config::config_7b_v2(false)
+    }
+}
+
+fn logits(model: &Llama, cfg: &Config, tokens: &Tensor) -> Result<Tensor> {
+    let mut cache = Cache::new(false, DType::F32, cfg, &Device::Cpu)?;
+    model.forward(tokens, 0, &mut cache)
+}
+
+#[test]
+fn llama_pruning() -> Result<()> {
+    let dev = &Device::Cpu;
+    let cfg = tiny_config();
+    let varmap = VarMap::new();
+    let model = Llama::load(VarBuilder::from_varmap(&varmap, DType::F32, dev), &cfg)?;
+    let tensors = varmap
+        .data()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.clone(), v.as_tensor().clone()))
+        .collect::<std::collections::HashMap<_, _>>();
+    let tokens = Tensor::new(&[[1u32, 7, 3, 12, 5]], dev)?;
+    let expected = logits(&model, &cfg, &tokens)?;
+
+    // Keeping all the channels leaves the model unchanged.
+    let target = PruningTarget {
+        intermediate_size: 32,
+        num_key_value_heads: 2,
+    };
+    let pruned = llama::prune(&tensors, &cfg, target, None)?;
+    let vb = VarBuilder::from_tensors(pruned.tensors.clone(), DType::F32, dev);
+    let model = Llama::load(vb, &pruned.config)?;
+    let diff = (logits(&model, &pruned.config, &tokens)? - &expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-5, "{diff}");
+
+    // Activation based pruning to a smaller model.
+    let mut calibrator = Calibrator::new();
+    let model = Llama::load(VarBuilder::from_varmap(&varmap, DType::F32, dev), &cfg)?;
+    let mut cache = Cache::new(false, DType::F32, &cfg, dev)?;
+    model.forward_calibrate(&tokens, 0, &mut cache, &mut calibrator)?;
+    assert_eq!(calibrator.names().len(), 4);
+    assert!(calibrator.stats("model.layers.1.mlp.down_proj").is_some());
+    let target = PruningTarget {
+        intermediate_size: 12,
+        num_key_value_heads: 1,
+    };
+    let pruned = llama::prune(&tensors, &cfg, target, Some(&calibrator))?;
+    assert_eq!(pruned.config.num_attention_heads, 2);
+    assert_eq!(pruned.config.head_dim, 4);
+    assert_eq!(
+        pruned.tensors["model.layers.0.self_attn.q_proj.weight"].dims(),
+        [8, 16]
+    );
+    assert_eq!(
+        pruned.tensors["model.layers.0.mlp.down_proj.weight"].dims(),
+        [16, 12]
+    );
+    // The pruned model can be fine-tuned.
+    let varmap = VarMap::from_tensors(pruned.tensors.iter())?;
+    let model = Llama::load(
+        VarBuilder::from_varmap(&varmap, DType::F32, dev),
+        &pruned.config,
+    )?;
+    assert_eq!(logits(&model, &pruned.config, &tokens)?.dims(), [1, 50]);
+
+    let mut json = serde_json::json!({"hidden_size": 16, "intermediate_size": 32});
+    pruned.update_config_json(&mut json)?;
+    assert_eq!(json["intermediate_size"], 12);
+    assert_eq!(json["head_dim"], 4);
+
+    let target = PruningTarget {
+        intermediate_size: 64,
+        num_key_value_heads: 1,
+    };
+    assert!(llama::prune(&tensors, &cfg, target, None).is_err());
+    Ok(())
+}
@@ -0,0 +1,134 @@
+use candle::quantized::gguf_file::{self, Value};
+use candle::quantized::GgmlDType;
+use candle::{DType, Device, Result, Tensor};
+use candle_transformers::models::llama::{Cache, Llama, LlamaConfig};
+use candle_transformers::models::quantized_llama::ModelWeights;
+use candle_transformers::quantize::{convert_llama, llama_tensor_name, Quantizer};
+use std::collections::HashMap;
+
+#[test]
+fn tensor_names() {
+    let name = |n: &str| llama_tensor_name(n);
+    assert_eq!(
+        name("model.embed_tokens.weight").unwrap(),
+        "token_embd.weight"
+    );
+    assert_eq!(name("lm_head.weight").unwrap(), "output.weight");
+    assert_eq!(
+        name("model.layers.12.mlp.down_proj.weight").unwrap(),
+        "blk.12.ffn_down.weight"
+    );
+    assert_eq!(
+        name("model.layers.0.post_attention_layernorm.weight").unwrap(),
+        "blk.0.ffn_norm.weight"
+    );
+    assert!(name("model.layers.0.self_attn.rotary_emb.inv_freq").is_none());
+    assert!(name("model.layers.x.mlp.up_proj.weight").is_none());
+
+    let quantizer = Quantizer::new(GgmlDType::Q4K).with_output_dtype(GgmlDType::Q8_0);
+    assert_eq!(
+        quantizer.dtype_for("output.weight", &[16, 512]),
+        GgmlDType::Q8_0
+    );
+    // The rows do not split in blocks of 256 values.
+    assert_eq!(
+        quantizer.dtype_for("blk.0.attn_q.weight", &[8, 96]),
+        GgmlDType::Q8_0
+    );
+    assert_eq!(
+        quantizer.dtype_for("blk.0.attn_q.weight", &[8, 12]),
+        GgmlDType::F16
+    );
+    assert_eq!(
+        quantizer.dtype_for("rope_freqs.weight", &[4]),
+        GgmlDType::F32
+    );
+}
+
+#[test]
+fn convert_tiny_llama() -> Result<()> {
+    let dev = &Device::Cpu;
+    // Two query heads sharing a single kv head, with tied embeddings.
+    let cfg: LlamaConfig = serde_json::from_str(
+        r#"{
+            "hidden_size": 8,
+            "intermediate_size": 12,
+            "vocab_size": 16,
+            "num_hidden_layers": 2,
+            "num_attention_heads": 2,
+            "num_key_value_heads": 1,
+            "rms_norm_eps": 1e-5,
+            "rope_theta": 10000.0,
+            "bos_token_id": 1,
+            "eos_token_id": [2, 3],
+            "max_position_embeddings": 64,
+            "tie_word_embeddings": true
+        }"#,
+    )
+    .map_err(candle::Error::wrap)?;
+    let w = |shape: (usize, usize)| Tensor::randn(0f32, 0.3, shape, dev);
+    let mut tensors = HashMap::new();
+    tensors.insert("model.embed_tokens.weight".to_string(), w((16, 8))?);
+    tensors.insert(
+        "model.norm.weight".to_string(),
+        Tensor::ones(8, DType::F32, dev)?,
+    );
+    for layer_idx in 0..2 {
+        let p = format!("model.layers.{layer_idx}");
+        let ones = Tensor::ones(8, DType::F32, dev)?;
+        for (name, shape) in [
+            ("self_attn.q_proj", (8, 8)),
+            ("self_attn.k_proj", (4, 8)),
+            ("self_attn.v_proj", (4, 8)),
+            ("self_attn.o_proj", (8, 8)),
+            ("mlp.gate_proj", (12, 8)),
+            ("mlp.up_proj", (12, 8)),
+            ("mlp.down_proj", (8, 12)),
+        ] {
+            tensors.insert(format!("{p}.{name}.weight"), w(shape)?);
+        }
+        tensors.insert(format!("{p}.input_layernorm.weight"), ones.clone());
+        tensors.insert(format!("{p}.post_attention_layernorm.weight"), ones);
+    }
+
+    let quantizer = Quantizer::new(GgmlDType::F32).with_output_dtype(GgmlDType::F32);
+    let names = tensors.keys().cloned().collect::<Vec<_>>();
+    let converted = convert_llama(&cfg, &names, |n| Ok(tensors[n].clone()), &quantizer)?;
+    assert_eq!(converted.tensors.len(), names.len() + 1);
+    let mut buffer = std::io::Cursor::new(vec![]);
+    converted.write(&mut buffer)?;
+    buffer.set_position(0);
+    let ct = gguf_file::Content::read(&mut buffer)?;
+    assert_eq!(ct.metadata["llama.attention.head_count_kv"], Value::U32(1));
+    assert_eq!(ct.metadata["tokenizer.ggml.eos_token_id"], Value::U32(2));
+    let mut quantized = ModelWeights::from_gguf(ct, &mut buffer, dev)?;
+
+    let llama_cfg = cfg.clone().into_config(false);
+    let vb = candle_nn::VarBuilder::from_tensors(tensors, DType::F32, dev);
+    let llama = Llama::load(vb, &llama_cfg)?;
+    let mut cache = Cache::new(true, DType::F32, &llama_cfg, dev)?;
+    // Once the query and key projections are permuted, both models compute the same logits.
+    let prompt = Tensor::new(&[[1u32, 5, 7, 3, 9]], dev)?;
+    let expected = llama.forward(&prompt, 0, &mut cache)?;
+    let logits = quantized.forward(&prompt, 0)?;
+    let diff = (logits - &expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-4, "{diff}");
+    let next = Tensor::new(&[[4u32]], dev)?;
+    let expected = llama.forward(&next, 5, &mut cache)?;
+    let logits = quantized.forward(&next, 5)?;
+    let diff = (logits - &expected)?
+        .abs()?
+        .flatten_all()?
+        .max(0)?
+        .to_scalar::<f32>()?;
+    assert!(diff < 1e-4, "{diff}");
+
+    let unknown = ["model.layers.0.mlp.unknown.weight".to_string()];
+    let load = |_: &str| -> Result<Tensor> { candle::bail!("not loaded") };
+    assert!(convert_llama(&cfg, &unknown, load, &quantizer).is_err());
+    Ok(())
+}
@@ -0,0 +1,499 @@
+use candle::quantized::gguf_file::{self, Value};
+use candle::quantized::{GgmlDType, QTensor};
+use candle::{Device, Result, Tensor};
+use candle_transformers::models::quantized_auto::{
+    Architecture, MemoryReport, ModelInfo, ModelWeights,
+};
+
+fn write_gguf(metadata: &[(&str, Value)], tensors: &[(&str, Tensor)]) -> Result<Vec<u8>> {
+    let metadata = metadata.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    let tensors = tensors
+        .iter()
+        .map(|(k, v)| Ok((*k, QTensor::quantize(v, GgmlDType::F32)?)))
+        .collect::<Result<Vec<_>>>()?;
+    let tensors = tensors.iter().map(|(k, v)| (*k, v)).collect::<Vec<_>>();
+    let mut buffer = std::io::Cursor::new(vec![]);
+    gguf_file::write(&mut buffer, &metadata, &tensors)?;
+    Ok(buffer.into_inner())
+}
+
+#[test]
+fn detect_architecture() -> Result<()> {
+    let arch = |metadata: &[(&str, Value)]| -> Result<Architecture> {
+        let data = write_gguf(metadata, &[])?;
+        let ct = gguf_file::Content::read(&mut std::io::Cursor::new(data))?;
+        Architecture::from_gguf(&ct)
+    };
+    let general = |arch: &str| ("general.architecture", Value::String(arch.to_string()));
+    assert_eq!(arch(&[general("llama")])?, Architecture::Llama);
+    assert_eq!(
+        arch(&[general("llama"), ("llama.expert_count", Value::U32(8))])?,
+        Architecture::Mixtral
+    );
+    assert_eq!(arch(&[general("phi2")])?, Architecture::Phi2);
+    assert_eq!(arch(&[general("phi3")])?, Architecture::Phi3);
+    assert_eq!(arch(&[general("qwen2")])?, Architecture::Qwen2);
+    assert_eq!(arch(&[general("starcoder2")])?, Architecture::StarCoder2);
+    assert!(arch(&[general("rwkv")]).is_err());
+    assert!(arch(&[]).is_err());
+    Ok(())
+}
+
+const VOCAB: usize = 16;
+
+/// A single layer llama model with an embedding size of 8 and all the weights in f32.
+fn tiny_llama(dev: &Device) -> Result<Vec<u8>> {
+    let (vocab, dim, hidden) = (VOCAB, 8, 12);
+    let w = |shape: (usize, usize)| Tensor::randn(0f32, 0.1, shape, dev);
+    let ones = Tensor::ones(dim, candle::DType::F32, dev)?;
+    let metadata = [
+        ("general.architecture", Value::String("llama".to_string())),
+        ("general.name", Value::String("tiny".to_string())),
+        ("llama.context_length", Value::U32(64)),
+        ("llama.attention.head_count", Value::U32(2)),
+        ("llama.attention.head_count_kv", Value::U32(2)),
+        ("llama.block_count", Value::U32(1)),
+        ("llama.embedding_length", Value::U32(dim as u32)),
+        ("llama.rope.dimension_count", Value::U32(4)),
+        ("llama.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+        ("tokenizer.ggml.eos_token_id", Value::U32(2)),
+    ];
+    let tensors = [
+        ("token_embd.weight", w((vocab, dim))?),
+        ("output_norm.weight", ones.clone()),
+        ("output.weight", w((vocab, dim))?),
+        ("blk.0.attn_q.weight", w((dim, dim))?),
+        ("blk.0.attn_k.weight", w((dim, dim))?),
+        ("blk.0.attn_v.weight", w((dim, dim))?),
+        ("blk.0.attn_output.weight", w((dim, dim))?),
+        ("blk.0.ffn_gate.weight", w((hidden, dim))?),
+        ("blk.0.ffn_up.weight", w((hidden, dim))?),
+        ("blk.0.ffn_down.weight", w((dim, hidden))?),
+        ("blk.0.attn_norm.weight", ones.clone()),
+        ("blk.0.ffn_norm.weight", ones.clone()),
+    ];
+    write_gguf(&metadata, &tensors)
+}
+
+#[test]
+fn load_llama() -> Result<()> {
+    let dev = &Device::Cpu;
+    let mut reader = std::io::Cursor::new(tiny_llama(dev)?);
+    let ct = gguf_file::Content::read(&mut reader)?;
+    let info = ModelInfo::from_gguf(&ct)?;
+    assert_eq!(info.architecture, Architecture::Llama);
+    assert_eq!(info.name.as_deref(), Some("tiny"));
+    assert_eq!(info.context_length, Some(64));
+    assert_eq!(info.eos_token_id, Some(2));
+    assert_eq!(info.bos_token_id, None);
+    assert_eq!(info.stop_token_ids(), [2]);
+
+    let mut model = ModelWeights::from_gguf(ct, &mut reader, dev)?;
+    match &model {
+        ModelWeights::Llama(model) => assert_eq!(model.max_seq_len(), 64),
+        _ => panic!("expected a llama model"),
+    }
+    let input = Tensor::new(&[[1u32, 5, 3]], dev)?;
+    let logits = model.forward(&input, 0)?;
+    assert_eq!(logits.dims(), [1, VOCAB]);
+    let input = Tensor::new(&[[7u32]], dev)?;
+    let logits = model.forward(&input, 3)?;
+    assert_eq!(logits.dims(), [1, VOCAB]);
+    Ok(())
+}
+
+#[test]
+fn llama3_stop_tokens() -> Result<()> {
+    let tokens = [
+        "<|begin_of_text|>",
+        "<|end_of_text|>",
+        "hello",
+        "<|eot_id|>",
+    ]
+    .iter()
+    .map(|t| Value::String(t.to_string()))
+    .collect::<Vec<_>>();
+    let info = |metadata: &[(&str, Value)]| -> Result<ModelInfo> {
+        let data = write_gguf(metadata, &[])?;
+        let ct = gguf_file::Content::read(&mut std::io::Cursor::new(data))?;
+        ModelInfo::from_gguf(&ct)
+    };
+    let mut metadata = vec![
+        ("general.architecture", Value::String("llama".to_string())),
+        ("tokenizer.ggml.model", Value::String("gpt2".to_string())),
+        ("tokenizer.ggml.eos_token_id", Value::U32(1)),
+        ("tokenizer.ggml.tokens", Value::Array(tokens)),
+    ];
+    // The end of turn token is found in the vocabulary when its id is missing.
+    let without_id = info(&metadata)?;
+    assert_eq!(without_id.tokenizer_model.as_deref(), Some("gpt2"));
+    assert_eq!(without_id.eot_token_id, Some(3));
+    assert_eq!(without_id.stop_token_ids(), [1, 3]);
+    metadata.push(("tokenizer.ggml.eot_token_id", Value::U32(1)));
+    assert_eq!(info(&metadata)?.stop_token_ids(), [1]);
+    Ok(())
+}
+
+/// A single layer starcoder2 model with grouped query attention, tied embeddings, and a sliding
+/// window of 2 tokens.
+fn tiny_starcoder2(dev: &Device) -> Result<Vec<u8>> {
+    let (vocab, dim, hidden, kv_dim) = (VOCAB, 8, 12, 4);
+    let w = |shape: (usize, usize)| Tensor::randn(0f32, 0.1, shape, dev);
+    let b = |size: usize| Tensor::randn(0f32, 0.1, size, dev);
+    let ones = Tensor::ones(dim, candle::DType::F32, dev)?;
+    let metadata = [
+        (
+            "general.architecture",
+            Value::String("starcoder2".to_string()),
+        ),
+        ("starcoder2.context_length", Value::U32(64)),
+        ("starcoder2.attention.head_count", Value::U32(2)),
+        ("starcoder2.attention.head_count_kv", Value::U32(1)),
+        ("starcoder2.attention.sliding_window", Value::U32(2)),
+        ("starcoder2.block_count", Value::U32(1)),
+        ("starcoder2.embedding_length", Value::U32(dim as u32)),
+        ("starcoder2.feed_forward_length", Value::U32(hidden as u32)),
+        ("starcoder2.attention.layer_norm_epsilon", Value::F32(1e-5)),
+    ];
+    let tensors = [
+        ("token_embd.weight", w((vocab, dim))?),
+        ("output_norm.weight", ones.clone()),
+        ("output_norm.bias", b(dim)?),
+        ("blk.0.attn_q.weight", w((dim, dim))?),
+        ("blk.0.attn_q.bias", b(dim)?),
+        ("blk.0.attn_k.weight", w((kv_dim, dim))?),
+        ("blk.0.attn_k.bias", b(kv_dim)?),
+        ("blk.0.attn_v.weight", w((kv_dim, dim))?),
+        ("blk.0.attn_v.bias", b(kv_dim)?),
+        ("blk.0.attn_output.weight", w((dim, dim))?),
+        ("blk.0.attn_output.bias", b(dim)?),
+        ("blk.0.ffn_up.weight", w((hidden, dim))?),
+        ("blk.0.ffn_up.bias", b(hidden)?),
+        ("blk.0.ffn_down.weight", w((dim, hidden))?),
+        ("blk.0.ffn_down.bias", b(dim)?),
+        ("blk.0.attn_norm.weight", ones.clone()),
+        ("blk.0.attn_norm.bias", b(dim)?),
+        ("blk.0.ffn_norm.weight", ones.clone()),
+        ("blk.0.ffn_norm.bias", b(dim)?),
+    ];
+    write_gguf(&metadata, &tensors)
+}
+
+#[test]
+fn starcoder2_sliding_window() -> Result<()> {
+    let dev = &Device::Cpu;
+    let data = tiny_starcoder2(dev)?;
+    let prompt = [1u32, 5, 3, 7, 9, 2, 4];
+    let logits = |chunk_size: usize| -> Result<Vec<f32>> {
+        let mut reader = std::io::Cursor::new(&data);
+        let ct = gguf_file::Content::read(&mut reader)?;
+        let mut model = ModelWeights::from_gguf(ct, &mut reader, dev)?;
+        assert!(matches!(model, ModelWeights::StarCoder2(_)));
+        let logits = candle_transformers::generation::forward_chunked(
+            &prompt,
+            chunk_size,
+            0,
+            dev,
+            |input, pos| model.forward(input, pos),
+        )?;
+        logits.squeeze(0)?.to_vec1::<f32>()
+    };
+    // The kv cache is trimmed to the sliding window, processing the prompt in chunks must give
+    // the same results as the masked full prompt.
+    let expected = logits(prompt.len())?;
+    assert_eq!(expected.len(), VOCAB);
+    for chunk_size in [1, 2, 3] {
+        for (e, v) in expected.iter().zip(logits(chunk_size)?.iter()) {
+            assert!((e - v).abs() < 1e-5, "{chunk_size} {e} {v}");
+        }
+    }
+    Ok(())
+}
+
+/// A single layer phi-3 model with grouped query attention and the long rope scaling of the 128k
+/// models, the original context length being 4 tokens.
+fn tiny_phi3(dev: &Device) -> Result<Vec<u8>> {
+    let (vocab, dim, hidden, kv_dim) = (VOCAB, 8, 12, 4);
+    let w = |shape: (usize, usize)| Tensor::randn(0f32, 0.1, shape, dev);
+    let ones = Tensor::ones(dim, candle::DType::F32, dev)?;
+    let metadata = [
+        ("general.architecture", Value::String("phi3".to_string())),
+        ("phi3.context_length", Value::U32(16)),
+        ("phi3.rope.scaling.original_context_length", Value::U32(4)),
+        ("phi3.attention.head_count", Value::U32(2)),
+        ("phi3.attention.head_count_kv", Value::U32(1)),
+        ("phi3.block_count", Value::U32(1)),
+        ("phi3.embedding_length", Value::U32(dim as u32)),
+        ("phi3.feed_forward_length", Value::U32(hidden as u32)),
+        ("phi3.rope.dimension_count", Value::U32(4)),
+        ("phi3.attention.layer_norm_rms_epsilon", Value::F32(1e-5)),
+    ];
+    let tensors = [
+        ("token_embd.weight", w((vocab, dim))?),
+        ("output_norm.weight", ones.clone()),
+        ("output.weight", w((vocab, dim))?),
+        ("rope_factors_short.weight", Tensor::new(&[1f32, 1.5], dev)?),
+        ("rope_factors_long.weight", Tensor::new(&[2f32, 4.], dev)?),
+        ("blk.0.attn_qkv.weight", w((dim + 2 * kv_dim, dim))?),
+        ("blk.0.attn_output.weight", w((dim, dim))?),
+        ("blk.0.ffn_up.weight", w((2 * hidden, dim))?),
+        ("blk.0.ffn_down.weight", w((dim, hidden))?),
+        ("blk.0.attn_norm.weight", ones.clone()),
+        ("blk.0.ffn_norm.weight", ones.clone()),
+    ];
+    write_gguf(&metadata, &tensors)
+}
+
+#[test]
+fn phi3_longrope() -> Result<()> {
+    let dev = &Device::Cpu;
+    let data = tiny_phi3(dev)?;
+    let logits = |prompt: &[u32], chunk_size: usize| -> Result<Vec<f32>> {
+        let mut reader = std::io::Cursor::new(&data);
+        let ct = gguf_file::Content::read(&mut reader)?;
+        let mut model = ModelWeights::from_gguf(ct, &mut reader, dev)?;
+        assert!(matches!(model, ModelWeights::Phi3(_)));
+        let logits = candle_transformers::generation::forward_chunked(
+            prompt,
+            chunk_size,
+            0,
+            dev,
+            |input, pos| model.forward(input, pos),
+        )?;
+        logits.squeeze(0)?.to_vec1::<f32>()
+    };
+    // Within the original context length the short factors are used for all the chunks.
+    let prompt = [1u32, 5, 3, 7];
+    let expected = logits(&prompt, prompt.len())?;
+    assert_eq!(expected.len(), VOCAB);
+    for chunk_size in [1, 3] {
+        for (e, v) in expected.iter().zip(logits(&prompt, chunk_size)?.iter()) {
+            assert!((e - v).abs() < 1e-5, "{chunk_size} {e} {v}");
+        }
+    }
+    // Past it the long factors are used.
+    let long = logits(&[1, 5, 3, 7, 9, 2, 4], 7)?;
+    assert_eq!(long.len(), VOCAB);
+    Ok(())
+}
+
+#[test]
+fn memory_report() -> Result<()> {
+    let dev = &Device::Cpu;
+    let ct = gguf_file::Content::read(&mut std::io::Cursor::new(tiny_llama(dev)?))?;
+    let report = MemoryReport::from_gguf(&ct, dev, 64)?;
+    // 824 f32 weights, 2 heads of size 4 for the keys and values of a single layer.
+    assert_eq!(report.weights, 824 * 4);
+    assert_eq!(report.kv_cache, 2 * 2 * 4 * 64 * 4);
+    assert_eq!(
+        report.with_kv_dtype(candle::DType::F16).kv_cache,
+        report.kv_cache / 2
+    );
+    let small = report.with_context_length(32);
+    assert_eq!(small.kv_cache, report.kv_cache / 2);
+    assert!(small.activations < report.activations);
+
+    assert_eq!(report.max_context_length(small.total()), Some(32));
+    assert_eq!(report.max_context_length(small.total() + 1), Some(32));
+    assert_eq!(report.max_context_length(usize::MAX), Some(64));
+    assert_eq!(report.max_context_length(report.weights), None);
+    Ok(())
+}
+
+#[test]
+fn llama_model_config() -> Result<()> {
+    use candle_transformers::models::quantized_llama::{self, ModelConfig};
+    let dev = &Device::Cpu;
+    let data = tiny_llama(dev)?;
+    let logits = |config: ModelConfig| -> Result<Vec<f32>> {
+        let mut reader = std::io::Cursor::new(&data);
+        let ct = gguf_file::Content::read(&mut reader)?;
+        let mut model =
+            quantized_llama::ModelWeights::from_gguf_with_config(ct, &mut reader, dev, config)?;
+        model.forward(&Tensor::new(&[[1u32, 5, 3]], dev)?, 0)?;
+        let logits = model.forward(&Tensor::new(&[[7u32]], dev)?, 3)?;
+        assert_eq!(logits.dtype(), candle::DType::F32);
+        logits.squeeze(0)?.to_vec1::<f32>()
+    };
+    let expected = logits(ModelConfig::default())?;
+    let f16_kv = logits(ModelConfig::default().with_kv_dtype(candle::DType::F16))?;
+    let f16 = logits(ModelConfig::new(candle::DType::F16))?;
+    for (e, (v1, v2)) in expected.iter().zip(f16_kv.iter().zip(f16.iter())) {
+        assert!((e - v1).abs() < 1e-3, "{e} {v1}");
+        assert!((e - v2).abs() < 1e-2, "{e} {v2}");
+    }
+    Ok(())
+}
+
+#[test]
+fn chunked_prompt() -> Result<()> {
+    let dev = &Device::Cpu;
+    let data = tiny_llama(dev)?;
+    let prompt = [1u32, 5, 3, 7, 9, 2, 4];
+    let logits = |chunk_size: usize| -> Result<Vec<f32>> {
+        let mut reader = std::io::Cursor::new(&data);
+        let ct = gguf_file::Content::read(&mut reader)?;
+        let mut model = ModelWeights::from_gguf(ct, &mut reader, dev)?;
+        let logits = candle_transformers::generation::forward_chunked(
+            &prompt,
+            chunk_size,
+            0,
+            dev,
+            |input, pos| model.forward(input, pos),
+        )?;
+        logits.squeeze(0)?.to_vec1::<f32>()
+    };
+    let expected = logits(prompt.len())?;
+    for chunk_size in [1, 2, 3] {
+        for (e, v) in expected.iter().zip(logits(chunk_size)?.iter()) {
+            assert!((e - v).abs() < 1e-5, "{chunk_size} {e} {v}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn shared_weights() -> Result<()> {
+    use candle_transformers::models::quantized_llama;
+    let dev = &Device::Cpu;
+    let data = tiny_llama(dev)?;
+    let mut reader = std::io::Cursor::new(&data);
+    let ct = gguf_file::Content::read(&mut reader)?;
+    let mut model = quantized_llama::ModelWeights::from_gguf(ct, &mut reader, dev)?;
+    let prompts = [[1u32, 5, 3], [4, 2, 9]];
+    let expected = prompts
+        .iter()
+        .map(|prompt| {
+            model.clear_kv_cache();
+            model.forward(&Tensor::new(&prompt[..2], dev)?.unsqueeze(0)?, 0)?;
+            let logits = model.forward(&Tensor::new(&prompt[2..], dev)?.unsqueeze(0)?, 2)?;
+            logits.flatten_all()?.to_vec1::<f32>()
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    // A single copy of the weights shared by multiple threads, each with its own cache.
+    fn assert_sync<T: Sync + Send>(_: &T) {}
+    assert_sync(&model);
+    let model = std::sync::Arc::new(model);
+    let handles = prompts
+        .iter()
+        .map(|prompt| {
+            let (model, prompt, dev) = (model.clone(), *prompt, dev.clone());
+            std::thread::spawn(move || -> Result<Vec<f32>> {
+                let mut cache = model.new_cache();
+                let input = Tensor::new(&prompt[..2], &dev)?.unsqueeze(0)?;
+                model.forward_with_cache(&input, 0, &mut cache)?;
+                assert_eq!(cache.seq_len(), 2);
+                let input = Tensor::new(&prompt[2..], &dev)?.unsqueeze(0)?;
+                let logits = model.forward_with_cache(&input, 2, &mut cache)?;
+                logits.flatten_all()?.to_vec1::<f32>()
+            })
+        })
+        .collect::<Vec<_>>();
+    for (handle, expected) in handles.into_iter().zip(expected) {
+        let logits = handle.join().unwrap()?;
+        for (e, v) in expected.iter().zip(logits.iter()) {
+            assert!((e - v).abs() < 1e-6, "{e} {v}");
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn batched_forward() -> Result<()> {
+    use candle_transformers::models::quantized_llama;
+    let dev = &Device::Cpu;
+    let data = tiny_llama(dev)?;
+    let mut reader = std::io::Cursor::new(&data);
+    let ct = gguf_file::Content::read(&mut reader)?;
+    let model = quantized_llama::ModelWeights::from_gguf(ct, &mut reader, dev)?;
+    // The logits for the last token of a sequence processed on its own.
+    let last_logits = |tokens: &[u32]| -> Result<Tensor> {
+        let input = Tensor::new(tokens, dev)?.unsqueeze(0)?;
+        model
+            .forward_with_cache(&input, 0, &mut model.new_cache())?
+            .squeeze(0)
+    };
+    let assert_close = |a: &Tensor, b: &Tensor| -> Result<()> {
+        let diff = (a - b)?.abs()?.max(0)?.to_vec0::<f32>()?;
+        assert!(diff < 1e-5, "{diff}");
+        Ok(())
+    };
+
+    // The prompts of different lengths are processed separately, then the sequences are
+    // processed as a batch with a different position for each of them.
+    let mut sequences = vec![vec![1u32, 5, 3], vec![4, 2, 9, 8, 6], vec![7]];
+    let mut caches = sequences
+        .iter()
+        .map(|prompt| {
+            let mut cache = model.new_cache();
+            let input = Tensor::new(prompt.as_slice(), dev)?.unsqueeze(0)?;
+            model.forward_with_cache(&input, 0, &mut cache)?;
+            Ok(cache)
+        })
+        .collect::<Result<Vec<_>>>()?;
+    for next_tokens in [[2u32, 3, 5], [6, 1, 5]] {
+        let index_pos = sequences.iter().map(|s| s.len()).collect::<Vec<_>>();
+        let input = Tensor::new(next_tokens.as_slice(), dev)?.unsqueeze(1)?;
+        let logits = model.forward_batch(&input, &index_pos, &mut caches)?;
+        assert_eq!(logits.dims(), &[3, VOCAB]);
+        for (i, (sequence, &token)) in sequences.iter_mut().zip(next_tokens.iter()).enumerate() {
+            sequence.push(token);
+            assert_close(&logits.get(i)?, &last_logits(sequence.as_slice())?)?;
+        }
+    }
+    for (cache, sequence) in caches.iter().zip(sequences.iter()) {
+        assert_eq!(cache.seq_len(), sequence.len());
+    }
+
+    // Multiple tokens per sequence, the first sequence being rewound after its first token.
+    let input = Tensor::new(&[[5u32, 3], [9, 8]], dev)?;
+    let logits = model.forward_batch(&input, &[1, 2], &mut caches[..2])?;
+    assert_close(&logits.get(0)?, &last_logits(&[1, 5, 3])?)?;
+    assert_close(&logits.get(1)?, &last_logits(&[4, 2, 9, 8])?)?;
+    assert!(model.forward_batch(&input, &[1], &mut caches[..2]).is_err());
+    Ok(())
+}
+
+#[test]
+fn context_shift() -> Result<()> {
+    use candle_transformers::models::quantized_llama;
+    let dev = &Device::Cpu;
+    let data = tiny_llama(dev)?;
+    let mut reader = std::io::Cursor::new(&data);
+    let ct = gguf_file::Content::read(&mut reader)?;
+    let model = quantized_llama::ModelWeights::from_gguf(ct, &mut reader, dev)?;
+    let logits = |tokens: &[u32], index_pos: usize, cache: &mut quantized_llama::KvCache| {
+        let input = Tensor::new(tokens, dev)?.unsqueeze(0)?;
+        model
+            .forward_with_cache(&input, index_pos, cache)?
+            .flatten_all()?
+            .to_vec1::<f32>()
+    };
+
+    // With a single layer the cached keys and values only depend on their token and position, so
+    // after dropping positions 1 and 2 the cache matches the one computed for the other tokens.
+    let mut cache = model.new_cache();
+    logits(&[1, 5, 3, 7, 9], 0, &mut cache)?;
+    model.shift_cache(&mut cache, 1, 2)?;
+    assert_eq!(cache.seq_len(), 3);
+    let shifted = logits(&[4], 3, &mut cache)?;
+    let expected = logits(&[1, 7, 9, 4], 0, &mut model.new_cache())?;
+    for (e, v) in expected.iter().zip(shifted.iter()) {
+        assert!((e - v).abs() < 1e-5, "{e} {v}");
+    }
+
+    // Without kept positions, and dropping everything.
+    let mut cache = model.new_cache();
+    logits(&[1, 5, 3, 7], 0, &mut cache)?;
+    model.shift_cache(&mut cache, 0, 3)?;
+    let shifted = logits(&[2, 6], 1, &mut cache)?;
+    let expected = logits(&[7, 2, 6], 0, &mut model.new_cache())?;
+    for (e, v) in expected.iter().zip(shifted.iter()) {
+        assert!((e - v).abs() < 1e-5, "{e} {v}");
+    }
+    assert!(model.shift_cache(&mut cache, 2, 2).is_err());
+    model.shift_cache(&mut cache, 0, 3)?;
+    assert_eq!(cache.seq_len(), 0);
+    Ok(())
+}
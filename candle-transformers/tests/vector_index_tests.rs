@@ -0,0 +1,104 @@
+use candle::{Device, Result, Tensor};
+use candle_transformers::vector_index::{FlatIndex, HnswConfig, HnswIndex, Metric, VectorIndex};
+
+#[test]
+fn flat_index() -> Result<()> {
+    let dev = &Device::Cpu;
+    let vectors = Tensor::new(&[[1f32, 0.], [0., 2.], [3., 3.]], dev)?;
+    let mut index = FlatIndex::new(2, Metric::Dot, dev);
+    assert!(index.is_empty());
+    assert_eq!(index.search(&Tensor::new(&[1f32, 0.], dev)?, 2)?, [vec![]]);
+    index.add(&vectors.narrow(0, 0, 2)?)?;
+    index.add(&vectors.get(2)?)?;
+    assert_eq!(index.len(), 3);
+
+    let queries = Tensor::new(&[[1f32, 0.], [0., 1.]], dev)?;
+    let matches = index.search(&queries, 2)?;
+    assert_eq!(matches, [vec![(2, 3.), (0, 1.)], vec![(2, 3.), (1, 2.)]]);
+
+    let mut index = FlatIndex::new(2, Metric::Cosine, dev);
+    index.add(&vectors)?;
+    let matches = index.search(&queries, 5)?;
+    let ids = matches
+        .iter()
+        .map(|m| m.iter().map(|(id, _)| *id).collect::<Vec<_>>())
+        .collect::<Vec<_>>();
+    assert_eq!(ids, [[0, 2, 1], [1, 2, 0]]);
+    assert!((matches[0][0].1 - 1.).abs() < 1e-6);
+    assert!((matches[0][1].1 - 0.5f32.sqrt()).abs() < 1e-6);
+
+    assert!(index.add(&Tensor::new(&[1f32, 2., 3.], dev)?).is_err());
+    Ok(())
+}
+
+fn random_vectors(n: usize, dim: usize, seed: u64) -> Result<Tensor> {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    let data = (0..n * dim)
+        .map(|_| rng.gen_range(-1f32..1.))
+        .collect::<Vec<_>>();
+    Tensor::from_vec(data, (n, dim), &Device::Cpu)
+}
+
+#[test]
+fn hnsw_recall() -> Result<()> {
+    let dev = &Device::Cpu;
+    let vectors = random_vectors(1000, 16, 42)?;
+    let queries = random_vectors(50, 16, 43)?;
+    let mut flat = FlatIndex::new(16, Metric::Cosine, dev);
+    flat.add(&vectors)?;
+    let config = HnswConfig::default().with_m(8).with_ef_construction(64);
+    let mut hnsw = HnswIndex::new(16, Metric::Cosine, config);
+    hnsw.add(&vectors)?;
+    assert_eq!(hnsw.len(), 1000);
+
+    let k = 10;
+    let exact = flat.search(&queries, k)?;
+    let approx = hnsw.search(&queries, k)?;
+    let mut found = 0;
+    for (exact, approx) in exact.iter().zip(approx.iter()) {
+        assert_eq!(approx.len(), k);
+        assert!(approx.windows(2).all(|w| w[0].1 >= w[1].1));
+        found += approx
+            .iter()
+            .filter(|(id, _)| exact.iter().any(|(i, _)| i == id))
+            .count();
+    }
+    let recall = found as f64 / (k * exact.len()) as f64;
+    assert!(recall > 0.9, "recall {recall}");
+    Ok(())
+}
+
+#[test]
+fn vector_index_persistence() -> Result<()> {
+    let dev = &Device::Cpu;
+    let vectors = random_vectors(200, 8, 1)?;
+    let queries = random_vectors(10, 8, 2)?;
+    let dir = std::env::temp_dir();
+
+    let mut flat = FlatIndex::new(8, Metric::Dot, dev);
+    flat.add(&vectors)?;
+    let path = dir.join("candle_flat_index_test.safetensors");
+    flat.save(&path)?;
+    let loaded = FlatIndex::load(&path, dev)?;
+    assert_eq!(loaded.metric(), Metric::Dot);
+    assert_eq!(loaded.search(&queries, 5)?, flat.search(&queries, 5)?);
+
+    let mut hnsw = HnswIndex::new(8, Metric::Cosine, HnswConfig::default().with_m(4));
+    hnsw.add(&vectors.narrow(0, 0, 100)?)?;
+    let path = dir.join("candle_hnsw_index_test.safetensors");
+    hnsw.save(&path)?;
+    let mut loaded = HnswIndex::load(&path)?;
+    assert_eq!(loaded.config(), hnsw.config());
+    assert_eq!(loaded.search(&queries, 5)?, hnsw.search(&queries, 5)?);
+    // The loaded index can still be extended.
+    loaded.add(&vectors.narrow(0, 100, 100)?)?;
+    assert_eq!(loaded.len(), 200);
+    assert_eq!(loaded.search(&queries, 5)?.len(), 10);
+
+    let empty = HnswIndex::new(8, Metric::Dot, HnswConfig::default());
+    empty.save(&path)?;
+    assert!(HnswIndex::load(&path)?.is_empty());
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
@@ -2,29 +2,13 @@ use crate::console_log;
 use crate::worker::{ModelData, Worker, WorkerInput, WorkerOutput};
 use std::str::FromStr;
 use wasm_bindgen::prelude::*;
-use wasm_bindgen_futures::JsFuture;
 use yew::{html, Component, Context, Html};
 use yew_agent::{Bridge, Bridged};
 
 async fn fetch_url(url: &str) -> Result<Vec<u8>, JsValue> {
-    use web_sys::{Request, RequestCache, RequestInit, RequestMode, Response};
-    let window = web_sys::window().ok_or("window")?;
-    let opts = RequestInit::new();
-    opts.set_method("GET");
-    opts.set_mode(RequestMode::Cors);
-    opts.set_cache(RequestCache::NoCache);
-    let request = Request::new_with_str_and_init(url, &opts)?;
-
-    let resp_value = JsFuture::from(window.fetch_with_request(&request)).await?;
-
-    // `resp_value` is a `Response` object.
-    assert!(resp_value.is_instance_of::<Response>());
-    let resp: Response = resp_value.dyn_into()?;
-    let data = JsFuture::from(resp.blob()?).await?;
-    let blob = web_sys::Blob::from(data);
-    let array_buffer = JsFuture::from(blob.array_buffer()).await?;
-    let data = js_sys::Uint8Array::new(&array_buffer).to_vec();
-    Ok(data)
+    // The weights are cached by the browser so that they are only downloaded once.
+    let cache = candle_wasm_utils::FileCache::open("candle-llama2-c").await?;
+    cache.fetch(url, None).await
 }
 
 pub enum Msg {
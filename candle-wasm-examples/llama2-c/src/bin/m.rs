@@ -43,10 +43,13 @@ impl Model {
 impl Model {
     #[wasm_bindgen(constructor)]
     pub fn new(weights: Vec<u8>, tokenizer: Vec<u8>) -> Result<Model, JsError> {
-        let model = M::load(ModelData {
-            tokenizer,
-            model: weights,
-        });
+        let model = M::load_with_device(
+            ModelData {
+                tokenizer,
+                model: weights,
+            },
+            &Device::Cpu,
+        );
         let logits_processor = LogitsProcessor::new(299792458, None, None);
         match model {
             Ok(inner) => Ok(Self {
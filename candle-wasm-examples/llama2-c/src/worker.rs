@@ -4,6 +4,8 @@ use candle::{DType, Device, IndexOp, Result, Shape, Tensor};
 use candle_nn::VarBuilder;
 use candle_transformers::generation::LogitsProcessor;
 use serde::{Deserialize, Serialize};
+use std::cell::RefCell;
+use std::rc::Rc;
 use tokenizers::Tokenizer;
 use wasm_bindgen::prelude::*;
 use yew_agent::{HandlerId, Public, WorkerLink};
@@ -54,10 +56,11 @@ pub struct Model {
     pub config: Config,
     pub llama: Llama,
     pub tokenizer: Tokenizer,
+    pub device: Device,
 }
 
 impl Model {
-    fn run(
+    async fn run(
         &self,
         link: &WorkerLink<Worker>,
         id: HandlerId,
@@ -65,7 +68,7 @@ impl Model {
         top_p: f64,
         prompt: String,
     ) -> Result<()> {
-        let dev = Device::Cpu;
+        let dev = &self.device;
         let temp = if temp <= 0. { None } else { Some(temp) };
         let top_p = if top_p <= 0. || top_p >= 1.0 {
             None
@@ -93,9 +96,10 @@ impl Model {
                 tokens.len()
             };
             let ctxt = &tokens[tokens.len().saturating_sub(context_size)..];
-            let input = Tensor::new(ctxt, &dev)?.unsqueeze(0)?;
+            let input = Tensor::new(ctxt, dev)?.unsqueeze(0)?;
             let logits = self.llama.forward(&input, index_pos)?;
-            let logits = logits.squeeze(0)?;
+            // Reading wgpu tensors cannot block in the browser.
+            let logits = logits.squeeze(0)?.to_cpu_async().await?;
             index_pos += ctxt.len();
 
             let next_token = logits_processor.sample(&logits)?;
@@ -247,12 +251,19 @@ impl TransformerWeights {
 }
 
 impl Model {
-    pub fn load(md: ModelData) -> Result<Self> {
-        let dev = Device::Cpu;
+    /// Loads the model on the gpu when built with the `wgpu` feature and WebGPU is available in
+    /// the browser, and on the cpu otherwise.
+    pub async fn load(md: ModelData) -> Result<Self> {
+        let dev = Device::wgpu_if_available_async(0).await?;
+        console_log!("running on {:?}", dev.location());
+        Self::load_with_device(md, &dev)
+    }
+
+    pub fn load_with_device(md: ModelData, dev: &Device) -> Result<Self> {
         let mut model = std::io::Cursor::new(md.model);
         let config = Config::from_reader(&mut model)?;
-        let weights = TransformerWeights::from_reader(&mut model, &config, &dev)?;
-        let vb = weights.var_builder(&config, &dev)?;
+        let weights = TransformerWeights::from_reader(&mut model, &config, dev)?;
+        let vb = weights.var_builder(&config, dev)?;
         let cache = Cache::new(true, &config, vb.pp("rot"))?;
         let llama = Llama::load(vb, &cache, &config)?;
         let tokenizer =
@@ -262,13 +273,15 @@ impl Model {
             config,
             llama,
             tokenizer,
+            device: dev.clone(),
         })
     }
 }
 
 pub struct Worker {
     link: WorkerLink<Self>,
-    model: Option<Model>,
+    // The model is shared with the futures spawned to load it and to run it.
+    model: Rc<RefCell<Option<Rc<Model>>>>,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -291,7 +304,10 @@ impl yew_agent::Worker for Worker {
     type Reach = Public<Self>;
 
     fn create(link: WorkerLink<Self>) -> Self {
-        Self { link, model: None }
+        Self {
+            link,
+            model: Rc::new(RefCell::new(None)),
+        }
     }
 
     fn update(&mut self, _msg: Self::Message) {
@@ -299,31 +315,39 @@ impl yew_agent::Worker for Worker {
     }
 
     fn handle_input(&mut self, msg: Self::Input, id: HandlerId) {
-        let output = match msg {
-            WorkerInput::ModelData(md) => match Model::load(md) {
-                Ok(model) => {
-                    self.model = Some(model);
-                    Ok(WorkerOutput::WeightsLoaded)
-                }
-                Err(err) => Err(format!("model creation error {err:?}")),
-            },
-            WorkerInput::Run(temp, top_p, prompt) => match &mut self.model {
-                None => Err("model has not been set yet".to_string()),
-                Some(model) => {
-                    {
-                        let mut cache = model.cache.kvs.lock().unwrap();
-                        for elem in cache.iter_mut() {
-                            *elem = None
+        let link = self.link.clone();
+        let model = self.model.clone();
+        wasm_bindgen_futures::spawn_local(async move {
+            let output = match msg {
+                WorkerInput::ModelData(md) => match Model::load(md).await {
+                    Ok(m) => {
+                        *model.borrow_mut() = Some(Rc::new(m));
+                        Ok(WorkerOutput::WeightsLoaded)
+                    }
+                    Err(err) => Err(format!("model creation error {err:?}")),
+                },
+                WorkerInput::Run(temp, top_p, prompt) => {
+                    let model = model.borrow().clone();
+                    match model {
+                        None => Err("model has not been set yet".to_string()),
+                        Some(model) => {
+                            {
+                                let mut cache = model.cache.kvs.lock().unwrap();
+                                for elem in cache.iter_mut() {
+                                    *elem = None
+                                }
+                            }
+                            let result = model
+                                .run(&link, id, temp, top_p, prompt)
+                                .await
+                                .map_err(|e| e.to_string());
+                            Ok(WorkerOutput::GenerationDone(result))
                         }
                     }
-                    let result = model
-                        .run(&self.link, id, temp, top_p, prompt)
-                        .map_err(|e| e.to_string());
-                    Ok(WorkerOutput::GenerationDone(result))
                 }
-            },
-        };
-        self.link.respond(id, output);
+            };
+            link.respond(id, output);
+        });
     }
 
     fn name_of_resource() -> &'static str {
@@ -0,0 +1,320 @@
+//! Browser side caching of the model files.
+//!
+//! Files are downloaded by chunks using http range requests and each chunk is stored with the
+//! Cache API as soon as it has been received, so an interrupted download resumes where it stopped
+//! and a file is only downloaded once. When all the chunks are available, the sha256 of the
+//! content is checked against the expected one and a metadata entry marks the file as complete.
+use sha2::{Digest, Sha256};
+use wasm_bindgen::prelude::*;
+use wasm_bindgen_futures::JsFuture;
+use web_sys::{Cache, CacheStorage, Request, RequestInit, RequestMode, Response};
+
+pub const DEFAULT_CHUNK_SIZE: usize = 32 * 1024 * 1024;
+
+// The Cache API is available both from the main thread and from the web workers.
+enum Scope {
+    Window(web_sys::Window),
+    Worker(web_sys::WorkerGlobalScope),
+}
+
+impl Scope {
+    fn get() -> Result<Self, JsValue> {
+        let global = js_sys::global();
+        if let Some(window) = global.dyn_ref::<web_sys::Window>() {
+            Ok(Self::Window(window.clone()))
+        } else if let Some(worker) = global.dyn_ref::<web_sys::WorkerGlobalScope>() {
+            Ok(Self::Worker(worker.clone()))
+        } else {
+            Err("unsupported global scope".into())
+        }
+    }
+
+    fn caches(&self) -> Result<CacheStorage, JsValue> {
+        match self {
+            Self::Window(w) => w.caches(),
+            Self::Worker(w) => w.caches(),
+        }
+    }
+
+    fn fetch(&self, request: &Request) -> js_sys::Promise {
+        match self {
+            Self::Window(w) => w.fetch_with_request(request),
+            Self::Worker(w) => w.fetch_with_request(request),
+        }
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn key(url: &str, suffix: &str) -> String {
+    let sep = if url.contains('?') { '&' } else { '?' };
+    format!("{url}{sep}candle-{suffix}")
+}
+
+fn chunk_key(url: &str, chunk_size: usize, index: usize) -> String {
+    key(url, &format!("chunk={chunk_size}-{index}"))
+}
+
+fn metadata_key(url: &str) -> String {
+    key(url, "metadata")
+}
+
+async fn response_bytes(response: &Response) -> Result<Vec<u8>, JsValue> {
+    let buffer = JsFuture::from(response.array_buffer()?).await?;
+    Ok(js_sys::Uint8Array::new(&buffer).to_vec())
+}
+
+// The content of a completely downloaded file.
+struct Metadata {
+    size: usize,
+    chunk_size: usize,
+    sha256: String,
+}
+
+impl Metadata {
+    fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split_whitespace();
+        let size = parts.next()?.parse().ok()?;
+        let chunk_size = parts.next()?.parse().ok()?;
+        let sha256 = parts.next()?.to_string();
+        Some(Self {
+            size,
+            chunk_size,
+            sha256,
+        })
+    }
+
+    fn num_chunks(&self) -> usize {
+        self.size.div_ceil(self.chunk_size.max(1))
+    }
+}
+
+/// A cache for the files downloaded by the examples, backed by the browser Cache API.
+pub struct FileCache {
+    scope: Scope,
+    cache: Cache,
+    chunk_size: usize,
+}
+
+impl FileCache {
+    /// Opens the cache named `name`, creating it if needed.
+    pub async fn open(name: &str) -> Result<Self, JsValue> {
+        let scope = Scope::get()?;
+        let cache = JsFuture::from(scope.caches()?.open(name)).await?;
+        Ok(Self {
+            scope,
+            cache: cache.dyn_into()?,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+        })
+    }
+
+    /// The size of the range requests, this only applies to the files that are not cached yet.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    /// Returns the content of `url`, downloading it if it is not cached. When `sha256` is
+    /// specified, the content is checked against this hex encoded hash.
+    pub async fn fetch(&self, url: &str, sha256: Option<&str>) -> Result<Vec<u8>, JsValue> {
+        self.fetch_with_progress(url, sha256, |_, _| {}).await
+    }
+
+    /// Same as [`Self::fetch`], `progress` is called with the number of bytes available so far
+    /// and the total size after each chunk.
+    pub async fn fetch_with_progress(
+        &self,
+        url: &str,
+        sha256: Option<&str>,
+        mut progress: impl FnMut(usize, usize),
+    ) -> Result<Vec<u8>, JsValue> {
+        if let Some(metadata) = self.metadata(url).await? {
+            let hash_matches = sha256.is_none_or(|h| h.eq_ignore_ascii_case(&metadata.sha256));
+            if hash_matches {
+                if let Some(data) = self.read_chunks(url, &metadata).await? {
+                    progress(data.len(), data.len());
+                    return Ok(data);
+                }
+            }
+            // Some chunks have been evicted or the file has changed upstream.
+            self.delete(url).await?;
+        }
+        let (data, chunk_size) = match self.content_length(url).await? {
+            Some(size) => self.download_chunks(url, size, &mut progress).await?,
+            None => {
+                let data = self.download(url, None).await?;
+                self.put(&chunk_key(url, data.len(), 0), &data).await;
+                progress(data.len(), data.len());
+                let size = data.len();
+                (data, size)
+            }
+        };
+        let hash = hex(&Sha256::digest(&data));
+        if let Some(sha256) = sha256 {
+            if !sha256.eq_ignore_ascii_case(&hash) {
+                self.delete(url).await?;
+                return Err(
+                    format!("sha256 mismatch for {url}, expected {sha256}, got {hash}").into(),
+                );
+            }
+        }
+        let metadata = format!("{} {chunk_size} {hash}", data.len());
+        self.put(&metadata_key(url), metadata.as_bytes()).await;
+        Ok(data)
+    }
+
+    /// Removes `url` from the cache, including the chunks of a partial download. Returns whether
+    /// some entries were removed.
+    pub async fn delete(&self, url: &str) -> Result<bool, JsValue> {
+        // Keys are stored as absolute urls.
+        let prefix = Request::new_with_str(&key(url, ""))?.url();
+        let keys: js_sys::Array = JsFuture::from(self.cache.keys()).await?.dyn_into()?;
+        let mut deleted = false;
+        for request in keys.iter() {
+            let request: Request = request.dyn_into()?;
+            if request.url().starts_with(&prefix) {
+                let removed = JsFuture::from(self.cache.delete_with_request(&request)).await?;
+                deleted |= removed.as_bool().unwrap_or(false);
+            }
+        }
+        Ok(deleted)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Response>, JsValue> {
+        let response = JsFuture::from(self.cache.match_with_str(key)).await?;
+        if response.is_undefined() {
+            Ok(None)
+        } else {
+            Ok(Some(response.dyn_into()?))
+        }
+    }
+
+    async fn put(&self, key: &str, data: &[u8]) {
+        let mut data = data.to_vec();
+        let response = match Response::new_with_opt_u8_array(Some(&mut data)) {
+            Ok(response) => response,
+            Err(_) => return,
+        };
+        // Failing to store a chunk, e.g. when the storage quota is exceeded, only means that it
+        // will be downloaded again.
+        let _ = JsFuture::from(self.cache.put_with_str(key, &response)).await;
+    }
+
+    async fn metadata(&self, url: &str) -> Result<Option<Metadata>, JsValue> {
+        let response = match self.get(&metadata_key(url)).await? {
+            None => return Ok(None),
+            Some(response) => response,
+        };
+        let text = JsFuture::from(response.text()?).await?;
+        Ok(text.as_string().as_deref().and_then(Metadata::parse))
+    }
+
+    async fn read_chunks(
+        &self,
+        url: &str,
+        metadata: &Metadata,
+    ) -> Result<Option<Vec<u8>>, JsValue> {
+        let mut data = Vec::with_capacity(metadata.size);
+        for index in 0..metadata.num_chunks() {
+            match self
+                .get(&chunk_key(url, metadata.chunk_size, index))
+                .await?
+            {
+                None => return Ok(None),
+                Some(response) => data.extend_from_slice(&response_bytes(&response).await?),
+            }
+        }
+        if data.len() != metadata.size {
+            return Ok(None);
+        }
+        Ok(Some(data))
+    }
+
+    async fn request(
+        &self,
+        url: &str,
+        method: &str,
+        range: Option<(usize, usize)>,
+    ) -> Result<Response, JsValue> {
+        let opts = RequestInit::new();
+        opts.set_method(method);
+        opts.set_mode(RequestMode::Cors);
+        let request = Request::new_with_str_and_init(url, &opts)?;
+        if let Some((start, end)) = range {
+            request
+                .headers()
+                .set("Range", &format!("bytes={start}-{}", end - 1))?;
+        }
+        let response: Response = JsFuture::from(self.scope.fetch(&request))
+            .await?
+            .dyn_into()?;
+        if !response.ok() {
+            return Err(format!("cannot fetch {url}: status {}", response.status()).into());
+        }
+        Ok(response)
+    }
+
+    async fn content_length(&self, url: &str) -> Result<Option<usize>, JsValue> {
+        let response = self.request(url, "HEAD", None).await?;
+        let length = response.headers().get("Content-Length")?;
+        Ok(length.and_then(|l| l.parse().ok()).filter(|&l| l > 0))
+    }
+
+    async fn download(&self, url: &str, range: Option<(usize, usize)>) -> Result<Vec<u8>, JsValue> {
+        let response = self.request(url, "GET", range).await?;
+        response_bytes(&response).await
+    }
+
+    // Returns the content and the chunk size used to store it.
+    async fn download_chunks(
+        &self,
+        url: &str,
+        size: usize,
+        progress: &mut impl FnMut(usize, usize),
+    ) -> Result<(Vec<u8>, usize), JsValue> {
+        let chunk_size = self.chunk_size.min(size);
+        let mut data = Vec::with_capacity(size);
+        for (index, start) in (0..size).step_by(chunk_size).enumerate() {
+            let key = chunk_key(url, chunk_size, index);
+            let chunk = match self.get(&key).await? {
+                Some(response) => response_bytes(&response).await?,
+                None => {
+                    let end = usize::min(start + chunk_size, size);
+                    let response = self.request(url, "GET", Some((start, end))).await?;
+                    let chunk = response_bytes(&response).await?;
+                    // Servers that do not support range requests return the whole file.
+                    if response.status() != 206 {
+                        if chunk.len() != size {
+                            return Err(format!("unexpected size for {url}").into());
+                        }
+                        self.put(&chunk_key(url, size, 0), &chunk).await;
+                        progress(size, size);
+                        return Ok((chunk, size));
+                    }
+                    if chunk.len() != end - start {
+                        return Err(format!("unexpected chunk size for {url}").into());
+                    }
+                    self.put(&key, &chunk).await;
+                    chunk
+                }
+            };
+            data.extend_from_slice(&chunk);
+            progress(data.len(), size);
+        }
+        Ok((data, chunk_size))
+    }
+}
+
+/// Returns the content of `url` using the cache named `cache_name`, see [`FileCache::fetch`].
+/// This is exported for the examples that fetch their weights from javascript.
+#[wasm_bindgen(js_name = fetchCached)]
+pub async fn fetch_cached(
+    cache_name: String,
+    url: String,
+    sha256: Option<String>,
+) -> Result<Vec<u8>, JsValue> {
+    let cache = FileCache::open(&cache_name).await?;
+    cache.fetch(&url, sha256.as_deref()).await
+}
@@ -0,0 +1,3 @@
+//! Utilities shared by the candle wasm examples.
+pub mod cache;
+pub use cache::FileCache;
@@ -11,17 +11,27 @@ enum QuantizationMode {
 }
 
 impl QuantizationMode {
-    fn quantize(&self, name: &str, tensor: QTensor, dtype: GgmlDType) -> Result<QTensor> {
+    fn quantize(
+        &self,
+        name: &str,
+        tensor: QTensor,
+        dtype: GgmlDType,
+        imatrix: Option<&[f32]>,
+    ) -> Result<QTensor> {
         match self {
             Self::Llama => {
                 // Same behavior as the llama.cpp quantization.
                 let should_quantize = name.ends_with(".weight") && tensor.rank() == 2;
                 if should_quantize {
                     let tensor = tensor.dequantize(&Device::Cpu)?;
-                    if name == "output.weight" {
-                        QTensor::quantize(&tensor, GgmlDType::Q6K)
+                    let dtype = if name == "output.weight" {
+                        GgmlDType::Q6K
                     } else {
-                        QTensor::quantize(&tensor, dtype)
+                        dtype
+                    };
+                    match imatrix {
+                        Some(imatrix) => QTensor::quantize_imatrix(&tensor, imatrix, dtype),
+                        None => QTensor::quantize(&tensor, dtype),
                     }
                 } else {
                     Ok(tensor)
@@ -51,6 +61,12 @@ enum Quantization {
     Q5k,
     Q6k,
     Q8k,
+    #[value(name = "iq4_nl")]
+    Iq4Nl,
+    #[value(name = "iq2_xs")]
+    Iq2Xs,
+    #[value(name = "iq3_s")]
+    Iq3S,
     F16,
     F32,
 }
@@ -70,6 +86,9 @@ impl Quantization {
             Quantization::Q5k => GgmlDType::Q5K,
             Quantization::Q6k => GgmlDType::Q6K,
             Quantization::Q8k => GgmlDType::Q8K,
+            Quantization::Iq4Nl => GgmlDType::IQ4NL,
+            Quantization::Iq2Xs => GgmlDType::IQ2XS,
+            Quantization::Iq3S => GgmlDType::IQ3S,
             Quantization::F16 => GgmlDType::F16,
             Quantization::F32 => GgmlDType::F32,
         }
@@ -150,6 +169,11 @@ enum Command {
         /// Which tensor to quantize.
         #[arg(long, value_enum, default_value_t = QuantizationMode::Llama)]
         mode: QuantizationMode,
+
+        /// An importance matrix file, as generated by the llama.cpp imatrix tool, only used when
+        /// quantizing gguf files.
+        #[arg(long)]
+        imatrix: Option<std::path::PathBuf>,
     },
 
     Dequantize {
@@ -454,6 +478,7 @@ fn run_quantize(
     out_file: std::path::PathBuf,
     q: Quantization,
     qmode: QuantizationMode,
+    imatrix: Option<std::path::PathBuf>,
     device: &Device,
 ) -> Result<()> {
     if in_files.is_empty() {
@@ -480,6 +505,10 @@ fn run_quantize(
     let content = gguf_file::Content::read(&mut in_)?;
     println!("tensors: {}", content.tensor_infos.len());
 
+    let imatrix = match imatrix {
+        Some(imatrix) => candle::quantized::imatrix_file::load(imatrix)?,
+        None => std::collections::HashMap::new(),
+    };
     let dtype = q.dtype();
     let qtensors = content
         .tensor_infos
@@ -488,7 +517,8 @@ fn run_quantize(
             println!("  quantizing {name}");
             let mut in_file = std::fs::File::open(&in_files[0])?;
             let tensor = content.tensor(&mut in_file, name, device)?;
-            let tensor = qmode.quantize(name, tensor, dtype)?;
+            let imatrix = imatrix.get(name).map(|v| v.as_slice());
+            let tensor = qmode.quantize(name, tensor, dtype, imatrix)?;
             Ok((name, tensor))
         })
         .collect::<Result<Vec<_>>>()?;
@@ -535,7 +565,8 @@ fn main() -> anyhow::Result<()> {
             out_file,
             quantization,
             mode,
-        } => run_quantize(&in_file, out_file, quantization, mode, &device)?,
+            imatrix,
+        } => run_quantize(&in_file, out_file, quantization, mode, imatrix, &device)?,
         Command::Dequantize { in_file, out_file } => run_dequantize(in_file, out_file, &device)?,
     }
     Ok(())